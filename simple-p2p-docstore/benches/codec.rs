@@ -0,0 +1,88 @@
+//! Envelope encode/decode and snapshot chunk reassembly.
+//!
+//! `cargo bench --bench codec`; build with `--features compression` to include zstd.
+//! Payloads are fixed text, so runs are comparable.
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+
+use simple_p2p_docstore::behaviour::docstore::{
+    coalesce, CodecOptions, DocUpdate, Envelope, Snapshot, SnapshotAssembler,
+};
+
+const SIZES: [usize; 4] = [64, 1024, 16 * 1024, 256 * 1024];
+
+fn payload(size: usize) -> Vec<u8> {
+    (0..size).map(|i| b"lorem ipsum dolor sit amet "[i % 27]).collect()
+}
+
+fn uncompressed() -> CodecOptions {
+    CodecOptions { compression_threshold: None, ..CodecOptions::default() }
+}
+
+fn envelope(c: &mut Criterion) {
+    let mut group = c.benchmark_group("envelope");
+    for (label, opts) in [("plain", uncompressed()), ("default", CodecOptions::default())] {
+        for size in SIZES {
+            let env = Envelope::Update(DocUpdate::new("doc", payload(size)));
+            let bytes = env.encode_with(&opts);
+            group.throughput(Throughput::Bytes(size as u64));
+            group.bench_with_input(BenchmarkId::new(format!("encode/{label}"), size), &env, |b, env| {
+                b.iter(|| env.encode_with(&opts))
+            });
+            group.bench_with_input(BenchmarkId::new(format!("decode/{label}"), size), &bytes, |b, bytes| {
+                b.iter(|| Envelope::decode_with(bytes, &opts).expect("valid envelope"))
+            });
+        }
+    }
+    group.finish();
+}
+
+fn batch(c: &mut Criterion) {
+    let mut group = c.benchmark_group("batch");
+    let updates: Vec<DocUpdate> = (0..64).map(|_| DocUpdate::new("doc", payload(256))).collect();
+    group.throughput(Throughput::Elements(updates.len() as u64));
+    group.bench_function("coalesce+encode/64x256", |b| {
+        b.iter_batched(
+            || updates.clone(),
+            |updates| coalesce(updates, 64 * 1024).iter().map(|env| env.encode_with(&uncompressed())).count(),
+            BatchSize::SmallInput,
+        )
+    });
+    let envelopes: Vec<Vec<u8>> =
+        coalesce(updates.clone(), 64 * 1024).iter().map(|env| env.encode_with(&uncompressed())).collect();
+    group.bench_function("decode+unpack/64x256", |b| {
+        b.iter(|| {
+            envelopes
+                .iter()
+                .flat_map(|bytes| Envelope::decode_with(bytes, &uncompressed()).expect("valid envelope").into_updates())
+                .count()
+        })
+    });
+    group.finish();
+}
+
+fn reassembly(c: &mut Criterion) {
+    let mut group = c.benchmark_group("snapshot_reassembly");
+    for (size, chunk_size) in [(64 * 1024, 16 * 1024), (1024 * 1024, 64 * 1024), (4 * 1024 * 1024, 64 * 1024)] {
+        let chunks = Snapshot::new("doc", 1, payload(size)).chunks(chunk_size);
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::new(format!("chunks_of_{chunk_size}"), size), &chunks, |b, chunks| {
+            b.iter_batched(
+                || chunks.clone(),
+                |chunks| {
+                    let mut assembler = SnapshotAssembler::default();
+                    let mut done = None;
+                    for chunk in chunks {
+                        done = assembler.push(chunk).expect("consistent chunks");
+                    }
+                    done.expect("snapshot complete")
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, envelope, batch, reassembly);
+criterion_main!(benches);
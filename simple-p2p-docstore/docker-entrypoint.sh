@@ -3,7 +3,7 @@ set -euo pipefail
 
 P2P_DATA_DIR=${P2P_DATA_DIR:-/app/.p2p}
 IDENTITY_KEY_PATH=${IDENTITY_KEY_PATH:-$P2P_DATA_DIR/identity.key}
-CERT_PATH=${CERT_PATH:-$P2P_DATA_DIR/webrtc_cert.der}
+CERT_PATH=${CERT_PATH:-$P2P_DATA_DIR/webrtc_cert.pem}
 SIGNALING_PORT=${SIGNALING_PORT:-9090}
 
 # Ensure the data dir exists with safe permissions
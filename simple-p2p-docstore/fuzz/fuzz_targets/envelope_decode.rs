@@ -0,0 +1,20 @@
+//! `cargo +nightly fuzz run envelope_decode` from `simple-p2p-docstore/`.
+//!
+//! Feeds arbitrary bytes to the envelope decoder with a small decompression cap, so
+//! oversized frames and batches are hit quickly. Whatever decodes must re-encode to the
+//! same envelope.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use simple_p2p_docstore::behaviour::docstore::{CodecOptions, Envelope};
+
+fuzz_target!(|data: &[u8]| {
+    let opts = CodecOptions { max_decompressed_size: 64 * 1024, ..CodecOptions::default() };
+    let Ok(env) = Envelope::decode_with(data, &opts) else {
+        return;
+    };
+    let again = Envelope::decode_with(&env.encode_with(&opts), &opts).expect("re-encoded envelope decodes");
+    assert_eq!(again, env);
+    let _ = env.into_updates();
+});
@@ -1,35 +1,573 @@
-use libp2p::gossipsub::{self, IdentTopic, MessageAuthenticity, MessageId};
+use libp2p::gossipsub::{self, IdentTopic, MessageAuthenticity, MessageId, TopicHash, ValidationMode};
 use libp2p::identity::Keypair;
+use libp2p::PeerId;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
 use std::time::Duration;
 
+use crate::Error;
+
+pub mod announce;
+pub mod auth;
+pub mod cas;
+pub mod envelope;
+pub mod guest_link;
+pub mod hlc;
+pub mod order;
+pub mod pipeline;
+pub mod receipt;
+pub mod rooms;
+pub mod schema;
+pub mod scope;
+pub mod snapshot;
+pub mod topics;
+pub mod transaction;
+pub mod wire;
+
+pub use announce::{AnnounceError, AnnouncementKind, NetworkAnnouncement, Severity};
+pub use cas::CasConflict;
+pub use envelope::{
+    ack_requested, coalesce, supported_versions, unsupported_version, CodecOptions, DecodeError, DocUpdate, Envelope,
+    PublishDebouncer, CURRENT_PROTOCOL_VERSION, FLAG_ACK_REQUESTED, FLAG_CAS, FLAG_LOCAL_SCOPE, MIN_SUPPORTED_VERSION,
+};
+pub use hlc::{AuthorSeqs, Freshness, Hlc, HlcClock, ReplayGuard, Stamp, VectorClock};
+pub use order::UpdateOrder;
+pub use pipeline::{ClockLedger, Incoming, MessagePipeline, UpdateSink};
+pub use receipt::{
+    make_receipt_behaviour, ReceiptBehaviour, ReceiptError, ReceiptOutcome, UpdateReceipt, RECEIPT_PROTOCOL,
+};
+pub use rooms::{RoomChannel, RoomId, Rooms};
+pub use schema::{SchemaRegistry, SchemaValidator, SchemaViolation};
+pub use scope::{MessageScope, ScopeFilter};
+pub use snapshot::{Snapshot, SnapshotAssembler, SnapshotChunk, SnapshotPolicy, SnapshotScheduler};
+pub use topics::TopicRegistry;
+pub use transaction::{Transaction, TransactionAssembler, TransactionError, TransactionPart};
+
+/// Default cap on a single update payload.
+pub const DEFAULT_MAX_UPDATE_SIZE: usize = 256 * 1024;
+
+/// Default cap on a whole document.
+pub const DEFAULT_MAX_DOCUMENT_SIZE: usize = 16 * 1024 * 1024;
+
+/// Bytes reserved on top of `max_update_size` for the gossipsub protobuf framing,
+/// signature, envelope header and document id.
+pub const ENVELOPE_OVERHEAD: usize = 1024;
+
+/// Whether docstore messages are attributable to the peer that published them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum GossipAuthenticity {
+    /// Messages carry our peer id, a sequence number and a signature by our identity key.
+    #[default]
+    Signed,
+    /// Messages carry no author, sequence number or signature, so receivers only learn which
+    /// neighbour forwarded them. Requires `ValidationMode::Permissive` or `None`. Messages
+    /// are identified by content, so publishing identical bytes twice within the duplicate
+    /// cache window delivers them once. Applications that still want attribution sign it
+    /// into their payload.
+    Anonymous,
+}
+
+/// Tunables for the docstore gossipsub behaviour and its envelope codec.
+#[derive(Debug, Clone)]
+pub struct DocstoreGossipsubConfig {
+    pub heartbeat_interval: Duration,
+    /// Largest update payload that may be published or accepted.
+    pub max_update_size: usize,
+    /// Largest document a store may hold after applying updates.
+    pub max_document_size: usize,
+    /// Envelope bodies larger than this are zstd-compressed (requires the `compression`
+    /// feature; ignored otherwise). `None` disables compression.
+    pub compression_threshold: Option<usize>,
+    /// Decoders refuse compressed bodies that expand beyond this many bytes.
+    pub max_decompressed_size: usize,
+    pub authenticity: GossipAuthenticity,
+    /// How the author, sequence number and signature of inbound messages are checked.
+    /// `Strict` (the default) requires all three, so it only goes with `Signed`.
+    pub validation_mode: ValidationMode,
+    /// Topics published and accepted on.
+    pub topics: TopicRegistry,
+    /// Peers whose announcements are accepted on the announce topic; with none, every
+    /// announcement is rejected. See [`announce`].
+    pub announcers: HashSet<PeerId>,
+    /// Peers trusted with snapshots of any document, usually the FullNodes we rely on.
+    /// Besides them, only a document's own authors are. See [`snapshot`].
+    pub snapshot_publishers: HashSet<PeerId>,
+    /// Updates stamped further ahead of our wall clock than this are ignored, see
+    /// [`hlc`].
+    pub max_clock_drift: Duration,
+}
+
+impl Default for DocstoreGossipsubConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat_interval: Duration::from_secs(1),
+            max_update_size: DEFAULT_MAX_UPDATE_SIZE,
+            max_document_size: DEFAULT_MAX_DOCUMENT_SIZE,
+            compression_threshold: Some(envelope::DEFAULT_COMPRESSION_THRESHOLD),
+            max_decompressed_size: envelope::DEFAULT_MAX_DECOMPRESSED_SIZE,
+            authenticity: GossipAuthenticity::Signed,
+            validation_mode: ValidationMode::Strict,
+            topics: TopicRegistry::default(),
+            announcers: HashSet::new(),
+            snapshot_publishers: HashSet::new(),
+            max_clock_drift: hlc::DEFAULT_MAX_DRIFT,
+        }
+    }
+}
+
+impl DocstoreGossipsubConfig {
+    /// Envelope codec settings derived from this config.
+    pub fn codec(&self) -> CodecOptions {
+        CodecOptions {
+            compression_threshold: self.compression_threshold,
+            max_decompressed_size: self.max_decompressed_size,
+        }
+    }
+
+    /// Gossipsub `max_transmit_size` derived from `max_update_size`, so that every update
+    /// the size check lets through can actually be transmitted.
+    pub fn max_transmit_size(&self) -> usize {
+        self.max_update_size + ENVELOPE_OVERHEAD
+    }
+
+    /// Unsigned messages without an author, checked permissively. Inbound signed messages
+    /// are still accepted (and their signatures checked).
+    pub fn anonymous(mut self) -> Self {
+        self.authenticity = GossipAuthenticity::Anonymous;
+        self.validation_mode = ValidationMode::Permissive;
+        self
+    }
+
+    /// Reject combinations that could never publish anything, or that gossipsub refuses.
+    pub fn validate(&self) -> Result<(), Error> {
+        self.topics.validate()?;
+        if self.heartbeat_interval.is_zero() {
+            return Err(Error::InvalidConfig("heartbeat_interval must be non-zero".into()));
+        }
+        if self.max_update_size == 0 {
+            return Err(Error::InvalidConfig("max_update_size must be non-zero".into()));
+        }
+        if self.max_update_size > self.max_document_size {
+            return Err(Error::InvalidConfig(format!(
+                "max_update_size ({}) exceeds max_document_size ({})",
+                self.max_update_size, self.max_document_size
+            )));
+        }
+        if self.max_decompressed_size < self.max_transmit_size() {
+            return Err(Error::InvalidConfig(format!(
+                "max_decompressed_size ({}) is smaller than the transmit size ({})",
+                self.max_decompressed_size,
+                self.max_transmit_size()
+            )));
+        }
+        match (self.authenticity, &self.validation_mode) {
+            (GossipAuthenticity::Anonymous, ValidationMode::Strict) => {
+                return Err(Error::InvalidConfig(
+                    "anonymous messages are unsigned and Strict validation rejects them; use Permissive or None".into(),
+                ));
+            }
+            (GossipAuthenticity::Signed, ValidationMode::Anonymous) => {
+                return Err(Error::InvalidConfig(
+                    "Anonymous validation rejects signed messages; use Signed with Strict or Permissive".into(),
+                ));
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    pub fn check_update_size(&self, size: usize) -> Result<(), Error> {
+        if size > self.max_update_size {
+            return Err(Error::UpdateTooLarge { size, max: self.max_update_size });
+        }
+        Ok(())
+    }
+
+    pub fn check_document_size(&self, size: usize) -> Result<(), Error> {
+        if size > self.max_document_size {
+            return Err(Error::DocumentTooLarge { size, max: self.max_document_size });
+        }
+        Ok(())
+    }
+}
+
 /// Helper to construct a gossipsub behaviour configured for the docstore topic(s).
-pub fn make_docstore_gossipsub(local_key: &Keypair) -> gossipsub::Behaviour {
-    let config = gossipsub::ConfigBuilder::default()
-        .validation_mode(gossipsub::ValidationMode::Strict)
-        .heartbeat_interval(Duration::from_secs(1))
-        .build()
-        .expect("valid gossipsub config");
+pub fn make_docstore_gossipsub(local_key: &Keypair) -> Result<gossipsub::Behaviour, Error> {
+    make_docstore_gossipsub_with(local_key, &DocstoreGossipsubConfig::default())
+}
+
+/// Like [`make_docstore_gossipsub`], with explicit configuration. Fails with
+/// `Error::InvalidConfig` if `cfg` doesn't validate or gossipsub refuses the result.
+pub fn make_docstore_gossipsub_with(
+    local_key: &Keypair,
+    cfg: &DocstoreGossipsubConfig,
+) -> Result<gossipsub::Behaviour, Error> {
+    cfg.validate()?;
+    // Messages are held until the application reports a validation result, see
+    // `validate_incoming`.
+    let mut builder = gossipsub::ConfigBuilder::default();
+    builder
+        .validation_mode(cfg.validation_mode.clone())
+        .validate_messages()
+        .heartbeat_interval(cfg.heartbeat_interval)
+        .max_transmit_size(cfg.max_transmit_size());
+    let authenticity = match cfg.authenticity {
+        GossipAuthenticity::Signed => MessageAuthenticity::Signed(local_key.clone()),
+        GossipAuthenticity::Anonymous => {
+            // The default id is author + sequence number, which anonymous messages lack
+            builder.message_id_fn(content_message_id);
+            MessageAuthenticity::Anonymous
+        }
+    };
+    let config = builder.build().map_err(|e| Error::InvalidConfig(format!("gossipsub: {e}")))?;
 
-    gossipsub::Behaviour::new(MessageAuthenticity::Signed(local_key.clone()), config)
-        .expect("gossipsub")
+    gossipsub::Behaviour::new(authenticity, config).map_err(|e| Error::InvalidConfig(format!("gossipsub: {e}")))
 }
 
-/// Topic used for public document updates
+/// Message id derived from topic and payload, for messages without author and sequence number.
+/// Also what duplicate detection keys on, whatever id gossipsub itself uses.
+pub fn content_message_id(message: &gossipsub::Message) -> MessageId {
+    let mut hasher = Sha256::new();
+    hasher.update(message.topic.as_str().as_bytes());
+    hasher.update([0]);
+    hasher.update(&message.data);
+    MessageId::from(hasher.finalize().to_vec())
+}
+
+/// Topic used for public document updates, in the default namespace. Nodes use
+/// [`TopicRegistry::updates`] of their config.
 pub fn docstore_topic() -> IdentTopic {
-    IdentTopic::new("docstore/v1/updates")
+    TopicRegistry::default().updates()
 }
 
-/// Subscribe the provided gossipsub behaviour to the docstore topic.
-pub fn subscribe(beh: &mut gossipsub::Behaviour) -> anyhow::Result<()> {
-    beh.subscribe(&docstore_topic()).map(|_b| ()).map_err(|e| anyhow::anyhow!(e))
+/// Subscribe the provided gossipsub behaviour to the update topic of `topics`.
+pub fn subscribe(beh: &mut gossipsub::Behaviour, topics: &TopicRegistry) -> anyhow::Result<()> {
+    beh.subscribe(&topics.updates()).map(|_b| ()).map_err(|e| anyhow::anyhow!(e))
 }
 
 /// Publish data to the docstore topic using the given gossipsub behaviour.
+///
+/// Checked against the default size limits; use [`publish_update_with`] when the
+/// behaviour was built from a custom config.
 pub fn publish_update(
     beh: &mut gossipsub::Behaviour,
     data: impl Into<Vec<u8>>,
+) -> Result<MessageId, Error> {
+    publish_update_with(beh, &DocstoreGossipsubConfig::default(), data)
+}
+
+/// Publish data to the docstore topic, refusing oversized payloads before they reach
+/// the network.
+pub fn publish_update_with(
+    beh: &mut gossipsub::Behaviour,
+    cfg: &DocstoreGossipsubConfig,
+    data: impl Into<Vec<u8>>,
+) -> Result<MessageId, Error> {
+    let data = data.into();
+    cfg.check_update_size(data.len())?;
+    Ok(beh.publish(cfg.topics.updates(), data)?)
+}
+
+/// Publish a single document update wrapped in an [`Envelope`].
+pub fn publish_doc_update(
+    beh: &mut gossipsub::Behaviour,
+    cfg: &DocstoreGossipsubConfig,
+    update: DocUpdate,
+) -> Result<MessageId, Error> {
+    Ok(beh.publish(cfg.topics.updates(), encode_doc_update(cfg, update)?)?)
+}
+
+/// The message [`publish_doc_update`] sends for `update`.
+pub fn encode_doc_update(cfg: &DocstoreGossipsubConfig, update: DocUpdate) -> Result<Vec<u8>, Error> {
+    cfg.check_update_size(update.payload.len())?;
+    Ok(Envelope::Update(update).encode_with(&cfg.codec()))
+}
+
+/// Like [`encode_doc_update`], asking whoever applies the update for a receipt, see
+/// [`receipt`].
+pub fn encode_doc_update_requesting_ack(cfg: &DocstoreGossipsubConfig, update: DocUpdate) -> Result<Vec<u8>, Error> {
+    cfg.check_update_size(update.payload.len())?;
+    Ok(Envelope::Update(update).encode_flagged(&cfg.codec(), FLAG_ACK_REQUESTED))
+}
+
+/// The message for a CAS update of `update`'s document expected at `expected_version`,
+/// see [`cas`]. Always asks for receipts, which is how conflicts get back to us.
+pub fn encode_cas_update(cfg: &DocstoreGossipsubConfig, update: DocUpdate, expected_version: u64) -> Result<Vec<u8>, Error> {
+    cfg.check_update_size(update.payload.len())?;
+    Ok(Envelope::Cas { update, expected_version }.encode_flagged(&cfg.codec(), FLAG_ACK_REQUESTED))
+}
+
+/// Publish several updates, packing updates for the same document into one envelope.
+///
+/// Every update is size-checked before anything is sent. Returns one message id per
+/// envelope actually published. Stops at the first publish error; envelopes before it
+/// have already been sent.
+pub fn publish_batch(
+    beh: &mut gossipsub::Behaviour,
+    cfg: &DocstoreGossipsubConfig,
+    updates: Vec<DocUpdate>,
+) -> Result<Vec<MessageId>, Error> {
+    encode_batch(cfg, updates)?
+        .into_iter()
+        .map(|data| Ok(beh.publish(cfg.topics.updates(), data)?))
+        .collect()
+}
+
+/// The messages [`publish_batch`] sends for `updates`, in order.
+pub fn encode_batch(cfg: &DocstoreGossipsubConfig, updates: Vec<DocUpdate>) -> Result<Vec<Vec<u8>>, Error> {
+    for update in &updates {
+        cfg.check_update_size(update.payload.len())?;
+    }
+    let codec = cfg.codec();
+    Ok(coalesce(updates, cfg.max_update_size).iter().map(|env| env.encode_with(&codec)).collect())
+}
+
+/// The messages that publish `tx`: one per part, each part's payloads within
+/// `max_update_size`. Every update is size-checked first; an empty transaction is an error.
+pub fn encode_transaction(cfg: &DocstoreGossipsubConfig, tx: &Transaction) -> Result<Vec<Vec<u8>>, Error> {
+    if tx.is_empty() {
+        return Err(Error::EmptyTransaction);
+    }
+    for update in &tx.updates {
+        cfg.check_update_size(update.payload.len())?;
+    }
+    let codec = cfg.codec();
+    Ok(tx.parts(cfg.max_update_size).into_iter().map(|part| Envelope::Transaction(part).encode_with(&codec)).collect())
+}
+
+/// Decode a received docstore message into individual updates. Batches are unpacked
+/// transparently so subscribers always see one update at a time.
+pub fn decode_updates(data: &[u8]) -> Result<Vec<DocUpdate>, DecodeError> {
+    Envelope::decode(data).map(Envelope::into_updates)
+}
+
+/// Validation hook for messages received on the docstore topic. The result must be
+/// reported back via [`report_validation`] since the behaviour is built with
+/// `validate_messages()`.
+///
+/// Payloads that are not envelopes (plain `publish_update` data) are only size-checked.
+/// Envelopes of a version we cannot read are ignored rather than rejected, so peers
+/// running a newer format are not penalised for it.
+pub fn validate_incoming(cfg: &DocstoreGossipsubConfig, data: &[u8]) -> gossipsub::MessageAcceptance {
+    match Envelope::decode_with(data, &cfg.codec()) {
+        Ok(env) => {
+            if env.into_updates().iter().any(|u| cfg.check_update_size(u.payload.len()).is_err()) {
+                gossipsub::MessageAcceptance::Reject
+            } else {
+                gossipsub::MessageAcceptance::Accept
+            }
+        }
+        Err(DecodeError::DecompressedTooLarge { .. } | DecodeError::UnpackedTooLarge { .. }) => {
+            gossipsub::MessageAcceptance::Reject
+        }
+        Err(DecodeError::UnsupportedVersion { .. }) => gossipsub::MessageAcceptance::Ignore,
+        Err(_) if cfg.check_update_size(data.len()).is_err() => gossipsub::MessageAcceptance::Reject,
+        Err(_) => gossipsub::MessageAcceptance::Accept,
+    }
+}
+
+/// Validation hook for any message received on the docstore gossipsub behaviour,
+/// dispatching on the topic: snapshot chunks must decode and fit in `max_update_size`,
+/// announcements must be signed by one of `cfg.announcers` (expired ones are ignored),
+/// receipts must carry a valid signature, everything else goes through
+/// [`validate_incoming`]. Topics outside `cfg.topics` are
+/// rejected.
+pub fn validate_message(cfg: &DocstoreGossipsubConfig, message: &gossipsub::Message) -> gossipsub::MessageAcceptance {
+    if !cfg.topics.owns(&message.topic) {
+        return gossipsub::MessageAcceptance::Reject;
+    }
+    if message.topic == cfg.topics.announce().hash() {
+        let now_ms = crate::store::now_ms();
+        return match NetworkAnnouncement::decode(&message.data).and_then(|a| a.verify(&cfg.announcers, now_ms)) {
+            Ok(_) => gossipsub::MessageAcceptance::Accept,
+            Err(AnnounceError::Expired) => gossipsub::MessageAcceptance::Ignore,
+            Err(_) => gossipsub::MessageAcceptance::Reject,
+        };
+    }
+    if message.topic == cfg.topics.receipts().hash() {
+        return match UpdateReceipt::decode(&message.data).and_then(|r| r.verify()) {
+            Ok(_) => gossipsub::MessageAcceptance::Accept,
+            Err(_) => gossipsub::MessageAcceptance::Reject,
+        };
+    }
+    if message.topic == cfg.topics.snapshots().hash() {
+        return match SnapshotChunk::decode(&message.data) {
+            Ok(chunk) if cfg.check_update_size(chunk.data.len()).is_ok() => gossipsub::MessageAcceptance::Accept,
+            _ => gossipsub::MessageAcceptance::Reject,
+        };
+    }
+    validate_incoming(cfg, &message.data)
+}
+
+/// Report a validation verdict to gossipsub so the message is forwarded (or dropped
+/// and its source penalised).
+pub fn report_validation(
+    beh: &mut gossipsub::Behaviour,
+    msg_id: &MessageId,
+    propagation_source: &PeerId,
+    acceptance: gossipsub::MessageAcceptance,
+) {
+    let _ = beh.report_message_validation_result(msg_id, propagation_source, acceptance);
+}
+
+/// Mesh and subscription view of one topic, see [`mesh_info`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopicMeshInfo {
+    pub topic: String,
+    pub mesh_peers: Vec<PeerId>,
+    /// Known peers subscribed to the topic, in the mesh or not.
+    pub subscribed_peers: usize,
+}
+
+/// Known peers subscribed to `topic`.
+pub fn topic_peers(beh: &gossipsub::Behaviour, topic: &TopicHash) -> Vec<PeerId> {
+    beh.all_peers().filter(|(_, topics)| topics.contains(&topic)).map(|(peer, _)| *peer).collect()
+}
+
+/// Peers in our mesh for `topic`. Publishing only reaches the network while this is
+/// non-empty (or fanout peers are known).
+pub fn mesh_peers(beh: &gossipsub::Behaviour, topic: &TopicHash) -> Vec<PeerId> {
+    beh.mesh_peers(topic).copied().collect()
+}
+
+/// Mesh state of every topic we are subscribed to.
+pub fn mesh_info(beh: &gossipsub::Behaviour) -> Vec<TopicMeshInfo> {
+    beh.topics()
+        .map(|topic| TopicMeshInfo {
+            topic: topic.to_string(),
+            mesh_peers: mesh_peers(beh, topic),
+            subscribed_peers: topic_peers(beh, topic).len(),
+        })
+        .collect()
+}
+
+/// Topic FullNodes publish document snapshots on, in the default namespace.
+pub fn snapshot_topic() -> IdentTopic {
+    TopicRegistry::default().snapshots()
+}
+
+pub fn subscribe_snapshots(beh: &mut gossipsub::Behaviour, topics: &TopicRegistry) -> anyhow::Result<()> {
+    beh.subscribe(&topics.snapshots()).map(|_b| ()).map_err(|e| anyhow::anyhow!(e))
+}
+
+/// Publish a snapshot on the snapshot topic, chunked to fit `max_update_size`.
+pub fn publish_snapshot(
+    beh: &mut gossipsub::Behaviour,
+    cfg: &DocstoreGossipsubConfig,
+    snapshot: &Snapshot,
+) -> Result<Vec<MessageId>, Error> {
+    encode_snapshot(cfg, snapshot)?
+        .into_iter()
+        .map(|data| Ok(beh.publish(cfg.topics.snapshots(), data)?))
+        .collect()
+}
+
+/// The chunk messages [`publish_snapshot`] sends for `snapshot`, in order.
+pub fn encode_snapshot(cfg: &DocstoreGossipsubConfig, snapshot: &Snapshot) -> Result<Vec<Vec<u8>>, Error> {
+    cfg.check_document_size(snapshot.bytes.len())?;
+    let chunk_size = snapshot::DEFAULT_SNAPSHOT_CHUNK_SIZE.min(cfg.max_update_size);
+    Ok(snapshot.chunks(chunk_size).iter().map(SnapshotChunk::encode).collect())
+}
+
+pub fn subscribe_announcements(beh: &mut gossipsub::Behaviour, topics: &TopicRegistry) -> anyhow::Result<()> {
+    beh.subscribe(&topics.announce()).map(|_b| ()).map_err(|e| anyhow::anyhow!(e))
+}
+
+/// Subscribe to the receipts topic, to take in receipts for our own updates and forward
+/// the others'.
+pub fn subscribe_receipts(beh: &mut gossipsub::Behaviour, topics: &TopicRegistry) -> anyhow::Result<()> {
+    beh.subscribe(&topics.receipts()).map(|_b| ()).map_err(|e| anyhow::anyhow!(e))
+}
+
+/// Publish a signed announcement on the announce topic. Receivers accept it only if its
+/// signer is in their `announcers`.
+pub fn publish_announcement(
+    beh: &mut gossipsub::Behaviour,
+    cfg: &DocstoreGossipsubConfig,
+    announcement: &NetworkAnnouncement,
+) -> Result<MessageId, Error> {
+    announcement.check(crate::store::now_ms())?;
+    Ok(beh.publish(cfg.topics.announce(), announcement.encode())?)
+}
+
+/// Topic prefix for per-document ephemeral traffic (cursors, typing indicators), in the
+/// default namespace.
+pub const EPHEMERAL_TOPIC_PREFIX: &str = "docstore/v1/ephemeral/";
+
+/// Largest ephemeral payload accepted on the wire. Cursor and presence blips are tiny;
+/// anything bigger belongs on the durable update topic.
+pub const EPHEMERAL_MAX_TRANSMIT_SIZE: usize = 4 * 1024;
+
+/// Messages queued per connection on the ephemeral behaviour, four times gossipsub's
+/// default. Neither behaviour scores peers or caps messages per RPC, so the send queue
+/// is the rate a peer is allowed: a burst of cursor moves is normal, and with payloads
+/// this small a longer queue still holds less than the durable one can.
+pub const EPHEMERAL_QUEUE_LEN: usize = 20_000;
+
+/// Helper to construct a gossipsub behaviour for ephemeral traffic.
+///
+/// This runs as a separate gossipsub instance under its own protocol id so that
+/// ephemeral messages never share a mesh, message cache or validation path with
+/// durable document updates. History is kept to the minimum gossipsub allows and
+/// the duplicate cache is short-lived, since a stale cursor is worthless, while each
+/// peer may have more messages in flight, see [`EPHEMERAL_QUEUE_LEN`].
+pub fn make_ephemeral_gossipsub(local_key: &Keypair) -> Result<gossipsub::Behaviour, Error> {
+    ephemeral_gossipsub(local_key, false)
+}
+
+/// Like [`make_ephemeral_gossipsub`], for relays: messages are held until the relay
+/// reports a verdict, which [`ScopeFilter::verdict`] gives, so local-scope messages from
+/// other relays go no further.
+pub fn make_relay_ephemeral_gossipsub(local_key: &Keypair) -> Result<gossipsub::Behaviour, Error> {
+    ephemeral_gossipsub(local_key, true)
+}
+
+fn ephemeral_gossipsub(local_key: &Keypair, validate: bool) -> Result<gossipsub::Behaviour, Error> {
+    gossipsub::Behaviour::new(MessageAuthenticity::Signed(local_key.clone()), ephemeral_config(validate)?)
+        .map_err(|e| Error::InvalidConfig(format!("ephemeral gossipsub: {e}")))
+}
+
+fn ephemeral_config(validate: bool) -> Result<gossipsub::Config, Error> {
+    let mut builder = gossipsub::ConfigBuilder::default();
+    if validate {
+        builder.validate_messages();
+    }
+    builder
+        .protocol_id_prefix("/docstore-ephemeral")
+        .validation_mode(gossipsub::ValidationMode::Strict)
+        .heartbeat_interval(Duration::from_millis(500))
+        .history_length(1)
+        .history_gossip(1)
+        .duplicate_cache_time(Duration::from_secs(5))
+        .max_transmit_size(EPHEMERAL_MAX_TRANSMIT_SIZE)
+        .connection_handler_queue_len(EPHEMERAL_QUEUE_LEN)
+        .build()
+        .map_err(|e| Error::InvalidConfig(format!("ephemeral gossipsub: {e}")))
+}
+
+/// Ephemeral topic for a single document, in the default namespace.
+pub fn ephemeral_topic(doc_id: &str) -> IdentTopic {
+    TopicRegistry::default().ephemeral(doc_id)
+}
+
+/// Returns the document id if `topic` is an ephemeral topic of the default namespace.
+pub fn ephemeral_doc_id(topic: &TopicHash) -> Option<&str> {
+    topic.as_str().strip_prefix(EPHEMERAL_TOPIC_PREFIX)
+}
+
+/// Subscribe the provided (ephemeral) gossipsub behaviour to a document's ephemeral topic.
+pub fn subscribe_ephemeral(beh: &mut gossipsub::Behaviour, topics: &TopicRegistry, doc_id: &str) -> anyhow::Result<()> {
+    beh.subscribe(&topics.ephemeral(doc_id)).map(|_b| ()).map_err(|e| anyhow::anyhow!(e))
+}
+
+/// Publish ephemeral data for a document using the ephemeral gossipsub behaviour.
+pub fn publish_ephemeral(
+    beh: &mut gossipsub::Behaviour,
+    topics: &TopicRegistry,
+    doc_id: &str,
+    data: impl Into<Vec<u8>>,
 ) -> Result<MessageId, gossipsub::PublishError> {
-    beh.publish(docstore_topic(), data.into())
+    beh.publish(topics.ephemeral(doc_id), data.into())
 }
 
 #[cfg(test)]
@@ -40,9 +578,267 @@ mod tests {
     #[test]
     fn test_subscribe_and_publish() {
         let key = Keypair::generate_ed25519();
-        let mut beh = make_docstore_gossipsub(&key);
-        assert!(subscribe(&mut beh).is_ok());
+        let mut beh = make_docstore_gossipsub(&key).unwrap();
+        assert!(subscribe(&mut beh, &TopicRegistry::default()).is_ok());
         let res = publish_update(&mut beh, b"hello world".to_vec());
         assert!(res.is_ok());
     }
+
+    #[test]
+    fn oversized_update_is_rejected_before_publishing() {
+        let key = Keypair::generate_ed25519();
+        let cfg = DocstoreGossipsubConfig { max_update_size: 16, ..Default::default() };
+        let mut beh = make_docstore_gossipsub_with(&key, &cfg).unwrap();
+        subscribe(&mut beh, &cfg.topics).unwrap();
+        let res = publish_doc_update(&mut beh, &cfg, DocUpdate::new("doc", vec![0u8; 17]));
+        assert!(matches!(res, Err(Error::UpdateTooLarge { size: 17, max: 16 })));
+    }
+
+    #[test]
+    fn size_limits_must_be_publishable() {
+        let cfg = DocstoreGossipsubConfig { max_update_size: 1024, max_document_size: 512, ..Default::default() };
+        assert!(matches!(cfg.validate(), Err(Error::InvalidConfig(_))));
+        assert!(DocstoreGossipsubConfig::default().validate().is_ok());
+        assert!(DocstoreGossipsubConfig::default().max_transmit_size() > DEFAULT_MAX_UPDATE_SIZE);
+    }
+
+    #[test]
+    fn validation_rejects_oversized_incoming_updates() {
+        let cfg = DocstoreGossipsubConfig { max_update_size: 16, ..Default::default() };
+        let small = Envelope::Update(DocUpdate::new("doc", vec![0u8; 8])).encode();
+        let big = Envelope::Update(DocUpdate::new("doc", vec![0u8; 32])).encode();
+        assert!(matches!(validate_incoming(&cfg, &small), gossipsub::MessageAcceptance::Accept));
+        assert!(matches!(validate_incoming(&cfg, &big), gossipsub::MessageAcceptance::Reject));
+        assert!(matches!(validate_incoming(&cfg, b"plain text"), gossipsub::MessageAcceptance::Accept));
+
+        // Other envelope versions are ignored, never rejected
+        let mut future = small.clone();
+        future[0] = CURRENT_PROTOCOL_VERSION + 1;
+        assert!(matches!(validate_incoming(&cfg, &future), gossipsub::MessageAcceptance::Ignore));
+        assert!(matches!(validate_incoming(&cfg, &small[1..]), gossipsub::MessageAcceptance::Ignore));
+    }
+
+    #[test]
+    fn full_batches_fit_the_transmit_size() {
+        // Small payloads under wide vector clocks: the stamps outweigh the payloads
+        let cfg = DocstoreGossipsubConfig { compression_threshold: None, ..Default::default() };
+        let mut doc_clock = VectorClock::default();
+        for node in 0..32 {
+            doc_clock.increment(u64::MAX - node);
+        }
+        let mut clock = HlcClock::new(1);
+        let payload_len = cfg.max_update_size / envelope::MAX_BATCH_UPDATES - 8;
+        let updates: Vec<_> = (0..envelope::MAX_BATCH_UPDATES)
+            .map(|_| DocUpdate::new("doc", vec![7u8; payload_len]).with_stamp(Stamp::next(&mut clock, &doc_clock)))
+            .collect();
+
+        let batch = encode_batch(&cfg, updates.clone()).unwrap();
+        assert!(batch.len() > 1);
+        assert!(batch.iter().all(|data| data.len() <= cfg.max_transmit_size()));
+        let decoded: usize = batch.iter().map(|data| decode_updates(data).unwrap().len()).sum();
+        assert_eq!(decoded, updates.len());
+
+        let mut tx = Transaction::new();
+        updates.into_iter().for_each(|update| tx.add_update(update));
+        let parts = encode_transaction(&cfg, &tx).unwrap();
+        assert!(parts.len() > 1);
+        assert!(parts.iter().all(|data| data.len() <= cfg.max_transmit_size()));
+    }
+
+    #[test]
+    fn announcements_are_validated_against_the_allowlist() {
+        let announcer = Keypair::generate_ed25519();
+        let mut cfg = DocstoreGossipsubConfig::default();
+        let now = crate::store::now_ms();
+        let message = |expires_at_ms| {
+            let announcement =
+                NetworkAnnouncement::sign(&announcer, AnnouncementKind::Upgrade, Severity::Warning, "v2 cutover", now, expires_at_ms)
+                    .unwrap();
+            gossipsub::Message {
+                source: None,
+                data: announcement.encode(),
+                sequence_number: None,
+                topic: cfg.topics.announce().hash(),
+            }
+        };
+        let current = message(Some(now + 60_000));
+        assert!(matches!(validate_message(&cfg, &current), gossipsub::MessageAcceptance::Reject));
+
+        cfg.announcers.insert(announcer.public().to_peer_id());
+        assert!(matches!(validate_message(&cfg, &current), gossipsub::MessageAcceptance::Accept));
+        assert!(matches!(validate_message(&cfg, &message(Some(now - 1))), gossipsub::MessageAcceptance::Ignore));
+        let unsigned = gossipsub::Message { data: b"maintenance at 22:00".to_vec(), ..current };
+        assert!(matches!(validate_message(&cfg, &unsigned), gossipsub::MessageAcceptance::Reject));
+    }
+
+    #[test]
+    fn receipts_must_be_signed() {
+        let cfg = DocstoreGossipsubConfig::default();
+        let receiver = Keypair::generate_ed25519();
+        let receipt = UpdateReceipt::sign(&receiver, &PeerId::random(), &MessageId::new(b"m"), "doc", 1).unwrap();
+        let message = |data| gossipsub::Message { source: None, data, sequence_number: None, topic: cfg.topics.receipts().hash() };
+        assert!(matches!(validate_message(&cfg, &message(receipt.encode())), gossipsub::MessageAcceptance::Accept));
+        let mut forged = receipt;
+        forged.applied_version += 1;
+        assert!(matches!(validate_message(&cfg, &message(forged.encode())), gossipsub::MessageAcceptance::Reject));
+        assert!(matches!(validate_message(&cfg, &message(b"ack".to_vec())), gossipsub::MessageAcceptance::Reject));
+    }
+
+    #[test]
+    fn ephemeral_and_durable_subscriptions_use_disjoint_topics() {
+        let key = Keypair::generate_ed25519();
+
+        // Topic namespaces are disjoint.
+        assert_ne!(ephemeral_topic("doc-1").hash(), docstore_topic().hash());
+        assert_eq!(ephemeral_doc_id(&ephemeral_topic("doc-1").hash()), Some("doc-1"));
+        assert_eq!(ephemeral_doc_id(&docstore_topic().hash()), None);
+
+        // A durable-only behaviour has no ephemeral subscription to deliver into...
+        let mut durable = make_docstore_gossipsub(&key).unwrap();
+        subscribe(&mut durable, &TopicRegistry::default()).unwrap();
+        assert!(!durable.topics().any(|t| ephemeral_doc_id(t).is_some()));
+
+        // ...and an ephemeral-only behaviour has no durable one.
+        let mut ephemeral = make_ephemeral_gossipsub(&key).unwrap();
+        subscribe_ephemeral(&mut ephemeral, &TopicRegistry::default(), "doc-1").unwrap();
+        assert!(!ephemeral.topics().any(|t| *t == docstore_topic().hash()));
+    }
+
+    #[test]
+    fn ephemeral_rejects_oversized_payloads() {
+        let key = Keypair::generate_ed25519();
+        let mut ephemeral = make_ephemeral_gossipsub(&key).unwrap();
+        subscribe_ephemeral(&mut ephemeral, &TopicRegistry::default(), "doc-1").unwrap();
+        let big = vec![0u8; EPHEMERAL_MAX_TRANSMIT_SIZE * 2];
+        assert!(publish_ephemeral(&mut ephemeral, &TopicRegistry::default(), "doc-1", big).is_err());
+    }
+
+    #[test]
+    fn ephemeral_config_keeps_less_and_allows_more() {
+        let durable = gossipsub::ConfigBuilder::default().build().unwrap();
+        let ephemeral = ephemeral_config(false).unwrap();
+        assert!(ephemeral.history_length() < durable.history_length());
+        assert!(ephemeral.max_transmit_size() < DocstoreGossipsubConfig::default().max_transmit_size());
+        assert!(ephemeral.connection_handler_queue_len() > durable.connection_handler_queue_len());
+    }
+
+    #[test]
+    fn invalid_config_is_an_error_not_a_panic() {
+        let key = Keypair::generate_ed25519();
+        let zero = DocstoreGossipsubConfig { heartbeat_interval: Duration::ZERO, ..Default::default() };
+        assert!(matches!(make_docstore_gossipsub_with(&key, &zero), Err(Error::InvalidConfig(_))));
+        let bad_topics = DocstoreGossipsubConfig { topics: TopicRegistry::new("a/b"), ..Default::default() };
+        assert!(matches!(make_docstore_gossipsub_with(&key, &bad_topics), Err(Error::InvalidConfig(_))));
+        assert!(make_ephemeral_gossipsub(&key).is_ok());
+    }
+
+    #[test]
+    fn rejects_incompatible_authenticity_and_validation() {
+        assert!(DocstoreGossipsubConfig::default().anonymous().validate().is_ok());
+        let none = DocstoreGossipsubConfig { validation_mode: ValidationMode::None, ..Default::default() }.anonymous();
+        assert!(none.validate().is_ok());
+
+        let strict = DocstoreGossipsubConfig { authenticity: GossipAuthenticity::Anonymous, ..Default::default() };
+        assert!(matches!(strict.validate(), Err(Error::InvalidConfig(_))));
+        let signed = DocstoreGossipsubConfig { validation_mode: ValidationMode::Anonymous, ..Default::default() };
+        assert!(matches!(signed.validate(), Err(Error::InvalidConfig(_))));
+    }
+
+    /// Publish `data` from one in-memory swarm and return what the other receives.
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn deliver(cfg: &DocstoreGossipsubConfig, data: &[u8]) -> (PeerId, gossipsub::Message) {
+        use futures::StreamExt;
+        use libp2p::multiaddr::Protocol;
+        use libp2p::swarm::SwarmEvent;
+        use libp2p::Transport;
+
+        let swarm = || {
+            libp2p::SwarmBuilder::with_new_identity()
+                .with_tokio()
+                .with_other_transport(|key| {
+                    libp2p::core::transport::MemoryTransport::default()
+                        .upgrade(libp2p::core::upgrade::Version::V1)
+                        .authenticate(libp2p::noise::Config::new(key).expect("noise config"))
+                        .multiplex(libp2p::yamux::Config::default())
+                })
+                .unwrap()
+                .with_behaviour(|key| make_docstore_gossipsub_with(key, cfg).unwrap())
+                .unwrap()
+                .build()
+        };
+        let mut publisher = swarm();
+        let mut receiver = swarm();
+        subscribe(publisher.behaviour_mut(), &cfg.topics).unwrap();
+        subscribe(receiver.behaviour_mut(), &cfg.topics).unwrap();
+
+        publisher.listen_on(Protocol::Memory(0).into()).unwrap();
+        let addr = loop {
+            if let SwarmEvent::NewListenAddr { address, .. } = publisher.select_next_some().await {
+                break address;
+            }
+        };
+        let publisher_id = *publisher.local_peer_id();
+        receiver.dial(addr.with(Protocol::P2p(publisher_id))).unwrap();
+
+        let message = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                tokio::select! {
+                    event = publisher.select_next_some() => {
+                        if let SwarmEvent::Behaviour(gossipsub::Event::Subscribed { .. }) = event {
+                            publish_update_with(publisher.behaviour_mut(), cfg, data.to_vec()).unwrap();
+                        }
+                    }
+                    event = receiver.select_next_some() => {
+                        if let SwarmEvent::Behaviour(gossipsub::Event::Message { message, .. }) = event {
+                            break message;
+                        }
+                    }
+                }
+            }
+        })
+        .await
+        .expect("message not delivered");
+        (publisher_id, message)
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[tokio::test]
+    async fn delivers_signed_messages_with_their_author() {
+        let (publisher, message) = deliver(&DocstoreGossipsubConfig::default(), b"signed").await;
+        assert_eq!(message.data, b"signed");
+        assert_eq!(message.source, Some(publisher));
+        assert!(message.signature.is_some());
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[tokio::test]
+    async fn delivers_anonymous_messages_without_attribution() {
+        let (_, message) = deliver(&DocstoreGossipsubConfig::default().anonymous(), b"anonymous").await;
+        assert_eq!(message.data, b"anonymous");
+        assert_eq!(message.source, None);
+        assert_eq!(message.sequence_number, None);
+        assert!(message.signature.is_none());
+    }
+
+    #[test]
+    fn messages_from_other_namespaces_are_rejected() {
+        let cfg = DocstoreGossipsubConfig { topics: TopicRegistry::new("myapp"), ..Default::default() };
+        let update = encode_doc_update(&cfg, DocUpdate::new("doc", b"hi".to_vec())).unwrap();
+        let message = |topic: IdentTopic| gossipsub::Message {
+            source: None,
+            data: update.clone(),
+            sequence_number: None,
+            topic: topic.hash(),
+        };
+        assert!(matches!(validate_message(&cfg, &message(cfg.topics.updates())), gossipsub::MessageAcceptance::Accept));
+        assert!(matches!(validate_message(&cfg, &message(docstore_topic())), gossipsub::MessageAcceptance::Reject));
+        assert!(matches!(
+            validate_message(&DocstoreGossipsubConfig::default(), &message(cfg.topics.updates())),
+            gossipsub::MessageAcceptance::Reject
+        ));
+        assert!(matches!(
+            DocstoreGossipsubConfig { topics: TopicRegistry::new(""), ..Default::default() }.validate(),
+            Err(Error::InvalidConfig(_))
+        ));
+    }
 }
@@ -0,0 +1,238 @@
+//! Network-wide notices ("maintenance at 22:00", "upgrade before the v2 cutover") on the
+//! announce topic.
+//!
+//! Anyone can publish on a gossipsub topic, so every [`NetworkAnnouncement`] is signed by
+//! its announcer's identity key, and receivers only accept announcements from the peers in
+//! their configured allowlist ([`DocstoreGossipsubConfig::announcers`]). Announcements
+//! past their expiry are dropped on receipt without penalising whoever forwarded them.
+//!
+//! Postcard-encoded and signed like [`crate::behaviour::SuccessorAnnouncement`].
+//!
+//! [`DocstoreGossipsubConfig::announcers`]: super::DocstoreGossipsubConfig::announcers
+
+use std::collections::HashSet;
+
+use libp2p::identity::{Keypair, PublicKey, SigningError};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+
+use super::wire;
+
+/// Longest announcement text, in bytes.
+pub const MAX_ANNOUNCEMENT_TEXT: usize = 2048;
+
+/// What an announcement is about.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AnnouncementKind {
+    #[default]
+    Notice,
+    Maintenance,
+    Upgrade,
+}
+
+impl AnnouncementKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AnnouncementKind::Notice => "notice",
+            AnnouncementKind::Maintenance => "maintenance",
+            AnnouncementKind::Upgrade => "upgrade",
+        }
+    }
+}
+
+impl std::str::FromStr for AnnouncementKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "notice" => Ok(AnnouncementKind::Notice),
+            "maintenance" => Ok(AnnouncementKind::Maintenance),
+            "upgrade" => Ok(AnnouncementKind::Upgrade),
+            other => Err(format!("unknown announcement type '{other}' (expected notice, maintenance or upgrade)")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Severity {
+    #[default]
+    Info,
+    Warning,
+    Critical,
+}
+
+impl Severity {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Critical => "critical",
+        }
+    }
+}
+
+impl std::str::FromStr for Severity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "info" => Ok(Severity::Info),
+            "warning" => Ok(Severity::Warning),
+            "critical" => Ok(Severity::Critical),
+            other => Err(format!("unknown severity '{other}' (expected info, warning or critical)")),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum AnnounceError {
+    #[error("malformed announcement")]
+    Malformed,
+    #[error("announcement text of {size} bytes exceeds the maximum of {max} bytes")]
+    TextTooLong { size: usize, max: usize },
+    #[error("{0} is not an allowed announcer")]
+    NotAllowed(PeerId),
+    #[error("announcement signature does not verify")]
+    BadSignature,
+    #[error("announcement expired")]
+    Expired,
+}
+
+/// A notice from an announcer to every node, signed with the announcer's identity key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NetworkAnnouncement {
+    pub kind: AnnouncementKind,
+    pub severity: Severity,
+    /// At most [`MAX_ANNOUNCEMENT_TEXT`] bytes.
+    pub text: String,
+    pub issued_at_ms: u64,
+    /// Unix time in milliseconds after which receivers drop the announcement.
+    pub expires_at_ms: Option<u64>,
+    /// Protobuf-encoded public key of the announcer.
+    #[serde(deserialize_with = "wire::bytes")]
+    pub public_key: Vec<u8>,
+    #[serde(deserialize_with = "wire::bytes")]
+    pub signature: Vec<u8>,
+}
+
+impl NetworkAnnouncement {
+    pub fn sign(
+        announcer: &Keypair,
+        kind: AnnouncementKind,
+        severity: Severity,
+        text: impl Into<String>,
+        issued_at_ms: u64,
+        expires_at_ms: Option<u64>,
+    ) -> Result<Self, SigningError> {
+        let mut announcement = Self {
+            kind,
+            severity,
+            text: text.into(),
+            issued_at_ms,
+            expires_at_ms,
+            public_key: announcer.public().encode_protobuf(),
+            signature: Vec::new(),
+        };
+        announcement.signature = announcer.sign(&announcement.payload())?;
+        Ok(announcement)
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        let mut payload = b"docstore-announcement:".to_vec();
+        payload.push(self.kind as u8);
+        payload.push(self.severity as u8);
+        for field in [self.text.as_bytes(), self.public_key.as_slice()] {
+            payload.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            payload.extend_from_slice(field);
+        }
+        payload.extend_from_slice(&self.issued_at_ms.to_be_bytes());
+        match self.expires_at_ms {
+            Some(at) => {
+                payload.push(1);
+                payload.extend_from_slice(&at.to_be_bytes());
+            }
+            None => payload.push(0),
+        }
+        payload
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        postcard::to_allocvec(self).expect("announcement serialization cannot fail")
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, AnnounceError> {
+        postcard::from_bytes(data).map_err(|_| AnnounceError::Malformed)
+    }
+
+    /// Refuse announcements no receiver would accept at `now_ms`: overlong or expired.
+    pub fn check(&self, now_ms: u64) -> Result<(), AnnounceError> {
+        if self.text.len() > MAX_ANNOUNCEMENT_TEXT {
+            return Err(AnnounceError::TextTooLong { size: self.text.len(), max: MAX_ANNOUNCEMENT_TEXT });
+        }
+        if self.expires_at_ms.is_some_and(|at| now_ms >= at) {
+            return Err(AnnounceError::Expired);
+        }
+        Ok(())
+    }
+
+    /// Check that the announcement is signed by one of `announcers` and still current at
+    /// `now_ms`. Returns the announcer.
+    pub fn verify(&self, announcers: &HashSet<PeerId>, now_ms: u64) -> Result<PeerId, AnnounceError> {
+        let key = PublicKey::try_decode_protobuf(&self.public_key).map_err(|_| AnnounceError::Malformed)?;
+        let announcer = key.to_peer_id();
+        if !announcers.contains(&announcer) {
+            return Err(AnnounceError::NotAllowed(announcer));
+        }
+        if !key.verify(&self.payload(), &self.signature) {
+            return Err(AnnounceError::BadSignature);
+        }
+        self.check(now_ms)?;
+        Ok(announcer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000_000;
+
+    fn maintenance(key: &Keypair, expires_at_ms: Option<u64>) -> NetworkAnnouncement {
+        NetworkAnnouncement::sign(key, AnnouncementKind::Maintenance, Severity::Warning, "maintenance at 22:00", NOW, expires_at_ms)
+            .unwrap()
+    }
+
+    #[test]
+    fn only_allowlisted_announcers_are_accepted() {
+        let (announcer, stranger) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let allowed = HashSet::from([announcer.public().to_peer_id()]);
+
+        let announcement = NetworkAnnouncement::decode(&maintenance(&announcer, None).encode()).unwrap();
+        assert_eq!(announcement.verify(&allowed, NOW), Ok(announcer.public().to_peer_id()));
+        assert_eq!(
+            maintenance(&stranger, None).verify(&allowed, NOW),
+            Err(AnnounceError::NotAllowed(stranger.public().to_peer_id()))
+        );
+        assert!(matches!(announcement.verify(&HashSet::new(), NOW), Err(AnnounceError::NotAllowed(_))));
+
+        // Raising the severity after signing breaks the signature
+        let mut forged = announcement.clone();
+        forged.severity = Severity::Critical;
+        assert_eq!(forged.verify(&allowed, NOW), Err(AnnounceError::BadSignature));
+
+        assert_eq!(NetworkAnnouncement::decode(b"junk"), Err(AnnounceError::Malformed));
+    }
+
+    #[test]
+    fn expired_and_overlong_announcements_are_refused() {
+        let key = Keypair::generate_ed25519();
+        let allowed = HashSet::from([key.public().to_peer_id()]);
+        let expiring = maintenance(&key, Some(NOW + 1000));
+        assert!(expiring.verify(&allowed, NOW + 999).is_ok());
+        assert_eq!(expiring.verify(&allowed, NOW + 1000), Err(AnnounceError::Expired));
+
+        let text = "x".repeat(MAX_ANNOUNCEMENT_TEXT + 1);
+        let long = NetworkAnnouncement::sign(&key, AnnouncementKind::Notice, Severity::Info, text, NOW, None).unwrap();
+        assert!(matches!(long.check(NOW), Err(AnnounceError::TextTooLong { .. })));
+    }
+}
@@ -0,0 +1,643 @@
+//! Capability tokens for restricted rooms.
+//!
+//! Room topics are public: anyone who knows a room id can subscribe. In a restricted room
+//! the creator signs a [`Capability`] per member (room, grantee, read or write, expiry),
+//! members attach theirs to their presence announcements, and receivers ignore traffic
+//! from peers that have not shown a valid token. The creator withdraws tokens early by
+//! publishing a signed [`RevocationList`]. Guests holding a
+//! [guest link](super::guest_link) show a [`GuestPass`] instead.
+//!
+//! Tokens and revocation lists are postcard-encoded and signed with the creator's identity
+//! key, like [`crate::behaviour::SuccessorAnnouncement`].
+
+use std::collections::{HashMap, HashSet};
+
+use libp2p::identity::{Keypair, PublicKey, SigningError};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::wire;
+
+/// Identifies a token in revocation lists: the SHA-256 of its signed payload.
+pub type TokenId = [u8; 32];
+
+/// Most tokens one revocation list may name; longer lists don't decode.
+pub const MAX_REVOKED_TOKENS: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Permission {
+    /// Receive the room's traffic and announce presence.
+    Read,
+    /// Read, and publish updates and ephemeral messages.
+    Write,
+}
+
+impl Permission {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Permission::Read => "read",
+            Permission::Write => "write",
+        }
+    }
+
+    /// Whether holding `self` grants `needed`.
+    pub fn allows(self, needed: Permission) -> bool {
+        self == Permission::Write || needed == Permission::Read
+    }
+}
+
+impl std::str::FromStr for Permission {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Permission::Read),
+            "write" => Ok(Permission::Write),
+            other => Err(format!("unknown permission '{other}' (expected read or write)")),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum AuthError {
+    #[error("malformed capability token")]
+    Malformed,
+    #[error("token is for room {found:?}, not {expected:?}")]
+    WrongRoom { expected: String, found: String },
+    #[error("token was issued by {found}, not the room creator {expected}")]
+    WrongIssuer { expected: PeerId, found: PeerId },
+    #[error("token was granted to another peer")]
+    WrongGrantee,
+    #[error("token signature does not verify")]
+    BadSignature,
+    #[error("token expired")]
+    Expired,
+    #[error("token was revoked")]
+    Revoked,
+}
+
+/// "`grantee` may read (or write) `room_id` until `expires_at_ms`", signed by the room creator.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capability {
+    pub room_id: String,
+    /// Protobuf-encoded public key of the issuer.
+    #[serde(deserialize_with = "wire::bytes")]
+    pub issuer_public_key: Vec<u8>,
+    /// Peer id bytes of the grantee.
+    #[serde(deserialize_with = "wire::bytes")]
+    pub grantee: Vec<u8>,
+    pub permission: Permission,
+    /// Unix time in milliseconds.
+    pub expires_at_ms: u64,
+    #[serde(deserialize_with = "wire::bytes")]
+    pub signature: Vec<u8>,
+}
+
+impl Capability {
+    fn payload(&self) -> Vec<u8> {
+        let mut payload = b"docstore-capability:".to_vec();
+        for field in [self.room_id.as_bytes(), self.issuer_public_key.as_slice(), self.grantee.as_slice()] {
+            payload.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            payload.extend_from_slice(field);
+        }
+        payload.push(self.permission as u8);
+        payload.extend_from_slice(&self.expires_at_ms.to_be_bytes());
+        payload
+    }
+
+    pub fn id(&self) -> TokenId {
+        Sha256::digest(self.payload()).into()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        postcard::to_allocvec(self).expect("capability serialization cannot fail")
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, AuthError> {
+        postcard::from_bytes(data).map_err(|_| AuthError::Malformed)
+    }
+}
+
+/// Sign a token granting `grantee` `permission` on `room_id` until `expires_at_ms`.
+pub fn issue_capability(
+    issuer: &Keypair,
+    room_id: &str,
+    grantee: &PeerId,
+    permission: Permission,
+    expires_at_ms: u64,
+) -> Result<Capability, SigningError> {
+    let mut token = Capability {
+        room_id: room_id.to_string(),
+        issuer_public_key: issuer.public().encode_protobuf(),
+        grantee: grantee.to_bytes(),
+        permission,
+        expires_at_ms,
+        signature: Vec::new(),
+    };
+    token.signature = issuer.sign(&token.payload())?;
+    Ok(token)
+}
+
+/// Proof that the bearer opened a guest link: the link's token, granted to the link key,
+/// and that key's signature over the bearer's peer id. Seeing someone's pass doesn't let
+/// anyone else in, and revoking the link's token shuts out every guest that used it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GuestPass {
+    pub token: Capability,
+    /// Protobuf-encoded public key of the link.
+    #[serde(deserialize_with = "wire::bytes")]
+    pub link_public_key: Vec<u8>,
+    #[serde(deserialize_with = "wire::bytes")]
+    pub signature: Vec<u8>,
+}
+
+impl GuestPass {
+    /// Sign a pass for `bearer` with `link_key`, to which `token` was granted.
+    pub fn sign(link_key: &Keypair, token: Capability, bearer: &PeerId) -> Result<Self, SigningError> {
+        let signature = link_key.sign(&Self::payload(&token, bearer))?;
+        Ok(Self { token, link_public_key: link_key.public().encode_protobuf(), signature })
+    }
+
+    fn payload(token: &Capability, bearer: &PeerId) -> Vec<u8> {
+        let mut payload = b"docstore-guest:".to_vec();
+        payload.extend_from_slice(&token.id());
+        payload.extend_from_slice(&bearer.to_bytes());
+        payload
+    }
+
+    /// [`verify_capability`] for a pass: the token must be granted to the link key, and
+    /// the link key must have signed for `bearer`.
+    pub fn verify(
+        &self,
+        room_id: &str,
+        creator: &PeerId,
+        bearer: &PeerId,
+        now_ms: u64,
+        revoked: &HashSet<TokenId>,
+    ) -> Result<Permission, AuthError> {
+        let link = PublicKey::try_decode_protobuf(&self.link_public_key).map_err(|_| AuthError::Malformed)?;
+        let permission = verify_capability(&self.token, room_id, creator, &link.to_peer_id(), now_ms, revoked)?;
+        if !link.verify(&Self::payload(&self.token, bearer), &self.signature) {
+            return Err(AuthError::BadSignature);
+        }
+        Ok(permission)
+    }
+}
+
+/// What a member shows to get into a restricted room.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Credential {
+    /// A token granted to the member itself.
+    Token(Capability),
+    /// A pass signed with a guest link's key.
+    Guest(GuestPass),
+}
+
+impl Credential {
+    /// Id of the token behind it, as named in revocation lists.
+    pub fn id(&self) -> TokenId {
+        match self {
+            Credential::Token(token) => token.id(),
+            Credential::Guest(pass) => pass.token.id(),
+        }
+    }
+
+    pub fn verify(
+        &self,
+        room_id: &str,
+        creator: &PeerId,
+        bearer: &PeerId,
+        now_ms: u64,
+        revoked: &HashSet<TokenId>,
+    ) -> Result<Permission, AuthError> {
+        match self {
+            Credential::Token(token) => verify_capability(token, room_id, creator, bearer, now_ms, revoked),
+            Credential::Guest(pass) => pass.verify(room_id, creator, bearer, now_ms, revoked),
+        }
+    }
+}
+
+impl From<Capability> for Credential {
+    fn from(token: Capability) -> Self {
+        Credential::Token(token)
+    }
+}
+
+/// Check that `token` lets `bearer` into `room_id`, which `creator` created: issued and
+/// signed by the creator, for this room and bearer, unexpired at `now_ms` and not in
+/// `revoked`. Returns the granted permission.
+pub fn verify_capability(
+    token: &Capability,
+    room_id: &str,
+    creator: &PeerId,
+    bearer: &PeerId,
+    now_ms: u64,
+    revoked: &HashSet<TokenId>,
+) -> Result<Permission, AuthError> {
+    if token.room_id != room_id {
+        return Err(AuthError::WrongRoom { expected: room_id.to_string(), found: token.room_id.clone() });
+    }
+    let issuer = verify_issuer(&token.issuer_public_key, creator)?;
+    if PeerId::from_bytes(&token.grantee).ok().as_ref() != Some(bearer) {
+        return Err(AuthError::WrongGrantee);
+    }
+    if !issuer.verify(&token.payload(), &token.signature) {
+        return Err(AuthError::BadSignature);
+    }
+    if now_ms >= token.expires_at_ms {
+        return Err(AuthError::Expired);
+    }
+    if revoked.contains(&token.id()) {
+        return Err(AuthError::Revoked);
+    }
+    Ok(token.permission)
+}
+
+fn verify_issuer(public_key: &[u8], creator: &PeerId) -> Result<PublicKey, AuthError> {
+    let issuer = PublicKey::try_decode_protobuf(public_key).map_err(|_| AuthError::Malformed)?;
+    let found = issuer.to_peer_id();
+    if found != *creator {
+        return Err(AuthError::WrongIssuer { expected: *creator, found });
+    }
+    Ok(issuer)
+}
+
+/// The tokens of a room its creator has withdrawn. Each list replaces the previous one, so
+/// it names every revoked token that has not expired yet.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RevocationList {
+    pub room_id: String,
+    #[serde(deserialize_with = "wire::bytes")]
+    pub issuer_public_key: Vec<u8>,
+    /// At most [`MAX_REVOKED_TOKENS`].
+    #[serde(deserialize_with = "revoked_tokens")]
+    pub revoked: Vec<TokenId>,
+    /// Orders lists from the same creator; older ones are ignored.
+    pub issued_at_ms: u64,
+    #[serde(deserialize_with = "wire::bytes")]
+    pub signature: Vec<u8>,
+}
+
+fn revoked_tokens<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<TokenId>, D::Error> {
+    wire::bounded_seq::<D, TokenId, TokenId>(deserializer, MAX_REVOKED_TOKENS)
+}
+
+impl RevocationList {
+    pub fn sign(issuer: &Keypair, room_id: &str, revoked: Vec<TokenId>, issued_at_ms: u64) -> Result<Self, SigningError> {
+        let mut list = Self {
+            room_id: room_id.to_string(),
+            issuer_public_key: issuer.public().encode_protobuf(),
+            revoked,
+            issued_at_ms,
+            signature: Vec::new(),
+        };
+        list.signature = issuer.sign(&list.payload())?;
+        Ok(list)
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        let mut payload = b"docstore-revocations:".to_vec();
+        for field in [self.room_id.as_bytes(), self.issuer_public_key.as_slice()] {
+            payload.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            payload.extend_from_slice(field);
+        }
+        payload.extend_from_slice(&(self.revoked.len() as u32).to_be_bytes());
+        for id in &self.revoked {
+            payload.extend_from_slice(id);
+        }
+        payload.extend_from_slice(&self.issued_at_ms.to_be_bytes());
+        payload
+    }
+
+    /// Check that the list is about `room_id` and signed by its `creator`.
+    pub fn verify(&self, room_id: &str, creator: &PeerId) -> Result<(), AuthError> {
+        if self.room_id != room_id {
+            return Err(AuthError::WrongRoom { expected: room_id.to_string(), found: self.room_id.clone() });
+        }
+        let issuer = verify_issuer(&self.issuer_public_key, creator)?;
+        if !issuer.verify(&self.payload(), &self.signature) {
+            return Err(AuthError::BadSignature);
+        }
+        Ok(())
+    }
+}
+
+/// What restricted rooms carry on their presence channel: presence with the sender's token
+/// or guest pass attached, or the creator's revocation list.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PresenceFrame {
+    Presence {
+        token: Option<Capability>,
+        #[serde(deserialize_with = "wire::bytes")]
+        data: Vec<u8>,
+    },
+    Revocations(RevocationList),
+    /// Last, so older peers fail to decode it and ignore the guest.
+    GuestPresence {
+        pass: GuestPass,
+        #[serde(deserialize_with = "wire::bytes")]
+        data: Vec<u8>,
+    },
+}
+
+impl PresenceFrame {
+    /// Presence `data` carrying `credential`.
+    pub fn presence(credential: Option<Credential>, data: Vec<u8>) -> Self {
+        match credential {
+            Some(Credential::Guest(pass)) => PresenceFrame::GuestPresence { pass, data },
+            Some(Credential::Token(token)) => PresenceFrame::Presence { token: Some(token), data },
+            None => PresenceFrame::Presence { token: None, data },
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        postcard::to_allocvec(self).expect("presence frame serialization cannot fail")
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, AuthError> {
+        postcard::from_bytes(data).map_err(|_| AuthError::Malformed)
+    }
+}
+
+/// Who may do what in one restricted room, from the tokens peers have presented and the
+/// creator's latest revocation list.
+#[derive(Debug)]
+pub struct RoomAccess {
+    room_id: String,
+    creator: PeerId,
+    grants: HashMap<PeerId, Credential>,
+    revoked: HashSet<TokenId>,
+    revocations_issued_at_ms: Option<u64>,
+}
+
+impl RoomAccess {
+    pub fn new(room_id: impl Into<String>, creator: PeerId) -> Self {
+        Self {
+            room_id: room_id.into(),
+            creator,
+            grants: HashMap::new(),
+            revoked: HashSet::new(),
+            revocations_issued_at_ms: None,
+        }
+    }
+
+    pub fn creator(&self) -> &PeerId {
+        &self.creator
+    }
+
+    /// `bearer` presented `credential`. Remembered if it verifies.
+    pub fn present(&mut self, bearer: PeerId, credential: impl Into<Credential>, now_ms: u64) -> Result<Permission, AuthError> {
+        let credential = credential.into();
+        let permission = credential.verify(&self.room_id, &self.creator, &bearer, now_ms, &self.revoked)?;
+        self.grants.insert(bearer, credential);
+        Ok(permission)
+    }
+
+    /// Apply a revocation list from the creator. Lists older than the current one are
+    /// ignored, so a replayed list cannot bring revoked tokens back.
+    pub fn apply_revocations(&mut self, list: &RevocationList) -> Result<(), AuthError> {
+        list.verify(&self.room_id, &self.creator)?;
+        if self.revocations_issued_at_ms.is_some_and(|at| list.issued_at_ms <= at) {
+            return Ok(());
+        }
+        self.revocations_issued_at_ms = Some(list.issued_at_ms);
+        self.revoked = list.revoked.iter().copied().collect();
+        let revoked = &self.revoked;
+        self.grants.retain(|_, credential| !revoked.contains(&credential.id()));
+        Ok(())
+    }
+
+    /// Handle a presence-channel frame from `author`: remember its token or guest pass,
+    /// apply its revocations. Returns the presence data to deliver, if the author may read
+    /// the room.
+    pub fn receive_presence(&mut self, author: PeerId, frame: PresenceFrame, now_ms: u64) -> Option<Vec<u8>> {
+        let (credential, data) = match frame {
+            PresenceFrame::Presence { token, data } => (token.map(Credential::Token), data),
+            PresenceFrame::GuestPresence { pass, data } => (Some(Credential::Guest(pass)), data),
+            PresenceFrame::Revocations(list) => {
+                if let Err(e) = self.apply_revocations(&list) {
+                    tracing::debug!("Ignoring revocations from {} for room {}: {}", author, self.room_id, e);
+                }
+                return None;
+            }
+        };
+        if let Some(credential) = credential {
+            if let Err(e) = self.present(author, credential, now_ms) {
+                tracing::debug!("Ignoring credential from {} for room {}: {}", author, self.room_id, e);
+            }
+        }
+        self.allows(&author, Permission::Read, now_ms).then_some(data)
+    }
+
+    /// Whether `peer` may act with `permission` at `now_ms`. The creator always may.
+    pub fn allows(&self, peer: &PeerId, permission: Permission, now_ms: u64) -> bool {
+        if *peer == self.creator {
+            return true;
+        }
+        self.grants.get(peer).is_some_and(|credential| {
+            credential
+                .verify(&self.room_id, &self.creator, peer, now_ms, &self.revoked)
+                .is_ok_and(|granted| granted.allows(permission))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000_000;
+
+    fn setup() -> (Keypair, PeerId, PeerId) {
+        let creator = Keypair::generate_ed25519();
+        let creator_id = creator.public().to_peer_id();
+        (creator, creator_id, PeerId::random())
+    }
+
+    #[test]
+    fn valid_tokens_verify_and_roundtrip() {
+        let (creator, creator_id, member) = setup();
+        let token = issue_capability(&creator, "room", &member, Permission::Write, NOW + 1000).unwrap();
+        let token = Capability::decode(&token.encode()).unwrap();
+        let verified = verify_capability(&token, "room", &creator_id, &member, NOW, &HashSet::new());
+        assert_eq!(verified, Ok(Permission::Write));
+        assert!(Permission::Write.allows(Permission::Read));
+        assert!(!Permission::Read.allows(Permission::Write));
+        assert_eq!(Capability::decode(b"junk"), Err(AuthError::Malformed));
+    }
+
+    #[test]
+    fn rejects_expired_revoked_and_misdirected_tokens() {
+        let (creator, creator_id, member) = setup();
+        let token = issue_capability(&creator, "room", &member, Permission::Write, NOW + 1000).unwrap();
+        let none = HashSet::new();
+        let verify = |token: &Capability, room: &str, bearer: &PeerId, now: u64, revoked: &HashSet<TokenId>| {
+            verify_capability(token, room, &creator_id, bearer, now, revoked)
+        };
+
+        assert_eq!(verify(&token, "room", &member, NOW + 1000, &none), Err(AuthError::Expired));
+        assert_eq!(verify(&token, "room", &member, NOW, &HashSet::from([token.id()])), Err(AuthError::Revoked));
+        assert!(matches!(verify(&token, "other", &member, NOW, &none), Err(AuthError::WrongRoom { .. })));
+        assert_eq!(verify(&token, "room", &PeerId::random(), NOW, &none), Err(AuthError::WrongGrantee));
+
+        // Extending the expiry breaks the signature
+        let mut forged = token.clone();
+        forged.expires_at_ms = u64::MAX;
+        assert_eq!(verify(&forged, "room", &member, NOW, &none), Err(AuthError::BadSignature));
+
+        // Someone else can't hand out tokens for the room
+        let stranger = Keypair::generate_ed25519();
+        let self_issued = issue_capability(&stranger, "room", &member, Permission::Write, NOW + 1000).unwrap();
+        assert!(matches!(verify(&self_issued, "room", &member, NOW, &none), Err(AuthError::WrongIssuer { .. })));
+    }
+
+    #[test]
+    fn room_access_follows_tokens_and_revocations() {
+        let (creator, creator_id, writer) = setup();
+        let reader = PeerId::random();
+        let mut access = RoomAccess::new("room", creator_id);
+        assert!(access.allows(&creator_id, Permission::Write, NOW));
+        assert!(!access.allows(&writer, Permission::Read, NOW));
+
+        let write = issue_capability(&creator, "room", &writer, Permission::Write, NOW + 1000).unwrap();
+        let read = issue_capability(&creator, "room", &reader, Permission::Read, NOW + 1000).unwrap();
+        assert_eq!(access.present(writer, write.clone(), NOW), Ok(Permission::Write));
+        assert_eq!(access.present(reader, read, NOW), Ok(Permission::Read));
+        // A token only works for its grantee
+        assert_eq!(access.present(reader, write.clone(), NOW), Err(AuthError::WrongGrantee));
+        assert!(access.allows(&writer, Permission::Write, NOW));
+        assert!(access.allows(&reader, Permission::Read, NOW) && !access.allows(&reader, Permission::Write, NOW));
+        assert!(!access.allows(&writer, Permission::Write, NOW + 1000));
+
+        let stranger = Keypair::generate_ed25519();
+        let forged = RevocationList::sign(&stranger, "room", vec![write.id()], NOW).unwrap();
+        assert!(matches!(access.apply_revocations(&forged), Err(AuthError::WrongIssuer { .. })));
+        assert!(access.allows(&writer, Permission::Write, NOW));
+
+        let revocations = RevocationList::sign(&creator, "room", vec![write.id()], NOW).unwrap();
+        access.apply_revocations(&revocations).unwrap();
+        assert!(!access.allows(&writer, Permission::Write, NOW));
+        assert_eq!(access.present(writer, write.clone(), NOW), Err(AuthError::Revoked));
+
+        // An older list doesn't undo a newer one
+        let stale = RevocationList::sign(&creator, "room", Vec::new(), NOW - 1).unwrap();
+        access.apply_revocations(&stale).unwrap();
+        assert_eq!(access.present(writer, write, NOW), Err(AuthError::Revoked));
+    }
+
+    #[test]
+    fn presence_frames_carry_tokens_and_revocations() {
+        let (creator, creator_id, member) = setup();
+        let mut access = RoomAccess::new("room", creator_id);
+        let token = issue_capability(&creator, "room", &member, Permission::Write, NOW + 1000).unwrap();
+
+        let anonymous = PresenceFrame::Presence { token: None, data: b"hi".to_vec() };
+        assert_eq!(access.receive_presence(member, anonymous, NOW), None);
+        let frame = PresenceFrame::Presence { token: Some(token.clone()), data: b"hi".to_vec() };
+        let frame = PresenceFrame::decode(&frame.encode()).unwrap();
+        assert_eq!(access.receive_presence(member, frame, NOW), Some(b"hi".to_vec()));
+
+        let revocations = RevocationList::sign(&creator, "room", vec![token.id()], NOW).unwrap();
+        assert_eq!(access.receive_presence(creator_id, PresenceFrame::Revocations(revocations), NOW), None);
+        assert!(!access.allows(&member, Permission::Read, NOW));
+    }
+
+    #[test]
+    fn guest_passes_are_bound_to_their_bearer() {
+        let (creator, creator_id, guest) = setup();
+        let link = Keypair::generate_ed25519();
+        let token = issue_capability(&creator, "room", &link.public().to_peer_id(), Permission::Read, NOW + 1000).unwrap();
+        let pass = GuestPass::sign(&link, token.clone(), &guest).unwrap();
+        let mut access = RoomAccess::new("room", creator_id);
+
+        // Replayed by another peer
+        let eavesdropper = PeerId::random();
+        let stolen = PresenceFrame::presence(Some(Credential::Guest(pass.clone())), b"hi".to_vec());
+        assert_eq!(access.receive_presence(eavesdropper, stolen, NOW), None);
+        // The link's token alone is granted to the link, not to the guest
+        assert_eq!(access.present(guest, token.clone(), NOW), Err(AuthError::WrongGrantee));
+
+        let frame = PresenceFrame::presence(Some(Credential::Guest(pass)), b"hi".to_vec());
+        let frame = PresenceFrame::decode(&frame.encode()).unwrap();
+        assert_eq!(access.receive_presence(guest, frame, NOW), Some(b"hi".to_vec()));
+        assert!(access.allows(&guest, Permission::Read, NOW) && !access.allows(&guest, Permission::Write, NOW));
+
+        // Revoking the link's token shuts its guests out
+        let revocations = RevocationList::sign(&creator, "room", vec![token.id()], NOW).unwrap();
+        access.apply_revocations(&revocations).unwrap();
+        assert!(!access.allows(&guest, Permission::Read, NOW));
+    }
+
+    #[test]
+    fn absurd_lengths_are_rejected() {
+        const HUGE_LEN: [u8; 10] = [0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01];
+        // Capability for room "r" whose issuer key claims HUGE_LEN bytes
+        let token = [&[1, b'r'][..], &HUGE_LEN].concat();
+        assert_eq!(Capability::decode(&token), Err(AuthError::Malformed));
+
+        // Revocations (variant 1) for room "r", no key, HUGE_LEN or too many token ids
+        let list = [&[1, 1, b'r', 0][..], &HUGE_LEN].concat();
+        assert_eq!(PresenceFrame::decode(&list), Err(AuthError::Malformed));
+        let (creator, _, _) = setup();
+        let full = RevocationList::sign(&creator, "r", vec![[7; 32]; MAX_REVOKED_TOKENS], NOW).unwrap();
+        let frame = PresenceFrame::Revocations(full.clone());
+        assert_eq!(PresenceFrame::decode(&frame.encode()), Ok(frame));
+        let mut over = full;
+        over.revoked.push([7; 32]);
+        assert_eq!(PresenceFrame::decode(&PresenceFrame::Revocations(over).encode()), Err(AuthError::Malformed));
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    mod properties {
+        use proptest::collection::vec;
+        use proptest::prelude::*;
+
+        use super::*;
+
+        fn capability() -> impl Strategy<Value = Capability> {
+            (".{0,16}", vec(any::<u8>(), 0..64), vec(any::<u8>(), 0..48), any::<bool>(), any::<u64>(), vec(any::<u8>(), 0..80))
+                .prop_map(|(room_id, issuer_public_key, grantee, write, expires_at_ms, signature)| Capability {
+                    room_id,
+                    issuer_public_key,
+                    grantee,
+                    permission: if write { Permission::Write } else { Permission::Read },
+                    expires_at_ms,
+                    signature,
+                })
+        }
+
+        fn frame() -> impl Strategy<Value = PresenceFrame> {
+            prop_oneof![
+                (proptest::option::of(capability()), vec(any::<u8>(), 0..128))
+                    .prop_map(|(token, data)| PresenceFrame::Presence { token, data }),
+                (".{0,16}", vec(any::<u8>(), 0..64), vec(any::<[u8; 32]>(), 0..8), any::<u64>(), vec(any::<u8>(), 0..80))
+                    .prop_map(|(room_id, issuer_public_key, revoked, issued_at_ms, signature)| {
+                        PresenceFrame::Revocations(RevocationList { room_id, issuer_public_key, revoked, issued_at_ms, signature })
+                    }),
+                (capability(), vec(any::<u8>(), 0..64), vec(any::<u8>(), 0..80), vec(any::<u8>(), 0..128)).prop_map(
+                    |(token, link_public_key, signature, data)| PresenceFrame::GuestPresence {
+                        pass: GuestPass { token, link_public_key, signature },
+                        data,
+                    }
+                ),
+            ]
+        }
+
+        proptest! {
+            #[test]
+            fn tokens_and_frames_round_trip(token in capability(), frame in frame()) {
+                prop_assert_eq!(Capability::decode(&token.encode()), Ok(token));
+                prop_assert_eq!(PresenceFrame::decode(&frame.encode()), Ok(frame));
+            }
+
+            #[test]
+            fn decoding_arbitrary_bytes_never_panics(bytes in vec(any::<u8>(), 0..512)) {
+                if let Ok(token) = Capability::decode(&bytes) {
+                    let _ = verify_capability(&token, &token.room_id, &PeerId::random(), &PeerId::random(), 0, &HashSet::new());
+                }
+                let _ = PresenceFrame::decode(&bytes);
+            }
+        }
+    }
+}
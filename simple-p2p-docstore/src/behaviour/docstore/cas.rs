@@ -0,0 +1,42 @@
+//! Compare-and-swap updates, for documents used as small coordination records ("current
+//! leader", "active session") where last-writer-wins would let two writers both think
+//! they won.
+//!
+//! A CAS update travels in an [`Envelope::Cas`](super::Envelope::Cas) carrying the
+//! version the publisher expects the document to be at. The guarantees are per node and
+//! best-effort, which is why the API is `try_cas_update`:
+//!
+//! - The publisher refuses locally, without publishing, if its own store is not at the
+//!   expected version. Browsers keep no store and skip this check.
+//! - A node with a store applies the update only if the document is at the expected
+//!   version when the message arrives, so of several CAS updates against the same
+//!   version each such node applies exactly one: whichever reached it first. It ignores
+//!   the others (without penalising whoever forwarded them, and without forwarding them)
+//!   and answers their publishers with a conflict receipt naming its current version.
+//! - Nodes without a store cannot check and apply CAS updates like any other.
+//! - Nothing makes different nodes pick the same winner: two FullNodes that received
+//!   concurrent updates in different orders each keep the one they saw first. Agreement
+//!   needs a single arbiter, e.g. one FullNode whose receipts the writers wait for.
+//! - Peers running a build without CAS see the message as plain data and apply nothing.
+//!
+//! Conflicts reach the publisher through the receipt path (see [`super::receipt`]): CAS
+//! updates always ask for receipts, and a node that refuses one answers with a receipt
+//! whose outcome is [`ReceiptOutcome::CasConflict`](super::receipt::ReceiptOutcome).
+
+/// A CAS update refused because the document was at another version.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("{doc_id} is at version {current_version}, not {expected_version}")]
+pub struct CasConflict {
+    pub doc_id: String,
+    pub expected_version: u64,
+    pub current_version: u64,
+}
+
+/// Whether a CAS update of `doc_id` expecting `expected_version` applies to a document
+/// at `current_version`.
+pub fn check(doc_id: &str, expected_version: u64, current_version: u64) -> Result<(), CasConflict> {
+    if expected_version == current_version {
+        return Ok(());
+    }
+    Err(CasConflict { doc_id: doc_id.to_string(), expected_version, current_version })
+}
@@ -0,0 +1,655 @@
+//! Wire envelope for document updates carried on the docstore topic.
+//!
+//! Layout: `[version: u8][flags: u8][body]`, where `body` is the postcard-encoded
+//! [`Envelope`], zstd-compressed when [`FLAG_COMPRESSED`] is set.
+//!
+//! The version byte lets peers running different envelope formats recognise each other's
+//! messages instead of misreading them. Versions are drawn from `0..=LAST_VERSION_BYTE`,
+//! control characters that text never starts with, so plain data published on the same
+//! topic is not mistaken for an envelope of another version. `0` and `1` were the flag
+//! bytes of the original, unversioned layout.
+//!
+//! Peers reject flags they do not know, so a message with a newer flag set reaches older
+//! peers as plain data.
+
+use std::ops::RangeInclusive;
+
+use serde::{Deserialize, Serialize};
+
+use super::hlc::Stamp;
+use super::transaction::TransactionPart;
+use super::wire;
+
+/// Envelope version written by this build.
+pub const CURRENT_PROTOCOL_VERSION: u8 = 2;
+
+/// Oldest envelope version this build can still decode.
+pub const MIN_SUPPORTED_VERSION: u8 = 2;
+
+/// Highest byte value reserved for envelope versions. Messages starting with a larger
+/// byte are not envelopes at all.
+pub const LAST_VERSION_BYTE: u8 = 0x1f;
+
+/// Envelope versions this build decodes.
+pub fn supported_versions() -> RangeInclusive<u8> {
+    MIN_SUPPORTED_VERSION..=CURRENT_PROTOCOL_VERSION
+}
+
+/// The version of an envelope this build cannot read, if `data` is one. Cheaper than a
+/// full decode.
+pub fn unsupported_version(data: &[u8]) -> Option<u8> {
+    data.first().copied().filter(|v| *v <= LAST_VERSION_BYTE && !supported_versions().contains(v))
+}
+
+/// Envelope flag: the body is zstd-compressed.
+pub const FLAG_COMPRESSED: u8 = 0b0000_0001;
+
+/// Envelope flag: the publisher asks whoever applies the update for a receipt, see
+/// [`super::receipt`].
+pub const FLAG_ACK_REQUESTED: u8 = 0b0000_0010;
+
+/// Envelope flag: the body is an [`Envelope::Cas`]. Set by the encoder; builds that
+/// predate CAS refuse the flag and so never apply such an update unconditionally.
+pub const FLAG_CAS: u8 = 0b0000_0100;
+
+/// Envelope flag: the message has local scope and relays do not pass it on to other
+/// relays' clients, see [`super::scope`]. Peers that predate scopes refuse the flag.
+pub const FLAG_LOCAL_SCOPE: u8 = 0b0000_1000;
+
+const KNOWN_FLAGS: u8 = FLAG_COMPRESSED | FLAG_ACK_REQUESTED | FLAG_CAS | FLAG_LOCAL_SCOPE;
+
+/// Whether `data` is an envelope of a version we read whose publisher asks for receipts.
+/// Cheaper than a full decode.
+pub fn ack_requested(data: &[u8]) -> bool {
+    matches!(data, [version, flags, ..] if supported_versions().contains(version) && flags & FLAG_ACK_REQUESTED != 0)
+}
+
+/// Bodies larger than this are compressed by default (when compression is compiled in).
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 4 * 1024;
+
+/// Upper bound on the size a compressed body may expand to (zip-bomb protection).
+pub const DEFAULT_MAX_DECOMPRESSED_SIZE: usize = 16 * 1024 * 1024;
+
+/// Most updates one batch may carry; [`coalesce`] starts a new batch past this.
+pub const MAX_BATCH_UPDATES: usize = 1024;
+
+/// Encoder/decoder settings. Usually derived from `DocstoreGossipsubConfig::codec`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CodecOptions {
+    /// Compress bodies strictly larger than this many bytes. `None` disables compression.
+    pub compression_threshold: Option<usize>,
+    pub max_decompressed_size: usize,
+}
+
+impl Default for CodecOptions {
+    fn default() -> Self {
+        Self {
+            compression_threshold: Some(DEFAULT_COMPRESSION_THRESHOLD),
+            max_decompressed_size: DEFAULT_MAX_DECOMPRESSED_SIZE,
+        }
+    }
+}
+
+/// Errors produced while decoding an envelope.
+#[derive(Debug, thiserror::Error)]
+pub enum DecodeError {
+    #[error("empty envelope")]
+    Empty,
+    #[error("not a docstore envelope")]
+    NotAnEnvelope,
+    /// Sent by a peer running an older or newer envelope format.
+    #[error("envelope version {got} is not supported (expected {}..={})", .supported.start(), .supported.end())]
+    UnsupportedVersion { got: u8, supported: RangeInclusive<u8> },
+    #[error("unknown envelope flags {0:#04x}")]
+    UnknownFlags(u8),
+    #[error("envelope is compressed but compression support is not compiled in")]
+    CompressionUnsupported,
+    #[error("decompressed envelope exceeds {max} bytes")]
+    DecompressedTooLarge { max: usize },
+    /// A batch whose updates, each with its own copy of the doc id, would exceed
+    /// `max_decompressed_size` once unpacked.
+    #[error("batch unpacks to more than {max} bytes")]
+    UnpackedTooLarge { max: usize },
+    #[error("decompression failed: {0}")]
+    Decompress(String),
+    #[error("malformed envelope body: {0}")]
+    Malformed(#[from] postcard::Error),
+}
+
+/// A single application-level update for one document.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DocUpdate {
+    pub doc_id: String,
+    #[serde(deserialize_with = "wire::bytes")]
+    pub payload: Vec<u8>,
+    /// Ordering metadata set by the publishing node. Unstamped updates are applied in
+    /// arrival order.
+    pub stamp: Option<Stamp>,
+}
+
+impl DocUpdate {
+    pub fn new(doc_id: impl Into<String>, payload: impl Into<Vec<u8>>) -> Self {
+        Self { doc_id: doc_id.into(), payload: payload.into(), stamp: None }
+    }
+
+    pub fn with_stamp(mut self, stamp: Stamp) -> Self {
+        self.stamp = Some(stamp);
+        self
+    }
+}
+
+/// What actually goes over gossipsub. A batch packs several updates for the same
+/// document into one message; receivers unpack it with [`Envelope::into_updates`]. A
+/// transaction part carries updates to any documents that must only be applied once the
+/// other parts have arrived, see [`super::transaction`]. A CAS update only applies to
+/// the document at `expected_version`, see [`super::cas`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Envelope {
+    Update(DocUpdate),
+    /// `stamps` is parallel to `payloads`. Both hold at most [`MAX_BATCH_UPDATES`] entries.
+    Batch {
+        doc_id: String,
+        #[serde(deserialize_with = "batch_payloads")]
+        payloads: Vec<Vec<u8>>,
+        #[serde(deserialize_with = "batch_stamps")]
+        stamps: Vec<Option<Stamp>>,
+    },
+    Transaction(TransactionPart),
+    Cas {
+        update: DocUpdate,
+        expected_version: u64,
+    },
+}
+
+fn batch_payloads<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<Vec<u8>>, D::Error> {
+    wire::bounded_seq::<D, Vec<u8>, wire::ByteBuf>(deserializer, MAX_BATCH_UPDATES)
+}
+
+fn batch_stamps<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<Option<Stamp>>, D::Error> {
+    wire::bounded_seq::<D, Option<Stamp>, Option<Stamp>>(deserializer, MAX_BATCH_UPDATES)
+}
+
+impl Envelope {
+    pub fn encode(&self) -> Vec<u8> {
+        self.encode_with(&CodecOptions::default())
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        Self::decode_with(bytes, &CodecOptions::default())
+    }
+
+    pub fn encode_with(&self, opts: &CodecOptions) -> Vec<u8> {
+        self.encode_flagged(opts, 0)
+    }
+
+    /// Like [`encode_with`](Self::encode_with), with `flags` such as
+    /// [`FLAG_ACK_REQUESTED`] set in the header. [`FLAG_COMPRESSED`] is up to the encoder,
+    /// and [`FLAG_CAS`] is set for CAS envelopes.
+    pub fn encode_flagged(&self, opts: &CodecOptions, flags: u8) -> Vec<u8> {
+        let body = postcard::to_allocvec(self).expect("envelope serialization is infallible");
+        let (compressed, body) = match opts.compression_threshold {
+            Some(threshold) if body.len() > threshold => match compress(&body) {
+                // Only keep the compressed form if it actually helps
+                Some(packed) if packed.len() < body.len() => (FLAG_COMPRESSED, packed),
+                _ => (0, body),
+            },
+            _ => (0, body),
+        };
+        let mut out = Vec::with_capacity(body.len() + 2);
+        out.push(CURRENT_PROTOCOL_VERSION);
+        let cas = if matches!(self, Envelope::Cas { .. }) { FLAG_CAS } else { 0 };
+        out.push(flags & !(FLAG_COMPRESSED | FLAG_CAS) | compressed | cas);
+        out.extend_from_slice(&body);
+        out
+    }
+
+    pub fn decode_with(bytes: &[u8], opts: &CodecOptions) -> Result<Self, DecodeError> {
+        let (&version, rest) = bytes.split_first().ok_or(DecodeError::Empty)?;
+        if version > LAST_VERSION_BYTE {
+            return Err(DecodeError::NotAnEnvelope);
+        }
+        if !supported_versions().contains(&version) {
+            return Err(DecodeError::UnsupportedVersion { got: version, supported: supported_versions() });
+        }
+        let (&flags, body) = rest.split_first().ok_or(DecodeError::Empty)?;
+        if flags & !KNOWN_FLAGS != 0 {
+            return Err(DecodeError::UnknownFlags(flags));
+        }
+        let env: Self = if flags & FLAG_COMPRESSED != 0 {
+            postcard::from_bytes(&decompress(body, opts.max_decompressed_size)?)?
+        } else {
+            postcard::from_bytes(body)?
+        };
+        // Unpacking copies the doc id into every update, so a long id with many empty
+        // payloads would otherwise multiply a small message many times over
+        if let Envelope::Batch { doc_id, payloads, .. } = &env {
+            let unpacked: usize = payloads.iter().map(|p| doc_id.len() + p.len()).sum();
+            if unpacked > opts.max_decompressed_size {
+                return Err(DecodeError::UnpackedTooLarge { max: opts.max_decompressed_size });
+            }
+        }
+        Ok(env)
+    }
+
+    /// Unpack into individual updates, preserving the publish order. A transaction part
+    /// yields its own updates only; apply those through a `TransactionAssembler` instead.
+    pub fn into_updates(self) -> Vec<DocUpdate> {
+        match self {
+            Envelope::Update(update) => vec![update],
+            Envelope::Transaction(part) => part.updates,
+            Envelope::Cas { update, .. } => vec![update],
+            Envelope::Batch { doc_id, payloads, stamps } => payloads
+                .into_iter()
+                .zip(stamps.into_iter().chain(std::iter::repeat(None)))
+                .map(|(payload, stamp)| DocUpdate { doc_id: doc_id.clone(), payload, stamp })
+                .collect(),
+        }
+    }
+
+    /// The version a CAS envelope expects its document at.
+    pub fn expected_version(&self) -> Option<u64> {
+        match self {
+            Envelope::Cas { expected_version, .. } => Some(*expected_version),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "compression")]
+fn compress(body: &[u8]) -> Option<Vec<u8>> {
+    Some(ruzstd::encoding::compress_to_vec(body, ruzstd::encoding::CompressionLevel::Fastest))
+}
+
+#[cfg(not(feature = "compression"))]
+fn compress(_body: &[u8]) -> Option<Vec<u8>> {
+    None
+}
+
+#[cfg(feature = "compression")]
+fn decompress(body: &[u8], max: usize) -> Result<Vec<u8>, DecodeError> {
+    use std::io::Read;
+
+    let decoder = ruzstd::decoding::StreamingDecoder::new(body)
+        .map_err(|e| DecodeError::Decompress(e.to_string()))?;
+    // Read at most one byte past the cap so oversized frames are detected without
+    // ever materialising them.
+    let mut out = Vec::new();
+    decoder
+        .take(max as u64 + 1)
+        .read_to_end(&mut out)
+        .map_err(|e| DecodeError::Decompress(e.to_string()))?;
+    if out.len() > max {
+        return Err(DecodeError::DecompressedTooLarge { max });
+    }
+    Ok(out)
+}
+
+#[cfg(not(feature = "compression"))]
+fn decompress(_body: &[u8], _max: usize) -> Result<Vec<u8>, DecodeError> {
+    Err(DecodeError::CompressionUnsupported)
+}
+
+/// Group updates into one envelope per document. Documents keep the order in which
+/// they first appear and each document's updates keep their relative order.
+///
+/// A document's group is split into several envelopes once its encoded updates
+/// (length-prefixed payloads with their stamps) would exceed `max_batch_bytes` or
+/// it holds [`MAX_BATCH_UPDATES`] updates, so a batch is never larger than the largest
+/// publishable update.
+pub fn coalesce(updates: Vec<DocUpdate>, max_batch_bytes: usize) -> Vec<Envelope> {
+    // (doc_id, updates, encoded bytes); only the last group of a doc accepts more
+    let mut groups: Vec<(String, Vec<DocUpdate>, usize)> = Vec::new();
+    for update in updates {
+        let len = batched_len(&update);
+        match groups.iter_mut().rev().find(|(doc_id, _, _)| *doc_id == update.doc_id) {
+            Some((_, group, bytes)) if *bytes + len <= max_batch_bytes && group.len() < MAX_BATCH_UPDATES => {
+                group.push(update);
+                *bytes += len;
+            }
+            _ => groups.push((update.doc_id.clone(), vec![update], len)),
+        }
+    }
+    groups
+        .into_iter()
+        .map(|(doc_id, mut group, _)| {
+            if group.len() == 1 {
+                Envelope::Update(group.remove(0))
+            } else {
+                let (payloads, stamps) = group.into_iter().map(|u| (u.payload, u.stamp)).unzip();
+                Envelope::Batch { doc_id, payloads, stamps }
+            }
+        })
+        .collect()
+}
+
+/// The bytes `update` takes up in an [`Envelope::Batch`]: its length-prefixed payload
+/// and its stamp. The document id is only written once per batch.
+pub(crate) fn batched_len(update: &DocUpdate) -> usize {
+    let stamp = postcard::to_allocvec(&update.stamp).map_or(0, |stamp| stamp.len());
+    prefixed_len(update.payload.len()) + stamp
+}
+
+/// `len` bytes behind their postcard varint length prefix.
+pub(crate) fn prefixed_len(len: usize) -> usize {
+    len + (usize::BITS - len.leading_zeros()).div_ceil(7).max(1) as usize
+}
+
+/// Collects updates published within a debounce window so they can be flushed as
+/// one batch. The owner (an event loop) is responsible for the timer; this only
+/// tracks what is pending.
+#[derive(Debug, Default)]
+pub struct PublishDebouncer {
+    window: Option<std::time::Duration>,
+    pending: Vec<DocUpdate>,
+}
+
+impl PublishDebouncer {
+    /// `None` or a zero window disables debouncing.
+    pub fn set_window(&mut self, window: Option<std::time::Duration>) {
+        self.window = window.filter(|w| !w.is_zero());
+    }
+
+    pub fn window(&self) -> Option<std::time::Duration> {
+        self.window
+    }
+
+    /// Queue an update. Returns `true` if this is the first pending update, i.e. the
+    /// caller should arm its flush timer.
+    pub fn push(&mut self, update: DocUpdate) -> bool {
+        self.pending.push(update);
+        self.pending.len() == 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Take everything pending, in publish order. Hand the result to `publish_batch`
+    /// to coalesce it per document.
+    pub fn take(&mut self) -> Vec<DocUpdate> {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn envelope_round_trip() {
+        let env = Envelope::Batch {
+            doc_id: "doc".into(),
+            payloads: vec![b"a".to_vec(), b"b".to_vec()],
+            stamps: vec![None, None],
+        };
+        assert_eq!(Envelope::decode(&env.encode()).unwrap(), env);
+    }
+
+    #[test]
+    fn transaction_parts_round_trip() {
+        let mut tx = crate::behaviour::docstore::Transaction::new();
+        tx.add_update(DocUpdate::new("meta", b"m".to_vec()));
+        tx.add_update(DocUpdate::new("content", b"c".to_vec()));
+        let env = Envelope::Transaction(tx.parts(1024).remove(0));
+        assert_eq!(Envelope::decode(&env.encode()).unwrap(), env);
+        assert_eq!(env.into_updates(), tx.updates);
+    }
+
+    #[test]
+    fn ack_requests_ride_in_the_flags() {
+        let env = Envelope::Update(DocUpdate::new("d", b"x".to_vec()));
+        let plain = env.encode();
+        assert!(!ack_requested(&plain));
+        let acked = env.encode_flagged(&CodecOptions::default(), FLAG_ACK_REQUESTED);
+        assert!(ack_requested(&acked));
+        assert_eq!(Envelope::decode(&acked).unwrap(), env);
+
+        // Only in envelopes we read
+        let mut future = acked.clone();
+        future[0] = CURRENT_PROTOCOL_VERSION + 1;
+        assert!(!ack_requested(&future));
+        assert!(!ack_requested(&acked[..1]));
+    }
+
+    #[test]
+    fn cas_envelopes_are_flagged() {
+        let env = Envelope::Cas { update: DocUpdate::new("lock", b"me".to_vec()), expected_version: 7 };
+        let bytes = env.encode_flagged(&CodecOptions::default(), FLAG_ACK_REQUESTED);
+        assert_eq!(bytes[1], FLAG_ACK_REQUESTED | FLAG_CAS);
+        let decoded = Envelope::decode(&bytes).unwrap();
+        assert_eq!(decoded.expected_version(), Some(7));
+        assert_eq!(decoded.into_updates(), vec![DocUpdate::new("lock", b"me".to_vec())]);
+        // The flag alone does not make an update conditional
+        assert_eq!(Envelope::Update(DocUpdate::new("d", b"x".to_vec())).encode_flagged(&CodecOptions::default(), FLAG_CAS)[1], 0);
+    }
+
+    #[test]
+    fn unknown_flags_are_rejected() {
+        let mut bytes = Envelope::Update(DocUpdate::new("d", b"x".to_vec())).encode();
+        bytes[1] = 0x80;
+        assert!(matches!(Envelope::decode(&bytes), Err(DecodeError::UnknownFlags(0x80))));
+        assert!(matches!(Envelope::decode(&[]), Err(DecodeError::Empty)));
+        assert!(matches!(Envelope::decode(&[CURRENT_PROTOCOL_VERSION]), Err(DecodeError::Empty)));
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn compressed_round_trip() {
+        let env = Envelope::Update(DocUpdate::new("doc", vec![b'{'; 64 * 1024]));
+        let bytes = env.encode();
+        assert_eq!(bytes[1] & FLAG_COMPRESSED, FLAG_COMPRESSED);
+        assert!(bytes.len() < 64 * 1024 / 5);
+        assert_eq!(Envelope::decode(&bytes).unwrap(), env);
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn compression_threshold_boundary() {
+        let env = Envelope::Update(DocUpdate::new("doc", vec![b'a'; 2048]));
+        let body_len = env.encode_with(&CodecOptions { compression_threshold: None, ..Default::default() }).len() - 2;
+
+        let at = CodecOptions { compression_threshold: Some(body_len), ..Default::default() };
+        assert_eq!(env.encode_with(&at)[1], 0);
+
+        let below = CodecOptions { compression_threshold: Some(body_len - 1), ..Default::default() };
+        assert_eq!(env.encode_with(&below)[1], FLAG_COMPRESSED);
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn decompression_bomb_is_capped() {
+        let env = Envelope::Update(DocUpdate::new("doc", vec![0u8; 1024 * 1024]));
+        let bytes = env.encode();
+        let opts = CodecOptions { max_decompressed_size: 4096, ..Default::default() };
+        assert!(matches!(
+            Envelope::decode_with(&bytes, &opts),
+            Err(DecodeError::DecompressedTooLarge { max: 4096 })
+        ));
+    }
+
+    #[cfg(not(feature = "compression"))]
+    #[test]
+    fn compressed_envelope_without_support_is_an_error() {
+        assert!(matches!(
+            Envelope::decode(&[CURRENT_PROTOCOL_VERSION, FLAG_COMPRESSED, 0]),
+            Err(DecodeError::CompressionUnsupported)
+        ));
+    }
+
+    #[test]
+    fn version_byte_is_checked_first() {
+        let current = Envelope::Update(DocUpdate::new("d", b"x".to_vec())).encode();
+        assert_eq!(current[0], CURRENT_PROTOCOL_VERSION);
+        assert!(Envelope::decode(&current).is_ok());
+        assert_eq!(unsupported_version(&current), None);
+
+        // An unversioned envelope from before the version byte existed
+        let mut older = current[1..].to_vec();
+        assert!(matches!(
+            Envelope::decode(&older),
+            Err(DecodeError::UnsupportedVersion { got: 0, ref supported }) if *supported == supported_versions()
+        ));
+        older[0] = FLAG_COMPRESSED;
+        assert_eq!(unsupported_version(&older), Some(1));
+
+        let mut future = current.clone();
+        future[0] = CURRENT_PROTOCOL_VERSION + 1;
+        assert!(matches!(Envelope::decode(&future), Err(DecodeError::UnsupportedVersion { got: 3, .. })));
+        assert_eq!(unsupported_version(&future), Some(CURRENT_PROTOCOL_VERSION + 1));
+
+        // Plain text is not an envelope of any version
+        assert!(matches!(Envelope::decode(b"hello"), Err(DecodeError::NotAnEnvelope)));
+        assert_eq!(unsupported_version(b"hello"), None);
+    }
+
+    #[test]
+    fn coalesce_groups_per_doc_preserving_order() {
+        let envs = coalesce(vec![
+            DocUpdate::new("a", b"1".to_vec()),
+            DocUpdate::new("b", b"x".to_vec()),
+            DocUpdate::new("a", b"2".to_vec()),
+            DocUpdate::new("a", b"3".to_vec()),
+        ], usize::MAX);
+        assert_eq!(envs.len(), 2);
+        assert_eq!(envs[1], Envelope::Update(DocUpdate::new("b", b"x".to_vec())));
+
+        let a: Vec<_> = envs[0].clone().into_updates().into_iter().map(|u| u.payload).collect();
+        assert_eq!(a, vec![b"1".to_vec(), b"2".to_vec(), b"3".to_vec()]);
+    }
+
+    #[test]
+    fn coalesce_splits_batches_at_the_size_limit() {
+        // 4 payload bytes, their length and the empty stamp
+        let updates: Vec<_> = (0..5).map(|_| DocUpdate::new("a", vec![0u8; 4])).collect();
+        assert_eq!(batched_len(&updates[0]), 6);
+        let envs = coalesce(updates, 12);
+        assert_eq!(envs.len(), 3);
+        let total: usize = envs.into_iter().map(|e| e.into_updates().len()).sum();
+        assert_eq!(total, 5);
+    }
+
+    #[test]
+    fn length_prefixes_grow_with_the_payload() {
+        assert_eq!(prefixed_len(0), 1);
+        assert_eq!(prefixed_len(127), 128);
+        assert_eq!(prefixed_len(128), 130);
+        let update = DocUpdate::new("a", vec![0u8; 300]);
+        assert_eq!(batched_len(&update), postcard::to_allocvec(&(&update.payload, &update.stamp)).unwrap().len());
+    }
+
+    #[test]
+    fn debouncer_arms_once_and_drains() {
+        let mut d = PublishDebouncer::default();
+        d.set_window(Some(std::time::Duration::from_millis(50)));
+        assert!(d.push(DocUpdate::new("a", b"1".to_vec())));
+        assert!(!d.push(DocUpdate::new("a", b"2".to_vec())));
+        assert_eq!(d.take().len(), 2);
+        assert!(d.is_empty());
+
+        d.set_window(Some(std::time::Duration::ZERO));
+        assert_eq!(d.window(), None);
+    }
+
+    #[test]
+    fn coalesce_splits_batches_at_the_update_limit() {
+        let updates = (0..MAX_BATCH_UPDATES + 10).map(|_| DocUpdate::new("a", b"x".to_vec())).collect();
+        let envs = coalesce(updates, usize::MAX);
+        let sizes: Vec<usize> = envs.iter().map(|e| e.clone().into_updates().len()).collect();
+        assert_eq!(sizes, [MAX_BATCH_UPDATES, 10]);
+        for env in envs {
+            assert_eq!(Envelope::decode(&env.encode()).unwrap(), env);
+        }
+    }
+
+    /// `u64::MAX - 1` as a postcard varint.
+    const HUGE_LEN: [u8; 10] = [0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01];
+
+    #[test]
+    fn absurd_length_prefixes_are_rejected_before_allocating() {
+        let header = [CURRENT_PROTOCOL_VERSION, 0];
+        // Update (variant 0) of doc "d" whose payload claims HUGE_LEN bytes
+        let update = [&header[..], &[0, 1, b'd'], &HUGE_LEN].concat();
+        assert!(matches!(Envelope::decode(&update), Err(DecodeError::Malformed(_))));
+
+        // Batch (variant 1) claiming HUGE_LEN payloads, or one payload of HUGE_LEN bytes
+        let batch = [&header[..], &[1, 1, b'd'], &HUGE_LEN].concat();
+        assert!(matches!(Envelope::decode(&batch), Err(DecodeError::Malformed(_))));
+        let payload = [&header[..], &[1, 1, b'd', 1], &HUGE_LEN].concat();
+        assert!(matches!(Envelope::decode(&payload), Err(DecodeError::Malformed(_))));
+
+        // One empty payload over the limit, then no stamps
+        let count = postcard::to_allocvec(&(MAX_BATCH_UPDATES + 1)).unwrap();
+        let over = [&header[..], &[1, 1, b'd'], &count[..], &vec![0; MAX_BATCH_UPDATES + 1][..], &[0]].concat();
+        assert!(matches!(Envelope::decode(&over), Err(DecodeError::Malformed(_))));
+        let count = postcard::to_allocvec(&MAX_BATCH_UPDATES).unwrap();
+        let at = [&header[..], &[1, 1, b'd'], &count[..], &vec![0; MAX_BATCH_UPDATES][..], &[0]].concat();
+        assert_eq!(Envelope::decode(&at).unwrap().into_updates().len(), MAX_BATCH_UPDATES);
+    }
+
+    #[test]
+    fn batches_that_unpack_too_large_are_rejected() {
+        let env = Envelope::Batch { doc_id: "d".repeat(1024), payloads: vec![vec![]; 64], stamps: vec![None; 64] };
+        let opts = CodecOptions { compression_threshold: None, max_decompressed_size: 32 * 1024 };
+        assert!(env.encode_with(&opts).len() < 2048);
+        assert!(matches!(
+            Envelope::decode_with(&env.encode_with(&opts), &opts),
+            Err(DecodeError::UnpackedTooLarge { max: 32768 })
+        ));
+        let roomy = CodecOptions { max_decompressed_size: 64 * 1024, ..opts };
+        assert_eq!(Envelope::decode_with(&env.encode_with(&roomy), &roomy).unwrap(), env);
+    }
+
+    // proptest needs an OS random source, which wasm32-unknown-unknown lacks
+    #[cfg(not(target_arch = "wasm32"))]
+    mod properties {
+        use proptest::collection::vec;
+        use proptest::prelude::*;
+
+        use super::*;
+        use crate::behaviour::docstore::{Hlc, VectorClock};
+
+        fn stamp() -> impl Strategy<Value = Option<Stamp>> {
+            proptest::option::of((any::<u64>(), any::<u32>(), any::<u64>(), vec(any::<u64>(), 0..4)).prop_map(
+                |(wall_ms, logical, node, nodes)| {
+                    let mut clock = VectorClock::default();
+                    for node in nodes {
+                        clock.increment(node);
+                    }
+                    Stamp { hlc: Hlc { wall_ms, logical, node }, clock }
+                },
+            ))
+        }
+
+        fn envelope() -> impl Strategy<Value = Envelope> {
+            prop_oneof![
+                (".{0,16}", vec(any::<u8>(), 0..512), stamp())
+                    .prop_map(|(doc_id, payload, stamp)| Envelope::Update(DocUpdate { doc_id, payload, stamp })),
+                (".{0,16}", vec((vec(any::<u8>(), 0..64), stamp()), 0..8)).prop_map(|(doc_id, entries)| {
+                    let (payloads, stamps) = entries.into_iter().unzip();
+                    Envelope::Batch { doc_id, payloads, stamps }
+                }),
+            ]
+        }
+
+        proptest! {
+            #[test]
+            fn envelopes_round_trip(env in envelope(), compress in any::<bool>()) {
+                // A threshold of 0 compresses every body when compression is compiled in
+                let opts = CodecOptions { compression_threshold: compress.then_some(0), ..Default::default() };
+                prop_assert_eq!(Envelope::decode_with(&env.encode_with(&opts), &opts).unwrap(), env);
+            }
+
+            #[test]
+            fn decoding_arbitrary_bytes_never_panics(bytes in vec(any::<u8>(), 0..512)) {
+                let _ = Envelope::decode(&bytes);
+                // Behind a valid header, so the body parsers see the bytes too
+                for flags in [0, FLAG_COMPRESSED] {
+                    let framed = [&[CURRENT_PROTOCOL_VERSION, flags][..], &bytes[..]].concat();
+                    if let Ok(env) = Envelope::decode(&framed) {
+                        let _ = env.into_updates();
+                    }
+                }
+            }
+        }
+    }
+}
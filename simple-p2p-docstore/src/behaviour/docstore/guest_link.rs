@@ -0,0 +1,239 @@
+//! Guest links: access to a restricted room for whoever holds the link, without issuing
+//! a token per member.
+//!
+//! The creator makes a fresh link key and issues a [`Capability`] to it. The link carries
+//! that token, the link key's secret and the relays to dial, signed with the creator's
+//! identity key and encoded as unpadded base64url so it fits in a URL. A guest opening it
+//! signs a [`GuestPass`] for its own peer id with the link key and attaches that to its
+//! presence, see [`super::auth`]. Anyone holding the link can join until it expires or
+//! the creator revokes its token, so share it like the room itself.
+
+use std::collections::HashSet;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use libp2p::identity::{Keypair, PublicKey, SigningError};
+use libp2p::{Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+
+use super::auth::{issue_capability, verify_capability, AuthError, Capability, GuestPass, Permission};
+use super::wire;
+
+/// Longest link accepted, in characters.
+pub const MAX_GUEST_LINK_LEN: usize = 4096;
+
+/// Most relays one link lists.
+pub const MAX_LINK_RELAYS: usize = 8;
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum GuestLinkError {
+    #[error("malformed guest link")]
+    Malformed,
+    #[error("guest link signature does not verify")]
+    Tampered,
+    #[error("guest link expired")]
+    Expired,
+}
+
+#[derive(Serialize, Deserialize)]
+struct WireLink {
+    /// Granted to the link key.
+    token: Capability,
+    /// Ed25519 secret of the link key.
+    #[serde(deserialize_with = "wire::bytes")]
+    link_secret: Vec<u8>,
+    /// At most [`MAX_LINK_RELAYS`] binary multiaddrs.
+    #[serde(deserialize_with = "link_relays")]
+    relays: Vec<Vec<u8>>,
+    /// The creator's, over the token and the relays.
+    #[serde(deserialize_with = "wire::bytes")]
+    signature: Vec<u8>,
+}
+
+fn link_relays<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<Vec<u8>>, D::Error> {
+    wire::bounded_seq::<D, Vec<u8>, wire::ByteBuf>(deserializer, MAX_LINK_RELAYS)
+}
+
+/// A guest link to a restricted room, see the [module docs](self).
+#[derive(Debug, Clone)]
+pub struct GuestLink {
+    token: Capability,
+    creator: PeerId,
+    link_key: Keypair,
+    relays: Vec<Multiaddr>,
+    signature: Vec<u8>,
+}
+
+impl GuestLink {
+    /// Let whoever holds the link `permission` on `room_id`, which `creator` created,
+    /// until `expires_at_ms`. Guests dial `relays` to reach the room; only the first
+    /// [`MAX_LINK_RELAYS`] are kept.
+    pub fn create(
+        creator: &Keypair,
+        room_id: &str,
+        permission: Permission,
+        expires_at_ms: u64,
+        mut relays: Vec<Multiaddr>,
+    ) -> Result<Self, SigningError> {
+        relays.truncate(MAX_LINK_RELAYS);
+        let link_key = Keypair::generate_ed25519();
+        let token = issue_capability(creator, room_id, &link_key.public().to_peer_id(), permission, expires_at_ms)?;
+        let signature = creator.sign(&payload(&token, &relays.iter().map(Multiaddr::to_vec).collect::<Vec<_>>()))?;
+        Ok(Self { token, creator: creator.public().to_peer_id(), link_key, relays, signature })
+    }
+
+    pub fn encode(&self) -> String {
+        let link_key = self.link_key.clone().try_into_ed25519().expect("link keys are ed25519");
+        let link = WireLink {
+            token: self.token.clone(),
+            link_secret: link_key.secret().as_ref().to_vec(),
+            relays: self.relays.iter().map(Multiaddr::to_vec).collect(),
+            signature: self.signature.clone(),
+        };
+        URL_SAFE_NO_PAD.encode(postcard::to_allocvec(&link).expect("guest link serialization cannot fail"))
+    }
+
+    /// Decode a link and check it at `now_ms`: signed by the creator its token names,
+    /// unchanged since, and not expired.
+    pub fn parse(link: &str, now_ms: u64) -> Result<Self, GuestLinkError> {
+        let link = link.trim();
+        if link.len() > MAX_GUEST_LINK_LEN {
+            return Err(GuestLinkError::Malformed);
+        }
+        let bytes = URL_SAFE_NO_PAD.decode(link).map_err(|_| GuestLinkError::Malformed)?;
+        let wire: WireLink = postcard::from_bytes(&bytes).map_err(|_| GuestLinkError::Malformed)?;
+        let creator = PublicKey::try_decode_protobuf(&wire.token.issuer_public_key).map_err(|_| GuestLinkError::Malformed)?;
+        if !creator.verify(&payload(&wire.token, &wire.relays), &wire.signature) {
+            return Err(GuestLinkError::Tampered);
+        }
+        let link_key = Keypair::ed25519_from_bytes(wire.link_secret).map_err(|_| GuestLinkError::Malformed)?;
+        let creator = creator.to_peer_id();
+        let room_id = wire.token.room_id.clone();
+        match verify_capability(&wire.token, &room_id, &creator, &link_key.public().to_peer_id(), now_ms, &HashSet::new()) {
+            Ok(_) => {}
+            Err(AuthError::Expired) => return Err(GuestLinkError::Expired),
+            Err(AuthError::Malformed) => return Err(GuestLinkError::Malformed),
+            // The secret of another link, or a token edited since
+            Err(_) => return Err(GuestLinkError::Tampered),
+        }
+        let relays = wire
+            .relays
+            .into_iter()
+            .map(Multiaddr::try_from)
+            .collect::<Result<_, _>>()
+            .map_err(|_| GuestLinkError::Malformed)?;
+        Ok(Self { token: wire.token, creator, link_key, relays, signature: wire.signature })
+    }
+
+    pub fn room_id(&self) -> &str {
+        &self.token.room_id
+    }
+
+    pub fn creator(&self) -> PeerId {
+        self.creator
+    }
+
+    pub fn permission(&self) -> Permission {
+        self.token.permission
+    }
+
+    pub fn expires_at_ms(&self) -> u64 {
+        self.token.expires_at_ms
+    }
+
+    pub fn relays(&self) -> &[Multiaddr] {
+        &self.relays
+    }
+
+    /// The token behind the link. Revoking it shuts out every guest that used the link.
+    pub fn token(&self) -> &Capability {
+        &self.token
+    }
+
+    /// Sign the pass `bearer` presents in the room.
+    pub fn pass_for(&self, bearer: &PeerId) -> Result<GuestPass, SigningError> {
+        GuestPass::sign(&self.link_key, self.token.clone(), bearer)
+    }
+}
+
+fn payload(token: &Capability, relays: &[Vec<u8>]) -> Vec<u8> {
+    let mut payload = b"docstore-guest-link:".to_vec();
+    payload.extend_from_slice(&token.id());
+    for relay in relays {
+        payload.extend_from_slice(&(relay.len() as u32).to_be_bytes());
+        payload.extend_from_slice(relay);
+    }
+    payload
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::behaviour::docstore::auth::{Credential, PresenceFrame, RoomAccess};
+
+    const NOW: u64 = 1_700_000_000_000;
+
+    fn relay() -> Multiaddr {
+        format!("/ip4/198.51.100.7/udp/9090/webrtc-direct/p2p/{}", PeerId::random()).parse().unwrap()
+    }
+
+    fn reencode(link: &str, tamper: impl FnOnce(&mut WireLink)) -> String {
+        let mut wire: WireLink = postcard::from_bytes(&URL_SAFE_NO_PAD.decode(link).unwrap()).unwrap();
+        tamper(&mut wire);
+        URL_SAFE_NO_PAD.encode(postcard::to_allocvec(&wire).unwrap())
+    }
+
+    #[test]
+    fn links_round_trip_and_let_guests_in() {
+        let creator = Keypair::generate_ed25519();
+        let creator_id = creator.public().to_peer_id();
+        let relays = vec![relay(), relay()];
+        let link = GuestLink::create(&creator, "design-review", Permission::Read, NOW + 1000, relays.clone()).unwrap();
+        let encoded = link.encode();
+        assert!(encoded.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_'), "URL-safe");
+
+        let parsed = GuestLink::parse(&encoded, NOW).unwrap();
+        assert_eq!((parsed.room_id(), parsed.creator(), parsed.permission()), ("design-review", creator_id, Permission::Read));
+        assert_eq!((parsed.expires_at_ms(), parsed.relays()), (NOW + 1000, relays.as_slice()));
+        assert_eq!(parsed.token(), link.token());
+
+        let guest = PeerId::random();
+        let pass = parsed.pass_for(&guest).unwrap();
+        let mut access = RoomAccess::new("design-review", creator_id);
+        let frame = PresenceFrame::presence(Some(Credential::Guest(pass)), b"here".to_vec());
+        assert_eq!(access.receive_presence(guest, frame, NOW), Some(b"here".to_vec()));
+        assert!(!access.allows(&guest, Permission::Write, NOW));
+    }
+
+    #[test]
+    fn tampered_and_expired_links_are_rejected() {
+        let creator = Keypair::generate_ed25519();
+        let link = GuestLink::create(&creator, "room", Permission::Read, NOW + 1000, vec![relay()]).unwrap().encode();
+
+        assert_eq!(GuestLink::parse(&link, NOW + 1000).unwrap_err(), GuestLinkError::Expired);
+
+        let upgraded = reencode(&link, |wire| wire.token.permission = Permission::Write);
+        assert_eq!(GuestLink::parse(&upgraded, NOW).unwrap_err(), GuestLinkError::Tampered);
+        let extended = reencode(&link, |wire| wire.token.expires_at_ms = u64::MAX);
+        assert_eq!(GuestLink::parse(&extended, NOW).unwrap_err(), GuestLinkError::Tampered);
+        let redirected = reencode(&link, |wire| wire.relays = vec![relay().to_vec()]);
+        assert_eq!(GuestLink::parse(&redirected, NOW).unwrap_err(), GuestLinkError::Tampered);
+        let other = GuestLink::create(&creator, "room", Permission::Write, NOW + 1000, Vec::new()).unwrap().encode();
+        let swapped = reencode(&link, |wire| {
+            let other: WireLink = postcard::from_bytes(&URL_SAFE_NO_PAD.decode(&other).unwrap()).unwrap();
+            wire.link_secret = other.link_secret;
+        });
+        assert_eq!(GuestLink::parse(&swapped, NOW).unwrap_err(), GuestLinkError::Tampered);
+
+        // Signed again by someone other than the creator named in the token
+        let stranger = Keypair::generate_ed25519();
+        let resigned = reencode(&link, |wire| {
+            wire.signature = stranger.sign(&payload(&wire.token, &wire.relays)).unwrap();
+        });
+        assert_eq!(GuestLink::parse(&resigned, NOW).unwrap_err(), GuestLinkError::Tampered);
+
+        assert_eq!(GuestLink::parse("not a link!", NOW).unwrap_err(), GuestLinkError::Malformed);
+        assert_eq!(GuestLink::parse(&link[..link.len() / 2], NOW).unwrap_err(), GuestLinkError::Malformed);
+        assert_eq!(GuestLink::parse(&"A".repeat(MAX_GUEST_LINK_LEN + 1), NOW).unwrap_err(), GuestLinkError::Malformed);
+    }
+}
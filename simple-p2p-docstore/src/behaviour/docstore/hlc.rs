@@ -0,0 +1,393 @@
+//! Hybrid logical clocks and per-document vector clocks used to order updates.
+//!
+//! An [`Hlc`] tracks wall-clock time closely but never runs backwards: if the local clock
+//! jumps back, or a peer's clock is ahead, the logical counter takes over. Ties between
+//! nodes are broken by the node id, and updates under equal timestamps by their content,
+//! see [`order`](super::order), so last-writer-wins picks the same winner everywhere.
+//! A peer's clock more than [`DEFAULT_MAX_DRIFT`] ahead of ours is not followed, and its
+//! updates are not accepted either, or it would win every merge from then on.
+//!
+//! A [`VectorClock`] records how many updates from each node a document state has seen;
+//! two updates whose clocks are incomparable were written concurrently. An update's own
+//! entry in its stamp's clock numbers it among its author's updates to the document,
+//! which is how receivers tell a late update (see [`Freshness`]) from a replayed one.
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::time::Duration;
+
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::DocUpdate;
+
+/// How far ahead of the local wall clock a peer's timestamps may be.
+pub const DEFAULT_MAX_DRIFT: Duration = Duration::from_secs(60);
+
+/// Stamps a [`ReplayGuard`] remembers per document and author to recognise repeats.
+const RECENT_STAMPS: usize = 64;
+
+/// Hybrid logical timestamp. Ordered by wall time, then logical counter, then node id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Hlc {
+    /// Unix milliseconds.
+    pub wall_ms: u64,
+    pub logical: u32,
+    pub node: u64,
+}
+
+/// Compact node id for clocks, derived from the peer id.
+pub fn node_id(peer_id: &PeerId) -> u64 {
+    let digest = Sha256::digest(peer_id.to_bytes());
+    u64::from_be_bytes(digest[..8].try_into().expect("8 bytes"))
+}
+
+/// A node's hybrid logical clock.
+#[derive(Debug, Clone)]
+pub struct HlcClock {
+    last: Hlc,
+    max_drift: Duration,
+}
+
+impl HlcClock {
+    pub fn new(node: u64) -> Self {
+        Self { last: Hlc { wall_ms: 0, logical: 0, node }, max_drift: DEFAULT_MAX_DRIFT }
+    }
+
+    /// Follow peers' timestamps up to `max_drift` ahead of the wall clock, instead of
+    /// [`DEFAULT_MAX_DRIFT`].
+    pub fn with_max_drift(mut self, max_drift: Duration) -> Self {
+        self.max_drift = max_drift;
+        self
+    }
+
+    /// Whether `remote` is further ahead of `wall_ms` than the clock follows.
+    pub fn is_too_far_ahead(&self, remote: &Hlc, wall_ms: u64) -> bool {
+        let max_drift_ms = u64::try_from(self.max_drift.as_millis()).unwrap_or(u64::MAX);
+        remote.wall_ms > wall_ms.saturating_add(max_drift_ms)
+    }
+
+    pub fn for_peer(peer_id: &PeerId) -> Self {
+        Self::new(node_id(peer_id))
+    }
+
+    pub fn node(&self) -> u64 {
+        self.last.node
+    }
+
+    /// Timestamp for a local event.
+    pub fn now(&mut self) -> Hlc {
+        self.tick(wall_ms())
+    }
+
+    /// Like [`HlcClock::now`] with an explicit wall-clock reading.
+    pub fn tick(&mut self, wall_ms: u64) -> Hlc {
+        if wall_ms > self.last.wall_ms {
+            self.set(wall_ms, 0)
+        } else {
+            self.bump(self.last.wall_ms, self.last.logical)
+        }
+    }
+
+    /// Advance past a timestamp received from a peer.
+    pub fn observe(&mut self, remote: &Hlc) -> Hlc {
+        self.observe_at(remote, wall_ms())
+    }
+
+    /// Like [`HlcClock::observe`] with an explicit wall-clock reading. A timestamp too far
+    /// ahead (see [`HlcClock::with_max_drift`]) only ticks the clock.
+    pub fn observe_at(&mut self, remote: &Hlc, wall_ms: u64) -> Hlc {
+        if self.is_too_far_ahead(remote, wall_ms) {
+            return self.tick(wall_ms);
+        }
+        let last = self.last;
+        let max_wall = wall_ms.max(last.wall_ms).max(remote.wall_ms);
+        if max_wall == wall_ms && wall_ms > last.wall_ms && wall_ms > remote.wall_ms {
+            return self.set(wall_ms, 0);
+        }
+        let logical = match (max_wall == last.wall_ms, max_wall == remote.wall_ms) {
+            (true, true) => last.logical.max(remote.logical),
+            (true, false) => last.logical,
+            (false, _) => remote.logical,
+        };
+        self.bump(max_wall, logical)
+    }
+
+    /// `logical + 1` at `wall_ms`, spilling into the wall time if the counter is exhausted.
+    /// At the very end of time the clock stays where it is rather than wrap around.
+    fn bump(&mut self, wall_ms: u64, logical: u32) -> Hlc {
+        match (logical.checked_add(1), wall_ms.checked_add(1)) {
+            (Some(logical), _) => self.set(wall_ms, logical),
+            (None, Some(wall_ms)) => self.set(wall_ms, 0),
+            (None, None) => self.set(wall_ms, logical),
+        }
+    }
+
+    fn set(&mut self, wall_ms: u64, logical: u32) -> Hlc {
+        self.last = Hlc { wall_ms, logical, node: self.last.node };
+        self.last
+    }
+}
+
+fn wall_ms() -> u64 {
+    web_time::SystemTime::now()
+        .duration_since(web_time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Number of updates seen from each node.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VectorClock(BTreeMap<u64, u64>);
+
+impl VectorClock {
+    pub fn get(&self, node: u64) -> u64 {
+        self.0.get(&node).copied().unwrap_or(0)
+    }
+
+    /// Counts come from peers, so they saturate rather than overflow.
+    pub fn increment(&mut self, node: u64) {
+        let count = self.0.entry(node).or_default();
+        *count = count.saturating_add(1);
+    }
+
+    /// Updates seen from all nodes together, which is how far a node without a store
+    /// got with the document.
+    pub fn total(&self) -> u64 {
+        self.0.values().fold(0, |total, count| total.saturating_add(*count))
+    }
+
+    /// Pointwise maximum.
+    pub fn merge(&mut self, other: &VectorClock) {
+        for (&node, &count) in &other.0 {
+            let entry = self.0.entry(node).or_default();
+            *entry = (*entry).max(count);
+        }
+    }
+
+    /// Causal order between two clocks; `None` means they are concurrent.
+    pub fn compare(&self, other: &VectorClock) -> Option<Ordering> {
+        let mut ord = Ordering::Equal;
+        for node in self.0.keys().chain(other.0.keys()) {
+            match (ord, self.get(*node).cmp(&other.get(*node))) {
+                (_, Ordering::Equal) => {}
+                (Ordering::Equal, o) => ord = o,
+                (a, b) if a != b => return None,
+                _ => {}
+            }
+        }
+        Some(ord)
+    }
+}
+
+/// Ordering metadata attached to an outgoing update. The HLC strictly increases across
+/// everything one node publishes, and the author's entry in the clock across its updates
+/// to the document.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Stamp {
+    pub hlc: Hlc,
+    /// The author's view of the document, including this update.
+    pub clock: VectorClock,
+}
+
+impl Stamp {
+    /// True if the stamp was issued by `peer_id`'s clock.
+    pub fn is_from(&self, peer_id: &PeerId) -> bool {
+        self.hlc.node == node_id(peer_id)
+    }
+
+    /// The update's place among its author's updates to the document, from 1.
+    pub fn seq(&self) -> u64 {
+        self.clock.get(self.hlc.node)
+    }
+
+    /// Stamp the next local update to a document whose current clock is `doc_clock`.
+    pub fn next(clock: &mut HlcClock, doc_clock: &VectorClock) -> Self {
+        Self::next_at(clock, doc_clock, wall_ms())
+    }
+
+    /// Like [`Stamp::next`] with an explicit wall-clock reading.
+    pub fn next_at(clock: &mut HlcClock, doc_clock: &VectorClock, wall_ms: u64) -> Self {
+        let mut vc = doc_clock.clone();
+        vc.increment(clock.node());
+        Self { hlc: clock.tick(wall_ms), clock: vc }
+    }
+}
+
+/// How a received update relates to what was already applied from its author.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Freshness {
+    /// Not applied yet, however late it arrives. Unstamped updates always are.
+    Fresh,
+    /// Exactly an update applied before: a replay.
+    Repeat,
+    /// In a place of the author's sequence already taken, by an update no longer at hand
+    /// to compare it with. Dropped without blaming whoever passed it on.
+    Stale,
+    /// Stamped by someone other than the peer that signed it.
+    Forged,
+}
+
+/// Which of one author's updates to a document were applied, by [`Stamp::seq`]: all of
+/// them up to a watermark, and the ones above it that arrived early.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuthorSeqs {
+    contiguous: u64,
+    above: BTreeSet<u64>,
+}
+
+impl AuthorSeqs {
+    /// Everything up to and including `seq` applied.
+    pub fn up_to(seq: u64) -> Self {
+        Self { contiguous: seq, above: BTreeSet::new() }
+    }
+
+    pub fn contains(&self, seq: u64) -> bool {
+        seq <= self.contiguous || self.above.contains(&seq)
+    }
+
+    pub fn insert(&mut self, seq: u64) {
+        if seq <= self.contiguous {
+            return;
+        }
+        self.above.insert(seq);
+        while let Some(next) = self.contiguous.checked_add(1).filter(|next| self.above.remove(next)) {
+            self.contiguous = next;
+        }
+    }
+}
+
+/// In-memory replay check for nodes without a store (browsers): remembers which updates
+/// were seen per document and author, and the latest stamps, for the lifetime of the
+/// process.
+#[derive(Debug, Default)]
+pub struct ReplayGuard {
+    seen: HashMap<(String, u64), (AuthorSeqs, VecDeque<Hlc>)>,
+}
+
+impl ReplayGuard {
+    /// See `DocStore::freshness`; a repeat is one of the last [`RECENT_STAMPS`] stamps.
+    pub fn freshness(&self, source: &PeerId, update: &DocUpdate) -> Freshness {
+        let Some(stamp) = &update.stamp else {
+            return Freshness::Fresh;
+        };
+        if !stamp.is_from(source) {
+            return Freshness::Forged;
+        }
+        match self.seen.get(&(update.doc_id.clone(), stamp.hlc.node)) {
+            Some((seqs, _)) if !seqs.contains(stamp.seq()) => Freshness::Fresh,
+            Some((_, recent)) if recent.contains(&stamp.hlc) => Freshness::Repeat,
+            Some(_) => Freshness::Stale,
+            None => Freshness::Fresh,
+        }
+    }
+
+    pub fn record(&mut self, update: &DocUpdate) {
+        if let Some(stamp) = &update.stamp {
+            let (seqs, recent) = self.seen.entry((update.doc_id.clone(), stamp.hlc.node)).or_default();
+            seqs.insert(stamp.seq());
+            if recent.len() >= RECENT_STAMPS {
+                recent.pop_front();
+            }
+            recent.push_back(stamp.hlc);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hlc_is_monotonic_when_the_wall_clock_jumps_back() {
+        let mut clock = HlcClock::new(1);
+        let a = clock.tick(1_000);
+        let b = clock.tick(400);
+        let c = clock.tick(400);
+        assert!(a < b && b < c);
+        assert_eq!(c.wall_ms, 1_000);
+        assert_eq!(clock.tick(2_000), Hlc { wall_ms: 2_000, logical: 0, node: 1 });
+    }
+
+    #[test]
+    fn received_timestamps_advance_the_clock() {
+        let mut clock = HlcClock::new(1);
+        clock.tick(1_000);
+        let remote = Hlc { wall_ms: 5_000, logical: 7, node: 2 };
+        let seen = clock.observe_at(&remote, 1_001);
+        assert!(seen > remote);
+        assert!(clock.tick(1_002) > seen);
+    }
+
+    #[test]
+    fn far_future_timestamps_are_not_followed_and_nothing_overflows() {
+        let mut clock = HlcClock::new(1).with_max_drift(Duration::from_secs(10));
+        clock.tick(1_000);
+        let far = Hlc { wall_ms: 11_001, logical: 0, node: 2 };
+        assert!(clock.is_too_far_ahead(&far, 1_000));
+        assert_eq!(clock.observe_at(&far, 1_000), Hlc { wall_ms: 1_000, logical: 1, node: 1 });
+        let end = Hlc { wall_ms: u64::MAX, logical: u32::MAX, node: 2 };
+        assert!(clock.observe_at(&end, 1_000).wall_ms == 1_000);
+
+        // Even a clock at the very end does not wrap
+        let mut clock = HlcClock::new(1).with_max_drift(Duration::MAX);
+        let last = clock.observe_at(&end, 1_000);
+        assert_eq!(last, Hlc { wall_ms: u64::MAX, logical: u32::MAX, node: 1 });
+        assert!(clock.tick(1_000) >= last);
+
+        let mut vc = VectorClock::default();
+        vc.merge(&VectorClock([(1, u64::MAX), (2, u64::MAX)].into()));
+        vc.increment(1);
+        assert_eq!((vc.get(1), vc.total()), (u64::MAX, u64::MAX));
+    }
+
+    #[test]
+    fn late_updates_are_fresh_and_repeats_are_not() {
+        let author = PeerId::random();
+        let mut clock = HlcClock::for_peer(&author);
+        let mut doc = VectorClock::default();
+        let updates: Vec<DocUpdate> = (0..3)
+            .map(|i| {
+                let stamp = Stamp::next_at(&mut clock, &doc, 1_000 + i);
+                doc = stamp.clock.clone();
+                DocUpdate::new("doc", vec![i as u8]).with_stamp(stamp)
+            })
+            .collect();
+        let mut guard = ReplayGuard::default();
+        guard.record(&updates[2]);
+        assert_eq!(guard.freshness(&author, &updates[0]), Freshness::Fresh);
+        guard.record(&updates[0]);
+        assert_eq!(guard.freshness(&author, &updates[0]), Freshness::Repeat);
+        assert_eq!(guard.freshness(&author, &updates[1]), Freshness::Fresh);
+        assert_eq!(guard.freshness(&PeerId::random(), &updates[1]), Freshness::Forged);
+        // Another update in a place already taken
+        let mut other = updates[2].clone();
+        other.stamp.as_mut().unwrap().hlc.logical += 1;
+        assert_eq!(guard.freshness(&author, &other), Freshness::Stale);
+
+        let mut seqs = AuthorSeqs::default();
+        seqs.insert(3);
+        seqs.insert(1);
+        assert!(seqs.contains(1) && !seqs.contains(2) && seqs.contains(3));
+        seqs.insert(2);
+        assert_eq!(seqs, AuthorSeqs::up_to(3));
+    }
+
+    #[test]
+    fn vector_clocks_detect_concurrency() {
+        let mut a = VectorClock::default();
+        a.increment(1);
+        let mut b = a.clone();
+        b.increment(2);
+        assert_eq!(b.compare(&a), Some(Ordering::Greater));
+        assert_eq!(a.compare(&b), Some(Ordering::Less));
+
+        let mut c = a.clone();
+        c.increment(3);
+        assert_eq!(b.compare(&c), None);
+        b.merge(&c);
+        assert_eq!(b.compare(&c), Some(Ordering::Greater));
+        assert_eq!(b.total(), 3);
+    }
+}
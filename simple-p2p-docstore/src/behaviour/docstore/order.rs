@@ -0,0 +1,73 @@
+//! The total order of stamped updates, which every node has to agree on.
+//!
+//! Updates are ordered by their [`Hlc`]: wall time, then logical counter, then the
+//! author's clock node id (see [`node_id`](super::hlc::node_id), derived from the
+//! author's peer id bytes; the peer id itself is not on the wire). Updates whose HLCs are
+//! equal, as when two authors' peer ids hash to the same node id or one author publishes
+//! two different updates under one timestamp, are ordered by the SHA-256 of their
+//! payload. Two updates only compare equal if they carry the same payload under the same
+//! HLC, which makes them the same update.
+//!
+//! Stability guarantee: last-writer-wins picks its winner by this order on every node
+//! independently, and ordered delivery releases held updates by it, so two nodes that
+//! order differently diverge for good. The order is part of the protocol; changing it is
+//! a protocol break and needs a new envelope version.
+
+use std::cmp::Ordering;
+
+use super::hlc::{Hlc, Stamp};
+use super::snapshot::content_hash;
+
+/// An update's position in the total order, see the [module docs](self).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct UpdateOrder {
+    pub hlc: Hlc,
+    pub content_hash: [u8; 32],
+}
+
+impl UpdateOrder {
+    pub fn new(hlc: Hlc, payload: &[u8]) -> Self {
+        Self { hlc, content_hash: content_hash(payload) }
+    }
+
+    pub fn of(stamp: &Stamp, payload: &[u8]) -> Self {
+        Self::new(stamp.hlc, payload)
+    }
+}
+
+/// Compare two stamped updates by the total order, without hashing the payloads unless
+/// the HLCs tie.
+pub fn compare(a: &Hlc, a_payload: &[u8], b: &Hlc, b_payload: &[u8]) -> Ordering {
+    a.cmp(b).then_with(|| {
+        if a_payload == b_payload {
+            Ordering::Equal
+        } else {
+            content_hash(a_payload).cmp(&content_hash(b_payload))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hlc(wall_ms: u64, logical: u32, node: u64) -> Hlc {
+        Hlc { wall_ms, logical, node }
+    }
+
+    #[test]
+    fn orders_by_hlc_then_author_then_content() {
+        assert_eq!(compare(&hlc(1, 0, 9), b"b", &hlc(2, 0, 1), b"a"), Ordering::Less);
+        assert_eq!(compare(&hlc(2, 0, 1), b"z", &hlc(2, 1, 1), b"a"), Ordering::Less);
+        assert_eq!(compare(&hlc(2, 1, 1), b"z", &hlc(2, 1, 2), b"a"), Ordering::Less);
+        assert_eq!(compare(&hlc(2, 1, 2), b"same", &hlc(2, 1, 2), b"same"), Ordering::Equal);
+
+        // Equal HLCs: the content hash decides, the same way from either side
+        let (a, b): (&[u8], &[u8]) = (b"left", b"right");
+        let expected = content_hash(a).cmp(&content_hash(b));
+        assert_ne!(expected, Ordering::Equal);
+        assert_eq!(compare(&hlc(5, 0, 3), a, &hlc(5, 0, 3), b), expected);
+        assert_eq!(compare(&hlc(5, 0, 3), b, &hlc(5, 0, 3), a), expected.reverse());
+        assert_eq!(UpdateOrder::new(hlc(5, 0, 3), a).cmp(&UpdateOrder::new(hlc(5, 0, 3), b)), expected);
+    }
+}
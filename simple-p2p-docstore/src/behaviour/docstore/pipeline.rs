@@ -0,0 +1,905 @@
+//! What happens to a gossipsub message on the docstore behaviour between the swarm
+//! handing it over and the application hearing about it, kept apart from any swarm so
+//! both event loops share it and tests can drive it with recorded messages.
+//!
+//! [`MessagePipeline::handle_incoming`] validates the message, turns away replays,
+//! checks payloads against the registered [schemas](super::schema), dispatches on the
+//! topic, assembles snapshots and transactions, and applies updates to an
+//! [`UpdateSink`]. It answers with what the event loop has to act on, as a list of
+//! [`Incoming`] outputs: the verdict to report to gossipsub always comes first. Anything
+//! that needs the swarm, such as reporting the verdict, sending receipts, keeping
+//! connections alive or publishing snapshots, stays with the loop.
+//!
+//! Native nodes hand in their [`DocStore`], through a
+//! [`QuotaSink`](crate::store::quota::QuotaSink) when they enforce room quotas. Browsers
+//! keep no store, so they hand in a [`ClockLedger`], which only remembers the clocks seen
+//! this session.
+
+use std::collections::HashMap;
+
+use libp2p::gossipsub::{self, MessageAcceptance};
+use libp2p::PeerId;
+
+use super::announce::NetworkAnnouncement;
+use super::cas::{self, CasConflict};
+use super::envelope::{ack_requested, unsupported_version, DocUpdate, Envelope, CURRENT_PROTOCOL_VERSION};
+use super::hlc::{self, Freshness, HlcClock, ReplayGuard, VectorClock};
+use super::receipt::UpdateReceipt;
+use super::schema::{SchemaRegistry, SchemaViolation};
+use super::scope::ScopeFilter;
+use super::snapshot::{self, Snapshot, SnapshotAssembler, SnapshotChunk};
+use super::transaction::TransactionAssembler;
+use super::{decode_updates, validate_message, DocstoreGossipsubConfig};
+use crate::store::quota::QuotaExceeded;
+use crate::store::DocStore;
+
+/// Where the pipeline applies the updates it accepts.
+pub trait UpdateSink {
+    /// Whether `update`, published by `source`, was applied already.
+    fn freshness(&self, source: &PeerId, update: &DocUpdate) -> Freshness;
+
+    /// Whether there is room to store `updates`, the updates of one message signed by
+    /// `source`, all together. Sinks without quotas take everything.
+    fn admit(&self, _source: Option<&PeerId>, _updates: &[DocUpdate]) -> Result<(), QuotaExceeded> {
+        Ok(())
+    }
+
+    /// Count `updates`, once applied, against `source`, the peer that signed the message
+    /// carrying them. Sinks without quotas keep no count.
+    fn charge(&mut self, _source: &PeerId, _updates: &[DocUpdate]) {}
+
+    /// Apply an update and return the document's new version.
+    fn apply_update(&mut self, update: &DocUpdate) -> u64;
+
+    /// Apply a complete transaction. Returns each update's new version, in order.
+    fn apply_transaction(&mut self, updates: &[DocUpdate]) -> Vec<u64> {
+        updates.iter().map(|update| self.apply_update(update)).collect()
+    }
+
+    /// Whether `doc_id` is already at `version` or later, so a snapshot at `version`
+    /// brings nothing.
+    fn has_version(&self, doc_id: &str, version: u64) -> bool;
+
+    /// Returns whether the snapshot was taken.
+    fn install_snapshot(&mut self, snapshot: &Snapshot) -> bool;
+
+    /// Whether `peer_id` authored updates applied to `doc_id`, which trusts it with
+    /// snapshots of it. Sinks that cannot tell trust nobody.
+    fn is_author(&self, _doc_id: &str, _peer_id: &PeerId) -> bool {
+        false
+    }
+
+    /// The version CAS updates of `doc_id` are checked against, see [`cas`]. Sinks that
+    /// cannot tell return `None` and apply CAS updates unconditionally.
+    fn current_version(&self, _doc_id: &str) -> Option<u64> {
+        None
+    }
+}
+
+impl<S: DocStore + ?Sized> UpdateSink for S {
+    fn freshness(&self, source: &PeerId, update: &DocUpdate) -> Freshness {
+        DocStore::freshness(self, source, update)
+    }
+
+    fn apply_update(&mut self, update: &DocUpdate) -> u64 {
+        DocStore::apply_update(self, update)
+    }
+
+    fn apply_transaction(&mut self, updates: &[DocUpdate]) -> Vec<u64> {
+        DocStore::apply_transaction(self, updates)
+    }
+
+    fn has_version(&self, doc_id: &str, version: u64) -> bool {
+        version <= self.version(doc_id)
+    }
+
+    fn install_snapshot(&mut self, snapshot: &Snapshot) -> bool {
+        DocStore::install_snapshot(self, snapshot.clone())
+    }
+
+    fn is_author(&self, doc_id: &str, peer_id: &PeerId) -> bool {
+        self.author_hlc(doc_id, hlc::node_id(peer_id)).is_some()
+    }
+
+    fn current_version(&self, doc_id: &str) -> Option<u64> {
+        Some(self.version(doc_id))
+    }
+}
+
+/// The sink of nodes without a store: the clock of every document seen this session,
+/// best-effort replay protection and the latest snapshot version delivered per
+/// document. All of it is forgotten on reload.
+#[derive(Debug, Default)]
+pub struct ClockLedger {
+    clocks: HashMap<String, VectorClock>,
+    replay_guard: ReplayGuard,
+    snapshot_versions: HashMap<String, u64>,
+}
+
+impl ClockLedger {
+    /// The clock of `doc_id`, for stamping local updates.
+    pub fn clock_mut(&mut self, doc_id: &str) -> &mut VectorClock {
+        self.clocks.entry(doc_id.to_string()).or_default()
+    }
+}
+
+impl UpdateSink for ClockLedger {
+    fn freshness(&self, source: &PeerId, update: &DocUpdate) -> Freshness {
+        self.replay_guard.freshness(source, update)
+    }
+
+    /// Without a store, the version is how many updates of the document we saw.
+    fn apply_update(&mut self, update: &DocUpdate) -> u64 {
+        self.replay_guard.record(update);
+        if let Some(stamp) = &update.stamp {
+            self.clock_mut(&update.doc_id).merge(&stamp.clock);
+        }
+        self.clocks.get(&update.doc_id).map_or(0, VectorClock::total)
+    }
+
+    fn has_version(&self, doc_id: &str, version: u64) -> bool {
+        self.snapshot_versions.get(doc_id).is_some_and(|v| version <= *v)
+    }
+
+    fn install_snapshot(&mut self, snapshot: &Snapshot) -> bool {
+        self.snapshot_versions.insert(snapshot.doc_id.clone(), snapshot.version);
+        true
+    }
+}
+
+/// What the event loop has to act on after [`MessagePipeline::handle_incoming`].
+#[derive(Debug)]
+pub enum Incoming {
+    /// To report to gossipsub. Always the first output; a rejected message penalises the
+    /// peer that delivered it, and is the only output unless it broke a schema.
+    Verdict(MessageAcceptance),
+    /// An update of the message, or an assembled snapshot, published by `peer_id`,
+    /// failed its document type's validator. The message was rejected; a snapshot is
+    /// dropped.
+    SchemaViolation { peer_id: PeerId, violation: SchemaViolation },
+    /// An envelope of a version this build cannot read.
+    UnsupportedVersion { peer_id: PeerId, version: u8 },
+    Announcement { peer_id: PeerId, announcement: NetworkAnnouncement },
+    /// Updates from `peer_id` were ignored because storing them would go over a room's
+    /// or their author's quota; the message is not forwarded either. `doc_id` is the
+    /// update that did not fit, `ack_to` the publisher to tell with a receipt, if it
+    /// asked for receipts.
+    QuotaExceeded { peer_id: PeerId, doc_id: String, reason: QuotaExceeded, ack_to: Option<PeerId> },
+    /// A CAS update from `peer_id` expected its document at another version and was
+    /// ignored; the message is not forwarded either. `ack_to` is the publisher to send a
+    /// conflict receipt to, if it asked for receipts.
+    CasConflict { peer_id: PeerId, conflict: CasConflict, ack_to: Option<PeerId> },
+    /// Signed correctly, but most receipts are for someone else.
+    Receipt(UpdateReceipt),
+    SnapshotInstalled(Snapshot),
+    /// Applied to the sink, bringing the document to `version`. `ack_to` is the
+    /// publisher to send a receipt to, if it asked for one.
+    UpdateApplied { update: DocUpdate, version: u64, ack_to: Option<PeerId> },
+    /// Every update of the transaction was applied; they come just before.
+    TransactionApplied { id: u64, doc_ids: Vec<String> },
+    /// A message on an update topic, to deliver as is. Comes last, after the updates it
+    /// carried, if any.
+    Message,
+}
+
+/// Turns received messages into [`Incoming`] outputs, see the [module docs](self).
+#[derive(Debug)]
+pub struct MessagePipeline {
+    config: DocstoreGossipsubConfig,
+    hlc: HlcClock,
+    snapshots: SnapshotAssembler,
+    transactions: TransactionAssembler,
+    schemas: SchemaRegistry,
+    /// Relays only, see [`confine_local_scope`](Self::confine_local_scope).
+    scope: Option<ScopeFilter>,
+}
+
+impl MessagePipeline {
+    pub fn new(config: DocstoreGossipsubConfig, local_peer_id: &PeerId) -> Self {
+        Self {
+            config,
+            hlc: HlcClock::for_peer(local_peer_id).with_max_drift(config.max_clock_drift),
+            snapshots: SnapshotAssembler::new(config.max_document_size),
+            transactions: TransactionAssembler::default(),
+            schemas: SchemaRegistry::default(),
+            scope: None,
+        }
+    }
+
+    /// Ignore local-scope messages other relays pass on, as relays do, see
+    /// [`super::scope`]. Feed it which peers are relays through
+    /// [`scope_filter_mut`](Self::scope_filter_mut).
+    pub fn confine_local_scope(&mut self) {
+        self.scope.get_or_insert_with(ScopeFilter::default);
+    }
+
+    pub fn scope_filter_mut(&mut self) -> Option<&mut ScopeFilter> {
+        self.scope.as_mut()
+    }
+
+    pub fn config(&self) -> &DocstoreGossipsubConfig {
+        &self.config
+    }
+
+    /// The validators received payloads are checked against.
+    pub fn schemas_mut(&mut self) -> &mut SchemaRegistry {
+        &mut self.schemas
+    }
+
+    /// The local clock, advanced by every stamped update received; for stamping local
+    /// updates.
+    pub fn hlc_mut(&mut self) -> &mut HlcClock {
+        &mut self.hlc
+    }
+
+    /// Only the verdict on `message` (and the version it could not read, if ignored),
+    /// for messages the caller routes itself.
+    pub fn validate<S: UpdateSink + ?Sized>(
+        &self,
+        sink: &S,
+        propagation_source: PeerId,
+        message: &gossipsub::Message,
+    ) -> Vec<Incoming> {
+        let mut acceptance = validate_message(&self.config, message);
+        let out_of_scope = self.scope.as_ref().is_some_and(|scope| scope.is_out_of_scope(&propagation_source, &message.data));
+        if matches!(acceptance, MessageAcceptance::Accept) && out_of_scope {
+            tracing::trace!("Ignoring a local-scope message passed on by relay {}", propagation_source);
+            return vec![Incoming::Verdict(MessageAcceptance::Ignore)];
+        }
+        // A clock running ahead is not necessarily malice, but its updates would win every
+        // merge until ours caught up
+        if matches!(acceptance, MessageAcceptance::Accept) && self.is_from_the_future(message) {
+            tracing::debug!("Ignoring an update from {} stamped too far ahead of our clock", propagation_source);
+            acceptance = MessageAcceptance::Ignore;
+        }
+        if matches!(acceptance, MessageAcceptance::Accept) {
+            match self.freshness(sink, message) {
+                Freshness::Fresh => {}
+                Freshness::Stale => {
+                    tracing::debug!("Ignoring a stale update from {}", propagation_source);
+                    acceptance = MessageAcceptance::Ignore;
+                }
+                Freshness::Repeat | Freshness::Forged => {
+                    tracing::warn!("Rejecting replayed update from {}", propagation_source);
+                    acceptance = MessageAcceptance::Reject;
+                }
+            }
+        }
+        let violation = match acceptance {
+            MessageAcceptance::Accept => self.check_schemas(message).err(),
+            _ => None,
+        };
+        if let Some(violation) = &violation {
+            tracing::warn!("Rejecting update from {}: {}", propagation_source, violation);
+            acceptance = MessageAcceptance::Reject;
+        }
+        let over_quota = match acceptance {
+            MessageAcceptance::Accept => self.admit(sink, message).err(),
+            _ => None,
+        };
+        if over_quota.is_some() {
+            acceptance = MessageAcceptance::Ignore;
+        }
+        // Losing a race is not misbehaviour, so nobody is penalised for it
+        let conflict = match acceptance {
+            MessageAcceptance::Accept => self.check_cas(sink, message).err(),
+            _ => None,
+        };
+        if conflict.is_some() {
+            acceptance = MessageAcceptance::Ignore;
+        }
+        let ignored = matches!(acceptance, MessageAcceptance::Ignore);
+        let peer_id = message.source.unwrap_or(propagation_source);
+        let mut out = vec![Incoming::Verdict(acceptance)];
+        if let Some(violation) = violation {
+            out.push(Incoming::SchemaViolation { peer_id, violation });
+        } else if let Some((doc_id, reason)) = over_quota {
+            tracing::debug!("Ignoring updates from {}: {}", peer_id, reason);
+            let ack_to = message.source.filter(|_| ack_requested(&message.data));
+            out.push(Incoming::QuotaExceeded { peer_id, doc_id, reason, ack_to });
+        } else if let Some(conflict) = conflict {
+            tracing::debug!("Ignoring CAS update from {}: {}", peer_id, conflict);
+            let ack_to = message.source.filter(|_| ack_requested(&message.data));
+            out.push(Incoming::CasConflict { peer_id, conflict, ack_to });
+        } else if let Some(version) = unsupported_version(&message.data).filter(|_| ignored) {
+            tracing::debug!("Ignoring envelope v{} from {}", version, peer_id);
+            out.push(Incoming::UnsupportedVersion { peer_id, version });
+        }
+        out
+    }
+
+    /// Validate `message`, delivered by `propagation_source`, and take in whatever it
+    /// carries, see the [module docs](self).
+    pub fn handle_incoming<S: UpdateSink + ?Sized>(
+        &mut self,
+        sink: &mut S,
+        propagation_source: PeerId,
+        message: &gossipsub::Message,
+    ) -> Vec<Incoming> {
+        let mut out = self.validate(sink, propagation_source, message);
+        if !matches!(out[0], Incoming::Verdict(MessageAcceptance::Accept)) {
+            return out;
+        }
+        let topics = &self.config.topics;
+        if message.topic == topics.snapshots().hash() {
+            // Chunks of unsigned snapshots are passed on, but nobody is trusted with them
+            if let Some(publisher) = message.source {
+                self.snapshot_chunk(sink, publisher, &message.data, &mut out);
+            }
+        } else if message.topic == topics.announce().hash() {
+            // Validation accepted it; verifying again names the announcer
+            let announcement = NetworkAnnouncement::decode(&message.data);
+            if let Ok((peer_id, announcement)) = announcement.and_then(|a| {
+                a.verify(&self.config.announcers, crate::store::now_ms()).map(|peer_id| (peer_id, a))
+            }) {
+                out.push(Incoming::Announcement { peer_id, announcement });
+            }
+        } else if message.topic == topics.receipts().hash() {
+            if let Ok(receipt) = UpdateReceipt::decode(&message.data) {
+                out.push(Incoming::Receipt(receipt));
+            }
+        } else {
+            self.updates(sink, propagation_source, message, &mut out);
+            out.push(Incoming::Message);
+        }
+        out
+    }
+
+    /// Replay protection on top of gossipsub's duplicate cache, which forgets message ids
+    /// after a while: no stamped update may have been applied already, though it may
+    /// arrive after later ones of its author, and anonymous messages carry no stamped
+    /// updates at all. The message is as fresh as its least fresh update.
+    fn freshness<S: UpdateSink + ?Sized>(&self, sink: &S, message: &gossipsub::Message) -> Freshness {
+        if message.topic == self.config.topics.snapshots().hash() {
+            return Freshness::Fresh;
+        }
+        let Ok(updates) = decode_updates(&message.data) else {
+            return Freshness::Fresh;
+        };
+        let verdicts = updates.iter().map(|u| match &message.source {
+            Some(source) => sink.freshness(source, u),
+            None if u.stamp.is_some() => Freshness::Forged,
+            None => Freshness::Fresh,
+        });
+        let worst = |verdict: &Freshness| match verdict {
+            Freshness::Fresh => 0,
+            Freshness::Stale => 1,
+            Freshness::Repeat | Freshness::Forged => 2,
+        };
+        verdicts.max_by_key(worst).unwrap_or(Freshness::Fresh)
+    }
+
+    /// Whether an update of the message is stamped further ahead of the wall clock than
+    /// [`max_clock_drift`](DocstoreGossipsubConfig::max_clock_drift).
+    fn is_from_the_future(&self, message: &gossipsub::Message) -> bool {
+        let Ok(updates) = decode_updates(&message.data) else {
+            return false;
+        };
+        let now_ms = crate::store::now_ms();
+        updates.iter().filter_map(|u| u.stamp.as_ref()).any(|stamp| self.hlc.is_too_far_ahead(&stamp.hlc, now_ms))
+    }
+
+    /// Quotas apply to the updates of a message, not to snapshots: those replace history
+    /// rather than add to it. They are charged to the message's signer, stamped or not.
+    /// Names the document of the first update that does not fit.
+    fn admit<S: UpdateSink + ?Sized>(
+        &self,
+        sink: &S,
+        message: &gossipsub::Message,
+    ) -> Result<(), (String, QuotaExceeded)> {
+        let topics = &self.config.topics;
+        if [topics.snapshots(), topics.announce(), topics.receipts()].iter().any(|t| t.hash() == message.topic) {
+            return Ok(());
+        }
+        let Ok(updates) = decode_updates(&message.data) else {
+            return Ok(());
+        };
+        let source = message.source.as_ref();
+        if sink.admit(source, &updates).is_ok() {
+            return Ok(());
+        }
+        let first_over = (1..=updates.len()).find_map(|n| Some((n, sink.admit(source, &updates[..n]).err()?)));
+        let (n, reason) = first_over.expect("the whole message is over");
+        Err((updates[n - 1].doc_id.clone(), reason))
+    }
+
+    /// A CAS update against the sink's version of its document.
+    fn check_cas<S: UpdateSink + ?Sized>(&self, sink: &S, message: &gossipsub::Message) -> Result<(), CasConflict> {
+        let topics = &self.config.topics;
+        if [topics.snapshots(), topics.announce(), topics.receipts()].iter().any(|t| t.hash() == message.topic) {
+            return Ok(());
+        }
+        match Envelope::decode(&message.data) {
+            Ok(Envelope::Cas { update, expected_version }) => match sink.current_version(&update.doc_id) {
+                Some(current_version) => cas::check(&update.doc_id, expected_version, current_version),
+                None => Ok(()),
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// The updates of a message on an update topic, checked under the version of the
+    /// envelope carrying them.
+    fn check_schemas(&self, message: &gossipsub::Message) -> Result<(), SchemaViolation> {
+        let topics = &self.config.topics;
+        if self.schemas.is_empty()
+            || [topics.snapshots(), topics.announce(), topics.receipts()].iter().any(|t| t.hash() == message.topic)
+        {
+            return Ok(());
+        }
+        let Ok(updates) = decode_updates(&message.data) else {
+            return Ok(());
+        };
+        let version = message.data.first().copied().unwrap_or(CURRENT_PROTOCOL_VERSION);
+        updates.iter().try_for_each(|u| self.schemas.check(u, version))
+    }
+
+    fn snapshot_chunk<S: UpdateSink + ?Sized>(
+        &mut self,
+        sink: &mut S,
+        publisher: PeerId,
+        data: &[u8],
+        out: &mut Vec<Incoming>,
+    ) {
+        let Ok(chunk) = SnapshotChunk::decode(data) else {
+            return;
+        };
+        // Don't bother assembling snapshots we would ignore anyway
+        if sink.has_version(&chunk.doc_id, chunk.version) {
+            return;
+        }
+        // Forwarded for peers that trust the publisher, but never buffered for nothing
+        if !self.is_trusted_snapshot(sink, &publisher, &chunk) {
+            tracing::debug!("Ignoring a chunk of {}@{} published by {}", chunk.doc_id, chunk.version, publisher);
+            return;
+        }
+        match self.snapshots.push(chunk) {
+            Ok(Some(snapshot)) => {
+                // Snapshots carry no envelope; they are held to the current schemas
+                let checked = self.schemas.check_payload(&snapshot.doc_id, &snapshot.bytes, CURRENT_PROTOCOL_VERSION);
+                if let Err(violation) = checked {
+                    tracing::warn!("Dropping snapshot from {}: {}", publisher, violation);
+                    out.push(Incoming::SchemaViolation { peer_id: publisher, violation });
+                } else if sink.install_snapshot(&snapshot) {
+                    out.push(Incoming::SnapshotInstalled(snapshot));
+                }
+            }
+            Ok(None) => {}
+            Err(e) => tracing::debug!("Dropping snapshot: {}", e),
+        }
+    }
+
+    /// A snapshot must be published by one of the document's authors or the configured
+    /// snapshot publishers, and go no more than
+    /// [`MAX_VERSION_JUMP`](snapshot::MAX_VERSION_JUMP) past the sink's version.
+    fn is_trusted_snapshot<S: UpdateSink + ?Sized>(&self, sink: &S, publisher: &PeerId, chunk: &SnapshotChunk) -> bool {
+        let trusted = self.config.snapshot_publishers.contains(publisher) || sink.is_author(&chunk.doc_id, publisher);
+        let within_reach = sink
+            .current_version(&chunk.doc_id)
+            .map_or(true, |version| chunk.version <= version.saturating_add(snapshot::MAX_VERSION_JUMP));
+        trusted && within_reach
+    }
+
+    /// Envelopes (including batches) are unpacked into one output per update;
+    /// transactions only once all of their parts are in.
+    fn updates<S: UpdateSink + ?Sized>(
+        &mut self,
+        sink: &mut S,
+        propagation_source: PeerId,
+        message: &gossipsub::Message,
+        out: &mut Vec<Incoming>,
+    ) {
+        match Envelope::decode(&message.data) {
+            Ok(Envelope::Transaction(part)) => {
+                let tx = match self.transactions.push(message.source, part) {
+                    Ok(Some(tx)) => tx,
+                    Ok(None) => return,
+                    Err(e) => {
+                        tracing::debug!("Dropping transaction part from {}: {}", propagation_source, e);
+                        return;
+                    }
+                };
+                self.observe(&tx.updates);
+                let versions = sink.apply_transaction(&tx.updates);
+                if let Some(source) = &message.source {
+                    sink.charge(source, &tx.updates);
+                }
+                let doc_ids = tx.doc_ids();
+                for (update, version) in tx.updates.into_iter().zip(versions) {
+                    out.push(Incoming::UpdateApplied { update, version, ack_to: None });
+                }
+                out.push(Incoming::TransactionApplied { id: tx.id, doc_ids });
+            }
+            Ok(envelope) => {
+                // Only the author can be acknowledged, so anonymous updates never are
+                let ack_to = message.source.filter(|_| ack_requested(&message.data));
+                for update in envelope.into_updates() {
+                    self.observe(std::slice::from_ref(&update));
+                    let version = sink.apply_update(&update);
+                    if let Some(source) = &message.source {
+                        sink.charge(source, std::slice::from_ref(&update));
+                    }
+                    out.push(Incoming::UpdateApplied { update, version, ack_to });
+                }
+            }
+            // Plain data
+            Err(_) => {}
+        }
+    }
+
+    fn observe(&mut self, updates: &[DocUpdate]) {
+        for stamp in updates.iter().filter_map(|u| u.stamp.as_ref()) {
+            self.hlc.observe(&stamp.hlc);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::behaviour::docstore::{
+        encode_batch, encode_cas_update, encode_doc_update, encode_doc_update_requesting_ack, encode_snapshot,
+        AnnouncementKind, Severity, Stamp, Transaction,
+    };
+    use crate::store::MemoryDocStore;
+    use libp2p::gossipsub::{MessageId, TopicHash};
+    use libp2p::identity::Keypair;
+
+    /// A publishing peer, stamping its updates like a node would.
+    struct Author {
+        key: Keypair,
+        hlc: HlcClock,
+        clocks: HashMap<String, VectorClock>,
+    }
+
+    impl Author {
+        fn new(seed: u8) -> Self {
+            let key = Keypair::ed25519_from_bytes([seed; 32]).unwrap();
+            let hlc = HlcClock::for_peer(&key.public().to_peer_id());
+            Self { key, hlc, clocks: HashMap::new() }
+        }
+
+        fn peer_id(&self) -> PeerId {
+            self.key.public().to_peer_id()
+        }
+
+        fn update(&mut self, doc_id: &str, payload: &str, wall_ms: u64) -> DocUpdate {
+            let clock = self.clocks.entry(doc_id.to_string()).or_default();
+            let stamp = Stamp::next_at(&mut self.hlc, clock, wall_ms);
+            clock.merge(&stamp.clock);
+            DocUpdate { doc_id: doc_id.into(), payload: payload.into(), stamp: Some(stamp) }
+        }
+    }
+
+    fn message(source: Option<PeerId>, topic: TopicHash, data: Vec<u8>) -> gossipsub::Message {
+        gossipsub::Message { source, data, sequence_number: None, topic }
+    }
+
+    /// A conversation as the event loops saw it: updates, a replay and a forgery, a
+    /// newer envelope version, a transaction in two parts, a snapshot (twice, then one
+    /// from a stranger), a receipt and an announcement.
+    fn recording(
+        alice: &mut Author,
+        bob: &Author,
+        local: &PeerId,
+        cfg: &DocstoreGossipsubConfig,
+    ) -> Vec<(PeerId, gossipsub::Message)> {
+        let (a, b) = (alice.peer_id(), bob.peer_id());
+        let updates = cfg.topics.updates().hash();
+        let first = alice.update("notes", "v1", 1_000);
+        let mut tx = Transaction::new();
+        tx.id = 7;
+        tx.add_update(alice.update("notes", "v2", 2_000));
+        tx.add_update(alice.update("todo", "milk", 2_001));
+        // One part per update
+        let parts: Vec<_> = tx.parts(1).into_iter().map(|part| Envelope::Transaction(part).encode_with(&cfg.codec())).collect();
+        let snapshot = encode_snapshot(cfg, &Snapshot::new("notes", 10, b"snapshot".to_vec())).unwrap().remove(0);
+        let stranger = Author::new(3).peer_id();
+        let forged = encode_snapshot(cfg, &Snapshot::new("todo", 10, b"forged".to_vec())).unwrap().remove(0);
+        let receipt = UpdateReceipt::sign(&bob.key, local, &MessageId::new(b"m1"), "notes", 3).unwrap();
+        let announcement =
+            NetworkAnnouncement::sign(&bob.key, AnnouncementKind::Maintenance, Severity::Info, "reboot", 0, None).unwrap();
+        vec![
+            (a, message(Some(a), updates.clone(), b"hello".to_vec())),
+            (b, message(Some(a), updates.clone(), encode_doc_update_requesting_ack(cfg, first.clone()).unwrap())),
+            (b, message(Some(a), updates.clone(), encode_doc_update(cfg, first.clone()).unwrap())),
+            (b, message(Some(b), updates.clone(), encode_doc_update(cfg, Author::new(1).update("notes", "forged", 1_500)).unwrap())),
+            (b, message(Some(b), updates.clone(), vec![5, 0, 1, 2])),
+            (a, message(Some(a), updates.clone(), parts[0].clone())),
+            (a, message(Some(a), updates, parts[1].clone())),
+            (b, message(Some(b), cfg.topics.snapshots().hash(), snapshot.clone())),
+            (a, message(Some(b), cfg.topics.snapshots().hash(), snapshot)),
+            (b, message(Some(stranger), cfg.topics.snapshots().hash(), forged)),
+            (b, message(Some(b), cfg.topics.receipts().hash(), receipt.encode())),
+            (b, message(Some(b), cfg.topics.announce().hash(), announcement.encode())),
+        ]
+    }
+
+    fn transcript<S: UpdateSink>(sink: &mut S) -> Vec<String> {
+        let (mut alice, bob, local) = (Author::new(1), Author::new(2), PeerId::random());
+        let cfg = DocstoreGossipsubConfig {
+            announcers: [bob.peer_id()].into(),
+            snapshot_publishers: [bob.peer_id()].into(),
+            ..Default::default()
+        };
+        let alice_id = alice.peer_id();
+        let name = |peer: &PeerId| if *peer == alice_id { "alice" } else { "bob" };
+        let mut pipeline = MessagePipeline::new(cfg.clone(), &local);
+        let mut lines = Vec::new();
+        for (from, message) in recording(&mut alice, &bob, &local, &cfg) {
+            let outputs = pipeline.handle_incoming(sink, from, &message);
+            let line = outputs
+                .iter()
+                .map(|output| match output {
+                    Incoming::Verdict(acceptance) => format!("{:?}", acceptance).to_lowercase(),
+                    Incoming::UnsupportedVersion { peer_id, version } => format!("v{} from {}", version, name(peer_id)),
+                    Incoming::QuotaExceeded { peer_id, reason, .. } => format!("{} from {}", reason, name(peer_id)),
+                    Incoming::SchemaViolation { peer_id, violation } => format!("{} from {}", violation, name(peer_id)),
+                    Incoming::CasConflict { peer_id, conflict, .. } => format!("{} from {}", conflict, name(peer_id)),
+                    Incoming::Announcement { peer_id, announcement } => {
+                        format!("announcement {:?} from {}", announcement.text, name(peer_id))
+                    }
+                    Incoming::Receipt(receipt) => format!("receipt {}@{}", receipt.doc_id, receipt.applied_version),
+                    Incoming::SnapshotInstalled(s) => format!("snapshot {}@{}", s.doc_id, s.version),
+                    Incoming::UpdateApplied { update, version, ack_to } => format!(
+                        "{}@{} {:?}{}",
+                        update.doc_id,
+                        version,
+                        String::from_utf8_lossy(&update.payload),
+                        ack_to.map_or(String::new(), |peer| format!(" ack {}", name(&peer)))
+                    ),
+                    Incoming::TransactionApplied { id, doc_ids } => format!("tx {} {:?}", id, doc_ids),
+                    Incoming::Message => "message".into(),
+                })
+                .collect::<Vec<_>>();
+            lines.push(line.join(", "));
+        }
+        lines
+    }
+
+    const GOLDEN: &[&str] = &[
+        "accept, message",
+        r#"accept, notes@1 "v1" ack alice, message"#,
+        "reject",
+        "reject",
+        "ignore, v5 from bob",
+        "accept, message",
+        r#"accept, notes@2 "v2", todo@1 "milk", tx 7 ["notes", "todo"], message"#,
+        "accept, snapshot notes@10",
+        "accept",
+        "accept",
+        "accept, receipt notes@3",
+        r#"accept, announcement "reboot" from bob"#,
+    ];
+
+    #[test]
+    fn recorded_messages_replay_the_same_with_and_without_a_store() {
+        assert_eq!(transcript(&mut MemoryDocStore::default()), GOLDEN);
+        assert_eq!(transcript(&mut ClockLedger::default()), GOLDEN);
+    }
+
+    #[test]
+    fn snapshots_are_only_installed_from_authors_and_within_reach() {
+        let mut alice = Author::new(1);
+        let cfg = DocstoreGossipsubConfig::default();
+        let mut pipeline = MessagePipeline::new(cfg.clone(), &PeerId::random());
+        let mut store = MemoryDocStore::default();
+        let snapshots = cfg.topics.snapshots().hash();
+        let mut installs = |store: &mut MemoryDocStore, source: PeerId, version: u64| {
+            let data = encode_snapshot(&cfg, &Snapshot::new("notes", version, b"whole".to_vec())).unwrap().remove(0);
+            let out = pipeline.handle_incoming(store, source, &message(Some(source), snapshots.clone(), data));
+            out.iter().any(|output| matches!(output, Incoming::SnapshotInstalled(_)))
+        };
+
+        // Not an author yet
+        assert!(!installs(&mut store, alice.peer_id(), 5));
+        DocStore::apply_update(&mut store, &alice.update("notes", "v1", 1_000));
+        assert!(!installs(&mut store, Author::new(2).peer_id(), 5));
+        // An author, but not that far ahead
+        assert!(!installs(&mut store, alice.peer_id(), u64::MAX));
+        assert!(installs(&mut store, alice.peer_id(), 5));
+        assert_eq!(store.version("notes"), 5);
+    }
+
+    #[test]
+    fn received_stamps_advance_the_local_clock() {
+        let mut alice = Author::new(1);
+        let cfg = DocstoreGossipsubConfig::default();
+        let mut pipeline = MessagePipeline::new(cfg.clone(), &PeerId::random());
+        let mut ledger = ClockLedger::default();
+        let from = alice.peer_id();
+        let mut receive = |pipeline: &mut MessagePipeline, update: DocUpdate| {
+            let data = encode_doc_update(&cfg, update).unwrap();
+            let received = message(Some(from), cfg.topics.updates().hash(), data);
+            pipeline.handle_incoming(&mut ledger, from, &received)
+        };
+        let slightly_ahead = crate::store::now_ms() + cfg.max_clock_drift.as_millis() as u64 / 2;
+        receive(&mut pipeline, alice.update("notes", "v1", slightly_ahead));
+        assert!(pipeline.hlc_mut().tick(0).wall_ms >= slightly_ahead);
+
+        // A clock far ahead is neither followed nor allowed to win the merge
+        let out = receive(&mut pipeline, alice.update("notes", "v2", u64::MAX / 4));
+        assert!(matches!(out[..], [Incoming::Verdict(MessageAcceptance::Ignore)]));
+        assert!(pipeline.hlc_mut().tick(0).wall_ms < u64::MAX / 4);
+    }
+
+    #[test]
+    fn updates_over_a_room_quota_are_ignored() {
+        use crate::store::author_quota::AuthorQuotas;
+        use crate::store::quota::{QuotaSink, RoomQuota, RoomQuotas};
+
+        let mut alice = Author::new(1);
+        let cfg = DocstoreGossipsubConfig::default();
+        let mut pipeline = MessagePipeline::new(cfg.clone(), &PeerId::random());
+        let mut store = MemoryDocStore::default();
+        let mut quotas = RoomQuotas::new([("team".to_string(), RoomQuota { max_docs: Some(1), ..Default::default() })]);
+        let mut authors = AuthorQuotas::default();
+        let source = alice.peer_id();
+        let mut receive = |update: DocUpdate| {
+            let data = encode_doc_update(&cfg, update).unwrap();
+            let received = message(Some(source), cfg.topics.updates().hash(), data);
+            pipeline.handle_incoming(&mut QuotaSink::new(&mut store, &mut quotas, &mut authors), source, &received)
+        };
+        let out = receive(alice.update("team/a", "v1", 1_000));
+        assert!(matches!(out[..], [Incoming::Verdict(MessageAcceptance::Accept), Incoming::UpdateApplied { .. }, Incoming::Message]));
+
+        let out = receive(alice.update("team/b", "v1", 2_000));
+        assert!(matches!(
+            &out[..],
+            [Incoming::Verdict(MessageAcceptance::Ignore), Incoming::QuotaExceeded { reason: QuotaExceeded::Docs { .. }, .. }]
+        ));
+        assert_eq!(store.version("team/b"), 0);
+        assert_eq!(quotas.usage("team").docs, 1);
+    }
+
+    #[test]
+    fn unstamped_updates_are_held_to_their_signers_quota() {
+        use crate::store::author_quota::{AuthorQuota, AuthorQuotas};
+        use crate::store::quota::{QuotaSink, RoomQuotas};
+
+        let cfg = DocstoreGossipsubConfig::default();
+        let mut pipeline = MessagePipeline::new(cfg.clone(), &PeerId::random());
+        let mut store = MemoryDocStore::default();
+        let mut rooms = RoomQuotas::default();
+        let mut authors = AuthorQuotas::new(AuthorQuota { max_bytes: Some(10), ..Default::default() }, []);
+        let mallory = PeerId::random();
+        let mut receive = |updates: Vec<DocUpdate>| {
+            let data = encode_batch(&cfg, updates).unwrap().remove(0);
+            let received = message(Some(mallory), cfg.topics.updates().hash(), data);
+            pipeline.handle_incoming(&mut QuotaSink::new(&mut store, &mut rooms, &mut authors), mallory, &received)
+        };
+        // Each would fit on its own, all three don't
+        let out = receive((0..3).map(|i| DocUpdate::new(format!("doc-{i}"), vec![0; 4])).collect());
+        assert!(matches!(
+            &out[..],
+            [Incoming::Verdict(MessageAcceptance::Ignore), Incoming::QuotaExceeded { doc_id, reason: QuotaExceeded::AuthorBytes { bytes: 12, .. }, .. }]
+                if doc_id == "doc-2"
+        ));
+        let out = receive(vec![DocUpdate::new("a", vec![0; 8])]);
+        assert!(matches!(out[0], Incoming::Verdict(MessageAcceptance::Accept)));
+        let out = receive(vec![DocUpdate::new("b", vec![0; 8])]);
+        assert!(matches!(out[..], [Incoming::Verdict(MessageAcceptance::Ignore), Incoming::QuotaExceeded { .. }]));
+        assert_eq!(store.version("b"), 0);
+    }
+
+    #[test]
+    fn of_concurrent_cas_updates_one_applies() {
+        let (mut alice, mut bob) = (Author::new(1), Author::new(2));
+        let cfg = DocstoreGossipsubConfig::default();
+        let mut pipeline = MessagePipeline::new(cfg.clone(), &PeerId::random());
+        let mut store = MemoryDocStore::default();
+        let mut receive = |author: &mut Author, payload: &str, expected_version: u64, wall_ms: u64| {
+            let data = encode_cas_update(&cfg, author.update("lock", payload, wall_ms), expected_version).unwrap();
+            let received = message(Some(author.peer_id()), cfg.topics.updates().hash(), data);
+            pipeline.handle_incoming(&mut store, author.peer_id(), &received)
+        };
+        // Both saw the lock free at version 0
+        let first = receive(&mut alice, "alice", 0, 1_000);
+        let second = receive(&mut bob, "bob", 0, 1_000);
+        assert!(matches!(
+            &first[..],
+            [Incoming::Verdict(MessageAcceptance::Accept), Incoming::UpdateApplied { version: 1, ack_to: Some(_), .. }, Incoming::Message]
+        ));
+        let bob_id = bob.peer_id();
+        assert!(matches!(
+            &second[..],
+            [Incoming::Verdict(MessageAcceptance::Ignore), Incoming::CasConflict { conflict, ack_to: Some(to), .. }]
+                if conflict.current_version == 1 && *to == bob_id
+        ));
+        assert_eq!(store.version("lock"), 1);
+        assert_eq!(store.content("lock"), Some(b"alice".to_vec()));
+
+        // Retrying against the version it was told about works
+        let retry = receive(&mut bob, "bob", 1, 2_000);
+        assert!(matches!(retry[1], Incoming::UpdateApplied { version: 2, .. }));
+
+        // Without a store there is nothing to check against
+        let out = MessagePipeline::new(cfg.clone(), &PeerId::random()).handle_incoming(
+            &mut ClockLedger::default(),
+            alice.peer_id(),
+            &message(Some(alice.peer_id()), cfg.topics.updates().hash(), encode_cas_update(&cfg, alice.update("lock", "x", 3_000), 9).unwrap()),
+        );
+        assert!(matches!(out[1], Incoming::UpdateApplied { .. }));
+    }
+
+    #[test]
+    fn updates_breaking_a_schema_are_rejected() {
+        let mut alice = Author::new(1);
+        let cfg = DocstoreGossipsubConfig::default();
+        let mut pipeline = MessagePipeline::new(cfg.clone(), &PeerId::random());
+        let json_object: Box<dyn crate::behaviour::docstore::SchemaValidator> = Box::new(|_: &str, payload: &[u8]| {
+            if payload.starts_with(b"{") {
+                Ok(())
+            } else {
+                Err("not an object".to_string())
+            }
+        });
+        pipeline.schemas_mut().register("todo/", json_object);
+        let mut store = MemoryDocStore::default();
+        let source = alice.peer_id();
+        let mut receive = |update: DocUpdate| {
+            let data = encode_doc_update(&cfg, update).unwrap();
+            pipeline.handle_incoming(&mut store, source, &message(Some(source), cfg.topics.updates().hash(), data))
+        };
+
+        let out = receive(alice.update("todo/a", "{}", 1_000));
+        assert!(matches!(out[..], [Incoming::Verdict(MessageAcceptance::Accept), Incoming::UpdateApplied { .. }, Incoming::Message]));
+        let out = receive(alice.update("notes/a", "plain text", 2_000));
+        assert!(matches!(out[0], Incoming::Verdict(MessageAcceptance::Accept)));
+
+        let out = receive(alice.update("todo/a", "garbage", 3_000));
+        match &out[..] {
+            [Incoming::Verdict(MessageAcceptance::Reject), Incoming::SchemaViolation { peer_id, violation }] => {
+                assert_eq!(*peer_id, source);
+                assert_eq!((violation.doc_id.as_str(), violation.reason.as_str()), ("todo/a", "not an object"));
+            }
+            other => panic!("unexpected outputs {other:?}"),
+        }
+        assert_eq!(store.version("todo/a"), 1);
+    }
+
+    #[test]
+    fn late_updates_are_accepted_and_repeats_are_not() {
+        let mut alice = Author::new(1);
+        let source = alice.peer_id();
+        let cfg = DocstoreGossipsubConfig::default();
+        let mut pipeline = MessagePipeline::new(cfg.clone(), &PeerId::random());
+        let mut ledger = ClockLedger::default();
+        let mut receive = |update: &DocUpdate| {
+            let data = encode_doc_update(&cfg, update.clone()).unwrap();
+            pipeline.handle_incoming(&mut ledger, source, &message(Some(source), cfg.topics.updates().hash(), data))
+        };
+
+        let first = alice.update("notes", "v1", 1_000);
+        let second = alice.update("notes", "v2", 2_000);
+        assert!(matches!(receive(&second)[0], Incoming::Verdict(MessageAcceptance::Accept)));
+        assert!(matches!(receive(&first)[0], Incoming::Verdict(MessageAcceptance::Accept)), "overtaken, not replayed");
+        assert!(matches!(receive(&first)[..], [Incoming::Verdict(MessageAcceptance::Reject)]));
+
+        // Once it is no longer at hand to compare, an old update is only ignored
+        for i in 0..64 {
+            let more = alice.update("notes", "more", 3_000 + i);
+            assert!(matches!(receive(&more)[0], Incoming::Verdict(MessageAcceptance::Accept)));
+        }
+        assert!(matches!(receive(&first)[..], [Incoming::Verdict(MessageAcceptance::Ignore)]));
+    }
+
+    #[test]
+    fn anonymous_stamped_updates_are_rejected() {
+        let mut alice = Author::new(1);
+        let cfg = DocstoreGossipsubConfig::default();
+        let mut pipeline = MessagePipeline::new(cfg.clone(), &PeerId::random());
+        let mut ledger = ClockLedger::default();
+        let stamped = encode_doc_update(&cfg, alice.update("notes", "v1", 1_000)).unwrap();
+        let out = pipeline.handle_incoming(&mut ledger, alice.peer_id(), &message(None, cfg.topics.updates().hash(), stamped));
+        assert!(matches!(out[..], [Incoming::Verdict(MessageAcceptance::Reject)]));
+
+        let plain = DocUpdate { doc_id: "notes".into(), payload: b"v1".to_vec(), stamp: None };
+        let data = encode_doc_update_requesting_ack(&cfg, plain).unwrap();
+        let out = pipeline.handle_incoming(&mut ledger, alice.peer_id(), &message(None, cfg.topics.updates().hash(), data));
+        assert!(matches!(
+            out[..],
+            [Incoming::Verdict(MessageAcceptance::Accept), Incoming::UpdateApplied { ack_to: None, .. }, Incoming::Message]
+        ));
+    }
+}
@@ -0,0 +1,223 @@
+//! Delivery receipts for updates published with [`FLAG_ACK_REQUESTED`].
+//!
+//! A node that applies such an update answers its publisher with an [`UpdateReceipt`]:
+//! the gossipsub message id, the document and the version the update brought it to,
+//! signed with the receiver's identity key. The receipt goes straight to the publisher
+//! over [`RECEIPT_PROTOCOL`] when the two are connected, and onto the receipts topic
+//! otherwise, where every node forwards it and only the publisher it names takes it in.
+//! Updates published without the flag are never acknowledged.
+//!
+//! A CAS update a node refuses is answered too, with a receipt whose
+//! [`ReceiptOutcome::CasConflict`] says the update was not applied and `applied_version`
+//! is the version the document is at instead, see [`super::cas`]. So is an update a
+//! node ignores for going over a quota ([`ReceiptOutcome::QuotaExceeded`]).
+//!
+//! Signed like [`super::announce::NetworkAnnouncement`], so a receipt relayed over the
+//! topic cannot be forged for a peer that never saw the update.
+//!
+//! [`FLAG_ACK_REQUESTED`]: super::envelope::FLAG_ACK_REQUESTED
+
+use libp2p::gossipsub::MessageId;
+use libp2p::identity::{Keypair, PublicKey, SigningError};
+use libp2p::request_response::{self, ProtocolSupport};
+use libp2p::{PeerId, StreamProtocol};
+use serde::{Deserialize, Serialize};
+
+use super::wire;
+
+pub const RECEIPT_PROTOCOL: &str = "/docstore/receipt/1.0.0";
+
+/// Largest encoded receipt accepted on the receipts topic.
+pub const MAX_RECEIPT_SIZE: usize = 4096;
+
+/// Receipts delivered directly. The response tells whether the publisher was still
+/// waiting for it.
+pub type ReceiptBehaviour = request_response::cbor::Behaviour<UpdateReceipt, bool>;
+
+/// Every node publishes, so every node takes receipts in.
+pub fn make_receipt_behaviour() -> ReceiptBehaviour {
+    request_response::cbor::Behaviour::new(
+        [(StreamProtocol::new(RECEIPT_PROTOCOL), ProtocolSupport::Full)],
+        request_response::Config::default(),
+    )
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum ReceiptError {
+    #[error("malformed receipt")]
+    Malformed,
+    #[error("receipt of {size} bytes exceeds the maximum of {max} bytes")]
+    TooLarge { size: usize, max: usize },
+    #[error("receipt signature does not verify")]
+    BadSignature,
+}
+
+/// What became of the update a receipt answers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReceiptOutcome {
+    #[default]
+    Applied,
+    /// A CAS update that expected another version; nothing was applied.
+    CasConflict,
+    /// Storing the update would have gone over a room's or its author's quota; nothing
+    /// was applied.
+    QuotaExceeded,
+}
+
+/// Proof that a peer applied an update, see the [module docs](self).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UpdateReceipt {
+    /// Id of the gossipsub message that carried the update.
+    #[serde(deserialize_with = "wire::bytes")]
+    pub msg_id: Vec<u8>,
+    pub doc_id: String,
+    /// Version of the document at the receiver once the update was applied, or as it
+    /// stands for a CAS conflict.
+    pub applied_version: u64,
+    /// Peer id of the publisher the receipt answers.
+    #[serde(deserialize_with = "wire::bytes")]
+    pub publisher: Vec<u8>,
+    /// Protobuf-encoded public key of the receiver.
+    #[serde(deserialize_with = "wire::bytes")]
+    pub public_key: Vec<u8>,
+    #[serde(deserialize_with = "wire::bytes")]
+    pub signature: Vec<u8>,
+    /// Last, so receipts from builds without it still decode as `Applied`.
+    #[serde(default)]
+    pub outcome: ReceiptOutcome,
+}
+
+impl UpdateReceipt {
+    pub fn sign(
+        receiver: &Keypair,
+        publisher: &PeerId,
+        msg_id: &MessageId,
+        doc_id: impl Into<String>,
+        applied_version: u64,
+    ) -> Result<Self, SigningError> {
+        Self::sign_with_outcome(receiver, publisher, msg_id, doc_id, applied_version, ReceiptOutcome::Applied)
+    }
+
+    pub fn sign_with_outcome(
+        receiver: &Keypair,
+        publisher: &PeerId,
+        msg_id: &MessageId,
+        doc_id: impl Into<String>,
+        applied_version: u64,
+        outcome: ReceiptOutcome,
+    ) -> Result<Self, SigningError> {
+        let mut receipt = Self {
+            msg_id: msg_id.0.clone(),
+            doc_id: doc_id.into(),
+            applied_version,
+            publisher: publisher.to_bytes(),
+            public_key: receiver.public().encode_protobuf(),
+            signature: Vec::new(),
+            outcome,
+        };
+        receipt.signature = receiver.sign(&receipt.payload())?;
+        Ok(receipt)
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        let mut payload = b"docstore-receipt:".to_vec();
+        for field in [self.msg_id.as_slice(), self.doc_id.as_bytes(), self.publisher.as_slice(), self.public_key.as_slice()] {
+            payload.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            payload.extend_from_slice(field);
+        }
+        payload.extend_from_slice(&self.applied_version.to_be_bytes());
+        // Applied receipts sign what builds without outcomes sign
+        match self.outcome {
+            ReceiptOutcome::Applied => {}
+            ReceiptOutcome::CasConflict => payload.extend_from_slice(b":cas-conflict"),
+            ReceiptOutcome::QuotaExceeded => payload.extend_from_slice(b":quota-exceeded"),
+        }
+        payload
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        postcard::to_allocvec(self).expect("receipt serialization cannot fail")
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, ReceiptError> {
+        if data.len() > MAX_RECEIPT_SIZE {
+            return Err(ReceiptError::TooLarge { size: data.len(), max: MAX_RECEIPT_SIZE });
+        }
+        postcard::from_bytes(data)
+            .or_else(|_| {
+                // Older builds end before the outcome; postcard has no defaults for it
+                let mut padded = data.to_vec();
+                padded.push(0);
+                postcard::from_bytes(&padded)
+            })
+            .map_err(|_| ReceiptError::Malformed)
+    }
+
+    pub fn message_id(&self) -> MessageId {
+        MessageId::new(&self.msg_id)
+    }
+
+    /// Whether the receipt answers an update `peer` published.
+    pub fn is_for(&self, peer: &PeerId) -> bool {
+        self.publisher == peer.to_bytes()
+    }
+
+    /// Check the signature. Returns the receiver that signed it.
+    pub fn verify(&self) -> Result<PeerId, ReceiptError> {
+        let key = PublicKey::try_decode_protobuf(&self.public_key).map_err(|_| ReceiptError::Malformed)?;
+        if !key.verify(&self.payload(), &self.signature) {
+            return Err(ReceiptError::BadSignature);
+        }
+        Ok(key.to_peer_id())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn receipts_are_signed_by_the_receiver() {
+        let (receiver, publisher) = (Keypair::generate_ed25519(), PeerId::random());
+        let msg_id = MessageId::new(b"msg-1");
+        let receipt = UpdateReceipt::sign(&receiver, &publisher, &msg_id, "notes", 7).unwrap();
+
+        let decoded = UpdateReceipt::decode(&receipt.encode()).unwrap();
+        assert_eq!(decoded.verify(), Ok(receiver.public().to_peer_id()));
+        assert_eq!(decoded.message_id(), msg_id);
+        assert!(decoded.is_for(&publisher));
+        assert!(!decoded.is_for(&PeerId::random()));
+
+        // Claiming a later version, or redirecting it, breaks the signature
+        let mut forged = decoded.clone();
+        forged.applied_version = 8;
+        assert_eq!(forged.verify(), Err(ReceiptError::BadSignature));
+        let mut redirected = decoded;
+        redirected.publisher = PeerId::random().to_bytes();
+        assert_eq!(redirected.verify(), Err(ReceiptError::BadSignature));
+
+        assert_eq!(UpdateReceipt::decode(b"junk"), Err(ReceiptError::Malformed));
+        assert!(matches!(UpdateReceipt::decode(&[0; MAX_RECEIPT_SIZE + 1]), Err(ReceiptError::TooLarge { .. })));
+    }
+
+    #[test]
+    fn conflicts_are_signed_and_old_receipts_still_decode() {
+        let (receiver, publisher) = (Keypair::generate_ed25519(), PeerId::random());
+        let msg_id = MessageId::new(b"msg-1");
+        let conflict =
+            UpdateReceipt::sign_with_outcome(&receiver, &publisher, &msg_id, "lock", 3, ReceiptOutcome::CasConflict).unwrap();
+        let decoded = UpdateReceipt::decode(&conflict.encode()).unwrap();
+        assert_eq!(decoded.outcome, ReceiptOutcome::CasConflict);
+        assert!(decoded.verify().is_ok());
+        // Passing a conflict off as applied breaks the signature
+        let mut flipped = decoded;
+        flipped.outcome = ReceiptOutcome::Applied;
+        assert_eq!(flipped.verify(), Err(ReceiptError::BadSignature));
+
+        // A receipt from a build without outcomes
+        let applied = UpdateReceipt::sign(&receiver, &publisher, &msg_id, "lock", 3).unwrap();
+        let mut old = applied.encode();
+        assert_eq!(old.pop(), Some(0));
+        assert_eq!(UpdateReceipt::decode(&old), Ok(applied));
+    }
+}
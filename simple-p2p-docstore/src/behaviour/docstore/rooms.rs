@@ -0,0 +1,180 @@
+//! Rooms: isolated channels inside one namespace, each with its own update, presence and
+//! ephemeral topics, so one node can take part in several collaborations at once.
+//!
+//! Room ids come from applications (and often from users), so they are checked and
+//! escaped before they become part of a topic name.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use libp2p::gossipsub::{IdentTopic, TopicHash};
+use serde::{Deserialize, Serialize};
+
+use super::topics::TopicRegistry;
+use crate::Error;
+
+/// Longest accepted room id, in bytes.
+pub const MAX_ROOM_ID_LEN: usize = 128;
+
+/// A room id as given by the application, along with its escaped topic segment.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RoomId {
+    id: String,
+    segment: String,
+}
+
+impl RoomId {
+    /// Bytes outside `[A-Za-z0-9._-]` are percent-encoded, so no id can reach into
+    /// another topic and distinct ids never share one. Empty ids, ids longer than
+    /// [`MAX_ROOM_ID_LEN`] and ids with control characters are rejected.
+    pub fn new(id: impl Into<String>) -> Result<Self, Error> {
+        let id = id.into();
+        if id.is_empty() || id.len() > MAX_ROOM_ID_LEN || id.chars().any(char::is_control) {
+            return Err(Error::InvalidRoomId(id));
+        }
+        let mut segment = String::with_capacity(id.len());
+        for b in id.bytes() {
+            if b.is_ascii_alphanumeric() || matches!(b, b'.' | b'_' | b'-') {
+                segment.push(b as char);
+            } else {
+                let _ = write!(segment, "%{b:02X}");
+            }
+        }
+        Ok(Self { id, segment })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.id
+    }
+
+    /// The id as it appears in topic names.
+    pub fn topic_segment(&self) -> &str {
+        &self.segment
+    }
+}
+
+/// The topics of a room. Updates travel on the durable gossipsub behaviour, presence and
+/// ephemeral traffic on the ephemeral one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RoomChannel {
+    Updates,
+    Presence,
+    Ephemeral,
+}
+
+impl RoomChannel {
+    pub const ALL: [RoomChannel; 3] = [RoomChannel::Updates, RoomChannel::Presence, RoomChannel::Ephemeral];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RoomChannel::Updates => "updates",
+            RoomChannel::Presence => "presence",
+            RoomChannel::Ephemeral => "ephemeral",
+        }
+    }
+
+    /// Whether the channel belongs on the ephemeral gossipsub behaviour.
+    pub fn is_ephemeral(self) -> bool {
+        !matches!(self, RoomChannel::Updates)
+    }
+}
+
+/// The rooms a node has joined, and which room each of their topics belongs to.
+#[derive(Debug, Default)]
+pub struct Rooms {
+    topics: TopicRegistry,
+    joined: HashMap<String, RoomId>,
+    routes: HashMap<TopicHash, (String, RoomChannel)>,
+}
+
+impl Rooms {
+    pub fn new(topics: TopicRegistry) -> Self {
+        Self { topics, ..Default::default() }
+    }
+
+    /// Join `room`, returning the topics to subscribe to. Empty if already joined.
+    pub fn join(&mut self, room: RoomId) -> Vec<(RoomChannel, IdentTopic)> {
+        if self.joined.contains_key(room.as_str()) {
+            return Vec::new();
+        }
+        let topics: Vec<_> = RoomChannel::ALL.iter().map(|&c| (c, self.topics.room(&room, c))).collect();
+        for (channel, topic) in &topics {
+            self.routes.insert(topic.hash(), (room.as_str().to_string(), *channel));
+        }
+        self.joined.insert(room.as_str().to_string(), room);
+        topics
+    }
+
+    /// Leave a room, returning the topics to unsubscribe from. Empty if not joined.
+    pub fn leave(&mut self, room_id: &str) -> Vec<(RoomChannel, IdentTopic)> {
+        let Some(room) = self.joined.remove(room_id) else {
+            return Vec::new();
+        };
+        let topics: Vec<_> = RoomChannel::ALL.iter().map(|&c| (c, self.topics.room(&room, c))).collect();
+        for (_, topic) in &topics {
+            self.routes.remove(&topic.hash());
+        }
+        topics
+    }
+
+    pub fn is_joined(&self, room_id: &str) -> bool {
+        self.joined.contains_key(room_id)
+    }
+
+    /// Topic of a joined room's channel.
+    pub fn topic(&self, room_id: &str, channel: RoomChannel) -> Option<IdentTopic> {
+        self.joined.get(room_id).map(|room| self.topics.room(room, channel))
+    }
+
+    /// The joined room and channel `topic` belongs to, if any.
+    pub fn route(&self, topic: &TopicHash) -> Option<(&str, RoomChannel)> {
+        self.routes.get(topic).map(|(room_id, channel)| (room_id.as_str(), *channel))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn room_ids_are_escaped_into_one_segment() {
+        assert_eq!(RoomId::new("team-42_a.b").unwrap().topic_segment(), "team-42_a.b");
+        assert_eq!(RoomId::new("a/b c").unwrap().topic_segment(), "a%2Fb%20c");
+        assert_eq!(RoomId::new("ü").unwrap().topic_segment(), "%C3%BC");
+        // The escape character is escaped too, so ids can't collide
+        assert_ne!(RoomId::new("a%20b").unwrap().topic_segment(), RoomId::new("a b").unwrap().topic_segment());
+
+        assert!(matches!(RoomId::new(""), Err(Error::InvalidRoomId(_))));
+        assert!(RoomId::new("a\nb").is_err());
+        assert!(RoomId::new("x".repeat(MAX_ROOM_ID_LEN + 1)).is_err());
+        assert!(RoomId::new("x".repeat(MAX_ROOM_ID_LEN)).is_ok());
+
+        let topic = TopicRegistry::default().room(&RoomId::new("../updates").unwrap(), RoomChannel::Updates);
+        assert_eq!(topic.to_string(), "docstore/v1/rooms/..%2Fupdates/updates");
+    }
+
+    #[test]
+    fn topics_route_to_their_own_room_only() {
+        let mut rooms = Rooms::new(TopicRegistry::new("myapp"));
+        let a = rooms.join(RoomId::new("a").unwrap());
+        let b = rooms.join(RoomId::new("b").unwrap());
+        assert_eq!(a.len(), 3);
+        assert!(rooms.join(RoomId::new("a").unwrap()).is_empty());
+
+        for (channel, topic) in &a {
+            assert_eq!(rooms.route(&topic.hash()), Some(("a", *channel)));
+            assert!(TopicRegistry::new("myapp").owns(&topic.hash()));
+            assert!(TopicRegistry::new("myapp").is_room_topic(&topic.hash()));
+        }
+        assert_eq!(rooms.route(&b[0].1.hash()), Some(("b", RoomChannel::Updates)));
+        assert_eq!(rooms.route(&TopicRegistry::new("myapp").updates().hash()), None);
+        assert!(!TopicRegistry::new("myapp").is_room_topic(&TopicRegistry::new("myapp").updates().hash()));
+        assert_eq!(rooms.topic("a", RoomChannel::Presence).unwrap().to_string(), "myapp/v1/rooms/a/presence");
+
+        assert_eq!(rooms.leave("a").len(), 3);
+        assert!(rooms.leave("a").is_empty());
+        assert!(!rooms.is_joined("a") && rooms.is_joined("b"));
+        assert_eq!(rooms.route(&a[0].1.hash()), None);
+        assert!(rooms.topic("a", RoomChannel::Updates).is_none());
+    }
+}
@@ -0,0 +1,212 @@
+//! Schemas for typed documents. An application registers a validator per doc-id prefix
+//! (`todo/`, `board/`), and the [`MessagePipeline`](super::MessagePipeline) runs it on
+//! every update payload before anything is applied or stored. A message carrying an
+//! update that fails is rejected, which penalises the peer that delivered it like any
+//! other invalid message, and reported as a [`SchemaViolation`]. Snapshots are checked
+//! once assembled; one that fails is dropped.
+//!
+//! Validators are registered from an envelope version on. An update is checked by the
+//! validator of the longest matching prefix registered at or below the version of the
+//! envelope that carried it, so documents written under an older schema still load after
+//! the schema moves on: register the new validator from the envelope version that
+//! introduced it and keep the old one. Updates older than every registration of their
+//! prefix are not checked, and neither is what a store already holds.
+//!
+//! Empty payloads are deletions (see [`history`](crate::behaviour::history)) and always
+//! pass.
+
+use std::fmt;
+
+use super::envelope::{DocUpdate, CURRENT_PROTOCOL_VERSION};
+
+/// `Send + Sync` natively, where validators run on the tokio runtime; nothing on wasm32,
+/// where they may call into JavaScript.
+#[cfg(not(target_arch = "wasm32"))]
+pub trait ValidatorBounds: Send + Sync {}
+#[cfg(not(target_arch = "wasm32"))]
+impl<T: Send + Sync + ?Sized> ValidatorBounds for T {}
+#[cfg(target_arch = "wasm32")]
+pub trait ValidatorBounds {}
+#[cfg(target_arch = "wasm32")]
+impl<T: ?Sized> ValidatorBounds for T {}
+
+/// Checks the payloads of one document type.
+pub trait SchemaValidator: ValidatorBounds {
+    /// `Err` carries the reason, reported in the [`SchemaViolation`].
+    fn validate(&self, doc_id: &str, payload: &[u8]) -> Result<(), String>;
+}
+
+impl<F> SchemaValidator for F
+where
+    F: Fn(&str, &[u8]) -> Result<(), String> + ValidatorBounds,
+{
+    fn validate(&self, doc_id: &str, payload: &[u8]) -> Result<(), String> {
+        self(doc_id, payload)
+    }
+}
+
+/// A payload its document type's validator refused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaViolation {
+    pub doc_id: String,
+    /// The prefix whose validator refused it.
+    pub prefix: String,
+    /// The envelope version it was checked under.
+    pub version: u8,
+    pub reason: String,
+}
+
+impl fmt::Display for SchemaViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} breaks the schema of '{}' (v{}): {}", self.doc_id, self.prefix, self.version, self.reason)
+    }
+}
+
+struct Registration {
+    prefix: String,
+    since_version: u8,
+    validator: Box<dyn SchemaValidator>,
+}
+
+/// The registered validators, see the module docs.
+#[derive(Default)]
+pub struct SchemaRegistry {
+    registrations: Vec<Registration>,
+}
+
+impl fmt::Debug for SchemaRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.registrations.iter().map(|r| (&r.prefix, r.since_version))).finish()
+    }
+}
+
+impl SchemaRegistry {
+    /// Check documents under `prefix` from the current envelope version on.
+    pub fn register(&mut self, prefix: impl Into<String>, validator: Box<dyn SchemaValidator>) {
+        self.register_since(prefix, CURRENT_PROTOCOL_VERSION, validator);
+    }
+
+    /// Check documents under `prefix` carried in envelopes of `since_version` or later,
+    /// up to the next registration of the prefix. Replaces a validator registered for the
+    /// same prefix and version.
+    pub fn register_since(&mut self, prefix: impl Into<String>, since_version: u8, validator: Box<dyn SchemaValidator>) {
+        let prefix = prefix.into();
+        self.registrations.retain(|r| r.prefix != prefix || r.since_version != since_version);
+        self.registrations.push(Registration { prefix, since_version, validator });
+    }
+
+    /// Drop every validator of `prefix`. Returns false if it had none.
+    pub fn unregister(&mut self, prefix: &str) -> bool {
+        let before = self.registrations.len();
+        self.registrations.retain(|r| r.prefix != prefix);
+        self.registrations.len() != before
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.registrations.is_empty()
+    }
+
+    /// Check `update`, carried in an envelope of `version`.
+    pub fn check(&self, update: &DocUpdate, version: u8) -> Result<(), SchemaViolation> {
+        self.check_payload(&update.doc_id, &update.payload, version)
+    }
+
+    pub fn check_payload(&self, doc_id: &str, payload: &[u8], version: u8) -> Result<(), SchemaViolation> {
+        if payload.is_empty() {
+            return Ok(());
+        }
+        let Some(registration) = self
+            .registrations
+            .iter()
+            .filter(|r| doc_id.starts_with(&r.prefix) && r.since_version <= version)
+            .max_by_key(|r| (r.prefix.len(), r.since_version))
+        else {
+            return Ok(());
+        };
+        registration.validator.validate(doc_id, payload).map_err(|reason| SchemaViolation {
+            doc_id: doc_id.to_string(),
+            prefix: registration.prefix.clone(),
+            version,
+            reason,
+        })
+    }
+}
+
+/// A validator from a JSON Schema: payloads must be JSON documents the schema accepts.
+#[cfg(feature = "json-schema")]
+pub struct JsonSchema {
+    validator: jsonschema::Validator,
+}
+
+#[cfg(feature = "json-schema")]
+impl JsonSchema {
+    /// Fails if `schema` is not a valid JSON Schema.
+    pub fn new(schema: &serde_json::Value) -> Result<Self, String> {
+        jsonschema::validator_for(schema).map(|validator| Self { validator }).map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(feature = "json-schema")]
+impl SchemaValidator for JsonSchema {
+    fn validate(&self, _doc_id: &str, payload: &[u8]) -> Result<(), String> {
+        let value: serde_json::Value = serde_json::from_slice(payload).map_err(|e| format!("not JSON: {e}"))?;
+        match self.validator.iter_errors(&value).next() {
+            Some(error) => Err(format!("{} at '{}'", error, error.instance_path)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn starts_with(tag: &'static str) -> Box<dyn SchemaValidator> {
+        Box::new(move |_: &str, payload: &[u8]| {
+            if payload.starts_with(tag.as_bytes()) {
+                Ok(())
+            } else {
+                Err(format!("expected {tag}"))
+            }
+        })
+    }
+
+    #[test]
+    fn longest_prefix_at_or_below_the_version_decides() {
+        let mut schemas = SchemaRegistry::default();
+        schemas.register_since("todo/", 1, starts_with("v1:"));
+        schemas.register_since("todo/", 3, starts_with("v3:"));
+        schemas.register_since("todo/urgent/", 2, starts_with("!"));
+
+        assert!(schemas.check_payload("notes/a", b"anything", 3).is_ok());
+        // Written under the old schema, still loads
+        assert!(schemas.check_payload("todo/a", b"v1:milk", 2).is_ok());
+        let violation = schemas.check_payload("todo/a", b"v1:milk", 3).unwrap_err();
+        assert_eq!((violation.prefix.as_str(), violation.version, violation.reason.as_str()), ("todo/", 3, "expected v3:"));
+        assert!(schemas.check_payload("todo/urgent/a", b"!now", 3).is_ok());
+        // Older than the more specific prefix: the general one applies
+        assert!(schemas.check_payload("todo/urgent/a", b"v1:later", 1).is_ok());
+        // Older than every registration: unchecked
+        assert!(schemas.check_payload("todo/a", b"garbage", 0).is_ok());
+        // Deletions pass
+        assert!(schemas.check_payload("todo/a", b"", 3).is_ok());
+
+        assert!(schemas.unregister("todo/"));
+        assert!(!schemas.unregister("todo/"));
+        assert!(schemas.check_payload("todo/a", b"garbage", 3).is_ok());
+    }
+
+    #[cfg(feature = "json-schema")]
+    #[test]
+    fn json_schema_checks_shape() {
+        let schema = JsonSchema::new(&serde_json::json!({
+            "type": "object",
+            "required": ["title"],
+            "properties": { "title": { "type": "string" } }
+        }))
+        .unwrap();
+        assert!(schema.validate("todo/a", br#"{"title":"milk"}"#).is_ok());
+        assert!(schema.validate("todo/a", br#"{"title":3}"#).is_err());
+        assert!(schema.validate("todo/a", b"not json").unwrap_err().starts_with("not JSON"));
+    }
+}
@@ -0,0 +1,171 @@
+//! How far a gossip message travels. Document updates have global scope and reach every
+//! peer the mesh does. Cursor and typing traffic only matters near its author, so
+//! clients publish it with local scope: the relay a client is connected to forwards it
+//! to its other clients, and relays that get it from another relay ignore it (see
+//! [`ScopeFilter`]). It crosses at most one relay-relay link and goes no further, however
+//! many relays the network grows to.
+//!
+//! The scope rides in [`FLAG_LOCAL_SCOPE`] of the envelope header. Ephemeral messages are
+//! not envelopes; a local one gets the same two-byte header in front of its payload (see
+//! [`frame_ephemeral`]). Anything without the flag is global, as everything was before.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+
+use libp2p::gossipsub::MessageAcceptance;
+use libp2p::PeerId;
+
+use super::envelope::{supported_versions, CURRENT_PROTOCOL_VERSION, FLAG_LOCAL_SCOPE};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum MessageScope {
+    /// Everywhere the mesh reaches.
+    #[default]
+    Global,
+    /// The publisher's relay neighbourhood only.
+    Local,
+}
+
+impl MessageScope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MessageScope::Global => "global",
+            MessageScope::Local => "local",
+        }
+    }
+}
+
+impl fmt::Display for MessageScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for MessageScope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "global" => Ok(MessageScope::Global),
+            "local" => Ok(MessageScope::Local),
+            other => Err(format!("unknown scope {other:?} (expected global or local)")),
+        }
+    }
+}
+
+/// The scope of an envelope or ephemeral frame; anything else is global.
+pub fn scope_of(data: &[u8]) -> MessageScope {
+    match data {
+        [version, flags, ..] if supported_versions().contains(version) && flags & FLAG_LOCAL_SCOPE != 0 => MessageScope::Local,
+        _ => MessageScope::Global,
+    }
+}
+
+/// Give an encoded envelope `scope`. Leaves anything that is not an envelope alone.
+pub fn set_scope(data: &mut [u8], scope: MessageScope) {
+    if let [version, flags, ..] = data {
+        if supported_versions().contains(version) {
+            match scope {
+                MessageScope::Global => *flags &= !FLAG_LOCAL_SCOPE,
+                MessageScope::Local => *flags |= FLAG_LOCAL_SCOPE,
+            }
+        }
+    }
+}
+
+/// An ephemeral payload as published with `scope`: global ones go as they are.
+pub fn frame_ephemeral(payload: Vec<u8>, scope: MessageScope) -> Vec<u8> {
+    match scope {
+        MessageScope::Global => payload,
+        MessageScope::Local => [&[CURRENT_PROTOCOL_VERSION, FLAG_LOCAL_SCOPE][..], &payload].concat(),
+    }
+}
+
+/// The scope and payload of a received ephemeral message.
+pub fn unframe_ephemeral(data: &[u8]) -> (MessageScope, &[u8]) {
+    match data {
+        [version, flags, payload @ ..] if supported_versions().contains(version) && *flags == FLAG_LOCAL_SCOPE => {
+            (MessageScope::Local, payload)
+        }
+        _ => (MessageScope::Global, data),
+    }
+}
+
+/// What a relay needs to keep local-scope messages local: which of its peers are relays
+/// too, as told by identify.
+#[derive(Debug, Default)]
+pub struct ScopeFilter {
+    relays: HashSet<PeerId>,
+}
+
+impl ScopeFilter {
+    /// `peer_id` identified; `relay` if it serves the relay hop protocol.
+    pub fn identified(&mut self, peer_id: PeerId, relay: bool) {
+        if relay {
+            self.relays.insert(peer_id);
+        } else {
+            self.relays.remove(&peer_id);
+        }
+    }
+
+    pub fn disconnected(&mut self, peer_id: &PeerId) {
+        self.relays.remove(peer_id);
+    }
+
+    pub fn is_relay(&self, peer_id: &PeerId) -> bool {
+        self.relays.contains(peer_id)
+    }
+
+    /// Whether `data`, delivered by `propagation_source`, has gone as far as its scope
+    /// allows: it is local and came from another relay.
+    pub fn is_out_of_scope(&self, propagation_source: &PeerId, data: &[u8]) -> bool {
+        scope_of(data) == MessageScope::Local && self.is_relay(propagation_source)
+    }
+
+    /// The verdict for a message no other check applies to, such as an ephemeral one.
+    /// Ignored rather than rejected: the relay that forwarded it did nothing wrong.
+    pub fn verdict(&self, propagation_source: &PeerId, data: &[u8]) -> MessageAcceptance {
+        if self.is_out_of_scope(propagation_source, data) {
+            MessageAcceptance::Ignore
+        } else {
+            MessageAcceptance::Accept
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::behaviour::docstore::{DocUpdate, Envelope};
+
+    #[test]
+    fn local_messages_stop_at_the_first_relay_they_reach_from_another() {
+        let mut update = Envelope::Update(DocUpdate::new("doc", b"x".to_vec())).encode();
+        assert_eq!(scope_of(&update), MessageScope::Global);
+        set_scope(&mut update, MessageScope::Local);
+        assert_eq!(scope_of(&update), MessageScope::Local);
+        // Still an envelope every current peer reads
+        assert_eq!(Envelope::decode(&update).unwrap().into_updates(), vec![DocUpdate::new("doc", b"x".to_vec())]);
+
+        let cursor = frame_ephemeral(b"{\"x\":1}".to_vec(), MessageScope::Local);
+        assert_eq!(unframe_ephemeral(&cursor), (MessageScope::Local, &b"{\"x\":1}"[..]));
+        // Older clients send their cursors bare
+        assert_eq!(unframe_ephemeral(b"{\"x\":1}"), (MessageScope::Global, &b"{\"x\":1}"[..]));
+        assert_eq!(frame_ephemeral(b"hi".to_vec(), MessageScope::Global), b"hi");
+
+        let (client, relay) = (PeerId::random(), PeerId::random());
+        let mut filter = ScopeFilter::default();
+        filter.identified(client, false);
+        filter.identified(relay, true);
+        assert!(matches!(filter.verdict(&client, &cursor), MessageAcceptance::Accept));
+        assert!(matches!(filter.verdict(&relay, &cursor), MessageAcceptance::Ignore));
+        assert!(matches!(filter.verdict(&relay, b"{\"x\":1}"), MessageAcceptance::Accept));
+        assert!(filter.is_out_of_scope(&relay, &update));
+        filter.disconnected(&relay);
+        assert!(!filter.is_out_of_scope(&relay, &update));
+
+        assert_eq!("local".parse::<MessageScope>(), Ok(MessageScope::Local));
+        assert!("nearby".parse::<MessageScope>().is_err());
+    }
+}
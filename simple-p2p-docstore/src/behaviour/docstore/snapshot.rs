@@ -0,0 +1,381 @@
+//! Document snapshots published by FullNodes, so that new clients can catch up from the
+//! latest snapshot plus the updates after it instead of replaying the full history.
+//!
+//! Snapshots larger than one gossipsub message are split into [`SnapshotChunk`]s and
+//! reassembled by [`SnapshotAssembler`]; the reassembled bytes are checked against the
+//! SHA-256 content hash before use. Nothing is buffered for a snapshot larger than the
+//! largest document allowed, and [`MAX_BUFFERED_BYTES`] bounds what incomplete snapshots
+//! hold together.
+//!
+//! A snapshot replaces a document wholesale, so it is only installed from the
+//! document's authors and the configured
+//! [`snapshot_publishers`](super::DocstoreGossipsubConfig::snapshot_publishers), and only
+//! up to [`MAX_VERSION_JUMP`] versions past the local state.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::DEFAULT_MAX_DOCUMENT_SIZE;
+
+/// Largest chunk payload published on the snapshot topic.
+pub const DEFAULT_SNAPSHOT_CHUNK_SIZE: usize = 128 * 1024;
+
+/// Snapshots with more chunks than this are refused outright.
+const MAX_CHUNKS: u32 = 1024;
+
+/// Incomplete snapshots tracked at once; the oldest is dropped past this.
+const MAX_PARTIAL_SNAPSHOTS: usize = 16;
+
+/// Bytes held across incomplete snapshots, unless a single document may be larger; the
+/// oldest are dropped past this.
+pub const MAX_BUFFERED_BYTES: usize = 64 * 1024 * 1024;
+
+/// Furthest a snapshot may take a document past its local version. Far more updates
+/// than any document sees, but keeps versions from reaching where they overflow.
+pub const MAX_VERSION_JUMP: u64 = 1 << 32;
+
+/// Full content of a document at a given version.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub doc_id: String,
+    pub version: u64,
+    pub content_hash: [u8; 32],
+    pub bytes: Vec<u8>,
+}
+
+impl Snapshot {
+    pub fn new(doc_id: impl Into<String>, version: u64, bytes: Vec<u8>) -> Self {
+        Self { doc_id: doc_id.into(), version, content_hash: content_hash(&bytes), bytes }
+    }
+
+    /// True if `bytes` matches `content_hash`.
+    pub fn verify(&self) -> bool {
+        content_hash(&self.bytes) == self.content_hash
+    }
+
+    /// Split into chunks of at most `chunk_size` bytes. An empty document is one empty chunk.
+    pub fn chunks(&self, chunk_size: usize) -> Vec<SnapshotChunk> {
+        let parts: Vec<&[u8]> = if self.bytes.is_empty() {
+            vec![&[]]
+        } else {
+            self.bytes.chunks(chunk_size.max(1)).collect()
+        };
+        let total = parts.len() as u32;
+        parts
+            .into_iter()
+            .enumerate()
+            .map(|(i, data)| SnapshotChunk {
+                doc_id: self.doc_id.clone(),
+                version: self.version,
+                content_hash: self.content_hash,
+                index: i as u32,
+                total,
+                data: data.to_vec(),
+            })
+            .collect()
+    }
+}
+
+pub fn content_hash(bytes: &[u8]) -> [u8; 32] {
+    Sha256::digest(bytes).into()
+}
+
+/// One piece of a snapshot as sent on the wire (postcard-encoded).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotChunk {
+    pub doc_id: String,
+    pub version: u64,
+    pub content_hash: [u8; 32],
+    pub index: u32,
+    pub total: u32,
+    #[serde(deserialize_with = "super::wire::bytes")]
+    pub data: Vec<u8>,
+}
+
+impl SnapshotChunk {
+    pub fn encode(&self) -> Vec<u8> {
+        postcard::to_allocvec(self).expect("snapshot chunk serialization cannot fail")
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, postcard::Error> {
+        postcard::from_bytes(data)
+    }
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum SnapshotError {
+    #[error("chunk {index} is out of range for a snapshot of {total} chunks")]
+    BadChunkIndex { index: u32, total: u32 },
+    #[error("chunk does not match the other chunks of this snapshot")]
+    Inconsistent,
+    #[error("reassembled snapshot does not match its content hash")]
+    HashMismatch,
+    #[error("snapshot of at least {size} bytes is larger than the {max} bytes a document may have")]
+    TooLarge { size: u64, max: usize },
+}
+
+struct Partial {
+    content_hash: [u8; 32],
+    chunks: Vec<Option<Vec<u8>>>,
+    received: u32,
+    /// Bytes of the chunks received.
+    bytes: usize,
+    /// Order of arrival among the partial snapshots, oldest lowest.
+    started: u64,
+}
+
+/// Collects snapshot chunks until a snapshot is complete.
+pub struct SnapshotAssembler {
+    max_document_size: usize,
+    partial: HashMap<(String, u64), Partial>,
+    /// Bytes held across `partial`.
+    buffered: usize,
+    started: u64,
+}
+
+impl Default for SnapshotAssembler {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_DOCUMENT_SIZE)
+    }
+}
+
+impl SnapshotAssembler {
+    /// Refuses snapshots larger than `max_document_size`.
+    pub fn new(max_document_size: usize) -> Self {
+        Self { max_document_size, partial: HashMap::new(), buffered: 0, started: 0 }
+    }
+
+    /// Add a chunk. Returns the snapshot once all of its chunks have arrived and the
+    /// content hash checks out.
+    pub fn push(&mut self, chunk: SnapshotChunk) -> Result<Option<Snapshot>, SnapshotError> {
+        if chunk.total == 0 || chunk.total > MAX_CHUNKS || chunk.index >= chunk.total {
+            return Err(SnapshotError::BadChunkIndex { index: chunk.index, total: chunk.total });
+        }
+        // Every chunk but the last is at least as long as this one
+        let claimed = (chunk.data.len() as u64).saturating_mul(u64::from(chunk.total - 1)).max(chunk.data.len() as u64);
+        if claimed > self.max_document_size as u64 {
+            return Err(SnapshotError::TooLarge { size: claimed, max: self.max_document_size });
+        }
+        let key = (chunk.doc_id.clone(), chunk.version);
+        if !self.partial.contains_key(&key) && self.partial.len() >= MAX_PARTIAL_SNAPSHOTS {
+            self.drop_oldest();
+        }
+        let started = self.started;
+        let partial = self.partial.entry(key.clone()).or_insert_with(|| Partial {
+            content_hash: chunk.content_hash,
+            chunks: vec![None; chunk.total as usize],
+            received: 0,
+            bytes: 0,
+            started,
+        });
+        self.started += 1;
+        if partial.content_hash != chunk.content_hash || partial.chunks.len() != chunk.total as usize {
+            self.remove(&key);
+            return Err(SnapshotError::Inconsistent);
+        }
+        let slot = &mut partial.chunks[chunk.index as usize];
+        if slot.is_none() {
+            partial.bytes += chunk.data.len();
+            self.buffered += chunk.data.len();
+            *slot = Some(chunk.data);
+            partial.received += 1;
+        }
+        if partial.bytes > self.max_document_size {
+            let size = partial.bytes as u64;
+            self.remove(&key);
+            return Err(SnapshotError::TooLarge { size, max: self.max_document_size });
+        }
+        if partial.received < chunk.total {
+            while self.buffered > MAX_BUFFERED_BYTES.max(self.max_document_size) {
+                self.drop_oldest();
+            }
+            return Ok(None);
+        }
+
+        let partial = self.remove(&key).expect("present");
+        let bytes = partial.chunks.into_iter().flatten().flatten().collect();
+        let snapshot = Snapshot { doc_id: key.0, version: key.1, content_hash: partial.content_hash, bytes };
+        if !snapshot.verify() {
+            return Err(SnapshotError::HashMismatch);
+        }
+        Ok(Some(snapshot))
+    }
+
+    fn remove(&mut self, key: &(String, u64)) -> Option<Partial> {
+        let partial = self.partial.remove(key)?;
+        self.buffered -= partial.bytes;
+        Some(partial)
+    }
+
+    fn drop_oldest(&mut self) {
+        let oldest = self.partial.iter().min_by_key(|(_, partial)| partial.started).map(|(key, _)| key.clone());
+        if let Some(key) = oldest {
+            self.remove(&key);
+        }
+    }
+}
+
+/// When a FullNode publishes snapshots: every `interval` for documents that changed, and
+/// immediately once a document has accumulated `update_threshold` updates.
+#[derive(Debug, Clone)]
+pub struct SnapshotPolicy {
+    pub interval: Duration,
+    pub update_threshold: u64,
+}
+
+impl Default for SnapshotPolicy {
+    fn default() -> Self {
+        Self { interval: Duration::from_secs(300), update_threshold: 500 }
+    }
+}
+
+/// Tracks which documents changed since their last snapshot.
+#[derive(Debug, Default)]
+pub struct SnapshotScheduler {
+    pending: HashMap<String, u64>,
+}
+
+impl SnapshotScheduler {
+    /// Record an applied update. Returns true if `doc_id` reached the threshold, in which
+    /// case it is no longer pending and the caller should snapshot it now.
+    pub fn record(&mut self, doc_id: &str, policy: &SnapshotPolicy) -> bool {
+        let count = self.pending.entry(doc_id.to_string()).or_default();
+        *count += 1;
+        if *count >= policy.update_threshold {
+            self.pending.remove(doc_id);
+            return true;
+        }
+        false
+    }
+
+    /// Documents changed since their last snapshot; clears the pending set.
+    pub fn take_changed(&mut self) -> Vec<String> {
+        self.pending.drain().map(|(doc_id, _)| doc_id).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunked_snapshot_reassembles_in_any_order() {
+        let snap = Snapshot::new("doc", 7, (0..1000u32).flat_map(|i| i.to_le_bytes()).collect());
+        let mut chunks = snap.chunks(300);
+        assert_eq!(chunks.len(), 14);
+        chunks.reverse();
+
+        let mut assembler = SnapshotAssembler::default();
+        let last = chunks.pop().unwrap();
+        for chunk in chunks {
+            let chunk = SnapshotChunk::decode(&chunk.encode()).unwrap();
+            assert_eq!(assembler.push(chunk), Ok(None));
+        }
+        assert_eq!(assembler.push(last), Ok(Some(snap)));
+    }
+
+    #[test]
+    fn tampered_snapshot_fails_verification() {
+        let snap = Snapshot::new("doc", 1, b"hello".to_vec());
+        let mut chunk = snap.chunks(DEFAULT_SNAPSHOT_CHUNK_SIZE).remove(0);
+        chunk.data = b"jello".to_vec();
+        assert_eq!(SnapshotAssembler::default().push(chunk), Err(SnapshotError::HashMismatch));
+    }
+
+    #[test]
+    fn scheduler_fires_at_threshold() {
+        let policy = SnapshotPolicy { interval: Duration::from_secs(60), update_threshold: 3 };
+        let mut scheduler = SnapshotScheduler::default();
+        assert!(!scheduler.record("a", &policy));
+        assert!(!scheduler.record("a", &policy));
+        assert!(!scheduler.record("b", &policy));
+        assert!(scheduler.record("a", &policy));
+        assert_eq!(scheduler.take_changed(), vec!["b".to_string()]);
+        assert!(scheduler.take_changed().is_empty());
+    }
+
+    #[test]
+    fn absurd_chunk_lengths_are_rejected() {
+        let chunk = Snapshot::new("doc", 1, b"hello".to_vec()).chunks(DEFAULT_SNAPSHOT_CHUNK_SIZE).remove(0);
+        let mut bytes = chunk.encode();
+        // Replace the data length (1 byte for 5 bytes of data) with u64::MAX - 1
+        bytes.truncate(bytes.len() - 6);
+        bytes.extend([0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]);
+        assert!(SnapshotChunk::decode(&bytes).is_err());
+
+        let mut huge_total = chunk.clone();
+        huge_total.total = u32::MAX;
+        huge_total.index = 0;
+        let decoded = SnapshotChunk::decode(&huge_total.encode()).unwrap();
+        assert!(matches!(SnapshotAssembler::default().push(decoded), Err(SnapshotError::BadChunkIndex { .. })));
+    }
+
+    #[test]
+    fn oversized_snapshots_are_refused_and_buffering_is_bounded() {
+        let mut assembler = SnapshotAssembler::new(1000);
+        let chunk = |doc_id: &str, index: u32, total: u32, len: usize| SnapshotChunk {
+            doc_id: doc_id.into(),
+            version: 1,
+            content_hash: [0; 32],
+            index,
+            total,
+            data: vec![0; len],
+        };
+        // Claims 1024 chunks of 100 bytes
+        assert!(matches!(assembler.push(chunk("doc", 0, 1024, 100)), Err(SnapshotError::TooLarge { size: 102_300, .. })));
+        // Short chunks that add up to too much between them
+        for index in 0..9 {
+            assert_eq!(assembler.push(chunk("doc", index, 10, 100)), Ok(None));
+        }
+        assert!(matches!(assembler.push(chunk("doc", 9, 10, 111)), Err(SnapshotError::TooLarge { size: 1011, .. })));
+        assert_eq!(assembler.buffered, 0);
+
+        // New snapshots push out the oldest incomplete one, not all of them
+        for doc in 0..=MAX_PARTIAL_SNAPSHOTS {
+            assembler.push(chunk(&format!("doc-{doc}"), 0, 2, 10)).unwrap();
+        }
+        assert_eq!(assembler.partial.len(), MAX_PARTIAL_SNAPSHOTS);
+        assert!(!assembler.partial.contains_key(&("doc-0".to_string(), 1)));
+        assert_eq!(assembler.buffered, 10 * MAX_PARTIAL_SNAPSHOTS);
+
+        let mut large = SnapshotAssembler::new(40 * 1024 * 1024);
+        large.push(chunk("a", 0, 2, 30 * 1024 * 1024)).unwrap();
+        large.push(chunk("b", 0, 2, 30 * 1024 * 1024)).unwrap();
+        large.push(chunk("c", 0, 2, 30 * 1024 * 1024)).unwrap();
+        assert!(large.buffered <= MAX_BUFFERED_BYTES);
+        assert!(!large.partial.contains_key(&("a".to_string(), 1)));
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    mod properties {
+        use proptest::collection::vec;
+        use proptest::prelude::*;
+
+        use super::*;
+
+        proptest! {
+            #[test]
+            fn chunks_round_trip_and_reassemble(bytes in vec(any::<u8>(), 0..4096), chunk_size in 4usize..1024) {
+                let snap = Snapshot::new("doc", 7, bytes);
+                let mut assembler = SnapshotAssembler::default();
+                let mut done = None;
+                for chunk in snap.chunks(chunk_size) {
+                    let decoded = SnapshotChunk::decode(&chunk.encode()).unwrap();
+                    prop_assert_eq!(&decoded, &chunk);
+                    done = assembler.push(decoded).unwrap();
+                }
+                prop_assert_eq!(done, Some(snap));
+            }
+
+            #[test]
+            fn decoding_arbitrary_bytes_never_panics(bytes in vec(any::<u8>(), 0..512)) {
+                if let Ok(chunk) = SnapshotChunk::decode(&bytes) {
+                    let _ = SnapshotAssembler::default().push(chunk);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,169 @@
+//! Gossipsub topic names, derived from a namespace and a protocol version.
+//!
+//! Applications sharing relays and bootstrap nodes pick distinct namespaces so their
+//! traffic never meets. The default namespace `docstore` at version 1 produces the
+//! names existing deployments use (`docstore/v1/updates`, ...).
+
+use libp2p::gossipsub::{IdentTopic, TopicHash};
+
+use super::rooms::{RoomChannel, RoomId};
+use crate::Error;
+
+pub const DEFAULT_NAMESPACE: &str = "docstore";
+pub const DEFAULT_TOPIC_VERSION: u32 = 1;
+
+/// The topics a node publishes and subscribes to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopicRegistry {
+    namespace: String,
+    version: u32,
+}
+
+impl Default for TopicRegistry {
+    fn default() -> Self {
+        Self::new(DEFAULT_NAMESPACE)
+    }
+}
+
+impl TopicRegistry {
+    pub fn new(namespace: impl Into<String>) -> Self {
+        Self { namespace: namespace.into(), version: DEFAULT_TOPIC_VERSION }
+    }
+
+    pub fn with_version(mut self, version: u32) -> Self {
+        self.version = version;
+        self
+    }
+
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// A namespace is one non-empty topic segment.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.namespace.is_empty() || self.namespace.contains('/') {
+            return Err(Error::InvalidConfig(format!(
+                "topic namespace must be a non-empty name without '/', got {:?}",
+                self.namespace
+            )));
+        }
+        Ok(())
+    }
+
+    /// `<namespace>/v<version>/`, the prefix of every topic in this registry.
+    pub fn prefix(&self) -> String {
+        format!("{}/v{}/", self.namespace, self.version)
+    }
+
+    /// `<namespace>/v<version>`, the namespace peers register under at rendezvous points.
+    pub fn rendezvous_namespace(&self) -> String {
+        format!("{}/v{}", self.namespace, self.version)
+    }
+
+    /// Public document updates.
+    pub fn updates(&self) -> IdentTopic {
+        IdentTopic::new(format!("{}updates", self.prefix()))
+    }
+
+    /// Document snapshots published by FullNodes.
+    pub fn snapshots(&self) -> IdentTopic {
+        IdentTopic::new(format!("{}snapshots", self.prefix()))
+    }
+
+    /// Signed notices from the configured announcers, see [`super::announce`].
+    pub fn announce(&self) -> IdentTopic {
+        IdentTopic::new(format!("{}announce", self.prefix()))
+    }
+
+    /// Receipts for updates published with an ack request, see [`super::receipt`].
+    pub fn receipts(&self) -> IdentTopic {
+        IdentTopic::new(format!("{}receipts", self.prefix()))
+    }
+
+    /// Who is online, for applications that announce presence.
+    pub fn presence(&self) -> IdentTopic {
+        IdentTopic::new(format!("{}presence", self.prefix()))
+    }
+
+    /// Prefix of the per-document ephemeral topics.
+    pub fn ephemeral_prefix(&self) -> String {
+        format!("{}ephemeral/", self.prefix())
+    }
+
+    /// Ephemeral topic (cursors, typing indicators) of one document.
+    pub fn ephemeral(&self, doc_id: &str) -> IdentTopic {
+        IdentTopic::new(format!("{}{doc_id}", self.ephemeral_prefix()))
+    }
+
+    /// The document id if `topic` is one of our ephemeral topics.
+    pub fn ephemeral_doc_id<'a>(&self, topic: &'a TopicHash) -> Option<&'a str> {
+        topic.as_str().strip_prefix(self.ephemeral_prefix().as_str())
+    }
+
+    /// A channel of one room, see [`super::rooms`].
+    pub fn room(&self, room: &RoomId, channel: RoomChannel) -> IdentTopic {
+        IdentTopic::new(format!("{}rooms/{}/{}", self.prefix(), room.topic_segment(), channel.as_str()))
+    }
+
+    /// Whether `topic` is a channel of some room in this namespace.
+    pub fn is_room_topic(&self, topic: &TopicHash) -> bool {
+        topic.as_str().starts_with(format!("{}rooms/", self.prefix()).as_str())
+    }
+
+    /// The channel of a room topic in this namespace, `None` for any other topic.
+    pub fn room_channel(&self, topic: &TopicHash) -> Option<RoomChannel> {
+        if !self.is_room_topic(topic) {
+            return None;
+        }
+        let (_, channel) = topic.as_str().rsplit_once('/')?;
+        RoomChannel::ALL.into_iter().find(|c| c.as_str() == channel)
+    }
+
+    /// Whether `topic` belongs to this registry's namespace and version.
+    pub fn owns(&self, topic: &TopicHash) -> bool {
+        topic.as_str().starts_with(self.prefix().as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_names_match_existing_deployments() {
+        let topics = TopicRegistry::default();
+        assert_eq!(topics.updates().to_string(), "docstore/v1/updates");
+        assert_eq!(topics.snapshots().to_string(), "docstore/v1/snapshots");
+        assert_eq!(topics.announce().to_string(), "docstore/v1/announce");
+        assert_eq!(topics.receipts().to_string(), "docstore/v1/receipts");
+        assert_eq!(topics.ephemeral("doc-1").to_string(), "docstore/v1/ephemeral/doc-1");
+        assert_eq!(topics.rendezvous_namespace(), "docstore/v1");
+        assert!(topics.validate().is_ok());
+    }
+
+    #[test]
+    fn namespaces_do_not_mix() {
+        let (ours, theirs) = (TopicRegistry::new("myapp"), TopicRegistry::default());
+        assert_eq!(ours.updates().to_string(), "myapp/v1/updates");
+        assert_ne!(ours.updates().hash(), theirs.updates().hash());
+        assert_ne!(ours.clone().with_version(2).updates().hash(), TopicRegistry::new("myapp").updates().hash());
+
+        let foreign = theirs.ephemeral("doc-1").hash();
+        assert_eq!(theirs.ephemeral_doc_id(&foreign), Some("doc-1"));
+        assert_eq!(ours.ephemeral_doc_id(&foreign), None);
+        assert!(!ours.owns(&theirs.updates().hash()));
+        assert!(ours.owns(&ours.presence().hash()));
+
+        let room = RoomId::new("team").unwrap();
+        assert_eq!(ours.room_channel(&ours.room(&room, RoomChannel::Presence).hash()), Some(RoomChannel::Presence));
+        assert_eq!(theirs.room_channel(&ours.room(&room, RoomChannel::Presence).hash()), None);
+        assert_eq!(ours.room_channel(&ours.updates().hash()), None);
+
+        assert!(TopicRegistry::new("").validate().is_err());
+        assert!(TopicRegistry::new("my/app").validate().is_err());
+    }
+}
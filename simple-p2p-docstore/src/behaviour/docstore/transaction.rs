@@ -0,0 +1,282 @@
+//! Transactions: updates to several documents that are applied together or not at all.
+//!
+//! A [`Transaction`] is published as one [`Envelope::Transaction`] message, or as several
+//! [`TransactionPart`]s when its updates don't fit in one. Receivers collect the parts in a
+//! [`TransactionAssembler`] and only hand the updates on once every part has arrived, so
+//! the store can apply them in one write batch and observers never see half of a
+//! transaction. Parts are collected per publisher, so nobody can slip updates into
+//! another author's transaction by reusing its id.
+//!
+//! [`Envelope::Transaction`]: super::Envelope::Transaction
+
+use std::collections::{BTreeSet, HashMap};
+
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+
+use super::envelope::{batched_len, prefixed_len, DocUpdate, MAX_BATCH_UPDATES};
+
+/// Transactions with more parts than this are refused outright.
+const MAX_PARTS: u32 = 1024;
+
+/// Incomplete transactions tracked at once; the oldest is dropped past this.
+const MAX_PARTIAL_TRANSACTIONS: usize = 16;
+
+/// Updates to one or more documents that belong together.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Transaction {
+    /// Random, chosen by the publisher; ties the parts of a transaction together.
+    pub id: u64,
+    pub updates: Vec<DocUpdate>,
+}
+
+impl Default for Transaction {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Transaction {
+    pub fn new() -> Self {
+        let mut id = [0u8; 8];
+        getrandom::fill(&mut id).expect("the OS random source is available");
+        Self { id: u64::from_le_bytes(id), updates: Vec::new() }
+    }
+
+    pub fn add_update(&mut self, update: DocUpdate) {
+        self.updates.push(update);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.updates.is_empty()
+    }
+
+    /// The documents the transaction touches, each once, in order of first update.
+    pub fn doc_ids(&self) -> Vec<String> {
+        let mut seen = BTreeSet::new();
+        self.updates.iter().filter(|u| seen.insert(u.doc_id.as_str())).map(|u| u.doc_id.clone()).collect()
+    }
+
+    /// Split into parts whose encoded updates (document id, payload and stamp) add up to at
+    /// most `max_part_bytes` (a single larger update gets a part of its own) and that hold at most [`MAX_BATCH_UPDATES`] updates.
+    /// An empty transaction is one empty part.
+    pub fn parts(&self, max_part_bytes: usize) -> Vec<TransactionPart> {
+        let mut groups: Vec<(Vec<DocUpdate>, usize)> = vec![(Vec::new(), 0)];
+        for update in &self.updates {
+            let len = prefixed_len(update.doc_id.len()) + batched_len(update);
+            let (group, bytes) = groups.last_mut().expect("never empty");
+            if !group.is_empty() && (*bytes + len > max_part_bytes || group.len() >= MAX_BATCH_UPDATES) {
+                groups.push((Vec::new(), 0));
+            }
+            let (group, bytes) = groups.last_mut().expect("never empty");
+            group.push(update.clone());
+            *bytes += len;
+        }
+        let total = groups.len() as u32;
+        groups
+            .into_iter()
+            .enumerate()
+            .map(|(i, (updates, _))| TransactionPart { id: self.id, index: i as u32, total, updates })
+            .collect()
+    }
+}
+
+/// One piece of a transaction as carried by an envelope.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransactionPart {
+    pub id: u64,
+    pub index: u32,
+    pub total: u32,
+    /// At most [`MAX_BATCH_UPDATES`] entries.
+    #[serde(deserialize_with = "part_updates")]
+    pub updates: Vec<DocUpdate>,
+}
+
+fn part_updates<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<DocUpdate>, D::Error> {
+    super::wire::bounded_seq::<D, DocUpdate, DocUpdate>(deserializer, MAX_BATCH_UPDATES)
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum TransactionError {
+    #[error("part {index} is out of range for a transaction of {total} parts")]
+    BadPartIndex { index: u32, total: u32 },
+    #[error("part does not match the other parts of this transaction")]
+    Inconsistent,
+    #[error("part carries updates stamped by someone other than its publisher")]
+    NotFromPublisher,
+}
+
+/// The parts of a transaction received so far.
+#[derive(Debug)]
+struct Partial {
+    parts: Vec<Option<Vec<DocUpdate>>>,
+    received: u32,
+    /// Order of arrival, to drop the oldest transaction first.
+    started: u64,
+}
+
+/// Collects transaction parts until a transaction is complete.
+#[derive(Debug, Default)]
+pub struct TransactionAssembler {
+    /// Keyed by publisher (`None` for anonymous messages) and transaction id.
+    partial: HashMap<(Option<PeerId>, u64), Partial>,
+    started: u64,
+}
+
+impl TransactionAssembler {
+    /// Add a part published by `source`, the signed gossipsub author. Returns the
+    /// transaction, its updates in publish order, once all of its parts have arrived.
+    /// Stamped updates must carry `source`'s clock; anonymous parts can have none.
+    pub fn push(
+        &mut self,
+        source: Option<PeerId>,
+        part: TransactionPart,
+    ) -> Result<Option<Transaction>, TransactionError> {
+        if part.total == 0 || part.total > MAX_PARTS || part.index >= part.total {
+            return Err(TransactionError::BadPartIndex { index: part.index, total: part.total });
+        }
+        let stamped_by_source = |update: &DocUpdate| {
+            update.stamp.as_ref().is_none_or(|stamp| source.is_some_and(|source| stamp.is_from(&source)))
+        };
+        if !part.updates.iter().all(stamped_by_source) {
+            return Err(TransactionError::NotFromPublisher);
+        }
+        if part.total == 1 {
+            return Ok(Some(Transaction { id: part.id, updates: part.updates }));
+        }
+        let key = (source, part.id);
+        if !self.partial.contains_key(&key) {
+            if self.partial.len() >= MAX_PARTIAL_TRANSACTIONS {
+                self.drop_oldest();
+            }
+            let partial = Partial { parts: vec![None; part.total as usize], received: 0, started: self.started };
+            self.partial.insert(key, partial);
+            self.started += 1;
+        }
+        let partial = self.partial.get_mut(&key).expect("present");
+        if partial.parts.len() != part.total as usize {
+            self.partial.remove(&key);
+            return Err(TransactionError::Inconsistent);
+        }
+        let slot = &mut partial.parts[part.index as usize];
+        if slot.is_none() {
+            *slot = Some(part.updates);
+            partial.received += 1;
+        }
+        if partial.received < part.total {
+            return Ok(None);
+        }
+        let partial = self.partial.remove(&key).expect("present");
+        Ok(Some(Transaction { id: part.id, updates: partial.parts.into_iter().flatten().flatten().collect() }))
+    }
+
+    fn drop_oldest(&mut self) {
+        let oldest = self.partial.iter().min_by_key(|(_, partial)| partial.started).map(|(key, _)| *key);
+        if let Some(key) = oldest {
+            self.partial.remove(&key);
+        }
+    }
+
+    /// Transactions still waiting for parts.
+    pub fn pending(&self) -> usize {
+        self.partial.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::hlc::{HlcClock, Stamp, VectorClock};
+    use super::*;
+
+    fn transaction() -> Transaction {
+        let mut tx = Transaction::new();
+        tx.add_update(DocUpdate::new("project/meta", vec![1; 40]));
+        tx.add_update(DocUpdate::new("project/content", vec![2; 40]));
+        tx.add_update(DocUpdate::new("project/meta", vec![3; 40]));
+        tx
+    }
+
+    #[test]
+    fn parts_reassemble_in_any_order() {
+        let tx = transaction();
+        assert_eq!(tx.doc_ids(), ["project/meta", "project/content"]);
+        let mut parts = tx.parts(50);
+        assert_eq!(parts.len(), 3);
+        parts.reverse();
+
+        let mut assembler = TransactionAssembler::default();
+        let source = Some(PeerId::random());
+        let last = parts.pop().unwrap();
+        for part in parts {
+            assert_eq!(assembler.push(source, part.clone()), Ok(None));
+            // Duplicates are harmless
+            assert_eq!(assembler.push(source, part), Ok(None));
+        }
+        assert_eq!(assembler.pending(), 1);
+        assert_eq!(assembler.push(source, last), Ok(Some(tx)));
+        assert_eq!(assembler.pending(), 0);
+    }
+
+    #[test]
+    fn small_transactions_are_one_part() {
+        let tx = transaction();
+        let parts = tx.parts(1024);
+        assert_eq!(parts.len(), 1);
+        assert_eq!(TransactionAssembler::default().push(None, parts[0].clone()), Ok(Some(tx)));
+        assert_eq!(Transaction::new().parts(1024).len(), 1);
+    }
+
+    #[test]
+    fn malformed_parts_are_rejected() {
+        let mut assembler = TransactionAssembler::default();
+        let source = Some(PeerId::random());
+        let mut part = transaction().parts(50).remove(0);
+        part.index = part.total;
+        assert!(matches!(assembler.push(source, part.clone()), Err(TransactionError::BadPartIndex { .. })));
+
+        part.index = 0;
+        assert_eq!(assembler.push(source, part.clone()), Ok(None));
+        part.total += 1;
+        part.index = 1;
+        assert_eq!(assembler.push(source, part), Err(TransactionError::Inconsistent));
+        assert_eq!(assembler.pending(), 0);
+    }
+
+    #[test]
+    fn parts_are_collected_per_publisher() {
+        let (alice, mallory) = (PeerId::random(), PeerId::random());
+        let mut tx = Transaction::new();
+        for (i, doc_id) in ["a", "b"].into_iter().enumerate() {
+            let stamp = Stamp::next_at(&mut HlcClock::for_peer(&alice), &VectorClock::default(), i as u64);
+            tx.add_update(DocUpdate::new(doc_id, vec![1; 40]).with_stamp(stamp));
+        }
+        let parts = tx.parts(50);
+        let mut assembler = TransactionAssembler::default();
+        assert_eq!(assembler.push(Some(alice), parts[0].clone()), Ok(None));
+
+        // Alice's stamps can't be passed off as Mallory's, nor can Mallory finish hers
+        assert_eq!(assembler.push(Some(mallory), parts[1].clone()), Err(TransactionError::NotFromPublisher));
+        assert_eq!(assembler.push(None, parts[1].clone()), Err(TransactionError::NotFromPublisher));
+        let mut intruder = parts[1].clone();
+        intruder.updates = vec![DocUpdate::new("b", b"mallory".to_vec())];
+        assert_eq!(assembler.push(Some(mallory), intruder), Ok(None));
+        assert_eq!(assembler.pending(), 2);
+        assert_eq!(assembler.push(Some(alice), parts[1].clone()), Ok(Some(tx)));
+    }
+
+    #[test]
+    fn the_oldest_partial_transaction_is_dropped_first() {
+        let source = Some(PeerId::random());
+        let parts: Vec<_> = (0..=MAX_PARTIAL_TRANSACTIONS).map(|_| transaction().parts(50)).collect();
+        let mut assembler = TransactionAssembler::default();
+        for tx in &parts {
+            assert_eq!(assembler.push(source, tx[0].clone()), Ok(None));
+        }
+        assert_eq!(assembler.pending(), MAX_PARTIAL_TRANSACTIONS);
+        // The second one is still waiting, the first one lost its first part
+        assert_eq!(assembler.push(source, parts[1][1].clone()), Ok(None));
+        assert!(assembler.push(source, parts[1][2].clone()).unwrap().is_some());
+        assert_eq!(assembler.push(source, parts[0][1].clone()), Ok(None));
+        assert_eq!(assembler.push(source, parts[0][2].clone()), Ok(None));
+    }
+}
@@ -0,0 +1,154 @@
+//! Serde helpers for fields decoded from untrusted input.
+//!
+//! Postcard prefixes byte strings and sequences with their length, and serde's `Vec`
+//! impl reserves capacity from that prefix (up to 1 MiB) before reading an element, so a
+//! message of a few bytes claiming a huge length still costs a large allocation. Byte
+//! fields read through [`bytes`] are taken from the input as one slice, which fails on
+//! a short input before anything is allocated; other sequences go through
+//! [`bounded_seq`], which rejects a length prefix above its maximum up front.
+//!
+//! Only decoding changes: the encoding is the same as for plain `Vec` fields.
+
+use std::fmt;
+use std::marker::PhantomData;
+
+use serde::de::{self, Deserialize, Deserializer, SeqAccess, Visitor};
+
+/// Capacity reserved up front for byte strings that arrive as a sequence of numbers
+/// (self-describing formats such as JSON). Postcard never takes this path.
+const SEQ_PREALLOC: usize = 4096;
+
+/// `#[serde(deserialize_with = "wire::bytes")]` for `Vec<u8>` fields.
+pub fn bytes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    deserializer.deserialize_byte_buf(BytesVisitor)
+}
+
+struct BytesVisitor;
+
+impl<'de> Visitor<'de> for BytesVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a byte string")
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Vec<u8>, E> {
+        Ok(v.to_vec())
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Vec<u8>, E> {
+        Ok(v)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+        let mut out = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(SEQ_PREALLOC));
+        while let Some(byte) = seq.next_element()? {
+            out.push(byte);
+        }
+        Ok(out)
+    }
+}
+
+/// A `Vec<u8>` read with [`bytes`], for use as the element type of [`bounded_seq`].
+#[derive(Debug)]
+pub struct ByteBuf(pub Vec<u8>);
+
+impl<'de> Deserialize<'de> for ByteBuf {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        bytes(deserializer).map(ByteBuf)
+    }
+}
+
+impl From<ByteBuf> for Vec<u8> {
+    fn from(buf: ByteBuf) -> Self {
+        buf.0
+    }
+}
+
+/// A sequence of at most `max` elements, each read as `E` and converted to `T`. Longer
+/// sequences are rejected from their length prefix, before anything is allocated.
+pub fn bounded_seq<'de, D, T, E>(deserializer: D, max: usize) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    E: Deserialize<'de> + Into<T>,
+{
+    deserializer.deserialize_seq(BoundedSeq::<T, E> { max, marker: PhantomData })
+}
+
+struct BoundedSeq<T, E> {
+    max: usize,
+    marker: PhantomData<fn() -> (T, E)>,
+}
+
+impl<'de, T, E> Visitor<'de> for BoundedSeq<T, E>
+where
+    E: Deserialize<'de> + Into<T>,
+{
+    type Value = Vec<T>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a sequence of at most {} elements", self.max)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<T>, A::Error> {
+        let hint = seq.size_hint().unwrap_or(0);
+        if hint > self.max {
+            return Err(de::Error::invalid_length(hint, &self));
+        }
+        let mut out = Vec::with_capacity(hint);
+        while let Some(element) = seq.next_element::<E>()? {
+            if out.len() == self.max {
+                return Err(de::Error::invalid_length(self.max + 1, &self));
+            }
+            out.push(element.into());
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, serde::Serialize, Deserialize)]
+    struct Frame {
+        #[serde(deserialize_with = "bytes")]
+        data: Vec<u8>,
+        #[serde(deserialize_with = "three")]
+        items: Vec<Vec<u8>>,
+    }
+
+    fn three<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Vec<u8>>, D::Error> {
+        bounded_seq::<D, Vec<u8>, ByteBuf>(deserializer, 3)
+    }
+
+    #[test]
+    fn encoding_matches_plain_vectors() {
+        let frame = Frame { data: b"abc".to_vec(), items: vec![b"x".to_vec(), vec![]] };
+        let bytes = postcard::to_allocvec(&frame).unwrap();
+        assert_eq!(bytes, postcard::to_allocvec(&(b"abc".to_vec(), vec![b"x".to_vec(), vec![]])).unwrap());
+        assert_eq!(postcard::from_bytes::<Frame>(&bytes).unwrap(), frame);
+
+        let json = serde_json::to_string(&frame).unwrap();
+        assert_eq!(serde_json::from_str::<Frame>(&json).unwrap(), frame);
+    }
+
+    #[test]
+    fn absurd_length_prefixes_fail_without_allocating() {
+        // u64::MAX - 1 as a varint
+        let huge = [0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01];
+        let data_len: Vec<u8> = huge.to_vec();
+        assert!(postcard::from_bytes::<Frame>(&data_len).is_err());
+
+        let mut items_len = vec![0];
+        items_len.extend(huge);
+        assert!(postcard::from_bytes::<Frame>(&items_len).is_err());
+
+        // Four items where three are allowed, each claiming a huge length
+        let mut too_many = vec![0, 4];
+        too_many.extend(huge);
+        assert!(postcard::from_bytes::<Frame>(&too_many).is_err());
+        assert!(postcard::from_bytes::<Frame>(&[0, 4, 0, 0, 0, 0]).is_err());
+        assert_eq!(postcard::from_bytes::<Frame>(&[0, 3, 0, 0, 0]).unwrap().items.len(), 3);
+    }
+}
@@ -0,0 +1,252 @@
+//! `/docstore/fetch/1.0.0`: fetch a document's current content from a FullNode in ranged
+//! chunks, so a transfer cut off halfway resumes where it stopped instead of starting
+//! over, on a metered link or from another provider.
+//!
+//! The requester first asks for the document's [`FetchManifest`]: its size, its content
+//! hash and the SHA-256 of every chunk. It then asks for one range at a time, naming the
+//! content hash it is assembling, so a responder whose document has moved on answers with
+//! its new manifest ([`FetchResponse::Changed`]) instead of mixing two versions. Every
+//! chunk is checked against the manifest before it is kept. See [`crate::node::fetch`]
+//! for the requesting side.
+
+use std::collections::VecDeque;
+use std::ops::Range;
+use std::time::Duration;
+
+use libp2p::request_response::{self, ProtocolSupport};
+use libp2p::StreamProtocol;
+use serde::{Deserialize, Serialize};
+
+use crate::behaviour::docstore::snapshot::content_hash;
+use crate::store::DocStore;
+
+pub const FETCH_PROTOCOL: &str = "/docstore/fetch/1.0.0";
+
+/// Chunk size responders cut documents into.
+pub const DEFAULT_CHUNK_SIZE: u32 = 64 * 1024;
+
+/// Longest range a responder serves in one response. Longer requests are rejected rather
+/// than cut short.
+pub const MAX_RANGE_BYTES: u32 = 1024 * 1024;
+
+/// Fetch requests a single peer may make per [`DEFAULT_RATE_WINDOW`]. Every chunk is a
+/// request, so this is a lot more than for history: 16 MiB in 64 KiB chunks is 256.
+pub const DEFAULT_REQUESTS_PER_WINDOW: u32 = 1200;
+
+pub const DEFAULT_RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Documents a responder keeps at hand, see [`ManifestCache`].
+pub const MANIFEST_CACHE_ENTRIES: usize = 8;
+
+/// What a requester needs to know before fetching a document, and to check every chunk.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FetchManifest {
+    pub doc_id: String,
+    /// The document's version at the responder; versions are per responder.
+    pub version: u64,
+    /// Content length in bytes.
+    pub total: u64,
+    pub chunk_size: u32,
+    pub content_hash: [u8; 32],
+    /// SHA-256 of each chunk, in order; the last chunk may be short.
+    pub chunk_hashes: Vec<[u8; 32]>,
+}
+
+impl FetchManifest {
+    pub fn new(doc_id: impl Into<String>, version: u64, content: &[u8], chunk_size: u32) -> Self {
+        Self {
+            doc_id: doc_id.into(),
+            version,
+            total: content.len() as u64,
+            chunk_size,
+            content_hash: content_hash(content),
+            chunk_hashes: content.chunks(chunk_size as usize).map(content_hash).collect(),
+        }
+    }
+
+    pub fn chunk_count(&self) -> usize {
+        self.chunk_hashes.len()
+    }
+
+    /// The bytes chunk `index` covers.
+    pub fn chunk_range(&self, index: usize) -> Range<u64> {
+        let start = index as u64 * self.chunk_size as u64;
+        start.min(self.total)..(start + self.chunk_size as u64).min(self.total)
+    }
+
+    /// Why a requester should not trust this manifest, if it should not: the chunk list
+    /// must cover exactly `total` bytes.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.chunk_size == 0 || self.chunk_size > MAX_RANGE_BYTES {
+            return Err(format!("chunk size must be between 1 and {MAX_RANGE_BYTES}, got {}", self.chunk_size));
+        }
+        let expected = self.total.div_ceil(self.chunk_size as u64);
+        if self.chunk_hashes.len() as u64 != expected {
+            return Err(format!("{} bytes make {expected} chunks, the manifest lists {}", self.total, self.chunk_hashes.len()));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FetchRequest {
+    /// The document's current manifest.
+    Manifest { doc_id: String },
+    /// `len` bytes from `offset` of the content whose hash is `content_hash`.
+    Range { doc_id: String, content_hash: [u8; 32], offset: u64, len: u32 },
+}
+
+impl FetchRequest {
+    pub fn doc_id(&self) -> &str {
+        match self {
+            FetchRequest::Manifest { doc_id } | FetchRequest::Range { doc_id, .. } => doc_id,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FetchResponse {
+    Manifest(FetchManifest),
+    Chunk { offset: u64, data: Vec<u8> },
+    /// The requested content is no longer current; this is the manifest of what is.
+    Changed(FetchManifest),
+    /// The responder holds no such document.
+    NotFound,
+    /// The range is empty, too long or past the end.
+    Rejected { reason: String },
+    /// Too many requests from this peer; try again after this many milliseconds.
+    RateLimited { retry_after_ms: u64 },
+}
+
+pub type FetchBehaviour = request_response::cbor::Behaviour<FetchRequest, FetchResponse>;
+
+/// `serve`: answer requests as well as send them. Only nodes that keep documents for
+/// others (FullNodes) should, since identify advertises the protocol to peers looking
+/// for one.
+pub fn make_fetch_behaviour(serve: bool) -> FetchBehaviour {
+    let support = if serve { ProtocolSupport::Full } else { ProtocolSupport::Outbound };
+    request_response::cbor::Behaviour::new(
+        [(StreamProtocol::new(FETCH_PROTOCOL), support)],
+        request_response::Config::default(),
+    )
+}
+
+/// A document as a responder serves it.
+#[derive(Debug)]
+struct CachedDocument {
+    manifest: FetchManifest,
+    content: Vec<u8>,
+}
+
+/// The documents fetched lately with their manifests, so a large document is not copied
+/// out of the store and hashed again for every chunk. Entries are keyed by version and
+/// replaced once the document moves on; least recently used go first.
+#[derive(Debug)]
+pub struct ManifestCache {
+    entries: VecDeque<CachedDocument>,
+    capacity: usize,
+    chunk_size: u32,
+}
+
+impl Default for ManifestCache {
+    fn default() -> Self {
+        Self::new(MANIFEST_CACHE_ENTRIES, DEFAULT_CHUNK_SIZE)
+    }
+}
+
+impl ManifestCache {
+    pub fn new(capacity: usize, chunk_size: u32) -> Self {
+        Self { entries: VecDeque::with_capacity(capacity), capacity: capacity.max(1), chunk_size }
+    }
+
+    /// `doc_id`'s current manifest and content, `None` if `store` does not hold it.
+    fn get<S: DocStore + ?Sized>(&mut self, store: &S, doc_id: &str) -> Option<&CachedDocument> {
+        let version = store.version(doc_id);
+        let found = self.entries.iter().position(|entry| entry.manifest.doc_id == doc_id);
+        let entry = match found.and_then(|i| self.entries.remove(i)) {
+            Some(entry) if entry.manifest.version == version => entry,
+            _ if version == 0 => return None,
+            _ => {
+                let content = store.content(doc_id)?;
+                let manifest = FetchManifest::new(doc_id, version, &content, self.chunk_size);
+                CachedDocument { manifest, content }
+            }
+        };
+        if self.entries.len() >= self.capacity {
+            self.entries.pop_back();
+        }
+        self.entries.push_front(entry);
+        self.entries.front()
+    }
+}
+
+/// Answer `request` from `store`.
+pub fn respond<S: DocStore + ?Sized>(store: &S, request: &FetchRequest, cache: &mut ManifestCache) -> FetchResponse {
+    let Some(document) = cache.get(store, request.doc_id()) else {
+        return FetchResponse::NotFound;
+    };
+    let FetchRequest::Range { content_hash, offset, len, .. } = request else {
+        return FetchResponse::Manifest(document.manifest.clone());
+    };
+    if *content_hash != document.manifest.content_hash {
+        return FetchResponse::Changed(document.manifest.clone());
+    }
+    if *len == 0 || *len > MAX_RANGE_BYTES {
+        return FetchResponse::Rejected { reason: format!("range length must be between 1 and {MAX_RANGE_BYTES}, got {len}") };
+    }
+    let total = document.manifest.total;
+    match offset.checked_add(*len as u64).filter(|end| *end <= total) {
+        Some(end) => FetchResponse::Chunk { offset: *offset, data: document.content[*offset as usize..end as usize].to_vec() },
+        None => FetchResponse::Rejected { reason: format!("range {offset}+{len} is past the end ({total} bytes)") },
+    }
+}
+
+/// What a response to `doc_id` means when it does not carry a manifest or a chunk.
+pub fn failure(doc_id: &str, response: FetchResponse) -> crate::Error {
+    match response {
+        FetchResponse::NotFound => crate::Error::FetchFailed { reason: format!("the peer does not hold {doc_id:?}") },
+        FetchResponse::Rejected { reason } => crate::Error::FetchFailed { reason },
+        FetchResponse::RateLimited { retry_after_ms } => crate::Error::RateLimited { retry_after_ms },
+        FetchResponse::Manifest(_) | FetchResponse::Changed(_) | FetchResponse::Chunk { .. } => {
+            crate::Error::FetchFailed { reason: "unexpected response".to_string() }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::behaviour::docstore::DocUpdate;
+    use crate::store::MemoryDocStore;
+
+    #[test]
+    fn serves_ranges_of_the_current_content() {
+        let mut store = MemoryDocStore::default();
+        let content: Vec<u8> = (0..250u8).collect();
+        store.apply_update(&DocUpdate::new("doc", content.clone()));
+        let mut cache = ManifestCache::new(2, 100);
+
+        let FetchResponse::Manifest(manifest) = respond(&store, &FetchRequest::Manifest { doc_id: "doc".into() }, &mut cache)
+        else {
+            panic!("expected a manifest");
+        };
+        assert_eq!((manifest.total, manifest.chunk_count()), (250, 3));
+        assert_eq!(manifest.chunk_range(2), 200..250);
+        assert!(manifest.validate().is_ok());
+
+        let range = |offset, len, hash| FetchRequest::Range { doc_id: "doc".into(), content_hash: hash, offset, len };
+        assert_eq!(
+            respond(&store, &range(200, 50, manifest.content_hash), &mut cache),
+            FetchResponse::Chunk { offset: 200, data: content[200..].to_vec() }
+        );
+        assert!(matches!(respond(&store, &range(200, 51, manifest.content_hash), &mut cache), FetchResponse::Rejected { .. }));
+
+        // The document moved on: ranges of the old content get the new manifest
+        store.apply_update(&DocUpdate::new("doc", b"new".to_vec()));
+        match respond(&store, &range(0, 100, manifest.content_hash), &mut cache) {
+            FetchResponse::Changed(changed) => assert_eq!((changed.version, changed.total), (2, 3)),
+            other => panic!("expected the new manifest, got {other:?}"),
+        }
+        assert_eq!(respond(&store, &FetchRequest::Manifest { doc_id: "other".into() }, &mut cache), FetchResponse::NotFound);
+    }
+}
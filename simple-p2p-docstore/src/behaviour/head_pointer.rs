@@ -0,0 +1,236 @@
+//! Mutable pointers to the current head of a document, IPNS-style.
+//!
+//! An author publishes "document `doc_id` is at `version`, whose content hashes to
+//! `content_hash`" under [`head_pointer_key`], a key derived from the author's peer id and
+//! the document id. Anyone can put a record under any key, so each [`HeadPointer`] is
+//! signed by the author's identity key and carries a sequence number: resolvers keep the
+//! highest `seq` found among the copies, and [`HeadPointers`] refuses one older than a
+//! pointer already seen, so a peer replaying an old record cannot roll a document back.
+//!
+//! Postcard-encoded and signed like [`crate::behaviour::SuccessorAnnouncement`].
+
+use std::collections::HashMap;
+
+use libp2p::identity::{Keypair, PublicKey, SigningError};
+use libp2p::PeerId;
+use libp2p_kad::RecordKey;
+use serde::{Deserialize, Serialize};
+
+use super::docstore::{snapshot::content_hash, wire};
+
+/// DHT key of `author`'s head pointer for `doc_id`.
+pub fn head_pointer_key(author: &PeerId, doc_id: &str) -> RecordKey {
+    RecordKey::new(&format!("/docstore/head/{author}/{doc_id}"))
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum HeadPointerError {
+    #[error("malformed head pointer")]
+    Malformed,
+    #[error("head pointer is signed by {found}, not {expected}")]
+    WrongAuthor { expected: PeerId, found: PeerId },
+    #[error("head pointer is for document {found:?}, not {expected:?}")]
+    WrongDocument { expected: String, found: String },
+    #[error("head pointer signature does not verify")]
+    BadSignature,
+    #[error("head pointer seq {found} is older than seq {seen} already seen")]
+    Stale { seen: u64, found: u64 },
+}
+
+/// "`doc_id` is at `version`", signed by the document's author.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HeadPointer {
+    pub doc_id: String,
+    pub version: u64,
+    /// SHA-256 of the document content at `version`.
+    pub content_hash: [u8; 32],
+    /// Higher is newer. Never reused by an author for the same document.
+    pub seq: u64,
+    /// Protobuf-encoded public key of the author.
+    #[serde(deserialize_with = "wire::bytes")]
+    pub public_key: Vec<u8>,
+    #[serde(deserialize_with = "wire::bytes")]
+    pub signature: Vec<u8>,
+}
+
+impl HeadPointer {
+    pub fn sign(
+        author: &Keypair,
+        doc_id: impl Into<String>,
+        version: u64,
+        content_hash: [u8; 32],
+        seq: u64,
+    ) -> Result<Self, SigningError> {
+        let mut pointer = Self {
+            doc_id: doc_id.into(),
+            version,
+            content_hash,
+            seq,
+            public_key: author.public().encode_protobuf(),
+            signature: Vec::new(),
+        };
+        pointer.signature = author.sign(&pointer.payload())?;
+        Ok(pointer)
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        let mut payload = b"docstore-head:".to_vec();
+        for field in [self.doc_id.as_bytes(), self.public_key.as_slice()] {
+            payload.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            payload.extend_from_slice(field);
+        }
+        payload.extend_from_slice(&self.version.to_be_bytes());
+        payload.extend_from_slice(&self.content_hash);
+        payload.extend_from_slice(&self.seq.to_be_bytes());
+        payload
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        postcard::to_allocvec(self).expect("head pointer serialization cannot fail")
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, HeadPointerError> {
+        postcard::from_bytes(data).map_err(|_| HeadPointerError::Malformed)
+    }
+
+    /// Check that the pointer is `author`'s, for `doc_id`, and signed.
+    pub fn verify(&self, author: &PeerId, doc_id: &str) -> Result<(), HeadPointerError> {
+        let key = PublicKey::try_decode_protobuf(&self.public_key).map_err(|_| HeadPointerError::Malformed)?;
+        let found = key.to_peer_id();
+        if found != *author {
+            return Err(HeadPointerError::WrongAuthor { expected: *author, found });
+        }
+        if self.doc_id != doc_id {
+            return Err(HeadPointerError::WrongDocument { expected: doc_id.to_string(), found: self.doc_id.clone() });
+        }
+        if !key.verify(&self.payload(), &self.signature) {
+            return Err(HeadPointerError::BadSignature);
+        }
+        Ok(())
+    }
+
+    /// True if `content` is what the pointer points at.
+    pub fn matches(&self, content: &[u8]) -> bool {
+        content_hash(content) == self.content_hash
+    }
+}
+
+/// The highest `seq` published or resolved per author and document, shared by the native
+/// and wasm event loops. See the [module docs](self).
+#[derive(Debug, Default)]
+pub struct HeadPointers {
+    seen: HashMap<(PeerId, String), u64>,
+}
+
+impl HeadPointers {
+    /// Sign a pointer to `content` at `version`. Its `seq` is the current time in
+    /// milliseconds, so it stays ahead of pointers published before a restart, or one past
+    /// the last seq seen if the clock went back.
+    pub fn publish(
+        &mut self,
+        author: &Keypair,
+        doc_id: &str,
+        version: u64,
+        content: &[u8],
+        now_ms: u64,
+    ) -> Result<HeadPointer, SigningError> {
+        let seen = self.seen.entry((author.public().to_peer_id(), doc_id.to_string())).or_default();
+        let seq = now_ms.max(*seen + 1);
+        let pointer = HeadPointer::sign(author, doc_id, version, content_hash(content), seq)?;
+        *seen = seq;
+        Ok(pointer)
+    }
+
+    /// The newest valid pointer among the record `values` found under
+    /// [`head_pointer_key`]. Invalid copies are skipped; if no copy is valid the first
+    /// error is returned, and `Ok(None)` if there were none. A newest pointer older than
+    /// one already seen is [`HeadPointerError::Stale`].
+    pub fn resolve<'a>(
+        &mut self,
+        author: &PeerId,
+        doc_id: &str,
+        values: impl IntoIterator<Item = &'a [u8]>,
+    ) -> Result<Option<HeadPointer>, HeadPointerError> {
+        let mut newest: Option<HeadPointer> = None;
+        let mut first_error = None;
+        for value in values {
+            match HeadPointer::decode(value).and_then(|pointer| pointer.verify(author, doc_id).map(|()| pointer)) {
+                Ok(pointer) if !newest.as_ref().is_some_and(|n| n.seq >= pointer.seq) => newest = Some(pointer),
+                Ok(_) => {}
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        let Some(newest) = newest else {
+            return first_error.map_or(Ok(None), Err);
+        };
+        let seen = self.seen.entry((*author, doc_id.to_string())).or_default();
+        if newest.seq < *seen {
+            return Err(HeadPointerError::Stale { seen: *seen, found: newest.seq });
+        }
+        *seen = newest.seq;
+        Ok(Some(newest))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000_000;
+
+    #[test]
+    fn stale_pointers_are_rejected() {
+        let author = Keypair::generate_ed25519();
+        let peer_id = author.public().to_peer_id();
+        let mut publisher = HeadPointers::default();
+        let old = publisher.publish(&author, "notes", 3, b"v3", NOW).unwrap().encode();
+        // The clock went back; seq still moves forward
+        let new = publisher.publish(&author, "notes", 4, b"v4", NOW - 10).unwrap();
+        assert_eq!(new.seq, NOW + 1);
+
+        let mut resolver = HeadPointers::default();
+        let found = resolver.resolve(&peer_id, "notes", [old.as_slice(), new.encode().as_slice()]).unwrap().unwrap();
+        assert_eq!((found.version, found.seq), (4, NOW + 1));
+        assert!(found.matches(b"v4"));
+
+        // A peer still serving the old record cannot roll the document back
+        assert_eq!(
+            resolver.resolve(&peer_id, "notes", [old.as_slice()]),
+            Err(HeadPointerError::Stale { seen: NOW + 1, found: NOW })
+        );
+        assert_eq!(resolver.resolve(&peer_id, "notes", [new.encode().as_slice()]), Ok(Some(new)));
+        assert_eq!(resolver.resolve(&peer_id, "other", []), Ok(None));
+    }
+
+    #[test]
+    fn forged_and_misplaced_pointers_fail_verification() {
+        let (author, stranger) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let peer_id = author.public().to_peer_id();
+        let pointer = HeadPointer::sign(&author, "notes", 5, content_hash(b"v5"), NOW).unwrap();
+        assert_eq!(HeadPointer::decode(&pointer.encode()).unwrap().verify(&peer_id, "notes"), Ok(()));
+
+        // Pointing at other content after signing breaks the signature
+        let mut forged = pointer.clone();
+        forged.content_hash = content_hash(b"evil");
+        assert_eq!(forged.verify(&peer_id, "notes"), Err(HeadPointerError::BadSignature));
+        let mut bumped = pointer.clone();
+        bumped.seq += 1;
+        assert_eq!(bumped.verify(&peer_id, "notes"), Err(HeadPointerError::BadSignature));
+
+        let theirs = HeadPointer::sign(&stranger, "notes", 6, content_hash(b"v6"), NOW + 1).unwrap();
+        assert_eq!(
+            theirs.verify(&peer_id, "notes"),
+            Err(HeadPointerError::WrongAuthor { expected: peer_id, found: stranger.public().to_peer_id() })
+        );
+        assert!(matches!(pointer.verify(&peer_id, "todo"), Err(HeadPointerError::WrongDocument { .. })));
+
+        // Invalid copies are skipped when a valid one is found, and reported otherwise
+        let mut resolver = HeadPointers::default();
+        let (forged, theirs) = (forged.encode(), theirs.encode());
+        assert_eq!(resolver.resolve(&peer_id, "notes", [b"junk".as_slice(), forged.as_slice(), pointer.encode().as_slice()]), Ok(Some(pointer)));
+        assert_eq!(resolver.resolve(&peer_id, "notes", [forged.as_slice(), theirs.as_slice()]), Err(HeadPointerError::BadSignature));
+        assert_eq!(HeadPointer::decode(b"junk"), Err(HeadPointerError::Malformed));
+    }
+}
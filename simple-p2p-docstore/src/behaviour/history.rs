@@ -0,0 +1,490 @@
+//! `/docstore/history/1.0.0`: lets a peer page through the logged versions of a document
+//! held by a FullNode, e.g. to audit every version of a document between two times.
+//!
+//! A request names a document, where to start (a version or a time), an optional end
+//! time and a page size; the responder answers with one page of logged updates in order
+//! and the cursor for the next page. History only reaches back as far as the responder's
+//! retention policy kept it. Versions are per responder, so a cursor is only meaningful
+//! to the peer that handed it out.
+//!
+//! A request with [`HistoryRequest::at`] set asks for the document as of a version or a
+//! time instead. The responder replays its log on top of the latest snapshot (or from the
+//! first update, while the log still has it) and answers with the content at that point.
+//! An update with an empty payload is a tombstone, as whole-content replacement leaves
+//! nothing of the document, so a document deleted by then comes back as deleted. Points
+//! older than what retention kept are answered with the earliest one still available.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use libp2p::request_response::{self, ProtocolSupport};
+use libp2p::{PeerId, StreamProtocol};
+use serde::{Deserialize, Serialize};
+
+use crate::behaviour::docstore::Snapshot;
+use crate::store::{DocEntry, DocStore, MergePolicy, StoredUpdate};
+
+pub const HISTORY_PROTOCOL: &str = "/docstore/history/1.0.0";
+
+pub const DEFAULT_PAGE_SIZE: u32 = 100;
+
+/// Largest page size a responder accepts. Bigger requests are rejected rather than cut
+/// down, so nobody mistakes a partial answer for the whole range.
+pub const MAX_PAGE_SIZE: u32 = 1000;
+
+/// Payload bytes after which a page is cut short. A page always carries at least one
+/// update so oversized ones can still be fetched.
+pub const MAX_PAGE_BYTES: usize = 1024 * 1024;
+
+/// History requests a single peer may make per [`DEFAULT_RATE_WINDOW`]. Generous, since
+/// a browser catching up asks once per document it shows.
+pub const DEFAULT_REQUESTS_PER_WINDOW: u32 = 120;
+
+pub const DEFAULT_RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Reconstructed states a responder keeps, see [`StateCache`].
+pub const STATE_CACHE_ENTRIES: usize = 32;
+
+/// Where a history query starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HistoryFrom {
+    /// Updates with this version or later; `0` for everything still logged.
+    Version(u64),
+    /// Updates applied at or after this unix millisecond time.
+    Time(u64),
+    /// Only the newest logged update, i.e. the document's current content.
+    Latest,
+}
+
+/// The point in a document's history a time-travel read asks for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HistoryAt {
+    /// As of this version; versions past the current one give the current state.
+    Version(u64),
+    /// As of this unix millisecond time, i.e. with every update applied by then.
+    Time(u64),
+}
+
+/// A document as of a point in its history.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DocumentState {
+    /// `version` is the document's version at that point; the content was last set at
+    /// `updated_at_ms`, unknown if that update was compacted away.
+    Content { version: u64, bytes: Vec<u8>, updated_at_ms: Option<u64> },
+    /// A tombstone was the latest content at that point.
+    Deleted { version: u64, deleted_at_ms: Option<u64> },
+    /// The document had no updates yet at that point, or the responder never had it.
+    Missing,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryRequest {
+    pub doc_id: String,
+    pub from: HistoryFrom,
+    /// Only updates applied before this unix millisecond time; `None` for up to the latest.
+    pub until_ms: Option<u64>,
+    /// Between 1 and [`MAX_PAGE_SIZE`].
+    pub page_size: u32,
+    /// `next_cursor` of the previous page. Continues right after it, whatever `from` says.
+    pub cursor: Option<u64>,
+    /// Ask for the document as of this point instead of a page; the other fields are
+    /// ignored. Responders that predate it answer with a page.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub at: Option<HistoryAt>,
+}
+
+impl HistoryRequest {
+    /// All of `doc_id`'s logged history, a default-sized page at a time.
+    pub fn new(doc_id: impl Into<String>) -> Self {
+        HistoryOptions::default().request(doc_id)
+    }
+
+    /// `doc_id` as of `at`.
+    pub fn at(doc_id: impl Into<String>, at: HistoryAt) -> Self {
+        Self { at: Some(at), ..Self::new(doc_id) }
+    }
+
+    /// Why a responder refuses this request, if it does.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.page_size == 0 || self.page_size > MAX_PAGE_SIZE {
+            return Err(format!("page size must be between 1 and {MAX_PAGE_SIZE}, got {}", self.page_size));
+        }
+        if let (HistoryFrom::Time(from), Some(until)) = (self.from, self.until_ms) {
+            if until < from {
+                return Err(format!("time range ends ({until}) before it starts ({from})"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A history query as the node handles take it: the request minus the document, plus
+/// whom to ask.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryOptions {
+    /// `None` picks the best-ranked connected peer serving history, or this node itself
+    /// if it keeps history.
+    pub peer_id: Option<PeerId>,
+    pub from: HistoryFrom,
+    pub until_ms: Option<u64>,
+    pub page_size: u32,
+    pub cursor: Option<u64>,
+}
+
+impl Default for HistoryOptions {
+    fn default() -> Self {
+        Self { peer_id: None, from: HistoryFrom::Version(0), until_ms: None, page_size: DEFAULT_PAGE_SIZE, cursor: None }
+    }
+}
+
+impl HistoryOptions {
+    pub fn request(&self, doc_id: impl Into<String>) -> HistoryRequest {
+        HistoryRequest {
+            doc_id: doc_id.into(),
+            from: self.from,
+            until_ms: self.until_ms,
+            page_size: self.page_size,
+            cursor: self.cursor,
+            at: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryPage {
+    /// Oldest first.
+    pub updates: Vec<StoredUpdate>,
+    /// Pass as [`HistoryRequest::cursor`] for the following page; `None` once the range
+    /// is exhausted.
+    pub next_cursor: Option<u64>,
+    /// The start of the requested range was already compacted away (or predates a
+    /// snapshot the responder installed), so the history has a gap.
+    pub truncated: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HistoryResponse {
+    Page(HistoryPage),
+    /// The answer to a request with [`HistoryRequest::at`].
+    State(DocumentState),
+    /// The requested point is older than what the responder still has; `earliest` is the
+    /// oldest version it can reconstruct, `None` if it can reconstruct none.
+    Unavailable { earliest: Option<u64> },
+    /// The request failed [`HistoryRequest::validate`].
+    Rejected { reason: String },
+    /// Too many requests from this peer; try again after this many milliseconds.
+    RateLimited { retry_after_ms: u64 },
+}
+
+pub type HistoryBehaviour = request_response::cbor::Behaviour<HistoryRequest, HistoryResponse>;
+
+/// `serve`: answer requests as well as send them. Only nodes that keep history
+/// (FullNodes) should, since identify advertises the protocol to peers looking for one.
+pub fn make_history_behaviour(serve: bool) -> HistoryBehaviour {
+    let support = if serve { ProtocolSupport::Full } else { ProtocolSupport::Outbound };
+    request_response::cbor::Behaviour::new(
+        [(StreamProtocol::new(HISTORY_PROTOCOL), support)],
+        request_response::Config::default(),
+    )
+}
+
+/// What the answer to a page request means to its caller.
+pub fn page_result(response: HistoryResponse) -> Result<HistoryPage, crate::Error> {
+    match response {
+        HistoryResponse::Page(page) => Ok(page),
+        HistoryResponse::State(_) | HistoryResponse::Unavailable { .. } => {
+            Err(crate::Error::HistoryRejected { reason: "answered with a document state instead of a page".to_string() })
+        }
+        HistoryResponse::Rejected { reason } => Err(crate::Error::HistoryRejected { reason }),
+        HistoryResponse::RateLimited { retry_after_ms } => Err(crate::Error::RateLimited { retry_after_ms }),
+    }
+}
+
+/// What the answer to a request with [`HistoryRequest::at`] means to its caller.
+pub fn state_result(response: HistoryResponse) -> Result<DocumentState, crate::Error> {
+    match response {
+        HistoryResponse::State(state) => Ok(state),
+        HistoryResponse::Unavailable { earliest } => Err(crate::Error::HistoryUnavailable { earliest }),
+        HistoryResponse::Page(_) => {
+            Err(crate::Error::HistoryRejected { reason: "the peer does not reconstruct past states".to_string() })
+        }
+        HistoryResponse::Rejected { reason } => Err(crate::Error::HistoryRejected { reason }),
+        HistoryResponse::RateLimited { retry_after_ms } => Err(crate::Error::RateLimited { retry_after_ms }),
+    }
+}
+
+/// Answer `request` from `store`: a page of the document's log, or its state at
+/// [`HistoryRequest::at`].
+pub fn respond<S: DocStore + ?Sized>(store: &S, request: &HistoryRequest, cache: &mut StateCache) -> HistoryResponse {
+    let log = store.log(&request.doc_id);
+    let Some(at) = request.at else {
+        return page(log, request, MAX_PAGE_BYTES);
+    };
+    let snapshot = store.latest_snapshot(&request.doc_id);
+    match resolve(log, snapshot.as_ref(), at) {
+        Resolved::Missing => HistoryResponse::State(DocumentState::Missing),
+        Resolved::Unavailable { earliest } => HistoryResponse::Unavailable { earliest },
+        Resolved::Version(version) => HistoryResponse::State(cache.get_or_replay(&request.doc_id, version, || {
+            replay(log, snapshot.as_ref(), store.merge_policy(&request.doc_id), version)
+        })),
+    }
+}
+
+/// Which version a point in history is, given the log and latest snapshot of a document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolved {
+    Version(u64),
+    Missing,
+    Unavailable { earliest: Option<u64> },
+}
+
+pub fn resolve(log: &[StoredUpdate], snapshot: Option<&Snapshot>, at: HistoryAt) -> Resolved {
+    let from_start = log.first().is_some_and(|oldest| oldest.version == 1);
+    // Before the log's start only the snapshot's version can be rebuilt
+    let earliest = if from_start { Some(1) } else { snapshot.map(|s| s.version) };
+    let Some(current) = log.last().map(|u| u.version).max(snapshot.map(|s| s.version)) else {
+        return Resolved::Missing;
+    };
+    let version = match at {
+        HistoryAt::Version(version) => version.min(current),
+        HistoryAt::Time(ms) => match log.iter().take_while(|u| u.applied_at_ms <= ms).last() {
+            Some(update) => update.version,
+            None if from_start => 0,
+            // When the snapshot's state was reached is not known
+            None => return Resolved::Unavailable { earliest },
+        },
+    };
+    match earliest {
+        _ if version == 0 => Resolved::Missing,
+        Some(earliest) if version >= earliest => Resolved::Version(version),
+        _ => Resolved::Unavailable { earliest },
+    }
+}
+
+/// The state at `version`, which [`resolve`] found reconstructible: replayed from the
+/// snapshot if it is not newer, else from the first update.
+pub fn replay(log: &[StoredUpdate], snapshot: Option<&Snapshot>, policy: MergePolicy, version: u64) -> DocumentState {
+    let mut entry = DocEntry::default();
+    if let Some(snapshot) = snapshot.filter(|s| s.version <= version) {
+        entry.version = snapshot.version;
+        entry.content = snapshot.bytes.clone();
+        entry.content_version = snapshot.version;
+    }
+    let start = log.partition_point(|u| u.version <= entry.version);
+    for update in log[start..].iter().take_while(|u| u.version <= version) {
+        entry.replay(update, policy);
+    }
+    let set_at = log.binary_search_by_key(&entry.content_version, |u| u.version).ok().map(|i| log[i].applied_at_ms);
+    match entry.content_version {
+        0 => DocumentState::Missing,
+        _ if entry.content.is_empty() => DocumentState::Deleted { version: entry.version, deleted_at_ms: set_at },
+        _ => DocumentState::Content { version: entry.version, bytes: entry.content, updated_at_ms: set_at },
+    }
+}
+
+/// The states reconstructed last, as editors scrub back and forth through the same stretch
+/// of history. A state never changes once reached, so entries are only evicted, least
+/// recently used first, or forgotten with their document.
+#[derive(Debug)]
+pub struct StateCache {
+    entries: VecDeque<(String, u64, DocumentState)>,
+    capacity: usize,
+}
+
+impl Default for StateCache {
+    fn default() -> Self {
+        Self::new(STATE_CACHE_ENTRIES)
+    }
+}
+
+impl StateCache {
+    pub fn new(capacity: usize) -> Self {
+        Self { entries: VecDeque::with_capacity(capacity), capacity }
+    }
+
+    pub fn get_or_replay(&mut self, doc_id: &str, version: u64, replay: impl FnOnce() -> DocumentState) -> DocumentState {
+        let state = match self.entries.iter().position(|(d, v, _)| d == doc_id && *v == version) {
+            Some(i) => self.entries.remove(i).expect("position is in range").2,
+            None => replay(),
+        };
+        if self.capacity > 0 {
+            if self.entries.len() >= self.capacity {
+                self.entries.pop_back();
+            }
+            self.entries.push_front((doc_id.to_string(), version, state.clone()));
+        }
+        state
+    }
+
+    /// Drop `doc_id`'s states, for when its history is replaced.
+    pub fn forget(&mut self, doc_id: &str) {
+        self.entries.retain(|(d, _, _)| d != doc_id);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// One page of `log` (a document's logged updates, oldest first) answering `request`,
+/// cut at the request's page size and at `max_bytes` of payload.
+pub fn page(log: &[StoredUpdate], request: &HistoryRequest, max_bytes: usize) -> HistoryResponse {
+    if let Err(reason) = request.validate() {
+        return HistoryResponse::Rejected { reason };
+    }
+    // Where the page starts, and the first version the request asks for
+    let (start, first_wanted) = match (request.cursor, request.from) {
+        (Some(cursor), _) => (log.partition_point(|u| u.version <= cursor), cursor + 1),
+        (None, HistoryFrom::Version(v)) => (log.partition_point(|u| u.version < v), v.max(1)),
+        (None, HistoryFrom::Time(ms)) => (log.iter().position(|u| u.applied_at_ms >= ms).unwrap_or(log.len()), 1),
+        // Nothing older is wanted, so never a gap
+        (None, HistoryFrom::Latest) => (log.len().saturating_sub(1), u64::MAX),
+    };
+    let truncated = start == 0 && log.first().is_some_and(|oldest| oldest.version > first_wanted);
+    let in_range = |u: &StoredUpdate| request.until_ms.is_none_or(|until| u.applied_at_ms < until);
+
+    let mut updates = Vec::new();
+    let mut bytes = 0;
+    let mut rest = log[start..].iter().take_while(|u| in_range(u)).peekable();
+    while let Some(update) = rest.peek() {
+        if updates.len() >= request.page_size as usize
+            || (!updates.is_empty() && bytes + update.payload.len() > max_bytes)
+        {
+            break;
+        }
+        bytes += update.payload.len();
+        updates.push((*update).clone());
+        rest.next();
+    }
+    let next_cursor = rest.peek().and_then(|_| updates.last().map(|u| u.version));
+    HistoryResponse::Page(HistoryPage { updates, next_cursor, truncated })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log(versions: std::ops::RangeInclusive<u64>) -> Vec<StoredUpdate> {
+        versions
+            .map(|v| StoredUpdate { version: v, payload: vec![0; 10], applied_at_ms: 1000 * v, stamp: None })
+            .collect()
+    }
+
+    fn versions(response: &HistoryResponse) -> (Vec<u64>, Option<u64>, bool) {
+        match response {
+            HistoryResponse::Page(page) => {
+                (page.updates.iter().map(|u| u.version).collect(), page.next_cursor, page.truncated)
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[test]
+    fn pages_through_a_time_range_with_cursors() {
+        let log = log(1..=10);
+        let mut request = HistoryRequest {
+            doc_id: "doc".into(),
+            from: HistoryFrom::Time(3000),
+            until_ms: Some(8000),
+            page_size: 2,
+            cursor: None,
+            at: None,
+        };
+        let mut pages = Vec::new();
+        loop {
+            let (got, next, truncated) = versions(&page(&log, &request, MAX_PAGE_BYTES));
+            assert!(!truncated);
+            pages.push(got);
+            match next {
+                Some(cursor) => request.cursor = Some(cursor),
+                None => break,
+            }
+        }
+        assert_eq!(pages, [vec![3, 4], vec![5, 6], vec![7]]);
+
+        // Byte cap, but never an empty page
+        let request = HistoryRequest { page_size: 100, ..HistoryRequest::new("doc") };
+        assert_eq!(versions(&page(&log, &request, 25)), (vec![1, 2], Some(2), false));
+        assert_eq!(versions(&page(&log, &request, 5)), (vec![1], Some(1), false));
+        assert_eq!(versions(&page(&[], &request, 5)), (vec![], None, false));
+    }
+
+    #[test]
+    fn rejects_unbounded_pages_and_flags_compacted_history() {
+        let unbounded = HistoryRequest { page_size: MAX_PAGE_SIZE + 1, ..HistoryRequest::new("doc") };
+        assert!(matches!(page(&log(1..=3), &unbounded, MAX_PAGE_BYTES), HistoryResponse::Rejected { .. }));
+        let empty = HistoryRequest { page_size: 0, ..HistoryRequest::new("doc") };
+        assert!(empty.validate().is_err());
+
+        // Versions 1-4 were compacted away
+        let compacted = log(5..=6);
+        let from_start = HistoryRequest::new("doc");
+        assert_eq!(versions(&page(&compacted, &from_start, MAX_PAGE_BYTES)), (vec![5, 6], None, true));
+        let from_five = HistoryRequest { from: HistoryFrom::Version(5), ..HistoryRequest::new("doc") };
+        assert_eq!(versions(&page(&compacted, &from_five, MAX_PAGE_BYTES)), (vec![5, 6], None, false));
+        let latest = HistoryRequest { from: HistoryFrom::Latest, ..HistoryRequest::new("doc") };
+        assert_eq!(versions(&page(&compacted, &latest, MAX_PAGE_BYTES)), (vec![6], None, false));
+        assert_eq!(versions(&page(&compacted[1..], &latest, MAX_PAGE_BYTES)), (vec![6], None, false));
+        assert_eq!(versions(&page(&[], &latest, MAX_PAGE_BYTES)), (vec![], None, false));
+    }
+
+    fn state_at(log: &[StoredUpdate], snapshot: Option<&Snapshot>, at: HistoryAt) -> Result<DocumentState, Resolved> {
+        match resolve(log, snapshot, at) {
+            Resolved::Version(version) => Ok(replay(log, snapshot, MergePolicy::default(), version)),
+            other => Err(other),
+        }
+    }
+
+    #[test]
+    fn reconstructs_past_states_through_tombstones_and_compaction() {
+        let mut log = log(1..=4);
+        for (update, payload) in log.iter_mut().zip([&b"a"[..], b"ab", b"", b"again"]) {
+            update.payload = payload.to_vec();
+        }
+        let content = |version, bytes: &[u8], updated_at_ms| DocumentState::Content { version, bytes: bytes.to_vec(), updated_at_ms };
+
+        assert_eq!(state_at(&log, None, HistoryAt::Time(2500)), Ok(content(2, b"ab", Some(2000))));
+        assert_eq!(state_at(&log, None, HistoryAt::Time(3000)), Ok(DocumentState::Deleted { version: 3, deleted_at_ms: Some(3000) }));
+        assert_eq!(state_at(&log, None, HistoryAt::Version(99)), Ok(content(4, b"again", Some(4000))));
+        // Before the first update the document did not exist
+        assert_eq!(state_at(&log, None, HistoryAt::Time(999)), Err(Resolved::Missing));
+        assert_eq!(state_at(&[], None, HistoryAt::Version(1)), Err(Resolved::Missing));
+
+        // Versions 1-2 were compacted into a snapshot at 2
+        let snapshot = Snapshot::new("doc", 2, b"ab".to_vec());
+        let compacted = &log[2..];
+        assert_eq!(state_at(compacted, Some(&snapshot), HistoryAt::Version(2)), Ok(content(2, b"ab", None)));
+        assert_eq!(state_at(compacted, Some(&snapshot), HistoryAt::Version(4)), Ok(content(4, b"again", Some(4000))));
+        let unavailable = Err(Resolved::Unavailable { earliest: Some(2) });
+        assert_eq!(state_at(compacted, Some(&snapshot), HistoryAt::Version(1)), unavailable);
+        // When the snapshot's state was reached is not known
+        assert_eq!(state_at(compacted, Some(&snapshot), HistoryAt::Time(2500)), unavailable);
+    }
+
+    #[test]
+    fn caches_recent_states_least_recently_used_first() {
+        let mut cache = StateCache::new(2);
+        let mut replays = 0;
+        let mut get = |cache: &mut StateCache, doc_id: &str, version| {
+            cache.get_or_replay(doc_id, version, || {
+                replays += 1;
+                DocumentState::Missing
+            })
+        };
+        get(&mut cache, "a", 1);
+        get(&mut cache, "a", 2);
+        get(&mut cache, "a", 1);
+        // Evicts version 2, used longest ago
+        get(&mut cache, "b", 1);
+        get(&mut cache, "a", 1);
+        get(&mut cache, "a", 2);
+        assert_eq!(replays, 4);
+        assert_eq!(cache.len(), 2);
+        cache.forget("a");
+        assert!(cache.is_empty());
+    }
+}
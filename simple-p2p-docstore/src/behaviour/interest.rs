@@ -0,0 +1,239 @@
+//! `/docstore/interest/1.0.0`: clients tell their relay which room topics they still want.
+//! A gossipsub unsubscribe only takes effect once the relay processes it, and a client
+//! watching two of fifty rooms otherwise keeps its relay joined to rooms nobody behind it
+//! reads. The hint is the client's complete list of wanted topics, so each one replaces
+//! the last.
+//!
+//! The relay tracks who wants each room topic, from gossipsub subscriptions and from hints,
+//! and leaves a topic as soon as nobody does, which prunes its mesh right away. A client
+//! that never sends a hint is judged by its subscriptions alone. The relay also counts the
+//! bytes it forwards to each client, so the saving shows up in `/metrics`.
+
+use std::collections::{HashMap, HashSet};
+
+use libp2p::gossipsub::TopicHash;
+use libp2p::request_response::{self, ProtocolSupport};
+use libp2p::{PeerId, StreamProtocol};
+use serde::{Deserialize, Serialize};
+
+pub const INTEREST_PROTOCOL: &str = "/docstore/interest/1.0.0";
+
+/// The most topics one hint may list; longer hints are refused and change nothing.
+pub const MAX_INTEREST_TOPICS: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InterestRequest {
+    /// Every topic the client wants, replacing its previous hint.
+    pub topics: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InterestResponse {
+    pub accepted: bool,
+}
+
+pub type InterestBehaviour = request_response::cbor::Behaviour<InterestRequest, InterestResponse>;
+
+/// Relays (`serve`) take hints; the others only send them.
+pub fn make_interest_behaviour(serve: bool) -> InterestBehaviour {
+    let support = if serve { ProtocolSupport::Full } else { ProtocolSupport::Outbound };
+    request_response::cbor::Behaviour::new(
+        [(StreamProtocol::new(INTEREST_PROTOCOL), support)],
+        request_response::Config::default(),
+    )
+}
+
+/// Which connected peers want which topics, on a relay.
+#[derive(Debug, Default)]
+pub struct TopicInterest {
+    /// Gossipsub subscriptions of the topics the relay joins on demand.
+    subscribers: HashMap<TopicHash, HashSet<PeerId>>,
+    /// The latest hint of each peer that sent one.
+    hints: HashMap<PeerId, HashSet<TopicHash>>,
+    /// Bytes forwarded to each connected peer.
+    forwarded: HashMap<PeerId, u64>,
+    /// Bytes forwarded to peers whose hint left the topic out, since startup.
+    unwanted: u64,
+}
+
+impl TopicInterest {
+    /// `peer` subscribed to `topic`.
+    pub fn subscribed(&mut self, peer: PeerId, topic: TopicHash) {
+        self.subscribers.entry(topic).or_default().insert(peer);
+    }
+
+    /// `peer` unsubscribed from `topic`; `true` if nobody wants it anymore.
+    pub fn unsubscribed(&mut self, peer: &PeerId, topic: &TopicHash) -> bool {
+        let Some(peers) = self.subscribers.get_mut(topic) else {
+            return false;
+        };
+        peers.remove(peer);
+        self.forget_if_unwanted(topic)
+    }
+
+    /// Forget a peer that is no longer connected. Returns the topics nobody wants anymore.
+    pub fn disconnected(&mut self, peer: &PeerId) -> Vec<TopicHash> {
+        self.hints.remove(peer);
+        self.forwarded.remove(peer);
+        let topics: Vec<TopicHash> =
+            self.subscribers.iter_mut().filter_map(|(topic, peers)| peers.remove(peer).then(|| topic.clone())).collect();
+        topics.into_iter().filter(|topic| self.forget_if_unwanted(topic)).collect()
+    }
+
+    /// Replace `peer`'s hint. Returns the topics nobody wants anymore.
+    pub fn set_interest(&mut self, peer: PeerId, topics: impl IntoIterator<Item = TopicHash>) -> Vec<TopicHash> {
+        let hint: HashSet<TopicHash> = topics.into_iter().collect();
+        let dropped: Vec<TopicHash> = self
+            .subscribers
+            .iter()
+            .filter(|(topic, peers)| peers.contains(&peer) && !hint.contains(*topic))
+            .map(|(topic, _)| topic.clone())
+            .collect();
+        self.hints.insert(peer, hint);
+        dropped.into_iter().filter(|topic| self.forget_if_unwanted(topic)).collect()
+    }
+
+    /// Whether `peer` wants `topic`: it subscribed, and its hint, if any, lists the topic.
+    pub fn is_interested(&self, peer: &PeerId, topic: &TopicHash) -> bool {
+        self.subscribers.get(topic).is_some_and(|peers| peers.contains(peer)) && self.hint_allows(peer, topic)
+    }
+
+    /// Whether any peer wants `topic`.
+    pub fn is_wanted(&self, topic: &TopicHash) -> bool {
+        self.subscribers.get(topic).is_some_and(|peers| peers.iter().any(|peer| self.hint_allows(peer, topic)))
+    }
+
+    /// Count `bytes` of `topic` forwarded to `peer`.
+    pub fn record_forward(&mut self, peer: PeerId, topic: &TopicHash, bytes: usize) {
+        if !self.hint_allows(&peer, topic) {
+            self.unwanted += bytes as u64;
+        }
+        *self.forwarded.entry(peer).or_default() += bytes as u64;
+    }
+
+    /// Bytes forwarded to `peer` so far.
+    pub fn forwarded_bytes(&self, peer: &PeerId) -> u64 {
+        self.forwarded.get(peer).copied().unwrap_or(0)
+    }
+
+    /// Forwarded bytes per connected peer as `/metrics` lines, plus the bytes that went to
+    /// topics the receiving peer's hint left out.
+    pub fn metrics(&self) -> Vec<(String, u64)> {
+        let mut metrics: Vec<(String, u64)> =
+            self.forwarded.iter().map(|(peer, bytes)| (forwarded_metric(peer), *bytes)).collect();
+        metrics.push(("docstore_forwarded_unwanted_bytes".to_string(), self.unwanted));
+        metrics.push(("docstore_interest_hints".to_string(), self.hints.len() as u64));
+        metrics
+    }
+
+    fn hint_allows(&self, peer: &PeerId, topic: &TopicHash) -> bool {
+        !self.hints.get(peer).is_some_and(|hint| !hint.contains(topic))
+    }
+
+    fn forget_if_unwanted(&mut self, topic: &TopicHash) -> bool {
+        if self.is_wanted(topic) {
+            return false;
+        }
+        self.subscribers.remove(topic);
+        true
+    }
+}
+
+/// The `/metrics` line of the bytes forwarded to `peer`, dropped once it disconnects.
+pub fn forwarded_metric(peer: &PeerId) -> String {
+    format!("docstore_forwarded_bytes{{peer=\"{peer}\"}}")
+}
+
+/// Take a hint. Returns the topics nobody wants anymore, for the relay to leave.
+pub fn handle_event(
+    behaviour: &mut InterestBehaviour,
+    interest: &mut TopicInterest,
+    event: request_response::Event<InterestRequest, InterestResponse>,
+) -> Vec<TopicHash> {
+    match event {
+        request_response::Event::Message { peer, message: request_response::Message::Request { request, channel, .. }, .. } => {
+            let accepted = request.topics.len() <= MAX_INTEREST_TOPICS;
+            let unwanted = if accepted {
+                interest.set_interest(peer, request.topics.into_iter().map(TopicHash::from_raw))
+            } else {
+                tracing::debug!("Ignoring a hint of {} topics from {}", request.topics.len(), peer);
+                Vec::new()
+            };
+            if behaviour.send_response(channel, InterestResponse { accepted }).is_err() {
+                tracing::debug!("Interest hint sender {} went away before the response", peer);
+            }
+            unwanted
+        }
+        request_response::Event::InboundFailure { peer, error, .. } => {
+            tracing::debug!("Interest hint from {} failed: {}", peer, error);
+            Vec::new()
+        }
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn topic(name: &str) -> TopicHash {
+        TopicHash::from_raw(name)
+    }
+
+    #[test]
+    fn subscriptions_alone_decide_without_hints() {
+        let mut interest = TopicInterest::default();
+        let (a, b) = (PeerId::random(), PeerId::random());
+        interest.subscribed(a, topic("room/1"));
+        interest.subscribed(b, topic("room/1"));
+        assert!(interest.is_interested(&a, &topic("room/1")));
+        assert!(!interest.unsubscribed(&a, &topic("room/1")));
+        assert!(interest.unsubscribed(&b, &topic("room/1")));
+        assert!(!interest.is_wanted(&topic("room/1")));
+    }
+
+    #[test]
+    fn hints_drop_topics_ahead_of_unsubscribes() {
+        let mut interest = TopicInterest::default();
+        let (a, b) = (PeerId::random(), PeerId::random());
+        for name in ["room/1", "room/2", "room/3"] {
+            interest.subscribed(a, topic(name));
+        }
+        interest.subscribed(b, topic("room/3"));
+
+        let mut unwanted = interest.set_interest(a, [topic("room/1")]);
+        unwanted.sort_by(|x, y| x.as_str().cmp(y.as_str()));
+        // room/3 is still wanted by b, which never sent a hint
+        assert_eq!(unwanted, vec![topic("room/2")]);
+        assert!(!interest.is_interested(&a, &topic("room/3")));
+        assert!(interest.is_interested(&b, &topic("room/3")));
+
+        // The late unsubscribe of a dropped topic changes nothing
+        assert!(!interest.unsubscribed(&a, &topic("room/2")));
+        assert_eq!(interest.disconnected(&b), vec![topic("room/3")]);
+        assert_eq!(interest.disconnected(&a), vec![topic("room/1")]);
+    }
+
+    #[test]
+    fn counts_forwarded_bytes_per_peer() {
+        let mut interest = TopicInterest::default();
+        let (a, b) = (PeerId::random(), PeerId::random());
+        interest.subscribed(a, topic("room/1"));
+        interest.subscribed(a, topic("room/2"));
+        interest.set_interest(a, [topic("room/1")]);
+        interest.record_forward(a, &topic("room/1"), 100);
+        interest.record_forward(a, &topic("room/2"), 30);
+        interest.record_forward(b, &topic("room/2"), 7);
+        assert_eq!(interest.forwarded_bytes(&a), 130);
+
+        let metrics: HashMap<String, u64> = interest.metrics().into_iter().collect();
+        assert_eq!(metrics[&forwarded_metric(&a)], 130);
+        assert_eq!(metrics[&forwarded_metric(&b)], 7);
+        assert_eq!(metrics["docstore_forwarded_unwanted_bytes"], 30);
+
+        interest.disconnected(&a);
+        assert_eq!(interest.forwarded_bytes(&a), 0);
+        let metrics: HashMap<String, u64> = interest.metrics().into_iter().collect();
+        assert_eq!(metrics["docstore_forwarded_unwanted_bytes"], 30);
+    }
+}
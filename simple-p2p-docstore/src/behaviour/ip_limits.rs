@@ -0,0 +1,425 @@
+#![cfg(not(target_arch = "wasm32"))]
+
+//! Per-IP limits on inbound connections, for public relays that get hammered by scanners
+//! and misbehaving clients from a single address.
+//!
+//! Two limits apply to each remote IP (IPv6 addresses grouped by /64 unless configured
+//! otherwise): how many connections it may hold at once, and how many new connection
+//! attempts it may make within a sliding window. Peers on the allowlist are exempt. Their
+//! peer id is only known once the handshake is done, so an address over its attempt rate
+//! is refused early only if no allowlisted peer has connected from it.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, Ipv6Addr};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use libp2p::core::{transport::PortUse, Endpoint};
+use libp2p::multiaddr::Protocol;
+use libp2p::swarm::{
+    dummy, ConnectionDenied, ConnectionId, FromSwarm, NetworkBehaviour, THandler, THandlerInEvent, THandlerOutEvent,
+    ToSwarm,
+};
+use libp2p::{Multiaddr, PeerId};
+
+pub const DEFAULT_MAX_CONNECTIONS_PER_IP: usize = 16;
+pub const DEFAULT_MAX_ATTEMPTS_PER_IP: u32 = 30;
+pub const DEFAULT_ATTEMPT_WINDOW: Duration = Duration::from_secs(60);
+
+const MIN_PRUNE_AT: usize = 1024;
+
+#[derive(Debug, Clone)]
+pub struct IpLimitsConfig {
+    pub max_connections_per_ip: usize,
+    /// New inbound connections an IP may attempt within `attempt_window`.
+    pub max_attempts_per_ip: u32,
+    pub attempt_window: Duration,
+    /// Group IPv6 addresses by their /64 prefix, which usually belongs to one host or site.
+    pub ipv6_prefix_64: bool,
+    /// Peers never limited.
+    pub allowlist: HashSet<PeerId>,
+}
+
+impl Default for IpLimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_connections_per_ip: DEFAULT_MAX_CONNECTIONS_PER_IP,
+            max_attempts_per_ip: DEFAULT_MAX_ATTEMPTS_PER_IP,
+            attempt_window: DEFAULT_ATTEMPT_WINDOW,
+            ipv6_prefix_64: true,
+            allowlist: HashSet::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DenyReason {
+    TooManyConnections,
+    TooManyAttempts,
+}
+
+impl DenyReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DenyReason::TooManyConnections => "too_many_connections",
+            DenyReason::TooManyAttempts => "too_many_attempts",
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("inbound connection from {ip} denied: {}", .reason.as_str())]
+pub struct IpLimitExceeded {
+    pub ip: IpAddr,
+    pub reason: DenyReason,
+}
+
+/// Inbound connections denied so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IpLimitStats {
+    pub denied_connections: u64,
+    pub denied_attempts: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// `peer_id` is `None` when the attempt was refused before the handshake.
+    Denied { ip: IpAddr, peer_id: Option<PeerId>, reason: DenyReason },
+}
+
+/// Timestamps of recent hits, at most `max` of them within any `window`.
+#[derive(Debug, Clone)]
+pub struct SlidingWindow {
+    hits: VecDeque<u64>,
+    max: u32,
+    window_ms: u64,
+}
+
+impl SlidingWindow {
+    pub fn new(max: u32, window: Duration) -> Self {
+        Self { hits: VecDeque::new(), max, window_ms: window.as_millis() as u64 }
+    }
+
+    /// Record a hit at `now_ms`. Returns false, recording nothing, if the window is full.
+    pub fn try_hit(&mut self, now_ms: u64) -> bool {
+        self.expire(now_ms);
+        if self.hits.len() >= self.max as usize {
+            return false;
+        }
+        self.hits.push_back(now_ms);
+        true
+    }
+
+    /// True if a hit at `now_ms` would be refused.
+    pub fn is_full(&mut self, now_ms: u64) -> bool {
+        self.expire(now_ms);
+        self.hits.len() >= self.max as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hits.is_empty()
+    }
+
+    fn expire(&mut self, now_ms: u64) {
+        while self.hits.front().is_some_and(|t| now_ms.saturating_sub(*t) >= self.window_ms) {
+            self.hits.pop_front();
+        }
+    }
+}
+
+/// The IP an address connects from, if it has one.
+pub fn ip_of(addr: &Multiaddr) -> Option<IpAddr> {
+    addr.iter().find_map(|p| match p {
+        Protocol::Ip4(ip) => Some(IpAddr::V4(ip)),
+        Protocol::Ip6(ip) => Some(IpAddr::V6(ip)),
+        _ => None,
+    })
+}
+
+/// The address limits are counted against: IPv6 addresses are cut to their /64 when
+/// `ipv6_prefix_64` is set.
+pub fn limit_key(ip: IpAddr, ipv6_prefix_64: bool) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) if ipv6_prefix_64 => IpAddr::V6(Ipv6Addr::from(u128::from(v6) & !((1u128 << 64) - 1))),
+        other => other,
+    }
+}
+
+pub struct Behaviour {
+    config: IpLimitsConfig,
+    extract: fn(&Multiaddr) -> Option<IpAddr>,
+    attempts: HashMap<IpAddr, SlidingWindow>,
+    /// Size of `attempts` at which expired windows are swept.
+    prune_at: usize,
+    /// Limit key of every inbound connection we accepted.
+    connections: HashMap<ConnectionId, IpAddr>,
+    per_ip: HashMap<IpAddr, usize>,
+    /// Keys an allowlisted peer connected from; never refused before the handshake.
+    trusted_ips: HashSet<IpAddr>,
+    stats: IpLimitStats,
+    events: VecDeque<Event>,
+}
+
+impl Behaviour {
+    pub fn new(config: IpLimitsConfig) -> Self {
+        Self {
+            config,
+            extract: ip_of,
+            attempts: HashMap::new(),
+            prune_at: MIN_PRUNE_AT,
+            connections: HashMap::new(),
+            per_ip: HashMap::new(),
+            trusted_ips: HashSet::new(),
+            stats: IpLimitStats::default(),
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Replace how the remote IP is read from an address, e.g. for transports without
+    /// IPs in tests.
+    pub fn with_address_extractor(mut self, extract: fn(&Multiaddr) -> Option<IpAddr>) -> Self {
+        self.extract = extract;
+        self
+    }
+
+    /// Limit by `config` from now on. Connections already held stay, and count against
+    /// the new per-IP limit; attempt windows start over.
+    pub fn set_config(&mut self, config: IpLimitsConfig) {
+        self.config = config;
+        self.attempts.clear();
+        self.prune_at = MIN_PRUNE_AT;
+        // Only allowlisted peers earn an IP its trust, and the allowlist may have changed
+        self.trusted_ips.clear();
+    }
+
+    pub fn stats(&self) -> IpLimitStats {
+        self.stats
+    }
+
+    /// Inbound connections currently held from `ip`'s limit key.
+    pub fn connections_from(&self, ip: IpAddr) -> usize {
+        self.per_ip.get(&limit_key(ip, self.config.ipv6_prefix_64)).copied().unwrap_or(0)
+    }
+
+    fn key(&self, addr: &Multiaddr) -> Option<IpAddr> {
+        (self.extract)(addr).map(|ip| limit_key(ip, self.config.ipv6_prefix_64))
+    }
+
+    fn deny(&mut self, ip: IpAddr, peer_id: Option<PeerId>, reason: DenyReason) -> ConnectionDenied {
+        match reason {
+            DenyReason::TooManyConnections => self.stats.denied_connections += 1,
+            DenyReason::TooManyAttempts => self.stats.denied_attempts += 1,
+        }
+        self.events.push_back(Event::Denied { ip, peer_id, reason });
+        ConnectionDenied::new(IpLimitExceeded { ip, reason })
+    }
+}
+
+fn now_ms() -> u64 {
+    web_time::SystemTime::now().duration_since(web_time::UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+impl NetworkBehaviour for Behaviour {
+    type ConnectionHandler = dummy::ConnectionHandler;
+    type ToSwarm = Event;
+
+    fn handle_pending_inbound_connection(
+        &mut self,
+        _connection_id: ConnectionId,
+        _local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<(), ConnectionDenied> {
+        let Some(ip) = self.key(remote_addr) else {
+            return Ok(());
+        };
+        let now = now_ms();
+        if self.attempts.len() >= self.prune_at {
+            // Forget addresses whose window emptied, so one-off visitors are not kept forever
+            self.attempts.retain(|_, window| {
+                window.expire(now);
+                !window.is_empty()
+            });
+            self.prune_at = (self.attempts.len() * 2).max(MIN_PRUNE_AT);
+        }
+        let (max, window) = (self.config.max_attempts_per_ip, self.config.attempt_window);
+        let allowed = self.attempts.entry(ip).or_insert_with(|| SlidingWindow::new(max, window)).try_hit(now);
+        if allowed || self.trusted_ips.contains(&ip) {
+            return Ok(());
+        }
+        Err(self.deny(ip, None, DenyReason::TooManyAttempts))
+    }
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        _local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        let Some(ip) = self.key(remote_addr) else {
+            return Ok(dummy::ConnectionHandler);
+        };
+        if self.config.allowlist.contains(&peer) {
+            self.trusted_ips.insert(ip);
+            return Ok(dummy::ConnectionHandler);
+        }
+        if self.per_ip.get(&ip).copied().unwrap_or(0) >= self.config.max_connections_per_ip {
+            return Err(self.deny(ip, Some(peer), DenyReason::TooManyConnections));
+        }
+        self.connections.insert(connection_id, ip);
+        *self.per_ip.entry(ip).or_default() += 1;
+        Ok(dummy::ConnectionHandler)
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        _connection_id: ConnectionId,
+        _peer: PeerId,
+        _addr: &Multiaddr,
+        _role_override: Endpoint,
+        _port_use: PortUse,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        Ok(dummy::ConnectionHandler)
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm) {
+        if let FromSwarm::ConnectionClosed(closed) = event {
+            if let Some(ip) = self.connections.remove(&closed.connection_id) {
+                if let Some(count) = self.per_ip.get_mut(&ip) {
+                    *count -= 1;
+                    if *count == 0 {
+                        self.per_ip.remove(&ip);
+                    }
+                }
+            }
+        }
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        _peer_id: PeerId,
+        _connection_id: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        match event {}
+    }
+
+    fn poll(&mut self, _cx: &mut Context<'_>) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        if let Some(event) = self.events.pop_front() {
+            return Poll::Ready(ToSwarm::GenerateEvent(event));
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sliding_window_refuses_until_old_hits_expire() {
+        let mut window = SlidingWindow::new(2, Duration::from_millis(1000));
+        assert!(window.try_hit(0));
+        assert!(window.try_hit(500));
+        assert!(!window.try_hit(999));
+        // The refused hit was not recorded, and the first one has slid out
+        assert!(window.try_hit(1000));
+        assert!(!window.try_hit(1499));
+        assert!(window.try_hit(1500));
+        assert!(window.is_full(1500));
+        assert!(!window.is_full(2000));
+    }
+
+    #[test]
+    fn ipv6_is_grouped_by_prefix() {
+        let a: IpAddr = "2001:db8:1:2:aaaa::1".parse().unwrap();
+        let b: IpAddr = "2001:db8:1:2:bbbb::2".parse().unwrap();
+        assert_eq!(limit_key(a, true), limit_key(b, true));
+        assert_eq!(limit_key(a, true), "2001:db8:1:2::".parse::<IpAddr>().unwrap());
+        assert_ne!(limit_key(a, false), limit_key(b, false));
+        let v4: IpAddr = "10.0.0.1".parse().unwrap();
+        assert_eq!(limit_key(v4, true), v4);
+        assert_eq!(ip_of(&"/ip6/2001:db8::1/tcp/1".parse().unwrap()), Some("2001:db8::1".parse().unwrap()));
+    }
+
+    fn memory_swarm<B: NetworkBehaviour + Send>(behaviour: B) -> libp2p::Swarm<B> {
+        use libp2p::Transport;
+        libp2p::SwarmBuilder::with_new_identity()
+            .with_tokio()
+            .with_other_transport(|key| {
+                libp2p::core::transport::MemoryTransport::default()
+                    .upgrade(libp2p::core::upgrade::Version::V1)
+                    .authenticate(libp2p::noise::Config::new(key).expect("noise config"))
+                    .multiplex(libp2p::yamux::Config::default())
+            })
+            .unwrap()
+            .with_behaviour(|_| behaviour)
+            .unwrap()
+            .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
+            .build()
+    }
+
+    /// Memory addresses carry no IP; pretend every connection comes from one host.
+    fn same_host(_: &Multiaddr) -> Option<IpAddr> {
+        Some("192.0.2.1".parse().unwrap())
+    }
+
+    #[tokio::test]
+    async fn caps_connections_per_ip_except_for_allowlisted_peers() {
+        use futures::StreamExt;
+        use libp2p::swarm::SwarmEvent;
+
+        let allowed = memory_swarm(dummy::Behaviour);
+        let config = IpLimitsConfig {
+            max_connections_per_ip: 1,
+            allowlist: HashSet::from([*allowed.local_peer_id()]),
+            ..Default::default()
+        };
+        let mut server = memory_swarm(Behaviour::new(config).with_address_extractor(same_host));
+        server.listen_on(Protocol::Memory(0).into()).unwrap();
+        let addr = loop {
+            if let SwarmEvent::NewListenAddr { address, .. } = server.select_next_some().await {
+                break address;
+            }
+        };
+
+        let mut first = memory_swarm(dummy::Behaviour);
+        let mut second = memory_swarm(dummy::Behaviour);
+        let mut allowed = allowed;
+        let (first_id, second_id, allowed_id) = (*first.local_peer_id(), *second.local_peer_id(), *allowed.local_peer_id());
+
+        let mut established = HashSet::new();
+        let mut denied = None;
+        first.dial(addr.clone()).unwrap();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                tokio::select! {
+                    event = server.select_next_some() => match event {
+                        SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                            established.insert(peer_id);
+                            if peer_id == first_id {
+                                second.dial(addr.clone()).unwrap();
+                                allowed.dial(addr.clone()).unwrap();
+                            }
+                        }
+                        SwarmEvent::Behaviour(Event::Denied { peer_id, reason, .. }) => denied = Some((peer_id, reason)),
+                        _ => {}
+                    },
+                    _ = first.select_next_some() => {}
+                    _ = second.select_next_some() => {}
+                    _ = allowed.select_next_some() => {}
+                }
+                if denied.is_some() && established.contains(&allowed_id) {
+                    break;
+                }
+            }
+        })
+        .await
+        .expect("limits were not applied");
+
+        assert_eq!(denied, Some((Some(second_id), DenyReason::TooManyConnections)));
+        assert!(established.contains(&first_id) && !established.contains(&second_id));
+        assert_eq!(server.behaviour().connections_from("192.0.2.1".parse().unwrap()), 1);
+        assert_eq!(server.behaviour().stats().denied_connections, 1);
+    }
+}
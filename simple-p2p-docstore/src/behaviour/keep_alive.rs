@@ -0,0 +1,35 @@
+//! `/docstore/keep-alive/1.0.0`: an empty request answered with an empty response. Peers
+//! of active sessions are pinged with it, so their connections never count as idle; see
+//! [`crate::node::keeper`].
+
+use libp2p::request_response::{self, ProtocolSupport};
+use libp2p::StreamProtocol;
+use serde::{Deserialize, Serialize};
+
+pub const KEEP_ALIVE_PROTOCOL: &str = "/docstore/keep-alive/1.0.0";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeepAlive;
+
+pub type KeepAliveBehaviour = request_response::cbor::Behaviour<KeepAlive, KeepAlive>;
+
+/// Every role answers, so any peer can be kept.
+pub fn make_keep_alive_behaviour() -> KeepAliveBehaviour {
+    request_response::cbor::Behaviour::new(
+        [(StreamProtocol::new(KEEP_ALIVE_PROTOCOL), ProtocolSupport::Full)],
+        request_response::Config::default(),
+    )
+}
+
+/// Answer pings. Responses and failures need no handling: the exchange itself is the point.
+pub fn handle_event(behaviour: &mut KeepAliveBehaviour, event: request_response::Event<KeepAlive, KeepAlive>) {
+    match event {
+        request_response::Event::Message { message: request_response::Message::Request { channel, .. }, .. } => {
+            let _ = behaviour.send_response(channel, KeepAlive);
+        }
+        request_response::Event::OutboundFailure { peer, error, .. } => {
+            tracing::debug!("Keep-alive ping to {} failed: {}", peer, error);
+        }
+        _ => {}
+    }
+}
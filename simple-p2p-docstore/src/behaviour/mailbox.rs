@@ -0,0 +1,536 @@
+//! `/docstore/mailbox/1.0.0`: store-and-forward of messages addressed to a single peer,
+//! for peers that are offline when the message is sent.
+//!
+//! A sender deposits a blob for a recipient peer id, with a TTL, at a node serving the
+//! protocol (a FullNode or the relay server), which holds it until the recipient fetches
+//! it or the TTL runs out. Recipients fetch what is held for them as soon as they connect
+//! to such a node, or on demand, and acknowledge what they received, which the holder
+//! then deletes. A holder hands blobs only to the peer they are addressed to, as the
+//! connection authenticates it, but can read them: senders encrypt them for the
+//! recipient beforehand.
+//!
+//! Holders cap how much is held for each recipient, how much each sender may leave and
+//! how much they hold in all. They persist what they hold, at most every
+//! [`SAVE_INTERVAL`] and whenever their event loop ticks, so a restart loses nothing
+//! and a crash at most the last few seconds of deposits and acknowledgements.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use libp2p::request_response::{self, ProtocolSupport};
+use libp2p::{PeerId, StreamProtocol};
+use serde::{Deserialize, Serialize};
+
+use crate::node::migrations::{self, Artifact};
+
+pub const MAILBOX_PROTOCOL: &str = "/docstore/mailbox/1.0.0";
+
+/// Name of the file a holder keeps its mailbox in, in its store (or data) directory.
+pub const MAILBOX_FILE: &str = "mailbox.json";
+
+/// How long a blob is held when the sender does not say.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Longest a blob is held, whatever the sender asks for.
+pub const MAX_TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Mailbox requests a single peer may make per [`DEFAULT_RATE_WINDOW`].
+pub const DEFAULT_REQUESTS_PER_WINDOW: u32 = 60;
+
+pub const DEFAULT_RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Changes are saved at most this often as they happen; [`Mailbox::flush`] saves the
+/// rest.
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MailboxRequest {
+    /// Hold `blob` for `recipient` (a base58 peer id) for `ttl_ms`; `0` for the
+    /// holder's default.
+    Deposit { recipient: String, blob: Vec<u8>, ttl_ms: u64 },
+    /// Everything held for the requesting peer.
+    Fetch,
+    /// The requesting peer received these; delete them.
+    Ack { ids: Vec<u64> },
+}
+
+/// Where a deposited blob is held: its id at the holder and when it expires.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MailboxReceipt {
+    pub id: u64,
+    pub expires_at_ms: u64,
+}
+
+/// A blob held for the peer that fetched it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MailboxMessage {
+    /// Unique among the holder's messages.
+    pub id: u64,
+    /// Base58 peer id of the sender, as the holder authenticated it.
+    pub sender: String,
+    pub deposited_at_ms: u64,
+    pub expires_at_ms: u64,
+    pub blob: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MailboxResponse {
+    Deposited { id: u64, expires_at_ms: u64 },
+    /// Oldest first.
+    Messages(Vec<MailboxMessage>),
+    Acked { deleted: usize },
+    /// Over a quota, or not a valid deposit. See [`MailboxError`].
+    Rejected { reason: String },
+    /// Too many requests from this peer; try again after this many milliseconds.
+    RateLimited { retry_after_ms: u64 },
+}
+
+pub type MailboxBehaviour = request_response::cbor::Behaviour<MailboxRequest, MailboxResponse>;
+
+/// `serve`: hold blobs for others as well as send requests. Only nodes that stay online
+/// (FullNodes, the relay server) should, since recipients fetch from every peer that
+/// identify says serves the protocol.
+pub fn make_mailbox_behaviour(serve: bool) -> MailboxBehaviour {
+    let support = if serve { ProtocolSupport::Full } else { ProtocolSupport::Outbound };
+    request_response::cbor::Behaviour::new(
+        [(StreamProtocol::new(MAILBOX_PROTOCOL), support)],
+        request_response::Config::default(),
+    )
+}
+
+/// Why a deposit was refused.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum MailboxError {
+    #[error("invalid recipient {0:?}")]
+    InvalidRecipient(String),
+    #[error("blob of {size} bytes exceeds the maximum of {max} bytes")]
+    TooLarge { size: usize, max: usize },
+    #[error("mailbox of {recipient} is full")]
+    RecipientFull { recipient: PeerId },
+    #[error("sender already has the maximum of {max} messages held")]
+    SenderQuota { max: usize },
+    #[error("mailbox holds the maximum it will for all recipients")]
+    Full,
+}
+
+/// How much a holder keeps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MailboxLimits {
+    pub max_blob_bytes: usize,
+    /// Messages and bytes held for one recipient. A fetch answers with all of them, so
+    /// this also bounds the size of a response.
+    pub max_per_recipient: usize,
+    pub max_bytes_per_recipient: usize,
+    /// Messages held from one sender, across recipients.
+    pub max_per_sender: usize,
+    /// Messages and bytes held in all, whoever they are from and for.
+    pub max_messages: usize,
+    pub max_bytes: usize,
+    pub default_ttl: Duration,
+    pub max_ttl: Duration,
+}
+
+impl Default for MailboxLimits {
+    fn default() -> Self {
+        Self {
+            max_blob_bytes: 64 * 1024,
+            max_per_recipient: 100,
+            max_bytes_per_recipient: 1024 * 1024,
+            max_per_sender: 50,
+            max_messages: 100_000,
+            max_bytes: 256 * 1024 * 1024,
+            default_ttl: DEFAULT_TTL,
+            max_ttl: MAX_TTL,
+        }
+    }
+}
+
+/// A held message as the mailbox file keeps it. The blob is in base64, which JSON would
+/// otherwise spell out as a list of numbers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Held {
+    recipient: String,
+    id: u64,
+    sender: String,
+    deposited_at_ms: u64,
+    expires_at_ms: u64,
+    blob: String,
+}
+
+impl Held {
+    fn new(recipient: &PeerId, message: &MailboxMessage) -> Self {
+        Self {
+            recipient: recipient.to_string(),
+            id: message.id,
+            sender: message.sender.clone(),
+            deposited_at_ms: message.deposited_at_ms,
+            expires_at_ms: message.expires_at_ms,
+            blob: STANDARD.encode(&message.blob),
+        }
+    }
+
+    /// `None` if the recipient or blob don't decode.
+    fn into_message(self) -> Option<(PeerId, MailboxMessage)> {
+        let recipient = self.recipient.parse().ok()?;
+        let blob = STANDARD.decode(&self.blob).ok()?;
+        let Self { id, sender, deposited_at_ms, expires_at_ms, .. } = self;
+        Some((recipient, MailboxMessage { id, sender, deposited_at_ms, expires_at_ms, blob }))
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Stored {
+    next_id: u64,
+    held: Vec<Held>,
+}
+
+/// The blobs a holder keeps, see the [module docs](self).
+#[derive(Debug, Default)]
+pub struct Mailbox {
+    path: Option<PathBuf>,
+    limits: MailboxLimits,
+    /// Per recipient, oldest first.
+    held: HashMap<PeerId, Vec<MailboxMessage>>,
+    next_id: u64,
+    /// Whether anything changed since the last save, and when that was.
+    dirty: bool,
+    saved_at_ms: u64,
+}
+
+impl Mailbox {
+    /// Load what was held at `path`, or start empty if there is nothing or it is
+    /// unreadable. Without a path, the mailbox is kept in memory only, as it is when the
+    /// file is in another format version, so as not to save over it.
+    pub fn load(mut path: Option<PathBuf>, limits: MailboxLimits) -> Self {
+        let mut other_version = false;
+        let stored = match &path {
+            Some(path) => read_stored(path).unwrap_or_else(|e| {
+                tracing::warn!("Ignoring unreadable mailbox {}: {}", path.display(), e);
+                other_version = migrations::is_version_error(&e);
+                Stored::default()
+            }),
+            None => Stored::default(),
+        };
+        if other_version {
+            path = None;
+        }
+        let mut held: HashMap<PeerId, Vec<MailboxMessage>> = HashMap::new();
+        for (recipient, message) in stored.held.into_iter().filter_map(Held::into_message) {
+            held.entry(recipient).or_default().push(message);
+        }
+        Self { path, limits, held, next_id: stored.next_id, dirty: false, saved_at_ms: 0 }
+    }
+
+    /// Answer `request` from `peer`, saving the mailbox if it changed.
+    pub fn respond(&mut self, peer: PeerId, request: &MailboxRequest, now_ms: u64) -> MailboxResponse {
+        match request {
+            MailboxRequest::Deposit { recipient, blob, ttl_ms } => {
+                match self.deposit(peer, recipient, blob.clone(), *ttl_ms, now_ms) {
+                    Ok(MailboxReceipt { id, expires_at_ms }) => MailboxResponse::Deposited { id, expires_at_ms },
+                    Err(e) => MailboxResponse::Rejected { reason: e.to_string() },
+                }
+            }
+            MailboxRequest::Fetch => MailboxResponse::Messages(self.fetch(&peer, now_ms)),
+            MailboxRequest::Ack { ids } => MailboxResponse::Acked { deleted: self.ack(&peer, ids, now_ms) },
+        }
+    }
+
+    /// Hold `blob` from `sender` for `recipient`.
+    pub fn deposit(
+        &mut self,
+        sender: PeerId,
+        recipient: &str,
+        blob: Vec<u8>,
+        ttl_ms: u64,
+        now_ms: u64,
+    ) -> Result<MailboxReceipt, MailboxError> {
+        let recipient: PeerId = recipient.parse().map_err(|_| MailboxError::InvalidRecipient(recipient.to_string()))?;
+        let limits = self.limits;
+        if blob.len() > limits.max_blob_bytes {
+            return Err(MailboxError::TooLarge { size: blob.len(), max: limits.max_blob_bytes });
+        }
+        self.expire(now_ms);
+        let sender_id = sender.to_string();
+        let from_sender = self.held.values().flatten().filter(|m| m.sender == sender_id).count();
+        if from_sender >= limits.max_per_sender {
+            return Err(MailboxError::SenderQuota { max: limits.max_per_sender });
+        }
+        let total_bytes: usize = self.held.values().flatten().map(|m| m.blob.len()).sum();
+        if self.len() >= limits.max_messages || total_bytes + blob.len() > limits.max_bytes {
+            return Err(MailboxError::Full);
+        }
+        let queue = self.held.get(&recipient).map_or(&[][..], Vec::as_slice);
+        let bytes: usize = queue.iter().map(|m| m.blob.len()).sum();
+        if queue.len() >= limits.max_per_recipient || bytes + blob.len() > limits.max_bytes_per_recipient {
+            return Err(MailboxError::RecipientFull { recipient });
+        }
+        let ttl = match ttl_ms {
+            0 => limits.default_ttl,
+            ms => Duration::from_millis(ms).min(limits.max_ttl),
+        };
+        self.next_id += 1;
+        let message = MailboxMessage {
+            id: self.next_id,
+            sender: sender_id,
+            deposited_at_ms: now_ms,
+            expires_at_ms: now_ms + ttl.as_millis() as u64,
+            blob,
+        };
+        let receipt = MailboxReceipt { id: message.id, expires_at_ms: message.expires_at_ms };
+        self.held.entry(recipient).or_default().push(message);
+        self.changed(now_ms);
+        Ok(receipt)
+    }
+
+    /// Everything held for `recipient` that has not expired, oldest first. Kept until
+    /// acknowledged.
+    pub fn fetch(&mut self, recipient: &PeerId, now_ms: u64) -> Vec<MailboxMessage> {
+        self.expire(now_ms);
+        self.held.get(recipient).cloned().unwrap_or_default()
+    }
+
+    /// Delete the messages `ids` held for `recipient`; ids of other recipients' messages
+    /// are ignored. Returns how many were deleted.
+    pub fn ack(&mut self, recipient: &PeerId, ids: &[u64], now_ms: u64) -> usize {
+        let Some(queue) = self.held.get_mut(recipient) else { return 0 };
+        let before = queue.len();
+        queue.retain(|m| !ids.contains(&m.id));
+        let deleted = before - queue.len();
+        if queue.is_empty() {
+            self.held.remove(recipient);
+        }
+        if deleted > 0 {
+            self.changed(now_ms);
+        }
+        deleted
+    }
+
+    /// Drop the messages whose TTL ran out. Returns how many.
+    pub fn expire(&mut self, now_ms: u64) -> usize {
+        let mut expired = 0;
+        self.held.retain(|_, queue| {
+            let before = queue.len();
+            queue.retain(|m| m.expires_at_ms > now_ms);
+            expired += before - queue.len();
+            !queue.is_empty()
+        });
+        if expired > 0 {
+            self.changed(now_ms);
+        }
+        expired
+    }
+
+    /// Messages held, for all recipients.
+    pub fn len(&self) -> usize {
+        self.held.values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.held.is_empty()
+    }
+
+    /// Save what changed since the last save, if anything.
+    pub fn flush(&mut self) {
+        if !self.dirty {
+            return;
+        }
+        self.dirty = false;
+        let Some(path) = &self.path else {
+            return;
+        };
+        let mut held: Vec<Held> = self
+            .held
+            .iter()
+            .flat_map(|(recipient, queue)| queue.iter().map(|message| Held::new(recipient, message)))
+            .collect();
+        held.sort_by_key(|h| h.id);
+        if let Err(e) = write_stored(path, &Stored { next_id: self.next_id, held }) {
+            tracing::warn!("Failed to save the mailbox to {}: {}", path.display(), e);
+        }
+    }
+
+    /// Note a change, saving it unless the last save was less than [`SAVE_INTERVAL`] ago.
+    fn changed(&mut self, now_ms: u64) {
+        self.dirty = true;
+        if now_ms.saturating_sub(self.saved_at_ms) >= SAVE_INTERVAL.as_millis() as u64 {
+            self.saved_at_ms = now_ms;
+            self.flush();
+        }
+    }
+}
+
+impl Drop for Mailbox {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// Mailbox v1 held blobs as JSON lists of numbers, under a `message` object per entry.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn migrate_v1(path: &Path) -> io::Result<()> {
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+    let mut value: serde_json::Value = serde_json::from_slice(&std::fs::read(path)?).map_err(|e| invalid(e.to_string()))?;
+    let fields = value.as_object_mut().ok_or_else(|| invalid("expected an object".into()))?;
+    let held =
+        fields.get_mut("held").and_then(|held| held.as_array_mut()).ok_or_else(|| invalid("expected `held`".into()))?;
+    for entry in held.iter_mut() {
+        let entry = entry.as_object_mut().ok_or_else(|| invalid("expected an object per message".into()))?;
+        let Some(serde_json::Value::Object(mut message)) = entry.remove("message") else {
+            return Err(invalid("expected a `message` object".into()));
+        };
+        let blob: Vec<u8> = serde_json::from_value(message.remove("blob").unwrap_or_default())
+            .map_err(|e| invalid(e.to_string()))?;
+        entry.extend(message);
+        entry.insert("blob".to_string(), STANDARD.encode(blob).into());
+    }
+    fields.insert(migrations::FORMAT_FIELD.to_string(), 2.into());
+    let bytes = serde_json::to_vec(&value).map_err(io::Error::other)?;
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, bytes)?;
+    std::fs::rename(&tmp, path)
+}
+
+fn read_stored(path: &Path) -> io::Result<Stored> {
+    match std::fs::read(path) {
+        Ok(bytes) => migrations::decode_json(Artifact::Mailbox, &bytes),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Stored::default()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Through a temporary file, so a crash never leaves the mailbox truncated.
+fn write_stored(path: &Path, stored: &Stored) -> io::Result<()> {
+    let bytes = serde_json::to_vec(&migrations::with_header(Artifact::Mailbox, stored)?).map_err(io::Error::other)?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, bytes)?;
+    std::fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000_000;
+
+    fn deposit(recipient: &PeerId, blob: &[u8], ttl_ms: u64) -> MailboxRequest {
+        MailboxRequest::Deposit { recipient: recipient.to_string(), blob: blob.to_vec(), ttl_ms }
+    }
+
+    fn ids(response: MailboxResponse) -> Vec<u64> {
+        match response {
+            MailboxResponse::Messages(messages) => messages.iter().map(|m| m.id).collect(),
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[test]
+    fn held_until_acknowledged_or_expired() {
+        let dir = std::env::temp_dir().join(format!("mailbox-held-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join(MAILBOX_FILE);
+        let (sender, recipient, stranger) = (PeerId::random(), PeerId::random(), PeerId::random());
+
+        let mut mailbox = Mailbox::load(Some(path.clone()), MailboxLimits::default());
+        assert_eq!(
+            mailbox.respond(sender, &deposit(&recipient, b"hi", 0), NOW),
+            MailboxResponse::Deposited { id: 1, expires_at_ms: NOW + DEFAULT_TTL.as_millis() as u64 }
+        );
+        mailbox.respond(sender, &deposit(&recipient, b"short", 1_000), NOW);
+        // Only the recipient gets its messages, and only it can delete them
+        assert_eq!(ids(mailbox.respond(stranger, &MailboxRequest::Fetch, NOW)), Vec::<u64>::new());
+        assert_eq!(mailbox.respond(stranger, &MailboxRequest::Ack { ids: vec![1, 2] }, NOW), MailboxResponse::Acked { deleted: 0 });
+        drop(mailbox);
+        // Saved on the way out, with blobs in base64
+        assert!(String::from_utf8(std::fs::read(&path).unwrap()).unwrap().contains(r#""blob":"aGk=""#));
+
+        // Persisted across a restart
+        let mut mailbox = Mailbox::load(Some(path), MailboxLimits::default());
+        let MailboxResponse::Messages(messages) = mailbox.respond(recipient, &MailboxRequest::Fetch, NOW + 500) else {
+            panic!("expected messages");
+        };
+        assert_eq!((messages[0].sender.clone(), messages[0].blob.clone()), (sender.to_string(), b"hi".to_vec()));
+        assert_eq!(messages.len(), 2);
+        assert_eq!(ids(mailbox.respond(recipient, &MailboxRequest::Fetch, NOW + 1_000)), [1], "TTL ran out");
+        assert_eq!(mailbox.respond(recipient, &MailboxRequest::Ack { ids: vec![1] }, NOW), MailboxResponse::Acked { deleted: 1 });
+        assert!(mailbox.is_empty());
+        // Ids are not reused after a restart
+        assert_eq!(mailbox.deposit(sender, &recipient.to_string(), b"again".to_vec(), 0, NOW).map(|r| r.id), Ok(3));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn quotas_and_limits() {
+        let limits = MailboxLimits {
+            max_blob_bytes: 10,
+            max_per_recipient: 2,
+            max_bytes_per_recipient: 15,
+            max_per_sender: 3,
+            ..Default::default()
+        };
+        let mut mailbox = Mailbox::load(None, limits);
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+        let (carol, dave, erin) = (PeerId::random(), PeerId::random(), PeerId::random());
+
+        assert_eq!(mailbox.deposit(alice, "nobody", b"x".to_vec(), 0, NOW), Err(MailboxError::InvalidRecipient("nobody".into())));
+        assert_eq!(mailbox.deposit(alice, &carol.to_string(), vec![0; 11], 0, NOW), Err(MailboxError::TooLarge { size: 11, max: 10 }));
+        assert!(mailbox.deposit(alice, &carol.to_string(), vec![0; 10], 0, NOW).is_ok());
+        // Carol's 15 bytes would be exceeded, then her 2 messages
+        assert_eq!(mailbox.deposit(bob, &carol.to_string(), vec![0; 6], 0, NOW), Err(MailboxError::RecipientFull { recipient: carol }));
+        assert!(mailbox.deposit(bob, &carol.to_string(), vec![0; 5], 0, NOW).is_ok());
+        assert_eq!(mailbox.deposit(bob, &carol.to_string(), vec![0; 1], 0, NOW), Err(MailboxError::RecipientFull { recipient: carol }));
+
+        assert!(mailbox.deposit(alice, &dave.to_string(), b"1".to_vec(), 0, NOW).is_ok());
+        assert!(mailbox.deposit(alice, &erin.to_string(), b"2".to_vec(), 0, NOW).is_ok());
+        assert_eq!(mailbox.deposit(alice, &erin.to_string(), b"3".to_vec(), 0, NOW), Err(MailboxError::SenderQuota { max: 3 }));
+
+        // Expiry frees the quota; TTLs are capped
+        let expires = NOW + MAX_TTL.as_millis() as u64;
+        let receipt = MailboxReceipt { id: 6, expires_at_ms: expires };
+        assert_eq!(mailbox.deposit(bob, &dave.to_string(), b"4".to_vec(), u64::MAX, NOW), Ok(receipt));
+        assert_eq!(mailbox.expire(NOW + DEFAULT_TTL.as_millis() as u64), 4);
+        assert_eq!(mailbox.len(), 1);
+        assert!(mailbox.deposit(alice, &erin.to_string(), b"3".to_vec(), 0, NOW).is_ok());
+    }
+
+    #[test]
+    fn the_whole_mailbox_is_capped() {
+        let limits = MailboxLimits { max_messages: 2, max_bytes: 10, ..Default::default() };
+        let mut mailbox = Mailbox::load(None, limits);
+        let (alice, bob, carol) = (PeerId::random(), PeerId::random(), PeerId::random());
+        assert!(mailbox.deposit(alice, &bob.to_string(), vec![0; 6], 0, NOW).is_ok());
+        // Every sender and recipient is within their own quota, not the mailbox
+        assert_eq!(mailbox.deposit(carol, &alice.to_string(), vec![0; 5], 0, NOW), Err(MailboxError::Full));
+        assert!(mailbox.deposit(carol, &alice.to_string(), vec![0; 4], 0, NOW).is_ok());
+        assert_eq!(mailbox.deposit(bob, &carol.to_string(), Vec::new(), 0, NOW), Err(MailboxError::Full));
+        assert_eq!(mailbox.ack(&bob, &[1], NOW), 1);
+        assert!(mailbox.deposit(bob, &carol.to_string(), Vec::new(), 0, NOW).is_ok());
+    }
+
+    #[test]
+    fn saves_are_spaced_out_until_flushed() {
+        let dir = std::env::temp_dir().join(format!("mailbox-saves-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join(MAILBOX_FILE);
+        let (sender, recipient) = (PeerId::random(), PeerId::random());
+        let mut mailbox = Mailbox::load(Some(path.clone()), MailboxLimits::default());
+        let held = || Mailbox::load(Some(path.clone()), MailboxLimits::default()).fetch(&recipient, NOW).len();
+
+        mailbox.deposit(sender, &recipient.to_string(), b"1".to_vec(), 0, NOW).unwrap();
+        mailbox.deposit(sender, &recipient.to_string(), b"2".to_vec(), 0, NOW + 1).unwrap();
+        assert_eq!(held(), 1);
+        mailbox.deposit(sender, &recipient.to_string(), b"3".to_vec(), 0, NOW + SAVE_INTERVAL.as_millis() as u64).unwrap();
+        assert_eq!(held(), 3);
+        mailbox.ack(&recipient, &[3], NOW + SAVE_INTERVAL.as_millis() as u64);
+        assert_eq!(held(), 3);
+        mailbox.flush();
+        assert_eq!(held(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
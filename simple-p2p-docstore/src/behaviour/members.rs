@@ -0,0 +1,602 @@
+//! `/docstore/members/1.0.0`: who has ever joined a room, kept by FullNodes.
+//!
+//! Presence heartbeats only tell who is online right now. Members also report their
+//! presence in a room to the FullNodes they are connected to, and that they left when they
+//! leave it. A FullNode keeps a membership table per room from those reports: when each
+//! member first joined, when its latest stay began, when it was last seen and when it
+//! left. It pages through the table for anyone who asks. A member that stops reporting
+//! without leaving counts as gone [`MEMBER_TIMEOUT`] after it was last seen.
+//!
+//! In a restricted room a report carries the member's presence frame, and the FullNode
+//! only records members holding a token or guest pass from the room's creator. The
+//! FullNode never sees the creator's revocation lists, so a revoked member stays on the
+//! list until its token expires.
+//!
+//! A member may mark a room private. The FullNode then drops the room's table and records
+//! nothing more for it, keeping only the fact that it is private.
+//!
+//! Tables are saved whenever someone joins or leaves. Sightings in between only move
+//! `last_seen_ms`, and a restart loses them.
+//!
+//! Browsers also derive `memberJoined` and `memberLeft` events from the presence they
+//! receive, with a [`PresenceTracker`].
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use libp2p::request_response::{self, ProtocolSupport};
+use libp2p::{PeerId, StreamProtocol};
+use serde::{Deserialize, Serialize};
+
+use crate::behaviour::docstore::auth::{AuthError, Credential, PresenceFrame};
+use crate::node::migrations::{self, Artifact};
+
+pub const MEMBERS_PROTOCOL: &str = "/docstore/members/1.0.0";
+
+/// Name of the file a FullNode keeps its membership tables in, in its store directory.
+pub const MEMBERS_FILE: &str = "members.json";
+
+/// The most members one page lists.
+pub const MAX_MEMBERS_PAGE: usize = 256;
+
+/// Members kept per room. Once full, the member that left longest ago makes way for a
+/// new one; if everyone is still present, the newcomer is refused.
+pub const MAX_MEMBERS_PER_ROOM: usize = 10_000;
+
+/// Rooms a FullNode keeps tables for; reports about further rooms are refused.
+pub const MAX_MEMBER_ROOMS: usize = 10_000;
+
+/// How long a member counts as present after its last report: three missed presence
+/// heartbeats.
+pub const MEMBER_TIMEOUT: Duration = Duration::from_secs(90);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MembersRequest {
+    /// The requesting peer is in `room_id`. `private` asks the FullNode to keep no table
+    /// for the room at all.
+    Present { room_id: String, restricted: Option<RestrictedPresence>, private: bool },
+    /// The requesting peer left `room_id`.
+    Left { room_id: String },
+    /// Up to `limit` members of `room_id` (`0` for a full page), in peer id order,
+    /// starting after the peer id `after`.
+    List { room_id: String, after: Option<String>, limit: u32 },
+}
+
+/// What a member of a restricted room shows: the room's creator, and its latest presence
+/// frame, which carries its token or guest pass.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RestrictedPresence {
+    /// Base58 peer id.
+    pub creator: String,
+    pub frame: Vec<u8>,
+}
+
+/// One member of a room, as a FullNode saw it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Member {
+    /// Base58 peer id.
+    pub peer_id: String,
+    pub first_joined_at_ms: u64,
+    /// Start of the current stay, or of the last one if the member left.
+    pub joined_at_ms: u64,
+    pub last_seen_ms: u64,
+    /// `None` while the member is present.
+    pub left_at_ms: Option<u64>,
+}
+
+impl Member {
+    pub fn is_present(&self) -> bool {
+        self.left_at_ms.is_none()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MembersPage {
+    pub members: Vec<Member>,
+    /// Pass as `after` for the following page; `None` on the last one.
+    pub next: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MembersResponse {
+    Recorded,
+    Page(MembersPage),
+    /// Not a valid report. See [`MembersError`].
+    Refused { reason: String },
+    /// The room is private; the FullNode keeps nothing for it.
+    Private,
+}
+
+pub type MembersBehaviour = request_response::cbor::Behaviour<MembersRequest, MembersResponse>;
+
+/// FullNodes (`serve`) keep membership tables; the others report to them and ask them.
+pub fn make_members_behaviour(serve: bool) -> MembersBehaviour {
+    let support = if serve { ProtocolSupport::Full } else { ProtocolSupport::Outbound };
+    request_response::cbor::Behaviour::new(
+        [(StreamProtocol::new(MEMBERS_PROTOCOL), support)],
+        request_response::Config::default(),
+    )
+}
+
+/// A members query as the node handles take it: the request minus the room, plus whom to
+/// ask.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MembersOptions {
+    /// `None` picks the best-ranked connected peer keeping membership tables, or this node
+    /// itself if it keeps them.
+    pub peer_id: Option<PeerId>,
+    pub after: Option<String>,
+    /// `0` for a full page.
+    pub limit: u32,
+}
+
+impl MembersOptions {
+    pub fn request(&self, room_id: impl Into<String>) -> MembersRequest {
+        MembersRequest::List { room_id: room_id.into(), after: self.after.clone(), limit: self.limit }
+    }
+}
+
+/// The page in a response to a [`MembersRequest::List`], or why there is none.
+pub fn members_result(response: MembersResponse) -> Result<MembersPage, crate::Error> {
+    match response {
+        MembersResponse::Page(page) => Ok(page),
+        MembersResponse::Refused { reason } => Err(crate::Error::MembersUnavailable { reason }),
+        MembersResponse::Private => Err(crate::Error::MembersUnavailable { reason: "the room is private".to_string() }),
+        MembersResponse::Recorded => Err(crate::Error::Transport("unexpected members response".to_string())),
+    }
+}
+
+/// Why a report was refused.
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum MembersError {
+    #[error("invalid creator {0:?}")]
+    InvalidCreator(String),
+    #[error("the room was created by {expected}")]
+    WrongCreator { expected: String },
+    #[error("the room is restricted and the report shows no credential")]
+    MissingCredential,
+    #[error(transparent)]
+    Credential(#[from] AuthError),
+    #[error("the room already has {max} members present")]
+    RoomFull { max: usize },
+    #[error("tables are kept for at most {max} rooms")]
+    TooManyRooms { max: usize },
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct RoomMembers {
+    /// Base58 peer id of the creator of a restricted room, from its first report.
+    creator: Option<String>,
+    private: bool,
+    /// By peer id.
+    members: BTreeMap<String, Member>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Stored {
+    rooms: BTreeMap<String, RoomMembers>,
+}
+
+/// The membership tables of a FullNode, see the [module docs](self).
+#[derive(Debug, Default)]
+pub struct MembershipTable {
+    path: Option<PathBuf>,
+    rooms: BTreeMap<String, RoomMembers>,
+}
+
+impl MembershipTable {
+    /// Load the tables saved at `path`, or start empty if there are none or they are
+    /// unreadable. Without a path the tables are kept in memory only, as they are when the
+    /// file is in another format version, so as not to save over it.
+    pub fn load(mut path: Option<PathBuf>) -> Self {
+        let mut other_version = false;
+        let stored = match &path {
+            Some(path) => read_stored(path).unwrap_or_else(|e| {
+                tracing::warn!("Ignoring unreadable membership tables {}: {}", path.display(), e);
+                other_version = migrations::is_version_error(&e);
+                Stored::default()
+            }),
+            None => Stored::default(),
+        };
+        if other_version {
+            path = None;
+        }
+        Self { path, rooms: stored.rooms }
+    }
+
+    /// Answer `request` from `peer`, saving the tables if someone joined or left.
+    pub fn respond(&mut self, peer: PeerId, request: &MembersRequest, now_ms: u64) -> MembersResponse {
+        match request {
+            MembersRequest::Present { room_id, .. } | MembersRequest::Left { room_id } if self.is_private(room_id) => {
+                MembersResponse::Private
+            }
+            MembersRequest::Present { room_id, restricted, private } => {
+                match self.present(peer, room_id, restricted.as_ref(), *private, now_ms) {
+                    Ok(()) if *private => MembersResponse::Private,
+                    Ok(()) => MembersResponse::Recorded,
+                    Err(e) => MembersResponse::Refused { reason: e.to_string() },
+                }
+            }
+            MembersRequest::Left { room_id } => {
+                self.left(&peer, room_id, now_ms);
+                MembersResponse::Recorded
+            }
+            MembersRequest::List { room_id, after, limit } => match self.list(room_id, after.as_deref(), *limit, now_ms) {
+                Some(page) => MembersResponse::Page(page),
+                None => MembersResponse::Private,
+            },
+        }
+    }
+
+    /// `peer` is in `room_id`. In a restricted room, `restricted` must show that the
+    /// room's creator let it in. `private` drops the room's table for good.
+    pub fn present(
+        &mut self,
+        peer: PeerId,
+        room_id: &str,
+        restricted: Option<&RestrictedPresence>,
+        private: bool,
+        now_ms: u64,
+    ) -> Result<(), MembersError> {
+        let room = self.rooms.get(room_id);
+        if room.is_some_and(|room| room.private) {
+            return Ok(());
+        }
+        let recorded = room.and_then(|room| room.creator.as_deref());
+        let creator = match restricted {
+            Some(restricted) => Some(verify_presence(&peer, room_id, restricted, recorded, now_ms)?),
+            None if recorded.is_some() => return Err(MembersError::MissingCredential),
+            None => None,
+        };
+        if room.is_none() && self.rooms.len() >= MAX_MEMBER_ROOMS {
+            return Err(MembersError::TooManyRooms { max: MAX_MEMBER_ROOMS });
+        }
+        if private {
+            self.rooms.insert(room_id.to_string(), RoomMembers { private: true, ..Default::default() });
+            self.save();
+            return Ok(());
+        }
+
+        let room = self.rooms.entry(room_id.to_string()).or_default();
+        let id = peer.to_string();
+        if !room.members.contains_key(&id) && room.members.len() >= MAX_MEMBERS_PER_ROOM {
+            let gone = room.members.values().filter_map(|m| Some((m.left_at_ms?, m.peer_id.clone()))).min();
+            let Some((_, gone)) = gone else {
+                return Err(MembersError::RoomFull { max: MAX_MEMBERS_PER_ROOM });
+            };
+            room.members.remove(&gone);
+        }
+        let mut changed = room.creator.is_none() && creator.is_some();
+        room.creator = room.creator.take().or(creator);
+        match room.members.get_mut(&id) {
+            Some(member) => {
+                member.last_seen_ms = now_ms;
+                if member.left_at_ms.take().is_some() {
+                    member.joined_at_ms = now_ms;
+                    changed = true;
+                }
+            }
+            None => {
+                let member = Member {
+                    peer_id: id.clone(),
+                    first_joined_at_ms: now_ms,
+                    joined_at_ms: now_ms,
+                    last_seen_ms: now_ms,
+                    left_at_ms: None,
+                };
+                room.members.insert(id, member);
+                changed = true;
+            }
+        }
+        if changed {
+            self.save();
+        }
+        Ok(())
+    }
+
+    /// `peer` left `room_id`. Returns whether it had been present.
+    pub fn left(&mut self, peer: &PeerId, room_id: &str, now_ms: u64) -> bool {
+        let member = self.rooms.get_mut(room_id).and_then(|room| room.members.get_mut(&peer.to_string()));
+        let Some(member) = member.filter(|member| member.is_present()) else {
+            return false;
+        };
+        member.last_seen_ms = now_ms;
+        member.left_at_ms = Some(now_ms);
+        self.save();
+        true
+    }
+
+    /// Up to `limit` members of `room_id` after the peer id `after`; `None` if the room
+    /// is private.
+    pub fn list(&mut self, room_id: &str, after: Option<&str>, limit: u32, now_ms: u64) -> Option<MembersPage> {
+        self.expire(now_ms);
+        let Some(room) = self.rooms.get(room_id) else {
+            return Some(MembersPage { members: Vec::new(), next: None });
+        };
+        if room.private {
+            return None;
+        }
+        let limit = match limit as usize {
+            0 => MAX_MEMBERS_PAGE,
+            limit => limit.min(MAX_MEMBERS_PAGE),
+        };
+        let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+        let mut members: Vec<Member> =
+            room.members.range::<str, _>((start, Bound::Unbounded)).take(limit + 1).map(|(_, m)| m.clone()).collect();
+        let next = (members.len() > limit).then(|| {
+            members.truncate(limit);
+            members[limit - 1].peer_id.clone()
+        });
+        Some(MembersPage { members, next })
+    }
+
+    /// Mark the members not seen for [`MEMBER_TIMEOUT`] as gone since they were last seen.
+    /// Returns how many.
+    pub fn expire(&mut self, now_ms: u64) -> usize {
+        let timeout = MEMBER_TIMEOUT.as_millis() as u64;
+        let mut expired = 0;
+        for member in self.rooms.values_mut().flat_map(|room| room.members.values_mut()) {
+            if member.is_present() && member.last_seen_ms + timeout <= now_ms {
+                member.left_at_ms = Some(member.last_seen_ms);
+                expired += 1;
+            }
+        }
+        if expired > 0 {
+            self.save();
+        }
+        expired
+    }
+
+    pub fn is_private(&self, room_id: &str) -> bool {
+        self.rooms.get(room_id).is_some_and(|room| room.private)
+    }
+
+    /// Rooms with a table, private ones included.
+    pub fn len(&self) -> usize {
+        self.rooms.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rooms.is_empty()
+    }
+
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        if let Err(e) = write_stored(path, &self.rooms) {
+            tracing::warn!("Failed to save the membership tables to {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Check that `peer` may be in restricted `room_id`. Returns the room's creator.
+fn verify_presence(
+    peer: &PeerId,
+    room_id: &str,
+    restricted: &RestrictedPresence,
+    recorded: Option<&str>,
+    now_ms: u64,
+) -> Result<String, MembersError> {
+    let creator: PeerId =
+        restricted.creator.parse().map_err(|_| MembersError::InvalidCreator(restricted.creator.clone()))?;
+    if let Some(expected) = recorded.filter(|expected| *expected != restricted.creator) {
+        return Err(MembersError::WrongCreator { expected: expected.to_string() });
+    }
+    if *peer != creator {
+        let credential = match PresenceFrame::decode(&restricted.frame)? {
+            PresenceFrame::Presence { token: Some(token), .. } => Credential::Token(token),
+            PresenceFrame::GuestPresence { pass, .. } => Credential::Guest(pass),
+            PresenceFrame::Presence { token: None, .. } | PresenceFrame::Revocations(_) => {
+                return Err(MembersError::MissingCredential)
+            }
+        };
+        credential.verify(room_id, &creator, peer, now_ms, &HashSet::new())?;
+    }
+    Ok(restricted.creator.clone())
+}
+
+fn read_stored(path: &Path) -> io::Result<Stored> {
+    match std::fs::read(path) {
+        Ok(bytes) => migrations::decode_json(Artifact::Members, &bytes),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Stored::default()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Through a temporary file, so a crash never leaves the tables truncated.
+fn write_stored(path: &Path, rooms: &BTreeMap<String, RoomMembers>) -> io::Result<()> {
+    #[derive(Serialize)]
+    struct StoredRef<'a> {
+        rooms: &'a BTreeMap<String, RoomMembers>,
+    }
+    let value = migrations::with_header(Artifact::Members, &StoredRef { rooms })?;
+    let bytes = serde_json::to_vec(&value).map_err(io::Error::other)?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, bytes)?;
+    std::fs::rename(&tmp, path)
+}
+
+/// Answer a members request, on a FullNode.
+pub fn handle_request(
+    behaviour: &mut MembersBehaviour,
+    table: &mut MembershipTable,
+    peer: PeerId,
+    request: MembersRequest,
+    channel: request_response::ResponseChannel<MembersResponse>,
+    now_ms: u64,
+) {
+    let response = table.respond(peer, &request, now_ms);
+    if let MembersResponse::Refused { reason } = &response {
+        tracing::debug!("Refused members report from {}: {}", peer, reason);
+    }
+    if behaviour.send_response(channel, response).is_err() {
+        tracing::debug!("Members requester {} went away before the response", peer);
+    }
+}
+
+/// Who is online in the rooms we joined, from the presence we receive: a peer joins with
+/// its first presence message and leaves once it sent none for [`MEMBER_TIMEOUT`].
+#[derive(Debug, Default)]
+pub struct PresenceTracker {
+    /// When each peer was last seen, by room.
+    rooms: HashMap<String, HashMap<PeerId, u64>>,
+}
+
+impl PresenceTracker {
+    /// `peer` sent presence in `room_id`; `true` if it just joined.
+    pub fn seen(&mut self, room_id: &str, peer: PeerId, now_ms: u64) -> bool {
+        let room = self.rooms.entry(room_id.to_string()).or_default();
+        room.insert(peer, now_ms).is_none()
+    }
+
+    /// The peers that went quiet, by room.
+    pub fn expire(&mut self, now_ms: u64) -> Vec<(String, PeerId)> {
+        let timeout = MEMBER_TIMEOUT.as_millis() as u64;
+        let mut gone = Vec::new();
+        for (room_id, peers) in &mut self.rooms {
+            peers.retain(|peer, last_seen| {
+                let present = *last_seen + timeout > now_ms;
+                if !present {
+                    gone.push((room_id.clone(), *peer));
+                }
+                present
+            });
+        }
+        self.rooms.retain(|_, peers| !peers.is_empty());
+        gone
+    }
+
+    /// We left `room_id`.
+    pub fn forget_room(&mut self, room_id: &str) {
+        self.rooms.remove(room_id);
+    }
+
+    /// The peers present in `room_id`.
+    pub fn present(&self, room_id: &str) -> Vec<PeerId> {
+        self.rooms.get(room_id).map(|peers| peers.keys().copied().collect()).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::behaviour::docstore::auth::{issue_capability, Capability, Permission};
+    use libp2p::identity::Keypair;
+
+    const NOW: u64 = 1_700_000_000_000;
+
+    fn present(room_id: &str) -> MembersRequest {
+        MembersRequest::Present { room_id: room_id.to_string(), restricted: None, private: false }
+    }
+
+    fn list(room_id: &str, after: Option<&str>, limit: u32) -> MembersRequest {
+        MembersRequest::List { room_id: room_id.to_string(), after: after.map(str::to_string), limit }
+    }
+
+    fn page(response: MembersResponse) -> MembersPage {
+        members_result(response).expect("a page")
+    }
+
+    #[test]
+    fn member_seen_by_presence_is_listed_to_a_later_client() {
+        let dir = std::env::temp_dir().join(format!("members-persist-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join(MEMBERS_FILE);
+        let (alice, bob, later) = (PeerId::random(), PeerId::random(), PeerId::random());
+
+        let mut table = MembershipTable::load(Some(path.clone()));
+        assert_eq!(table.respond(alice, &present("room"), NOW), MembersResponse::Recorded);
+        assert_eq!(table.respond(bob, &present("room"), NOW + 10), MembersResponse::Recorded);
+        assert_eq!(table.respond(bob, &MembersRequest::Left { room_id: "room".into() }, NOW + 20), MembersResponse::Recorded);
+        drop(table);
+
+        // Persisted across a restart, and handed to a client that joins afterwards
+        let mut table = MembershipTable::load(Some(path));
+        let members = page(table.respond(later, &list("room", None, 0), NOW + 30)).members;
+        let alice_seen = members.iter().find(|m| m.peer_id == alice.to_string()).unwrap();
+        assert_eq!((alice_seen.first_joined_at_ms, alice_seen.left_at_ms), (NOW, None));
+        let bob_seen = members.iter().find(|m| m.peer_id == bob.to_string()).unwrap();
+        assert_eq!((bob_seen.joined_at_ms, bob_seen.left_at_ms), (NOW + 10, Some(NOW + 20)));
+
+        // Quiet members time out as of when they were last seen; returning starts a new stay
+        table.expire(NOW + MEMBER_TIMEOUT.as_millis() as u64);
+        table.respond(bob, &present("room"), NOW + 500_000);
+        let members = page(table.respond(later, &list("room", None, 0), NOW + 500_000)).members;
+        let by_id: HashMap<String, Member> = members.into_iter().map(|m| (m.peer_id.clone(), m)).collect();
+        assert_eq!(by_id[&alice.to_string()].left_at_ms, Some(NOW));
+        let bob_seen = &by_id[&bob.to_string()];
+        assert_eq!((bob_seen.first_joined_at_ms, bob_seen.joined_at_ms, bob_seen.is_present()), (NOW + 10, NOW + 500_000, true));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pages_in_peer_id_order() {
+        let mut table = MembershipTable::load(None);
+        let mut peers: Vec<String> = Vec::new();
+        for _ in 0..5 {
+            let peer = PeerId::random();
+            table.respond(peer, &present("room"), NOW);
+            peers.push(peer.to_string());
+        }
+        peers.sort();
+
+        let first = page(table.respond(PeerId::random(), &list("room", None, 2), NOW));
+        assert_eq!(first.members.iter().map(|m| m.peer_id.clone()).collect::<Vec<_>>(), peers[..2]);
+        let rest = page(table.respond(PeerId::random(), &list("room", first.next.as_deref(), 10), NOW));
+        assert_eq!(rest.members.iter().map(|m| m.peer_id.clone()).collect::<Vec<_>>(), peers[2..]);
+        assert_eq!(rest.next, None);
+        assert_eq!(page(table.respond(PeerId::random(), &list("elsewhere", None, 0), NOW)).members, vec![]);
+    }
+
+    #[test]
+    fn restricted_rooms_need_a_credential_and_private_rooms_keep_nothing() {
+        let creator = Keypair::generate_ed25519();
+        let creator_id = creator.public().to_peer_id();
+        let (member, stranger) = (PeerId::random(), PeerId::random());
+        let token = issue_capability(&creator, "room", &member, Permission::Read, NOW + 60_000).unwrap();
+        let restricted = |token: Option<Capability>| {
+            let frame = PresenceFrame::presence(token.map(Credential::Token), b"here".to_vec()).encode();
+            Some(RestrictedPresence { creator: creator_id.to_string(), frame })
+        };
+
+        let mut table = MembershipTable::load(None);
+        assert_eq!(table.present(creator_id, "room", restricted(None).as_ref(), false, NOW), Ok(()));
+        assert_eq!(table.present(member, "room", restricted(Some(token.clone())).as_ref(), false, NOW), Ok(()));
+        // Someone else's token, no token, or no frame at all
+        assert_eq!(
+            table.present(stranger, "room", restricted(Some(token)).as_ref(), false, NOW),
+            Err(MembersError::Credential(AuthError::WrongGrantee))
+        );
+        assert_eq!(table.present(stranger, "room", restricted(None).as_ref(), false, NOW), Err(MembersError::MissingCredential));
+        assert_eq!(table.present(stranger, "room", None, false, NOW), Err(MembersError::MissingCredential));
+        assert_eq!(page(table.respond(stranger, &list("room", None, 0), NOW)).members.len(), 2);
+
+        // A private room drops its table and takes no more reports
+        let private = MembersRequest::Present { room_id: "room".into(), restricted: restricted(None), private: true };
+        assert_eq!(table.respond(creator_id, &private, NOW), MembersResponse::Private);
+        assert_eq!(table.respond(member, &present("room"), NOW), MembersResponse::Private);
+        assert_eq!(table.respond(member, &list("room", None, 0), NOW), MembersResponse::Private);
+        assert!(table.is_private("room"));
+    }
+
+    #[test]
+    fn presence_tracker_joins_and_times_out() {
+        let mut tracker = PresenceTracker::default();
+        let (a, b) = (PeerId::random(), PeerId::random());
+        assert!(tracker.seen("room", a, NOW));
+        assert!(!tracker.seen("room", a, NOW + 30_000));
+        assert!(tracker.seen("room", b, NOW + 60_000));
+        let timeout = MEMBER_TIMEOUT.as_millis() as u64;
+        assert_eq!(tracker.expire(NOW + 30_000 + timeout), vec![("room".to_string(), a)]);
+        assert_eq!(tracker.present("room"), vec![b]);
+        tracker.forget_room("room");
+        assert!(tracker.expire(u64::MAX / 2).is_empty());
+    }
+}
@@ -2,11 +2,28 @@
 
 pub mod peer_dht;
 pub mod docstore;
+pub mod fetch;
+pub mod head_pointer;
+pub mod history;
+pub mod interest;
+pub mod keep_alive;
+pub mod mailbox;
+pub mod members;
+pub mod peers;
+pub mod profile;
+pub mod rendezvous;
+pub mod replay;
 
+#[cfg(not(target_arch = "wasm32"))]
+pub mod ip_limits;
+#[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+pub mod nat;
 #[cfg(not(target_arch = "wasm32"))]
 pub mod relay;
 
 pub use peer_dht::*;
 pub use docstore::*;
+pub use head_pointer::{head_pointer_key, HeadPointer, HeadPointerError, HeadPointers};
+pub use profile::{profile_key, Profile, ProfileCache, ProfileError, SignedProfile};
 #[cfg(not(target_arch = "wasm32"))]
 pub use relay::*;
@@ -0,0 +1,151 @@
+#![cfg(not(target_arch = "wasm32"))]
+
+//! Router port mapping (UPnP IGD) for nodes hosted behind a home NAT, checked by AutoNAT.
+//!
+//! UPnP asks the gateway to forward our listen ports and reports the resulting external
+//! addresses. A gateway happily maps ports that are still unreachable from outside (double
+//! NAT, ISP firewalls), so once AutoNAT probes say we are private the mapped addresses are
+//! withdrawn again, and re-advertised if a later probe reaches us. [`PortMappings`] keeps
+//! that bookkeeping; the owner of the swarm applies the [`NatAction`]s it returns.
+
+use std::collections::HashSet;
+
+use libp2p::autonat::{self, NatStatus};
+use libp2p::multiaddr::Protocol;
+use libp2p::swarm::{behaviour::toggle::Toggle, NetworkBehaviour};
+use libp2p::{upnp, Multiaddr, PeerId};
+
+#[derive(NetworkBehaviour)]
+pub struct NatBehaviour {
+    pub upnp: Toggle<upnp::tokio::Behaviour>,
+    pub autonat: Toggle<autonat::Behaviour>,
+}
+
+/// `upnp` maps ports on the gateway. `autonat` probes our reachability through other
+/// peers and answers their probes; it is needed to check mapped addresses, and public
+/// nodes should run it so home nodes have someone to ask.
+pub fn make_nat_behaviour(local_peer_id: PeerId, upnp: bool, autonat: bool) -> NatBehaviour {
+    NatBehaviour {
+        upnp: Toggle::from(upnp.then(upnp::tokio::Behaviour::default)),
+        autonat: Toggle::from(autonat.then(|| autonat::Behaviour::new(local_peer_id, autonat::Config::default()))),
+    }
+}
+
+/// What to do with the swarm's external addresses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NatAction {
+    /// `Swarm::add_external_address`
+    Advertise(Multiaddr),
+    /// `Swarm::remove_external_address`
+    Withdraw(Multiaddr),
+}
+
+/// External addresses obtained through port mapping, and whether AutoNAT lets us advertise them.
+#[derive(Debug, Default)]
+pub struct PortMappings {
+    mapped: HashSet<Multiaddr>,
+    /// Last AutoNAT verdict was "private": mapped addresses are kept but not advertised.
+    unreachable: bool,
+}
+
+impl PortMappings {
+    pub fn mapped(&self) -> impl Iterator<Item = &Multiaddr> {
+        self.mapped.iter()
+    }
+
+    /// The gateway mapped a port to `addr`. A mapping of the same port under another IP is
+    /// stale (the external IP changed) and gets withdrawn.
+    pub fn on_mapped(&mut self, addr: Multiaddr) -> Vec<NatAction> {
+        let port = without_ip(&addr);
+        let stale: Vec<Multiaddr> = self.mapped.iter().filter(|a| **a != addr && without_ip(a) == port).cloned().collect();
+        let mut actions = Vec::new();
+        for old in stale {
+            self.mapped.remove(&old);
+            actions.push(NatAction::Withdraw(old));
+        }
+        if self.mapped.insert(addr.clone()) && !self.unreachable {
+            actions.push(NatAction::Advertise(addr));
+        }
+        actions
+    }
+
+    /// The mapping for `addr` lapsed and could not be renewed.
+    pub fn on_expired(&mut self, addr: &Multiaddr) -> Vec<NatAction> {
+        if self.mapped.remove(addr) {
+            vec![NatAction::Withdraw(addr.clone())]
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// AutoNAT settled on a new status. `Unknown` keeps the current decision: no probe
+    /// result is no evidence either way.
+    pub fn on_nat_status(&mut self, status: &NatStatus) -> Vec<NatAction> {
+        let unreachable = match status {
+            NatStatus::Private => true,
+            NatStatus::Public(_) => false,
+            NatStatus::Unknown => return Vec::new(),
+        };
+        if unreachable == self.unreachable {
+            return Vec::new();
+        }
+        self.unreachable = unreachable;
+        self.mapped
+            .iter()
+            .cloned()
+            .map(|addr| if unreachable { NatAction::Withdraw(addr) } else { NatAction::Advertise(addr) })
+            .collect()
+    }
+}
+
+/// `addr` without its IP component, identifying the mapped port and transport.
+fn without_ip(addr: &Multiaddr) -> Multiaddr {
+    addr.iter().filter(|p| !matches!(p, Protocol::Ip4(_) | Protocol::Ip6(_))).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> Multiaddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn replaces_mappings_when_the_external_ip_changes() {
+        let mut mappings = PortMappings::default();
+        let first = addr("/ip4/203.0.113.7/tcp/4001");
+        assert_eq!(mappings.on_mapped(first.clone()), vec![NatAction::Advertise(first.clone())]);
+        // Renewals report the same address again
+        assert!(mappings.on_mapped(first.clone()).is_empty());
+
+        let udp = addr("/ip4/203.0.113.7/udp/9090/webrtc-direct");
+        assert_eq!(mappings.on_mapped(udp.clone()), vec![NatAction::Advertise(udp.clone())]);
+
+        let moved = addr("/ip4/198.51.100.2/tcp/4001");
+        assert_eq!(
+            mappings.on_mapped(moved.clone()),
+            vec![NatAction::Withdraw(first.clone()), NatAction::Advertise(moved.clone())]
+        );
+        assert_eq!(mappings.on_expired(&moved), vec![NatAction::Withdraw(moved.clone())]);
+        assert!(mappings.on_expired(&first).is_empty());
+        assert_eq!(mappings.mapped().collect::<Vec<_>>(), vec![&udp]);
+    }
+
+    #[test]
+    fn withholds_mappings_autonat_finds_unreachable() {
+        let mut mappings = PortMappings::default();
+        let mapped = addr("/ip4/203.0.113.7/tcp/4001");
+        mappings.on_mapped(mapped.clone());
+
+        assert_eq!(mappings.on_nat_status(&NatStatus::Private), vec![NatAction::Withdraw(mapped.clone())]);
+        assert!(mappings.on_nat_status(&NatStatus::Unknown).is_empty());
+        // New mappings are held back while we are unreachable
+        let other = addr("/ip4/203.0.113.7/tcp/4002");
+        assert!(mappings.on_mapped(other.clone()).is_empty());
+
+        let mut actions = mappings.on_nat_status(&NatStatus::Public(mapped.clone()));
+        actions.sort_by_key(|a| format!("{a:?}"));
+        assert_eq!(actions, vec![NatAction::Advertise(mapped), NatAction::Advertise(other)]);
+    }
+}
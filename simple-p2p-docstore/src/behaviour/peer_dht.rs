@@ -1,5 +1,191 @@
-use libp2p::{identify, ping, identity::PublicKey, PeerId};
-use libp2p_kad::{Behaviour as KademliaBehaviour, store::MemoryStore, Mode};
+use std::time::Duration;
+
+use libp2p::{identify, ping, identity::{Keypair, PublicKey, SigningError}, PeerId};
+use serde::{Deserialize, Serialize};
+use libp2p_kad::{Behaviour as KademliaBehaviour, store::MemoryStore, Mode, RecordKey, StoreInserts};
+pub use libp2p_kad::store::MemoryStoreConfig;
+
+use crate::Error;
+
+/// Identify protocol version advertised unless configured otherwise.
+pub const DEFAULT_PROTOCOL_VERSION: &str = "simple-p2p-docstore/0.1";
+
+/// Identify agent version advertised unless configured otherwise.
+pub fn default_agent_version() -> String {
+    format!("simple-p2p-docstore/{}", env!("CARGO_PKG_VERSION"))
+}
+
+/// What a node says about itself through identify.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdentifyConfig {
+    pub protocol_version: String,
+    /// Applications embedding the crate should set their own, so crawlers can tell them apart.
+    pub agent_version: String,
+    /// Push updated identify info to connected peers when our listen addresses change.
+    pub push_listen_addr_updates: bool,
+}
+
+impl Default for IdentifyConfig {
+    fn default() -> Self {
+        Self {
+            protocol_version: DEFAULT_PROTOCOL_VERSION.to_string(),
+            agent_version: default_agent_version(),
+            push_listen_addr_updates: false,
+        }
+    }
+}
+
+/// Kademlia record lifetimes and store limits. `None` disables the corresponding expiry or
+/// job. Defaults match libp2p's.
+#[derive(Debug, Clone)]
+pub struct PeerDhtConfig {
+    /// How long stored records live unless a record carries its own expiry.
+    pub record_ttl: Option<Duration>,
+    /// How often stored records are re-sent to the peers closest to their key.
+    pub replication_interval: Option<Duration>,
+    /// How often Kademlia itself re-publishes records this node originally put.
+    pub publication_interval: Option<Duration>,
+    /// Leave storing inbound records and provider records to the owner of the behaviour,
+    /// which then sees their contents in `InboundRequest` events and must call
+    /// `store_mut().put` / `add_provider` itself.
+    pub filter_inbound_records: bool,
+    /// Bounds on the records and provider records kept for other peers and ourselves. A
+    /// full store refuses further puts, see [`crate::node::dht_store`].
+    pub store: MemoryStoreConfig,
+}
+
+impl Default for PeerDhtConfig {
+    fn default() -> Self {
+        Self {
+            record_ttl: Some(Duration::from_secs(48 * 60 * 60)),
+            replication_interval: Some(Duration::from_secs(60 * 60)),
+            publication_interval: Some(Duration::from_secs(22 * 60 * 60)),
+            filter_inbound_records: false,
+            store: MemoryStoreConfig::default(),
+        }
+    }
+}
+
+impl PeerDhtConfig {
+    /// Reject zero durations, turning an expiry or job off with `None` instead, and zero
+    /// store limits.
+    pub fn validate(&self) -> Result<(), Error> {
+        for (name, value) in [
+            ("record_ttl", self.record_ttl),
+            ("replication_interval", self.replication_interval),
+            ("publication_interval", self.publication_interval),
+        ] {
+            if value.is_some_and(|d| d.is_zero()) {
+                return Err(Error::InvalidConfig(format!("{name} must be non-zero (use None to disable it)")));
+            }
+        }
+        for (name, value) in [
+            ("store.max_records", self.store.max_records),
+            ("store.max_value_bytes", self.store.max_value_bytes),
+            ("store.max_providers_per_key", self.store.max_providers_per_key),
+            ("store.max_provided_keys", self.store.max_provided_keys),
+        ] {
+            if value == 0 {
+                return Err(Error::InvalidConfig(format!("{name} must be non-zero")));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// DHT key under which holders of a document announce themselves as providers.
+pub fn doc_provider_key(doc_id: &str) -> RecordKey {
+    RecordKey::new(&format!("/docstore/doc/{doc_id}"))
+}
+
+/// DHT key under which relays announce themselves as providers, for browsers to find them.
+pub fn relay_provider_key() -> RecordKey {
+    RecordKey::new(&"/docstore/relays")
+}
+
+/// DHT key under which a rotated-away identity names its successor.
+pub fn successor_key(old_peer_id: &PeerId) -> RecordKey {
+    RecordKey::new(&format!("/docstore/successor/{old_peer_id}"))
+}
+
+/// "`old` was replaced by `new`", published under [`successor_key`] when a server rotates its
+/// identity, so peers holding the old bootstrap address can find the new one.
+///
+/// Both keys sign the same payload: the old key authorizes the handover and the new key
+/// shows it consents to being named. Stored postcard-encoded.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SuccessorAnnouncement {
+    /// Protobuf-encoded public keys.
+    pub old_public_key: Vec<u8>,
+    pub new_public_key: Vec<u8>,
+    pub issued_at_ms: u64,
+    pub old_signature: Vec<u8>,
+    pub new_signature: Vec<u8>,
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum SuccessorError {
+    #[error("malformed successor announcement")]
+    Malformed,
+    #[error("announcement is for {found}, not {expected}")]
+    WrongPeer { expected: PeerId, found: PeerId },
+    #[error("successor announcement signature does not verify")]
+    BadSignature,
+}
+
+impl SuccessorAnnouncement {
+    pub fn sign(old: &Keypair, new: &Keypair, issued_at_ms: u64) -> Result<Self, SigningError> {
+        let old_public_key = old.public().encode_protobuf();
+        let new_public_key = new.public().encode_protobuf();
+        let payload = Self::payload(&old_public_key, &new_public_key, issued_at_ms);
+        Ok(Self {
+            old_signature: old.sign(&payload)?,
+            new_signature: new.sign(&payload)?,
+            old_public_key,
+            new_public_key,
+            issued_at_ms,
+        })
+    }
+
+    fn payload(old_public_key: &[u8], new_public_key: &[u8], issued_at_ms: u64) -> Vec<u8> {
+        let mut payload = b"docstore-successor:".to_vec();
+        for key in [old_public_key, new_public_key] {
+            payload.extend_from_slice(&(key.len() as u32).to_be_bytes());
+            payload.extend_from_slice(key);
+        }
+        payload.extend_from_slice(&issued_at_ms.to_be_bytes());
+        payload
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        postcard::to_allocvec(self).expect("successor announcement serialization cannot fail")
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, SuccessorError> {
+        postcard::from_bytes(data).map_err(|_| SuccessorError::Malformed)
+    }
+
+    /// Check both signatures and that the announcement is about `old_peer_id`. Returns the
+    /// successor's peer id.
+    pub fn verify(&self, old_peer_id: &PeerId) -> Result<PeerId, SuccessorError> {
+        let old = PublicKey::try_decode_protobuf(&self.old_public_key).map_err(|_| SuccessorError::Malformed)?;
+        let new = PublicKey::try_decode_protobuf(&self.new_public_key).map_err(|_| SuccessorError::Malformed)?;
+        let found = old.to_peer_id();
+        if found != *old_peer_id {
+            return Err(SuccessorError::WrongPeer { expected: *old_peer_id, found });
+        }
+        let payload = Self::payload(&self.old_public_key, &self.new_public_key, self.issued_at_ms);
+        if !old.verify(&payload, &self.old_signature) || !new.verify(&payload, &self.new_signature) {
+            return Err(SuccessorError::BadSignature);
+        }
+        Ok(new.to_peer_id())
+    }
+}
+
+/// Decode and verify a record value found under `successor_key(old_peer_id)`.
+pub fn verify_successor(old_peer_id: &PeerId, value: &[u8]) -> Result<PeerId, SuccessorError> {
+    SuccessorAnnouncement::decode(value)?.verify(old_peer_id)
+}
 
 /// Construct basic PeerDHT behaviours (ping, identify, kademlia) for a node.
 ///
@@ -8,15 +194,89 @@ pub fn make_peer_dht(
     local_pub: &PublicKey,
     local_peer_id: PeerId,
     mode: Mode,
-) -> (ping::Behaviour, identify::Behaviour, KademliaBehaviour<MemoryStore>) {
-    let ping_behaviour = ping::Behaviour::default();
+) -> Result<(ping::Behaviour, identify::Behaviour, KademliaBehaviour<MemoryStore>), Error> {
+    make_peer_dht_with(
+        local_pub,
+        local_peer_id,
+        mode,
+        ping::Config::new(),
+        &IdentifyConfig::default(),
+        &PeerDhtConfig::default(),
+    )
+}
 
-    let identify_cfg = identify::Config::new("simple-p2p-docstore/0.1".to_string(), local_pub.clone());
+/// Like [`make_peer_dht`], with an explicit ping interval/timeout, identify info and
+/// record lifetimes and store limits. Fails with `Error::InvalidConfig` for a zero record
+/// TTL, interval or limit.
+pub fn make_peer_dht_with(
+    local_pub: &PublicKey,
+    local_peer_id: PeerId,
+    mode: Mode,
+    ping_cfg: ping::Config,
+    identify: &IdentifyConfig,
+    dht: &PeerDhtConfig,
+) -> Result<(ping::Behaviour, identify::Behaviour, KademliaBehaviour<MemoryStore>), Error> {
+    dht.validate()?;
+    let ping_behaviour = ping::Behaviour::new(ping_cfg);
+
+    let identify_cfg = identify::Config::new(identify.protocol_version.clone(), local_pub.clone())
+        .with_agent_version(identify.agent_version.clone())
+        .with_push_listen_addr_updates(identify.push_listen_addr_updates);
     let identify_behaviour = identify::Behaviour::new(identify_cfg);
 
-    let store = MemoryStore::new(local_peer_id);
-    let mut kademlia = KademliaBehaviour::new(local_peer_id, store);
+    let mut kad_cfg = libp2p_kad::Config::new(libp2p_kad::PROTOCOL_NAME);
+    kad_cfg
+        .set_record_ttl(dht.record_ttl)
+        .set_replication_interval(dht.replication_interval)
+        .set_publication_interval(dht.publication_interval);
+    if dht.filter_inbound_records {
+        kad_cfg.set_record_filtering(StoreInserts::FilterBoth);
+    }
+    let store = MemoryStore::with_config(local_peer_id, dht.store.clone());
+    let mut kademlia = KademliaBehaviour::with_config(local_peer_id, store, kad_cfg);
     kademlia.set_mode(Some(mode));
 
-    (ping_behaviour, identify_behaviour, kademlia)
+    Ok((ping_behaviour, identify_behaviour, kademlia))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn successor_announcements_verify_against_the_old_peer_id() {
+        let (old, new) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let announcement = SuccessorAnnouncement::sign(&old, &new, 1_700_000_000_000).unwrap();
+        let old_peer = old.public().to_peer_id();
+        assert_eq!(verify_successor(&old_peer, &announcement.encode()), Ok(new.public().to_peer_id()));
+
+        let other = PeerId::random();
+        assert_eq!(
+            announcement.verify(&other),
+            Err(SuccessorError::WrongPeer { expected: other, found: old_peer })
+        );
+
+        // Pointing the announcement at a different successor breaks the signatures
+        let mut forged = announcement.clone();
+        forged.new_public_key = Keypair::generate_ed25519().public().encode_protobuf();
+        assert_eq!(forged.verify(&old_peer), Err(SuccessorError::BadSignature));
+
+        assert_eq!(verify_successor(&old_peer, b"junk"), Err(SuccessorError::Malformed));
+    }
+
+    #[test]
+    fn zero_dht_intervals_are_rejected() {
+        let key = Keypair::generate_ed25519();
+        let peer_id = key.public().to_peer_id();
+        let build = |dht: &PeerDhtConfig| {
+            make_peer_dht_with(&key.public(), peer_id, Mode::Server, ping::Config::new(), &IdentifyConfig::default(), dht)
+        };
+        assert!(build(&PeerDhtConfig::default()).is_ok());
+        assert!(build(&PeerDhtConfig { record_ttl: None, ..Default::default() }).is_ok());
+        let zero = PeerDhtConfig { replication_interval: Some(Duration::ZERO), ..Default::default() };
+        assert!(matches!(build(&zero), Err(Error::InvalidConfig(msg)) if msg.contains("replication_interval")));
+        let mut no_records = PeerDhtConfig::default();
+        no_records.store.max_records = 0;
+        assert!(matches!(build(&no_records), Err(Error::InvalidConfig(msg)) if msg.contains("max_records")));
+    }
 }
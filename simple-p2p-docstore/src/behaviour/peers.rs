@@ -0,0 +1,193 @@
+//! `/docstore/peers/1.0.0`: relays tell their clients about each other. Two browsers on
+//! the same relay otherwise never learn of one another, and all of their gossip hairpins
+//! through the relay even though a circuit (or direct WebRTC) connection would do.
+//!
+//! A client asks with its own addresses and whether it wants to be listed; the relay
+//! answers with up to [`MAX_LISTED_PEERS`] of its connected clients that opted in. Only
+//! the nodes that serve relays answer; see [`crate::node::peer_exchange`] for the asking side.
+
+use libp2p::request_response::{self, ProtocolSupport};
+use libp2p::{Multiaddr, PeerId, StreamProtocol};
+use serde::{Deserialize, Serialize};
+
+pub const PEERS_PROTOCOL: &str = "/docstore/peers/1.0.0";
+
+/// The most peers a relay lists in one response, whatever the request's limit.
+pub const MAX_LISTED_PEERS: usize = 16;
+
+/// The most addresses kept for one listed peer.
+pub const MAX_PEER_ADDRS: usize = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeersRequest {
+    /// Whether the relay may list us to its other clients. `false` also withdraws an
+    /// earlier opt-in.
+    pub discoverable: bool,
+    /// How many peers we want at most; the relay caps it at [`MAX_LISTED_PEERS`].
+    pub limit: u32,
+    /// Where we can be reached besides a circuit through the relay.
+    pub addrs: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListedPeer {
+    pub peer_id: String,
+    pub addrs: Vec<String>,
+}
+
+impl ListedPeer {
+    /// The peer id and its parseable addresses, or `None` for an invalid peer id.
+    pub fn parse(&self) -> Option<(PeerId, Vec<Multiaddr>)> {
+        let peer_id = self.peer_id.parse().ok()?;
+        Some((peer_id, self.addrs.iter().filter_map(|a| a.parse().ok()).collect()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeersResponse {
+    pub peers: Vec<ListedPeer>,
+}
+
+pub type PeersBehaviour = request_response::cbor::Behaviour<PeersRequest, PeersResponse>;
+
+/// Nodes that serve relays (`serve`) answer; the others only ask.
+pub fn make_peers_behaviour(serve: bool) -> PeersBehaviour {
+    let support = if serve { ProtocolSupport::Full } else { ProtocolSupport::Outbound };
+    request_response::cbor::Behaviour::new(
+        [(StreamProtocol::new(PEERS_PROTOCOL), support)],
+        request_response::Config::default(),
+    )
+}
+
+/// The connected clients of a relay that opted into being listed, oldest opt-in first.
+/// Responses take turns through the list, so with more clients than fit in one response
+/// every one of them still gets listed.
+#[derive(Debug, Default)]
+pub struct PeerDirectory {
+    peers: Vec<(PeerId, Vec<String>)>,
+    cursor: usize,
+}
+
+impl PeerDirectory {
+    /// Record `peer`'s opt-in or opt-out and answer its request. The requester is never
+    /// listed to itself.
+    pub fn respond(&mut self, peer: PeerId, request: &PeersRequest) -> PeersResponse {
+        self.peers.retain(|(p, _)| *p != peer);
+        let peers = self.list(request.limit as usize);
+        if request.discoverable {
+            let addrs = request
+                .addrs
+                .iter()
+                .filter(|a| a.parse::<Multiaddr>().is_ok())
+                .take(MAX_PEER_ADDRS)
+                .cloned()
+                .collect();
+            self.peers.push((peer, addrs));
+        }
+        PeersResponse { peers }
+    }
+
+    /// Drop a peer that is no longer connected.
+    pub fn disconnected(&mut self, peer: &PeerId) {
+        self.peers.retain(|(p, _)| p != peer);
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    fn list(&mut self, limit: usize) -> Vec<ListedPeer> {
+        let count = limit.min(MAX_LISTED_PEERS).min(self.peers.len());
+        if count == 0 {
+            return Vec::new();
+        }
+        let start = self.cursor % self.peers.len();
+        self.cursor = start + count;
+        self.peers
+            .iter()
+            .cycle()
+            .skip(start)
+            .take(count)
+            .map(|(peer_id, addrs)| ListedPeer { peer_id: peer_id.to_string(), addrs: addrs.clone() })
+            .collect()
+    }
+}
+
+/// Answer a request from the directory. Responses and failures are for the asking side.
+pub fn handle_event(
+    behaviour: &mut PeersBehaviour,
+    directory: &mut PeerDirectory,
+    event: request_response::Event<PeersRequest, PeersResponse>,
+) {
+    match event {
+        request_response::Event::Message { peer, message: request_response::Message::Request { request, channel, .. }, .. } => {
+            let response = directory.respond(peer, &request);
+            if behaviour.send_response(channel, response).is_err() {
+                tracing::debug!("Peer list requester {} went away before the response", peer);
+            }
+        }
+        request_response::Event::InboundFailure { peer, error, .. } => {
+            tracing::debug!("Peer list request from {} failed: {}", peer, error);
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(discoverable: bool, limit: u32) -> PeersRequest {
+        PeersRequest { discoverable, limit, addrs: vec!["/memory/1".into(), "not an address".into()] }
+    }
+
+    fn listed(response: &PeersResponse) -> Vec<PeerId> {
+        response.peers.iter().map(|p| p.parse().unwrap().0).collect()
+    }
+
+    #[test]
+    fn lists_only_peers_that_opted_in() {
+        let mut directory = PeerDirectory::default();
+        let (a, b, c) = (PeerId::random(), PeerId::random(), PeerId::random());
+        assert!(directory.respond(a, &request(true, 10)).peers.is_empty());
+        assert_eq!(listed(&directory.respond(b, &request(false, 10))), vec![a]);
+        // Never listed to itself, and b stays unlisted
+        assert!(directory.respond(a, &request(true, 10)).peers.is_empty());
+        assert_eq!(listed(&directory.respond(c, &request(true, 10))), vec![a]);
+
+        // Opting out later takes a off the list
+        directory.respond(a, &request(false, 10));
+        assert_eq!(listed(&directory.respond(b, &request(false, 10))), vec![c]);
+        let entry = &directory.respond(b, &request(false, 10)).peers[0];
+        assert_eq!(entry.addrs, vec!["/memory/1".to_string()]);
+    }
+
+    #[test]
+    fn caps_the_list_and_takes_turns() {
+        let mut directory = PeerDirectory::default();
+        let peers: Vec<PeerId> = (0..MAX_LISTED_PEERS + 4).map(|_| PeerId::random()).collect();
+        for peer in &peers {
+            directory.respond(*peer, &request(true, 0));
+        }
+        let asker = PeerId::random();
+        let first = listed(&directory.respond(asker, &request(false, 1000)));
+        assert_eq!(first.len(), MAX_LISTED_PEERS);
+        let second = listed(&directory.respond(asker, &request(false, 1000)));
+        assert!(second.contains(&peers[MAX_LISTED_PEERS + 3]));
+        assert_eq!(listed(&directory.respond(asker, &request(false, 2))).len(), 2);
+    }
+
+    #[test]
+    fn forgets_disconnected_peers() {
+        let mut directory = PeerDirectory::default();
+        let (a, b) = (PeerId::random(), PeerId::random());
+        directory.respond(a, &request(true, 10));
+        directory.disconnected(&a);
+        assert!(directory.is_empty());
+        assert!(directory.respond(b, &request(false, 10)).peers.is_empty());
+    }
+}
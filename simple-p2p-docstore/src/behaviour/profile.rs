@@ -0,0 +1,322 @@
+//! Optional self-published profiles, so UIs can show a name instead of a peer id.
+//!
+//! A peer puts a [`SignedProfile`] (display name, avatar hash, when it was updated) under
+//! [`profile_key`] of its own peer id. Anyone can put a record under any key, so the
+//! profile is signed by the identity key the peer id derives from, and resolvers keep the
+//! newest valid copy. Peers storing DHT records refuse invalid ones as well (see
+//! [`accepts_record`]). Encoded profiles are capped at [`MAX_PROFILE_SIZE`].
+//!
+//! Resolved profiles are cached for [`DEFAULT_PROFILE_TTL`] by [`ProfileCache`].
+//!
+//! Rooms get a faster path: once a profile is set, presence carries just the display
+//! name, tagged with [`tag_presence`] and stripped again by [`untag_presence`]. Those
+//! names are unsigned hints, good for a member list until the profile is resolved.
+//!
+//! Postcard-encoded and signed like [`HeadPointer`](super::HeadPointer).
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use libp2p::identity::{Keypair, PublicKey, SigningError};
+use libp2p::PeerId;
+use libp2p_kad::RecordKey;
+use serde::{Deserialize, Serialize};
+
+use super::docstore::wire;
+
+/// Largest encoded profile, signature included.
+pub const MAX_PROFILE_SIZE: usize = 1024;
+
+/// Longest display name, in bytes.
+pub const MAX_DISPLAY_NAME_LEN: usize = 64;
+
+/// How long a resolved profile, or the absence of one, is cached.
+pub const DEFAULT_PROFILE_TTL: Duration = Duration::from_secs(600);
+
+const KEY_PREFIX: &str = "/docstore/profile/";
+
+/// Marks presence data carrying a display name; no UTF-8 text starts with 0xff.
+const PRESENCE_TAG: &[u8] = b"\xffname:";
+
+/// DHT key of `peer`'s profile.
+pub fn profile_key(peer: &PeerId) -> RecordKey {
+    RecordKey::new(&format!("{KEY_PREFIX}{peer}"))
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum ProfileError {
+    #[error("malformed profile")]
+    Malformed,
+    #[error("profile is {size} bytes, more than the maximum of {max}")]
+    TooLarge { size: usize, max: usize },
+    #[error("display name is {len} bytes, more than the maximum of {max}")]
+    NameTooLong { len: usize, max: usize },
+    #[error("profile is signed by {found}, not {expected}")]
+    WrongPeer { expected: PeerId, found: PeerId },
+    #[error("profile signature does not verify")]
+    BadSignature,
+}
+
+/// What a peer says about itself.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Profile {
+    pub display_name: String,
+    /// SHA-256 of the avatar image, fetched however the application shares files.
+    pub avatar_hash: Option<[u8; 32]>,
+}
+
+impl Profile {
+    pub fn new(display_name: impl Into<String>) -> Self {
+        Self { display_name: display_name.into(), avatar_hash: None }
+    }
+
+    pub fn with_avatar_hash(mut self, avatar_hash: [u8; 32]) -> Self {
+        self.avatar_hash = Some(avatar_hash);
+        self
+    }
+
+    pub fn validate(&self) -> Result<(), ProfileError> {
+        let len = self.display_name.len();
+        if len > MAX_DISPLAY_NAME_LEN {
+            return Err(ProfileError::NameTooLong { len, max: MAX_DISPLAY_NAME_LEN });
+        }
+        Ok(())
+    }
+}
+
+/// A [`Profile`] signed by the peer it describes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedProfile {
+    pub profile: Profile,
+    /// Higher is newer.
+    pub updated_at_ms: u64,
+    /// Protobuf-encoded public key of the peer.
+    #[serde(deserialize_with = "wire::bytes")]
+    pub public_key: Vec<u8>,
+    #[serde(deserialize_with = "wire::bytes")]
+    pub signature: Vec<u8>,
+}
+
+/// Signing fails, or the profile breaks a limit.
+#[derive(Debug, thiserror::Error)]
+pub enum SignProfileError {
+    #[error(transparent)]
+    Invalid(#[from] ProfileError),
+    #[error(transparent)]
+    Signing(#[from] SigningError),
+}
+
+impl SignedProfile {
+    pub fn sign(key: &Keypair, profile: Profile, updated_at_ms: u64) -> Result<Self, SignProfileError> {
+        profile.validate()?;
+        let mut signed = Self { profile, updated_at_ms, public_key: key.public().encode_protobuf(), signature: Vec::new() };
+        signed.signature = key.sign(&signed.payload())?;
+        let size = signed.encode().len();
+        if size > MAX_PROFILE_SIZE {
+            return Err(ProfileError::TooLarge { size, max: MAX_PROFILE_SIZE }.into());
+        }
+        Ok(signed)
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        let mut payload = b"docstore-profile:".to_vec();
+        for field in [self.profile.display_name.as_bytes(), self.public_key.as_slice()] {
+            payload.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            payload.extend_from_slice(field);
+        }
+        match &self.profile.avatar_hash {
+            Some(hash) => {
+                payload.push(1);
+                payload.extend_from_slice(hash);
+            }
+            None => payload.push(0),
+        }
+        payload.extend_from_slice(&self.updated_at_ms.to_be_bytes());
+        payload
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        postcard::to_allocvec(self).expect("profile serialization cannot fail")
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, ProfileError> {
+        if data.len() > MAX_PROFILE_SIZE {
+            return Err(ProfileError::TooLarge { size: data.len(), max: MAX_PROFILE_SIZE });
+        }
+        postcard::from_bytes(data).map_err(|_| ProfileError::Malformed)
+    }
+
+    /// Check that the profile is `peer`'s, within the limits and signed.
+    pub fn verify(&self, peer: &PeerId) -> Result<(), ProfileError> {
+        self.profile.validate()?;
+        let key = PublicKey::try_decode_protobuf(&self.public_key).map_err(|_| ProfileError::Malformed)?;
+        let found = key.to_peer_id();
+        if found != *peer {
+            return Err(ProfileError::WrongPeer { expected: *peer, found });
+        }
+        if !key.verify(&self.payload(), &self.signature) {
+            return Err(ProfileError::BadSignature);
+        }
+        Ok(())
+    }
+}
+
+/// False for a record under a profile key that is not a valid profile of that peer.
+/// Records under other keys are not this module's business.
+pub fn accepts_record(key: &RecordKey, value: &[u8]) -> bool {
+    let Some(peer) = std::str::from_utf8(key.as_ref()).ok().and_then(|key| key.strip_prefix(KEY_PREFIX)) else {
+        return true;
+    };
+    let Ok(peer) = peer.parse::<PeerId>() else {
+        return false;
+    };
+    SignedProfile::decode(value).and_then(|profile| profile.verify(&peer)).is_ok()
+}
+
+/// Presence `data` carrying `display_name`.
+pub fn tag_presence(display_name: &str, data: &[u8]) -> Vec<u8> {
+    let mut tagged = PRESENCE_TAG.to_vec();
+    tagged.push(display_name.len().min(MAX_DISPLAY_NAME_LEN) as u8);
+    tagged.extend_from_slice(&display_name.as_bytes()[..display_name.len().min(MAX_DISPLAY_NAME_LEN)]);
+    tagged.extend_from_slice(data);
+    tagged
+}
+
+/// The display name presence `data` carries, if any, and the data itself.
+pub fn untag_presence(data: &[u8]) -> (Option<String>, &[u8]) {
+    let Some(rest) = data.strip_prefix(PRESENCE_TAG) else {
+        return (None, data);
+    };
+    let Some((&len, rest)) = rest.split_first().filter(|(len, rest)| usize::from(**len) <= rest.len()) else {
+        return (None, data);
+    };
+    let (name, data) = rest.split_at(usize::from(len));
+    (Some(String::from_utf8_lossy(name).into_owned()), data)
+}
+
+/// Resolved profiles, each for a TTL; a peer without one is cached as `None`.
+#[derive(Debug)]
+pub struct ProfileCache {
+    ttl: Duration,
+    entries: HashMap<PeerId, (Option<SignedProfile>, u64)>,
+}
+
+impl ProfileCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self { ttl, entries: HashMap::new() }
+    }
+
+    /// `Some` with the cached result while it is fresh.
+    pub fn get(&self, peer: &PeerId, now_ms: u64) -> Option<Option<SignedProfile>> {
+        self.entries.get(peer).filter(|(_, expires)| *expires > now_ms).map(|(profile, _)| profile.clone())
+    }
+
+    /// Remember `profile` as `peer`'s, e.g. our own once published.
+    pub fn insert(&mut self, peer: PeerId, profile: Option<SignedProfile>, now_ms: u64) {
+        self.entries.retain(|_, (_, expires)| *expires > now_ms);
+        self.entries.insert(peer, (profile, now_ms + self.ttl.as_millis() as u64));
+    }
+
+    /// The newest valid profile among the record `values` found under [`profile_key`],
+    /// cached. Invalid copies are skipped; if no copy is valid the first error is returned
+    /// and nothing is cached, and `Ok(None)` if there were none.
+    pub fn resolve<'a>(
+        &mut self,
+        peer: &PeerId,
+        values: impl IntoIterator<Item = &'a [u8]>,
+        now_ms: u64,
+    ) -> Result<Option<SignedProfile>, ProfileError> {
+        let mut newest: Option<SignedProfile> = None;
+        let mut first_error = None;
+        for value in values {
+            match SignedProfile::decode(value).and_then(|profile| profile.verify(peer).map(|()| profile)) {
+                Ok(profile) if !newest.as_ref().is_some_and(|n| n.updated_at_ms >= profile.updated_at_ms) => {
+                    newest = Some(profile)
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        if let (None, Some(e)) = (&newest, first_error) {
+            return Err(e);
+        }
+        self.insert(*peer, newest.clone(), now_ms);
+        Ok(newest)
+    }
+}
+
+impl Default for ProfileCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_PROFILE_TTL)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000_000;
+
+    #[test]
+    fn resolves_the_newest_valid_profile_and_caches_it() {
+        let key = Keypair::generate_ed25519();
+        let peer = key.public().to_peer_id();
+        let old = SignedProfile::sign(&key, Profile::new("ada"), NOW).unwrap();
+        let new = SignedProfile::sign(&key, Profile::new("Ada L.").with_avatar_hash([7; 32]), NOW + 1).unwrap();
+
+        let mut cache = ProfileCache::new(Duration::from_secs(60));
+        assert_eq!(cache.get(&peer, NOW), None);
+        let (old_bytes, new_bytes) = (old.encode(), new.encode());
+        let resolved = cache.resolve(&peer, [new_bytes.as_slice(), b"junk".as_slice(), old_bytes.as_slice()], NOW).unwrap();
+        assert_eq!(resolved, Some(new.clone()));
+        assert_eq!(cache.get(&peer, NOW + 59_000), Some(Some(new)));
+        assert_eq!(cache.get(&peer, NOW + 60_000), None);
+
+        // No profile is cached too
+        let other = PeerId::random();
+        assert_eq!(cache.resolve(&other, [], NOW), Ok(None));
+        assert_eq!(cache.get(&other, NOW), Some(None));
+    }
+
+    #[test]
+    fn profiles_signed_by_the_wrong_key_are_rejected() {
+        let (key, impostor) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let peer = key.public().to_peer_id();
+        let forged = SignedProfile::sign(&impostor, Profile::new("ada"), NOW).unwrap();
+        assert_eq!(
+            forged.verify(&peer),
+            Err(ProfileError::WrongPeer { expected: peer, found: impostor.public().to_peer_id() })
+        );
+        // Claiming the victim's key without its signature
+        let mut stolen = forged.clone();
+        stolen.public_key = key.public().encode_protobuf();
+        assert_eq!(stolen.verify(&peer), Err(ProfileError::BadSignature));
+
+        let mut cache = ProfileCache::default();
+        assert!(cache.resolve(&peer, [forged.encode().as_slice(), stolen.encode().as_slice()], NOW).is_err());
+        assert_eq!(cache.get(&peer, NOW), None);
+        assert!(!accepts_record(&profile_key(&peer), &forged.encode()));
+        assert!(accepts_record(&profile_key(&impostor.public().to_peer_id()), &forged.encode()));
+        assert!(accepts_record(&RecordKey::new(&"/docstore/relays"), b"anything"));
+    }
+
+    #[test]
+    fn limits_are_enforced() {
+        let key = Keypair::generate_ed25519();
+        let long = "x".repeat(MAX_DISPLAY_NAME_LEN + 1);
+        assert!(matches!(
+            SignedProfile::sign(&key, Profile::new(long), NOW),
+            Err(SignProfileError::Invalid(ProfileError::NameTooLong { .. }))
+        ));
+        assert!(matches!(SignedProfile::decode(&[0; MAX_PROFILE_SIZE + 1]), Err(ProfileError::TooLarge { .. })));
+    }
+
+    #[test]
+    fn presence_tags_round_trip() {
+        let tagged = tag_presence("Ada", b"{\"cursor\":3}");
+        assert_eq!(untag_presence(&tagged), (Some("Ada".to_string()), b"{\"cursor\":3}".as_slice()));
+        assert_eq!(untag_presence(b"plain"), (None, b"plain".as_slice()));
+    }
+}
@@ -1,14 +1,37 @@
 #![cfg(not(target_arch = "wasm32"))]
 
-use libp2p::relay;
-
-/// Create a basic relay behaviour (Circuit Relay v2) with default config.
-///
-/// This requires the `relay` feature to be available in `libp2p` (native builds).
+use libp2p::swarm::behaviour::toggle::Toggle;
 use libp2p::PeerId;
 
-pub fn make_relay_behaviour(local_peer_id: PeerId) -> relay::Behaviour {
-    relay::Behaviour::new(local_peer_id, relay::Config::default())
+use crate::Error;
+
+/// The Circuit Relay v2 service. Builds without the `relay` feature get a stand-in that
+/// is never enabled, so behaviours composing it look the same either way.
+#[cfg(feature = "relay")]
+pub type RelayBehaviour = libp2p::relay::Behaviour;
+#[cfg(not(feature = "relay"))]
+pub type RelayBehaviour = libp2p::swarm::dummy::Behaviour;
+
+/// Create a basic relay behaviour (Circuit Relay v2) with default config. Fallible like
+/// the other behaviour constructors, for when it takes configuration.
+#[cfg(feature = "relay")]
+pub fn make_relay_behaviour(local_peer_id: PeerId) -> Result<libp2p::relay::Behaviour, Error> {
+    Ok(libp2p::relay::Behaviour::new(local_peer_id, libp2p::relay::Config::default()))
+}
+
+/// The relay service if `enabled`, else a disabled `Toggle`.
+#[cfg(feature = "relay")]
+pub fn relay_service(local_peer_id: PeerId, enabled: bool) -> Result<Toggle<RelayBehaviour>, Error> {
+    Ok(Toggle::from(enabled.then(|| make_relay_behaviour(local_peer_id)).transpose()?))
+}
+
+/// Always disabled: this build has no relay service to run.
+#[cfg(not(feature = "relay"))]
+pub fn relay_service(_local_peer_id: PeerId, enabled: bool) -> Result<Toggle<RelayBehaviour>, Error> {
+    if enabled {
+        tracing::warn!("built without the `relay` feature; not serving relay circuits");
+    }
+    Ok(Toggle::from(None))
 }
 
 #[cfg(test)]
@@ -16,7 +39,9 @@ mod tests {
     use super::*;
 
     #[test]
-    fn build_relay_behaviour() {
-        let _ = make_relay_behaviour();
+    fn relay_service_follows_the_feature() {
+        let peer = PeerId::random();
+        assert!(!relay_service(peer, false).unwrap().is_enabled());
+        assert_eq!(relay_service(peer, true).unwrap().is_enabled(), cfg!(feature = "relay"));
     }
 }
@@ -0,0 +1,184 @@
+//! libp2p rendezvous (`/rendezvous/1.0.0`), for finding peers without the DHT.
+//!
+//! Kademlia is heavyweight for a small deployment with one well-known server: peers can
+//! instead register with that server under a namespace and ask it for the other
+//! registrants. Relay and FullNode nodes run the server; clients register and discover,
+//! see [`crate::node::rendezvous`] for their bookkeeping.
+//!
+//! Builds without the `rendezvous` feature get a stand-in that neither serves nor
+//! registers, so behaviours composing it look the same either way.
+
+use std::time::Duration;
+
+use libp2p::identity::Keypair;
+use libp2p::swarm::NetworkBehaviour;
+use libp2p::{Multiaddr, PeerId};
+
+use crate::Error;
+
+pub const RENDEZVOUS_PROTOCOL: &str = "/rendezvous/1.0.0";
+
+/// A peer registered under our namespace, as a rendezvous point returned it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Registration {
+    pub peer_id: PeerId,
+    pub addrs: Vec<Multiaddr>,
+    pub ttl: Duration,
+}
+
+/// What the event loops act on, from [`RendezvousBehaviour::on_event`].
+#[derive(Debug)]
+pub enum RendezvousEvent {
+    Registered { point: PeerId, ttl: Duration },
+    RegisterFailed { point: PeerId, error: String },
+    /// One page of registrants; `cookie` asks `point` for those registered after it.
+    Discovered { point: PeerId, registrations: Vec<Registration>, cookie: Cookie },
+    DiscoverFailed { point: PeerId, error: String },
+    /// The registration of a discovered peer ran out.
+    Expired { peer: PeerId },
+}
+
+#[cfg(feature = "rendezvous")]
+pub use with_feature::*;
+#[cfg(not(feature = "rendezvous"))]
+pub use without_feature::*;
+
+#[cfg(feature = "rendezvous")]
+mod with_feature {
+    use super::*;
+    use libp2p::rendezvous::{self, client, server, Namespace};
+    use libp2p::swarm::behaviour::toggle::Toggle;
+
+    /// Position in a point's registrations, see [`RendezvousEvent::Discovered`].
+    pub type Cookie = rendezvous::Cookie;
+
+    #[derive(NetworkBehaviour)]
+    pub struct RendezvousBehaviour {
+        server: Toggle<server::Behaviour>,
+        client: Toggle<client::Behaviour>,
+    }
+
+    /// The server if `serve`, the client if `register`.
+    pub fn make_rendezvous_behaviour(key: &Keypair, serve: bool, register: bool) -> RendezvousBehaviour {
+        RendezvousBehaviour {
+            server: Toggle::from(serve.then(|| server::Behaviour::new(server::Config::default()))),
+            client: Toggle::from(register.then(|| client::Behaviour::new(key.clone()))),
+        }
+    }
+
+    fn namespace(namespace: &str) -> Result<Namespace, Error> {
+        Namespace::new(namespace.to_string()).map_err(|e| Error::InvalidConfig(format!("rendezvous namespace: {e}")))
+    }
+
+    impl RendezvousBehaviour {
+        /// Whether this node registers with and discovers through rendezvous points.
+        pub fn is_client(&self) -> bool {
+            self.client.is_enabled()
+        }
+
+        /// Register with `point` under `namespace` for `ttl`. The registration carries our
+        /// external addresses; it fails while we have none.
+        pub fn register(&mut self, point: PeerId, namespace_name: &str, ttl: Duration) -> Result<(), Error> {
+            let Some(client) = self.client.as_mut() else {
+                return Err(Error::RendezvousDisabled);
+            };
+            client
+                .register(namespace(namespace_name)?, point, Some(ttl.as_secs()))
+                .map_err(|e| Error::Transport(format!("rendezvous registration: {e}")))
+        }
+
+        /// Ask `point` for at most `limit` peers registered under `namespace`, after
+        /// `cookie` if given.
+        pub fn discover(&mut self, point: PeerId, namespace_name: &str, cookie: Option<Cookie>, limit: u64) -> Result<(), Error> {
+            let Some(client) = self.client.as_mut() else {
+                return Err(Error::RendezvousDisabled);
+            };
+            client.discover(Some(namespace(namespace_name)?), cookie, Some(limit), point);
+            Ok(())
+        }
+
+        /// Translate a behaviour event. Events of the server are only logged.
+        pub fn on_event(event: RendezvousBehaviourEvent) -> Option<RendezvousEvent> {
+            match event {
+                RendezvousBehaviourEvent::Client(event) => match event {
+                    client::Event::Registered { rendezvous_node, ttl, .. } => {
+                        Some(RendezvousEvent::Registered { point: rendezvous_node, ttl: Duration::from_secs(ttl) })
+                    }
+                    client::Event::RegisterFailed { rendezvous_node, error, .. } => {
+                        Some(RendezvousEvent::RegisterFailed { point: rendezvous_node, error: format!("{error:?}") })
+                    }
+                    client::Event::Discovered { rendezvous_node, registrations, cookie } => {
+                        let registrations = registrations
+                            .into_iter()
+                            .map(|r| Registration {
+                                peer_id: r.record.peer_id(),
+                                addrs: r.record.addresses().to_vec(),
+                                ttl: Duration::from_secs(r.ttl),
+                            })
+                            .collect();
+                        Some(RendezvousEvent::Discovered { point: rendezvous_node, registrations, cookie })
+                    }
+                    client::Event::DiscoverFailed { rendezvous_node, error, .. } => {
+                        Some(RendezvousEvent::DiscoverFailed { point: rendezvous_node, error: format!("{error:?}") })
+                    }
+                    client::Event::Expired { peer } => Some(RendezvousEvent::Expired { peer }),
+                },
+                RendezvousBehaviourEvent::Server(event) => {
+                    match event {
+                        server::Event::PeerRegistered { peer, registration } => {
+                            tracing::debug!("{} registered under {} for {}s", peer, registration.namespace, registration.ttl);
+                        }
+                        server::Event::PeerNotRegistered { peer, namespace, error } => {
+                            tracing::debug!("Refused to register {} under {}: {:?}", peer, namespace, error);
+                        }
+                        server::Event::DiscoverNotServed { enquirer, error } => {
+                            tracing::debug!("Refused a discovery from {}: {:?}", enquirer, error);
+                        }
+                        _ => {}
+                    }
+                    None
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "rendezvous"))]
+mod without_feature {
+    use super::*;
+
+    pub type Cookie = ();
+
+    #[derive(NetworkBehaviour)]
+    pub struct RendezvousBehaviour {
+        dummy: libp2p::swarm::dummy::Behaviour,
+    }
+
+    /// Neither serves nor registers: this build has no rendezvous.
+    pub fn make_rendezvous_behaviour(_key: &Keypair, serve: bool, _register: bool) -> RendezvousBehaviour {
+        if serve {
+            tracing::warn!("built without the `rendezvous` feature; not serving rendezvous");
+        }
+        RendezvousBehaviour { dummy: libp2p::swarm::dummy::Behaviour }
+    }
+
+    impl RendezvousBehaviour {
+        pub fn is_client(&self) -> bool {
+            false
+        }
+
+        pub fn register(&mut self, _point: PeerId, _namespace: &str, _ttl: Duration) -> Result<(), Error> {
+            Err(Error::RendezvousDisabled)
+        }
+
+        pub fn discover(&mut self, _point: PeerId, _namespace: &str, _cookie: Option<Cookie>, _limit: u64) -> Result<(), Error> {
+            Err(Error::RendezvousDisabled)
+        }
+
+        pub fn on_event(event: RendezvousBehaviourEvent) -> Option<RendezvousEvent> {
+            match event {
+                RendezvousBehaviourEvent::Dummy(never) => match never {},
+            }
+        }
+    }
+}
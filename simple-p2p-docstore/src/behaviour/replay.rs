@@ -0,0 +1,351 @@
+//! `/docstore/replay/1.0.0`: lets a peer that joined late, or was offline, fetch the
+//! topic messages it missed from a peer that logged them.
+//!
+//! A request names a topic and where to resume (after a sequence number, or from a
+//! timestamp); the responder answers with one page of logged messages in order and the
+//! cursor for the next page. Responders cap the size of a page and how often each peer
+//! may ask.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+use libp2p::request_response::{self, ProtocolSupport};
+use libp2p::{PeerId, StreamProtocol};
+use serde::{Deserialize, Serialize};
+
+pub const REPLAY_PROTOCOL: &str = "/docstore/replay/1.0.0";
+
+/// Messages a [`MessageLog`] keeps per topic by default.
+pub const DEFAULT_LOG_CAPACITY: usize = 10_000;
+
+/// Most messages one response page carries, whatever the request's `limit`.
+pub const DEFAULT_MAX_PAGE_MESSAGES: usize = 256;
+
+/// Payload bytes after which a page is cut short. A page always carries at least one
+/// message so oversized ones can still be fetched.
+pub const DEFAULT_MAX_PAGE_BYTES: usize = 1024 * 1024;
+
+/// Requests a single peer may make per [`DEFAULT_RATE_WINDOW`].
+pub const DEFAULT_REQUESTS_PER_WINDOW: u32 = 30;
+
+pub const DEFAULT_RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Where a replay resumes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReplayFrom {
+    /// Messages logged after this sequence number; `0` for everything still logged.
+    Seq(u64),
+    /// Messages logged at or after this unix millisecond timestamp.
+    Timestamp(u64),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplayRequest {
+    pub topic: String,
+    pub since: ReplayFrom,
+    /// Most messages wanted in this page; `0` lets the responder choose.
+    pub limit: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LoggedMessage {
+    /// Increases with every message the responder logged, across topics.
+    pub seq: u64,
+    pub timestamp_ms: u64,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReplayResponse {
+    Page {
+        messages: Vec<LoggedMessage>,
+        /// Resume with `ReplayFrom::Seq(next)` for the following page; `None` once caught up.
+        next: Option<u64>,
+        /// Messages right after the requested position were already evicted from the log,
+        /// so the replay has a gap.
+        truncated: bool,
+    },
+    /// Too many requests from this peer; try again after this many milliseconds.
+    RateLimited { retry_after_ms: u64 },
+}
+
+pub type ReplayBehaviour = request_response::cbor::Behaviour<ReplayRequest, ReplayResponse>;
+
+pub fn make_replay_behaviour() -> ReplayBehaviour {
+    request_response::cbor::Behaviour::new(
+        [(StreamProtocol::new(REPLAY_PROTOCOL), ProtocolSupport::Full)],
+        request_response::Config::default(),
+    )
+}
+
+/// Bounded per-topic log of the messages a node saw, oldest first.
+#[derive(Debug)]
+pub struct MessageLog {
+    topics: HashMap<String, VecDeque<LoggedMessage>>,
+    capacity: usize,
+    last_seq: u64,
+}
+
+impl Default for MessageLog {
+    fn default() -> Self {
+        Self::new(DEFAULT_LOG_CAPACITY)
+    }
+}
+
+impl MessageLog {
+    /// Keep up to `capacity` messages per topic, evicting the oldest.
+    pub fn new(capacity: usize) -> Self {
+        Self { topics: HashMap::new(), capacity: capacity.max(1), last_seq: 0 }
+    }
+
+    /// Log `data` on `topic` and return its sequence number.
+    pub fn append(&mut self, topic: &str, data: Vec<u8>, now_ms: u64) -> u64 {
+        self.last_seq += 1;
+        let log = self.topics.entry(topic.to_string()).or_default();
+        if log.len() >= self.capacity {
+            log.pop_front();
+        }
+        log.push_back(LoggedMessage { seq: self.last_seq, timestamp_ms: now_ms, data });
+        self.last_seq
+    }
+
+    pub fn len(&self, topic: &str) -> usize {
+        self.topics.get(topic).map_or(0, VecDeque::len)
+    }
+
+    /// One page answering `request`, cut at `max_messages` (or the request's smaller
+    /// limit) and at `max_bytes` of payload.
+    pub fn page(&self, request: &ReplayRequest, max_messages: usize, max_bytes: usize) -> ReplayResponse {
+        let empty = VecDeque::new();
+        let log = self.topics.get(&request.topic).unwrap_or(&empty);
+        let start = match request.since {
+            ReplayFrom::Seq(seq) => log.partition_point(|m| m.seq <= seq),
+            ReplayFrom::Timestamp(ms) => log.partition_point(|m| m.timestamp_ms < ms),
+        };
+        let truncated = match (request.since, log.front()) {
+            (ReplayFrom::Seq(seq), Some(oldest)) => seq > 0 && oldest.seq > seq + 1 && start == 0,
+            _ => false,
+        };
+        let limit = match request.limit {
+            0 => max_messages,
+            n => (n as usize).min(max_messages),
+        };
+        let mut messages = Vec::new();
+        let mut bytes = 0;
+        for message in log.iter().skip(start) {
+            if messages.len() >= limit || (!messages.is_empty() && bytes + message.data.len() > max_bytes) {
+                break;
+            }
+            bytes += message.data.len();
+            messages.push(message.clone());
+        }
+        let next = (start + messages.len() < log.len()).then(|| messages.last().map_or(0, |m| m.seq));
+        ReplayResponse::Page { messages, next, truncated }
+    }
+}
+
+/// Fixed-window request counter per peer.
+#[derive(Debug)]
+pub struct ReplayRateLimiter {
+    max_requests: u32,
+    window_ms: u64,
+    /// Per peer: (window start, requests in it).
+    peers: HashMap<PeerId, (u64, u32)>,
+}
+
+impl Default for ReplayRateLimiter {
+    fn default() -> Self {
+        Self::new(DEFAULT_REQUESTS_PER_WINDOW, DEFAULT_RATE_WINDOW)
+    }
+}
+
+impl ReplayRateLimiter {
+    pub fn new(max_requests: u32, window: Duration) -> Self {
+        Self { max_requests, window_ms: window.as_millis() as u64, peers: HashMap::new() }
+    }
+
+    /// Count a request from `peer`. Fails with the milliseconds until its window resets
+    /// once it has used up its requests.
+    pub fn check(&mut self, peer: PeerId, now_ms: u64) -> Result<(), u64> {
+        // Forget peers whose window has passed, so the map does not grow without bound
+        let window_ms = self.window_ms;
+        self.peers.retain(|_, (start, _)| now_ms < *start + window_ms);
+        let (start, count) = self.peers.entry(peer).or_insert((now_ms, 0));
+        if *count >= self.max_requests {
+            return Err(*start + window_ms - now_ms);
+        }
+        *count += 1;
+        Ok(())
+    }
+}
+
+/// The responder side: a message log plus the limits enforced on requests for it.
+#[derive(Debug)]
+pub struct ReplayResponder {
+    pub log: MessageLog,
+    limiter: ReplayRateLimiter,
+    max_page_messages: usize,
+    max_page_bytes: usize,
+}
+
+impl Default for ReplayResponder {
+    fn default() -> Self {
+        Self::new(MessageLog::default(), ReplayRateLimiter::default())
+    }
+}
+
+impl ReplayResponder {
+    pub fn new(log: MessageLog, limiter: ReplayRateLimiter) -> Self {
+        Self { log, limiter, max_page_messages: DEFAULT_MAX_PAGE_MESSAGES, max_page_bytes: DEFAULT_MAX_PAGE_BYTES }
+    }
+
+    pub fn with_page_limits(mut self, max_messages: usize, max_bytes: usize) -> Self {
+        self.max_page_messages = max_messages.max(1);
+        self.max_page_bytes = max_bytes;
+        self
+    }
+
+    pub fn respond(&mut self, peer: PeerId, request: &ReplayRequest, now_ms: u64) -> ReplayResponse {
+        match self.limiter.check(peer, now_ms) {
+            Ok(()) => self.page(request),
+            Err(retry_after_ms) => ReplayResponse::RateLimited { retry_after_ms },
+        }
+    }
+
+    /// Answer `request` within the page limits, leaving rate limiting to the caller (e.g.
+    /// a [`crate::node::audit::RequestAudit`]).
+    pub fn page(&self, request: &ReplayRequest) -> ReplayResponse {
+        self.log.page(request, self.max_page_messages, self.max_page_bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(since: ReplayFrom, limit: u32) -> ReplayRequest {
+        ReplayRequest { topic: "t".into(), since, limit }
+    }
+
+    fn seqs(response: &ReplayResponse) -> (Vec<u64>, Option<u64>, bool) {
+        match response {
+            ReplayResponse::Page { messages, next, truncated } => {
+                (messages.iter().map(|m| m.seq).collect(), *next, *truncated)
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[test]
+    fn pages_through_the_log_in_order() {
+        let mut log = MessageLog::new(4);
+        for i in 0..6u64 {
+            log.append("t", vec![0; 10], 1000 + i);
+        }
+        log.append("other", vec![0; 10], 2000);
+        // Seqs 1 and 2 were evicted
+        assert_eq!(log.len("t"), 4);
+
+        assert_eq!(seqs(&log.page(&request(ReplayFrom::Seq(0), 2), 100, 1000)), (vec![3, 4], Some(4), false));
+        assert_eq!(seqs(&log.page(&request(ReplayFrom::Seq(4), 0), 100, 1000)), (vec![5, 6], None, false));
+        assert_eq!(seqs(&log.page(&request(ReplayFrom::Seq(1), 0), 100, 1000)), (vec![3, 4, 5, 6], None, true));
+        assert_eq!(seqs(&log.page(&request(ReplayFrom::Timestamp(1004), 0), 100, 1000)), (vec![5, 6], None, false));
+        // Byte cap, but never an empty page
+        assert_eq!(seqs(&log.page(&request(ReplayFrom::Seq(0), 0), 100, 25)), (vec![3, 4], Some(4), false));
+        assert_eq!(seqs(&log.page(&request(ReplayFrom::Seq(0), 0), 100, 5)), (vec![3], Some(3), false));
+        assert_eq!(seqs(&log.page(&request(ReplayFrom::Seq(6), 0), 100, 1000)), (vec![], None, false));
+    }
+
+    #[test]
+    fn limits_requests_per_peer() {
+        let mut limiter = ReplayRateLimiter::new(2, Duration::from_secs(1));
+        let (a, b) = (PeerId::random(), PeerId::random());
+        assert!(limiter.check(a, 0).is_ok());
+        assert!(limiter.check(a, 100).is_ok());
+        assert_eq!(limiter.check(a, 400), Err(600));
+        assert!(limiter.check(b, 400).is_ok());
+        assert!(limiter.check(a, 1000).is_ok());
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn memory_swarm() -> libp2p::Swarm<ReplayBehaviour> {
+        use libp2p::Transport;
+        libp2p::SwarmBuilder::with_new_identity()
+            .with_tokio()
+            .with_other_transport(|key| {
+                libp2p::core::transport::MemoryTransport::default()
+                    .upgrade(libp2p::core::upgrade::Version::V1)
+                    .authenticate(libp2p::noise::Config::new(key).expect("noise config"))
+                    .multiplex(libp2p::yamux::Config::default())
+            })
+            .unwrap()
+            .with_behaviour(|_| make_replay_behaviour())
+            .unwrap()
+            .build()
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[tokio::test]
+    async fn late_joiner_replays_an_earlier_update() {
+        use futures::StreamExt;
+        use libp2p::multiaddr::Protocol;
+        use libp2p::swarm::SwarmEvent;
+
+        let mut server = memory_swarm();
+        let mut responder = ReplayResponder::default();
+        // Logged as it arrived, before the client existed
+        let topic = crate::behaviour::docstore_topic().to_string();
+        responder.log.append(&topic, b"hello".to_vec(), 1_000);
+
+        server.listen_on(Protocol::Memory(0).into()).unwrap();
+        let addr = loop {
+            if let SwarmEvent::NewListenAddr { address, .. } = server.select_next_some().await {
+                break address;
+            }
+        };
+        let server_id = *server.local_peer_id();
+
+        let mut client = memory_swarm();
+        client.dial(addr.with(Protocol::P2p(server_id))).unwrap();
+
+        let request = ReplayRequest { topic, since: ReplayFrom::Seq(0), limit: 0 };
+        let response = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                tokio::select! {
+                    event = server.select_next_some() => {
+                        if let SwarmEvent::Behaviour(request_response::Event::Message {
+                            peer,
+                            message: request_response::Message::Request { request, channel, .. },
+                            ..
+                        }) = event
+                        {
+                            let response = responder.respond(peer, &request, 2_000);
+                            server.behaviour_mut().send_response(channel, response).unwrap();
+                        }
+                    }
+                    event = client.select_next_some() => match event {
+                        SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                            client.behaviour_mut().send_request(&peer_id, request.clone());
+                        }
+                        SwarmEvent::Behaviour(request_response::Event::Message {
+                            message: request_response::Message::Response { response, .. },
+                            ..
+                        }) => break response,
+                        _ => {}
+                    },
+                }
+            }
+        })
+        .await
+        .expect("replay timed out");
+
+        match response {
+            ReplayResponse::Page { messages, next, truncated } => {
+                assert_eq!(messages.len(), 1);
+                assert_eq!(messages[0].data, b"hello");
+                assert_eq!((next, truncated), (None, false));
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+}
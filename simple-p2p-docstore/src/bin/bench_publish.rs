@@ -0,0 +1,211 @@
+//! End-to-end publish benchmark over in-process memory-transport nodes.
+//!
+//! `cargo run --release --bin bench_publish -- --nodes 4 --messages 1000 --size 1024`
+//!
+//! Node 0 publishes `--messages` document updates of `--size` bytes; every other node
+//! reports when each one arrives. Each run prints the rate at which messages reached
+//! every receiver and the latency percentiles from publish to delivery, followed by the
+//! median over `--runs`. Payloads are fixed and publishing is sequential, so run to run
+//! the numbers move by far less than a 2x regression.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::Context;
+use tokio::sync::mpsc;
+use web_time::Instant;
+
+use simple_p2p_docstore::behaviour::docstore::DocUpdate;
+use simple_p2p_docstore::node::{Node, NodeBuilder, NodeEvent, NodeRole};
+use simple_p2p_docstore::testing::spawn_test_node;
+
+mod common;
+
+use common::arg_value;
+
+const DOC_PREFIX: &str = "bench/";
+
+/// Documents the updates are spread over.
+const DOCS: u64 = 16;
+
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a run waits for the last delivery before counting the rest as lost.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(60);
+
+fn arg_number(name: &str, default: usize) -> anyhow::Result<usize> {
+    match arg_value(name) {
+        Some(v) => v.parse().with_context(|| format!("--{name} must be a number")),
+        None => Ok(default),
+    }
+}
+
+/// The update carrying sequence number `seq`: the number up front, then a fixed filler.
+fn bench_update(seq: u64, size: usize) -> DocUpdate {
+    let mut payload = seq.to_be_bytes().to_vec();
+    payload.extend((payload.len()..size).map(|i| b"lorem ipsum dolor sit amet "[i % 27]));
+    DocUpdate::new(format!("{DOC_PREFIX}{}", seq % DOCS), payload)
+}
+
+fn bench_seq(update: &DocUpdate) -> Option<u64> {
+    if !update.doc_id.starts_with(DOC_PREFIX) {
+        return None;
+    }
+    Some(u64::from_be_bytes(update.payload.get(..8)?.try_into().ok()?))
+}
+
+/// Value at quantile `q` of sorted `values`.
+fn percentile(values: &[Duration], q: f64) -> Duration {
+    values[((values.len() - 1) as f64 * q).round() as usize]
+}
+
+fn ms(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+struct RunResult {
+    messages_per_sec: f64,
+    p50: Duration,
+    p90: Duration,
+    p99: Duration,
+    max: Duration,
+    failed: usize,
+    lost: usize,
+}
+
+/// Publish `count` updates starting at sequence `first_seq` and wait until each of the
+/// `receivers` has seen them.
+async fn run(
+    publisher: &Node,
+    deliveries: &mut mpsc::UnboundedReceiver<(u64, Instant)>,
+    receivers: usize,
+    first_seq: u64,
+    count: usize,
+    size: usize,
+) -> RunResult {
+    let mut sent: HashMap<u64, Instant> = HashMap::with_capacity(count);
+    let mut failed = 0;
+    let start = Instant::now();
+    for seq in first_seq..first_seq + count as u64 {
+        let update = bench_update(seq, size);
+        let at = Instant::now();
+        match publisher.publish_doc_update(update).await {
+            Ok(_) => {
+                sent.insert(seq, at);
+            }
+            Err(e) => {
+                tracing::warn!("Publishing {} failed: {}", seq, e);
+                failed += 1;
+            }
+        }
+    }
+
+    let expected = sent.len() * receivers;
+    let mut latencies = Vec::with_capacity(expected);
+    let mut last = start;
+    let deadline = tokio::time::Instant::now() + DELIVERY_TIMEOUT;
+    while latencies.len() < expected {
+        match tokio::time::timeout_at(deadline, deliveries.recv()).await {
+            Ok(Some((seq, at))) => {
+                // Late deliveries of an earlier run are ignored
+                if let Some(published) = sent.get(&seq) {
+                    latencies.push(at.duration_since(*published));
+                    last = last.max(at);
+                }
+            }
+            Ok(None) | Err(_) => break,
+        }
+    }
+    latencies.sort();
+    let elapsed = last.duration_since(start).max(Duration::from_micros(1));
+    let delivered = latencies.len() as f64 / receivers as f64;
+    let zero = Duration::ZERO;
+    RunResult {
+        messages_per_sec: delivered / elapsed.as_secs_f64(),
+        p50: if latencies.is_empty() { zero } else { percentile(&latencies, 0.5) },
+        p90: if latencies.is_empty() { zero } else { percentile(&latencies, 0.9) },
+        p99: if latencies.is_empty() { zero } else { percentile(&latencies, 0.99) },
+        max: latencies.last().copied().unwrap_or(zero),
+        failed,
+        lost: expected - latencies.len(),
+    }
+}
+
+async fn bench() -> anyhow::Result<()> {
+    let nodes = arg_number("nodes", 4)?;
+    let messages = arg_number("messages", 1000)?;
+    let size = arg_number("size", 1024)?;
+    let runs = arg_number("runs", 3)?;
+    let warmup = arg_number("warmup", 50)?;
+    anyhow::ensure!(nodes >= 2, "--nodes must be at least 2");
+    anyhow::ensure!(messages > 0 && runs > 0, "--messages and --runs must be positive");
+    anyhow::ensure!(size >= 8, "--size must be at least 8 bytes");
+    let max_update_size = NodeBuilder::new(NodeRole::Client).docstore_config().max_update_size;
+    anyhow::ensure!(size <= max_update_size, "--size must be at most {} bytes", max_update_size);
+
+    // Every node dials every node spawned before it
+    let mut handles: Vec<Node> = Vec::with_capacity(nodes);
+    let mut addrs = Vec::with_capacity(nodes);
+    for _ in 0..nodes {
+        let (node, addr) = spawn_test_node(NodeBuilder::new(NodeRole::FullNode)).await?;
+        for earlier in &addrs {
+            node.dial(earlier.clone()).await?;
+        }
+        handles.push(node);
+        addrs.push(addr);
+    }
+    for node in &handles {
+        node.wait_ready(READY_TIMEOUT).await?;
+    }
+
+    let (tx, mut deliveries) = mpsc::unbounded_channel();
+    let mut handles = handles.into_iter();
+    let publisher = handles.next().expect("at least two nodes");
+    for mut node in handles {
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            while let Some(event) = node.next_event().await {
+                if let NodeEvent::DocUpdateReceived { update, .. } = event {
+                    if let Some(seq) = bench_seq(&update) {
+                        let _ = tx.send((seq, Instant::now()));
+                    }
+                }
+            }
+        });
+    }
+    let receivers = nodes - 1;
+
+    println!("{nodes} nodes, {messages} messages of {size} bytes, {runs} runs");
+    let mut seq = 0;
+    if warmup > 0 {
+        run(&publisher, &mut deliveries, receivers, seq, warmup, size).await;
+        seq += warmup as u64;
+    }
+    let mut rates = Vec::with_capacity(runs);
+    let mut p50s = Vec::with_capacity(runs);
+    for i in 1..=runs {
+        let r = run(&publisher, &mut deliveries, receivers, seq, messages, size).await;
+        seq += messages as u64;
+        println!(
+            "run {i}: {:.0} msg/s, latency p50 {:.2} ms, p90 {:.2} ms, p99 {:.2} ms, max {:.2} ms{}",
+            r.messages_per_sec,
+            ms(r.p50),
+            ms(r.p90),
+            ms(r.p99),
+            ms(r.max),
+            if r.failed + r.lost > 0 { format!(", {} failed, {} lost", r.failed, r.lost) } else { String::new() },
+        );
+        rates.push(r.messages_per_sec);
+        p50s.push(r.p50);
+    }
+    rates.sort_by(f64::total_cmp);
+    p50s.sort();
+    println!("median: {:.0} msg/s, latency p50 {:.2} ms", rates[rates.len() / 2], ms(p50s[p50s.len() / 2]));
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt().with_max_level(tracing::Level::WARN).init();
+    // A fixed worker count keeps scheduling comparable between runs and machines
+    tokio::runtime::Builder::new_multi_thread().worker_threads(4).enable_all().build()?.block_on(bench())
+}
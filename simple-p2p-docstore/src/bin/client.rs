@@ -0,0 +1,193 @@
+//! Native docstore client.
+//!
+//! `client sync-dir <path> --doc-prefix <prefix>` mirrors a directory into the docstore,
+//! see [`simple_p2p_docstore::node::dir_sync`].
+
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::Context;
+use notify::{RecursiveMode, Watcher};
+
+use simple_p2p_docstore::behaviour::docstore::DocUpdate;
+use simple_p2p_docstore::node::data_dir::DataDir;
+use simple_p2p_docstore::node::dir_sync::{DirSync, DirSyncConfig, LocalChange, RemoteChange};
+use simple_p2p_docstore::node::proxy::ProxyConfig;
+use simple_p2p_docstore::node::{keys, Node, NodeBuilder, NodeEvent, NodeRole};
+
+mod common;
+
+use common::{arg_value, arg_values};
+
+/// Quiet time after a file event before the changed paths are read, so an editor's
+/// burst of writes is published once.
+const SETTLE: Duration = Duration::from_millis(300);
+
+/// How often publishes that failed (e.g. with no mesh peers yet) are tried again.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Returns true if `--name` was passed on the command line.
+fn has_flag(name: &str) -> bool {
+    let flag = format!("--{name}");
+    std::env::args().skip(1).any(|a| a == flag)
+}
+
+/// Positional arguments after the subcommand, with `--flag value` pairs skipped.
+fn positional_args() -> Vec<String> {
+    const SWITCHES: &[&str] = &["--dry-run"];
+    let mut out = Vec::new();
+    let mut args = std::env::args().skip(2);
+    while let Some(arg) = args.next() {
+        if arg.starts_with("--") {
+            if !arg.contains('=') && !SWITCHES.contains(&arg.as_str()) {
+                args.next();
+            }
+            continue;
+        }
+        out.push(arg);
+    }
+    out
+}
+
+const USAGE: &str = "usage: client sync-dir <path> --doc-prefix <prefix> [--ignore <pattern>]... [--dry-run] \
+                     [--bootstrap <multiaddr,...>] [--data-dir <path>] [--proxy socks5://[user:pass@]host:port]";
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+    match std::env::args().nth(1).as_deref() {
+        Some("sync-dir") => run_sync_dir().await,
+        _ => anyhow::bail!(USAGE),
+    }
+}
+
+async fn run_sync_dir() -> anyhow::Result<()> {
+    let root: PathBuf = positional_args().into_iter().next().context(USAGE)?.into();
+    let root = root.canonicalize().with_context(|| format!("cannot open {}", root.display()))?;
+    anyhow::ensure!(root.is_dir(), "{} is not a directory", root.display());
+    let prefix = arg_value("doc-prefix").context("--doc-prefix is required")?;
+    anyhow::ensure!(!prefix.trim_matches('/').is_empty(), "--doc-prefix must not be empty");
+
+    let mut builder = NodeBuilder::new(NodeRole::Client);
+    let peers = arg_value("bootstrap").or_else(|| std::env::var("BOOTSTRAP_PEERS").ok()).unwrap_or_default();
+    for p in peers.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+        builder = builder.add_bootstrap(p.parse().with_context(|| format!("invalid multiaddr {}", p))?);
+    }
+    if let Some(proxy) = arg_value("proxy") {
+        let proxy: ProxyConfig = proxy.parse().map_err(anyhow::Error::msg).context("invalid --proxy")?;
+        println!("Dialing through proxy {}", proxy);
+        builder = builder.with_proxy(proxy);
+    }
+    // Without it the client keeps nothing between runs
+    if let Some(dir) = arg_value("data-dir") {
+        let dir = DataDir::new(dir);
+        builder = builder.with_address_book(dir.address_book()).with_store_path(dir.store());
+    }
+    let docstore_config = builder.docstore_config();
+    let mut config = DirSyncConfig::new(&root, prefix);
+    config.ignore = arg_values("ignore").iter().flat_map(|v| v.split(',')).map(|s| s.trim().to_string()).collect();
+    config.dry_run = has_flag("dry-run");
+    config.max_update_size = docstore_config.max_update_size;
+    config.max_file_size = docstore_config.max_document_size;
+    let dry_run = config.dry_run;
+    let mut sync = DirSync::new(config);
+
+    let mut node = builder.spawn(keys::generate_identity(keys::KeyType::default()))?;
+    println!("Syncing {} as {}/ (peer id {})", root.display(), sync.config().doc_prefix, node.peer_id());
+    if dry_run {
+        println!("Dry run: nothing is published or written");
+    }
+    if let Err(e) = node.wait_ready(READY_TIMEOUT).await {
+        tracing::warn!("{}; publishing anyway, failed updates are retried", e);
+    }
+
+    let (tx, mut file_events) = tokio::sync::mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+        let _ = tx.send(res);
+    })?;
+    watcher.watch(&root, RecursiveMode::Recursive)?;
+
+    let mut retry: Vec<DocUpdate> = Vec::new();
+    for change in sync.scan()? {
+        apply_local(&node, change, dry_run, &mut retry).await;
+    }
+
+    let mut changed: HashSet<PathBuf> = HashSet::new();
+    let settle = tokio::time::sleep(SETTLE);
+    tokio::pin!(settle);
+    let mut retry_tick = tokio::time::interval(RETRY_INTERVAL);
+    loop {
+        tokio::select! {
+            res = file_events.recv() => match res {
+                Some(Ok(event)) => {
+                    changed.extend(event.paths);
+                    settle.as_mut().reset(tokio::time::Instant::now() + SETTLE);
+                }
+                Some(Err(e)) => tracing::warn!("Watch error: {}", e),
+                None => anyhow::bail!("directory watcher stopped"),
+            },
+            () = &mut settle, if !changed.is_empty() => {
+                let mut paths: Vec<PathBuf> = changed.drain().collect();
+                paths.sort();
+                for path in paths {
+                    match sync.local_change(&path) {
+                        Ok(change) => apply_local(&node, change, dry_run, &mut retry).await,
+                        Err(e) => tracing::warn!("Cannot read {}: {}", path.display(), e),
+                    }
+                }
+            }
+            _ = retry_tick.tick(), if !retry.is_empty() => {
+                for update in std::mem::take(&mut retry) {
+                    publish(&node, update, &mut retry).await;
+                }
+            }
+            event = node.next_event() => match event {
+                Some(NodeEvent::DocUpdateReceived { peer_id, update }) => match sync.remote_update(&update) {
+                    Ok(RemoteChange::Written(path)) => println!("← {} from {}", path.display(), peer_id),
+                    Ok(RemoteChange::Deleted(path)) => println!("← deleted {} by {}", path.display(), peer_id),
+                    Ok(RemoteChange::Conflict(path)) => {
+                        tracing::warn!("{} has local edits; {}'s version was saved as {}", update.doc_id, peer_id, path.display());
+                    }
+                    Ok(RemoteChange::Partial | RemoteChange::Skipped) => {}
+                    Err(e) => tracing::warn!("Cannot write {}: {}", update.doc_id, e),
+                },
+                Some(_) => {}
+                None => anyhow::bail!("node stopped"),
+            },
+        }
+    }
+}
+
+async fn apply_local(node: &Node, change: LocalChange, dry_run: bool, retry: &mut Vec<DocUpdate>) {
+    match change {
+        LocalChange::Publish(updates) => {
+            // A failed publish of an older version must not land after this one
+            retry.retain(|queued| !updates.iter().any(|u| u.doc_id == queued.doc_id));
+            for update in updates {
+                if dry_run {
+                    println!("→ would publish {} ({} bytes)", update.doc_id, update.payload.len());
+                } else {
+                    publish(node, update, retry).await;
+                }
+            }
+        }
+        LocalChange::TooLarge { doc_id, size } => {
+            tracing::warn!("Skipping {}: {} bytes is over the document size limit", doc_id, size);
+        }
+        LocalChange::Unchanged | LocalChange::Skipped => {}
+    }
+}
+
+async fn publish(node: &Node, update: DocUpdate, retry: &mut Vec<DocUpdate>) {
+    let doc_id = update.doc_id.clone();
+    match node.publish_doc_update(update.clone()).await {
+        Ok(published) => println!("→ {} to {} peers", doc_id, published.sent_to.len()),
+        Err(e) => {
+            tracing::warn!("Publishing {} failed, will retry: {}", doc_id, e);
+            retry.push(update);
+        }
+    }
+}
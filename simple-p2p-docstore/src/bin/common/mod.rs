@@ -0,0 +1,22 @@
+//! Command-line helpers shared by the binaries. A directory module, so Cargo doesn't
+//! take it for a binary of its own.
+
+/// Returns the value of `--name value` / `--name=value` from the command line, if present.
+pub fn arg_value(name: &str) -> Option<String> {
+    arg_values(name).into_iter().next()
+}
+
+/// Every value of a repeatable `--name value` / `--name=value` flag.
+pub fn arg_values(name: &str) -> Vec<String> {
+    let flag = format!("--{name}");
+    let mut out = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == flag {
+            out.extend(args.next());
+        } else if let Some(v) = arg.strip_prefix(&format!("{flag}=")) {
+            out.push(v.to_string());
+        }
+    }
+    out
+}
@@ -1,8 +1,9 @@
+use futures::channel::mpsc;
 use futures::prelude::*;
 use libp2p::core::muxing::StreamMuxerBox;
 use libp2p::gossipsub::{self};
 use libp2p::identify;
-use libp2p_kad::{Behaviour as KademliaBehaviour, store::MemoryStore, Event as KademliaEvent, QueryResult};
+use libp2p_kad::{Behaviour as KademliaBehaviour, store::{MemoryStore, RecordStore}, Event as KademliaEvent, InboundRequest, QueryResult};
 use libp2p::identity;
 use std::path::{Path, PathBuf};
 use std::fs::OpenOptions;
@@ -13,13 +14,51 @@ use std::os::unix::fs::OpenOptionsExt;
 use libp2p::noise;
 use anyhow::Context;
 use libp2p::swarm::{NetworkBehaviour, SwarmEvent};
-use libp2p::{Multiaddr, PeerId};
+use libp2p::{Multiaddr, PeerId, Swarm};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, RwLock};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::{Layer, Layered, SubscriberExt};
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{reload, Registry};
+use web_time::Instant;
 use libp2p_yamux as yamux;
 
-use simple_p2p_docstore::behaviour::{make_docstore_gossipsub, make_peer_dht};
-use simple_p2p_docstore::node::{NodeBuilder, NodeRole};
+use simple_p2p_docstore::behaviour::{make_docstore_gossipsub, make_peer_dht, make_relay_ephemeral_gossipsub, relay_provider_key, successor_key, DocstoreGossipsubConfig, NetworkAnnouncement, PeerDhtConfig, ScopeFilter, SuccessorAnnouncement};
+use simple_p2p_docstore::behaviour::interest::{self, InterestBehaviour, TopicInterest};
+use simple_p2p_docstore::behaviour::ip_limits::{self, IpLimitsConfig};
+use simple_p2p_docstore::behaviour::keep_alive::{self, KeepAliveBehaviour};
+use simple_p2p_docstore::behaviour::peers::{self, PeerDirectory, PeersBehaviour};
+use simple_p2p_docstore::behaviour::mailbox::{self, Mailbox, MailboxBehaviour, MailboxLimits};
+use simple_p2p_docstore::behaviour::rendezvous::RendezvousBehaviour;
+use simple_p2p_docstore::behaviour::nat::{self, NatAction, NatBehaviourEvent, PortMappings};
+use simple_p2p_docstore::behaviour::replay::{self, MessageLog, ReplayBehaviour, ReplayRateLimiter, ReplayResponder};
+use simple_p2p_docstore::node::admin::{self, AdminCall, AdminCommand};
+use simple_p2p_docstore::node::audit::{self, RequestAudit};
+use simple_p2p_docstore::node::config_check::{self, CheckReport};
+use simple_p2p_docstore::node::data_dir::DataDir;
+use simple_p2p_docstore::node::dht_store::{DhtStoreMonitor, StoreFull, StoreFullKind};
+use simple_p2p_docstore::node::duplicates::{DuplicateConfig, DuplicateDetector, Verdict};
+use simple_p2p_docstore::node::event_log::{self, EventKind, EventMirror, MirrorEvent};
+use simple_p2p_docstore::node::federation::Federation;
+use simple_p2p_docstore::node::health::{self, Health, ReadinessState};
+use simple_p2p_docstore::node::migrations;
+use simple_p2p_docstore::node::proxy::{ProxiedTcp, ProxyConfig};
+use simple_p2p_docstore::node::observer::MessageInfo;
+use simple_p2p_docstore::node::partition::{PartitionChange, PartitionWatch, DEFAULT_PARTITION_THRESHOLD};
+use simple_p2p_docstore::node::redial::ImportantPeers;
+use simple_p2p_docstore::node::relay_discovery::{RELAY_HOP_PROTOCOL, RELAY_PROVIDER_REFRESH};
+use simple_p2p_docstore::node::reload::{HotSetting, ReloadOutcome, ServerConfig};
+use simple_p2p_docstore::node::{addrs, keys, BanList, ExternalAddrChange, ExternalAddrs, NodeBuilder, NodeEvent, NodeRole, TracingObserver, TrafficStats};
+
+mod common;
+
+use common::arg_value as cli_value;
 
 #[cfg(not(target_arch = "wasm32"))]
+use libp2p::core::upgrade::Version;
 use libp2p::{tcp, Transport};
 #[cfg(not(target_arch = "wasm32"))]
 use libp2p_webrtc as webrtc;
@@ -28,165 +67,1728 @@ use libp2p_webrtc as webrtc;
 struct MyBehaviour {
     ping: libp2p::ping::Behaviour,
     gossipsub: gossipsub::Behaviour,
+    ephemeral: gossipsub::Behaviour,
     identify: identify::Behaviour,
     kademlia: KademliaBehaviour<MemoryStore>,
+    /// Serves catch-up requests from the messages this server logged.
+    replay: ReplayBehaviour,
+    /// Holds messages for peers that are offline until they fetch them.
+    mailbox: MailboxBehaviour,
+    /// Rendezvous server, for deployments that find peers through us instead of the DHT.
+    rendezvous: RendezvousBehaviour,
+    /// Answers the pings browsers send to keep their room peers connected.
+    keep_alive: KeepAliveBehaviour,
+    /// Lists the browsers that opted in to each other, so they can connect directly.
+    peers: PeersBehaviour,
+    /// Takes the room topics each browser still wants, ahead of its unsubscribes.
+    interest: InterestBehaviour,
 
     #[cfg(not(target_arch = "wasm32"))]
-    relay: libp2p::relay::Behaviour,
+    relay: libp2p::swarm::behaviour::toggle::Toggle<libp2p::relay::Behaviour>,
+    #[cfg(not(target_arch = "wasm32"))]
+    ip_limits: ip_limits::Behaviour,
+    /// UPnP behind `--upnp`; AutoNAT always, so home-hosted nodes can probe through us.
+    #[cfg(not(target_arch = "wasm32"))]
+    nat: nat::NatBehaviour,
 }
 
 // PeerDHT and DocStore behaviour are provided by `src/behaviour`
 
-fn load_or_create_identity(path: &Path) -> anyhow::Result<identity::Keypair> {
-    // Try to load existing keypair from disk (protobuf encoding). If not present, generate and persist.
+/// Set when stdout carries the NDJSON event stream.
+static STATUS_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Human-readable status output: stdout, or stderr while stdout carries NDJSON events.
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if STATUS_TO_STDERR.load(std::sync::atomic::Ordering::Relaxed) {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// Settings from the `--config` file, behind the command line; see [`simple_p2p_docstore::node::reload`].
+static FILE_CONFIG: RwLock<Option<ServerConfig>> = RwLock::new(None);
+
+/// Read `--config`, if given, into [`FILE_CONFIG`].
+fn load_config_file() -> anyhow::Result<()> {
+    if let Some(path) = cli_value("config") {
+        set_file_config(ServerConfig::load(Path::new(&path))?);
+    }
+    Ok(())
+}
+
+fn file_config() -> ServerConfig {
+    FILE_CONFIG.read().unwrap_or_else(|e| e.into_inner()).clone().unwrap_or_default()
+}
+
+fn set_file_config(config: ServerConfig) {
+    *FILE_CONFIG.write().unwrap_or_else(|e| e.into_inner()) = Some(config);
+}
+
+/// Returns the value of `--name`, from the command line or else the `--config` file.
+fn arg_value(name: &str) -> Option<String> {
+    cli_value(name).or_else(|| file_config().get(name).map(str::to_string))
+}
+
+/// Whether `--name` was given on the command line, with or without a value.
+fn on_command_line(name: &str) -> bool {
+    let flag = format!("--{name}");
+    std::env::args().skip(1).any(|a| a == flag || a.starts_with(&format!("{flag}=")))
+}
+
+/// Returns true if `--name` was passed on the command line, or set in the `--config` file.
+fn has_flag(name: &str) -> bool {
+    let flag = format!("--{name}");
+    std::env::args().skip(1).any(|a| a == flag) || file_config().is_set(name)
+}
+
+/// Bootstrap peers as comma-separated multiaddrs, from `--bootstrap-peers` on the command
+/// line, the `BOOTSTRAP_PEERS` environment variable or the `--config` file, in that order.
+fn bootstrap_peers() -> Option<String> {
+    cli_value("bootstrap-peers")
+        .or_else(|| std::env::var("BOOTSTRAP_PEERS").ok())
+        .or_else(|| file_config().get("bootstrap-peers").map(str::to_string))
+}
+
+/// The bootstrap peers that parse; the others were reported at startup.
+fn bootstrap_addrs() -> Vec<Multiaddr> {
+    let peers = bootstrap_peers().unwrap_or_default();
+    peers.split(',').filter_map(|p| p.trim().parse().ok()).collect()
+}
+
+type LevelLayer = reload::Layer<LevelFilter, Registry>;
+type LogLayer = Box<dyn Layer<Layered<LevelLayer, Registry>> + Send + Sync>;
+
+/// Handles on the log level and format, which a reload changes.
+struct LogControl {
+    level: reload::Handle<LevelFilter, Registry>,
+    format: reload::Handle<LogLayer, Layered<LevelLayer, Registry>>,
+}
+
+impl LogControl {
+    /// Log as `--log` and `--log-format` say.
+    fn init() -> anyhow::Result<Self> {
+        let (level, compact) = log_settings()?;
+        let (level, level_handle) = reload::Layer::new(level);
+        let (format, format_handle) = reload::Layer::new(log_layer(compact));
+        tracing_subscriber::registry().with(level).with(format).init();
+        Ok(Self { level: level_handle, format: format_handle })
+    }
+
+    /// Switch to the current `--log` and `--log-format`.
+    fn apply(&self) -> anyhow::Result<()> {
+        let (level, compact) = log_settings()?;
+        self.level.reload(level)?;
+        self.format.reload(log_layer(compact))?;
+        Ok(())
+    }
+}
+
+/// `--log` (trace, debug, info, warn, error or off; info by default) and whether
+/// `--log-format` is `compact` rather than `full`.
+fn log_settings() -> anyhow::Result<(LevelFilter, bool)> {
+    let level = match arg_value("log") {
+        Some(level) => level.parse().map_err(|_| anyhow::anyhow!("invalid --log {level:?}: expected trace, debug, info, warn, error or off"))?,
+        None => LevelFilter::INFO,
+    };
+    let compact = match arg_value("log-format").as_deref() {
+        None | Some("full") => false,
+        Some("compact") => true,
+        Some(other) => anyhow::bail!("invalid --log-format {other:?}: expected full or compact"),
+    };
+    Ok((level, compact))
+}
+
+/// Log lines go to stdout, or stderr while stdout carries NDJSON events.
+fn log_layer(compact: bool) -> LogLayer {
+    let to_stderr = STATUS_TO_STDERR.load(std::sync::atomic::Ordering::Relaxed);
+    let layer = tracing_subscriber::fmt::layer().with_writer(move || -> Box<dyn Write> {
+        if to_stderr {
+            Box::new(std::io::stderr())
+        } else {
+            Box::new(std::io::stdout())
+        }
+    });
+    if compact {
+        Box::new(layer.compact())
+    } else {
+        Box::new(layer)
+    }
+}
+
+/// The outbound proxy from `--proxy socks5://[user:pass@]host:port` (or `http://...`).
+fn proxy_config() -> anyhow::Result<Option<ProxyConfig>> {
+    arg_value("proxy").map(|p| p.parse().map_err(anyhow::Error::msg).context("invalid --proxy")).transpose()
+}
+
+/// Passphrase for the identity key file, from `--identity-passphrase-file` or the
+/// `IDENTITY_KEY_PASSPHRASE` environment variable.
+fn get_identity_passphrase() -> anyhow::Result<Option<String>> {
+    if let Some(path) = arg_value("identity-passphrase-file") {
+        let p = std::fs::read_to_string(&path)
+            .with_context(|| format!("failed to read passphrase file: {}", path))?;
+        return Ok(Some(p.trim_end_matches(['\r', '\n']).to_string()));
+    }
+    Ok(std::env::var("IDENTITY_KEY_PASSPHRASE").ok().filter(|p| !p.is_empty()))
+}
+
+/// Decode a hex string (as passed to `--identity-seed-hex`).
+fn decode_hex(s: &str) -> anyhow::Result<Vec<u8>> {
+    let s = s.trim();
+    anyhow::ensure!(s.len() % 2 == 0, "hex string has odd length");
+    // By bytes, as slicing the string could split a multi-byte character
+    s.as_bytes()
+        .chunks(2)
+        .enumerate()
+        .map(|(i, pair)| {
+            let digits = std::str::from_utf8(pair).ok().filter(|d| d.bytes().all(|b| b.is_ascii_hexdigit()));
+            digits
+                .and_then(|d| u8::from_str_radix(d, 16).ok())
+                .with_context(|| format!("invalid hex at offset {}", i * 2))
+        })
+        .collect()
+}
+
+/// Load the identity from `path`, or generate and persist a new one if the file doesn't exist.
+/// Existing files may hold any supported key type; `key_type` only affects generation.
+///
+/// An unreadable or undecryptable file is an error. Replacing it with a fresh identity
+/// only happens when `regenerate_invalid` is set (`--regenerate-invalid-identity`).
+fn load_or_create_identity(
+    path: &Path,
+    passphrase: Option<&str>,
+    regenerate_invalid: bool,
+    key_type: keys::KeyType,
+) -> anyhow::Result<identity::Keypair> {
     if path.exists() {
         let mut f = OpenOptions::new().read(true).open(path)
             .with_context(|| format!("failed to open identity key file: {}", path.display()))?;
         let mut buf = Vec::new();
         f.read_to_end(&mut buf).with_context(|| format!("failed to read identity key file: {}", path.display()))?;
-        if let Ok(kp) = identity::Keypair::from_protobuf_encoding(&buf) {
-            tracing::info!("Loaded identity key from {}", path.display());
-            return Ok(kp);
-        } else {
-            tracing::warn!("Failed to parse identity key from {} — generating new one", path.display());
+        match keys::decode_identity(&buf, passphrase) {
+            Ok(kp) => {
+                tracing::info!("Loaded identity key from {}", path.display());
+                return Ok(kp);
+            }
+            Err(e) if regenerate_invalid => {
+                tracing::warn!("Failed to load identity key from {} ({}) — generating new one", path.display(), e);
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("failed to load identity key file: {}", path.display()));
+            }
         }
     }
 
     // Generate a new keypair and write it to disk.
-    let kp = identity::Keypair::generate_ed25519();
-    let bytes = kp.to_protobuf_encoding().context("failed to serialize identity key pair")?;
+    let kp = keys::generate_identity(key_type);
+    let bytes = keys::encode_identity(&kp, passphrase).context("failed to serialize identity key pair")?;
+    write_key_file(path, &bytes)?;
+    tracing::info!("Generated new {} identity key and saved to {}", key_type, path.display());
+    Ok(kp)
+}
+
+/// `--addr-file PATH`: the addresses peers can reach us on, one per line with our `/p2p`
+/// peer id: the listen addresses, then the confirmed external ones. Rewritten (atomically)
+/// whenever either changes, for scripts and containers handing them out.
+fn write_addr_file(path: &Path, swarm: &Swarm<MyBehaviour>) -> std::io::Result<()> {
+    let peer_id = *swarm.local_peer_id();
+    let mut seen = HashSet::new();
+    let mut out = String::new();
+    for addr in swarm.listeners().chain(swarm.external_addresses()) {
+        let addr = addr.clone().with_p2p(peer_id).unwrap_or_else(|addr| addr);
+        if seen.insert(addr.clone()) {
+            out.push_str(&format!("{addr}\n"));
+        }
+    }
+    let tmp = with_suffix(path, ".tmp");
+    std::fs::write(&tmp, out)?;
+    std::fs::rename(&tmp, path)
+}
+
+/// `path` with `suffix` appended to its file name (`identity.key` -> `identity.key.lock`).
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Write a key file atomically (temporary file, then rename), readable only by its owner (mode
+/// 0600 on unix, an owner-only ACL on Windows).
+fn write_key_file(path: &Path, bytes: &[u8]) -> anyhow::Result<()> {
     // Ensure parent directory exists if the path has a parent
     if let Some(parent) = path.parent() {
         std::fs::create_dir_all(parent).with_context(|| format!("failed to create identity parent directory: {}", parent.display()))?;
     }
-    // Create the file atomically and set permissions to 0o600 on unix.
+    let tmp = with_suffix(path, ".tmp");
     let mut opts = OpenOptions::new();
     opts.create(true).write(true).truncate(true);
     #[cfg(unix)] { opts.mode(0o600); }
-    let mut f = opts.open(path).with_context(|| format!("failed to create identity key file: {}", path.display()))?;
-    f.write_all(&bytes).with_context(|| format!("failed to write identity key file: {}", path.display()))?;
-    tracing::info!("Generated new identity key and saved to {}", path.display());
-    Ok(kp)
+    let mut f = opts.open(&tmp).with_context(|| format!("failed to create key file: {}", tmp.display()))?;
+    #[cfg(windows)]
+    simple_p2p_docstore::node::data_dir::restrict_to_owner(&tmp).with_context(|| format!("failed to restrict access to key file: {}", tmp.display()))?;
+    f.write_all(bytes).with_context(|| format!("failed to write key file: {}", tmp.display()))?;
+    f.sync_all().with_context(|| format!("failed to write key file: {}", tmp.display()))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("failed to replace key file: {}", path.display()))?;
+    Ok(())
+}
+
+/// Take the lock that marks the identity at `path` as in use. It is held until the
+/// returned file is dropped, and released by the OS if the process dies.
+fn lock_identity(path: &Path) -> anyhow::Result<std::fs::File> {
+    let lock_path = with_suffix(path, ".lock");
+    if let Some(parent) = lock_path.parent() {
+        std::fs::create_dir_all(parent).with_context(|| format!("failed to create identity parent directory: {}", parent.display()))?;
+    }
+    let file = OpenOptions::new().create(true).truncate(false).write(true).open(&lock_path)
+        .with_context(|| format!("failed to open lock file: {}", lock_path.display()))?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(std::fs::TryLockError::WouldBlock) => {
+            anyhow::bail!("identity key {} is in use by a running server (lock file {})", path.display(), lock_path.display())
+        }
+        Err(std::fs::TryLockError::Error(e)) => {
+            Err(e).with_context(|| format!("failed to lock {}", lock_path.display()))
+        }
+    }
+}
+
+/// `server rotate-key [--announce]`: replace the identity key with a new one, keeping the
+/// old key in a timestamped backup next to it. With `--announce`, the old identity also
+/// puts a signed successor announcement into the DHT, reached through `--announce-via`
+/// (comma-separated multiaddrs) or `BOOTSTRAP_PEERS`.
+async fn run_rotate_key() -> anyhow::Result<()> {
+    let path = get_identity_key_path()?;
+    let _lock = lock_identity(&path)?;
+    let passphrase = get_identity_passphrase()?;
+    let key_type = match arg_value("key-type") {
+        Some(s) => s.parse::<keys::KeyType>().map_err(anyhow::Error::msg)?,
+        None => keys::KeyType::default(),
+    };
+
+    let old_bytes = std::fs::read(&path).with_context(|| format!("failed to read identity key file: {}", path.display()))?;
+    let old = keys::decode_identity(&old_bytes, passphrase.as_deref())
+        .with_context(|| format!("failed to load identity key file: {}", path.display()))?;
+    let new = keys::generate_identity(key_type);
+    let issued_at_ms = unix_ms();
+
+    let backup = with_suffix(&path, &format!(".bak-{}", issued_at_ms / 1000));
+    write_key_file(&backup, &old_bytes)?;
+    let new_bytes = keys::encode_identity(&new, passphrase.as_deref()).context("failed to serialize identity key pair")?;
+    write_key_file(&path, &new_bytes)?;
+
+    println!("Old peer id: {}", old.public().to_peer_id());
+    println!("New peer id: {}", new.public().to_peer_id());
+    println!("Old key backed up to {}", backup.display());
+
+    if has_flag("announce") {
+        announce_successor(&old, &new, issued_at_ms).await?;
+    }
+    Ok(())
+}
+
+/// `server migrate [--dry-run]`: report the format of everything in the data directory
+/// and the steps that bring it up to date, then take them, copying each artifact under
+/// `backups/<unix ms>/` in the data directory first. `--dry-run` stops after the report.
+fn run_migrate() -> anyhow::Result<()> {
+    let data_dir = get_data_dir()?;
+    let identity_key = get_identity_key_path()?;
+    // Not under a running server
+    let _lock = lock_identity(&identity_key)?;
+    let plan = migrations::Plan::scan(&data_dir, &identity_key)
+        .with_context(|| format!("cannot migrate the data in {}", data_dir.root().display()))?;
+    if plan.found.is_empty() {
+        println!("Nothing to migrate in {}", data_dir.root().display());
+        return Ok(());
+    }
+    for found in &plan.found {
+        println!("{found}");
+    }
+    if plan.is_current() {
+        println!("Everything is up to date");
+        return Ok(());
+    }
+    if has_flag("dry-run") {
+        println!("Dry run: nothing was changed");
+        return Ok(());
+    }
+    let backups = plan.default_backup_dir(unix_ms());
+    let upgraded = plan
+        .apply(&backups)
+        .with_context(|| format!("migration failed; the originals are in {}", backups.display()))?;
+    println!("Upgraded {upgraded} artifact(s); the originals are in {}", backups.display());
+    Ok(())
+}
+
+/// `server check` (or `--check` on a normal command line): validate the configuration
+/// the server would start with, without starting it, and print a pass/warn/fail table.
+/// Fails if any check does. Ports are bound and released at once; the identity key, the
+/// certificate and the data directory are only read.
+fn run_check() -> anyhow::Result<()> {
+    let mut report = CheckReport::default();
+    // Loaded before anything else, so a broken one never gets this far
+    if let Some(path) = cli_value("config") {
+        report.pass("--config", path);
+    }
+    report.check("--log", log_settings(), |(level, _)| level.to_string());
+    let Some(data_dir) = report.check("data dir", get_data_dir(), |dir| dir.root().display().to_string()) else {
+        println!("{report}");
+        anyhow::bail!("configuration check failed");
+    };
+    report.check("data dir writable", config_check::check_writable_dir("data dir", data_dir.root()), |_| String::new());
+    let identity_key = data_dir.identity_key(|name| std::env::var(name).ok());
+    match migrations::Plan::scan(&data_dir, &identity_key) {
+        Ok(plan) if plan.is_current() => report.pass("data formats", "current"),
+        Ok(plan) => {
+            let pending: Vec<String> = plan.pending().map(|found| found.to_string()).collect();
+            let detail = pending.join("; ");
+            if has_flag("auto-migrate") {
+                report.warn("data formats", format!("upgraded at startup: {detail}"));
+            } else {
+                report.fail("data formats", format!("run `server migrate` or start with --auto-migrate: {detail}"));
+            }
+        }
+        Err(e) => report.fail("data formats", e.to_string()),
+    }
+
+    // Identity
+    if let Some(seed_hex) = arg_value("identity-seed-hex") {
+        let key = decode_hex(&seed_hex).and_then(|seed| Ok(keys::insecure_identity_from_seed(&seed)?));
+        if report.check("--identity-seed-hex", key, |key| format!("peer id {}", key.public().to_peer_id())).is_some() {
+            report.warn("--identity-seed-hex", "insecure identity for tests and docs; never use it in production");
+        }
+        if arg_value("identity-passphrase-file").is_some() || std::env::var("IDENTITY_KEY_PATH").is_ok() {
+            report.warn("--identity-seed-hex", "the identity key file and its passphrase are ignored");
+        }
+    } else {
+        let passphrase = get_identity_passphrase();
+        if let Some(path) = arg_value("identity-passphrase-file") {
+            report.check("--identity-passphrase-file", passphrase.as_ref().map(drop).map_err(|e| format!("{e:#}")), |_| path.clone());
+        }
+        match config_check::check_identity(&identity_key, passphrase.ok().flatten().as_deref()) {
+            Ok(Some(peer_id)) => report.pass("identity key", format!("{} (peer id {peer_id})", identity_key.display())),
+            Ok(None) => {
+                let writable = identity_key.parent().map_or(Ok(()), |dir| config_check::check_writable_dir("identity key", dir));
+                match writable {
+                    Ok(()) => report.warn("identity key", format!("{} does not exist; a new key will be generated", identity_key.display())),
+                    Err(e) => report.fail("identity key", e.to_string()),
+                }
+            }
+            Err(e) => report.fail("identity key", e.to_string()),
+        }
+    }
+    if let Some(key_type) = arg_value("key-type") {
+        report.check("--key-type", key_type.parse::<keys::KeyType>(), |key_type| key_type.to_string());
+    }
+    let cert_path = data_dir.webrtc_cert(|name| std::env::var(name).ok());
+    match config_check::check_webrtc_cert(&cert_path) {
+        Ok(true) => report.pass("WebRTC certificate", cert_path.display().to_string()),
+        Ok(false) => report.warn("WebRTC certificate", format!("{} does not exist; a new one will be generated", cert_path.display())),
+        Err(e) => report.fail("WebRTC certificate", e.to_string()),
+    }
+    let mailbox_file = data_dir.root().join(mailbox::MAILBOX_FILE);
+    if mailbox_file.exists() {
+        report.check("mailbox", config_check::check_readable("mailbox", &mailbox_file), |_| mailbox_file.display().to_string());
+    }
+
+    // Listeners
+    let udp_port = match std::env::var("SIGNALING_PORT") {
+        Ok(port) => report.check("SIGNALING_PORT", port.parse::<u16>(), |port| port.to_string()),
+        Err(_) => Some(9090),
+    };
+    if let Some(port) = udp_port {
+        let addr: Multiaddr = format!("/ip4/0.0.0.0/udp/{port}/webrtc-direct").parse()?;
+        report.check("WebRTC listener", config_check::check_listen("SIGNALING_PORT", &addr), |_| addr.to_string());
+    }
+    for (flag, host) in [("status-port", "0.0.0.0"), ("admin-tcp-port", "127.0.0.1")] {
+        let Some(port) = arg_value(flag) else { continue };
+        let name = format!("--{flag}");
+        if let Some(port) = report.check(&name, port.parse::<u16>(), |port| port.to_string()) {
+            let addr: Multiaddr = format!("/ip4/{host}/tcp/{port}").parse()?;
+            report.check(format!("{name} listener"), config_check::check_listen(&name, &addr), |_| addr.to_string());
+        }
+    }
+    if let Some(path) = arg_value("admin-socket") {
+        if !cfg!(unix) {
+            report.fail("--admin-socket", "needs Unix domain sockets; use --admin-tcp-port instead");
+        } else if arg_value("admin-tcp-port").is_some() {
+            report.warn("--admin-socket", "ignored: --admin-tcp-port takes precedence");
+        } else {
+            let dir = Path::new(&path).parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+            report.check("--admin-socket", config_check::check_writable_dir("--admin-socket", dir), |_| path.clone());
+        }
+    }
+    for flag in ["addr-file", "events-out"] {
+        let Some(path) = arg_value(flag) else { continue };
+        let dir = Path::new(&path).parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        let name = format!("--{flag}");
+        report.check(&name, config_check::check_writable_dir(&name, dir), |_| path.clone());
+    }
+
+    // Peers and the remaining flags
+    if let Some(peers) = bootstrap_peers() {
+        let parsed = config_check::parse_multiaddrs("BOOTSTRAP_PEERS", &peers);
+        if let Some(parsed) = report.check("BOOTSTRAP_PEERS", parsed, |parsed| format!("{} addresses", parsed.len())) {
+            for addr in parsed.iter().filter(|addr| addrs::peer_id_of(addr).is_none()) {
+                report.warn("BOOTSTRAP_PEERS", format!("{addr} has no /p2p peer id and can only be dialed blind"));
+            }
+        }
+    }
+    let role = arg_value("role").map(|role| role.parse::<NodeRole>());
+    if let Some(role) = role {
+        report.check("--role", role, |role| format!("{role:?}"));
+    }
+    report.check("--proxy", proxy_config(), |proxy| proxy.as_ref().map_or("none".to_string(), ToString::to_string));
+    report.check("--announcers", announcers(), |peers| format!("{} peers", peers.len()));
+    report.check("--expected-peers", expected_peers(), |peers| format!("{} peers", peers.len()));
+    if arg_value("federation").is_some() {
+        report.check("--federation", federation(), |federation| format!("{} relays", federation.len()));
+    }
+    report.check("--partition-threshold-secs", partition_threshold(), |threshold| format!("{threshold:?}"));
+    report.check("connection limits", ip_limits_config(), |_| String::new());
+    report.check("duplicate limits", duplicate_config(), |_| String::new());
+    report.check("DHT store limits", dht_config(), |_| String::new());
+    report.check("replay limits", replay_responder().and_then(|_| request_audit()), |_| String::new());
+    if let Some(list) = arg_value("events") {
+        report.check("--events", event_log::parse_kinds(&list), |kinds| format!("{} kinds", kinds.len()));
+    }
+
+    println!("{report}");
+    anyhow::ensure!(report.failures() == 0, "configuration check failed");
+    Ok(())
+}
+
+/// Refuse to start on data newer than this build reads. Outdated data is upgraded, with
+/// backups, under `--auto-migrate`, and refused otherwise.
+fn check_data_formats() -> anyhow::Result<()> {
+    let data_dir = get_data_dir()?;
+    let plan = migrations::Plan::scan(&data_dir, &get_identity_key_path()?)
+        .with_context(|| format!("refusing to open the data in {}", data_dir.root().display()))?;
+    if plan.is_current() {
+        return Ok(());
+    }
+    if !has_flag("auto-migrate") {
+        let pending: Vec<String> = plan.pending().map(|found| found.to_string()).collect();
+        anyhow::bail!(
+            "the data in {} is in an older format:\n{}\nRun `server migrate` first, or start with --auto-migrate",
+            data_dir.root().display(),
+            pending.join("\n")
+        );
+    }
+    let backups = plan.default_backup_dir(unix_ms());
+    let upgraded = plan
+        .apply(&backups)
+        .with_context(|| format!("migration failed; the originals are in {}", backups.display()))?;
+    status!("Upgraded {} artifact(s) to the current format; the originals are in {}", upgraded, backups.display());
+    Ok(())
+}
+
+/// Put a successor announcement for `old` -> `new` into the DHT, as `old`. The record is
+/// put once and lives for the record TTL of the peers storing it.
+async fn announce_successor(old: &identity::Keypair, new: &identity::Keypair, issued_at_ms: u64) -> anyhow::Result<()> {
+    const ANNOUNCE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+    let peers = arg_value("announce-via")
+        .or_else(bootstrap_peers)
+        .context("--announce needs --announce-via or BOOTSTRAP_PEERS to reach the DHT")?;
+    let mut builder = NodeBuilder::new(NodeRole::Client);
+    for p in peers.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+        builder = builder.add_bootstrap(p.parse().with_context(|| format!("invalid multiaddr {}", p))?);
+    }
+    if let Some(proxy) = proxy_config()? {
+        builder = builder.with_proxy(proxy);
+    }
+    let mut node = builder.spawn(old.clone())?;
+
+    // Kademlia learns a peer's addresses once identify ran; until then there is no one to
+    // store the record with
+    tokio::time::timeout(ANNOUNCE_TIMEOUT, async {
+        while let Some(event) = node.next_event().await {
+            if matches!(event, NodeEvent::PeerIdentified { .. }) {
+                break;
+            }
+        }
+    })
+    .await
+    .context("no DHT peer reachable for the successor announcement")?;
+
+    let announcement = SuccessorAnnouncement::sign(old, new, issued_at_ms)?;
+    let key = successor_key(&old.public().to_peer_id());
+    tokio::time::timeout(ANNOUNCE_TIMEOUT, node.put_record(key.to_vec(), announcement.encode(), None))
+        .await
+        .context("timed out putting the successor announcement")??;
+    println!("Successor announcement published under the old peer id");
+    Ok(())
+}
+
+/// Positional arguments after `server admin`, with `--flag value` pairs skipped.
+fn admin_args() -> Vec<String> {
+    let mut out = Vec::new();
+    let mut args = std::env::args().skip(2);
+    while let Some(arg) = args.next() {
+        if arg.starts_with("--") {
+            if !arg.contains('=') {
+                args.next();
+            }
+            continue;
+        }
+        out.push(arg);
+    }
+    out
+}
+
+/// `server admin <method> [args]`: send one command to a running server's admin socket.
+async fn run_admin_client() -> anyhow::Result<()> {
+    let args = admin_args();
+    let usage = || anyhow::anyhow!(
+        "usage: server admin (--admin-socket PATH | --admin-tcp-port PORT) <{}> [args]",
+        admin::METHODS.join("|")
+    );
+    let method = args.first().ok_or_else(usage)?.as_str();
+    let params = match method {
+        "publish" => json!({ "data": args.get(1).ok_or_else(usage)? }),
+        // server admin announce <text> [--type T] [--severity S] [--expires-secs N]
+        "announce" => {
+            let expires_secs = arg_value("expires-secs").map(|s| s.parse::<u64>()).transpose().context("invalid --expires-secs")?;
+            json!({
+                "text": args.get(1).ok_or_else(usage)?,
+                "type": arg_value("type"),
+                "severity": arg_value("severity"),
+                "expires_secs": expires_secs,
+            })
+        }
+        // server admin set-quota <room> [--max-bytes N] [--max-docs N] [--max-age-secs N]
+        "set-quota" => {
+            let limit = |name: &str| {
+                arg_value(name).map(|s| s.parse::<u64>()).transpose().with_context(|| format!("invalid --{name}"))
+            };
+            json!({
+                "room": args.get(1).ok_or_else(usage)?,
+                "max_bytes": limit("max-bytes")?,
+                "max_docs": limit("max-docs")?,
+                "max_age_secs": limit("max-age-secs")?,
+            })
+        }
+        // server admin set-author-quota [<peer_id>] [--max-bytes N] [--max-docs N] [--max-updates-per-minute N]
+        "set-author-quota" => {
+            let limit = |name: &str| {
+                arg_value(name).map(|s| s.parse::<u64>()).transpose().with_context(|| format!("invalid --{name}"))
+            };
+            json!({
+                "peer_id": args.get(1),
+                "max_bytes": limit("max-bytes")?,
+                "max_docs": limit("max-docs")?,
+                "max_updates_per_minute": limit("max-updates-per-minute")?,
+            })
+        }
+        "expect-peer" | "remove-expected-peer" => json!({ "peer_id": args.get(1).ok_or_else(usage)? }),
+        "block" => match args.get(2) {
+            Some(secs) => json!({ "peer_id": args.get(1).ok_or_else(usage)?, "secs": secs.parse::<u64>().context("invalid secs")? }),
+            None => json!({ "peer_id": args.get(1).ok_or_else(usage)? }),
+        },
+        _ => Value::Null,
+    };
+
+    let result = match arg_value("admin-tcp-port") {
+        Some(port) => admin::call_tcp(port.parse().context("invalid --admin-tcp-port")?, method, params).await?,
+        None => {
+            let path = arg_value("admin-socket").ok_or_else(usage)?;
+            call_admin_socket(Path::new(&path), method, params).await?
+        }
+    };
+    println!("{}", serde_json::to_string_pretty(&result)?);
+    Ok(())
+}
+
+/// Start the admin listener requested on the command line, if any.
+async fn start_admin(calls: mpsc::UnboundedSender<AdminCall>) -> anyhow::Result<()> {
+    if let Some(port) = arg_value("admin-tcp-port") {
+        let port: u16 = port.parse().context("invalid --admin-tcp-port")?;
+        let listener = admin::bind_tcp(port).await.context("failed to bind admin TCP port")?;
+        tracing::warn!("Admin listener on 127.0.0.1:{} is open to every local user", port);
+        tokio::spawn(admin::serve_tcp(listener, calls));
+        return Ok(());
+    }
+    if let Some(path) = arg_value("admin-socket") {
+        serve_admin_socket(Path::new(&path), calls)?;
+        println!("Admin socket: {}", path);
+    }
+    Ok(())
+}
+
+/// Start the readiness/liveness endpoint requested with `--status-port`, if any.
+async fn start_status(health: &Health) -> anyhow::Result<()> {
+    if let Some(port) = arg_value("status-port") {
+        let port: u16 = port.parse().context("invalid --status-port")?;
+        let listener = health::bind_http(port).await.context("failed to bind status port")?;
+        tokio::spawn(health::serve_http(listener, health.clone()));
+        status!("Status endpoint: http://0.0.0.0:{}/healthz and /livez", port);
+    }
+    Ok(())
+}
+
+/// Report readiness changes (and keep the watchdog fed) over `sd_notify` when `--notify` is set.
+fn notify_readiness(health: &Health, last: &mut Option<ReadinessState>) {
+    let state = health.state();
+    let mut message = String::new();
+    if last.as_ref() != Some(&state) {
+        if state.is_ready() && !matches!(last, Some(ReadinessState::NotReady { .. })) {
+            message.push_str("READY=1\n");
+        }
+        message.push_str(&format!("STATUS={}\n", state));
+        status!("Readiness: {}", state);
+        *last = Some(state);
+    }
+    if health.is_live() {
+        message.push_str("WATCHDOG=1\n");
+    }
+    if let Err(e) = health::sd_notify(&message) {
+        tracing::warn!("sd_notify failed: {}", e);
+    }
+}
+
+#[cfg(unix)]
+fn serve_admin_socket(path: &Path, calls: mpsc::UnboundedSender<AdminCall>) -> anyhow::Result<()> {
+    let listener = admin::bind_unix(path).with_context(|| format!("failed to bind admin socket {}", path.display()))?;
+    tokio::spawn(admin::serve_unix(listener, calls));
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn serve_admin_socket(_path: &Path, _calls: mpsc::UnboundedSender<AdminCall>) -> anyhow::Result<()> {
+    anyhow::bail!("--admin-socket needs Unix domain sockets; use --admin-tcp-port instead")
+}
+
+#[cfg(unix)]
+async fn call_admin_socket(path: &Path, method: &str, params: Value) -> anyhow::Result<Value> {
+    admin::call_unix(path, method, params).await
+}
+
+#[cfg(not(unix))]
+async fn call_admin_socket(_path: &Path, _method: &str, _params: Value) -> anyhow::Result<Value> {
+    anyhow::bail!("--admin-socket needs Unix domain sockets; use --admin-tcp-port instead")
+}
+
+/// Event mirror requested with `--events-ndjson` (stdout) or `--events-out PATH`,
+/// limited to the kinds listed in `--events` (default: all).
+fn start_event_mirror() -> anyhow::Result<Option<EventMirror>> {
+    let kinds = match arg_value("events") {
+        Some(list) => event_log::parse_kinds(&list).map_err(anyhow::Error::msg)?,
+        None => EventKind::ALL.into_iter().collect(),
+    };
+    let capacity = event_log::DEFAULT_QUEUE_CAPACITY;
+    if let Some(path) = arg_value("events-out") {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("failed to open --events-out file: {}", path))?;
+        return Ok(Some(EventMirror::spawn(file, kinds, capacity)));
+    }
+    if has_flag("events-ndjson") {
+        return Ok(Some(EventMirror::spawn(std::io::stdout(), kinds, capacity)));
+    }
+    Ok(None)
+}
+
+/// Mirror record for a finished Kademlia query step; `None` for query types not mirrored.
+fn kad_query_event(id: libp2p_kad::QueryId, result: &QueryResult) -> Option<MirrorEvent> {
+    let (kind, ok, peers) = match result {
+        QueryResult::Bootstrap(r) => ("bootstrap", r.is_ok(), r.iter().map(|b| b.peer.to_string()).collect()),
+        QueryResult::GetClosestPeers(r) => (
+            "get_closest_peers",
+            r.is_ok(),
+            r.iter().flat_map(|c| c.peers.iter().map(|p| p.peer_id.to_string())).collect(),
+        ),
+        QueryResult::GetProviders(r) => ("get_providers", r.is_ok(), Vec::new()),
+        QueryResult::StartProviding(r) => ("start_providing", r.is_ok(), Vec::new()),
+        _ => return None,
+    };
+    Some(MirrorEvent::KademliaQuery { query_id: format!("{id:?}"), kind: kind.into(), ok, peers })
+}
+
+fn unix_ms() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+/// Advertise UPnP-mapped addresses, and withdraw them when they expire or AutoNAT finds
+/// them unreachable.
+#[cfg(not(target_arch = "wasm32"))]
+fn handle_nat_event(swarm: &mut Swarm<MyBehaviour>, mappings: &mut PortMappings, event: NatBehaviourEvent) {
+    let actions = match event {
+        NatBehaviourEvent::Upnp(libp2p::upnp::Event::NewExternalAddr(addr)) => {
+            status!("✓ UPnP mapped external address {}", addr);
+            mappings.on_mapped(addr)
+        }
+        NatBehaviourEvent::Upnp(libp2p::upnp::Event::ExpiredExternalAddr(addr)) => {
+            status!("⚠ UPnP mapping for {} expired", addr);
+            mappings.on_expired(&addr)
+        }
+        NatBehaviourEvent::Upnp(libp2p::upnp::Event::GatewayNotFound) => {
+            status!("⚠ UPnP: no gateway found, inbound connections need manual port forwarding");
+            Vec::new()
+        }
+        NatBehaviourEvent::Upnp(libp2p::upnp::Event::NonRoutableGateway) => {
+            status!("⚠ UPnP: gateway is itself behind a NAT, mapped ports would be unreachable");
+            Vec::new()
+        }
+        NatBehaviourEvent::Autonat(libp2p::autonat::Event::StatusChanged { new, .. }) => {
+            status!("AutoNAT status: {:?}", new);
+            mappings.on_nat_status(&new)
+        }
+        NatBehaviourEvent::Autonat(_) => Vec::new(),
+    };
+    for action in actions {
+        match action {
+            NatAction::Advertise(addr) => swarm.add_external_address(addr),
+            NatAction::Withdraw(addr) => {
+                status!("Withdrawing external address {}", addr);
+                swarm.remove_external_address(&addr);
+            }
+        }
+    }
+}
+
+/// Replay responder sized by `--replay-log-size` (messages kept per topic).
+fn replay_responder() -> anyhow::Result<ReplayResponder> {
+    let capacity = match arg_value("replay-log-size") {
+        Some(n) => n.parse().context("invalid --replay-log-size")?,
+        None => replay::DEFAULT_LOG_CAPACITY,
+    };
+    // Requests are rate limited by the request audit, which answers through `page`
+    Ok(ReplayResponder::new(MessageLog::new(capacity), ReplayRateLimiter::default()))
+}
+
+/// Audit of the requests we answer, limiting each peer to `--replay-rate-limit` replay
+/// requests per minute.
+fn request_audit() -> anyhow::Result<RequestAudit> {
+    Ok(RequestAudit::default()
+        .with_limit(replay::REPLAY_PROTOCOL, replay_rate_limit()?, replay::DEFAULT_RATE_WINDOW)
+        .with_limit(mailbox::MAILBOX_PROTOCOL, mailbox::DEFAULT_REQUESTS_PER_WINDOW, mailbox::DEFAULT_RATE_WINDOW))
+}
+
+/// Replay requests each peer may make per minute, from `--replay-rate-limit`.
+fn replay_rate_limit() -> anyhow::Result<u32> {
+    match arg_value("replay-rate-limit") {
+        Some(n) => n.parse().context("invalid --replay-rate-limit"),
+        None => Ok(replay::DEFAULT_REQUESTS_PER_WINDOW),
+    }
+}
+
+/// Per-IP inbound limits from `--max-conns-per-ip`, `--max-conn-attempts-per-ip` within
+/// `--conn-attempt-window-secs`, `--ipv6-full-address` (limit single IPv6 addresses
+/// instead of /64s) and `--allow-peers` (comma-separated peer ids exempt from the limits).
+fn ip_limits_config() -> anyhow::Result<IpLimitsConfig> {
+    let mut config = IpLimitsConfig::default();
+    if let Some(n) = arg_value("max-conns-per-ip") {
+        config.max_connections_per_ip = n.parse().context("invalid --max-conns-per-ip")?;
+    }
+    if let Some(n) = arg_value("max-conn-attempts-per-ip") {
+        config.max_attempts_per_ip = n.parse().context("invalid --max-conn-attempts-per-ip")?;
+    }
+    if let Some(secs) = arg_value("conn-attempt-window-secs") {
+        let secs: u64 = secs.parse().context("invalid --conn-attempt-window-secs")?;
+        config.attempt_window = std::time::Duration::from_secs(secs);
+    }
+    config.ipv6_prefix_64 = !has_flag("ipv6-full-address");
+    if let Some(list) = arg_value("allow-peers") {
+        for peer in list.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            config.allowlist.insert(peer.parse().with_context(|| format!("invalid peer id in --allow-peers: {peer}"))?);
+        }
+    }
+    Ok(config)
+}
+
+/// Duplicate flood detection: a source may send `--max-duplicates-per-min` duplicates a
+/// minute (default 30), and is banned for `--duplicate-graylist-secs` (default 900) once it
+/// exceeded that `--duplicate-graylist-after` minutes in a row (default 3).
+fn duplicate_config() -> anyhow::Result<DuplicateConfig> {
+    let mut config = DuplicateConfig::default();
+    if let Some(n) = arg_value("max-duplicates-per-min") {
+        config.max_duplicates = n.parse().context("invalid --max-duplicates-per-min")?;
+    }
+    if let Some(n) = arg_value("duplicate-graylist-after") {
+        config.graylist_after = n.parse().context("invalid --duplicate-graylist-after")?;
+    }
+    if let Some(secs) = arg_value("duplicate-graylist-secs") {
+        let secs: u64 = secs.parse().context("invalid --duplicate-graylist-secs")?;
+        config.graylist_for = std::time::Duration::from_secs(secs);
+    }
+    Ok(config)
+}
+
+/// Peers whose announcements are accepted and forwarded, from `--announcers`
+/// (comma-separated peer ids).
+fn announcers() -> anyhow::Result<Vec<PeerId>> {
+    let Some(list) = arg_value("announcers") else { return Ok(Vec::new()) };
+    list.split(',')
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .map(|p| p.parse().with_context(|| format!("invalid peer id in --announcers: {p}")))
+        .collect()
+}
+
+/// Kademlia record store limits from `--dht-max-records`, `--dht-max-record-bytes`,
+/// `--dht-max-providers-per-key` and `--dht-max-provider-keys`; libp2p's defaults otherwise.
+/// Inbound records are stored by the event loop, which counts the ones the store refuses.
+fn dht_config() -> anyhow::Result<PeerDhtConfig> {
+    let mut config = PeerDhtConfig { filter_inbound_records: true, ..Default::default() };
+    if let Some(n) = arg_value("dht-max-records") {
+        config.store.max_records = n.parse().context("invalid --dht-max-records")?;
+    }
+    if let Some(n) = arg_value("dht-max-record-bytes") {
+        config.store.max_value_bytes = n.parse().context("invalid --dht-max-record-bytes")?;
+    }
+    if let Some(n) = arg_value("dht-max-providers-per-key") {
+        config.store.max_providers_per_key = n.parse().context("invalid --dht-max-providers-per-key")?;
+    }
+    if let Some(n) = arg_value("dht-max-provider-keys") {
+        config.store.max_provided_keys = n.parse().context("invalid --dht-max-provider-keys")?;
+    }
+    Ok(config)
+}
+
+/// Warn about and mirror a put the DHT record store refused, once per limit until the
+/// store accepts something again.
+fn report_store_full(mirror: &Option<EventMirror>, full: StoreFull) {
+    if full.newly {
+        tracing::warn!("{}; further refusals are only counted", full);
+        if let Some(mirror) = mirror {
+            mirror.emit(MirrorEvent::DhtStoreFull { kind: full.kind.to_string() });
+        }
+    }
+}
+
+/// Publish the DHT record store's contents and refusals at `/metrics`.
+fn dht_store_metrics(health: &Health, dht_store: &mut DhtStoreMonitor, swarm: &mut Swarm<MyBehaviour>) {
+    for (name, value) in dht_store.metrics(swarm.behaviour_mut().kademlia.store_mut()) {
+        health.set_metric(name, value);
+    }
+}
+
+/// Publish the duplicate counters at `/metrics`.
+fn duplicate_metrics(health: &Health, duplicates: &DuplicateDetector) {
+    health.set_metric("docstore_messages_total", duplicates.messages());
+    health.set_metric("docstore_duplicates_total", duplicates.duplicates());
+    health.set_metric("docstore_duplicate_floods_total", duplicates.floods());
+    health.set_metric("docstore_duplicate_graylisted_total", duplicates.graylisted());
+}
+
+/// Publish the bytes forwarded to each client at `/metrics`.
+fn interest_metrics(health: &Health, interest: &TopicInterest) {
+    for (name, value) in interest.metrics() {
+        health.set_metric(name, value);
+    }
+}
+
+/// Leave room topics nobody connected wants anymore, which prunes their meshes now rather
+/// than when the last unsubscribe arrives. They are joined again on the next subscribe.
+fn leave_unwanted(swarm: &mut Swarm<MyBehaviour>, config: &simple_p2p_docstore::behaviour::DocstoreGossipsubConfig, unwanted: Vec<gossipsub::TopicHash>) {
+    for topic in unwanted {
+        let Some(channel) = config.topics.room_channel(&topic) else {
+            continue;
+        };
+        let behaviour = swarm.behaviour_mut();
+        let gossipsub = if channel.is_ephemeral() { &mut behaviour.ephemeral } else { &mut behaviour.gossipsub };
+        if gossipsub.unsubscribe(&gossipsub::IdentTopic::new(topic.as_str())) {
+            tracing::debug!("Left room topic {}; nobody wants it", topic);
+        }
+    }
+}
+
+/// Count an accepted room message against every mesh peer gossipsub forwards it to.
+fn record_forwards(interest: &mut TopicInterest, gossipsub: &gossipsub::Behaviour, message: &gossipsub::Message, from: &PeerId) {
+    for peer in gossipsub.mesh_peers(&message.topic) {
+        if peer != from && Some(*peer) != message.source {
+            interest.record_forward(*peer, &message.topic, message.data.len());
+        }
+    }
+}
+
+/// Report a partition that started or healed, publish the partition metrics, and redial
+/// the missing peers and re-bootstrap while the partition lasts.
+fn check_partition(partition: &mut PartitionWatch, swarm: &mut Swarm<MyBehaviour>, health: &Health, mirror: &Option<EventMirror>) {
+    let now = unix_ms();
+    let clients = swarm.connected_peers().filter(|peer| !partition.is_expected(peer)).count();
+    match partition.check(now, clients) {
+        Some(PartitionChange::Detected { missing_peers, since_ms }) => {
+            tracing::warn!(
+                "Possible network partition: {} expected peer(s) unreachable since {} while {} client(s) are connected: {:?}",
+                missing_peers.len(),
+                since_ms,
+                clients,
+                missing_peers
+            );
+            if let Some(mirror) = mirror {
+                mirror.emit(MirrorEvent::PossiblePartition {
+                    missing_peers: missing_peers.iter().map(PeerId::to_string).collect(),
+                    since_ms,
+                });
+            }
+        }
+        Some(PartitionChange::Resolved { peers, since_ms, duration }) => {
+            tracing::warn!("Network partition resolved after {:?}", duration);
+            if let Some(mirror) = mirror {
+                mirror.emit(MirrorEvent::PartitionResolved {
+                    peers: peers.iter().map(PeerId::to_string).collect(),
+                    since_ms,
+                    duration_ms: duration.as_millis() as u64,
+                });
+            }
+        }
+        None => {}
+    }
+    for (name, value) in partition.status().metrics() {
+        health.set_metric(name, value);
+    }
+    let redial = partition.take_redial(now);
+    if redial.is_empty() {
+        return;
+    }
+    for peer_id in redial {
+        // Addresses come from Kademlia, where the bootstrap addresses were added
+        let opts = libp2p::swarm::dial_opts::DialOpts::peer_id(peer_id)
+            .condition(libp2p::swarm::dial_opts::PeerCondition::DisconnectedAndNotDialing)
+            .build();
+        if let Err(e) = swarm.dial(opts) {
+            tracing::debug!("Redialing missing peer {} failed: {}", peer_id, e);
+        }
+    }
+    if let Err(e) = swarm.behaviour_mut().kademlia.bootstrap() {
+        tracing::debug!("Kademlia bootstrap could not start: {}", e);
+    }
+}
+
+/// Add a bootstrap address: to Kademlia and the expected peers if it names its peer, or
+/// dialed blind otherwise, which learns the peer's addresses through identify.
+fn add_bootstrap_addr(swarm: &mut Swarm<MyBehaviour>, partition: &mut PartitionWatch, addr: Multiaddr) {
+    if let Some(peer_id) = addrs::peer_id_of(&addr) {
+        swarm.behaviour_mut().kademlia.add_address(&peer_id, addr.clone());
+        partition.expect(peer_id, unix_ms());
+        status!("Added bootstrap address for {}: {}", peer_id, addr);
+    } else if let Err(e) = swarm.dial(addr.clone()) {
+        status!("Failed to dial bootstrap addr {}: {}", addr, e);
+    } else {
+        status!("Dialed bootstrap address: {}", addr);
+    }
+}
+
+/// Relays and FullNodes expected to stay reachable besides the bootstrap peers, from
+/// `--expected-peers` (comma-separated peer ids).
+fn expected_peers() -> anyhow::Result<Vec<PeerId>> {
+    let Some(list) = arg_value("expected-peers") else { return Ok(Vec::new()) };
+    list.split(',')
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .map(|p| p.parse().with_context(|| format!("invalid peer id in --expected-peers: {p}")))
+        .collect()
+}
+
+/// The other relays of our federation, from `--federation` (comma-separated multiaddrs
+/// ending in `/p2p/<peer id>`; the same list can be given to every member).
+fn federation() -> anyhow::Result<Federation> {
+    Federation::parse(&arg_value("federation").unwrap_or_default()).map_err(anyhow::Error::msg).context("invalid --federation")
+}
+
+/// Redial the federation links that are due, see `ImportantPeers`.
+fn redial_federation(important: &mut ImportantPeers, swarm: &mut Swarm<MyBehaviour>) {
+    let now = Instant::now();
+    for peer_id in important.take_due(now) {
+        let opts = libp2p::swarm::dial_opts::DialOpts::peer_id(peer_id)
+            .addresses(important.addrs(&peer_id).to_vec())
+            .condition(libp2p::swarm::dial_opts::PeerCondition::DisconnectedAndNotDialing)
+            .build();
+        if let Err(e) = swarm.dial(opts) {
+            tracing::debug!("Redialing federation peer {} failed: {}", peer_id, e);
+            important.dial_failed(&peer_id, now);
+        }
+    }
+}
+
+/// Publish the federation links and the client connections apart from them at `/metrics`.
+fn federation_metrics(health: &Health, federation: &Federation, swarm: &Swarm<MyBehaviour>) {
+    let clients = swarm.connected_peers().filter(|peer| !federation.is_member(peer)).count();
+    for (name, value) in federation.status(clients).metrics() {
+        health.set_metric(name, value);
+    }
+}
+
+/// How long an expected peer may be unreachable before a partition is suspected, from
+/// `--partition-threshold-secs`.
+fn partition_threshold() -> anyhow::Result<std::time::Duration> {
+    match arg_value("partition-threshold-secs") {
+        Some(secs) => Ok(std::time::Duration::from_secs(secs.parse().context("invalid --partition-threshold-secs")?)),
+        None => Ok(DEFAULT_PARTITION_THRESHOLD),
+    }
+}
+
+/// Publish the request counters per protocol at `/metrics`.
+fn request_metrics(health: &Health, audit: &RequestAudit) {
+    for (name, value) in audit.metrics() {
+        health.set_metric(name, value);
+    }
+}
+
+/// Publish connections established so far, per transport, at `/metrics`.
+fn connection_metrics(health: &Health, traffic: &TrafficStats) {
+    for (transport, count) in traffic.snapshot().connections_by_transport {
+        health.set_metric(format!("docstore_connections_total{{transport=\"{transport}\"}}"), count);
+    }
+}
+
+/// Resolves on Ctrl-C, or on SIGTERM where there is one (`docker stop`, systemd).
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = term.recv() => {}
+                }
+                return;
+            }
+            Err(e) => tracing::warn!("Cannot watch for SIGTERM: {}", e),
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Announce the relay service in the DHT, so browsers can find us with `discover_relays()`.
+fn provide_relay(swarm: &mut Swarm<MyBehaviour>, dht_store: &mut DhtStoreMonitor, mirror: &Option<EventMirror>) {
+    let key = relay_provider_key();
+    let provided = swarm.behaviour_mut().kademlia.start_providing(key.clone());
+    if let Err(full) = dht_store.provide(&key, provided) {
+        status!("Failed to announce relay service: {}", full);
+        report_store_full(mirror, full);
+    }
+}
+
+/// Resolves on every SIGHUP once the server watches for it; never where there is none.
+struct ReloadSignal {
+    #[cfg(unix)]
+    hangup: Option<tokio::signal::unix::Signal>,
+}
+
+impl ReloadSignal {
+    /// Only a server with a `--config` file to reload takes SIGHUP over; others keep its
+    /// default of terminating.
+    fn new() -> Self {
+        #[cfg(unix)]
+        let hangup = cli_value("config").and_then(|_| {
+            match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+                Ok(hangup) => Some(hangup),
+                Err(e) => {
+                    tracing::warn!("Cannot watch for SIGHUP: {}", e);
+                    None
+                }
+            }
+        });
+        Self {
+            #[cfg(unix)]
+            hangup,
+        }
+    }
+
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(hangup) = &mut self.hangup {
+            if hangup.recv().await.is_some() {
+                return;
+            }
+        }
+        std::future::pending().await
+    }
+}
+
+/// The parts of the server a reload changes.
+struct Reloadable<'a> {
+    swarm: &'a mut Swarm<MyBehaviour>,
+    log: &'a LogControl,
+    duplicates: &'a mut DuplicateDetector,
+    audit: &'a mut RequestAudit,
+    docstore_config: &'a mut DocstoreGossipsubConfig,
+    partition: &'a mut PartitionWatch,
+}
+
+/// Read the `--config` file again and apply what can change while running, see
+/// [`ServerConfig::reload`]. The outcome is logged as well as returned.
+fn reload_config(mut live: Reloadable<'_>) -> Result<ReloadOutcome, String> {
+    let path = cli_value("config").ok_or("started without --config, so there is nothing to reload")?;
+    let new = ServerConfig::load(Path::new(&path)).map_err(|e| {
+        status!("✗ Reload of {} failed: {}", path, e);
+        e.to_string()
+    })?;
+    let mut running = file_config();
+    // What the peer lists were, to tell what to drop
+    let old_bootstrap = bootstrap_addrs();
+    let old_expected = expected_peers().unwrap_or_default();
+    let overridden =
+        |key: &str| on_command_line(key) || (key == "bootstrap-peers" && std::env::var_os("BOOTSTRAP_PEERS").is_some());
+    let outcome = running.reload(&new, overridden, |setting, candidate| {
+        // The flag helpers read the candidate; whatever is kept is set below
+        set_file_config(candidate.clone());
+        apply_setting(&mut live, setting, &old_bootstrap, &old_expected).map_err(|e| format!("{e:#}"))
+    });
+    set_file_config(running);
+    status!("Reloaded {}: {}", path, outcome);
+    Ok(outcome)
+}
+
+/// Put `setting` into effect as the flags now say.
+fn apply_setting(live: &mut Reloadable<'_>, setting: HotSetting, old_bootstrap: &[Multiaddr], old_expected: &[PeerId]) -> anyhow::Result<()> {
+    match setting {
+        HotSetting::Log => live.log.apply()?,
+        HotSetting::ConnectionLimits => live.swarm.behaviour_mut().ip_limits.set_config(ip_limits_config()?),
+        HotSetting::Duplicates => live.duplicates.set_config(duplicate_config()?),
+        HotSetting::ReplayRate => live.audit.set_limit(replay::REPLAY_PROTOCOL, replay_rate_limit()?, replay::DEFAULT_RATE_WINDOW),
+        HotSetting::Announcers => live.docstore_config.announcers = announcers()?.into_iter().collect(),
+        HotSetting::PartitionThreshold => live.partition.set_threshold(partition_threshold()?),
+        HotSetting::ExpectedPeers => {
+            let expected = expected_peers()?;
+            let bootstrap: Vec<PeerId> = bootstrap_addrs().iter().filter_map(addrs::peer_id_of).collect();
+            for peer_id in old_expected.iter().filter(|peer| !expected.contains(peer) && !bootstrap.contains(peer)) {
+                live.partition.remove(peer_id);
+            }
+            for peer_id in expected {
+                live.partition.expect(peer_id, unix_ms());
+                if live.swarm.is_connected(&peer_id) {
+                    live.partition.connected(&peer_id);
+                }
+            }
+        }
+        HotSetting::Bootstrap => {
+            let peers = bootstrap_peers().unwrap_or_default();
+            let new_addrs = config_check::parse_multiaddrs("--bootstrap-peers", &peers)?;
+            let expected = expected_peers()?;
+            for addr in old_bootstrap.iter().filter(|addr| !new_addrs.contains(addr)) {
+                let Some(peer_id) = addrs::peer_id_of(addr) else { continue };
+                live.swarm.behaviour_mut().kademlia.remove_address(&peer_id, addr);
+                if !expected.contains(&peer_id) {
+                    live.partition.remove(&peer_id);
+                }
+            }
+            for addr in new_addrs.iter().filter(|addr| !old_bootstrap.contains(addr)) {
+                add_bootstrap_addr(live.swarm, live.partition, addr.clone());
+            }
+            if !new_addrs.is_empty() {
+                if let Err(e) = live.swarm.behaviour_mut().kademlia.bootstrap() {
+                    status!("Failed to bootstrap Kademlia: {}", e);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Execute an admin command against the running swarm.
+#[allow(clippy::too_many_arguments)]
+fn handle_admin(
+    swarm: &mut Swarm<MyBehaviour>,
+    bans: &mut BanList,
+    partition: &mut PartitionWatch,
+    reservations: &HashSet<PeerId>,
+    message_log: &mut MessageLog,
+    duplicates: &mut DuplicateDetector,
+    audit: &mut RequestAudit,
+    dht_store: &mut DhtStoreMonitor,
+    identity: &identity::Keypair,
+    docstore_config: &mut DocstoreGossipsubConfig,
+    log: &LogControl,
+    command: AdminCommand,
+) -> Result<Value, String> {
+    match command {
+        AdminCommand::Peers => Ok(json!(swarm.connected_peers().map(|p| p.to_string()).collect::<Vec<_>>())),
+        AdminCommand::Reservations => Ok(json!(reservations.iter().map(|p| p.to_string()).collect::<Vec<_>>())),
+        AdminCommand::Publish { data } => {
+            let data = data.into_bytes();
+            let id = simple_p2p_docstore::behaviour::publish_update_with(
+                &mut swarm.behaviour_mut().gossipsub,
+                docstore_config,
+                data.clone(),
+            )
+            .map_err(|e| e.to_string())?;
+            message_log.append(&docstore_config.topics.updates().to_string(), data, unix_ms());
+            Ok(json!({ "message_id": id.to_string() }))
+        }
+        AdminCommand::Announce { kind, severity, text, expires_in } => {
+            let now = unix_ms();
+            let expires_at_ms = expires_in.map(|d| now + d.as_millis() as u64);
+            let announcement =
+                NetworkAnnouncement::sign(identity, kind, severity, text, now, expires_at_ms).map_err(|e| e.to_string())?;
+            let id = simple_p2p_docstore::behaviour::publish_announcement(
+                &mut swarm.behaviour_mut().gossipsub,
+                docstore_config,
+                &announcement,
+            )
+            .map_err(|e| e.to_string())?;
+            Ok(json!({ "message_id": id.to_string() }))
+        }
+        AdminCommand::Bootstrap => swarm
+            .behaviour_mut()
+            .kademlia
+            .bootstrap()
+            .map(|id| json!({ "query_id": format!("{id:?}") }))
+            .map_err(|e| e.to_string()),
+        AdminCommand::Block { peer_id, duration } => {
+            bans.ban(peer_id, duration, Instant::now());
+            swarm.behaviour_mut().gossipsub.blacklist_peer(&peer_id);
+            let _ = swarm.disconnect_peer_id(peer_id);
+            Ok(json!({ "blocked": peer_id.to_string(), "secs": duration.as_secs() }))
+        }
+        AdminCommand::Limits => {
+            let stats = swarm.behaviour().ip_limits.stats();
+            Ok(json!({ "denied_connections": stats.denied_connections, "denied_attempts": stats.denied_attempts }))
+        }
+        AdminCommand::Duplicates => {
+            let sources: Vec<Value> = duplicates
+                .top_sources(20)
+                .iter()
+                .map(|s| json!({ "peer_id": s.peer_id.to_string(), "duplicates": s.duplicates, "floods": s.floods }))
+                .collect();
+            Ok(json!({
+                "messages": duplicates.messages(),
+                "duplicates": duplicates.duplicates(),
+                "floods": duplicates.floods(),
+                "graylisted": duplicates.graylisted(),
+                "sources": sources,
+            }))
+        }
+        AdminCommand::Requests => {
+            let snapshot = audit.snapshot();
+            let protocols: serde_json::Map<String, Value> = snapshot
+                .protocols
+                .iter()
+                .map(|(protocol, c)| {
+                    let counters = json!({
+                        "requests": c.requests,
+                        "bytes_served": c.bytes_served,
+                        "errors": c.errors,
+                        "rate_limited": c.rate_limited,
+                    });
+                    (protocol.to_string(), counters)
+                })
+                .collect();
+            let recent: Vec<Value> = snapshot
+                .recent
+                .iter()
+                .map(|e| {
+                    json!({
+                        "peer_id": e.peer_id.to_string(),
+                        "protocol": e.protocol,
+                        "doc_id": e.doc_id,
+                        "duration_us": e.duration.as_micros() as u64,
+                        "outcome": e.outcome.as_str(),
+                        "at_ms": e.at_ms,
+                    })
+                })
+                .collect();
+            Ok(json!({ "protocols": protocols, "recent": recent }))
+        }
+        // Documents are only relayed here, never stored
+        AdminCommand::Verify => Err("this server keeps no document store to verify".to_string()),
+        AdminCommand::Partition => Ok(admin::partition_result(&partition.status())),
+        AdminCommand::ExpectPeer { peer_id } => {
+            partition.expect(peer_id, unix_ms());
+            if swarm.is_connected(&peer_id) {
+                partition.connected(&peer_id);
+            }
+            Ok(json!({ "expected": true }))
+        }
+        AdminCommand::RemoveExpectedPeer { peer_id } => Ok(json!({ "removed": partition.remove(&peer_id) })),
+        AdminCommand::Quotas | AdminCommand::SetQuota { .. } => {
+            Err("this server keeps no document store to hold to room quotas".to_string())
+        }
+        AdminCommand::AuthorQuotas | AdminCommand::SetAuthorQuota { .. } => {
+            Err("this server keeps no document store to hold to author quotas".to_string())
+        }
+        AdminCommand::Replication => Err("this server keeps no document store to replicate into".to_string()),
+        AdminCommand::Reload => {
+            let live = Reloadable { swarm, log, duplicates, audit, docstore_config, partition };
+            reload_config(live).map(|outcome| admin::reload_result(&outcome))
+        }
+        AdminCommand::DhtStore => {
+            let stats = dht_store.stats(swarm.behaviour_mut().kademlia.store_mut());
+            let full: serde_json::Map<String, Value> = StoreFullKind::ALL
+                .iter()
+                .map(|kind| (kind.to_string(), json!({ "refused": dht_store.refused(*kind), "full": dht_store.is_full(*kind) })))
+                .collect();
+            Ok(json!({
+                "records": stats.records,
+                "record_bytes": stats.record_bytes,
+                "providers": stats.providers,
+                "provider_keys": stats.provider_keys,
+                "limits": full,
+            }))
+        }
+    }
+}
+
+/// The directory our files go under, from `--data-dir`, `P2P_DATA_DIR` or the platform
+/// default; see [`DataDir`].
+fn get_data_dir() -> anyhow::Result<DataDir> {
+    DataDir::from_env(arg_value("data-dir").map(PathBuf::from)).context("failed to determine current working directory")
 }
 
 /// Returns the identity key path to use, giving precedence to the `IDENTITY_KEY_PATH` environment
-/// variable. Otherwise default to ./ .p2p/identity.key in the process working directory.
+/// variable. Otherwise default to identity.key in the data directory.
 fn get_identity_key_path() -> anyhow::Result<PathBuf> {
-    if let Ok(p) = std::env::var("IDENTITY_KEY_PATH") {
-        return Ok(PathBuf::from(p));
+    Ok(get_data_dir()?.identity_key(|name| std::env::var(name).ok()))
+}
+
+/// Load the WebRTC certificate from `path`, or generate one and save it there, so the
+/// `/certhash` in our addresses stays the same across restarts.
+fn load_or_create_cert(path: &Path) -> anyhow::Result<webrtc::tokio::Certificate> {
+    match std::fs::read_to_string(path) {
+        Ok(pem) => {
+            return webrtc::tokio::Certificate::from_pem(&pem)
+                .with_context(|| format!("invalid WebRTC certificate file: {}", path.display()));
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).with_context(|| format!("failed to read WebRTC certificate file: {}", path.display())),
     }
-    let cwd = std::env::current_dir().context("failed to determine current working directory")?;
-    Ok(cwd.join(".p2p").join("identity.key"))
+    let cert = webrtc::tokio::Certificate::generate(&mut rand::thread_rng())?;
+    // Holds the certificate's private key
+    write_key_file(path, cert.serialize_pem().as_bytes())?;
+    tracing::info!("Generated new WebRTC certificate and saved to {}", path.display());
+    Ok(cert)
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt::init();
+    load_config_file()?;
+    if std::env::args().nth(1).as_deref() == Some("admin") {
+        tracing_subscriber::fmt::init();
+        return run_admin_client().await;
+    }
+    if std::env::args().nth(1).as_deref() == Some("rotate-key") {
+        tracing_subscriber::fmt::init();
+        return run_rotate_key().await;
+    }
+    if std::env::args().nth(1).as_deref() == Some("migrate") {
+        tracing_subscriber::fmt::init();
+        return run_migrate();
+    }
+    if std::env::args().nth(1).as_deref() == Some("check") || has_flag("check") {
+        tracing_subscriber::fmt::init();
+        return run_check();
+    }
+
+    // `--events-ndjson` claims stdout for the event stream; everything human-readable
+    // moves to stderr so the stream stays parseable
+    let mirror = start_event_mirror()?;
+    if has_flag("events-ndjson") {
+        STATUS_TO_STDERR.store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+    let log = LogControl::init()?;
 
-    let key_path_buf = get_identity_key_path()?;
-    println!("Using identity key path: {}", key_path_buf.display());
-    let local_key = load_or_create_identity(&key_path_buf)?;
+    check_data_formats()?;
+
+    // Held for the lifetime of the server so `server rotate-key` can't swap the key under it
+    let mut _identity_lock = None;
+    let local_key = if let Some(seed_hex) = arg_value("identity-seed-hex") {
+        // Dev-only: deterministic peer id for tests and docs. Never persisted.
+        let seed = decode_hex(&seed_hex).context("invalid --identity-seed-hex")?;
+        let kp = keys::insecure_identity_from_seed(&seed).context("invalid --identity-seed-hex")?;
+        tracing::warn!("Using an INSECURE identity derived from --identity-seed-hex; do not use in production");
+        kp
+    } else {
+        let key_path_buf = get_identity_key_path()?;
+        status!("Using identity key path: {}", key_path_buf.display());
+        _identity_lock = Some(lock_identity(&key_path_buf)?);
+        let passphrase = get_identity_passphrase()?;
+        let key_type = match arg_value("key-type") {
+            Some(s) => s.parse::<keys::KeyType>().map_err(anyhow::Error::msg)?,
+            None => keys::KeyType::default(),
+        };
+        load_or_create_identity(
+            &key_path_buf,
+            passphrase.as_deref(),
+            has_flag("regenerate-invalid-identity"),
+            key_type,
+        )?
+    };
     let local_peer_id = PeerId::from(local_key.public());
-    println!("Local peer id: {}", local_peer_id);
+    status!("Local peer id: {}", local_peer_id);
+    let cert_path = get_data_dir()?.webrtc_cert(|name| std::env::var(name).ok());
+    let cert = load_or_create_cert(&cert_path)?;
+
+    // `--role observer` runs a passive collector: no relay service, Kademlia client mode
+    let role = match arg_value("role") {
+        Some(r) => r.parse::<NodeRole>().map_err(anyhow::Error::msg)?,
+        None => NodeRole::Relay,
+    };
+    status!("Running as {:?}", role);
+    let mut node_builder = NodeBuilder::new(role);
+    if let Some(secs) = arg_value("idle-timeout-secs") {
+        let secs: u64 = secs.parse().context("invalid --idle-timeout-secs")?;
+        node_builder = node_builder.with_idle_timeout(std::time::Duration::from_secs(secs));
+    }
+    if let Some(agent) = arg_value("agent-version") {
+        node_builder = node_builder.with_agent_version(agent);
+    }
+    if let Some(namespace) = arg_value("topic-namespace") {
+        node_builder = node_builder.with_topic_namespace(namespace);
+    }
+    node_builder = node_builder.with_announcers(announcers()?);
+    let mut docstore_config = node_builder.docstore_config();
+    docstore_config.validate()?;
+    // Answer AutoNAT probes so home-hosted nodes can check their reachability through us
+    node_builder = node_builder.with_upnp(has_flag("upnp")).with_autonat(true);
+    // Relays learn their public addresses late; tell connected peers right away
+    node_builder = node_builder.with_identify_push(true);
+    node_builder = node_builder.with_dht(dht_config()?);
+    // Connections, messages, queries and errors as `docstore::observer` tracing events
+    node_builder = node_builder.with_observer(Arc::new(TracingObserver));
+    let ip_limits_config = ip_limits_config()?;
+    // Outbound dials only; the listeners stay direct
+    let proxy = proxy_config()?;
+    if let Some(proxy) = &proxy {
+        status!("Dialing TCP addresses through proxy {}", proxy);
+    }
 
     // Build swarm with the new builder API
     let mut swarm = libp2p::SwarmBuilder::with_existing_identity(local_key.clone())
         .with_tokio()
-        .with_tcp(
-            tcp::Config::default().nodelay(true),
-            noise::Config::new,
-            yamux::Config::default,
-        )?
+        .with_other_transport(|local_key| {
+            Ok(ProxiedTcp::new(tcp::Config::default().nodelay(true), proxy)
+                .upgrade(Version::V1)
+                .authenticate(noise::Config::new(local_key)?)
+                .multiplex(yamux::Config::default()))
+        })?
         .with_other_transport(|local_key| {
             // WebRTC transport for browser connectivity
-            Ok(webrtc::tokio::Transport::new(
-                local_key.clone(),
-                webrtc::tokio::Certificate::generate(&mut rand::thread_rng())?,
-            )
-            .map(|(peer_id, conn), _| (peer_id, StreamMuxerBox::new(conn))))
+            Ok(webrtc::tokio::Transport::new(local_key.clone(), cert.clone())
+                .map(|(peer_id, conn), _| (peer_id, StreamMuxerBox::new(conn))))
         })?
+        // Resolve /dns4, /dns6 and /dnsaddr bootstrap addresses
+        .with_dns()?
         .with_behaviour(|key| {
-            #[cfg(target_arch = "wasm32")]
-            {
-                let (ping_beh, gossipsub_beh, identify_beh, kademlia_beh) = NodeBuilder::new(NodeRole::Relay).build_behaviours(key);
-                Ok(MyBehaviour {
-                    ping: ping_beh,
-                    gossipsub: gossipsub_beh,
-                    identify: identify_beh,
-                    kademlia: kademlia_beh,
-                })
-            }
-
-            #[cfg(not(target_arch = "wasm32"))]
-            {
-                let (ping_beh, gossipsub_beh, identify_beh, kademlia_beh, relay_beh) = NodeBuilder::new(NodeRole::Relay).build_behaviours(key);
-                Ok(MyBehaviour {
-                    ping: ping_beh,
-                    gossipsub: gossipsub_beh,
-                    identify: identify_beh,
-                    kademlia: kademlia_beh,
-                    relay: relay_beh.expect("relay behaviour expected for relay role"),
-                })
-            }
+            let behaviours = node_builder.build_behaviours(key)?;
+            Ok(MyBehaviour {
+                ping: behaviours.ping,
+                gossipsub: behaviours.gossipsub,
+                ephemeral: make_relay_ephemeral_gossipsub(key)?,
+                identify: behaviours.identify,
+                kademlia: behaviours.kademlia,
+                replay: replay::make_replay_behaviour(),
+                mailbox: mailbox::make_mailbox_behaviour(true),
+                rendezvous: behaviours.rendezvous,
+                keep_alive: behaviours.keep_alive,
+                peers: behaviours.peers,
+                interest: interest::make_interest_behaviour(true),
+                #[cfg(not(target_arch = "wasm32"))]
+                relay: behaviours.relay,
+                #[cfg(not(target_arch = "wasm32"))]
+                ip_limits: ip_limits::Behaviour::new(ip_limits_config),
+                #[cfg(not(target_arch = "wasm32"))]
+                nat: behaviours.nat,
+            })
         })?
+        .with_swarm_config(|c| c.with_idle_connection_timeout(node_builder.idle_timeout()))
         .build();
 
+    // Readiness: every listener up, topic subscribed, bootstrap attempted
+    let health = Health::new(health::DEFAULT_STALL_TIMEOUT);
+    start_status(&health).await?;
+    let notify = has_flag("notify");
+
     // Listen on TCP random port
-    swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
+    let tcp_listener = swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
+    health.update(|r| r.expect_listener(tcp_listener));
 
     // Listen on WebRTC-direct UDP port (9090 by default)
-    let udp_port: u16 = std::env::var("SIGNALING_PORT").ok().and_then(|s| s.parse().ok()).unwrap_or(9090);
+    let udp_port: u16 =
+        arg_value("signaling-port").or_else(|| std::env::var("SIGNALING_PORT").ok()).and_then(|s| s.parse().ok()).unwrap_or(9090);
     let webrtc_addr: Multiaddr = format!("/ip4/0.0.0.0/udp/{}/webrtc-direct", udp_port).parse()?;
-    swarm.listen_on(webrtc_addr.clone())?;
+    let webrtc_listener = swarm.listen_on(webrtc_addr.clone())?;
+    health.update(|r| r.expect_listener(webrtc_listener));
 
     // Subscribe to the public docstore topic via behaviour helper
-    simple_p2p_docstore::behaviour::docstore::subscribe(&mut swarm.behaviour_mut().gossipsub)?;
-    println!("✓ Subscribed to topic: docstore/v1/updates");
+    simple_p2p_docstore::behaviour::docstore::subscribe(&mut swarm.behaviour_mut().gossipsub, &docstore_config.topics)?;
+    status!("✓ Subscribed to topic: {}", docstore_config.topics.updates());
+    health.update(|r| r.set_subscribed());
+    // Relay FullNode snapshots to clients
+    simple_p2p_docstore::behaviour::docstore::subscribe_snapshots(&mut swarm.behaviour_mut().gossipsub, &docstore_config.topics)?;
+    // Forward announcements, from the peers in `--announcers` only
+    simple_p2p_docstore::behaviour::docstore::subscribe_announcements(&mut swarm.behaviour_mut().gossipsub, &docstore_config.topics)?;
+    // Forward receipts to publishers that are not connected to the receiver
+    simple_p2p_docstore::behaviour::docstore::subscribe_receipts(&mut swarm.behaviour_mut().gossipsub, &docstore_config.topics)?;
 
-    println!("Listening on TCP & WebRTC port {}", udp_port);
+    status!("Listening on TCP & WebRTC port {}", udp_port);
 
-    // Bootstrap peers (if provided) - environment variable: BOOTSTRAP_PEERS (comma-separated multiaddrs)
-    if let Ok(peers) = std::env::var("BOOTSTRAP_PEERS") {
+    // Bootstrap peers and `--expected-peers` should stay reachable; losing them while
+    // clients are still here looks like a partition
+    let mut partition = PartitionWatch::new(partition_threshold()?);
+    for peer_id in expected_peers()? {
+        partition.expect(peer_id, unix_ms());
+    }
+    // Bootstrap peers (if provided), see `bootstrap_peers`
+    if let Some(peers) = bootstrap_peers() {
         for p in peers.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
             match p.parse::<Multiaddr>() {
-                Ok(addr) => {
-                    // Try to extract a PeerId from the multiaddr. If found, add it into Kademlia store; otherwise dial.
-                    let mut peer_id_opt: Option<PeerId> = None;
-                    for protocol in addr.iter() {
-                        use libp2p::multiaddr::Protocol;
-                        if let Protocol::P2p(pid) = protocol {
-                            peer_id_opt = Some(pid);
-                        }
-                    }
-                    if let Some(peer_id) = peer_id_opt {
-                        swarm.behaviour_mut().kademlia.add_address(&peer_id, addr.clone());
-                        println!("Added bootstrap address for {}: {}", peer_id, addr);
-                    } else {
-                        // Dial the address; this will eventually learn addresses from the peer via Identify
-                        if let Err(e) = swarm.dial(addr.clone()) {
-                            println!("Failed to dial bootstrap addr {}: {}", addr, e);
-                        } else {
-                            println!("Dialed bootstrap address: {}", addr);
-                        }
-                    }
-                }
+                Ok(addr) => add_bootstrap_addr(&mut swarm, &mut partition, addr),
                 Err(e) => {
-                    println!("Invalid bootstrap multiaddr {}: {}", p, e);
+                    status!("Invalid bootstrap multiaddr {}: {}", p, e);
                 }
             }
         }
         // Kick off bootstrap query
         if let Err(e) = swarm.behaviour_mut().kademlia.bootstrap() {
-            println!("Failed to bootstrap Kademlia: {}", e);
+            status!("Failed to bootstrap Kademlia: {}", e);
         } else {
-            println!("Kademlia bootstrap started");
+            status!("Kademlia bootstrap started");
         }
     }
+    health.update(|r| r.set_bootstrap_attempted());
+
+    // The other relays of our federation: kept connected for good and sent every message
+    // directly, see `federation`
+    let mut federation = federation()?.without(&local_peer_id);
+    let mut federation_links = ImportantPeers::default();
+    for (peer_id, addrs) in federation.members() {
+        for addr in addrs {
+            swarm.behaviour_mut().kademlia.add_address(peer_id, addr.clone());
+            federation_links.mark(*peer_id, Some(addr.clone()));
+        }
+        federation_links.dial_now(peer_id, Instant::now());
+        swarm.behaviour_mut().gossipsub.add_explicit_peer(peer_id);
+        partition.expect(*peer_id, unix_ms());
+    }
+    if !federation.is_empty() {
+        status!("Federated with {} relays", federation.len());
+    }
+
+    // Admin requests reach the loop over a command channel, like `Node` commands do
+    let (admin_tx, mut admin_rx) = mpsc::unbounded::<AdminCall>();
+    start_admin(admin_tx.clone()).await?;
+    let mut bans = BanList::default();
+    let mut reservations: HashSet<PeerId> = HashSet::new();
+    // Connected clients that asked to be listed to the others
+    let mut peer_directory = PeerDirectory::default();
+    // Who wants each room topic, and the bytes forwarded to each client
+    let mut topic_interest = TopicInterest::default();
+    // Which peers are relays too, so local-scope messages go no further than one hop between relays
+    let mut scope_filter = ScopeFilter::default();
+    // Accepted docstore messages, served to peers catching up over /docstore/replay/1.0.0
+    let mut replay = replay_responder()?;
+    let mut audit = request_audit()?;
+    let mut mailbox = Mailbox::load(Some(get_data_dir()?.root().join(mailbox::MAILBOX_FILE)), MailboxLimits::default());
+    // Republished updates per source, which gossipsub would otherwise drop silently
+    let mut duplicates = DuplicateDetector::new(duplicate_config()?);
+    // Refusals by the Kademlia record store, which it would otherwise keep to itself
+    let mut dht_store = DhtStoreMonitor::default();
+    let mut port_mappings = PortMappings::default();
+    // How peers see us; confirmed addresses also go to `--addr-file`
+    let mut external_addrs = ExternalAddrs::default();
+    let addr_file = arg_value("addr-file").map(PathBuf::from);
+    let update_addr_file = |swarm: &Swarm<MyBehaviour>| {
+        if let Some(path) = &addr_file {
+            if let Err(e) = write_addr_file(path, swarm) {
+                tracing::warn!("Failed to write {}: {}", path.display(), e);
+            }
+        }
+    };
+    // Connections per transport, for `/metrics`
+    let traffic = TrafficStats::default().with_observer(node_builder.observer());
+
+    // Ticks the readiness watchdog even when the swarm is quiet
+    let mut health_tick = tokio::time::interval(std::time::Duration::from_secs(1));
+    let mut last_readiness: Option<ReadinessState> = None;
+    // Relays announce themselves in the DHT now, once bootstrapped, and on every refresh
+    let provides_relay = swarm.behaviour().relay.is_enabled();
+    let mut relay_provider_tick = tokio::time::interval(RELAY_PROVIDER_REFRESH);
+    let mut shutdown = std::pin::pin!(shutdown_signal());
+    let mut reload_signal = ReloadSignal::new();
 
     loop {
-        match swarm.select_next_some().await {
-            SwarmEvent::NewListenAddr { address, .. } => {
-                println!("New listen addr: {}", address);
+        health.tick();
+        let event = tokio::select! {
+            _ = health_tick.tick() => {
+                if notify {
+                    notify_readiness(&health, &mut last_readiness);
+                }
+                duplicate_metrics(&health, &duplicates);
+                interest_metrics(&health, &topic_interest);
+                request_metrics(&health, &audit);
+                connection_metrics(&health, &traffic);
+                dht_store_metrics(&health, &mut dht_store, &mut swarm);
+                check_partition(&mut partition, &mut swarm, &health, &mirror);
+                redial_federation(&mut federation_links, &mut swarm);
+                federation_metrics(&health, &federation, &swarm);
+                for peer in bans.expire(Instant::now()) {
+                    swarm.behaviour_mut().gossipsub.remove_blacklisted_peer(&peer);
+                }
+                mailbox.expire(unix_ms());
+                mailbox.flush();
+                continue;
+            }
+            _ = relay_provider_tick.tick(), if provides_relay => {
+                provide_relay(&mut swarm, &mut dht_store, &mirror);
+                continue;
+            }
+            _ = &mut shutdown => {
+                status!("Shutting down");
+                if provides_relay {
+                    // Copies held by other peers expire on their own
+                    swarm.behaviour_mut().kademlia.stop_providing(&relay_provider_key());
+                }
+                break;
+            }
+            _ = reload_signal.recv() => {
+                let live = Reloadable {
+                    swarm: &mut swarm,
+                    log: &log,
+                    duplicates: &mut duplicates,
+                    audit: &mut audit,
+                    docstore_config: &mut docstore_config,
+                    partition: &mut partition,
+                };
+                // Failures are logged by the reload itself
+                let _ = reload_config(live);
+                continue;
+            }
+            Some(call) = admin_rx.next() => {
+                let result =
+                    handle_admin(&mut swarm, &mut bans, &mut partition, &reservations, &mut replay.log, &mut duplicates, &mut audit, &mut dht_store, &local_key, &mut docstore_config, &log, call.command);
+                let _ = call.reply.send(result);
+                continue;
+            }
+            event = swarm.select_next_some() => event,
+        };
+        traffic.observer().swarm_event(&event);
+        match event {
+            SwarmEvent::NewListenAddr { listener_id, address } => {
+                status!("New listen addr: {}", address);
+                health.update(|r| r.listen_addr_added(listener_id));
+                update_addr_file(&swarm);
+            }
+            SwarmEvent::ExpiredListenAddr { listener_id, address } => {
+                status!("Expired listen addr: {}", address);
+                health.update(|r| r.listen_addr_expired(listener_id));
+                update_addr_file(&swarm);
+            }
+            event @ (SwarmEvent::NewExternalAddrCandidate { .. }
+            | SwarmEvent::ExternalAddrConfirmed { .. }
+            | SwarmEvent::ExternalAddrExpired { .. }) => {
+                let Some(change) = external_addrs.on_swarm_event(&event) else { continue };
+                match &change {
+                    ExternalAddrChange::Candidate(addr) => status!("External addr candidate: {}", addr),
+                    ExternalAddrChange::Confirmed(addr) => status!("Confirmed external addr: {}", addr),
+                    ExternalAddrChange::Expired(addr) => status!("Expired external addr: {}", addr),
+                }
+                if !matches!(change, ExternalAddrChange::Candidate(_)) {
+                    // What identify advertises changed; tell the connected peers now
+                    let peers: Vec<PeerId> = swarm.connected_peers().copied().collect();
+                    swarm.behaviour_mut().identify.push(peers);
+                    update_addr_file(&swarm);
+                }
+            }
+            SwarmEvent::ListenerClosed { listener_id, reason, .. } => {
+                status!("Listener closed: {:?}", reason);
+                health.update(|r| r.listener_closed(listener_id));
             }
             SwarmEvent::Behaviour(ev) => {
                 match ev {
@@ -195,37 +1797,179 @@ async fn main() -> anyhow::Result<()> {
                         message_id,
                         message,
                     }) => {
+                        traffic.observer().message_received(MessageInfo::new(&message, &propagation_source));
+                        let mut acceptance = simple_p2p_docstore::behaviour::validate_message(&docstore_config, &message);
+                        if matches!(acceptance, gossipsub::MessageAcceptance::Accept) && scope_filter.is_out_of_scope(&propagation_source, &message.data) {
+                            acceptance = gossipsub::MessageAcceptance::Ignore;
+                        }
+                        let rejected = matches!(acceptance, gossipsub::MessageAcceptance::Reject);
+                        let ignored = matches!(acceptance, gossipsub::MessageAcceptance::Ignore);
+                        simple_p2p_docstore::behaviour::report_validation(
+                            &mut swarm.behaviour_mut().gossipsub, &message_id, &propagation_source, acceptance,
+                        );
+                        if rejected {
+                            status!("✗ Rejected invalid message {} from {}", message_id, propagation_source);
+                            continue;
+                        }
+                        if ignored {
+                            if let Some(version) = simple_p2p_docstore::behaviour::unsupported_version(&message.data) {
+                                status!("⚠ Ignoring envelope v{} from {} (unsupported format)", version, propagation_source);
+                            }
+                            continue;
+                        }
+                        // Keyed on the author, so a flood forwarded by another relay is not pinned on it
+                        let source = message.source.unwrap_or(propagation_source);
+                        let content_id = simple_p2p_docstore::behaviour::content_message_id(&message);
+                        let verdict = duplicates.observe(source, content_id, unix_ms());
+                        if let Verdict::Flooding { duplicates: count } | Verdict::Graylist { duplicates: count } = verdict {
+                            let config = *duplicates.config();
+                            let graylisted = matches!(verdict, Verdict::Graylist { .. });
+                            status!("⚠ {} sent {} duplicate messages within {}s", source, count, config.rate_window.as_secs());
+                            if graylisted {
+                                status!("✗ Graylisting {} for {}s after sustained duplicate floods", source, config.graylist_for.as_secs());
+                                bans.ban(source, config.graylist_for, Instant::now());
+                                swarm.behaviour_mut().gossipsub.blacklist_peer(&source);
+                                let _ = swarm.disconnect_peer_id(source);
+                            }
+                            if let Some(mirror) = &mirror {
+                                mirror.emit(MirrorEvent::DuplicateFlood {
+                                    peer_id: source.to_string(),
+                                    duplicates: count,
+                                    window_secs: config.rate_window.as_secs(),
+                                    graylisted,
+                                });
+                            }
+                        }
+                        if let Some(mirror) = &mirror {
+                            mirror.emit(MirrorEvent::GossipMessage {
+                                topic: message.topic.to_string(),
+                                source: message.source.map(|p| p.to_string()),
+                                propagation_source: propagation_source.to_string(),
+                                message_id: message_id.to_string(),
+                                size: message.data.len(),
+                                payload_hash: event_log::payload_hash(&message.data),
+                            });
+                        }
+                        if docstore_config.topics.is_room_topic(&message.topic) {
+                            record_forwards(&mut topic_interest, &swarm.behaviour().gossipsub, &message, &propagation_source);
+                        }
+                        replay.log.append(message.topic.as_str(), message.data.clone(), unix_ms());
                         let data = String::from_utf8_lossy(&message.data);
-                        println!("📨 Received GossipSub message:");
-                        println!("   From: {}", propagation_source);
-                        println!("   ID: {:?}", message_id);
-                        println!("   Topic: {:?}", message.topic);
-                        println!("   Data: {}", data);
+                        status!("📨 Received GossipSub message:");
+                        status!("   From: {}", propagation_source);
+                        status!("   ID: {:?}", message_id);
+                        status!("   Topic: {:?}", message.topic);
+                        status!("   Data: {}", data);
                     }
                     MyBehaviourEvent::Gossipsub(gossipsub::Event::Subscribed { peer_id, topic }) => {
-                        println!("✓ Peer {} subscribed to topic: {:?}", peer_id, topic);
+                        status!("✓ Peer {} subscribed to topic: {:?}", peer_id, topic);
+                        // Join room update topics on demand so the relay forwards them between clients
+                        if docstore_config.topics.is_room_topic(&topic) {
+                            topic_interest.subscribed(peer_id, topic.clone());
+                        }
+                        if topic_interest.is_wanted(&topic) {
+                            if let Err(e) = swarm.behaviour_mut().gossipsub.subscribe(&gossipsub::IdentTopic::new(topic.as_str())) {
+                                tracing::warn!("Failed to join room topic {}: {}", topic, e);
+                            }
+                        }
                     }
                     MyBehaviourEvent::Gossipsub(gossipsub::Event::Unsubscribed { peer_id, topic }) => {
-                        println!("✗ Peer {} unsubscribed from topic: {:?}", peer_id, topic);
+                        status!("✗ Peer {} unsubscribed from topic: {:?}", peer_id, topic);
+                        if topic_interest.unsubscribed(&peer_id, &topic) {
+                            leave_unwanted(&mut swarm, &docstore_config, vec![topic]);
+                        }
+                    }
+                    MyBehaviourEvent::Ephemeral(gossipsub::Event::Subscribed { peer_id, topic }) => {
+                        // Join ephemeral topics on demand so the relay forwards cursor traffic
+                        // between clients. Ephemeral payloads are never printed or stored.
+                        if docstore_config.topics.is_room_topic(&topic) {
+                            topic_interest.subscribed(peer_id, topic.clone());
+                            if !topic_interest.is_wanted(&topic) {
+                                continue;
+                            }
+                            if let Err(e) = swarm.behaviour_mut().ephemeral.subscribe(&gossipsub::IdentTopic::new(topic.as_str())) {
+                                tracing::warn!("Failed to join room topic {}: {}", topic, e);
+                            }
+                        } else if let Some(doc_id) = docstore_config.topics.ephemeral_doc_id(&topic) {
+                            tracing::debug!("Peer {} joined ephemeral channel for {}", peer_id, doc_id);
+                            if let Err(e) = simple_p2p_docstore::behaviour::subscribe_ephemeral(
+                                &mut swarm.behaviour_mut().ephemeral,
+                                &docstore_config.topics,
+                                doc_id,
+                            ) {
+                                tracing::warn!("Failed to join ephemeral channel for {}: {}", doc_id, e);
+                            }
+                        }
+                    }
+                    MyBehaviourEvent::Ephemeral(gossipsub::Event::Unsubscribed { peer_id, topic }) => {
+                        if topic_interest.unsubscribed(&peer_id, &topic) {
+                            leave_unwanted(&mut swarm, &docstore_config, vec![topic]);
+                        }
+                    }
+                    // Forwarded by gossipsub itself unless out of scope; only counted
+                    MyBehaviourEvent::Ephemeral(gossipsub::Event::Message { propagation_source, message_id, message }) => {
+                        let acceptance = scope_filter.verdict(&propagation_source, &message.data);
+                        let accepted = matches!(acceptance, gossipsub::MessageAcceptance::Accept);
+                        simple_p2p_docstore::behaviour::report_validation(
+                            &mut swarm.behaviour_mut().ephemeral, &message_id, &propagation_source, acceptance,
+                        );
+                        if accepted && docstore_config.topics.is_room_topic(&message.topic) {
+                            record_forwards(&mut topic_interest, &swarm.behaviour().ephemeral, &message, &propagation_source);
+                        }
                     }
                         MyBehaviourEvent::Identify(identify::Event::Received { peer_id, info, .. }) => {
                             tracing::debug!("Identify Received for peer {}: addresses: {:?}", peer_id, info.listen_addrs);
+                            // With a federation, only its members are trusted as relays
+                            let serves_hop = info.protocols.iter().any(|p| p.as_ref() == RELAY_HOP_PROTOCOL);
+                            scope_filter.identified(peer_id, federation.is_relay(&peer_id, serves_hop));
                             for addr in info.listen_addrs {
                                 swarm.behaviour_mut().kademlia.add_address(&peer_id, addr.clone());
-                                println!("Added address {} for peer {} to Kademlia", addr, peer_id);
+                                status!("Added address {} for peer {} to Kademlia", addr, peer_id);
                             }
                         }
                         MyBehaviourEvent::Kademlia(evt) => {
                             // Log some Kademlia events for now
                             tracing::debug!("Kademlia event: {:?}", evt);
+                            traffic.observer().kad_event(&evt);
+                            if let (Some(mirror), KademliaEvent::OutboundQueryProgressed { id, result, .. }) = (&mirror, &evt) {
+                                if let Some(event) = kad_query_event(*id, result) {
+                                    mirror.emit(event);
+                                }
+                            }
                             match evt {
                                 KademliaEvent::OutboundQueryProgressed { id, result, .. } => {
                                     match result {
                                         QueryResult::GetClosestPeers(Ok(get_closest)) => {
-                                            println!("Kademlia GetClosestPeers result for query {:?}: peers={:?}", id, get_closest.peers);
+                                            status!("Kademlia GetClosestPeers result for query {:?}: peers={:?}", id, get_closest.peers);
                                         }
                                         QueryResult::GetClosestPeers(Err(err)) => {
-                                            println!("Kademlia GetClosestPeers query {:?} failed: {:?}", id, err);
+                                            status!("Kademlia GetClosestPeers query {:?} failed: {:?}", id, err);
+                                        }
+                                        // The announcement made at startup reached nobody
+                                        QueryResult::Bootstrap(Ok(ok)) if ok.num_remaining == 0 && provides_relay => {
+                                            provide_relay(&mut swarm, &mut dht_store, &mirror);
+                                        }
+                                        _ => {}
+                                    }
+                                }
+                                // `dht_config` leaves storing inbound records to us
+                                KademliaEvent::InboundRequest { request } => {
+                                    let store = swarm.behaviour_mut().kademlia.store_mut();
+                                    match request {
+                                        InboundRequest::PutRecord { source, record: Some(record), .. } => {
+                                            let stored = store.put(record);
+                                            if let Err(full) = dht_store.put(stored) {
+                                                tracing::debug!("Not storing record from {}: {}", source, full);
+                                                report_store_full(&mirror, full);
+                                            }
+                                        }
+                                        InboundRequest::AddProvider { record: Some(record) } => {
+                                            let key = record.key.clone();
+                                            let stored = store.add_provider(record);
+                                            if let Err(full) = dht_store.provide(&key, stored) {
+                                                tracing::debug!("Not storing provider record: {}", full);
+                                                report_store_full(&mirror, full);
+                                            }
                                         }
                                         _ => {}
                                     }
@@ -233,19 +1977,121 @@ async fn main() -> anyhow::Result<()> {
                                 _ => {}
                             }
                         }
+                        MyBehaviourEvent::Replay(libp2p::request_response::Event::Message {
+                            peer,
+                            message: libp2p::request_response::Message::Request { request, channel, .. },
+                            ..
+                        }) => {
+                            let response = audit.handle::<audit::Replay>(peer, &request, unix_ms(), |request| replay.page(request));
+                            if let replay::ReplayResponse::RateLimited { .. } = response {
+                                tracing::info!("Rate limiting replay requests from {}", peer);
+                            }
+                            if swarm.behaviour_mut().replay.send_response(channel, response).is_err() {
+                                tracing::debug!("Replay requester {} went away before the response", peer);
+                            }
+                        }
+                        MyBehaviourEvent::Mailbox(libp2p::request_response::Event::Message {
+                            peer,
+                            message: libp2p::request_response::Message::Request { request, channel, .. },
+                            ..
+                        }) => {
+                            let now = unix_ms();
+                            let response = audit.handle::<audit::Mailbox>(peer, &request, now, |request| mailbox.respond(peer, request, now));
+                            if swarm.behaviour_mut().mailbox.send_response(channel, response).is_err() {
+                                tracing::debug!("Mailbox requester {} went away before the response", peer);
+                            }
+                        }
+                        MyBehaviourEvent::KeepAlive(event) => keep_alive::handle_event(&mut swarm.behaviour_mut().keep_alive, event),
+                        MyBehaviourEvent::Peers(event) => {
+                            peers::handle_event(&mut swarm.behaviour_mut().peers, &mut peer_directory, event)
+                        }
+                        MyBehaviourEvent::Interest(event) => {
+                            let unwanted = interest::handle_event(&mut swarm.behaviour_mut().interest, &mut topic_interest, event);
+                            leave_unwanted(&mut swarm, &docstore_config, unwanted);
+                        }
+                        // Only serves; the registrations are logged
+                        MyBehaviourEvent::Rendezvous(event) => {
+                            let _ = RendezvousBehaviour::on_event(event);
+                        }
+                        #[cfg(not(target_arch = "wasm32"))]
+                        MyBehaviourEvent::IpLimits(ip_limits::Event::Denied { ip, peer_id, reason }) => {
+                            tracing::debug!("Denied inbound connection from {} ({:?}): {}", ip, peer_id, reason.as_str());
+                            if let Some(mirror) = &mirror {
+                                mirror.emit(MirrorEvent::ConnectionDenied {
+                                    ip: ip.to_string(),
+                                    peer_id: peer_id.map(|p| p.to_string()),
+                                    reason: reason.as_str().into(),
+                                });
+                            }
+                        }
+                        #[cfg(not(target_arch = "wasm32"))]
+                        MyBehaviourEvent::Nat(event) => handle_nat_event(&mut swarm, &mut port_mappings, event),
+                        #[cfg(not(target_arch = "wasm32"))]
+                        MyBehaviourEvent::Relay(libp2p::relay::Event::ReservationReqAccepted { src_peer_id, .. }) => {
+                            reservations.insert(src_peer_id);
+                            if let Some(mirror) = &mirror {
+                                mirror.emit(MirrorEvent::RelayReservation { peer_id: src_peer_id.to_string(), state: "accepted".into() });
+                            }
+                        }
+                        #[cfg(not(target_arch = "wasm32"))]
+                        MyBehaviourEvent::Relay(libp2p::relay::Event::ReservationTimedOut { src_peer_id }) => {
+                            reservations.remove(&src_peer_id);
+                            if let Some(mirror) = &mirror {
+                                mirror.emit(MirrorEvent::RelayReservation { peer_id: src_peer_id.to_string(), state: "timed_out".into() });
+                            }
+                        }
                         _ => {
                             // Other events (ping, identify, etc.)
                             tracing::debug!("Behaviour event: {:?}", ev);
                         }
                 }
             }
-            SwarmEvent::ConnectionEstablished { peer_id, .. } => {
-                println!("Connection established: {}", peer_id);
+            SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. } => {
+                if bans.is_banned(&peer_id, Instant::now()) {
+                    tracing::info!("Dropping connection from blocked peer {}", peer_id);
+                    let _ = swarm.disconnect_peer_id(peer_id);
+                    continue;
+                }
+                let transport = addrs::transport_name(endpoint.get_remote_address());
+                traffic.record_connection(transport);
+                partition.connected(&peer_id);
+                federation_links.connected(&peer_id, endpoint.is_dialer().then(|| endpoint.get_remote_address()));
+                if federation.connected(&peer_id) {
+                    status!("Federation link to {} is up", peer_id);
+                }
+                status!("Connection established: {} over {}", peer_id, transport);
+                if let Some(mirror) = &mirror {
+                    mirror.emit(MirrorEvent::ConnectionEstablished {
+                        peer_id: peer_id.to_string(),
+                        address: endpoint.get_remote_address().to_string(),
+                    });
+                }
+            }
+            SwarmEvent::ConnectionClosed { peer_id, num_established, .. } => {
+                status!("Connection closed: {}", peer_id);
+                if num_established == 0 {
+                    peer_directory.disconnected(&peer_id);
+                    partition.disconnected(&peer_id, unix_ms());
+                    let unwanted = topic_interest.disconnected(&peer_id);
+                    leave_unwanted(&mut swarm, &docstore_config, unwanted);
+                    scope_filter.disconnected(&peer_id);
+                    health.remove_metric(&interest::forwarded_metric(&peer_id));
+                    if federation.disconnected(&peer_id) {
+                        tracing::warn!("Federation link to {} is down; redialing", peer_id);
+                    }
+                    if !bans.is_banned(&peer_id, Instant::now()) {
+                        federation_links.connection_lost(&peer_id, Instant::now());
+                    }
+                }
+                if let Some(mirror) = &mirror {
+                    mirror.emit(MirrorEvent::ConnectionClosed { peer_id: peer_id.to_string() });
+                }
             }
-            SwarmEvent::ConnectionClosed { peer_id, .. } => {
-                println!("Connection closed: {}", peer_id);
+            SwarmEvent::OutgoingConnectionError { peer_id: Some(peer_id), .. } => {
+                federation_links.dial_failed(&peer_id, Instant::now());
             }
             _ => {}
         }
     }
+    Ok(())
 }
@@ -0,0 +1,145 @@
+//! Crate-level error type shared by the behaviour helpers and node handles.
+
+use libp2p::{gossipsub, PeerId};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("update of {size} bytes exceeds the maximum of {max} bytes")]
+    UpdateTooLarge { size: usize, max: usize },
+    #[error("document of {size} bytes exceeds the maximum of {max} bytes")]
+    DocumentTooLarge { size: usize, max: usize },
+    #[error("invalid configuration: {0}")]
+    InvalidConfig(String),
+    #[error("publish failed: {0}")]
+    Publish(#[from] gossipsub::PublishError),
+    #[error("peer {peer_id} is banned")]
+    PeerBanned { peer_id: PeerId },
+    #[error("{peer_id} is our own peer id; refusing to dial ourselves")]
+    SelfDial { peer_id: PeerId },
+    #[error("transport error: {0}")]
+    Transport(String),
+    #[error("node has stopped")]
+    NodeStopped,
+    #[error("node is read-only (observer role or replica) and cannot publish")]
+    ReadOnly,
+    #[error("DHT operation failed: {0}")]
+    Dht(String),
+    #[error("already republishing the maximum of {max} records")]
+    TooManyRecords { max: usize },
+    #[error("the DHT is disabled on this node")]
+    DhtDisabled,
+    #[error("this node does not discover peers through rendezvous")]
+    RendezvousDisabled,
+    #[error("invalid room id {0:?}")]
+    InvalidRoomId(String),
+    #[error("not a member of room {room_id:?}")]
+    NotInRoom { room_id: String },
+    #[error("capability rejected: {0}")]
+    Capability(#[from] crate::behaviour::docstore::auth::AuthError),
+    #[error("only the creator of room {room_id:?} can do this")]
+    NotRoomCreator { room_id: String },
+    #[error("guest link rejected: {0}")]
+    GuestLink(#[from] crate::behaviour::docstore::guest_link::GuestLinkError),
+    #[error("read-only access to room {room_id:?}")]
+    ReadOnlyRoom { room_id: String },
+    #[error("signing failed: {0}")]
+    Signing(#[from] libp2p::identity::SigningError),
+    #[error("no bootstrap address could be reached: {}", failures.join("; "))]
+    BootstrapFailed { failures: Vec<String> },
+    #[error("node not ready: waiting for {}", waiting_for.join(", "))]
+    NotReady { waiting_for: Vec<&'static str> },
+    #[error("node is suspended or over its bandwidth budget and already holds the maximum of {max} publishes")]
+    SuspendQueueFull { max: usize },
+    #[error("no connected peer serves document history")]
+    NoHistoryPeer,
+    #[error("history request rejected: {reason}")]
+    HistoryRejected { reason: String },
+    /// The requested point is older than the peer's history reaches; `earliest` is the
+    /// oldest version it can reconstruct.
+    #[error("history is not kept that far back{}", .earliest.map(|v| format!(", earliest version is {v}")).unwrap_or_default())]
+    HistoryUnavailable { earliest: Option<u64> },
+    #[error("no connected peer serves document fetches")]
+    NoFetchPeer,
+    #[error("fetch failed: {reason}")]
+    FetchFailed { reason: String },
+    #[error("rate limited by the peer; retry in {retry_after_ms}ms")]
+    RateLimited { retry_after_ms: u64 },
+    #[error("transaction has no updates")]
+    EmptyTransaction,
+    #[error("announcement rejected: {0}")]
+    Announcement(#[from] crate::behaviour::docstore::announce::AnnounceError),
+    #[error("mailbox deposit rejected: {reason}")]
+    MailboxRejected { reason: String },
+    #[error("no connected peer keeps room membership tables")]
+    NoMembersPeer,
+    #[error("room members unavailable: {reason}")]
+    MembersUnavailable { reason: String },
+    #[error("head pointer rejected: {0}")]
+    HeadPointer(#[from] crate::behaviour::HeadPointerError),
+    #[error("profile rejected: {0}")]
+    Profile(#[from] crate::behaviour::ProfileError),
+    #[error("quota exceeded: {0}")]
+    QuotaExceeded(#[from] crate::store::quota::QuotaExceeded),
+    #[error("CAS update refused: {0}")]
+    CasConflict(#[from] crate::behaviour::docstore::CasConflict),
+    /// An argument that does not parse, e.g. a malformed peer id. `reason` names the input.
+    #[error("{reason}")]
+    InvalidArgument { field: String, reason: String },
+}
+
+impl Error {
+    /// Stable machine-readable code, used e.g. for the `code` field of wasm error objects.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::UpdateTooLarge { .. } => "UpdateTooLarge",
+            Error::DocumentTooLarge { .. } => "DocumentTooLarge",
+            Error::InvalidConfig(_) => "InvalidConfig",
+            Error::Publish(_) => "PublishFailed",
+            Error::PeerBanned { .. } => "PeerBanned",
+            Error::SelfDial { .. } => "SelfDial",
+            Error::Transport(_) => "TransportError",
+            Error::NodeStopped => "NodeStopped",
+            Error::ReadOnly => "ReadOnly",
+            Error::Dht(_) => "DhtError",
+            Error::TooManyRecords { .. } => "TooManyRecords",
+            Error::DhtDisabled => "DhtDisabled",
+            Error::RendezvousDisabled => "RendezvousDisabled",
+            Error::InvalidRoomId(_) => "InvalidRoomId",
+            Error::NotInRoom { .. } => "NotInRoom",
+            Error::Capability(_) => "InvalidCapability",
+            Error::NotRoomCreator { .. } => "NotRoomCreator",
+            Error::GuestLink(crate::behaviour::docstore::guest_link::GuestLinkError::Expired) => "GuestLinkExpired",
+            Error::GuestLink(_) => "InvalidGuestLink",
+            Error::ReadOnlyRoom { .. } => "ReadOnlyRoom",
+            Error::Signing(_) => "SigningFailed",
+            Error::BootstrapFailed { .. } => "BootstrapFailed",
+            Error::NotReady { .. } => "NotReady",
+            Error::SuspendQueueFull { .. } => "SuspendQueueFull",
+            Error::NoHistoryPeer => "NoHistoryPeer",
+            Error::HistoryRejected { .. } => "HistoryRejected",
+            Error::HistoryUnavailable { .. } => "HistoryUnavailable",
+            Error::NoFetchPeer => "NoFetchPeer",
+            Error::FetchFailed { .. } => "FetchFailed",
+            Error::RateLimited { .. } => "RateLimited",
+            Error::EmptyTransaction => "EmptyTransaction",
+            Error::Announcement(_) => "InvalidAnnouncement",
+            Error::MailboxRejected { .. } => "MailboxRejected",
+            Error::NoMembersPeer => "NoMembersPeer",
+            Error::MembersUnavailable { .. } => "MembersUnavailable",
+            Error::HeadPointer(_) => "InvalidHeadPointer",
+            Error::Profile(_) => "InvalidProfile",
+            Error::QuotaExceeded(_) => "QuotaExceeded",
+            Error::CasConflict(_) => "CasConflict",
+            Error::InvalidArgument { .. } => "InvalidArgument",
+        }
+    }
+}
+
+impl From<crate::behaviour::profile::SignProfileError> for Error {
+    fn from(e: crate::behaviour::profile::SignProfileError) -> Self {
+        match e {
+            crate::behaviour::profile::SignProfileError::Invalid(e) => Error::Profile(e),
+            crate::behaviour::profile::SignProfileError::Signing(e) => Error::Signing(e),
+        }
+    }
+}
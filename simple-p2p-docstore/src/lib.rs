@@ -1,12 +1,34 @@
 // Root library: expose behaviour and node modules to binaries and tests.
 pub mod behaviour;
+pub mod error;
 pub mod node;
+#[cfg(any(test, feature = "sim"))]
+pub mod sim;
+pub mod store;
+pub mod sync;
+#[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+pub mod testing;
+
+pub use error::Error;
 
 // WASM-specific bindings are implemented in a separate module to avoid
-// compiling wasm-only code for native targets.
-#[cfg(target_arch = "wasm32")]
+// compiling wasm-only code for native targets, and behind the `wasm` feature so the
+// behaviours and codec can be used in the browser without them.
+#[cfg(all(target_arch = "wasm32", feature = "wasm"))]
 mod wasm_bindings;
-#[cfg(target_arch = "wasm32")]
+#[cfg(all(target_arch = "wasm32", feature = "wasm"))]
+mod wasm_idb;
+#[cfg(all(target_arch = "wasm32", feature = "wasm"))]
+mod wasm_ids;
+#[cfg(all(target_arch = "wasm32", feature = "wasm"))]
+mod wasm_log;
+#[cfg(all(target_arch = "wasm32", feature = "wasm"))]
 mod wasm_transport;
-#[cfg(target_arch = "wasm32")]
+#[cfg(all(target_arch = "wasm32", feature = "wasm"))]
+mod wasm_worker;
+#[cfg(all(target_arch = "wasm32", feature = "wasm"))]
 pub use wasm_bindings::*;
+#[cfg(all(target_arch = "wasm32", feature = "wasm"))]
+pub use wasm_ids::{MultiaddrWrapper, PeerIdWrapper};
+#[cfg(all(target_arch = "wasm32", feature = "wasm"))]
+pub use wasm_worker::worker_main;
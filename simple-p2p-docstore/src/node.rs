@@ -1,6 +1,128 @@
-use libp2p::{identity, Multiaddr, PeerId};
+use std::collections::HashSet;
+use std::time::Duration;
+
+#[cfg(not(target_arch = "wasm32"))]
+use libp2p::swarm::behaviour::toggle::Toggle;
+use libp2p::{identity, ping, Multiaddr, PeerId};
 use libp2p_kad::Mode;
-use crate::behaviour::{make_docstore_gossipsub, make_peer_dht};
+use crate::behaviour::docstore::{DocstoreGossipsubConfig, TopicRegistry};
+use crate::behaviour::fetch::{make_fetch_behaviour, FetchBehaviour, FETCH_PROTOCOL};
+use crate::behaviour::history::{make_history_behaviour, HistoryBehaviour, HISTORY_PROTOCOL};
+use crate::behaviour::keep_alive::{make_keep_alive_behaviour, KeepAliveBehaviour, KEEP_ALIVE_PROTOCOL};
+use crate::behaviour::mailbox::{make_mailbox_behaviour, MailboxBehaviour, MAILBOX_PROTOCOL};
+use crate::behaviour::members::{make_members_behaviour, MembersBehaviour, MEMBERS_PROTOCOL};
+use crate::behaviour::docstore::receipt::{make_receipt_behaviour, ReceiptBehaviour, RECEIPT_PROTOCOL};
+use crate::behaviour::peers::{make_peers_behaviour, PeersBehaviour, PEERS_PROTOCOL};
+use crate::behaviour::rendezvous::{make_rendezvous_behaviour, RendezvousBehaviour};
+use crate::behaviour::{make_docstore_gossipsub_with, make_peer_dht_with, IdentifyConfig, PeerDhtConfig, SnapshotPolicy};
+use crate::Error;
+
+pub mod address_book;
+pub mod addrs;
+pub mod announcements;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod audit;
+#[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+pub mod admin;
+pub mod bans;
+pub mod bootstrap;
+pub mod budget;
+pub mod catch_up;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod config_check;
+pub mod connections;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod data_dir;
+pub mod dedup_cache;
+pub mod dht_store;
+pub mod dht_summary;
+pub mod dial;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod dir_sync;
+pub mod duplicates;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod event_log;
+pub mod event_queue;
+#[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+pub mod event_stream;
+pub mod external_addrs;
+pub mod federation;
+pub mod fetch;
+pub mod find_peer;
+#[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+pub mod health;
+pub mod history;
+pub mod ids;
+pub mod keeper;
+pub mod keys;
+pub mod liveness;
+pub mod migrations;
+pub mod observer;
+pub mod ordering;
+pub mod partition;
+pub mod peer_info;
+pub mod peer_exchange;
+#[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+pub mod proxy;
+pub mod published_records;
+pub mod quality;
+pub mod readiness;
+pub mod receipts;
+pub mod recorder;
+pub mod redial;
+pub mod relay_discovery;
+pub mod relay_rank;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod reload;
+pub mod rendezvous;
+pub mod replica;
+pub mod reputation;
+#[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+mod native;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod scrub;
+pub mod seeds;
+#[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+pub mod shutdown;
+pub mod topic_health;
+pub mod traffic;
+
+pub use address_book::{AddressBook, RemovalReason};
+pub use announcements::{Announcement, DhtAnnouncements, RenewalSummary};
+pub use bans::BanList;
+pub use budget::{BandwidthBudget, BudgetChange, BudgetUsage};
+pub use connections::{ConnectionState, ConnectionStates, DialFailure};
+pub use dht_store::{DhtStoreMonitor, DhtStoreStats, StoreFull, StoreFullKind};
+pub use dht_summary::DhtSummary;
+pub use dial::{DialConcurrency, DialOptions};
+pub use external_addrs::{ExternalAddrChange, ExternalAddrs};
+pub use find_peer::{FindPeerOptions, FoundPeer};
+pub use history::{EventHistory, HistoryEntry, HistoryEvent};
+pub use keeper::ConnectionKeeper;
+pub use liveness::PingPolicy;
+pub use observer::{NoopObserver, Observer, ObserverHooks, TracingObserver};
+pub use ordering::{OrderedDelivery, OrderedUpdate, Origin};
+pub use peer_exchange::PeerExchange;
+pub use peer_info::{PeerInfo, PeerInfoCache};
+pub use published_records::PublishedRecords;
+pub use quality::{ConnectionQuality, QualityBucket, QualityChange, QualityParams, QualityTracker};
+pub use readiness::{DhtBootstrap, NodeReadiness};
+pub use receipts::{Ack, AckTracker, PublishOptions, Unacked};
+pub use recorder::{Recording, RecorderOptions, RecordingError, RecordingStatus, SessionRecorder};
+pub use relay_discovery::{RelayCheck, RelayDiscovery, RelayLookup};
+pub use rendezvous::{Registrant, RendezvousPeers};
+pub use replica::ReplicationStatus;
+pub use reputation::{PeerReputation, PeerSignal};
+#[cfg(not(target_arch = "wasm32"))]
+pub use scrub::{ScrubReport, ScrubStats, StoreScrub};
+pub use topic_health::{TopicChange, TopicHealth, TopicHealthConfig};
+pub use traffic::{Published, TrafficCounts, TrafficSnapshot, TrafficStats};
+#[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+pub use native::{DocstoreBehaviour, DocstoreBehaviourEvent, Node, NodeEvent, NodeEventStream, PendingTransaction};
+#[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+pub use event_stream::{DocumentWatch, EventSubscription, Lagged};
+#[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+pub use shutdown::{ShutdownMode, ShutdownReport};
 
 /// Node roles that determine which behaviours are enabled and how Kademlia is configured.
 #[derive(Debug, Clone, Copy)]
@@ -8,16 +130,176 @@ pub enum NodeRole {
     Client,
     Relay,
     FullNode,
+    /// Subscribes and records but never publishes; publish calls fail with `Error::ReadOnly`.
+    Observer,
+}
+
+impl std::str::FromStr for NodeRole {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "client" => Ok(NodeRole::Client),
+            "relay" => Ok(NodeRole::Relay),
+            "full" | "fullnode" => Ok(NodeRole::FullNode),
+            "observer" => Ok(NodeRole::Observer),
+            other => Err(format!("unknown role '{other}' (expected client, relay, full or observer)")),
+        }
+    }
+}
+
+impl NodeRole {
+    /// True for roles that must never inject messages.
+    pub fn is_read_only(self) -> bool {
+        matches!(self, NodeRole::Observer)
+    }
+
+    /// True for roles that run the relay service and announce it in the DHT.
+    pub fn serves_relay(self) -> bool {
+        matches!(self, NodeRole::Relay | NodeRole::FullNode)
+    }
+
+    /// Default idle connection timeout. Relays keep quiet connections around for longer.
+    pub fn default_idle_timeout(self) -> Duration {
+        match self {
+            NodeRole::Client | NodeRole::Observer => Duration::from_secs(120),
+            NodeRole::Relay | NodeRole::FullNode => Duration::from_secs(600),
+        }
+    }
+
+    /// How long snapshotted updates are kept before compaction. FullNodes serve replay
+    /// requests, so they hold on to history for longer.
+    pub fn default_retention(self) -> Duration {
+        match self {
+            NodeRole::FullNode => Duration::from_secs(7 * 24 * 60 * 60),
+            _ => crate::store::DEFAULT_RETENTION,
+        }
+    }
+
+    /// Default ping `(interval, timeout)`. Clients ping more often to keep NAT mappings alive.
+    pub fn default_ping(self) -> (Duration, Duration) {
+        match self {
+            NodeRole::Client | NodeRole::Observer => (Duration::from_secs(10), Duration::from_secs(10)),
+            NodeRole::Relay | NodeRole::FullNode => (Duration::from_secs(30), Duration::from_secs(20)),
+        }
+    }
+
+    /// Default [`PingPolicy`]. Browsers give up on a silent peer soonest: their WebRTC
+    /// connections die quietly and they have relays to fall back on.
+    pub fn default_ping_policy(self) -> PingPolicy {
+        match self {
+            NodeRole::Client | NodeRole::Observer if cfg!(target_arch = "wasm32") => {
+                PingPolicy { unresponsive_after: 1, disconnect_after: 2 }
+            }
+            NodeRole::Client | NodeRole::Observer => PingPolicy { unresponsive_after: 2, disconnect_after: 3 },
+            NodeRole::Relay | NodeRole::FullNode => PingPolicy { unresponsive_after: 3, disconnect_after: 5 },
+        }
+    }
 }
 
 pub struct NodeBuilder {
     role: NodeRole,
     bootstrap_peers: Vec<Multiaddr>,
+    listen_addrs: Vec<Multiaddr>,
+    idle_timeout: Duration,
+    ping_interval: Duration,
+    ping_timeout: Duration,
+    ping_policy: PingPolicy,
+    dial_concurrency: DialConcurrency,
+    snapshot_policy: Option<SnapshotPolicy>,
+    retention: Duration,
+    history_entries: usize,
+    history_bytes: usize,
+    identify: IdentifyConfig,
+    dht: PeerDhtConfig,
+    max_published_records: usize,
+    reannounce_after: Duration,
+    topics: TopicRegistry,
+    announcers: HashSet<PeerId>,
+    snapshot_publishers: HashSet<PeerId>,
+    discoverable: bool,
+    replica: bool,
+    observer: Observer,
+    topic_health: topic_health::TopicHealthConfig,
+    #[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+    address_book: Option<std::path::PathBuf>,
+    #[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+    store_path: Option<std::path::PathBuf>,
+    #[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+    store: Option<Box<dyn crate::store::DocStore + Send>>,
+    #[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+    room_quotas: crate::store::quota::RoomQuotas,
+    #[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+    author_quotas: crate::store::author_quota::AuthorQuotas,
+    #[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+    upnp: bool,
+    #[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+    autonat: bool,
+    #[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+    proxy: Option<proxy::ProxyConfig>,
+    #[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+    expected_peers: Vec<PeerId>,
+    #[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+    partition_threshold: Duration,
+    #[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+    federation: federation::Federation,
+    #[cfg(target_arch = "wasm32")]
+    dht_enabled: bool,
 }
 
 impl NodeBuilder {
     pub fn new(role: NodeRole) -> Self {
-        Self { role, bootstrap_peers: Vec::new() }
+        let (ping_interval, ping_timeout) = role.default_ping();
+        Self {
+            role,
+            bootstrap_peers: Vec::new(),
+            listen_addrs: Vec::new(),
+            idle_timeout: role.default_idle_timeout(),
+            ping_interval,
+            ping_timeout,
+            ping_policy: role.default_ping_policy(),
+            dial_concurrency: DialConcurrency::default(),
+            // Only FullNodes publish snapshots by default
+            snapshot_policy: matches!(role, NodeRole::FullNode).then(SnapshotPolicy::default),
+            retention: role.default_retention(),
+            history_entries: history::DEFAULT_HISTORY_ENTRIES,
+            history_bytes: history::DEFAULT_HISTORY_BYTES,
+            identify: IdentifyConfig::default(),
+            dht: PeerDhtConfig::default(),
+            max_published_records: published_records::DEFAULT_MAX_PUBLISHED_RECORDS,
+            reannounce_after: announcements::DEFAULT_REANNOUNCE_AFTER,
+            topics: TopicRegistry::default(),
+            announcers: HashSet::new(),
+            snapshot_publishers: HashSet::new(),
+            discoverable: false,
+            replica: false,
+            observer: Observer::default(),
+            topic_health: topic_health::TopicHealthConfig::default(),
+            #[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+            address_book: None,
+            #[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+            store_path: None,
+            #[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+            store: None,
+            #[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+            room_quotas: Default::default(),
+            #[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+            author_quotas: Default::default(),
+            #[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+            upnp: false,
+            #[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+            autonat: false,
+            #[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+            proxy: None,
+            #[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+            expected_peers: Vec::new(),
+            #[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+            partition_threshold: partition::DEFAULT_PARTITION_THRESHOLD,
+            #[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+            federation: federation::Federation::default(),
+            #[cfg(target_arch = "wasm32")]
+            dht_enabled: true,
+        }
     }
 
     pub fn add_bootstrap(mut self, addr: Multiaddr) -> Self {
@@ -25,50 +307,456 @@ impl NodeBuilder {
         self
     }
 
-    /// Assemble the behaviour components for the given identity key.
-    /// Returns (ping, gossipsub, identify, kademlia) which can be used to construct a NetworkBehaviour.
+    /// Address the spawned native node listens on (ignored by `build_behaviours`).
+    pub fn add_listen_addr(mut self, addr: Multiaddr) -> Self {
+        self.listen_addrs.push(addr);
+        self
+    }
+
+    /// How long a connection with no active streams is kept open.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    pub fn with_ping(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.ping_interval = interval;
+        self.ping_timeout = timeout;
+        self
+    }
+
+    /// How many failed pings in a row make a peer unresponsive, and get its connection
+    /// closed.
+    pub fn with_ping_policy(mut self, policy: PingPolicy) -> Self {
+        self.ping_policy = policy;
+        self
+    }
+
+    pub fn ping_policy(&self) -> PingPolicy {
+        self.ping_policy
+    }
+
+    /// How many of a peer's addresses are dialed at once, for every peer and for single
+    /// peers. Addresses are tried best first, see [`dial::plan`].
+    pub fn with_dial_concurrency(mut self, concurrency: DialConcurrency) -> Self {
+        self.dial_concurrency = concurrency;
+        self
+    }
+
+    pub fn dial_concurrency(&self) -> &DialConcurrency {
+        &self.dial_concurrency
+    }
+
+    /// How long a subscribed topic may go without mesh peers while connected before the
+    /// node resubscribes to it, and how long after the first connection it waits before
+    /// checking at all, see [`topic_health`].
+    pub fn with_topic_health(mut self, config: topic_health::TopicHealthConfig) -> Self {
+        self.topic_health = config;
+        self
+    }
+
+    pub fn topic_health(&self) -> topic_health::TopicHealthConfig {
+        self.topic_health
+    }
+
+    /// Override when (and whether) this node publishes document snapshots.
+    pub fn with_snapshot_policy(mut self, policy: Option<SnapshotPolicy>) -> Self {
+        self.snapshot_policy = policy;
+        self
+    }
+
+    /// Retention window for updates already covered by a snapshot.
+    pub fn with_retention(mut self, retention: Duration) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Size of the recent-event history: at most `entries` events holding about `bytes`.
+    /// `0` entries disables it.
+    pub fn with_event_history(mut self, entries: usize, bytes: usize) -> Self {
+        self.history_entries = entries;
+        self.history_bytes = bytes;
+        self
+    }
+
+    /// Run a FullNode as a read replica, see [`replica`]: it stores, serves and syncs
+    /// documents but its publish calls fail with `Error::ReadOnly`, and its agent version
+    /// says it is a replica. Other roles ignore it.
+    pub fn with_replica(mut self, replica: bool) -> Self {
+        self.replica = replica;
+        self
+    }
+
+    /// Agent version advertised through identify (default `simple-p2p-docstore/<version>`).
+    pub fn with_agent_version(mut self, agent_version: impl Into<String>) -> Self {
+        self.identify.agent_version = agent_version.into();
+        self
+    }
+
+    /// Protocol version advertised through identify (default `simple-p2p-docstore/0.1`).
+    pub fn with_protocol_version(mut self, protocol_version: impl Into<String>) -> Self {
+        self.identify.protocol_version = protocol_version.into();
+        self
+    }
+
+    /// Push fresh identify info to connected peers whenever our listen addresses change,
+    /// instead of waiting for their next periodic request.
+    pub fn with_identify_push(mut self, enabled: bool) -> Self {
+        self.identify.push_listen_addr_updates = enabled;
+        self
+    }
+
+    /// Kademlia record TTL, replication/publication intervals and record store limits.
+    pub fn with_dht(mut self, dht: PeerDhtConfig) -> Self {
+        self.dht = dht;
+        self
+    }
+
+    /// How many records put with `Node::put_record` are kept alive at once.
+    pub fn with_max_published_records(mut self, max: usize) -> Self {
+        self.max_published_records = max;
+        self
+    }
+
+    /// After how long without connections everything this node announced in the DHT is
+    /// announced again once it reconnects, see [`announcements`].
+    pub fn with_reannounce_after(mut self, gap: Duration) -> Self {
+        self.reannounce_after = gap;
+        self
+    }
+
+    /// Publish and subscribe under `namespace` (`<namespace>/v1/updates`, ...) instead of
+    /// `docstore`. Nodes in different namespaces share relays and the DHT but never see
+    /// each other's messages.
+    pub fn with_topic_namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.topics = TopicRegistry::new(namespace);
+        self
+    }
+
+    pub fn topics(&self) -> &TopicRegistry {
+        &self.topics
+    }
+
+    /// Accept network announcements signed by these peers; with none, every announcement
+    /// is rejected. See [`crate::behaviour::docstore::announce`].
+    pub fn with_announcers(mut self, announcers: impl IntoIterator<Item = PeerId>) -> Self {
+        self.announcers = announcers.into_iter().collect();
+        self
+    }
+
+    /// Install snapshots of any document published by these peers, usually the FullNodes
+    /// we rely on. Without them, only snapshots by a document's own authors are. See
+    /// [`crate::behaviour::docstore::snapshot`].
+    pub fn with_snapshot_publishers(mut self, publishers: impl IntoIterator<Item = PeerId>) -> Self {
+        self.snapshot_publishers = publishers.into_iter().collect();
+        self
+    }
+
+    /// Let relays list us to their other clients, so they can connect to us directly
+    /// instead of through the relay. Off by default; see [`peer_exchange`].
+    pub fn with_discoverable(mut self, discoverable: bool) -> Self {
+        self.discoverable = discoverable;
+        self
+    }
+
+    pub fn discoverable(&self) -> bool {
+        self.discoverable
+    }
+
+    /// Telemetry hooks the event loop calls, see [`observer`]. Nodes observe nothing by
+    /// default.
+    pub fn with_observer(mut self, hooks: std::sync::Arc<dyn ObserverHooks>) -> Self {
+        self.observer = Observer::new(hooks);
+        self
+    }
+
+    pub fn observer(&self) -> Observer {
+        self.observer.clone()
+    }
+
+    /// The gossipsub settings nodes built from this builder run with.
+    pub fn docstore_config(&self) -> DocstoreGossipsubConfig {
+        DocstoreGossipsubConfig {
+            topics: self.topics.clone(),
+            announcers: self.announcers.clone(),
+            snapshot_publishers: self.snapshot_publishers.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Persist documents under `path` instead of keeping them in memory (native nodes only).
+    #[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+    pub fn with_store_path(mut self, path: impl Into<std::path::PathBuf>) -> Self {
+        self.store_path = Some(path.into());
+        self
+    }
+
+    /// Keep documents in `store`, taking precedence over [`NodeBuilder::with_store_path`]
+    /// (native nodes only).
+    #[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+    pub fn with_store(mut self, store: impl crate::store::DocStore + Send + 'static) -> Self {
+        self.store = Some(Box::new(store));
+        self
+    }
+
+    /// Limit what each room's documents may take in the store, see
+    /// [`crate::store::quota`]. No room is limited by default; quotas can also be changed
+    /// while running with `Node::set_room_quota` (native nodes only).
+    #[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+    pub fn with_room_quotas(mut self, quotas: crate::store::quota::RoomQuotas) -> Self {
+        self.room_quotas = quotas;
+        self
+    }
+
+    /// Limit what each peer may store here as an author, see
+    /// [`crate::store::author_quota`]. Nobody is limited by default; the default and
+    /// per-peer quotas can also be changed while running with `Node::set_author_quota`
+    /// (native nodes only).
+    #[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+    pub fn with_author_quotas(mut self, quotas: crate::store::author_quota::AuthorQuotas) -> Self {
+        self.author_quotas = quotas;
+        self
+    }
+
+    /// Leave Kademlia out of the node (browser nodes only): no routing table, lookups or
+    /// provider records, just gossip with the peers it is connected to. DHT operations
+    /// fail with `Error::DhtDisabled`.
     #[cfg(target_arch = "wasm32")]
-    pub fn build_behaviours(
-        &self,
-        key: &identity::Keypair,
-    ) -> (
-        libp2p::ping::Behaviour,
-        libp2p::gossipsub::Behaviour,
-        libp2p::identify::Behaviour,
-        libp2p_kad::Behaviour<libp2p_kad::store::MemoryStore>,
-    ) {
-        let local_peer_id = PeerId::from(key.public());
-        let mode = match self.role {
-            NodeRole::Client => Mode::Client,
-            NodeRole::Relay | NodeRole::FullNode => Mode::Server,
-        };
-        let (ping_beh, identify_beh, kademlia_beh) = make_peer_dht(&key.public(), local_peer_id, mode);
-        let gossipsub = make_docstore_gossipsub(key);
-        (ping_beh, gossipsub, identify_beh, kademlia_beh)
+    pub fn with_dht_enabled(mut self, enabled: bool) -> Self {
+        self.dht_enabled = enabled;
+        self
     }
 
-    #[cfg(not(target_arch = "wasm32"))]
-    pub fn build_behaviours(
-        &self,
-        key: &identity::Keypair,
-    ) -> (
-        libp2p::ping::Behaviour,
-        libp2p::gossipsub::Behaviour,
-        libp2p::identify::Behaviour,
-        libp2p_kad::Behaviour<libp2p_kad::store::MemoryStore>,
-        Option<libp2p::relay::Behaviour>,
-    ) {
+    #[cfg(target_arch = "wasm32")]
+    pub fn dht_enabled(&self) -> bool {
+        self.dht_enabled
+    }
+
+    pub fn role(&self) -> NodeRole {
+        self.role
+    }
+
+    /// Check the settings that touch the host without starting anything, see
+    /// [`config_check`]: every listen address can be bound, and the store directory is
+    /// writable and loads. `spawn` runs this first.
+    #[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+    pub fn check(&self) -> Result<(), config_check::ConfigError> {
+        for addr in &self.listen_addrs {
+            config_check::check_listen("listen address", addr)?;
+        }
+        if let (None, Some(path)) = (&self.store, &self.store_path) {
+            config_check::check_store(path)?;
+        }
+        Ok(())
+    }
+
+    /// True for a FullNode built [`with_replica`](Self::with_replica).
+    pub fn is_replica(&self) -> bool {
+        self.replica && matches!(self.role, NodeRole::FullNode)
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout
+    }
+
+    pub fn bootstrap_peers(&self) -> &[Multiaddr] {
+        &self.bootstrap_peers
+    }
+
+    pub fn listen_addrs(&self) -> &[Multiaddr] {
+        &self.listen_addrs
+    }
+
+    pub fn snapshot_policy(&self) -> Option<&SnapshotPolicy> {
+        self.snapshot_policy.as_ref()
+    }
+
+    pub fn retention(&self) -> Duration {
+        self.retention
+    }
+
+    pub fn max_published_records(&self) -> usize {
+        self.max_published_records
+    }
+
+    /// Default expiry of the records we put.
+    pub fn record_ttl(&self) -> Option<Duration> {
+        self.dht.record_ttl
+    }
+
+    pub fn reannounce_after(&self) -> Duration {
+        self.reannounce_after
+    }
+
+    /// An empty history sized as configured with [`NodeBuilder::with_event_history`].
+    pub fn event_history<E: HistoryEvent>(&self) -> EventHistory<E> {
+        EventHistory::new(self.history_entries, self.history_bytes)
+    }
+
+    /// Protocols nodes built from this builder speak, to tell which ones a peer shares
+    /// with us once it identifies (see [`PeerInfo::shared_protocols`]). Gossipsub is
+    /// listed by the meshsub versions it negotiates.
+    pub fn local_protocols(&self) -> Vec<String> {
+        let mut protocols: Vec<String> = [
+            libp2p::identify::PROTOCOL_NAME,
+            libp2p::identify::PUSH_PROTOCOL_NAME,
+            ping::PROTOCOL_NAME,
+            libp2p_kad::PROTOCOL_NAME,
+        ]
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+        #[cfg(target_arch = "wasm32")]
+        if !self.dht_enabled {
+            protocols.retain(|p| p.as_str() != libp2p_kad::PROTOCOL_NAME.as_ref());
+        }
+        protocols.extend(["/meshsub/1.2.0", "/meshsub/1.1.0", "/meshsub/1.0.0", HISTORY_PROTOCOL, FETCH_PROTOCOL, MAILBOX_PROTOCOL, MEMBERS_PROTOCOL, RECEIPT_PROTOCOL, KEEP_ALIVE_PROTOCOL].map(String::from));
+        #[cfg(all(not(target_arch = "wasm32"), feature = "relay"))]
+        if self.role.serves_relay() {
+            protocols.push(libp2p::relay::HOP_PROTOCOL_NAME.to_string());
+        }
+        if self.role.serves_relay() {
+            protocols.push(PEERS_PROTOCOL.to_string());
+        }
+        #[cfg(feature = "rendezvous")]
+        protocols.push(crate::behaviour::rendezvous::RENDEZVOUS_PROTOCOL.to_string());
+        protocols
+    }
+
+    fn ping_config(&self) -> ping::Config {
+        ping::Config::new().with_interval(self.ping_interval).with_timeout(self.ping_timeout)
+    }
+
+    /// Persist known peers to `path` and use them to seed Kademlia and reconnect on the
+    /// next start (native nodes only).
+    #[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+    pub fn with_address_book(mut self, path: impl Into<std::path::PathBuf>) -> Self {
+        self.address_book = Some(path.into());
+        self
+    }
+
+    /// Ask the home router to forward our listen ports (UPnP) and advertise the mapped
+    /// addresses while AutoNAT probes don't show them unreachable (native nodes only).
+    #[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+    pub fn with_upnp(mut self, enabled: bool) -> Self {
+        self.upnp = enabled;
+        self
+    }
+
+    /// Answer AutoNAT probes from other peers (native nodes only). Nodes with UPnP enabled
+    /// run AutoNAT anyway, to check their mapped addresses.
+    #[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+    pub fn with_autonat(mut self, enabled: bool) -> Self {
+        self.autonat = enabled;
+        self
+    }
+
+    /// Dial TCP addresses through a SOCKS5 or HTTP CONNECT proxy (native nodes only).
+    /// Listening is unaffected.
+    #[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+    pub fn with_proxy(mut self, proxy: proxy::ProxyConfig) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Relays and FullNodes this node expects to stay reachable, on top of the bootstrap
+    /// peers and discovered relays. Relays and FullNodes report a possible partition when
+    /// one is unreachable for longer than `threshold` while clients are still connected,
+    /// see [`partition`]. Decommissioned peers are removed with
+    /// [`Node::remove_expected_peer`].
+    #[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+    pub fn with_expected_peers(mut self, peers: impl IntoIterator<Item = PeerId>, threshold: Duration) -> Self {
+        self.expected_peers.extend(peers);
+        self.partition_threshold = threshold;
+        self
+    }
+
+    /// The other relays of our federation, see [`federation`]: dialed at the start,
+    /// redialed with backoff whenever their link drops, sent every message directly and
+    /// expected to stay reachable. Once set, only members count as relays. Our own entry,
+    /// if the list has one, is left out (native nodes only).
+    #[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+    pub fn with_federation(mut self, federation: federation::Federation) -> Self {
+        self.federation = federation;
+        self
+    }
+
+    /// Assemble the behaviour components for the given identity key, for composing into a
+    /// `NetworkBehaviour`. Optional members are enabled according to the role and flags.
+    /// Fails with `Error::InvalidConfig` if the settings don't make a working node.
+    pub fn build_behaviours(&self, key: &identity::Keypair) -> Result<Behaviours, Error> {
         let local_peer_id = PeerId::from(key.public());
         let mode = match self.role {
-            NodeRole::Client => Mode::Client,
+            NodeRole::Client | NodeRole::Observer => Mode::Client,
             NodeRole::Relay | NodeRole::FullNode => Mode::Server,
         };
-        let (ping_beh, identify_beh, kademlia_beh) = make_peer_dht(&key.public(), local_peer_id, mode);
-        let gossipsub = make_docstore_gossipsub(key);
-        let relay_beh = match self.role {
-            NodeRole::Relay | NodeRole::FullNode => Some(crate::behaviour::relay::make_relay_behaviour(local_peer_id)),
-            _ => None,
-        };
-        (ping_beh, gossipsub, identify_beh, kademlia_beh, relay_beh)
+        let mut identify_config = self.identify.clone();
+        if self.is_replica() {
+            identify_config.agent_version = replica::replica_agent_version(&identify_config.agent_version);
+        }
+        let (ping, identify, kademlia) =
+            make_peer_dht_with(&key.public(), local_peer_id, mode, self.ping_config(), &identify_config, &self.dht)?;
+        Ok(Behaviours {
+            ping,
+            gossipsub: make_docstore_gossipsub_with(key, &self.docstore_config())?,
+            identify,
+            kademlia,
+            // Only FullNodes keep history worth serving
+            history: make_history_behaviour(matches!(self.role, NodeRole::FullNode)),
+            // Same for the documents themselves
+            fetch: make_fetch_behaviour(matches!(self.role, NodeRole::FullNode)),
+            // FullNodes stay online to hold messages for peers that are not
+            mailbox: make_mailbox_behaviour(matches!(self.role, NodeRole::FullNode)),
+            // Membership tables outlive the members' sessions only on nodes that stay online
+            members: make_members_behaviour(matches!(self.role, NodeRole::FullNode)),
+            receipts: make_receipt_behaviour(),
+            // The nodes that relay are the well-known ones; everyone else registers with them
+            rendezvous: make_rendezvous_behaviour(key, self.role.serves_relay(), !self.role.serves_relay()),
+            keep_alive: make_keep_alive_behaviour(),
+            // Relays list their clients to each other
+            peers: make_peers_behaviour(self.role.serves_relay()),
+            #[cfg(not(target_arch = "wasm32"))]
+            relay: crate::behaviour::relay::relay_service(local_peer_id, self.role.serves_relay())?,
+            #[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+            nat: crate::behaviour::nat::make_nat_behaviour(local_peer_id, self.upnp, self.upnp || self.autonat),
+        })
     }
 }
+
+/// The behaviours [`NodeBuilder::build_behaviours`] hands out. Optional ones are wrapped in
+/// `Toggle` and switched on by the builder, so adding another doesn't change what callers
+/// get back; a disabled `Toggle` never emits events.
+pub struct Behaviours {
+    pub ping: ping::Behaviour,
+    pub gossipsub: libp2p::gossipsub::Behaviour,
+    pub identify: libp2p::identify::Behaviour,
+    /// Not a `Toggle`: native nodes always need it. Browser nodes built with
+    /// [`NodeBuilder::with_dht_enabled`]`(false)` wrap it in one themselves.
+    pub kademlia: libp2p_kad::Behaviour<libp2p_kad::store::MemoryStore>,
+    /// `/docstore/history/1.0.0`, answering requests only on FullNodes.
+    pub history: HistoryBehaviour,
+    /// `/docstore/fetch/1.0.0`, serving documents in verified chunks only on FullNodes.
+    pub fetch: FetchBehaviour,
+    /// `/docstore/mailbox/1.0.0`, holding messages for other peers only on FullNodes.
+    pub mailbox: MailboxBehaviour,
+    /// `/docstore/members/1.0.0`, keeping room membership tables only on FullNodes.
+    pub members: MembersBehaviour,
+    /// `/docstore/receipt/1.0.0`, taking in receipts for updates published with an ack
+    /// request.
+    pub receipts: ReceiptBehaviour,
+    /// `/rendezvous/1.0.0`: the server on Relay and FullNode nodes, registration and
+    /// discovery on the others. Neither in builds without the `rendezvous` feature.
+    pub rendezvous: RendezvousBehaviour,
+    /// `/docstore/keep-alive/1.0.0`, pinging the peers of active sessions; see [`keeper`].
+    pub keep_alive: KeepAliveBehaviour,
+    /// `/docstore/peers/1.0.0`, answered on Relay and FullNode nodes and asked by the
+    /// others; see [`peer_exchange`].
+    pub peers: PeersBehaviour,
+    /// Relay service, for the Relay and FullNode roles. Always present, but never enabled
+    /// in builds without the `relay` feature.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub relay: Toggle<crate::behaviour::relay::RelayBehaviour>,
+    /// UPnP port mapping and AutoNAT, see [`NodeBuilder::with_upnp`] and [`NodeBuilder::with_autonat`].
+    #[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+    pub nat: crate::behaviour::nat::NatBehaviour,
+}
@@ -0,0 +1,341 @@
+//! Persistent record of peers and the addresses they were reachable on, so a restarted
+//! node can reconnect to more than its single bootstrap address.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+
+use crate::node::migrations::{self, Artifact};
+
+/// Entries not seen for this long are dropped by [`AddressBook::prune`].
+pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// Consecutive dial failures after which an address is dropped by [`AddressBook::prune`].
+pub const MAX_FAILURES: u32 = 5;
+
+/// Why an address or peer was dropped from the DHT routing table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RemovalReason {
+    /// Asked for through the node handle.
+    Requested,
+    /// The address failed [`MAX_FAILURES`] dials in a row.
+    DialFailures,
+}
+
+impl RemovalReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RemovalReason::Requested => "requested",
+            RemovalReason::DialFailures => "dial_failures",
+        }
+    }
+}
+
+/// How `addr` is recorded: without a trailing `/p2p/<peer id>`, which the book keys on
+/// already and which dial errors leave out while identify does not.
+fn normalize(addr: &Multiaddr) -> String {
+    let mut addr = addr.clone();
+    if let Some(Protocol::P2p(_)) = addr.iter().last() {
+        addr.pop();
+    }
+    addr.to_string()
+}
+
+/// What we know about one address of a peer. Timestamps are unix milliseconds.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AddrRecord {
+    pub addr: String,
+    pub last_seen_ms: u64,
+    pub successes: u32,
+    /// Consecutive failed dials; reset by a successful connection.
+    pub failures: u32,
+}
+
+/// Records as written by versions that kept the `/p2p` suffix, normalized, keeping the
+/// first record of an address.
+fn normalized(records: Vec<AddrRecord>) -> Vec<AddrRecord> {
+    let mut out: Vec<AddrRecord> = Vec::with_capacity(records.len());
+    for mut record in records {
+        if let Ok(addr) = record.addr.parse::<Multiaddr>() {
+            record.addr = normalize(&addr);
+        }
+        if !out.iter().any(|r| r.addr == record.addr) {
+            out.push(record);
+        }
+    }
+    out
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredPeer {
+    peer_id: String,
+    addrs: Vec<AddrRecord>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredBook {
+    peers: Vec<StoredPeer>,
+}
+
+#[derive(Debug, Default)]
+pub struct AddressBook {
+    peers: HashMap<PeerId, Vec<AddrRecord>>,
+}
+
+impl AddressBook {
+    /// Load the book from `path`. A missing file yields an empty book; unparsable peer ids
+    /// are skipped. A book in another format version is refused, see
+    /// [`migrations`](crate::node::migrations).
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let bytes = match std::fs::read(path) {
+            Ok(b) => b,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e),
+        };
+        let stored: StoredBook = migrations::decode_json(Artifact::AddressBook, &bytes)?;
+        let peers = stored
+            .peers
+            .into_iter()
+            .filter_map(|p| Some((p.peer_id.parse().ok()?, normalized(p.addrs))))
+            .collect();
+        Ok(Self { peers })
+    }
+
+    /// Write the book to `path` via a temporary file so a crash never leaves it truncated.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let peers = self
+            .peers
+            .iter()
+            .map(|(peer_id, addrs)| StoredPeer { peer_id: peer_id.to_string(), addrs: addrs.clone() })
+            .collect();
+        let stored = migrations::with_header(Artifact::AddressBook, &StoredBook { peers })?;
+        let bytes = serde_json::to_vec_pretty(&stored).map_err(io::Error::other)?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, bytes)?;
+        std::fs::rename(&tmp, path)
+    }
+
+    fn entry(&mut self, peer_id: PeerId, addr: &Multiaddr) -> &mut AddrRecord {
+        let addrs = self.peers.entry(peer_id).or_default();
+        let addr = normalize(addr);
+        match addrs.iter().position(|r| r.addr == addr) {
+            Some(i) => &mut addrs[i],
+            None => {
+                addrs.push(AddrRecord { addr, last_seen_ms: 0, successes: 0, failures: 0 });
+                addrs.last_mut().expect("just pushed")
+            }
+        }
+    }
+
+    /// Record an address a peer advertised (e.g. via identify).
+    pub fn observe(&mut self, peer_id: PeerId, addr: &Multiaddr, now_ms: u64) {
+        self.entry(peer_id, addr).last_seen_ms = now_ms;
+    }
+
+    /// Record a successful connection to `peer_id` on `addr`.
+    pub fn record_success(&mut self, peer_id: PeerId, addr: &Multiaddr, now_ms: u64) {
+        let rec = self.entry(peer_id, addr);
+        rec.last_seen_ms = now_ms;
+        rec.successes = rec.successes.saturating_add(1);
+        rec.failures = 0;
+    }
+
+    /// Record a failed dial and return the address's consecutive failures. Only addresses
+    /// already in the book are tracked; others report 0.
+    pub fn record_failure(&mut self, peer_id: &PeerId, addr: &Multiaddr) -> u32 {
+        let addr = normalize(addr);
+        match self.peers.get_mut(peer_id).and_then(|a| a.iter_mut().find(|r| r.addr == addr)) {
+            Some(rec) => {
+                rec.failures = rec.failures.saturating_add(1);
+                rec.failures
+            }
+            None => 0,
+        }
+    }
+
+    /// Forget one address of a peer, and the peer once it has none left. Returns false if
+    /// the address was not in the book.
+    pub fn remove_address(&mut self, peer_id: &PeerId, addr: &Multiaddr) -> bool {
+        let addr = normalize(addr);
+        let Some(addrs) = self.peers.get_mut(peer_id) else {
+            return false;
+        };
+        let before = addrs.len();
+        addrs.retain(|r| r.addr != addr);
+        let removed = addrs.len() < before;
+        if addrs.is_empty() {
+            self.peers.remove(peer_id);
+        }
+        removed
+    }
+
+    /// Forget a peer and all its addresses. Returns false if it was not in the book.
+    pub fn remove_peer(&mut self, peer_id: &PeerId) -> bool {
+        self.peers.remove(peer_id).is_some()
+    }
+
+    /// Drop addresses not seen within `max_age` or that failed [`MAX_FAILURES`] times in a
+    /// row, and peers left without addresses. Returns the number of addresses removed.
+    pub fn prune(&mut self, now_ms: u64, max_age: Duration) -> usize {
+        let cutoff = now_ms.saturating_sub(max_age.as_millis() as u64);
+        let mut removed = 0;
+        self.peers.retain(|_, addrs| {
+            let before = addrs.len();
+            addrs.retain(|r| r.last_seen_ms >= cutoff && r.failures < MAX_FAILURES);
+            removed += before - addrs.len();
+            !addrs.is_empty()
+        });
+        removed
+    }
+
+    /// Addresses for `peer_id`, best first: fewest recent failures, then most recently seen.
+    pub fn addresses(&self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        let Some(addrs) = self.peers.get(peer_id) else {
+            return Vec::new();
+        };
+        let mut ranked: Vec<&AddrRecord> = addrs.iter().collect();
+        ranked.sort_by(|a, b| a.failures.cmp(&b.failures).then(b.last_seen_ms.cmp(&a.last_seen_ms)));
+        ranked.into_iter().filter_map(|r| r.addr.parse().ok()).collect()
+    }
+
+    /// What the book knows about `addr` of `peer_id`.
+    pub fn record(&self, peer_id: &PeerId, addr: &Multiaddr) -> Option<&AddrRecord> {
+        let addr = normalize(addr);
+        self.peers.get(peer_id)?.iter().find(|r| r.addr == addr)
+    }
+
+    /// Every known peer with its ranked addresses.
+    pub fn peers(&self) -> impl Iterator<Item = (PeerId, Vec<Multiaddr>)> + '_ {
+        self.peers.keys().map(|p| (*p, self.addresses(p)))
+    }
+
+    /// Up to `limit` peers to dial at startup, most recently seen first, each with its best address.
+    pub fn dial_candidates(&self, limit: usize) -> Vec<(PeerId, Multiaddr)> {
+        let mut candidates: Vec<(u64, PeerId, Multiaddr)> = self
+            .peers
+            .iter()
+            .filter_map(|(peer_id, addrs)| {
+                let last_seen = addrs.iter().map(|r| r.last_seen_ms).max()?;
+                Some((last_seen, *peer_id, self.addresses(peer_id).into_iter().next()?))
+            })
+            .collect();
+        candidates.sort_by(|a, b| b.0.cmp(&a.0));
+        candidates.into_iter().take(limit).map(|(_, p, a)| (p, a)).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+
+    fn addr(port: u16) -> Multiaddr {
+        format!("/ip4/127.0.0.1/tcp/{port}").parse().unwrap()
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let dir = std::env::temp_dir().join(format!("addrbook-test-{}", std::process::id()));
+        let path = dir.join("peers.json");
+        let peer = PeerId::random();
+        let mut book = AddressBook::default();
+        book.record_success(peer, &addr(4001), 10);
+        book.save(&path).unwrap();
+
+        let loaded = AddressBook::load(&path).unwrap();
+        assert_eq!(loaded.addresses(&peer), vec![addr(4001)]);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // Missing file is an empty book, not an error
+        assert!(AddressBook::load(&path).unwrap().is_empty());
+    }
+
+    #[test]
+    fn prune_drops_stale_and_failing_addresses() {
+        let peer = PeerId::random();
+        let stale_peer = PeerId::random();
+        let mut book = AddressBook::default();
+        let now = 30 * DAY_MS;
+        book.observe(peer, &addr(1), now);
+        book.observe(peer, &addr(2), now);
+        for _ in 0..MAX_FAILURES {
+            book.record_failure(&peer, &addr(2));
+        }
+        book.observe(stale_peer, &addr(3), now - 8 * DAY_MS);
+
+        assert_eq!(book.prune(now, DEFAULT_MAX_AGE), 2);
+        assert_eq!(book.addresses(&peer), vec![addr(1)]);
+        assert!(book.addresses(&stale_peer).is_empty());
+        assert_eq!(book.len(), 1);
+    }
+
+    #[test]
+    fn failures_demote_addresses_until_a_success() {
+        let peer = PeerId::random();
+        let mut book = AddressBook::default();
+        book.observe(peer, &addr(1), 100);
+        book.observe(peer, &addr(2), 50);
+        assert_eq!(book.addresses(&peer), vec![addr(1), addr(2)]);
+
+        book.record_failure(&peer, &addr(1));
+        assert_eq!(book.addresses(&peer), vec![addr(2), addr(1)]);
+
+        book.record_success(peer, &addr(1), 200);
+        assert_eq!(book.addresses(&peer), vec![addr(1), addr(2)]);
+        assert_eq!(book.dial_candidates(8), vec![(peer, addr(1))]);
+    }
+
+    #[test]
+    fn failures_reported_without_the_peer_id_demote_and_prune() {
+        let peer = PeerId::random();
+        let mut book = AddressBook::default();
+        // Identify reports addresses with the peer id, dial errors without it
+        book.observe(peer, &addr(1).with(Protocol::P2p(peer)), 100);
+        book.observe(peer, &addr(2), 50);
+        assert_eq!(book.addresses(&peer), vec![addr(1), addr(2)]);
+
+        assert_eq!(book.record_failure(&peer, &addr(1)), 1);
+        assert_eq!(book.addresses(&peer), vec![addr(2), addr(1)]);
+        for failures in 2..=MAX_FAILURES {
+            assert_eq!(book.record_failure(&peer, &addr(1).with(Protocol::P2p(peer))), failures);
+        }
+        assert_eq!(book.prune(100, DEFAULT_MAX_AGE), 1);
+        assert_eq!(book.addresses(&peer), vec![addr(2)]);
+    }
+
+    #[test]
+    fn counts_failures_and_removes_addresses() {
+        let peer = PeerId::random();
+        let mut book = AddressBook::default();
+        assert_eq!(book.record_failure(&peer, &addr(1)), 0);
+        book.observe(peer, &addr(1), 100);
+        book.observe(peer, &addr(2), 100);
+        assert_eq!(book.record_failure(&peer, &addr(1)), 1);
+        assert_eq!(book.record_failure(&peer, &addr(1)), 2);
+
+        assert!(book.remove_address(&peer, &addr(1)));
+        assert!(!book.remove_address(&peer, &addr(1)));
+        assert_eq!(book.addresses(&peer), vec![addr(2)]);
+        assert!(book.remove_address(&peer, &addr(2)));
+        assert!(book.is_empty());
+
+        book.observe(peer, &addr(3), 100);
+        assert!(book.remove_peer(&peer));
+        assert!(!book.remove_peer(&peer));
+    }
+}
@@ -0,0 +1,334 @@
+//! Multiaddr checks shared by the native and browser dial paths.
+
+use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
+
+use crate::Error;
+
+/// Returns true if `addr` names its host by DNS rather than by IP.
+pub fn is_dns(addr: &Multiaddr) -> bool {
+    addr.iter()
+        .any(|p| matches!(p, Protocol::Dns(_) | Protocol::Dns4(_) | Protocol::Dns6(_) | Protocol::Dnsaddr(_)))
+}
+
+/// Check whether a browser node can dial `addr`.
+///
+/// The browser resolves `/dns4` and `/dns6` itself for websocket addresses, but
+/// webrtc-direct needs a literal IP next to the certhash, and there is no way to resolve
+/// `/dnsaddr` TXT records from a page.
+pub fn check_browser_dialable(addr: &Multiaddr) -> Result<(), String> {
+    if !is_dns(addr) {
+        return Ok(());
+    }
+    if addr.iter().any(|p| matches!(p, Protocol::Dnsaddr(_))) {
+        return Err(format!("unsupported multiaddr {addr}: /dnsaddr cannot be resolved in the browser"));
+    }
+    if addr.iter().any(|p| matches!(p, Protocol::WebRTCDirect)) {
+        return Err(format!(
+            "unsupported multiaddr {addr}: webrtc-direct requires an IP address, not a DNS name"
+        ));
+    }
+    Ok(())
+}
+
+/// Whether a native node, which only has TCP (plus DNS), can dial `addr`.
+pub fn is_tcp_dialable(addr: &Multiaddr) -> bool {
+    let mut tcp = false;
+    for p in addr.iter() {
+        match p {
+            Protocol::Tcp(_) => tcp = true,
+            Protocol::Ws(_)
+            | Protocol::Wss(_)
+            | Protocol::P2pCircuit
+            | Protocol::WebRTC
+            | Protocol::WebRTCDirect
+            | Protocol::Quic
+            | Protocol::QuicV1
+            | Protocol::Memory(_) => return false,
+            _ => {}
+        }
+    }
+    tcp
+}
+
+/// Whether a browser node can dial `addr`: webrtc-direct, WebTransport, websockets or
+/// WebRTC through a relay circuit, subject to [`check_browser_dialable`].
+pub fn is_browser_dialable(addr: &Multiaddr) -> bool {
+    check_browser_dialable(addr).is_ok()
+        && addr.iter().any(|p| {
+            matches!(p, Protocol::WebRTCDirect | Protocol::WebRTC | Protocol::WebTransport | Protocol::Ws(_) | Protocol::Wss(_))
+        })
+}
+
+/// What [`validate_browser_addr`] found out about a multiaddr a user typed in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddrReport {
+    /// `None` if the string isn't a multiaddr at all.
+    pub addr: Option<Multiaddr>,
+    /// Protocol names in order, e.g. `["ip4", "udp", "webrtc-direct", "certhash", "p2p"]`.
+    pub protocols: Vec<&'static str>,
+    /// See [`peer_id_of`].
+    pub peer_id: Option<PeerId>,
+    /// See [`transport_name`].
+    pub transport: &'static str,
+    /// Why a browser can't dial it, most fundamental first. Empty if it can.
+    pub errors: Vec<String>,
+}
+
+impl AddrReport {
+    pub fn is_dialable(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    /// The parsed address if dialable, else the first error.
+    pub fn into_result(self) -> Result<Multiaddr, String> {
+        match (self.addr, self.errors.into_iter().next()) {
+            (Some(addr), None) => Ok(addr),
+            (_, Some(error)) => Err(error),
+            (None, None) => unreachable!("unparsed addresses always carry an error"),
+        }
+    }
+}
+
+/// Check a multiaddr string the way a browser node will use it, naming each problem
+/// rather than leaving a dial to fail or hang: no transport a page can open,
+/// webrtc-direct or WebTransport without the `/certhash` and `/p2p` they need, relay
+/// circuits missing a peer id, and the DNS limits of [`check_browser_dialable`].
+pub fn validate_browser_addr(input: &str) -> AddrReport {
+    let addr: Multiaddr = match input.trim().parse() {
+        Ok(addr) => addr,
+        Err(e) => {
+            return AddrReport {
+                addr: None,
+                protocols: Vec::new(),
+                peer_id: None,
+                transport: "unknown",
+                errors: vec![format!("invalid multiaddr {input:?}: {e}")],
+            }
+        }
+    };
+    let protocols: Vec<&'static str> = addr.iter().map(|p| p.tag()).collect();
+    let has = |tag: &str| protocols.contains(&tag);
+    let mut errors = Vec::new();
+    if let Err(e) = check_browser_dialable(&addr) {
+        errors.push(e);
+    }
+    let browser_transport = ["webrtc-direct", "webrtc", "webtransport", "ws", "wss"].iter().any(|t| has(t));
+    if !browser_transport && !has("dnsaddr") {
+        errors.push(format!(
+            "unsupported multiaddr {addr}: browsers can only dial /ws, /wss, /webrtc-direct, /webtransport or /p2p-circuit/webrtc"
+        ));
+    }
+    for transport in ["webrtc-direct", "webtransport"] {
+        if !has(transport) || has("p2p-circuit") {
+            continue;
+        }
+        if !has("certhash") {
+            errors.push(format!("{addr} has no /certhash: {transport} can't verify the server's certificate without it"));
+        }
+        if !has("p2p") {
+            errors.push(format!("{addr} has no /p2p/ peer id, which {transport} needs to authenticate the server"));
+        }
+    }
+    if let Some(circuit) = protocols.iter().position(|p| *p == "p2p-circuit") {
+        if !protocols[..circuit].contains(&"p2p") {
+            errors.push(format!("{addr} has no /p2p/ peer id for the relay before /p2p-circuit"));
+        }
+        if !protocols[circuit..].contains(&"p2p") {
+            errors.push(format!("{addr} has no /p2p/ peer id for the peer behind the relay"));
+        }
+    }
+    AddrReport { peer_id: peer_id_of(&addr), transport: transport_name(&addr), protocols, errors, addr: Some(addr) }
+}
+
+/// The peer `addr` leads to: its last `/p2p` component, so for relay circuits the peer
+/// behind the relay.
+pub fn peer_id_of(addr: &Multiaddr) -> Option<PeerId> {
+    addr.iter()
+        .filter_map(|p| match p {
+            Protocol::P2p(peer_id) => Some(peer_id),
+            _ => None,
+        })
+        .last()
+}
+
+/// The relay a circuit address goes through, `None` for direct addresses.
+pub fn relay_of(addr: &Multiaddr) -> Option<PeerId> {
+    let mut relay = None;
+    for p in addr.iter() {
+        match p {
+            Protocol::P2p(peer_id) => relay = Some(peer_id),
+            Protocol::P2pCircuit => return relay,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Refuse to dial `addr` if it leads to `local`: a browser's own circuit address comes
+/// back in DHT results, and dialing it can only fail with a peer id mismatch.
+pub fn check_not_self(local: &PeerId, addr: &Multiaddr) -> Result<(), Error> {
+    match peer_id_of(addr) {
+        Some(peer_id) if peer_id == *local => Err(Error::SelfDial { peer_id }),
+        _ => Ok(()),
+    }
+}
+
+/// Whether `addr` may be recorded as an address of `peer_id`, in Kademlia or the
+/// address book: not if either is us.
+pub fn is_peer_addr(local: &PeerId, peer_id: &PeerId, addr: &Multiaddr) -> bool {
+    peer_id != local && peer_id_of(addr).is_none_or(|p| p == *peer_id)
+}
+
+/// `peers` without `local`, for closest peer and provider results, which include us when
+/// a peer knows our address.
+pub fn without_self<T>(local: &PeerId, peers: impl IntoIterator<Item = T>, peer_id: impl Fn(&T) -> PeerId) -> Vec<T> {
+    peers.into_iter().filter(|p| peer_id(p) != *local).collect()
+}
+
+/// Short name of the transport a connection to `addr` runs over, for connection events.
+/// Relayed connections report `relay` unless upgraded to direct WebRTC (`webrtc`).
+pub fn transport_name(addr: &Multiaddr) -> &'static str {
+    let (mut circuit, mut name) = (false, "unknown");
+    for p in addr.iter() {
+        match p {
+            Protocol::P2pCircuit => circuit = true,
+            Protocol::WebRTC => return "webrtc",
+            Protocol::WebRTCDirect => name = "webrtc-direct",
+            Protocol::WebTransport => name = "webtransport",
+            Protocol::Ws(_) | Protocol::Wss(_) => name = "websocket",
+            Protocol::QuicV1 | Protocol::Quic if name == "unknown" => name = "quic",
+            Protocol::Tcp(_) if name == "unknown" => name = "tcp",
+            Protocol::Memory(_) => name = "memory",
+            _ => {}
+        }
+    }
+    if circuit {
+        "relay"
+    } else {
+        name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn our_own_id_is_filtered_and_never_dialed() {
+        let (local, other, relay) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let own_circuit: Multiaddr = format!("/ip4/10.0.0.1/tcp/4001/p2p/{relay}/p2p-circuit/p2p/{local}").parse().unwrap();
+        let theirs: Multiaddr = format!("/ip4/10.0.0.2/tcp/4001/p2p/{other}").parse().unwrap();
+
+        // A closest peers result that found us through the relay
+        let closest = vec![(other, vec![theirs.clone()]), (local, vec![own_circuit.clone()])];
+        assert_eq!(without_self(&local, closest, |(peer_id, _)| *peer_id), [(other, vec![theirs.clone()])]);
+        assert_eq!(without_self(&local, [local, other, relay], |p| *p), [other, relay]);
+
+        assert!(matches!(check_not_self(&local, &own_circuit), Err(Error::SelfDial { peer_id }) if peer_id == local));
+        assert!(check_not_self(&local, &theirs).is_ok());
+        // Reaching us through the relay is not dialing the relay
+        assert!(check_not_self(&relay, &own_circuit).is_ok());
+
+        assert!(is_peer_addr(&local, &other, &theirs));
+        assert!(!is_peer_addr(&local, &local, &theirs), "our own entry");
+        assert!(!is_peer_addr(&local, &other, &own_circuit), "our address reported by another peer");
+        assert!(is_peer_addr(&local, &other, &"/ip4/10.0.0.2/tcp/4001".parse().unwrap()));
+    }
+
+    #[test]
+    fn websocket_dns_is_dialable_in_the_browser() {
+        let addr: Multiaddr = "/dns4/relay.example.com/tcp/443/wss".parse().unwrap();
+        assert!(is_dns(&addr));
+        assert!(check_browser_dialable(&addr).is_ok());
+    }
+
+    #[test]
+    fn webrtc_direct_and_dnsaddr_need_an_ip() {
+        let direct: Multiaddr = "/dns4/relay.example.com/udp/9090/webrtc-direct".parse().unwrap();
+        assert!(check_browser_dialable(&direct).is_err());
+        let dnsaddr: Multiaddr = "/dnsaddr/bootstrap.example.com".parse().unwrap();
+        assert!(check_browser_dialable(&dnsaddr).is_err());
+        let ip: Multiaddr = "/ip4/127.0.0.1/udp/9090/webrtc-direct".parse().unwrap();
+        assert!(check_browser_dialable(&ip).is_ok());
+    }
+
+    #[test]
+    fn dialability_depends_on_the_transport() {
+        let tcp: Multiaddr = "/ip4/10.0.0.1/tcp/4001".parse().unwrap();
+        let direct: Multiaddr = "/ip4/10.0.0.1/udp/9090/webrtc-direct".parse().unwrap();
+        let circuit: Multiaddr = "/ip4/10.0.0.1/tcp/4001/p2p-circuit/webrtc".parse().unwrap();
+        assert!(is_tcp_dialable(&tcp));
+        assert!(!is_tcp_dialable(&direct));
+        assert!(!is_tcp_dialable(&circuit));
+        assert!(!is_browser_dialable(&tcp));
+        assert!(is_browser_dialable(&direct));
+        assert!(is_browser_dialable(&circuit));
+        let webtransport: Multiaddr = "/dns4/relay.example.com/udp/443/quic-v1/webtransport".parse().unwrap();
+        assert!(is_browser_dialable(&webtransport));
+        assert!(!is_tcp_dialable(&webtransport));
+    }
+
+    #[test]
+    fn reports_why_a_browser_cannot_dial() {
+        let peer = PeerId::random();
+        let relay = PeerId::random();
+        let certhash = "uEiDDq4_xNyDorZBH3TlGazyJdOWSwvo4PUo5YHFMrvDE8g";
+        // (address, dialable, transport, an expected error fragment)
+        let table: Vec<(String, bool, &str, &str)> = vec![
+            (format!("/ip4/127.0.0.1/tcp/9091/ws/p2p/{peer}"), true, "websocket", ""),
+            ("/dns4/relay.example.com/tcp/443/wss".into(), true, "websocket", ""),
+            (format!("/ip4/10.0.0.1/udp/9090/webrtc-direct/certhash/{certhash}/p2p/{peer}"), true, "webrtc-direct", ""),
+            (format!("/ip4/10.0.0.1/udp/443/quic-v1/webtransport/certhash/{certhash}/p2p/{peer}"), true, "webtransport", ""),
+            (format!("/ip4/10.0.0.1/tcp/443/wss/p2p/{relay}/p2p-circuit/webrtc/p2p/{peer}"), true, "webrtc", ""),
+            ("not a multiaddr".into(), false, "unknown", "invalid multiaddr"),
+            ("/ip4/300.0.0.1/tcp/1".into(), false, "unknown", "invalid multiaddr"),
+            (format!("/ip4/10.0.0.1/tcp/4001/p2p/{peer}"), false, "tcp", "browsers can only dial"),
+            ("/ip4/10.0.0.1/udp/4001/quic-v1".into(), false, "quic", "browsers can only dial"),
+            (format!("/ip4/10.0.0.1/udp/9090/webrtc-direct/p2p/{peer}"), false, "webrtc-direct", "no /certhash"),
+            (format!("/ip4/10.0.0.1/udp/9090/webrtc-direct/certhash/{certhash}"), false, "webrtc-direct", "no /p2p/ peer id"),
+            (format!("/dns4/relay.example.com/udp/9090/webrtc-direct/certhash/{certhash}/p2p/{peer}"), false, "webrtc-direct", "requires an IP"),
+            ("/dnsaddr/bootstrap.example.com".into(), false, "unknown", "/dnsaddr cannot be resolved"),
+            (format!("/ip4/10.0.0.1/tcp/443/wss/p2p-circuit/webrtc/p2p/{peer}"), false, "webrtc", "for the relay"),
+            (format!("/ip4/10.0.0.1/tcp/443/wss/p2p/{relay}/p2p-circuit/webrtc"), false, "webrtc", "behind the relay"),
+        ];
+        for (addr, dialable, transport, error) in table {
+            let report = validate_browser_addr(&addr);
+            assert_eq!(report.is_dialable(), dialable, "{addr}: {:?}", report.errors);
+            assert_eq!(report.transport, transport, "{addr}");
+            assert!(error.is_empty() || report.errors.iter().any(|e| e.contains(error)), "{addr}: {:?}", report.errors);
+        }
+
+        let report = validate_browser_addr(&format!(" /ip4/127.0.0.1/tcp/9091/ws/p2p/{peer} "));
+        assert_eq!(report.protocols, ["ip4", "tcp", "ws", "p2p"]);
+        assert_eq!(report.peer_id, Some(peer));
+        assert!(report.into_result().is_ok());
+        let err = validate_browser_addr("/ip4/10.0.0.1/tcp/4001").into_result().unwrap_err();
+        assert!(err.contains("browsers can only dial"), "{err}");
+    }
+
+    #[test]
+    fn names_the_transport_of_a_connection() {
+        let relay = format!("p2p/{}", PeerId::random());
+        for (addr, expected) in [
+            ("/ip4/10.0.0.1/tcp/4001".to_string(), "tcp"),
+            (format!("/ip6/::1/tcp/4001/{relay}"), "tcp"),
+            ("/ip4/10.0.0.1/udp/4001/quic-v1".to_string(), "quic"),
+            ("/ip4/10.0.0.1/udp/443/quic-v1/webtransport".to_string(), "webtransport"),
+            ("/ip4/10.0.0.1/udp/9090/webrtc-direct".to_string(), "webrtc-direct"),
+            ("/ip4/10.0.0.1/tcp/443/wss".to_string(), "websocket"),
+            ("/dns4/relay.example.com/tcp/80/ws".to_string(), "websocket"),
+            ("/memory/1234".to_string(), "memory"),
+            ("/ip4/10.0.0.1/udp/9090".to_string(), "unknown"),
+            // Circuits are reported as such whatever carries them to the relay
+            (format!("/ip4/10.0.0.1/tcp/4001/{relay}/p2p-circuit"), "relay"),
+            (format!("/ip4/10.0.0.1/udp/4001/quic-v1/{relay}/p2p-circuit"), "relay"),
+            (format!("/ip4/10.0.0.1/tcp/443/wss/{relay}/p2p-circuit"), "relay"),
+            (format!("/ip4/10.0.0.1/udp/9090/webrtc-direct/{relay}/p2p-circuit"), "relay"),
+            // ... unless upgraded to a direct WebRTC connection
+            (format!("/ip4/10.0.0.1/udp/9090/webrtc-direct/{relay}/p2p-circuit/webrtc"), "webrtc"),
+            (format!("/ip4/10.0.0.1/tcp/4001/{relay}/p2p-circuit/webrtc"), "webrtc"),
+        ] {
+            assert_eq!(transport_name(&addr.parse().unwrap()), expected, "{addr}");
+        }
+    }
+}
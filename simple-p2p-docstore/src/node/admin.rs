@@ -0,0 +1,527 @@
+//! Admin control channel for a running node: newline-delimited JSON-RPC 2.0 over a Unix
+//! domain socket, or over a localhost TCP port where Unix sockets are not available.
+//!
+//! The Unix socket is created with mode 0600, so access is governed by filesystem
+//! permissions; the TCP fallback is reachable by every local user and must be opted into.
+//!
+//! Requests are one JSON object per line, for example
+//! `{"jsonrpc":"2.0","id":1,"method":"block","params":{"peer_id":"12D3...","secs":3600}}`.
+//! Each request is parsed into an [`AdminCommand`] and handed to the node's event loop as
+//! an [`AdminCall`]; the loop answers through the call's reply channel.
+
+use std::io;
+use std::net::Ipv4Addr;
+#[cfg(unix)]
+use std::path::Path;
+use std::time::Duration;
+
+use futures::channel::{mpsc, oneshot};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+
+use crate::behaviour::{AnnouncementKind, Severity};
+use crate::node::partition::PartitionStatus;
+use crate::node::reload::ReloadOutcome;
+use crate::node::replica::ReplicationStatus;
+use crate::node::ScrubReport;
+use crate::store::author_quota::AuthorQuota;
+use crate::store::quota::RoomQuota;
+
+/// Ban length for `block` when no `secs` param is given.
+pub const DEFAULT_BLOCK_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Method names accepted by [`AdminCommand::parse`].
+pub const METHODS: &[&str] = &[
+    "peers",
+    "reservations",
+    "publish",
+    "announce",
+    "bootstrap",
+    "block",
+    "limits",
+    "duplicates",
+    "requests",
+    "dht-store",
+    "verify",
+    "quotas",
+    "set-quota",
+    "author-quotas",
+    "set-author-quota",
+    "replication",
+    "partition",
+    "expect-peer",
+    "remove-expected-peer",
+    "reload",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdminCommand {
+    /// List connected peers.
+    Peers,
+    /// List peers holding a relay reservation with us.
+    Reservations,
+    /// Publish `data` on the docstore topic.
+    Publish { data: String },
+    /// Sign `text` with the node's key and publish it on the announce topic.
+    Announce { kind: AnnouncementKind, severity: Severity, text: String, expires_in: Option<Duration> },
+    /// Start a Kademlia bootstrap.
+    Bootstrap,
+    /// Disconnect and ban a peer.
+    Block { peer_id: PeerId, duration: Duration },
+    /// Counters of inbound connections denied by the per-IP limits.
+    Limits,
+    /// Duplicate message counters, and the sources sending the most duplicates.
+    Duplicates,
+    /// Counters per protocol of the requests we answered, and the most recent ones.
+    Requests,
+    /// What the Kademlia record store holds, and the puts it refused per limit.
+    DhtStore,
+    /// Check every stored document now, see [`Node::verify_now`](crate::node::Node::verify_now).
+    Verify,
+    /// Each room's store usage against its quota, see
+    /// [`Node::room_usage`](crate::node::Node::room_usage).
+    Quotas,
+    /// Set `room`'s quota, or lift it when no limit is given.
+    SetQuota { room: String, quota: Option<RoomQuota> },
+    /// Each author's store usage against their quota, see
+    /// [`Node::author_usage`](crate::node::Node::author_usage).
+    AuthorQuotas,
+    /// Set `peer_id`'s quota, or the default one without a peer; no limit lifts it.
+    SetAuthorQuota { peer_id: Option<PeerId>, quota: Option<AuthorQuota> },
+    /// Whether this is a read replica and how far behind the newest update it is, see
+    /// [`Node::replication_status`](crate::node::Node::replication_status).
+    Replication,
+    /// The expected relays and FullNodes, which are unreachable, and the suspected
+    /// partition if any, see [`crate::node::partition`].
+    Partition,
+    /// Expect `peer_id` to stay reachable.
+    ExpectPeer { peer_id: PeerId },
+    /// Stop expecting `peer_id`, e.g. because it was decommissioned.
+    RemoveExpectedPeer { peer_id: PeerId },
+    /// Read the configuration file again and apply what can be changed while running,
+    /// see [`crate::node::reload`].
+    Reload,
+}
+
+impl AdminCommand {
+    pub fn parse(method: &str, params: &Value) -> Result<Self, RpcError> {
+        let str_param = |name: &str| {
+            params
+                .get(name)
+                .and_then(Value::as_str)
+                .ok_or_else(|| RpcError::invalid_params(format!("{method} needs a string `{name}` param")))
+        };
+        match method {
+            "peers" => Ok(Self::Peers),
+            "reservations" => Ok(Self::Reservations),
+            "bootstrap" => Ok(Self::Bootstrap),
+            "limits" => Ok(Self::Limits),
+            "duplicates" => Ok(Self::Duplicates),
+            "requests" => Ok(Self::Requests),
+            "dht-store" => Ok(Self::DhtStore),
+            "verify" => Ok(Self::Verify),
+            "quotas" => Ok(Self::Quotas),
+            "author-quotas" => Ok(Self::AuthorQuotas),
+            "replication" => Ok(Self::Replication),
+            "partition" => Ok(Self::Partition),
+            "reload" => Ok(Self::Reload),
+            "expect-peer" | "remove-expected-peer" => {
+                let peer_id = str_param("peer_id")?
+                    .parse()
+                    .map_err(|e| RpcError::invalid_params(format!("invalid peer_id: {e}")))?;
+                Ok(if method == "expect-peer" { Self::ExpectPeer { peer_id } } else { Self::RemoveExpectedPeer { peer_id } })
+            }
+            "set-quota" => {
+                let room = str_param("room")?.to_string();
+                let limit = |name: &str| params.get(name).and_then(Value::as_u64);
+                let quota = RoomQuota {
+                    max_bytes: limit("max_bytes"),
+                    max_docs: limit("max_docs"),
+                    max_age_secs: limit("max_age_secs"),
+                };
+                Ok(Self::SetQuota { room, quota: (quota != RoomQuota::default()).then_some(quota) })
+            }
+            "set-author-quota" => {
+                let peer_id = match params.get("peer_id").and_then(Value::as_str) {
+                    Some(peer_id) => {
+                        Some(peer_id.parse().map_err(|e| RpcError::invalid_params(format!("invalid peer_id: {e}")))?)
+                    }
+                    None => None,
+                };
+                let limit = |name: &str| params.get(name).and_then(Value::as_u64);
+                let quota = AuthorQuota {
+                    max_bytes: limit("max_bytes"),
+                    max_docs: limit("max_docs"),
+                    max_updates_per_minute: limit("max_updates_per_minute"),
+                };
+                Ok(Self::SetAuthorQuota { peer_id, quota: (quota != AuthorQuota::default()).then_some(quota) })
+            }
+            "publish" => Ok(Self::Publish { data: str_param("data")?.to_string() }),
+            "announce" => {
+                let text = str_param("text")?.to_string();
+                let kind = match params.get("type").and_then(Value::as_str) {
+                    Some(kind) => kind.parse().map_err(RpcError::invalid_params)?,
+                    None => AnnouncementKind::default(),
+                };
+                let severity = match params.get("severity").and_then(Value::as_str) {
+                    Some(severity) => severity.parse().map_err(RpcError::invalid_params)?,
+                    None => Severity::default(),
+                };
+                let expires_in = params.get("expires_secs").and_then(Value::as_u64).map(Duration::from_secs);
+                Ok(Self::Announce { kind, severity, text, expires_in })
+            }
+            "block" => {
+                let peer_id = str_param("peer_id")?
+                    .parse()
+                    .map_err(|e| RpcError::invalid_params(format!("invalid peer_id: {e}")))?;
+                let duration = params.get("secs").and_then(Value::as_u64).map_or(DEFAULT_BLOCK_DURATION, Duration::from_secs);
+                Ok(Self::Block { peer_id, duration })
+            }
+            other => Err(RpcError { code: RpcError::METHOD_NOT_FOUND, message: format!("unknown method '{other}'") }),
+        }
+    }
+}
+
+/// The result of `verify`, from a [`ScrubReport`].
+pub fn verify_result(report: &ScrubReport) -> Value {
+    let corrupted: Vec<Value> = report
+        .corrupted
+        .iter()
+        .map(|(doc_id, corruption)| json!({ "doc_id": doc_id, "reason": corruption.to_string() }))
+        .collect();
+    json!({ "verified": report.verified, "corrupted": corrupted })
+}
+
+/// The result of `replication`, from a [`ReplicationStatus`].
+pub fn replication_result(status: &ReplicationStatus) -> Value {
+    json!({ "replica": status.replica, "newest_update_ms": status.newest_update_ms, "lag_ms": status.lag_ms })
+}
+
+/// The result of `partition`, from a [`PartitionStatus`].
+pub fn partition_result(status: &PartitionStatus) -> Value {
+    let expected: Vec<Value> = status
+        .expected
+        .iter()
+        .map(|(peer_id, since)| json!({ "peer_id": peer_id.to_string(), "unreachable_since_ms": since }))
+        .collect();
+    let partition = status.partition.as_ref().map(|partition| {
+        json!({
+            "missing_peers": partition.missing_peers.iter().map(PeerId::to_string).collect::<Vec<_>>(),
+            "since_ms": partition.since_ms,
+        })
+    });
+    json!({ "expected": expected, "partition": partition })
+}
+
+/// The result of `reload`, from a [`ReloadOutcome`].
+pub fn reload_result(outcome: &ReloadOutcome) -> Value {
+    let rejected: Vec<Value> =
+        outcome.rejected.iter().map(|rejected| json!({ "key": rejected.key, "reason": rejected.reason })).collect();
+    json!({ "applied": outcome.applied, "rejected": rejected })
+}
+
+/// A parsed admin request on its way to the event loop.
+#[derive(Debug)]
+pub struct AdminCall {
+    pub command: AdminCommand,
+    /// `Err` carries a human-readable reason, returned to the caller as a JSON-RPC error.
+    pub reply: oneshot::Sender<Result<Value, String>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    pub const PARSE_ERROR: i64 = -32700;
+    pub const METHOD_NOT_FOUND: i64 = -32601;
+    pub const INVALID_PARAMS: i64 = -32602;
+    /// The event loop refused the command or has stopped.
+    pub const SERVER_ERROR: i64 = -32000;
+
+    fn invalid_params(message: String) -> Self {
+        Self { code: Self::INVALID_PARAMS, message }
+    }
+
+    fn server(message: impl Into<String>) -> Self {
+        Self { code: Self::SERVER_ERROR, message: message.into() }
+    }
+}
+
+#[derive(Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Response {
+    jsonrpc: String,
+    id: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+}
+
+impl Response {
+    fn new(id: Value, outcome: Result<Value, RpcError>) -> Self {
+        let (result, error) = match outcome {
+            Ok(v) => (Some(v), None),
+            Err(e) => (None, Some(e)),
+        };
+        Self { jsonrpc: "2.0".into(), id, result, error }
+    }
+}
+
+/// Bind the admin socket at `path` with mode 0600. A stale socket left by a previous run
+/// is replaced; any other existing file is an error.
+#[cfg(unix)]
+pub fn bind_unix(path: &Path) -> io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let listener = tokio::net::UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// Accept admin connections until the process exits.
+#[cfg(unix)]
+pub async fn serve_unix(listener: tokio::net::UnixListener, calls: mpsc::UnboundedSender<AdminCall>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tracing::info!("Admin client connected");
+                tokio::spawn(handle_connection(stream, calls.clone()));
+            }
+            Err(e) => tracing::warn!("Admin socket accept failed: {}", e),
+        }
+    }
+}
+
+/// Bind the TCP fallback on 127.0.0.1.
+pub async fn bind_tcp(port: u16) -> io::Result<TcpListener> {
+    TcpListener::bind((Ipv4Addr::LOCALHOST, port)).await
+}
+
+pub async fn serve_tcp(listener: TcpListener, calls: mpsc::UnboundedSender<AdminCall>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                tracing::info!("Admin client connected from {}", addr);
+                tokio::spawn(handle_connection(stream, calls.clone()));
+            }
+            Err(e) => tracing::warn!("Admin listener accept failed: {}", e),
+        }
+    }
+}
+
+async fn handle_connection<S>(stream: S, calls: mpsc::UnboundedSender<AdminCall>)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (read, mut write) = tokio::io::split(stream);
+    let mut lines = BufReader::new(read).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = handle_line(&line, &calls).await;
+        let mut out = serde_json::to_vec(&response).expect("admin response serialization cannot fail");
+        out.push(b'\n');
+        if write.write_all(&out).await.is_err() {
+            break;
+        }
+    }
+}
+
+async fn handle_line(line: &str, calls: &mpsc::UnboundedSender<AdminCall>) -> Response {
+    let request: Request = match serde_json::from_str(line) {
+        Ok(r) => r,
+        Err(e) => {
+            tracing::warn!("Malformed admin request: {}", e);
+            return Response::new(Value::Null, Err(RpcError { code: RpcError::PARSE_ERROR, message: e.to_string() }));
+        }
+    };
+    let command = match AdminCommand::parse(&request.method, &request.params) {
+        Ok(c) => c,
+        Err(e) => {
+            tracing::warn!("Refused admin request '{}': {}", request.method, e.message);
+            return Response::new(request.id, Err(e));
+        }
+    };
+    tracing::info!("Admin command: {:?}", command);
+    let (reply, rx) = oneshot::channel();
+    if calls.unbounded_send(AdminCall { command, reply }).is_err() {
+        return Response::new(request.id, Err(RpcError::server("node stopped")));
+    }
+    let outcome = match rx.await {
+        Ok(Ok(v)) => Ok(v),
+        Ok(Err(msg)) => Err(RpcError::server(msg)),
+        Err(_) => Err(RpcError::server("node stopped")),
+    };
+    if let Err(e) = &outcome {
+        tracing::warn!("Admin command failed: {}", e.message);
+    }
+    Response::new(request.id, outcome)
+}
+
+/// Send one request to the admin socket at `path` and return its result.
+#[cfg(unix)]
+pub async fn call_unix(path: &Path, method: &str, params: Value) -> anyhow::Result<Value> {
+    let stream = tokio::net::UnixStream::connect(path).await?;
+    call(stream, method, params).await
+}
+
+pub async fn call_tcp(port: u16, method: &str, params: Value) -> anyhow::Result<Value> {
+    let stream = tokio::net::TcpStream::connect((Ipv4Addr::LOCALHOST, port)).await?;
+    call(stream, method, params).await
+}
+
+async fn call<S>(stream: S, method: &str, params: Value) -> anyhow::Result<Value>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (read, mut write) = tokio::io::split(stream);
+    let mut request = serde_json::to_vec(&json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params }))?;
+    request.push(b'\n');
+    write.write_all(&request).await?;
+
+    let line = BufReader::new(read)
+        .lines()
+        .next_line()
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("admin socket closed without a response"))?;
+    let response: Response = serde_json::from_str(&line)?;
+    match (response.result, response.error) {
+        (_, Some(e)) => anyhow::bail!("{} (code {})", e.message, e.code),
+        (Some(v), None) => Ok(v),
+        (None, None) => Ok(Value::Null),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    #[test]
+    fn parses_commands_and_rejects_bad_params() {
+        let peer = PeerId::random();
+        assert_eq!(AdminCommand::parse("peers", &Value::Null), Ok(AdminCommand::Peers));
+        assert_eq!(AdminCommand::parse("limits", &Value::Null), Ok(AdminCommand::Limits));
+        assert_eq!(AdminCommand::parse("duplicates", &Value::Null), Ok(AdminCommand::Duplicates));
+        assert_eq!(AdminCommand::parse("requests", &Value::Null), Ok(AdminCommand::Requests));
+        assert_eq!(AdminCommand::parse("dht-store", &Value::Null), Ok(AdminCommand::DhtStore));
+        assert_eq!(AdminCommand::parse("verify", &Value::Null), Ok(AdminCommand::Verify));
+        assert_eq!(AdminCommand::parse("quotas", &Value::Null), Ok(AdminCommand::Quotas));
+        assert_eq!(
+            AdminCommand::parse("set-quota", &json!({ "room": "team", "max_docs": 100 })),
+            Ok(AdminCommand::SetQuota {
+                room: "team".to_string(),
+                quota: Some(RoomQuota { max_docs: Some(100), ..Default::default() }),
+            })
+        );
+        assert_eq!(
+            AdminCommand::parse("set-quota", &json!({ "room": "team" })),
+            Ok(AdminCommand::SetQuota { room: "team".to_string(), quota: None })
+        );
+        assert_eq!(AdminCommand::parse("set-quota", &json!({})).unwrap_err().code, RpcError::INVALID_PARAMS);
+        assert_eq!(AdminCommand::parse("author-quotas", &Value::Null), Ok(AdminCommand::AuthorQuotas));
+        assert_eq!(AdminCommand::parse("replication", &Value::Null), Ok(AdminCommand::Replication));
+        assert_eq!(
+            AdminCommand::parse("set-author-quota", &json!({ "peer_id": peer.to_string(), "max_bytes": 1000 })),
+            Ok(AdminCommand::SetAuthorQuota {
+                peer_id: Some(peer),
+                quota: Some(AuthorQuota { max_bytes: Some(1000), ..Default::default() }),
+            })
+        );
+        assert_eq!(
+            AdminCommand::parse("set-author-quota", &json!({ "max_updates_per_minute": 60 })),
+            Ok(AdminCommand::SetAuthorQuota {
+                peer_id: None,
+                quota: Some(AuthorQuota { max_updates_per_minute: Some(60), ..Default::default() }),
+            })
+        );
+        assert_eq!(
+            AdminCommand::parse("set-author-quota", &json!({ "peer_id": "nope" })).unwrap_err().code,
+            RpcError::INVALID_PARAMS
+        );
+        assert_eq!(
+            AdminCommand::parse("block", &json!({ "peer_id": peer.to_string(), "secs": 60 })),
+            Ok(AdminCommand::Block { peer_id: peer, duration: Duration::from_secs(60) })
+        );
+        assert_eq!(AdminCommand::parse("partition", &Value::Null), Ok(AdminCommand::Partition));
+        assert_eq!(AdminCommand::parse("reload", &Value::Null), Ok(AdminCommand::Reload));
+        assert_eq!(
+            AdminCommand::parse("remove-expected-peer", &json!({ "peer_id": peer.to_string() })),
+            Ok(AdminCommand::RemoveExpectedPeer { peer_id: peer })
+        );
+        assert_eq!(
+            AdminCommand::parse("expect-peer", &json!({ "peer_id": "nope" })).unwrap_err().code,
+            RpcError::INVALID_PARAMS
+        );
+        assert_eq!(AdminCommand::parse("publish", &json!({})).unwrap_err().code, RpcError::INVALID_PARAMS);
+        assert_eq!(
+            AdminCommand::parse("announce", &json!({ "text": "maintenance at 22:00", "type": "maintenance", "expires_secs": 600 })),
+            Ok(AdminCommand::Announce {
+                kind: AnnouncementKind::Maintenance,
+                severity: Severity::Info,
+                text: "maintenance at 22:00".to_string(),
+                expires_in: Some(Duration::from_secs(600)),
+            })
+        );
+        assert_eq!(
+            AdminCommand::parse("announce", &json!({ "text": "hi", "severity": "dire" })).unwrap_err().code,
+            RpcError::INVALID_PARAMS
+        );
+        assert_eq!(AdminCommand::parse("reboot", &Value::Null).unwrap_err().code, RpcError::METHOD_NOT_FOUND);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn socket_round_trip_with_owner_only_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("admin-{}.sock", std::process::id()));
+        let listener = bind_unix(&path).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        let (tx, mut rx) = mpsc::unbounded();
+        tokio::spawn(serve_unix(listener, tx));
+        // Stand-in for the event loop
+        tokio::spawn(async move {
+            while let Some(call) = rx.next().await {
+                let reply = match call.command {
+                    AdminCommand::Peers => Ok(json!(["peer-a"])),
+                    _ => Err("not supported".to_string()),
+                };
+                let _ = call.reply.send(reply);
+            }
+        });
+
+        assert_eq!(call_unix(&path, "peers", Value::Null).await.unwrap(), json!(["peer-a"]));
+        let err = call_unix(&path, "bootstrap", Value::Null).await.unwrap_err();
+        assert!(err.to_string().contains("not supported"));
+        let _ = std::fs::remove_file(&path);
+    }
+}
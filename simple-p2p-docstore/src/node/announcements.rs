@@ -0,0 +1,215 @@
+//! Everything this node announced in the DHT, so all of it can be announced again after
+//! an outage, shared by the native and wasm event loops.
+//!
+//! While we are offline nothing refreshes our provider records and records elsewhere, and
+//! after a long enough outage they have expired everywhere without anyone noticing. Once a
+//! reconnected peer has identified itself (so Kademlia has somewhere to send queries) after
+//! more than [`DhtAnnouncements::reannounce_after`] offline, the event loop takes the
+//! [`Announcement`]s from here, issues them again and reports one [`RenewalSummary`] when
+//! the last query settles. Whatever the app withdrew (an unpinned document, a forgotten
+//! record) is dropped from the registry and never re-issued.
+
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::time::Duration;
+
+use libp2p_kad::RecordKey;
+use web_time::Instant;
+
+/// How long we must have been without connections before everything is announced again.
+pub const DEFAULT_REANNOUNCE_AFTER: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Announcement {
+    /// We provide `key`: a pinned document or the relay service.
+    Provider(RecordKey),
+    /// A record we put, with its TTL if it has one.
+    Record { key: RecordKey, value: Vec<u8>, ttl: Option<Duration> },
+}
+
+impl Announcement {
+    pub fn key(&self) -> &RecordKey {
+        match self {
+            Announcement::Provider(key) | Announcement::Record { key, .. } => key,
+        }
+    }
+}
+
+/// How one renewal pass went.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RenewalSummary {
+    pub renewed: usize,
+    pub failed: Vec<RecordKey>,
+}
+
+/// The registry, and the renewal pass in flight if any. `Q` identifies a query.
+#[derive(Debug)]
+pub struct DhtAnnouncements<Q> {
+    providers: HashSet<RecordKey>,
+    records: HashMap<RecordKey, (Vec<u8>, Option<Duration>)>,
+    reannounce_after: Duration,
+    offline_since: Option<Instant>,
+    /// Queries of the current pass; `None` when no pass runs.
+    pass: Option<(HashMap<Q, RecordKey>, RenewalSummary)>,
+}
+
+impl<Q: Eq + Hash> DhtAnnouncements<Q> {
+    pub fn new(reannounce_after: Duration) -> Self {
+        Self {
+            providers: HashSet::new(),
+            records: HashMap::new(),
+            reannounce_after,
+            offline_since: None,
+            pass: None,
+        }
+    }
+
+    pub fn reannounce_after(&self) -> Duration {
+        self.reannounce_after
+    }
+
+    /// We started providing `key`.
+    pub fn provide(&mut self, key: RecordKey) {
+        self.providers.insert(key);
+    }
+
+    /// We put a record, replacing whatever we put under `key` before.
+    pub fn record(&mut self, key: RecordKey, value: Vec<u8>, ttl: Option<Duration>) {
+        self.records.insert(key, (value, ttl));
+    }
+
+    /// The app withdrew the provider record for `key`. Returns false if we didn't provide it.
+    pub fn stop_providing(&mut self, key: &RecordKey) -> bool {
+        self.providers.remove(key)
+    }
+
+    /// The app withdrew the record under `key`. Returns false if we hadn't put one.
+    pub fn forget_record(&mut self, key: &RecordKey) -> bool {
+        self.records.remove(key).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.providers.len() + self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The last connection closed.
+    pub fn offline(&mut self, now: Instant) {
+        self.offline_since.get_or_insert(now);
+    }
+
+    /// A peer identified itself. If that ends an outage longer than `reannounce_after`,
+    /// starts a pass and returns what to announce again; the caller reports each one
+    /// with [`DhtAnnouncements::issued`]. Nothing is returned while a pass runs.
+    pub fn reconnected(&mut self, now: Instant) -> Option<Vec<Announcement>> {
+        let since = self.offline_since.take()?;
+        if now.saturating_duration_since(since) < self.reannounce_after || self.pass.is_some() || self.is_empty() {
+            return None;
+        }
+        self.pass = Some((HashMap::new(), RenewalSummary::default()));
+        let providers = self.providers.iter().cloned().map(Announcement::Provider);
+        let records = self
+            .records
+            .iter()
+            .map(|(key, (value, ttl))| Announcement::Record { key: key.clone(), value: value.clone(), ttl: *ttl });
+        Some(providers.chain(records).collect())
+    }
+
+    /// An announcement of the current pass was issued as `query`, or failed to start.
+    pub fn issued(&mut self, key: RecordKey, query: Option<Q>) {
+        let Some((pending, summary)) = &mut self.pass else { return };
+        match query {
+            Some(query) => {
+                pending.insert(query, key);
+            }
+            None => summary.failed.push(key),
+        }
+    }
+
+    /// `query` finished. Returns the summary once the pass has no query left; also call
+    /// it with no query after issuing, for passes where nothing started.
+    pub fn settle(&mut self, query: Option<&Q>, ok: bool) -> Option<RenewalSummary> {
+        let (pending, summary) = self.pass.as_mut()?;
+        if let Some(query) = query {
+            let key = pending.remove(query)?;
+            if ok {
+                summary.renewed += 1;
+            } else {
+                summary.failed.push(key);
+            }
+        }
+        if !pending.is_empty() {
+            return None;
+        }
+        self.pass.take().map(|(_, summary)| summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(name: &str) -> RecordKey {
+        RecordKey::new(&name)
+    }
+
+    #[test]
+    fn renews_after_a_long_outage_only() {
+        let t0 = Instant::now();
+        let mut announcements = DhtAnnouncements::<u32>::new(Duration::from_secs(60));
+        announcements.provide(key("doc"));
+        announcements.record(key("rec"), b"v".to_vec(), Some(Duration::from_secs(10)));
+        announcements.provide(key("unpinned"));
+        assert!(announcements.stop_providing(&key("unpinned")));
+
+        // Never offline, then a short blip
+        assert!(announcements.reconnected(t0).is_none());
+        announcements.offline(t0);
+        assert!(announcements.reconnected(t0 + Duration::from_secs(59)).is_none());
+
+        announcements.offline(t0);
+        announcements.offline(t0 + Duration::from_secs(30));
+        let mut renewed: Vec<RecordKey> = announcements
+            .reconnected(t0 + Duration::from_secs(60))
+            .expect("outage was long enough")
+            .iter()
+            .map(|a| a.key().clone())
+            .collect();
+        renewed.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
+        assert_eq!(renewed, [key("doc"), key("rec")], "withdrawn announcements are skipped");
+        // One pass at a time
+        announcements.offline(t0);
+        assert!(announcements.reconnected(t0 + Duration::from_secs(600)).is_none());
+    }
+
+    #[test]
+    fn summarises_the_pass_once_every_query_settled() {
+        let t0 = Instant::now();
+        let mut announcements = DhtAnnouncements::<u32>::new(Duration::ZERO);
+        for name in ["a", "b", "c"] {
+            announcements.provide(key(name));
+        }
+        announcements.offline(t0);
+        assert_eq!(announcements.reconnected(t0).unwrap().len(), 3);
+        announcements.issued(key("a"), Some(1));
+        announcements.issued(key("b"), Some(2));
+        announcements.issued(key("c"), None);
+        assert_eq!(announcements.settle(None, true), None);
+        assert_eq!(announcements.settle(Some(&7), true), None, "not ours");
+        assert_eq!(announcements.settle(Some(&1), true), None);
+        let summary = announcements.settle(Some(&2), false).unwrap();
+        assert_eq!(summary, RenewalSummary { renewed: 1, failed: vec![key("c"), key("b")] });
+        assert_eq!(announcements.settle(None, true), None, "pass is over");
+
+        // Nothing started at all
+        announcements.offline(t0);
+        announcements.reconnected(t0).unwrap();
+        for name in ["a", "b", "c"] {
+            announcements.issued(key(name), None);
+        }
+        assert_eq!(announcements.settle(None, true).unwrap().failed.len(), 3);
+    }
+}
@@ -0,0 +1,436 @@
+//! Auditing the inbound requests of the request-response protocols a node serves
+//! (replay, history, ...), shared by the native node and the server.
+//!
+//! Responders answer through [`RequestAudit::handle`], which enforces the per-peer rate
+//! limit configured for the protocol, times the responder and files the outcome: counters
+//! per protocol and a bounded log of recent requests, read through the admin socket and
+//! `/metrics`. A peer over its limit gets the protocol's typed slow-down response rather
+//! than silence. Implementing [`AuditedProtocol`] is all a new protocol needs for this.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::time::Duration;
+
+use libp2p::PeerId;
+use web_time::Instant;
+
+use crate::behaviour::fetch::{FetchRequest, FetchResponse, FETCH_PROTOCOL};
+use crate::behaviour::history::{DocumentState, HistoryRequest, HistoryResponse, HISTORY_PROTOCOL};
+use crate::behaviour::mailbox::{MailboxRequest, MailboxResponse, MAILBOX_PROTOCOL};
+use crate::behaviour::replay::{ReplayRateLimiter, ReplayRequest, ReplayResponse, REPLAY_PROTOCOL};
+
+/// Requests kept in the recent-requests log by default.
+pub const DEFAULT_AUDIT_LOG_ENTRIES: usize = 256;
+
+/// How an inbound request was answered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestOutcome {
+    Served,
+    /// Answered with an error, e.g. an invalid request.
+    Failed,
+    /// The peer was over its limit and told to slow down.
+    RateLimited,
+}
+
+impl RequestOutcome {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RequestOutcome::Served => "served",
+            RequestOutcome::Failed => "failed",
+            RequestOutcome::RateLimited => "rate_limited",
+        }
+    }
+}
+
+/// A request-response protocol whose inbound requests go through a [`RequestAudit`].
+pub trait AuditedProtocol {
+    type Request;
+    type Response;
+
+    /// What counters and log entries are filed under.
+    const PROTOCOL: &'static str;
+
+    /// The document `request` is about, for the log.
+    fn doc_id(_request: &Self::Request) -> Option<&str> {
+        None
+    }
+
+    /// Payload bytes `response` carries.
+    fn response_bytes(response: &Self::Response) -> usize;
+
+    fn outcome(response: &Self::Response) -> RequestOutcome;
+
+    /// The response telling a peer over its limit to come back in `retry_after_ms`.
+    fn slow_down(retry_after_ms: u64) -> Self::Response;
+}
+
+/// `/docstore/replay/1.0.0`.
+#[derive(Debug)]
+pub enum Replay {}
+
+impl AuditedProtocol for Replay {
+    type Request = ReplayRequest;
+    type Response = ReplayResponse;
+
+    const PROTOCOL: &'static str = REPLAY_PROTOCOL;
+
+    fn response_bytes(response: &ReplayResponse) -> usize {
+        match response {
+            ReplayResponse::Page { messages, .. } => messages.iter().map(|m| m.data.len()).sum(),
+            ReplayResponse::RateLimited { .. } => 0,
+        }
+    }
+
+    fn outcome(response: &ReplayResponse) -> RequestOutcome {
+        match response {
+            ReplayResponse::Page { .. } => RequestOutcome::Served,
+            ReplayResponse::RateLimited { .. } => RequestOutcome::RateLimited,
+        }
+    }
+
+    fn slow_down(retry_after_ms: u64) -> ReplayResponse {
+        ReplayResponse::RateLimited { retry_after_ms }
+    }
+}
+
+/// `/docstore/history/1.0.0`.
+#[derive(Debug)]
+pub enum History {}
+
+impl AuditedProtocol for History {
+    type Request = HistoryRequest;
+    type Response = HistoryResponse;
+
+    const PROTOCOL: &'static str = HISTORY_PROTOCOL;
+
+    fn doc_id(request: &HistoryRequest) -> Option<&str> {
+        Some(&request.doc_id)
+    }
+
+    fn response_bytes(response: &HistoryResponse) -> usize {
+        match response {
+            HistoryResponse::Page(page) => page.updates.iter().map(|u| u.payload.len()).sum(),
+            HistoryResponse::State(DocumentState::Content { bytes, .. }) => bytes.len(),
+            HistoryResponse::State(_)
+            | HistoryResponse::Unavailable { .. }
+            | HistoryResponse::Rejected { .. }
+            | HistoryResponse::RateLimited { .. } => 0,
+        }
+    }
+
+    fn outcome(response: &HistoryResponse) -> RequestOutcome {
+        match response {
+            HistoryResponse::Page(_) | HistoryResponse::State(_) | HistoryResponse::Unavailable { .. } => {
+                RequestOutcome::Served
+            }
+            HistoryResponse::Rejected { .. } => RequestOutcome::Failed,
+            HistoryResponse::RateLimited { .. } => RequestOutcome::RateLimited,
+        }
+    }
+
+    fn slow_down(retry_after_ms: u64) -> HistoryResponse {
+        HistoryResponse::RateLimited { retry_after_ms }
+    }
+}
+
+/// `/docstore/fetch/1.0.0`.
+#[derive(Debug)]
+pub enum Fetch {}
+
+impl AuditedProtocol for Fetch {
+    type Request = FetchRequest;
+    type Response = FetchResponse;
+
+    const PROTOCOL: &'static str = FETCH_PROTOCOL;
+
+    fn doc_id(request: &FetchRequest) -> Option<&str> {
+        Some(request.doc_id())
+    }
+
+    fn response_bytes(response: &FetchResponse) -> usize {
+        match response {
+            FetchResponse::Chunk { data, .. } => data.len(),
+            _ => 0,
+        }
+    }
+
+    fn outcome(response: &FetchResponse) -> RequestOutcome {
+        match response {
+            FetchResponse::Rejected { .. } => RequestOutcome::Failed,
+            FetchResponse::RateLimited { .. } => RequestOutcome::RateLimited,
+            _ => RequestOutcome::Served,
+        }
+    }
+
+    fn slow_down(retry_after_ms: u64) -> FetchResponse {
+        FetchResponse::RateLimited { retry_after_ms }
+    }
+}
+
+/// `/docstore/mailbox/1.0.0`.
+#[derive(Debug)]
+pub enum Mailbox {}
+
+impl AuditedProtocol for Mailbox {
+    type Request = MailboxRequest;
+    type Response = MailboxResponse;
+
+    const PROTOCOL: &'static str = MAILBOX_PROTOCOL;
+
+    fn response_bytes(response: &MailboxResponse) -> usize {
+        match response {
+            MailboxResponse::Messages(messages) => messages.iter().map(|m| m.blob.len()).sum(),
+            _ => 0,
+        }
+    }
+
+    fn outcome(response: &MailboxResponse) -> RequestOutcome {
+        match response {
+            MailboxResponse::Rejected { .. } => RequestOutcome::Failed,
+            MailboxResponse::RateLimited { .. } => RequestOutcome::RateLimited,
+            _ => RequestOutcome::Served,
+        }
+    }
+
+    fn slow_down(retry_after_ms: u64) -> MailboxResponse {
+        MailboxResponse::RateLimited { retry_after_ms }
+    }
+}
+
+/// Totals for one protocol.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProtocolCounters {
+    pub requests: u64,
+    pub bytes_served: u64,
+    pub errors: u64,
+    pub rate_limited: u64,
+}
+
+/// One request in the recent-requests log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditEntry {
+    pub peer_id: PeerId,
+    pub protocol: &'static str,
+    pub doc_id: Option<String>,
+    /// How long the responder took.
+    pub duration: Duration,
+    pub outcome: RequestOutcome,
+    pub at_ms: u64,
+}
+
+/// The audit's state at one point, for callers outside the event loop.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuditSnapshot {
+    pub protocols: BTreeMap<&'static str, ProtocolCounters>,
+    /// Newest first.
+    pub recent: Vec<AuditEntry>,
+}
+
+#[derive(Debug)]
+pub struct RequestAudit {
+    counters: BTreeMap<&'static str, ProtocolCounters>,
+    recent: VecDeque<AuditEntry>,
+    max_entries: usize,
+    limits: HashMap<&'static str, ReplayRateLimiter>,
+}
+
+impl Default for RequestAudit {
+    fn default() -> Self {
+        Self::new(DEFAULT_AUDIT_LOG_ENTRIES)
+    }
+}
+
+impl RequestAudit {
+    /// Keep the last `max_entries` requests in the log. No protocol is rate limited until
+    /// given a limit with [`RequestAudit::with_limit`].
+    pub fn new(max_entries: usize) -> Self {
+        Self { counters: BTreeMap::new(), recent: VecDeque::new(), max_entries, limits: HashMap::new() }
+    }
+
+    /// Let each peer make `max_requests` requests for `protocol` per `window`.
+    pub fn with_limit(mut self, protocol: &'static str, max_requests: u32, window: Duration) -> Self {
+        self.set_limit(protocol, max_requests, window);
+        self
+    }
+
+    /// Change `protocol`'s limit while running. Every peer's count starts over.
+    pub fn set_limit(&mut self, protocol: &'static str, max_requests: u32, window: Duration) {
+        self.limits.insert(protocol, ReplayRateLimiter::new(max_requests, window));
+    }
+
+    /// Answer `request` from `peer` with `respond`, or with a slow-down response if the
+    /// peer is over its limit, and record how it went.
+    pub fn handle<P: AuditedProtocol>(
+        &mut self,
+        peer: PeerId,
+        request: &P::Request,
+        now_ms: u64,
+        respond: impl FnOnce(&P::Request) -> P::Response,
+    ) -> P::Response {
+        let started = Instant::now();
+        let response = match self.limits.get_mut(P::PROTOCOL).map(|limiter| limiter.check(peer, now_ms)) {
+            Some(Err(retry_after_ms)) => P::slow_down(retry_after_ms),
+            Some(Ok(())) | None => respond(request),
+        };
+        let outcome = P::outcome(&response);
+        let counters = self.counters.entry(P::PROTOCOL).or_default();
+        counters.requests += 1;
+        counters.bytes_served += P::response_bytes(&response) as u64;
+        match outcome {
+            RequestOutcome::Served => {}
+            RequestOutcome::Failed => counters.errors += 1,
+            RequestOutcome::RateLimited => counters.rate_limited += 1,
+        }
+        if self.max_entries > 0 {
+            if self.recent.len() >= self.max_entries {
+                self.recent.pop_front();
+            }
+            self.recent.push_back(AuditEntry {
+                peer_id: peer,
+                protocol: P::PROTOCOL,
+                doc_id: P::doc_id(request).map(str::to_string),
+                duration: started.elapsed(),
+                outcome,
+                at_ms: now_ms,
+            });
+        }
+        response
+    }
+
+    pub fn counters(&self, protocol: &str) -> ProtocolCounters {
+        self.counters.get(protocol).copied().unwrap_or_default()
+    }
+
+    /// The last `n` requests, newest first.
+    pub fn recent(&self, n: usize) -> Vec<AuditEntry> {
+        self.recent.iter().rev().take(n).cloned().collect()
+    }
+
+    pub fn snapshot(&self) -> AuditSnapshot {
+        AuditSnapshot { protocols: self.counters.clone(), recent: self.recent(self.max_entries) }
+    }
+
+    /// The counters as Prometheus samples labelled by protocol, e.g.
+    /// `docstore_inbound_requests_total{protocol="/docstore/replay/1.0.0"}`.
+    pub fn metrics(&self) -> Vec<(String, u64)> {
+        let mut metrics = Vec::new();
+        for (protocol, counters) in &self.counters {
+            for (name, value) in [
+                ("docstore_inbound_requests_total", counters.requests),
+                ("docstore_inbound_request_bytes_total", counters.bytes_served),
+                ("docstore_inbound_request_errors_total", counters.errors),
+                ("docstore_inbound_requests_rate_limited_total", counters.rate_limited),
+            ] {
+                metrics.push((format!("{name}{{protocol=\"{protocol}\"}}"), value));
+            }
+        }
+        metrics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::behaviour::history::HistoryPage;
+
+    /// A protocol that echoes the requested doc id, and fails on empty ones.
+    enum Echo {}
+
+    #[derive(Debug, PartialEq)]
+    enum EchoResponse {
+        Echo(String),
+        Empty,
+        SlowDown(u64),
+    }
+
+    impl AuditedProtocol for Echo {
+        type Request = String;
+        type Response = EchoResponse;
+
+        const PROTOCOL: &'static str = "/test/echo/1.0.0";
+
+        fn doc_id(request: &String) -> Option<&str> {
+            Some(request)
+        }
+
+        fn response_bytes(response: &EchoResponse) -> usize {
+            match response {
+                EchoResponse::Echo(s) => s.len(),
+                _ => 0,
+            }
+        }
+
+        fn outcome(response: &EchoResponse) -> RequestOutcome {
+            match response {
+                EchoResponse::Echo(_) => RequestOutcome::Served,
+                EchoResponse::Empty => RequestOutcome::Failed,
+                EchoResponse::SlowDown(_) => RequestOutcome::RateLimited,
+            }
+        }
+
+        fn slow_down(retry_after_ms: u64) -> EchoResponse {
+            EchoResponse::SlowDown(retry_after_ms)
+        }
+    }
+
+    fn echo(audit: &mut RequestAudit, peer: PeerId, doc_id: &str, now_ms: u64) -> EchoResponse {
+        audit.handle::<Echo>(peer, &doc_id.to_string(), now_ms, |request| match request.as_str() {
+            "" => EchoResponse::Empty,
+            doc_id => EchoResponse::Echo(doc_id.to_string()),
+        })
+    }
+
+    #[test]
+    fn counts_and_logs_every_request() {
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let mut audit = RequestAudit::new(2);
+        assert_eq!(echo(&mut audit, a, "doc", 0), EchoResponse::Echo("doc".into()));
+        assert_eq!(echo(&mut audit, a, "", 1), EchoResponse::Empty);
+        assert_eq!(echo(&mut audit, b, "other", 2), EchoResponse::Echo("other".into()));
+
+        assert_eq!(
+            audit.counters(Echo::PROTOCOL),
+            ProtocolCounters { requests: 3, bytes_served: 8, errors: 1, rate_limited: 0 }
+        );
+        assert_eq!(audit.counters(REPLAY_PROTOCOL), ProtocolCounters::default());
+        // Bounded, newest first
+        let recent: Vec<_> = audit.recent(10).into_iter().map(|e| (e.peer_id, e.doc_id, e.outcome, e.at_ms)).collect();
+        assert_eq!(
+            recent,
+            [(b, Some("other".to_string()), RequestOutcome::Served, 2), (a, Some(String::new()), RequestOutcome::Failed, 1)]
+        );
+        assert_eq!(
+            audit.metrics()[0],
+            ("docstore_inbound_requests_total{protocol=\"/test/echo/1.0.0\"}".to_string(), 3)
+        );
+    }
+
+    #[test]
+    fn peers_over_their_limit_are_told_to_slow_down() {
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let mut audit = RequestAudit::default().with_limit(Echo::PROTOCOL, 2, Duration::from_secs(1));
+        assert!(matches!(echo(&mut audit, a, "doc", 0), EchoResponse::Echo(_)));
+        assert!(matches!(echo(&mut audit, a, "doc", 100), EchoResponse::Echo(_)));
+        assert_eq!(echo(&mut audit, a, "doc", 400), EchoResponse::SlowDown(600));
+        assert!(matches!(echo(&mut audit, b, "doc", 400), EchoResponse::Echo(_)), "limits are per peer");
+        assert!(matches!(echo(&mut audit, a, "doc", 1000), EchoResponse::Echo(_)));
+
+        let counters = audit.counters(Echo::PROTOCOL);
+        assert_eq!((counters.requests, counters.rate_limited), (5, 1));
+        assert_eq!(audit.snapshot().recent[2].outcome, RequestOutcome::RateLimited);
+    }
+
+    #[test]
+    fn history_requests_are_classified() {
+        let peer = PeerId::random();
+        let mut audit = RequestAudit::default().with_limit(HISTORY_PROTOCOL, 1, Duration::from_secs(60));
+        let request = HistoryRequest::new("doc");
+        let page = HistoryResponse::Page(HistoryPage { updates: Vec::new(), next_cursor: None, truncated: false });
+        assert_eq!(audit.handle::<History>(peer, &request, 0, |_| page.clone()), page);
+        assert_eq!(
+            audit.handle::<History>(peer, &request, 1, |_| page.clone()),
+            HistoryResponse::RateLimited { retry_after_ms: 59_999 }
+        );
+        assert_eq!(audit.recent(1)[0].doc_id.as_deref(), Some("doc"));
+        assert_eq!(audit.counters(HISTORY_PROTOCOL).rate_limited, 1);
+    }
+}
@@ -0,0 +1,93 @@
+//! Runtime peer bans shared by the native and wasm event loops.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use libp2p::PeerId;
+use web_time::Instant;
+
+/// Peers banned until a deadline. Bans live for the process lifetime only.
+#[derive(Debug, Default)]
+pub struct BanList {
+    /// When each ban ends; `None` for bans too long to have an end.
+    bans: HashMap<PeerId, Option<Instant>>,
+}
+
+impl BanList {
+    /// Ban `peer_id` for `duration`. A duration past what `Instant` can hold bans it for
+    /// good, or until [`unban`](Self::unban)ned.
+    pub fn ban(&mut self, peer_id: PeerId, duration: Duration, now: Instant) {
+        self.bans.insert(peer_id, now.checked_add(duration));
+    }
+
+    pub fn unban(&mut self, peer_id: &PeerId) -> bool {
+        self.bans.remove(peer_id).is_some()
+    }
+
+    pub fn is_banned(&self, peer_id: &PeerId, now: Instant) -> bool {
+        self.bans.get(peer_id).is_some_and(|until| until.map_or(true, |until| until > now))
+    }
+
+    /// Drop expired bans, returning the peers that are allowed back in.
+    pub fn expire(&mut self, now: Instant) -> Vec<PeerId> {
+        let expired: Vec<PeerId> = self
+            .bans
+            .iter()
+            .filter(|(_, until)| until.is_some_and(|until| until <= now))
+            .map(|(peer, _)| *peer)
+            .collect();
+        for peer in &expired {
+            self.bans.remove(peer);
+        }
+        expired
+    }
+
+    pub fn banned_peers(&self) -> impl Iterator<Item = &PeerId> {
+        self.bans.keys()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ban_expires_after_duration() {
+        let mut bans = BanList::default();
+        let peer = PeerId::random();
+        let now = Instant::now();
+        bans.ban(peer, Duration::from_secs(10), now);
+
+        assert!(bans.is_banned(&peer, now));
+        assert!(bans.is_banned(&peer, now + Duration::from_secs(9)));
+        assert!(!bans.is_banned(&peer, now + Duration::from_secs(10)));
+
+        assert!(bans.expire(now + Duration::from_secs(5)).is_empty());
+        assert_eq!(bans.expire(now + Duration::from_secs(11)), vec![peer]);
+        assert_eq!(bans.banned_peers().count(), 0);
+    }
+
+    #[test]
+    fn unban_lifts_immediately() {
+        let mut bans = BanList::default();
+        let peer = PeerId::random();
+        let now = Instant::now();
+        bans.ban(peer, Duration::from_secs(60), now);
+        assert!(bans.unban(&peer));
+        assert!(!bans.is_banned(&peer, now));
+        assert!(!bans.unban(&peer));
+    }
+
+    #[test]
+    fn a_ban_too_long_to_end_is_permanent() {
+        let mut bans = BanList::default();
+        let peer = PeerId::random();
+        let now = Instant::now();
+        bans.ban(peer, Duration::MAX, now);
+        bans.ban(PeerId::random(), Duration::from_millis(u64::MAX), now);
+        assert!(bans.is_banned(&peer, now + Duration::from_secs(100 * 365 * 24 * 3600)));
+        assert!(bans.expire(now + Duration::from_secs(100 * 365 * 24 * 3600)).is_empty());
+        assert!(bans.unban(&peer));
+        assert!(!bans.is_banned(&peer, now));
+    }
+}
@@ -0,0 +1,106 @@
+//! Racing several bootstrap addresses: the node is up as soon as one connects, and only
+//! fails to start if every one of them fails.
+
+use libp2p::Multiaddr;
+
+use crate::Error;
+
+/// Bootstrap dials still in flight, and what became of the others.
+#[derive(Debug, Default)]
+pub struct BootstrapDials {
+    pending: Vec<Multiaddr>,
+    connected: Vec<Multiaddr>,
+    failed: Vec<(Multiaddr, String)>,
+    settled: bool,
+}
+
+impl BootstrapDials {
+    pub fn new(addrs: impl IntoIterator<Item = Multiaddr>) -> Self {
+        Self { pending: addrs.into_iter().collect(), ..Default::default() }
+    }
+
+    /// A dial of `addr` connected. Returns false if `addr` is not a pending bootstrap dial.
+    pub fn connected(&mut self, addr: &Multiaddr) -> bool {
+        let Some(i) = self.pending.iter().position(|a| a == addr) else {
+            return false;
+        };
+        self.connected.push(self.pending.remove(i));
+        true
+    }
+
+    /// A dial of `addr` gave up. Returns false if `addr` is not a pending bootstrap dial.
+    pub fn failed(&mut self, addr: &Multiaddr, error: impl Into<String>) -> bool {
+        let Some(i) = self.pending.iter().position(|a| a == addr) else {
+            return false;
+        };
+        self.failed.push((self.pending.remove(i), error.into()));
+        true
+    }
+
+    /// Every address was tried and none connected.
+    pub fn all_failed(&self) -> bool {
+        self.pending.is_empty() && self.connected.is_empty()
+    }
+
+    /// Addresses that connected, fastest first.
+    pub fn connected_addrs(&self) -> &[Multiaddr] {
+        &self.connected
+    }
+
+    /// `Ok` once the first address connected, an error once all of them failed, `None`
+    /// until then. Returned only once.
+    pub fn take_outcome(&mut self) -> Option<Result<(), Error>> {
+        if self.settled {
+            return None;
+        }
+        let outcome = if !self.connected.is_empty() {
+            Ok(())
+        } else if self.pending.is_empty() {
+            let failures = self.failed.iter().map(|(addr, e)| format!("{addr}: {e}")).collect();
+            Err(Error::BootstrapFailed { failures })
+        } else {
+            return None;
+        };
+        self.settled = true;
+        Some(outcome)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> Multiaddr {
+        format!("/ip4/10.0.0.1/tcp/{port}").parse().unwrap()
+    }
+
+    #[test]
+    fn first_connection_wins() {
+        let mut dials = BootstrapDials::new([addr(1), addr(2), addr(3)]);
+        assert!(dials.failed(&addr(1), "refused"));
+        assert!(dials.take_outcome().is_none());
+        assert!(dials.connected(&addr(3)));
+        assert!(matches!(dials.take_outcome(), Some(Ok(()))));
+        assert!(dials.take_outcome().is_none());
+
+        // The others keep going and are still recorded
+        assert!(dials.connected(&addr(2)));
+        assert_eq!(dials.connected_addrs(), [addr(3), addr(2)]);
+        assert!(!dials.connected(&addr(2)));
+        assert!(!dials.failed(&addr(4), "refused"));
+    }
+
+    #[test]
+    fn fails_only_when_every_address_failed() {
+        let mut dials = BootstrapDials::new([addr(1), addr(2)]);
+        dials.failed(&addr(2), "timeout");
+        assert!(dials.take_outcome().is_none());
+        assert!(!dials.all_failed());
+        dials.failed(&addr(1), "refused");
+        assert!(dials.all_failed());
+        match dials.take_outcome() {
+            Some(Err(Error::BootstrapFailed { failures })) => assert_eq!(failures.len(), 2),
+            other => panic!("unexpected outcome {other:?}"),
+        }
+    }
+}
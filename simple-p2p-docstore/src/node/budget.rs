@@ -0,0 +1,183 @@
+//! Bandwidth budget for metered connections: at most so many gossipsub bytes, in and out,
+//! per interval. Once the current interval's budget is spent the node defers what can
+//! wait until the next one — publishes are held (up to the usual queue cap), history
+//! fetches and catch-up pause, ephemeral messages are dropped and counted, and presence
+//! heartbeats go out at half their rate — while control traffic (ping, identify) carries
+//! on. Nothing is deferred while the budget is unlimited, the default.
+//!
+//! The counters are shared like [`TrafficStats`](super::TrafficStats), which spends from
+//! the budget as it counts, so handles read the usage without asking the event loop.
+//! Whether the budget counts as exhausted only changes in [`BandwidthBudget::poll`],
+//! which the event loop calls, so what it defers and the events it emits agree.
+//!
+//! Times are unix milliseconds, so this works the same on every platform.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::Serialize;
+
+/// The interval a budget is granted for.
+pub const BUDGET_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Publishes held while the budget is exhausted; further ones fail with
+/// [`Error::SuspendQueueFull`](crate::Error::SuspendQueueFull). The same cap as for a
+/// suspended browser node.
+pub const MAX_DEFERRED_PUBLISHES: usize = 1024;
+
+/// A change reported by [`BandwidthBudget::poll`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BudgetChange {
+    /// `used` bytes of the `limit` are spent; non-essential traffic waits until
+    /// `resets_at_ms`.
+    Exceeded { used: u64, limit: u64, resets_at_ms: u64 },
+    /// A new interval started or the budget was raised or lifted; deferred traffic goes out.
+    Reset,
+}
+
+/// The budget's state, for the stats API.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct BudgetUsage {
+    /// `None` while unlimited.
+    pub bytes_per_interval: Option<u64>,
+    pub interval_ms: u64,
+    /// Spent in the current interval.
+    pub used_bytes: u64,
+    pub interval_started_ms: u64,
+    pub exhausted: bool,
+    /// Ephemeral messages dropped over the budget since start.
+    pub dropped_ephemeral: u64,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    limit: Option<u64>,
+    started_ms: u64,
+    used: u64,
+    exhausted: bool,
+    dropped_ephemeral: u64,
+}
+
+/// See the module docs. Cheap to clone; clones share the budget.
+#[derive(Debug, Clone, Default)]
+pub struct BandwidthBudget {
+    state: Arc<Mutex<State>>,
+}
+
+impl BandwidthBudget {
+    /// Allow `bytes_per_interval` from now on, `None` for unlimited, starting a fresh
+    /// interval at `now_ms`. The next [`BandwidthBudget::poll`] reports the change.
+    pub fn set_limit(&self, bytes_per_interval: Option<u64>, now_ms: u64) {
+        let mut state = self.lock();
+        state.limit = bytes_per_interval;
+        state.started_ms = now_ms;
+        state.used = 0;
+    }
+
+    pub fn limit(&self) -> Option<u64> {
+        self.lock().limit
+    }
+
+    /// Count `bytes` sent or received.
+    pub fn spend(&self, bytes: u64) {
+        let mut state = self.lock();
+        state.used = state.used.saturating_add(bytes);
+    }
+
+    /// Count an ephemeral message dropped because the budget is exhausted.
+    pub fn drop_ephemeral(&self) {
+        self.lock().dropped_ephemeral += 1;
+    }
+
+    /// Whether non-essential traffic is deferred, as of the last poll.
+    pub fn is_exhausted(&self) -> bool {
+        self.lock().exhausted
+    }
+
+    /// Start a new interval if the current one is over, and report whether the budget
+    /// became exhausted or available again.
+    pub fn poll(&self, now_ms: u64) -> Option<BudgetChange> {
+        let mut state = self.lock();
+        let interval_ms = BUDGET_INTERVAL.as_millis() as u64;
+        if now_ms >= state.started_ms + interval_ms {
+            // Skip whole idle intervals, so resets stay on the interval grid
+            state.started_ms = now_ms - (now_ms - state.started_ms) % interval_ms;
+            state.used = 0;
+        }
+        let over = state.limit.is_some_and(|limit| state.used >= limit);
+        if over == state.exhausted {
+            return None;
+        }
+        state.exhausted = over;
+        Some(match state.limit.filter(|_| over) {
+            Some(limit) => BudgetChange::Exceeded { used: state.used, limit, resets_at_ms: state.started_ms + interval_ms },
+            None => BudgetChange::Reset,
+        })
+    }
+
+    pub fn usage(&self, now_ms: u64) -> BudgetUsage {
+        let state = self.lock();
+        let interval_ms = BUDGET_INTERVAL.as_millis() as u64;
+        // Not polled since the interval ended: nothing of the new one is spent yet
+        let current = now_ms < state.started_ms + interval_ms;
+        BudgetUsage {
+            bytes_per_interval: state.limit,
+            interval_ms,
+            used_bytes: if current { state.used } else { 0 },
+            interval_started_ms: state.started_ms,
+            exhausted: state.exhausted,
+            dropped_ephemeral: state.dropped_ephemeral,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("budget lock")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEC: u64 = 1000;
+
+    #[test]
+    fn exhausts_within_an_interval_and_resets_with_the_next() {
+        let budget = BandwidthBudget::default();
+        budget.spend(10_000);
+        // Unlimited: never exhausted
+        assert_eq!(budget.poll(SEC), None);
+
+        budget.set_limit(Some(1000), 0);
+        budget.spend(600);
+        assert_eq!(budget.poll(10 * SEC), None);
+        budget.spend(600);
+        assert_eq!(budget.poll(20 * SEC), Some(BudgetChange::Exceeded { used: 1200, limit: 1000, resets_at_ms: 60 * SEC }));
+        assert!(budget.is_exhausted());
+        assert_eq!(budget.poll(30 * SEC), None);
+        budget.drop_ephemeral();
+
+        let usage = budget.usage(30 * SEC);
+        assert_eq!((usage.used_bytes, usage.exhausted, usage.dropped_ephemeral), (1200, true, 1));
+        assert_eq!(budget.usage(61 * SEC).used_bytes, 0);
+
+        // Polled late: the new interval still starts on the grid
+        assert_eq!(budget.poll(130 * SEC), Some(BudgetChange::Reset));
+        assert_eq!(budget.usage(130 * SEC).interval_started_ms, 120 * SEC);
+        assert!(!budget.is_exhausted());
+    }
+
+    #[test]
+    fn lifting_the_limit_resets_at_once() {
+        let budget = BandwidthBudget::default();
+        budget.set_limit(Some(100), 0);
+        budget.spend(500);
+        assert!(matches!(budget.poll(SEC), Some(BudgetChange::Exceeded { .. })));
+
+        budget.set_limit(None, 2 * SEC);
+        assert_eq!(budget.poll(2 * SEC), Some(BudgetChange::Reset));
+        budget.spend(500);
+        assert_eq!(budget.poll(3 * SEC), None);
+        assert_eq!(budget.usage(3 * SEC).bytes_per_interval, None);
+    }
+}
@@ -0,0 +1,157 @@
+//! Fetching the current state of the documents an application cares about, so a fresh
+//! node doesn't show empty documents until someone edits them. A run asks one peer
+//! serving history for the newest update of every document of interest; it is due at
+//! start, when new documents are added, and again after the node lost touch with every
+//! such peer (a long disconnect or a suspended tab).
+
+use std::collections::{BTreeSet, HashMap};
+use std::hash::Hash;
+
+/// How a run ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CatchUpOutcome {
+    /// Every request was answered; `doc_count` documents had state to apply.
+    CaughtUp { doc_count: usize },
+    /// Some requests failed. The documents that did come back were still applied; the
+    /// rest only get live updates.
+    Failed { doc_count: usize, failed: Vec<String> },
+}
+
+#[derive(Debug)]
+struct Run<K> {
+    pending: HashMap<K, String>,
+    doc_count: usize,
+    failed: Vec<String>,
+}
+
+/// Documents of interest and the catch-up run in flight, keyed by request id `K`.
+#[derive(Debug)]
+pub struct CatchUp<K> {
+    interest: BTreeSet<String>,
+    /// Documents not fetched since they became due.
+    due: BTreeSet<String>,
+    run: Option<Run<K>>,
+}
+
+impl<K> Default for CatchUp<K> {
+    fn default() -> Self {
+        Self { interest: BTreeSet::new(), due: BTreeSet::new(), run: None }
+    }
+}
+
+impl<K: Eq + Hash> CatchUp<K> {
+    /// Add documents of interest; the new ones become due.
+    pub fn interest(&mut self, doc_ids: impl IntoIterator<Item = String>) {
+        for doc_id in doc_ids {
+            if self.interest.insert(doc_id.clone()) {
+                self.due.insert(doc_id);
+            }
+        }
+    }
+
+    /// Every document becomes due again, e.g. after a disconnect.
+    pub fn reset(&mut self) {
+        self.due = self.interest.clone();
+    }
+
+    /// Whether a run should start once a peer serving history is connected.
+    pub fn is_due(&self) -> bool {
+        self.run.is_none() && !self.due.is_empty()
+    }
+
+    /// Start a run: the documents to request, each then passed to [`Self::requested`].
+    pub fn start(&mut self) -> Vec<String> {
+        self.run = Some(Run { pending: HashMap::new(), doc_count: 0, failed: Vec::new() });
+        std::mem::take(&mut self.due).into_iter().collect()
+    }
+
+    pub fn requested(&mut self, id: K, doc_id: String) {
+        if let Some(run) = &mut self.run {
+            run.pending.insert(id, doc_id);
+        }
+    }
+
+    /// The document requested by `id`, if it is a request of the current run.
+    pub fn pending(&self, id: &K) -> Option<&str> {
+        self.run.as_ref()?.pending.get(id).map(String::as_str)
+    }
+
+    /// Request `id` was answered, with state to apply (`found`) or without. Returns the
+    /// outcome once it was the last of the run.
+    pub fn answered(&mut self, id: &K, found: bool) -> Option<CatchUpOutcome> {
+        let run = self.run.as_mut()?;
+        run.pending.remove(id)?;
+        run.doc_count += usize::from(found);
+        self.finish_if_done()
+    }
+
+    /// Request `id` failed; its document is left to live updates.
+    pub fn failed(&mut self, id: &K) -> Option<CatchUpOutcome> {
+        let run = self.run.as_mut()?;
+        let doc_id = run.pending.remove(id)?;
+        run.failed.push(doc_id);
+        self.finish_if_done()
+    }
+
+    fn finish_if_done(&mut self) -> Option<CatchUpOutcome> {
+        if !self.run.as_ref()?.pending.is_empty() {
+            return None;
+        }
+        let run = self.run.take()?;
+        Some(if run.failed.is_empty() {
+            CatchUpOutcome::CaughtUp { doc_count: run.doc_count }
+        } else {
+            CatchUpOutcome::Failed { doc_count: run.doc_count, failed: run.failed }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_once_for_the_interest_and_again_after_a_reset() {
+        let mut catch_up = CatchUp::<u32>::default();
+        assert!(!catch_up.is_due());
+        catch_up.interest(["a".to_string(), "b".to_string()]);
+        assert!(catch_up.is_due());
+
+        let docs = catch_up.start();
+        assert_eq!(docs, ["a", "b"]);
+        assert!(!catch_up.is_due());
+        catch_up.requested(1, "a".into());
+        catch_up.requested(2, "b".into());
+        assert_eq!((catch_up.pending(&1), catch_up.pending(&3)), (Some("a"), None));
+        // Interest added mid-run waits for the next run
+        catch_up.interest(["b".to_string(), "c".to_string()]);
+        assert_eq!(catch_up.answered(&1, true), None);
+        assert_eq!(catch_up.answered(&3, true), None);
+        assert_eq!(catch_up.answered(&2, false), Some(CatchUpOutcome::CaughtUp { doc_count: 1 }));
+
+        assert!(catch_up.is_due());
+        assert_eq!(catch_up.start(), ["c"]);
+        catch_up.requested(3, "c".into());
+        assert_eq!(catch_up.answered(&3, true), Some(CatchUpOutcome::CaughtUp { doc_count: 1 }));
+        assert!(!catch_up.is_due());
+
+        catch_up.reset();
+        assert_eq!(catch_up.start(), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn failures_end_the_run_with_the_documents_left_out() {
+        let mut catch_up = CatchUp::<u32>::default();
+        catch_up.interest(["a".to_string(), "b".to_string()]);
+        for (id, doc_id) in catch_up.start().into_iter().enumerate() {
+            catch_up.requested(id as u32, doc_id);
+        }
+        assert_eq!(catch_up.failed(&0), None);
+        assert_eq!(catch_up.failed(&0), None);
+        assert_eq!(
+            catch_up.answered(&1, true),
+            Some(CatchUpOutcome::Failed { doc_count: 1, failed: vec!["a".into()] })
+        );
+        assert!(!catch_up.is_due());
+    }
+}
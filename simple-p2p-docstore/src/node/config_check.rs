@@ -0,0 +1,324 @@
+//! Startup self-checks: validate a node's configuration without running it, so a bad
+//! multiaddr, an unreadable key file, an occupied port or a store directory the node may
+//! not write to turn up before deployment instead of after.
+//!
+//! Each validator returns a typed [`ConfigError`]. [`NodeBuilder::check`] runs the ones
+//! that apply to a builder, and `spawn` runs them before building a swarm; `server check`
+//! runs them all and prints a [`CheckReport`]. Nothing is changed: listeners are bound
+//! and closed again at once, and keys and stores are only read.
+//!
+//! [`NodeBuilder::check`]: crate::node::NodeBuilder::check
+
+use std::fmt;
+use std::fs::{self, File};
+use std::net::{IpAddr, SocketAddr, TcpListener, UdpSocket};
+use std::path::{Path, PathBuf};
+
+use libp2p::multiaddr::Protocol;
+use libp2p::{Multiaddr, PeerId};
+
+use crate::node::keys;
+use crate::store::file::FileDocStore;
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ConfigError {
+    #[error("{what}: invalid multiaddr {addr:?}: {reason}")]
+    InvalidMultiaddr { what: String, addr: String, reason: String },
+    #[error("{what}: cannot listen on {addr}: {reason}")]
+    CannotListen { what: String, addr: Multiaddr, reason: String },
+    #[error("{what}: cannot read {}: {reason}", .path.display())]
+    Unreadable { what: String, path: PathBuf, reason: String },
+    #[error("{what}: cannot write to {}: {reason}", .path.display())]
+    NotWritable { what: String, path: PathBuf, reason: String },
+    #[error("invalid identity key {}: {reason}", .path.display())]
+    BadIdentity { path: PathBuf, reason: String },
+    #[error("invalid store {}: {reason}", .path.display())]
+    BadStore { path: PathBuf, reason: String },
+    #[error("invalid WebRTC certificate {}: {reason}", .path.display())]
+    BadCertificate { path: PathBuf, reason: String },
+    #[error("invalid config file {}: {reason}", .path.display())]
+    BadConfigFile { path: PathBuf, reason: String },
+    /// Settings that each work but not together.
+    #[error("{0}")]
+    Incompatible(String),
+}
+
+impl From<ConfigError> for crate::Error {
+    fn from(e: ConfigError) -> Self {
+        crate::Error::InvalidConfig(e.to_string())
+    }
+}
+
+/// `addr`, given for `what` (a flag or variable name), as a multiaddr.
+pub fn parse_multiaddr(what: &str, addr: &str) -> Result<Multiaddr, ConfigError> {
+    addr.trim().parse().map_err(|e: libp2p::multiaddr::Error| ConfigError::InvalidMultiaddr {
+        what: what.to_string(),
+        addr: addr.to_string(),
+        reason: e.to_string(),
+    })
+}
+
+/// A comma-separated list of multiaddrs, like `BOOTSTRAP_PEERS`.
+pub fn parse_multiaddrs(what: &str, list: &str) -> Result<Vec<Multiaddr>, ConfigError> {
+    list.split(',').map(str::trim).filter(|addr| !addr.is_empty()).map(|addr| parse_multiaddr(what, addr)).collect()
+}
+
+/// Whether `addr` can be listened on: its TCP or UDP port is bound and closed again.
+/// Port 0 and addresses without an IP (`/memory/..`, `/dns/..`) always pass.
+pub fn check_listen(what: &str, addr: &Multiaddr) -> Result<(), ConfigError> {
+    let mut ip = None;
+    let mut bind = None;
+    for protocol in addr.iter() {
+        match protocol {
+            Protocol::Ip4(v4) => ip = Some(IpAddr::V4(v4)),
+            Protocol::Ip6(v6) => ip = Some(IpAddr::V6(v6)),
+            Protocol::Tcp(port) => bind = Some((port, true)),
+            Protocol::Udp(port) => bind = Some((port, false)),
+            _ => {}
+        }
+    }
+    let (Some(ip), Some((port, tcp))) = (ip, bind) else {
+        return Ok(());
+    };
+    if port == 0 {
+        return Ok(());
+    }
+    let socket = SocketAddr::new(ip, port);
+    let bound = if tcp { TcpListener::bind(socket).map(drop) } else { UdpSocket::bind(socket).map(drop) };
+    bound.map_err(|e| ConfigError::CannotListen { what: what.to_string(), addr: addr.clone(), reason: e.to_string() })
+}
+
+/// Whether the file at `path` can be opened for reading.
+pub fn check_readable(what: &str, path: &Path) -> Result<(), ConfigError> {
+    File::open(path)
+        .map(drop)
+        .map_err(|e| ConfigError::Unreadable { what: what.to_string(), path: path.to_path_buf(), reason: e.to_string() })
+}
+
+/// Whether files can be created in the directory at `path`, or, while it does not exist
+/// yet, in its nearest existing ancestor, where it would be created. A file is created
+/// there and removed again.
+pub fn check_writable_dir(what: &str, path: &Path) -> Result<(), ConfigError> {
+    let not_writable =
+        |reason: String| ConfigError::NotWritable { what: what.to_string(), path: path.to_path_buf(), reason };
+    if path.exists() && !path.is_dir() {
+        return Err(not_writable("not a directory".to_string()));
+    }
+    let Some(existing) = path.ancestors().find(|dir| dir.exists()) else {
+        return Ok(());
+    };
+    let probe = existing.join(format!(".write-check-{}", std::process::id()));
+    File::create(&probe).map_err(|e| not_writable(e.to_string()))?;
+    let _ = fs::remove_file(&probe);
+    Ok(())
+}
+
+/// Open the identity key at `path` without changing it, returning its peer id; `None`
+/// if there is no key yet, so one would be generated.
+pub fn check_identity(path: &Path, passphrase: Option<&str>) -> Result<Option<PeerId>, ConfigError> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(ConfigError::Unreadable {
+                what: "identity key".to_string(),
+                path: path.to_path_buf(),
+                reason: e.to_string(),
+            })
+        }
+    };
+    let key = keys::decode_identity(&bytes, passphrase)
+        .map_err(|e| ConfigError::BadIdentity { path: path.to_path_buf(), reason: e.to_string() })?;
+    Ok(Some(key.public().to_peer_id()))
+}
+
+/// Check the document store at `path` as [`NodeBuilder::with_store_path`] would open it,
+/// only reading it: the directory must be writable and every document must load.
+/// Returns how many documents it holds.
+///
+/// [`NodeBuilder::with_store_path`]: crate::node::NodeBuilder::with_store_path
+pub fn check_store(path: &Path) -> Result<usize, ConfigError> {
+    check_writable_dir("store", path)?;
+    FileDocStore::inspect(path).map_err(|e| ConfigError::BadStore { path: path.to_path_buf(), reason: e.to_string() })
+}
+
+/// Parse the WebRTC certificate at `path`; false if there is none yet, so one would be
+/// generated.
+#[cfg(feature = "webrtc")]
+pub fn check_webrtc_cert(path: &Path) -> Result<bool, ConfigError> {
+    let pem = match fs::read_to_string(path) {
+        Ok(pem) => pem,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => {
+            return Err(ConfigError::Unreadable {
+                what: "WebRTC certificate".to_string(),
+                path: path.to_path_buf(),
+                reason: e.to_string(),
+            })
+        }
+    };
+    libp2p_webrtc::tokio::Certificate::from_pem(&pem)
+        .map(|_| true)
+        .map_err(|e| ConfigError::BadCertificate { path: path.to_path_buf(), reason: e.to_string() })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckStatus {
+    Pass,
+    /// Works, but probably not as intended.
+    Warn,
+    /// The node would not start, or not work.
+    Fail,
+}
+
+impl fmt::Display for CheckStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            CheckStatus::Pass => "pass",
+            CheckStatus::Warn => "warn",
+            CheckStatus::Fail => "FAIL",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckEntry {
+    /// What was checked, e.g. a flag or file.
+    pub name: String,
+    pub status: CheckStatus,
+    pub detail: String,
+}
+
+/// The outcome of every check, in the order they ran. Displays as a table.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CheckReport {
+    pub entries: Vec<CheckEntry>,
+}
+
+impl CheckReport {
+    pub fn push(&mut self, name: impl Into<String>, status: CheckStatus, detail: impl Into<String>) {
+        self.entries.push(CheckEntry { name: name.into(), status, detail: detail.into() });
+    }
+
+    pub fn pass(&mut self, name: impl Into<String>, detail: impl Into<String>) {
+        self.push(name, CheckStatus::Pass, detail);
+    }
+
+    pub fn warn(&mut self, name: impl Into<String>, detail: impl Into<String>) {
+        self.push(name, CheckStatus::Warn, detail);
+    }
+
+    pub fn fail(&mut self, name: impl Into<String>, detail: impl Into<String>) {
+        self.push(name, CheckStatus::Fail, detail);
+    }
+
+    /// Record `result` as a pass described by `detail`, or a failure with its error.
+    /// Returns the value on success.
+    pub fn check<T, E: fmt::Display>(
+        &mut self,
+        name: impl Into<String>,
+        result: Result<T, E>,
+        detail: impl FnOnce(&T) -> String,
+    ) -> Option<T> {
+        match result {
+            Ok(value) => {
+                self.pass(name, detail(&value));
+                Some(value)
+            }
+            Err(e) => {
+                self.fail(name, e.to_string());
+                None
+            }
+        }
+    }
+
+    pub fn failures(&self) -> usize {
+        self.entries.iter().filter(|entry| entry.status == CheckStatus::Fail).count()
+    }
+
+    pub fn warnings(&self) -> usize {
+        self.entries.iter().filter(|entry| entry.status == CheckStatus::Warn).count()
+    }
+}
+
+impl fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let width = self.entries.iter().map(|entry| entry.name.len()).max().unwrap_or(0);
+        for entry in &self.entries {
+            writeln!(f, "{:<4}  {:<width$}  {}", entry.status, entry.name, entry.detail)?;
+        }
+        write!(f, "{} checks, {} warnings, {} failures", self.entries.len(), self.warnings(), self.failures())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("config-check-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn broken_configs_map_to_their_failures() {
+        let dir = temp_dir("broken");
+        let mut report = CheckReport::default();
+
+        // A typo in a bootstrap address
+        let bootstrap = parse_multiaddrs("BOOTSTRAP_PEERS", "/ip4/10.0.0.1/tcp/4001, /ip4/10.0.0.2/tpc/4001");
+        assert!(matches!(&bootstrap, Err(ConfigError::InvalidMultiaddr { addr, .. }) if addr == "/ip4/10.0.0.2/tpc/4001"));
+        report.check("BOOTSTRAP_PEERS", bootstrap, |addrs| format!("{} addresses", addrs.len()));
+
+        // A port another process holds
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr: Multiaddr = format!("/ip4/127.0.0.1/tcp/{}", taken.local_addr().unwrap().port()).parse().unwrap();
+        assert!(matches!(check_listen("--status-port", &addr), Err(ConfigError::CannotListen { .. })));
+        report.check("--status-port", check_listen("--status-port", &addr), |_| String::new());
+        drop(taken);
+        assert_eq!(check_listen("--status-port", &addr), Ok(()));
+        assert_eq!(check_listen("listen address", &"/ip4/0.0.0.0/udp/0/quic-v1".parse().unwrap()), Ok(()));
+
+        // A key file holding something else; a missing one would be generated
+        let key = dir.join("identity.key");
+        assert_eq!(check_identity(&key, None), Ok(None));
+        fs::write(&key, b"not a key").unwrap();
+        let identity = check_identity(&key, None);
+        assert!(matches!(identity, Err(ConfigError::BadIdentity { .. })));
+        report.check("identity key", identity, |_| String::new());
+        let generated = keys::generate_identity(keys::KeyType::Ed25519);
+        fs::write(&key, keys::encode_identity(&generated, None).unwrap()).unwrap();
+        assert_eq!(check_identity(&key, None), Ok(Some(generated.public().to_peer_id())));
+
+        // A store path that is a file, and a passphrase file that is not there
+        let store = dir.join("store");
+        fs::write(&store, b"").unwrap();
+        assert!(matches!(check_store(&store), Err(ConfigError::NotWritable { reason, .. }) if reason == "not a directory"));
+        report.check("store", check_store(&store), |docs| format!("{docs} documents"));
+        let missing = dir.join("passphrase");
+        assert!(matches!(check_readable("--identity-passphrase-file", &missing), Err(ConfigError::Unreadable { .. })));
+
+        // A store still to be created, under a directory that exists
+        assert_eq!(check_store(&dir.join("new/store")), Ok(0));
+        report.pass("data dir", dir.display().to_string());
+        report.warn("--identity-seed-hex", "insecure");
+
+        let statuses: Vec<(&str, CheckStatus)> = report.entries.iter().map(|e| (e.name.as_str(), e.status)).collect();
+        assert_eq!(
+            statuses,
+            [
+                ("BOOTSTRAP_PEERS", CheckStatus::Fail),
+                ("--status-port", CheckStatus::Fail),
+                ("identity key", CheckStatus::Fail),
+                ("store", CheckStatus::Fail),
+                ("data dir", CheckStatus::Pass),
+                ("--identity-seed-hex", CheckStatus::Warn),
+            ]
+        );
+        let table = report.to_string();
+        assert!(table.starts_with("FAIL  BOOTSTRAP_PEERS      BOOTSTRAP_PEERS: invalid multiaddr"));
+        assert!(table.ends_with("6 checks, 1 warnings, 4 failures"));
+    }
+}
@@ -0,0 +1,213 @@
+//! Connection progress per peer (dialing, connected) and why dials fail, for UIs that
+//! want to show more than "connected" or "not connected".
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+
+use libp2p::core::transport::TransportError;
+use libp2p::core::ConnectedPoint;
+use libp2p::swarm::DialError;
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Disconnected,
+    /// A dial is in flight and no connection is up yet.
+    Dialing,
+    Connected,
+}
+
+impl ConnectionState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ConnectionState::Disconnected => "disconnected",
+            ConnectionState::Dialing => "dialing",
+            ConnectionState::Connected => "connected",
+        }
+    }
+}
+
+/// Tracks [`ConnectionState`] from swarm events.
+#[derive(Debug, Default)]
+pub struct ConnectionStates {
+    /// Dials in flight per peer.
+    dialing: HashMap<PeerId, usize>,
+    connected: HashSet<PeerId>,
+}
+
+impl ConnectionStates {
+    /// `SwarmEvent::Dialing`
+    pub fn dialing(&mut self, peer_id: PeerId) {
+        *self.dialing.entry(peer_id).or_insert(0) += 1;
+    }
+
+    /// `SwarmEvent::ConnectionEstablished`; `dialer` is whether we dialed it.
+    pub fn established(&mut self, peer_id: PeerId, dialer: bool) {
+        if dialer {
+            self.dial_finished(&peer_id);
+        }
+        self.connected.insert(peer_id);
+    }
+
+    /// `SwarmEvent::OutgoingConnectionError`
+    pub fn dial_failed(&mut self, peer_id: &PeerId) {
+        self.dial_finished(peer_id);
+    }
+
+    /// `SwarmEvent::ConnectionClosed`
+    pub fn closed(&mut self, peer_id: &PeerId, num_established: u32) {
+        if num_established == 0 {
+            self.connected.remove(peer_id);
+        }
+    }
+
+    pub fn state(&self, peer_id: &PeerId) -> ConnectionState {
+        if self.connected.contains(peer_id) {
+            ConnectionState::Connected
+        } else if self.dialing.contains_key(peer_id) {
+            ConnectionState::Dialing
+        } else {
+            ConnectionState::Disconnected
+        }
+    }
+
+    fn dial_finished(&mut self, peer_id: &PeerId) {
+        if let Some(count) = self.dialing.get_mut(peer_id) {
+            *count -= 1;
+            if *count == 0 {
+                self.dialing.remove(peer_id);
+            }
+        }
+    }
+}
+
+/// `"outbound"` for connections we dialed, `"inbound"` for ones we accepted.
+pub fn direction(endpoint: &ConnectedPoint) -> &'static str {
+    if endpoint.is_dialer() {
+        "outbound"
+    } else {
+        "inbound"
+    }
+}
+
+/// Why a dial failed, coarse enough to act on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DialFailure {
+    /// No answer in time.
+    Timeout,
+    /// The remote (or something on the way) actively refused or reset the connection.
+    Refused,
+    /// Someone answered, but not the peer we asked for.
+    WrongPeerId,
+    /// None of the addresses can be dialed by our transports.
+    TransportUnsupported,
+    /// The configured outbound proxy itself could not be reached, whatever the target.
+    ProxyUnreachable,
+    Other,
+}
+
+impl DialFailure {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DialFailure::Timeout => "timeout",
+            DialFailure::Refused => "refused",
+            DialFailure::WrongPeerId => "wrong_peer_id",
+            DialFailure::TransportUnsupported => "transport_unsupported",
+            DialFailure::ProxyUnreachable => "proxy_unreachable",
+            DialFailure::Other => "other",
+        }
+    }
+}
+
+impl From<&DialError> for DialFailure {
+    /// For dials over several addresses, the first address our transports could attempt
+    /// decides.
+    fn from(error: &DialError) -> Self {
+        match error {
+            DialError::WrongPeerId { .. } => DialFailure::WrongPeerId,
+            DialError::Transport(failed) => failed
+                .iter()
+                .find_map(|(_, e)| match e {
+                    TransportError::MultiaddrNotSupported(_) => None,
+                    TransportError::Other(e) => Some(io_failure(e)),
+                })
+                .unwrap_or(if failed.is_empty() { DialFailure::Other } else { DialFailure::TransportUnsupported }),
+            _ => DialFailure::Other,
+        }
+    }
+}
+
+fn io_failure(error: &io::Error) -> DialFailure {
+    #[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+    if crate::node::proxy::is_proxy_unreachable(error) {
+        return DialFailure::ProxyUnreachable;
+    }
+    match error.kind() {
+        io::ErrorKind::TimedOut => DialFailure::Timeout,
+        io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted => {
+            DialFailure::Refused
+        }
+        _ => DialFailure::Other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::Multiaddr;
+
+    fn transport(errors: Vec<TransportError<io::Error>>) -> DialError {
+        let addr: Multiaddr = "/ip4/127.0.0.1/tcp/4001".parse().unwrap();
+        DialError::Transport(errors.into_iter().map(|e| (addr.clone(), e)).collect())
+    }
+
+    fn io(kind: io::ErrorKind) -> TransportError<io::Error> {
+        TransportError::Other(io::Error::new(kind, "dial"))
+    }
+
+    fn unsupported() -> TransportError<io::Error> {
+        TransportError::MultiaddrNotSupported("/dns4/example.com/tcp/443/wss".parse().unwrap())
+    }
+
+    #[test]
+    fn categorizes_dial_errors() {
+        let category = |e: DialError| DialFailure::from(&e);
+        assert_eq!(category(transport(vec![io(io::ErrorKind::TimedOut)])), DialFailure::Timeout);
+        assert_eq!(category(transport(vec![io(io::ErrorKind::ConnectionRefused)])), DialFailure::Refused);
+        assert_eq!(category(transport(vec![unsupported(), unsupported()])), DialFailure::TransportUnsupported);
+        // An address we could try says more than the ones we couldn't
+        assert_eq!(category(transport(vec![unsupported(), io(io::ErrorKind::ConnectionReset)])), DialFailure::Refused);
+        assert_eq!(category(transport(vec![io(io::ErrorKind::Other)])), DialFailure::Other);
+        assert_eq!(
+            category(DialError::WrongPeerId {
+                obtained: PeerId::random(),
+                address: "/ip4/127.0.0.1/tcp/4001".parse().unwrap(),
+            }),
+            DialFailure::WrongPeerId
+        );
+        assert_eq!(category(DialError::NoAddresses), DialFailure::Other);
+        assert_eq!(category(DialError::Aborted).as_str(), "other");
+    }
+
+    #[test]
+    fn tracks_dialing_and_connected_peers() {
+        let mut states = ConnectionStates::default();
+        let peer = PeerId::random();
+        assert_eq!(states.state(&peer), ConnectionState::Disconnected);
+
+        states.dialing(peer);
+        states.dialing(peer);
+        assert_eq!(states.state(&peer), ConnectionState::Dialing);
+        states.dial_failed(&peer);
+        assert_eq!(states.state(&peer), ConnectionState::Dialing);
+        states.established(peer, true);
+        assert_eq!(states.state(&peer).as_str(), "connected");
+
+        states.established(peer, false);
+        states.closed(&peer, 1);
+        assert_eq!(states.state(&peer), ConnectionState::Connected);
+        states.closed(&peer, 0);
+        assert_eq!(states.state(&peer), ConnectionState::Disconnected);
+    }
+}
@@ -0,0 +1,199 @@
+//! Where the native binaries keep their files: the identity key, the WebRTC certificate,
+//! the address book and document stores, all under one data directory.
+//!
+//! The directory is, in order: `--data-dir`, the `P2P_DATA_DIR` environment variable,
+//! `./.p2p` if it already holds an identity key (where earlier versions kept it), the
+//! platform's per-user data directory, and `./.p2p` when there is none. A service started
+//! from `system32` on Windows thus keeps its key under `%APPDATA%` instead of the working
+//! directory. `IDENTITY_KEY_PATH` and `CERT_PATH` still override single files, relative
+//! paths being relative to the working directory as before.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Name of the per-app directory inside the platform data directory.
+pub const APP_DIR: &str = "simple-p2p-docstore";
+/// The directory earlier versions always used, relative to the working directory.
+pub const LEGACY_DIR: &str = ".p2p";
+
+const IDENTITY_KEY_FILE: &str = "identity.key";
+const WEBRTC_CERT_FILE: &str = "webrtc_cert.pem";
+const ADDRESS_BOOK_FILE: &str = "address-book.json";
+const STORE_DIR: &str = "store";
+
+/// Whose conventions the default directory follows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Platform {
+    /// `%APPDATA%\simple-p2p-docstore`.
+    Windows,
+    /// `~/Library/Application Support/simple-p2p-docstore`.
+    MacOs,
+    /// `$XDG_DATA_HOME/simple-p2p-docstore`, or `~/.local/share/simple-p2p-docstore`.
+    Unix,
+}
+
+impl Platform {
+    pub fn current() -> Self {
+        if cfg!(windows) {
+            Platform::Windows
+        } else if cfg!(target_os = "macos") {
+            Platform::MacOs
+        } else {
+            Platform::Unix
+        }
+    }
+
+    /// The per-user data directory for the app; `None` if the environment variables it
+    /// derives from are unset, as for some service accounts.
+    pub fn data_dir(self, env: impl Fn(&str) -> Option<String>) -> Option<PathBuf> {
+        let var = |name: &str| env(name).filter(|v| !v.is_empty()).map(PathBuf::from);
+        let base = match self {
+            Platform::Windows => var("APPDATA")?,
+            Platform::MacOs => var("HOME")?.join("Library").join("Application Support"),
+            Platform::Unix => var("XDG_DATA_HOME").or_else(|| Some(var("HOME")?.join(".local").join("share")))?,
+        };
+        Some(base.join(APP_DIR))
+    }
+}
+
+/// The resolved data directory, see the [module docs](self).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataDir {
+    root: PathBuf,
+}
+
+impl DataDir {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// Pick the directory from `flag` (`--data-dir`), the environment and `cwd`, in the
+    /// order given in the [module docs](self).
+    pub fn resolve(flag: Option<PathBuf>, env: impl Fn(&str) -> Option<String>, cwd: &Path, platform: Platform) -> Self {
+        if let Some(root) = flag.or_else(|| env("P2P_DATA_DIR").filter(|v| !v.is_empty()).map(PathBuf::from)) {
+            return Self::new(root);
+        }
+        let legacy = cwd.join(LEGACY_DIR);
+        if legacy.join(IDENTITY_KEY_FILE).exists() {
+            return Self::new(legacy);
+        }
+        Self::new(platform.data_dir(env).unwrap_or(legacy))
+    }
+
+    /// [`resolve`](Self::resolve) against this process's environment and working directory.
+    pub fn from_env(flag: Option<PathBuf>) -> io::Result<Self> {
+        let cwd = std::env::current_dir()?;
+        Ok(Self::resolve(flag, |name| std::env::var(name).ok(), &cwd, Platform::current()))
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// `IDENTITY_KEY_PATH` from `env` if set, else `identity.key` in the directory.
+    pub fn identity_key(&self, env: impl Fn(&str) -> Option<String>) -> PathBuf {
+        env("IDENTITY_KEY_PATH").map_or_else(|| self.root.join(IDENTITY_KEY_FILE), PathBuf::from)
+    }
+
+    /// `CERT_PATH` from `env` if set, else `webrtc_cert.pem` in the directory.
+    pub fn webrtc_cert(&self, env: impl Fn(&str) -> Option<String>) -> PathBuf {
+        env("CERT_PATH").map_or_else(|| self.root.join(WEBRTC_CERT_FILE), PathBuf::from)
+    }
+
+    pub fn address_book(&self) -> PathBuf {
+        self.root.join(ADDRESS_BOOK_FILE)
+    }
+
+    /// Directory for [`NodeBuilder::with_store_path`](crate::node::NodeBuilder::with_store_path).
+    pub fn store(&self) -> PathBuf {
+        self.root.join(STORE_DIR)
+    }
+}
+
+/// Make `path` readable and writable by its owner only: mode 0600 on unix, and on
+/// Windows an ACL granting the current user full control with inherited entries removed.
+pub fn restrict_to_owner(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+    }
+    #[cfg(windows)]
+    {
+        let user = std::env::var("USERNAME").map_err(|_| io::Error::other("USERNAME is not set"))?;
+        let status = std::process::Command::new("icacls")
+            .arg(path)
+            .args(["/inheritance:r", "/grant:r"])
+            .arg(format!("{user}:F"))
+            .stdout(std::process::Stdio::null())
+            .status()?;
+        if !status.success() {
+            return Err(io::Error::other(format!("icacls exited with {status}")));
+        }
+        Ok(())
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        let _ = path;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    #[test]
+    fn platform_defaults() {
+        let home = env(&[("HOME", "/home/ada"), ("APPDATA", r"C:\Users\ada\AppData\Roaming")]);
+        assert_eq!(Platform::Windows.data_dir(&home), Some(PathBuf::from(r"C:\Users\ada\AppData\Roaming").join(APP_DIR)));
+        let under_home = |parts: &[&str]| Some(parts.iter().fold(PathBuf::from("/home/ada"), |p, part| p.join(part)).join(APP_DIR));
+        assert_eq!(Platform::MacOs.data_dir(&home), under_home(&["Library", "Application Support"]));
+        assert_eq!(Platform::Unix.data_dir(&home), under_home(&[".local", "share"]));
+        let xdg = env(&[("HOME", "/home/ada"), ("XDG_DATA_HOME", "/data")]);
+        assert_eq!(Platform::Unix.data_dir(xdg), Some(PathBuf::from("/data").join(APP_DIR)));
+        // A service account without a profile
+        assert_eq!(Platform::Windows.data_dir(env(&[("APPDATA", "")])), None);
+    }
+
+    #[test]
+    fn resolution_precedence() {
+        let cwd = std::env::temp_dir().join(format!("data-dir-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&cwd);
+        std::fs::create_dir_all(&cwd).unwrap();
+        let vars = env(&[("HOME", "/home/ada"), ("APPDATA", r"C:\Roaming"), ("P2P_DATA_DIR", "/srv/p2p")]);
+
+        for platform in [Platform::Windows, Platform::MacOs, Platform::Unix] {
+            let flag = Some(PathBuf::from("/opt/node"));
+            assert_eq!(DataDir::resolve(flag, &vars, &cwd, platform).root(), Path::new("/opt/node"));
+            assert_eq!(DataDir::resolve(None, &vars, &cwd, platform).root(), Path::new("/srv/p2p"));
+            let home = env(&[("HOME", "/home/ada"), ("APPDATA", r"C:\Roaming")]);
+            assert_eq!(DataDir::resolve(None, &home, &cwd, platform).root(), platform.data_dir(&home).unwrap());
+            assert_eq!(DataDir::resolve(None, env(&[]), &cwd, platform).root(), cwd.join(LEGACY_DIR));
+        }
+
+        // A key left in ./.p2p by an earlier version keeps being used
+        std::fs::create_dir_all(cwd.join(LEGACY_DIR)).unwrap();
+        std::fs::write(cwd.join(LEGACY_DIR).join(IDENTITY_KEY_FILE), b"key").unwrap();
+        let home = env(&[("HOME", "/home/ada")]);
+        assert_eq!(DataDir::resolve(None, &home, &cwd, Platform::Unix).root(), cwd.join(LEGACY_DIR));
+        std::fs::remove_dir_all(&cwd).unwrap();
+    }
+
+    #[test]
+    fn single_files_can_be_overridden() {
+        let dir = DataDir::new("/srv/p2p");
+        assert_eq!(dir.identity_key(env(&[])), Path::new("/srv/p2p").join("identity.key"));
+        // Relative, as the Docker image and existing setups pass it
+        let explicit = env(&[("IDENTITY_KEY_PATH", "keys/id.key"), ("CERT_PATH", "cert.pem")]);
+        assert_eq!(dir.identity_key(&explicit), Path::new("keys/id.key"));
+        assert_eq!(dir.webrtc_cert(&explicit), Path::new("cert.pem"));
+        assert_eq!(dir.store(), Path::new("/srv/p2p").join("store"));
+    }
+}
@@ -0,0 +1,234 @@
+//! Which updates the app has already been handed, kept across page reloads.
+//!
+//! Gossipsub only remembers message ids in memory, so a browser that reloads gets
+//! updates it already applied again through catch-up and replays. A node that persists
+//! a [`DedupCache`] (see `dedupCacheKey` of the wasm node) loads it at startup and does
+//! not deliver those a second time.
+//!
+//! An update is known by its document and its stamp's HLC, which strictly increases
+//! across everything one author publishes. Unstamped updates cannot be told apart from
+//! a legitimate repeat and are always delivered. The cache is bounded per document and
+//! in total, forgetting the oldest entries first; a forgotten update is delivered again,
+//! but nothing is ever suppressed that was not delivered before.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use serde::{Deserialize, Serialize};
+
+use crate::behaviour::docstore::{Hlc, Stamp};
+
+/// Updates remembered per document.
+pub const DEFAULT_PER_DOC: usize = 256;
+
+/// Updates remembered across documents.
+pub const DEFAULT_TOTAL: usize = 8192;
+
+#[derive(Debug, Clone)]
+pub struct DedupCache {
+    per_doc: usize,
+    total: usize,
+    /// Every remembered update, oldest first.
+    order: VecDeque<(String, Hlc)>,
+    index: HashSet<(String, Hlc)>,
+    per_doc_count: HashMap<String, usize>,
+    /// Changed since the last [`DedupCache::take_dirty`].
+    dirty: bool,
+}
+
+/// The encoded form: the entries, oldest first.
+#[derive(Serialize, Deserialize)]
+struct Saved {
+    entries: Vec<(String, Hlc)>,
+}
+
+impl Default for DedupCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_PER_DOC, DEFAULT_TOTAL)
+    }
+}
+
+impl DedupCache {
+    pub fn new(per_doc: usize, total: usize) -> Self {
+        Self {
+            per_doc,
+            total,
+            order: VecDeque::new(),
+            index: HashSet::new(),
+            per_doc_count: HashMap::new(),
+            dirty: false,
+        }
+    }
+
+    /// Whether the update of `doc_id` with `stamp` was delivered before, as far as the
+    /// cache remembers.
+    pub fn seen(&self, doc_id: &str, stamp: Option<&Stamp>) -> bool {
+        stamp.is_some_and(|stamp| self.index.contains(&(doc_id.to_string(), stamp.hlc)))
+    }
+
+    /// Remember that the update of `doc_id` with `stamp` was delivered. Unstamped
+    /// updates are not remembered.
+    pub fn delivered(&mut self, doc_id: &str, stamp: Option<&Stamp>) {
+        if let Some(stamp) = stamp {
+            self.remember(doc_id.to_string(), stamp.hlc);
+        }
+    }
+
+    /// Forget everything.
+    pub fn clear(&mut self) {
+        self.dirty |= !self.order.is_empty();
+        self.order.clear();
+        self.index.clear();
+        self.per_doc_count.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    /// Whether the cache changed since the last call, i.e. needs saving.
+    pub fn take_dirty(&mut self) -> bool {
+        std::mem::take(&mut self.dirty)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        postcard::to_allocvec(&Saved { entries: self.order.iter().cloned().collect() }).expect("in-memory encoding")
+    }
+
+    /// A cache saved by [`DedupCache::encode`], held to `per_doc` and `total`: if they
+    /// shrank, the oldest entries are dropped.
+    pub fn decode(bytes: &[u8], per_doc: usize, total: usize) -> Result<Self, postcard::Error> {
+        let saved: Saved = postcard::from_bytes(bytes)?;
+        let mut cache = Self::new(per_doc, total);
+        for (doc_id, hlc) in saved.entries {
+            cache.remember(doc_id, hlc);
+        }
+        cache.dirty = false;
+        Ok(cache)
+    }
+
+    fn remember(&mut self, doc_id: String, hlc: Hlc) {
+        let key = (doc_id, hlc);
+        if self.per_doc == 0 || self.total == 0 || !self.index.insert(key.clone()) {
+            return;
+        }
+        self.dirty = true;
+        let count = self.per_doc_count.entry(key.0.clone()).or_default();
+        *count += 1;
+        if *count > self.per_doc {
+            let oldest = self.order.iter().position(|(doc_id, _)| *doc_id == key.0).expect("counted");
+            let removed = self.order.remove(oldest).expect("in range");
+            self.forget(&removed);
+        }
+        self.order.push_back(key);
+        while self.order.len() > self.total {
+            let removed = self.order.pop_front().expect("not empty");
+            self.forget(&removed);
+        }
+    }
+
+    fn forget(&mut self, (doc_id, hlc): &(String, Hlc)) {
+        self.index.remove(&(doc_id.clone(), *hlc));
+        if let Some(count) = self.per_doc_count.get_mut(doc_id) {
+            *count -= 1;
+            if *count == 0 {
+                self.per_doc_count.remove(doc_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stamp(wall_ms: u64, node: u64) -> Stamp {
+        Stamp { hlc: Hlc { wall_ms, logical: 0, node }, clock: Default::default() }
+    }
+
+    #[test]
+    fn remembers_stamped_updates_within_its_caps_and_across_a_reload() {
+        let mut cache = DedupCache::new(2, 3);
+        cache.delivered("a", Some(&stamp(1, 7)));
+        assert!(cache.seen("a", Some(&stamp(1, 7))));
+        // Same stamp, other document; other author, same time
+        assert!(!cache.seen("b", Some(&stamp(1, 7))));
+        assert!(!cache.seen("a", Some(&stamp(1, 8))));
+        // Nothing tells an unstamped repeat from a new edit
+        cache.delivered("a", None);
+        assert!(!cache.seen("a", None));
+        assert!(cache.take_dirty());
+        assert!(!cache.take_dirty());
+
+        // The oldest of a document goes first, then the oldest overall
+        cache.delivered("a", Some(&stamp(2, 7)));
+        cache.delivered("a", Some(&stamp(3, 7)));
+        assert!(!cache.seen("a", Some(&stamp(1, 7))));
+        cache.delivered("b", Some(&stamp(1, 7)));
+        cache.delivered("b", Some(&stamp(2, 7)));
+        assert_eq!(cache.len(), 3);
+        assert!(!cache.seen("a", Some(&stamp(2, 7))));
+        assert!(cache.seen("a", Some(&stamp(3, 7))));
+
+        let reloaded = DedupCache::decode(&cache.encode(), 2, 3).unwrap();
+        assert!(reloaded.seen("a", Some(&stamp(3, 7))) && reloaded.seen("b", Some(&stamp(2, 7))));
+        let smaller = DedupCache::decode(&cache.encode(), 2, 1).unwrap();
+        assert!(smaller.seen("b", Some(&stamp(2, 7))) && !smaller.seen("a", Some(&stamp(3, 7))));
+        assert!(DedupCache::decode(b"\xff", 2, 3).is_err());
+
+        cache.clear();
+        assert!(cache.is_empty() && cache.take_dirty());
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    mod properties {
+        use proptest::collection::vec;
+        use proptest::prelude::*;
+
+        use super::*;
+
+        proptest! {
+            /// Whatever is delivered, reloaded or forgotten, only updates delivered before
+            /// are ever reported seen, and the caps hold.
+            #[test]
+            fn never_suppresses_an_update_not_delivered_before(
+                ops in vec((0u8..4, 0u8..3, 0u64..6, 0u64..2), 0..200),
+                per_doc in 0usize..5,
+                total in 0usize..8,
+            ) {
+                let mut cache = DedupCache::new(per_doc, total);
+                let mut delivered = HashSet::new();
+                for (op, doc, wall_ms, node) in ops {
+                    let doc_id = format!("doc-{doc}");
+                    let incoming = stamp(wall_ms, node);
+                    match op {
+                        0 | 1 => {
+                            if !cache.seen(&doc_id, Some(&incoming)) {
+                                delivered.insert((doc_id.clone(), wall_ms, node));
+                                cache.delivered(&doc_id, Some(&incoming));
+                            }
+                        }
+                        2 => cache = DedupCache::decode(&cache.encode(), per_doc, total).unwrap(),
+                        _ => prop_assert!(!cache.seen(&doc_id, None)),
+                    }
+                    for doc in 0..3 {
+                        let doc_id = format!("doc-{doc}");
+                        for wall_ms in 0..6 {
+                            for node in 0..2 {
+                                if cache.seen(&doc_id, Some(&stamp(wall_ms, node))) {
+                                    prop_assert!(delivered.contains(&(doc_id.clone(), wall_ms, node)));
+                                }
+                            }
+                        }
+                        prop_assert!(cache.per_doc_count.get(&doc_id).copied().unwrap_or(0) <= per_doc);
+                    }
+                    prop_assert!(cache.len() <= total);
+                    prop_assert_eq!(cache.index.len(), cache.len());
+                }
+            }
+        }
+    }
+}
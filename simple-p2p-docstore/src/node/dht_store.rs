@@ -0,0 +1,224 @@
+//! What the Kademlia record store holds, and when it is full.
+//!
+//! The store's limits come from [`PeerDhtConfig::store`]. Once one is reached the store
+//! refuses further records quietly, so every put and provider announcement goes through a
+//! [`DhtStoreMonitor`], which counts the refusals per limit and says when a limit is hit
+//! for the first time since the store last accepted something.
+//!
+//! [`PeerDhtConfig::store`]: crate::behaviour::PeerDhtConfig::store
+
+use std::collections::HashSet;
+use std::fmt;
+
+use libp2p_kad::store::{self, MemoryStore, RecordStore};
+use libp2p_kad::RecordKey;
+
+/// The store limit a put or provider announcement ran into.
+///
+/// Going over `max_providers_per_key` is not one of them: the store then ignores further
+/// providers of that key, which is what the limit is for, and keeps no count of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StoreFullKind {
+    /// `max_records` records are stored already.
+    Records,
+    /// The record's value is larger than `max_value_bytes`.
+    ValueTooLarge,
+    /// Providers are stored for `max_provided_keys` keys already.
+    ProvidedKeys,
+}
+
+impl StoreFullKind {
+    pub const ALL: [Self; 3] = [Self::Records, Self::ValueTooLarge, Self::ProvidedKeys];
+
+    pub fn of(error: &store::Error) -> Self {
+        match error {
+            store::Error::MaxRecords => Self::Records,
+            store::Error::ValueTooLarge => Self::ValueTooLarge,
+            store::Error::MaxProvidedKeys => Self::ProvidedKeys,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Records => "records",
+            Self::ValueTooLarge => "value_too_large",
+            Self::ProvidedKeys => "provided_keys",
+        }
+    }
+}
+
+impl fmt::Display for StoreFullKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A put or provider announcement the store refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("DHT record store is full ({kind})")]
+pub struct StoreFull {
+    pub kind: StoreFullKind,
+    /// The first refusal for `kind` since the store last accepted something of the same
+    /// sort; report it. Later ones are only counted.
+    pub newly: bool,
+}
+
+/// How much the record store holds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DhtStoreStats {
+    pub records: usize,
+    /// Keys plus values of the stored records.
+    pub record_bytes: usize,
+    /// Provider records, ours and other peers'.
+    pub providers: usize,
+    /// Keys with at least one provider.
+    pub provider_keys: usize,
+}
+
+/// Watches puts into a [`MemoryStore`], see the [module docs](self).
+#[derive(Debug, Default)]
+pub struct DhtStoreMonitor {
+    /// `MemoryStore` can't list every key it has providers for; these are the ones we saw
+    /// go in.
+    provider_keys: HashSet<RecordKey>,
+    full: HashSet<StoreFullKind>,
+    refused: [u64; 3],
+}
+
+impl DhtStoreMonitor {
+    /// The outcome of storing a record, in the store directly or through Kademlia.
+    pub fn put<T>(&mut self, result: Result<T, store::Error>) -> Result<T, StoreFull> {
+        if result.is_ok() {
+            self.full.remove(&StoreFullKind::Records);
+            self.full.remove(&StoreFullKind::ValueTooLarge);
+        }
+        result.map_err(|e| self.refuse(StoreFullKind::of(&e)))
+    }
+
+    /// The outcome of storing a provider record for `key`, ours or another peer's.
+    pub fn provide<T>(&mut self, key: &RecordKey, result: Result<T, store::Error>) -> Result<T, StoreFull> {
+        if result.is_ok() {
+            self.full.remove(&StoreFullKind::ProvidedKeys);
+            self.provider_keys.insert(key.clone());
+        }
+        result.map_err(|e| self.refuse(StoreFullKind::of(&e)))
+    }
+
+    fn refuse(&mut self, kind: StoreFullKind) -> StoreFull {
+        self.refused[kind as usize] += 1;
+        StoreFull { kind, newly: self.full.insert(kind) }
+    }
+
+    /// Puts and announcements refused because of `kind`, since startup.
+    pub fn refused(&self, kind: StoreFullKind) -> u64 {
+        self.refused[kind as usize]
+    }
+
+    /// Limits hit and not cleared since.
+    pub fn is_full(&self, kind: StoreFullKind) -> bool {
+        self.full.contains(&kind)
+    }
+
+    /// Count what `store` holds, forgetting keys whose providers have all expired.
+    pub fn stats(&mut self, store: &MemoryStore) -> DhtStoreStats {
+        let mut stats = DhtStoreStats::default();
+        for record in store.records() {
+            stats.records += 1;
+            stats.record_bytes += record.key.as_ref().len() + record.value.len();
+        }
+        self.provider_keys.retain(|key| {
+            let providers = store.providers(key).len();
+            stats.providers += providers;
+            providers > 0
+        });
+        stats.provider_keys = self.provider_keys.len();
+        stats
+    }
+
+    /// `/metrics` lines: refusals per limit and the store's contents.
+    pub fn metrics(&mut self, store: &MemoryStore) -> Vec<(String, u64)> {
+        let stats = self.stats(store);
+        let mut metrics: Vec<(String, u64)> = StoreFullKind::ALL
+            .iter()
+            .map(|kind| (format!("docstore_dht_store_full_total{{kind=\"{kind}\"}}"), self.refused(*kind)))
+            .collect();
+        metrics.extend([
+            ("docstore_dht_store_records".to_string(), stats.records as u64),
+            ("docstore_dht_store_record_bytes".to_string(), stats.record_bytes as u64),
+            ("docstore_dht_store_providers".to_string(), stats.providers as u64),
+        ]);
+        metrics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::behaviour::PeerDhtConfig;
+    use libp2p::PeerId;
+    use libp2p_kad::{store::MemoryStoreConfig, ProviderRecord, Record};
+
+    /// A store built the way `make_peer_dht_with` builds it.
+    fn store(config: MemoryStoreConfig) -> MemoryStore {
+        let dht = PeerDhtConfig { store: config, ..Default::default() };
+        MemoryStore::with_config(PeerId::random(), dht.store)
+    }
+
+    fn record(key: &str, len: usize) -> Record {
+        Record::new(RecordKey::new(&key), vec![0; len])
+    }
+
+    #[test]
+    fn full_record_store_is_reported_once() {
+        let mut store = store(MemoryStoreConfig { max_records: 2, max_value_bytes: 100, ..Default::default() });
+        let mut monitor = DhtStoreMonitor::default();
+        for key in ["a", "b"] {
+            assert_eq!(monitor.put(store.put(record(key, 10))), Ok(()));
+        }
+        let full = StoreFull { kind: StoreFullKind::Records, newly: true };
+        assert_eq!(monitor.put(store.put(record("c", 10))), Err(full));
+        assert_eq!(monitor.put(store.put(record("d", 10))), Err(StoreFull { newly: false, ..full }));
+        assert_eq!(monitor.refused(StoreFullKind::Records), 2);
+
+        assert_eq!(
+            monitor.put(store.put(record("a", 101))),
+            Err(StoreFull { kind: StoreFullKind::ValueTooLarge, newly: true })
+        );
+
+        // Replacing a stored record still works, and clears the state
+        assert_eq!(monitor.put(store.put(record("a", 20))), Ok(()));
+        assert!(!monitor.is_full(StoreFullKind::Records));
+        assert_eq!(monitor.put(store.put(record("c", 10))), Err(full));
+
+        let stats = monitor.stats(&store);
+        assert_eq!((stats.records, stats.record_bytes), (2, 1 + 20 + 1 + 10));
+    }
+
+    #[test]
+    fn provider_keys_and_providers_per_key_are_bounded() {
+        let local = PeerId::random();
+        let mut store = store(MemoryStoreConfig { max_provided_keys: 1, max_providers_per_key: 2, ..Default::default() });
+        let mut monitor = DhtStoreMonitor::default();
+        let provide = |key: &RecordKey, provider| ProviderRecord::new(key.clone(), provider, Vec::new());
+
+        let (first, second) = (RecordKey::new(&"first"), RecordKey::new(&"second"));
+        assert_eq!(monitor.provide(&first, store.add_provider(provide(&first, local))), Ok(()));
+        assert_eq!(
+            monitor.provide(&second, store.add_provider(provide(&second, PeerId::random()))),
+            Err(StoreFull { kind: StoreFullKind::ProvidedKeys, newly: true })
+        );
+
+        // A full provider list takes no more providers, without an error
+        for _ in 0..2 {
+            assert_eq!(monitor.provide(&first, store.add_provider(provide(&first, PeerId::random()))), Ok(()));
+        }
+        assert!(!monitor.is_full(StoreFullKind::ProvidedKeys));
+        let stats = monitor.stats(&store);
+        assert_eq!((stats.providers, stats.provider_keys), (2, 1));
+
+        for provider in store.providers(&first) {
+            store.remove_provider(&first, &provider.provider);
+        }
+        assert_eq!(monitor.stats(&store), DhtStoreStats::default());
+    }
+}
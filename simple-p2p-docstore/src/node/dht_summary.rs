@@ -0,0 +1,50 @@
+//! A small health summary of the Kademlia routing table, so applications can show how
+//! much of the DHT this node sees and notice when it sees none of it.
+
+use libp2p_kad::{store::MemoryStore, Behaviour as KademliaBehaviour};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DhtSummary {
+    /// Non-empty k-buckets.
+    pub buckets: usize,
+    /// Peers in the routing table.
+    pub peers: usize,
+    /// Peers waiting for a slot in a full bucket.
+    pub pending: usize,
+}
+
+impl DhtSummary {
+    pub fn of(kademlia: &mut KademliaBehaviour<MemoryStore>) -> Self {
+        let mut summary = Self::default();
+        for bucket in kademlia.kbuckets() {
+            summary.buckets += 1;
+            summary.peers += bucket.num_entries();
+            if bucket.has_pending() {
+                summary.pending += 1;
+            }
+        }
+        summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::PeerId;
+
+    #[test]
+    fn counts_routing_table_entries() {
+        let local = PeerId::random();
+        let mut kademlia = KademliaBehaviour::new(local, MemoryStore::new(local));
+        assert_eq!(DhtSummary::of(&mut kademlia), DhtSummary::default());
+
+        let peer = PeerId::random();
+        kademlia.add_address(&peer, "/ip4/10.0.0.1/tcp/4001".parse().unwrap());
+        let summary = DhtSummary::of(&mut kademlia);
+        assert_eq!((summary.buckets, summary.peers, summary.pending), (1, 1, 0));
+
+        kademlia.remove_peer(&peer);
+        assert_eq!(DhtSummary::of(&mut kademlia).peers, 0);
+    }
+}
@@ -0,0 +1,389 @@
+//! Outbound dials that are retried on transient failures.
+//!
+//! A refused or timed-out dial (a relay that is still booting, a flaky link) is tried
+//! again with exponential backoff. Failures that won't go away by waiting (the wrong peer
+//! answered, no transport for the address) end the dial at once.
+//!
+//! Dials by peer id try several addresses, a few at a time (see [`DialConcurrency`]), in
+//! the order [`plan`] puts them in: the ones likely to connect first, relay circuits last.
+
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::num::NonZeroU8;
+use std::time::Duration;
+
+use libp2p::multiaddr::Protocol;
+use libp2p::swarm::ConnectionId;
+use libp2p::{Multiaddr, PeerId};
+use web_time::Instant;
+
+use super::address_book::AddrRecord;
+use super::addrs::{peer_id_of, relay_of};
+use super::connections::DialFailure;
+use crate::Error;
+
+/// Upper bound on the delay between attempts.
+pub const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// How a dial is retried. The default dials once, as `swarm.dial` does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DialOptions {
+    /// Attempts after the first one, for retryable failures only.
+    pub retries: u32,
+    /// Delay before the first retry; doubled for each one after it.
+    pub backoff: Duration,
+    /// No retry starts later than this after the first attempt.
+    pub timeout: Option<Duration>,
+    /// Reject addresses without a `/p2p/<peer id>`, so we never connect to whoever
+    /// happens to answer.
+    pub require_peer_id: bool,
+}
+
+impl Default for DialOptions {
+    fn default() -> Self {
+        Self { retries: 0, backoff: Duration::from_secs(1), timeout: None, require_peer_id: false }
+    }
+}
+
+impl DialOptions {
+    /// For bootstrap and relay addresses: five retries over about half a minute.
+    pub fn retrying() -> Self {
+        Self { retries: 5, timeout: Some(Duration::from_secs(60)), ..Default::default() }
+    }
+
+    /// The peer id `addr` ends in, or an error if one is required and missing.
+    pub fn check(&self, addr: &Multiaddr) -> Result<Option<PeerId>, Error> {
+        match peer_id_of(addr) {
+            None if self.require_peer_id => {
+                Err(Error::Transport(format!("{addr} has no /p2p/ peer id and one is required")))
+            }
+            peer_id => Ok(peer_id),
+        }
+    }
+
+    fn backoff(&self, retry: u32) -> Duration {
+        self.backoff.saturating_mul(1 << retry.min(16)).min(MAX_BACKOFF)
+    }
+}
+
+/// Addresses of one peer dialed at once, libp2p's default.
+pub const DEFAULT_DIAL_CONCURRENCY: NonZeroU8 = NonZeroU8::new(8).unwrap();
+
+/// How many of a peer's addresses are dialed in parallel; the first to connect wins and
+/// the others are dropped. A peer advertising a dozen addresses, most of them stale or
+/// behind relays, otherwise costs a burst of sockets for a single connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DialConcurrency {
+    /// For every peer without an override (the swarm's dial concurrency factor).
+    pub global: NonZeroU8,
+    /// Per-peer overrides, e.g. 1 for a relay known under many addresses.
+    pub peers: HashMap<PeerId, NonZeroU8>,
+}
+
+impl Default for DialConcurrency {
+    fn default() -> Self {
+        Self::new(DEFAULT_DIAL_CONCURRENCY)
+    }
+}
+
+impl DialConcurrency {
+    pub fn new(global: NonZeroU8) -> Self {
+        Self { global, peers: HashMap::new() }
+    }
+
+    pub fn with_peer(mut self, peer_id: PeerId, factor: NonZeroU8) -> Self {
+        self.peers.insert(peer_id, factor);
+        self
+    }
+
+    /// The factor to dial `peer_id` with, if it is not the global one.
+    pub fn for_peer(&self, peer_id: &PeerId) -> Option<NonZeroU8> {
+        self.peers.get(peer_id).copied().filter(|factor| *factor != self.global)
+    }
+}
+
+/// Order `addrs` of one peer for dialing, so the few dialed at once are the likely ones.
+/// Direct addresses go before relay circuits. Among each, addresses that connected and
+/// have not failed since come first, most recently seen first; then those we know
+/// nothing about, in the order given; then those that failed, fewest failures first.
+/// Circuits through relays with a better `relay_score` (see
+/// [`PeerReputation::score`]) go before the others. Duplicates are dropped.
+///
+/// `record` looks an address up in the address book.
+///
+/// [`PeerReputation::score`]: super::reputation::PeerReputation::score
+pub fn plan<'a>(
+    addrs: impl IntoIterator<Item = Multiaddr>,
+    record: impl Fn(&Multiaddr) -> Option<&'a AddrRecord>,
+    relay_score: impl Fn(&PeerId) -> f64,
+) -> Vec<Multiaddr> {
+    let mut seen = HashSet::new();
+    let mut ranked: Vec<(AddrRank, Multiaddr)> = addrs
+        .into_iter()
+        .filter(|addr| seen.insert(addr.clone()))
+        .map(|addr| (AddrRank::new(&addr, record(&addr), &relay_score), addr))
+        .collect();
+    // Stable, so addresses that rank the same keep their order
+    ranked.sort_by(|(a, _), (b, _)| a.cmp(b));
+    ranked.into_iter().map(|(_, addr)| addr).collect()
+}
+
+/// Where [`plan`] puts an address, lowest first.
+#[derive(Debug)]
+struct AddrRank {
+    circuit: bool,
+    relay_score: f64,
+    /// 0: connected and not failed since, 1: unknown, 2: failed lately.
+    tier: u8,
+    failures: u32,
+    last_seen_ms: u64,
+}
+
+impl AddrRank {
+    fn new(addr: &Multiaddr, record: Option<&AddrRecord>, relay_score: impl Fn(&PeerId) -> f64) -> Self {
+        let circuit = addr.iter().any(|p| p == Protocol::P2pCircuit);
+        let relay_score = if circuit { relay_of(addr).map_or(0.0, |relay| relay_score(&relay)) } else { 0.0 };
+        let (tier, failures, last_seen_ms) = match record {
+            Some(r) if r.failures > 0 => (2, r.failures, r.last_seen_ms),
+            Some(r) if r.successes > 0 => (0, 0, r.last_seen_ms),
+            _ => (1, 0, 0),
+        };
+        Self { circuit, relay_score, tier, failures, last_seen_ms }
+    }
+
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.circuit
+            .cmp(&other.circuit)
+            .then(other.relay_score.total_cmp(&self.relay_score))
+            .then(self.tier.cmp(&other.tier))
+            .then(self.failures.cmp(&other.failures))
+            .then(other.last_seen_ms.cmp(&self.last_seen_ms))
+    }
+}
+
+impl DialFailure {
+    /// Whether trying again later may succeed.
+    pub fn is_retryable(self) -> bool {
+        matches!(self, DialFailure::Timeout | DialFailure::Refused | DialFailure::ProxyUnreachable)
+    }
+}
+
+/// A dial in flight or waiting for its next attempt.
+#[derive(Debug, Clone)]
+pub struct PendingDial {
+    pub addr: Multiaddr,
+    pub options: DialOptions,
+    /// Attempts made so far, including the one in flight.
+    pub attempts: u32,
+    started: Instant,
+}
+
+/// What became of a failed attempt.
+#[derive(Debug, PartialEq, Eq)]
+pub enum DialOutcome {
+    /// Another attempt is waiting until `at`.
+    Retry { at: Instant },
+    /// No more attempts: the failure was permanent, or retries or time ran out.
+    GaveUp { addr: Multiaddr, attempts: u32 },
+    /// Not a dial we track.
+    Unknown,
+}
+
+/// Dials by connection id, and the retries waiting for their turn.
+#[derive(Debug, Default)]
+pub struct PendingDials {
+    in_flight: HashMap<ConnectionId, PendingDial>,
+    waiting: Vec<(Instant, PendingDial)>,
+}
+
+impl PendingDials {
+    /// First attempt of a dial was started as `connection_id`.
+    pub fn started(&mut self, connection_id: ConnectionId, addr: Multiaddr, options: DialOptions, now: Instant) {
+        self.in_flight.insert(connection_id, PendingDial { addr, options, attempts: 1, started: now });
+    }
+
+    /// A retry taken from [`take_due`](Self::take_due) was started as `connection_id`.
+    pub fn retried(&mut self, connection_id: ConnectionId, mut dial: PendingDial) {
+        dial.attempts += 1;
+        self.in_flight.insert(connection_id, dial);
+    }
+
+    /// The address dialed as `connection_id`, if we track it.
+    pub fn addr(&self, connection_id: &ConnectionId) -> Option<&Multiaddr> {
+        self.in_flight.get(connection_id).map(|d| &d.addr)
+    }
+
+    /// The attempt connected; the dial is done.
+    pub fn connected(&mut self, connection_id: &ConnectionId) {
+        self.in_flight.remove(connection_id);
+    }
+
+    /// The attempt dialed as `connection_id` failed with `failure`.
+    pub fn failed(&mut self, connection_id: &ConnectionId, failure: DialFailure, now: Instant) -> DialOutcome {
+        self.failed_slowed(connection_id, failure, now, 1)
+    }
+
+    /// As [`failed`](Self::failed), waiting `factor` times longer before the retry, for
+    /// peers that keep failing (see [`PeerReputation::retry_backoff_factor`]).
+    ///
+    /// [`PeerReputation::retry_backoff_factor`]: super::reputation::PeerReputation::retry_backoff_factor
+    pub fn failed_slowed(&mut self, connection_id: &ConnectionId, failure: DialFailure, now: Instant, factor: u32) -> DialOutcome {
+        let Some(dial) = self.in_flight.remove(connection_id) else {
+            return DialOutcome::Unknown;
+        };
+        let at = now.checked_add(dial.options.backoff(dial.attempts - 1).saturating_mul(factor.max(1)));
+        // A timeout too long to reach (e.g. `Infinity` from JS) is no timeout at all
+        let deadline = dial.options.timeout.and_then(|timeout| dial.started.checked_add(timeout));
+        let at = at.filter(|at| deadline.is_none_or(|deadline| *at <= deadline));
+        match at {
+            Some(at) if failure.is_retryable() && dial.attempts <= dial.options.retries => {
+                self.waiting.push((at, dial));
+                DialOutcome::Retry { at }
+            }
+            _ => DialOutcome::GaveUp { addr: dial.addr, attempts: dial.attempts },
+        }
+    }
+
+    /// Retries whose time has come; start each and report it with [`retried`](Self::retried).
+    pub fn take_due(&mut self, now: Instant) -> Vec<PendingDial> {
+        let (due, waiting) = std::mem::take(&mut self.waiting).into_iter().partition(|(at, _)| *at <= now);
+        self.waiting = waiting;
+        due.into_iter().map(|(_, dial)| dial).collect()
+    }
+
+    /// Drop the retries waiting for their turn, e.g. while the node is suspended. Returns
+    /// how many there were.
+    pub fn cancel_retries(&mut self) -> usize {
+        std::mem::take(&mut self.waiting).len()
+    }
+
+    /// When the next retry is due, if any is waiting.
+    pub fn next_due(&self) -> Option<Instant> {
+        self.waiting.iter().map(|(at, _)| *at).min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::address_book::AddressBook;
+
+    #[test]
+    fn retries_transient_failures_with_backoff() {
+        let mut dials = PendingDials::default();
+        let addr: Multiaddr = "/ip4/10.0.0.1/tcp/4001".parse().unwrap();
+        let options = DialOptions { retries: 2, ..Default::default() };
+        let now = Instant::now();
+
+        let first = ConnectionId::new_unchecked(1);
+        dials.started(first, addr.clone(), options, now);
+        assert_eq!(dials.addr(&first), Some(&addr));
+        assert_eq!(dials.failed(&first, DialFailure::Refused, now), DialOutcome::Retry { at: now + options.backoff });
+        assert!(dials.take_due(now).is_empty());
+
+        let at = dials.next_due().unwrap();
+        let retry = dials.take_due(at).pop().unwrap();
+        let second = ConnectionId::new_unchecked(2);
+        dials.retried(second, retry);
+        assert_eq!(dials.failed(&second, DialFailure::Timeout, at), DialOutcome::Retry { at: at + options.backoff * 2 });
+
+        let at = dials.next_due().unwrap();
+        let third = ConnectionId::new_unchecked(3);
+        let retry = dials.take_due(at).pop().unwrap();
+        dials.retried(third, retry);
+        assert_eq!(dials.failed(&third, DialFailure::Timeout, at), DialOutcome::GaveUp { addr, attempts: 3 });
+        assert_eq!(dials.next_due(), None);
+        assert_eq!(dials.failed(&third, DialFailure::Timeout, at), DialOutcome::Unknown);
+    }
+
+    #[test]
+    fn permanent_failures_and_deadlines_end_the_dial() {
+        let mut dials = PendingDials::default();
+        let addr: Multiaddr = "/ip4/10.0.0.1/tcp/4001".parse().unwrap();
+        let now = Instant::now();
+
+        let id = ConnectionId::new_unchecked(1);
+        dials.started(id, addr.clone(), DialOptions::retrying(), now);
+        assert_eq!(dials.failed(&id, DialFailure::WrongPeerId, now), DialOutcome::GaveUp { addr: addr.clone(), attempts: 1 });
+        dials.started(id, addr.clone(), DialOptions::retrying(), now);
+        assert!(matches!(dials.failed(&id, DialFailure::TransportUnsupported, now), DialOutcome::GaveUp { .. }));
+
+        dials.started(id, addr.clone(), DialOptions::retrying(), now);
+        let slowed = dials.failed_slowed(&id, DialFailure::Refused, now, 4);
+        assert_eq!(slowed, DialOutcome::Retry { at: now + Duration::from_secs(4) });
+        assert_eq!(dials.cancel_retries(), 1);
+        assert_eq!(dials.next_due(), None);
+
+        let options = DialOptions { retries: 10, timeout: Some(Duration::from_millis(500)), ..Default::default() };
+        dials.started(id, addr.clone(), options, now);
+        assert_eq!(dials.failed(&id, DialFailure::Refused, now), DialOutcome::GaveUp { addr: addr.clone(), attempts: 1 });
+
+        let endless = DialOptions { retries: 1, backoff: Duration::MAX, timeout: Some(Duration::MAX), ..Default::default() };
+        dials.started(id, addr.clone(), endless, now);
+        assert_eq!(dials.failed(&id, DialFailure::Refused, now), DialOutcome::Retry { at: now + MAX_BACKOFF });
+
+        let unnamed: Multiaddr = "/ip4/10.0.0.1/tcp/4001".parse().unwrap();
+        let required = DialOptions { require_peer_id: true, ..Default::default() };
+        assert!(required.check(&unnamed).is_err());
+        assert_eq!(DialOptions::default().check(&unnamed).unwrap(), None);
+        let peer = PeerId::random();
+        assert_eq!(required.check(&unnamed.with(Protocol::P2p(peer))).unwrap(), Some(peer));
+    }
+
+    #[test]
+    fn plans_direct_addresses_that_worked_first_and_circuits_last() {
+        let (peer, good_relay, bad_relay) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let addr = |s: &str| -> Multiaddr { s.parse().unwrap() };
+        let circuit = |relay: PeerId| addr(&format!("/ip4/10.0.0.9/tcp/4001/p2p/{relay}/p2p-circuit/p2p/{peer}"));
+        let (stale, fresh, unknown, failing, flaky) = (
+            addr("/ip4/10.0.0.1/tcp/4001"),
+            addr("/ip4/10.0.0.2/tcp/4001"),
+            addr("/ip4/10.0.0.3/tcp/4001"),
+            addr("/ip4/10.0.0.4/tcp/4001"),
+            addr("/ip4/10.0.0.5/tcp/4001"),
+        );
+
+        let mut book = AddressBook::default();
+        book.record_success(peer, &stale, 1_000);
+        book.record_success(peer, &fresh, 5_000);
+        book.observe(peer, &failing, 9_000);
+        for _ in 0..3 {
+            book.record_failure(&peer, &failing);
+        }
+        book.record_success(peer, &flaky, 9_000);
+        book.record_failure(&peer, &flaky);
+        // The circuit through the good relay worked too, but direct goes first
+        book.record_success(peer, &circuit(good_relay), 9_000);
+
+        let scores = HashMap::from([(good_relay, 2.0), (bad_relay, -3.0)]);
+        let given = [
+            circuit(bad_relay),
+            failing.clone(),
+            unknown.clone(),
+            circuit(good_relay),
+            stale.clone(),
+            flaky.clone(),
+            fresh.clone(),
+            unknown.clone(),
+        ];
+        let planned = plan(given, |a| book.record(&peer, a), |relay| scores.get(relay).copied().unwrap_or(0.0));
+        assert_eq!(planned, [fresh, stale, unknown, flaky, failing, circuit(good_relay), circuit(bad_relay)]);
+
+        // Nothing known: the order given, circuits last
+        let plain = [circuit(bad_relay), addr("/ip4/10.0.0.7/tcp/1"), addr("/ip4/10.0.0.6/tcp/1")];
+        let planned = plan(plain, |_| None, |_| 0.0);
+        assert_eq!(planned, [addr("/ip4/10.0.0.7/tcp/1"), addr("/ip4/10.0.0.6/tcp/1"), circuit(bad_relay)]);
+    }
+
+    #[test]
+    fn per_peer_concurrency_overrides_the_global_factor() {
+        let (relay, other) = (PeerId::random(), PeerId::random());
+        let two = NonZeroU8::new(2).unwrap();
+        let concurrency = DialConcurrency::new(two).with_peer(relay, NonZeroU8::MIN).with_peer(other, two);
+        assert_eq!(concurrency.for_peer(&relay), Some(NonZeroU8::MIN));
+        // Same as the global factor: nothing to override
+        assert_eq!(concurrency.for_peer(&other), None);
+        assert_eq!(concurrency.for_peer(&PeerId::random()), None);
+        assert_eq!(DialConcurrency::default().global, DEFAULT_DIAL_CONCURRENCY);
+    }
+}
@@ -0,0 +1,491 @@
+//! Mirroring a local directory into the docstore (`client sync-dir`). Every file below
+//! the root is a document named `<prefix>/<relative path>`; local changes become
+//! document updates and inbound updates for the prefix are written back to disk.
+//!
+//! Payloads are postcard-encoded [`FileUpdate`]s. Files too big for one update are sent
+//! as several [`FileUpdate::Part`]s of the same document and written once all have
+//! arrived and match the whole file's hash. A deleted file is a [`FileUpdate::Deleted`]
+//! tombstone. Writes go through a temporary file and a rename, and an inbound update
+//! never overwrites local edits that were not published yet: it lands next to the file
+//! as `<name>.sync-conflict` instead.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::behaviour::docstore::{DocUpdate, DEFAULT_MAX_DOCUMENT_SIZE, DEFAULT_MAX_UPDATE_SIZE};
+
+/// Suffix of the temporary files inbound writes go through.
+pub const TEMP_SUFFIX: &str = ".sync-tmp";
+
+/// Suffix of the copies written when an inbound update conflicts with local edits.
+pub const CONFLICT_SUFFIX: &str = ".sync-conflict";
+
+/// Bytes of a part's header and the postcard framing, kept free in each update.
+const PART_OVERHEAD: usize = 128;
+
+/// Files assembled from parts at once; the oldest is dropped past this.
+const MAX_PARTIAL_FILES: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FileUpdate {
+    Content(Vec<u8>),
+    /// Part `index` of `count` of a file whose content hashes to `hash`.
+    Part { hash: [u8; 32], index: u32, count: u32, bytes: Vec<u8> },
+    Deleted,
+}
+
+impl FileUpdate {
+    pub fn encode(&self) -> Vec<u8> {
+        postcard::to_allocvec(self).expect("file update serialization cannot fail")
+    }
+
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        postcard::from_bytes(data).ok()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DirSyncConfig {
+    pub root: PathBuf,
+    /// Documents are named `<doc_prefix>/<relative path>`.
+    pub doc_prefix: String,
+    /// Glob patterns (`*`, `?`). Patterns with a `/` match the whole relative path,
+    /// others any single path component.
+    pub ignore: Vec<String>,
+    /// Report what would be published and written without doing it.
+    pub dry_run: bool,
+    /// Largest payload of one update; bigger files are split into parts.
+    pub max_update_size: usize,
+    /// Larger files are skipped.
+    pub max_file_size: usize,
+}
+
+impl DirSyncConfig {
+    pub fn new(root: impl Into<PathBuf>, doc_prefix: impl Into<String>) -> Self {
+        Self {
+            root: root.into(),
+            doc_prefix: doc_prefix.into().trim_end_matches('/').to_string(),
+            ignore: Vec::new(),
+            dry_run: false,
+            max_update_size: DEFAULT_MAX_UPDATE_SIZE,
+            max_file_size: DEFAULT_MAX_DOCUMENT_SIZE,
+        }
+    }
+
+    /// The document for `path`, unless it is outside the root or ignored.
+    pub fn doc_id(&self, path: &Path) -> Option<String> {
+        let rel = path.strip_prefix(&self.root).ok()?;
+        let parts: Vec<&str> = rel.components().map(|c| match c {
+            Component::Normal(part) => part.to_str(),
+            _ => None,
+        }).collect::<Option<_>>()?;
+        let rel = parts.join("/");
+        (!parts.is_empty() && !self.is_ignored(&rel)).then(|| format!("{}/{}", self.doc_prefix, rel))
+    }
+
+    /// Where `doc_id` lives on disk. `None` for other prefixes, ignored paths and ids that
+    /// would reach outside the root.
+    pub fn path(&self, doc_id: &str) -> Option<PathBuf> {
+        let rel = doc_id.strip_prefix(&self.doc_prefix)?.strip_prefix('/')?;
+        let safe = rel.split('/').all(|part| !matches!(part, "" | "." | "..") && !part.contains('\\'));
+        (safe && !self.is_ignored(rel)).then(|| self.root.join(rel))
+    }
+
+    pub fn is_ignored(&self, rel: &str) -> bool {
+        let builtin = [TEMP_SUFFIX, CONFLICT_SUFFIX].iter().any(|suffix| rel.ends_with(suffix));
+        builtin
+            || self.ignore.iter().any(|pattern| {
+                if pattern.contains('/') {
+                    glob_match(pattern.trim_start_matches('/'), rel)
+                } else {
+                    rel.split('/').any(|part| glob_match(pattern, part))
+                }
+            })
+    }
+}
+
+/// `*` matches any run of characters, `?` any single one.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let (p, t): (Vec<char>, Vec<char>) = (pattern.chars().collect(), text.chars().collect());
+    let (mut pi, mut ti) = (0, 0);
+    // Where the last `*` was, and how much of the text it has taken so far
+    let mut star: Option<(usize, usize)> = None;
+    while ti < t.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == t[ti]) {
+            pi += 1;
+            ti += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, ti));
+            pi += 1;
+        } else if let Some((star_pi, star_ti)) = star {
+            pi = star_pi + 1;
+            ti = star_ti + 1;
+            star = Some((star_pi, star_ti + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|c| *c == '*')
+}
+
+fn content_hash(bytes: &[u8]) -> [u8; 32] {
+    Sha256::digest(bytes).into()
+}
+
+/// What a local change amounts to.
+#[derive(Debug, PartialEq, Eq)]
+pub enum LocalChange {
+    /// Updates to publish, in order.
+    Publish(Vec<DocUpdate>),
+    /// Already in the state last published or written.
+    Unchanged,
+    /// Ignored, outside the root, or not a regular file.
+    Skipped,
+    TooLarge { doc_id: String, size: u64 },
+}
+
+/// What an inbound update did to the directory.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RemoteChange {
+    Written(PathBuf),
+    Deleted(PathBuf),
+    /// The local file had unpublished edits; the update was written to this path instead,
+    /// or not applied at all if it was a tombstone.
+    Conflict(PathBuf),
+    /// More parts are needed before the file can be written.
+    Partial,
+    /// Not under our prefix, ignored, or unreadable.
+    Skipped,
+}
+
+#[derive(Debug)]
+struct Partial {
+    count: u32,
+    parts: HashMap<u32, Vec<u8>>,
+}
+
+/// The mirror's state: what each document last looked like on both sides.
+#[derive(Debug)]
+pub struct DirSync {
+    config: DirSyncConfig,
+    /// Hash of each document's content as last published or written; `None` once deleted.
+    synced: HashMap<String, Option<[u8; 32]>>,
+    /// Files being assembled from parts, by document and content hash, oldest first.
+    partial: Vec<((String, [u8; 32]), Partial)>,
+}
+
+impl DirSync {
+    pub fn new(config: DirSyncConfig) -> Self {
+        Self { config, synced: HashMap::new(), partial: Vec::new() }
+    }
+
+    pub fn config(&self) -> &DirSyncConfig {
+        &self.config
+    }
+
+    /// Every file below the root, as it is now.
+    pub fn scan(&mut self) -> io::Result<Vec<LocalChange>> {
+        let mut files = Vec::new();
+        let mut dirs = vec![self.config.root.clone()];
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(&dir)? {
+                let entry = entry?;
+                let kind = entry.file_type()?;
+                if kind.is_dir() {
+                    dirs.push(entry.path());
+                } else if kind.is_file() {
+                    files.push(entry.path());
+                }
+            }
+        }
+        files.sort();
+        files.iter().map(|path| self.local_change(path)).collect()
+    }
+
+    /// `path` changed on disk (created, modified or removed).
+    pub fn local_change(&mut self, path: &Path) -> io::Result<LocalChange> {
+        let bytes = match fs::metadata(path) {
+            Ok(meta) if meta.is_file() => {
+                let Some(doc_id) = self.config.doc_id(path) else {
+                    return Ok(LocalChange::Skipped);
+                };
+                if meta.len() > self.config.max_file_size as u64 {
+                    return Ok(LocalChange::TooLarge { doc_id, size: meta.len() });
+                }
+                Some((doc_id, fs::read(path)?))
+            }
+            Ok(_) => return Ok(LocalChange::Skipped),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        let Some((doc_id, bytes)) = bytes else {
+            return Ok(self.removed(path));
+        };
+        let hash = content_hash(&bytes);
+        if self.synced.get(&doc_id) == Some(&Some(hash)) {
+            return Ok(LocalChange::Unchanged);
+        }
+        self.synced.insert(doc_id.clone(), Some(hash));
+        Ok(LocalChange::Publish(self.file_updates(&doc_id, bytes, hash)))
+    }
+
+    /// Tombstones for `path`, or for every synced file below it if it was a directory.
+    fn removed(&mut self, path: &Path) -> LocalChange {
+        let Some(doc_id) = self.config.doc_id(path) else {
+            return LocalChange::Skipped;
+        };
+        let below = format!("{doc_id}/");
+        let mut gone: Vec<String> = self
+            .synced
+            .iter()
+            .filter(|(id, hash)| hash.is_some() && (**id == doc_id || id.starts_with(&below)))
+            .map(|(id, _)| id.clone())
+            .collect();
+        if gone.is_empty() {
+            return LocalChange::Unchanged;
+        }
+        gone.sort();
+        for id in &gone {
+            self.synced.insert(id.clone(), None);
+        }
+        LocalChange::Publish(gone.into_iter().map(|id| DocUpdate::new(id, FileUpdate::Deleted.encode())).collect())
+    }
+
+    /// File bytes carried by each part.
+    fn part_size(&self) -> usize {
+        self.config.max_update_size.saturating_sub(PART_OVERHEAD).max(1)
+    }
+
+    fn file_updates(&self, doc_id: &str, bytes: Vec<u8>, hash: [u8; 32]) -> Vec<DocUpdate> {
+        let part_size = self.part_size();
+        if bytes.len() + PART_OVERHEAD <= self.config.max_update_size {
+            return vec![DocUpdate::new(doc_id, FileUpdate::Content(bytes).encode())];
+        }
+        let count = bytes.len().div_ceil(part_size) as u32;
+        bytes
+            .chunks(part_size)
+            .enumerate()
+            .map(|(index, chunk)| {
+                let part = FileUpdate::Part { hash, index: index as u32, count, bytes: chunk.to_vec() };
+                DocUpdate::new(doc_id, part.encode())
+            })
+            .collect()
+    }
+
+    /// Apply an inbound update for our prefix to the directory.
+    pub fn remote_update(&mut self, update: &DocUpdate) -> io::Result<RemoteChange> {
+        let (Some(path), Some(file_update)) = (self.config.path(&update.doc_id), FileUpdate::decode(&update.payload))
+        else {
+            return Ok(RemoteChange::Skipped);
+        };
+        let doc_id = update.doc_id.clone();
+        let bytes = match file_update {
+            FileUpdate::Content(bytes) => bytes,
+            FileUpdate::Part { hash, index, count, bytes } => match self.add_part(&doc_id, hash, index, count, bytes) {
+                Some(bytes) => bytes,
+                None => return Ok(RemoteChange::Partial),
+            },
+            FileUpdate::Deleted => {
+                if self.has_local_edits(&doc_id, &path)? {
+                    return Ok(RemoteChange::Conflict(path));
+                }
+                self.synced.insert(doc_id, None);
+                if !self.config.dry_run {
+                    match fs::remove_file(&path) {
+                        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                        _ => {}
+                    }
+                }
+                return Ok(RemoteChange::Deleted(path));
+            }
+        };
+        if self.has_local_edits(&doc_id, &path)? {
+            let mut name = path.file_name().unwrap_or_default().to_os_string();
+            name.push(CONFLICT_SUFFIX);
+            let conflict = path.with_file_name(name);
+            if !self.config.dry_run {
+                write_atomic(&conflict, &bytes)?;
+            }
+            return Ok(RemoteChange::Conflict(conflict));
+        }
+        self.synced.insert(doc_id, Some(content_hash(&bytes)));
+        if !self.config.dry_run {
+            write_atomic(&path, &bytes)?;
+        }
+        Ok(RemoteChange::Written(path))
+    }
+
+    /// The file at `path` differs from what we last published or wrote for `doc_id`.
+    fn has_local_edits(&self, doc_id: &str, path: &Path) -> io::Result<bool> {
+        let on_disk = match fs::read(path) {
+            Ok(bytes) => Some(content_hash(&bytes)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        // Never synced: a local file is unpublished work, a missing one is fair game
+        let synced = self.synced.get(doc_id).copied().flatten();
+        Ok(on_disk.is_some() && on_disk != synced)
+    }
+
+    /// Store a part; the whole file once every part is in and the hash matches.
+    fn add_part(&mut self, doc_id: &str, hash: [u8; 32], index: u32, count: u32, bytes: Vec<u8>) -> Option<Vec<u8>> {
+        if index >= count || count as usize > self.config.max_file_size.div_ceil(self.part_size()) {
+            return None;
+        }
+        let key = (doc_id.to_string(), hash);
+        let at = match self.partial.iter().position(|(k, _)| *k == key) {
+            Some(at) => at,
+            None => {
+                if self.partial.len() >= MAX_PARTIAL_FILES {
+                    self.partial.remove(0);
+                }
+                self.partial.push((key, Partial { count, parts: HashMap::new() }));
+                self.partial.len() - 1
+            }
+        };
+        let partial = &mut self.partial[at].1;
+        if partial.count != count {
+            return None;
+        }
+        partial.parts.insert(index, bytes);
+        if partial.parts.len() < count as usize {
+            return None;
+        }
+        let (_, mut partial) = self.partial.remove(at);
+        let bytes: Vec<u8> = (0..count).flat_map(|i| partial.parts.remove(&i).unwrap_or_default()).collect();
+        (content_hash(&bytes) == hash).then_some(bytes)
+    }
+}
+
+/// Write `bytes` to `path` through a temporary file next to it, creating parent
+/// directories as needed, so readers never see a half-written file.
+fn write_atomic(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(TEMP_SUFFIX);
+    let tmp = path.with_file_name(name);
+    {
+        let mut f = File::create(&tmp)?;
+        f.write_all(bytes)?;
+        f.sync_all()?;
+    }
+    fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dir-sync-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn published(change: LocalChange) -> Vec<DocUpdate> {
+        match change {
+            LocalChange::Publish(updates) => updates,
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[test]
+    fn maps_paths_to_documents_and_refuses_escapes() {
+        let mut config = DirSyncConfig::new("/data/notes", "notes/");
+        config.ignore = vec!["*.swp".into(), ".git".into(), "build/*.o".into()];
+        assert_eq!(config.doc_id(Path::new("/data/notes/a/b.txt")).as_deref(), Some("notes/a/b.txt"));
+        assert_eq!(config.path("notes/a/b.txt"), Some(PathBuf::from("/data/notes/a/b.txt")));
+
+        for ignored in ["/data/notes/.b.txt.swp", "/data/notes/.git/HEAD", "/data/notes/build/x.o", "/data/notes/x.sync-tmp"] {
+            assert_eq!(config.doc_id(Path::new(ignored)), None, "{ignored}");
+        }
+        assert!(config.doc_id(Path::new("/data/notes/src/build/x.o")).is_some());
+        assert_eq!(config.doc_id(Path::new("/elsewhere/a")), None);
+
+        for escape in ["notes/../etc/passwd", "notes//a", "notes/a/./b", "other/a", "notesx/a", "notes/a\\..\\b"] {
+            assert_eq!(config.path(escape), None, "{escape}");
+        }
+    }
+
+    #[test]
+    fn mirrors_changes_both_ways_without_echoes_or_clobbering() {
+        let (a, b) = (temp_dir("a"), temp_dir("b"));
+        let mut left = DirSync::new(DirSyncConfig::new(&a, "shared"));
+        let mut right = DirSync::new(DirSyncConfig::new(&b, "shared"));
+
+        fs::create_dir_all(a.join("sub")).unwrap();
+        fs::write(a.join("sub/one.txt"), b"hello").unwrap();
+        let updates: Vec<DocUpdate> = left.scan().unwrap().into_iter().flat_map(published).collect();
+        assert_eq!(updates.len(), 1);
+        assert_eq!(right.remote_update(&updates[0]).unwrap(), RemoteChange::Written(b.join("sub/one.txt")));
+        assert_eq!(fs::read(b.join("sub/one.txt")).unwrap(), b"hello");
+        // The watcher reports our own write; nothing to publish
+        assert_eq!(right.local_change(&b.join("sub/one.txt")).unwrap(), LocalChange::Unchanged);
+
+        // Unpublished local edits are kept; the inbound version goes next to them
+        fs::write(b.join("sub/one.txt"), b"local edit").unwrap();
+        fs::write(a.join("sub/one.txt"), b"remote edit").unwrap();
+        let update = published(left.local_change(&a.join("sub/one.txt")).unwrap()).remove(0);
+        let conflict = b.join("sub/one.txt.sync-conflict");
+        assert_eq!(right.remote_update(&update).unwrap(), RemoteChange::Conflict(conflict.clone()));
+        assert_eq!(fs::read(b.join("sub/one.txt")).unwrap(), b"local edit");
+        assert_eq!(fs::read(&conflict).unwrap(), b"remote edit");
+        assert_eq!(right.local_change(&conflict).unwrap(), LocalChange::Skipped);
+
+        // Deleting the directory tombstones the files in it
+        fs::remove_dir_all(a.join("sub")).unwrap();
+        let tombstones = published(left.local_change(&a.join("sub")).unwrap());
+        assert_eq!(tombstones.len(), 1);
+        assert_eq!(FileUpdate::decode(&tombstones[0].payload), Some(FileUpdate::Deleted));
+        assert_eq!(left.local_change(&a.join("sub/one.txt")).unwrap(), LocalChange::Unchanged);
+        fs::write(b.join("sub/one.txt"), b"remote edit").unwrap();
+        right.local_change(&b.join("sub/one.txt")).unwrap();
+        assert_eq!(right.remote_update(&tombstones[0]).unwrap(), RemoteChange::Deleted(b.join("sub/one.txt")));
+        assert!(!b.join("sub/one.txt").exists());
+
+        let _ = (fs::remove_dir_all(&a), fs::remove_dir_all(&b));
+    }
+
+    #[test]
+    fn splits_large_files_into_parts_and_honours_dry_run() {
+        let (a, b) = (temp_dir("parts-a"), temp_dir("parts-b"));
+        let mut config = DirSyncConfig::new(&a, "big");
+        config.max_update_size = 1000;
+        config.max_file_size = 10_000;
+        let mut left = DirSync::new(config.clone());
+        let content: Vec<u8> = (0..5000u32).map(|i| i as u8).collect();
+        fs::write(a.join("blob"), &content).unwrap();
+        fs::write(a.join("huge"), vec![0; 10_001]).unwrap();
+
+        let changes = left.scan().unwrap();
+        assert_eq!(changes[1], LocalChange::TooLarge { doc_id: "big/huge".into(), size: 10_001 });
+        let parts = match &changes[0] {
+            LocalChange::Publish(parts) => parts.clone(),
+            other => panic!("unexpected {other:?}"),
+        };
+        assert!(parts.len() > 1 && parts.iter().all(|p| p.payload.len() <= 1000));
+
+        let mut right = DirSync::new(DirSyncConfig { root: b.clone(), ..config.clone() });
+        // Out of order is fine
+        for part in parts.iter().rev().skip(1) {
+            assert_eq!(right.remote_update(part).unwrap(), RemoteChange::Partial);
+        }
+        assert_eq!(right.remote_update(&parts[parts.len() - 1]).unwrap(), RemoteChange::Written(b.join("blob")));
+        assert_eq!(fs::read(b.join("blob")).unwrap(), content);
+
+        let mut dry = DirSync::new(DirSyncConfig { root: b.join("dry"), dry_run: true, ..config });
+        let update = DocUpdate::new("big/new", FileUpdate::Content(b"x".to_vec()).encode());
+        assert_eq!(dry.remote_update(&update).unwrap(), RemoteChange::Written(b.join("dry/new")));
+        assert!(!b.join("dry").exists());
+
+        let _ = (fs::remove_dir_all(&a), fs::remove_dir_all(&b));
+    }
+}
@@ -0,0 +1,275 @@
+//! Counting duplicate messages per source. Gossipsub drops messages it has already seen
+//! without telling anyone, so a client republishing the same updates in a loop is
+//! invisible to the relay. Keyed by content-hash message ids, a message arriving again
+//! from the same source counts as a duplicate; a source exceeding the duplicate rate is
+//! flooding, and one that keeps flooding window after window should be graylisted.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+use libp2p::gossipsub::MessageId;
+use libp2p::PeerId;
+
+/// Arrivals remembered, across all sources; the oldest are forgotten first.
+pub const MAX_REMEMBERED: usize = 65_536;
+
+/// Sources with duplicate counts kept at once; the one with the fewest duplicates is
+/// forgotten to make room.
+pub const MAX_TRACKED_SOURCES: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DuplicateConfig {
+    /// A message arriving again from the same source within this time is a duplicate.
+    pub remember_for: Duration,
+    /// Duplicates tolerated from one source per `rate_window`.
+    pub max_duplicates: u32,
+    pub rate_window: Duration,
+    /// Consecutive flooding windows before the source is graylisted.
+    pub graylist_after: u32,
+    pub graylist_for: Duration,
+}
+
+impl Default for DuplicateConfig {
+    fn default() -> Self {
+        Self {
+            remember_for: Duration::from_secs(5 * 60),
+            max_duplicates: 30,
+            rate_window: Duration::from_secs(60),
+            graylist_after: 3,
+            graylist_for: Duration::from_secs(15 * 60),
+        }
+    }
+}
+
+/// What a received message turned out to be.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    New,
+    Duplicate,
+    /// This duplicate took the source over [`DuplicateConfig::max_duplicates`] in the
+    /// current window. Reported once per window.
+    Flooding { duplicates: u32 },
+    /// Like `Flooding`, for the [`DuplicateConfig::graylist_after`]th window in a row.
+    Graylist { duplicates: u32 },
+}
+
+/// Duplicates seen from one source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceDuplicates {
+    pub peer_id: PeerId,
+    pub duplicates: u64,
+    /// Windows in which it exceeded the duplicate rate.
+    pub floods: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Source {
+    duplicates: u64,
+    floods: u64,
+    window_start_ms: u64,
+    in_window: u32,
+    /// Flooding windows in a row, up to the current one.
+    flooding_windows: u32,
+}
+
+/// Per-source duplicate counters and flood detection. Times are unix milliseconds.
+#[derive(Debug, Default)]
+pub struct DuplicateDetector {
+    config: DuplicateConfig,
+    seen: HashMap<(PeerId, MessageId), u64>,
+    /// `seen` keys in arrival order, for expiry.
+    arrivals: VecDeque<(u64, PeerId, MessageId)>,
+    sources: HashMap<PeerId, Source>,
+    messages: u64,
+    duplicates: u64,
+    floods: u64,
+    graylisted: u64,
+}
+
+impl DuplicateDetector {
+    pub fn new(config: DuplicateConfig) -> Self {
+        Self { config, ..Default::default() }
+    }
+
+    pub fn config(&self) -> &DuplicateConfig {
+        &self.config
+    }
+
+    /// Judge by `config` from now on; what was seen so far still counts.
+    pub fn set_config(&mut self, config: DuplicateConfig) {
+        self.config = config;
+    }
+
+    /// Count a message `id` received from `source`.
+    pub fn observe(&mut self, source: PeerId, id: MessageId, now_ms: u64) -> Verdict {
+        self.expire(now_ms);
+        self.messages += 1;
+        if self.arrivals.len() >= MAX_REMEMBERED {
+            self.forget_oldest();
+        }
+        self.arrivals.push_back((now_ms, source, id.clone()));
+        match self.seen.insert((source, id), now_ms) {
+            Some(_) => self.duplicate(source, now_ms),
+            None => Verdict::New,
+        }
+    }
+
+    fn duplicate(&mut self, peer: PeerId, now_ms: u64) -> Verdict {
+        self.duplicates += 1;
+        if !self.sources.contains_key(&peer) && self.sources.len() >= MAX_TRACKED_SOURCES {
+            let fewest = self.sources.iter().min_by_key(|(_, s)| s.duplicates).map(|(p, _)| *p);
+            if let Some(fewest) = fewest {
+                self.sources.remove(&fewest);
+            }
+        }
+        let config = self.config;
+        let window_ms = config.rate_window.as_millis() as u64;
+        let source = self.sources.entry(peer).or_insert(Source { window_start_ms: now_ms, ..Default::default() });
+        source.duplicates += 1;
+
+        let elapsed = now_ms.saturating_sub(source.window_start_ms);
+        if elapsed >= window_ms {
+            let flooded = source.in_window > config.max_duplicates;
+            if flooded && elapsed < 2 * window_ms {
+                source.window_start_ms += window_ms;
+            } else {
+                // A calm window, or a gap, ends the streak
+                source.flooding_windows = 0;
+                source.window_start_ms = now_ms;
+            }
+            source.in_window = 0;
+        }
+        source.in_window += 1;
+        if source.in_window != config.max_duplicates + 1 {
+            return Verdict::Duplicate;
+        }
+        source.floods += 1;
+        source.flooding_windows += 1;
+        self.floods += 1;
+        let duplicates = source.in_window;
+        if source.flooding_windows >= config.graylist_after {
+            source.flooding_windows = 0;
+            self.graylisted += 1;
+            Verdict::Graylist { duplicates }
+        } else {
+            Verdict::Flooding { duplicates }
+        }
+    }
+
+    fn expire(&mut self, now_ms: u64) {
+        let remember_ms = self.config.remember_for.as_millis() as u64;
+        while self.arrivals.front().is_some_and(|(at, _, _)| at + remember_ms <= now_ms) {
+            self.forget_oldest();
+        }
+    }
+
+    fn forget_oldest(&mut self) {
+        let Some((at, peer, id)) = self.arrivals.pop_front() else {
+            return;
+        };
+        // Only if it wasn't seen again since
+        let key = (peer, id);
+        if self.seen.get(&key) == Some(&at) {
+            self.seen.remove(&key);
+        }
+    }
+
+    /// Messages observed, including duplicates.
+    pub fn messages(&self) -> u64 {
+        self.messages
+    }
+
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates
+    }
+
+    /// Windows in which some source exceeded the duplicate rate.
+    pub fn floods(&self) -> u64 {
+        self.floods
+    }
+
+    /// Times a source was found flooding long enough to be graylisted.
+    pub fn graylisted(&self) -> u64 {
+        self.graylisted
+    }
+
+    /// The `n` sources with the most duplicates, most first.
+    pub fn top_sources(&self, n: usize) -> Vec<SourceDuplicates> {
+        let mut sources: Vec<_> = self
+            .sources
+            .iter()
+            .map(|(peer_id, s)| SourceDuplicates { peer_id: *peer_id, duplicates: s.duplicates, floods: s.floods })
+            .collect();
+        sources.sort_by(|a, b| b.duplicates.cmp(&a.duplicates));
+        sources.truncate(n);
+        sources
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(n: u8) -> MessageId {
+        MessageId::from(vec![n])
+    }
+
+    fn detector() -> DuplicateDetector {
+        DuplicateDetector::new(DuplicateConfig {
+            remember_for: Duration::from_secs(60),
+            max_duplicates: 3,
+            rate_window: Duration::from_secs(10),
+            graylist_after: 2,
+            graylist_for: Duration::from_secs(60),
+        })
+    }
+
+    #[test]
+    fn counts_repeats_per_source_within_the_memory() {
+        let mut dups = detector();
+        let (a, b) = (PeerId::random(), PeerId::random());
+
+        assert_eq!(dups.observe(a, id(1), 0), Verdict::New);
+        // The same message from another source is that source's first copy
+        assert_eq!(dups.observe(b, id(1), 0), Verdict::New);
+        assert_eq!(dups.observe(a, id(1), 1_000), Verdict::Duplicate);
+        assert_eq!(dups.observe(a, id(2), 1_000), Verdict::New);
+        // Forgotten 60s after it was last seen
+        assert_eq!(dups.observe(a, id(1), 61_000), Verdict::New);
+
+        assert_eq!((dups.messages(), dups.duplicates()), (5, 1));
+        assert_eq!(dups.top_sources(5), [SourceDuplicates { peer_id: a, duplicates: 1, floods: 0 }]);
+    }
+
+    #[test]
+    fn floods_are_reported_once_per_window_and_sustained_ones_graylisted() {
+        let mut dups = detector();
+        let peer = PeerId::random();
+        dups.observe(peer, id(1), 0);
+
+        let verdicts: Vec<_> = (1..=5).map(|t| dups.observe(peer, id(1), t * 100)).collect();
+        assert_eq!(
+            verdicts,
+            [
+                Verdict::Duplicate,
+                Verdict::Duplicate,
+                Verdict::Duplicate,
+                Verdict::Flooding { duplicates: 4 },
+                Verdict::Duplicate
+            ]
+        );
+
+        // The next window floods too
+        let verdicts: Vec<_> = (0..4).map(|t| dups.observe(peer, id(1), 10_100 + t * 100)).collect();
+        assert_eq!(verdicts.last(), Some(&Verdict::Graylist { duplicates: 4 }));
+        assert_eq!((dups.floods(), dups.graylisted()), (2, 1));
+
+        // Graylisting starts the streak over, and a calm window in between ends it
+        let verdicts: Vec<_> = (0..4).map(|t| dups.observe(peer, id(1), 20_100 + t * 100)).collect();
+        assert_eq!(verdicts.last(), Some(&Verdict::Flooding { duplicates: 4 }));
+        dups.observe(peer, id(1), 30_100);
+        let verdicts: Vec<_> = (0..4).map(|t| dups.observe(peer, id(1), 40_100 + t * 100)).collect();
+        assert_eq!(verdicts.last(), Some(&Verdict::Flooding { duplicates: 4 }));
+        assert_eq!(dups.top_sources(1)[0].floods, 4);
+    }
+}
@@ -0,0 +1,278 @@
+//! Machine-readable mirror of swarm activity: one JSON object per line (NDJSON), for
+//! piping into `jq` or a log pipeline without the metrics stack.
+//!
+//! Every record carries `schema_version`, `ts_ms` and an `event` tag; fields are only
+//! ever added within a schema version. Records are written by a dedicated thread through
+//! a bounded queue. When the consumer falls behind, new records are dropped instead of
+//! stalling the swarm, and an `events_dropped` record reports how many were lost.
+
+use std::collections::HashSet;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::Arc;
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Records queued before new ones are dropped.
+pub const DEFAULT_QUEUE_CAPACITY: usize = 4096;
+
+/// Groups of events that can be mirrored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    Connections,
+    Gossip,
+    Kademlia,
+    Relay,
+}
+
+impl EventKind {
+    pub const ALL: [EventKind; 4] = [EventKind::Connections, EventKind::Gossip, EventKind::Kademlia, EventKind::Relay];
+}
+
+impl FromStr for EventKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "connections" => Ok(EventKind::Connections),
+            "gossip" => Ok(EventKind::Gossip),
+            "kademlia" | "kad" => Ok(EventKind::Kademlia),
+            "relay" => Ok(EventKind::Relay),
+            other => Err(format!("unknown event kind '{other}' (expected connections, gossip, kademlia or relay)")),
+        }
+    }
+}
+
+/// Parse a comma-separated list of [`EventKind`]s.
+pub fn parse_kinds(s: &str) -> Result<HashSet<EventKind>, String> {
+    s.split(',').filter(|k| !k.trim().is_empty()).map(str::parse).collect()
+}
+
+/// One mirrored event. Serialized with its variant name in `event`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum MirrorEvent {
+    ConnectionEstablished { peer_id: String, address: String },
+    ConnectionClosed { peer_id: String },
+    GossipMessage {
+        topic: String,
+        /// Signed author, if any.
+        source: Option<String>,
+        propagation_source: String,
+        message_id: String,
+        size: usize,
+        /// First 8 bytes of the payload's SHA-256, hex.
+        payload_hash: String,
+    },
+    KademliaQuery { query_id: String, kind: String, ok: bool, peers: Vec<String> },
+    RelayReservation { peer_id: String, state: String },
+    /// An inbound connection was refused by the per-IP limits.
+    ConnectionDenied { ip: String, peer_id: Option<String>, reason: String },
+    /// A source exceeded the duplicate rate; `graylisted` if it kept doing so long enough
+    /// to be banned.
+    DuplicateFlood { peer_id: String, duplicates: u32, window_secs: u64, graylisted: bool },
+    /// The Kademlia record store refused a record, the first time since it last accepted
+    /// one; `kind` names the limit, see [`StoreFullKind`](crate::node::StoreFullKind).
+    DhtStoreFull { kind: String },
+    /// Expected relays or FullNodes have been unreachable past the threshold while
+    /// clients are connected, the first of them since `since_ms`.
+    PossiblePartition { missing_peers: Vec<String>, since_ms: u64 },
+    /// Every peer of a `possible_partition` is back or no longer expected.
+    PartitionResolved { peers: Vec<String>, since_ms: u64, duration_ms: u64 },
+    /// Emitted by the writer after records were dropped.
+    EventsDropped { count: u64 },
+}
+
+impl MirrorEvent {
+    pub fn kind(&self) -> Option<EventKind> {
+        match self {
+            MirrorEvent::ConnectionEstablished { .. }
+            | MirrorEvent::ConnectionClosed { .. }
+            | MirrorEvent::ConnectionDenied { .. }
+            | MirrorEvent::PossiblePartition { .. }
+            | MirrorEvent::PartitionResolved { .. } => {
+                Some(EventKind::Connections)
+            }
+            MirrorEvent::GossipMessage { .. } | MirrorEvent::DuplicateFlood { .. } => Some(EventKind::Gossip),
+            MirrorEvent::KademliaQuery { .. } | MirrorEvent::DhtStoreFull { .. } => Some(EventKind::Kademlia),
+            MirrorEvent::RelayReservation { .. } => Some(EventKind::Relay),
+            MirrorEvent::EventsDropped { .. } => None,
+        }
+    }
+}
+
+/// Truncated payload hash used in [`MirrorEvent::GossipMessage`].
+pub fn payload_hash(data: &[u8]) -> String {
+    Sha256::digest(data)[..8].iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    schema_version: u32,
+    ts_ms: u64,
+    #[serde(flatten)]
+    event: &'a MirrorEvent,
+}
+
+fn encode(event: &MirrorEvent) -> String {
+    let record = Record { schema_version: SCHEMA_VERSION, ts_ms: crate::store::now_ms(), event };
+    serde_json::to_string(&record).expect("event record serialization cannot fail")
+}
+
+/// Handle for emitting mirrored events. Cheap to clone; emitting never blocks.
+#[derive(Clone)]
+pub struct EventMirror {
+    queue: SyncSender<String>,
+    kinds: Arc<HashSet<EventKind>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl EventMirror {
+    /// Start the writer thread for `out`, mirroring only `kinds`.
+    pub fn spawn(out: impl Write + Send + 'static, kinds: HashSet<EventKind>, capacity: usize) -> Self {
+        let (queue, rx) = mpsc::sync_channel::<String>(capacity);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let writer_dropped = dropped.clone();
+        std::thread::Builder::new()
+            .name("event-mirror".into())
+            .spawn(move || write_loop(out, rx, writer_dropped))
+            .expect("spawn event mirror thread");
+        Self { queue, kinds: Arc::new(kinds), dropped }
+    }
+
+    pub fn wants(&self, kind: EventKind) -> bool {
+        self.kinds.contains(&kind)
+    }
+
+    pub fn emit(&self, event: MirrorEvent) {
+        if event.kind().is_some_and(|k| !self.wants(k)) {
+            return;
+        }
+        match self.queue.try_send(encode(&event)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            // Writer is gone (output closed); nothing left to do
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+
+    /// Records dropped since the mirror started.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+fn write_loop(out: impl Write, rx: mpsc::Receiver<String>, dropped: Arc<AtomicU64>) {
+    let mut out = std::io::BufWriter::new(out);
+    let mut reported = 0;
+    while let Ok(line) = rx.recv() {
+        let mut batch = Some(line);
+        while let Some(line) = batch {
+            if writeln!(out, "{line}").is_err() {
+                return;
+            }
+            batch = rx.try_recv().ok();
+        }
+        let total = dropped.load(Ordering::Relaxed);
+        if total > reported {
+            let event = MirrorEvent::EventsDropped { count: total - reported };
+            if writeln!(out, "{}", encode(&event)).is_err() {
+                return;
+            }
+            reported = total;
+        }
+        if out.flush().is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Condvar, Mutex};
+
+    /// Writer that blocks until released, then collects everything written.
+    #[derive(Clone, Default)]
+    struct GatedWriter {
+        state: Arc<(Mutex<(bool, Vec<u8>)>, Condvar)>,
+    }
+
+    impl GatedWriter {
+        fn release(&self) {
+            let (lock, cvar) = &*self.state;
+            lock.lock().unwrap().0 = true;
+            cvar.notify_all();
+        }
+
+        fn lines(&self) -> Vec<serde_json::Value> {
+            let data = String::from_utf8(self.state.0.lock().unwrap().1.clone()).unwrap();
+            data.split_inclusive('\n')
+                .filter(|l| l.ends_with('\n'))
+                .map(|l| serde_json::from_str(l).unwrap())
+                .collect()
+        }
+    }
+
+    impl Write for GatedWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let (lock, cvar) = &*self.state;
+            let mut state = cvar.wait_while(lock.lock().unwrap(), |s| !s.0).unwrap();
+            state.1.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn records_have_a_stable_shape() {
+        let line = encode(&MirrorEvent::ConnectionClosed { peer_id: "p".into() });
+        let v: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(v["schema_version"], 1);
+        assert_eq!(v["event"], "connection_closed");
+        assert_eq!(v["peer_id"], "p");
+        assert!(v["ts_ms"].is_u64());
+        assert_eq!(payload_hash(b"hello"), "2cf24dba5fb0a30e");
+        assert_eq!(parse_kinds("gossip, kad").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn slow_consumer_drops_instead_of_blocking() {
+        let writer = GatedWriter::default();
+        let kinds = HashSet::from([EventKind::Connections]);
+        let mirror = EventMirror::spawn(writer.clone(), kinds, 2);
+
+        mirror.emit(MirrorEvent::KademliaQuery { query_id: "q".into(), kind: "bootstrap".into(), ok: true, peers: vec![] });
+        for i in 0..50 {
+            mirror.emit(MirrorEvent::ConnectionClosed { peer_id: i.to_string() });
+        }
+        assert!(mirror.dropped() > 0);
+
+        writer.release();
+        drop(mirror);
+        // Everything emitted is either written or accounted for in events_dropped records
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        loop {
+            let lines = writer.lines();
+            let written = lines.iter().filter(|l| l["event"] == "connection_closed").count() as u64;
+            let dropped: u64 = lines.iter().filter_map(|l| l["count"].as_u64()).sum();
+            if written + dropped == 50 {
+                assert!(dropped > 0);
+                assert!(lines.iter().all(|l| l["event"] != "kademlia_query"));
+                break;
+            }
+            assert!(std::time::Instant::now() < deadline, "mirror output incomplete");
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+}
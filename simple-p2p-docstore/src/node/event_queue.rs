@@ -0,0 +1,322 @@
+//! Receiving from a node's event channels when several callers may be waiting at once,
+//! as the JS bindings allow, and fanning events out to filtered subscriptions.
+//!
+//! A receiver sits behind an async mutex: concurrent callers queue on the lock and each
+//! event goes to exactly one of them. A deadline covers both the wait for the lock and the
+//! wait for an event, and an event is only taken off the channel when it is returned, so
+//! timing out never loses one.
+//!
+//! Every [`Subscription`] has its own bounded channel and receives a copy of each matching
+//! event. A subscriber that falls behind loses new events rather than slowing the node;
+//! the losses are counted per subscription.
+
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use futures::channel::mpsc;
+use futures::lock::Mutex;
+use futures::{FutureExt, Stream, StreamExt};
+
+use super::HistoryEvent;
+
+/// Events queued per subscription by default before new ones are dropped.
+pub const DEFAULT_SUBSCRIPTION_CAPACITY: usize = 256;
+
+/// Outcome of waiting for an event.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Next<T> {
+    Event(T),
+    /// The node stopped; no more events will arrive.
+    Closed,
+    TimedOut,
+}
+
+/// Wait for the next event, or [`Next::Closed`] once the channel has ended.
+pub async fn next<S: Stream + Unpin>(receiver: &Mutex<S>) -> Next<S::Item> {
+    match receiver.lock().await.next().await {
+        Some(event) => Next::Event(event),
+        None => Next::Closed,
+    }
+}
+
+/// Like [`next`], giving up with [`Next::TimedOut`] when `deadline` completes first.
+pub async fn next_until<S: Stream + Unpin>(receiver: &Mutex<S>, deadline: impl Future<Output = ()>) -> Next<S::Item> {
+    let mut event = std::pin::pin!(next(receiver).fuse());
+    let mut deadline = std::pin::pin!(deadline.fuse());
+    // Biased so an event that is ready when the deadline fires is still delivered
+    futures::select_biased! {
+        next = event => next,
+        () = deadline => Next::TimedOut,
+    }
+}
+
+/// Where an event belongs, for filtering subscriptions.
+pub trait EventScope {
+    fn topic(&self) -> Option<String> {
+        None
+    }
+
+    fn doc_id(&self) -> Option<&str> {
+        None
+    }
+}
+
+/// Which events a subscription receives. Unset fields match everything; a set `topic` or
+/// `doc_id` only matches events that carry one.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EventFilter {
+    /// Event kinds, as named by [`HistoryEvent::kind`].
+    pub kinds: Option<Vec<String>>,
+    pub topic: Option<String>,
+    pub doc_id: Option<String>,
+}
+
+impl EventFilter {
+    pub fn matches<E: HistoryEvent + EventScope>(&self, event: &E) -> bool {
+        self.kinds.as_ref().is_none_or(|kinds| kinds.iter().any(|k| k == event.kind()))
+            && self.topic.as_ref().is_none_or(|topic| event.topic().as_ref() == Some(topic))
+            && self.doc_id.as_deref().is_none_or(|doc_id| event.doc_id() == Some(doc_id))
+    }
+}
+
+struct Subscriber<E> {
+    id: u64,
+    filter: EventFilter,
+    sender: mpsc::Sender<E>,
+    dropped: Arc<AtomicU64>,
+}
+
+/// The set of live subscriptions, fed by the event loop. Cheap to clone.
+pub struct Subscriptions<E> {
+    subscribers: Arc<std::sync::Mutex<Vec<Subscriber<E>>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl<E> Clone for Subscriptions<E> {
+    fn clone(&self) -> Self {
+        Self { subscribers: self.subscribers.clone(), next_id: self.next_id.clone() }
+    }
+}
+
+impl<E> Default for Subscriptions<E> {
+    fn default() -> Self {
+        Self { subscribers: Default::default(), next_id: Default::default() }
+    }
+}
+
+impl<E: HistoryEvent + EventScope> Subscriptions<E> {
+    /// Start receiving copies of events matching `filter`, queueing up to `capacity`.
+    pub fn subscribe(&self, filter: EventFilter, capacity: usize) -> Subscription<E> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = mpsc::channel(capacity);
+        let dropped = Arc::new(AtomicU64::new(0));
+        self.lock().push(Subscriber { id, filter, sender, dropped: dropped.clone() });
+        Subscription { id, receiver: Mutex::new(receiver), dropped, subscriptions: self.clone() }
+    }
+
+    /// Hand a copy of `event` to every matching subscription. Never blocks.
+    pub fn dispatch(&self, event: &E) {
+        self.lock().retain_mut(|sub| {
+            if !sub.filter.matches(event) {
+                return true;
+            }
+            match sub.sender.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(e) if e.is_full() => {
+                    sub.dropped.fetch_add(1, Ordering::Relaxed);
+                    true
+                }
+                // Subscription handle dropped
+                Err(_) => false,
+            }
+        });
+    }
+
+    /// End every subscription. Events already queued can still be read.
+    pub fn close(&self) {
+        self.lock().clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn remove(&self, id: u64) {
+        self.lock().retain(|sub| sub.id != id);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Subscriber<E>>> {
+        self.subscribers.lock().expect("subscriptions lock")
+    }
+}
+
+/// A filtered stream of events, independent of the node's main event queue.
+pub struct Subscription<E: HistoryEvent + EventScope> {
+    id: u64,
+    receiver: Mutex<mpsc::Receiver<E>>,
+    dropped: Arc<AtomicU64>,
+    subscriptions: Subscriptions<E>,
+}
+
+impl<E: HistoryEvent + EventScope> Subscription<E> {
+    /// Next matching event; [`Next::Closed`] after [`Subscription::cancel`] once the
+    /// events already queued have been taken.
+    pub async fn next(&self) -> Next<E> {
+        next(&self.receiver).await
+    }
+
+    pub async fn next_until(&self, deadline: impl Future<Output = ()>) -> Next<E> {
+        next_until(&self.receiver, deadline).await
+    }
+
+    /// Matching events lost because this subscription's queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Stop receiving new events.
+    pub fn cancel(&self) {
+        self.subscriptions.remove(self.id);
+    }
+}
+
+impl<E: HistoryEvent + EventScope> Drop for Subscription<E> {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn concurrent_callers_each_get_one_event() {
+        let (tx, rx) = mpsc::unbounded();
+        let rx = Arc::new(Mutex::new(rx));
+        let a = tokio::spawn({
+            let rx = rx.clone();
+            async move { next(&rx).await }
+        });
+        let b = tokio::spawn({
+            let rx = rx.clone();
+            async move { next(&rx).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        tx.unbounded_send(1).unwrap();
+        tx.unbounded_send(2).unwrap();
+
+        let mut got = [a.await.unwrap(), b.await.unwrap()].map(|n| match n {
+            Next::Event(v) => v,
+            other => panic!("unexpected {other:?}"),
+        });
+        got.sort();
+        assert_eq!(got, [1, 2]);
+
+        drop(tx);
+        assert_eq!(next(&rx).await, Next::Closed);
+    }
+
+    #[tokio::test]
+    async fn timing_out_keeps_later_events() {
+        let (tx, rx) = mpsc::unbounded();
+        let rx = Mutex::new(rx);
+
+        // A second caller blocked behind the lock also honours its deadline
+        let guard = rx.lock().await;
+        let blocked = next_until(&rx, tokio::time::sleep(Duration::from_millis(10))).await;
+        assert_eq!(blocked, Next::TimedOut);
+        drop(guard);
+
+        assert_eq!(next_until(&rx, tokio::time::sleep(Duration::from_millis(10))).await, Next::TimedOut);
+        tx.unbounded_send("late").unwrap();
+        assert_eq!(next_until(&rx, std::future::ready(())).await, Next::Event("late"));
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    enum TestEvent {
+        Connected,
+        Message { topic: String },
+    }
+
+    impl HistoryEvent for TestEvent {
+        fn kind(&self) -> &'static str {
+            match self {
+                TestEvent::Connected => "connected",
+                TestEvent::Message { .. } => "message",
+            }
+        }
+
+        fn approx_size(&self) -> usize {
+            0
+        }
+    }
+
+    impl EventScope for TestEvent {
+        fn topic(&self) -> Option<String> {
+            match self {
+                TestEvent::Message { topic } => Some(topic.clone()),
+                TestEvent::Connected => None,
+            }
+        }
+    }
+
+    fn message(topic: &str) -> TestEvent {
+        TestEvent::Message { topic: topic.into() }
+    }
+
+    #[tokio::test]
+    async fn subscriptions_get_their_own_copies_and_count_overflow() {
+        let subs = Subscriptions::default();
+        let on_a = EventFilter { kinds: Some(vec!["message".into()]), topic: Some("a".into()), ..Default::default() };
+        let first = subs.subscribe(on_a.clone(), 1);
+        let second = subs.subscribe(on_a, 8);
+        let everything = subs.subscribe(EventFilter::default(), 8);
+
+        for event in [TestEvent::Connected, message("b"), message("a"), message("a")] {
+            subs.dispatch(&event);
+        }
+
+        assert_eq!(second.next().await, Next::Event(message("a")));
+        assert_eq!(second.next().await, Next::Event(message("a")));
+        // Capacity 1 plus the sender's own slot
+        assert_eq!(first.next().await, Next::Event(message("a")));
+        assert_eq!(first.next().await, Next::Event(message("a")));
+        assert_eq!(first.dropped(), 0);
+        subs.dispatch(&message("a"));
+        subs.dispatch(&message("a"));
+        subs.dispatch(&message("a"));
+        assert_eq!(first.dropped(), 1);
+        assert_eq!(everything.next().await, Next::Event(TestEvent::Connected));
+
+        second.cancel();
+        assert_eq!(second.next().await, Next::Event(message("a")));
+        second.next().await;
+        second.next().await;
+        assert_eq!(second.next().await, Next::Closed);
+        drop(everything);
+        assert_eq!(subs.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn closing_ends_every_subscription() {
+        let subs = Subscriptions::default();
+        let a = subs.subscribe(EventFilter::default(), 8);
+        let b = subs.subscribe(EventFilter::default(), 8);
+        subs.dispatch(&TestEvent::Connected);
+        subs.close();
+        subs.dispatch(&message("a"));
+
+        assert_eq!(a.next().await, Next::Event(TestEvent::Connected));
+        assert_eq!(a.next().await, Next::Closed);
+        assert_eq!(b.next().await, Next::Event(TestEvent::Connected));
+        assert_eq!(b.next().await, Next::Closed);
+        assert!(subs.is_empty());
+    }
+}
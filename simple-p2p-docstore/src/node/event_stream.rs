@@ -0,0 +1,129 @@
+//! Event streams for applications that consume a native node's events from more than one
+//! place, or want to `select!` on them next to their own futures.
+//!
+//! [`Node::events`] and [`Node::into_event_stream`] are the node's own event channel: one
+//! consumer, nothing ever dropped. [`Node::subscribe_events`] hands out as many
+//! [`EventSubscription`]s as needed, each seeing every event emitted after it was made.
+//! Subscriptions share one ring of [`EVENT_SUBSCRIPTION_CAPACITY`] events: a subscriber
+//! that falls further behind than that gets [`Lagged`] with the number of events it
+//! missed, then carries on from the oldest one still kept. A slow subscriber never slows
+//! the node or the other consumers.
+//!
+//! [`Node::watch_document`] is a stream of its own: one document's updates in order, see
+//! [`super::ordering`]. Like the event channel it never drops anything.
+//!
+//! [`Node::watch_document`]: super::Node::watch_document
+//! [`Node::events`]: super::Node::events
+//! [`Node::into_event_stream`]: super::Node::into_event_stream
+//! [`Node::subscribe_events`]: super::Node::subscribe_events
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::channel::mpsc;
+use futures::stream::BoxStream;
+use futures::{Stream, StreamExt};
+use tokio::sync::broadcast;
+
+use super::ordering::OrderedUpdate;
+
+/// Events kept for subscriptions that have not received them yet.
+pub const EVENT_SUBSCRIPTION_CAPACITY: usize = 1024;
+
+/// A subscription fell behind and missed this many events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("subscription lagged behind and missed {0} events")]
+pub struct Lagged(pub u64);
+
+/// One consumer of a node's events, see the [module docs](self). Ends once the node
+/// has stopped and every event emitted before was received.
+pub struct EventSubscription<T> {
+    inner: BoxStream<'static, Result<T, Lagged>>,
+}
+
+impl<T> std::fmt::Debug for EventSubscription<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EventSubscription").finish_non_exhaustive()
+    }
+}
+
+impl<T: Clone + Send + 'static> EventSubscription<T> {
+    pub(crate) fn new(receiver: broadcast::Receiver<T>) -> Self {
+        let inner = futures::stream::unfold(receiver, |mut receiver| async move {
+            match receiver.recv().await {
+                Ok(event) => Some((Ok(event), receiver)),
+                Err(broadcast::error::RecvError::Lagged(missed)) => Some((Err(Lagged(missed)), receiver)),
+                Err(broadcast::error::RecvError::Closed) => None,
+            }
+        });
+        Self { inner: inner.boxed() }
+    }
+
+    /// Wait for the next event. Returns `None` once the node has stopped.
+    pub async fn recv(&mut self) -> Option<Result<T, Lagged>> {
+        self.inner.next().await
+    }
+}
+
+impl<T> Stream for EventSubscription<T> {
+    type Item = Result<T, Lagged>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.poll_next_unpin(cx)
+    }
+}
+
+/// The updates of one document in order, each marked with how it reached the node, see
+/// [`Node::watch_document`](super::Node::watch_document). Ends once the node has stopped;
+/// dropping it stops the ordering unless the document has other watchers.
+#[derive(Debug)]
+pub struct DocumentWatch {
+    doc_id: String,
+    receiver: mpsc::UnboundedReceiver<OrderedUpdate>,
+}
+
+impl DocumentWatch {
+    pub(crate) fn new(doc_id: String, receiver: mpsc::UnboundedReceiver<OrderedUpdate>) -> Self {
+        Self { doc_id, receiver }
+    }
+
+    pub fn doc_id(&self) -> &str {
+        &self.doc_id
+    }
+
+    /// Wait for the next update. Returns `None` once the node has stopped.
+    pub async fn recv(&mut self) -> Option<OrderedUpdate> {
+        self.receiver.next().await
+    }
+}
+
+impl Stream for DocumentWatch {
+    type Item = OrderedUpdate;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_next_unpin(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn slow_subscribers_learn_what_they_missed() {
+        let (sender, receiver) = broadcast::channel(4);
+        let mut fast = EventSubscription::new(receiver);
+        let mut slow = EventSubscription::new(sender.subscribe());
+        for event in 0..6u32 {
+            sender.send(event).unwrap();
+            assert_eq!(fast.recv().await, Some(Ok(event)));
+        }
+        drop(sender);
+
+        assert_eq!(slow.recv().await, Some(Err(Lagged(2))));
+        // The oldest events still kept, then the end of the stream
+        let rest: Vec<_> = slow.collect().await;
+        assert_eq!(rest, [Ok(2), Ok(3), Ok(4), Ok(5)]);
+        assert_eq!(fast.recv().await, None);
+    }
+}
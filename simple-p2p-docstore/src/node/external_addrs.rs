@@ -0,0 +1,129 @@
+//! Our own addresses as other peers see them, shared by the native and wasm event loops.
+//!
+//! Identify tells us the address each peer observed us on, which the swarm reports as a
+//! candidate. Candidates become confirmed once something vouches for them (AutoNAT, a
+//! UPnP mapping, or the app) and are expired again when that stops. [`ExternalAddrs`]
+//! keeps the current list from the swarm events, so the app can ask "how do others see
+//! me" and be told when the answer changes.
+
+use std::collections::VecDeque;
+
+use libp2p::swarm::SwarmEvent;
+use libp2p::Multiaddr;
+
+/// Candidates kept; the oldest are forgotten past this. Every peer can report a different
+/// address, so the list would otherwise grow with the peers met.
+pub const MAX_CANDIDATES: usize = 16;
+
+/// A change to the list, to report to the app.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExternalAddrChange {
+    /// A peer observed us on an address we had not seen yet.
+    Candidate(Multiaddr),
+    /// Confirmed external: advertised to peers from now on.
+    Confirmed(Multiaddr),
+    /// No longer confirmed, nor advertised.
+    Expired(Multiaddr),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExternalAddrs {
+    /// Oldest first.
+    candidates: VecDeque<Multiaddr>,
+    confirmed: Vec<Multiaddr>,
+}
+
+impl ExternalAddrs {
+    /// Update the list from a swarm event. Returns the change if there is one: candidates
+    /// are reported for every identify exchange, so repeats are dropped here.
+    pub fn on_swarm_event<E>(&mut self, event: &SwarmEvent<E>) -> Option<ExternalAddrChange> {
+        match event {
+            SwarmEvent::NewExternalAddrCandidate { address } => self.candidate(address),
+            SwarmEvent::ExternalAddrConfirmed { address } => self.confirm(address),
+            SwarmEvent::ExternalAddrExpired { address } => self.expire(address),
+            _ => None,
+        }
+    }
+
+    fn candidate(&mut self, addr: &Multiaddr) -> Option<ExternalAddrChange> {
+        if self.candidates.contains(addr) || self.confirmed.contains(addr) {
+            return None;
+        }
+        if self.candidates.len() >= MAX_CANDIDATES {
+            self.candidates.pop_front();
+        }
+        self.candidates.push_back(addr.clone());
+        Some(ExternalAddrChange::Candidate(addr.clone()))
+    }
+
+    fn confirm(&mut self, addr: &Multiaddr) -> Option<ExternalAddrChange> {
+        self.candidates.retain(|a| a != addr);
+        if self.confirmed.contains(addr) {
+            return None;
+        }
+        self.confirmed.push(addr.clone());
+        Some(ExternalAddrChange::Confirmed(addr.clone()))
+    }
+
+    fn expire(&mut self, addr: &Multiaddr) -> Option<ExternalAddrChange> {
+        let before = self.confirmed.len();
+        self.confirmed.retain(|a| a != addr);
+        (self.confirmed.len() < before).then(|| ExternalAddrChange::Expired(addr.clone()))
+    }
+
+    /// Confirmed external addresses, in the order they were confirmed.
+    pub fn confirmed(&self) -> &[Multiaddr] {
+        &self.confirmed
+    }
+
+    /// Addresses peers observed us on that are not confirmed, oldest first.
+    pub fn candidates(&self) -> impl Iterator<Item = &Multiaddr> {
+        self.candidates.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Event = SwarmEvent<()>;
+
+    fn addr(port: u16) -> Multiaddr {
+        format!("/ip4/203.0.113.7/tcp/{port}").parse().unwrap()
+    }
+
+    #[test]
+    fn candidates_are_confirmed_and_expired() {
+        let mut addrs = ExternalAddrs::default();
+        let candidate = Event::NewExternalAddrCandidate { address: addr(4001) };
+        assert_eq!(addrs.on_swarm_event(&candidate), Some(ExternalAddrChange::Candidate(addr(4001))));
+        // Every peer reports it again
+        assert_eq!(addrs.on_swarm_event(&candidate), None);
+        assert_eq!(addrs.candidates().collect::<Vec<_>>(), [&addr(4001)]);
+
+        let confirmed = Event::ExternalAddrConfirmed { address: addr(4001) };
+        assert_eq!(addrs.on_swarm_event(&confirmed), Some(ExternalAddrChange::Confirmed(addr(4001))));
+        assert_eq!(addrs.confirmed(), [addr(4001)]);
+        assert_eq!(addrs.candidates().count(), 0);
+        assert_eq!(addrs.on_swarm_event(&candidate), None, "already confirmed");
+
+        let expired = Event::ExternalAddrExpired { address: addr(4001) };
+        assert_eq!(addrs.on_swarm_event(&expired), Some(ExternalAddrChange::Expired(addr(4001))));
+        assert_eq!(addrs.on_swarm_event(&expired), None);
+        assert!(addrs.confirmed().is_empty());
+
+        // Our listen addresses are not external ones
+        let listen = Event::NewListenAddr { listener_id: libp2p::core::transport::ListenerId::next(), address: addr(4002) };
+        assert_eq!(addrs.on_swarm_event(&listen), None);
+    }
+
+    #[test]
+    fn only_the_latest_candidates_are_kept() {
+        let mut addrs = ExternalAddrs::default();
+        for port in 0..MAX_CANDIDATES as u16 + 2 {
+            addrs.on_swarm_event(&Event::NewExternalAddrCandidate { address: addr(port) });
+        }
+        assert_eq!(addrs.candidates().count(), MAX_CANDIDATES);
+        assert_eq!(addrs.candidates().next(), Some(&addr(2)));
+    }
+}
@@ -0,0 +1,206 @@
+//! Federations: relays run by one operator, in different regions, kept meshed with each
+//! other. Each relay is given the others' addresses (`server --federation`, or
+//! [`crate::node::NodeBuilder::with_federation`]) and
+//!
+//! - keeps a connection to every member, redialing forever with backoff when it drops,
+//! - makes members explicit gossipsub peers, so every message goes to them straight
+//!   away rather than through the mesh, which prunes, backs off and scores browsers,
+//! - counts the links apart from client connections in its metrics.
+//!
+//! Links are authenticated by peer id, which the transport's handshake proves. Once a
+//! federation is configured, only its members count as relays: a peer that is not in the
+//! list but serves the relay hop protocol is treated as an ordinary client, so it gets
+//! no local-scope traffic from the other relays and is not trusted as a relay.
+
+use std::collections::BTreeMap;
+
+use libp2p::{Multiaddr, PeerId};
+
+use crate::node::addrs::peer_id_of;
+
+#[derive(Debug, Clone, Default)]
+struct Member {
+    addrs: Vec<Multiaddr>,
+    connected: bool,
+    /// Connections established since the start.
+    connects: u64,
+    /// Times the link went down since the start.
+    drops: u64,
+}
+
+/// The members of a federation and the state of the links to them. See the module docs.
+#[derive(Debug, Clone, Default)]
+pub struct Federation {
+    members: BTreeMap<PeerId, Member>,
+}
+
+impl Federation {
+    /// Comma-separated member addresses, each ending in `/p2p/<peer id>`. A member may be
+    /// listed under several addresses.
+    pub fn parse(list: &str) -> Result<Self, String> {
+        let mut federation = Self::default();
+        for addr in list.split(',').map(str::trim).filter(|a| !a.is_empty()) {
+            let addr: Multiaddr = addr.parse().map_err(|e| format!("invalid federation address {addr}: {e}"))?;
+            federation.add(addr)?;
+        }
+        Ok(federation)
+    }
+
+    /// Add a member at `addr`, which must name its peer. Returns the member's peer id.
+    pub fn add(&mut self, addr: Multiaddr) -> Result<PeerId, String> {
+        let peer_id = peer_id_of(&addr).ok_or_else(|| format!("federation address {addr} doesn't end in /p2p/<peer id>"))?;
+        let member = self.members.entry(peer_id).or_default();
+        if !member.addrs.contains(&addr) {
+            member.addrs.push(addr);
+        }
+        Ok(peer_id)
+    }
+
+    /// Leave `local` out, so one list can be handed to every member.
+    pub fn without(mut self, local: &PeerId) -> Self {
+        self.members.remove(local);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn is_member(&self, peer_id: &PeerId) -> bool {
+        self.members.contains_key(peer_id)
+    }
+
+    /// Each member with its configured addresses.
+    pub fn members(&self) -> impl Iterator<Item = (&PeerId, &[Multiaddr])> {
+        self.members.iter().map(|(peer_id, member)| (peer_id, member.addrs.as_slice()))
+    }
+
+    /// Whether `peer_id` counts as a relay, given whether it serves the relay hop
+    /// protocol: without a federation every peer serving it does, with one only members.
+    pub fn is_relay(&self, peer_id: &PeerId, serves_hop: bool) -> bool {
+        serves_hop && (self.is_empty() || self.is_member(peer_id))
+    }
+
+    /// A connection to `peer_id` is up. Returns true if it is a member whose link just
+    /// came up.
+    pub fn connected(&mut self, peer_id: &PeerId) -> bool {
+        match self.members.get_mut(peer_id) {
+            Some(member) if !member.connected => {
+                member.connected = true;
+                member.connects += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// The last connection to `peer_id` closed. Returns true if it was a member's link.
+    pub fn disconnected(&mut self, peer_id: &PeerId) -> bool {
+        match self.members.get_mut(peer_id) {
+            Some(member) if member.connected => {
+                member.connected = false;
+                member.drops += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// The links, with `clients` connected peers that are not members.
+    pub fn status(&self, clients: usize) -> FederationStatus {
+        FederationStatus {
+            links: self
+                .members
+                .iter()
+                .map(|(peer_id, member)| FederationLink {
+                    peer_id: *peer_id,
+                    connected: member.connected,
+                    connects: member.connects,
+                    drops: member.drops,
+                })
+                .collect(),
+            clients,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FederationLink {
+    pub peer_id: PeerId,
+    pub connected: bool,
+    pub connects: u64,
+    pub drops: u64,
+}
+
+/// A snapshot of a [`Federation`], for operators.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FederationStatus {
+    pub links: Vec<FederationLink>,
+    /// Connected peers that are not members.
+    pub clients: usize,
+}
+
+impl FederationStatus {
+    /// `/metrics` lines: links up and configured, client connections apart from them,
+    /// and each link's state labelled with the member's peer id.
+    pub fn metrics(&self) -> Vec<(String, u64)> {
+        let mut metrics = vec![
+            ("docstore_federation_peers".to_string(), self.links.len() as u64),
+            ("docstore_federation_links_up".to_string(), self.links.iter().filter(|l| l.connected).count() as u64),
+            ("docstore_client_connections".to_string(), self.clients as u64),
+        ];
+        for link in &self.links {
+            let peer = link.peer_id;
+            metrics.push((format!("docstore_federation_link_up{{peer=\"{peer}\"}}"), link.connected as u64));
+            metrics.push((format!("docstore_federation_link_connects_total{{peer=\"{peer}\"}}"), link.connects));
+            metrics.push((format!("docstore_federation_link_drops_total{{peer=\"{peer}\"}}"), link.drops));
+        }
+        metrics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_members_count_as_relays_and_links_are_counted_apart() {
+        let (a, b, stranger) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let list = format!("/ip4/10.0.0.1/tcp/4001/p2p/{a}, /ip4/10.0.0.2/tcp/4001/p2p/{b},/dns4/b.example/tcp/4001/p2p/{b}");
+        let mut federation = Federation::parse(&list).unwrap();
+        assert_eq!(federation.len(), 2);
+        assert_eq!(federation.members().find(|(peer, _)| **peer == b).unwrap().1.len(), 2);
+        assert!(Federation::parse("/ip4/10.0.0.1/tcp/4001").is_err());
+        assert!(Federation::parse("not an address").is_err());
+
+        assert!(federation.is_relay(&a, true));
+        assert!(!federation.is_relay(&stranger, true));
+        assert!(!federation.is_relay(&a, false));
+        assert!(Federation::default().is_relay(&stranger, true));
+
+        assert!(federation.connected(&a));
+        assert!(!federation.connected(&a));
+        assert!(!federation.connected(&stranger));
+        assert!(federation.disconnected(&a));
+        assert!(!federation.disconnected(&stranger));
+        federation.connected(&a);
+        let metrics = federation.status(3).metrics();
+        assert_eq!(
+            metrics[..3],
+            [
+                ("docstore_federation_peers".to_string(), 2),
+                ("docstore_federation_links_up".to_string(), 1),
+                ("docstore_client_connections".to_string(), 3),
+            ]
+        );
+        assert!(metrics.contains(&(format!("docstore_federation_link_connects_total{{peer=\"{a}\"}}"), 2)));
+        assert!(metrics.contains(&(format!("docstore_federation_link_drops_total{{peer=\"{a}\"}}"), 1)));
+
+        // The same list works on every member
+        assert!(!federation.without(&a).is_member(&a));
+    }
+}
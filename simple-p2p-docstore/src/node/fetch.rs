@@ -0,0 +1,178 @@
+//! The requesting side of `/docstore/fetch/1.0.0` (see [`crate::behaviour::fetch`]): a
+//! [`Transfer`] holds the verified prefix of a document being fetched, a chunk at a time.
+//!
+//! Transfers outlive failed requests and dropped connections. Asking for the document
+//! again fetches a fresh manifest, possibly from another provider, and keeps every
+//! verified chunk the new manifest has at the same place, so only what is missing is
+//! downloaded. Native nodes also keep transfers in their store, which outlives a
+//! restart; browsers keep them in memory.
+
+use crate::behaviour::docstore::snapshot::content_hash;
+use crate::behaviour::fetch::FetchManifest;
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum TransferError {
+    #[error("invalid manifest: {0}")]
+    BadManifest(String),
+    #[error("expected the chunk at {expected}, got one at {offset}")]
+    UnexpectedOffset { offset: u64, expected: u64 },
+    #[error("the chunk at {offset} does not match the manifest")]
+    BadChunk { offset: u64 },
+    #[error("the assembled document does not match its content hash")]
+    HashMismatch,
+}
+
+impl From<TransferError> for crate::Error {
+    fn from(e: TransferError) -> Self {
+        crate::Error::FetchFailed { reason: e.to_string() }
+    }
+}
+
+/// See the module docs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transfer {
+    manifest: FetchManifest,
+    /// The chunks verified so far, in order.
+    data: Vec<u8>,
+}
+
+impl Transfer {
+    pub fn new(manifest: FetchManifest) -> Result<Self, TransferError> {
+        manifest.validate().map_err(TransferError::BadManifest)?;
+        Ok(Self { manifest, data: Vec::new() })
+    }
+
+    /// A transfer kept in a store: `data` is checked again, and only the chunks that still
+    /// match the manifest are kept.
+    pub fn restore(manifest: FetchManifest, data: Vec<u8>) -> Result<Self, TransferError> {
+        let mut transfer = Self::new(manifest)?;
+        for index in 0..transfer.manifest.chunk_count() {
+            let range = transfer.manifest.chunk_range(index);
+            let Some(chunk) = data.get(range.start as usize..range.end as usize) else {
+                break;
+            };
+            if transfer.accept(range.start, chunk).is_err() {
+                break;
+            }
+        }
+        Ok(transfer)
+    }
+
+    /// Carry on with `manifest`, freshly fetched from this provider or another. Verified
+    /// chunks are kept up to the first one the new manifest has differently; returns how
+    /// many bytes were kept.
+    pub fn resume(&mut self, manifest: FetchManifest) -> Result<u64, TransferError> {
+        manifest.validate().map_err(TransferError::BadManifest)?;
+        let same_layout = manifest.chunk_size == self.manifest.chunk_size;
+        let mut kept = 0;
+        for index in 0..self.verified_chunks() {
+            let range = self.manifest.chunk_range(index);
+            let same_chunk = manifest.chunk_range(index) == range
+                && manifest.chunk_hashes.get(index) == self.manifest.chunk_hashes.get(index);
+            if !same_layout || !same_chunk {
+                break;
+            }
+            kept = range.end;
+        }
+        self.data.truncate(kept as usize);
+        self.manifest = manifest;
+        Ok(kept)
+    }
+
+    pub fn manifest(&self) -> &FetchManifest {
+        &self.manifest
+    }
+
+    /// The verified bytes, from the start of the document.
+    pub fn verified(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn received(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    pub fn total(&self) -> u64 {
+        self.manifest.total
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.received() == self.total()
+    }
+
+    /// Offset and length of the chunk to ask for next, `None` once complete.
+    pub fn next_range(&self) -> Option<(u64, u32)> {
+        let range = self.manifest.chunk_range(self.verified_chunks());
+        (!range.is_empty()).then(|| (range.start, (range.end - range.start) as u32))
+    }
+
+    /// Keep the chunk at `offset` if it is the next one and matches the manifest.
+    pub fn accept(&mut self, offset: u64, data: &[u8]) -> Result<(), TransferError> {
+        let index = self.verified_chunks();
+        let expected = self.manifest.chunk_range(index);
+        if offset != expected.start {
+            return Err(TransferError::UnexpectedOffset { offset, expected: expected.start });
+        }
+        if data.len() as u64 != expected.end - expected.start || self.manifest.chunk_hashes.get(index) != Some(&content_hash(data)) {
+            return Err(TransferError::BadChunk { offset });
+        }
+        self.data.extend_from_slice(data);
+        Ok(())
+    }
+
+    /// The whole document, once complete and matching the manifest's content hash.
+    pub fn finish(self) -> Result<(FetchManifest, Vec<u8>), TransferError> {
+        if !self.is_complete() || content_hash(&self.data) != self.manifest.content_hash {
+            return Err(TransferError::HashMismatch);
+        }
+        Ok((self.manifest, self.data))
+    }
+
+    /// Chunks are only kept whole, so the prefix always ends on a chunk boundary.
+    fn verified_chunks(&self) -> usize {
+        self.data.len().div_ceil(self.manifest.chunk_size as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunks(transfer: &mut Transfer, content: &[u8], count: usize) {
+        for _ in 0..count {
+            let (offset, len) = transfer.next_range().unwrap();
+            transfer.accept(offset, &content[offset as usize..(offset + len as u64) as usize]).unwrap();
+        }
+    }
+
+    #[test]
+    fn resumes_with_another_providers_manifest_keeping_matching_chunks() {
+        let content: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+        let mut transfer = Transfer::new(FetchManifest::new("doc", 3, &content, 100)).unwrap();
+        chunks(&mut transfer, &content, 4);
+        assert_eq!(transfer.received(), 400);
+
+        // A bad chunk is refused and leaves the prefix alone
+        assert_eq!(transfer.accept(400, &[0; 100]), Err(TransferError::BadChunk { offset: 400 }));
+        assert_eq!(transfer.accept(500, &content[500..600]), Err(TransferError::UnexpectedOffset { offset: 500, expected: 400 }));
+
+        // Another provider has the same content under its own version: nothing is lost
+        assert_eq!(transfer.resume(FetchManifest::new("doc", 7, &content, 100)), Ok(400));
+        assert_eq!(transfer.next_range(), Some((400, 100)));
+
+        // The document changed in its third chunk: the chunks before it are kept
+        let mut changed = content.clone();
+        changed[250] ^= 1;
+        changed.truncate(950);
+        assert_eq!(transfer.resume(FetchManifest::new("doc", 8, &changed, 100)), Ok(200));
+        chunks(&mut transfer, &changed, 8);
+        assert_eq!(transfer.next_range(), None);
+        assert_eq!(transfer.finish().unwrap().1, changed);
+
+        // A stored prefix is checked again: the chunk corrupted on disk and what follows go
+        let mut stored = content[..300].to_vec();
+        stored[150] ^= 1;
+        let restored = Transfer::restore(FetchManifest::new("doc", 3, &content, 100), stored).unwrap();
+        assert_eq!(restored.received(), 100);
+    }
+}
@@ -0,0 +1,81 @@
+//! Options and results of peer lookups, shared by the native and wasm event loops.
+//!
+//! A networked lookup walks the DHT towards the target and reports the closest peers it
+//! met; a local lookup only consults what this node already knows and never waits on the
+//! network.
+
+use std::time::Duration;
+
+use libp2p::{Multiaddr, PeerId};
+
+/// Closest peers reported by a networked lookup unless asked otherwise (Kademlia's `k`).
+pub const DEFAULT_FIND_PEER_RESULTS: usize = 20;
+
+/// How long a networked lookup may take before the caller gives up on it.
+pub const DEFAULT_FIND_PEER_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FindPeerOptions {
+    /// Number of closest peers to report. `0` is treated as `1`.
+    pub num_results: usize,
+    pub timeout: Duration,
+    /// Dial the target through its dialable addresses once the lookup finds it.
+    pub dial: bool,
+}
+
+impl Default for FindPeerOptions {
+    fn default() -> Self {
+        Self { num_results: DEFAULT_FIND_PEER_RESULTS, timeout: DEFAULT_FIND_PEER_TIMEOUT, dial: false }
+    }
+}
+
+/// A peer and the addresses known for it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FoundPeer {
+    pub peer_id: PeerId,
+    pub addrs: Vec<Multiaddr>,
+    /// The subset of `addrs` this node's transports can dial.
+    pub dialable: Vec<Multiaddr>,
+}
+
+impl FoundPeer {
+    /// Collect `addrs` without duplicates, keeping their order, and classify them with
+    /// `is_dialable`.
+    pub fn new(
+        peer_id: PeerId,
+        addrs: impl IntoIterator<Item = Multiaddr>,
+        is_dialable: impl Fn(&Multiaddr) -> bool,
+    ) -> Self {
+        let mut unique: Vec<Multiaddr> = Vec::new();
+        for addr in addrs {
+            if !unique.contains(&addr) {
+                unique.push(addr);
+            }
+        }
+        let dialable = unique.iter().filter(|a| is_dialable(a)).cloned().collect();
+        Self { peer_id, addrs: unique, dialable }
+    }
+
+    pub fn is_dialable(&self) -> bool {
+        !self.dialable.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dedups_addresses_and_splits_out_the_dialable_ones() {
+        let tcp: Multiaddr = "/ip4/10.0.0.1/tcp/4001".parse().unwrap();
+        let direct: Multiaddr = "/ip4/10.0.0.1/udp/9090/webrtc-direct".parse().unwrap();
+        let peer = FoundPeer::new(
+            PeerId::random(),
+            vec![tcp.clone(), direct.clone(), tcp.clone()],
+            crate::node::addrs::is_tcp_dialable,
+        );
+        assert_eq!(peer.addrs, vec![tcp.clone(), direct]);
+        assert_eq!(peer.dialable, vec![tcp]);
+        assert!(peer.is_dialable());
+    }
+}
@@ -0,0 +1,352 @@
+//! Readiness and liveness of a running server, for container orchestration.
+//!
+//! The event loop reports what it has done (listeners up, topic subscribed, bootstrap
+//! attempted) and ticks [`Health`] on every iteration. A minimal HTTP endpoint serves
+//! `/livez` (the loop is still turning) and `/healthz` (the node is ready for traffic),
+//! and under systemd the same transitions can be sent through `sd_notify`. Counters the
+//! loop publishes with [`Health::set_metric`] are served at `/metrics` in the Prometheus
+//! text format.
+//!
+//! Readiness regresses once every listener has closed or the loop stops ticking for
+//! longer than the stall timeout.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use libp2p::core::transport::ListenerId;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+
+/// The loop is considered stalled after this long without a tick.
+pub const DEFAULT_STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReadinessState {
+    /// Not ready yet for the first time; `waiting_for` says on what.
+    Starting { waiting_for: &'static str },
+    Ready,
+    /// Was ready, and no longer is.
+    NotReady { reason: &'static str },
+}
+
+impl ReadinessState {
+    pub fn is_ready(&self) -> bool {
+        matches!(self, ReadinessState::Ready)
+    }
+}
+
+impl std::fmt::Display for ReadinessState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReadinessState::Starting { waiting_for } => write!(f, "starting: waiting for {waiting_for}"),
+            ReadinessState::Ready => write!(f, "ready"),
+            ReadinessState::NotReady { reason } => write!(f, "not ready: {reason}"),
+        }
+    }
+}
+
+/// What the event loop has reported so far. Times are unix milliseconds.
+#[derive(Debug)]
+pub struct Readiness {
+    /// Listeners asked for, and how many addresses each currently has.
+    listeners: HashMap<ListenerId, usize>,
+    subscribed: bool,
+    bootstrap_attempted: bool,
+    last_tick_ms: u64,
+    stall_timeout_ms: u64,
+    been_ready: bool,
+}
+
+impl Readiness {
+    pub fn new(now_ms: u64, stall_timeout: Duration) -> Self {
+        Self {
+            listeners: HashMap::new(),
+            subscribed: false,
+            bootstrap_attempted: false,
+            last_tick_ms: now_ms,
+            stall_timeout_ms: stall_timeout.as_millis() as u64,
+            been_ready: false,
+        }
+    }
+
+    /// A listener was requested; readiness waits for it to report an address.
+    pub fn expect_listener(&mut self, id: ListenerId) {
+        self.listeners.entry(id).or_insert(0);
+    }
+
+    pub fn listen_addr_added(&mut self, id: ListenerId) {
+        *self.listeners.entry(id).or_insert(0) += 1;
+    }
+
+    pub fn listen_addr_expired(&mut self, id: ListenerId) {
+        if let Some(count) = self.listeners.get_mut(&id) {
+            *count = count.saturating_sub(1);
+        }
+    }
+
+    pub fn listener_closed(&mut self, id: ListenerId) {
+        if let Some(count) = self.listeners.get_mut(&id) {
+            *count = 0;
+        }
+    }
+
+    pub fn set_subscribed(&mut self) {
+        self.subscribed = true;
+    }
+
+    /// Bootstrap ran (whatever its outcome), or there was nothing to bootstrap from.
+    pub fn set_bootstrap_attempted(&mut self) {
+        self.bootstrap_attempted = true;
+    }
+
+    /// The event loop is alive at `now_ms`.
+    pub fn tick(&mut self, now_ms: u64) {
+        self.last_tick_ms = now_ms;
+    }
+
+    pub fn is_live(&self, now_ms: u64) -> bool {
+        now_ms.saturating_sub(self.last_tick_ms) <= self.stall_timeout_ms
+    }
+
+    /// Current state. The first `Ready` is remembered, so later losses read as `NotReady`.
+    pub fn state(&mut self, now_ms: u64) -> ReadinessState {
+        let listening = self.listeners.values().filter(|addrs| **addrs > 0).count();
+        if !self.is_live(now_ms) {
+            return self.not_ready("event loop stalled");
+        }
+        if self.been_ready {
+            if listening == 0 {
+                return ReadinessState::NotReady { reason: "all listeners closed" };
+            }
+            return ReadinessState::Ready;
+        }
+        let waiting_for = if self.listeners.is_empty() || listening < self.listeners.len() {
+            "listeners"
+        } else if !self.subscribed {
+            "topic subscription"
+        } else if !self.bootstrap_attempted {
+            "bootstrap"
+        } else {
+            self.been_ready = true;
+            return ReadinessState::Ready;
+        };
+        ReadinessState::Starting { waiting_for }
+    }
+
+    fn not_ready(&self, reason: &'static str) -> ReadinessState {
+        if self.been_ready {
+            ReadinessState::NotReady { reason }
+        } else {
+            ReadinessState::Starting { waiting_for: reason }
+        }
+    }
+}
+
+fn now_ms() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+/// [`Readiness`] shared between the event loop and the status endpoint. Cheap to clone.
+#[derive(Debug, Clone)]
+pub struct Health {
+    inner: Arc<Mutex<Readiness>>,
+    metrics: Arc<Mutex<BTreeMap<String, u64>>>,
+}
+
+impl Health {
+    pub fn new(stall_timeout: Duration) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Readiness::new(now_ms(), stall_timeout))),
+            metrics: Arc::default(),
+        }
+    }
+
+    /// Update the readiness inputs.
+    pub fn update(&self, f: impl FnOnce(&mut Readiness)) {
+        f(&mut self.inner.lock().expect("health lock"));
+    }
+
+    pub fn tick(&self) {
+        self.update(|r| r.tick(now_ms()));
+    }
+
+    pub fn state(&self) -> ReadinessState {
+        self.inner.lock().expect("health lock").state(now_ms())
+    }
+
+    pub fn is_live(&self) -> bool {
+        self.inner.lock().expect("health lock").is_live(now_ms())
+    }
+
+    /// Set the counter `name` served at `/metrics`, e.g. `docstore_duplicates_total`.
+    pub fn set_metric(&self, name: impl Into<String>, value: u64) {
+        self.metrics.lock().expect("health lock").insert(name.into(), value);
+    }
+
+    /// Stop serving the counter `name`, e.g. one labelled with a peer that went away.
+    pub fn remove_metric(&self, name: &str) {
+        self.metrics.lock().expect("health lock").remove(name);
+    }
+
+    /// Every counter, one `name value` line each.
+    pub fn render_metrics(&self) -> String {
+        self.metrics.lock().expect("health lock").iter().map(|(name, value)| format!("{name} {value}\n")).collect()
+    }
+}
+
+/// Bind the status endpoint on all interfaces, so orchestrators can probe it from outside.
+pub async fn bind_http(port: u16) -> io::Result<TcpListener> {
+    TcpListener::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port))).await
+}
+
+/// Answer `GET /healthz`, `GET /livez` and `GET /metrics` until the process exits.
+pub async fn serve_http(listener: TcpListener, health: Health) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(handle_http(stream, health.clone()));
+            }
+            Err(e) => tracing::warn!("Status endpoint accept failed: {}", e),
+        }
+    }
+}
+
+async fn handle_http(stream: tokio::net::TcpStream, health: Health) {
+    let (read, mut write) = stream.into_split();
+    let mut request_line = String::new();
+    if BufReader::new(read).read_line(&mut request_line).await.is_err() {
+        return;
+    }
+    let path = request_line.split_whitespace().nth(1).unwrap_or("");
+    let (status, body) = match path {
+        "/healthz" => {
+            let state = health.state();
+            (if state.is_ready() { "200 OK" } else { "503 Service Unavailable" }, state.to_string())
+        }
+        "/livez" if health.is_live() => ("200 OK", "live".to_string()),
+        "/livez" => ("503 Service Unavailable", "event loop stalled".to_string()),
+        "/metrics" => ("200 OK", health.render_metrics().trim_end().to_string()),
+        _ => ("404 Not Found", "not found".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}\n",
+        body.len() + 1
+    );
+    let _ = write.write_all(response.as_bytes()).await;
+}
+
+/// Send `state` (e.g. `READY=1`) to systemd's notification socket. Returns false when not
+/// running under systemd (`NOTIFY_SOCKET` unset).
+#[cfg(unix)]
+pub fn sd_notify(state: &str) -> io::Result<bool> {
+    use std::os::unix::net::UnixDatagram;
+
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return Ok(false);
+    };
+    let socket = UnixDatagram::unbound()?;
+    let path = path.to_string_lossy();
+    match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes())?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        _ => {
+            socket.send_to(state.as_bytes(), path.as_ref())?;
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(not(unix))]
+pub fn sd_notify(_state: &str) -> io::Result<bool> {
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STALL: Duration = Duration::from_secs(30);
+
+    #[test]
+    fn becomes_ready_once_everything_is_up() {
+        let mut r = Readiness::new(0, STALL);
+        let (tcp, webrtc) = (ListenerId::next(), ListenerId::next());
+        assert_eq!(r.state(0), ReadinessState::Starting { waiting_for: "listeners" });
+        r.expect_listener(tcp);
+        r.expect_listener(webrtc);
+        r.listen_addr_added(tcp);
+        // One of two transports is not enough
+        assert_eq!(r.state(0), ReadinessState::Starting { waiting_for: "listeners" });
+        r.listen_addr_added(webrtc);
+        assert_eq!(r.state(0), ReadinessState::Starting { waiting_for: "topic subscription" });
+        r.set_subscribed();
+        assert_eq!(r.state(0), ReadinessState::Starting { waiting_for: "bootstrap" });
+        r.set_bootstrap_attempted();
+        assert_eq!(r.state(0), ReadinessState::Ready);
+
+        // Losing one listener is tolerated, losing all is not
+        r.listener_closed(tcp);
+        assert_eq!(r.state(0), ReadinessState::Ready);
+        r.listen_addr_expired(webrtc);
+        assert_eq!(r.state(0), ReadinessState::NotReady { reason: "all listeners closed" });
+        r.listen_addr_added(webrtc);
+        assert_eq!(r.state(0), ReadinessState::Ready);
+    }
+
+    #[test]
+    fn a_stalled_loop_is_neither_live_nor_ready() {
+        let mut r = Readiness::new(0, STALL);
+        let id = ListenerId::next();
+        r.expect_listener(id);
+        r.listen_addr_added(id);
+        r.set_subscribed();
+        r.set_bootstrap_attempted();
+        assert!(r.state(1_000).is_ready());
+
+        assert!(r.is_live(30_000));
+        assert!(!r.is_live(30_001));
+        assert_eq!(r.state(30_001), ReadinessState::NotReady { reason: "event loop stalled" });
+        r.tick(30_001);
+        assert!(r.state(30_001).is_ready());
+    }
+
+    #[tokio::test]
+    async fn serves_probes_over_http() {
+        use tokio::io::AsyncReadExt;
+
+        let health = Health::new(STALL);
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(serve_http(listener, health.clone()));
+
+        let get = |path: &'static str| async move {
+            let mut stream = tokio::net::TcpStream::connect((Ipv4Addr::LOCALHOST, port)).await.unwrap();
+            stream.write_all(format!("GET {path} HTTP/1.1\r\nHost: x\r\n\r\n").as_bytes()).await.unwrap();
+            let mut out = String::new();
+            stream.read_to_string(&mut out).await.unwrap();
+            out
+        };
+        assert!(get("/livez").await.starts_with("HTTP/1.1 200"));
+        assert!(get("/healthz").await.starts_with("HTTP/1.1 503"));
+
+        health.update(|r| {
+            let id = ListenerId::next();
+            r.expect_listener(id);
+            r.listen_addr_added(id);
+            r.set_subscribed();
+            r.set_bootstrap_attempted();
+        });
+        assert!(get("/healthz").await.starts_with("HTTP/1.1 200"));
+        assert!(get("/nope").await.starts_with("HTTP/1.1 404"));
+
+        health.set_metric("docstore_duplicates_total", 3);
+        assert!(get("/metrics").await.ends_with("\r\n\r\ndocstore_duplicates_total 3\n"));
+    }
+}
@@ -0,0 +1,164 @@
+//! Bounded history of recently emitted node events, kept for debugging after the live
+//! consumer has missed or discarded them.
+//!
+//! The ring is capped both by entry count and by the approximate bytes its events hold;
+//! the oldest entries are evicted first. Its storage is allocated up front, so recording
+//! an event costs nothing beyond the entry itself.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// Entries kept by default.
+pub const DEFAULT_HISTORY_ENTRIES: usize = 256;
+/// Approximate event bytes kept by default.
+pub const DEFAULT_HISTORY_BYTES: usize = 256 * 1024;
+
+/// An event type that can be kept in an [`EventHistory`].
+pub trait HistoryEvent: Clone {
+    /// Name used to filter events, e.g. `"connected"`.
+    fn kind(&self) -> &'static str;
+
+    /// Rough heap and inline size, counted against the byte cap.
+    fn approx_size(&self) -> usize;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryEntry<E> {
+    /// Unix milliseconds at which the event was emitted.
+    pub at_ms: u64,
+    pub event: E,
+}
+
+#[derive(Debug)]
+struct Ring<E> {
+    entries: VecDeque<(HistoryEntry<E>, usize)>,
+    max_entries: usize,
+    max_bytes: usize,
+    bytes: usize,
+}
+
+/// Shared ring buffer of recent events. Cheap to clone; clones see the same history.
+#[derive(Debug, Clone)]
+pub struct EventHistory<E> {
+    ring: Arc<Mutex<Ring<E>>>,
+}
+
+impl<E: HistoryEvent> EventHistory<E> {
+    /// A history of at most `max_entries` events holding at most `max_bytes`. Zero
+    /// entries disables recording.
+    pub fn new(max_entries: usize, max_bytes: usize) -> Self {
+        let ring = Ring { entries: VecDeque::with_capacity(max_entries), max_entries, max_bytes, bytes: 0 };
+        Self { ring: Arc::new(Mutex::new(ring)) }
+    }
+
+    pub fn record(&self, event: &E) {
+        self.record_at(crate::store::now_ms(), event);
+    }
+
+    /// Like [`EventHistory::record`] with an explicit timestamp.
+    pub fn record_at(&self, at_ms: u64, event: &E) {
+        let size = event.approx_size();
+        let mut ring = self.ring.lock().expect("history lock");
+        if ring.max_entries == 0 || size > ring.max_bytes {
+            return;
+        }
+        while ring.entries.len() >= ring.max_entries || ring.bytes + size > ring.max_bytes {
+            let Some((_, evicted)) = ring.entries.pop_front() else {
+                break;
+            };
+            ring.bytes -= evicted;
+        }
+        ring.bytes += size;
+        ring.entries.push_back((HistoryEntry { at_ms, event: event.clone() }, size));
+    }
+
+    /// The most recent `limit` events (all if `None`) whose kind is in `kinds` (any kind
+    /// if `None`), oldest first.
+    pub fn recent(&self, kinds: Option<&[&str]>, limit: Option<usize>) -> Vec<HistoryEntry<E>> {
+        let ring = self.ring.lock().expect("history lock");
+        let mut out: Vec<_> = ring
+            .entries
+            .iter()
+            .rev()
+            .map(|(entry, _)| entry)
+            .filter(|entry| kinds.is_none_or(|kinds| kinds.contains(&entry.event.kind())))
+            .take(limit.unwrap_or(usize::MAX))
+            .cloned()
+            .collect();
+        out.reverse();
+        out
+    }
+
+    pub fn len(&self) -> usize {
+        self.ring.lock().expect("history lock").entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&self) {
+        let mut ring = self.ring.lock().expect("history lock");
+        ring.entries.clear();
+        ring.bytes = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    enum TestEvent {
+        Connected,
+        Error(String),
+    }
+
+    impl HistoryEvent for TestEvent {
+        fn kind(&self) -> &'static str {
+            match self {
+                TestEvent::Connected => "connected",
+                TestEvent::Error(_) => "error",
+            }
+        }
+
+        fn approx_size(&self) -> usize {
+            match self {
+                TestEvent::Connected => 1,
+                TestEvent::Error(msg) => msg.len(),
+            }
+        }
+    }
+
+    #[test]
+    fn evicts_oldest_by_count_and_bytes() {
+        let history = EventHistory::new(3, 10);
+        for at in 0..5 {
+            history.record_at(at, &TestEvent::Connected);
+        }
+        let times: Vec<u64> = history.recent(None, None).iter().map(|e| e.at_ms).collect();
+        assert_eq!(times, [2, 3, 4]);
+
+        // 9 bytes pushes out all but one 1-byte entry
+        history.record_at(5, &TestEvent::Error("9 bytes!!".into()));
+        assert_eq!(history.len(), 2);
+        // Larger than the whole budget: not kept at all
+        history.record_at(6, &TestEvent::Error("far too long".into()));
+        assert_eq!(history.recent(None, None).last().unwrap().at_ms, 5);
+    }
+
+    #[test]
+    fn filters_by_kind_and_limits_to_the_latest() {
+        let history = EventHistory::new(10, 1024);
+        history.record_at(1, &TestEvent::Error("a".into()));
+        history.record_at(2, &TestEvent::Connected);
+        history.record_at(3, &TestEvent::Error("b".into()));
+        history.record_at(4, &TestEvent::Error("c".into()));
+
+        let errors = history.recent(Some(&["error"]), Some(2));
+        assert_eq!(errors.iter().map(|e| e.at_ms).collect::<Vec<_>>(), [3, 4]);
+        assert_eq!(history.recent(Some(&["connected"]), None).len(), 1);
+        history.clear();
+        assert!(history.is_empty());
+    }
+}
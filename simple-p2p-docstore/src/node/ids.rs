@@ -0,0 +1,153 @@
+//! Peer ids and multiaddrs given as text, e.g. by the JS API or pasted into a form.
+//!
+//! Peer ids are accepted in both encodings the libp2p spec allows: the legacy base58btc
+//! multihash (`12D3KooW…`, `Qm…`) and a CIDv1 with the `libp2p-key` codec in multibase
+//! base32 (`bafz…`), the form IPFS tools print.
+
+use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
+
+/// Multicodec of a CID holding a peer id.
+const LIBP2P_KEY_CODEC: u8 = 0x72;
+
+const BASE32: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// Characters kept at each end by [`short_id`].
+const SHORT_ID_CHARS: usize = 6;
+
+/// Parse a peer id in either encoding. Errors name the input and what is wrong with it.
+pub fn parse_peer_id(text: &str) -> Result<PeerId, String> {
+    let text = text.trim();
+    let invalid = |reason: &dyn std::fmt::Display| format!("invalid peer id {text:?}: {reason}");
+    // Per the spec, base58 peer ids are the ones starting with 1 or Q, anything else is
+    // multibase
+    if text.starts_with(['1', 'Q']) {
+        return text.parse().map_err(|e| invalid(&e));
+    }
+    let bytes = match text.strip_prefix(['b', 'B']) {
+        Some(base32) => base32_decode(base32).ok_or_else(|| invalid(&"not base32"))?,
+        None => return Err(invalid(&"expected base58 or a base32 CID")),
+    };
+    match bytes.as_slice() {
+        [1, LIBP2P_KEY_CODEC, multihash @ ..] => PeerId::from_bytes(multihash).map_err(|e| invalid(&e)),
+        [1, ..] => Err(invalid(&"CID is not a libp2p-key")),
+        _ => Err(invalid(&"not a CIDv1")),
+    }
+}
+
+/// `peer_id` as a CIDv1 in base32, the other encoding [`parse_peer_id`] accepts.
+pub fn peer_id_to_cid(peer_id: &PeerId) -> String {
+    let mut bytes = vec![1, LIBP2P_KEY_CODEC];
+    bytes.extend_from_slice(&peer_id.to_bytes());
+    format!("b{}", base32_encode(&bytes))
+}
+
+pub fn parse_multiaddr(text: &str) -> Result<Multiaddr, String> {
+    text.trim().parse().map_err(|e| format!("invalid multiaddr {text:?}: {e}"))
+}
+
+/// The first and last few characters of a peer id, for display: `12D3Ko…AJU5SA`.
+pub fn short_id(peer_id: &str) -> String {
+    let chars: Vec<char> = peer_id.chars().collect();
+    if chars.len() <= 2 * SHORT_ID_CHARS + 1 {
+        return peer_id.to_string();
+    }
+    let head: String = chars[..SHORT_ID_CHARS].iter().collect();
+    let tail: String = chars[chars.len() - SHORT_ID_CHARS..].iter().collect();
+    format!("{head}…{tail}")
+}
+
+/// `addr` with each `/p2p` peer id shortened by [`short_id`].
+pub fn short_multiaddr(addr: &Multiaddr) -> String {
+    addr.iter()
+        .map(|protocol| match protocol {
+            Protocol::P2p(peer_id) => format!("/p2p/{}", short_id(&peer_id.to_string())),
+            other => other.to_string(),
+        })
+        .collect()
+}
+
+/// RFC 4648 base32, lower case without padding, as multibase uses it. Upper case is
+/// accepted too.
+fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in text.bytes() {
+        let value = BASE32.iter().position(|&b| b == c.to_ascii_lowercase())? as u32;
+        buffer = ((buffer << 5) | value) & 0xfff;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity((bytes.len() * 8).div_ceil(5));
+    let (mut buffer, mut bits) = (0u32, 0);
+    for &byte in bytes {
+        buffer = ((buffer << 8) | byte as u32) & 0xfff;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The same peer ids as base58 and as CIDv1, from the peer id spec.
+    const ENCODINGS: [(&str, &str); 2] = [
+        (
+            "12D3KooWD3eckifWpRn9wQpMG9R9hX3sD158z7EqHWmweQAJU5SA",
+            "bafzaajaiaejcal72gwuz2or47oyxxn6b3rkwdmmkrxgkjxzy3rqt5kczyn7lcm3l",
+        ),
+        ("QmYyQSo1c1Ym7orWxLYvCrM2EmxFTANf8wXmmE7DWjhx5N", "bafzbeie5745rpv2m6tjyuugywy4d5ewrqgqqhfnf445he3omzpjbx5xqxe"),
+    ];
+
+    #[test]
+    fn base58_and_cid_peer_ids_parse_to_the_same_id() {
+        for (base58, cid) in ENCODINGS {
+            let peer_id = parse_peer_id(base58).unwrap();
+            assert_eq!(parse_peer_id(cid), Ok(peer_id));
+            assert_eq!(parse_peer_id(&cid.to_uppercase()), Ok(peer_id));
+            assert_eq!(peer_id_to_cid(&peer_id), cid);
+        }
+        let random = PeerId::random();
+        assert_eq!(parse_peer_id(&peer_id_to_cid(&random)), Ok(random));
+    }
+
+    #[test]
+    fn bad_peer_ids_and_addrs_are_named_in_the_error() {
+        let cases = [
+            "",
+            "12D3KooWnope",
+            "bafzaaja!",
+            // base58btc multibase, and a CID of something else
+            "zb2rhe5P4gXftAwvA4eXQ5HJwsER2owDyS9sKaQRRVQPn93bA",
+            "bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi",
+        ];
+        for bad in cases {
+            let err = parse_peer_id(bad).unwrap_err();
+            assert!(err.starts_with(&format!("invalid peer id {:?}", bad.trim())), "{err}");
+        }
+        assert!(parse_multiaddr("/ip4/nope").unwrap_err().starts_with("invalid multiaddr \"/ip4/nope\""));
+    }
+
+    #[test]
+    fn short_ids_keep_both_ends() {
+        let (base58, _) = ENCODINGS[0];
+        assert_eq!(short_id(base58), "12D3Ko…AJU5SA");
+        assert_eq!(short_id("12D3Koo"), "12D3Koo");
+        let addr: Multiaddr = format!("/dns4/relay.example.com/tcp/443/wss/p2p/{base58}").parse().unwrap();
+        assert_eq!(short_multiaddr(&addr), "/dns4/relay.example.com/tcp/443/wss/p2p/12D3Ko…AJU5SA");
+    }
+}
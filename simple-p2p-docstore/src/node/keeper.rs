@@ -0,0 +1,199 @@
+//! Keeping connections to the peers of active document sessions open, shared by the
+//! native and wasm event loops.
+//!
+//! The idle timeout closes connections nothing is using, which is right for bystanders
+//! but not for the peers we are editing with: a quiet minute in a room shouldn't cost the
+//! next edit a reconnect. While a session (a joined room, a watched document) holds a
+//! peer, the event loop pings it over [`KEEP_ALIVE_PROTOCOL`] more often than the idle
+//! timeout. Once no session holds it the pings stop, and its connection idles out like
+//! any other.
+//!
+//! [`KEEP_ALIVE_PROTOCOL`]: crate::behaviour::keep_alive::KEEP_ALIVE_PROTOCOL
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::time::Duration;
+
+use libp2p::PeerId;
+
+/// Pings are never sent more often than this, however short the idle timeout.
+const MIN_PING_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How often held peers are pinged: a few times per `idle_timeout`, so one late ping
+/// doesn't close the connection.
+pub fn ping_interval(idle_timeout: Duration) -> Duration {
+    (idle_timeout / 3).max(MIN_PING_INTERVAL)
+}
+
+/// Session of a joined room.
+pub fn room_session(room_id: &str) -> String {
+    format!("room/{room_id}")
+}
+
+/// Session of a watched document.
+pub fn doc_session(doc_id: &str) -> String {
+    format!("doc/{doc_id}")
+}
+
+/// Active sessions and the peers each one holds.
+#[derive(Debug, Default)]
+pub struct ConnectionKeeper {
+    sessions: HashMap<String, HashSet<PeerId>>,
+}
+
+impl ConnectionKeeper {
+    /// Start a session. Returns false if it was already open.
+    pub fn open(&mut self, session: impl Into<String>) -> bool {
+        let mut added = false;
+        self.sessions.entry(session.into()).or_insert_with(|| {
+            added = true;
+            HashSet::new()
+        });
+        added
+    }
+
+    pub fn is_open(&self, session: &str) -> bool {
+        self.sessions.contains_key(session)
+    }
+
+    /// Keep `peer` while `session` is open. Returns true if the session didn't hold it
+    /// yet; peers offered to sessions that aren't open are ignored.
+    pub fn hold(&mut self, session: &str, peer: PeerId) -> bool {
+        self.sessions.get_mut(session).is_some_and(|peers| peers.insert(peer))
+    }
+
+    /// End `session`, returning the peers no other session holds: their pings stop and
+    /// their connections may idle out.
+    pub fn close(&mut self, session: &str) -> Vec<PeerId> {
+        let Some(peers) = self.sessions.remove(session) else { return Vec::new() };
+        let mut released: Vec<PeerId> = peers.into_iter().filter(|peer| !self.is_held(peer)).collect();
+        released.sort();
+        released
+    }
+
+    pub fn is_held(&self, peer: &PeerId) -> bool {
+        self.sessions.values().any(|peers| peers.contains(peer))
+    }
+
+    /// Every held peer, once each, in a stable order.
+    pub fn peers(&self) -> Vec<PeerId> {
+        let peers: BTreeSet<PeerId> = self.sessions.values().flatten().copied().collect();
+        peers.into_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(not(target_arch = "wasm32"))]
+    use crate::behaviour::keep_alive::{self, KeepAlive, KeepAliveBehaviour};
+    #[cfg(not(target_arch = "wasm32"))]
+    use futures::StreamExt;
+    #[cfg(not(target_arch = "wasm32"))]
+    use libp2p::{
+        core::{transport::MemoryTransport, upgrade::Version},
+        noise,
+        swarm::{Swarm, SwarmEvent},
+        yamux, Multiaddr, Transport,
+    };
+
+    #[test]
+    fn a_peer_is_held_until_its_last_session_closes() {
+        let [a, b] = [(); 2].map(|_| PeerId::random());
+        let mut keeper = ConnectionKeeper::default();
+        assert!(!keeper.hold("room/x", a), "session not open");
+
+        assert!(keeper.open(room_session("x")));
+        assert!(!keeper.open(room_session("x")));
+        assert!(keeper.open(doc_session("d")));
+        assert!(keeper.hold("room/x", a));
+        assert!(!keeper.hold("room/x", a));
+        assert!(keeper.hold("room/x", b));
+        assert!(keeper.hold("doc/d", a));
+        assert_eq!(keeper.peers().len(), 2);
+
+        // `a` is still needed for the document
+        assert_eq!(keeper.close("room/x"), [b]);
+        assert!(keeper.is_held(&a) && !keeper.is_held(&b));
+        assert_eq!(keeper.close("doc/d"), [a]);
+        assert!(keeper.peers().is_empty());
+        assert!(keeper.close("doc/d").is_empty());
+    }
+
+    #[test]
+    fn pings_several_times_per_idle_timeout() {
+        assert_eq!(ping_interval(Duration::from_secs(120)), Duration::from_secs(40));
+        assert_eq!(ping_interval(Duration::ZERO), MIN_PING_INTERVAL);
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn memory_swarm(idle_timeout: Duration) -> Swarm<KeepAliveBehaviour> {
+        libp2p::SwarmBuilder::with_new_identity()
+            .with_tokio()
+            .with_other_transport(|key| {
+                Ok(MemoryTransport::default()
+                    .upgrade(Version::V1)
+                    .authenticate(noise::Config::new(key)?)
+                    .multiplex(yamux::Config::default()))
+            })
+            .unwrap()
+            .with_behaviour(|_| keep_alive::make_keep_alive_behaviour())
+            .unwrap()
+            .with_swarm_config(|c| c.with_idle_connection_timeout(idle_timeout))
+            .build()
+    }
+
+    /// The edge case: a connection that carried nothing but the held session must close
+    /// once released, not stay open on the strength of its earlier pings.
+    #[cfg(not(target_arch = "wasm32"))]
+    #[tokio::test]
+    async fn held_connections_outlive_the_idle_timeout_until_released() {
+        let idle_timeout = Duration::from_millis(300);
+        let mut listener = memory_swarm(idle_timeout);
+        let mut dialer = memory_swarm(idle_timeout);
+        let addr: Multiaddr = format!("/memory/{}", rand::random::<u64>()).parse().unwrap();
+        listener.listen_on(addr.clone()).unwrap();
+        dialer.dial(addr).unwrap();
+
+        let peer = *listener.local_peer_id();
+        let mut keeper = ConnectionKeeper::default();
+        keeper.open(doc_session("d"));
+        keeper.hold("doc/d", peer);
+        let mut pings = tokio::time::interval(ping_interval(idle_timeout));
+        // Held for several idle timeouts, then released
+        let release_at = tokio::time::Instant::now() + idle_timeout * 4;
+        let mut released_at = None;
+
+        let closed_at = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                tokio::select! {
+                    event = listener.select_next_some() => {
+                        if let SwarmEvent::Behaviour(event) = event {
+                            keep_alive::handle_event(listener.behaviour_mut(), event);
+                        }
+                    }
+                    event = dialer.select_next_some() => match event {
+                        SwarmEvent::Behaviour(event) => keep_alive::handle_event(dialer.behaviour_mut(), event),
+                        SwarmEvent::ConnectionClosed { .. } => return tokio::time::Instant::now(),
+                        _ => {}
+                    },
+                    _ = pings.tick() => {
+                        if released_at.is_none() && tokio::time::Instant::now() >= release_at {
+                            assert_eq!(keeper.close("doc/d"), [peer]);
+                            released_at = Some(tokio::time::Instant::now());
+                        }
+                        for held in keeper.peers() {
+                            if dialer.is_connected(&held) {
+                                dialer.behaviour_mut().send_request(&held, KeepAlive);
+                            }
+                        }
+                    }
+                }
+            }
+        })
+        .await
+        .expect("the released connection never idled out");
+
+        let released_at = released_at.expect("closed while still held");
+        assert!(closed_at >= released_at, "closed while still held");
+    }
+}
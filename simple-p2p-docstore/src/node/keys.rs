@@ -0,0 +1,276 @@
+//! Identity key file encoding.
+//!
+//! Two on-disk formats are supported:
+//! - plain: the raw protobuf encoding of the keypair (what older versions wrote);
+//! - encrypted: `MAGIC || version || argon2 params || salt || nonce || ciphertext`, where
+//!   the protobuf keypair is sealed with ChaCha20-Poly1305 under an argon2id-derived key.
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use std::{fmt, str::FromStr};
+
+use libp2p::identity::Keypair;
+
+const MAGIC: &[u8; 6] = b"P2PKEY";
+/// Version of the encrypted format; see [`format_version`].
+pub const FORMAT_VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const HEADER_LEN: usize = MAGIC.len() + 1 + 12 + SALT_LEN + NONCE_LEN;
+
+/// Argon2id cost parameters stored alongside the ciphertext.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    pub m_cost_kib: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        // OWASP-recommended argon2id baseline
+        Self { m_cost_kib: 19 * 1024, t_cost: 2, p_cost: 1 }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum KeyError {
+    #[error("identity key file is encrypted; a passphrase is required")]
+    PassphraseRequired,
+    #[error("wrong passphrase for identity key file (or the file is corrupted)")]
+    WrongPassphrase,
+    #[error("unsupported identity key file version {0}")]
+    UnsupportedVersion(u8),
+    #[error("malformed identity key file: {0}")]
+    Malformed(String),
+    #[error("identity seed must be exactly {expected} bytes, got {got}")]
+    InvalidSeedLength { expected: usize, got: usize },
+}
+
+/// Key algorithms a fresh identity can be generated with. Loading accepts any key type
+/// that `Keypair::from_protobuf_encoding` understands, regardless of this setting.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KeyType {
+    #[default]
+    Ed25519,
+    Secp256k1,
+    Ecdsa,
+}
+
+impl FromStr for KeyType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ed25519" => Ok(KeyType::Ed25519),
+            "secp256k1" => Ok(KeyType::Secp256k1),
+            "ecdsa" => Ok(KeyType::Ecdsa),
+            other => Err(format!("unknown key type '{other}' (expected ed25519, secp256k1 or ecdsa)")),
+        }
+    }
+}
+
+impl fmt::Display for KeyType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            KeyType::Ed25519 => "ed25519",
+            KeyType::Secp256k1 => "secp256k1",
+            KeyType::Ecdsa => "ecdsa",
+        })
+    }
+}
+
+/// Generate a new random identity of the given key type.
+pub fn generate_identity(key_type: KeyType) -> Keypair {
+    match key_type {
+        KeyType::Ed25519 => Keypair::generate_ed25519(),
+        KeyType::Secp256k1 => Keypair::generate_secp256k1(),
+        KeyType::Ecdsa => Keypair::generate_ecdsa(),
+    }
+}
+
+/// Length of the seed accepted by [`insecure_identity_from_seed`].
+pub const SEED_LEN: usize = 32;
+
+/// Derive an ed25519 identity deterministically from a 32-byte seed.
+///
+/// **Not for production keys.** Anyone who knows the seed owns the identity; this exists
+/// so tests and docs can hardcode peer ids. Seeds of any other length are refused rather
+/// than padded or hashed.
+pub fn insecure_identity_from_seed(seed: &[u8]) -> Result<Keypair, KeyError> {
+    if seed.len() != SEED_LEN {
+        return Err(KeyError::InvalidSeedLength { expected: SEED_LEN, got: seed.len() });
+    }
+    let mut secret = [0u8; SEED_LEN];
+    secret.copy_from_slice(seed);
+    Keypair::ed25519_from_bytes(secret).map_err(|e| KeyError::Malformed(e.to_string()))
+}
+
+/// Returns true if `bytes` uses the encrypted format.
+pub fn is_encrypted(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// The format version of a stored key: the header's for encrypted keys, and
+/// [`FORMAT_VERSION`] for plain ones, which have no header but are still current.
+pub fn format_version(bytes: &[u8]) -> u8 {
+    match bytes.get(MAGIC.len()) {
+        Some(version) if is_encrypted(bytes) => *version,
+        _ => FORMAT_VERSION,
+    }
+}
+
+/// Encode a keypair for storage, encrypting it when a passphrase is given.
+pub fn encode_identity(kp: &Keypair, passphrase: Option<&str>) -> Result<Vec<u8>, KeyError> {
+    match passphrase {
+        Some(p) => encrypt_identity(kp, p, KdfParams::default()),
+        None => kp.to_protobuf_encoding().map_err(|e| KeyError::Malformed(e.to_string())),
+    }
+}
+
+/// Decode a stored keypair in either format.
+pub fn decode_identity(bytes: &[u8], passphrase: Option<&str>) -> Result<Keypair, KeyError> {
+    if !is_encrypted(bytes) {
+        return Keypair::from_protobuf_encoding(bytes).map_err(|e| KeyError::Malformed(e.to_string()));
+    }
+    let passphrase = passphrase.ok_or(KeyError::PassphraseRequired)?;
+    if bytes.len() < HEADER_LEN {
+        return Err(KeyError::Malformed("truncated header".into()));
+    }
+    let version = bytes[MAGIC.len()];
+    if version != FORMAT_VERSION {
+        return Err(KeyError::UnsupportedVersion(version));
+    }
+    let mut at = MAGIC.len() + 1;
+    let mut read_u32 = || {
+        let v = u32::from_le_bytes(bytes[at..at + 4].try_into().expect("4 bytes"));
+        at += 4;
+        v
+    };
+    let params = KdfParams { m_cost_kib: read_u32(), t_cost: read_u32(), p_cost: read_u32() };
+    let salt = &bytes[at..at + SALT_LEN];
+    let nonce = &bytes[at + SALT_LEN..HEADER_LEN];
+    let ciphertext = &bytes[HEADER_LEN..];
+
+    let key = derive_key(passphrase, salt, params)?;
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(&key));
+    let plaintext = cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| KeyError::WrongPassphrase)?;
+    Keypair::from_protobuf_encoding(&plaintext).map_err(|e| KeyError::Malformed(e.to_string()))
+}
+
+/// Encrypt a keypair with explicit KDF parameters.
+pub fn encrypt_identity(kp: &Keypair, passphrase: &str, params: KdfParams) -> Result<Vec<u8>, KeyError> {
+    let plaintext = kp.to_protobuf_encoding().map_err(|e| KeyError::Malformed(e.to_string()))?;
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    getrandom::fill(&mut salt).map_err(|e| KeyError::Malformed(e.to_string()))?;
+    getrandom::fill(&mut nonce).map_err(|e| KeyError::Malformed(e.to_string()))?;
+
+    let key = derive_key(passphrase, &salt, params)?;
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(&key));
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), plaintext.as_slice())
+        .map_err(|e| KeyError::Malformed(e.to_string()))?;
+
+    let mut out = Vec::with_capacity(HEADER_LEN + ciphertext.len());
+    out.extend_from_slice(MAGIC);
+    out.push(FORMAT_VERSION);
+    out.extend_from_slice(&params.m_cost_kib.to_le_bytes());
+    out.extend_from_slice(&params.t_cost.to_le_bytes());
+    out.extend_from_slice(&params.p_cost.to_le_bytes());
+    out.extend_from_slice(&salt);
+    out.extend_from_slice(&nonce);
+    out.extend_from_slice(&ciphertext);
+    Ok(out)
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], params: KdfParams) -> Result<[u8; 32], KeyError> {
+    let params = Params::new(params.m_cost_kib, params.t_cost, params.p_cost, Some(32))
+        .map_err(|e| KeyError::Malformed(format!("invalid kdf parameters: {e}")))?;
+    let mut key = [0u8; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| KeyError::Malformed(format!("key derivation failed: {e}")))?;
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Cheap parameters so the tests stay fast
+    const TEST_PARAMS: KdfParams = KdfParams { m_cost_kib: 64, t_cost: 1, p_cost: 1 };
+
+    #[test]
+    fn seeded_identity_is_deterministic() {
+        let a = insecure_identity_from_seed(&[7u8; 32]).unwrap();
+        let b = insecure_identity_from_seed(&[7u8; 32]).unwrap();
+        let c = insecure_identity_from_seed(&[8u8; 32]).unwrap();
+        assert_eq!(a.public().to_peer_id(), b.public().to_peer_id());
+        assert_ne!(a.public().to_peer_id(), c.public().to_peer_id());
+    }
+
+    #[test]
+    fn seeded_identity_refuses_wrong_lengths() {
+        assert!(matches!(
+            insecure_identity_from_seed(&[1u8; 16]),
+            Err(KeyError::InvalidSeedLength { expected: 32, got: 16 })
+        ));
+        assert!(matches!(
+            insecure_identity_from_seed(&[1u8; 33]),
+            Err(KeyError::InvalidSeedLength { expected: 32, got: 33 })
+        ));
+    }
+
+    #[test]
+    fn plain_format_round_trip() {
+        let kp = Keypair::generate_ed25519();
+        let bytes = encode_identity(&kp, None).unwrap();
+        assert!(!is_encrypted(&bytes));
+        let loaded = decode_identity(&bytes, None).unwrap();
+        assert_eq!(loaded.public(), kp.public());
+        // A passphrase is simply not needed for plain files
+        assert_eq!(decode_identity(&bytes, Some("unused")).unwrap().public(), kp.public());
+    }
+
+    #[test]
+    fn encrypted_format_round_trip() {
+        let kp = Keypair::generate_ed25519();
+        let bytes = encrypt_identity(&kp, "correct horse", TEST_PARAMS).unwrap();
+        assert!(is_encrypted(&bytes));
+        let loaded = decode_identity(&bytes, Some("correct horse")).unwrap();
+        assert_eq!(loaded.public(), kp.public());
+    }
+
+    #[test]
+    fn every_key_type_round_trips_through_both_formats() {
+        for key_type in [KeyType::Ed25519, KeyType::Secp256k1, KeyType::Ecdsa] {
+            let kp = generate_identity(key_type);
+            let plain = encode_identity(&kp, None).unwrap();
+            assert_eq!(decode_identity(&plain, None).unwrap().public(), kp.public(), "{key_type}");
+            let sealed = encrypt_identity(&kp, "pw", TEST_PARAMS).unwrap();
+            assert_eq!(decode_identity(&sealed, Some("pw")).unwrap().public(), kp.public(), "{key_type}");
+        }
+    }
+
+    #[test]
+    fn key_type_parses_case_insensitively() {
+        assert_eq!("Secp256k1".parse::<KeyType>(), Ok(KeyType::Secp256k1));
+        assert_eq!("ecdsa".parse::<KeyType>(), Ok(KeyType::Ecdsa));
+        assert!("rsa".parse::<KeyType>().is_err());
+    }
+
+    #[test]
+    fn wrong_or_missing_passphrase_is_an_error() {
+        let kp = Keypair::generate_ed25519();
+        let bytes = encrypt_identity(&kp, "correct horse", TEST_PARAMS).unwrap();
+        assert!(matches!(decode_identity(&bytes, Some("battery staple")), Err(KeyError::WrongPassphrase)));
+        assert!(matches!(decode_identity(&bytes, None), Err(KeyError::PassphraseRequired)));
+        assert!(matches!(decode_identity(&bytes[..10], Some("x")), Err(KeyError::Malformed(_))));
+    }
+}
@@ -0,0 +1,101 @@
+//! Acting on failed pings. A connection whose pings keep failing is usually dead (a
+//! WebRTC peer that vanished, a NAT mapping that expired) long before the transport
+//! notices, and publishes sent over it are lost. After a few failures in a row the peer
+//! is reported unresponsive and no longer used as an explicit gossipsub peer; after a
+//! few more the connection is closed so reconnecting can start.
+
+use std::collections::HashMap;
+
+use libp2p::PeerId;
+
+/// Consecutive ping failures that trigger each step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PingPolicy {
+    /// Failures before the peer is reported unresponsive and demoted.
+    pub unresponsive_after: u32,
+    /// Failures before the connection is closed. At least `unresponsive_after`.
+    pub disconnect_after: u32,
+}
+
+/// What to do after a ping outcome.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PingAction {
+    None,
+    /// Just reached [`PingPolicy::unresponsive_after`] failures.
+    Unresponsive,
+    /// Just reached [`PingPolicy::disconnect_after`] failures.
+    Disconnect,
+    /// A ping succeeded again after the peer was reported unresponsive.
+    Recovered,
+}
+
+/// Consecutive ping failures per connected peer.
+#[derive(Debug)]
+pub struct PingFailures {
+    policy: PingPolicy,
+    failures: HashMap<PeerId, u32>,
+}
+
+impl PingFailures {
+    pub fn new(policy: PingPolicy) -> Self {
+        Self { policy, failures: HashMap::new() }
+    }
+
+    pub fn policy(&self) -> PingPolicy {
+        self.policy
+    }
+
+    /// A ping to `peer` succeeded (`ok`) or failed.
+    pub fn record(&mut self, peer: PeerId, ok: bool) -> PingAction {
+        if ok {
+            let failures = self.failures.remove(&peer).unwrap_or(0);
+            return if failures >= self.policy.unresponsive_after { PingAction::Recovered } else { PingAction::None };
+        }
+        let failures = self.failures.entry(peer).or_insert(0);
+        *failures += 1;
+        if *failures == self.policy.disconnect_after.max(self.policy.unresponsive_after) {
+            PingAction::Disconnect
+        } else if *failures == self.policy.unresponsive_after {
+            PingAction::Unresponsive
+        } else {
+            PingAction::None
+        }
+    }
+
+    /// `peer` disconnected; a new connection starts with a clean slate.
+    pub fn forget(&mut self, peer: &PeerId) {
+        self.failures.remove(peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn demotes_then_disconnects_after_consecutive_failures() {
+        let mut pings = PingFailures::new(PingPolicy { unresponsive_after: 2, disconnect_after: 4 });
+        let peer = PeerId::random();
+
+        assert_eq!(pings.record(peer, false), PingAction::None);
+        // A success in between starts the count over
+        assert_eq!(pings.record(peer, true), PingAction::None);
+        assert_eq!(pings.record(peer, false), PingAction::None);
+        assert_eq!(pings.record(peer, false), PingAction::Unresponsive);
+        assert_eq!(pings.record(peer, true), PingAction::Recovered);
+
+        let outcomes: Vec<_> = (0..5).map(|_| pings.record(peer, false)).collect();
+        assert_eq!(
+            outcomes,
+            [PingAction::None, PingAction::Unresponsive, PingAction::None, PingAction::Disconnect, PingAction::None]
+        );
+        pings.forget(&peer);
+        assert_eq!(pings.record(peer, true), PingAction::None);
+    }
+
+    #[test]
+    fn disconnect_wins_when_both_thresholds_coincide() {
+        let mut pings = PingFailures::new(PingPolicy { unresponsive_after: 1, disconnect_after: 1 });
+        assert_eq!(pings.record(PeerId::random(), false), PingAction::Disconnect);
+    }
+}
@@ -0,0 +1,495 @@
+//! Format versions of the files the crate persists, and upgrades from the formats earlier
+//! versions wrote.
+//!
+//! Every artifact records its format version: the document store in `<store>/format`,
+//! which covers the per-document files only the store writes; the address book, mailbox,
+//! scrub cursor and membership tables in a top-level `format_version` field; encrypted
+//! identity keys in the version byte of their header. Files from before versions were recorded are version 0.
+//!
+//! Loading refuses data newer than this build reads, and older data too: it is upgraded
+//! on purpose, with `server migrate` or [`Plan::apply`], which copies every artifact it
+//! changes to a backup directory first. Each [`Migration`] takes one artifact from one
+//! version to the next, so an artifact several versions behind goes through every step
+//! in turn.
+
+use std::fmt;
+use std::io;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::node::keys;
+
+/// Name of the JSON field holding the format version.
+pub const FORMAT_FIELD: &str = "format_version";
+
+/// Directory under the data directory that [`Plan::apply`] backups go to by default, one
+/// subdirectory per run.
+pub const BACKUP_DIR: &str = "backups";
+
+/// The kinds of file the crate persists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Artifact {
+    Store,
+    AddressBook,
+    Mailbox,
+    ScrubProgress,
+    Members,
+    IdentityKey,
+}
+
+impl Artifact {
+    pub const ALL: [Artifact; 6] = [
+        Artifact::Store,
+        Artifact::AddressBook,
+        Artifact::Mailbox,
+        Artifact::ScrubProgress,
+        Artifact::Members,
+        Artifact::IdentityKey,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Artifact::Store => "store",
+            Artifact::AddressBook => "address book",
+            Artifact::Mailbox => "mailbox",
+            Artifact::ScrubProgress => "scrub progress",
+            Artifact::Members => "membership tables",
+            Artifact::IdentityKey => "identity key",
+        }
+    }
+
+    /// The format version this build reads and writes.
+    pub fn current(self) -> u32 {
+        match self {
+            // Plain keys predate the header but are still written without a passphrase,
+            // so they count as current too
+            Artifact::IdentityKey => keys::FORMAT_VERSION as u32,
+            Artifact::Mailbox => 2,
+            Artifact::Store | Artifact::AddressBook | Artifact::ScrubProgress | Artifact::Members => 1,
+        }
+    }
+}
+
+impl fmt::Display for Artifact {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MigrationError {
+    #[error("{artifact} has format version {version}, newer than the {supported} this build reads")]
+    Newer { artifact: Artifact, version: u32, supported: u32 },
+    #[error("{artifact} has format version {version}, older than the {current} this build reads; upgrade it with `server migrate`")]
+    Outdated { artifact: Artifact, version: u32, current: u32 },
+    #[error("no migration of the {artifact} from format version {version}")]
+    Missing { artifact: Artifact, version: u32 },
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl From<MigrationError> for io::Error {
+    fn from(e: MigrationError) -> Self {
+        match e {
+            MigrationError::Io(e) => e,
+            other => io::Error::new(io::ErrorKind::InvalidData, other),
+        }
+    }
+}
+
+/// Ok only for data in exactly the format this build writes.
+pub fn ensure_current(artifact: Artifact, version: u32) -> Result<(), MigrationError> {
+    let current = artifact.current();
+    match version.cmp(&current) {
+        std::cmp::Ordering::Equal => Ok(()),
+        std::cmp::Ordering::Greater => Err(MigrationError::Newer { artifact, version, supported: current }),
+        std::cmp::Ordering::Less => Err(MigrationError::Outdated { artifact, version, current }),
+    }
+}
+
+/// Whether `e` is a refusal by [`ensure_current`], rather than a missing or garbled file.
+pub fn is_version_error(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|inner| inner.is::<MigrationError>())
+}
+
+/// The format version of a JSON artifact: its [`FORMAT_FIELD`], or 0 without one.
+pub fn json_version(value: &serde_json::Value) -> u32 {
+    value.get(FORMAT_FIELD).and_then(|v| v.as_u64()).map_or(0, |v| v.min(u32::MAX as u64) as u32)
+}
+
+/// Decode a JSON artifact, refusing any format but the current one.
+pub fn decode_json<T: DeserializeOwned>(artifact: Artifact, bytes: &[u8]) -> io::Result<T> {
+    let value: serde_json::Value =
+        serde_json::from_slice(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    ensure_current(artifact, json_version(&value))?;
+    serde_json::from_value(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// `value`, which must serialize to a JSON object, with the current format version added.
+pub fn with_header<T: Serialize>(artifact: Artifact, value: &T) -> io::Result<serde_json::Value> {
+    let mut value = serde_json::to_value(value).map_err(io::Error::other)?;
+    let fields = value.as_object_mut().ok_or_else(|| io::Error::other(format!("{artifact} is not a JSON object")))?;
+    fields.insert(FORMAT_FIELD.to_string(), artifact.current().into());
+    Ok(value)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use plan::*;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod plan {
+    use std::fmt;
+    use std::fs;
+    use std::io;
+    use std::path::{Path, PathBuf};
+
+    use serde_json::json;
+
+    use super::{json_version, Artifact, MigrationError, FORMAT_FIELD};
+    use crate::behaviour::mailbox::{self, MAILBOX_FILE};
+    use crate::behaviour::members::MEMBERS_FILE;
+    use crate::node::data_dir::DataDir;
+    use crate::node::keys;
+    use crate::node::scrub::SCRUB_FILE;
+    use crate::store::file;
+
+    /// One step of one artifact's upgrade path.
+    pub struct Migration {
+        pub artifact: Artifact,
+        /// The version the step starts from; it ends at the next one.
+        pub from: u32,
+        pub description: &'static str,
+        apply: fn(&Path) -> io::Result<()>,
+    }
+
+    impl fmt::Debug for Migration {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{} v{} -> v{}: {}", self.artifact, self.from, self.from + 1, self.description)
+        }
+    }
+
+    /// Every registered step, see the [module docs](super).
+    pub const MIGRATIONS: &[Migration] = &[
+        Migration {
+            artifact: Artifact::Store,
+            from: 0,
+            description: "re-encode unstamped log records, add per-author watermarks to clocks, digest undigested logs, record the format version",
+            apply: file::migrate_unversioned,
+        },
+        Migration {
+            artifact: Artifact::AddressBook,
+            from: 0,
+            description: "move the peer list under `peers` and record the format version",
+            apply: wrap_address_book,
+        },
+        Migration {
+            artifact: Artifact::Mailbox,
+            from: 0,
+            description: "record the format version",
+            apply: add_header,
+        },
+        Migration {
+            artifact: Artifact::Mailbox,
+            from: 1,
+            description: "hold blobs in base64 rather than lists of numbers",
+            apply: mailbox::migrate_v1,
+        },
+        Migration {
+            artifact: Artifact::ScrubProgress,
+            from: 0,
+            description: "record the format version",
+            apply: add_header,
+        },
+        Migration {
+            artifact: Artifact::Members,
+            from: 0,
+            description: "record the format version",
+            apply: add_header,
+        },
+    ];
+
+    /// The steps from `version` of `artifact` to the current one, in order.
+    pub fn steps(artifact: Artifact, version: u32) -> Result<Vec<&'static Migration>, MigrationError> {
+        let current = artifact.current();
+        if version > current {
+            return Err(MigrationError::Newer { artifact, version, supported: current });
+        }
+        (version..current)
+            .map(|from| {
+                MIGRATIONS
+                    .iter()
+                    .find(|m| m.artifact == artifact && m.from == from)
+                    .ok_or(MigrationError::Missing { artifact, version: from })
+            })
+            .collect()
+    }
+
+    /// The format version of the artifact at `path`; `None` if there is none.
+    pub fn version_at(artifact: Artifact, path: &Path) -> io::Result<Option<u32>> {
+        if artifact == Artifact::Store {
+            return file::format_version(path);
+        }
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if artifact == Artifact::IdentityKey {
+            return Ok(Some(keys::format_version(&bytes) as u32));
+        }
+        let value = serde_json::from_slice(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(Some(json_version(&value)))
+    }
+
+    /// An artifact found on disk, and what it takes to bring it up to date.
+    #[derive(Debug)]
+    pub struct Found {
+        pub artifact: Artifact,
+        pub path: PathBuf,
+        pub version: u32,
+        /// Empty if it is current.
+        pub steps: Vec<&'static Migration>,
+    }
+
+    impl fmt::Display for Found {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let current = self.artifact.current();
+            if self.steps.is_empty() {
+                return write!(f, "{} at {}: format v{}, up to date", self.artifact, self.path.display(), self.version);
+            }
+            write!(f, "{} at {}: format v{} -> v{}", self.artifact, self.path.display(), self.version, current)?;
+            for step in &self.steps {
+                write!(f, "\n  v{} -> v{}: {}", step.from, step.from + 1, step.description)?;
+            }
+            Ok(())
+        }
+    }
+
+    /// What a data directory holds, see the [module docs](super).
+    #[derive(Debug)]
+    pub struct Plan {
+        root: PathBuf,
+        pub found: Vec<Found>,
+    }
+
+    impl Plan {
+        /// An empty plan for artifacts under `root`, added with [`add`](Self::add).
+        pub fn new(root: impl Into<PathBuf>) -> Self {
+            Self { root: root.into(), found: Vec::new() }
+        }
+
+        /// Everything a node keeps under `data_dir`, and the identity key at
+        /// `identity_key`. Fails if anything is newer than this build reads.
+        pub fn scan(data_dir: &DataDir, identity_key: &Path) -> Result<Self, MigrationError> {
+            let mut plan = Self::new(data_dir.root());
+            let store = data_dir.store();
+            plan.add(Artifact::IdentityKey, identity_key)?;
+            plan.add(Artifact::AddressBook, data_dir.address_book())?;
+            // The server keeps its mailbox in the data directory, nodes with a store in it
+            plan.add(Artifact::Mailbox, data_dir.root().join(MAILBOX_FILE))?;
+            plan.add(Artifact::Store, &store)?;
+            plan.add(Artifact::ScrubProgress, store.join(SCRUB_FILE))?;
+            plan.add(Artifact::Mailbox, store.join(MAILBOX_FILE))?;
+            plan.add(Artifact::Members, store.join(MEMBERS_FILE))?;
+            Ok(plan)
+        }
+
+        /// Add the artifact at `path`, if there is one. Fails if it is newer than this
+        /// build reads.
+        pub fn add(&mut self, artifact: Artifact, path: impl Into<PathBuf>) -> Result<(), MigrationError> {
+            let path = path.into();
+            let Some(version) = version_at(artifact, &path)? else {
+                return Ok(());
+            };
+            let steps = steps(artifact, version)?;
+            self.found.push(Found { artifact, path, version, steps });
+            Ok(())
+        }
+
+        /// The artifacts that need upgrading.
+        pub fn pending(&self) -> impl Iterator<Item = &Found> {
+            self.found.iter().filter(|found| !found.steps.is_empty())
+        }
+
+        pub fn is_current(&self) -> bool {
+            self.pending().next().is_none()
+        }
+
+        /// Where [`apply`](Self::apply) should put backups by default: a fresh
+        /// directory under [`BACKUP_DIR`](super::BACKUP_DIR) in the root.
+        pub fn default_backup_dir(&self, now_ms: u64) -> PathBuf {
+            self.root.join(super::BACKUP_DIR).join(now_ms.to_string())
+        }
+
+        /// Upgrade every pending artifact, copying it under `backup_dir` first (by its
+        /// path relative to the root, or its file name if it lives elsewhere). Stops at
+        /// the first failure; what was upgraded stays upgraded, and the backups are kept.
+        /// Returns how many artifacts were upgraded.
+        pub fn apply(&self, backup_dir: &Path) -> Result<usize, MigrationError> {
+            let mut upgraded = 0;
+            for found in self.pending() {
+                let relative = match found.path.strip_prefix(&self.root) {
+                    Ok(relative) => relative.to_path_buf(),
+                    Err(_) => found.path.file_name().map(PathBuf::from).unwrap_or_else(|| found.artifact.as_str().into()),
+                };
+                copy_all(&found.path, &backup_dir.join(relative))?;
+                for step in &found.steps {
+                    (step.apply)(&found.path)?;
+                }
+                tracing::info!("Upgraded the {} at {} to format v{}", found.artifact, found.path.display(), found.artifact.current());
+                upgraded += 1;
+            }
+            Ok(upgraded)
+        }
+    }
+
+    /// Copy a file, or a directory and everything under it.
+    fn copy_all(from: &Path, to: &Path) -> io::Result<()> {
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if !from.is_dir() {
+            return fs::copy(from, to).map(|_| ());
+        }
+        fs::create_dir_all(to)?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy_all(&entry.path(), &to.join(entry.file_name()))?;
+        }
+        Ok(())
+    }
+
+    fn read_json(path: &Path) -> io::Result<serde_json::Value> {
+        serde_json::from_slice(&fs::read(path)?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Through a temporary file, so a crash mid-migration leaves the old file whole.
+    fn write_json(path: &Path, value: &serde_json::Value) -> io::Result<()> {
+        let bytes = serde_json::to_vec_pretty(value).map_err(io::Error::other)?;
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, bytes)?;
+        fs::rename(&tmp, path)
+    }
+
+    /// Address book v0 was a bare list of peers.
+    fn wrap_address_book(path: &Path) -> io::Result<()> {
+        let peers = read_json(path)?;
+        if !peers.is_array() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "expected a list of peers"));
+        }
+        write_json(path, &json!({ FORMAT_FIELD: 1, "peers": peers }))
+    }
+
+    fn add_header(path: &Path) -> io::Result<()> {
+        let mut value = read_json(path)?;
+        let fields = value.as_object_mut().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "expected an object"))?;
+        fields.insert(FORMAT_FIELD.to_string(), 1.into());
+        write_json(path, &value)
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use std::path::PathBuf;
+
+    use libp2p::PeerId;
+
+    use super::*;
+    use crate::behaviour::mailbox::{Mailbox, MailboxLimits, MailboxRequest, MailboxResponse, MAILBOX_FILE};
+    use crate::node::address_book::AddressBook;
+    use crate::node::data_dir::DataDir;
+    use crate::node::scrub::{StoreScrub, SCRUB_FILE};
+    use crate::store::file::FileDocStore;
+    use crate::store::DocStore;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("migrations-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn every_artifact_has_a_path_to_its_current_version() {
+        for artifact in Artifact::ALL {
+            let steps = steps(artifact, 0).unwrap();
+            let from: Vec<u32> = steps.iter().map(|m| m.from).collect();
+            let expected: Vec<u32> = (0..artifact.current()).collect();
+            // Identity keys have never needed one
+            if artifact != Artifact::IdentityKey {
+                assert_eq!(from, expected, "{artifact}");
+            }
+        }
+        assert!(matches!(steps(Artifact::Mailbox, 3), Err(MigrationError::Newer { version: 3, supported: 2, .. })));
+        assert!(matches!(ensure_current(Artifact::Mailbox, 0), Err(MigrationError::Outdated { .. })));
+    }
+
+    #[test]
+    fn old_data_directories_are_upgraded_with_backups() {
+        let root = temp_dir("upgrade");
+        let data_dir = DataDir::new(&root);
+        let store = data_dir.store();
+        let (peer, sender) = (PeerId::random(), PeerId::random());
+
+        // Fixtures as the versions before format headers wrote them
+        let book = serde_json::json!([{
+            "peer_id": peer.to_string(),
+            "addrs": [{ "addr": "/ip4/127.0.0.1/tcp/4001", "last_seen_ms": 10, "successes": 1, "failures": 0 }],
+        }]);
+        std::fs::write(data_dir.address_book(), book.to_string()).unwrap();
+        let mailbox = serde_json::json!({
+            "next_id": 2,
+            "held": [{ "recipient": peer.to_string(), "message": {
+                "id": 1, "sender": sender.to_string(), "blob": [104, 105], "deposited_at_ms": 0, "expires_at_ms": u64::MAX,
+            }}],
+        });
+        std::fs::write(root.join(MAILBOX_FILE), mailbox.to_string()).unwrap();
+        let doc_dir = store.join("646f63"); // "doc"
+        std::fs::create_dir_all(&doc_dir).unwrap();
+        // An update from before stamps: version 1, payload "old", applied at 0
+        let body = [1, 3, b'o', b'l', b'd', 0];
+        let mut log = (body.len() as u32).to_le_bytes().to_vec();
+        log.extend_from_slice(&body);
+        std::fs::write(doc_dir.join("log"), log).unwrap();
+        std::fs::write(store.join(SCRUB_FILE), r#"{"cursor":"doc","passes":3}"#).unwrap();
+
+        // Nothing opens until it is upgraded
+        assert!(FileDocStore::open(&store).is_err());
+        assert!(AddressBook::load(&data_dir.address_book()).is_err());
+
+        let plan = Plan::scan(&data_dir, &data_dir.identity_key(|_| None)).unwrap();
+        let pending: Vec<Artifact> = plan.pending().map(|found| found.artifact).collect();
+        assert_eq!(pending, vec![Artifact::AddressBook, Artifact::Mailbox, Artifact::Store, Artifact::ScrubProgress]);
+        let backups = plan.default_backup_dir(1);
+        assert_eq!(plan.apply(&backups).unwrap(), 4);
+        assert!(backups.join("store").join("646f63").join("log").exists());
+        assert_eq!(std::fs::read(backups.join(MAILBOX_FILE)).unwrap(), mailbox.to_string().into_bytes());
+        assert!(Plan::scan(&data_dir, &data_dir.identity_key(|_| None)).unwrap().is_current());
+
+        // And all of it opens with the current code
+        let docs = FileDocStore::open(&store).unwrap();
+        assert_eq!(docs.content("doc"), Some(b"old".to_vec()));
+        assert_eq!(docs.version("doc"), 1);
+        let book = AddressBook::load(&data_dir.address_book()).unwrap();
+        assert_eq!(book.addresses(&peer), vec!["/ip4/127.0.0.1/tcp/4001".parse().unwrap()]);
+        let mut mailbox = Mailbox::load(Some(root.join(MAILBOX_FILE)), MailboxLimits::default());
+        let MailboxResponse::Messages(messages) = mailbox.respond(peer, &MailboxRequest::Fetch, 1) else {
+            panic!("expected messages");
+        };
+        assert_eq!(messages[0].blob, b"hi".to_vec());
+        assert_eq!(StoreScrub::load(Some(store.join(SCRUB_FILE))).stats().passes, 3);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn newer_data_is_refused() {
+        let root = temp_dir("newer");
+        let data_dir = DataDir::new(&root);
+        std::fs::write(data_dir.address_book(), r#"{"format_version":7,"peers":[]}"#).unwrap();
+        let error = Plan::scan(&data_dir, &data_dir.identity_key(|_| None)).unwrap_err();
+        assert!(matches!(error, MigrationError::Newer { artifact: Artifact::AddressBook, version: 7, supported: 1 }));
+        assert!(AddressBook::load(&data_dir.address_book()).is_err());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,6065 @@
+//! Native node handle: the swarm runs on a tokio task and is driven through a
+//! command channel, mirroring how `WasmNode` works in the browser.
+
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::{
+    channel::{mpsc, oneshot},
+    Stream, StreamExt,
+};
+use libp2p::core::{transport::MemoryTransport, upgrade::Version};
+use libp2p::{
+    gossipsub::{self, MessageId},
+    identify, identity, noise, ping, request_response,
+    swarm::{
+        behaviour::toggle::Toggle,
+        dial_opts::{DialOpts, PeerCondition},
+        DialError, NetworkBehaviour, SwarmEvent,
+    },
+    tcp, yamux, Multiaddr, PeerId, Swarm, Transport,
+};
+use libp2p_kad::{
+    self as kad,
+    store::{MemoryStore, RecordStore},
+    Behaviour as KademliaBehaviour, QueryId, RecordKey,
+};
+use tokio::sync::broadcast;
+use web_time::Instant;
+
+use crate::behaviour::docstore::{
+    self, cas, scope, AnnouncementKind, DocUpdate, DocstoreGossipsubConfig, Envelope, Incoming, MessagePipeline, NetworkAnnouncement,
+    PublishDebouncer, ReceiptBehaviour, ReceiptOutcome, SchemaValidator, TopicRegistry, SchemaViolation, Severity, SnapshotPolicy, SnapshotScheduler, Stamp, Transaction, UpdateReceipt, VectorClock,
+};
+use crate::behaviour::keep_alive::{self, KeepAlive, KeepAliveBehaviour};
+use crate::behaviour::mailbox::{
+    self, Mailbox, MailboxBehaviour, MailboxLimits, MailboxMessage, MailboxReceipt, MailboxRequest, MailboxResponse,
+};
+use crate::behaviour::members::{self, MembersBehaviour, MembersOptions, MembersPage, MembersRequest, MembersResponse, MembershipTable};
+use crate::behaviour::peers::{self, PeerDirectory, PeersBehaviour, PeersRequest, PeersResponse};
+use crate::behaviour::rendezvous::{self as rendezvous_behaviour, RendezvousBehaviour, RendezvousEvent};
+use crate::behaviour::profile::{self, Profile, ProfileCache, SignedProfile};
+use crate::behaviour::{head_pointer_key, HeadPointer, HeadPointers};
+use crate::behaviour::fetch::{self as doc_fetch, FetchBehaviour, FetchManifest, FetchRequest, FetchResponse, ManifestCache};
+use crate::behaviour::history::{self as doc_history, DocumentState, HistoryAt, HistoryBehaviour, HistoryFrom, HistoryOptions, HistoryPage, HistoryRequest, HistoryResponse, StateCache};
+use crate::behaviour::nat::{NatAction, NatBehaviour, NatBehaviourEvent, PortMappings};
+use crate::node::address_book::{self, AddressBook, RemovalReason};
+use crate::node::migrations;
+use crate::node::announcements::{Announcement, DhtAnnouncements};
+use crate::node::budget::{BudgetChange, BudgetUsage, MAX_DEFERRED_PUBLISHES};
+use crate::node::audit::{self, AuditSnapshot, RequestAudit};
+use crate::node::dht_store::{StoreFull, StoreFullKind};
+use crate::node::fetch::Transfer;
+use crate::node::event_stream::{DocumentWatch, EventSubscription, EVENT_SUBSCRIPTION_CAPACITY};
+use crate::node::dial::{self, DialConcurrency, DialOutcome, PendingDials};
+use crate::node::replica::ReplicationStatus;
+use crate::node::keeper::{self, ConnectionKeeper};
+use crate::node::liveness::{PingAction, PingFailures};
+use crate::node::ordering::{Ordered, OrderedDelivery, OrderedUpdate, Origin};
+use crate::node::proxy::ProxiedTcp;
+use crate::node::peer_exchange::{ListedDial, PeerExchange, PEER_EXCHANGE_INTERVAL};
+use crate::node::receipts::{AckTracker, PublishOptions};
+use crate::node::federation::{Federation, FederationStatus};
+use crate::node::partition::{PartitionChange, PartitionStatus, PartitionWatch};
+use crate::node::topic_health::{TopicChange, TopicHealth};
+use crate::node::redial::ImportantPeers;
+use crate::node::scrub::{ScrubReport, ScrubStats, StoreScrub, SCRUB_FILE};
+use crate::node::shutdown::{ShutdownMode, ShutdownReport};
+use crate::node::rendezvous::{
+    Registrant, RendezvousPeers, DEFAULT_REGISTRANTS_TO_DIAL, DISCOVER_INTERVAL, DISCOVER_LIMIT, REGISTRATION_TTL,
+};
+use crate::node::relay_discovery::{DEFAULT_RELAYS_TO_DIAL, DEFAULT_RELAY_DISCOVERY_TIMEOUT, RELAY_HOP_PROTOCOL, RELAY_PROVIDER_REFRESH};
+use crate::node::addrs::{check_not_self, is_peer_addr, is_tcp_dialable, without_self};
+use crate::node::{
+    Behaviours, BanList, DhtBootstrap, DhtStoreMonitor, DhtStoreStats, DhtSummary, ExternalAddrChange, ExternalAddrs, DialFailure, DialOptions, EventHistory, FindPeerOptions, FoundPeer, HistoryEntry, HistoryEvent, NodeBuilder, NodeReadiness, PeerInfo, PeerInfoCache, PeerReputation, PeerSignal, Published, PublishedRecords,
+    ConnectionQuality, QualityBucket, QualityTracker, RecorderOptions, Recording, RecordingStatus, RelayCheck, RelayDiscovery,
+    RelayLookup, TrafficSnapshot, TrafficStats,
+};
+use crate::store::author_quota::{AuthorQuota, AuthorQuotas, AuthorReport};
+use crate::store::quota::{self, QuotaExceeded, QuotaSink, RoomQuota, RoomQuotas, RoomReport};
+use crate::store::{Corruption, DocStore, FileDocStore, MemoryDocStore, MergePolicy};
+use crate::Error;
+
+/// Behaviours composed by a native node.
+#[derive(NetworkBehaviour)]
+pub struct DocstoreBehaviour {
+    pub ping: ping::Behaviour,
+    pub gossipsub: gossipsub::Behaviour,
+    pub identify: identify::Behaviour,
+    pub kademlia: KademliaBehaviour<MemoryStore>,
+    pub relay: Toggle<crate::behaviour::relay::RelayBehaviour>,
+    /// UPnP port mapping and AutoNAT, off unless [`NodeBuilder::with_upnp`] / [`NodeBuilder::with_autonat`].
+    pub nat: NatBehaviour,
+    pub history: HistoryBehaviour,
+    pub fetch: FetchBehaviour,
+    pub mailbox: MailboxBehaviour,
+    pub members: MembersBehaviour,
+    pub receipts: ReceiptBehaviour,
+    pub rendezvous: RendezvousBehaviour,
+    pub keep_alive: KeepAliveBehaviour,
+    pub peers: PeersBehaviour,
+}
+
+impl From<Behaviours> for DocstoreBehaviour {
+    fn from(b: Behaviours) -> Self {
+        Self {
+            ping: b.ping,
+            gossipsub: b.gossipsub,
+            identify: b.identify,
+            kademlia: b.kademlia,
+            relay: b.relay,
+            nat: b.nat,
+            history: b.history,
+            fetch: b.fetch,
+            mailbox: b.mailbox,
+            members: b.members,
+            receipts: b.receipts,
+            rendezvous: b.rendezvous,
+            keep_alive: b.keep_alive,
+            peers: b.peers,
+        }
+    }
+}
+
+/// Events emitted by a running [`Node`].
+#[derive(Debug, Clone)]
+pub enum NodeEvent {
+    ListenStarted { addr: Multiaddr },
+    /// `transport` is named by [`crate::node::addrs::transport_name`]; `direction` is
+    /// "inbound" or "outbound".
+    Connected { peer_id: PeerId, addr: Multiaddr, transport: &'static str, direction: &'static str },
+    Disconnected { peer_id: PeerId },
+    /// An important peer (see [`Node::mark_important`]) is connected again after its
+    /// connection was lost.
+    PeerRecovered { peer_id: PeerId },
+    /// Expected relays or FullNodes (see [`NodeBuilder::with_expected_peers`]) have been
+    /// unreachable for longer than the threshold while clients are still connected, the
+    /// first of them since `since_ms`. They are redialed often until the partition heals.
+    PossiblePartition { missing_peers: Vec<PeerId>, since_ms: u64 },
+    /// Every peer of a [`NodeEvent::PossiblePartition`] is back or no longer expected.
+    PartitionResolved { peers: Vec<PeerId>, since_ms: u64, duration: Duration },
+    /// `used` bytes of the bandwidth budget's `limit` are spent (see
+    /// [`Node::set_bandwidth_budget`]); publishes and history fetches wait until
+    /// `resets_at_ms`.
+    BudgetExceeded { used: u64, limit: u64, resets_at_ms: u64 },
+    /// The bandwidth budget is available again; what waited goes out now.
+    BudgetReset,
+    /// A provider found by [`Node::discover_relays`] serves the relay hop protocol and is
+    /// now marked important.
+    RelayDiscovered { peer_id: PeerId },
+    /// `failures` pings in a row to `peer_id` failed. Its connection is closed if they
+    /// go on failing, see [`NodeBuilder::with_ping_policy`].
+    PeerUnresponsive { peer_id: PeerId, failures: u32 },
+    /// A dial to `peer_id` started, trying `addrs` in this order (see [`dial::plan`]), a
+    /// few at a time as [`NodeBuilder::with_dial_concurrency`] allows. Addresses the
+    /// behaviours add are tried after them.
+    DialPlan { peer_id: PeerId, addrs: Vec<Multiaddr> },
+    /// `peer_id`'s connection quality moved from one bucket to another, see
+    /// [`Node::peer_quality`]. Not emitted for score changes within a bucket.
+    QualityChanged { peer_id: PeerId, from: QualityBucket, to: QualityBucket, score: u8 },
+    /// Identify info arrived for a connected peer, or changed. `shared_protocols` are the
+    /// ones it has in common with us, see [`NodeBuilder::local_protocols`].
+    PeerIdentified { peer_id: PeerId, info: PeerInfo, shared_protocols: Vec<String> },
+    /// A message on one of our topics; `message_id` is stable across replays and relays.
+    MessageReceived { peer_id: PeerId, topic: String, message_id: MessageId, data: Vec<u8> },
+    DocUpdateReceived { peer_id: PeerId, update: DocUpdate },
+    /// Every part of transaction `id` from `peer_id` arrived and its updates, each already
+    /// reported as a `DocUpdateReceived`, were applied to the store together.
+    TransactionApplied { peer_id: PeerId, id: u64, doc_ids: Vec<String> },
+    /// A verified snapshot newer than our state was installed into the local store.
+    SnapshotInstalled { doc_id: String, version: u64 },
+    /// A chunk of `doc_id` fetched with [`Node::fetch_document`] was verified: `received`
+    /// of its `total` bytes are in, counting what an earlier attempt fetched.
+    FetchProgress { doc_id: String, received: u64, total: u64 },
+    /// A current announcement signed by `peer_id`, one of the configured announcers.
+    Announcement { peer_id: PeerId, announcement: NetworkAnnouncement },
+    /// A compaction run removed old updates from the local store.
+    Compacted { removed_updates: usize, reclaimed_bytes: usize },
+    /// Updates from `peer_id` were turned away because storing them would take a room or
+    /// their author over a quota, see [`NodeBuilder::with_room_quotas`] and
+    /// [`NodeBuilder::with_author_quotas`].
+    QuotaExceeded { peer_id: PeerId, reason: QuotaExceeded },
+    /// An update or snapshot published by `peer_id` failed the validator of its document
+    /// type, see [`Node::register_schema`]. The message was rejected, or the snapshot
+    /// dropped, before anything was applied.
+    SchemaViolation { peer_id: PeerId, violation: SchemaViolation },
+    /// Our mesh for the docstore topic lost its last peer; publishes fail until it refills.
+    MeshEmpty { topic: String },
+    /// `topic` has had no mesh peers since `empty_since_ms` although we are connected, so
+    /// the node unsubscribes and subscribes again, see [`crate::node::topic_health`].
+    /// `attempt` counts the tries since it was last healthy.
+    TopicUnhealthy { topic: String, empty_since_ms: u64, attempt: u32 },
+    /// A [`NodeEvent::TopicUnhealthy`] topic has mesh peers again.
+    TopicRecovered { topic: String, duration: Duration, attempts: u32 },
+    /// A banned peer connected (or was dialed) and was turned away.
+    BannedPeerRejected { peer_id: PeerId },
+    /// `peer_id` published an envelope in a format version we cannot read. It was ignored
+    /// rather than rejected, so the peer keeps its score.
+    UnsupportedVersion { peer_id: PeerId, version: u8 },
+    /// `peer_id` stored a record with us.
+    RecordStored { peer_id: PeerId, key: RecordKey },
+    /// A record in our local DHT store reached its expiry and was dropped.
+    RecordExpired { key: RecordKey },
+    /// A record put with [`Node::put_record`] was put again before it expired.
+    RecordRepublished { key: RecordKey },
+    /// Putting a record again failed; it is retried on the next round.
+    RecordRepublishFailed { key: RecordKey, error: String },
+    /// Back online after more than [`NodeBuilder::with_reannounce_after`] without
+    /// connections, everything we had announced in the DHT was announced again: `renewed`
+    /// announcements succeeded, the ones under `failed` did not.
+    AnnouncementsRenewed { renewed: usize, failed: Vec<RecordKey> },
+    /// An address was dropped from the DHT routing table and the address book.
+    AddressRemoved { peer_id: PeerId, addr: Multiaddr, reason: RemovalReason },
+    /// A peer was dropped from the DHT routing table and the address book.
+    PeerRemoved { peer_id: PeerId, reason: RemovalReason },
+    /// A peer entered the DHT routing table or its addresses there changed. `evicted` is
+    /// the peer it replaced in a full bucket, if any.
+    RoutingUpdated { peer_id: PeerId, is_new_peer: bool, addrs: Vec<Multiaddr>, evicted: Option<PeerId> },
+    /// A connected peer could not be added to the routing table: it did not tell us an address.
+    UnroutablePeer { peer_id: PeerId },
+    /// A connected peer with a known address did not make it into the routing table, e.g.
+    /// because its bucket is full.
+    RoutablePeer { peer_id: PeerId, addr: Multiaddr },
+    /// Kademlia switched between client and server mode.
+    DhtModeChanged { mode: kad::Mode },
+    /// The routing table grew or shrank, see [`Node::dht_summary`].
+    DhtSummaryChanged { summary: DhtSummary },
+    /// The DHT record store refused a record or provider announcement, the first time
+    /// since it last accepted one; see [`Node::dht_store_stats`] and
+    /// [`PeerDhtConfig::store`](crate::behaviour::PeerDhtConfig::store).
+    DhtStoreFull { kind: StoreFullKind },
+    /// The gateway forwards a port to us; `addr` is advertised as an external address.
+    PortMapped { addr: Multiaddr },
+    /// A port mapping lapsed and could not be renewed; `addr` is no longer advertised.
+    PortMappingExpired { addr: Multiaddr },
+    /// UPnP port mapping is not possible: no gateway answered, or it is itself behind a NAT.
+    PortMappingUnavailable { reason: String },
+    /// AutoNAT probes could not reach us, so mapped addresses are withdrawn until they do.
+    PortMappingUnreachable,
+    /// A peer observed us on an address not seen before, see [`Node::external_addrs`].
+    ExternalAddrCandidate { addr: Multiaddr },
+    /// `addr` is a confirmed external address (by AutoNAT, a port mapping or the app) and
+    /// is advertised to peers.
+    ExternalAddrConfirmed { addr: Multiaddr },
+    /// `addr` is no longer a confirmed external address.
+    ExternalAddrExpired { addr: Multiaddr },
+    /// A dial started by [`Node::dial_with`] (or a bootstrap dial) gave up after `attempts`
+    /// attempts; `reason` is the last failure.
+    DialFailed { peer_id: Option<PeerId>, addr: Multiaddr, attempts: u32, reason: DialFailure },
+    Error { msg: String },
+    /// A stored document failed its integrity check (by the background scrub or
+    /// [`Node::verify_now`]). It was quarantined, so it is no longer served, and is being
+    /// fetched again from its providers.
+    StoreCorruption { doc_id: String, reason: String },
+    /// A quarantined document was restored from the newest update `peer_id` had of it.
+    StoreRepaired { doc_id: String, peer_id: PeerId, version: u64 },
+    /// No peer could give back a quarantined document; it stays missing until an update
+    /// or snapshot of it arrives.
+    StoreRepairFailed { doc_id: String, reason: String },
+    /// `peer_id` applied an update we published with
+    /// [`PublishOptions::ack_requested`], bringing `doc_id` to `applied_version` there.
+    /// Reported once per peer.
+    UpdateAcknowledged { msg_id: MessageId, peer_id: PeerId, doc_id: String, applied_version: u64 },
+    /// Nobody acknowledged an update published with [`PublishOptions::ack_requested`]
+    /// within 30 seconds.
+    UpdateUnacknowledged { msg_id: MessageId, doc_id: String },
+    /// `peer_id` refused a CAS update we published with [`Node::try_cas_update`]:
+    /// `doc_id` is at `current_version` there. Reported once per peer; other peers may
+    /// still have applied it.
+    CasConflict { msg_id: MessageId, peer_id: PeerId, doc_id: String, current_version: u64 },
+    /// `peer_id` ignored an update we published asking for receipts, because storing it
+    /// would go over a room's quota or ours there. Why is left in our mailbox at
+    /// `peer_id`, at most every 5 minutes, when it holds one.
+    QuotaRefused { msg_id: MessageId, peer_id: PeerId, doc_id: String },
+    /// `holder` handed over a message left for us in its mailbox, fetched when we
+    /// connected to it. Acknowledged, so the holder has deleted it.
+    MailboxDelivered { holder: PeerId, message: MailboxMessage },
+    /// A gap in watched document `doc_id` was not filled in time. Its `missing` updates
+    /// are given up on and the updates held back behind it were delivered, see
+    /// [`Node::watch_document`].
+    GapAbandoned { doc_id: String, missing: u64 },
+    /// [`Node::shutdown`] finished; the last event before the stream ends.
+    ShutdownComplete { report: ShutdownReport },
+}
+
+impl HistoryEvent for NodeEvent {
+    fn kind(&self) -> &'static str {
+        match self {
+            NodeEvent::ListenStarted { .. } => "listen_started",
+            NodeEvent::Connected { .. } => "connected",
+            NodeEvent::Disconnected { .. } => "disconnected",
+            NodeEvent::PeerRecovered { .. } => "peer_recovered",
+            NodeEvent::PossiblePartition { .. } => "possible_partition",
+            NodeEvent::PartitionResolved { .. } => "partition_resolved",
+            NodeEvent::BudgetExceeded { .. } => "budget_exceeded",
+            NodeEvent::BudgetReset => "budget_reset",
+            NodeEvent::RelayDiscovered { .. } => "relay_discovered",
+            NodeEvent::PeerUnresponsive { .. } => "peer_unresponsive",
+            NodeEvent::DialPlan { .. } => "dial_plan",
+            NodeEvent::QualityChanged { .. } => "quality_changed",
+            NodeEvent::PeerIdentified { .. } => "peer_identified",
+            NodeEvent::MessageReceived { .. } => "message_received",
+            NodeEvent::DocUpdateReceived { .. } => "doc_update_received",
+            NodeEvent::TransactionApplied { .. } => "transaction_applied",
+            NodeEvent::SnapshotInstalled { .. } => "snapshot_installed",
+            NodeEvent::FetchProgress { .. } => "fetch_progress",
+            NodeEvent::Announcement { .. } => "announcement",
+            NodeEvent::Compacted { .. } => "compacted",
+            NodeEvent::QuotaExceeded { .. } => "quota_exceeded",
+            NodeEvent::SchemaViolation { .. } => "schema_violation",
+            NodeEvent::MeshEmpty { .. } => "mesh_empty",
+            NodeEvent::TopicUnhealthy { .. } => "topic_unhealthy",
+            NodeEvent::TopicRecovered { .. } => "topic_recovered",
+            NodeEvent::BannedPeerRejected { .. } => "banned_peer_rejected",
+            NodeEvent::UnsupportedVersion { .. } => "unsupported_version",
+            NodeEvent::RecordStored { .. } => "record_stored",
+            NodeEvent::RecordExpired { .. } => "record_expired",
+            NodeEvent::RecordRepublished { .. } => "record_republished",
+            NodeEvent::RecordRepublishFailed { .. } => "record_republish_failed",
+            NodeEvent::AnnouncementsRenewed { .. } => "announcements_renewed",
+            NodeEvent::AddressRemoved { .. } => "address_removed",
+            NodeEvent::PeerRemoved { .. } => "peer_removed",
+            NodeEvent::RoutingUpdated { .. } => "routing_updated",
+            NodeEvent::UnroutablePeer { .. } => "unroutable_peer",
+            NodeEvent::RoutablePeer { .. } => "routable_peer",
+            NodeEvent::DhtModeChanged { .. } => "dht_mode_changed",
+            NodeEvent::DhtSummaryChanged { .. } => "dht_summary_changed",
+            NodeEvent::DhtStoreFull { .. } => "dht_store_full",
+            NodeEvent::PortMapped { .. } => "port_mapped",
+            NodeEvent::PortMappingExpired { .. } => "port_mapping_expired",
+            NodeEvent::PortMappingUnavailable { .. } => "port_mapping_unavailable",
+            NodeEvent::PortMappingUnreachable => "port_mapping_unreachable",
+            NodeEvent::ExternalAddrCandidate { .. } => "external_addr_candidate",
+            NodeEvent::ExternalAddrConfirmed { .. } => "external_addr_confirmed",
+            NodeEvent::ExternalAddrExpired { .. } => "external_addr_expired",
+            NodeEvent::DialFailed { .. } => "dial_failed",
+            NodeEvent::StoreCorruption { .. } => "store_corruption",
+            NodeEvent::StoreRepaired { .. } => "store_repaired",
+            NodeEvent::StoreRepairFailed { .. } => "store_repair_failed",
+            NodeEvent::UpdateAcknowledged { .. } => "update_acknowledged",
+            NodeEvent::UpdateUnacknowledged { .. } => "update_unacknowledged",
+            NodeEvent::CasConflict { .. } => "cas_conflict",
+            NodeEvent::QuotaRefused { .. } => "quota_refused",
+            NodeEvent::MailboxDelivered { .. } => "mailbox_delivered",
+            NodeEvent::GapAbandoned { .. } => "gap_abandoned",
+            NodeEvent::Error { .. } => "error",
+            NodeEvent::ShutdownComplete { .. } => "shutdown_complete",
+        }
+    }
+
+    fn approx_size(&self) -> usize {
+        let heap = match self {
+            NodeEvent::ListenStarted { addr }
+            | NodeEvent::Connected { addr, .. }
+            | NodeEvent::AddressRemoved { addr, .. }
+            | NodeEvent::RoutablePeer { addr, .. }
+            | NodeEvent::PortMapped { addr }
+            | NodeEvent::DialFailed { addr, .. }
+            | NodeEvent::ExternalAddrCandidate { addr }
+            | NodeEvent::ExternalAddrConfirmed { addr }
+            | NodeEvent::ExternalAddrExpired { addr }
+            | NodeEvent::PortMappingExpired { addr } => addr.len(),
+            NodeEvent::RoutingUpdated { addrs, .. } => addrs.iter().map(Multiaddr::len).sum(),
+            NodeEvent::PeerIdentified { info, shared_protocols, .. } => {
+                shared_protocols.iter().map(String::len).sum::<usize>()
+                    + info.agent_version.len()
+                    + info.protocol_version.len()
+                    + info.protocols.iter().map(String::len).sum::<usize>()
+                    + info.listen_addrs.iter().map(Multiaddr::len).sum::<usize>()
+            }
+            NodeEvent::DialPlan { addrs, .. } => addrs.iter().map(Multiaddr::len).sum(),
+            NodeEvent::MessageReceived { topic, message_id, data, .. } => topic.len() + message_id.0.len() + data.len(),
+            NodeEvent::DocUpdateReceived { update, .. } => update.doc_id.len() + update.payload.len(),
+            NodeEvent::TransactionApplied { doc_ids, .. } => doc_ids.iter().map(String::len).sum(),
+            NodeEvent::SnapshotInstalled { doc_id, .. }
+            | NodeEvent::FetchProgress { doc_id, .. }
+            | NodeEvent::StoreRepaired { doc_id, .. }
+            | NodeEvent::GapAbandoned { doc_id, .. } => doc_id.len(),
+            NodeEvent::StoreCorruption { doc_id, reason } | NodeEvent::StoreRepairFailed { doc_id, reason } => {
+                doc_id.len() + reason.len()
+            }
+            NodeEvent::Announcement { announcement, .. } => {
+                announcement.text.len() + announcement.public_key.len() + announcement.signature.len()
+            }
+            NodeEvent::MeshEmpty { topic }
+            | NodeEvent::TopicUnhealthy { topic, .. }
+            | NodeEvent::TopicRecovered { topic, .. } => topic.len(),
+            NodeEvent::QuotaExceeded { reason, .. } => reason.scope().len(),
+            NodeEvent::SchemaViolation { violation, .. } => {
+                violation.doc_id.len() + violation.prefix.len() + violation.reason.len()
+            }
+            NodeEvent::UpdateAcknowledged { msg_id, doc_id, .. }
+            | NodeEvent::UpdateUnacknowledged { msg_id, doc_id }
+            | NodeEvent::CasConflict { msg_id, doc_id, .. }
+            | NodeEvent::QuotaRefused { msg_id, doc_id, .. } => msg_id.0.len() + doc_id.len(),
+            NodeEvent::MailboxDelivered { message, .. } => message.sender.len() + message.blob.len(),
+            NodeEvent::RecordStored { key, .. }
+            | NodeEvent::RecordExpired { key }
+            | NodeEvent::RecordRepublished { key } => key.as_ref().len(),
+            NodeEvent::RecordRepublishFailed { key, error } => key.as_ref().len() + error.len(),
+            NodeEvent::AnnouncementsRenewed { failed, .. } => failed.iter().map(|k| k.as_ref().len()).sum(),
+            NodeEvent::Error { msg } | NodeEvent::PortMappingUnavailable { reason: msg } => msg.len(),
+            NodeEvent::Disconnected { .. }
+            | NodeEvent::PeerRecovered { .. }
+            | NodeEvent::PossiblePartition { .. }
+            | NodeEvent::PartitionResolved { .. }
+            | NodeEvent::BudgetExceeded { .. }
+            | NodeEvent::BudgetReset
+            | NodeEvent::RelayDiscovered { .. }
+            | NodeEvent::PeerUnresponsive { .. }
+            | NodeEvent::QualityChanged { .. }
+            | NodeEvent::Compacted { .. }
+            | NodeEvent::BannedPeerRejected { .. }
+            | NodeEvent::UnsupportedVersion { .. }
+            | NodeEvent::PeerRemoved { .. }
+            | NodeEvent::UnroutablePeer { .. }
+            | NodeEvent::DhtModeChanged { .. }
+            | NodeEvent::DhtSummaryChanged { .. }
+            | NodeEvent::DhtStoreFull { .. }
+            | NodeEvent::PortMappingUnreachable
+            | NodeEvent::ShutdownComplete { .. } => 0,
+        };
+        std::mem::size_of::<Self>() + heap
+    }
+}
+
+enum Command {
+    Publish { data: Vec<u8>, reply: oneshot::Sender<Result<Published, Error>> },
+    PublishDocUpdate { update: DocUpdate, options: PublishOptions, reply: oneshot::Sender<Result<Published, Error>> },
+    CommitTransaction { tx: Transaction, reply: oneshot::Sender<Result<Vec<Published>, Error>> },
+    PublishAnnouncement { announcement: NetworkAnnouncement, reply: oneshot::Sender<Result<Published, Error>> },
+    Dial { addr: Multiaddr, options: DialOptions, reply: oneshot::Sender<Result<(), Error>> },
+    DisconnectPeer { peer_id: PeerId },
+    RemovePeerAddress { peer_id: PeerId, addr: Multiaddr },
+    RemovePeer { peer_id: PeerId },
+    BanPeer { peer_id: PeerId, duration: Duration },
+    MarkImportant { peer_id: PeerId },
+    UnmarkImportant { peer_id: PeerId },
+    RegisterSchema { prefix: String, since_version: u8, validator: Box<dyn SchemaValidator> },
+    UnregisterSchema { prefix: String, reply: oneshot::Sender<bool> },
+    ExpectPeer { peer_id: PeerId },
+    RemoveExpectedPeer { peer_id: PeerId, reply: oneshot::Sender<bool> },
+    PartitionStatus { reply: oneshot::Sender<PartitionStatus> },
+    FederationStatus { reply: oneshot::Sender<FederationStatus> },
+    SetBandwidthBudget { bytes_per_interval: Option<u64> },
+    SetPublishDebounce { window: Option<Duration> },
+    SetMergePolicy { doc_id: String, policy: MergePolicy },
+    /// Leave `topic` in gossipsub only, as a lost subscription would.
+    #[cfg(test)]
+    DropSubscription { topic: gossipsub::IdentTopic },
+    GetDocument { doc_id: String, reply: oneshot::Sender<Option<(u64, Vec<u8>)>> },
+    Pin { doc_id: String, reply: oneshot::Sender<bool> },
+    Unpin { doc_id: String, reply: oneshot::Sender<bool> },
+    Pins { reply: oneshot::Sender<Vec<String>> },
+    WatchDoc { doc_id: String, reply: oneshot::Sender<bool> },
+    UnwatchDoc { doc_id: String, reply: oneshot::Sender<bool> },
+    WatchDocument { doc_id: String, watcher: mpsc::UnboundedSender<OrderedUpdate>, reply: oneshot::Sender<()> },
+    KeepalivePeers { reply: oneshot::Sender<Vec<PeerId>> },
+    RequestAudit { reply: oneshot::Sender<AuditSnapshot> },
+    TopicPeers { topic: String, reply: oneshot::Sender<Vec<PeerId>> },
+    MeshPeers { topic: String, reply: oneshot::Sender<Vec<PeerId>> },
+    PeerInfo { peer_id: PeerId, reply: oneshot::Sender<Option<PeerInfo>> },
+    PutRecord { key: RecordKey, value: Vec<u8>, ttl: Option<Duration>, reply: oneshot::Sender<Result<(), Error>> },
+    ForgetRecord { key: RecordKey, reply: oneshot::Sender<bool> },
+    PublishHeadPointer { doc_id: String, reply: oneshot::Sender<Result<HeadPointer, Error>> },
+    ResolveHead { author: PeerId, doc_id: String, reply: oneshot::Sender<Result<Option<HeadPointer>, Error>> },
+    SetProfile { profile: Profile, reply: oneshot::Sender<Result<SignedProfile, Error>> },
+    ResolveProfile { peer_id: PeerId, reply: oneshot::Sender<Result<Option<SignedProfile>, Error>> },
+    FindPeer { peer_id: PeerId, options: FindPeerOptions, reply: oneshot::Sender<Result<Vec<FoundPeer>, Error>> },
+    FindPeerLocal { peer_id: PeerId, reply: oneshot::Sender<Option<FoundPeer>> },
+    DhtSummary { reply: oneshot::Sender<DhtSummary> },
+    DhtStoreStats { reply: oneshot::Sender<DhtStoreStats> },
+    VerifyStore { reply: oneshot::Sender<ScrubReport> },
+    ScrubStats { reply: oneshot::Sender<ScrubStats> },
+    RoomUsage { reply: oneshot::Sender<Vec<RoomReport>> },
+    SetRoomQuota { room: String, quota: Option<RoomQuota>, reply: oneshot::Sender<Option<RoomQuota>> },
+    AuthorUsage { reply: oneshot::Sender<Vec<AuthorReport>> },
+    SetAuthorQuota { peer_id: Option<PeerId>, quota: Option<AuthorQuota>, reply: oneshot::Sender<Option<AuthorQuota>> },
+    Replication { reply: oneshot::Sender<ReplicationStatus> },
+    ExternalAddrs { reply: oneshot::Sender<ExternalAddrs> },
+    DiscoverRelays { reply: oneshot::Sender<Result<Vec<PeerId>, Error>> },
+    Readiness { reply: oneshot::Sender<NodeReadiness> },
+    /// Answered once the node is ready, see [`Node::wait_ready`].
+    WaitReady { reply: oneshot::Sender<NodeReadiness> },
+    History {
+        peer_id: Option<PeerId>,
+        request: HistoryRequest,
+        reply: oneshot::Sender<Result<(PeerId, HistoryPage), Error>>,
+    },
+    DocumentAt {
+        peer_id: Option<PeerId>,
+        request: HistoryRequest,
+        reply: oneshot::Sender<Result<(PeerId, DocumentState), Error>>,
+    },
+    FetchDocument { doc_id: String, peer_id: Option<PeerId>, reply: oneshot::Sender<Result<(PeerId, Vec<u8>), Error>> },
+    SendToMailbox {
+        holder: PeerId,
+        recipient: PeerId,
+        blob: Vec<u8>,
+        ttl: Option<Duration>,
+        reply: oneshot::Sender<Result<MailboxReceipt, Error>>,
+    },
+    CheckMailbox { holder: PeerId, reply: oneshot::Sender<Result<Vec<MailboxMessage>, Error>> },
+    RoomMembers {
+        peer_id: Option<PeerId>,
+        request: MembersRequest,
+        reply: oneshot::Sender<Result<(PeerId, MembersPage), Error>>,
+    },
+    DiscoverPeers { reply: oneshot::Sender<Result<Vec<Registrant>, Error>> },
+}
+
+impl Command {
+    /// Held while the bandwidth budget is exhausted.
+    fn waits_for_budget(&self) -> bool {
+        self.is_publish()
+            || matches!(self, Command::History { .. } | Command::DocumentAt { .. } | Command::FetchDocument { .. })
+    }
+
+    /// Announcements are not: they are rare, and operators want them out.
+    fn is_publish(&self) -> bool {
+        matches!(self, Command::Publish { .. } | Command::PublishDocUpdate { .. } | Command::CommitTransaction { .. })
+    }
+}
+
+/// Who is waiting for a `put_record` query.
+enum PendingPut {
+    Caller(oneshot::Sender<Result<(), Error>>),
+    HeadPointer { pointer: HeadPointer, reply: oneshot::Sender<Result<HeadPointer, Error>> },
+    Profile { profile: SignedProfile, reply: oneshot::Sender<Result<SignedProfile, Error>> },
+    Republish(RecordKey),
+}
+
+/// A `resolve_profile` query collecting the copies Kademlia finds.
+struct PendingProfile {
+    peer_id: PeerId,
+    values: Vec<Vec<u8>>,
+    reply: oneshot::Sender<Result<Option<SignedProfile>, Error>>,
+}
+
+/// A `resolve_head` query collecting the copies Kademlia finds.
+struct PendingResolve {
+    author: PeerId,
+    doc_id: String,
+    values: Vec<Vec<u8>>,
+    reply: oneshot::Sender<Result<Option<HeadPointer>, Error>>,
+}
+
+/// A `find_peer` query waiting for Kademlia.
+struct PendingFind {
+    target: PeerId,
+    dial: bool,
+    reply: oneshot::Sender<Result<Vec<FoundPeer>, Error>>,
+}
+
+/// What a mailbox request was sent for.
+enum PendingMailbox {
+    Deposit(oneshot::Sender<Result<MailboxReceipt, Error>>),
+    /// `None` for the fetch made when a holder identifies, whose messages become events.
+    Fetch(Option<oneshot::Sender<Result<Vec<MailboxMessage>, Error>>>),
+    Ack,
+}
+
+/// A `discover_relays` query waiting for Kademlia.
+struct PendingRelayLookup {
+    lookup: RelayLookup,
+    reply: oneshot::Sender<Result<Vec<PeerId>, Error>>,
+}
+
+/// How often the address book is flushed to disk while running.
+const ADDRESS_BOOK_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+/// How often FullNodes compact their store.
+const COMPACTION_INTERVAL: Duration = Duration::from_secs(600);
+/// How often the scrub checks the next stored document.
+const SCRUB_INTERVAL: Duration = Duration::from_secs(10);
+/// How often expired records are swept from the local DHT store.
+const RECORD_EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// How often readiness is re-checked while someone waits for it; mesh changes made by
+/// the gossipsub heartbeat don't show up as swarm events.
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// How often FullNodes and Relays look for a partition, see [`crate::node::partition`].
+const PARTITION_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// How often the bandwidth budget is checked, see [`crate::node::budget`].
+const BUDGET_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Peers from the address book dialed at startup.
+const ADDRESS_BOOK_DIAL_CANDIDATES: usize = 8;
+/// How long a draining node keeps its connections after unsubscribing, so the
+/// unsubscribes and the publishes flushed before them go out.
+const SHUTDOWN_FLUSH_GRACE: Duration = Duration::from_millis(100);
+
+fn unix_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+/// Handle to a node running on the tokio runtime. Dropping it stops the node at once;
+/// [`Node::shutdown`] stops it in order.
+pub struct Node {
+    cmd_sender: mpsc::UnboundedSender<Command>,
+    /// Separate from commands, so a shutdown overtakes whatever is queued.
+    shutdown_sender: mpsc::UnboundedSender<(ShutdownMode, oneshot::Sender<ShutdownReport>)>,
+    event_receiver: mpsc::UnboundedReceiver<NodeEvent>,
+    /// Never read; subscriptions are made from it.
+    event_subscriptions: broadcast::Receiver<NodeEvent>,
+    peer_id: PeerId,
+    read_only: bool,
+    traffic: TrafficStats,
+    history: EventHistory<NodeEvent>,
+    reputation: PeerReputation,
+    quality: QualityTracker,
+    /// For session recordings, which replays route by.
+    topics: TopicRegistry,
+}
+
+/// A [`Node`] turned into its events by [`Node::into_event_stream`]. Dropping it stops
+/// the node.
+pub struct NodeEventStream {
+    node: Node,
+}
+
+impl NodeEventStream {
+    pub fn peer_id(&self) -> PeerId {
+        self.node.peer_id
+    }
+}
+
+impl Stream for NodeEventStream {
+    type Item = NodeEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<NodeEvent>> {
+        self.node.event_receiver.poll_next_unpin(cx)
+    }
+}
+
+impl NodeBuilder {
+    /// Build the swarm and spawn its event loop. Must be called from within a tokio runtime.
+    /// Fails with `Error::InvalidConfig` if [`check`](Self::check) does.
+    pub fn spawn(mut self, key: identity::Keypair) -> Result<Node, Error> {
+        self.check()?;
+        // The event loop stores inbound records itself so it can report them
+        self.dht.filter_inbound_records = true;
+        let local_peer_id = PeerId::from(key.public());
+        let identity = key.clone();
+        let read_only = self.role.is_read_only() || self.is_replica();
+        let docstore_config = self.docstore_config();
+        docstore_config.validate()?;
+        let behaviour = DocstoreBehaviour::from(self.build_behaviours(&key)?);
+        let provides_relay = behaviour.relay.is_enabled();
+        if let Some(proxy) = &self.proxy {
+            tracing::info!("Dialing through proxy {}", proxy);
+        }
+        let proxy = self.proxy.clone();
+        let mut swarm = libp2p::SwarmBuilder::with_existing_identity(key)
+            .with_tokio()
+            .with_other_transport(|key| {
+                Ok(ProxiedTcp::new(tcp::Config::default().nodelay(true), proxy)
+                    .upgrade(Version::V1)
+                    .authenticate(noise::Config::new(key)?)
+                    .multiplex(yamux::Config::default()))
+            })
+            .map_err(|e| Error::Transport(e.to_string()))?
+            // In-process `/memory/<n>` addresses, see `crate::testing`
+            .with_other_transport(|key| {
+                Ok(MemoryTransport::default()
+                    .upgrade(Version::V1)
+                    .authenticate(noise::Config::new(key)?)
+                    .multiplex(yamux::Config::default()))
+            })
+            .map_err(|e| Error::Transport(e.to_string()))?
+            .with_dns()
+            .map_err(|e| Error::Transport(e.to_string()))?
+            .with_behaviour(|_| behaviour)
+            .map_err(|e| Error::Transport(e.to_string()))?
+            .with_swarm_config(|c| {
+                c.with_idle_connection_timeout(self.idle_timeout())
+                    .with_dial_concurrency_factor(self.dial_concurrency().global)
+            })
+            .build();
+
+        docstore::subscribe(&mut swarm.behaviour_mut().gossipsub, &docstore_config.topics)
+            .map_err(|e| Error::Transport(e.to_string()))?;
+        docstore::subscribe_snapshots(&mut swarm.behaviour_mut().gossipsub, &docstore_config.topics)
+            .map_err(|e| Error::Transport(e.to_string()))?;
+        docstore::subscribe_announcements(&mut swarm.behaviour_mut().gossipsub, &docstore_config.topics)
+            .map_err(|e| Error::Transport(e.to_string()))?;
+        docstore::subscribe_receipts(&mut swarm.behaviour_mut().gossipsub, &docstore_config.topics)
+            .map_err(|e| Error::Transport(e.to_string()))?;
+        for addr in &self.listen_addrs {
+            swarm.listen_on(addr.clone()).map_err(|e| Error::Transport(e.to_string()))?;
+        }
+        let mut important = ImportantPeers::default();
+        let mut pending_dials = PendingDials::default();
+        for addr in &self.bootstrap_peers {
+            if let Some(peer_id) = crate::node::addrs::peer_id_of(addr) {
+                important.mark(peer_id, Some(addr.clone()));
+            }
+            if let Err(e) = start_dial(&mut swarm, &mut pending_dials, addr.clone(), DialOptions::retrying()) {
+                tracing::warn!("Failed to dial bootstrap address {}: {}", addr, e);
+            }
+        }
+        // Federation members are dialed until they connect, and redialed whenever they drop
+        let federation = std::mem::take(&mut self.federation).without(&local_peer_id);
+        for (peer_id, addrs) in federation.members() {
+            for addr in addrs {
+                important.mark(*peer_id, Some(addr.clone()));
+                swarm.behaviour_mut().kademlia.add_address(peer_id, addr.clone());
+            }
+            important.dial_now(peer_id, Instant::now());
+            swarm.behaviour_mut().gossipsub.add_explicit_peer(peer_id);
+        }
+        let partition = matches!(self.role, crate::node::NodeRole::Relay | crate::node::NodeRole::FullNode).then(|| {
+            let mut watch = PartitionWatch::new(self.partition_threshold);
+            let bootstrap = self.bootstrap_peers.iter().filter_map(crate::node::addrs::peer_id_of);
+            let members = federation.members().map(|(peer_id, _)| *peer_id);
+            for peer_id in bootstrap.chain(self.expected_peers.iter().copied()).chain(members).filter(|p| *p != local_peer_id) {
+                watch.expect(peer_id, unix_ms());
+            }
+            watch
+        });
+        let mut topic_health = TopicHealth::new(self.topic_health, docstore_config.heartbeat_interval);
+        let registry = &docstore_config.topics;
+        for topic in [registry.updates(), registry.snapshots(), registry.announce(), registry.receipts()] {
+            topic_health.watch(topic.hash());
+        }
+        let (dht_bootstrap, bootstrap_query) = if self.bootstrap_peers.is_empty() {
+            (DhtBootstrap::NotConfigured, None)
+        } else {
+            for addr in &self.bootstrap_peers {
+                if let Some(peer_id) = crate::node::addrs::peer_id_of(addr).filter(|p| *p != local_peer_id) {
+                    swarm.behaviour_mut().kademlia.add_address(&peer_id, addr.clone());
+                }
+            }
+            match swarm.behaviour_mut().kademlia.bootstrap() {
+                Ok(query) => (DhtBootstrap::Pending, Some(query)),
+                Err(e) => {
+                    tracing::warn!("Kademlia bootstrap could not start: {}", e);
+                    (DhtBootstrap::Failed, None)
+                }
+            }
+        };
+
+        let mailbox_path = self.store_path.as_ref().map(|path| path.join(mailbox::MAILBOX_FILE));
+        let members_path = self.store_path.as_ref().map(|path| path.join(members::MEMBERS_FILE));
+        let (store, scrub_path): (Box<dyn DocStore + Send>, _) = match (self.store.take(), &self.store_path) {
+            (Some(store), _) => (store, None),
+            (None, Some(path)) => (
+                Box::new(
+                    FileDocStore::open_with_retention(path, self.retention)
+                        .map_err(|e| Error::InvalidConfig(format!("cannot open store {}: {e}", path.display())))?,
+                ),
+                Some(path.join(SCRUB_FILE)),
+            ),
+            (None, None) => (Box::new(MemoryDocStore::with_retention(self.retention)), None),
+        };
+
+        let mut quotas = std::mem::take(&mut self.room_quotas);
+        quotas.recount(&*store);
+        let mut author_quotas = std::mem::take(&mut self.author_quotas);
+        author_quotas.recount(&*store);
+
+        // Pinned documents stay provided; Kademlia republishes provider records on its own
+        // interval for as long as we keep providing them.
+        let mut announcements = DhtAnnouncements::new(self.reannounce_after());
+        let mut dht_store = DhtStoreMonitor::default();
+        for doc_id in store.pins() {
+            let key = crate::behaviour::doc_provider_key(&doc_id);
+            let provided = swarm.behaviour_mut().kademlia.start_providing(key.clone());
+            if let Err(full) = dht_store.provide(&key, provided) {
+                tracing::warn!("Failed to provide pinned document {}: {}", doc_id, full);
+            }
+            announcements.provide(key);
+        }
+
+        // Kept in memory even when not persisted, to count dial failures
+        let mut address_book_path = self.address_book.clone();
+        let address_book = match &self.address_book {
+            Some(path) => {
+                let mut book = match AddressBook::load(path) {
+                    Ok(book) => book,
+                    Err(e) => {
+                        tracing::warn!("Ignoring unreadable address book {}: {}", path.display(), e);
+                        // Left for the version that wrote it, or for `server migrate`
+                        if migrations::is_version_error(&e) {
+                            address_book_path = None;
+                        }
+                        AddressBook::default()
+                    }
+                };
+                book.prune(unix_ms(), address_book::DEFAULT_MAX_AGE);
+                // Recorded by a version that did not filter our own addresses
+                book.remove_peer(&local_peer_id);
+                for (peer_id, addrs) in book.peers() {
+                    for addr in addrs.into_iter().filter(|a| is_peer_addr(&local_peer_id, &peer_id, a)) {
+                        swarm.behaviour_mut().kademlia.add_address(&peer_id, addr);
+                    }
+                }
+                // Dialed by peer id, so failures come back to the book
+                for (peer_id, addr) in book.dial_candidates(ADDRESS_BOOK_DIAL_CANDIDATES) {
+                    let opts = DialOpts::peer_id(peer_id).addresses(vec![addr.clone()]).condition(PeerCondition::DisconnectedAndNotDialing);
+                    if let Err(e) = swarm.dial(opts.build()) {
+                        tracing::debug!("Failed to dial known peer {} at {}: {}", peer_id, addr, e);
+                    }
+                }
+                tracing::info!("Loaded {} peers from address book {}", book.len(), path.display());
+                book
+            }
+            None => AddressBook::default(),
+        };
+
+        #[allow(clippy::disallowed_methods)]
+        let (cmd_sender, cmd_receiver) = mpsc::unbounded();
+        #[allow(clippy::disallowed_methods)]
+        let (shutdown_sender, shutdown_receiver) = mpsc::unbounded();
+        #[allow(clippy::disallowed_methods)]
+        let (event_sender, event_receiver) = mpsc::unbounded();
+        let (event_broadcast, event_subscriptions) = broadcast::channel(EVENT_SUBSCRIPTION_CAPACITY);
+        let traffic = TrafficStats::default().with_observer(self.observer());
+        let history = self.event_history();
+        let reputation = PeerReputation::default();
+        let quality = QualityTracker::default();
+        let topics = docstore_config.topics.clone();
+
+        let mut pipeline = MessagePipeline::new(docstore_config.clone(), &local_peer_id);
+        if self.role().serves_relay() {
+            pipeline.confine_local_scope();
+        }
+        let event_loop = EventLoop {
+            swarm,
+            cmd_receiver,
+            shutdown_receiver,
+            event_sender,
+            event_broadcast,
+            docstore_config,
+            bans: BanList::default(),
+            address_book,
+            address_book_path,
+            store,
+            quotas,
+            author_quotas,
+            replica: self.is_replica(),
+            dial_concurrency: self.dial_concurrency().clone(),
+            newest_update_ms: None,
+            scrub: StoreScrub::load(scrub_path),
+            pending_refetch_lookups: HashMap::new(),
+            pending_refetches: HashMap::new(),
+            rendezvous: RendezvousPeers::new(local_peer_id),
+            rendezvous_waiters: Vec::new(),
+            peer_directory: self.role.serves_relay().then(PeerDirectory::default),
+            peer_exchange: PeerExchange::new(local_peer_id, self.discoverable()),
+            acks: AckTracker::default(),
+            debouncer: PublishDebouncer::default(),
+            debounced: Vec::new(),
+            flush_at: None,
+            ordering: OrderedDelivery::default(),
+            document_watchers: HashMap::new(),
+            pending_gap_fills: HashMap::new(),
+            pending_receipts: HashMap::new(),
+            compact: matches!(self.role, crate::node::NodeRole::FullNode),
+            snapshot_policy: self.snapshot_policy.clone(),
+            snapshot_scheduler: SnapshotScheduler::default(),
+            pipeline,
+            docstore_mesh_empty: true,
+            traffic: traffic.clone(),
+            history: history.clone(),
+            peer_infos: PeerInfoCache::default(),
+            local_protocols: self.local_protocols(),
+            record_ttl: self.dht.record_ttl,
+            published: PublishedRecords::new(self.max_published_records),
+            pending_puts: HashMap::new(),
+            pending_finds: HashMap::new(),
+            identity,
+            head_pointers: HeadPointers::default(),
+            pending_resolves: HashMap::new(),
+            profiles: ProfileCache::default(),
+            pending_profiles: HashMap::new(),
+            announcements,
+            relay_discovery: RelayDiscovery::default(),
+            pending_relay_lookups: HashMap::new(),
+            provides_relay,
+            dht_summary: DhtSummary::default(),
+            dht_store,
+            external_addrs: ExternalAddrs::default(),
+            port_mappings: PortMappings::default(),
+            important,
+            federation,
+            partition,
+            topic_health,
+            deferred: VecDeque::new(),
+            deferred_gaps: Vec::new(),
+            deferred_fetches: Vec::new(),
+            pending_dials,
+            dht_bootstrap,
+            bootstrap_query,
+            ready_waiters: Vec::new(),
+            reputation: reputation.clone(),
+            quality: quality.clone(),
+            ping_failures: PingFailures::new(self.ping_policy()),
+            serves_history: matches!(self.role, crate::node::NodeRole::FullNode),
+            audit: RequestAudit::default()
+                .with_limit(
+                    doc_history::HISTORY_PROTOCOL,
+                    doc_history::DEFAULT_REQUESTS_PER_WINDOW,
+                    doc_history::DEFAULT_RATE_WINDOW,
+                )
+                .with_limit(doc_fetch::FETCH_PROTOCOL, doc_fetch::DEFAULT_REQUESTS_PER_WINDOW, doc_fetch::DEFAULT_RATE_WINDOW)
+                .with_limit(mailbox::MAILBOX_PROTOCOL, mailbox::DEFAULT_REQUESTS_PER_WINDOW, mailbox::DEFAULT_RATE_WINDOW),
+            mailbox: matches!(self.role, crate::node::NodeRole::FullNode)
+                .then(|| Mailbox::load(mailbox_path, MailboxLimits::default())),
+            pending_mailbox: HashMap::new(),
+            members: matches!(self.role, crate::node::NodeRole::FullNode).then(|| MembershipTable::load(members_path)),
+            pending_members: HashMap::new(),
+            keeper: ConnectionKeeper::default(),
+            keep_alive_interval: keeper::ping_interval(self.idle_timeout()),
+            pending_history: HashMap::new(),
+            pending_document_at: HashMap::new(),
+            fetch_cache: ManifestCache::default(),
+            transfers: HashMap::new(),
+            active_fetches: HashMap::new(),
+            pending_fetches: HashMap::new(),
+            state_cache: StateCache::default(),
+        };
+        tokio::spawn(event_loop.run());
+
+        Ok(Node {
+            cmd_sender,
+            shutdown_sender,
+            event_receiver,
+            event_subscriptions,
+            peer_id: local_peer_id,
+            read_only,
+            traffic,
+            history,
+            reputation,
+            quality,
+            topics,
+        })
+    }
+}
+
+impl Node {
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id
+    }
+
+    /// Publish raw data on the docstore topic. An empty [`Published::sent_to`] means no
+    /// peer got the message.
+    pub async fn publish(&self, data: impl Into<Vec<u8>>) -> Result<Published, Error> {
+        self.ensure_writable()?;
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::Publish { data: data.into(), reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)?
+    }
+
+    /// Publish a document update wrapped in an envelope. Unstamped updates are stamped
+    /// with this node's HLC and the document's vector clock.
+    pub async fn publish_doc_update(&self, update: DocUpdate) -> Result<Published, Error> {
+        self.publish_doc_update_with(update, PublishOptions::default()).await
+    }
+
+    /// [`publish_doc_update`](Self::publish_doc_update) with `options`. With
+    /// [`PublishOptions::ack_requested`], every peer that applies the update sends a
+    /// signed receipt, reported as [`NodeEvent::UpdateAcknowledged`], or
+    /// [`NodeEvent::UpdateUnacknowledged`] if none arrives within 30 seconds. Peers running
+    /// a version without receipts see the update as plain data.
+    pub async fn publish_doc_update_with(&self, update: DocUpdate, options: PublishOptions) -> Result<Published, Error> {
+        self.ensure_writable()?;
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::PublishDocUpdate { update, options, reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)?
+    }
+
+    /// Publish `payload` as a compare-and-swap update of `doc_id`, applied only where the
+    /// document is at `expected_version`; see [`docstore::cas`] for what that does and
+    /// does not guarantee. Fails with [`Error::CasConflict`], publishing nothing, if our
+    /// own store is at another version. Peers that refuse it answer with
+    /// [`NodeEvent::CasConflict`], those that apply it with
+    /// [`NodeEvent::UpdateAcknowledged`].
+    pub async fn try_cas_update(
+        &self,
+        doc_id: impl Into<String>,
+        expected_version: u64,
+        payload: impl Into<Vec<u8>>,
+    ) -> Result<Published, Error> {
+        let options = PublishOptions { expected_version: Some(expected_version), ..Default::default() };
+        self.publish_doc_update_with(DocUpdate::new(doc_id, payload), options).await
+    }
+
+    /// Publish an announcement on the announce topic, signed with
+    /// [`NetworkAnnouncement::sign`], usually by this node's own key. Peers only accept it
+    /// if they list the signer among their announcers.
+    pub async fn publish_announcement(&self, announcement: NetworkAnnouncement) -> Result<Published, Error> {
+        self.ensure_writable()?;
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::PublishAnnouncement { announcement, reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)?
+    }
+
+    /// Start a transaction: updates added to it are published together by
+    /// [`PendingTransaction::commit`], and every peer applies all of them or none.
+    pub fn begin_transaction(&self) -> PendingTransaction<'_> {
+        PendingTransaction { node: self, tx: Transaction::new() }
+    }
+
+    /// Dial `addr` once. Same as [`dial_with`](Self::dial_with) with default options.
+    pub async fn dial(&self, addr: Multiaddr) -> Result<(), Error> {
+        self.dial_with(addr, DialOptions::default()).await
+    }
+
+    /// Dial `addr`, retrying refused and timed-out attempts as `options` says. Resolves
+    /// once the first attempt has started; if the dial finally fails,
+    /// [`NodeEvent::DialFailed`] reports how many attempts were made.
+    pub async fn dial_with(&self, addr: Multiaddr, options: DialOptions) -> Result<(), Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::Dial { addr, options, reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)?
+    }
+
+    /// Close all connections to `peer_id` and forget its DHT addresses. The peer may reconnect.
+    pub fn disconnect_peer(&self, peer_id: PeerId) -> Result<(), Error> {
+        self.send(Command::DisconnectPeer { peer_id })
+    }
+
+    /// Drop a known-bad address of `peer_id` from the DHT routing table and the address
+    /// book, e.g. a stale relay circuit that keeps timing out. Emits
+    /// [`NodeEvent::AddressRemoved`].
+    pub fn remove_peer_address(&self, peer_id: PeerId, addr: Multiaddr) -> Result<(), Error> {
+        self.send(Command::RemovePeerAddress { peer_id, addr })
+    }
+
+    /// Drop `peer_id` from the DHT routing table and the address book without
+    /// disconnecting it. Emits [`NodeEvent::PeerRemoved`].
+    pub fn remove_peer(&self, peer_id: PeerId) -> Result<(), Error> {
+        self.send(Command::RemovePeer { peer_id })
+    }
+
+    /// Disconnect `peer_id` and refuse it (inbound and outbound) until `duration` has passed.
+    /// Bans are held in memory for the lifetime of the process; `Duration::MAX` lasts that
+    /// long.
+    pub fn ban_peer(&self, peer_id: PeerId, duration: Duration) -> Result<(), Error> {
+        self.send(Command::BanPeer { peer_id, duration })
+    }
+
+    /// Redial `peer_id` with backoff whenever its last connection closes, until it is
+    /// unmarked or banned. Bootstrap peers are marked from the start. Emits
+    /// [`NodeEvent::PeerRecovered`] once a lost peer is connected again.
+    pub fn mark_important(&self, peer_id: PeerId) -> Result<(), Error> {
+        self.send(Command::MarkImportant { peer_id })
+    }
+
+    /// Stop redialing `peer_id`, including redials already scheduled.
+    pub fn unmark_important(&self, peer_id: PeerId) -> Result<(), Error> {
+        self.send(Command::UnmarkImportant { peer_id })
+    }
+
+    /// Check received updates of the documents under `prefix` with `validator` before
+    /// they are applied, see [`crate::behaviour::docstore::schema`]. Messages with an
+    /// update that fails are rejected and reported as [`NodeEvent::SchemaViolation`].
+    /// Applies to envelopes of the current version on.
+    pub fn register_schema(&self, prefix: impl Into<String>, validator: Box<dyn SchemaValidator>) -> Result<(), Error> {
+        self.register_schema_since(prefix, docstore::CURRENT_PROTOCOL_VERSION, validator)
+    }
+
+    /// [`register_schema`](Self::register_schema) for envelopes of `since_version` on,
+    /// up to the prefix's next registration, so documents written under an older schema
+    /// keep their validator.
+    pub fn register_schema_since(
+        &self,
+        prefix: impl Into<String>,
+        since_version: u8,
+        validator: Box<dyn SchemaValidator>,
+    ) -> Result<(), Error> {
+        self.send(Command::RegisterSchema { prefix: prefix.into(), since_version, validator })
+    }
+
+    /// Stop checking the documents under `prefix`. Returns false if it had no validator.
+    pub async fn unregister_schema(&self, prefix: impl Into<String>) -> Result<bool, Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::UnregisterSchema { prefix: prefix.into(), reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)
+    }
+
+    /// Expect `peer_id` to stay reachable, see [`NodeBuilder::with_expected_peers`].
+    /// Ignored on roles other than Relay and FullNode.
+    pub fn expect_peer(&self, peer_id: PeerId) -> Result<(), Error> {
+        self.send(Command::ExpectPeer { peer_id })
+    }
+
+    /// Stop expecting `peer_id`, e.g. because it was decommissioned, so its absence is no
+    /// longer taken for a partition. Returns false if it was not expected.
+    pub async fn remove_expected_peer(&self, peer_id: PeerId) -> Result<bool, Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::RemoveExpectedPeer { peer_id, reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)
+    }
+
+    /// The expected peers, which of them are unreachable, and the suspected partition if
+    /// any. [`PartitionStatus::metrics`] turns it into `/metrics` lines.
+    pub async fn partition_status(&self) -> Result<PartitionStatus, Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::PartitionStatus { reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)
+    }
+
+    /// The links to the other relays of our federation (see
+    /// [`NodeBuilder::with_federation`]) and how many clients are connected besides.
+    /// [`FederationStatus::metrics`] turns it into `/metrics` lines.
+    pub async fn federation_status(&self) -> Result<FederationStatus, Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::FederationStatus { reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)
+    }
+
+    /// Spend at most `bytes_per_interval` gossipsub bytes, in and out, per
+    /// [`BUDGET_INTERVAL`](crate::node::budget::BUDGET_INTERVAL); `None` lifts the limit.
+    /// Once it is spent, publishes wait for the next interval (up to
+    /// [`MAX_DEFERRED_PUBLISHES`], further ones fail with [`Error::SuspendQueueFull`]),
+    /// as do history fetches and gap re-requests, and [`NodeEvent::BudgetExceeded`] is
+    /// emitted. Setting a new budget starts a fresh interval, so raising or lifting it
+    /// sends what waited at once.
+    pub fn set_bandwidth_budget(&self, bytes_per_interval: Option<u64>) -> Result<(), Error> {
+        self.send(Command::SetBandwidthBudget { bytes_per_interval })
+    }
+
+    /// Hold [`publish_doc_update`](Self::publish_doc_update) calls for `window` after the
+    /// first one and publish them together, one envelope per document in publish order;
+    /// `None` or zero publishes each at once again, flushing what is held. Held updates
+    /// are in the local store already and their calls resolve when they go out. They go
+    /// out early before any other publish, before answering a fetch or history request
+    /// and on shutdown. Updates asking for receipts, CAS updates and local-scope ones are
+    /// never held.
+    pub fn set_publish_debounce(&self, window: Option<Duration>) -> Result<(), Error> {
+        self.send(Command::SetPublishDebounce { window })
+    }
+
+    /// Choose how concurrent updates to `doc_id` are merged in the local store.
+    pub fn set_merge_policy(&self, doc_id: impl Into<String>, policy: MergePolicy) -> Result<(), Error> {
+        self.send(Command::SetMergePolicy { doc_id: doc_id.into(), policy })
+    }
+
+    /// Unsubscribe from `topic` behind the topic health monitor's back, leaving the node
+    /// in the state of a subscription that silently failed.
+    #[cfg(test)]
+    pub(crate) fn drop_subscription(&self, topic: gossipsub::IdentTopic) -> Result<(), Error> {
+        self.send(Command::DropSubscription { topic })
+    }
+
+    /// Current `(version, content)` of a document in the local store.
+    pub async fn get_document(&self, doc_id: impl Into<String>) -> Result<Option<(u64, Vec<u8>)>, Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::GetDocument { doc_id: doc_id.into(), reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)
+    }
+
+    /// Keep `doc_id` in full and keep announcing it in the DHT. Returns false if it was
+    /// already pinned.
+    pub async fn pin(&self, doc_id: impl Into<String>) -> Result<bool, Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::Pin { doc_id: doc_id.into(), reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)
+    }
+
+    pub async fn unpin(&self, doc_id: impl Into<String>) -> Result<bool, Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::Unpin { doc_id: doc_id.into(), reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)
+    }
+
+    pub async fn pins(&self) -> Result<Vec<String>, Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::Pins { reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)
+    }
+
+    /// Start working on `doc_id`: peers we receive its updates from are kept connected,
+    /// however quiet, until [`Node::unwatch_doc`]. Returns false if it was already watched.
+    pub async fn watch_doc(&self, doc_id: impl Into<String>) -> Result<bool, Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::WatchDoc { doc_id: doc_id.into(), reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)
+    }
+
+    /// Stop watching `doc_id`. Its peers that no other watched document needs go back to
+    /// the idle timeout. Returns false if it wasn't watched.
+    pub async fn unwatch_doc(&self, doc_id: impl Into<String>) -> Result<bool, Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::UnwatchDoc { doc_id: doc_id.into(), reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)
+    }
+
+    /// `doc_id`'s updates from now on, in order, each marked with how it reached the node.
+    /// An update is held back until everything its stamp says came before it was
+    /// delivered. While updates are held, the gap is re-requested from a peer serving
+    /// history; if it is not filled within
+    /// [`DEFAULT_GAP_TIMEOUT`](crate::node::ordering::DEFAULT_GAP_TIMEOUT),
+    /// [`NodeEvent::GapAbandoned`] is emitted and delivery carries on. Updates the store
+    /// already had are not delivered. [`NodeEvent::DocUpdateReceived`] still reports
+    /// every gossiped update as it arrives.
+    pub async fn watch_document(&self, doc_id: impl Into<String>) -> Result<DocumentWatch, Error> {
+        let doc_id = doc_id.into();
+        #[allow(clippy::disallowed_methods)]
+        let (watcher, updates) = mpsc::unbounded();
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::WatchDocument { doc_id: doc_id.clone(), watcher, reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)?;
+        Ok(DocumentWatch::new(doc_id, updates))
+    }
+
+    /// Peers currently kept alive for watched documents, for debugging.
+    pub async fn keepalive_peers(&self) -> Result<Vec<PeerId>, Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::KeepalivePeers { reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)
+    }
+
+    /// Per-protocol counters and the recent log of the requests this node answered, see
+    /// [`crate::node::audit`].
+    pub async fn request_audit(&self) -> Result<AuditSnapshot, Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::RequestAudit { reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)
+    }
+
+    /// Known peers subscribed to `topic`.
+    pub async fn topic_peers(&self, topic: impl Into<String>) -> Result<Vec<PeerId>, Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::TopicPeers { topic: topic.into(), reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)
+    }
+
+    /// Peers in our gossipsub mesh for `topic`.
+    pub async fn mesh_peers(&self, topic: impl Into<String>) -> Result<Vec<PeerId>, Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::MeshPeers { topic: topic.into(), reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)
+    }
+
+    /// What a connected peer reported through identify; `None` until it has, or once it
+    /// has disconnected.
+    pub async fn peer_info(&self, peer_id: PeerId) -> Result<Option<PeerInfo>, Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::PeerInfo { peer_id, reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)
+    }
+
+    /// Put a record into the DHT and keep putting it again before it expires until
+    /// [`Node::forget_record`]. `ttl` defaults to the configured record TTL; records that
+    /// never expire are not republished. Resolves once the first put reached one peer.
+    pub async fn put_record(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Option<Duration>,
+    ) -> Result<(), Error> {
+        self.ensure_writable()?;
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::PutRecord { key: RecordKey::from(key.into()), value: value.into(), ttl, reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)?
+    }
+
+    /// Stop republishing a record put with [`Node::put_record`]. Returns false if it was
+    /// not being republished.
+    pub async fn forget_record(&self, key: impl Into<Vec<u8>>) -> Result<bool, Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::ForgetRecord { key: RecordKey::from(key.into()), reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)
+    }
+
+    /// Point the DHT at the current version of `doc_id` in the local store: a signed
+    /// [`HeadPointer`] under [`head_pointer_key`] of our peer id, kept alive like a
+    /// [`Node::put_record`] record. Every call supersedes the previous pointer. Resolves
+    /// once the first put reached one peer.
+    pub async fn publish_head_pointer(&self, doc_id: impl Into<String>) -> Result<HeadPointer, Error> {
+        self.ensure_writable()?;
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::PublishHeadPointer { doc_id: doc_id.into(), reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)?
+    }
+
+    /// Look up `author`'s head pointer for `doc_id`: the newest verified copy found, or
+    /// `None` if no peer has one. Fails if no copy verifies, or with
+    /// [`HeadPointerError::Stale`] if the newest is older than a pointer seen before.
+    ///
+    /// [`HeadPointerError::Stale`]: crate::behaviour::HeadPointerError::Stale
+    pub async fn resolve_head(&self, author: PeerId, doc_id: impl Into<String>) -> Result<Option<HeadPointer>, Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::ResolveHead { author, doc_id: doc_id.into(), reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)?
+    }
+
+    /// Publish our [`Profile`], signed, under [`profile_key`](crate::behaviour::profile_key)
+    /// of our peer id, kept alive like a [`Node::put_record`] record. Every call supersedes
+    /// the previous profile. Fails if it breaks the size limits; resolves once the first
+    /// put reached one peer.
+    pub async fn set_profile(&self, profile: Profile) -> Result<SignedProfile, Error> {
+        self.ensure_writable()?;
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::SetProfile { profile, reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)?
+    }
+
+    /// Look up `peer_id`'s profile: the newest verified copy found, or `None` if it
+    /// published none. Answered from a cache for
+    /// [`DEFAULT_PROFILE_TTL`](crate::behaviour::profile::DEFAULT_PROFILE_TTL). Fails if no
+    /// copy verifies.
+    pub async fn resolve_profile(&self, peer_id: PeerId) -> Result<Option<SignedProfile>, Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::ResolveProfile { peer_id, reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)?
+    }
+
+    /// Look `peer_id` up in the DHT. Resolves with up to `options.num_results` peers
+    /// closest to it, the target first if it was found, or fails once `options.timeout`
+    /// has passed. With `options.dial` the target is dialed through its dialable addresses.
+    pub async fn find_peer(&self, peer_id: PeerId, options: FindPeerOptions) -> Result<Vec<FoundPeer>, Error> {
+        let timeout = options.timeout;
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::FindPeer { peer_id, options, reply })?;
+        match tokio::time::timeout(timeout, rx).await {
+            Ok(res) => res.map_err(|_| Error::NodeStopped)?,
+            Err(_) => Err(Error::Dht(format!("lookup of {peer_id} timed out"))),
+        }
+    }
+
+    /// Look for relays in the DHT, where relays announce themselves as providers of
+    /// [`relay_provider_key`](crate::behaviour::relay_provider_key). Dials a few of the
+    /// providers and resolves with every one found, or fails after
+    /// [`DEFAULT_RELAY_DISCOVERY_TIMEOUT`]. Providers that identify shows serving the relay
+    /// hop protocol are marked important and reported as [`NodeEvent::RelayDiscovered`];
+    /// the others are never trusted.
+    pub async fn discover_relays(&self) -> Result<Vec<PeerId>, Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::DiscoverRelays { reply })?;
+        match tokio::time::timeout(DEFAULT_RELAY_DISCOVERY_TIMEOUT, rx).await {
+            Ok(res) => res.map_err(|_| Error::NodeStopped)?,
+            Err(_) => Err(Error::Dht("relay discovery timed out".into())),
+        }
+    }
+
+    /// Ask every connected rendezvous point for the peers registered under our namespace
+    /// and resolve with all registrants known, whose registration is still valid. Client
+    /// and Observer nodes register with rendezvous points as they meet them and dial a few
+    /// registrants by themselves; the other roles, and builds without the `rendezvous`
+    /// feature, fail with [`Error::RendezvousDisabled`].
+    pub async fn discover_peers(&self) -> Result<Vec<Registrant>, Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::DiscoverPeers { reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)?
+    }
+
+    /// What this node already knows about `peer_id` from its routing table, address book
+    /// and identify, without asking the network. `None` if it knows no address.
+    pub async fn find_peer_local(&self, peer_id: PeerId) -> Result<Option<FoundPeer>, Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::FindPeerLocal { peer_id, reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)
+    }
+
+    /// Wait until the node is usable: connected to a peer, meshed on the docstore topic,
+    /// and done with the initial Kademlia bootstrap (if bootstrap peers were configured),
+    /// successfully or not. Fails with [`Error::NotReady`] naming the unmet conditions
+    /// once `timeout` has passed.
+    pub async fn wait_ready(&self, timeout: Duration) -> Result<NodeReadiness, Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::WaitReady { reply })?;
+        match tokio::time::timeout(timeout, rx).await {
+            Ok(readiness) => readiness.map_err(|_| Error::NodeStopped),
+            Err(_) => Err(Error::NotReady { waiting_for: self.readiness().await?.waiting_for() }),
+        }
+    }
+
+    /// The readiness conditions as they stand now, see [`wait_ready`](Self::wait_ready).
+    pub async fn readiness(&self) -> Result<NodeReadiness, Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::Readiness { reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)
+    }
+
+    /// One page of `doc_id`'s logged history, from `options.peer_id` or else from the
+    /// best-ranked connected peer serving `/docstore/history/1.0.0` (this node itself if it
+    /// is a FullNode). Returns the page and the peer that served it. Versions differ
+    /// between FullNodes, so ask the same peer for the following pages, passing the
+    /// page's `next_cursor`.
+    pub async fn history(
+        &self,
+        doc_id: impl Into<String>,
+        options: HistoryOptions,
+    ) -> Result<(PeerId, HistoryPage), Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::History { peer_id: options.peer_id, request: options.request(doc_id), reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)?
+    }
+
+    /// `doc_id` as of `at`, reconstructed by `peer_id` or else by the peer [`history`]
+    /// would ask, from its logged history. A document deleted by then (its content was a
+    /// tombstone, an empty update) comes back as [`DocumentState::Deleted`]. Fails with
+    /// [`Error::HistoryUnavailable`] for points older than the peer's retention kept.
+    ///
+    /// [`history`]: Self::history
+    pub async fn document_at(
+        &self,
+        doc_id: impl Into<String>,
+        at: HistoryAt,
+        peer_id: Option<PeerId>,
+    ) -> Result<(PeerId, DocumentState), Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::DocumentAt { peer_id, request: HistoryRequest::at(doc_id, at), reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)?
+    }
+
+    /// `doc_id`'s current content, fetched in verified chunks from `peer_id` or else from
+    /// the best-ranked connected FullNode serving `/docstore/fetch/1.0.0`, and installed
+    /// in the local store unless that is newer. Returns the peer that served it and the
+    /// content; [`NodeEvent::FetchProgress`] reports each chunk.
+    ///
+    /// A fetch that fails halfway, e.g. because the connection dropped, is kept (in the
+    /// store too, if it persists anything) and calling again resumes it from the last
+    /// verified chunk, whichever peer serves it then. Without `peer_id` another peer is
+    /// tried before giving up. Chunks wait while the bandwidth budget is exhausted.
+    pub async fn fetch_document(&self, doc_id: impl Into<String>, peer_id: Option<PeerId>) -> Result<(PeerId, Vec<u8>), Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::FetchDocument { doc_id: doc_id.into(), peer_id, reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)?
+    }
+
+    /// Leave `blob` for `recipient` with `holder`, a FullNode or relay server serving
+    /// `/docstore/mailbox/1.0.0`, to be fetched when the recipient next connects to it.
+    /// The holder can read the blob, so encrypt it for the recipient first. Kept for
+    /// `ttl`, or the holder's default (7 days) if `None`. Fails with
+    /// [`Error::MailboxRejected`] when a quota is exceeded.
+    pub async fn send_to_mailbox(
+        &self,
+        holder: PeerId,
+        recipient: PeerId,
+        blob: impl Into<Vec<u8>>,
+        ttl: Option<Duration>,
+    ) -> Result<MailboxReceipt, Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::SendToMailbox { holder, recipient, blob: blob.into(), ttl, reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)?
+    }
+
+    /// Fetch the messages `holder` keeps for us, oldest first, and acknowledge them so it
+    /// deletes them. Messages are also fetched whenever a holder identifies, and reported
+    /// as [`NodeEvent::MailboxDelivered`].
+    pub async fn check_mailbox(&self, holder: PeerId) -> Result<Vec<MailboxMessage>, Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::CheckMailbox { holder, reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)?
+    }
+
+    /// One page of the members room `room_id` ever had, from `options.peer_id` or else
+    /// from the best-ranked connected peer serving `/docstore/members/1.0.0` (this node
+    /// itself if it is a FullNode). Returns the page and the peer that served it; ask the
+    /// same peer for the following pages, passing the page's `next` as `after`. Fails with
+    /// [`Error::MembersUnavailable`] for rooms marked private.
+    pub async fn room_members(
+        &self,
+        room_id: impl Into<String>,
+        options: MembersOptions,
+    ) -> Result<(PeerId, MembersPage), Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::RoomMembers { peer_id: options.peer_id, request: options.request(room_id), reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)?
+    }
+
+    /// Size of our DHT routing table. Changes are reported as [`NodeEvent::DhtSummaryChanged`].
+    pub async fn dht_summary(&self) -> Result<DhtSummary, Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::DhtSummary { reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)
+    }
+
+    /// What the DHT record store holds. Puts it refuses are reported as
+    /// [`NodeEvent::DhtStoreFull`].
+    pub async fn dht_store_stats(&self) -> Result<DhtStoreStats, Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::DhtStoreStats { reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)
+    }
+
+    /// Check every stored document against the digests recorded when it was written,
+    /// now rather than when the background scrub gets to it. Corrupted documents are
+    /// handled as the scrub handles them: quarantined, reported as
+    /// [`NodeEvent::StoreCorruption`] and fetched again from peers.
+    pub async fn verify_now(&self) -> Result<ScrubReport, Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::VerifyStore { reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)
+    }
+
+    /// How far the background scrub of the store got, see [`crate::node::scrub`].
+    pub async fn scrub_stats(&self) -> Result<ScrubStats, Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::ScrubStats { reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)
+    }
+
+    /// What each room's documents take up in the store, against its quota, see
+    /// [`crate::store::quota`]. [`quota::metrics`] turns it into `/metrics` lines.
+    pub async fn room_usage(&self) -> Result<Vec<RoomReport>, Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::RoomUsage { reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)
+    }
+
+    /// Set or (with `None`) lift `room`'s quota while running. Returns the previous one.
+    pub async fn set_room_quota(&self, room: impl Into<String>, quota: Option<RoomQuota>) -> Result<Option<RoomQuota>, Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::SetRoomQuota { room: room.into(), quota, reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)
+    }
+
+    /// What each author's updates take up in the store, against their quota, biggest
+    /// first; see [`crate::store::author_quota`]. [`author_quota::metrics`] turns it into
+    /// `/metrics` lines.
+    ///
+    /// [`author_quota::metrics`]: crate::store::author_quota::metrics
+    pub async fn author_usage(&self) -> Result<Vec<AuthorReport>, Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::AuthorUsage { reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)
+    }
+
+    /// Set `peer_id`'s own quota while running, or the default one with `None`. Lifting
+    /// a peer's quota holds them to the default again. Returns the previous one.
+    pub async fn set_author_quota(
+        &self,
+        peer_id: Option<PeerId>,
+        quota: Option<AuthorQuota>,
+    ) -> Result<Option<AuthorQuota>, Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::SetAuthorQuota { peer_id, quota, reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)
+    }
+
+    /// When the newest update applied here was written and how long ago that is, see
+    /// [`crate::node::replica`]. The admin socket's `replication` method reports the same.
+    pub async fn replication_status(&self) -> Result<ReplicationStatus, Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::Replication { reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)
+    }
+
+    /// How other peers see us: the confirmed external addresses we advertise, and the
+    /// unconfirmed ones peers observed us on. Changes are reported as
+    /// [`NodeEvent::ExternalAddrCandidate`], [`NodeEvent::ExternalAddrConfirmed`] and
+    /// [`NodeEvent::ExternalAddrExpired`].
+    pub async fn external_addrs(&self) -> Result<ExternalAddrs, Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::ExternalAddrs { reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)
+    }
+
+    /// `peer_id`'s reputation: positive for peers that answer quickly and serve fetches,
+    /// negative for ones that fail dials, pings or requests or send invalid messages.
+    /// Decays back towards `0` (unknown) over time. Local to this process.
+    pub fn peer_score(&self, peer_id: &PeerId) -> f64 {
+        self.reputation.score(peer_id, Instant::now())
+    }
+
+    /// The `n` best-scoring peers with their scores, best first.
+    pub fn top_peers(&self, n: usize) -> Vec<(PeerId, f64)> {
+        self.reputation.top(n, Instant::now())
+    }
+
+    /// Order `candidates` best first, to choose whom to fetch from.
+    pub fn rank_peers(&self, candidates: impl IntoIterator<Item = PeerId>) -> Vec<PeerId> {
+        self.reputation.rank(candidates, Instant::now())
+    }
+
+    /// `peer_id`'s smoothed connection quality: round-trip time, ping loss and failed
+    /// requests combined into a 0–100 score and a bucket. `None` unless connected.
+    pub fn peer_quality(&self, peer_id: &PeerId) -> Option<ConnectionQuality> {
+        self.quality.get(peer_id)
+    }
+
+    /// The connected peers with their connection quality, see [`Node::peer_quality`].
+    pub fn connected_peers(&self) -> Vec<(PeerId, ConnectionQuality)> {
+        self.quality.all()
+    }
+
+    /// Feed something the application observed of `peer_id` into its score, e.g. the
+    /// outcome of a replay fetch or being rate limited by it.
+    pub fn report_peer(&self, peer_id: PeerId, signal: PeerSignal) {
+        self.reputation.record(peer_id, signal, Instant::now());
+    }
+
+    /// Gossipsub traffic counted since start or the last [`Node::reset_stats`].
+    pub fn stats(&self) -> TrafficSnapshot {
+        self.traffic.snapshot()
+    }
+
+    pub fn reset_stats(&self) {
+        self.traffic.reset();
+    }
+
+    /// The bandwidth budget and how much of it is spent, see [`Node::set_bandwidth_budget`].
+    pub fn budget_usage(&self) -> BudgetUsage {
+        self.traffic.budget().usage(unix_ms())
+    }
+
+    /// Start recording every message received, and with
+    /// [`RecorderOptions::include_outbound`] every message published, until
+    /// [`Node::stop_recording`]; see [`recorder`](crate::node::recorder). Recordings hold
+    /// message data, documents included, so only start one when asked to. Returns false if
+    /// one is already running.
+    pub fn start_recording(&self, options: RecorderOptions) -> bool {
+        self.traffic.recorder().start(options, &self.peer_id, &self.topics, unix_ms())
+    }
+
+    /// Stop recording and hand over the recording, if one was running. Save it with
+    /// [`Recording::save`], after [`Recording::redact`] if it leaves the machine.
+    pub fn stop_recording(&self) -> Option<Recording> {
+        self.traffic.recorder().stop(unix_ms())
+    }
+
+    /// How far the running recording got, if one is running.
+    pub fn recording_status(&self) -> Option<RecordingStatus> {
+        self.traffic.recorder().status()
+    }
+
+    /// The latest `limit` emitted events (all kept if `None`) whose kind (see
+    /// [`HistoryEvent::kind`]) is in `kinds`, oldest first. Includes events already taken
+    /// with [`Node::next_event`].
+    pub fn recent_events(&self, kinds: Option<&[&str]>, limit: Option<usize>) -> Vec<HistoryEntry<NodeEvent>> {
+        self.history.recent(kinds, limit)
+    }
+
+    /// Wait for the next event. Returns `None` once the node has stopped.
+    pub async fn next_event(&mut self) -> Option<NodeEvent> {
+        self.event_receiver.next().await
+    }
+
+    /// The events [`Node::next_event`] returns, as a stream to `select!` on. Nothing is
+    /// dropped, however far behind the consumer is.
+    pub fn events(&mut self) -> impl Stream<Item = NodeEvent> + Unpin + '_ {
+        &mut self.event_receiver
+    }
+
+    /// A further consumer of every event emitted from now on, independent of
+    /// [`Node::next_event`] and of other subscriptions. One that falls behind receives
+    /// [`Lagged`](super::event_stream::Lagged) instead of the events it missed, see
+    /// [`super::event_stream`].
+    pub fn subscribe_events(&self) -> EventSubscription<NodeEvent> {
+        EventSubscription::new(self.event_subscriptions.resubscribe())
+    }
+
+    /// Turn the handle into its event stream. The node runs until the stream is dropped.
+    pub fn into_event_stream(self) -> NodeEventStream {
+        NodeEventStream { node: self }
+    }
+
+    /// Stop the node. New commands fail with [`Error::NodeStopped`] from the moment the
+    /// event loop sees the shutdown; commands queued before it are dropped, except that
+    /// [`ShutdownMode::Drain`] still publishes the queued updates until its timeout. The
+    /// event stream ends after a final [`NodeEvent::ShutdownComplete`].
+    pub async fn shutdown(&self, mode: ShutdownMode) -> Result<ShutdownReport, Error> {
+        let (reply, rx) = oneshot::channel();
+        self.shutdown_sender.unbounded_send((mode, reply)).map_err(|_| Error::NodeStopped)?;
+        rx.await.map_err(|_| Error::NodeStopped)
+    }
+
+    fn ensure_writable(&self) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        Ok(())
+    }
+
+    fn send(&self, cmd: Command) -> Result<(), Error> {
+        self.cmd_sender.unbounded_send(cmd).map_err(|_| Error::NodeStopped)
+    }
+}
+
+/// A transaction being put together, see [`Node::begin_transaction`]. Dropping it
+/// discards the updates.
+pub struct PendingTransaction<'a> {
+    node: &'a Node,
+    tx: Transaction,
+}
+
+impl PendingTransaction<'_> {
+    pub fn id(&self) -> u64 {
+        self.tx.id
+    }
+
+    /// Add an update to any document. Unstamped updates are stamped at commit.
+    pub fn add_update(&mut self, update: DocUpdate) -> &mut Self {
+        self.tx.add_update(update);
+        self
+    }
+
+    /// Publish the transaction, one message per part (usually one), and apply it to the
+    /// local store. Fails without publishing anything if an update is too large or there
+    /// are none; if a later part fails to publish, receivers never apply the earlier ones.
+    pub async fn commit(self) -> Result<Vec<Published>, Error> {
+        self.node.ensure_writable()?;
+        let (reply, rx) = oneshot::channel();
+        self.node.send(Command::CommitTransaction { tx: self.tx, reply })?;
+        rx.await.map_err(|_| Error::NodeStopped)?
+    }
+}
+
+struct EventLoop {
+    swarm: Swarm<DocstoreBehaviour>,
+    cmd_receiver: mpsc::UnboundedReceiver<Command>,
+    shutdown_receiver: mpsc::UnboundedReceiver<(ShutdownMode, oneshot::Sender<ShutdownReport>)>,
+    event_sender: mpsc::UnboundedSender<NodeEvent>,
+    event_broadcast: broadcast::Sender<NodeEvent>,
+    docstore_config: DocstoreGossipsubConfig,
+    bans: BanList,
+    address_book: AddressBook,
+    /// Where the address book is persisted, if anywhere.
+    address_book_path: Option<PathBuf>,
+    store: Box<dyn DocStore + Send>,
+    /// Per-room quotas, and what each room of `store` uses.
+    quotas: RoomQuotas,
+    /// Per-author quotas, and what each author's updates in `store` take.
+    author_quotas: AuthorQuotas,
+    /// A FullNode that never authors, see [`crate::node::replica`].
+    replica: bool,
+    /// The newest stamp among the updates applied, for [`ReplicationStatus`].
+    newest_update_ms: Option<u64>,
+    /// Per-peer overrides for dials by peer id; the global factor is in the swarm config.
+    dial_concurrency: DialConcurrency,
+    /// Run periodic compaction (FullNodes).
+    compact: bool,
+    snapshot_policy: Option<SnapshotPolicy>,
+    snapshot_scheduler: SnapshotScheduler,
+    /// Validates received messages and applies their updates to `store`.
+    pipeline: MessagePipeline,
+    /// Last observed state of the docstore topic mesh, to report when it empties.
+    docstore_mesh_empty: bool,
+    /// Shared with the [`Node`] handle.
+    traffic: TrafficStats,
+    history: EventHistory<NodeEvent>,
+    peer_infos: PeerInfoCache,
+    /// What we speak, to work out the protocols an identified peer shares with us.
+    local_protocols: Vec<String>,
+    /// Default expiry of records we put.
+    record_ttl: Option<Duration>,
+    published: PublishedRecords,
+    pending_puts: HashMap<QueryId, PendingPut>,
+    pending_finds: HashMap<QueryId, PendingFind>,
+    /// Signs our head pointers.
+    identity: identity::Keypair,
+    head_pointers: HeadPointers,
+    pending_resolves: HashMap<QueryId, PendingResolve>,
+    /// Resolved profiles, ours included once set.
+    profiles: ProfileCache,
+    pending_profiles: HashMap<QueryId, PendingProfile>,
+    /// What we announced in the DHT, renewed after an outage.
+    announcements: DhtAnnouncements<QueryId>,
+    /// Providers of the relay key being dialed and checked.
+    relay_discovery: RelayDiscovery,
+    pending_relay_lookups: HashMap<QueryId, PendingRelayLookup>,
+    /// Announce ourselves as a relay in the DHT (Relay and FullNode roles).
+    provides_relay: bool,
+    /// Last reported routing table summary.
+    dht_summary: DhtSummary,
+    dht_store: DhtStoreMonitor,
+    external_addrs: ExternalAddrs,
+    port_mappings: PortMappings,
+    important: ImportantPeers,
+    /// The other relays of our federation and the links to them.
+    federation: Federation,
+    /// Expected relays and FullNodes, on Relays and FullNodes.
+    partition: Option<PartitionWatch>,
+    /// Subscribed topics, resubscribed when they stay without mesh peers.
+    topic_health: TopicHealth,
+    /// Publishes and history fetches held while the bandwidth budget is exhausted, in order.
+    deferred: VecDeque<Command>,
+    /// Gap re-requests held likewise: (doc id, since, source).
+    deferred_gaps: Vec<(String, u64, Option<PeerId>)>,
+    /// Document fetches whose next chunk is held likewise.
+    deferred_fetches: Vec<String>,
+    pending_dials: PendingDials,
+    dht_bootstrap: DhtBootstrap,
+    /// The initial Kademlia bootstrap, while it runs.
+    bootstrap_query: Option<QueryId>,
+    ready_waiters: Vec<oneshot::Sender<NodeReadiness>>,
+    reputation: PeerReputation,
+    /// Smoothed quality of the connected peers, for display.
+    quality: QualityTracker,
+    ping_failures: PingFailures,
+    /// Answer history requests from the local store (FullNodes).
+    serves_history: bool,
+    /// Counters, recent log and per-peer limits of the requests we answer.
+    audit: RequestAudit,
+    /// Peers of watched documents, pinged every `keep_alive_interval`.
+    keeper: ConnectionKeeper,
+    keep_alive_interval: Duration,
+    pending_history: HashMap<request_response::OutboundRequestId, oneshot::Sender<Result<(PeerId, HistoryPage), Error>>>,
+    pending_document_at:
+        HashMap<request_response::OutboundRequestId, oneshot::Sender<Result<(PeerId, DocumentState), Error>>>,
+    /// States recently reconstructed for `document_at` requests, ours and our peers'.
+    state_cache: StateCache,
+    /// Documents recently fetched from us, with their manifests (FullNodes).
+    fetch_cache: ManifestCache,
+    /// Document fetches under way or interrupted, by document; see [`crate::node::fetch`].
+    transfers: HashMap<String, Transfer>,
+    /// The `fetch_document` calls under way, by document.
+    active_fetches: HashMap<String, ActiveFetch>,
+    /// Fetch requests in flight, by document.
+    pending_fetches: HashMap<request_response::OutboundRequestId, String>,
+    scrub: StoreScrub,
+    /// Provider lookups for quarantined documents, by document.
+    pending_refetch_lookups: HashMap<QueryId, String>,
+    /// History requests fetching quarantined documents again.
+    pending_refetches: HashMap<request_response::OutboundRequestId, String>,
+    /// Messages held for other peers (FullNodes).
+    mailbox: Option<Mailbox>,
+    pending_mailbox: HashMap<request_response::OutboundRequestId, PendingMailbox>,
+    /// Room membership tables (FullNodes).
+    members: Option<MembershipTable>,
+    pending_members: HashMap<request_response::OutboundRequestId, oneshot::Sender<Result<(PeerId, MembersPage), Error>>>,
+    /// Rendezvous points we register with and the peers found through them (clients).
+    rendezvous: RendezvousPeers<rendezvous_behaviour::Cookie>,
+    /// `discover_peers` calls waiting for the discoveries in flight.
+    rendezvous_waiters: Vec<oneshot::Sender<Result<Vec<Registrant>, Error>>>,
+    /// The clients that opted into being listed to each other (Relay and FullNode).
+    peer_directory: Option<PeerDirectory>,
+    /// Relays asked for their clients, and the listed peers we dialed (the other roles).
+    peer_exchange: PeerExchange,
+    /// Our updates waiting for receipts.
+    acks: AckTracker,
+    /// Updates held by `set_publish_debounce`, with the replies to their publishes in
+    /// the same order, and when they are due to go out.
+    debouncer: PublishDebouncer,
+    debounced: Vec<(String, oneshot::Sender<Result<Published, Error>>)>,
+    flush_at: Option<Instant>,
+    /// Updates of the documents watched with `watch_document`, put in order.
+    ordering: OrderedDelivery,
+    document_watchers: HashMap<String, Vec<mpsc::UnboundedSender<OrderedUpdate>>>,
+    /// History requests re-requesting a gap in a watched document.
+    pending_gap_fills: HashMap<request_response::OutboundRequestId, String>,
+    /// Receipts sent directly, published on the receipts topic instead if that fails.
+    pending_receipts: HashMap<request_response::OutboundRequestId, UpdateReceipt>,
+}
+
+/// A [`Node::fetch_document`] under way.
+struct ActiveFetch {
+    /// The peer asked now.
+    peer_id: PeerId,
+    /// The caller named the peer, so no other is tried.
+    chosen: bool,
+    /// Peers this fetch failed with already.
+    failed: Vec<PeerId>,
+    /// The callers, more than one if the document was asked for again meanwhile.
+    replies: Vec<oneshot::Sender<Result<(PeerId, Vec<u8>), Error>>>,
+}
+
+/// What a history response means to the caller of [`Node::history`].
+fn history_result(peer_id: PeerId, response: HistoryResponse) -> Result<(PeerId, HistoryPage), Error> {
+    doc_history::page_result(response).map(|page| (peer_id, page))
+}
+
+/// Dial `addr`, tracking it so transient failures are retried as `options` says.
+fn start_dial(
+    swarm: &mut Swarm<DocstoreBehaviour>,
+    pending_dials: &mut PendingDials,
+    addr: Multiaddr,
+    options: DialOptions,
+) -> Result<(), Error> {
+    options.check(&addr)?;
+    check_not_self(swarm.local_peer_id(), &addr)?;
+    let opts = DialOpts::from(addr.clone());
+    let connection_id = opts.connection_id();
+    swarm.dial(opts).map_err(|e| Error::Transport(e.to_string()))?;
+    pending_dials.started(connection_id, addr, options, Instant::now());
+    Ok(())
+}
+
+impl EventLoop {
+    async fn run(mut self) {
+        let mut save_timer = tokio::time::interval(ADDRESS_BOOK_SAVE_INTERVAL);
+        let snapshot_interval = self.snapshot_policy.as_ref().map_or(Duration::from_secs(3600), |p| p.interval);
+        let mut snapshot_timer = tokio::time::interval(snapshot_interval);
+        let mut compaction_timer = tokio::time::interval(COMPACTION_INTERVAL);
+        let mut expiry_timer = tokio::time::interval(RECORD_EXPIRY_CHECK_INTERVAL);
+        let mut scrub_timer = tokio::time::interval(SCRUB_INTERVAL);
+        let mut relay_provider_timer = tokio::time::interval(RELAY_PROVIDER_REFRESH);
+        let mut keep_alive_timer = tokio::time::interval(self.keep_alive_interval);
+        let mut rendezvous_timer = tokio::time::interval(DISCOVER_INTERVAL);
+        let mut peer_exchange_timer = tokio::time::interval(PEER_EXCHANGE_INTERVAL);
+        let mut partition_timer = tokio::time::interval(PARTITION_CHECK_INTERVAL);
+        let mut budget_timer = tokio::time::interval(BUDGET_CHECK_INTERVAL);
+        let mut topic_health_timer = tokio::time::interval(self.topic_health.config().check_interval());
+        let shutdown = loop {
+            // Checked before anything else, so a shutdown overtakes queued commands
+            if let Ok(Some(request)) = self.shutdown_receiver.try_next() {
+                break Some(request);
+            }
+            let until_republish =
+                self.published.next_due_ms().map(|due| Duration::from_millis(due.saturating_sub(unix_ms())));
+            let until_redial = self.important.next_due().map(|due| due.saturating_duration_since(Instant::now()));
+            let until_retry = self.pending_dials.next_due().map(|due| due.saturating_duration_since(Instant::now()));
+            let until_ack_deadline = self.acks.next_deadline().map(|due| due.saturating_duration_since(Instant::now()));
+            let until_gap_timeout = self.ordering.next_deadline().map(|due| due.saturating_duration_since(Instant::now()));
+            let until_resubscribe =
+                self.topic_health.next_resubscribe_ms().map(|due| Duration::from_millis(due.saturating_sub(unix_ms())));
+            let until_flush = self.flush_at.map(|due| due.saturating_duration_since(Instant::now()));
+            tokio::select! {
+                cmd = self.cmd_receiver.next() => match cmd {
+                    Some(cmd) => self.handle_command(cmd),
+                    // Node handle dropped
+                    None => break None,
+                },
+                request = self.shutdown_receiver.next() => match request {
+                    Some(request) => break Some(request),
+                    None => break None,
+                },
+                event = self.swarm.select_next_some() => {
+                    self.handle_swarm_event(event);
+                    self.check_docstore_mesh();
+                    self.notify_ready();
+                }
+                _ = tokio::time::sleep(READY_POLL_INTERVAL), if !self.ready_waiters.is_empty() => {
+                    self.check_docstore_mesh();
+                    self.notify_ready();
+                }
+                _ = save_timer.tick() => self.save_address_book(),
+                _ = snapshot_timer.tick(), if self.snapshot_policy.is_some() => {
+                    for doc_id in self.snapshot_scheduler.take_changed() {
+                        self.publish_snapshot(&doc_id);
+                    }
+                }
+                _ = tokio::time::sleep(until_republish.unwrap_or_default()), if until_republish.is_some() => {
+                    self.republish_records();
+                }
+                _ = tokio::time::sleep(until_flush.unwrap_or_default()), if until_flush.is_some() => {
+                    self.flush_debounced();
+                }
+                _ = tokio::time::sleep(until_redial.unwrap_or_default()), if until_redial.is_some() => {
+                    self.redial_important_peers();
+                }
+                _ = tokio::time::sleep(until_retry.unwrap_or_default()), if until_retry.is_some() => {
+                    self.retry_dials();
+                }
+                _ = tokio::time::sleep(until_ack_deadline.unwrap_or_default()), if until_ack_deadline.is_some() => {
+                    for unacked in self.acks.expire(Instant::now()) {
+                        self.emit(NodeEvent::UpdateUnacknowledged { msg_id: unacked.msg_id, doc_id: unacked.doc_id });
+                    }
+                }
+                _ = tokio::time::sleep(until_gap_timeout.unwrap_or_default()), if until_gap_timeout.is_some() => {
+                    let outputs = self.ordering.expire(Instant::now());
+                    self.deliver_ordered(outputs, None);
+                }
+                _ = tokio::time::sleep(until_resubscribe.unwrap_or_default()), if until_resubscribe.is_some() => {
+                    self.resubscribe_topics();
+                }
+                _ = expiry_timer.tick() => {
+                    self.expire_records();
+                    if let Some(mailbox) = &mut self.mailbox {
+                        mailbox.expire(unix_ms());
+                        mailbox.flush();
+                    }
+                    if let Some(table) = &mut self.members {
+                        table.expire(unix_ms());
+                    }
+                }
+                _ = scrub_timer.tick() => self.scrub_next(),
+                _ = relay_provider_timer.tick(), if self.provides_relay => self.provide_relay(),
+                _ = keep_alive_timer.tick() => self.ping_held_peers(),
+                _ = partition_timer.tick(), if self.partition.is_some() => self.check_partition(),
+                _ = budget_timer.tick(), if self.traffic.budget().limit().is_some() => self.check_budget(),
+                _ = topic_health_timer.tick() => self.check_topic_health(),
+                _ = rendezvous_timer.tick(), if self.swarm.behaviour().rendezvous.is_client() => self.rendezvous_tick(),
+                _ = peer_exchange_timer.tick(), if self.peer_directory.is_none() => self.peer_exchange_tick(),
+                _ = compaction_timer.tick(), if self.compact => {
+                    let report = self.quotas.compact(&mut *self.store);
+                    self.author_quotas.recount(&*self.store);
+                    if report.removed_updates > 0 {
+                        tracing::info!("Compacted {} updates ({} bytes)", report.removed_updates, report.reclaimed_bytes);
+                        self.emit(NodeEvent::Compacted {
+                            removed_updates: report.removed_updates,
+                            reclaimed_bytes: report.reclaimed_bytes,
+                        });
+                    }
+                }
+            }
+        };
+        let Some((mode, reply)) = shutdown else {
+            self.stop();
+            return;
+        };
+        let report = self.shut_down(mode).await;
+        self.emit(NodeEvent::ShutdownComplete { report });
+        let _ = reply.send(report);
+    }
+
+    /// Withdraw what only a running node should announce and persist what it learned.
+    fn stop(&mut self) {
+        self.flush_debounced();
+        if self.provides_relay {
+            // Copies held by other peers expire on their own
+            self.swarm.behaviour_mut().kademlia.stop_providing(&crate::behaviour::relay_provider_key());
+        }
+        self.save_address_book();
+    }
+
+    /// Stop taking commands and go through `mode`, see [`Node::shutdown`].
+    async fn shut_down(&mut self, mode: ShutdownMode) -> ShutdownReport {
+        let started = Instant::now();
+        // A timeout too long to reach is no deadline at all
+        let deadline = match mode {
+            ShutdownMode::Immediate => Some(started),
+            ShutdownMode::Drain { timeout } => started.checked_add(timeout),
+        };
+        let mut report = ShutdownReport::default();
+        // Held by the debounce window before anything queued was published
+        report.published += self.flush_debounced();
+        // Later sends fail; what is queued already is still received below, after what
+        // the bandwidth budget held
+        self.cmd_receiver.close();
+        while let Ok(Some(cmd)) = self.cmd_receiver.try_next() {
+            self.deferred.push_back(cmd);
+        }
+        while let Some(cmd) = self.deferred.pop_front() {
+            match cmd {
+                // Dropping the reply fails the call with `Error::NodeStopped`
+                Command::Publish { .. }
+                | Command::PublishDocUpdate { .. }
+                | Command::CommitTransaction { .. }
+                | Command::PublishAnnouncement { .. }
+                    if deadline.is_some_and(|deadline| Instant::now() >= deadline) =>
+                {
+                    report.dropped += 1;
+                }
+                Command::Publish { data, reply } => {
+                    let res = self.publish_data(data);
+                    report.published += usize::from(res.is_ok());
+                    let _ = reply.send(res);
+                }
+                Command::PublishDocUpdate { update, options, reply } => {
+                    let res = self.publish_doc_update(update, options);
+                    report.published += usize::from(res.is_ok());
+                    let _ = reply.send(res);
+                }
+                Command::CommitTransaction { tx, reply } => {
+                    let res = self.commit_transaction(tx);
+                    report.published += usize::from(res.is_ok());
+                    let _ = reply.send(res);
+                }
+                // A "going down for maintenance" notice is typically the last thing sent
+                Command::PublishAnnouncement { announcement, reply } => {
+                    let res = self.publish_announcement(&announcement);
+                    report.published += usize::from(res.is_ok());
+                    let _ = reply.send(res);
+                }
+                // Nothing else is worth finishing on the way out
+                _ => {}
+            }
+        }
+        if matches!(mode, ShutdownMode::Drain { .. }) {
+            self.leave(deadline).await;
+        }
+        self.stop();
+        report.duration = started.elapsed();
+        report
+    }
+
+    /// Unsubscribe from our topics and close every connection, driving the swarm so both
+    /// reach our peers, until the connections are gone or `deadline`, if any, passes.
+    async fn leave(&mut self, deadline: Option<Instant>) {
+        let remaining = || deadline.map_or(Duration::MAX, |deadline| deadline.saturating_duration_since(Instant::now()));
+        let topics = [
+            self.docstore_config.topics.updates(),
+            self.docstore_config.topics.snapshots(),
+            self.docstore_config.topics.receipts(),
+        ];
+        for topic in &topics {
+            self.swarm.behaviour_mut().gossipsub.unsubscribe(topic);
+        }
+        let grace = SHUTDOWN_FLUSH_GRACE.min(remaining());
+        let _ = tokio::time::timeout(grace, async {
+            loop {
+                let event = self.swarm.select_next_some().await;
+                self.handle_swarm_event(event);
+            }
+        })
+        .await;
+
+        let peers: Vec<PeerId> = self.swarm.connected_peers().copied().collect();
+        for peer_id in peers {
+            let _ = self.swarm.disconnect_peer_id(peer_id);
+        }
+        let _ = tokio::time::timeout(remaining(), async {
+            while self.swarm.connected_peers().next().is_some() {
+                let event = self.swarm.select_next_some().await;
+                self.handle_swarm_event(event);
+            }
+        })
+        .await;
+    }
+
+    /// Apply an update to the local store, snapshotting the document if it crossed the
+    /// policy's update threshold. Returns the document's new version.
+    fn apply_update(&mut self, update: &DocUpdate) -> u64 {
+        let version = self.store.apply_update(update);
+        self.note_applied(update);
+        self.quotas.observe(&*self.store, &update.doc_id);
+        self.author_quotas.observe(&*self.store, &update.doc_id);
+        self.snapshot_if_due(&update.doc_id);
+        version
+    }
+
+    /// Apply a complete transaction to the local store in one write batch, then snapshot
+    /// the documents that crossed the policy's update threshold.
+    fn apply_transaction(&mut self, updates: &[DocUpdate]) {
+        self.store.apply_transaction(updates);
+        for update in updates {
+            self.note_applied(update);
+            self.quotas.observe(&*self.store, &update.doc_id);
+            self.author_quotas.observe(&*self.store, &update.doc_id);
+            self.snapshot_if_due(&update.doc_id);
+        }
+    }
+
+    /// Keep track of the newest update applied, by its stamp.
+    fn note_applied(&mut self, update: &DocUpdate) {
+        if let Some(stamp) = &update.stamp {
+            self.newest_update_ms = self.newest_update_ms.max(Some(stamp.hlc.wall_ms));
+        }
+    }
+
+    /// Count an update applied to `doc_id` and publish a snapshot of it if that crossed
+    /// the policy's update threshold.
+    fn snapshot_if_due(&mut self, doc_id: &str) {
+        if let Some(policy) = &self.snapshot_policy {
+            if self.snapshot_scheduler.record(doc_id, policy) {
+                self.publish_snapshot(doc_id);
+            }
+        }
+    }
+
+    fn publish_snapshot(&mut self, doc_id: &str) {
+        let Some(snapshot) = self.store.make_snapshot(doc_id) else {
+            return;
+        };
+        let res = docstore::encode_snapshot(&self.docstore_config, &snapshot).and_then(|chunks| {
+            for chunk in chunks {
+                self.publish(self.docstore_config.topics.snapshots(), chunk)?;
+            }
+            Ok(())
+        });
+        if let Err(e) = res {
+            tracing::debug!("Failed to publish snapshot of {} at v{}: {}", doc_id, snapshot.version, e);
+        }
+    }
+
+    fn save_address_book(&mut self) {
+        self.address_book.prune(unix_ms(), address_book::DEFAULT_MAX_AGE);
+        if let Some(path) = &self.address_book_path {
+            if let Err(e) = self.address_book.save(path) {
+                tracing::warn!("Failed to save address book {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    /// `peer_id`, or else this node if it keeps history, or else the best-ranked connected
+    /// peer serving it.
+    fn history_peer(&self, peer_id: Option<PeerId>) -> Result<PeerId, Error> {
+        match peer_id {
+            Some(peer_id) => Ok(peer_id),
+            None if self.serves_history => Ok(*self.swarm.local_peer_id()),
+            None => {
+                let serving = self.peer_infos.supporting(doc_history::HISTORY_PROTOCOL);
+                self.reputation.rank(serving, Instant::now()).first().copied().ok_or(Error::NoHistoryPeer)
+            }
+        }
+    }
+
+    fn readiness(&self) -> NodeReadiness {
+        NodeReadiness {
+            connected: self.swarm.network_info().num_peers() > 0,
+            mesh: !self.docstore_mesh_empty,
+            dht_bootstrap: self.dht_bootstrap,
+        }
+    }
+
+    /// Answer everyone waiting for readiness, if the node is ready.
+    fn notify_ready(&mut self) {
+        let readiness = self.readiness();
+        if readiness.is_ready() {
+            for reply in self.ready_waiters.drain(..) {
+                let _ = reply.send(readiness);
+            }
+        }
+    }
+
+    fn check_docstore_mesh(&mut self) {
+        let topic = self.docstore_config.topics.updates().hash();
+        let empty = self.swarm.behaviour().gossipsub.mesh_peers(&topic).next().is_none();
+        if empty && !self.docstore_mesh_empty {
+            tracing::warn!("Mesh for {} is empty; publishes will fail until peers graft", topic);
+            self.emit(NodeEvent::MeshEmpty { topic: topic.to_string() });
+        }
+        self.docstore_mesh_empty = empty;
+    }
+
+    /// Resubscribe to topics that have been without mesh peers for too long, and report
+    /// the ones that recovered. Explicit peers subscribed to a topic count like mesh peers.
+    fn check_topic_health(&mut self) {
+        let connected = self.swarm.connected_peers().next().is_some();
+        let gossipsub = &self.swarm.behaviour().gossipsub;
+        let changes = self.topic_health.check(unix_ms(), connected, |topic, explicit| {
+            gossipsub.mesh_peers(topic).next().is_some()
+                || gossipsub.all_peers().any(|(peer, topics)| explicit.contains(peer) && topics.contains(&topic))
+        });
+        for change in changes {
+            match change {
+                TopicChange::Unhealthy { topic, empty_since_ms, attempt } => {
+                    tracing::warn!("{} has had no mesh peers since {}; resubscribing (attempt {})", topic, empty_since_ms, attempt);
+                    self.swarm.behaviour_mut().gossipsub.unsubscribe(&gossipsub::IdentTopic::new(topic.as_str()));
+                    self.emit(NodeEvent::TopicUnhealthy { topic: topic.to_string(), empty_since_ms, attempt });
+                }
+                TopicChange::Recovered { topic, duration, attempts, .. } => {
+                    tracing::info!("{} has mesh peers again after {:?}", topic, duration);
+                    self.emit(NodeEvent::TopicRecovered { topic: topic.to_string(), duration, attempts });
+                }
+            }
+        }
+    }
+
+    /// Second half of a resubscribe cycle, a heartbeat after unsubscribing.
+    fn resubscribe_topics(&mut self) {
+        for topic in self.topic_health.take_resubscribes(unix_ms()) {
+            let topic = gossipsub::IdentTopic::new(topic.as_str());
+            if let Err(e) = self.swarm.behaviour_mut().gossipsub.subscribe(&topic) {
+                tracing::warn!("Resubscribing to {} failed: {}", topic, e);
+            }
+        }
+    }
+
+    fn add_explicit_peer(&mut self, peer_id: PeerId) {
+        self.swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+        self.topic_health.add_explicit(peer_id);
+    }
+
+    fn remove_explicit_peer(&mut self, peer_id: &PeerId) {
+        self.swarm.behaviour_mut().gossipsub.remove_explicit_peer(peer_id);
+        self.topic_health.remove_explicit(peer_id);
+    }
+
+    /// Publish on the docstore gossipsub behaviour, counting the message as outgoing traffic.
+    fn publish(&mut self, topic: gossipsub::IdentTopic, data: Vec<u8>) -> Result<Published, Error> {
+        Ok(self.traffic.publish(&mut self.swarm.behaviour_mut().gossipsub, topic, data)?)
+    }
+
+    /// Stamp, store and hold a local update for the debounce window, see
+    /// [`Node::set_publish_debounce`]. `reply` is answered when it goes out.
+    fn debounce(&mut self, mut update: DocUpdate, reply: oneshot::Sender<Result<Published, Error>>) {
+        let admitted = self
+            .docstore_config
+            .check_update_size(update.payload.len())
+            .and_then(|()| self.admit(std::slice::from_ref(&update)));
+        if let Err(e) = admitted {
+            let _ = reply.send(Err(e));
+            return;
+        }
+        if update.stamp.is_none() {
+            update.stamp = Some(Stamp::next(self.pipeline.hlc_mut(), &self.store.clock(&update.doc_id)));
+        }
+        // Stored now, so the next update builds on this one's stamp
+        self.apply_update(&update);
+        self.order(&update, Origin::Live, None);
+        self.debounced.push((update.doc_id.clone(), reply));
+        if self.debouncer.push(update) {
+            // A window too long to reach only ends by the other flushes
+            self.flush_at = self.debouncer.window().and_then(|window| Instant::now().checked_add(window));
+        }
+    }
+
+    /// Publish what the debouncer holds, one envelope per document, and answer each held
+    /// call with the envelope that carried its update. Returns how many went out.
+    fn flush_debounced(&mut self) -> usize {
+        self.flush_at = None;
+        if self.debouncer.is_empty() {
+            return 0;
+        }
+        let codec = self.docstore_config.codec();
+        let envelopes = docstore::coalesce(self.debouncer.take(), self.docstore_config.max_update_size);
+        let mut replies = std::mem::take(&mut self.debounced);
+        let mut sent = 0;
+        for envelope in envelopes {
+            let (doc_id, count) = match &envelope {
+                Envelope::Update(update) => (update.doc_id.clone(), 1),
+                Envelope::Batch { doc_id, payloads, .. } => (doc_id.clone(), payloads.len()),
+                _ => unreachable!("coalesce only builds updates and batches"),
+            };
+            let results: Vec<Result<Published, Error>> =
+                match self.publish(self.docstore_config.topics.updates(), envelope.encode_with(&codec)) {
+                    Ok(published) => {
+                        sent += count;
+                        vec![published; count].into_iter().map(Ok).collect()
+                    }
+                    // Only one caller gets the typed error, the others its description
+                    Err(e) => {
+                        let description = e.to_string();
+                        std::iter::once(Err(e)).chain((1..count).map(|_| Err(Error::Transport(description.clone())))).collect()
+                    }
+                };
+            for res in results {
+                let Some(i) = replies.iter().position(|(held, _)| *held == doc_id) else {
+                    break;
+                };
+                let _ = replies.remove(i).1.send(res);
+            }
+        }
+        sent
+    }
+
+    fn publish_data(&mut self, data: Vec<u8>) -> Result<Published, Error> {
+        self.docstore_config
+            .check_update_size(data.len())
+            .and_then(|()| self.publish(self.docstore_config.topics.updates(), data))
+    }
+
+    /// Local updates are held to the room quotas like received ones. Author quotas are
+    /// for what others store here, so they leave them alone.
+    fn admit(&self, updates: &[DocUpdate]) -> Result<(), Error> {
+        Ok(self.quotas.admit(|doc_id| self.store.version(doc_id) == 0, updates)?)
+    }
+
+    /// Report updates a quota turned away, answering `ack_to` with a receipt if the
+    /// publisher asked for one. At most once every [`quota::NOTICE_INTERVAL`] per room,
+    /// tell the network a room is full; peers reject announcements from anyone they don't
+    /// list as an announcer, so that notice only goes out if we list ourselves. An author
+    /// over their quota is told in our mailbox, if we hold one, as often.
+    fn quota_exceeded(
+        &mut self,
+        peer_id: PeerId,
+        msg_id: &MessageId,
+        doc_id: &str,
+        reason: QuotaExceeded,
+        ack_to: Option<PeerId>,
+    ) {
+        if let Some(publisher) = ack_to {
+            let version = self.store.version(doc_id);
+            self.send_receipt(publisher, msg_id, doc_id, version, ReceiptOutcome::QuotaExceeded);
+        }
+        if reason.author().is_some() && self.author_quotas.notice_due(&peer_id, Instant::now()) {
+            let text = format!("Not storing further updates: {reason}");
+            let local_peer_id = *self.swarm.local_peer_id();
+            if let Some(mailbox) = &mut self.mailbox {
+                // Held for the mailbox's default TTL, so an author who went offline still learns why
+                if let Err(e) = mailbox.deposit(local_peer_id, &peer_id.to_string(), text.into_bytes(), 0, unix_ms()) {
+                    tracing::debug!("Failed to leave {} an over-quota notice: {}", peer_id, e);
+                }
+            }
+        }
+        let room = reason.room().map(str::to_string);
+        let announcer = self.docstore_config.announcers.contains(self.swarm.local_peer_id());
+        if let Some(room) = room.filter(|room| announcer && self.quotas.notice_due(room, Instant::now())) {
+            let now = unix_ms();
+            let expires_at_ms = now + quota::NOTICE_INTERVAL.as_millis() as u64;
+            let text = format!("Not storing further updates: {reason}");
+            let notice = NetworkAnnouncement::sign(
+                &self.identity,
+                AnnouncementKind::Notice,
+                Severity::Warning,
+                text,
+                now,
+                Some(expires_at_ms),
+            );
+            if let Err(e) = notice.map_err(Error::from).and_then(|notice| self.publish_announcement(&notice)) {
+                tracing::debug!("Failed to announce that room {} is over quota: {}", room, e);
+            }
+        }
+        self.emit(NodeEvent::QuotaExceeded { peer_id, reason });
+    }
+
+    /// Stamp (unless already stamped), publish and store a local update.
+    fn publish_doc_update(&mut self, mut update: DocUpdate, options: PublishOptions) -> Result<Published, Error> {
+        if let Some(expected_version) = options.expected_version {
+            cas::check(&update.doc_id, expected_version, self.store.version(&update.doc_id))?;
+        }
+        self.admit(std::slice::from_ref(&update))?;
+        if update.stamp.is_none() {
+            update.stamp = Some(Stamp::next(self.pipeline.hlc_mut(), &self.store.clock(&update.doc_id)));
+        }
+        let encoded = match options.expected_version {
+            Some(expected_version) => docstore::encode_cas_update(&self.docstore_config, update.clone(), expected_version),
+            None if options.ack_requested => docstore::encode_doc_update_requesting_ack(&self.docstore_config, update.clone()),
+            None => docstore::encode_doc_update(&self.docstore_config, update.clone()),
+        };
+        let res = encoded.and_then(|mut data| {
+            scope::set_scope(&mut data, options.scope);
+            self.publish(self.docstore_config.topics.updates(), data)
+        });
+        if let Ok(published) = &res {
+            if options.wants_receipts() {
+                self.acks.expect(published.msg_id.clone(), update.doc_id.clone(), Instant::now());
+            }
+            self.apply_update(&update);
+            self.order(&update, Origin::Live, None);
+        }
+        res
+    }
+
+    /// Answer an update `publisher` asked receipts for: directly if we are connected, on
+    /// the receipts topic otherwise.
+    fn send_receipt(
+        &mut self,
+        publisher: PeerId,
+        msg_id: &MessageId,
+        doc_id: &str,
+        applied_version: u64,
+        outcome: ReceiptOutcome,
+    ) {
+        let signed = UpdateReceipt::sign_with_outcome(&self.identity, &publisher, msg_id, doc_id, applied_version, outcome);
+        let receipt = match signed {
+            Ok(receipt) => receipt,
+            Err(e) => {
+                tracing::warn!("Failed to sign a receipt for {}: {}", msg_id, e);
+                return;
+            }
+        };
+        if self.swarm.is_connected(&publisher) {
+            let request_id = self.swarm.behaviour_mut().receipts.send_request(&publisher, receipt.clone());
+            self.pending_receipts.insert(request_id, receipt);
+        } else {
+            self.publish_receipt(receipt);
+        }
+    }
+
+    fn publish_receipt(&mut self, receipt: UpdateReceipt) {
+        if let Err(e) = self.publish(self.docstore_config.topics.receipts(), receipt.encode()) {
+            tracing::debug!("Failed to publish a receipt for {}: {}", receipt.doc_id, e);
+        }
+    }
+
+    /// A receipt arrived, directly from `from` or (with `None`) on the receipts topic.
+    /// Returns whether it acknowledged one of our updates for the first time.
+    fn receipt_received(&mut self, receipt: &UpdateReceipt, from: Option<PeerId>) -> bool {
+        if !receipt.is_for(self.swarm.local_peer_id()) {
+            return false;
+        }
+        let peer_id = match receipt.verify() {
+            Ok(signer) if from.is_none_or(|from| from == signer) => signer,
+            Ok(signer) => {
+                tracing::debug!("Ignoring a receipt signed by {} from {:?}", signer, from);
+                return false;
+            }
+            Err(e) => {
+                tracing::debug!("Ignoring receipt from {:?}: {}", from, e);
+                return false;
+            }
+        };
+        let Some(ack) = self.acks.received(receipt, peer_id) else {
+            return false;
+        };
+        self.emit(match ack.outcome {
+            ReceiptOutcome::Applied => NodeEvent::UpdateAcknowledged {
+                msg_id: ack.msg_id,
+                peer_id: ack.peer_id,
+                doc_id: ack.doc_id,
+                applied_version: ack.applied_version,
+            },
+            ReceiptOutcome::CasConflict => NodeEvent::CasConflict {
+                msg_id: ack.msg_id,
+                peer_id: ack.peer_id,
+                doc_id: ack.doc_id,
+                current_version: ack.applied_version,
+            },
+            ReceiptOutcome::QuotaExceeded => {
+                NodeEvent::QuotaRefused { msg_id: ack.msg_id, peer_id: ack.peer_id, doc_id: ack.doc_id }
+            }
+        });
+        true
+    }
+
+    fn handle_receipt_event(&mut self, event: request_response::Event<UpdateReceipt, bool>) {
+        match event {
+            request_response::Event::Message {
+                peer,
+                message: request_response::Message::Request { request, channel, .. },
+                ..
+            } => {
+                let awaited = self.receipt_received(&request, Some(peer));
+                if self.swarm.behaviour_mut().receipts.send_response(channel, awaited).is_err() {
+                    tracing::debug!("Receipt sender {} went away before the response", peer);
+                }
+            }
+            request_response::Event::Message {
+                message: request_response::Message::Response { request_id, .. }, ..
+            } => {
+                self.pending_receipts.remove(&request_id);
+            }
+            request_response::Event::OutboundFailure { peer, request_id, error, .. } => {
+                self.quality.send_failed(&peer);
+                if let Some(receipt) = self.pending_receipts.remove(&request_id) {
+                    tracing::debug!("Sending a receipt to {} failed ({}), publishing it instead", peer, error);
+                    self.publish_receipt(receipt);
+                }
+            }
+            request_response::Event::InboundFailure { peer, error, .. } => {
+                tracing::debug!("Receipt from {} failed: {}", peer, error);
+            }
+            request_response::Event::ResponseSent { .. } => {}
+        }
+    }
+
+    fn publish_announcement(&mut self, announcement: &NetworkAnnouncement) -> Result<Published, Error> {
+        announcement.check(unix_ms())?;
+        self.publish(self.docstore_config.topics.announce(), announcement.encode())
+    }
+
+    /// Stamp the unstamped updates of `tx`, publish its parts and, once all went out,
+    /// store it.
+    fn commit_transaction(&mut self, mut tx: Transaction) -> Result<Vec<Published>, Error> {
+        self.admit(&tx.updates)?;
+        // Later updates to a document build on the stamps of the earlier ones
+        let mut clocks: HashMap<String, VectorClock> = HashMap::new();
+        for update in &mut tx.updates {
+            let clock = clocks.entry(update.doc_id.clone()).or_insert_with(|| self.store.clock(&update.doc_id));
+            let stamp = update.stamp.get_or_insert_with(|| Stamp::next(self.pipeline.hlc_mut(), clock));
+            clock.merge(&stamp.clock);
+        }
+        let published = docstore::encode_transaction(&self.docstore_config, &tx)?
+            .into_iter()
+            .map(|data| self.publish(self.docstore_config.topics.updates(), data))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.apply_transaction(&tx.updates);
+        for update in &tx.updates {
+            self.order(update, Origin::Live, None);
+        }
+        Ok(published)
+    }
+
+    /// Put an update applied to the store in order for the watchers of its document, if
+    /// it has any. `source` is the peer it came from.
+    fn order(&mut self, update: &DocUpdate, origin: Origin, source: Option<PeerId>) {
+        if self.ordering.is_watched(&update.doc_id) {
+            let outputs = self.ordering.push(update.clone(), origin, Instant::now());
+            self.deliver_ordered(outputs, source);
+        }
+    }
+
+    fn deliver_ordered(&mut self, outputs: Vec<Ordered>, source: Option<PeerId>) {
+        for output in outputs {
+            match output {
+                Ordered::Deliver(ordered) => {
+                    let doc_id = ordered.update.doc_id.clone();
+                    let Some(watchers) = self.document_watchers.get_mut(&doc_id) else { continue };
+                    watchers.retain(|watcher| watcher.unbounded_send(ordered.clone()).is_ok());
+                    if watchers.is_empty() {
+                        // Every `DocumentWatch` was dropped
+                        self.document_watchers.remove(&doc_id);
+                        self.ordering.unwatch(&doc_id);
+                    }
+                }
+                Ordered::GapOpened { doc_id, since_ms } => self.request_gap(doc_id, since_ms, source),
+                Ordered::GapAbandoned { doc_id, missing } => {
+                    tracing::info!("Gave up on {} missing updates of {}", missing, doc_id);
+                    self.emit(NodeEvent::GapAbandoned { doc_id, missing });
+                }
+            }
+        }
+    }
+
+    /// Re-request `doc_id`'s updates since `since_ms`, from `source` if it serves history
+    /// (it evidently has them), from the best-ranked peer that does otherwise.
+    fn request_gap(&mut self, doc_id: String, since_ms: u64, source: Option<PeerId>) {
+        if self.traffic.budget().is_exhausted() {
+            self.deferred_gaps.push((doc_id, since_ms, source));
+            return;
+        }
+        let serving = self.peer_infos.supporting(doc_history::HISTORY_PROTOCOL);
+        let peer_id = match source.filter(|source| serving.contains(source)) {
+            Some(source) => source,
+            None => match self.reputation.rank(serving, Instant::now()).first() {
+                Some(peer_id) => *peer_id,
+                None => {
+                    tracing::debug!("No peer serving history to fill the gap in {}", doc_id);
+                    return;
+                }
+            },
+        };
+        let request = HistoryOptions { from: HistoryFrom::Time(since_ms), ..Default::default() }.request(doc_id.clone());
+        let id = self.swarm.behaviour_mut().history.send_request(&peer_id, request);
+        self.pending_gap_fills.insert(id, doc_id);
+    }
+
+    /// Apply the updates a gap re-request brought back, and deliver them.
+    fn gap_filled(&mut self, doc_id: String, result: Result<(PeerId, HistoryPage), Error>) {
+        let (peer_id, page) = match result {
+            Ok(answer) => answer,
+            Err(e) => {
+                // The held updates go out when the gap times out
+                tracing::debug!("Re-requesting a gap in {} failed: {}", doc_id, e);
+                return;
+            }
+        };
+        for stored in page.updates {
+            // Unstamped updates have no place in the order, so they cannot fill a gap
+            let Some(stamp) = stored.stamp else { continue };
+            let applied = self
+                .store
+                .log(&doc_id)
+                .iter()
+                .any(|u| u.stamp.as_ref().is_some_and(|s| s.hlc == stamp.hlc) && u.payload == stored.payload);
+            if applied {
+                continue;
+            }
+            let update = DocUpdate { doc_id: doc_id.clone(), payload: stored.payload, stamp: Some(stamp) };
+            self.apply_update(&update);
+            self.order(&update, Origin::Replay, None);
+            self.emit(NodeEvent::DocUpdateReceived { peer_id, update });
+        }
+    }
+
+    fn emit(&self, event: NodeEvent) {
+        self.history.record(&event);
+        // The handle's own receiver only makes subscriptions, so skip the clone until one exists
+        if self.event_broadcast.receiver_count() > 1 {
+            let _ = self.event_broadcast.send(event.clone());
+        }
+        let _ = self.event_sender.unbounded_send(event);
+    }
+
+    fn handle_command(&mut self, cmd: Command) {
+        if cmd.waits_for_budget() && self.traffic.budget().is_exhausted() {
+            self.defer(cmd);
+            return;
+        }
+        match cmd {
+            // Anything held by the debounce window goes out first, keeping publish order
+            Command::Publish { data, reply } => {
+                self.flush_debounced();
+                let _ = reply.send(self.publish_data(data));
+            }
+            Command::PublishDocUpdate { update, options, reply }
+                if self.debouncer.window().is_some() && options == PublishOptions::default() =>
+            {
+                self.debounce(update, reply);
+            }
+            Command::PublishDocUpdate { update, options, reply } => {
+                self.flush_debounced();
+                let _ = reply.send(self.publish_doc_update(update, options));
+            }
+            Command::CommitTransaction { tx, reply } => {
+                self.flush_debounced();
+                let _ = reply.send(self.commit_transaction(tx));
+            }
+            Command::PublishAnnouncement { announcement, reply } => {
+                let _ = reply.send(self.publish_announcement(&announcement));
+            }
+            Command::GetDocument { doc_id, reply } => {
+                let doc = self.store.content(&doc_id).map(|c| (self.store.version(&doc_id), c));
+                let _ = reply.send(doc);
+            }
+            Command::Pin { doc_id, reply } => {
+                let added = self.store.pin(&doc_id);
+                if added {
+                    let key = crate::behaviour::doc_provider_key(&doc_id);
+                    if let Err(e) = self.start_providing(key.clone()) {
+                        tracing::warn!("Failed to provide pinned document {}: {}", doc_id, e);
+                    }
+                    self.announcements.provide(key);
+                }
+                let _ = reply.send(added);
+            }
+            Command::Unpin { doc_id, reply } => {
+                let removed = self.store.unpin(&doc_id);
+                if removed {
+                    let key = crate::behaviour::doc_provider_key(&doc_id);
+                    self.swarm.behaviour_mut().kademlia.stop_providing(&key);
+                    self.announcements.stop_providing(&key);
+                }
+                let _ = reply.send(removed);
+            }
+            Command::Pins { reply } => {
+                let _ = reply.send(self.store.pins());
+            }
+            Command::WatchDoc { doc_id, reply } => {
+                let _ = reply.send(self.keeper.open(keeper::doc_session(&doc_id)));
+            }
+            Command::UnwatchDoc { doc_id, reply } => {
+                let session = keeper::doc_session(&doc_id);
+                let watched = self.keeper.is_open(&session);
+                for peer_id in self.keeper.close(&session) {
+                    tracing::debug!("No longer keeping {} alive", peer_id);
+                }
+                let _ = reply.send(watched);
+            }
+            Command::WatchDocument { doc_id, watcher, reply } => {
+                self.ordering.watch(doc_id.clone(), self.store.clock(&doc_id));
+                self.document_watchers.entry(doc_id).or_default().push(watcher);
+                let _ = reply.send(());
+            }
+            Command::KeepalivePeers { reply } => {
+                let _ = reply.send(self.keeper.peers());
+            }
+            Command::RequestAudit { reply } => {
+                let _ = reply.send(self.audit.snapshot());
+            }
+            Command::TopicPeers { topic, reply } => {
+                let hash = gossipsub::TopicHash::from_raw(topic);
+                let _ = reply.send(docstore::topic_peers(&self.swarm.behaviour().gossipsub, &hash));
+            }
+            Command::MeshPeers { topic, reply } => {
+                let hash = gossipsub::TopicHash::from_raw(topic);
+                let _ = reply.send(docstore::mesh_peers(&self.swarm.behaviour().gossipsub, &hash));
+            }
+            Command::PeerInfo { peer_id, reply } => {
+                let _ = reply.send(self.peer_infos.get(&peer_id).cloned());
+            }
+            Command::PutRecord { key, value, ttl, reply } => match self.publish_record(key, value, ttl) {
+                Ok(query) => {
+                    self.pending_puts.insert(query, PendingPut::Caller(reply));
+                }
+                Err(e) => {
+                    let _ = reply.send(Err(e));
+                }
+            },
+            Command::PublishHeadPointer { doc_id, reply } => {
+                let Some(content) = self.store.content(&doc_id) else {
+                    let reason = format!("no document {doc_id:?} in the local store");
+                    let _ = reply.send(Err(Error::InvalidArgument { field: "doc_id".to_string(), reason }));
+                    return;
+                };
+                let version = self.store.version(&doc_id);
+                let pointer = match self.head_pointers.publish(&self.identity, &doc_id, version, &content, unix_ms()) {
+                    Ok(pointer) => pointer,
+                    Err(e) => {
+                        let _ = reply.send(Err(e.into()));
+                        return;
+                    }
+                };
+                let key = head_pointer_key(self.swarm.local_peer_id(), &doc_id);
+                match self.publish_record(key, pointer.encode(), None) {
+                    Ok(query) => {
+                        self.pending_puts.insert(query, PendingPut::HeadPointer { pointer, reply });
+                    }
+                    Err(e) => {
+                        let _ = reply.send(Err(e));
+                    }
+                }
+            }
+            Command::ResolveHead { author, doc_id, reply } => {
+                let query = self.swarm.behaviour_mut().kademlia.get_record(head_pointer_key(&author, &doc_id));
+                self.pending_resolves.insert(query, PendingResolve { author, doc_id, values: Vec::new(), reply });
+            }
+            Command::SetProfile { profile, reply } => {
+                let profile = match SignedProfile::sign(&self.identity, profile, unix_ms()) {
+                    Ok(profile) => profile,
+                    Err(e) => {
+                        let _ = reply.send(Err(e.into()));
+                        return;
+                    }
+                };
+                let local_peer_id = *self.swarm.local_peer_id();
+                self.profiles.insert(local_peer_id, Some(profile.clone()), unix_ms());
+                match self.publish_record(profile::profile_key(&local_peer_id), profile.encode(), None) {
+                    Ok(query) => {
+                        self.pending_puts.insert(query, PendingPut::Profile { profile, reply });
+                    }
+                    Err(e) => {
+                        let _ = reply.send(Err(e));
+                    }
+                }
+            }
+            Command::ResolveProfile { peer_id, reply } => {
+                if let Some(cached) = self.profiles.get(&peer_id, unix_ms()) {
+                    let _ = reply.send(Ok(cached));
+                    return;
+                }
+                let query = self.swarm.behaviour_mut().kademlia.get_record(profile::profile_key(&peer_id));
+                self.pending_profiles.insert(query, PendingProfile { peer_id, values: Vec::new(), reply });
+            }
+            Command::ForgetRecord { key, reply } => {
+                self.announcements.forget_record(&key);
+                let _ = reply.send(self.published.forget(&key));
+            }
+            Command::FindPeer { peer_id, options, reply } => {
+                let count = std::num::NonZeroUsize::new(options.num_results).unwrap_or(std::num::NonZeroUsize::MIN);
+                let query = self.swarm.behaviour_mut().kademlia.get_n_closest_peers(peer_id, count);
+                self.pending_finds.insert(query, PendingFind { target: peer_id, dial: options.dial, reply });
+            }
+            Command::FindPeerLocal { peer_id, reply } => {
+                let found = FoundPeer::new(peer_id, self.known_addresses(&peer_id), is_tcp_dialable);
+                let _ = reply.send((!found.addrs.is_empty()).then_some(found));
+            }
+            Command::DhtSummary { reply } => {
+                let _ = reply.send(self.dht_summary);
+            }
+            Command::DhtStoreStats { reply } => {
+                let _ = reply.send(self.dht_store.stats(self.swarm.behaviour_mut().kademlia.store_mut()));
+            }
+            Command::VerifyStore { reply } => {
+                let _ = reply.send(self.verify_store());
+            }
+            Command::ScrubStats { reply } => {
+                let _ = reply.send(self.scrub.stats());
+            }
+            Command::RoomUsage { reply } => {
+                let _ = reply.send(self.quotas.report());
+            }
+            Command::SetRoomQuota { room, quota, reply } => {
+                let _ = reply.send(self.quotas.set(room, quota));
+            }
+            Command::AuthorUsage { reply } => {
+                let _ = reply.send(self.author_quotas.report(unix_ms()));
+            }
+            Command::SetAuthorQuota { peer_id, quota, reply } => {
+                let _ = reply.send(self.author_quotas.set(peer_id, quota));
+            }
+            Command::Replication { reply } => {
+                let _ = reply.send(ReplicationStatus::new(self.replica, self.newest_update_ms, unix_ms()));
+            }
+            Command::ExternalAddrs { reply } => {
+                let _ = reply.send(self.external_addrs.clone());
+            }
+            Command::DiscoverRelays { reply } => {
+                let query = self.swarm.behaviour_mut().kademlia.get_providers(crate::behaviour::relay_provider_key());
+                let lookup = RelayLookup::new(DEFAULT_RELAYS_TO_DIAL);
+                self.pending_relay_lookups.insert(query, PendingRelayLookup { lookup, reply });
+            }
+            Command::Readiness { reply } => {
+                let _ = reply.send(self.readiness());
+            }
+            Command::History { peer_id, request, reply } => {
+                if let Err(reason) = request.validate() {
+                    let _ = reply.send(Err(Error::HistoryRejected { reason }));
+                    return;
+                }
+                let peer_id = match self.history_peer(peer_id) {
+                    Ok(peer_id) => peer_id,
+                    Err(e) => {
+                        let _ = reply.send(Err(e));
+                        return;
+                    }
+                };
+                if peer_id == *self.swarm.local_peer_id() {
+                    let response = doc_history::respond(&*self.store, &request, &mut self.state_cache);
+                    let _ = reply.send(history_result(peer_id, response));
+                    return;
+                }
+                let id = self.swarm.behaviour_mut().history.send_request(&peer_id, request);
+                self.pending_history.insert(id, reply);
+            }
+            Command::DocumentAt { peer_id, request, reply } => {
+                let peer_id = match self.history_peer(peer_id) {
+                    Ok(peer_id) => peer_id,
+                    Err(e) => {
+                        let _ = reply.send(Err(e));
+                        return;
+                    }
+                };
+                if peer_id == *self.swarm.local_peer_id() {
+                    let response = doc_history::respond(&*self.store, &request, &mut self.state_cache);
+                    let _ = reply.send(doc_history::state_result(response).map(|state| (peer_id, state)));
+                    return;
+                }
+                let id = self.swarm.behaviour_mut().history.send_request(&peer_id, request);
+                self.pending_document_at.insert(id, reply);
+            }
+            Command::FetchDocument { doc_id, peer_id, reply } => self.start_fetch(doc_id, peer_id, reply),
+            Command::SendToMailbox { holder, recipient, blob, ttl, reply } => {
+                // 0 asks for the holder's default
+                let ttl_ms = ttl.map_or(0, |ttl| (ttl.as_millis() as u64).max(1));
+                let request = MailboxRequest::Deposit { recipient: recipient.to_string(), blob, ttl_ms };
+                self.send_mailbox_request(holder, request, PendingMailbox::Deposit(reply));
+            }
+            Command::CheckMailbox { holder, reply } => {
+                self.send_mailbox_request(holder, MailboxRequest::Fetch, PendingMailbox::Fetch(Some(reply)));
+            }
+            Command::RoomMembers { peer_id, request, reply } => {
+                let local = *self.swarm.local_peer_id();
+                let peer_id = match peer_id {
+                    Some(peer_id) => peer_id,
+                    None if self.members.is_some() => local,
+                    None => {
+                        let serving = self.peer_infos.supporting(members::MEMBERS_PROTOCOL);
+                        match self.reputation.rank(serving, Instant::now()).first() {
+                            Some(peer_id) => *peer_id,
+                            None => {
+                                let _ = reply.send(Err(Error::NoMembersPeer));
+                                return;
+                            }
+                        }
+                    }
+                };
+                if peer_id == local {
+                    let result = match &mut self.members {
+                        Some(table) => members::members_result(table.respond(local, &request, unix_ms())),
+                        None => Err(Error::NoMembersPeer),
+                    };
+                    let _ = reply.send(result.map(|page| (local, page)));
+                    return;
+                }
+                let id = self.swarm.behaviour_mut().members.send_request(&peer_id, request);
+                self.pending_members.insert(id, reply);
+            }
+            Command::DiscoverPeers { reply } => {
+                if !self.swarm.behaviour().rendezvous.is_client() {
+                    let _ = reply.send(Err(Error::RendezvousDisabled));
+                    return;
+                }
+                for point in self.rendezvous.points() {
+                    self.discover_at(point);
+                }
+                self.rendezvous_waiters.push(reply);
+                self.answer_rendezvous_waiters();
+            }
+            Command::WaitReady { reply } => {
+                let readiness = self.readiness();
+                if readiness.is_ready() {
+                    let _ = reply.send(readiness);
+                } else {
+                    self.ready_waiters.push(reply);
+                }
+            }
+            Command::Dial { addr, options, reply } => {
+                let target = addr.iter().find_map(|p| match p {
+                    libp2p::multiaddr::Protocol::P2p(peer_id) => Some(peer_id),
+                    _ => None,
+                });
+                if let Some(peer_id) = target.filter(|p| self.bans.is_banned(p, Instant::now())) {
+                    self.emit(NodeEvent::BannedPeerRejected { peer_id });
+                    let _ = reply.send(Err(Error::PeerBanned { peer_id }));
+                    return;
+                }
+                let res = start_dial(&mut self.swarm, &mut self.pending_dials, addr, options);
+                let _ = reply.send(res);
+            }
+            Command::DisconnectPeer { peer_id } => self.disconnect(peer_id),
+            Command::RemovePeerAddress { peer_id, addr } => self.remove_address(peer_id, addr, RemovalReason::Requested),
+            Command::RemovePeer { peer_id } => {
+                self.swarm.behaviour_mut().kademlia.remove_peer(&peer_id);
+                self.address_book.remove_peer(&peer_id);
+                self.emit(NodeEvent::PeerRemoved { peer_id, reason: RemovalReason::Requested });
+                self.refresh_dht_summary();
+            }
+            Command::BanPeer { peer_id, duration } => {
+                tracing::info!("Banning peer {} for {:?}", peer_id, duration);
+                self.bans.ban(peer_id, duration, Instant::now());
+                self.important.stop(&peer_id);
+                self.swarm.behaviour_mut().gossipsub.blacklist_peer(&peer_id);
+                self.disconnect(peer_id);
+            }
+            Command::MarkImportant { peer_id } => self.important.mark(peer_id, None),
+            Command::UnmarkImportant { peer_id } => {
+                self.important.unmark(&peer_id);
+            }
+            Command::RegisterSchema { prefix, since_version, validator } => {
+                self.pipeline.schemas_mut().register_since(prefix, since_version, validator);
+            }
+            Command::UnregisterSchema { prefix, reply } => {
+                let _ = reply.send(self.pipeline.schemas_mut().unregister(&prefix));
+            }
+            Command::ExpectPeer { peer_id } => self.expect_peer(peer_id),
+            Command::RemoveExpectedPeer { peer_id, reply } => {
+                let removed = self.partition.as_mut().is_some_and(|watch| watch.remove(&peer_id));
+                if removed {
+                    tracing::info!("No longer expecting {}", peer_id);
+                    self.check_partition();
+                }
+                let _ = reply.send(removed);
+            }
+            Command::PartitionStatus { reply } => {
+                let _ = reply.send(self.partition.as_ref().map(PartitionWatch::status).unwrap_or_default());
+            }
+            Command::FederationStatus { reply } => {
+                let clients = self.swarm.connected_peers().filter(|peer| !self.federation.is_member(peer)).count();
+                let _ = reply.send(self.federation.status(clients));
+            }
+            Command::SetBandwidthBudget { bytes_per_interval } => {
+                self.traffic.budget().set_limit(bytes_per_interval, unix_ms());
+                self.check_budget();
+            }
+            Command::SetPublishDebounce { window } => {
+                self.debouncer.set_window(window);
+                if self.debouncer.window().is_none() {
+                    self.flush_debounced();
+                }
+            }
+            Command::SetMergePolicy { doc_id, policy } => self.store.set_merge_policy(&doc_id, policy),
+            #[cfg(test)]
+            Command::DropSubscription { topic } => {
+                self.swarm.behaviour_mut().gossipsub.unsubscribe(&topic);
+            }
+        }
+    }
+
+    /// Put a record of ours, republished before it expires and renewed after an outage.
+    /// `ttl` defaults to the configured record TTL.
+    fn publish_record(&mut self, key: RecordKey, value: Vec<u8>, ttl: Option<Duration>) -> Result<QueryId, Error> {
+        let ttl = ttl.or(self.record_ttl);
+        if let Some(ttl) = ttl {
+            self.published.insert(key.clone(), value.clone(), ttl, unix_ms())?;
+        }
+        match self.put_record(key.clone(), value.clone(), ttl) {
+            Ok(query) => {
+                self.announcements.record(key, value, ttl);
+                Ok(query)
+            }
+            Err(e) => {
+                self.published.forget(&key);
+                Err(e)
+            }
+        }
+    }
+
+    fn put_record(&mut self, key: RecordKey, value: Vec<u8>, ttl: Option<Duration>) -> Result<QueryId, Error> {
+        let mut record = kad::Record::new(key, value);
+        // A TTL too long to reckon with never runs out
+        record.expires = ttl.and_then(|ttl| Instant::now().checked_add(ttl));
+        let put = self.swarm.behaviour_mut().kademlia.put_record(record, kad::Quorum::One);
+        self.dht_store.put(put).map_err(|full| {
+            self.report_store_full(full);
+            Error::Dht(full.to_string())
+        })
+    }
+
+    fn start_providing(&mut self, key: RecordKey) -> Result<QueryId, StoreFull> {
+        let provided = self.swarm.behaviour_mut().kademlia.start_providing(key.clone());
+        self.dht_store.provide(&key, provided).inspect_err(|full| self.report_store_full(*full))
+    }
+
+    fn report_store_full(&mut self, full: StoreFull) {
+        if full.newly {
+            tracing::warn!("{}; further refusals are only counted", full);
+            self.emit(NodeEvent::DhtStoreFull { kind: full.kind });
+        }
+    }
+
+    fn republish_records(&mut self) {
+        for due in self.published.take_due(unix_ms()) {
+            match self.put_record(due.key.clone(), due.value, Some(due.ttl)) {
+                Ok(query) => {
+                    self.pending_puts.insert(query, PendingPut::Republish(due.key));
+                }
+                Err(e) => self.emit(NodeEvent::RecordRepublishFailed { key: due.key, error: e.to_string() }),
+            }
+        }
+    }
+
+    /// Kademlia ignores expired records when asked for them but never reports or drops
+    /// them; do both here.
+    fn expire_records(&mut self) {
+        let now = Instant::now();
+        let store = self.swarm.behaviour_mut().kademlia.store_mut();
+        let expired: Vec<RecordKey> = store.records().filter(|r| r.is_expired(now)).map(|r| r.key.clone()).collect();
+        for key in expired {
+            self.swarm.behaviour_mut().kademlia.store_mut().remove(&key);
+            self.emit(NodeEvent::RecordExpired { key });
+        }
+    }
+
+    /// Addresses of `peer_id` from the routing table, the address book and identify.
+    fn known_addresses(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        let mut addrs = Vec::new();
+        for bucket in self.swarm.behaviour_mut().kademlia.kbuckets() {
+            for entry in bucket.iter() {
+                if entry.node.key.preimage() == peer_id {
+                    addrs.extend(entry.node.value.iter().cloned());
+                }
+            }
+        }
+        addrs.extend(self.address_book.addresses(peer_id));
+        if let Some(info) = self.peer_infos.get(peer_id) {
+            addrs.extend(info.listen_addrs.iter().cloned());
+        }
+        addrs
+    }
+
+    /// Dial `peer_id` unless connected or already dialing, trying `addrs` best first (see
+    /// [`dial::plan`]) and, with `through_behaviour`, then what the behaviours know.
+    fn dial_peer(&mut self, peer_id: PeerId, addrs: Vec<Multiaddr>, through_behaviour: bool) -> Result<(), DialError> {
+        let now = Instant::now();
+        let (book, reputation) = (&self.address_book, &self.reputation);
+        let addrs = dial::plan(addrs, |addr| book.record(&peer_id, addr), |relay| reputation.score(relay, now));
+        let opts = DialOpts::peer_id(peer_id).addresses(addrs.clone()).condition(PeerCondition::DisconnectedAndNotDialing);
+        let opts = if through_behaviour { opts.extend_addresses_through_behaviour() } else { opts };
+        let opts = match self.dial_concurrency.for_peer(&peer_id) {
+            Some(factor) => opts.override_dial_concurrency_factor(factor),
+            None => opts,
+        };
+        self.swarm.dial(opts.build())?;
+        self.emit(NodeEvent::DialPlan { peer_id, addrs });
+        Ok(())
+    }
+
+    fn finish_resolve(&mut self, id: QueryId) {
+        if let Some(PendingProfile { peer_id, values, reply }) = self.pending_profiles.remove(&id) {
+            let resolved = self.profiles.resolve(&peer_id, values.iter().map(Vec::as_slice), unix_ms());
+            let _ = reply.send(resolved.map_err(Error::from));
+            return;
+        }
+        let Some(PendingResolve { author, doc_id, values, reply }) = self.pending_resolves.remove(&id) else { return };
+        let resolved = self.head_pointers.resolve(&author, &doc_id, values.iter().map(Vec::as_slice));
+        let _ = reply.send(resolved.map_err(Error::from));
+    }
+
+    fn finish_find(&mut self, id: QueryId, peers: Vec<kad::PeerInfo>) {
+        let Some(pending) = self.pending_finds.remove(&id) else { return };
+        let mut found: Vec<FoundPeer> =
+            peers.into_iter().map(|p| FoundPeer::new(p.peer_id, p.addrs, is_tcp_dialable)).collect();
+        // The target is in the results only if some peer knew it
+        if let Some(pos) = found.iter().position(|p| p.peer_id == pending.target) {
+            let target = found.remove(pos);
+            if pending.dial && target.is_dialable() && !self.swarm.is_connected(&target.peer_id) {
+                if let Err(e) = self.dial_peer(target.peer_id, target.dialable.clone(), false) {
+                    tracing::debug!("Dialing {} after lookup failed: {}", target.peer_id, e);
+                }
+            }
+            found.insert(0, target);
+        }
+        let _ = pending.reply.send(Ok(found));
+    }
+
+    /// Check the next document of the scrub pass.
+    fn scrub_next(&mut self) {
+        let Some(doc_id) = self.scrub.next(self.store.doc_ids()) else { return };
+        let result = self.store.verify(&doc_id);
+        self.scrub.checked(&doc_id, &result);
+        if let Err(corruption) = result {
+            self.store_corrupted(doc_id, corruption);
+        }
+    }
+
+    /// Check every stored document, leaving the scrub pass where it is.
+    fn verify_store(&mut self) -> ScrubReport {
+        let mut report = ScrubReport::default();
+        for doc_id in self.store.doc_ids() {
+            let result = self.store.verify(&doc_id);
+            self.scrub.counted(&result);
+            report.verified += 1;
+            if let Err(corruption) = result {
+                report.corrupted.push((doc_id.clone(), corruption.clone()));
+                self.store_corrupted(doc_id, corruption);
+            }
+        }
+        report
+    }
+
+    /// Stop serving a corrupted document and look for a peer to fetch it from again.
+    fn store_corrupted(&mut self, doc_id: String, corruption: Corruption) {
+        tracing::error!("Stored document {} is corrupted ({}); quarantining it", doc_id, corruption);
+        self.store.quarantine(&doc_id);
+        self.state_cache.forget(&doc_id);
+        self.quotas.observe(&*self.store, &doc_id);
+        self.author_quotas.observe(&*self.store, &doc_id);
+        self.emit(NodeEvent::StoreCorruption { doc_id: doc_id.clone(), reason: corruption.to_string() });
+        let query = self.swarm.behaviour_mut().kademlia.get_providers(crate::behaviour::doc_provider_key(&doc_id));
+        self.pending_refetch_lookups.insert(query, doc_id);
+    }
+
+    /// Ask the first usable provider of a quarantined document for it, ending the lookup.
+    fn refetch_providers_found(&mut self, id: QueryId, providers: impl IntoIterator<Item = PeerId>) {
+        let now = Instant::now();
+        let Some(provider) = providers.into_iter().find(|peer| !self.bans.is_banned(peer, now)) else {
+            return;
+        };
+        let doc_id = self.pending_refetch_lookups.remove(&id).expect("checked by the caller");
+        if let Some(mut query) = self.swarm.behaviour_mut().kademlia.query_mut(&id) {
+            query.finish();
+        }
+        self.request_refetch(doc_id, provider);
+    }
+
+    fn refetch_from_history_peer(&mut self, doc_id: String) {
+        let serving = self.peer_infos.supporting(doc_history::HISTORY_PROTOCOL);
+        match self.reputation.rank(serving, Instant::now()).first() {
+            Some(peer_id) => self.request_refetch(doc_id, *peer_id),
+            None => self.emit(NodeEvent::StoreRepairFailed { doc_id, reason: "no peer provides the document".into() }),
+        }
+    }
+
+    /// Request the newest update of `doc_id`, i.e. its current content, from `peer_id`.
+    fn request_refetch(&mut self, doc_id: String, peer_id: PeerId) {
+        let request = HistoryOptions { from: HistoryFrom::Latest, ..Default::default() }.request(doc_id.clone());
+        let id = self.swarm.behaviour_mut().history.send_request(&peer_id, request);
+        self.pending_refetches.insert(id, doc_id);
+    }
+
+    fn refetched(&mut self, doc_id: String, result: Result<(PeerId, HistoryPage), Error>) {
+        let (peer_id, latest) = match result {
+            Ok((peer_id, page)) => (peer_id, page.updates.into_iter().last()),
+            Err(e) => {
+                self.emit(NodeEvent::StoreRepairFailed { doc_id, reason: e.to_string() });
+                return;
+            }
+        };
+        let Some(stored) = latest else {
+            self.emit(NodeEvent::StoreRepairFailed { doc_id, reason: format!("{peer_id} has no history of it") });
+            return;
+        };
+        self.apply_update(&DocUpdate { doc_id: doc_id.clone(), payload: stored.payload, stamp: stored.stamp });
+        let version = self.store.version(&doc_id);
+        tracing::info!("Restored quarantined document {} from {}", doc_id, peer_id);
+        self.emit(NodeEvent::StoreRepaired { doc_id, peer_id, version });
+    }
+
+    /// Dial the first few new providers a relay lookup turns up. Ones we are already
+    /// connected to are checked as soon as they are identified.
+    fn relay_providers_found(&mut self, id: QueryId, providers: impl IntoIterator<Item = PeerId>) {
+        let Some(pending) = self.pending_relay_lookups.get_mut(&id) else { return };
+        let local = *self.swarm.local_peer_id();
+        let now = Instant::now();
+        let candidates: Vec<PeerId> = providers
+            .into_iter()
+            .filter(|peer| !self.bans.is_banned(peer, now))
+            .filter(|peer| self.relay_discovery.found(&mut pending.lookup, *peer, &local))
+            .collect();
+        for peer in candidates {
+            if let Some(info) = self.peer_infos.get(&peer).cloned() {
+                self.check_relay(peer, &info);
+                continue;
+            }
+            if self.swarm.is_connected(&peer) {
+                continue;
+            }
+            let addrs = self.known_addresses(&peer);
+            if let Err(e) = self.dial_peer(peer, addrs, true) {
+                tracing::debug!("Dialing relay candidate {} failed: {}", peer, e);
+                self.relay_discovery.dial_failed(&peer);
+            }
+        }
+    }
+
+    /// Settle a relay candidate once identify tells what it serves.
+    fn check_relay(&mut self, peer_id: PeerId, info: &PeerInfo) {
+        match self.relay_discovery.identified(&peer_id, info) {
+            RelayCheck::Verified if !self.federation.is_relay(&peer_id, true) => {
+                tracing::warn!("{} serves the hop protocol but is not in our federation; treating it as a client", peer_id);
+            }
+            RelayCheck::Verified => {
+                tracing::info!("Discovered relay {}", peer_id);
+                self.important.mark(peer_id, None);
+                self.expect_peer(peer_id);
+                self.emit(NodeEvent::RelayDiscovered { peer_id });
+            }
+            RelayCheck::Rejected => {
+                tracing::warn!("{} is registered as a relay but doesn't serve the hop protocol", peer_id);
+            }
+            RelayCheck::NotACandidate => {}
+        }
+    }
+
+    /// Announce ourselves as a relay. Kademlia republishes the record by itself as well;
+    /// announcing again also reaches peers met since.
+    fn provide_relay(&mut self) {
+        if let Err(e) = self.start_providing(crate::behaviour::relay_provider_key()) {
+            tracing::warn!("Failed to announce the relay service: {}", e);
+        }
+        self.announcements.provide(crate::behaviour::relay_provider_key());
+    }
+
+    /// Announce everything again if a peer identifying itself ends a long outage. Records
+    /// we keep republishing are rescheduled rather than put twice.
+    fn renew_announcements(&mut self) {
+        let Some(announcements) = self.announcements.reconnected(Instant::now()) else { return };
+        tracing::info!("Back online after an outage, renewing {} DHT announcements", announcements.len());
+        for announcement in announcements {
+            let key = announcement.key().clone();
+            let query = match announcement {
+                Announcement::Provider(key) => self.start_providing(key).ok(),
+                Announcement::Record { key, value, ttl } => {
+                    if let Some(ttl) = ttl.filter(|_| self.published.contains(&key)) {
+                        let _ = self.published.insert(key.clone(), value.clone(), ttl, unix_ms());
+                    }
+                    self.put_record(key, value, ttl).ok()
+                }
+            };
+            self.announcements.issued(key, query);
+        }
+        if let Some(summary) = self.announcements.settle(None, true) {
+            self.emit(NodeEvent::AnnouncementsRenewed { renewed: summary.renewed, failed: summary.failed });
+        }
+    }
+
+    fn remove_address(&mut self, peer_id: PeerId, addr: Multiaddr, reason: RemovalReason) {
+        self.swarm.behaviour_mut().kademlia.remove_address(&peer_id, &addr);
+        self.address_book.remove_address(&peer_id, &addr);
+        self.emit(NodeEvent::AddressRemoved { peer_id, addr, reason });
+        self.refresh_dht_summary();
+    }
+
+    /// Start the dial retries that are due. Banned peers are not retried.
+    fn retry_dials(&mut self) {
+        let now = Instant::now();
+        for dial in self.pending_dials.take_due(now) {
+            let peer_id = crate::node::addrs::peer_id_of(&dial.addr);
+            if peer_id.is_some_and(|p| self.bans.is_banned(&p, now)) {
+                continue;
+            }
+            tracing::debug!("Retrying dial of {} (attempt {})", dial.addr, dial.attempts + 1);
+            let opts = DialOpts::from(dial.addr.clone());
+            let connection_id = opts.connection_id();
+            match self.swarm.dial(opts) {
+                Ok(()) => self.pending_dials.retried(connection_id, dial),
+                Err(e) => self.emit(NodeEvent::DialFailed {
+                    peer_id,
+                    addr: dial.addr,
+                    attempts: dial.attempts + 1,
+                    reason: DialFailure::from(&e),
+                }),
+            }
+        }
+    }
+
+    fn redial_important_peers(&mut self) {
+        let now = Instant::now();
+        for peer_id in self.important.take_due(now) {
+            if self.bans.is_banned(&peer_id, now) {
+                self.important.stop(&peer_id);
+                continue;
+            }
+            tracing::debug!("Redialing important peer {}", peer_id);
+            if let Err(e) = self.dial_peer(peer_id, self.important.addrs(&peer_id).to_vec(), true) {
+                tracing::debug!("Redialing {} failed: {}", peer_id, e);
+                self.important.dial_failed(&peer_id, now);
+            }
+        }
+    }
+
+    fn expect_peer(&mut self, peer_id: PeerId) {
+        let connected = self.swarm.is_connected(&peer_id);
+        if let Some(watch) = &mut self.partition {
+            watch.expect(peer_id, unix_ms());
+            if connected {
+                watch.connected(&peer_id);
+            }
+        }
+    }
+
+    /// Hold `cmd` until the bandwidth budget is available again. Publishes beyond
+    /// [`MAX_DEFERRED_PUBLISHES`] fail instead.
+    fn defer(&mut self, cmd: Command) {
+        let held = self.deferred.iter().filter(|held| held.is_publish()).count();
+        if !cmd.is_publish() || held < MAX_DEFERRED_PUBLISHES {
+            self.deferred.push_back(cmd);
+            return;
+        }
+        let full = Error::SuspendQueueFull { max: MAX_DEFERRED_PUBLISHES };
+        match cmd {
+            Command::Publish { reply, .. } | Command::PublishDocUpdate { reply, .. } => {
+                let _ = reply.send(Err(full));
+            }
+            Command::CommitTransaction { reply, .. } => {
+                let _ = reply.send(Err(full));
+            }
+            _ => {}
+        }
+    }
+
+    /// Report the bandwidth budget running out or coming back, and send what it held.
+    fn check_budget(&mut self) {
+        match self.traffic.budget().poll(unix_ms()) {
+            Some(BudgetChange::Exceeded { used, limit, resets_at_ms }) => {
+                tracing::info!("Bandwidth budget spent ({} of {} bytes), deferring traffic until {}", used, limit, resets_at_ms);
+                self.emit(NodeEvent::BudgetExceeded { used, limit, resets_at_ms });
+            }
+            Some(BudgetChange::Reset) => {
+                tracing::info!("Bandwidth budget available, sending {} deferred commands", self.deferred.len());
+                self.emit(NodeEvent::BudgetReset);
+                for cmd in std::mem::take(&mut self.deferred) {
+                    self.handle_command(cmd);
+                }
+                for (doc_id, since_ms, source) in std::mem::take(&mut self.deferred_gaps) {
+                    self.request_gap(doc_id, since_ms, source);
+                }
+                for doc_id in std::mem::take(&mut self.deferred_fetches) {
+                    self.request_chunk(doc_id);
+                }
+            }
+            None => {}
+        }
+    }
+
+    /// Report a partition that started or healed, and redial the missing peers hard while
+    /// it lasts.
+    fn check_partition(&mut self) {
+        let now = unix_ms();
+        let Some(watch) = &mut self.partition else {
+            return;
+        };
+        let clients = self.swarm.connected_peers().filter(|peer| !watch.is_expected(peer)).count();
+        match watch.check(now, clients) {
+            Some(PartitionChange::Detected { missing_peers, since_ms }) => {
+                tracing::warn!(
+                    "Possible network partition: {} expected peer(s) unreachable since {} while {} client(s) are connected: {:?}",
+                    missing_peers.len(),
+                    since_ms,
+                    clients,
+                    missing_peers
+                );
+                self.emit(NodeEvent::PossiblePartition { missing_peers, since_ms });
+            }
+            Some(PartitionChange::Resolved { peers, since_ms, duration }) => {
+                tracing::warn!("Network partition resolved after {:?}", duration);
+                self.emit(NodeEvent::PartitionResolved { peers, since_ms, duration });
+            }
+            None => {}
+        }
+        let Some(redial) = self.partition.as_mut().map(|watch| watch.take_redial(now)).filter(|peers| !peers.is_empty()) else {
+            return;
+        };
+        let instant = Instant::now();
+        for peer_id in redial {
+            if self.bans.is_banned(&peer_id, instant) {
+                continue;
+            }
+            if let Err(e) = self.dial_peer(peer_id, self.important.addrs(&peer_id).to_vec(), true) {
+                tracing::debug!("Redialing missing peer {} failed: {}", peer_id, e);
+            }
+        }
+        if let Err(e) = self.swarm.behaviour_mut().kademlia.bootstrap() {
+            tracing::debug!("Kademlia bootstrap could not start: {}", e);
+        }
+    }
+
+    fn disconnect(&mut self, peer_id: PeerId) {
+        let _ = self.swarm.disconnect_peer_id(peer_id);
+        self.swarm.behaviour_mut().kademlia.remove_peer(&peer_id);
+        self.refresh_dht_summary();
+    }
+
+    /// Kademlia reports peers entering the routing table but not leaving it, so recount
+    /// after every change we know of.
+    fn refresh_dht_summary(&mut self) {
+        let summary = DhtSummary::of(&mut self.swarm.behaviour_mut().kademlia);
+        if summary != self.dht_summary {
+            self.dht_summary = summary;
+            self.emit(NodeEvent::DhtSummaryChanged { summary });
+        }
+    }
+
+    fn apply_nat_actions(&mut self, actions: Vec<NatAction>) {
+        for action in actions {
+            match action {
+                NatAction::Advertise(addr) => {
+                    self.swarm.add_external_address(addr.clone());
+                    self.emit(NodeEvent::PortMapped { addr });
+                }
+                NatAction::Withdraw(addr) => self.swarm.remove_external_address(&addr),
+            }
+        }
+    }
+
+    /// Keep-alive pings to the connected peers of watched documents. Released peers get
+    /// none, so their connections idle out.
+    fn ping_held_peers(&mut self) {
+        for peer_id in self.keeper.peers() {
+            if self.swarm.is_connected(&peer_id) {
+                self.swarm.behaviour_mut().keep_alive.send_request(&peer_id, KeepAlive);
+            }
+        }
+    }
+
+    fn handle_history_event(&mut self, event: request_response::Event<HistoryRequest, HistoryResponse>) {
+        match event {
+            request_response::Event::Message {
+                peer,
+                message: request_response::Message::Request { request, channel, .. },
+                ..
+            } => {
+                // Only FullNodes accept inbound history requests. What we hold back is in
+                // the store already, so it goes out before the response shows it.
+                self.flush_debounced();
+                let (store, cache) = (&self.store, &mut self.state_cache);
+                let response = self.audit.handle::<audit::History>(peer, &request, unix_ms(), |request| {
+                    doc_history::respond(&**store, request, cache)
+                });
+                match &response {
+                    HistoryResponse::Rejected { reason } => {
+                        tracing::debug!("Rejected history request from {}: {}", peer, reason)
+                    }
+                    HistoryResponse::RateLimited { .. } => tracing::info!("Rate limiting history requests from {}", peer),
+                    HistoryResponse::Page(_) | HistoryResponse::State(_) | HistoryResponse::Unavailable { .. } => {}
+                }
+                if self.swarm.behaviour_mut().history.send_response(channel, response).is_err() {
+                    tracing::debug!("History requester {} went away before the response", peer);
+                }
+            }
+            request_response::Event::Message {
+                peer,
+                message: request_response::Message::Response { request_id, response },
+                ..
+            } => {
+                let signal = match response {
+                    HistoryResponse::RateLimited { .. } => PeerSignal::RateLimited,
+                    _ => PeerSignal::FetchSucceeded,
+                };
+                self.reputation.record(peer, signal, Instant::now());
+                if let Some(reply) = self.pending_history.remove(&request_id) {
+                    let _ = reply.send(history_result(peer, response));
+                } else if let Some(reply) = self.pending_document_at.remove(&request_id) {
+                    let _ = reply.send(doc_history::state_result(response).map(|state| (peer, state)));
+                } else if let Some(doc_id) = self.pending_refetches.remove(&request_id) {
+                    self.refetched(doc_id, history_result(peer, response));
+                } else if let Some(doc_id) = self.pending_gap_fills.remove(&request_id) {
+                    self.gap_filled(doc_id, history_result(peer, response));
+                }
+            }
+            request_response::Event::OutboundFailure { peer, request_id, error, .. } => {
+                self.reputation.record(peer, PeerSignal::FetchFailed, Instant::now());
+                self.quality.send_failed(&peer);
+                let error = Error::Transport(format!("history request to {peer} failed: {error}"));
+                if let Some(reply) = self.pending_history.remove(&request_id) {
+                    let _ = reply.send(Err(error));
+                } else if let Some(reply) = self.pending_document_at.remove(&request_id) {
+                    let _ = reply.send(Err(error));
+                } else if let Some(doc_id) = self.pending_refetches.remove(&request_id) {
+                    self.refetched(doc_id, Err(error));
+                } else if let Some(doc_id) = self.pending_gap_fills.remove(&request_id) {
+                    self.gap_filled(doc_id, Err(error));
+                }
+            }
+            request_response::Event::InboundFailure { peer, error, .. } => {
+                tracing::debug!("History request from {} failed: {}", peer, error);
+            }
+            request_response::Event::ResponseSent { .. } => {}
+        }
+    }
+
+    fn handle_fetch_event(&mut self, event: request_response::Event<FetchRequest, FetchResponse>) {
+        match event {
+            request_response::Event::Message {
+                peer,
+                message: request_response::Message::Request { request, channel, .. },
+                ..
+            } => {
+                // Only FullNodes accept inbound fetch requests; held updates go out first, as
+                // for history requests
+                self.flush_debounced();
+                let (store, cache) = (&self.store, &mut self.fetch_cache);
+                let response = self.audit.handle::<audit::Fetch>(peer, &request, unix_ms(), |request| {
+                    doc_fetch::respond(&**store, request, cache)
+                });
+                match &response {
+                    FetchResponse::Rejected { reason } => tracing::debug!("Rejected fetch request from {}: {}", peer, reason),
+                    FetchResponse::RateLimited { .. } => tracing::info!("Rate limiting fetch requests from {}", peer),
+                    _ => {}
+                }
+                if self.swarm.behaviour_mut().fetch.send_response(channel, response).is_err() {
+                    tracing::debug!("Fetch requester {} went away before the response", peer);
+                }
+            }
+            request_response::Event::Message {
+                peer,
+                message: request_response::Message::Response { request_id, response },
+                ..
+            } => {
+                let Some(doc_id) = self.pending_fetches.remove(&request_id) else {
+                    return;
+                };
+                match response {
+                    FetchResponse::Manifest(manifest) | FetchResponse::Changed(manifest) => {
+                        self.reputation.record(peer, PeerSignal::FetchSucceeded, Instant::now());
+                        self.fetch_manifest(doc_id, peer, manifest);
+                    }
+                    FetchResponse::Chunk { offset, data } => self.fetch_chunk(doc_id, peer, offset, data),
+                    response => {
+                        let signal = match response {
+                            FetchResponse::RateLimited { .. } => PeerSignal::RateLimited,
+                            _ => PeerSignal::FetchFailed,
+                        };
+                        self.reputation.record(peer, signal, Instant::now());
+                        self.fetch_failed(doc_id, peer, doc_fetch::failure(&doc_id, response));
+                    }
+                }
+            }
+            request_response::Event::OutboundFailure { peer, request_id, error, .. } => {
+                self.reputation.record(peer, PeerSignal::FetchFailed, Instant::now());
+                self.quality.send_failed(&peer);
+                if let Some(doc_id) = self.pending_fetches.remove(&request_id) {
+                    let error = Error::Transport(format!("fetch request to {peer} failed: {error}"));
+                    self.fetch_failed(doc_id, peer, error);
+                }
+            }
+            request_response::Event::InboundFailure { peer, error, .. } => {
+                tracing::debug!("Fetch request from {} failed: {}", peer, error);
+            }
+            request_response::Event::ResponseSent { .. } => {}
+        }
+    }
+
+    /// Fetch `doc_id` for `reply`, resuming what an earlier call or run left of it.
+    fn start_fetch(&mut self, doc_id: String, peer_id: Option<PeerId>, reply: oneshot::Sender<Result<(PeerId, Vec<u8>), Error>>) {
+        if let Some(active) = self.active_fetches.get_mut(&doc_id) {
+            active.replies.push(reply);
+            return;
+        }
+        let provider = match self.fetch_peer(peer_id, &[]) {
+            Ok(provider) => provider,
+            Err(e) => {
+                let _ = reply.send(Err(e));
+                return;
+            }
+        };
+        if !self.transfers.contains_key(&doc_id) {
+            // Whatever a previous run verified, checked again
+            let stored = self.store.partial_fetch(&doc_id).and_then(|(manifest, data)| Transfer::restore(manifest, data).ok());
+            if let Some(transfer) = stored {
+                self.transfers.insert(doc_id.clone(), transfer);
+            }
+        }
+        let active = ActiveFetch { peer_id: provider, chosen: peer_id.is_some(), failed: Vec::new(), replies: vec![reply] };
+        self.active_fetches.insert(doc_id.clone(), active);
+        self.request_manifest(doc_id);
+    }
+
+    /// `peer_id`, or else the best-ranked connected peer serving document fetches that is
+    /// not in `failed`.
+    fn fetch_peer(&self, peer_id: Option<PeerId>, failed: &[PeerId]) -> Result<PeerId, Error> {
+        if let Some(peer_id) = peer_id {
+            return Ok(peer_id);
+        }
+        let serving = self.peer_infos.supporting(doc_fetch::FETCH_PROTOCOL).into_iter().filter(|peer| !failed.contains(peer));
+        self.reputation.rank(serving, Instant::now()).first().copied().ok_or(Error::NoFetchPeer)
+    }
+
+    fn request_manifest(&mut self, doc_id: String) {
+        let Some(active) = self.active_fetches.get(&doc_id) else {
+            return;
+        };
+        let request = FetchRequest::Manifest { doc_id: doc_id.clone() };
+        let id = self.swarm.behaviour_mut().fetch.send_request(&active.peer_id, request);
+        self.pending_fetches.insert(id, doc_id);
+    }
+
+    /// Carry on with the manifest `peer` sent, keeping the verified chunks it agrees with.
+    fn fetch_manifest(&mut self, doc_id: String, peer: PeerId, manifest: FetchManifest) {
+        let resumed = match self.transfers.get_mut(&doc_id) {
+            Some(transfer) => transfer.resume(manifest).map(|_| ()),
+            None => Transfer::new(manifest).map(|transfer| {
+                self.transfers.insert(doc_id.clone(), transfer);
+            }),
+        };
+        if let Err(e) = resumed {
+            self.reputation.record(peer, PeerSignal::FetchFailed, Instant::now());
+            self.fetch_failed(doc_id, peer, e.into());
+            return;
+        }
+        if let Some(transfer) = self.transfers.get(&doc_id) {
+            self.store.start_partial_fetch(transfer.manifest(), transfer.verified());
+        }
+        self.request_chunk(doc_id);
+    }
+
+    fn fetch_chunk(&mut self, doc_id: String, peer: PeerId, offset: u64, data: Vec<u8>) {
+        let Some(transfer) = self.transfers.get_mut(&doc_id) else {
+            return;
+        };
+        if let Err(e) = transfer.accept(offset, &data) {
+            tracing::warn!("Bad chunk of {} from {}: {}", doc_id, peer, e);
+            self.reputation.record(peer, PeerSignal::FetchFailed, Instant::now());
+            self.fetch_failed(doc_id, peer, e.into());
+            return;
+        }
+        let (received, total) = (transfer.received(), transfer.total());
+        self.reputation.record(peer, PeerSignal::FetchSucceeded, Instant::now());
+        self.store.append_partial_fetch(&doc_id, &data);
+        self.emit(NodeEvent::FetchProgress { doc_id: doc_id.clone(), received, total });
+        self.request_chunk(doc_id);
+    }
+
+    /// Ask for `doc_id`'s next chunk, or finish the fetch if there is none.
+    fn request_chunk(&mut self, doc_id: String) {
+        let (Some(transfer), Some(active)) = (self.transfers.get(&doc_id), self.active_fetches.get(&doc_id)) else {
+            return;
+        };
+        let Some((offset, len)) = transfer.next_range() else {
+            let peer = active.peer_id;
+            self.complete_fetch(doc_id, peer);
+            return;
+        };
+        if self.traffic.budget().is_exhausted() {
+            self.deferred_fetches.push(doc_id);
+            return;
+        }
+        let request = FetchRequest::Range { doc_id: doc_id.clone(), content_hash: transfer.manifest().content_hash, offset, len };
+        let id = self.swarm.behaviour_mut().fetch.send_request(&active.peer_id, request);
+        self.pending_fetches.insert(id, doc_id);
+    }
+
+    fn complete_fetch(&mut self, doc_id: String, peer: PeerId) {
+        let Some(transfer) = self.transfers.remove(&doc_id) else {
+            return;
+        };
+        self.store.clear_partial_fetch(&doc_id);
+        let result = match transfer.finish() {
+            Ok((manifest, bytes)) => {
+                if self.store.install_snapshot(docstore::Snapshot::new(doc_id.clone(), manifest.version, bytes.clone())) {
+                    self.emit(NodeEvent::SnapshotInstalled { doc_id: doc_id.clone(), version: manifest.version });
+                }
+                Ok((peer, bytes))
+            }
+            Err(e) => Err(e.into()),
+        };
+        self.finish_fetch(&doc_id, result);
+    }
+
+    /// `peer` failed the fetch of `doc_id`. What was verified is kept; the fetch goes on
+    /// with another peer unless the caller chose this one or there is none.
+    fn fetch_failed(&mut self, doc_id: String, peer: PeerId, error: Error) {
+        let Some(active) = self.active_fetches.get_mut(&doc_id) else {
+            return;
+        };
+        active.failed.push(peer);
+        let (chosen, failed) = (active.chosen, active.failed.clone());
+        match self.fetch_peer(None, &failed) {
+            Ok(next) if !chosen => {
+                tracing::debug!("Fetch of {} from {} failed ({}), trying {}", doc_id, peer, error, next);
+                if let Some(active) = self.active_fetches.get_mut(&doc_id) {
+                    active.peer_id = next;
+                }
+                self.request_manifest(doc_id);
+            }
+            _ => self.finish_fetch(&doc_id, Err(error)),
+        }
+    }
+
+    fn finish_fetch(&mut self, doc_id: &str, result: Result<(PeerId, Vec<u8>), Error>) {
+        let Some(active) = self.active_fetches.remove(doc_id) else {
+            return;
+        };
+        self.deferred_fetches.retain(|d| d != doc_id);
+        let mut replies = active.replies;
+        let first = replies.remove(0);
+        for reply in replies {
+            // Errors don't clone; callers that joined later get the message
+            let _ = reply.send(match &result {
+                Ok(fetched) => Ok(fetched.clone()),
+                Err(e) => Err(Error::FetchFailed { reason: e.to_string() }),
+            });
+        }
+        let _ = first.send(result);
+    }
+
+    fn handle_members_event(&mut self, event: request_response::Event<MembersRequest, MembersResponse>) {
+        match event {
+            request_response::Event::Message {
+                peer,
+                message: request_response::Message::Request { request, channel, .. },
+                ..
+            } => {
+                // Only FullNodes accept inbound members requests
+                let Some(table) = &mut self.members else { return };
+                members::handle_request(&mut self.swarm.behaviour_mut().members, table, peer, request, channel, unix_ms());
+            }
+            request_response::Event::Message {
+                peer,
+                message: request_response::Message::Response { request_id, response },
+                ..
+            } => {
+                if let Some(reply) = self.pending_members.remove(&request_id) {
+                    let _ = reply.send(members::members_result(response).map(|page| (peer, page)));
+                }
+            }
+            request_response::Event::OutboundFailure { peer, request_id, error, .. } => {
+                self.quality.send_failed(&peer);
+                if let Some(reply) = self.pending_members.remove(&request_id) {
+                    let _ = reply.send(Err(Error::Transport(format!("members request to {peer} failed: {error}"))));
+                }
+            }
+            request_response::Event::InboundFailure { peer, error, .. } => {
+                tracing::debug!("Members request from {} failed: {}", peer, error);
+            }
+            request_response::Event::ResponseSent { .. } => {}
+        }
+    }
+
+    fn handle_mailbox_event(&mut self, event: request_response::Event<MailboxRequest, MailboxResponse>) {
+        match event {
+            request_response::Event::Message {
+                peer,
+                message: request_response::Message::Request { request, channel, .. },
+                ..
+            } => {
+                // Only FullNodes accept inbound mailbox requests
+                let Some(mailbox) = &mut self.mailbox else { return };
+                let now = unix_ms();
+                let response =
+                    self.audit.handle::<audit::Mailbox>(peer, &request, now, |request| mailbox.respond(peer, request, now));
+                if let MailboxResponse::Rejected { reason } = &response {
+                    tracing::debug!("Rejected mailbox request from {}: {}", peer, reason);
+                }
+                if self.swarm.behaviour_mut().mailbox.send_response(channel, response).is_err() {
+                    tracing::debug!("Mailbox requester {} went away before the response", peer);
+                }
+            }
+            request_response::Event::Message {
+                peer,
+                message: request_response::Message::Response { request_id, response },
+                ..
+            } => {
+                if let Some(pending) = self.pending_mailbox.remove(&request_id) {
+                    self.mailbox_answered(peer, pending, Ok(response));
+                }
+            }
+            request_response::Event::OutboundFailure { peer, request_id, error, .. } => {
+                self.quality.send_failed(&peer);
+                if let Some(pending) = self.pending_mailbox.remove(&request_id) {
+                    let error = Error::Transport(format!("mailbox request to {peer} failed: {error}"));
+                    self.mailbox_answered(peer, pending, Err(error));
+                }
+            }
+            request_response::Event::InboundFailure { peer, error, .. } => {
+                tracing::debug!("Mailbox request from {} failed: {}", peer, error);
+            }
+            request_response::Event::ResponseSent { .. } => {}
+        }
+    }
+
+    /// Send `request` to `holder`, or answer it from our own mailbox if we are the holder.
+    fn send_mailbox_request(&mut self, holder: PeerId, request: MailboxRequest, pending: PendingMailbox) {
+        if holder == *self.swarm.local_peer_id() {
+            let response = match &mut self.mailbox {
+                Some(mailbox) => mailbox.respond(holder, &request, unix_ms()),
+                None => MailboxResponse::Rejected { reason: "this node holds no mailbox".to_string() },
+            };
+            self.mailbox_answered(holder, pending, Ok(response));
+            return;
+        }
+        let id = self.swarm.behaviour_mut().mailbox.send_request(&holder, request);
+        self.pending_mailbox.insert(id, pending);
+    }
+
+    /// Hand a mailbox response to whoever is waiting for it. Fetched messages are
+    /// acknowledged right away, so the holder deletes them.
+    fn mailbox_answered(&mut self, holder: PeerId, pending: PendingMailbox, result: Result<MailboxResponse, Error>) {
+        let result = result.and_then(|response| match response {
+            MailboxResponse::Rejected { reason } => Err(Error::MailboxRejected { reason }),
+            MailboxResponse::RateLimited { retry_after_ms } => Err(Error::RateLimited { retry_after_ms }),
+            response => Ok(response),
+        });
+        match (pending, result) {
+            (PendingMailbox::Deposit(reply), Ok(MailboxResponse::Deposited { id, expires_at_ms })) => {
+                let _ = reply.send(Ok(MailboxReceipt { id, expires_at_ms }));
+            }
+            (PendingMailbox::Fetch(reply), Ok(MailboxResponse::Messages(messages))) => {
+                if !messages.is_empty() {
+                    let ids = messages.iter().map(|m| m.id).collect();
+                    self.send_mailbox_request(holder, MailboxRequest::Ack { ids }, PendingMailbox::Ack);
+                }
+                match reply {
+                    Some(reply) => {
+                        let _ = reply.send(Ok(messages));
+                    }
+                    None => {
+                        for message in messages {
+                            self.emit(NodeEvent::MailboxDelivered { holder, message });
+                        }
+                    }
+                }
+            }
+            (PendingMailbox::Ack, Ok(MailboxResponse::Acked { .. })) => {}
+            (pending, result) => {
+                let error =
+                    result.err().unwrap_or_else(|| Error::Transport(format!("unexpected mailbox response from {holder}")));
+                match pending {
+                    PendingMailbox::Deposit(reply) => {
+                        let _ = reply.send(Err(error));
+                    }
+                    PendingMailbox::Fetch(Some(reply)) => {
+                        let _ = reply.send(Err(error));
+                    }
+                    PendingMailbox::Fetch(None) | PendingMailbox::Ack => {
+                        tracing::debug!("Mailbox request to {} failed: {}", holder, error)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Renew the registrations that are due and ask every point for new registrants.
+    fn rendezvous_tick(&mut self) {
+        for point in self.rendezvous.due_registrations(Instant::now()) {
+            self.register_at(point);
+        }
+        for point in self.rendezvous.points() {
+            self.discover_at(point);
+        }
+    }
+
+    fn register_at(&mut self, point: PeerId) {
+        let namespace = self.docstore_config.topics.rendezvous_namespace();
+        if let Err(e) = self.swarm.behaviour_mut().rendezvous.register(point, &namespace, REGISTRATION_TTL) {
+            // Typically no external address yet
+            tracing::debug!("Cannot register with rendezvous point {} yet: {}", point, e);
+            self.rendezvous.register_failed(&point, Instant::now());
+        }
+    }
+
+    fn discover_at(&mut self, point: PeerId) {
+        let Some(cookie) = self.rendezvous.start_discovery(&point) else {
+            return;
+        };
+        let namespace = self.docstore_config.topics.rendezvous_namespace();
+        if let Err(e) = self.swarm.behaviour_mut().rendezvous.discover(point, &namespace, cookie, DISCOVER_LIMIT) {
+            tracing::debug!("Cannot discover through rendezvous point {}: {}", point, e);
+            self.rendezvous.discovery_failed(&point);
+        }
+    }
+
+    fn handle_rendezvous_event(&mut self, event: RendezvousEvent) {
+        let now = Instant::now();
+        match event {
+            RendezvousEvent::Registered { point, ttl } => {
+                tracing::debug!("Registered with rendezvous point {} for {:?}", point, ttl);
+                self.rendezvous.registered(&point, ttl, now);
+            }
+            RendezvousEvent::RegisterFailed { point, error } => {
+                tracing::warn!("Rendezvous point {} refused our registration: {}", point, error);
+                self.rendezvous.register_failed(&point, now);
+            }
+            RendezvousEvent::Discovered { point, registrations, cookie } => {
+                let discovered = self.rendezvous.discovered(&point, registrations, cookie, now);
+                let unconnected: Vec<Registrant> =
+                    discovered.new.into_iter().filter(|r| !self.swarm.is_connected(&r.peer_id)).collect();
+                for registrant in unconnected.into_iter().take(DEFAULT_REGISTRANTS_TO_DIAL) {
+                    self.dial_registrant(registrant);
+                }
+                if discovered.more {
+                    self.discover_at(point);
+                }
+            }
+            RendezvousEvent::DiscoverFailed { point, error } => {
+                tracing::debug!("Discovery through rendezvous point {} failed: {}", point, error);
+                self.rendezvous.discovery_failed(&point);
+            }
+            RendezvousEvent::Expired { peer } => {
+                self.rendezvous.expired(&peer);
+                self.remove_explicit_peer(&peer);
+            }
+        }
+        self.answer_rendezvous_waiters();
+    }
+
+    /// Dial a peer found at a rendezvous point and gossip with it directly.
+    fn dial_registrant(&mut self, registrant: Registrant) {
+        if let Err(e) = self.dial_peer(registrant.peer_id, registrant.addrs, false) {
+            tracing::debug!("Dialing registrant {} failed: {}", registrant.peer_id, e);
+            return;
+        }
+        self.add_explicit_peer(registrant.peer_id);
+    }
+
+    /// Ask every relay for the clients it lists.
+    fn peer_exchange_tick(&mut self) {
+        for relay in self.peer_exchange.relays() {
+            self.ask_for_peers(relay);
+        }
+    }
+
+    fn ask_for_peers(&mut self, relay: PeerId) {
+        let addrs: Vec<Multiaddr> = self.swarm.external_addresses().chain(self.swarm.listeners()).cloned().collect();
+        let request = self.peer_exchange.request(addrs);
+        self.swarm.behaviour_mut().peers.send_request(&relay, request);
+    }
+
+    /// Relays answer from their directory; the others dial a few of the peers listed.
+    fn handle_peers_event(&mut self, event: request_response::Event<PeersRequest, PeersResponse>) {
+        if let Some(directory) = &mut self.peer_directory {
+            peers::handle_event(&mut self.swarm.behaviour_mut().peers, directory, event);
+            return;
+        }
+        match event {
+            request_response::Event::Message { peer, message: request_response::Message::Response { response, .. }, .. } => {
+                let swarm = &self.swarm;
+                for dial in self.peer_exchange.listed(peer, response, |p| swarm.is_connected(p)) {
+                    self.dial_listed(dial);
+                }
+            }
+            request_response::Event::OutboundFailure { peer, error, .. } => {
+                self.quality.send_failed(&peer);
+                tracing::debug!("Asking {} for its peers failed: {}", peer, error);
+            }
+            _ => {}
+        }
+    }
+
+    /// Dial a peer a relay listed; it joins the mesh once connected.
+    fn dial_listed(&mut self, dial: ListedDial) {
+        if self.bans.is_banned(&dial.peer_id, Instant::now()) {
+            self.peer_exchange.dial_failed(&dial.peer_id);
+            return;
+        }
+        tracing::debug!("Dialing {}, listed by {}", dial.peer_id, dial.relay);
+        if let Err(e) = self.dial_peer(dial.peer_id, dial.addrs, false) {
+            tracing::debug!("Dialing listed peer {} failed: {}", dial.peer_id, e);
+            self.peer_exchange.dial_failed(&dial.peer_id);
+        }
+    }
+
+    /// Answer `discover_peers` once no discovery is in flight.
+    fn answer_rendezvous_waiters(&mut self) {
+        if self.rendezvous_waiters.is_empty() || self.rendezvous.is_discovering() {
+            return;
+        }
+        let registrants = self.rendezvous.registrants(Instant::now());
+        for reply in self.rendezvous_waiters.drain(..) {
+            let _ = reply.send(Ok(registrants.clone()));
+        }
+    }
+
+    fn handle_nat_event(&mut self, event: NatBehaviourEvent) {
+        match event {
+            NatBehaviourEvent::Upnp(libp2p::upnp::Event::NewExternalAddr(addr)) => {
+                tracing::info!("UPnP mapped external address {}", addr);
+                let actions = self.port_mappings.on_mapped(addr);
+                self.apply_nat_actions(actions);
+            }
+            NatBehaviourEvent::Upnp(libp2p::upnp::Event::ExpiredExternalAddr(addr)) => {
+                tracing::info!("UPnP mapping for {} expired", addr);
+                let actions = self.port_mappings.on_expired(&addr);
+                self.apply_nat_actions(actions);
+                self.emit(NodeEvent::PortMappingExpired { addr });
+            }
+            NatBehaviourEvent::Upnp(libp2p::upnp::Event::GatewayNotFound) => {
+                tracing::info!("UPnP: no gateway found");
+                self.emit(NodeEvent::PortMappingUnavailable { reason: "no UPnP gateway found".to_string() });
+            }
+            NatBehaviourEvent::Upnp(libp2p::upnp::Event::NonRoutableGateway) => {
+                tracing::info!("UPnP: gateway is not exposed to the public network");
+                self.emit(NodeEvent::PortMappingUnavailable { reason: "UPnP gateway is itself behind a NAT".to_string() });
+            }
+            NatBehaviourEvent::Autonat(libp2p::autonat::Event::StatusChanged { new, .. }) => {
+                tracing::info!("AutoNAT status: {:?}", new);
+                let actions = self.port_mappings.on_nat_status(&new);
+                if matches!(new, libp2p::autonat::NatStatus::Private) && !actions.is_empty() {
+                    self.emit(NodeEvent::PortMappingUnreachable);
+                }
+                self.apply_nat_actions(actions);
+            }
+            NatBehaviourEvent::Autonat(_) => {}
+        }
+    }
+
+    /// Report a change to our external addresses. Confirmed and expired ones change what
+    /// identify advertises, so it is pushed to the connected peers right away instead of
+    /// waiting for them to ask again.
+    fn external_addr_changed(&mut self, change: ExternalAddrChange) {
+        let event = match change {
+            ExternalAddrChange::Candidate(addr) => NodeEvent::ExternalAddrCandidate { addr },
+            ExternalAddrChange::Confirmed(addr) => NodeEvent::ExternalAddrConfirmed { addr },
+            ExternalAddrChange::Expired(addr) => NodeEvent::ExternalAddrExpired { addr },
+        };
+        if !matches!(event, NodeEvent::ExternalAddrCandidate { .. }) {
+            let peers: Vec<PeerId> = self.swarm.connected_peers().copied().collect();
+            self.swarm.behaviour_mut().identify.push(peers);
+            // Our registrations carry the addresses too
+            self.rendezvous.renew_all(Instant::now());
+        }
+        self.emit(event);
+    }
+
+    fn handle_swarm_event(&mut self, event: SwarmEvent<DocstoreBehaviourEvent>) {
+        self.traffic.observer().swarm_event(&event);
+        if let SwarmEvent::Behaviour(DocstoreBehaviourEvent::Kademlia(kad_event)) = &event {
+            self.traffic.observer().kad_event(kad_event);
+        }
+        if let Some(change) = self.external_addrs.on_swarm_event(&event) {
+            self.external_addr_changed(change);
+            return;
+        }
+        match event {
+            SwarmEvent::NewListenAddr { address, .. } => {
+                self.emit(NodeEvent::ListenStarted { addr: address });
+            }
+            SwarmEvent::ConnectionEstablished { peer_id, connection_id, endpoint, .. } => {
+                self.pending_dials.connected(&connection_id);
+                let now = Instant::now();
+                for peer in self.bans.expire(now) {
+                    self.swarm.behaviour_mut().gossipsub.remove_blacklisted_peer(&peer);
+                }
+                if self.bans.is_banned(&peer_id, now) {
+                    tracing::info!("Rejecting connection from banned peer {}", peer_id);
+                    let _ = self.swarm.disconnect_peer_id(peer_id);
+                    self.emit(NodeEvent::BannedPeerRejected { peer_id });
+                    return;
+                }
+                let dialed = match &endpoint {
+                    libp2p::core::ConnectedPoint::Dialer { address, .. } => {
+                        self.address_book.record_success(peer_id, address, unix_ms());
+                        Some(address)
+                    }
+                    libp2p::core::ConnectedPoint::Listener { .. } => None,
+                };
+                let recovered = self.important.connected(&peer_id, dialed);
+                if self.federation.connected(&peer_id) {
+                    tracing::info!("Federation link to {} is up", peer_id);
+                }
+                self.quality.connected(peer_id);
+                self.topic_health.connected(unix_ms());
+                if let Some(watch) = &mut self.partition {
+                    watch.connected(&peer_id);
+                }
+                let transport = crate::node::addrs::transport_name(endpoint.get_remote_address());
+                self.traffic.record_connection(transport);
+                self.emit(NodeEvent::Connected {
+                    peer_id,
+                    addr: endpoint.get_remote_address().clone(),
+                    transport,
+                    direction: crate::node::connections::direction(&endpoint),
+                });
+                if recovered {
+                    tracing::info!("Important peer {} is back", peer_id);
+                    self.emit(NodeEvent::PeerRecovered { peer_id });
+                }
+                if self.peer_exchange.connected(&peer_id) {
+                    tracing::info!("Connected to {}, listed by a relay; gossiping directly", peer_id);
+                    self.add_explicit_peer(peer_id);
+                }
+            }
+            SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } => {
+                self.peer_infos.remove(&peer_id);
+                if let Some(scope) = self.pipeline.scope_filter_mut() {
+                    scope.disconnected(&peer_id);
+                }
+                self.quality.disconnected(&peer_id);
+                self.rendezvous.remove_point(&peer_id);
+                self.answer_rendezvous_waiters();
+                if self.peer_exchange.disconnected(&peer_id) {
+                    self.remove_explicit_peer(&peer_id);
+                }
+                if let Some(directory) = &mut self.peer_directory {
+                    directory.disconnected(&peer_id);
+                }
+                self.ping_failures.forget(&peer_id);
+                if self.federation.disconnected(&peer_id) {
+                    tracing::warn!("Federation link to {} is down", peer_id);
+                }
+                let now = Instant::now();
+                if !self.bans.is_banned(&peer_id, now) && self.important.connection_lost(&peer_id, now) {
+                    tracing::debug!("Lost important peer {}, redialing", peer_id);
+                }
+                if let Some(watch) = &mut self.partition {
+                    watch.disconnected(&peer_id, unix_ms());
+                }
+                if self.swarm.connected_peers().next().is_none() {
+                    self.announcements.offline(now);
+                }
+                self.emit(NodeEvent::Disconnected { peer_id });
+            }
+            SwarmEvent::Behaviour(DocstoreBehaviourEvent::Gossipsub(gossipsub::Event::Message {
+                propagation_source,
+                message_id,
+                mut message,
+            })) => {
+                self.traffic.record_in(&message, &propagation_source);
+                if let Some(source) = message.source {
+                    self.author_quotas.learn(source);
+                }
+                let mut sink = QuotaSink::new(&mut *self.store, &mut self.quotas, &mut self.author_quotas);
+                for incoming in self.pipeline.handle_incoming(&mut sink, propagation_source, &message) {
+                    match incoming {
+                        Incoming::Verdict(acceptance) => {
+                            let accepted = matches!(acceptance, gossipsub::MessageAcceptance::Accept);
+                            if matches!(acceptance, gossipsub::MessageAcceptance::Reject) {
+                                self.reputation.record(propagation_source, PeerSignal::InvalidMessage, Instant::now());
+                            }
+                            docstore::report_validation(
+                                &mut self.swarm.behaviour_mut().gossipsub,
+                                &message_id,
+                                &propagation_source,
+                                acceptance,
+                            );
+                            if accepted {
+                                // Accepting hands the message to gossipsub for forwarding
+                                self.traffic.record_forward(&self.swarm.behaviour().gossipsub, &message, &propagation_source);
+                            }
+                        }
+                        Incoming::UnsupportedVersion { peer_id, version } => {
+                            self.emit(NodeEvent::UnsupportedVersion { peer_id, version });
+                        }
+                        Incoming::QuotaExceeded { peer_id, doc_id, reason, ack_to } => {
+                            self.quota_exceeded(peer_id, &message_id, &doc_id, reason, ack_to);
+                        }
+                        Incoming::CasConflict { conflict, ack_to, .. } => {
+                            if let Some(publisher) = ack_to {
+                                let (doc_id, version) = (&conflict.doc_id, conflict.current_version);
+                                self.send_receipt(publisher, &message_id, doc_id, version, ReceiptOutcome::CasConflict);
+                            }
+                        }
+                        Incoming::SchemaViolation { peer_id, violation } => {
+                            self.emit(NodeEvent::SchemaViolation { peer_id, violation });
+                        }
+                        Incoming::Announcement { peer_id, announcement } => {
+                            tracing::info!(
+                                "{} announcement from {}: {}",
+                                announcement.severity.as_str(),
+                                peer_id,
+                                announcement.text
+                            );
+                            self.emit(NodeEvent::Announcement { peer_id, announcement });
+                        }
+                        Incoming::Receipt(receipt) => {
+                            self.receipt_received(&receipt, None);
+                        }
+                        Incoming::SnapshotInstalled(snapshot) => {
+                            if self.ordering.is_watched(&snapshot.doc_id) {
+                                let clock = self.store.clock(&snapshot.doc_id);
+                                let outputs =
+                                    self.ordering.snapshot(&snapshot.doc_id, snapshot.bytes.clone(), &clock, Instant::now());
+                                self.deliver_ordered(outputs, Some(propagation_source));
+                            }
+                            self.emit(NodeEvent::SnapshotInstalled { doc_id: snapshot.doc_id, version: snapshot.version });
+                        }
+                        Incoming::UpdateApplied { update, version, ack_to } => {
+                            self.snapshot_if_due(&update.doc_id);
+                            if let Some(publisher) = ack_to {
+                                self.send_receipt(publisher, &message_id, &update.doc_id, version, ReceiptOutcome::Applied);
+                            }
+                            if self.keeper.hold(&keeper::doc_session(&update.doc_id), propagation_source) {
+                                tracing::debug!("Keeping {} alive for {}", propagation_source, update.doc_id);
+                            }
+                            self.order(&update, Origin::Live, Some(propagation_source));
+                            self.emit(NodeEvent::DocUpdateReceived { peer_id: propagation_source, update });
+                        }
+                        Incoming::TransactionApplied { id, doc_ids } => {
+                            self.emit(NodeEvent::TransactionApplied { peer_id: propagation_source, id, doc_ids });
+                        }
+                        Incoming::Message => self.emit(NodeEvent::MessageReceived {
+                            peer_id: propagation_source,
+                            topic: message.topic.to_string(),
+                            message_id: message_id.clone(),
+                            data: std::mem::take(&mut message.data),
+                        }),
+                    }
+                }
+            }
+            SwarmEvent::Behaviour(DocstoreBehaviourEvent::Identify(identify::Event::Received {
+                peer_id,
+                info,
+                ..
+            })) => {
+                let peer_info = PeerInfo::from(&info);
+                self.check_relay(peer_id, &peer_info);
+                let relay = self.federation.is_relay(&peer_id, peer_info.supports(RELAY_HOP_PROTOCOL));
+                if let Some(scope) = self.pipeline.scope_filter_mut() {
+                    scope.identified(peer_id, relay);
+                }
+                if peer_info.supports(rendezvous_behaviour::RENDEZVOUS_PROTOCOL)
+                    && self.swarm.behaviour().rendezvous.is_client()
+                    && self.rendezvous.add_point(peer_id)
+                {
+                    tracing::info!("Registering with rendezvous point {}", peer_id);
+                    self.register_at(peer_id);
+                    self.discover_at(peer_id);
+                }
+                if peer_info.supports(peers::PEERS_PROTOCOL)
+                    && self.peer_directory.is_none()
+                    && self.peer_exchange.add_relay(peer_id)
+                {
+                    self.ask_for_peers(peer_id);
+                }
+                if self.peer_infos.update(peer_id, peer_info.clone()) {
+                    // Pick up what was left for us while we were away
+                    if peer_info.supports(mailbox::MAILBOX_PROTOCOL) {
+                        self.send_mailbox_request(peer_id, MailboxRequest::Fetch, PendingMailbox::Fetch(None));
+                    }
+                    let shared_protocols = peer_info.shared_protocols(&self.local_protocols);
+                    self.emit(NodeEvent::PeerIdentified { peer_id, info: peer_info, shared_protocols });
+                }
+                let local = *self.swarm.local_peer_id();
+                for addr in info.listen_addrs.into_iter().filter(|a| is_peer_addr(&local, &peer_id, a)) {
+                    self.address_book.observe(peer_id, &addr, unix_ms());
+                    self.swarm.behaviour_mut().kademlia.add_address(&peer_id, addr);
+                }
+                // Only now can queries reach the peer
+                self.renew_announcements();
+            }
+            SwarmEvent::Behaviour(DocstoreBehaviourEvent::Kademlia(kad::Event::OutboundQueryProgressed {
+                id,
+                result: kad::QueryResult::PutRecord(result),
+                ..
+            })) => {
+                let result = result.map(|_| ()).map_err(|e| Error::Dht(e.to_string()));
+                match self.pending_puts.remove(&id) {
+                    Some(PendingPut::Caller(reply)) => {
+                        let _ = reply.send(result);
+                    }
+                    Some(PendingPut::HeadPointer { pointer, reply }) => {
+                        let _ = reply.send(result.map(|()| pointer));
+                    }
+                    Some(PendingPut::Profile { profile, reply }) => {
+                        let _ = reply.send(result.map(|()| profile));
+                    }
+                    Some(PendingPut::Republish(key)) => match result {
+                        Ok(()) => self.emit(NodeEvent::RecordRepublished { key }),
+                        Err(e) => self.emit(NodeEvent::RecordRepublishFailed { key, error: e.to_string() }),
+                    },
+                    None => {
+                        // Else one of Kademlia's own replication and publication jobs
+                        if let Some(summary) = self.announcements.settle(Some(&id), result.is_ok()) {
+                            self.emit(NodeEvent::AnnouncementsRenewed { renewed: summary.renewed, failed: summary.failed });
+                        }
+                    }
+                }
+            }
+            SwarmEvent::Behaviour(DocstoreBehaviourEvent::Kademlia(kad::Event::OutboundQueryProgressed {
+                id,
+                result: kad::QueryResult::GetClosestPeers(result),
+                ..
+            })) => {
+                // A timed out query still reports the closest peers it got to
+                let peers = match result {
+                    Ok(ok) => ok.peers,
+                    Err(kad::GetClosestPeersError::Timeout { peers, .. }) => peers,
+                };
+                let peers = without_self(self.swarm.local_peer_id(), peers, |p| p.peer_id);
+                self.finish_find(id, peers);
+            }
+            SwarmEvent::Behaviour(DocstoreBehaviourEvent::Kademlia(kad::Event::OutboundQueryProgressed {
+                id,
+                result: kad::QueryResult::StartProviding(result),
+                ..
+            })) => {
+                if let Some(summary) = self.announcements.settle(Some(&id), result.is_ok()) {
+                    self.emit(NodeEvent::AnnouncementsRenewed { renewed: summary.renewed, failed: summary.failed });
+                }
+            }
+            SwarmEvent::Behaviour(DocstoreBehaviourEvent::Kademlia(kad::Event::OutboundQueryProgressed {
+                id,
+                result: kad::QueryResult::GetProviders(result),
+                step,
+                ..
+            })) => {
+                match result {
+                    Ok(kad::GetProvidersOk::FoundProviders { providers, .. }) => {
+                        // We are among the providers of what we provide ourselves
+                        let providers = without_self(self.swarm.local_peer_id(), providers, |p| *p);
+                        if self.pending_refetch_lookups.contains_key(&id) {
+                            self.refetch_providers_found(id, providers)
+                        } else {
+                            self.relay_providers_found(id, providers)
+                        }
+                    }
+                    Ok(kad::GetProvidersOk::FinishedWithNoAdditionalRecord { .. }) => {}
+                    // Providers found before the timeout were already reported
+                    Err(e) => tracing::debug!("Provider lookup {:?} ended: {}", id, e),
+                }
+                if step.last {
+                    if let Some(pending) = self.pending_relay_lookups.remove(&id) {
+                        let _ = pending.reply.send(Ok(pending.lookup.into_providers()));
+                    }
+                    // No provider could be asked; try whoever serves history
+                    if let Some(doc_id) = self.pending_refetch_lookups.remove(&id) {
+                        self.refetch_from_history_peer(doc_id);
+                    }
+                }
+            }
+            SwarmEvent::Behaviour(DocstoreBehaviourEvent::Kademlia(kad::Event::OutboundQueryProgressed {
+                id,
+                result: kad::QueryResult::GetRecord(result),
+                step,
+                ..
+            })) => {
+                if let Ok(kad::GetRecordOk::FoundRecord(found)) = result {
+                    if let Some(pending) = self.pending_resolves.get_mut(&id) {
+                        pending.values.push(found.record.value);
+                    } else if let Some(pending) = self.pending_profiles.get_mut(&id) {
+                        pending.values.push(found.record.value);
+                    }
+                }
+                // Not found, or every copy reported
+                if step.last {
+                    self.finish_resolve(id);
+                }
+            }
+            SwarmEvent::Behaviour(DocstoreBehaviourEvent::Kademlia(kad::Event::OutboundQueryProgressed {
+                id,
+                result: kad::QueryResult::Bootstrap(result),
+                step,
+                ..
+            })) if Some(id) == self.bootstrap_query => {
+                if result.is_err() || step.last {
+                    tracing::debug!("Initial Kademlia bootstrap finished: {:?}", result.as_ref().map(|_| ()));
+                    self.dht_bootstrap = if result.is_ok() { DhtBootstrap::Done } else { DhtBootstrap::Failed };
+                    self.bootstrap_query = None;
+                    // The announcement made at startup reached nobody
+                    if result.is_ok() && self.provides_relay {
+                        self.provide_relay();
+                    }
+                }
+            }
+            SwarmEvent::Behaviour(DocstoreBehaviourEvent::Kademlia(kad::Event::RoutingUpdated {
+                peer,
+                is_new_peer,
+                addresses,
+                old_peer,
+                ..
+            })) => {
+                self.emit(NodeEvent::RoutingUpdated {
+                    peer_id: peer,
+                    is_new_peer,
+                    addrs: addresses.into_vec(),
+                    evicted: old_peer,
+                });
+                self.refresh_dht_summary();
+            }
+            SwarmEvent::Behaviour(DocstoreBehaviourEvent::Kademlia(kad::Event::UnroutablePeer { peer })) => {
+                self.emit(NodeEvent::UnroutablePeer { peer_id: peer });
+            }
+            SwarmEvent::Behaviour(DocstoreBehaviourEvent::Kademlia(
+                kad::Event::RoutablePeer { peer, address } | kad::Event::PendingRoutablePeer { peer, address },
+            )) => {
+                self.emit(NodeEvent::RoutablePeer { peer_id: peer, addr: address });
+                self.refresh_dht_summary();
+            }
+            SwarmEvent::Behaviour(DocstoreBehaviourEvent::Kademlia(kad::Event::ModeChanged { new_mode })) => {
+                self.emit(NodeEvent::DhtModeChanged { mode: new_mode });
+            }
+            SwarmEvent::Behaviour(DocstoreBehaviourEvent::Kademlia(kad::Event::InboundRequest { request })) => {
+                let store = self.swarm.behaviour_mut().kademlia.store_mut();
+                match request {
+                    kad::InboundRequest::PutRecord { source, record: Some(record), .. }
+                        if !profile::accepts_record(&record.key, &record.value) =>
+                    {
+                        tracing::debug!("Not storing an invalid profile from {}", source);
+                    }
+                    kad::InboundRequest::PutRecord { source, record: Some(record), .. } => {
+                        let key = record.key.clone();
+                        let stored = store.put(record);
+                        match self.dht_store.put(stored) {
+                            Ok(()) => self.emit(NodeEvent::RecordStored { peer_id: source, key }),
+                            Err(full) => {
+                                tracing::debug!("Not storing record from {}: {}", source, full);
+                                self.report_store_full(full);
+                            }
+                        }
+                    }
+                    kad::InboundRequest::AddProvider { record: Some(record) } => {
+                        let key = record.key.clone();
+                        let stored = store.add_provider(record);
+                        if let Err(full) = self.dht_store.provide(&key, stored) {
+                            tracing::debug!("Not storing provider record: {}", full);
+                            self.report_store_full(full);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            SwarmEvent::Behaviour(DocstoreBehaviourEvent::Nat(event)) => self.handle_nat_event(event),
+            SwarmEvent::Behaviour(DocstoreBehaviourEvent::History(event)) => self.handle_history_event(event),
+            SwarmEvent::Behaviour(DocstoreBehaviourEvent::Fetch(event)) => self.handle_fetch_event(event),
+            SwarmEvent::Behaviour(DocstoreBehaviourEvent::Mailbox(event)) => self.handle_mailbox_event(event),
+            SwarmEvent::Behaviour(DocstoreBehaviourEvent::Members(event)) => self.handle_members_event(event),
+            SwarmEvent::Behaviour(DocstoreBehaviourEvent::Receipts(event)) => self.handle_receipt_event(event),
+            SwarmEvent::Behaviour(DocstoreBehaviourEvent::Rendezvous(event)) => {
+                if let Some(event) = RendezvousBehaviour::on_event(event) {
+                    self.handle_rendezvous_event(event);
+                }
+            }
+            SwarmEvent::Behaviour(DocstoreBehaviourEvent::KeepAlive(event)) => {
+                keep_alive::handle_event(&mut self.swarm.behaviour_mut().keep_alive, event)
+            }
+            SwarmEvent::Behaviour(DocstoreBehaviourEvent::Peers(event)) => self.handle_peers_event(event),
+            SwarmEvent::Behaviour(DocstoreBehaviourEvent::Ping(ping::Event { peer, result, .. })) => {
+                let signal = match result {
+                    Ok(rtt) => PeerSignal::Ping { rtt },
+                    Err(_) => PeerSignal::PingFailed,
+                };
+                self.reputation.record(peer, signal, Instant::now());
+                if let Some(change) = self.quality.ping(peer, result.as_ref().ok().copied()) {
+                    tracing::debug!("Connection to {} is now {} ({})", peer, change.to, change.score);
+                    self.emit(NodeEvent::QualityChanged { peer_id: peer, from: change.from, to: change.to, score: change.score });
+                }
+                match self.ping_failures.record(peer, result.is_ok()) {
+                    PingAction::Unresponsive => {
+                        let failures = self.ping_failures.policy().unresponsive_after;
+                        tracing::info!("{} missed {} pings in a row", peer, failures);
+                        self.emit(NodeEvent::PeerUnresponsive { peer_id: peer, failures });
+                    }
+                    PingAction::Disconnect => {
+                        // Important peers are redialed once the connection is gone
+                        tracing::info!("Closing connection to unresponsive peer {}", peer);
+                        let _ = self.swarm.disconnect_peer_id(peer);
+                    }
+                    PingAction::Recovered => tracing::debug!("{} answers pings again", peer),
+                    PingAction::None => {}
+                }
+            }
+            SwarmEvent::OutgoingConnectionError { peer_id, connection_id, error } => {
+                let now = Instant::now();
+                if let Some(peer) = peer_id {
+                    self.important.dial_failed(&peer, now);
+                    self.relay_discovery.dial_failed(&peer);
+                    self.peer_exchange.dial_failed(&peer);
+                }
+                // Peers that keep failing are retried less eagerly
+                let failing = peer_id.or_else(|| self.pending_dials.addr(&connection_id).and_then(crate::node::addrs::peer_id_of));
+                let factor = failing.map_or(1, |peer| {
+                    self.reputation.record(peer, PeerSignal::DialFailed, now);
+                    self.reputation.retry_backoff_factor(&peer, now)
+                });
+                let reason = DialFailure::from(&error);
+                match self.pending_dials.failed_slowed(&connection_id, reason, now, factor) {
+                    DialOutcome::Retry { .. } => tracing::debug!("Dial to {:?} failed ({}), retrying", peer_id, reason.as_str()),
+                    DialOutcome::GaveUp { addr, attempts } => {
+                        self.emit(NodeEvent::DialFailed { peer_id, addr, attempts, reason });
+                    }
+                    DialOutcome::Unknown => {}
+                }
+                if let (Some(peer), libp2p::swarm::DialError::Transport(failed)) = (peer_id, &error) {
+                    for (addr, _) in failed {
+                        if self.address_book.record_failure(&peer, addr) >= address_book::MAX_FAILURES {
+                            tracing::info!("Dropping {} of {} after repeated dial failures", addr, peer);
+                            self.remove_address(peer, addr.clone(), RemovalReason::DialFailures);
+                        }
+                    }
+                }
+                self.emit(NodeEvent::Error {
+                    msg: format!("Connection error to {:?}: {}", peer_id, error),
+                });
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::behaviour::docstore::TopicRegistry;
+    use crate::node::keys::{generate_identity, KeyType};
+    use crate::node::NodeRole;
+    use libp2p::multiaddr::Protocol;
+
+    async fn wait_for<T>(node: &mut Node, mut f: impl FnMut(NodeEvent) -> Option<T>) -> T {
+        tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                let event = node.next_event().await.expect("node stopped");
+                if let Some(v) = f(event) {
+                    return v;
+                }
+            }
+        })
+        .await
+        .expect("timed out waiting for node event")
+    }
+
+    #[tokio::test]
+    async fn every_key_type_produces_a_connectable_node() {
+        for key_type in [KeyType::Ed25519, KeyType::Secp256k1, KeyType::Ecdsa] {
+            let mut listener = NodeBuilder::new(NodeRole::FullNode)
+                .add_listen_addr("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+                .spawn(generate_identity(key_type))
+                .unwrap();
+            let addr = wait_for(&mut listener, |e| match e {
+                NodeEvent::ListenStarted { addr } => Some(addr),
+                _ => None,
+            })
+            .await;
+
+            let mut dialer = NodeBuilder::new(NodeRole::Client).spawn(generate_identity(key_type)).unwrap();
+            dialer.dial(addr.with(Protocol::P2p(listener.peer_id()))).await.unwrap();
+            let connected = wait_for(&mut dialer, |e| match e {
+                NodeEvent::Connected { peer_id, .. } => Some(peer_id),
+                _ => None,
+            })
+            .await;
+            assert_eq!(connected, listener.peer_id(), "{key_type}");
+        }
+    }
+
+    #[tokio::test]
+    async fn every_event_consumer_sees_the_connection() {
+        let mut listener = NodeBuilder::new(NodeRole::FullNode)
+            .add_listen_addr("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .spawn(generate_identity(KeyType::Ed25519))
+            .unwrap();
+        let addr = wait_for(&mut listener, |e| match e {
+            NodeEvent::ListenStarted { addr } => Some(addr),
+            _ => None,
+        })
+        .await;
+
+        let dialer = NodeBuilder::new(NodeRole::Client).spawn(generate_identity(KeyType::Ed25519)).unwrap();
+        let (mut first, mut second) = (dialer.subscribe_events(), dialer.subscribe_events());
+        dialer.dial(addr.with(Protocol::P2p(listener.peer_id()))).await.unwrap();
+        for subscription in [&mut first, &mut second] {
+            let connected = tokio::time::timeout(Duration::from_secs(10), async {
+                loop {
+                    match subscription.recv().await.expect("node stopped") {
+                        Ok(NodeEvent::Connected { peer_id, .. }) => return peer_id,
+                        Ok(_) => {}
+                        Err(lagged) => panic!("{lagged}"),
+                    }
+                }
+            })
+            .await
+            .expect("timed out waiting for the connection");
+            assert_eq!(connected, listener.peer_id());
+        }
+
+        // The handle's own channel saw it as well
+        let mut events = dialer.into_event_stream();
+        let connected = tokio::time::timeout(Duration::from_secs(10), async {
+            while let Some(event) = events.next().await {
+                if let NodeEvent::Connected { peer_id, .. } = event {
+                    return peer_id;
+                }
+            }
+            panic!("node stopped");
+        })
+        .await
+        .unwrap();
+        assert_eq!(connected, listener.peer_id());
+
+        // Dropping the stream stops the node, which ends the subscriptions
+        drop(events);
+        let rest = tokio::time::timeout(Duration::from_secs(10), first.collect::<Vec<_>>()).await.unwrap();
+        assert!(rest.iter().all(Result::is_ok));
+    }
+
+    #[tokio::test]
+    async fn observer_refuses_to_publish() {
+        let mut a = NodeBuilder::new(NodeRole::FullNode)
+            .add_listen_addr("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .spawn(generate_identity(KeyType::Ed25519))
+            .unwrap();
+        let addr = wait_for(&mut a, |e| match e {
+            NodeEvent::ListenStarted { addr } => Some(addr),
+            _ => None,
+        })
+        .await;
+        let observer = NodeBuilder::new(NodeRole::Observer).spawn(generate_identity(KeyType::Ed25519)).unwrap();
+        observer.dial(addr.with(Protocol::P2p(a.peer_id()))).await.unwrap();
+
+        // In the mesh, so a publish would have somewhere to go
+        let topic = docstore::docstore_topic().to_string();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while observer.mesh_peers(topic.clone()).await.unwrap().is_empty() {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .expect("mesh never formed");
+
+        assert!(matches!(observer.publish(b"hello".to_vec()).await, Err(Error::ReadOnly)));
+        assert!(matches!(
+            observer.publish_doc_update(DocUpdate::new("doc", b"x".to_vec())).await,
+            Err(Error::ReadOnly)
+        ));
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(observer.stats().total.messages_out, 0);
+        assert_eq!(a.stats().total.messages_in, 0);
+    }
+
+    #[tokio::test]
+    async fn short_idle_timeout_closes_unused_connections() {
+        let builder = NodeBuilder::new(NodeRole::Client).with_idle_timeout(Duration::from_millis(300));
+        let make_swarm = || {
+            libp2p::SwarmBuilder::with_new_identity()
+                .with_tokio()
+                .with_other_transport(|key| {
+                    Ok(MemoryTransport::default()
+                        .upgrade(Version::V1)
+                        .authenticate(noise::Config::new(key)?)
+                        .multiplex(yamux::Config::default()))
+                })
+                .unwrap()
+                .with_behaviour(|_| ping::Behaviour::new(builder.ping_config()))
+                .unwrap()
+                .with_swarm_config(|c| c.with_idle_connection_timeout(builder.idle_timeout()))
+                .build()
+        };
+        let mut listener = make_swarm();
+        let mut dialer = make_swarm();
+        let addr: Multiaddr = format!("/memory/{}", rand::random::<u64>()).parse().unwrap();
+        listener.listen_on(addr.clone()).unwrap();
+        dialer.dial(addr).unwrap();
+
+        tokio::time::timeout(Duration::from_secs(10), async {
+            let mut established = false;
+            loop {
+                tokio::select! {
+                    _ = listener.select_next_some() => {}
+                    event = dialer.select_next_some() => match event {
+                        SwarmEvent::ConnectionEstablished { .. } => established = true,
+                        SwarmEvent::ConnectionClosed { .. } if established => return,
+                        _ => {}
+                    },
+                }
+            }
+        })
+        .await
+        .expect("idle connection was not closed");
+    }
+
+    #[tokio::test]
+    async fn dials_dns_bootstrap_addresses() {
+        let mut listener = NodeBuilder::new(NodeRole::FullNode)
+            .add_listen_addr("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .spawn(generate_identity(KeyType::Ed25519))
+            .unwrap();
+        let port = wait_for(&mut listener, |e| match e {
+            NodeEvent::ListenStarted { addr } => addr.iter().find_map(|p| match p {
+                Protocol::Tcp(port) => Some(port),
+                _ => None,
+            }),
+            _ => None,
+        })
+        .await;
+
+        let bootstrap: Multiaddr = format!("/dns4/localhost/tcp/{port}/p2p/{}", listener.peer_id())
+            .parse()
+            .unwrap();
+        let mut dialer = NodeBuilder::new(NodeRole::Client)
+            .add_bootstrap(bootstrap)
+            .spawn(generate_identity(KeyType::Ed25519))
+            .unwrap();
+        let (connected, transport, direction) = wait_for(&mut dialer, |e| match e {
+            NodeEvent::Connected { peer_id, transport, direction, .. } => Some((peer_id, transport, direction)),
+            _ => None,
+        })
+        .await;
+        assert_eq!(connected, listener.peer_id());
+        assert_eq!((transport, direction), ("tcp", "outbound"));
+        assert_eq!(dialer.stats().connections_by_transport.get("tcp"), Some(&1));
+    }
+
+    #[tokio::test]
+    async fn redials_a_lost_bootstrap_peer() {
+        let mut server = NodeBuilder::new(NodeRole::FullNode)
+            .add_listen_addr("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .spawn(generate_identity(KeyType::Ed25519))
+            .unwrap();
+        let addr = wait_for(&mut server, |e| match e {
+            NodeEvent::ListenStarted { addr } => Some(addr),
+            _ => None,
+        })
+        .await;
+        let mut client = NodeBuilder::new(NodeRole::Client)
+            .add_bootstrap(addr.with(Protocol::P2p(server.peer_id())))
+            .spawn(generate_identity(KeyType::Ed25519))
+            .unwrap();
+        wait_for(&mut client, |e| matches!(e, NodeEvent::Connected { .. }).then_some(())).await;
+
+        server.disconnect_peer(client.peer_id()).unwrap();
+        wait_for(&mut client, |e| matches!(e, NodeEvent::Disconnected { .. }).then_some(())).await;
+        let recovered = wait_for(&mut client, |e| match e {
+            NodeEvent::PeerRecovered { peer_id } => Some(peer_id),
+            _ => None,
+        })
+        .await;
+        assert_eq!(recovered, server.peer_id());
+    }
+
+    #[tokio::test]
+    async fn retries_refused_dials_and_reports_attempts() {
+        // A port nobody listens on refuses connections
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let addr: Multiaddr = format!("/ip4/127.0.0.1/tcp/{port}").parse().unwrap();
+        let mut node = NodeBuilder::new(NodeRole::Client).spawn(generate_identity(KeyType::Ed25519)).unwrap();
+
+        let required = DialOptions { require_peer_id: true, ..Default::default() };
+        assert!(node.dial_with(addr.clone(), required).await.is_err());
+
+        let options = DialOptions { retries: 2, backoff: Duration::from_millis(10), ..Default::default() };
+        node.dial_with(addr.clone(), options).await.unwrap();
+        let (failed_addr, attempts, reason) = wait_for(&mut node, |e| match e {
+            NodeEvent::DialFailed { addr, attempts, reason, .. } => Some((addr, attempts, reason)),
+            _ => None,
+        })
+        .await;
+        assert_eq!(failed_addr, addr);
+        assert_eq!(attempts, 3);
+        assert_eq!(reason, DialFailure::Refused);
+    }
+
+    #[tokio::test]
+    async fn failed_dials_push_a_peer_down_the_fetch_ranking() {
+        let mut node = NodeBuilder::new(NodeRole::Client).spawn(generate_identity(KeyType::Ed25519)).unwrap();
+        let (flaky, steady) = (PeerId::random(), PeerId::random());
+        assert_eq!(node.rank_peers([flaky, steady]), [flaky, steady]);
+
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let addr: Multiaddr = format!("/ip4/127.0.0.1/tcp/{port}/p2p/{flaky}").parse().unwrap();
+        let options = DialOptions { retries: 3, backoff: Duration::from_millis(10), ..Default::default() };
+        node.dial_with(addr, options).await.unwrap();
+        let attempts = wait_for(&mut node, |e| match e {
+            NodeEvent::DialFailed { attempts, .. } => Some(attempts),
+            _ => None,
+        })
+        .await;
+        assert_eq!(attempts, 4);
+        assert!(node.peer_score(&flaky) < 0.0);
+        assert_eq!(node.rank_peers([flaky, steady]), [steady, flaky]);
+
+        node.report_peer(steady, PeerSignal::FetchSucceeded);
+        assert_eq!(node.top_peers(1)[0].0, steady);
+    }
+
+    #[tokio::test]
+    async fn waits_until_connected_meshed_and_bootstrapped() {
+        let lonely = NodeBuilder::new(NodeRole::Client).spawn(generate_identity(KeyType::Ed25519)).unwrap();
+        match lonely.wait_ready(Duration::from_millis(200)).await {
+            Err(Error::NotReady { waiting_for }) => assert_eq!(waiting_for, ["connection", "mesh"]),
+            other => panic!("unexpected {other:?}"),
+        }
+
+        let mut server = NodeBuilder::new(NodeRole::FullNode)
+            .add_listen_addr("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .spawn(generate_identity(KeyType::Ed25519))
+            .unwrap();
+        let addr = wait_for(&mut server, |e| match e {
+            NodeEvent::ListenStarted { addr } => Some(addr),
+            _ => None,
+        })
+        .await;
+        let client = NodeBuilder::new(NodeRole::Client)
+            .add_bootstrap(addr.with(Protocol::P2p(server.peer_id())))
+            .spawn(generate_identity(KeyType::Ed25519))
+            .unwrap();
+        let readiness = client.wait_ready(Duration::from_secs(10)).await.unwrap();
+        assert!(readiness.connected && readiness.mesh);
+        assert!(readiness.dht_bootstrap.is_settled());
+        assert!(client.readiness().await.unwrap().is_ready());
+    }
+
+    #[tokio::test]
+    async fn reports_mesh_peers_and_empty_mesh() {
+        let mut a = NodeBuilder::new(NodeRole::FullNode)
+            .add_listen_addr("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .spawn(generate_identity(KeyType::Ed25519))
+            .unwrap();
+        let addr = wait_for(&mut a, |e| match e {
+            NodeEvent::ListenStarted { addr } => Some(addr),
+            _ => None,
+        })
+        .await;
+        let b = NodeBuilder::new(NodeRole::Client).spawn(generate_identity(KeyType::Ed25519)).unwrap();
+        b.dial(addr.with(Protocol::P2p(a.peer_id()))).await.unwrap();
+
+        let topic = docstore::docstore_topic().to_string();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !a.mesh_peers(topic.clone()).await.unwrap().contains(&b.peer_id()) {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .expect("peer never joined the mesh");
+        assert!(a.topic_peers(topic.clone()).await.unwrap().contains(&b.peer_id()));
+        assert!(a.topic_peers("no/such/topic").await.unwrap().is_empty());
+
+        a.disconnect_peer(b.peer_id()).unwrap();
+        let emptied = wait_for(&mut a, |e| match e {
+            NodeEvent::MeshEmpty { topic } => Some(topic),
+            _ => None,
+        })
+        .await;
+        assert_eq!(emptied, topic);
+    }
+
+    #[tokio::test]
+    async fn debounced_publishes_go_out_together_and_on_shutdown() {
+        let mut hub = NodeBuilder::new(NodeRole::FullNode)
+            .add_listen_addr("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .spawn(generate_identity(KeyType::Ed25519))
+            .unwrap();
+        let addr = wait_for(&mut hub, |e| match e {
+            NodeEvent::ListenStarted { addr } => Some(addr),
+            _ => None,
+        })
+        .await;
+        let node = NodeBuilder::new(NodeRole::Client).spawn(generate_identity(KeyType::Ed25519)).unwrap();
+        node.dial(addr.with(Protocol::P2p(hub.peer_id()))).await.unwrap();
+        let topic = docstore::docstore_topic().to_string();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while node.mesh_peers(topic.clone()).await.unwrap().is_empty() {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .expect("mesh never formed");
+        async fn received(hub: &mut Node) -> Vec<u8> {
+            wait_for(hub, |e| match e {
+                NodeEvent::DocUpdateReceived { update, .. } => Some(update.payload),
+                _ => None,
+            })
+            .await
+        }
+
+        node.set_publish_debounce(Some(Duration::from_millis(200))).unwrap();
+        let results = futures::future::join_all(
+            (0..3).map(|i| node.publish_doc_update(DocUpdate::new("notes", format!("edit {i}").into_bytes()))),
+        )
+        .await;
+        let ids: Vec<_> = results.into_iter().map(|r| r.unwrap().msg_id).collect();
+        assert!(ids.iter().all(|id| *id == ids[0]), "one message for the three: {ids:?}");
+        for i in 0..3 {
+            assert_eq!(received(&mut hub).await, format!("edit {i}").into_bytes());
+        }
+        assert_eq!(node.stats().total.messages_out, 1);
+
+        // Nowhere near the end of the window, but the node is going away
+        node.set_publish_debounce(Some(Duration::from_secs(60))).unwrap();
+        let (held, report) = tokio::join!(node.publish_doc_update(DocUpdate::new("notes", b"last".to_vec())), async {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            node.shutdown(ShutdownMode::Drain { timeout: Duration::from_secs(5) }).await
+        });
+        held.unwrap();
+        assert_eq!(report.unwrap().published, 1);
+        assert_eq!(received(&mut hub).await, b"last".to_vec());
+    }
+
+    #[tokio::test]
+    async fn counts_traffic_on_both_ends() {
+        let mut a = NodeBuilder::new(NodeRole::FullNode)
+            .add_listen_addr("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .spawn(generate_identity(KeyType::Ed25519))
+            .unwrap();
+        let addr = wait_for(&mut a, |e| match e {
+            NodeEvent::ListenStarted { addr } => Some(addr),
+            _ => None,
+        })
+        .await;
+        let mut b = NodeBuilder::new(NodeRole::Client).spawn(generate_identity(KeyType::Ed25519)).unwrap();
+        b.dial(addr.with(Protocol::P2p(a.peer_id()))).await.unwrap();
+
+        let topic = docstore::docstore_topic().to_string();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while b.mesh_peers(topic.clone()).await.unwrap().is_empty() {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .expect("mesh never formed");
+
+        let published = b.publish(b"hello".to_vec()).await.unwrap();
+        assert_eq!(published.sent_to, vec![a.peer_id()]);
+        let received = wait_for(&mut a, |e| match e {
+            NodeEvent::MessageReceived { topic, message_id, .. } => Some((topic, message_id)),
+            _ => None,
+        })
+        .await;
+        assert_eq!(received, (topic.clone(), published.msg_id));
+
+        let sent = b.stats();
+        assert_eq!(sent.total.messages_out, 1);
+        assert_eq!(sent.peers[&a.peer_id().to_string()].bytes_out, 5);
+        let received = a.stats();
+        assert_eq!(received.topics[&topic].messages_in, 1);
+        assert_eq!(received.peers[&b.peer_id().to_string()].bytes_in, 5);
+        assert_eq!(received.total.relayed_messages_in, 0);
+
+        a.reset_stats();
+        assert_eq!(a.stats().total, Default::default());
+    }
+
+    #[tokio::test]
+    async fn keeps_consumed_events_in_history() {
+        let mut node = NodeBuilder::new(NodeRole::Client)
+            .add_listen_addr("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .with_event_history(8, 4096)
+            .spawn(generate_identity(KeyType::Ed25519))
+            .unwrap();
+        let addr = wait_for(&mut node, |e| match e {
+            NodeEvent::ListenStarted { addr } => Some(addr),
+            _ => None,
+        })
+        .await;
+
+        let recent = node.recent_events(Some(&["listen_started"]), Some(1));
+        assert_eq!(recent.len(), 1);
+        assert!(matches!(&recent[0].event, NodeEvent::ListenStarted { addr: a } if *a == addr));
+        assert!(node.recent_events(Some(&["error"]), None).is_empty());
+    }
+
+    #[tokio::test]
+    async fn caches_identify_info_until_disconnect() {
+        let mut a = NodeBuilder::new(NodeRole::FullNode)
+            .add_listen_addr("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .spawn(generate_identity(KeyType::Ed25519))
+            .unwrap();
+        let addr = wait_for(&mut a, |e| match e {
+            NodeEvent::ListenStarted { addr } => Some(addr),
+            _ => None,
+        })
+        .await;
+        let b = NodeBuilder::new(NodeRole::Client).spawn(generate_identity(KeyType::Ed25519)).unwrap();
+        b.dial(addr.with(Protocol::P2p(a.peer_id()))).await.unwrap();
+
+        let b_id = b.peer_id();
+        let (info, shared) = wait_for(&mut a, |e| match e {
+            NodeEvent::PeerIdentified { peer_id, info, shared_protocols } if peer_id == b_id => {
+                Some((info, shared_protocols))
+            }
+            _ => None,
+        })
+        .await;
+        assert!(!info.protocols.is_empty());
+        for protocol in ["/ipfs/id/1.0.0", "/ipfs/ping/1.0.0"] {
+            assert!(shared.iter().any(|p| p == protocol), "{protocol} missing from {shared:?}");
+        }
+        assert_eq!(a.peer_info(b_id).await.unwrap(), Some(info));
+
+        a.disconnect_peer(b_id).unwrap();
+        wait_for(&mut a, |e| matches!(e, NodeEvent::Disconnected { peer_id } if peer_id == b_id).then_some(())).await;
+        assert_eq!(a.peer_info(b_id).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn advertises_configured_agent_version() {
+        let mut a = NodeBuilder::new(NodeRole::FullNode)
+            .add_listen_addr("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .spawn(generate_identity(KeyType::Ed25519))
+            .unwrap();
+        let addr = wait_for(&mut a, |e| match e {
+            NodeEvent::ListenStarted { addr } => Some(addr),
+            _ => None,
+        })
+        .await;
+        let b = NodeBuilder::new(NodeRole::Client)
+            .with_agent_version("my-app/2.3")
+            .spawn(generate_identity(KeyType::Ed25519))
+            .unwrap();
+        b.dial(addr.with(Protocol::P2p(a.peer_id()))).await.unwrap();
+
+        let info = wait_for(&mut a, |e| match e {
+            NodeEvent::PeerIdentified { info, .. } => Some(info),
+            _ => None,
+        })
+        .await;
+        assert_eq!(info.agent_version, "my-app/2.3");
+        assert_eq!(info.protocol_version, crate::behaviour::DEFAULT_PROTOCOL_VERSION);
+    }
+
+    #[tokio::test]
+    async fn replicas_serve_what_they_learned_but_never_publish() {
+        let mut replica = NodeBuilder::new(NodeRole::FullNode)
+            .add_listen_addr("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .with_replica(true)
+            .spawn(generate_identity(KeyType::Ed25519))
+            .unwrap();
+        let replica_id = replica.peer_id();
+        let addr = wait_for(&mut replica, |e| match e {
+            NodeEvent::ListenStarted { addr } => Some(addr),
+            _ => None,
+        })
+        .await
+        .with(Protocol::P2p(replica_id));
+        let writer = NodeBuilder::new(NodeRole::Client).spawn(generate_identity(KeyType::Ed25519)).unwrap();
+        writer.dial(addr.clone()).await.unwrap();
+        writer.wait_ready(Duration::from_secs(10)).await.unwrap();
+
+        // Learned through gossip, like on any FullNode
+        let content: Vec<u8> = (0..200u8).collect();
+        writer.publish_doc_update(DocUpdate::new("doc", content.clone())).await.unwrap();
+        wait_for(&mut replica, |e| match e {
+            NodeEvent::DocUpdateReceived { update, .. } if update.doc_id == "doc" => Some(()),
+            _ => None,
+        })
+        .await;
+        let status = replica.replication_status().await.unwrap();
+        assert!(status.replica && status.newest_update_ms.is_some());
+
+        // Readers can tell it is a replica, and fetch from it
+        let mut reader = NodeBuilder::new(NodeRole::Client).spawn(generate_identity(KeyType::Ed25519)).unwrap();
+        reader.dial(addr).await.unwrap();
+        let info = wait_for(&mut reader, |e| match e {
+            NodeEvent::PeerIdentified { peer_id, info, .. } if peer_id == replica_id => Some(info),
+            _ => None,
+        })
+        .await;
+        assert!(info.is_replica());
+        assert_eq!(reader.fetch_document("doc", Some(replica_id)).await.unwrap(), (replica_id, content));
+
+        let local = replica.publish_doc_update(DocUpdate::new("doc", b"mine".to_vec())).await;
+        assert!(matches!(local, Err(Error::ReadOnly)));
+        assert!(!writer.replication_status().await.unwrap().replica);
+    }
+
+    #[tokio::test]
+    async fn republishes_own_records_before_they_expire() {
+        let mut a = NodeBuilder::new(NodeRole::FullNode)
+            .add_listen_addr("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .spawn(generate_identity(KeyType::Ed25519))
+            .unwrap();
+        let addr = wait_for(&mut a, |e| match e {
+            NodeEvent::ListenStarted { addr } => Some(addr),
+            _ => None,
+        })
+        .await;
+        let mut b = NodeBuilder::new(NodeRole::FullNode)
+            .add_listen_addr("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .spawn(generate_identity(KeyType::Ed25519))
+            .unwrap();
+        b.dial(addr.with(Protocol::P2p(a.peer_id()))).await.unwrap();
+        // Identify puts b's listen address into a's routing table
+        let b_id = b.peer_id();
+        wait_for(&mut a, |e| matches!(e, NodeEvent::PeerIdentified { peer_id, .. } if peer_id == b_id).then_some(()))
+            .await;
+
+        a.put_record(b"greeting".to_vec(), b"hello".to_vec(), Some(Duration::from_secs(2))).await.unwrap();
+        let key = RecordKey::new(&b"greeting");
+        let (from, stored) = wait_for(&mut b, |e| match e {
+            NodeEvent::RecordStored { peer_id, key } => Some((peer_id, key)),
+            _ => None,
+        })
+        .await;
+        assert_eq!((from, stored), (a.peer_id(), key.clone()));
+
+        let republished = wait_for(&mut a, |e| match e {
+            NodeEvent::RecordRepublished { key } => Some(key),
+            NodeEvent::RecordRepublishFailed { error, .. } => panic!("republish failed: {error}"),
+            _ => None,
+        })
+        .await;
+        assert_eq!(republished, key);
+
+        assert!(a.forget_record(b"greeting".to_vec()).await.unwrap());
+        assert!(!a.forget_record(b"greeting".to_vec()).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn resolves_published_head_pointers() {
+        let mut store = MemoryDocStore::default();
+        store.apply_update(&DocUpdate::new("notes", b"v1".to_vec()));
+        let mut a = NodeBuilder::new(NodeRole::FullNode)
+            .add_listen_addr("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .with_store(store)
+            .spawn(generate_identity(KeyType::Ed25519))
+            .unwrap();
+        let addr = wait_for(&mut a, |e| match e {
+            NodeEvent::ListenStarted { addr } => Some(addr),
+            _ => None,
+        })
+        .await;
+        let b = NodeBuilder::new(NodeRole::FullNode).spawn(generate_identity(KeyType::Ed25519)).unwrap();
+        b.dial(addr.with(Protocol::P2p(a.peer_id()))).await.unwrap();
+        let b_id = b.peer_id();
+        wait_for(&mut a, |e| matches!(e, NodeEvent::PeerIdentified { peer_id, .. } if peer_id == b_id).then_some(()))
+            .await;
+
+        let pointer = a.publish_head_pointer("notes").await.unwrap();
+        assert_eq!(pointer.version, a.get_document("notes").await.unwrap().unwrap().0);
+        assert!(pointer.matches(b"v1"));
+        assert_eq!(b.resolve_head(a.peer_id(), "notes").await.unwrap(), Some(pointer));
+        assert_eq!(b.resolve_head(a.peer_id(), "todo").await.unwrap(), None);
+        assert!(matches!(a.publish_head_pointer("todo").await, Err(Error::InvalidArgument { .. })));
+    }
+
+    #[tokio::test]
+    async fn resolves_published_profiles() {
+        let mut a = NodeBuilder::new(NodeRole::FullNode)
+            .add_listen_addr("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .spawn(generate_identity(KeyType::Ed25519))
+            .unwrap();
+        let addr = wait_for(&mut a, |e| match e {
+            NodeEvent::ListenStarted { addr } => Some(addr),
+            _ => None,
+        })
+        .await;
+        let b = NodeBuilder::new(NodeRole::FullNode).spawn(generate_identity(KeyType::Ed25519)).unwrap();
+        b.dial(addr.clone().with(Protocol::P2p(a.peer_id()))).await.unwrap();
+        let b_id = b.peer_id();
+        wait_for(&mut a, |e| matches!(e, NodeEvent::PeerIdentified { peer_id, .. } if peer_id == b_id).then_some(()))
+            .await;
+
+        assert_eq!(b.resolve_profile(a.peer_id()).await.unwrap(), None);
+        let profile = a.set_profile(Profile::new("Ada").with_avatar_hash([1; 32])).await.unwrap();
+        assert_eq!(a.resolve_profile(a.peer_id()).await.unwrap(), Some(profile.clone()));
+        // b cached the miss; a fresh node asks the DHT
+        let c = NodeBuilder::new(NodeRole::FullNode).spawn(generate_identity(KeyType::Ed25519)).unwrap();
+        c.dial(addr.with(Protocol::P2p(a.peer_id()))).await.unwrap();
+        let c_id = c.peer_id();
+        wait_for(&mut a, |e| matches!(e, NodeEvent::PeerIdentified { peer_id, .. } if peer_id == c_id).then_some(()))
+            .await;
+        let resolved = c.resolve_profile(a.peer_id()).await.unwrap().unwrap();
+        assert_eq!(resolved.profile.display_name, "Ada");
+        assert!(matches!(a.set_profile(Profile::new("x".repeat(100))).await, Err(Error::Profile(_))));
+    }
+
+    #[tokio::test]
+    async fn mailbox_messages_wait_for_their_recipient() {
+        let mut holder = NodeBuilder::new(NodeRole::FullNode)
+            .add_listen_addr("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .spawn(generate_identity(KeyType::Ed25519))
+            .unwrap();
+        let addr = wait_for(&mut holder, |e| match e {
+            NodeEvent::ListenStarted { addr } => Some(addr),
+            _ => None,
+        })
+        .await
+        .with(Protocol::P2p(holder.peer_id()));
+        let sender = NodeBuilder::new(NodeRole::Client).spawn(generate_identity(KeyType::Ed25519)).unwrap();
+        sender.dial(addr.clone()).await.unwrap();
+        let sender_id = sender.peer_id();
+        wait_for(&mut holder, |e| matches!(e, NodeEvent::PeerIdentified { peer_id, .. } if peer_id == sender_id).then_some(()))
+            .await;
+
+        // The recipient is offline
+        let recipient_key = generate_identity(KeyType::Ed25519);
+        let recipient_id = recipient_key.public().to_peer_id();
+        let receipt = sender.send_to_mailbox(holder.peer_id(), recipient_id, b"sealed".to_vec(), None).await.unwrap();
+        let too_big = vec![0; MailboxLimits::default().max_blob_bytes + 1];
+        assert!(matches!(
+            sender.send_to_mailbox(holder.peer_id(), recipient_id, too_big, None).await,
+            Err(Error::MailboxRejected { .. })
+        ));
+        // Clients hold nothing for others
+        assert!(matches!(
+            sender.send_to_mailbox(sender_id, recipient_id, b"x".to_vec(), None).await,
+            Err(Error::MailboxRejected { .. })
+        ));
+
+        let mut recipient = NodeBuilder::new(NodeRole::Client).spawn(recipient_key).unwrap();
+        recipient.dial(addr).await.unwrap();
+        let (from, message) = wait_for(&mut recipient, |e| match e {
+            NodeEvent::MailboxDelivered { holder, message } => Some((holder, message)),
+            _ => None,
+        })
+        .await;
+        assert_eq!(from, holder.peer_id());
+        assert_eq!((message.id, message.sender, message.blob), (receipt.id, sender_id.to_string(), b"sealed".to_vec()));
+        // Acknowledged, so deleted; the ack may still be on its way
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !recipient.check_mailbox(holder.peer_id()).await.unwrap().is_empty() {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("message not deleted");
+    }
+
+    #[cfg(feature = "rendezvous")]
+    #[tokio::test]
+    async fn clients_discover_through_rendezvous_points() {
+        let mut point = NodeBuilder::new(NodeRole::FullNode)
+            .add_listen_addr("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .spawn(generate_identity(KeyType::Ed25519))
+            .unwrap();
+        let addr = wait_for(&mut point, |e| match e {
+            NodeEvent::ListenStarted { addr } => Some(addr),
+            _ => None,
+        })
+        .await
+        .with(Protocol::P2p(point.peer_id()));
+        assert!(matches!(point.discover_peers().await, Err(Error::RendezvousDisabled)));
+
+        let mut client = NodeBuilder::new(NodeRole::Client).spawn(generate_identity(KeyType::Ed25519)).unwrap();
+        // Nobody to ask yet
+        assert!(client.discover_peers().await.unwrap().is_empty());
+        client.dial(addr).await.unwrap();
+        let point_id = point.peer_id();
+        wait_for(&mut client, |e| matches!(e, NodeEvent::PeerIdentified { peer_id, .. } if peer_id == point_id).then_some(()))
+            .await;
+        // Asked the point; no one could register, for lack of an external address
+        assert!(client.discover_peers().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn corrupted_documents_are_quarantined_and_fetched_again() {
+        let dir = std::env::temp_dir().join(format!("node-scrub-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut store = FileDocStore::open(&dir).unwrap();
+        store.apply_update(&DocUpdate::new("notes", b"v1".to_vec()));
+        drop(store);
+        let log = std::fs::read_dir(&dir).unwrap().next().unwrap().unwrap().path().join("log");
+        let mut bytes = std::fs::read(&log).unwrap();
+        *bytes.last_mut().unwrap() ^= 1;
+        std::fs::write(&log, bytes).unwrap();
+
+        let mut healthy = MemoryDocStore::default();
+        healthy.apply_update(&DocUpdate::new("notes", b"v1".to_vec()));
+        let mut a = NodeBuilder::new(NodeRole::FullNode)
+            .add_listen_addr("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .with_store_path(&dir)
+            .spawn(generate_identity(KeyType::Ed25519))
+            .unwrap();
+        let addr = wait_for(&mut a, |e| match e {
+            NodeEvent::ListenStarted { addr } => Some(addr),
+            _ => None,
+        })
+        .await;
+        let b = NodeBuilder::new(NodeRole::FullNode).with_store(healthy).spawn(generate_identity(KeyType::Ed25519)).unwrap();
+        b.dial(addr.with(Protocol::P2p(a.peer_id()))).await.unwrap();
+        let b_id = b.peer_id();
+        wait_for(&mut a, |e| matches!(e, NodeEvent::PeerIdentified { peer_id, .. } if peer_id == b_id).then_some(()))
+            .await;
+
+        let report = a.verify_now().await.unwrap();
+        assert_eq!(report.verified, 1);
+        assert_eq!(report.corrupted, [("notes".to_string(), Corruption::LogRecord { index: 0 })]);
+        assert_eq!(a.get_document("notes").await.unwrap(), None, "no longer served");
+        let stats = a.scrub_stats().await.unwrap();
+        assert_eq!((stats.verified, stats.corrupted), (1, 1));
+
+        wait_for(&mut a, |e| matches!(e, NodeEvent::StoreCorruption { doc_id, .. } if doc_id == "notes").then_some(())).await;
+        let repaired_from = wait_for(&mut a, |e| match e {
+            NodeEvent::StoreRepaired { peer_id, .. } => Some(peer_id),
+            _ => None,
+        })
+        .await;
+        assert_eq!(repaired_from, b_id);
+        assert_eq!(a.get_document("notes").await.unwrap().map(|(_, content)| content), Some(b"v1".to_vec()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn endless_record_ttls_are_no_expiry() {
+        let a = NodeBuilder::new(NodeRole::FullNode).spawn(generate_identity(KeyType::Ed25519)).unwrap();
+        // With nobody to put it to the put fails its quorum; the event loop carries on
+        let _ = a.put_record(b"forever".to_vec(), b"hello".to_vec(), Some(Duration::MAX)).await;
+        assert!(!a.forget_record(b"forever".to_vec()).await.unwrap(), "never republished");
+    }
+
+    #[tokio::test]
+    async fn reports_a_full_record_store() {
+        let mut dht = crate::behaviour::PeerDhtConfig::default();
+        dht.store.max_records = 1;
+        let mut a = NodeBuilder::new(NodeRole::FullNode).with_dht(dht).spawn(generate_identity(KeyType::Ed25519)).unwrap();
+
+        // With nobody to put it to the first put fails its quorum, but the record is stored
+        let _ = a.put_record(b"first".to_vec(), b"hello".to_vec(), None).await;
+        assert!(matches!(a.put_record(b"second".to_vec(), b"hello".to_vec(), None).await, Err(Error::Dht(_))));
+        let kind = wait_for(&mut a, |e| match e {
+            NodeEvent::DhtStoreFull { kind } => Some(kind),
+            _ => None,
+        })
+        .await;
+        assert_eq!(kind, StoreFullKind::Records);
+
+        let stats = a.dht_store_stats().await.unwrap();
+        assert_eq!((stats.records, stats.record_bytes), (1, b"first".len() + b"hello".len()));
+    }
+
+    #[tokio::test]
+    async fn renews_announcements_after_an_outage() {
+        let mut hub = NodeBuilder::new(NodeRole::FullNode)
+            .add_listen_addr("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .spawn(generate_identity(KeyType::Ed25519))
+            .unwrap();
+        let addr = wait_for(&mut hub, |e| match e {
+            NodeEvent::ListenStarted { addr } => Some(addr),
+            _ => None,
+        })
+        .await
+        .with(Protocol::P2p(hub.peer_id()));
+        let mut node = NodeBuilder::new(NodeRole::Client)
+            .with_reannounce_after(Duration::ZERO)
+            .spawn(generate_identity(KeyType::Ed25519))
+            .unwrap();
+        let hub_id = hub.peer_id();
+        node.dial(addr.clone()).await.unwrap();
+        wait_for(&mut node, |e| matches!(e, NodeEvent::PeerIdentified { peer_id, .. } if peer_id == hub_id).then_some(()))
+            .await;
+        assert!(node.pin("kept").await.unwrap());
+        // Withdrawn before the outage: not announced again
+        assert!(node.pin("dropped").await.unwrap());
+        assert!(node.unpin("dropped").await.unwrap());
+
+        node.disconnect_peer(hub_id).unwrap();
+        wait_for(&mut node, |e| matches!(e, NodeEvent::Disconnected { .. }).then_some(())).await;
+        node.dial(addr).await.unwrap();
+        let (renewed, failed) = wait_for(&mut node, |e| match e {
+            NodeEvent::AnnouncementsRenewed { renewed, failed } => Some((renewed, failed)),
+            _ => None,
+        })
+        .await;
+        assert_eq!((renewed, failed), (1, Vec::new()));
+    }
+
+    #[tokio::test]
+    async fn reports_requested_routing_removals() {
+        let mut node = NodeBuilder::new(NodeRole::Client).spawn(generate_identity(KeyType::Ed25519)).unwrap();
+        let peer = PeerId::random();
+        let addr: Multiaddr = "/ip4/127.0.0.1/tcp/4001".parse().unwrap();
+
+        node.remove_peer_address(peer, addr.clone()).unwrap();
+        let removed = wait_for(&mut node, |e| match e {
+            NodeEvent::AddressRemoved { peer_id, addr, reason } => Some((peer_id, addr, reason)),
+            _ => None,
+        })
+        .await;
+        assert_eq!(removed, (peer, addr, RemovalReason::Requested));
+
+        node.remove_peer(peer).unwrap();
+        let removed = wait_for(&mut node, |e| match e {
+            NodeEvent::PeerRemoved { peer_id, reason } => Some((peer_id, reason)),
+            _ => None,
+        })
+        .await;
+        assert_eq!(removed, (peer, RemovalReason::Requested));
+    }
+
+    #[tokio::test]
+    async fn finds_peers_locally_and_through_the_dht() {
+        let mut a = NodeBuilder::new(NodeRole::FullNode)
+            .add_listen_addr("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .spawn(generate_identity(KeyType::Ed25519))
+            .unwrap();
+        let addr = wait_for(&mut a, |e| match e {
+            NodeEvent::ListenStarted { addr } => Some(addr),
+            _ => None,
+        })
+        .await;
+        let b = NodeBuilder::new(NodeRole::FullNode)
+            .add_listen_addr("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .spawn(generate_identity(KeyType::Ed25519))
+            .unwrap();
+        b.dial(addr.clone().with(Protocol::P2p(a.peer_id()))).await.unwrap();
+        let b_id = b.peer_id();
+        wait_for(&mut a, |e| matches!(e, NodeEvent::PeerIdentified { peer_id, .. } if peer_id == b_id).then_some(()))
+            .await;
+
+        let local = a.find_peer_local(b_id).await.unwrap().expect("b is in a's routing table");
+        assert!(local.is_dialable());
+        assert_eq!(a.find_peer_local(PeerId::random()).await.unwrap(), None);
+
+        // c only knows a and learns b's addresses from it
+        let mut c = NodeBuilder::new(NodeRole::Client).spawn(generate_identity(KeyType::Ed25519)).unwrap();
+        c.dial(addr.with(Protocol::P2p(a.peer_id()))).await.unwrap();
+        let a_id = a.peer_id();
+        wait_for(&mut c, |e| matches!(e, NodeEvent::PeerIdentified { peer_id, .. } if peer_id == a_id).then_some(()))
+            .await;
+        let options = FindPeerOptions { num_results: 2, dial: true, ..Default::default() };
+        let found = c.find_peer(b_id, options).await.unwrap();
+        assert_eq!(found[0].peer_id, b_id);
+        assert!(found[0].is_dialable());
+        wait_for(&mut c, |e| matches!(e, NodeEvent::Connected { peer_id, .. } if peer_id == b_id).then_some(())).await;
+    }
+
+    #[cfg(feature = "relay")]
+    #[tokio::test]
+    async fn discovers_relays_through_the_dht() {
+        let mut hub = NodeBuilder::new(NodeRole::FullNode)
+            .add_listen_addr("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .spawn(generate_identity(KeyType::Ed25519))
+            .unwrap();
+        let addr = wait_for(&mut hub, |e| match e {
+            NodeEvent::ListenStarted { addr } => Some(addr),
+            _ => None,
+        })
+        .await
+        .with(Protocol::P2p(hub.peer_id()));
+        let relay = NodeBuilder::new(NodeRole::Relay)
+            .add_listen_addr("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .add_bootstrap(addr.clone())
+            .spawn(generate_identity(KeyType::Ed25519))
+            .unwrap();
+        let mut client =
+            NodeBuilder::new(NodeRole::Client).add_bootstrap(addr).spawn(generate_identity(KeyType::Ed25519)).unwrap();
+
+        // The relay announces itself to the hub once its bootstrap is done
+        let relay_id = relay.peer_id();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !client.discover_relays().await.unwrap().contains(&relay_id) {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .expect("the relay never showed up as a provider");
+        wait_for(&mut client, |e| {
+            matches!(e, NodeEvent::RelayDiscovered { peer_id } if peer_id == relay_id).then_some(())
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn relay_introduces_its_discoverable_clients() {
+        use crate::testing::spawn_test_node;
+
+        let (_relay, relay_addr) = spawn_test_node(NodeBuilder::new(NodeRole::Relay)).await.unwrap();
+        let (mut a, _) = spawn_test_node(NodeBuilder::new(NodeRole::Client).with_discoverable(true)).await.unwrap();
+        let (mut b, _) = spawn_test_node(NodeBuilder::new(NodeRole::Client).with_discoverable(true)).await.unwrap();
+        a.dial(relay_addr.clone()).await.unwrap();
+        b.dial(relay_addr).await.unwrap();
+
+        // Whichever client asks the relay second finds the other listed and dials it
+        let (a_id, b_id) = (a.peer_id(), b.peer_id());
+        let connected_to = |event: Option<NodeEvent>, other: PeerId| {
+            matches!(event, Some(NodeEvent::Connected { peer_id, .. }) if peer_id == other)
+        };
+        tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                tokio::select! {
+                    event = a.next_event() => if connected_to(event, b_id) { return },
+                    event = b.next_event() => if connected_to(event, a_id) { return },
+                }
+            }
+        })
+        .await
+        .expect("the clients never connected to each other");
+    }
+
+    #[tokio::test]
+    async fn reports_routing_table_changes() {
+        let mut a = NodeBuilder::new(NodeRole::FullNode)
+            .add_listen_addr("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .spawn(generate_identity(KeyType::Ed25519))
+            .unwrap();
+        let addr = wait_for(&mut a, |e| match e {
+            NodeEvent::ListenStarted { addr } => Some(addr),
+            _ => None,
+        })
+        .await;
+        assert_eq!(a.dht_summary().await.unwrap().peers, 0);
+
+        let b = NodeBuilder::new(NodeRole::FullNode)
+            .add_listen_addr("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .spawn(generate_identity(KeyType::Ed25519))
+            .unwrap();
+        b.dial(addr.with(Protocol::P2p(a.peer_id()))).await.unwrap();
+        let b_id = b.peer_id();
+        let (is_new_peer, addrs) = wait_for(&mut a, |e| match e {
+            NodeEvent::RoutingUpdated { peer_id, is_new_peer, addrs, .. } if peer_id == b_id => Some((is_new_peer, addrs)),
+            _ => None,
+        })
+        .await;
+        assert!(is_new_peer);
+        assert!(!addrs.is_empty());
+        let summary = wait_for(&mut a, |e| match e {
+            NodeEvent::DhtSummaryChanged { summary } => Some(summary),
+            _ => None,
+        })
+        .await;
+        assert_eq!(summary.peers, 1);
+        assert_eq!(a.dht_summary().await.unwrap(), summary);
+
+        a.remove_peer(b_id).unwrap();
+        let summary = wait_for(&mut a, |e| match e {
+            NodeEvent::DhtSummaryChanged { summary } => Some(summary),
+            _ => None,
+        })
+        .await;
+        assert_eq!(summary.peers, 0);
+    }
+
+    #[test]
+    fn builder_flags_enable_optional_behaviours() {
+        let key = identity::Keypair::generate_ed25519();
+        let client = NodeBuilder::new(crate::node::NodeRole::Client).build_behaviours(&key).unwrap();
+        assert!(!client.relay.is_enabled());
+        assert!(!client.nat.upnp.is_enabled() && !client.nat.autonat.is_enabled());
+
+        let full = NodeBuilder::new(crate::node::NodeRole::FullNode).with_autonat(true).build_behaviours(&key).unwrap();
+        assert_eq!(full.relay.is_enabled(), cfg!(feature = "relay"));
+        assert!(full.nat.autonat.is_enabled() && !full.nat.upnp.is_enabled());
+    }
+
+    #[tokio::test]
+    async fn invalid_builder_settings_fail_to_spawn() {
+        let key = identity::Keypair::generate_ed25519();
+        let zero_ttl = crate::behaviour::PeerDhtConfig { record_ttl: Some(Duration::ZERO), ..Default::default() };
+        let builder = NodeBuilder::new(NodeRole::Client).with_dht(zero_ttl);
+        assert!(matches!(builder.build_behaviours(&key), Err(Error::InvalidConfig(_))));
+        assert!(matches!(builder.spawn(key.clone()), Err(Error::InvalidConfig(_))));
+
+        // A listen port in use is caught before a swarm is built
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = format!("/ip4/127.0.0.1/tcp/{}", taken.local_addr().unwrap().port()).parse().unwrap();
+        let builder = NodeBuilder::new(NodeRole::FullNode).add_listen_addr(addr);
+        assert!(matches!(builder.check(), Err(crate::node::config_check::ConfigError::CannotListen { .. })));
+        assert!(matches!(builder.spawn(key), Err(Error::InvalidConfig(msg)) if msg.contains("cannot listen")));
+    }
+
+    #[tokio::test]
+    async fn namespaces_isolate_traffic() {
+        let mut a = NodeBuilder::new(NodeRole::FullNode)
+            .with_topic_namespace("myapp")
+            .add_listen_addr("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .spawn(generate_identity(KeyType::Ed25519))
+            .unwrap();
+        let addr = wait_for(&mut a, |e| match e {
+            NodeEvent::ListenStarted { addr } => Some(addr),
+            _ => None,
+        })
+        .await
+        .with(Protocol::P2p(a.peer_id()));
+        let b = NodeBuilder::new(NodeRole::Client)
+            .with_topic_namespace("myapp")
+            .spawn(generate_identity(KeyType::Ed25519))
+            .unwrap();
+        let other = NodeBuilder::new(NodeRole::Client).spawn(generate_identity(KeyType::Ed25519)).unwrap();
+        b.dial(addr.clone()).await.unwrap();
+        other.dial(addr).await.unwrap();
+
+        let topic = TopicRegistry::new("myapp").updates().to_string();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !a.mesh_peers(topic.clone()).await.unwrap().contains(&b.peer_id()) {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .expect("mesh never formed");
+
+        assert_eq!(b.publish(b"ours".to_vec()).await.unwrap().sent_to, vec![a.peer_id()]);
+        // Connected, but nobody subscribes to the default namespace
+        assert!(other.publish(b"theirs".to_vec()).await.is_err());
+        assert!(!a.topic_peers(topic).await.unwrap().contains(&other.peer_id()));
+    }
+
+    #[tokio::test]
+    async fn transactions_are_applied_whole_on_receipt() {
+        let mut hub = NodeBuilder::new(NodeRole::FullNode)
+            .add_listen_addr("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .spawn(generate_identity(KeyType::Ed25519))
+            .unwrap();
+        let addr = wait_for(&mut hub, |e| match e {
+            NodeEvent::ListenStarted { addr } => Some(addr),
+            _ => None,
+        })
+        .await
+        .with(Protocol::P2p(hub.peer_id()));
+        let node = NodeBuilder::new(NodeRole::Client).spawn(generate_identity(KeyType::Ed25519)).unwrap();
+        node.dial(addr).await.unwrap();
+        node.wait_ready(Duration::from_secs(10)).await.unwrap();
+
+        assert!(matches!(node.begin_transaction().commit().await, Err(Error::EmptyTransaction)));
+        // Too large for one message, so it goes out in two parts
+        let mut tx = node.begin_transaction();
+        tx.add_update(DocUpdate::new("project/meta", vec![1; 200 * 1024]))
+            .add_update(DocUpdate::new("project/content", vec![2; 200 * 1024]));
+        let id = tx.id();
+        assert_eq!(tx.commit().await.unwrap().len(), 2);
+        assert_eq!(node.get_document("project/meta").await.unwrap().map(|(v, _)| v), Some(1));
+
+        let mut received = Vec::new();
+        let doc_ids = wait_for(&mut hub, |e| match e {
+            NodeEvent::DocUpdateReceived { update, .. } => {
+                received.push(update.doc_id);
+                None
+            }
+            NodeEvent::TransactionApplied { id: applied, doc_ids, .. } if applied == id => Some(doc_ids),
+            _ => None,
+        })
+        .await;
+        assert_eq!(doc_ids, ["project/meta", "project/content"]);
+        assert_eq!(received, doc_ids);
+        for (doc_id, byte) in [("project/meta", 1), ("project/content", 2)] {
+            let (version, content) = hub.get_document(doc_id).await.unwrap().unwrap();
+            assert_eq!((version, content.len(), content[0]), (1, 200 * 1024, byte));
+        }
+    }
+
+    #[tokio::test]
+    async fn full_nodes_hold_rooms_to_their_quotas() {
+        let quotas = RoomQuotas::new([("team".to_string(), RoomQuota { max_docs: Some(1), ..Default::default() })]);
+        let mut hub = NodeBuilder::new(NodeRole::FullNode)
+            .add_listen_addr("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .with_room_quotas(quotas)
+            .spawn(generate_identity(KeyType::Ed25519))
+            .unwrap();
+        let addr = wait_for(&mut hub, |e| match e {
+            NodeEvent::ListenStarted { addr } => Some(addr),
+            _ => None,
+        })
+        .await
+        .with(Protocol::P2p(hub.peer_id()));
+        let node = NodeBuilder::new(NodeRole::Client).spawn(generate_identity(KeyType::Ed25519)).unwrap();
+        node.dial(addr).await.unwrap();
+        node.wait_ready(Duration::from_secs(10)).await.unwrap();
+
+        node.publish_doc_update(DocUpdate::new("team/a", b"v1".to_vec())).await.unwrap();
+        node.publish_doc_update(DocUpdate::new("team/b", b"v1".to_vec())).await.unwrap();
+        let node_id = node.peer_id();
+        let reason = wait_for(&mut hub, |e| match e {
+            NodeEvent::QuotaExceeded { peer_id, reason } if peer_id == node_id => Some(reason),
+            _ => None,
+        })
+        .await;
+        assert_eq!(reason, QuotaExceeded::Docs { room: "team".into(), max: 1 });
+        assert!(hub.get_document("team/a").await.unwrap().is_some());
+        assert!(hub.get_document("team/b").await.unwrap().is_none());
+        let usage = hub.room_usage().await.unwrap();
+        assert_eq!((usage[0].room.as_str(), usage[0].usage.docs), ("team", 1));
+
+        // The hub's own updates are held to the quota too, until it is lifted
+        let local = hub.publish_doc_update(DocUpdate::new("team/c", b"v1".to_vec())).await;
+        assert!(matches!(local, Err(Error::QuotaExceeded(QuotaExceeded::Docs { .. }))));
+        assert!(hub.set_room_quota("team", None).await.unwrap().is_some());
+        hub.publish_doc_update(DocUpdate::new("team/c", b"v1".to_vec())).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn one_author_over_quota_leaves_others_unaffected() {
+        let quotas = AuthorQuotas::new(AuthorQuota { max_bytes: Some(100), ..Default::default() }, []);
+        let mut hub = NodeBuilder::new(NodeRole::FullNode)
+            .add_listen_addr("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .with_author_quotas(quotas)
+            .spawn(generate_identity(KeyType::Ed25519))
+            .unwrap();
+        let addr = wait_for(&mut hub, |e| match e {
+            NodeEvent::ListenStarted { addr } => Some(addr),
+            _ => None,
+        })
+        .await
+        .with(Protocol::P2p(hub.peer_id()));
+        let mut alice = NodeBuilder::new(NodeRole::Client).spawn(generate_identity(KeyType::Ed25519)).unwrap();
+        let bob = NodeBuilder::new(NodeRole::Client).spawn(generate_identity(KeyType::Ed25519)).unwrap();
+        for node in [&alice, &bob] {
+            node.dial(addr.clone()).await.unwrap();
+            node.wait_ready(Duration::from_secs(10)).await.unwrap();
+        }
+
+        alice.publish_doc_update(DocUpdate::new("a1", vec![1; 60])).await.unwrap();
+        let options = PublishOptions { ack_requested: true, ..Default::default() };
+        let refused = alice.publish_doc_update_with(DocUpdate::new("a2", vec![1; 60]), options).await.unwrap();
+        let alice_id = alice.peer_id();
+        let reason = wait_for(&mut hub, |e| match e {
+            NodeEvent::QuotaExceeded { peer_id, reason } if peer_id == alice_id => Some(reason),
+            _ => None,
+        })
+        .await;
+        assert_eq!(reason, QuotaExceeded::AuthorBytes { author: alice_id.to_string(), bytes: 120, max: 100 });
+        let hub_id = hub.peer_id();
+        let msg_id = wait_for(&mut alice, |e| match e {
+            NodeEvent::QuotaRefused { msg_id, peer_id, doc_id } if peer_id == hub_id && doc_id == "a2" => Some(msg_id),
+            _ => None,
+        })
+        .await;
+        assert_eq!(msg_id, refused.msg_id);
+        // The reason waits in the hub's mailbox
+        let notices = alice.check_mailbox(hub_id).await.unwrap();
+        assert_eq!(notices.len(), 1);
+        assert!(String::from_utf8_lossy(&notices[0].blob).contains("over their quota of 100"));
+
+        // Bob has a quota of his own to use up
+        bob.publish_doc_update(DocUpdate::new("b1", vec![2; 90])).await.unwrap();
+        let bob_id = bob.peer_id();
+        let mut usage = Vec::new();
+        for _ in 0..50 {
+            usage = hub.author_usage().await.unwrap();
+            if usage.iter().any(|report| report.author == bob_id.to_string()) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        let bytes = |author: PeerId| usage.iter().find(|r| r.author == author.to_string()).map(|r| r.usage.bytes);
+        assert_eq!((bytes(alice_id), bytes(bob_id)), (Some(60), Some(90)));
+        assert!(hub.get_document("a2").await.unwrap().is_none());
+
+        // Raised at runtime, Alice's next update is stored
+        hub.set_author_quota(Some(alice_id), Some(AuthorQuota { max_bytes: Some(1000), ..Default::default() })).await.unwrap();
+        alice.publish_doc_update(DocUpdate::new("a3", vec![1; 60])).await.unwrap();
+        wait_for(&mut hub, |e| match e {
+            NodeEvent::DocUpdateReceived { update, .. } if update.doc_id == "a3" => Some(()),
+            _ => None,
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn receivers_acknowledge_updates_that_ask_for_it() {
+        let mut hub = NodeBuilder::new(NodeRole::FullNode)
+            .add_listen_addr("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .spawn(generate_identity(KeyType::Ed25519))
+            .unwrap();
+        let addr = wait_for(&mut hub, |e| match e {
+            NodeEvent::ListenStarted { addr } => Some(addr),
+            _ => None,
+        })
+        .await
+        .with(Protocol::P2p(hub.peer_id()));
+        let mut node = NodeBuilder::new(NodeRole::Client).spawn(generate_identity(KeyType::Ed25519)).unwrap();
+        node.dial(addr).await.unwrap();
+        node.wait_ready(Duration::from_secs(10)).await.unwrap();
+
+        // Plain updates are not acknowledged
+        node.publish_doc_update(DocUpdate::new("notes", b"v1".to_vec())).await.unwrap();
+        let acked = node
+            .publish_doc_update_with(DocUpdate::new("notes", b"v2".to_vec()), PublishOptions { ack_requested: true, ..Default::default() })
+            .await
+            .unwrap();
+        let hub_id = hub.peer_id();
+        let (msg_id, doc_id, applied_version) = wait_for(&mut node, |e| match e {
+            NodeEvent::UpdateAcknowledged { msg_id, peer_id, doc_id, applied_version } if peer_id == hub_id => {
+                Some((msg_id, doc_id, applied_version))
+            }
+            NodeEvent::UpdateAcknowledged { .. } => panic!("unexpected receipt"),
+            _ => None,
+        })
+        .await;
+        assert_eq!((msg_id, doc_id.as_str(), applied_version), (acked.msg_id, "notes", 2));
+        assert_eq!(hub.get_document("notes").await.unwrap().map(|(v, _)| v), Some(2));
+    }
+
+    #[tokio::test]
+    async fn local_scope_updates_stop_at_the_second_relay() {
+        use crate::behaviour::docstore::MessageScope;
+        use crate::testing::spawn_test_node;
+
+        // client -> near -> far, both relays serving the hop protocol
+        let (mut near, near_addr) = spawn_test_node(NodeBuilder::new(NodeRole::FullNode)).await.unwrap();
+        let (mut far, _) = spawn_test_node(NodeBuilder::new(NodeRole::FullNode)).await.unwrap();
+        let (client, _) = spawn_test_node(NodeBuilder::new(NodeRole::Client)).await.unwrap();
+        far.dial(near_addr.clone()).await.unwrap();
+        client.dial(near_addr).await.unwrap();
+        client.wait_ready(Duration::from_secs(10)).await.unwrap();
+        let topic = TopicRegistry::default().updates().to_string();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !near.mesh_peers(topic.clone()).await.unwrap().contains(&far.peer_id()) {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .expect("mesh never formed");
+
+        let local = PublishOptions { scope: MessageScope::Local, ..Default::default() };
+        client.publish_doc_update_with(DocUpdate::new("cursor", b"x".to_vec()), local).await.unwrap();
+        client.publish_doc_update(DocUpdate::new("notes", b"v1".to_vec())).await.unwrap();
+
+        // The first relay takes both
+        for doc in ["cursor", "notes"] {
+            wait_for(&mut near, |e| match e {
+                NodeEvent::DocUpdateReceived { update, .. } if update.doc_id == doc => Some(()),
+                _ => None,
+            })
+            .await;
+        }
+        // The second only the global one
+        let doc_id = wait_for(&mut far, |e| match e {
+            NodeEvent::DocUpdateReceived { update, .. } => Some(update.doc_id),
+            _ => None,
+        })
+        .await;
+        assert_eq!(doc_id, "notes");
+        assert!(far.get_document("cursor").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn federated_relays_carry_updates_between_their_clients_and_relink_after_a_drop() {
+        use crate::node::federation::Federation;
+        use crate::testing::spawn_test_node;
+
+        // Both relays get the same list; each leaves itself out
+        let keys = [generate_identity(KeyType::Ed25519), generate_identity(KeyType::Ed25519)];
+        let addrs: Vec<Multiaddr> = keys
+            .iter()
+            .map(|key| {
+                let addr: Multiaddr = format!("/memory/{}", rand::random::<u64>()).parse().unwrap();
+                addr.with(Protocol::P2p(key.public().to_peer_id()))
+            })
+            .collect();
+        let list = addrs.iter().map(Multiaddr::to_string).collect::<Vec<_>>().join(",");
+        let [mut a, b] = keys.map(|key| {
+            let listen = addrs.iter().find(|addr| crate::node::addrs::peer_id_of(addr) == Some(key.public().to_peer_id())).unwrap();
+            NodeBuilder::new(NodeRole::Relay)
+                .add_listen_addr(listen.clone())
+                .with_federation(Federation::parse(&list).unwrap())
+                .spawn(key)
+                .unwrap()
+        });
+        // Up once A knows B is on the updates topic, so it has somewhere to send them
+        let topic = TopicRegistry::default().updates().to_string();
+        let b_id = b.peer_id();
+        let linked = |a: &Node| {
+            let topic = topic.clone();
+            async move {
+                tokio::time::timeout(Duration::from_secs(10), async {
+                    while !a.topic_peers(topic.clone()).await.unwrap().contains(&b_id) {
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                    }
+                })
+                .await
+                .expect("federation link never came up");
+            }
+        };
+        linked(&a).await;
+
+        let (sender, _) = spawn_test_node(NodeBuilder::new(NodeRole::Client)).await.unwrap();
+        let (mut receiver, _) = spawn_test_node(NodeBuilder::new(NodeRole::Client)).await.unwrap();
+        sender.dial(addrs[0].clone()).await.unwrap();
+        receiver.dial(addrs[1].clone()).await.unwrap();
+        sender.wait_ready(Duration::from_secs(10)).await.unwrap();
+        receiver.wait_ready(Duration::from_secs(10)).await.unwrap();
+
+        let received = |payload: &'static [u8]| {
+            move |e: NodeEvent| match e {
+                NodeEvent::DocUpdateReceived { update, .. } if update.payload == payload => Some(()),
+                _ => None,
+            }
+        };
+        sender.publish_doc_update(DocUpdate::new("notes", b"v1".to_vec())).await.unwrap();
+        wait_for(&mut receiver, received(b"v1")).await;
+        let status = a.federation_status().await.unwrap();
+        assert_eq!((status.links[0].peer_id, status.links[0].connected, status.clients), (b_id, true, 1));
+
+        // Drop the link: it comes back on its own
+        a.disconnect_peer(b_id).unwrap();
+        wait_for(&mut a, |e| matches!(e, NodeEvent::PeerRecovered { peer_id } if peer_id == b_id).then_some(())).await;
+        linked(&a).await;
+        sender.publish_doc_update(DocUpdate::new("notes", b"v2".to_vec())).await.unwrap();
+        wait_for(&mut receiver, received(b"v2")).await;
+        let link = a.federation_status().await.unwrap().links[0].clone();
+        assert!(link.connected && link.drops >= 1 && link.connects >= 2);
+    }
+
+    #[tokio::test]
+    async fn announcements_reach_nodes_that_allow_the_announcer() {
+        let key = generate_identity(KeyType::Ed25519);
+        let mut hub = NodeBuilder::new(NodeRole::FullNode)
+            .add_listen_addr("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .spawn(key.clone())
+            .unwrap();
+        let addr = wait_for(&mut hub, |e| match e {
+            NodeEvent::ListenStarted { addr } => Some(addr),
+            _ => None,
+        })
+        .await
+        .with(Protocol::P2p(hub.peer_id()));
+        let mut node = NodeBuilder::new(NodeRole::Client)
+            .with_announcers([hub.peer_id()])
+            .spawn(generate_identity(KeyType::Ed25519))
+            .unwrap();
+        node.dial(addr).await.unwrap();
+        node.wait_ready(Duration::from_secs(10)).await.unwrap();
+
+        let sign = |expires_at_ms| {
+            NetworkAnnouncement::sign(
+                &key,
+                docstore::AnnouncementKind::Maintenance,
+                docstore::Severity::Warning,
+                "maintenance at 22:00",
+                unix_ms(),
+                expires_at_ms,
+            )
+            .unwrap()
+        };
+        let expired = sign(Some(unix_ms() - 1));
+        assert!(matches!(hub.publish_announcement(expired).await, Err(Error::Announcement(_))));
+
+        let announcement = sign(Some(unix_ms() + 60_000));
+        hub.publish_announcement(announcement.clone()).await.unwrap();
+        let (from, received) = wait_for(&mut node, |e| match e {
+            NodeEvent::Announcement { peer_id, announcement } => Some((peer_id, announcement)),
+            NodeEvent::MessageReceived { topic, .. } if topic.ends_with("announce") => panic!("announcement delivered as data"),
+            _ => None,
+        })
+        .await;
+        assert_eq!((from, received), (hub.peer_id(), announcement));
+    }
+
+    /// A memory store that takes `delay` for every write.
+    struct SlowStore {
+        inner: MemoryDocStore,
+        delay: Duration,
+    }
+
+    impl DocStore for SlowStore {
+        fn apply_update(&mut self, update: &DocUpdate) -> u64 {
+            std::thread::sleep(self.delay);
+            self.inner.apply_update(update)
+        }
+        fn version(&self, doc_id: &str) -> u64 {
+            self.inner.version(doc_id)
+        }
+        fn content(&self, doc_id: &str) -> Option<Vec<u8>> {
+            self.inner.content(doc_id)
+        }
+        fn updates_since(&self, doc_id: &str, version: u64) -> Vec<crate::store::StoredUpdate> {
+            self.inner.updates_since(doc_id, version)
+        }
+        fn log(&self, doc_id: &str) -> &[crate::store::StoredUpdate] {
+            self.inner.log(doc_id)
+        }
+        fn latest_snapshot(&self, doc_id: &str) -> Option<crate::behaviour::docstore::Snapshot> {
+            self.inner.latest_snapshot(doc_id)
+        }
+        fn make_snapshot(&mut self, doc_id: &str) -> Option<crate::behaviour::docstore::Snapshot> {
+            self.inner.make_snapshot(doc_id)
+        }
+        fn install_snapshot(&mut self, snapshot: crate::behaviour::docstore::Snapshot) -> bool {
+            self.inner.install_snapshot(snapshot)
+        }
+        fn doc_ids(&self) -> Vec<String> {
+            self.inner.doc_ids()
+        }
+        fn compact(&mut self, doc_id: &str) -> crate::store::CompactionReport {
+            self.inner.compact(doc_id)
+        }
+        fn pin(&mut self, doc_id: &str) -> bool {
+            self.inner.pin(doc_id)
+        }
+        fn unpin(&mut self, doc_id: &str) -> bool {
+            self.inner.unpin(doc_id)
+        }
+        fn pins(&self) -> Vec<String> {
+            self.inner.pins()
+        }
+        fn merge_policy(&self, doc_id: &str) -> MergePolicy {
+            self.inner.merge_policy(doc_id)
+        }
+        fn set_merge_policy(&mut self, doc_id: &str, policy: MergePolicy) {
+            self.inner.set_merge_policy(doc_id, policy)
+        }
+        fn clock(&self, doc_id: &str) -> crate::behaviour::docstore::VectorClock {
+            self.inner.clock(doc_id)
+        }
+        fn author_hlc(&self, doc_id: &str, author: u64) -> Option<crate::behaviour::docstore::Hlc> {
+            self.inner.author_hlc(doc_id, author)
+        }
+        fn has_applied(&self, doc_id: &str, author: u64, seq: u64) -> bool {
+            self.inner.has_applied(doc_id, author, seq)
+        }
+    }
+
+    /// Queue `updates` publishes on a node whose store writes take `write`, then drain it
+    /// within `timeout`. Returns the report, how many publishes failed and the events
+    /// that followed.
+    async fn drain_behind_slow_writes(updates: usize, write: Duration, timeout: Duration) -> (ShutdownReport, usize, Vec<NodeEvent>) {
+        let mut hub = NodeBuilder::new(NodeRole::FullNode)
+            .add_listen_addr("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .spawn(generate_identity(KeyType::Ed25519))
+            .unwrap();
+        let addr = wait_for(&mut hub, |e| match e {
+            NodeEvent::ListenStarted { addr } => Some(addr),
+            _ => None,
+        })
+        .await
+        .with(Protocol::P2p(hub.peer_id()));
+        let mut node = NodeBuilder::new(NodeRole::Client)
+            .with_store(SlowStore { inner: MemoryDocStore::default(), delay: write })
+            .spawn(generate_identity(KeyType::Ed25519))
+            .unwrap();
+        node.dial(addr).await.unwrap();
+        node.wait_ready(Duration::from_secs(10)).await.unwrap();
+
+        // Publishes are queued first, then the shutdown overtakes them
+        let publishes = futures::future::join_all(
+            (0..updates).map(|i| node.publish_doc_update(DocUpdate::new("doc", format!("edit {i}").into_bytes()))),
+        );
+        let (results, report) = tokio::join!(publishes, node.shutdown(ShutdownMode::Drain { timeout }));
+        let report = report.unwrap();
+        let failed = results.iter().filter(|r| matches!(r, Err(Error::NodeStopped))).count();
+        assert_eq!(failed, results.iter().filter(|r| r.is_err()).count(), "{results:?}");
+
+        let mut events = Vec::new();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while let Some(event) = node.next_event().await {
+                events.push(event);
+            }
+        })
+        .await
+        .expect("event stream never ended");
+        assert!(node.publish(b"late".to_vec()).await.is_err());
+        (report, failed, events)
+    }
+
+    #[tokio::test]
+    async fn draining_shutdown_flushes_queued_publishes_until_its_timeout() {
+        let write = Duration::from_millis(50);
+
+        // Enough time for every write
+        let timeout = Duration::from_secs(5);
+        let (report, failed, events) = drain_behind_slow_writes(10, write, timeout).await;
+        assert_eq!((report.dropped, failed), (0, 0));
+        assert!(report.duration < timeout, "{report:?}");
+        assert!(matches!(events.last(), Some(NodeEvent::ShutdownComplete { report: last }) if *last == report));
+
+        // Time for a few: the rest are dropped, and each dropped publish fails its caller
+        let timeout = Duration::from_millis(120);
+        let (report, failed, events) = drain_behind_slow_writes(10, write, timeout).await;
+        assert!(report.dropped > 0, "{report:?}");
+        assert_eq!(report.dropped, failed);
+        assert!(report.published + report.dropped <= 10);
+        assert!(report.duration < timeout + write, "{report:?}");
+        assert!(matches!(events.last(), Some(NodeEvent::ShutdownComplete { report: last }) if *last == report));
+
+        // No deadline at all
+        let (report, failed, _) = drain_behind_slow_writes(3, write, Duration::MAX).await;
+        assert_eq!((report.dropped, failed), (0, 0));
+    }
+}
@@ -0,0 +1,313 @@
+//! Hooks for applications that ship their telemetry somewhere other than `/metrics`,
+//! such as OpenTelemetry or an in-house pipeline.
+//!
+//! Implement [`ObserverHooks`] and hand it to [`NodeBuilder::with_observer`]. Both event
+//! loops call it where they already count traffic: as connections open and close,
+//! messages arrive, publishes go out, Kademlia queries finish and dials fail. Arguments
+//! borrow from the event being handled, so hooks that ignore them cost nothing.
+//!
+//! Hooks run on the event loop itself and must return quickly. Anything slow, and
+//! anything async, belongs on a channel or task of the hook's own. The loop never waits
+//! on a hook beyond the call: a hook that panics is caught and logged, and one that takes
+//! longer than [`SLOW_HOOK`] is reported. Builds with `panic = "abort"`, which includes
+//! wasm32, cannot catch the panic.
+//!
+//! [`NodeBuilder::with_observer`]: crate::node::NodeBuilder::with_observer
+
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::time::Duration;
+
+use libp2p::gossipsub::{self, PublishError, TopicHash};
+use libp2p::swarm::SwarmEvent;
+use libp2p::{Multiaddr, PeerId};
+use libp2p_kad::{self as kad, QueryId, QueryResult};
+use web_time::Instant;
+
+use super::addrs::transport_name;
+use super::traffic::Published;
+
+/// Longest a hook may run before the event loop warns about it.
+pub const SLOW_HOOK: Duration = Duration::from_millis(5);
+
+const TRACING_TARGET: &str = "docstore::observer";
+
+/// A connection opened or closed.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionInfo<'a> {
+    pub peer_id: &'a PeerId,
+    pub address: &'a Multiaddr,
+    /// See [`transport_name`].
+    pub transport: &'static str,
+    /// False once it closed.
+    pub established: bool,
+}
+
+/// A gossipsub message received, before validation.
+#[derive(Debug, Clone, Copy)]
+pub struct MessageInfo<'a> {
+    pub topic: &'a TopicHash,
+    /// Signed author, if any.
+    pub source: Option<&'a PeerId>,
+    /// The peer that delivered it.
+    pub propagation_source: &'a PeerId,
+    pub size: usize,
+}
+
+impl<'a> MessageInfo<'a> {
+    pub fn new(message: &'a gossipsub::Message, propagation_source: &'a PeerId) -> Self {
+        Self { topic: &message.topic, source: message.source.as_ref(), propagation_source, size: message.data.len() }
+    }
+}
+
+/// A message we published, or failed to.
+#[derive(Debug, Clone, Copy)]
+pub struct PublishInfo<'a> {
+    pub topic: &'a TopicHash,
+    pub size: usize,
+    pub result: Result<&'a Published, &'a PublishError>,
+}
+
+/// A Kademlia query that finished.
+#[derive(Debug, Clone, Copy)]
+pub struct QueryInfo<'a> {
+    pub id: QueryId,
+    /// `bootstrap`, `get_record`, `put_record`, `get_providers`, ...
+    pub kind: &'static str,
+    pub ok: bool,
+    pub stats: &'a kad::QueryStats,
+}
+
+/// Something went wrong that no caller is waiting to hear about.
+#[derive(Debug, Clone, Copy)]
+pub struct ErrorInfo<'a> {
+    /// What the node was doing, e.g. `dial` or `listen`.
+    pub context: &'static str,
+    pub peer_id: Option<&'a PeerId>,
+    pub error: &'a dyn fmt::Display,
+}
+
+/// Telemetry hooks, see the [module docs](self). Every hook does nothing by default.
+pub trait ObserverHooks: Send + Sync {
+    fn on_connection(&self, _connection: ConnectionInfo<'_>) {}
+
+    fn on_message_received(&self, _message: MessageInfo<'_>) {}
+
+    fn on_publish(&self, _publish: PublishInfo<'_>) {}
+
+    fn on_query_result(&self, _query: QueryInfo<'_>) {}
+
+    fn on_error(&self, _error: ErrorInfo<'_>) {}
+}
+
+/// Observes nothing; what nodes run with unless given other hooks.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopObserver;
+
+impl ObserverHooks for NoopObserver {}
+
+/// Turns every hook into a `tracing` event with target `docstore::observer`: debug
+/// level, warnings for failed publishes and errors.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TracingObserver;
+
+impl ObserverHooks for TracingObserver {
+    fn on_connection(&self, c: ConnectionInfo<'_>) {
+        tracing::debug!(
+            target: TRACING_TARGET,
+            peer_id = %c.peer_id,
+            address = %c.address,
+            transport = c.transport,
+            established = c.established,
+            "connection"
+        );
+    }
+
+    fn on_message_received(&self, m: MessageInfo<'_>) {
+        tracing::debug!(
+            target: TRACING_TARGET,
+            topic = %m.topic,
+            source = m.source.map(tracing::field::display),
+            propagation_source = %m.propagation_source,
+            size = m.size,
+            "message received"
+        );
+    }
+
+    fn on_publish(&self, p: PublishInfo<'_>) {
+        match p.result {
+            Ok(published) => tracing::debug!(
+                target: TRACING_TARGET,
+                topic = %p.topic,
+                size = p.size,
+                msg_id = %published.msg_id,
+                recipients = published.sent_to.len(),
+                "published"
+            ),
+            Err(e) => tracing::warn!(target: TRACING_TARGET, topic = %p.topic, size = p.size, error = %e, "publish failed"),
+        }
+    }
+
+    fn on_query_result(&self, q: QueryInfo<'_>) {
+        tracing::debug!(
+            target: TRACING_TARGET,
+            query_id = ?q.id,
+            kind = q.kind,
+            ok = q.ok,
+            requests = q.stats.num_requests(),
+            duration_ms = q.stats.duration().map(|d| d.as_millis() as u64),
+            "query finished"
+        );
+    }
+
+    fn on_error(&self, e: ErrorInfo<'_>) {
+        tracing::warn!(
+            target: TRACING_TARGET,
+            context = e.context,
+            peer_id = e.peer_id.map(tracing::field::display),
+            error = %e.error,
+            "error"
+        );
+    }
+}
+
+/// The event loops' handle on the configured hooks. Cheap to clone.
+#[derive(Clone)]
+pub struct Observer {
+    hooks: Arc<dyn ObserverHooks>,
+}
+
+impl fmt::Debug for Observer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Observer").finish_non_exhaustive()
+    }
+}
+
+impl Default for Observer {
+    fn default() -> Self {
+        Self::new(Arc::new(NoopObserver))
+    }
+}
+
+impl Observer {
+    pub fn new(hooks: Arc<dyn ObserverHooks>) -> Self {
+        Self { hooks }
+    }
+
+    pub fn connection(&self, connection: ConnectionInfo<'_>) {
+        self.call("on_connection", |hooks| hooks.on_connection(connection));
+    }
+
+    pub fn message_received(&self, message: MessageInfo<'_>) {
+        self.call("on_message_received", |hooks| hooks.on_message_received(message));
+    }
+
+    pub fn publish(&self, publish: PublishInfo<'_>) {
+        self.call("on_publish", |hooks| hooks.on_publish(publish));
+    }
+
+    pub fn query_result(&self, query: QueryInfo<'_>) {
+        self.call("on_query_result", |hooks| hooks.on_query_result(query));
+    }
+
+    pub fn error(&self, error: ErrorInfo<'_>) {
+        self.call("on_error", |hooks| hooks.on_error(error));
+    }
+
+    /// Report the connections and failures among swarm events, whatever the behaviour.
+    pub fn swarm_event<E>(&self, event: &SwarmEvent<E>) {
+        match event {
+            SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. }
+            | SwarmEvent::ConnectionClosed { peer_id, endpoint, .. } => {
+                let address = endpoint.get_remote_address();
+                self.connection(ConnectionInfo {
+                    peer_id,
+                    address,
+                    transport: transport_name(address),
+                    established: matches!(event, SwarmEvent::ConnectionEstablished { .. }),
+                });
+            }
+            SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
+                self.error(ErrorInfo { context: "dial", peer_id: peer_id.as_ref(), error });
+            }
+            SwarmEvent::IncomingConnectionError { error, .. } => {
+                self.error(ErrorInfo { context: "incoming connection", peer_id: None, error });
+            }
+            SwarmEvent::ListenerError { error, .. } => {
+                self.error(ErrorInfo { context: "listen", peer_id: None, error });
+            }
+            _ => {}
+        }
+    }
+
+    /// Report a Kademlia query once its last step is in.
+    pub fn kad_event(&self, event: &kad::Event) {
+        if let kad::Event::OutboundQueryProgressed { id, result, stats, step } = event {
+            if step.last {
+                let (kind, ok) = query_kind(result);
+                self.query_result(QueryInfo { id: *id, kind, ok, stats });
+            }
+        }
+    }
+
+    fn call(&self, hook: &'static str, f: impl FnOnce(&dyn ObserverHooks)) {
+        let started = Instant::now();
+        if panic::catch_unwind(AssertUnwindSafe(|| f(&*self.hooks))).is_err() {
+            tracing::error!("Observer hook {} panicked", hook);
+        }
+        let elapsed = started.elapsed();
+        if elapsed > SLOW_HOOK {
+            tracing::warn!("Observer hook {} took {:?}; hooks run on the event loop and must not block", hook, elapsed);
+        }
+    }
+}
+
+fn query_kind(result: &QueryResult) -> (&'static str, bool) {
+    match result {
+        QueryResult::Bootstrap(r) => ("bootstrap", r.is_ok()),
+        QueryResult::GetClosestPeers(r) => ("get_closest_peers", r.is_ok()),
+        QueryResult::GetProviders(r) => ("get_providers", r.is_ok()),
+        QueryResult::StartProviding(r) => ("start_providing", r.is_ok()),
+        QueryResult::RepublishProvider(r) => ("republish_provider", r.is_ok()),
+        QueryResult::GetRecord(r) => ("get_record", r.is_ok()),
+        QueryResult::PutRecord(r) => ("put_record", r.is_ok()),
+        QueryResult::RepublishRecord(r) => ("republish_record", r.is_ok()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Recording(Mutex<Vec<String>>);
+
+    impl ObserverHooks for Recording {
+        fn on_message_received(&self, m: MessageInfo<'_>) {
+            self.0.lock().unwrap().push(format!("{} {}", m.topic, m.size));
+        }
+
+        fn on_publish(&self, _publish: PublishInfo<'_>) {
+            panic!("broken hook");
+        }
+    }
+
+    #[test]
+    fn hooks_are_called_and_panics_stay_inside() {
+        let recording = Arc::new(Recording::default());
+        let observer = Observer::new(recording.clone());
+        let (peer, topic) = (PeerId::random(), TopicHash::from_raw("docstore/v1/updates"));
+        let message = gossipsub::Message { source: None, data: vec![0; 12], sequence_number: None, topic: topic.clone() };
+        observer.message_received(MessageInfo::new(&message, &peer));
+        assert_eq!(*recording.0.lock().unwrap(), ["docstore/v1/updates 12"]);
+
+        // The panic is logged, not propagated to the event loop
+        let published = Published { msg_id: gossipsub::MessageId::new(b"m"), sent_to: Vec::new() };
+        observer.publish(PublishInfo { topic: &topic, size: 12, result: Ok(&published) });
+        // Hooks left unimplemented do nothing
+        observer.error(ErrorInfo { context: "dial", peer_id: Some(&peer), error: &"unreachable" });
+        Observer::default().publish(PublishInfo { topic: &topic, size: 12, result: Err(&PublishError::Duplicate) });
+    }
+}
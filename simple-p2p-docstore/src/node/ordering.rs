@@ -0,0 +1,448 @@
+//! In-order delivery of watched documents' updates, shared by the native and wasm event
+//! loops.
+//!
+//! Gossip hands over a document's updates in whatever order they arrive, and the store
+//! merges them regardless. Applications that feed updates into their own model usually
+//! want them in order instead. For every watched document, [`OrderedDelivery`] holds an
+//! update back until everything its stamp says came before it was delivered: the
+//! author's previous update and every update the author had seen. While updates are
+//! held, the loop re-requests the gap from a peer serving history. If nothing fills it
+//! within the gap timeout, the gap is abandoned and the held updates go out in HLC
+//! order, which still respects causality as far as the stamps tell.
+//!
+//! Unstamped updates carry no order and are delivered as they come. A snapshot stands
+//! in for everything before it: it is delivered as an update carrying the snapshot's
+//! bytes, and the document's clock at that point becomes the new baseline.
+
+use std::cmp::Ordering;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use web_time::Instant;
+
+use crate::behaviour::docstore::{DocUpdate, Stamp, UpdateOrder, VectorClock};
+
+/// How long held updates wait for a gap to be filled.
+pub const DEFAULT_GAP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How far before the newest delivered update a gap re-request starts. The missing
+/// updates may have been published a little earlier by a concurrent author, or stamped by
+/// a clock running behind.
+pub const GAP_LOOKBACK: Duration = Duration::from_secs(60);
+
+/// How an update reached the node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Origin {
+    /// Gossiped as it was published.
+    Live,
+    /// Fetched from a peer's history to fill a gap.
+    Replay,
+    /// The content of a snapshot, standing in for the updates it covers.
+    Snapshot,
+    /// Bundled with the application, see [`super::seeds`].
+    Seed,
+}
+
+impl Origin {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Origin::Live => "live",
+            Origin::Replay => "replay",
+            Origin::Snapshot => "snapshot",
+            Origin::Seed => "seed",
+        }
+    }
+}
+
+/// An update delivered to the watchers of its document.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OrderedUpdate {
+    pub update: DocUpdate,
+    pub origin: Origin,
+}
+
+/// What the event loop has to act on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Ordered {
+    /// Hand to the document's watchers.
+    Deliver(OrderedUpdate),
+    /// Delivery of `doc_id` is held back on updates that never arrived. Re-request its
+    /// updates since `since_ms`, [`GAP_LOOKBACK`] before the newest one delivered.
+    GapOpened { doc_id: String, since_ms: u64 },
+    /// The gap was not filled in time: `missing` updates are given up on, and the held
+    /// ones are delivered right after.
+    GapAbandoned { doc_id: String, missing: u64 },
+}
+
+#[derive(Debug, Default)]
+struct Watched {
+    /// Everything delivered so far, or taken as known when watching started.
+    delivered: VectorClock,
+    /// Stamped updates waiting on a gap, in arrival order.
+    held: Vec<OrderedUpdate>,
+    /// When the current gap is abandoned.
+    gap_deadline: Option<Instant>,
+    newest_ms: u64,
+}
+
+impl Watched {
+    fn deliver(&mut self, ordered: OrderedUpdate, out: &mut Vec<Ordered>) {
+        if let Some(stamp) = &ordered.update.stamp {
+            self.delivered.merge(&stamp.clock);
+            self.newest_ms = self.newest_ms.max(stamp.hlc.wall_ms);
+        }
+        out.push(Ordered::Deliver(ordered));
+    }
+
+    /// Deliver held updates for as long as one of them is next in line. Returns whether
+    /// any was.
+    fn release(&mut self, out: &mut Vec<Ordered>) -> bool {
+        let delivered = &self.delivered;
+        self.held.retain(|held| !seen(delivered, held_stamp(held)));
+        let mut progressed = false;
+        while let Some(i) = self.held.iter().position(|held| is_next(&self.delivered, held_stamp(held))) {
+            let next = self.held.remove(i);
+            self.deliver(next, out);
+            progressed = true;
+        }
+        progressed
+    }
+
+    /// Open, re-arm or close the gap after updates came in.
+    fn settle(&mut self, doc_id: &str, progressed: bool, now: Instant, timeout: Duration, out: &mut Vec<Ordered>) {
+        if self.held.is_empty() {
+            self.gap_deadline = None;
+        } else if progressed || self.gap_deadline.is_none() {
+            // What is still missing is a different gap now; give it its own time
+            self.gap_deadline = Some(now + timeout);
+            let since_ms = self.newest_ms.saturating_sub(GAP_LOOKBACK.as_millis() as u64);
+            out.push(Ordered::GapOpened { doc_id: doc_id.to_string(), since_ms });
+        }
+    }
+
+    /// Give up on the gap and deliver everything held in the updates' total order.
+    fn abandon(&mut self, doc_id: &str, out: &mut Vec<Ordered>) {
+        self.gap_deadline = None;
+        let mut held = std::mem::take(&mut self.held);
+        held.sort_by_cached_key(|held| UpdateOrder::of(held_stamp(held), &held.update.payload));
+        let mut missing = 0;
+        let mut delivered = Vec::new();
+        for next in held {
+            let clock = &held_stamp(&next).clock;
+            if seen(&self.delivered, held_stamp(&next)) {
+                continue;
+            }
+            let mut covered = self.delivered.clone();
+            covered.merge(clock);
+            // Everything the update depends on that was never delivered
+            missing = missing.saturating_add(covered.total().saturating_sub(self.delivered.total()).saturating_sub(1));
+            self.deliver(next, &mut delivered);
+        }
+        out.push(Ordered::GapAbandoned { doc_id: doc_id.to_string(), missing });
+        out.extend(delivered);
+    }
+}
+
+fn held_stamp(held: &OrderedUpdate) -> &Stamp {
+    held.update.stamp.as_ref().expect("only stamped updates are held")
+}
+
+/// Whether the update with `stamp` was delivered already.
+fn seen(delivered: &VectorClock, stamp: &Stamp) -> bool {
+    stamp.clock.get(stamp.hlc.node) <= delivered.get(stamp.hlc.node)
+}
+
+/// Whether the update with `stamp` is the author's next one and everything it saw was
+/// delivered.
+fn is_next(delivered: &VectorClock, stamp: &Stamp) -> bool {
+    let mut next = delivered.clone();
+    next.increment(stamp.hlc.node);
+    stamp.clock.get(stamp.hlc.node) == next.get(stamp.hlc.node)
+        && matches!(stamp.clock.compare(&next), Some(Ordering::Less | Ordering::Equal))
+}
+
+/// The watched documents and their held updates, see the [module docs](self).
+#[derive(Debug)]
+pub struct OrderedDelivery {
+    gap_timeout: Duration,
+    docs: HashMap<String, Watched>,
+}
+
+impl Default for OrderedDelivery {
+    fn default() -> Self {
+        Self::new(DEFAULT_GAP_TIMEOUT)
+    }
+}
+
+impl OrderedDelivery {
+    pub fn new(gap_timeout: Duration) -> Self {
+        Self { gap_timeout, docs: HashMap::new() }
+    }
+
+    /// Deliver `doc_id`'s updates in order from now on. `clock` is what the node already
+    /// applied; updates it covers are not delivered again. Returns false if it was
+    /// already watched.
+    pub fn watch(&mut self, doc_id: impl Into<String>, clock: VectorClock) -> bool {
+        match self.docs.entry(doc_id.into()) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(Watched { delivered: clock, ..Default::default() });
+                true
+            }
+        }
+    }
+
+    /// Stop ordering `doc_id`, dropping whatever it held. Returns false if it wasn't
+    /// watched.
+    pub fn unwatch(&mut self, doc_id: &str) -> bool {
+        self.docs.remove(doc_id).is_some()
+    }
+
+    pub fn is_watched(&self, doc_id: &str) -> bool {
+        self.docs.contains_key(doc_id)
+    }
+
+    /// An update applied by the node. Nothing comes out for documents nobody watches,
+    /// or for updates already delivered.
+    pub fn push(&mut self, update: DocUpdate, origin: Origin, now: Instant) -> Vec<Ordered> {
+        let mut out = Vec::new();
+        let Some(doc) = self.docs.get_mut(&update.doc_id) else {
+            return out;
+        };
+        let Some(stamp) = &update.stamp else {
+            doc.deliver(OrderedUpdate { update, origin }, &mut out);
+            return out;
+        };
+        if seen(&doc.delivered, stamp)
+            || doc.held.iter().any(|held| held_stamp(held).hlc == stamp.hlc && held.update.payload == update.payload)
+        {
+            return out;
+        }
+        let doc_id = update.doc_id.clone();
+        doc.held.push(OrderedUpdate { update, origin });
+        let progressed = doc.release(&mut out);
+        doc.settle(&doc_id, progressed, now, self.gap_timeout, &mut out);
+        out
+    }
+
+    /// A snapshot of `doc_id` was installed, after which the document's clock is
+    /// `clock`. Held updates the snapshot covers are dropped.
+    pub fn snapshot(&mut self, doc_id: &str, bytes: Vec<u8>, clock: &VectorClock, now: Instant) -> Vec<Ordered> {
+        let mut out = Vec::new();
+        let Some(doc) = self.docs.get_mut(doc_id) else {
+            return out;
+        };
+        doc.delivered.merge(clock);
+        let update = DocUpdate::new(doc_id, bytes);
+        doc.deliver(OrderedUpdate { update, origin: Origin::Snapshot }, &mut out);
+        let progressed = doc.release(&mut out);
+        doc.settle(doc_id, progressed, now, self.gap_timeout, &mut out);
+        out
+    }
+
+    /// Abandon the gaps whose timeout passed.
+    pub fn expire(&mut self, now: Instant) -> Vec<Ordered> {
+        let mut out = Vec::new();
+        for (doc_id, doc) in &mut self.docs {
+            if doc.gap_deadline.is_some_and(|deadline| deadline <= now) {
+                doc.abandon(doc_id, &mut out);
+            }
+        }
+        out
+    }
+
+    /// When the next gap times out, if any is open.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.docs.values().filter_map(|doc| doc.gap_deadline).min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::behaviour::docstore::{encode_doc_update, DocstoreGossipsubConfig, HlcClock, Incoming, MessagePipeline};
+    use crate::store::{DocStore, MemoryDocStore};
+    use libp2p::gossipsub;
+    use libp2p::identity::Keypair;
+    use libp2p::PeerId;
+    use rand::rngs::StdRng;
+    use rand::seq::SliceRandom;
+    use rand::SeedableRng;
+
+    /// `count` updates to "notes" by authors taking turns, each having seen all before.
+    fn history(authors: &[u64], count: usize) -> Vec<DocUpdate> {
+        let mut clocks: Vec<HlcClock> = authors.iter().map(|&node| HlcClock::new(node)).collect();
+        let mut doc_clock = VectorClock::default();
+        (0..count)
+            .map(|i| {
+                let clock = &mut clocks[i % authors.len()];
+                let stamp = Stamp::next_at(clock, &doc_clock, 100_000 + i as u64);
+                doc_clock = stamp.clock.clone();
+                DocUpdate { stamp: Some(stamp), ..DocUpdate::new("notes", format!("{i}")) }
+            })
+            .collect()
+    }
+
+    fn delivered(out: &[Ordered]) -> Vec<String> {
+        out.iter()
+            .filter_map(|o| match o {
+                Ordered::Deliver(d) => Some(String::from_utf8(d.update.payload.clone()).unwrap()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn expected(range: std::ops::Range<usize>) -> Vec<String> {
+        range.map(|i| i.to_string()).collect()
+    }
+
+    #[test]
+    fn shuffled_updates_are_delivered_in_order() {
+        let updates = history(&[1, 2, 3], 30);
+        for seed in 0..20 {
+            let mut shuffled = updates.clone();
+            shuffled.shuffle(&mut StdRng::seed_from_u64(seed));
+            let mut ordering = OrderedDelivery::default();
+            assert!(ordering.watch("notes", VectorClock::default()));
+            let now = Instant::now();
+            let mut out = Vec::new();
+            for update in shuffled {
+                out.extend(ordering.push(update, Origin::Live, now));
+            }
+            assert_eq!(delivered(&out), expected(0..30), "seed {seed}");
+            assert_eq!(ordering.next_deadline(), None, "no gap left open");
+        }
+    }
+
+    #[test]
+    fn shuffled_gossip_through_the_pipeline_reaches_the_watcher_in_order() {
+        let cfg = DocstoreGossipsubConfig::default();
+        let authors: Vec<Keypair> = (1..=3).map(|seed| Keypair::ed25519_from_bytes([seed; 32]).unwrap()).collect();
+        let mut hlcs: Vec<HlcClock> = authors.iter().map(|key| HlcClock::for_peer(&key.public().to_peer_id())).collect();
+        let mut doc_clock = VectorClock::default();
+        let mut published = Vec::new();
+        for i in 0..12 {
+            let author = i % authors.len();
+            let stamp = Stamp::next_at(&mut hlcs[author], &doc_clock, 100_000 + i as u64);
+            doc_clock = stamp.clock.clone();
+            let update = DocUpdate { stamp: Some(stamp), ..DocUpdate::new("notes", format!("{i}")) };
+            published.push((authors[author].public().to_peer_id(), update));
+        }
+
+        for seed in 0..10 {
+            let mut arrivals = published.clone();
+            arrivals.shuffle(&mut StdRng::seed_from_u64(seed));
+            let mut store = MemoryDocStore::default();
+            let mut pipeline = MessagePipeline::new(cfg.clone(), &PeerId::random());
+            let mut ordering = OrderedDelivery::default();
+            ordering.watch("notes", store.clock("notes"));
+            let now = Instant::now();
+            let mut out = Vec::new();
+            for (author, update) in arrivals {
+                let data = encode_doc_update(&cfg, update).unwrap();
+                let message =
+                    gossipsub::Message { source: Some(author), data, sequence_number: None, topic: cfg.topics.updates().hash() };
+                // An author's update arriving after its next one is still applied
+                for incoming in pipeline.handle_incoming(&mut store, author, &message) {
+                    if let Incoming::UpdateApplied { update, .. } = incoming {
+                        out.extend(ordering.push(update, Origin::Live, now));
+                    }
+                }
+            }
+            assert_eq!(store.log("notes").len(), 12, "seed {seed}");
+            assert_eq!(delivered(&out), expected(0..12), "seed {seed}");
+            assert_eq!(ordering.next_deadline(), None, "seed {seed}");
+            assert!(!out.iter().any(|o| matches!(o, Ordered::GapAbandoned { .. })));
+        }
+    }
+
+    #[test]
+    fn a_gap_holds_delivery_until_replay_fills_it() {
+        let updates = history(&[1, 2], 4);
+        let mut ordering = OrderedDelivery::new(Duration::from_secs(5));
+        ordering.watch("notes", VectorClock::default());
+        let now = Instant::now();
+        assert_eq!(delivered(&ordering.push(updates[0].clone(), Origin::Live, now)), ["0"]);
+
+        let out = ordering.push(updates[2].clone(), Origin::Live, now);
+        assert_eq!(out, [Ordered::GapOpened { doc_id: "notes".into(), since_ms: 40_000 }]);
+        assert_eq!(ordering.next_deadline(), Some(now + Duration::from_secs(5)));
+        // Still the same gap
+        assert!(ordering.push(updates[3].clone(), Origin::Live, now).is_empty());
+        // Duplicates go nowhere
+        assert!(ordering.push(updates[0].clone(), Origin::Live, now).is_empty());
+        assert!(ordering.push(updates[2].clone(), Origin::Live, now).is_empty());
+
+        let out = ordering.push(updates[1].clone(), Origin::Replay, now + Duration::from_secs(1));
+        assert_eq!(delivered(&out), ["1", "2", "3"]);
+        let Ordered::Deliver(first) = &out[0] else { panic!("expected a delivery, got {:?}", out[0]) };
+        assert_eq!(first.origin, Origin::Replay);
+        let Ordered::Deliver(last) = &out[2] else { panic!("expected a delivery, got {:?}", out[2]) };
+        assert_eq!(last.origin, Origin::Live);
+        assert_eq!(ordering.next_deadline(), None);
+        assert!(ordering.expire(now + Duration::from_secs(60)).is_empty());
+    }
+
+    #[test]
+    fn an_unfilled_gap_is_abandoned_after_the_timeout() {
+        let updates = history(&[1, 2, 3], 6);
+        let mut ordering = OrderedDelivery::new(Duration::from_secs(5));
+        ordering.watch("notes", VectorClock::default());
+        let now = Instant::now();
+        let mut out = ordering.push(updates[0].clone(), Origin::Live, now);
+        for i in [4, 2, 5] {
+            out.extend(ordering.push(updates[i].clone(), Origin::Live, now));
+        }
+        assert_eq!(delivered(&out), ["0"]);
+
+        assert!(ordering.expire(now + Duration::from_secs(4)).is_empty());
+        let out = ordering.expire(now + Duration::from_secs(5));
+        assert_eq!(out[0], Ordered::GapAbandoned { doc_id: "notes".into(), missing: 2 });
+        assert_eq!(delivered(&out), ["2", "4", "5"]);
+
+        // Delivery carries on in order from there; the given up updates stay given up
+        let next = history(&[1, 2, 3], 8);
+        assert!(ordering.push(updates[1].clone(), Origin::Replay, now).is_empty());
+        assert_eq!(delivered(&ordering.push(next[7].clone(), Origin::Live, now)), Vec::<String>::new());
+        assert_eq!(delivered(&ordering.push(next[6].clone(), Origin::Live, now)), ["6", "7"]);
+    }
+
+    #[test]
+    fn snapshots_and_unstamped_updates() {
+        let updates = history(&[1, 2], 5);
+        let mut ordering = OrderedDelivery::default();
+        let now = Instant::now();
+        assert!(ordering.push(updates[0].clone(), Origin::Live, now).is_empty(), "not watched");
+        ordering.watch("notes", VectorClock::default());
+        assert!(!ordering.watch("notes", VectorClock::default()));
+
+        assert!(matches!(ordering.push(updates[3].clone(), Origin::Live, now)[..], [Ordered::GapOpened { .. }]));
+        // Unstamped updates have no place in the order, so they don't wait
+        assert_eq!(delivered(&ordering.push(DocUpdate::new("notes", "x"), Origin::Live, now)), ["x"]);
+
+        // The snapshot covers the first three updates; the held one follows it
+        let covered = updates[2].stamp.clone().unwrap().clock;
+        let out = ordering.snapshot("notes", b"012".to_vec(), &covered, now);
+        assert_eq!(delivered(&out), ["012", "3"]);
+        let Ordered::Deliver(snapshot) = &out[0] else { panic!("expected a delivery, got {:?}", out[0]) };
+        assert_eq!(snapshot.origin, Origin::Snapshot);
+        assert_eq!(ordering.next_deadline(), None);
+        assert!(ordering.push(updates[1].clone(), Origin::Replay, now).is_empty());
+
+        assert!(ordering.unwatch("notes"));
+        assert!(ordering.push(updates[4].clone(), Origin::Live, now).is_empty());
+    }
+
+    #[test]
+    fn watching_starts_from_what_the_node_already_has() {
+        let updates = history(&[1], 3);
+        let mut ordering = OrderedDelivery::default();
+        ordering.watch("notes", updates[1].stamp.clone().unwrap().clock);
+        let now = Instant::now();
+        assert!(ordering.push(updates[0].clone(), Origin::Live, now).is_empty());
+        assert_eq!(delivered(&ordering.push(updates[2].clone(), Origin::Live, now)), ["2"]);
+    }
+}
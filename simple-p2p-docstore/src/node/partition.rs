@@ -0,0 +1,233 @@
+//! Split-brain detection: notice when the relays and FullNodes we expect to reach are
+//! gone while our own clients are still here, which usually means the network split
+//! between us and them rather than that we are offline.
+//!
+//! Expected peers come from the bootstrap config and the relay registry, plus whatever
+//! an operator adds. A peer counts as missing once it has been unreachable for the
+//! threshold; a possible partition is reported when any peer is missing while at least
+//! one client is connected, and resolved once every missing peer is back or was removed
+//! from the expected list. Removing a peer is how a decommissioned node stops counting.
+//!
+//! Times are unix milliseconds, so this works the same on every platform.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use libp2p::PeerId;
+
+/// How long an expected peer may be unreachable before it counts as missing.
+pub const DEFAULT_PARTITION_THRESHOLD: Duration = Duration::from_secs(120);
+
+/// How often missing peers are redialed while a partition is suspected.
+pub const PARTITION_REDIAL_INTERVAL: Duration = Duration::from_secs(15);
+
+/// A change reported by [`PartitionWatch::check`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PartitionChange {
+    /// `missing_peers` have been unreachable for the threshold, the first of them since
+    /// `since_ms`, while clients are connected.
+    Detected { missing_peers: Vec<PeerId>, since_ms: u64 },
+    /// Every missing peer is back or no longer expected. `peers` are the ones that were
+    /// missing; the partition lasted `duration` from `since_ms`.
+    Resolved { peers: Vec<PeerId>, since_ms: u64, duration: Duration },
+}
+
+/// An ongoing suspected partition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Partition {
+    pub missing_peers: Vec<PeerId>,
+    pub since_ms: u64,
+}
+
+/// A snapshot of a [`PartitionWatch`], for operators.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PartitionStatus {
+    /// Each expected peer, with when it became unreachable if it is.
+    pub expected: Vec<(PeerId, Option<u64>)>,
+    pub partition: Option<Partition>,
+}
+
+impl PartitionStatus {
+    /// `/metrics` lines.
+    pub fn metrics(&self) -> Vec<(String, u64)> {
+        let unreachable = self.expected.iter().filter(|(_, since)| since.is_some()).count();
+        vec![
+            ("docstore_partition_suspected".to_string(), self.partition.is_some() as u64),
+            ("docstore_expected_peers".to_string(), self.expected.len() as u64),
+            ("docstore_expected_peers_unreachable".to_string(), unreachable as u64),
+        ]
+    }
+}
+
+/// See the module docs.
+#[derive(Debug)]
+pub struct PartitionWatch {
+    threshold: Duration,
+    /// Expected peers, with when each became unreachable; `None` while connected.
+    peers: BTreeMap<PeerId, Option<u64>>,
+    partition: Option<Partition>,
+    next_redial_ms: u64,
+}
+
+impl PartitionWatch {
+    pub fn new(threshold: Duration) -> Self {
+        Self { threshold, peers: BTreeMap::new(), partition: None, next_redial_ms: 0 }
+    }
+
+    /// Judge by `threshold` from the next check on.
+    pub fn set_threshold(&mut self, threshold: Duration) {
+        self.threshold = threshold;
+    }
+
+    /// Expect `peer` to stay reachable. A new peer counts as unreachable from `now_ms`
+    /// until it connects; expecting a peer again changes nothing.
+    pub fn expect(&mut self, peer: PeerId, now_ms: u64) {
+        self.peers.entry(peer).or_insert(Some(now_ms));
+    }
+
+    /// Stop expecting `peer`, e.g. because it was decommissioned. Returns false if it was
+    /// not expected.
+    pub fn remove(&mut self, peer: &PeerId) -> bool {
+        if let Some(partition) = &mut self.partition {
+            partition.missing_peers.retain(|missing| missing != peer);
+        }
+        self.peers.remove(peer).is_some()
+    }
+
+    pub fn is_expected(&self, peer: &PeerId) -> bool {
+        self.peers.contains_key(peer)
+    }
+
+    pub fn partition(&self) -> Option<&Partition> {
+        self.partition.as_ref()
+    }
+
+    pub fn status(&self) -> PartitionStatus {
+        PartitionStatus {
+            expected: self.peers.iter().map(|(peer, since)| (*peer, *since)).collect(),
+            partition: self.partition.clone(),
+        }
+    }
+
+    /// A connection to `peer` is up.
+    pub fn connected(&mut self, peer: &PeerId) {
+        if let Some(since) = self.peers.get_mut(peer) {
+            *since = None;
+        }
+    }
+
+    /// The last connection to `peer` closed.
+    pub fn disconnected(&mut self, peer: &PeerId, now_ms: u64) {
+        if let Some(since) = self.peers.get_mut(peer).filter(|since| since.is_none()) {
+            *since = Some(now_ms);
+        }
+    }
+
+    /// Re-evaluate with `clients` connected peers that are not expected ones.
+    pub fn check(&mut self, now_ms: u64, clients: usize) -> Option<PartitionChange> {
+        let threshold_ms = self.threshold.as_millis() as u64;
+        let missing: Vec<(PeerId, u64)> = self
+            .peers
+            .iter()
+            .filter_map(|(peer, since)| since.filter(|since| now_ms.saturating_sub(*since) >= threshold_ms).map(|s| (*peer, s)))
+            .collect();
+        let Some(partition) = &mut self.partition else {
+            if missing.is_empty() || clients == 0 {
+                return None;
+            }
+            let since_ms = missing.iter().map(|(_, since)| *since).min().unwrap_or(now_ms);
+            let missing_peers: Vec<PeerId> = missing.into_iter().map(|(peer, _)| peer).collect();
+            self.partition = Some(Partition { missing_peers: missing_peers.clone(), since_ms });
+            self.next_redial_ms = now_ms;
+            return Some(PartitionChange::Detected { missing_peers, since_ms });
+        };
+        // Peers that go missing during the partition are part of it
+        for (peer, _) in &missing {
+            if !partition.missing_peers.contains(peer) {
+                partition.missing_peers.push(*peer);
+            }
+        }
+        let peers = &self.peers;
+        if partition.missing_peers.iter().any(|peer| peers.get(peer).is_some_and(Option::is_some)) {
+            return None;
+        }
+        let Partition { missing_peers, since_ms } = self.partition.take()?;
+        let duration = Duration::from_millis(now_ms.saturating_sub(since_ms));
+        Some(PartitionChange::Resolved { peers: missing_peers, since_ms, duration })
+    }
+
+    /// The missing peers to redial now, while a partition is suspected; at most once per
+    /// [`PARTITION_REDIAL_INTERVAL`].
+    pub fn take_redial(&mut self, now_ms: u64) -> Vec<PeerId> {
+        let Some(partition) = &self.partition else {
+            return Vec::new();
+        };
+        if now_ms < self.next_redial_ms {
+            return Vec::new();
+        }
+        self.next_redial_ms = now_ms + PARTITION_REDIAL_INTERVAL.as_millis() as u64;
+        partition.missing_peers.iter().filter(|peer| self.peers.get(*peer).is_some_and(Option::is_some)).copied().collect()
+    }
+}
+
+impl Default for PartitionWatch {
+    fn default() -> Self {
+        Self::new(DEFAULT_PARTITION_THRESHOLD)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEC: u64 = 1000;
+
+    #[test]
+    fn detects_only_with_clients_and_resolves_on_return() {
+        let mut watch = PartitionWatch::new(Duration::from_secs(60));
+        let (a, b) = (PeerId::random(), PeerId::random());
+        watch.expect(a, 0);
+        watch.expect(b, 0);
+        watch.connected(&a);
+        watch.connected(&b);
+
+        watch.disconnected(&a, 10 * SEC);
+        assert_eq!(watch.check(50 * SEC, 3), None);
+        // Past the threshold, but nobody local to be split off with
+        assert_eq!(watch.check(80 * SEC, 0), None);
+        assert_eq!(watch.check(90 * SEC, 3), Some(PartitionChange::Detected { missing_peers: vec![a], since_ms: 10 * SEC }));
+        assert_eq!(watch.check(95 * SEC, 3), None);
+        assert_eq!(watch.take_redial(95 * SEC), vec![a]);
+        assert_eq!(watch.take_redial(100 * SEC), Vec::<PeerId>::new());
+
+        watch.connected(&a);
+        assert_eq!(
+            watch.check(130 * SEC, 3),
+            Some(PartitionChange::Resolved { peers: vec![a], since_ms: 10 * SEC, duration: Duration::from_secs(120) })
+        );
+        assert!(watch.partition().is_none());
+        assert_eq!(watch.take_redial(200 * SEC), Vec::<PeerId>::new());
+    }
+
+    #[test]
+    fn removed_peers_stop_counting() {
+        let mut watch = PartitionWatch::new(Duration::from_secs(60));
+        let (a, b) = (PeerId::random(), PeerId::random());
+        watch.expect(a, 0);
+        watch.expect(b, 0);
+        watch.connected(&b);
+        assert!(matches!(watch.check(60 * SEC, 1), Some(PartitionChange::Detected { .. })));
+
+        // a was decommissioned: the partition it caused is over
+        assert!(watch.remove(&a));
+        assert!(!watch.is_expected(&a));
+        assert!(matches!(watch.check(61 * SEC, 1), Some(PartitionChange::Resolved { .. })));
+
+        watch.disconnected(&b, 100 * SEC);
+        assert!(watch.remove(&b));
+        assert_eq!(watch.check(500 * SEC, 1), None);
+        let metrics: BTreeMap<String, u64> = watch.status().metrics().into_iter().collect();
+        assert_eq!(metrics["docstore_expected_peers"], 0);
+        assert_eq!(metrics["docstore_partition_suspected"], 0);
+    }
+}
@@ -0,0 +1,168 @@
+//! Meeting the other clients of our relays, shared by the native and wasm event loops.
+//!
+//! Every peer we meet that serves
+//! [`PEERS_PROTOCOL`](crate::behaviour::peers::PEERS_PROTOCOL) is asked for the clients
+//! it lists, right away and then every [`PEER_EXCHANGE_INTERVAL`]; the same request tells it
+//! whether to list us. A few of the listed peers we aren't connected to yet are dialed
+//! (browsers add a circuit through the relay that listed them), and the ones that
+//! connect join the gossipsub mesh as explicit peers, so traffic between them no longer
+//! hairpins through the relay.
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use libp2p::{Multiaddr, PeerId};
+
+use crate::behaviour::peers::{PeersRequest, PeersResponse, MAX_LISTED_PEERS, MAX_PEER_ADDRS};
+
+/// How often each relay is asked for its peers again.
+pub const PEER_EXCHANGE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Listed peers dialed per response.
+pub const DEFAULT_LISTED_PEERS_TO_DIAL: usize = 3;
+
+/// A listed peer worth dialing, and the relay that listed it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListedDial {
+    pub peer_id: PeerId,
+    pub addrs: Vec<Multiaddr>,
+    pub relay: PeerId,
+}
+
+#[derive(Debug)]
+pub struct PeerExchange {
+    local_peer_id: PeerId,
+    discoverable: bool,
+    /// Peers serving the protocol.
+    relays: HashSet<PeerId>,
+    /// Listed peers dialed and not connected yet.
+    dialing: HashSet<PeerId>,
+    /// Listed peers we connected to, explicit gossipsub peers until they disconnect.
+    meshed: HashSet<PeerId>,
+}
+
+impl PeerExchange {
+    pub fn new(local_peer_id: PeerId, discoverable: bool) -> Self {
+        Self { local_peer_id, discoverable, relays: HashSet::new(), dialing: HashSet::new(), meshed: HashSet::new() }
+    }
+
+    /// The request to send, carrying our own addresses for the relay to list.
+    pub fn request(&self, addrs: impl IntoIterator<Item = Multiaddr>) -> PeersRequest {
+        let addrs = if self.discoverable { addrs.into_iter().take(MAX_PEER_ADDRS).map(|a| a.to_string()).collect() } else { Vec::new() };
+        PeersRequest { discoverable: self.discoverable, limit: MAX_LISTED_PEERS as u32, addrs }
+    }
+
+    /// Note a peer that identified with the protocol. True if it is new, and should be
+    /// asked right away.
+    pub fn add_relay(&mut self, peer_id: PeerId) -> bool {
+        self.relays.insert(peer_id)
+    }
+
+    pub fn relays(&self) -> Vec<PeerId> {
+        self.relays.iter().copied().collect()
+    }
+
+    /// Pick the listed peers to dial from `relay`'s response: not us, not a relay, not
+    /// connected (`is_connected`) and not dialed already.
+    pub fn listed(
+        &mut self,
+        relay: PeerId,
+        response: PeersResponse,
+        is_connected: impl Fn(&PeerId) -> bool,
+    ) -> Vec<ListedDial> {
+        let mut dials = Vec::new();
+        for (peer_id, addrs) in response.peers.iter().filter_map(|p| p.parse()) {
+            if dials.len() == DEFAULT_LISTED_PEERS_TO_DIAL {
+                break;
+            }
+            if peer_id == self.local_peer_id
+                || self.relays.contains(&peer_id)
+                || self.dialing.contains(&peer_id)
+                || is_connected(&peer_id)
+            {
+                continue;
+            }
+            self.dialing.insert(peer_id);
+            dials.push(ListedDial { peer_id, addrs, relay });
+        }
+        dials
+    }
+
+    /// A connection came up. True if it is to a listed peer we dialed, which should now be
+    /// added to the mesh.
+    pub fn connected(&mut self, peer_id: &PeerId) -> bool {
+        self.dialing.remove(peer_id) && self.meshed.insert(*peer_id)
+    }
+
+    /// Dialing a listed peer failed; a later response may list it again.
+    pub fn dial_failed(&mut self, peer_id: &PeerId) {
+        self.dialing.remove(peer_id);
+    }
+
+    /// The last connection to `peer_id` closed. True if it was a meshed listed peer, which
+    /// should be removed from the mesh.
+    pub fn disconnected(&mut self, peer_id: &PeerId) -> bool {
+        self.relays.remove(peer_id);
+        self.dialing.remove(peer_id);
+        self.meshed.remove(peer_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::behaviour::peers::ListedPeer;
+
+    fn response(peers: &[PeerId]) -> PeersResponse {
+        PeersResponse {
+            peers: peers.iter().map(|p| ListedPeer { peer_id: p.to_string(), addrs: vec!["/memory/7".into()] }).collect(),
+        }
+    }
+
+    #[test]
+    fn asks_each_relay_once_with_our_addresses() {
+        let mut exchange = PeerExchange::new(PeerId::random(), true);
+        let relay = PeerId::random();
+        assert!(exchange.add_relay(relay));
+        assert!(!exchange.add_relay(relay));
+        assert_eq!(exchange.relays(), vec![relay]);
+
+        let request = exchange.request(["/memory/7".parse().unwrap()]);
+        assert!(request.discoverable);
+        assert_eq!(request.addrs, vec!["/memory/7".to_string()]);
+        let hidden = PeerExchange::new(PeerId::random(), false);
+        assert!(hidden.request(["/memory/7".parse().unwrap()]).addrs.is_empty());
+    }
+
+    #[test]
+    fn dials_a_few_new_peers_and_meshes_the_ones_that_connect() {
+        let local = PeerId::random();
+        let mut exchange = PeerExchange::new(local, true);
+        let relay = PeerId::random();
+        exchange.add_relay(relay);
+        let connected = PeerId::random();
+        let others: Vec<PeerId> = (0..5).map(|_| PeerId::random()).collect();
+        let mut listed = vec![local, relay, connected];
+        listed.extend(&others);
+
+        let dials = exchange.listed(relay, response(&listed), |p| *p == connected);
+        let dialed: Vec<PeerId> = dials.iter().map(|d| d.peer_id).collect();
+        assert_eq!(dialed, others[..DEFAULT_LISTED_PEERS_TO_DIAL].to_vec());
+        assert_eq!(dials[0].addrs, vec!["/memory/7".parse::<Multiaddr>().unwrap()]);
+        assert_eq!(dials[0].relay, relay);
+
+        // Dials in flight aren't repeated; failed ones may be
+        exchange.dial_failed(&others[1]);
+        let again: Vec<PeerId> =
+            exchange.listed(relay, response(&others), |_| false).iter().map(|d| d.peer_id).collect();
+        assert_eq!(again, vec![others[1], others[3], others[4]]);
+
+        assert!(exchange.connected(&others[0]));
+        assert!(!exchange.connected(&others[0]));
+        assert!(!exchange.connected(&PeerId::random()));
+        assert!(exchange.disconnected(&others[0]));
+        assert!(!exchange.disconnected(&others[0]));
+        exchange.disconnected(&relay);
+        assert!(exchange.relays().is_empty());
+    }
+}
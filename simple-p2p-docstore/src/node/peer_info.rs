@@ -0,0 +1,112 @@
+//! What connected peers told us about themselves through identify, shared by the native
+//! and wasm event loops.
+
+use std::collections::HashMap;
+
+use libp2p::{identify, Multiaddr, PeerId};
+
+/// The parts of a peer's identify info applications care about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerInfo {
+    pub agent_version: String,
+    pub protocol_version: String,
+    pub protocols: Vec<String>,
+    pub listen_addrs: Vec<Multiaddr>,
+}
+
+impl From<&identify::Info> for PeerInfo {
+    fn from(info: &identify::Info) -> Self {
+        Self {
+            agent_version: info.agent_version.clone(),
+            protocol_version: info.protocol_version.clone(),
+            protocols: info.protocols.iter().map(ToString::to_string).collect(),
+            listen_addrs: info.listen_addrs.clone(),
+        }
+    }
+}
+
+impl PeerInfo {
+    pub fn supports(&self, protocol: &str) -> bool {
+        self.protocols.iter().any(|p| p == protocol)
+    }
+
+    /// Whether the peer is a read replica, see [`crate::node::replica`]: prefer it for
+    /// reads, and a writer for CAS updates and receipts.
+    pub fn is_replica(&self) -> bool {
+        crate::node::replica::is_replica_agent(&self.agent_version)
+    }
+
+    /// The protocols of `local` (see [`crate::node::NodeBuilder::local_protocols`]) that
+    /// the peer also advertises, in `local`'s order.
+    pub fn shared_protocols(&self, local: &[String]) -> Vec<String> {
+        local.iter().filter(|p| self.supports(p)).cloned().collect()
+    }
+}
+
+/// Identify info of currently connected peers.
+#[derive(Debug, Clone, Default)]
+pub struct PeerInfoCache {
+    peers: HashMap<PeerId, PeerInfo>,
+}
+
+impl PeerInfoCache {
+    /// Remember `info` for `peer_id`. Returns true if it is new or differs from what the
+    /// peer sent before (identify repeats itself periodically).
+    pub fn update(&mut self, peer_id: PeerId, info: PeerInfo) -> bool {
+        if self.peers.get(&peer_id) == Some(&info) {
+            return false;
+        }
+        self.peers.insert(peer_id, info);
+        true
+    }
+
+    pub fn get(&self, peer_id: &PeerId) -> Option<&PeerInfo> {
+        self.peers.get(peer_id)
+    }
+
+    /// Connected peers that advertise `protocol`.
+    pub fn supporting(&self, protocol: &str) -> Vec<PeerId> {
+        self.peers.iter().filter(|(_, info)| info.supports(protocol)).map(|(peer, _)| *peer).collect()
+    }
+
+    /// Forget a peer once its last connection has closed.
+    pub fn remove(&mut self, peer_id: &PeerId) {
+        self.peers.remove(peer_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(agent: &str) -> PeerInfo {
+        PeerInfo {
+            agent_version: agent.into(),
+            protocol_version: "/docstore/1.0.0".into(),
+            protocols: vec!["/ipfs/kad/1.0.0".into()],
+            listen_addrs: vec![],
+        }
+    }
+
+    #[test]
+    fn reports_only_new_or_changed_info() {
+        let mut cache = PeerInfoCache::default();
+        let peer = PeerId::random();
+        assert!(cache.update(peer, info("a/1")));
+        assert!(!cache.update(peer, info("a/1")));
+        assert!(cache.update(peer, info("a/2")));
+        assert!(cache.get(&peer).unwrap().supports("/ipfs/kad/1.0.0"));
+        assert_eq!(cache.supporting("/ipfs/kad/1.0.0"), [peer]);
+        cache.remove(&peer);
+        assert!(cache.get(&peer).is_none());
+    }
+
+    #[test]
+    fn shared_protocols_keep_the_local_order() {
+        let mut peer = info("a/1");
+        peer.protocols = vec!["/meshsub/1.1.0".into(), "/custom/1.0.0".into(), "/ipfs/ping/1.0.0".into()];
+        let local: Vec<String> = ["/ipfs/ping/1.0.0", "/ipfs/kad/1.0.0", "/meshsub/1.1.0"].map(String::from).to_vec();
+        assert_eq!(peer.shared_protocols(&local), ["/ipfs/ping/1.0.0", "/meshsub/1.1.0"]);
+        assert!(peer.shared_protocols(&[]).is_empty());
+    }
+}
@@ -0,0 +1,529 @@
+//! Outbound proxying for native nodes in networks that only let traffic out through a
+//! SOCKS5 or HTTP CONNECT proxy. [`ProxiedTcp`] wraps the TCP transport: listening is
+//! unchanged, dials open a tunnel through the proxy instead of a direct connection.
+//!
+//! Host names are resolved by the DNS transport in front of it, so the proxy is handed IP
+//! addresses. Credentials only go to the proxy; `Debug` and `Display` of a [`ProxyConfig`]
+//! leave them out, so it can be logged as is.
+
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use libp2p::core::multiaddr::Protocol;
+use libp2p::core::transport::{DialOpts, ListenerId, TransportError, TransportEvent};
+use libp2p::core::Transport;
+use libp2p::tcp;
+use libp2p::Multiaddr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// How long the proxy may take to accept the connection, and then to open the tunnel.
+pub const PROXY_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// The longest HTTP CONNECT response head we read before giving up on the proxy.
+const MAX_RESPONSE_HEAD: usize = 8 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyKind {
+    Socks5,
+    HttpConnect,
+}
+
+impl ProxyKind {
+    fn scheme(self) -> &'static str {
+        match self {
+            ProxyKind::Socks5 => "socks5",
+            ProxyKind::HttpConnect => "http",
+        }
+    }
+}
+
+/// Username and password for the proxy.
+#[derive(Clone, PartialEq, Eq)]
+pub struct ProxyAuth {
+    pub username: String,
+    pub password: String,
+}
+
+impl fmt::Debug for ProxyAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ProxyAuth(<redacted>)")
+    }
+}
+
+/// A proxy to dial through, parsed from `socks5://[user:pass@]host:port` or
+/// `http://[user:pass@]host:port`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyConfig {
+    pub kind: ProxyKind,
+    /// `host:port` of the proxy itself.
+    pub addr: String,
+    pub auth: Option<ProxyAuth>,
+}
+
+impl ProxyConfig {
+    pub fn new(kind: ProxyKind, addr: impl Into<String>) -> Self {
+        Self { kind, addr: addr.into(), auth: None }
+    }
+
+    pub fn with_auth(mut self, username: impl Into<String>, password: impl Into<String>) -> Self {
+        self.auth = Some(ProxyAuth { username: username.into(), password: password.into() });
+        self
+    }
+
+    /// Open a tunnel to `target` through the proxy.
+    pub async fn connect(&self, target: &ProxyTarget) -> Result<TcpStream, ProxyError> {
+        let mut stream = tokio::time::timeout(PROXY_TIMEOUT, TcpStream::connect(self.addr.as_str()))
+            .await
+            .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()))
+            .map_err(|source| ProxyError::ProxyUnreachable { proxy: self.to_string(), source })?;
+        let _ = stream.set_nodelay(true);
+        let handshake = async {
+            match self.kind {
+                ProxyKind::Socks5 => socks5_handshake(&mut stream, target, self.auth.as_ref()).await,
+                ProxyKind::HttpConnect => http_connect(&mut stream, target, self.auth.as_ref()).await,
+            }
+        };
+        tokio::time::timeout(PROXY_TIMEOUT, handshake).await.unwrap_or_else(|_| {
+            Err(ProxyError::TargetUnreachable {
+                target: target.to_string(),
+                reason: "no answer from the proxy in time".to_string(),
+                kind: io::ErrorKind::TimedOut,
+            })
+        })?;
+        Ok(stream)
+    }
+}
+
+/// Shows the proxy without its credentials.
+impl fmt::Display for ProxyConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let auth = if self.auth.is_some() { "***@" } else { "" };
+        write!(f, "{}://{}{}", self.kind.scheme(), auth, self.addr)
+    }
+}
+
+impl FromStr for ProxyConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (scheme, rest) = s.split_once("://").ok_or_else(|| "proxy must look like socks5://host:port".to_string())?;
+        let kind = match scheme.to_ascii_lowercase().as_str() {
+            "socks5" => ProxyKind::Socks5,
+            "http" => ProxyKind::HttpConnect,
+            other => return Err(format!("unsupported proxy scheme {other}, use socks5 or http")),
+        };
+        let rest = rest.trim_end_matches('/');
+        let (auth, addr) = match rest.rsplit_once('@') {
+            Some((userinfo, addr)) => {
+                let (username, password) =
+                    userinfo.split_once(':').ok_or_else(|| "proxy credentials must be user:password".to_string())?;
+                (Some(ProxyAuth { username: username.to_string(), password: password.to_string() }), addr)
+            }
+            None => (None, rest),
+        };
+        let port = addr.rsplit_once(':').map(|(host, port)| (host, port.parse::<u16>()));
+        match port {
+            Some((host, Ok(_))) if !host.is_empty() => {}
+            // Not echoed: a mistyped address may well be the credentials
+            _ => return Err("proxy address must be host:port".to_string()),
+        }
+        if auth.as_ref().is_some_and(|auth| auth.username.len() > 255 || auth.password.len() > 255) {
+            return Err("proxy username and password must be at most 255 bytes".to_string());
+        }
+        Ok(Self { kind, addr: addr.to_string(), auth })
+    }
+}
+
+/// Where a proxied dial goes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProxyTarget {
+    Ip(IpAddr, u16),
+    /// Handed to the proxy to resolve, for addresses that reach us unresolved.
+    Domain(String, u16),
+}
+
+impl ProxyTarget {
+    /// The target of a `/ip4|ip6|dns|dns4|dns6/<host>/tcp/<port>[/p2p/<id>]` address; other
+    /// addresses are not for the TCP transport.
+    pub fn from_multiaddr(addr: &Multiaddr) -> Option<Self> {
+        let mut protocols = addr.iter();
+        let host = protocols.next()?;
+        let Some(Protocol::Tcp(port)) = protocols.next() else {
+            return None;
+        };
+        match protocols.next() {
+            None | Some(Protocol::P2p(_)) => {}
+            Some(_) => return None,
+        }
+        match host {
+            Protocol::Ip4(ip) => Some(ProxyTarget::Ip(ip.into(), port)),
+            Protocol::Ip6(ip) => Some(ProxyTarget::Ip(ip.into(), port)),
+            Protocol::Dns(name) | Protocol::Dns4(name) | Protocol::Dns6(name) => {
+                Some(ProxyTarget::Domain(name.to_string(), port))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ProxyTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProxyTarget::Ip(IpAddr::V6(ip), port) => write!(f, "[{ip}]:{port}"),
+            ProxyTarget::Ip(ip, port) => write!(f, "{ip}:{port}"),
+            ProxyTarget::Domain(name, port) => write!(f, "{name}:{port}"),
+        }
+    }
+}
+
+/// Why a proxied dial failed. Never carries the credentials.
+#[derive(Debug, thiserror::Error)]
+pub enum ProxyError {
+    /// We could not reach the proxy itself.
+    #[error("proxy {proxy} unreachable: {source}")]
+    ProxyUnreachable {
+        proxy: String,
+        #[source]
+        source: io::Error,
+    },
+    /// The proxy answered but could not reach the target.
+    #[error("{target} unreachable through the proxy: {reason}")]
+    TargetUnreachable { target: String, reason: String, kind: io::ErrorKind },
+    #[error("the proxy refused our credentials")]
+    AuthRejected,
+    #[error("the proxy wants credentials")]
+    AuthRequired,
+    #[error("unexpected answer from the proxy: {0}")]
+    Protocol(String),
+}
+
+impl ProxyError {
+    fn io(target: &ProxyTarget, error: io::Error) -> Self {
+        ProxyError::TargetUnreachable { target: target.to_string(), reason: error.to_string(), kind: error.kind() }
+    }
+}
+
+impl From<ProxyError> for io::Error {
+    fn from(error: ProxyError) -> Self {
+        let kind = match &error {
+            ProxyError::ProxyUnreachable { source, .. } => source.kind(),
+            ProxyError::TargetUnreachable { kind, .. } => *kind,
+            ProxyError::AuthRejected | ProxyError::AuthRequired => io::ErrorKind::PermissionDenied,
+            ProxyError::Protocol(_) => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, error)
+    }
+}
+
+/// Whether a dial error, however deeply the transport stack wrapped it, is the proxy
+/// itself being unreachable.
+pub fn is_proxy_unreachable(error: &(dyn StdError + 'static)) -> bool {
+    let mut next = Some(error);
+    while let Some(error) = next {
+        if matches!(error.downcast_ref(), Some(ProxyError::ProxyUnreachable { .. })) {
+            return true;
+        }
+        // `io::Error::source` skips the error it wraps
+        next = match error.downcast_ref::<io::Error>() {
+            Some(io) => io.get_ref().map(|inner| inner as &(dyn StdError + 'static)),
+            None => error.source(),
+        };
+    }
+    false
+}
+
+/// SOCKS5 (RFC 1928) `CONNECT` to `target`, with username/password authentication
+/// (RFC 1929) when `auth` is set.
+pub async fn socks5_handshake<S>(stream: &mut S, target: &ProxyTarget, auth: Option<&ProxyAuth>) -> Result<(), ProxyError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let io = |e| ProxyError::io(target, e);
+    let methods: &[u8] = if auth.is_some() { &[0x00, 0x02] } else { &[0x00] };
+    let mut greeting = vec![0x05, methods.len() as u8];
+    greeting.extend_from_slice(methods);
+    stream.write_all(&greeting).await.map_err(io)?;
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await.map_err(io)?;
+    if choice[0] != 0x05 {
+        return Err(ProxyError::Protocol(format!("SOCKS version {}", choice[0])));
+    }
+    match (choice[1], auth) {
+        (0x00, _) => {}
+        (0x02, Some(auth)) => {
+            let mut request = vec![0x01, auth.username.len() as u8];
+            request.extend_from_slice(auth.username.as_bytes());
+            request.push(auth.password.len() as u8);
+            request.extend_from_slice(auth.password.as_bytes());
+            stream.write_all(&request).await.map_err(io)?;
+            let mut status = [0u8; 2];
+            stream.read_exact(&mut status).await.map_err(io)?;
+            if status[1] != 0x00 {
+                return Err(ProxyError::AuthRejected);
+            }
+        }
+        (0x02, None) | (0xff, None) => return Err(ProxyError::AuthRequired),
+        (0xff, Some(_)) => return Err(ProxyError::AuthRejected),
+        (method, _) => return Err(ProxyError::Protocol(format!("SOCKS method {method}"))),
+    }
+
+    let mut request = vec![0x05, 0x01, 0x00];
+    let port = match target {
+        ProxyTarget::Ip(IpAddr::V4(ip), port) => {
+            request.push(0x01);
+            request.extend_from_slice(&ip.octets());
+            port
+        }
+        ProxyTarget::Ip(IpAddr::V6(ip), port) => {
+            request.push(0x04);
+            request.extend_from_slice(&ip.octets());
+            port
+        }
+        ProxyTarget::Domain(name, port) => {
+            let len = u8::try_from(name.len()).map_err(|_| ProxyError::Protocol("host name too long".to_string()))?;
+            request.extend_from_slice(&[0x03, len]);
+            request.extend_from_slice(name.as_bytes());
+            port
+        }
+    };
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await.map_err(io)?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await.map_err(io)?;
+    if reply[0] != 0x05 {
+        return Err(ProxyError::Protocol(format!("SOCKS version {}", reply[0])));
+    }
+    if reply[1] != 0x00 {
+        let (reason, kind) = socks5_reply(reply[1]);
+        return Err(ProxyError::TargetUnreachable { target: target.to_string(), reason: reason.to_string(), kind });
+    }
+    // The address the proxy bound for us, which we have no use for
+    let bound = match reply[3] {
+        0x01 => 4,
+        0x04 => 16,
+        0x03 => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len).await.map_err(io)?;
+            len[0] as usize
+        }
+        atyp => return Err(ProxyError::Protocol(format!("SOCKS address type {atyp}"))),
+    };
+    let mut rest = vec![0u8; bound + 2];
+    stream.read_exact(&mut rest).await.map_err(io)?;
+    Ok(())
+}
+
+fn socks5_reply(code: u8) -> (&'static str, io::ErrorKind) {
+    match code {
+        0x02 => ("connection not allowed by the proxy's rules", io::ErrorKind::PermissionDenied),
+        0x03 => ("network unreachable", io::ErrorKind::Other),
+        0x04 => ("host unreachable", io::ErrorKind::Other),
+        0x05 => ("connection refused", io::ErrorKind::ConnectionRefused),
+        0x06 => ("TTL expired", io::ErrorKind::TimedOut),
+        0x07 => ("command not supported", io::ErrorKind::Unsupported),
+        0x08 => ("address type not supported", io::ErrorKind::Unsupported),
+        _ => ("general failure", io::ErrorKind::Other),
+    }
+}
+
+/// HTTP `CONNECT` to `target`, with basic authentication when `auth` is set. Reads the
+/// response head byte by byte so nothing of the tunnel is consumed.
+pub async fn http_connect<S>(stream: &mut S, target: &ProxyTarget, auth: Option<&ProxyAuth>) -> Result<(), ProxyError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let io = |e| ProxyError::io(target, e);
+    let mut request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+    if let Some(auth) = auth {
+        let credentials = STANDARD.encode(format!("{}:{}", auth.username, auth.password));
+        request.push_str(&format!("Proxy-Authorization: Basic {credentials}\r\n"));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await.map_err(io)?;
+
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_RESPONSE_HEAD {
+            return Err(ProxyError::Protocol("response head too long".to_string()));
+        }
+        let mut byte = [0u8; 1];
+        stream.read_exact(&mut byte).await.map_err(io)?;
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let status_line = head.lines().next().unwrap_or_default();
+    let status = status_line
+        .strip_prefix("HTTP/1.")
+        .and_then(|rest| rest.split_whitespace().nth(1))
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or_else(|| ProxyError::Protocol(status_line.to_string()))?;
+    match status {
+        200..=299 => Ok(()),
+        407 if auth.is_some() => Err(ProxyError::AuthRejected),
+        407 => Err(ProxyError::AuthRequired),
+        _ => Err(ProxyError::TargetUnreachable {
+            target: target.to_string(),
+            reason: status_line.to_string(),
+            kind: if matches!(status, 502 | 503) { io::ErrorKind::ConnectionRefused } else { io::ErrorKind::Other },
+        }),
+    }
+}
+
+/// The tokio TCP transport, dialing through a proxy when one is set.
+pub struct ProxiedTcp {
+    tcp: tcp::tokio::Transport,
+    proxy: Option<ProxyConfig>,
+}
+
+impl ProxiedTcp {
+    pub fn new(config: tcp::Config, proxy: Option<ProxyConfig>) -> Self {
+        Self { tcp: tcp::tokio::Transport::new(config), proxy }
+    }
+}
+
+impl Transport for ProxiedTcp {
+    type Output = tcp::tokio::TcpStream;
+    type Error = io::Error;
+    type ListenerUpgrade = <tcp::tokio::Transport as Transport>::ListenerUpgrade;
+    type Dial = BoxFuture<'static, Result<Self::Output, Self::Error>>;
+
+    fn listen_on(&mut self, id: ListenerId, addr: Multiaddr) -> Result<(), TransportError<Self::Error>> {
+        self.tcp.listen_on(id, addr)
+    }
+
+    fn remove_listener(&mut self, id: ListenerId) -> bool {
+        self.tcp.remove_listener(id)
+    }
+
+    fn dial(&mut self, addr: Multiaddr, opts: DialOpts) -> Result<Self::Dial, TransportError<Self::Error>> {
+        let Some(proxy) = &self.proxy else {
+            return Ok(self.tcp.dial(addr, opts)?.boxed());
+        };
+        let Some(target) = ProxyTarget::from_multiaddr(&addr) else {
+            return Err(TransportError::MultiaddrNotSupported(addr));
+        };
+        let proxy = proxy.clone();
+        Ok(async move {
+            let stream = proxy.connect(&target).await?;
+            Ok(tcp::tokio::TcpStream::from(stream))
+        }
+        .boxed())
+    }
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<TransportEvent<Self::ListenerUpgrade, Self::Error>> {
+        Pin::new(&mut self.tcp).poll(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use tokio::net::TcpListener;
+
+    /// Accepts one client, checks the RFC 1929 credentials and connects it to `127.0.0.1:4001`
+    /// with the given reply code, echoing the tunnel afterwards.
+    async fn socks5_stub(expect: ProxyAuth, reply: u8) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut s, _) = listener.accept().await.unwrap();
+            let mut head = [0u8; 2];
+            s.read_exact(&mut head).await.unwrap();
+            let mut methods = vec![0u8; head[1] as usize];
+            s.read_exact(&mut methods).await.unwrap();
+            assert!(methods.contains(&0x02));
+            s.write_all(&[0x05, 0x02]).await.unwrap();
+
+            let mut version_len = [0u8; 2];
+            s.read_exact(&mut version_len).await.unwrap();
+            let mut username = vec![0u8; version_len[1] as usize];
+            s.read_exact(&mut username).await.unwrap();
+            let mut len = [0u8; 1];
+            s.read_exact(&mut len).await.unwrap();
+            let mut password = vec![0u8; len[0] as usize];
+            s.read_exact(&mut password).await.unwrap();
+            let ok = username == expect.username.as_bytes() && password == expect.password.as_bytes();
+            s.write_all(&[0x01, if ok { 0x00 } else { 0x01 }]).await.unwrap();
+            if !ok {
+                return;
+            }
+
+            let mut request = [0u8; 10];
+            s.read_exact(&mut request).await.unwrap();
+            assert_eq!(request, [0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, 0x0f, 0xa1]);
+            s.write_all(&[0x05, reply, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await.unwrap();
+            let mut ping = [0u8; 4];
+            if reply == 0x00 && s.read_exact(&mut ping).await.is_ok() {
+                s.write_all(&ping).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    fn target() -> ProxyTarget {
+        ProxyTarget::from_multiaddr(&"/ip4/127.0.0.1/tcp/4001/p2p/12D3KooWDpJ7As7BWAwRMfu1VU2WCqNjvq387JEYKDBj4kx6nXTN".parse().unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    fn parses_proxy_urls_and_never_shows_credentials() {
+        let proxy: ProxyConfig = "socks5://alice:s3cr:et@proxy.example:1080".parse().unwrap();
+        assert_eq!(proxy.kind, ProxyKind::Socks5);
+        assert_eq!(proxy.addr, "proxy.example:1080");
+        assert_eq!(proxy.auth.as_ref().unwrap().password, "s3cr:et");
+        assert_eq!(proxy.to_string(), "socks5://***@proxy.example:1080");
+        assert!(!format!("{proxy:?}").contains("s3cr"));
+        assert_eq!("http://10.0.0.1:3128".parse::<ProxyConfig>().unwrap(), ProxyConfig::new(ProxyKind::HttpConnect, "10.0.0.1:3128"));
+        assert!("socks4://proxy:1080".parse::<ProxyConfig>().is_err());
+        assert!("socks5://proxy".parse::<ProxyConfig>().is_err());
+        assert!(!"socks5://alice:s3cret".parse::<ProxyConfig>().unwrap_err().contains("s3cret"));
+
+        assert_eq!(target(), ProxyTarget::Ip(Ipv4Addr::LOCALHOST.into(), 4001));
+        assert_eq!(ProxyTarget::from_multiaddr(&"/ip4/127.0.0.1/udp/4001/quic-v1".parse().unwrap()), None);
+        assert_eq!(ProxyTarget::from_multiaddr(&"/dns4/example.com/tcp/443/wss".parse().unwrap()), None);
+    }
+
+    #[tokio::test]
+    async fn tunnels_through_a_socks5_proxy_with_credentials() {
+        let addr = socks5_stub(ProxyAuth { username: "alice".into(), password: "secret".into() }, 0x00).await;
+        let proxy = ProxyConfig::new(ProxyKind::Socks5, addr).with_auth("alice", "secret");
+        let mut stream = proxy.connect(&target()).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut echo = [0u8; 4];
+        stream.read_exact(&mut echo).await.unwrap();
+        assert_eq!(&echo, b"ping");
+    }
+
+    #[tokio::test]
+    async fn tells_proxy_failures_from_target_failures() {
+        let expect = ProxyAuth { username: "alice".into(), password: "secret".into() };
+        let addr = socks5_stub(expect.clone(), 0x00).await;
+        let wrong = ProxyConfig::new(ProxyKind::Socks5, addr).with_auth("alice", "guess");
+        assert!(matches!(wrong.connect(&target()).await, Err(ProxyError::AuthRejected)));
+
+        let addr = socks5_stub(expect, 0x05).await;
+        let proxy = ProxyConfig::new(ProxyKind::Socks5, addr).with_auth("alice", "secret");
+        let error = io::Error::from(proxy.connect(&target()).await.unwrap_err());
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+        assert!(!is_proxy_unreachable(&error));
+
+        // Nothing listens on a port we just let go of
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let gone = ProxyConfig::new(ProxyKind::Socks5, closed.to_string());
+        let error = io::Error::other(io::Error::from(gone.connect(&target()).await.unwrap_err()));
+        assert!(is_proxy_unreachable(&error));
+    }
+}
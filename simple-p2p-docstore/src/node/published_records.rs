@@ -0,0 +1,143 @@
+//! Records this node put into the DHT itself, tracked so they can be put again before
+//! they expire. Kademlia drops a record once its TTL passes; without re-putting it the
+//! record would silently vanish from the network.
+//!
+//! The registry only schedules: the event loop owning the Kademlia behaviour takes the due
+//! records, puts them and reports the outcome.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use libp2p_kad::RecordKey;
+
+use crate::Error;
+
+/// Records kept alive at once by default.
+pub const DEFAULT_MAX_PUBLISHED_RECORDS: usize = 1024;
+
+#[derive(Debug, Clone)]
+struct Published {
+    value: Vec<u8>,
+    ttl: Duration,
+    due_ms: u64,
+}
+
+/// A record due to be put again, see [`PublishedRecords::take_due`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DueRecord {
+    pub key: RecordKey,
+    pub value: Vec<u8>,
+    pub ttl: Duration,
+}
+
+#[derive(Debug)]
+pub struct PublishedRecords {
+    records: HashMap<RecordKey, Published>,
+    max_records: usize,
+}
+
+impl PublishedRecords {
+    pub fn new(max_records: usize) -> Self {
+        Self { records: HashMap::new(), max_records }
+    }
+
+    /// Keep `key` alive with `value`, replacing what was tracked for it before. The record
+    /// is due again after three quarters of `ttl`, leaving time for a retry. A `ttl` too
+    /// long to reckon with never runs out, so such a record is not tracked.
+    pub fn insert(&mut self, key: RecordKey, value: Vec<u8>, ttl: Duration, now_ms: u64) -> Result<(), Error> {
+        let Some(due_ms) = republish_at_ms(ttl, now_ms) else {
+            self.records.remove(&key);
+            return Ok(());
+        };
+        if !self.records.contains_key(&key) && self.records.len() >= self.max_records {
+            return Err(Error::TooManyRecords { max: self.max_records });
+        }
+        self.records.insert(key, Published { value, ttl, due_ms });
+        Ok(())
+    }
+
+    /// Stop republishing `key`. Returns false if it was not tracked. The copies already
+    /// stored in the DHT live out their TTL.
+    pub fn forget(&mut self, key: &RecordKey) -> bool {
+        self.records.remove(key).is_some()
+    }
+
+    pub fn contains(&self, key: &RecordKey) -> bool {
+        self.records.contains_key(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Unix milliseconds at which the next record is due, if any is tracked.
+    pub fn next_due_ms(&self) -> Option<u64> {
+        self.records.values().map(|r| r.due_ms).min()
+    }
+
+    /// Records due at `now_ms`, already rescheduled for their next round.
+    pub fn take_due(&mut self, now_ms: u64) -> Vec<DueRecord> {
+        let mut due = Vec::new();
+        for (key, record) in &mut self.records {
+            if record.due_ms <= now_ms {
+                record.due_ms = republish_at_ms(record.ttl, now_ms).unwrap_or(u64::MAX);
+                due.push(DueRecord { key: key.clone(), value: record.value.clone(), ttl: record.ttl });
+            }
+        }
+        due
+    }
+}
+
+/// When a record put at `now_ms` is due again, or `None` if that is out of reach.
+fn republish_at_ms(ttl: Duration, now_ms: u64) -> Option<u64> {
+    let after_ms = u64::try_from(ttl.as_millis() * 3 / 4).ok()?;
+    now_ms.checked_add(after_ms.max(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(name: &str) -> RecordKey {
+        RecordKey::new(&name)
+    }
+
+    #[test]
+    fn schedules_before_expiry_and_respects_the_cap() {
+        let mut records = PublishedRecords::new(2);
+        records.insert(key("a"), b"1".to_vec(), Duration::from_secs(4), 0).unwrap();
+        records.insert(key("b"), b"2".to_vec(), Duration::from_secs(40), 0).unwrap();
+        assert!(matches!(
+            records.insert(key("c"), b"3".to_vec(), Duration::from_secs(4), 0),
+            Err(Error::TooManyRecords { max: 2 })
+        ));
+        // Replacing a tracked record is not limited
+        records.insert(key("a"), b"1b".to_vec(), Duration::from_secs(4), 0).unwrap();
+
+        assert_eq!(records.next_due_ms(), Some(3000));
+        assert!(records.take_due(2999).is_empty());
+        let due = records.take_due(3000);
+        assert_eq!(due, vec![DueRecord { key: key("a"), value: b"1b".to_vec(), ttl: Duration::from_secs(4) }]);
+        assert_eq!(records.next_due_ms(), Some(6000));
+
+        assert!(records.forget(&key("a")));
+        assert!(!records.forget(&key("a")));
+        assert_eq!(records.next_due_ms(), Some(30_000));
+    }
+
+    #[test]
+    fn endless_ttls_are_not_republished() {
+        let mut records = PublishedRecords::new(1);
+        records.insert(key("a"), b"1".to_vec(), Duration::from_secs(4), 0).unwrap();
+        records.insert(key("a"), b"1".to_vec(), Duration::MAX, 0).unwrap();
+        assert!(!records.contains(&key("a")));
+        records.insert(key("b"), b"2".to_vec(), Duration::from_millis(u64::MAX), u64::MAX - 1).unwrap();
+        assert!(records.is_empty());
+        assert_eq!(records.next_due_ms(), None);
+        assert!(records.take_due(u64::MAX).is_empty());
+    }
+}
@@ -0,0 +1,293 @@
+//! Connection quality per connected peer, for display: raw round trips and failure
+//! counts jump around too much to show. Each ping smooths the round-trip time and the
+//! ping loss rate with exponential moving averages, folds in the requests to the peer
+//! that failed since the previous ping, and turns them into a score from 0 to 100 with a
+//! [`QualityBucket`]. A bucket only changes once the score is clearly past a threshold,
+//! so a score hovering at one does not flap.
+//!
+//! Unlike [`PeerReputation`](super::PeerReputation), which outlives connections and
+//! decides whom to fetch from, quality is about the current connection and is forgotten
+//! when the last one closes.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+
+/// Scores at or above this are [`QualityBucket::Good`].
+pub const GOOD_SCORE: u8 = 70;
+
+/// Scores at or above this, and below [`GOOD_SCORE`], are [`QualityBucket::Fair`].
+pub const FAIR_SCORE: u8 = 40;
+
+/// How far past a threshold the score must be before the bucket changes.
+pub const BUCKET_HYSTERESIS: u8 = 5;
+
+/// Smoothing factors: the weight of each new sample, between 0 and 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QualityParams {
+    /// For the round-trip time; TCP's 1/8 by default.
+    pub rtt_alpha: f64,
+    /// For the ping loss rate.
+    pub loss_alpha: f64,
+    /// For the rate of pings with failed requests since the previous one.
+    pub failure_alpha: f64,
+    /// Round trips at or below this count as perfect.
+    pub best_rtt: Duration,
+    /// Round trips at or above this count as unusable.
+    pub worst_rtt: Duration,
+}
+
+impl Default for QualityParams {
+    fn default() -> Self {
+        Self {
+            rtt_alpha: 0.125,
+            loss_alpha: 0.2,
+            failure_alpha: 0.2,
+            best_rtt: Duration::from_millis(50),
+            worst_rtt: Duration::from_millis(1000),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QualityBucket {
+    Poor,
+    Fair,
+    /// Also where a peer starts before its first ping.
+    #[default]
+    Good,
+}
+
+impl QualityBucket {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            QualityBucket::Good => "good",
+            QualityBucket::Fair => "fair",
+            QualityBucket::Poor => "poor",
+        }
+    }
+
+    /// The bucket for `score`, given the bucket it is in now.
+    fn next(self, score: u8) -> Self {
+        let plain = |score: u8| match score {
+            s if s >= GOOD_SCORE => QualityBucket::Good,
+            s if s >= FAIR_SCORE => QualityBucket::Fair,
+            _ => QualityBucket::Poor,
+        };
+        // Better only once clearly above the threshold, worse once clearly below
+        let up = plain(score.saturating_sub(BUCKET_HYSTERESIS));
+        let down = plain(score.saturating_add(BUCKET_HYSTERESIS));
+        if up > self {
+            up
+        } else if down < self {
+            down
+        } else {
+            self
+        }
+    }
+}
+
+impl std::fmt::Display for QualityBucket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// One peer's smoothed connection quality.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct ConnectionQuality {
+    /// `None` until a ping was answered.
+    pub smoothed_rtt_ms: Option<f64>,
+    /// Between 0 and 1.
+    pub loss_rate: f64,
+    /// Between 0 and 1: how often requests failed between pings, lately.
+    pub failure_rate: f64,
+    /// 0 to 100.
+    pub score: u8,
+    pub bucket: QualityBucket,
+    /// Requests failed since the last ping.
+    #[serde(skip)]
+    pending_failures: u32,
+}
+
+impl Default for ConnectionQuality {
+    fn default() -> Self {
+        Self { smoothed_rtt_ms: None, loss_rate: 0.0, failure_rate: 0.0, score: 100, bucket: QualityBucket::Good, pending_failures: 0 }
+    }
+}
+
+impl ConnectionQuality {
+    /// Fold in a ping: its round trip, or `None` if it failed. Returns the previous bucket
+    /// if the bucket changed.
+    pub fn ping(&mut self, rtt: Option<Duration>, params: &QualityParams) -> Option<QualityBucket> {
+        let ewma = |average: f64, sample: f64, alpha: f64| average + alpha * (sample - average);
+        if let Some(rtt) = rtt {
+            let rtt_ms = rtt.as_secs_f64() * 1000.0;
+            // The first answer is the best estimate there is
+            self.smoothed_rtt_ms = Some(self.smoothed_rtt_ms.map_or(rtt_ms, |average| ewma(average, rtt_ms, params.rtt_alpha)));
+        }
+        self.loss_rate = ewma(self.loss_rate, if rtt.is_some() { 0.0 } else { 1.0 }, params.loss_alpha);
+        let failed = if self.pending_failures > 0 { 1.0 } else { 0.0 };
+        self.failure_rate = ewma(self.failure_rate, failed, params.failure_alpha);
+        self.pending_failures = 0;
+
+        self.score = self.compute_score(params);
+        let bucket = self.bucket.next(self.score);
+        (bucket != self.bucket).then(|| std::mem::replace(&mut self.bucket, bucket))
+    }
+
+    /// Count a request to the peer that failed; it weighs in at the next ping.
+    pub fn send_failed(&mut self) {
+        self.pending_failures = self.pending_failures.saturating_add(1);
+    }
+
+    /// Round trips weigh most, failed requests less; lost pings scale the lot down, since
+    /// a connection that drops half its pings is poor however fast the rest are.
+    fn compute_score(&self, params: &QualityParams) -> u8 {
+        let rtt = match self.smoothed_rtt_ms {
+            Some(rtt_ms) => {
+                let (best, worst) = (params.best_rtt.as_secs_f64() * 1000.0, params.worst_rtt.as_secs_f64() * 1000.0);
+                (1.0 - (rtt_ms - best) / (worst - best).max(1.0)).clamp(0.0, 1.0)
+            }
+            None => 0.0,
+        };
+        let delivery = 1.0 - self.failure_rate;
+        let received = (1.0 - self.loss_rate).powi(2);
+        (100.0 * (0.7 * rtt + 0.3 * delivery) * received).round().clamp(0.0, 100.0) as u8
+    }
+}
+
+/// A bucket change reported by [`QualityTracker::ping`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QualityChange {
+    pub peer: PeerId,
+    pub from: QualityBucket,
+    pub to: QualityBucket,
+    pub score: u8,
+}
+
+/// Quality of every connected peer. Cheap to clone; clones share the table, so handles
+/// read it without asking the event loop.
+#[derive(Debug, Clone, Default)]
+pub struct QualityTracker {
+    params: QualityParams,
+    peers: Arc<Mutex<HashMap<PeerId, ConnectionQuality>>>,
+}
+
+impl QualityTracker {
+    pub fn new(params: QualityParams) -> Self {
+        Self { params, peers: Arc::default() }
+    }
+
+    /// A connection to `peer` is up; keeps what was measured if one already was.
+    pub fn connected(&self, peer: PeerId) {
+        self.lock().entry(peer).or_default();
+    }
+
+    /// The last connection to `peer` closed.
+    pub fn disconnected(&self, peer: &PeerId) {
+        self.lock().remove(peer);
+    }
+
+    /// Fold in a ping to a connected `peer`, see [`ConnectionQuality::ping`].
+    pub fn ping(&self, peer: PeerId, rtt: Option<Duration>) -> Option<QualityChange> {
+        let mut peers = self.lock();
+        let quality = peers.get_mut(&peer)?;
+        let from = quality.ping(rtt, &self.params)?;
+        Some(QualityChange { peer, from, to: quality.bucket, score: quality.score })
+    }
+
+    pub fn send_failed(&self, peer: &PeerId) {
+        if let Some(quality) = self.lock().get_mut(peer) {
+            quality.send_failed();
+        }
+    }
+
+    pub fn get(&self, peer: &PeerId) -> Option<ConnectionQuality> {
+        self.lock().get(peer).copied()
+    }
+
+    pub fn all(&self) -> Vec<(PeerId, ConnectionQuality)> {
+        self.lock().iter().map(|(peer, quality)| (*peer, *quality)).collect()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<PeerId, ConnectionQuality>> {
+        self.peers.lock().expect("quality lock")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Option<Duration> {
+        Some(Duration::from_millis(ms))
+    }
+
+    #[test]
+    fn smooths_pings_into_a_score() {
+        let params = QualityParams::default();
+        let mut quality = ConnectionQuality::default();
+        assert_eq!(quality.ping(ms(40), &params), None);
+        assert_eq!((quality.smoothed_rtt_ms, quality.score, quality.bucket), (Some(40.0), 100, QualityBucket::Good));
+
+        // One slow answer moves the average by an eighth of the difference
+        quality.ping(ms(840), &params);
+        assert_eq!(quality.smoothed_rtt_ms, Some(140.0));
+        assert_eq!(quality.bucket, QualityBucket::Good);
+
+        // A lost ping and a failed request weigh in, but don't sink a fast peer at once
+        quality.send_failed();
+        quality.ping(None, &params);
+        assert!((quality.loss_rate - 0.2).abs() < 1e-9);
+        assert!((quality.failure_rate - 0.2).abs() < 1e-9);
+        assert_eq!(quality.bucket, QualityBucket::Fair);
+        assert_eq!(quality.smoothed_rtt_ms, Some(140.0));
+    }
+
+    #[test]
+    fn buckets_change_only_past_the_hysteresis() {
+        let params = QualityParams::default();
+        let tracker = QualityTracker::new(params);
+        let peer = PeerId::random();
+        // Not connected: nothing tracked
+        assert_eq!(tracker.ping(peer, ms(10)), None);
+        tracker.connected(peer);
+        assert_eq!(tracker.ping(peer, ms(50)), None);
+
+        let mut changes = Vec::new();
+        for _ in 0..20 {
+            changes.extend(tracker.ping(peer, None));
+        }
+        assert_eq!(
+            changes.iter().map(|change| (change.from, change.to)).collect::<Vec<_>>(),
+            vec![(QualityBucket::Good, QualityBucket::Fair), (QualityBucket::Fair, QualityBucket::Poor)]
+        );
+        assert_eq!(tracker.get(&peer).unwrap().score, 0);
+
+        // Recovering: no event until clearly back above each threshold
+        let mut changes = Vec::new();
+        for _ in 0..40 {
+            changes.extend(tracker.ping(peer, ms(50)));
+        }
+        assert_eq!(changes.len(), 2);
+        assert!(changes.iter().all(|change| change.score >= FAIR_SCORE + BUCKET_HYSTERESIS));
+        assert_eq!(changes[1].to, QualityBucket::Good);
+
+        // A score sitting just under the threshold stays put
+        let mut bucket = QualityBucket::Good;
+        for score in [68, 72, 67, 69] {
+            bucket = bucket.next(score);
+            assert_eq!(bucket, QualityBucket::Good);
+        }
+        assert_eq!(bucket.next(64), QualityBucket::Fair);
+        assert_eq!(QualityBucket::Fair.next(74), QualityBucket::Fair);
+
+        tracker.disconnected(&peer);
+        assert_eq!(tracker.get(&peer), None);
+    }
+}
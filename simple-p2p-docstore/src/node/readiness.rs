@@ -0,0 +1,81 @@
+//! When a node is usable: connected to someone, meshed on the docstore topic, and done
+//! with its first Kademlia bootstrap. Applications wait for this before letting users
+//! edit; the same snapshot is kept around for diagnostics.
+
+/// Progress of the initial Kademlia bootstrap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DhtBootstrap {
+    /// No bootstrap peers were configured (or the DHT is off), so there is nothing to wait for.
+    #[default]
+    NotConfigured,
+    Pending,
+    Done,
+    /// The query could not start or ended in an error. Conclusive, so it doesn't block
+    /// readiness.
+    Failed,
+}
+
+impl DhtBootstrap {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DhtBootstrap::NotConfigured => "not_configured",
+            DhtBootstrap::Pending => "pending",
+            DhtBootstrap::Done => "done",
+            DhtBootstrap::Failed => "failed",
+        }
+    }
+
+    /// Whether the bootstrap has finished one way or another.
+    pub fn is_settled(self) -> bool {
+        !matches!(self, DhtBootstrap::Pending)
+    }
+}
+
+/// The readiness conditions as they stand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct NodeReadiness {
+    /// At least one connection is established.
+    pub connected: bool,
+    /// The docstore topic has at least one mesh peer.
+    pub mesh: bool,
+    pub dht_bootstrap: DhtBootstrap,
+}
+
+impl NodeReadiness {
+    pub fn is_ready(&self) -> bool {
+        self.waiting_for().is_empty()
+    }
+
+    /// The conditions not met yet: `"connection"`, `"mesh"` and `"dht_bootstrap"`.
+    pub fn waiting_for(&self) -> Vec<&'static str> {
+        let mut missing = Vec::new();
+        if !self.connected {
+            missing.push("connection");
+        }
+        if !self.mesh {
+            missing.push("mesh");
+        }
+        if !self.dht_bootstrap.is_settled() {
+            missing.push("dht_bootstrap");
+        }
+        missing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ready_once_every_condition_holds() {
+        let mut readiness = NodeReadiness { dht_bootstrap: DhtBootstrap::Pending, ..Default::default() };
+        assert_eq!(readiness.waiting_for(), ["connection", "mesh", "dht_bootstrap"]);
+        readiness.connected = true;
+        readiness.mesh = true;
+        assert_eq!(readiness.waiting_for(), ["dht_bootstrap"]);
+        // A failed bootstrap is conclusive
+        readiness.dht_bootstrap = DhtBootstrap::Failed;
+        assert!(readiness.is_ready());
+        assert!(NodeReadiness { connected: true, mesh: true, ..Default::default() }.is_ready());
+    }
+}
@@ -0,0 +1,170 @@
+//! The receipts a publisher waits for, shared by the native and wasm event loops.
+//!
+//! Publishing an update with [`PublishOptions::ack_requested`] starts an entry that
+//! collects the receipts of every peer that applied it, see
+//! [`crate::behaviour::docstore::receipt`]. Each peer counts once, however many copies
+//! of its receipt arrive. At the deadline the entry goes away, reported as
+//! unacknowledged if nobody answered; receipts arriving later are dropped.
+
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use libp2p::gossipsub::MessageId;
+use libp2p::PeerId;
+use web_time::Instant;
+
+use crate::behaviour::docstore::receipt::ReceiptOutcome;
+use crate::behaviour::docstore::scope::MessageScope;
+use crate::behaviour::docstore::UpdateReceipt;
+
+/// How long a publisher waits for receipts.
+pub const DEFAULT_ACK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How to publish a document update.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PublishOptions {
+    /// Ask every peer that applies the update for a receipt. The update is sent on its
+    /// own, never batched with others.
+    pub ack_requested: bool,
+    /// Publish as a CAS update, applied only where the document is at this version, see
+    /// [`crate::behaviour::docstore::cas`]. Implies `ack_requested`.
+    pub expected_version: Option<u64>,
+    /// How far the update travels. A local one stops at the first relay that gets it from
+    /// another relay, see [`crate::behaviour::docstore::scope`].
+    pub scope: MessageScope,
+}
+
+impl PublishOptions {
+    /// Whether receipts are asked for, explicitly or by a CAS update.
+    pub fn wants_receipts(&self) -> bool {
+        self.ack_requested || self.expected_version.is_some()
+    }
+}
+
+/// A receipt for one of our updates, from a peer that had not answered it yet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ack {
+    pub msg_id: MessageId,
+    pub peer_id: PeerId,
+    pub doc_id: String,
+    /// The version the update brought the document to, or the one it is at for a
+    /// conflict.
+    pub applied_version: u64,
+    pub outcome: ReceiptOutcome,
+}
+
+/// An update nobody acknowledged in time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unacked {
+    pub msg_id: MessageId,
+    pub doc_id: String,
+}
+
+#[derive(Debug)]
+struct Outstanding {
+    doc_id: String,
+    deadline: Instant,
+    acked_by: HashSet<PeerId>,
+}
+
+/// Our updates waiting for receipts, see the [module docs](self).
+#[derive(Debug)]
+pub struct AckTracker {
+    timeout: Duration,
+    outstanding: HashMap<MessageId, Outstanding>,
+}
+
+impl Default for AckTracker {
+    fn default() -> Self {
+        Self::new(DEFAULT_ACK_TIMEOUT)
+    }
+}
+
+impl AckTracker {
+    pub fn new(timeout: Duration) -> Self {
+        Self { timeout, outstanding: HashMap::new() }
+    }
+
+    /// We published `doc_id` as `msg_id`, asking for receipts.
+    pub fn expect(&mut self, msg_id: MessageId, doc_id: impl Into<String>, now: Instant) {
+        let entry = Outstanding { doc_id: doc_id.into(), deadline: now + self.timeout, acked_by: HashSet::new() };
+        self.outstanding.insert(msg_id, entry);
+    }
+
+    /// A verified receipt signed by `peer_id`. `Some` the first time `peer_id`
+    /// acknowledges an update we still wait on; `None` for duplicates and for receipts of
+    /// updates we never published, or gave up on.
+    pub fn received(&mut self, receipt: &UpdateReceipt, peer_id: PeerId) -> Option<Ack> {
+        let msg_id = receipt.message_id();
+        let entry = self.outstanding.get_mut(&msg_id).filter(|e| e.doc_id == receipt.doc_id)?;
+        if !entry.acked_by.insert(peer_id) {
+            return None;
+        }
+        Some(Ack {
+            msg_id,
+            peer_id,
+            doc_id: receipt.doc_id.clone(),
+            applied_version: receipt.applied_version,
+            outcome: receipt.outcome,
+        })
+    }
+
+    /// Stop waiting on the updates whose deadline passed. Returns those nobody
+    /// acknowledged.
+    pub fn expire(&mut self, now: Instant) -> Vec<Unacked> {
+        let mut unacked = Vec::new();
+        self.outstanding.retain(|msg_id, entry| {
+            if entry.deadline > now {
+                return true;
+            }
+            if entry.acked_by.is_empty() {
+                unacked.push(Unacked { msg_id: msg_id.clone(), doc_id: entry.doc_id.clone() });
+            }
+            false
+        });
+        unacked
+    }
+
+    /// When the next update runs out of time, if any is waiting.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.outstanding.values().map(|e| e.deadline).min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::identity::Keypair;
+
+    #[test]
+    fn receipts_count_once_per_peer_until_the_deadline() {
+        let publisher = PeerId::random();
+        let (alice, bob) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let (msg_id, quiet) = (MessageId::new(b"m1"), MessageId::new(b"m2"));
+        let mut acks = AckTracker::new(Duration::from_secs(10));
+        let now = Instant::now();
+        assert_eq!(acks.next_deadline(), None);
+        acks.expect(msg_id.clone(), "notes", now);
+        acks.expect(quiet.clone(), "todo", now + Duration::from_secs(1));
+        assert_eq!(acks.next_deadline(), Some(now + Duration::from_secs(10)));
+
+        let receipt = |key: &Keypair, msg_id: &MessageId, doc_id: &str| {
+            UpdateReceipt::sign(key, &publisher, msg_id, doc_id, 3).unwrap()
+        };
+        let alice_id = alice.public().to_peer_id();
+        let ack = acks.received(&receipt(&alice, &msg_id, "notes"), alice_id).unwrap();
+        assert_eq!((ack.peer_id, ack.doc_id.as_str(), ack.applied_version), (alice_id, "notes", 3));
+        // Directly and again over the topic
+        assert_eq!(acks.received(&receipt(&alice, &msg_id, "notes"), alice_id), None);
+        assert_eq!(acks.received(&receipt(&bob, &msg_id, "other"), bob.public().to_peer_id()), None);
+        assert!(acks.received(&receipt(&bob, &msg_id, "notes"), bob.public().to_peer_id()).is_some());
+        assert_eq!(acks.received(&receipt(&bob, &MessageId::new(b"never"), "notes"), alice_id), None);
+
+        assert!(acks.expire(now + Duration::from_secs(9)).is_empty());
+        // The acknowledged update just goes away
+        assert!(acks.expire(now + Duration::from_secs(10)).is_empty());
+        assert_eq!(acks.expire(now + Duration::from_secs(11)), [Unacked { msg_id: quiet.clone(), doc_id: "todo".into() }]);
+        assert_eq!(acks.received(&receipt(&bob, &quiet, "todo"), bob.public().to_peer_id()), None, "too late");
+        assert_eq!(acks.next_deadline(), None);
+    }
+}
@@ -0,0 +1,457 @@
+//! Session recordings, for turning "the doc got corrupted yesterday" into something to
+//! replay.
+//!
+//! Recording is off unless started explicitly, with [`RecorderOptions`]. While it runs,
+//! every gossipsub message the node receives is kept with the time it arrived and who
+//! delivered it, and with [`RecorderOptions::include_outbound`] every message it
+//! publishes too. The recorder is shared like [`TrafficStats`](super::TrafficStats),
+//! which feeds it as it counts, so both event loops record the same way. Once the
+//! recording holds [`RecorderOptions::max_bytes`] of messages it stops taking more and is
+//! marked truncated.
+//!
+//! A stopped [`Recording`] encodes to a compact binary form, to save as a file or, in
+//! browsers, wherever the application keeps blobs. [`Recording::redact`] zeroes every
+//! payload while keeping the envelopes, document ids, stamps and sizes, so a user can
+//! share a recording without sharing their documents.
+//!
+//! [`replay`] feeds a recording through a fresh [`MessagePipeline`] into any
+//! [`UpdateSink`]: received messages as the pipeline saw them, our own publishes applied
+//! directly, as the node applied them. `testing::replay_session` does that from a file
+//! into a memory store, so a recording becomes a regression test.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use libp2p::gossipsub::{self, TopicHash};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+
+use crate::behaviour::docstore::envelope::FLAG_COMPRESSED;
+use crate::behaviour::docstore::{
+    wire, CodecOptions, DocstoreGossipsubConfig, Envelope, Incoming, MessagePipeline, SnapshotChunk, TopicRegistry,
+    UpdateSink,
+};
+
+/// Default cap on the messages a recording holds, in bytes of message data.
+pub const DEFAULT_MAX_RECORDING_BYTES: usize = 16 * 1024 * 1024;
+
+/// Starts every encoded recording.
+const MAGIC: &[u8; 4] = b"dsrc";
+
+const FORMAT_VERSION: u8 = 1;
+
+#[derive(Debug, thiserror::Error)]
+pub enum RecordingError {
+    #[error("not a session recording")]
+    NotARecording,
+    #[error("recording format {0} is not supported (expected {FORMAT_VERSION})")]
+    UnsupportedFormat(u8),
+    #[error("malformed recording: {0}")]
+    Malformed(#[from] postcard::Error),
+    #[error("malformed peer id in recording")]
+    BadPeerId,
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// How to record, see the [module docs](self).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecorderOptions {
+    /// Record the messages we publish as well as those we receive.
+    pub include_outbound: bool,
+    /// Stop taking messages once their data adds up to this many bytes.
+    pub max_bytes: usize,
+}
+
+impl Default for RecorderOptions {
+    fn default() -> Self {
+        Self { include_outbound: false, max_bytes: DEFAULT_MAX_RECORDING_BYTES }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// One gossipsub message, as it went in or out.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedMessage {
+    /// Unix milliseconds.
+    pub at_ms: u64,
+    pub direction: Direction,
+    /// The peer that delivered an inbound message; empty for outbound ones.
+    #[serde(deserialize_with = "wire::bytes")]
+    pub propagation_source: Vec<u8>,
+    /// The signed author, if any.
+    pub source: Option<Vec<u8>>,
+    pub topic: String,
+    #[serde(deserialize_with = "wire::bytes")]
+    pub data: Vec<u8>,
+}
+
+impl RecordedMessage {
+    /// The message as gossipsub handed it over, and who delivered it.
+    pub fn to_gossipsub(&self) -> Result<(PeerId, gossipsub::Message), RecordingError> {
+        let peer = |bytes: &[u8]| PeerId::from_bytes(bytes).map_err(|_| RecordingError::BadPeerId);
+        let source = self.source.as_deref().map(peer).transpose()?;
+        let propagation_source = match self.direction {
+            Direction::Inbound => peer(&self.propagation_source)?,
+            Direction::Outbound => source.ok_or(RecordingError::BadPeerId)?,
+        };
+        let message = gossipsub::Message {
+            source,
+            data: self.data.clone(),
+            sequence_number: None,
+            topic: TopicHash::from_raw(&self.topic),
+        };
+        Ok((propagation_source, message))
+    }
+}
+
+/// A recorded session, see the [module docs](self).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Recording {
+    /// Peer id of the recording node.
+    #[serde(deserialize_with = "wire::bytes")]
+    pub local_peer: Vec<u8>,
+    /// Topic namespace and version of the recording node, which replays route by.
+    pub namespace: String,
+    pub topic_version: u32,
+    pub started_ms: u64,
+    pub stopped_ms: u64,
+    pub messages: Vec<RecordedMessage>,
+    /// Messages stopped being recorded at the size cap.
+    pub truncated: bool,
+    pub redacted: bool,
+}
+
+impl Recording {
+    pub fn local_peer_id(&self) -> Result<PeerId, RecordingError> {
+        PeerId::from_bytes(&self.local_peer).map_err(|_| RecordingError::BadPeerId)
+    }
+
+    pub fn topics(&self) -> TopicRegistry {
+        TopicRegistry::new(self.namespace.clone()).with_version(self.topic_version)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.push(FORMAT_VERSION);
+        out.extend(postcard::to_allocvec(self).expect("recording serialization cannot fail"));
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, RecordingError> {
+        let rest = data.strip_prefix(MAGIC.as_slice()).ok_or(RecordingError::NotARecording)?;
+        match rest.split_first() {
+            Some((&FORMAT_VERSION, body)) => Ok(postcard::from_bytes(body)?),
+            Some((&version, _)) => Err(RecordingError::UnsupportedFormat(version)),
+            None => Err(RecordingError::NotARecording),
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn save(&self, path: &std::path::Path) -> std::io::Result<()> {
+        std::fs::write(path, self.encode())
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn load(path: &std::path::Path) -> Result<Self, RecordingError> {
+        Self::decode(&std::fs::read(path)?)
+    }
+
+    /// A copy with every payload zeroed: update payloads inside their envelopes, snapshot
+    /// chunks (whose content hash goes too, so they no longer verify and replays skip
+    /// them) and plain data. Announcements and receipts carry no document content and
+    /// stay as they are, signatures intact.
+    pub fn redact(&self) -> Recording {
+        let topics = self.topics();
+        let keep = [topics.announce().hash(), topics.receipts().hash()];
+        let snapshots = topics.snapshots().hash();
+        let messages = self
+            .messages
+            .iter()
+            .map(|m| {
+                let topic = TopicHash::from_raw(&m.topic);
+                let data = if keep.contains(&topic) {
+                    m.data.clone()
+                } else if topic == snapshots {
+                    redact_chunk(&m.data)
+                } else {
+                    redact_envelope(&m.data)
+                };
+                RecordedMessage { data, ..m.clone() }
+            })
+            .collect();
+        Recording { messages, redacted: true, ..self.clone() }
+    }
+}
+
+fn zeroed(data: &[u8]) -> Vec<u8> {
+    vec![0; data.len()]
+}
+
+/// Keeps the envelope version and flags, so the replay takes the same paths.
+fn redact_envelope(data: &[u8]) -> Vec<u8> {
+    let Ok(envelope) = Envelope::decode(data) else {
+        return zeroed(data);
+    };
+    let envelope = match envelope {
+        Envelope::Update(mut update) => {
+            update.payload = zeroed(&update.payload);
+            Envelope::Update(update)
+        }
+        Envelope::Batch { doc_id, payloads, stamps } => {
+            Envelope::Batch { doc_id, payloads: payloads.iter().map(|p| zeroed(p)).collect(), stamps }
+        }
+        Envelope::Transaction(mut part) => {
+            for update in &mut part.updates {
+                update.payload = zeroed(&update.payload);
+            }
+            Envelope::Transaction(part)
+        }
+        Envelope::Cas { mut update, expected_version } => {
+            update.payload = zeroed(&update.payload);
+            Envelope::Cas { update, expected_version }
+        }
+    };
+    let mut out = envelope.encode_flagged(&CodecOptions::default(), data[1] & !FLAG_COMPRESSED);
+    out[0] = data[0];
+    out
+}
+
+fn redact_chunk(data: &[u8]) -> Vec<u8> {
+    match SnapshotChunk::decode(data) {
+        Ok(chunk) => SnapshotChunk { content_hash: [0; 32], data: zeroed(&chunk.data), ..chunk }.encode(),
+        Err(_) => zeroed(data),
+    }
+}
+
+/// How far a running recording got.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct RecordingStatus {
+    pub messages: usize,
+    pub bytes: usize,
+    pub truncated: bool,
+}
+
+#[derive(Debug)]
+struct Active {
+    options: RecorderOptions,
+    recording: Recording,
+    bytes: usize,
+}
+
+impl Active {
+    fn push(&mut self, message: RecordedMessage) {
+        if self.recording.truncated {
+            return;
+        }
+        if self.bytes + message.data.len() > self.options.max_bytes {
+            tracing::warn!("Session recording reached {} bytes; recording no further messages", self.options.max_bytes);
+            self.recording.truncated = true;
+            return;
+        }
+        self.bytes += message.data.len();
+        self.recording.messages.push(message);
+    }
+}
+
+/// Records the session while started. Cheap to clone; clones share the recording.
+#[derive(Debug, Clone, Default)]
+pub struct SessionRecorder {
+    active: Arc<Mutex<Option<Active>>>,
+}
+
+impl SessionRecorder {
+    /// Start recording as `local_peer`, whose topics are `topics`. Returns false, changing
+    /// nothing, if a recording is already running.
+    pub fn start(&self, options: RecorderOptions, local_peer: &PeerId, topics: &TopicRegistry, now_ms: u64) -> bool {
+        let mut active = self.lock();
+        if active.is_some() {
+            return false;
+        }
+        let recording = Recording {
+            local_peer: local_peer.to_bytes(),
+            namespace: topics.namespace().to_string(),
+            topic_version: topics.version(),
+            started_ms: now_ms,
+            stopped_ms: now_ms,
+            messages: Vec::new(),
+            truncated: false,
+            redacted: false,
+        };
+        *active = Some(Active { options, recording, bytes: 0 });
+        true
+    }
+
+    /// Stop recording and hand over what was recorded, if a recording was running.
+    pub fn stop(&self, now_ms: u64) -> Option<Recording> {
+        let mut recording = self.lock().take()?.recording;
+        recording.stopped_ms = now_ms;
+        Some(recording)
+    }
+
+    pub fn status(&self) -> Option<RecordingStatus> {
+        self.lock().as_ref().map(|active| RecordingStatus {
+            messages: active.recording.messages.len(),
+            bytes: active.bytes,
+            truncated: active.recording.truncated,
+        })
+    }
+
+    /// Whether a running recording wants the messages we publish.
+    pub fn wants_outbound(&self) -> bool {
+        self.lock().as_ref().is_some_and(|active| active.options.include_outbound)
+    }
+
+    /// A message `propagation_source` delivered.
+    pub fn inbound(&self, message: &gossipsub::Message, propagation_source: &PeerId, now_ms: u64) {
+        if let Some(active) = self.lock().as_mut() {
+            active.push(RecordedMessage {
+                at_ms: now_ms,
+                direction: Direction::Inbound,
+                propagation_source: propagation_source.to_bytes(),
+                source: message.source.map(|source| source.to_bytes()),
+                topic: message.topic.to_string(),
+                data: message.data.clone(),
+            });
+        }
+    }
+
+    /// A message we published on `topic`.
+    pub fn outbound(&self, topic: &TopicHash, data: Vec<u8>, now_ms: u64) {
+        if let Some(active) = self.lock().as_mut().filter(|active| active.options.include_outbound) {
+            let local_peer = active.recording.local_peer.clone();
+            active.push(RecordedMessage {
+                at_ms: now_ms,
+                direction: Direction::Outbound,
+                propagation_source: Vec::new(),
+                source: Some(local_peer),
+                topic: topic.to_string(),
+                data,
+            });
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Option<Active>> {
+        self.active.lock().expect("recorder lock")
+    }
+}
+
+/// Feed `recording` into `sink` through a fresh pipeline built from `config`, with the
+/// recording's topics. Received messages go through the pipeline; our own publishes on
+/// the updates topic are applied directly, as the node applied them. Returns the
+/// pipeline's outputs per message, in order.
+pub fn replay<S: UpdateSink + ?Sized>(
+    recording: &Recording,
+    config: DocstoreGossipsubConfig,
+    sink: &mut S,
+) -> Result<Vec<Vec<Incoming>>, RecordingError> {
+    let config = DocstoreGossipsubConfig { topics: recording.topics(), ..config };
+    let updates = config.topics.updates().hash();
+    let mut pipeline = MessagePipeline::new(config, &recording.local_peer_id()?);
+    let mut transactions: HashMap<u64, Vec<crate::behaviour::docstore::DocUpdate>> = HashMap::new();
+    let mut outputs = Vec::with_capacity(recording.messages.len());
+    for recorded in &recording.messages {
+        let (propagation_source, message) = recorded.to_gossipsub()?;
+        match recorded.direction {
+            Direction::Inbound => outputs.push(pipeline.handle_incoming(sink, propagation_source, &message)),
+            Direction::Outbound if message.topic == updates => {
+                match Envelope::decode(&message.data) {
+                    // Local transactions were applied whole, once every part was out
+                    Ok(Envelope::Transaction(part)) => {
+                        let (id, last) = (part.id, part.index + 1 == part.total);
+                        transactions.entry(id).or_default().extend(part.updates);
+                        if last {
+                            sink.apply_transaction(&transactions.remove(&id).unwrap_or_default());
+                        }
+                    }
+                    Ok(envelope) => {
+                        for update in envelope.into_updates() {
+                            sink.apply_update(&update);
+                        }
+                    }
+                    Err(_) => {}
+                }
+                outputs.push(Vec::new());
+            }
+            Direction::Outbound => outputs.push(Vec::new()),
+        }
+    }
+    Ok(outputs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::behaviour::docstore::{encode_doc_update, DocUpdate};
+    use crate::store::{DocStore, MemoryDocStore};
+
+    const NOW: u64 = 1_700_000_000_000;
+
+    fn record_session(options: RecorderOptions) -> (SessionRecorder, TopicRegistry) {
+        let (local, remote) = (PeerId::random(), PeerId::random());
+        let cfg = DocstoreGossipsubConfig::default();
+        let topics = TopicRegistry::new("app");
+        let recorder = SessionRecorder::default();
+        let update = |payload: &str| encode_doc_update(&cfg, DocUpdate::new("notes", payload.as_bytes().to_vec())).unwrap();
+        let received = |data: Vec<u8>| gossipsub::Message { source: Some(remote), data, sequence_number: None, topic: topics.updates().hash() };
+        // Nothing is recorded before starting
+        recorder.inbound(&received(update("lost")), &remote, NOW);
+        assert!(recorder.start(options, &local, &topics, NOW));
+        assert!(!recorder.start(options, &local, &topics, NOW));
+
+        recorder.inbound(&received(update("hello")), &remote, NOW + 1);
+        recorder.outbound(&topics.updates().hash(), update("mine"), NOW + 2);
+        recorder.inbound(&received(update("world")), &remote, NOW + 3);
+        (recorder, topics)
+    }
+
+    #[test]
+    fn recordings_replay_into_a_fresh_store() {
+        let options = RecorderOptions { include_outbound: true, ..Default::default() };
+        let (recorder, topics) = record_session(options);
+        assert_eq!(recorder.status().map(|status| (status.messages, status.truncated)), Some((3, false)));
+        let recording = Recording::decode(&recorder.stop(NOW + 4).unwrap().encode()).unwrap();
+        assert_eq!(recorder.stop(NOW + 5), None);
+        assert_eq!((recording.started_ms, recording.stopped_ms, recording.messages.len()), (NOW, NOW + 4, 3));
+        assert_eq!(recording.topics(), topics);
+
+        let mut store = MemoryDocStore::default();
+        let outputs = replay(&recording, DocstoreGossipsubConfig::default(), &mut store).unwrap();
+        assert_eq!(outputs.len(), 3);
+        assert_eq!(store.version("notes"), 3);
+        assert_eq!(store.content("notes"), Some(b"world".to_vec()));
+
+        assert!(matches!(Recording::decode(b"junk"), Err(RecordingError::NotARecording)));
+    }
+
+    #[test]
+    fn redacted_recordings_keep_their_shape() {
+        let (recorder, _) = record_session(RecorderOptions::default());
+        let recording = recorder.stop(NOW + 4).unwrap();
+        // Outbound messages were not asked for
+        assert_eq!(recording.messages.len(), 2);
+
+        let redacted = recording.redact();
+        assert!(redacted.redacted);
+        let mut store = MemoryDocStore::default();
+        replay(&redacted, DocstoreGossipsubConfig::default(), &mut store).unwrap();
+        assert_eq!(store.version("notes"), 2);
+        assert_eq!(store.content("notes"), Some(vec![0; 5]));
+        let envelope = Envelope::decode(&redacted.messages[0].data).unwrap();
+        assert_eq!(envelope.into_updates()[0].doc_id, "notes");
+    }
+
+    #[test]
+    fn recordings_stop_growing_at_the_cap() {
+        let (recorder, _) = record_session(RecorderOptions { include_outbound: false, max_bytes: 20 });
+        let status = recorder.status().unwrap();
+        assert!(status.truncated);
+        assert_eq!(status.messages, 1);
+        assert!(recorder.stop(NOW).unwrap().truncated);
+    }
+}
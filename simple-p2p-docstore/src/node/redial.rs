@@ -0,0 +1,217 @@
+//! Peers worth staying connected to, and when to redial them after a disconnect.
+//!
+//! Redials back off exponentially per peer and stop once the peer is unmarked or
+//! banned, or the connection is back.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use libp2p::{Multiaddr, PeerId};
+use web_time::Instant;
+
+/// Delay before the first redial.
+pub const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Upper bound on the delay between redials; redials continue at this pace.
+pub const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Default)]
+struct ImportantPeer {
+    /// Addresses we reached the peer at, or were given for it.
+    addrs: Vec<Multiaddr>,
+    /// Failed redials since the connection was lost.
+    failures: u32,
+    /// Set while the peer is disconnected: when to dial next. `None` while a dial is in
+    /// flight or the peer is connected.
+    next_dial: Option<Instant>,
+    /// Disconnected since the last connection, so reconnecting counts as a recovery.
+    lost: bool,
+    /// Failed dials are retried until the peer connects.
+    retrying: bool,
+}
+
+/// See the module docs.
+#[derive(Debug, Default)]
+pub struct ImportantPeers {
+    peers: HashMap<PeerId, ImportantPeer>,
+}
+
+impl ImportantPeers {
+    /// Keep `peer_id` connected from now on. Marking again only adds `addr`.
+    pub fn mark(&mut self, peer_id: PeerId, addr: Option<Multiaddr>) {
+        let peer = self.peers.entry(peer_id).or_default();
+        if let Some(addr) = addr {
+            if !peer.addrs.contains(&addr) {
+                peer.addrs.push(addr);
+            }
+        }
+    }
+
+    /// Stop redialing `peer_id`. Returns false if it was not marked.
+    pub fn unmark(&mut self, peer_id: &PeerId) -> bool {
+        self.peers.remove(peer_id).is_some()
+    }
+
+    pub fn is_important(&self, peer_id: &PeerId) -> bool {
+        self.peers.contains_key(peer_id)
+    }
+
+    pub fn addrs(&self, peer_id: &PeerId) -> &[Multiaddr] {
+        self.peers.get(peer_id).map_or(&[], |p| &p.addrs)
+    }
+
+    /// The last connection to `peer_id` closed. Returns true if a redial was scheduled.
+    pub fn connection_lost(&mut self, peer_id: &PeerId, now: Instant) -> bool {
+        let Some(peer) = self.peers.get_mut(peer_id) else {
+            return false;
+        };
+        peer.lost = true;
+        peer.retrying = true;
+        peer.failures = 0;
+        peer.next_dial = Some(now + INITIAL_BACKOFF);
+        true
+    }
+
+    /// Dial `peer_id` as soon as [`take_due`](Self::take_due) is asked, and keep
+    /// redialing with backoff until it connects, as after losing it. For peers we were
+    /// never connected to, so the first connection is no recovery.
+    pub fn dial_now(&mut self, peer_id: &PeerId, now: Instant) {
+        if let Some(peer) = self.peers.get_mut(peer_id) {
+            peer.retrying = true;
+            peer.failures = 0;
+            peer.next_dial = Some(now);
+        }
+    }
+
+    /// A redial of `peer_id` failed; the next one waits twice as long.
+    pub fn dial_failed(&mut self, peer_id: &PeerId, now: Instant) {
+        if let Some(peer) = self.peers.get_mut(peer_id).filter(|p| p.retrying) {
+            peer.failures = peer.failures.saturating_add(1);
+            peer.next_dial = Some(now + backoff(peer.failures));
+        }
+    }
+
+    /// A connection to `peer_id` is up. Returns true if it had been lost, i.e. the peer
+    /// recovered. `addr` is remembered for later redials if we dialed it.
+    pub fn connected(&mut self, peer_id: &PeerId, addr: Option<&Multiaddr>) -> bool {
+        let Some(peer) = self.peers.get_mut(peer_id) else {
+            return false;
+        };
+        if let Some(addr) = addr {
+            if !peer.addrs.contains(addr) {
+                peer.addrs.push(addr.clone());
+            }
+        }
+        peer.next_dial = None;
+        peer.failures = 0;
+        peer.retrying = false;
+        std::mem::take(&mut peer.lost)
+    }
+
+    /// `peer_id` was banned: no more redials until it connects again.
+    pub fn stop(&mut self, peer_id: &PeerId) {
+        if let Some(peer) = self.peers.get_mut(peer_id) {
+            peer.next_dial = None;
+            peer.lost = false;
+            peer.retrying = false;
+        }
+    }
+
+    /// Peers whose redial is due. Each is returned once; report the outcome through
+    /// [`connected`](Self::connected) or [`dial_failed`](Self::dial_failed).
+    pub fn take_due(&mut self, now: Instant) -> Vec<PeerId> {
+        let mut due = Vec::new();
+        for (peer_id, peer) in &mut self.peers {
+            if peer.next_dial.is_some_and(|at| at <= now) {
+                peer.next_dial = None;
+                due.push(*peer_id);
+            }
+        }
+        due
+    }
+
+    /// When the next redial is due, if any is scheduled.
+    pub fn next_due(&self) -> Option<Instant> {
+        self.peers.values().filter_map(|p| p.next_dial).min()
+    }
+}
+
+fn backoff(failures: u32) -> Duration {
+    INITIAL_BACKOFF.saturating_mul(1 << failures.min(16)).min(MAX_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redials_lost_peers_with_backoff_until_they_recover() {
+        let mut peers = ImportantPeers::default();
+        let (important, other) = (PeerId::random(), PeerId::random());
+        peers.mark(important, Some("/ip4/10.0.0.1/tcp/4001".parse().unwrap()));
+        let now = Instant::now();
+
+        assert!(!peers.connected(&important, None));
+        assert!(!peers.connection_lost(&other, now));
+        assert!(peers.connection_lost(&important, now));
+        assert!(peers.take_due(now).is_empty());
+        assert_eq!(peers.next_due(), Some(now + INITIAL_BACKOFF));
+
+        let mut at = now + INITIAL_BACKOFF;
+        assert_eq!(peers.take_due(at), vec![important]);
+        // In flight: not handed out twice
+        assert!(peers.take_due(at).is_empty());
+        let mut waits = Vec::new();
+        for _ in 0..8 {
+            peers.dial_failed(&important, at);
+            let next = peers.next_due().unwrap();
+            waits.push(next - at);
+            at = next;
+            assert_eq!(peers.take_due(at), vec![important]);
+        }
+        assert_eq!(waits[..3], [Duration::from_secs(2), Duration::from_secs(4), Duration::from_secs(8)]);
+        assert_eq!(*waits.last().unwrap(), MAX_BACKOFF);
+
+        assert!(peers.connected(&important, Some(&"/ip4/10.0.0.2/tcp/4001".parse().unwrap())));
+        assert_eq!(peers.addrs(&important).len(), 2);
+        assert!(!peers.connected(&important, None));
+        assert_eq!(peers.next_due(), None);
+    }
+
+    #[test]
+    fn peers_dialed_from_the_start_are_retried_until_they_connect() {
+        let mut peers = ImportantPeers::default();
+        let peer = PeerId::random();
+        peers.mark(peer, None);
+        let now = Instant::now();
+
+        peers.dial_now(&peer, now);
+        assert_eq!(peers.take_due(now), vec![peer]);
+        peers.dial_failed(&peer, now);
+        assert_eq!(peers.next_due(), Some(now + Duration::from_secs(2)));
+        // Never connected before, so nothing recovered
+        assert!(!peers.connected(&peer, None));
+        peers.dial_failed(&peer, now);
+        assert_eq!(peers.next_due(), None);
+    }
+
+    #[test]
+    fn banning_or_unmarking_stops_redials() {
+        let mut peers = ImportantPeers::default();
+        let peer = PeerId::random();
+        peers.mark(peer, None);
+        let now = Instant::now();
+
+        peers.connection_lost(&peer, now);
+        peers.stop(&peer);
+        assert_eq!(peers.next_due(), None);
+        // A failure reported after the ban doesn't reschedule
+        peers.dial_failed(&peer, now);
+        assert_eq!(peers.next_due(), None);
+
+        peers.connection_lost(&peer, now);
+        assert!(peers.unmark(&peer));
+        assert_eq!(peers.next_due(), None);
+        assert!(!peers.is_important(&peer));
+    }
+}
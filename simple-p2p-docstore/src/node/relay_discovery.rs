@@ -0,0 +1,147 @@
+//! Finding relays through the DHT, shared by the native and wasm event loops.
+//!
+//! Relays announce themselves as providers of [`relay_provider_key`]. A lookup dials a few
+//! of the providers it finds, and each joins the relay set only once identify shows it
+//! serves the Circuit Relay v2 hop protocol: a junk provider record costs one dial.
+//!
+//! [`relay_provider_key`]: crate::behaviour::relay_provider_key
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use libp2p::PeerId;
+
+use crate::node::PeerInfo;
+
+/// Providers dialed per lookup unless asked otherwise.
+pub const DEFAULT_RELAYS_TO_DIAL: usize = 3;
+
+/// How long a lookup may take before the caller gives up on it.
+pub const DEFAULT_RELAY_DISCOVERY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often relays re-announce themselves, matching Kademlia's provider republication.
+pub const RELAY_PROVIDER_REFRESH: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// What a relay must speak to be trusted: the Circuit Relay v2 hop protocol.
+pub const RELAY_HOP_PROTOCOL: &str = "/libp2p/circuit/relay/0.2.0/hop";
+
+/// One `discover_relays` lookup: the providers found so far and how many more to dial.
+#[derive(Debug)]
+pub struct RelayLookup {
+    providers: Vec<PeerId>,
+    dials_left: usize,
+}
+
+impl RelayLookup {
+    pub fn new(max_dials: usize) -> Self {
+        Self { providers: Vec::new(), dials_left: max_dials }
+    }
+
+    /// Every provider found, in the order they came in.
+    pub fn providers(&self) -> &[PeerId] {
+        &self.providers
+    }
+
+    pub fn into_providers(self) -> Vec<PeerId> {
+        self.providers
+    }
+}
+
+/// What identify showed about a dialed provider.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelayCheck {
+    /// Not a provider we are waiting on.
+    NotACandidate,
+    /// Serves the hop protocol: add it to the relay set.
+    Verified,
+    /// Announced itself as a relay but doesn't serve the hop protocol.
+    Rejected,
+}
+
+/// Providers dialed and waiting for identify, and the ones that passed.
+#[derive(Debug, Default)]
+pub struct RelayDiscovery {
+    candidates: HashSet<PeerId>,
+    verified: HashSet<PeerId>,
+}
+
+impl RelayDiscovery {
+    /// A provider turned up in `lookup`. True if it should be dialed: it is new to the
+    /// lookup, the lookup has dials left, and it is neither `local` nor a relay we already
+    /// know or are checking.
+    pub fn found(&mut self, lookup: &mut RelayLookup, peer: PeerId, local: &PeerId) -> bool {
+        if lookup.providers.contains(&peer) || peer == *local {
+            return false;
+        }
+        lookup.providers.push(peer);
+        if lookup.dials_left == 0 || self.candidates.contains(&peer) || self.verified.contains(&peer) {
+            return false;
+        }
+        lookup.dials_left -= 1;
+        self.candidates.insert(peer);
+        true
+    }
+
+    /// `peer` identified itself. Candidates are settled either way.
+    pub fn identified(&mut self, peer: &PeerId, info: &PeerInfo) -> RelayCheck {
+        if !self.candidates.remove(peer) {
+            return RelayCheck::NotACandidate;
+        }
+        if info.supports(RELAY_HOP_PROTOCOL) {
+            self.verified.insert(*peer);
+            RelayCheck::Verified
+        } else {
+            RelayCheck::Rejected
+        }
+    }
+
+    /// Dialing `peer` failed; a later lookup may try it again.
+    pub fn dial_failed(&mut self, peer: &PeerId) {
+        self.candidates.remove(peer);
+    }
+
+    pub fn is_verified(&self, peer: &PeerId) -> bool {
+        self.verified.contains(peer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(protocols: &[&str]) -> PeerInfo {
+        PeerInfo {
+            agent_version: String::new(),
+            protocol_version: String::new(),
+            protocols: protocols.iter().map(|p| p.to_string()).collect(),
+            listen_addrs: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn dials_a_few_new_providers_and_trusts_only_hop_servers() {
+        let local = PeerId::random();
+        let [a, b, c, d] = [(); 4].map(|_| PeerId::random());
+        let mut discovery = RelayDiscovery::default();
+        let mut lookup = RelayLookup::new(2);
+
+        assert!(!discovery.found(&mut lookup, local, &local));
+        assert!(discovery.found(&mut lookup, a, &local));
+        assert!(!discovery.found(&mut lookup, a, &local), "reported twice");
+        assert!(discovery.found(&mut lookup, b, &local));
+        assert!(!discovery.found(&mut lookup, c, &local), "out of dials");
+        assert_eq!(lookup.providers(), [a, b, c]);
+
+        assert_eq!(discovery.identified(&a, &info(&[RELAY_HOP_PROTOCOL])), RelayCheck::Verified);
+        assert_eq!(discovery.identified(&b, &info(&["/ipfs/id/1.0.0"])), RelayCheck::Rejected);
+        assert_eq!(discovery.identified(&d, &info(&[RELAY_HOP_PROTOCOL])), RelayCheck::NotACandidate);
+        assert!(discovery.is_verified(&a) && !discovery.is_verified(&b));
+
+        // Known relays aren't dialed again; failed and rejected ones may be
+        let mut next = RelayLookup::new(3);
+        discovery.dial_failed(&c);
+        assert!(!discovery.found(&mut next, a, &local));
+        assert!(discovery.found(&mut next, b, &local));
+        assert!(discovery.found(&mut next, c, &local));
+    }
+}
@@ -0,0 +1,215 @@
+//! Ranking connected relays by ping round-trip time, so latency-sensitive traffic goes
+//! through the fastest ones. A faster relay only takes over after beating the one it
+//! replaces by a clear margin on several samples in a row, so noisy pings don't make the
+//! choice flap.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use libp2p::PeerId;
+
+/// How many relays are preferred at once.
+pub const PREFERRED_RELAYS: usize = 2;
+
+/// A challenger must be this much faster than the slowest preferred relay...
+pub const SWITCH_MARGIN: f64 = 0.2;
+
+/// ...on this many consecutive samples before it replaces it.
+pub const SWITCH_AFTER: u32 = 3;
+
+/// Weight of a new sample in the smoothed round-trip time.
+const SMOOTHING: f64 = 0.3;
+
+#[derive(Debug, Clone, Copy)]
+struct Relay {
+    /// Smoothed round-trip time; `None` until the first ping.
+    rtt: Option<Duration>,
+    connected: bool,
+}
+
+/// Relays that joined or left the preferred set.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PreferredChange {
+    pub added: Vec<PeerId>,
+    pub removed: Vec<PeerId>,
+}
+
+impl PreferredChange {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// Relays we connected to, their round-trip times, and which of them are preferred.
+#[derive(Debug, Default)]
+pub struct RelayRanking {
+    relays: HashMap<PeerId, Relay>,
+    preferred: Vec<PeerId>,
+    /// A relay beating the slowest preferred one, and on how many samples in a row.
+    challenger: Option<(PeerId, u32)>,
+}
+
+impl RelayRanking {
+    /// Whether `peer` is a relay we have been connected to.
+    pub fn is_relay(&self, peer: &PeerId) -> bool {
+        self.relays.contains_key(peer)
+    }
+
+    /// Smoothed round-trip time to `relay`, once it has been pinged.
+    pub fn rtt(&self, relay: &PeerId) -> Option<Duration> {
+        self.relays.get(relay).and_then(|r| r.rtt)
+    }
+
+    pub fn preferred(&self) -> &[PeerId] {
+        &self.preferred
+    }
+
+    pub fn is_preferred(&self, relay: &PeerId) -> bool {
+        self.preferred.contains(relay)
+    }
+
+    /// The fastest preferred relay.
+    pub fn best(&self) -> Option<PeerId> {
+        self.preferred.iter().copied().min_by_key(|relay| self.rtt(relay).unwrap_or(Duration::MAX))
+    }
+
+    /// `relay` (re)connected. It is preferred right away while there is room.
+    pub fn connected(&mut self, relay: PeerId) -> PreferredChange {
+        let entry = self.relays.entry(relay).or_insert(Relay { rtt: None, connected: false });
+        entry.connected = true;
+        let mut change = PreferredChange::default();
+        if self.preferred.len() < PREFERRED_RELAYS && !self.is_preferred(&relay) {
+            self.preferred.push(relay);
+            change.added.push(relay);
+        }
+        change
+    }
+
+    /// `relay` lost its last connection; the fastest connected relay left takes its place.
+    pub fn disconnected(&mut self, relay: &PeerId) -> PreferredChange {
+        let mut change = PreferredChange::default();
+        let Some(entry) = self.relays.get_mut(relay) else {
+            return change;
+        };
+        // Measured afresh once it's back
+        *entry = Relay { rtt: None, connected: false };
+        if self.challenger.is_some_and(|(c, _)| c == *relay) {
+            self.challenger = None;
+        }
+        if !self.is_preferred(relay) {
+            return change;
+        }
+        self.preferred.retain(|r| r != relay);
+        change.removed.push(*relay);
+        if let Some(next) = self.fastest_standby() {
+            self.preferred.push(next);
+            change.added.push(next);
+        }
+        change
+    }
+
+    /// A ping to `peer` took `rtt`. Ignored unless `peer` is a relay.
+    pub fn record_rtt(&mut self, peer: PeerId, rtt: Duration) -> PreferredChange {
+        let Some(relay) = self.relays.get_mut(&peer) else {
+            return PreferredChange::default();
+        };
+        relay.rtt = Some(match relay.rtt {
+            Some(smoothed) => smoothed.mul_f64(1.0 - SMOOTHING) + rtt.mul_f64(SMOOTHING),
+            None => rtt,
+        });
+        self.reconsider()
+    }
+
+    /// Swap the slowest preferred relay for the fastest standby once it has been clearly
+    /// faster for [`SWITCH_AFTER`] samples.
+    fn reconsider(&mut self) -> PreferredChange {
+        let mut change = PreferredChange::default();
+        let slowest = self.preferred.iter().map(|r| (*r, self.rtt(r))).max_by_key(|(_, rtt)| rtt.unwrap_or(Duration::MAX));
+        let (Some((slowest, Some(slowest_rtt))), Some(challenger)) = (slowest, self.fastest_standby()) else {
+            self.challenger = None;
+            return change;
+        };
+        let faster = self.rtt(&challenger).is_some_and(|rtt| rtt < slowest_rtt.mul_f64(1.0 - SWITCH_MARGIN));
+        if !faster {
+            self.challenger = None;
+            return change;
+        }
+        let samples = match self.challenger {
+            Some((c, n)) if c == challenger => n + 1,
+            _ => 1,
+        };
+        if samples < SWITCH_AFTER {
+            self.challenger = Some((challenger, samples));
+            return change;
+        }
+        self.challenger = None;
+        self.preferred.retain(|r| *r != slowest);
+        self.preferred.push(challenger);
+        change.removed.push(slowest);
+        change.added.push(challenger);
+        change
+    }
+
+    /// The fastest connected relay that isn't preferred; unpinged ones come last.
+    fn fastest_standby(&self) -> Option<PeerId> {
+        self.relays
+            .iter()
+            .filter(|(peer, relay)| relay.connected && !self.is_preferred(peer))
+            .min_by_key(|(_, relay)| relay.rtt.unwrap_or(Duration::MAX))
+            .map(|(peer, _)| *peer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn prefers_the_first_relays_until_a_faster_one_proves_itself() {
+        let mut ranking = RelayRanking::default();
+        let (a, b, c) = (PeerId::random(), PeerId::random(), PeerId::random());
+        assert_eq!(ranking.connected(a).added, [a]);
+        assert_eq!(ranking.connected(b).added, [b]);
+        assert!(ranking.connected(c).is_empty());
+        assert!(ranking.record_rtt(PeerId::random(), ms(1)).is_empty());
+
+        ranking.record_rtt(a, ms(50));
+        ranking.record_rtt(b, ms(200));
+        assert_eq!(ranking.best(), Some(a));
+
+        // Much faster, but a single sample is not enough, and a slip resets the count
+        assert!(ranking.record_rtt(c, ms(20)).is_empty());
+        assert!(ranking.record_rtt(c, ms(20)).is_empty());
+        assert!(ranking.record_rtt(c, ms(600)).is_empty());
+        assert!(ranking.record_rtt(c, ms(20)).is_empty());
+        assert!(ranking.record_rtt(c, ms(20)).is_empty());
+        let change = ranking.record_rtt(c, ms(20));
+        assert_eq!(change, PreferredChange { added: vec![c], removed: vec![b] });
+        assert_eq!(ranking.preferred(), [a, c]);
+        assert_eq!(ranking.best(), Some(a));
+    }
+
+    #[test]
+    fn small_improvements_do_not_switch_and_losses_are_replaced() {
+        let mut ranking = RelayRanking::default();
+        let (a, b, c) = (PeerId::random(), PeerId::random(), PeerId::random());
+        for relay in [a, b, c] {
+            ranking.connected(relay);
+        }
+        ranking.record_rtt(a, ms(100));
+        ranking.record_rtt(b, ms(100));
+        for _ in 0..10 {
+            // 10% faster is within the margin
+            assert!(ranking.record_rtt(c, ms(90)).is_empty());
+        }
+        assert_eq!(ranking.preferred(), [a, b]);
+
+        assert_eq!(ranking.disconnected(&a), PreferredChange { added: vec![c], removed: vec![a] });
+        assert!(ranking.is_relay(&a) && ranking.rtt(&a).is_none());
+        assert!(ranking.connected(a).is_empty());
+    }
+}
@@ -0,0 +1,302 @@
+//! The server's configuration file and its hot reload.
+//!
+//! `server --config PATH` reads a JSON object whose keys are the server's flag names
+//! without the dashes, e.g. `{"max-conns-per-ip": 8, "announcers": ["12D3..."]}`. Flags
+//! given on the command line take precedence over the file.
+//!
+//! On SIGHUP or the admin `reload` method the server reads the file again and
+//! [`ServerConfig::reload`]s it: every changed key is classified, the [`HotSetting`]s
+//! whose keys changed are applied one at a time, and the rest is rejected with a reason.
+//! A setting that fails to apply (an invalid value) keeps running as it was, so the
+//! running configuration never holds a value the server did not take.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::Path;
+
+use serde_json::Value;
+
+use crate::node::config_check::ConfigError;
+
+/// A group of keys the server can change while running. The keys of a group are
+/// applied together, as the server reads them together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum HotSetting {
+    Log,
+    ConnectionLimits,
+    Duplicates,
+    ReplayRate,
+    Announcers,
+    ExpectedPeers,
+    PartitionThreshold,
+    Bootstrap,
+}
+
+impl HotSetting {
+    pub const ALL: [HotSetting; 8] = [
+        HotSetting::Log,
+        HotSetting::ConnectionLimits,
+        HotSetting::Duplicates,
+        HotSetting::ReplayRate,
+        HotSetting::Announcers,
+        HotSetting::ExpectedPeers,
+        HotSetting::PartitionThreshold,
+        HotSetting::Bootstrap,
+    ];
+
+    pub fn keys(self) -> &'static [&'static str] {
+        match self {
+            HotSetting::Log => &["log", "log-format"],
+            HotSetting::ConnectionLimits => &[
+                "max-conns-per-ip",
+                "max-conn-attempts-per-ip",
+                "conn-attempt-window-secs",
+                "ipv6-full-address",
+                "allow-peers",
+            ],
+            HotSetting::Duplicates => &["max-duplicates-per-min", "duplicate-graylist-after", "duplicate-graylist-secs"],
+            HotSetting::ReplayRate => &["replay-rate-limit"],
+            HotSetting::Announcers => &["announcers"],
+            HotSetting::ExpectedPeers => &["expected-peers"],
+            HotSetting::PartitionThreshold => &["partition-threshold-secs"],
+            HotSetting::Bootstrap => &["bootstrap-peers"],
+        }
+    }
+
+    pub fn of(key: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|setting| setting.keys().contains(&key))
+    }
+}
+
+/// Keys the server only reads at startup: where it listens, who it is, how it reaches
+/// the network and where its files go.
+pub const RESTART_KEYS: &[&str] = &[
+    "config",
+    "role",
+    "data-dir",
+    "identity-seed-hex",
+    "identity-passphrase-file",
+    "regenerate-invalid-identity",
+    "key-type",
+    "signaling-port",
+    "proxy",
+    "upnp",
+    "agent-version",
+    "topic-namespace",
+    "idle-timeout-secs",
+    "dht-max-records",
+    "dht-max-record-bytes",
+    "dht-max-providers-per-key",
+    "dht-max-provider-keys",
+    "replay-log-size",
+    "events",
+    "events-out",
+    "events-ndjson",
+    "admin-socket",
+    "admin-tcp-port",
+    "status-port",
+    "addr-file",
+    "auto-migrate",
+    "notify",
+    "federation",
+];
+
+/// A configuration file's settings, as flag values.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServerConfig {
+    values: BTreeMap<String, String>,
+}
+
+impl ServerConfig {
+    /// A JSON object of flag names to values. Strings and numbers are taken as written,
+    /// arrays are joined with commas and booleans switch flags on (`true`) or leave them
+    /// out (`false`), as on the command line.
+    pub fn parse(json: &str) -> Result<Self, String> {
+        let Value::Object(object) = serde_json::from_str(json).map_err(|e| e.to_string())? else {
+            return Err("expected a JSON object of settings".to_string());
+        };
+        let mut values = BTreeMap::new();
+        for (key, value) in object {
+            let value = match value {
+                Value::String(s) => s,
+                Value::Number(n) => n.to_string(),
+                Value::Bool(true) => "true".to_string(),
+                Value::Bool(false) | Value::Null => continue,
+                Value::Array(items) => items
+                    .iter()
+                    .map(|item| match item {
+                        Value::String(s) => Ok(s.clone()),
+                        Value::Number(n) => Ok(n.to_string()),
+                        _ => Err(format!("{key}: expected a list of strings")),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?
+                    .join(","),
+                Value::Object(_) => return Err(format!("{key}: expected a string, number, boolean or list")),
+            };
+            values.insert(key, value);
+        }
+        Ok(Self { values })
+    }
+
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let json = std::fs::read_to_string(path).map_err(|e| ConfigError::Unreadable {
+            what: "--config".to_string(),
+            path: path.to_path_buf(),
+            reason: e.to_string(),
+        })?;
+        Self::parse(&json).map_err(|reason| ConfigError::BadConfigFile { path: path.to_path_buf(), reason })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(String::as_str)
+    }
+
+    /// Whether the flag `key` is switched on.
+    pub fn is_set(&self, key: &str) -> bool {
+        self.get(key) == Some("true")
+    }
+
+    pub fn set(&mut self, key: &str, value: Option<&str>) {
+        match value {
+            Some(value) => self.values.insert(key.to_string(), value.to_string()),
+            None => self.values.remove(key),
+        };
+    }
+
+    /// The keys whose values differ between `self` and `other`.
+    pub fn diff(&self, other: &ServerConfig) -> BTreeSet<String> {
+        self.values
+            .keys()
+            .chain(other.values.keys())
+            .filter(|key| self.get(key) != other.get(key))
+            .cloned()
+            .collect()
+    }
+
+    /// Move to `new` as far as can be done while running. `overridden` tells which keys
+    /// the command line sets, which the file cannot change. `apply` puts a setting into
+    /// effect given the configuration with its new values, or says why it cannot; only
+    /// settings it took are kept.
+    pub fn reload(
+        &mut self,
+        new: &ServerConfig,
+        overridden: impl Fn(&str) -> bool,
+        mut apply: impl FnMut(HotSetting, &ServerConfig) -> Result<(), String>,
+    ) -> ReloadOutcome {
+        let mut outcome = ReloadOutcome::default();
+        let mut settings: BTreeMap<HotSetting, Vec<String>> = BTreeMap::new();
+        for key in self.diff(new) {
+            let reason = if overridden(&key) {
+                "set on the command line, which takes precedence"
+            } else if let Some(setting) = HotSetting::of(&key) {
+                settings.entry(setting).or_default().push(key);
+                continue;
+            } else if RESTART_KEYS.contains(&key.as_str()) {
+                "needs a restart"
+            } else {
+                "unknown setting"
+            };
+            outcome.rejected.push(RejectedKey { key, reason: reason.to_string() });
+        }
+        for (setting, keys) in settings {
+            let mut candidate = self.clone();
+            for key in &keys {
+                candidate.set(key, new.get(key));
+            }
+            match apply(setting, &candidate) {
+                Ok(()) => {
+                    *self = candidate;
+                    outcome.applied.extend(keys);
+                }
+                Err(reason) => {
+                    outcome.rejected.extend(keys.into_iter().map(|key| RejectedKey { key, reason: reason.clone() }))
+                }
+            }
+        }
+        outcome.rejected.sort_by(|a, b| a.key.cmp(&b.key));
+        outcome
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RejectedKey {
+    pub key: String,
+    pub reason: String,
+}
+
+/// What a reload changed, see [`ServerConfig::reload`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReloadOutcome {
+    pub applied: Vec<String>,
+    pub rejected: Vec<RejectedKey>,
+}
+
+impl fmt::Display for ReloadOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.applied.is_empty() && self.rejected.is_empty() {
+            return f.write_str("nothing changed");
+        }
+        write!(f, "applied [{}]", self.applied.join(", "))?;
+        for rejected in &self.rejected {
+            write!(f, "; rejected {}: {}", rejected.key, rejected.reason)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn applies_hot_changes_and_rejects_the_rest() {
+        let running = ServerConfig::parse(
+            r#"{"max-conns-per-ip": 8, "role": "relay", "log": "info", "announcers": ["a", "b"], "upnp": true}"#,
+        )
+        .unwrap();
+        assert_eq!((running.get("max-conns-per-ip"), running.get("announcers")), (Some("8"), Some("a,b")));
+        assert!(running.is_set("upnp"));
+        let new = ServerConfig::parse(
+            r#"{"max-conns-per-ip": 16, "ipv6-full-address": true, "role": "observer", "log": "debug",
+                "announcers": ["a"], "replay-rate-limit": "many", "upnp": false, "relay-mode": "x"}"#,
+        )
+        .unwrap();
+
+        let mut config = running.clone();
+        let mut applied = Vec::new();
+        let outcome = config.reload(
+            &new,
+            |key| key == "log",
+            |setting, candidate| {
+                applied.push(setting);
+                match setting {
+                    HotSetting::ReplayRate => candidate.get("replay-rate-limit").unwrap().parse::<u32>().map(drop).map_err(|e| e.to_string()),
+                    _ => Ok(()),
+                }
+            },
+        );
+        // Keys of one setting are applied together
+        assert_eq!(applied, [HotSetting::ConnectionLimits, HotSetting::ReplayRate, HotSetting::Announcers]);
+        assert_eq!(outcome.applied, ["ipv6-full-address", "max-conns-per-ip", "announcers"]);
+        let rejected: Vec<(&str, &str)> = outcome.rejected.iter().map(|r| (r.key.as_str(), r.reason.as_str())).collect();
+        assert_eq!(
+            rejected,
+            [
+                ("log", "set on the command line, which takes precedence"),
+                ("relay-mode", "unknown setting"),
+                ("replay-rate-limit", "invalid digit found in string"),
+                ("role", "needs a restart"),
+                ("upnp", "needs a restart"),
+            ]
+        );
+
+        // What was rejected still runs as before, and is reported again next time
+        assert_eq!((config.get("max-conns-per-ip"), config.get("announcers")), (Some("16"), Some("a")));
+        assert_eq!((config.get("role"), config.get("log"), config.get("replay-rate-limit")), (Some("relay"), Some("info"), None));
+        assert_eq!(config.diff(&new).len(), 5);
+        assert_eq!(config.clone().reload(&config, |_| false, |_, _| unreachable!()).to_string(), "nothing changed");
+
+        assert!(ServerConfig::parse("[1, 2]").is_err());
+        assert!(ServerConfig::parse(r#"{"allow-peers": [{}]}"#).is_err());
+    }
+}
@@ -0,0 +1,290 @@
+//! Peer discovery through rendezvous points, shared by the native and wasm event loops.
+//!
+//! A client registers with every peer it meets that serves
+//! [`RENDEZVOUS_PROTOCOL`](crate::behaviour::rendezvous::RENDEZVOUS_PROTOCOL), under the
+//! namespace of its topic registry, and renews the registration halfway through its
+//! TTL. Every [`DISCOVER_INTERVAL`] it asks each point for the peers registered since the
+//! last answer, and dials a few of the new ones. Points return at most
+//! [`DISCOVER_LIMIT`] registrations at a time; a full page is followed by another
+//! request for the rest.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use libp2p::{Multiaddr, PeerId};
+use web_time::Instant;
+
+use crate::behaviour::rendezvous::Registration;
+
+/// How long our registrations last, the shortest TTL rendezvous servers accept.
+pub const REGISTRATION_TTL: Duration = Duration::from_secs(2 * 60 * 60);
+
+/// Longest TTL taken from a point, the longest rendezvous servers grant. Points are
+/// not trusted to send sane ones.
+pub const MAX_TTL: Duration = Duration::from_secs(72 * 60 * 60);
+
+/// How often each point is asked for new registrants.
+pub const DISCOVER_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Registrations asked for per discovery request.
+pub const DISCOVER_LIMIT: u64 = 100;
+
+/// Wait before registering again after a point refused, or while we had no external
+/// address to register.
+pub const REGISTER_RETRY: Duration = Duration::from_secs(60);
+
+/// New registrants dialed per discovery.
+pub const DEFAULT_REGISTRANTS_TO_DIAL: usize = 3;
+
+/// A peer found at a rendezvous point.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Registrant {
+    pub peer_id: PeerId,
+    pub addrs: Vec<Multiaddr>,
+    /// The point it is registered with.
+    pub point: PeerId,
+}
+
+#[derive(Debug)]
+struct Point<C> {
+    cookie: Option<C>,
+    /// When to register (again); `None` while a registration is in flight.
+    register_at: Option<Instant>,
+    discovering: bool,
+}
+
+#[derive(Debug)]
+struct Known {
+    registrant: Registrant,
+    expires: Instant,
+}
+
+/// What an answer to a discovery brought.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Discovered {
+    /// Registrants not known before, in the order the point returned them.
+    pub new: Vec<Registrant>,
+    /// The page was full: ask the point again for the rest.
+    pub more: bool,
+}
+
+/// Rendezvous points and the registrants found through them, with cookie type `C`.
+#[derive(Debug)]
+pub struct RendezvousPeers<C> {
+    local: PeerId,
+    points: HashMap<PeerId, Point<C>>,
+    registrants: HashMap<PeerId, Known>,
+}
+
+impl<C> RendezvousPeers<C> {
+    pub fn new(local: PeerId) -> Self {
+        Self { local, points: HashMap::new(), registrants: HashMap::new() }
+    }
+
+    /// `peer` identified as a rendezvous point. True if it is a new one, to register with
+    /// and discover through right away.
+    pub fn add_point(&mut self, peer: PeerId) -> bool {
+        if self.points.contains_key(&peer) {
+            return false;
+        }
+        self.points.insert(peer, Point { cookie: None, register_at: None, discovering: false });
+        true
+    }
+
+    /// We lost our connection to `peer`. Registrants found through it are kept until
+    /// their registration runs out.
+    pub fn remove_point(&mut self, peer: &PeerId) {
+        self.points.remove(peer);
+    }
+
+    pub fn points(&self) -> Vec<PeerId> {
+        self.points.keys().copied().collect()
+    }
+
+    /// `point` accepted our registration for `ttl`; renew it halfway through.
+    pub fn registered(&mut self, point: &PeerId, ttl: Duration, now: Instant) {
+        if let Some(p) = self.points.get_mut(point) {
+            p.register_at = Some(later(now, ttl.min(MAX_TTL) / 2));
+        }
+    }
+
+    /// Registering with `point` failed; try again after [`REGISTER_RETRY`].
+    pub fn register_failed(&mut self, point: &PeerId, now: Instant) {
+        if let Some(p) = self.points.get_mut(point) {
+            p.register_at = Some(now + REGISTER_RETRY);
+        }
+    }
+
+    /// Points to register with now. They are taken to be in flight until
+    /// [`registered`](Self::registered) or [`register_failed`](Self::register_failed).
+    pub fn due_registrations(&mut self, now: Instant) -> Vec<PeerId> {
+        let mut due = Vec::new();
+        for (peer, point) in &mut self.points {
+            if point.register_at.is_some_and(|at| at <= now) {
+                point.register_at = None;
+                due.push(*peer);
+            }
+        }
+        due
+    }
+
+    /// Our addresses changed, and registrations carry them: register everywhere again.
+    pub fn renew_all(&mut self, now: Instant) {
+        for point in self.points.values_mut() {
+            if point.register_at.is_some() {
+                point.register_at = Some(now);
+            }
+        }
+    }
+
+    /// Start a discovery at `point`: `Some` with the cookie to send along, `None` if
+    /// `point` is unknown or already has one in flight.
+    pub fn start_discovery(&mut self, point: &PeerId) -> Option<Option<C>>
+    where
+        C: Clone,
+    {
+        let p = self.points.get_mut(point).filter(|p| !p.discovering)?;
+        p.discovering = true;
+        Some(p.cookie.clone())
+    }
+
+    /// `point` answered a discovery with `registrations`, continuing after `cookie`.
+    pub fn discovered(&mut self, point: &PeerId, registrations: Vec<Registration>, cookie: C, now: Instant) -> Discovered {
+        let more = registrations.len() as u64 >= DISCOVER_LIMIT;
+        if let Some(p) = self.points.get_mut(point) {
+            p.discovering = false;
+            p.cookie = Some(cookie);
+        }
+        let mut new = Vec::new();
+        for registration in registrations {
+            if registration.peer_id == self.local {
+                continue;
+            }
+            let registrant = Registrant { peer_id: registration.peer_id, addrs: registration.addrs, point: *point };
+            let known = Known { registrant: registrant.clone(), expires: later(now, registration.ttl.min(MAX_TTL)) };
+            if self.registrants.insert(registration.peer_id, known).is_none() {
+                new.push(registrant);
+            }
+        }
+        Discovered { new, more }
+    }
+
+    /// A discovery at `point` failed. The cookie is dropped too, in case the point no
+    /// longer accepts it; the next discovery asks for everything.
+    pub fn discovery_failed(&mut self, point: &PeerId) {
+        if let Some(p) = self.points.get_mut(point) {
+            p.discovering = false;
+            p.cookie = None;
+        }
+    }
+
+    /// Whether a discovery is in flight at any point.
+    pub fn is_discovering(&self) -> bool {
+        self.points.values().any(|p| p.discovering)
+    }
+
+    /// `peer`'s registration ran out.
+    pub fn expired(&mut self, peer: &PeerId) {
+        self.registrants.remove(peer);
+    }
+
+    /// Registrants whose registration is still valid, by peer id.
+    pub fn registrants(&mut self, now: Instant) -> Vec<Registrant> {
+        self.registrants.retain(|_, known| known.expires > now);
+        let mut registrants: Vec<Registrant> = self.registrants.values().map(|k| k.registrant.clone()).collect();
+        registrants.sort_by_key(|r| r.peer_id);
+        registrants
+    }
+}
+
+/// `now + delay`, or `now` should that not fit in an `Instant`.
+fn later(now: Instant, delay: Duration) -> Instant {
+    now.checked_add(delay).unwrap_or(now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registration(peer_id: PeerId, ttl_secs: u64) -> Registration {
+        let addr: Multiaddr = "/ip4/198.51.100.4/tcp/4001".parse().unwrap();
+        Registration { peer_id, addrs: vec![addr], ttl: Duration::from_secs(ttl_secs) }
+    }
+
+    #[test]
+    fn registrations_are_renewed_and_retried() {
+        let (local, point) = (PeerId::random(), PeerId::random());
+        let mut peers = RendezvousPeers::<u32>::new(local);
+        let now = Instant::now();
+        assert!(peers.add_point(point));
+        assert!(!peers.add_point(point), "already known");
+        assert!(peers.due_registrations(now).is_empty(), "registered on identify, not here");
+
+        peers.registered(&point, REGISTRATION_TTL, now);
+        assert!(peers.due_registrations(now + REGISTRATION_TTL / 4).is_empty());
+        assert_eq!(peers.due_registrations(now + REGISTRATION_TTL / 2), [point]);
+        assert!(peers.due_registrations(now + REGISTRATION_TTL).is_empty(), "in flight");
+
+        // No external address yet: retried a little later, or as soon as one shows up
+        peers.register_failed(&point, now);
+        assert!(peers.due_registrations(now).is_empty());
+        assert_eq!(peers.due_registrations(now + REGISTER_RETRY), [point]);
+        peers.register_failed(&point, now);
+        peers.renew_all(now);
+        assert_eq!(peers.due_registrations(now), [point]);
+    }
+
+    #[test]
+    fn discoveries_page_through_registrants() {
+        let (local, point) = (PeerId::random(), PeerId::random());
+        let mut peers = RendezvousPeers::new(local);
+        let now = Instant::now();
+        assert_eq!(peers.start_discovery(&point), None, "not a point");
+        peers.add_point(point);
+        assert_eq!(peers.start_discovery(&point), Some(None));
+        assert_eq!(peers.start_discovery(&point), None, "in flight");
+
+        // A full page, including ourselves
+        let mut page: Vec<Registration> = (1..DISCOVER_LIMIT).map(|_| registration(PeerId::random(), 60)).collect();
+        page.push(registration(local, 60));
+        let discovered = peers.discovered(&point, page, 1, now);
+        assert!(discovered.more);
+        assert_eq!(discovered.new.len() as u64, DISCOVER_LIMIT - 1);
+        assert!(!peers.is_discovering());
+
+        let late = PeerId::random();
+        assert_eq!(peers.start_discovery(&point), Some(Some(1)));
+        let discovered = peers.discovered(&point, vec![registration(late, 7200), registration(discovered.new[0].peer_id, 60)], 2, now);
+        assert_eq!(discovered.new.iter().map(|r| r.peer_id).collect::<Vec<_>>(), [late], "renewals are not new");
+        assert!(!discovered.more);
+
+        // Losing the point keeps what it told us, until the registrations run out
+        peers.remove_point(&point);
+        assert_eq!(peers.registrants(now).len() as u64, DISCOVER_LIMIT);
+        let later = peers.registrants(now + Duration::from_secs(61));
+        assert_eq!(later.iter().map(|r| r.peer_id).collect::<Vec<_>>(), [late]);
+        assert_eq!(later[0].point, point);
+        peers.expired(&late);
+        assert!(peers.registrants(now).is_empty());
+
+        peers.add_point(point);
+        peers.start_discovery(&point);
+        peers.discovery_failed(&point);
+        assert_eq!(peers.start_discovery(&point), Some(None), "cookie dropped");
+    }
+
+    #[test]
+    fn huge_ttls_from_a_point_are_capped() {
+        let (local, point, peer) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let mut peers = RendezvousPeers::new(local);
+        let now = Instant::now();
+        peers.add_point(point);
+        peers.registered(&point, Duration::from_secs(u64::MAX), now);
+        assert_eq!(peers.due_registrations(now + MAX_TTL / 2), [point]);
+
+        peers.start_discovery(&point);
+        peers.discovered(&point, vec![registration(peer, u64::MAX)], 1, now);
+        assert_eq!(peers.registrants(now).len(), 1);
+        assert!(peers.registrants(now + MAX_TTL).is_empty());
+    }
+}
@@ -0,0 +1,63 @@
+//! Read replicas: FullNodes that store, serve, re-provide and sync documents like any
+//! other but never author a change, so every update can be traced to a writer node. See
+//! [`crate::node::NodeBuilder::with_replica`]; publishing on a replica fails with
+//! `Error::ReadOnly`.
+//!
+//! A replica says so in its identify agent version, so clients can send reads (fetch,
+//! replay, history) its way and keep CAS updates and receipt-requesting publishes for
+//! writers. [`ReplicationStatus`] tells how long ago the newest update it holds was
+//! written.
+
+/// Appended to a replica's identify agent version.
+pub const REPLICA_AGENT_MARKER: &str = "(replica)";
+
+/// `agent_version` marked as a replica's.
+pub fn replica_agent_version(agent_version: &str) -> String {
+    if is_replica_agent(agent_version) {
+        agent_version.to_string()
+    } else {
+        format!("{agent_version} {REPLICA_AGENT_MARKER}")
+    }
+}
+
+/// Whether a peer advertising `agent_version` is a replica.
+pub fn is_replica_agent(agent_version: &str) -> bool {
+    agent_version.ends_with(REPLICA_AGENT_MARKER)
+}
+
+/// How far behind the writers a node is, as far as it can tell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplicationStatus {
+    pub replica: bool,
+    /// When the newest stamped update applied here was written, by its author's clock
+    /// (unix milliseconds).
+    pub newest_update_ms: Option<u64>,
+    /// Wall clock minus `newest_update_ms`, 0 if the author's clock is ahead of ours.
+    /// It keeps growing while nobody writes, so compare it with a writer's.
+    pub lag_ms: Option<u64>,
+}
+
+impl ReplicationStatus {
+    pub fn new(replica: bool, newest_update_ms: Option<u64>, now_ms: u64) -> Self {
+        Self { replica, newest_update_ms, lag_ms: newest_update_ms.map(|newest| now_ms.saturating_sub(newest)) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn marks_agents_once_and_measures_lag() {
+        let agent = replica_agent_version("my-app/2.3");
+        assert_eq!(agent, "my-app/2.3 (replica)");
+        assert_eq!(replica_agent_version(&agent), agent);
+        assert!(is_replica_agent(&agent));
+        assert!(!is_replica_agent("my-app/2.3"));
+
+        assert_eq!(ReplicationStatus::new(true, Some(1_000), 4_000).lag_ms, Some(3_000));
+        // A writer whose clock runs ahead is not in the future
+        assert_eq!(ReplicationStatus::new(true, Some(5_000), 4_000).lag_ms, Some(0));
+        assert_eq!(ReplicationStatus::new(false, None, 4_000).lag_ms, None);
+    }
+}
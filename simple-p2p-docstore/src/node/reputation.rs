@@ -0,0 +1,205 @@
+//! Process-local peer reputation: one score per peer, built from what we observe of it
+//! (ping round trips, dial failures, invalid messages, rate limiting, fetch outcomes) and
+//! decaying back towards neutral. Used to pick whom to fetch from first and to slow down
+//! retries of peers that keep failing. Scores are never gossiped.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use libp2p::PeerId;
+use web_time::Instant;
+
+/// Time for a score to decay halfway back to neutral.
+pub const HALF_LIFE: Duration = Duration::from_secs(10 * 60);
+
+/// Scores stay within `-MAX_SCORE..=MAX_SCORE`.
+pub const MAX_SCORE: f64 = 100.0;
+
+/// At or below this score a peer is failing chronically: dial retries to it wait
+/// [`FAILING_BACKOFF_FACTOR`] times longer.
+pub const FAILING_SCORE: f64 = -20.0;
+
+pub const FAILING_BACKOFF_FACTOR: u32 = 4;
+
+/// Peers scored at once; the most neutral one is forgotten to make room.
+pub const MAX_TRACKED_PEERS: usize = 4096;
+
+/// Something we observed of a peer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PeerSignal {
+    Ping { rtt: Duration },
+    PingFailed,
+    DialFailed,
+    /// It sent a message that failed validation (malformed, replayed, ...).
+    InvalidMessage,
+    /// It refused a request of ours for asking too often.
+    RateLimited,
+    /// It answered a request of ours (a replay fetch, a direct message).
+    FetchSucceeded,
+    FetchFailed,
+}
+
+impl PeerSignal {
+    /// How much the signal moves the score.
+    pub fn delta(self) -> f64 {
+        match self {
+            PeerSignal::Ping { rtt } if rtt < Duration::from_millis(100) => 1.0,
+            PeerSignal::Ping { rtt } if rtt < Duration::from_millis(500) => 0.5,
+            PeerSignal::Ping { .. } => 0.0,
+            PeerSignal::PingFailed => -2.0,
+            PeerSignal::DialFailed => -5.0,
+            PeerSignal::InvalidMessage => -20.0,
+            PeerSignal::RateLimited => -10.0,
+            PeerSignal::FetchSucceeded => 5.0,
+            PeerSignal::FetchFailed => -5.0,
+        }
+    }
+}
+
+/// Signals applications report themselves; pings are observed by the node.
+impl std::str::FromStr for PeerSignal {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ping_failed" => Ok(PeerSignal::PingFailed),
+            "dial_failed" => Ok(PeerSignal::DialFailed),
+            "invalid_message" => Ok(PeerSignal::InvalidMessage),
+            "rate_limited" => Ok(PeerSignal::RateLimited),
+            "fetch_succeeded" => Ok(PeerSignal::FetchSucceeded),
+            "fetch_failed" => Ok(PeerSignal::FetchFailed),
+            other => Err(format!(
+                "unknown peer signal '{other}' (expected ping_failed, dial_failed, invalid_message, rate_limited, fetch_succeeded or fetch_failed)"
+            )),
+        }
+    }
+}
+
+/// `score` after `elapsed` of halving every [`HALF_LIFE`].
+pub fn decay(score: f64, elapsed: Duration) -> f64 {
+    score * 0.5f64.powf(elapsed.as_secs_f64() / HALF_LIFE.as_secs_f64())
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Score {
+    value: f64,
+    updated: Instant,
+}
+
+impl Score {
+    fn at(&self, now: Instant) -> f64 {
+        decay(self.value, now.saturating_duration_since(self.updated))
+    }
+}
+
+/// Shared peer scores. Cheap to clone; clones score into the same table.
+#[derive(Debug, Clone, Default)]
+pub struct PeerReputation {
+    scores: Arc<Mutex<HashMap<PeerId, Score>>>,
+}
+
+impl PeerReputation {
+    /// Apply `signal` to `peer`'s score and return the new score.
+    pub fn record(&self, peer: PeerId, signal: PeerSignal, now: Instant) -> f64 {
+        let mut scores = self.lock();
+        if !scores.contains_key(&peer) && scores.len() >= MAX_TRACKED_PEERS {
+            let most_neutral = scores
+                .iter()
+                .min_by(|(_, a), (_, b)| a.at(now).abs().total_cmp(&b.at(now).abs()))
+                .map(|(peer, _)| *peer);
+            if let Some(most_neutral) = most_neutral {
+                scores.remove(&most_neutral);
+            }
+        }
+        let score = scores.entry(peer).or_insert(Score { value: 0.0, updated: now });
+        let value = (score.at(now) + signal.delta()).clamp(-MAX_SCORE, MAX_SCORE);
+        *score = Score { value, updated: now };
+        value
+    }
+
+    /// `peer`'s current score; `0` (neutral) for peers we know nothing about.
+    pub fn score(&self, peer: &PeerId, now: Instant) -> f64 {
+        self.lock().get(peer).map_or(0.0, |score| score.at(now))
+    }
+
+    /// The `n` best-scoring peers, best first.
+    pub fn top(&self, n: usize, now: Instant) -> Vec<(PeerId, f64)> {
+        let mut peers: Vec<_> = self.lock().iter().map(|(peer, score)| (*peer, score.at(now))).collect();
+        peers.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+        peers.truncate(n);
+        peers
+    }
+
+    /// `candidates` ordered best first, e.g. to pick whom to fetch from. Peers with equal
+    /// scores keep their order.
+    pub fn rank(&self, candidates: impl IntoIterator<Item = PeerId>, now: Instant) -> Vec<PeerId> {
+        let scores = self.lock();
+        let mut ranked: Vec<_> = candidates
+            .into_iter()
+            .map(|peer| (peer, scores.get(&peer).map_or(0.0, |score| score.at(now))))
+            .collect();
+        ranked.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+        ranked.into_iter().map(|(peer, _)| peer).collect()
+    }
+
+    /// How much longer than usual to wait before redialing `peer`.
+    pub fn retry_backoff_factor(&self, peer: &PeerId, now: Instant) -> u32 {
+        if self.score(peer, now) <= FAILING_SCORE {
+            FAILING_BACKOFF_FACTOR
+        } else {
+            1
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<PeerId, Score>> {
+        self.scores.lock().expect("reputation lock")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signals_add_up_and_decay_towards_neutral() {
+        let reputation = PeerReputation::default();
+        let peer = PeerId::random();
+        let now = Instant::now();
+
+        assert_eq!(reputation.score(&peer, now), 0.0);
+        reputation.record(peer, PeerSignal::FetchSucceeded, now);
+        assert_eq!(reputation.record(peer, PeerSignal::Ping { rtt: Duration::from_millis(20) }, now), 6.0);
+        assert_eq!(reputation.score(&peer, now + HALF_LIFE), 3.0);
+        assert_eq!(reputation.score(&peer, now + HALF_LIFE * 2), 1.5);
+        assert_eq!(decay(-8.0, HALF_LIFE * 3), -1.0);
+
+        // Decay applies before the next signal
+        assert_eq!(reputation.record(peer, PeerSignal::DialFailed, now + HALF_LIFE), -2.0);
+
+        for _ in 0..10 {
+            reputation.record(peer, PeerSignal::InvalidMessage, now + HALF_LIFE);
+        }
+        assert_eq!(reputation.score(&peer, now + HALF_LIFE), -MAX_SCORE);
+    }
+
+    #[test]
+    fn ranks_candidates_and_slows_retries_of_failing_peers() {
+        let reputation = PeerReputation::default();
+        let (good, unknown, bad) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let now = Instant::now();
+
+        reputation.record(good, PeerSignal::FetchSucceeded, now);
+        for _ in 0..4 {
+            reputation.record(bad, PeerSignal::DialFailed, now);
+        }
+        assert_eq!(reputation.rank([bad, unknown, good], now), [good, unknown, bad]);
+        assert_eq!(reputation.top(1, now), [(good, 5.0)]);
+        assert_eq!(reputation.retry_backoff_factor(&bad, now), FAILING_BACKOFF_FACTOR);
+        assert_eq!(reputation.retry_backoff_factor(&unknown, now), 1);
+        // Forgiven once the failures have decayed
+        assert_eq!(reputation.retry_backoff_factor(&bad, now + HALF_LIFE), 1);
+        assert!("rate_limited".parse::<PeerSignal>().is_ok());
+        assert!("ping".parse::<PeerSignal>().is_err());
+    }
+}
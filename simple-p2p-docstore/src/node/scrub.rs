@@ -0,0 +1,202 @@
+//! Background integrity checks of the persistent document store.
+//!
+//! Bit rot and torn writes corrupt stored documents silently, and a FullNode serving a
+//! corrupted one poisons every peer that syncs from it. [`StoreScrub`] walks the stored
+//! documents one at a time, in document id order, for the event loop to
+//! [`DocStore::verify`](crate::store::DocStore::verify) each at a low rate. The last
+//! document checked is saved after every check, so a restart picks the pass up where it
+//! stopped instead of starting over.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::node::migrations::{self, Artifact};
+use crate::store::Corruption;
+
+/// Name of the cursor file, kept in the store directory.
+pub const SCRUB_FILE: &str = "scrub";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct Progress {
+    /// The last document checked in the current pass; `None` at the start of a pass.
+    cursor: Option<String>,
+    passes: u64,
+}
+
+/// Scrub progress, for `/metrics`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ScrubStats {
+    /// Complete passes over the store, including before a restart.
+    pub passes: u64,
+    /// Documents checked since the node started, by the scrub and by
+    /// [`Node::verify_now`](crate::node::Node::verify_now).
+    pub verified: u64,
+    pub corrupted: u64,
+    /// Documents the current pass has checked, out of `pass_docs`.
+    pub pass_checked: usize,
+    pub pass_docs: usize,
+}
+
+impl ScrubStats {
+    pub fn metrics(&self) -> Vec<(String, u64)> {
+        vec![
+            ("docstore_scrub_passes_total".to_string(), self.passes),
+            ("docstore_scrub_verified_total".to_string(), self.verified),
+            ("docstore_scrub_corrupted_total".to_string(), self.corrupted),
+            ("docstore_scrub_pass_checked".to_string(), self.pass_checked as u64),
+            ("docstore_scrub_pass_docs".to_string(), self.pass_docs as u64),
+        ]
+    }
+}
+
+/// What [`Node::verify_now`](crate::node::Node::verify_now) found.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScrubReport {
+    /// Documents checked, corrupted ones included.
+    pub verified: usize,
+    /// The documents found corrupted, now quarantined.
+    pub corrupted: Vec<(String, Corruption)>,
+}
+
+/// Where the scrub is, see the [module docs](self).
+#[derive(Debug, Default)]
+pub struct StoreScrub {
+    path: Option<PathBuf>,
+    progress: Progress,
+    stats: ScrubStats,
+}
+
+impl StoreScrub {
+    /// Resume from the cursor saved at `path`, or start at the beginning if there is none
+    /// or it is unreadable. Without a path, progress is kept in memory only.
+    pub fn load(path: Option<PathBuf>) -> Self {
+        let progress = match &path {
+            Some(path) => read_progress(path).unwrap_or_else(|e| {
+                tracing::warn!("Ignoring unreadable scrub progress {}: {}", path.display(), e);
+                Progress::default()
+            }),
+            None => Progress::default(),
+        };
+        let stats = ScrubStats { passes: progress.passes, ..Default::default() };
+        Self { path, progress, stats }
+    }
+
+    /// The next document to check among `doc_ids`: the first after the cursor in id
+    /// order. When none is left the pass is complete and the next one starts at the first.
+    /// `None` if the store is empty.
+    pub fn next(&mut self, mut doc_ids: Vec<String>) -> Option<String> {
+        doc_ids.sort();
+        let position = match &self.progress.cursor {
+            None => 0,
+            Some(cursor) => match doc_ids.iter().position(|doc_id| doc_id > cursor) {
+                Some(position) => position,
+                None => {
+                    self.progress.passes += 1;
+                    self.progress.cursor = None;
+                    self.stats.passes = self.progress.passes;
+                    self.save();
+                    0
+                }
+            },
+        };
+        self.stats.pass_checked = position;
+        self.stats.pass_docs = doc_ids.len();
+        doc_ids.into_iter().nth(position)
+    }
+
+    /// Record the outcome of checking `doc_id`, returned by [`next`](Self::next), and
+    /// move the cursor past it.
+    pub fn checked(&mut self, doc_id: &str, result: &Result<(), Corruption>) {
+        self.counted(result);
+        self.stats.pass_checked += 1;
+        self.progress.cursor = Some(doc_id.to_string());
+        self.save();
+    }
+
+    /// Count a check made outside the pass, leaving the cursor where it is.
+    pub fn counted(&mut self, result: &Result<(), Corruption>) {
+        self.stats.verified += 1;
+        if result.is_err() {
+            self.stats.corrupted += 1;
+        }
+    }
+
+    pub fn stats(&self) -> ScrubStats {
+        self.stats
+    }
+
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        if let Err(e) = write_progress(path, &self.progress) {
+            tracing::warn!("Failed to save scrub progress to {}: {}", path.display(), e);
+        }
+    }
+}
+
+fn read_progress(path: &Path) -> io::Result<Progress> {
+    match std::fs::read(path) {
+        Ok(bytes) => migrations::decode_json(Artifact::ScrubProgress, &bytes),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Progress::default()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Through a temporary file, so a crash never leaves the cursor truncated.
+fn write_progress(path: &Path, progress: &Progress) -> io::Result<()> {
+    let bytes = serde_json::to_vec(&migrations::with_header(Artifact::ScrubProgress, progress)?).map_err(io::Error::other)?;
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, bytes)?;
+    std::fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn a_restart_resumes_the_pass() {
+        let dir = std::env::temp_dir().join(format!("scrub-resume-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(SCRUB_FILE);
+
+        let mut scrub = StoreScrub::load(Some(path.clone()));
+        let doc_id = scrub.next(ids(&["c", "a", "b"])).unwrap();
+        assert_eq!(doc_id, "a");
+        scrub.checked(&doc_id, &Ok(()));
+        let doc_id = scrub.next(ids(&["c", "a", "b"])).unwrap();
+        assert_eq!(doc_id, "b");
+        scrub.checked(&doc_id, &Err(Corruption::Snapshot));
+        assert_eq!(
+            scrub.stats(),
+            ScrubStats { passes: 0, verified: 2, corrupted: 1, pass_checked: 2, pass_docs: 3 }
+        );
+        drop(scrub);
+
+        // "b" was quarantined meanwhile; the pass goes on from where it stopped
+        let mut scrub = StoreScrub::load(Some(path.clone()));
+        assert_eq!(scrub.next(ids(&["a", "c"])).as_deref(), Some("c"));
+        scrub.checked("c", &Ok(()));
+        assert_eq!(scrub.next(ids(&["a", "c"])).as_deref(), Some("a"), "next pass");
+        assert_eq!(scrub.stats().passes, 1);
+        assert_eq!(StoreScrub::load(Some(path)).stats().passes, 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn checks_outside_the_pass_leave_the_cursor() {
+        let mut scrub = StoreScrub::load(None);
+        assert_eq!(scrub.next(Vec::new()), None);
+        scrub.counted(&Err(Corruption::LogRecord { index: 0 }));
+        assert_eq!(scrub.next(ids(&["a", "b"])).as_deref(), Some("a"));
+        assert_eq!((scrub.stats().verified, scrub.stats().corrupted), (1, 1));
+    }
+}
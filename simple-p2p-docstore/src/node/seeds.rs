@@ -0,0 +1,132 @@
+//! Seed documents: content an application bundles so a first run shows something before
+//! the network answers. Seeds are handed out as they are at start, then give way to the
+//! network's state of their documents.
+//!
+//! A seed counts as older than any real update, so the first update or snapshot of its
+//! document seen live or through catch-up overrides it. A pinned seed only gives way to
+//! state newer than its `version`; catch-up results at or below it are not delivered. If
+//! catch-up finds nothing at all for a seeded document, a seed marked `publish_if_absent`
+//! is published as its first update.
+
+use std::collections::BTreeMap;
+
+/// One bundled document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SeedDocument {
+    pub doc_id: String,
+    pub bytes: Vec<u8>,
+    /// Only compared for pinned seeds.
+    pub version: u64,
+    /// Publish the seed when the network has nothing for the document.
+    pub publish_if_absent: bool,
+    /// Hold against network state at or below `version`.
+    pub pinned: bool,
+}
+
+impl SeedDocument {
+    pub fn new(doc_id: impl Into<String>, bytes: impl Into<Vec<u8>>) -> Self {
+        Self { doc_id: doc_id.into(), bytes: bytes.into(), version: 0, publish_if_absent: false, pinned: false }
+    }
+}
+
+/// What a piece of network state means for a document's seed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeedCheck {
+    /// The document has no seed (anymore).
+    Unseeded,
+    /// The state is newer; the seed is dropped.
+    Overridden,
+    /// A pinned seed is at least as new; it stays.
+    Held,
+}
+
+/// The seeds still standing, by document id.
+#[derive(Debug, Default)]
+pub struct Seeds {
+    docs: BTreeMap<String, SeedDocument>,
+}
+
+impl Seeds {
+    /// A later seed of the same document replaces an earlier one.
+    pub fn new(seeds: impl IntoIterator<Item = SeedDocument>) -> Self {
+        Self { docs: seeds.into_iter().map(|seed| (seed.doc_id.clone(), seed)).collect() }
+    }
+
+    pub fn get(&self, doc_id: &str) -> Option<&SeedDocument> {
+        self.docs.get(doc_id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &SeedDocument> {
+        self.docs.values()
+    }
+
+    /// The seeded documents, for catch-up to fetch.
+    pub fn doc_ids(&self) -> Vec<String> {
+        self.docs.keys().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.docs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.docs.is_empty()
+    }
+
+    /// State of `doc_id` at `version` came from the network.
+    pub fn observe(&mut self, doc_id: &str, version: u64) -> SeedCheck {
+        match self.docs.get(doc_id) {
+            None => SeedCheck::Unseeded,
+            Some(seed) if seed.pinned && version <= seed.version => SeedCheck::Held,
+            Some(_) => {
+                self.docs.remove(doc_id);
+                SeedCheck::Overridden
+            }
+        }
+    }
+
+    /// Catch-up found nothing for `doc_id`: the seed to publish, if it asked for that.
+    /// It is published once; afterwards it is the document's real first update.
+    pub fn absent(&mut self, doc_id: &str) -> Option<SeedDocument> {
+        if !self.docs.get(doc_id)?.publish_if_absent {
+            return None;
+        }
+        self.docs.remove(doc_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn network_state_overrides_seeds_unless_pinned_higher() {
+        let pinned = SeedDocument { version: 5, pinned: true, ..SeedDocument::new("pinned", b"seed".to_vec()) };
+        let mut seeds = Seeds::new([SeedDocument::new("plain", b"old".to_vec()), SeedDocument::new("plain", b"seed".to_vec()), pinned]);
+        assert_eq!(seeds.len(), 2);
+        assert_eq!(seeds.get("plain").unwrap().bytes, b"seed");
+
+        // Any real update beats an unpinned seed, even one at version 0
+        assert_eq!(seeds.observe("plain", 0), SeedCheck::Overridden);
+        assert_eq!(seeds.observe("plain", 9), SeedCheck::Unseeded);
+
+        assert_eq!(seeds.observe("pinned", 3), SeedCheck::Held);
+        assert_eq!(seeds.observe("pinned", 5), SeedCheck::Held);
+        assert_eq!(seeds.observe("pinned", 6), SeedCheck::Overridden);
+        assert!(seeds.is_empty());
+    }
+
+    #[test]
+    fn only_flagged_seeds_are_published_when_absent() {
+        let flagged = SeedDocument { publish_if_absent: true, ..SeedDocument::new("welcome", b"hello".to_vec()) };
+        let mut seeds = Seeds::new([flagged.clone(), SeedDocument::new("draft", b"local".to_vec())]);
+        assert_eq!(seeds.doc_ids(), ["draft", "welcome"]);
+
+        assert_eq!(seeds.absent("draft"), None);
+        assert_eq!(seeds.absent("welcome"), Some(flagged));
+        // Published once; the network's copy takes over from there
+        assert_eq!(seeds.absent("welcome"), None);
+        assert_eq!(seeds.observe("welcome", 1), SeedCheck::Unseeded);
+        assert!(seeds.get("draft").is_some());
+    }
+}
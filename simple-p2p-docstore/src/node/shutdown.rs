@@ -0,0 +1,32 @@
+//! How a native [`Node`] stops, see [`Node::shutdown`].
+//!
+//! Dropping the handle stops the event loop at once, and whatever was still queued is
+//! lost without a word. `shutdown` lets the app choose: [`ShutdownMode::Immediate`] stops
+//! the same way but reports what was lost, [`ShutdownMode::Drain`] first publishes (and
+//! stores) the updates queued before the call, tells peers we are leaving our topics and
+//! closes our connections, giving up on the rest at a deadline.
+//!
+//! [`Node`]: crate::node::Node
+//! [`Node::shutdown`]: crate::node::Node::shutdown
+
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownMode {
+    /// Stop now; queued commands are dropped.
+    Immediate,
+    /// Flush queued publishes, unsubscribe and close connections, dropping whatever is
+    /// left after `timeout`. A store write already started is finished, so the shutdown
+    /// may overrun by one write.
+    Drain { timeout: Duration },
+}
+
+/// What a shutdown got done.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// Queued publishes sent (and stored, for document updates) while shutting down.
+    pub published: usize,
+    /// Queued publishes given up on; their callers got `Error::NodeStopped`.
+    pub dropped: usize,
+    pub duration: Duration,
+}
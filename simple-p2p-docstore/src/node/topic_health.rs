@@ -0,0 +1,262 @@
+//! Topic health: notice a subscribed gossipsub topic that stays without mesh peers while
+//! we are connected, and resubscribe to it. After a transport flap `subscribe` can have
+//! returned Ok while no peer learned of the subscription, so nobody grafts us and every
+//! publish fails until restart. Unsubscribing, waiting a heartbeat and subscribing again
+//! announces the subscription afresh, which gets peers to graft again.
+//!
+//! A topic counts as healthy while it has mesh peers or an explicit peer subscribed to
+//! it: explicit peers get every message without ever joining the mesh, so a browser
+//! peering only with its relays has an empty mesh and is fine. Nothing is checked before
+//! the grace period after the first connection is over, nor while no connection is up.
+//! A topic that stays empty after a resubscribe is tried again after twice as long each
+//! time, up to [`MAX_BACKOFF_FACTOR`] times the threshold.
+//!
+//! Times are unix milliseconds, so this works the same on every platform.
+
+use std::collections::{BTreeMap, HashSet};
+use std::time::Duration;
+
+use libp2p::gossipsub::TopicHash;
+use libp2p::PeerId;
+
+/// How long a topic may have no mesh peers while we are connected before it is
+/// resubscribed.
+pub const DEFAULT_UNHEALTHY_AFTER: Duration = Duration::from_secs(30);
+
+/// How long after the first connection topics are left alone, while the mesh forms.
+pub const DEFAULT_STARTUP_GRACE: Duration = Duration::from_secs(30);
+
+/// How often topics are checked, at most.
+pub const TOPIC_HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Retries wait at most this many times the threshold.
+pub const MAX_BACKOFF_FACTOR: u32 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TopicHealthConfig {
+    pub unhealthy_after: Duration,
+    pub startup_grace: Duration,
+}
+
+impl Default for TopicHealthConfig {
+    fn default() -> Self {
+        Self { unhealthy_after: DEFAULT_UNHEALTHY_AFTER, startup_grace: DEFAULT_STARTUP_GRACE }
+    }
+}
+
+impl TopicHealthConfig {
+    /// Often enough to act within about a quarter of the threshold.
+    pub fn check_interval(&self) -> Duration {
+        (self.unhealthy_after / 4).clamp(Duration::from_millis(100), TOPIC_HEALTH_CHECK_INTERVAL)
+    }
+}
+
+/// A change reported by [`TopicHealth::check`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TopicChange {
+    /// `topic` has had no mesh peers since `empty_since_ms`; unsubscribe from it now and
+    /// subscribe again once [`TopicHealth::take_resubscribes`] returns it. `attempt`
+    /// counts the cycles since it was last healthy, from 1.
+    Unhealthy { topic: TopicHash, empty_since_ms: u64, attempt: u32 },
+    /// `topic` has mesh peers again, after `attempts` resubscribe cycles; it was unhealthy
+    /// for `duration` from `since_ms`.
+    Recovered { topic: TopicHash, since_ms: u64, duration: Duration, attempts: u32 },
+}
+
+#[derive(Debug, Clone, Default)]
+struct TopicState {
+    /// Since when the topic has had no mesh peers while connected.
+    empty_since_ms: Option<u64>,
+    /// Since when the topic has been unhealthy, once it was reported.
+    unhealthy_since_ms: Option<u64>,
+    attempts: u32,
+    /// Set while unsubscribed for a cycle: when to subscribe again.
+    resubscribe_at_ms: Option<u64>,
+}
+
+/// See the module docs.
+#[derive(Debug)]
+pub struct TopicHealth {
+    config: TopicHealthConfig,
+    /// How long to stay unsubscribed: one gossipsub heartbeat.
+    heartbeat: Duration,
+    first_connected_ms: Option<u64>,
+    topics: BTreeMap<TopicHash, TopicState>,
+    explicit: HashSet<PeerId>,
+}
+
+impl TopicHealth {
+    pub fn new(config: TopicHealthConfig, heartbeat: Duration) -> Self {
+        Self { config, heartbeat, first_connected_ms: None, topics: BTreeMap::new(), explicit: HashSet::new() }
+    }
+
+    pub fn config(&self) -> &TopicHealthConfig {
+        &self.config
+    }
+
+    /// Watch `topic`, which we subscribed to.
+    pub fn watch(&mut self, topic: TopicHash) {
+        self.topics.entry(topic).or_default();
+    }
+
+    /// Stop watching `topic`, which we left.
+    pub fn unwatch(&mut self, topic: &TopicHash) {
+        self.topics.remove(topic);
+    }
+
+    /// A connection came up; the first one starts the grace period.
+    pub fn connected(&mut self, now_ms: u64) {
+        self.first_connected_ms.get_or_insert(now_ms);
+    }
+
+    /// `peer` was made an explicit gossipsub peer.
+    pub fn add_explicit(&mut self, peer: PeerId) {
+        self.explicit.insert(peer);
+    }
+
+    /// `peer` is no longer an explicit gossipsub peer.
+    pub fn remove_explicit(&mut self, peer: &PeerId) {
+        self.explicit.remove(peer);
+    }
+
+    /// The topics reported unhealthy and not yet recovered.
+    pub fn unhealthy(&self) -> Vec<TopicHash> {
+        self.topics.iter().filter(|(_, state)| state.unhealthy_since_ms.is_some()).map(|(topic, _)| topic.clone()).collect()
+    }
+
+    /// Check every watched topic. `meshed` tells whether a topic has mesh peers or one of
+    /// the explicit peers it is given is subscribed to it; `connected` whether any
+    /// connection is up.
+    pub fn check(
+        &mut self,
+        now_ms: u64,
+        connected: bool,
+        meshed: impl Fn(&TopicHash, &HashSet<PeerId>) -> bool,
+    ) -> Vec<TopicChange> {
+        let grace_ms = self.config.startup_grace.as_millis() as u64;
+        let in_grace = !self.first_connected_ms.is_some_and(|first| now_ms >= first + grace_ms);
+        let mut changes = Vec::new();
+        for (topic, state) in &mut self.topics {
+            // Unsubscribed on purpose until the heartbeat passed
+            if state.resubscribe_at_ms.is_some() {
+                continue;
+            }
+            if meshed(topic, &self.explicit) {
+                state.empty_since_ms = None;
+                if let Some(since_ms) = state.unhealthy_since_ms.take() {
+                    let duration = Duration::from_millis(now_ms.saturating_sub(since_ms));
+                    changes.push(TopicChange::Recovered { topic: topic.clone(), since_ms, duration, attempts: state.attempts });
+                }
+                state.attempts = 0;
+                continue;
+            }
+            if !connected || in_grace {
+                // An empty mesh is expected; start counting once connected
+                state.empty_since_ms = None;
+                continue;
+            }
+            let empty_since_ms = *state.empty_since_ms.get_or_insert(now_ms);
+            let factor = 2u32.saturating_pow(state.attempts).min(MAX_BACKOFF_FACTOR);
+            let wait_ms = (self.config.unhealthy_after * factor).as_millis() as u64;
+            if now_ms < empty_since_ms + wait_ms {
+                continue;
+            }
+            state.attempts += 1;
+            state.unhealthy_since_ms.get_or_insert(empty_since_ms);
+            state.resubscribe_at_ms = Some(now_ms + self.heartbeat.as_millis() as u64);
+            changes.push(TopicChange::Unhealthy { topic: topic.clone(), empty_since_ms, attempt: state.attempts });
+        }
+        changes
+    }
+
+    /// When the next topic is due to be subscribed again.
+    pub fn next_resubscribe_ms(&self) -> Option<u64> {
+        self.topics.values().filter_map(|state| state.resubscribe_at_ms).min()
+    }
+
+    /// The topics to subscribe to again now. The mesh gets another, longer wait to form
+    /// from here.
+    pub fn take_resubscribes(&mut self, now_ms: u64) -> Vec<TopicHash> {
+        let mut due = Vec::new();
+        for (topic, state) in &mut self.topics {
+            if state.resubscribe_at_ms.is_some_and(|at| at <= now_ms) {
+                state.resubscribe_at_ms = None;
+                state.empty_since_ms = Some(now_ms);
+                due.push(topic.clone());
+            }
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEC: u64 = 1000;
+
+    fn health() -> TopicHealth {
+        let config = TopicHealthConfig { unhealthy_after: Duration::from_secs(10), startup_grace: Duration::from_secs(5) };
+        let mut health = TopicHealth::new(config, Duration::from_secs(1));
+        health.watch(TopicHash::from_raw("updates"));
+        health
+    }
+
+    #[test]
+    fn resubscribes_a_topic_left_without_mesh_and_reports_recovery() {
+        let mut health = health();
+        let empty = |_: &TopicHash, _: &HashSet<PeerId>| false;
+        // Not connected yet, then within the grace period: nothing happens
+        assert!(health.check(0, false, empty).is_empty());
+        health.connected(SEC);
+        assert!(health.check(4 * SEC, true, empty).is_empty());
+
+        // Counting starts after the grace period
+        assert!(health.check(6 * SEC, true, empty).is_empty());
+        assert!(health.check(15 * SEC, true, empty).is_empty());
+        let topic = TopicHash::from_raw("updates");
+        assert_eq!(
+            health.check(16 * SEC, true, empty),
+            vec![TopicChange::Unhealthy { topic: topic.clone(), empty_since_ms: 6 * SEC, attempt: 1 }]
+        );
+        assert_eq!(health.unhealthy(), vec![topic.clone()]);
+
+        // Left alone while unsubscribed, then subscribed again a heartbeat later
+        assert!(health.check(16 * SEC + 500, true, empty).is_empty());
+        assert!(health.take_resubscribes(16 * SEC + 500).is_empty());
+        assert_eq!(health.next_resubscribe_ms(), Some(17 * SEC));
+        assert_eq!(health.take_resubscribes(17 * SEC), vec![topic.clone()]);
+
+        // Still empty: the next attempt waits twice as long
+        assert!(health.check(27 * SEC, true, empty).is_empty());
+        assert!(matches!(health.check(37 * SEC, true, empty).as_slice(), [TopicChange::Unhealthy { attempt: 2, .. }]));
+        health.take_resubscribes(38 * SEC);
+
+        let meshed = |_: &TopicHash, _: &HashSet<PeerId>| true;
+        assert_eq!(
+            health.check(40 * SEC, true, meshed),
+            vec![TopicChange::Recovered { topic, since_ms: 6 * SEC, duration: Duration::from_secs(34), attempts: 2 }]
+        );
+        assert!(health.unhealthy().is_empty());
+        assert!(health.check(41 * SEC, true, meshed).is_empty());
+    }
+
+    #[test]
+    fn explicit_peers_and_disconnects_keep_a_topic_healthy() {
+        let mut health = health();
+        health.connected(0);
+        let relay = PeerId::random();
+        health.add_explicit(relay);
+        // Only the explicit relay is subscribed, and it never joins the mesh
+        let via_explicit = |_: &TopicHash, explicit: &HashSet<PeerId>| explicit.contains(&relay);
+        assert!(health.check(10 * SEC, true, via_explicit).is_empty());
+        assert!(health.check(60 * SEC, true, via_explicit).is_empty());
+
+        // Not connected: an empty mesh is expected and the wait starts over on reconnect
+        health.remove_explicit(&relay);
+        assert!(health.check(61 * SEC, false, via_explicit).is_empty());
+        assert!(health.check(80 * SEC, true, via_explicit).is_empty());
+        assert!(health.check(89 * SEC, true, via_explicit).is_empty());
+        assert_eq!(health.check(90 * SEC, true, via_explicit).len(), 1);
+    }
+}
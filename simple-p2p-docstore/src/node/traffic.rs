@@ -0,0 +1,306 @@
+//! Gossipsub traffic accounting: messages and bytes in and out, per topic and per peer.
+//!
+//! Counters are plain atomics behind a shared handle, so the node handle can read them
+//! while the event loop updates them. Every copy of a message counts: a publish reaching
+//! five subscribers adds five messages to `messages_out`, so the totals always equal the
+//! sum over peers (and the sum over topics).
+//!
+//! Incoming messages whose author is not the peer that delivered them are also counted as
+//! `relayed_in`. Messages we forward for others after validating them are counted as
+//! `forwarded`; both are subsets of the plain in/out figures.
+//!
+//! Established connections are counted per transport (see
+//! [`crate::node::addrs::transport_name`]), to show which transports peers actually use.
+//!
+//! Received and published messages are also reported to the node's
+//! [`Observer`](crate::node::observer), which the event loops reach through
+//! [`TrafficStats::observer`], and spent from its
+//! [`BandwidthBudget`](crate::node::budget), which [`TrafficStats::budget`] reaches.
+//! While a session is being recorded they go to the
+//! [`SessionRecorder`](crate::node::recorder) of [`TrafficStats::recorder`] as well.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use libp2p::gossipsub::{self, IdentTopic, MessageId, PublishError, TopicHash};
+use libp2p::PeerId;
+use serde::Serialize;
+
+use super::budget::BandwidthBudget;
+use super::observer::{MessageInfo, Observer, PublishInfo};
+use super::recorder::SessionRecorder;
+
+/// Point-in-time counter values.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct TrafficCounts {
+    pub messages_in: u64,
+    pub bytes_in: u64,
+    pub messages_out: u64,
+    pub bytes_out: u64,
+    /// Received messages delivered by a peer other than their author.
+    pub relayed_messages_in: u64,
+    pub relayed_bytes_in: u64,
+    /// Sent messages that other peers authored.
+    pub forwarded_messages: u64,
+    pub forwarded_bytes: u64,
+}
+
+/// Everything counted since start or the last [`TrafficStats::reset`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct TrafficSnapshot {
+    pub total: TrafficCounts,
+    pub topics: BTreeMap<String, TrafficCounts>,
+    /// Keyed by base58 peer id.
+    pub peers: BTreeMap<String, TrafficCounts>,
+    /// Connections established, keyed by transport name.
+    pub connections_by_transport: BTreeMap<String, u64>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    messages_in: AtomicU64,
+    bytes_in: AtomicU64,
+    messages_out: AtomicU64,
+    bytes_out: AtomicU64,
+    relayed_messages_in: AtomicU64,
+    relayed_bytes_in: AtomicU64,
+    forwarded_messages: AtomicU64,
+    forwarded_bytes: AtomicU64,
+}
+
+impl Counters {
+    fn add_in(&self, bytes: u64, relayed: bool) {
+        self.messages_in.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in.fetch_add(bytes, Ordering::Relaxed);
+        if relayed {
+            self.relayed_messages_in.fetch_add(1, Ordering::Relaxed);
+            self.relayed_bytes_in.fetch_add(bytes, Ordering::Relaxed);
+        }
+    }
+
+    fn add_out(&self, copies: u64, bytes: u64, forwarded: bool) {
+        self.messages_out.fetch_add(copies, Ordering::Relaxed);
+        self.bytes_out.fetch_add(copies * bytes, Ordering::Relaxed);
+        if forwarded {
+            self.forwarded_messages.fetch_add(copies, Ordering::Relaxed);
+            self.forwarded_bytes.fetch_add(copies * bytes, Ordering::Relaxed);
+        }
+    }
+
+    fn load(&self) -> TrafficCounts {
+        TrafficCounts {
+            messages_in: self.messages_in.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            messages_out: self.messages_out.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            relayed_messages_in: self.relayed_messages_in.load(Ordering::Relaxed),
+            relayed_bytes_in: self.relayed_bytes_in.load(Ordering::Relaxed),
+            forwarded_messages: self.forwarded_messages.load(Ordering::Relaxed),
+            forwarded_bytes: self.forwarded_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    total: Counters,
+    // Locked only to look up or insert an entry, never while counting or across an await
+    topics: Mutex<HashMap<TopicHash, Arc<Counters>>>,
+    peers: Mutex<HashMap<PeerId, Arc<Counters>>>,
+    connections: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+/// What [`TrafficStats::publish`] sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Published {
+    pub msg_id: MessageId,
+    /// Known subscribers of the topic at publish time. Empty means gossipsub accepted the
+    /// message but handed it to nobody.
+    pub sent_to: Vec<PeerId>,
+}
+
+/// Shared traffic counters. Cheap to clone; clones count into the same totals.
+#[derive(Debug, Clone, Default)]
+pub struct TrafficStats {
+    inner: Arc<Inner>,
+    observer: Observer,
+    budget: BandwidthBudget,
+    recorder: SessionRecorder,
+}
+
+impl TrafficStats {
+    /// Report what is counted to `observer` as well.
+    pub fn with_observer(mut self, observer: Observer) -> Self {
+        self.observer = observer;
+        self
+    }
+
+    pub fn observer(&self) -> &Observer {
+        &self.observer
+    }
+
+    /// The bandwidth budget counted traffic is spent from. Not cleared by [`TrafficStats::reset`].
+    pub fn budget(&self) -> &BandwidthBudget {
+        &self.budget
+    }
+
+    /// Records the session once started. Not cleared by [`TrafficStats::reset`].
+    pub fn recorder(&self) -> &SessionRecorder {
+        &self.recorder
+    }
+
+    /// Count a message received on `topic` from `propagation_source`.
+    pub fn record_in(&self, message: &gossipsub::Message, propagation_source: &PeerId) {
+        let bytes = message.data.len() as u64;
+        let relayed = message.source.is_some_and(|author| author != *propagation_source);
+        self.inner.total.add_in(bytes, relayed);
+        self.topic(&message.topic).add_in(bytes, relayed);
+        self.peer(propagation_source).add_in(bytes, relayed);
+        self.budget.spend(bytes);
+        self.observer.message_received(MessageInfo::new(message, propagation_source));
+        self.recorder.inbound(message, propagation_source, crate::store::now_ms());
+    }
+
+    /// Count one copy of a `bytes`-long message on `topic` sent to each of `recipients`.
+    pub fn record_out(&self, topic: &TopicHash, bytes: usize, recipients: &[PeerId], forwarded: bool) {
+        let (bytes, copies) = (bytes as u64, recipients.len() as u64);
+        self.inner.total.add_out(copies, bytes, forwarded);
+        self.topic(topic).add_out(copies, bytes, forwarded);
+        for peer in recipients {
+            self.peer(peer).add_out(1, bytes, forwarded);
+        }
+        self.budget.spend(copies * bytes);
+    }
+
+    /// Publish `data` and count it as sent to every known subscriber of `topic`, which is
+    /// where gossipsub's flood publishing delivers it.
+    pub fn publish(
+        &self,
+        beh: &mut gossipsub::Behaviour,
+        topic: IdentTopic,
+        data: Vec<u8>,
+    ) -> Result<Published, PublishError> {
+        let hash = topic.hash();
+        let bytes = data.len();
+        let recorded = self.recorder.wants_outbound().then(|| data.clone());
+        let result = beh.publish(topic, data).map(|msg_id| {
+            let sent_to = crate::behaviour::docstore::topic_peers(beh, &hash);
+            self.record_out(&hash, bytes, &sent_to, false);
+            Published { msg_id, sent_to }
+        });
+        if let (Ok(_), Some(data)) = (&result, recorded) {
+            self.recorder.outbound(&hash, data, crate::store::now_ms());
+        }
+        self.observer.publish(PublishInfo { topic: &hash, size: bytes, result: result.as_ref() });
+        result
+    }
+
+    /// Count an accepted message that gossipsub forwards to the rest of our mesh, i.e.
+    /// every mesh peer except the one that delivered it and its author.
+    pub fn record_forward(&self, beh: &gossipsub::Behaviour, message: &gossipsub::Message, propagation_source: &PeerId) {
+        let recipients: Vec<PeerId> = beh
+            .mesh_peers(&message.topic)
+            .filter(|p| *p != propagation_source && Some(**p) != message.source)
+            .copied()
+            .collect();
+        if !recipients.is_empty() {
+            self.record_out(&message.topic, message.data.len(), &recipients, true);
+        }
+    }
+
+    /// Count a connection established over `transport`.
+    pub fn record_connection(&self, transport: &'static str) {
+        *self.inner.connections.lock().expect("traffic lock").entry(transport).or_default() += 1;
+    }
+
+    pub fn snapshot(&self) -> TrafficSnapshot {
+        let topics = self.inner.topics.lock().expect("traffic lock").clone();
+        let peers = self.inner.peers.lock().expect("traffic lock").clone();
+        let connections = self.inner.connections.lock().expect("traffic lock");
+        TrafficSnapshot {
+            total: self.inner.total.load(),
+            topics: topics.iter().map(|(t, c)| (t.to_string(), c.load())).collect(),
+            peers: peers.iter().map(|(p, c)| (p.to_string(), c.load())).collect(),
+            connections_by_transport: connections.iter().map(|(t, n)| (t.to_string(), *n)).collect(),
+        }
+    }
+
+    /// Start counting from zero. Counts racing with the reset may land on either side of it.
+    pub fn reset(&self) {
+        let total = &self.inner.total;
+        for counter in [
+            &total.messages_in,
+            &total.bytes_in,
+            &total.messages_out,
+            &total.bytes_out,
+            &total.relayed_messages_in,
+            &total.relayed_bytes_in,
+            &total.forwarded_messages,
+            &total.forwarded_bytes,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+        self.inner.topics.lock().expect("traffic lock").clear();
+        self.inner.peers.lock().expect("traffic lock").clear();
+        self.inner.connections.lock().expect("traffic lock").clear();
+    }
+
+    fn topic(&self, topic: &TopicHash) -> Arc<Counters> {
+        self.inner.topics.lock().expect("traffic lock").entry(topic.clone()).or_default().clone()
+    }
+
+    fn peer(&self, peer: &PeerId) -> Arc<Counters> {
+        self.inner.peers.lock().expect("traffic lock").entry(*peer).or_default().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(topic: &str, source: Option<PeerId>, len: usize) -> gossipsub::Message {
+        gossipsub::Message {
+            source,
+            data: vec![0; len],
+            sequence_number: None,
+            topic: TopicHash::from_raw(topic),
+        }
+    }
+
+    #[test]
+    fn totals_match_per_peer_and_per_topic_sums() {
+        let stats = TrafficStats::default();
+        let (a, b, author) = (PeerId::random(), PeerId::random(), PeerId::random());
+
+        stats.record_in(&message("t1", Some(a), 10), &a);
+        stats.record_in(&message("t2", Some(author), 5), &b);
+        stats.record_out(&TopicHash::from_raw("t1"), 7, &[a, b], false);
+
+        let snap = stats.snapshot();
+        assert_eq!(snap.total.messages_in, 2);
+        assert_eq!(snap.total.bytes_in, 15);
+        assert_eq!(snap.total.relayed_messages_in, 1);
+        assert_eq!(snap.total.relayed_bytes_in, 5);
+        assert_eq!(snap.total.messages_out, 2);
+        assert_eq!(snap.total.bytes_out, 14);
+        assert_eq!(snap.topics["t1"].bytes_out, 14);
+        assert_eq!(snap.peers[&a.to_string()].bytes_in, 10);
+        assert_eq!(snap.peers[&b.to_string()].bytes_out, 7);
+
+        let peer_sum: u64 = snap.peers.values().map(|c| c.bytes_in + c.bytes_out).sum();
+        let topic_sum: u64 = snap.topics.values().map(|c| c.bytes_in + c.bytes_out).sum();
+        assert_eq!(peer_sum, snap.total.bytes_in + snap.total.bytes_out);
+        assert_eq!(topic_sum, peer_sum);
+
+        stats.record_connection("tcp");
+        stats.record_connection("relay");
+        stats.record_connection("tcp");
+        let by_transport = stats.snapshot().connections_by_transport;
+        assert_eq!(by_transport, BTreeMap::from([("relay".to_string(), 1), ("tcp".to_string(), 2)]));
+
+        let clone = stats.clone();
+        clone.reset();
+        assert_eq!(stats.snapshot(), TrafficSnapshot::default());
+    }
+}
@@ -0,0 +1,601 @@
+//! A deterministic virtual network for protocol tests.
+//!
+//! Nodes are stores with their clocks, wired together by simulated links rather than
+//! libp2p swarms: swarms run on real timers and tasks, so no two runs interleave the
+//! same way. Here every message goes through one queue ordered by virtual delivery time,
+//! and every latency, loss and peer choice is drawn from one RNG seeded by the test, so
+//! a seed replays a run event for event.
+//!
+//! The nodes speak the docstore protocols in miniature. Updates are flooded to every
+//! neighbour, as gossipsub would. A node that receives an update whose stamp depends on
+//! updates it never applied asks the sender for the gap. And every
+//! [`SimConfig::anti_entropy_interval_ms`] each node sends a random peer its
+//! [`HeadDigest`], so documents nobody writes to anymore still converge.
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ops::RangeInclusive;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::behaviour::docstore::{DocUpdate, Hlc, HlcClock, Stamp, UpdateOrder, VectorClock};
+use crate::store::{DocStore, MemoryDocStore, MergePolicy};
+use crate::sync::digest::{compare, HeadDigest};
+
+/// Position of a node in the simulation.
+pub type NodeIndex = usize;
+
+/// How messages travel from one node to another.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Link {
+    /// Delivery delay in virtual milliseconds, drawn uniformly from this range.
+    pub latency_ms: RangeInclusive<u64>,
+    /// Chance that a message is lost, between 0 and 1.
+    pub drop_probability: f64,
+}
+
+impl Default for Link {
+    fn default() -> Self {
+        Self { latency_ms: 10..=50, drop_probability: 0.0 }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimConfig {
+    /// Every link not set with [`Sim::set_link`].
+    pub link: Link,
+    /// How often each node starts an anti-entropy exchange; `None` to rely on gossip and
+    /// gap re-requests alone.
+    pub anti_entropy_interval_ms: Option<u64>,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self { link: Link::default(), anti_entropy_interval_ms: Some(1000) }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Message {
+    Gossip(DocUpdate),
+    Digest(HeadDigest),
+    /// The updates to `doc_id` beyond `have`, see [`contiguous`].
+    Pull { doc_id: String, have: VectorClock },
+    Repair(Vec<DocUpdate>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MessageKind {
+    Gossip,
+    Digest,
+    Pull,
+    Repair,
+}
+
+impl Message {
+    fn kind(&self) -> MessageKind {
+        match self {
+            Message::Gossip(_) => MessageKind::Gossip,
+            Message::Digest(_) => MessageKind::Digest,
+            Message::Pull { .. } => MessageKind::Pull,
+            Message::Repair(_) => MessageKind::Repair,
+        }
+    }
+}
+
+/// What happened in a run, in order. Equal seeds give equal traces.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SimEvent {
+    Delivered { at_ms: u64, from: NodeIndex, to: NodeIndex, kind: MessageKind },
+    /// Lost on the link, or cut off by a partition while in flight.
+    Dropped { at_ms: u64, from: NodeIndex, to: NodeIndex, kind: MessageKind, partitioned: bool },
+    Applied { at_ms: u64, node: NodeIndex, doc_id: String, hlc: Hlc },
+    /// `node` received an update from `from` that depends on updates it never applied,
+    /// and asked `from` for them.
+    GapDetected { at_ms: u64, node: NodeIndex, from: NodeIndex, doc_id: String },
+    /// `node` compared its digest with `peer`'s; it pulls the documents that differ.
+    Compared { at_ms: u64, node: NodeIndex, peer: NodeIndex, differing: Vec<String> },
+}
+
+/// One step of a scripted scenario, see [`Sim::run_script`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    Edit { node: NodeIndex, doc_id: String, payload: Vec<u8> },
+    /// See [`Sim::partition`].
+    Partition(Vec<Vec<NodeIndex>>),
+    Heal,
+    /// Let this many virtual milliseconds pass.
+    Advance(u64),
+}
+
+#[derive(Debug)]
+enum Scheduled {
+    Deliver { from: NodeIndex, to: NodeIndex, message: Message },
+    AntiEntropy { node: NodeIndex },
+}
+
+#[derive(Debug)]
+struct SimNode {
+    store: MemoryDocStore,
+    clock: HlcClock,
+    digest: HeadDigest,
+}
+
+/// The simulated network, see the [module docs](self).
+#[derive(Debug)]
+pub struct Sim {
+    config: SimConfig,
+    rng: StdRng,
+    now_ms: u64,
+    nodes: Vec<SimNode>,
+    links: HashMap<(NodeIndex, NodeIndex), Link>,
+    /// Each node's group while partitioned; `None` for nodes cut off from everyone.
+    groups: Option<Vec<Option<usize>>>,
+    /// Keyed by delivery time, then by scheduling order.
+    queue: BTreeMap<(u64, u64), Scheduled>,
+    scheduled: u64,
+    trace: Vec<SimEvent>,
+}
+
+impl Sim {
+    /// `nodes` nodes, fully connected, at virtual time 0.
+    pub fn new(nodes: usize, seed: u64, config: SimConfig) -> Self {
+        let mut sim = Self {
+            config,
+            rng: StdRng::seed_from_u64(seed),
+            now_ms: 0,
+            nodes: (0..nodes)
+                .map(|n| SimNode {
+                    store: MemoryDocStore::default(),
+                    clock: HlcClock::new(n as u64 + 1),
+                    digest: HeadDigest::default(),
+                })
+                .collect(),
+            links: HashMap::new(),
+            groups: None,
+            queue: BTreeMap::new(),
+            scheduled: 0,
+            trace: Vec::new(),
+        };
+        if let Some(interval) = sim.config.anti_entropy_interval_ms {
+            for node in 0..nodes {
+                // Spread out, so the nodes don't all exchange digests at once
+                let first = sim.rng.gen_range(1..=interval);
+                sim.schedule(first, Scheduled::AntiEntropy { node });
+            }
+        }
+        sim
+    }
+
+    pub fn now_ms(&self) -> u64 {
+        self.now_ms
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn store(&self, node: NodeIndex) -> &MemoryDocStore {
+        &self.nodes[node].store
+    }
+
+    pub fn trace(&self) -> &[SimEvent] {
+        &self.trace
+    }
+
+    /// Messages from `from` to `to` travel over `link` from now on. Links are one-way;
+    /// set both directions for a symmetric one.
+    pub fn set_link(&mut self, from: NodeIndex, to: NodeIndex, link: Link) {
+        self.links.insert((from, to), link);
+    }
+
+    /// On every node.
+    pub fn set_merge_policy(&mut self, doc_id: &str, policy: MergePolicy) {
+        for node in &mut self.nodes {
+            node.store.set_merge_policy(doc_id, policy);
+        }
+    }
+
+    /// Cut every link between nodes in different groups, including messages already in
+    /// flight. Nodes in no group are cut off from everyone.
+    pub fn partition(&mut self, groups: &[Vec<NodeIndex>]) {
+        let mut of = vec![None; self.nodes.len()];
+        for (group, nodes) in groups.iter().enumerate() {
+            for &node in nodes {
+                of[node] = Some(group);
+            }
+        }
+        self.groups = Some(of);
+    }
+
+    pub fn heal(&mut self) {
+        self.groups = None;
+    }
+
+    fn reachable(&self, from: NodeIndex, to: NodeIndex) -> bool {
+        self.groups.as_ref().is_none_or(|groups| groups[from].is_some() && groups[from] == groups[to])
+    }
+
+    /// `node` writes `payload` to `doc_id` and publishes it.
+    pub fn edit(&mut self, node: NodeIndex, doc_id: &str, payload: impl Into<Vec<u8>>) {
+        let n = &mut self.nodes[node];
+        let stamp = Stamp::next_at(&mut n.clock, &n.store.clock(doc_id), self.now_ms);
+        self.publish(node, DocUpdate::new(doc_id, payload).with_stamp(stamp));
+    }
+
+    /// Apply an update stamped elsewhere at `node` and gossip it, as a node would that
+    /// shares its author's key, or forwards a forged stamp.
+    pub fn publish(&mut self, node: NodeIndex, update: DocUpdate) {
+        self.apply(node, &update);
+        for peer in (0..self.nodes.len()).filter(|&peer| peer != node) {
+            self.send(node, peer, Message::Gossip(update.clone()));
+        }
+    }
+
+    pub fn run_script(&mut self, steps: impl IntoIterator<Item = Step>) {
+        for step in steps {
+            match step {
+                Step::Edit { node, doc_id, payload } => self.edit(node, &doc_id, payload),
+                Step::Partition(groups) => self.partition(&groups),
+                Step::Heal => self.heal(),
+                Step::Advance(ms) => self.advance(ms),
+            }
+        }
+    }
+
+    /// Handle the next scheduled event. False if there is none.
+    pub fn step(&mut self) -> bool {
+        let Some(((at_ms, _), scheduled)) = self.queue.pop_first() else {
+            return false;
+        };
+        self.now_ms = at_ms;
+        match scheduled {
+            Scheduled::Deliver { from, to, message } => {
+                let kind = message.kind();
+                if !self.reachable(from, to) {
+                    self.trace.push(SimEvent::Dropped { at_ms, from, to, kind, partitioned: true });
+                    return true;
+                }
+                self.trace.push(SimEvent::Delivered { at_ms, from, to, kind });
+                self.deliver(from, to, message);
+            }
+            Scheduled::AntiEntropy { node } => {
+                let interval = self.config.anti_entropy_interval_ms.expect("only scheduled with anti-entropy on");
+                self.schedule(interval, Scheduled::AntiEntropy { node });
+                if self.nodes.len() > 1 {
+                    // Partitioned peers are picked too; the digest is lost, as the dial would fail
+                    let peer = (node + self.rng.gen_range(1..self.nodes.len())) % self.nodes.len();
+                    self.send(node, peer, Message::Digest(self.nodes[node].digest.clone()));
+                }
+            }
+        }
+        true
+    }
+
+    /// Let `ms` virtual milliseconds pass, handling everything due by then.
+    pub fn advance(&mut self, ms: u64) {
+        let until = self.now_ms + ms;
+        while self.queue.first_key_value().is_some_and(|(&(at_ms, _), _)| at_ms <= until) {
+            self.step();
+        }
+        self.now_ms = until;
+    }
+
+    /// Run until every node holds the same documents, for at most `max_ms`. Returns
+    /// whether they converged.
+    pub fn settle(&mut self, max_ms: u64) -> bool {
+        let deadline = self.now_ms + max_ms;
+        while !self.converged() {
+            if !self.queue.first_key_value().is_some_and(|(&(at_ms, _), _)| at_ms <= deadline) {
+                self.now_ms = deadline;
+                return false;
+            }
+            self.step();
+        }
+        true
+    }
+
+    /// Every node applied the same updates to the same documents, and holds the same
+    /// content for last-writer-wins documents. Other policies leave the content to the
+    /// order of arrival, or to the application merging the log.
+    pub fn converged(&self) -> bool {
+        self.divergence().is_none()
+    }
+
+    #[track_caller]
+    pub fn assert_converged(&self) {
+        if let Some(divergence) = self.divergence() {
+            panic!("not converged at {}ms: {divergence}", self.now_ms);
+        }
+    }
+
+    fn divergence(&self) -> Option<String> {
+        let (first, rest) = self.nodes.split_first()?;
+        let expected = summary(&first.store);
+        for (n, node) in rest.iter().enumerate() {
+            let got = summary(&node.store);
+            if got != expected {
+                let doc_id = expected.keys().chain(got.keys()).find(|d| expected.get(*d) != got.get(*d))?;
+                return Some(format!("node {} differs from node 0 on {doc_id:?}", n + 1));
+            }
+        }
+        None
+    }
+
+    fn schedule(&mut self, delay_ms: u64, scheduled: Scheduled) {
+        self.queue.insert((self.now_ms + delay_ms, self.scheduled), scheduled);
+        self.scheduled += 1;
+    }
+
+    fn send(&mut self, from: NodeIndex, to: NodeIndex, message: Message) {
+        let link = self.links.get(&(from, to)).unwrap_or(&self.config.link);
+        let (latency_ms, drop_probability) = (link.latency_ms.clone(), link.drop_probability);
+        if self.rng.gen_bool(drop_probability) {
+            let kind = message.kind();
+            self.trace.push(SimEvent::Dropped { at_ms: self.now_ms, from, to, kind, partitioned: false });
+            return;
+        }
+        let delay = self.rng.gen_range(latency_ms);
+        self.schedule(delay, Scheduled::Deliver { from, to, message });
+    }
+
+    fn deliver(&mut self, from: NodeIndex, to: NodeIndex, message: Message) {
+        match message {
+            Message::Gossip(update) => {
+                if !self.apply(to, &update) {
+                    return;
+                }
+                for peer in (0..self.nodes.len()).filter(|&peer| peer != to && peer != from) {
+                    self.send(to, peer, Message::Gossip(update.clone()));
+                }
+                let stamp = update.stamp.as_ref().expect("simulated updates are stamped");
+                let have = contiguous(&self.nodes[to].store, &update.doc_id);
+                if !matches!(stamp.clock.compare(&have), Some(Ordering::Less | Ordering::Equal)) {
+                    let at_ms = self.now_ms;
+                    self.trace.push(SimEvent::GapDetected { at_ms, node: to, from, doc_id: update.doc_id.clone() });
+                    self.send(to, from, Message::Pull { doc_id: update.doc_id, have });
+                }
+            }
+            Message::Digest(theirs) => {
+                let differing = compare(&self.nodes[to].digest, &theirs).differing;
+                let at_ms = self.now_ms;
+                self.trace.push(SimEvent::Compared { at_ms, node: to, peer: from, differing: differing.clone() });
+                for doc_id in differing {
+                    let have = contiguous(&self.nodes[to].store, &doc_id);
+                    self.send(to, from, Message::Pull { doc_id, have });
+                }
+            }
+            Message::Pull { doc_id, have } => {
+                let missing: Vec<DocUpdate> = self.nodes[to]
+                    .store
+                    .log(&doc_id)
+                    .iter()
+                    .filter_map(|u| {
+                        let stamp = u.stamp.as_ref()?;
+                        let author = stamp.hlc.node;
+                        (stamp.clock.get(author) > have.get(author))
+                            .then(|| DocUpdate::new(doc_id.clone(), u.payload.clone()).with_stamp(stamp.clone()))
+                    })
+                    .collect();
+                if !missing.is_empty() {
+                    self.send(to, from, Message::Repair(missing));
+                }
+            }
+            Message::Repair(updates) => {
+                for update in &updates {
+                    self.apply(to, update);
+                }
+            }
+        }
+    }
+
+    /// Apply `update` at `node` unless it already did. Returns whether it was new.
+    fn apply(&mut self, node: NodeIndex, update: &DocUpdate) -> bool {
+        let stamp = update.stamp.as_ref().expect("simulated updates are stamped");
+        let n = &mut self.nodes[node];
+        let log = n.store.log(&update.doc_id);
+        if log.iter().any(|u| u.stamp.as_ref().is_some_and(|s| s.hlc == stamp.hlc) && u.payload == update.payload) {
+            return false;
+        }
+        n.clock.observe_at(&stamp.hlc, self.now_ms);
+        let version = n.store.apply_update(update);
+        n.digest.insert(update.doc_id.clone(), version);
+        let at_ms = self.now_ms;
+        self.trace.push(SimEvent::Applied { at_ms, node, doc_id: update.doc_id.clone(), hlc: stamp.hlc });
+        true
+    }
+}
+
+/// Per author, how many of its updates to `doc_id` the store applied without a hole.
+/// Unlike [`DocStore::clock`], which also counts the updates other stamps merely
+/// mention, this is what the store can vouch for.
+fn contiguous(store: &MemoryDocStore, doc_id: &str) -> VectorClock {
+    let mut seqs: BTreeMap<u64, BTreeSet<u64>> = BTreeMap::new();
+    for stamp in store.log(doc_id).iter().filter_map(|u| u.stamp.as_ref()) {
+        seqs.entry(stamp.hlc.node).or_default().insert(stamp.clock.get(stamp.hlc.node));
+    }
+    let mut clock = VectorClock::default();
+    for (author, seqs) in seqs {
+        for _ in seqs.iter().zip(1..).take_while(|(seq, n)| **seq == *n) {
+            clock.increment(author);
+        }
+    }
+    clock
+}
+
+/// Per document, the updates applied and, for last-writer-wins documents, the content.
+fn summary(store: &MemoryDocStore) -> BTreeMap<String, (Vec<UpdateOrder>, Option<Vec<u8>>)> {
+    store
+        .doc_ids()
+        .into_iter()
+        .map(|doc_id| {
+            let mut applied: Vec<UpdateOrder> =
+                store.log(&doc_id).iter().filter_map(|u| u.stamp.as_ref().map(|s| UpdateOrder::of(s, &u.payload))).collect();
+            applied.sort();
+            let content = (store.merge_policy(&doc_id) == MergePolicy::Lww).then(|| store.content(&doc_id)).flatten();
+            (doc_id, (applied, content))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edit(node: NodeIndex, doc_id: &str, payload: &str) -> Step {
+        Step::Edit { node, doc_id: doc_id.into(), payload: payload.into() }
+    }
+
+    fn lossy() -> SimConfig {
+        SimConfig { link: Link { latency_ms: 5..=200, drop_probability: 0.2 }, ..SimConfig::default() }
+    }
+
+    fn partitioned_run(seed: u64) -> Sim {
+        let mut sim = Sim::new(4, seed, lossy());
+        sim.set_merge_policy("title", MergePolicy::Lww);
+        sim.run_script([
+            edit(0, "title", "draft"),
+            Step::Advance(500),
+            Step::Partition(vec![vec![0, 1], vec![2, 3]]),
+            edit(0, "title", "left"),
+            edit(2, "title", "right"),
+            edit(3, "notes", "only on the right"),
+            Step::Advance(3000),
+            Step::Heal,
+        ]);
+        assert!(sim.settle(60_000), "seed {seed}");
+        sim
+    }
+
+    #[test]
+    fn the_same_seed_replays_the_same_run() {
+        let (a, b) = (partitioned_run(7), partitioned_run(7));
+        assert_eq!(a.trace(), b.trace());
+        assert_eq!(a.now_ms(), b.now_ms());
+        assert_eq!(a.store(3).content("title"), b.store(3).content("title"));
+        assert_ne!(partitioned_run(8).trace(), a.trace());
+    }
+
+    #[test]
+    fn anti_entropy_repairs_a_healed_partition() {
+        let mut sim = Sim::new(4, 1, SimConfig::default());
+        sim.set_merge_policy("title", MergePolicy::Lww);
+        sim.partition(&[vec![0, 1], vec![2, 3]]);
+        sim.edit(0, "title", "left");
+        sim.advance(100);
+        sim.edit(2, "title", "right");
+        sim.edit(3, "notes", "only on the right");
+        sim.advance(5000);
+        assert!(!sim.converged());
+        assert_eq!(sim.store(1).content("title"), Some(b"left".to_vec()));
+        assert_eq!(sim.store(1).version("notes"), 0);
+
+        // Nobody writes after the heal, so only the digest exchanges can find the difference
+        sim.heal();
+        let healed_at = sim.now_ms();
+        assert!(sim.settle(30_000));
+        sim.assert_converged();
+        assert_eq!(sim.store(1).content("title"), Some(b"right".to_vec()), "the later write wins");
+        assert_eq!(sim.store(0).content("notes"), Some(b"only on the right".to_vec()));
+        assert!(sim.trace().iter().any(|e| matches!(e,
+            SimEvent::Compared { at_ms, differing, .. } if *at_ms >= healed_at && !differing.is_empty())));
+
+        // Without anti-entropy the two sides never learn of each other's writes
+        let mut sim = Sim::new(4, 1, SimConfig { anti_entropy_interval_ms: None, ..SimConfig::default() });
+        sim.partition(&[vec![0, 1], vec![2, 3]]);
+        sim.edit(0, "title", "left");
+        sim.edit(2, "title", "right");
+        sim.advance(5000);
+        sim.heal();
+        assert!(!sim.settle(30_000));
+    }
+
+    #[test]
+    fn gaps_are_re_requested_from_the_sender() {
+        let mut sim = Sim::new(2, 3, SimConfig { anti_entropy_interval_ms: None, ..SimConfig::default() });
+        sim.set_merge_policy("doc", MergePolicy::Lww);
+        sim.set_link(0, 1, Link { drop_probability: 1.0, ..Link::default() });
+        for n in 1..=3 {
+            sim.edit(0, "doc", format!("v{n}"));
+            sim.advance(10);
+        }
+        sim.advance(1000);
+        assert_eq!(sim.store(1).version("doc"), 0);
+
+        // The next update depends on the three lost ones
+        sim.set_link(0, 1, Link::default());
+        sim.edit(0, "doc", "v4");
+        assert!(sim.settle(1000));
+        assert!(sim
+            .trace()
+            .iter()
+            .any(|e| matches!(e, SimEvent::GapDetected { node: 1, from: 0, doc_id, .. } if doc_id == "doc")));
+        assert_eq!(sim.store(1).version("doc"), 4);
+        assert_eq!(sim.store(1).content("doc"), Some(b"v4".to_vec()));
+    }
+
+    #[test]
+    fn crdt_edits_converge_over_lossy_links() {
+        let mut sim = Sim::new(5, 11, lossy());
+        // Every node adds to a grow-only set at the same time
+        for round in 0..10 {
+            for node in 0..5 {
+                sim.edit(node, "set", format!("{node}-{round}"));
+            }
+            if round == 4 {
+                sim.partition(&[vec![0, 1, 2], vec![3, 4]]);
+            }
+            sim.advance(30);
+        }
+        sim.heal();
+        assert!(sim.settle(120_000));
+        sim.assert_converged();
+
+        let all: BTreeSet<Vec<u8>> =
+            (0..10).flat_map(|round| (0..5).map(move |node| format!("{node}-{round}").into_bytes())).collect();
+        for node in 0..5 {
+            // What the application merges from the log
+            let merged: BTreeSet<Vec<u8>> = sim.store(node).log("set").iter().map(|u| u.payload.clone()).collect();
+            assert_eq!(merged, all, "node {node}");
+            assert_eq!(sim.store(node).version("set"), 50);
+        }
+    }
+
+    #[test]
+    fn equal_timestamps_resolve_the_same_everywhere() {
+        for seed in 0..20 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let config = SimConfig {
+                link: Link { latency_ms: 1..=300, drop_probability: 0.0 },
+                anti_entropy_interval_ms: None,
+            };
+            let mut sim = Sim::new(4, seed, config);
+            sim.set_merge_policy("title", MergePolicy::Lww);
+            // One author whose key several nodes share: every round, some of them publish
+            // different content under the very same stamp
+            let mut author = HlcClock::new(99);
+            let mut seen = VectorClock::default();
+            for round in 0..6 {
+                let stamp = Stamp::next_at(&mut author, &seen, sim.now_ms());
+                seen = stamp.clock.clone();
+                for node in (0..4).filter(|_| rng.gen_bool(0.6)) {
+                    let update = DocUpdate::new("title", format!("fork {round} by {node}")).with_stamp(stamp.clone());
+                    sim.publish(node, update);
+                }
+                // And everyone writes at the same wall time
+                for node in 0..4 {
+                    sim.edit(node, "title", format!("edit {round} by {node}"));
+                }
+                sim.advance(rng.gen_range(0..=200));
+            }
+            assert!(sim.settle(60_000), "seed {seed}");
+            sim.assert_converged();
+            let content = sim.store(0).content("title");
+            assert!(content.is_some());
+            for node in 1..4 {
+                assert_eq!(sim.store(node).content("title"), content, "seed {seed}, node {node}");
+            }
+        }
+    }
+}
@@ -0,0 +1,386 @@
+//! Local document state: each document's ordered update log and its latest snapshot.
+//!
+//! Updates carry the document's new content (whole-content replacement). Versions count
+//! the updates applied to a document; installing a snapshot jumps straight to the
+//! snapshot's version. Whether an update also replaces the content depends on its
+//! [`Stamp`] and the document's [`MergePolicy`]: unstamped updates and updates that
+//! causally follow the current state always do.
+
+#[cfg(not(target_arch = "wasm32"))]
+pub mod file;
+pub mod author_quota;
+pub mod memory;
+pub mod quota;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use file::FileDocStore;
+pub use memory::MemoryDocStore;
+
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use libp2p::PeerId;
+
+use serde::{Deserialize, Serialize};
+
+use crate::behaviour::docstore::{order, AuthorSeqs, DocUpdate, Freshness, Hlc, Snapshot, Stamp, VectorClock};
+use crate::behaviour::fetch::FetchManifest;
+
+/// How long updates already covered by a snapshot are kept by default, so peers that
+/// missed them can still re-request the gap.
+pub const DEFAULT_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How a document resolves stamped updates that are concurrent by vector clock.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MergePolicy {
+    /// The update with the highest HLC wins, whatever order updates arrive in.
+    Lww,
+    /// Keep the current content; concurrent updates are only logged, for the application
+    /// to resolve by publishing a follow-up update.
+    Manual,
+    /// Payloads are CRDT operations the application merges from the log; the content is
+    /// simply the latest arrival.
+    #[default]
+    Crdt,
+}
+
+/// An update as recorded in the log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredUpdate {
+    pub version: u64,
+    pub payload: Vec<u8>,
+    /// Unix milliseconds at which the update was applied locally.
+    pub applied_at_ms: u64,
+    pub stamp: Option<Stamp>,
+}
+
+/// What a compaction run removed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompactionReport {
+    pub removed_updates: usize,
+    pub reclaimed_bytes: usize,
+}
+
+/// What [`DocStore::verify`] found wrong with a stored document.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum Corruption {
+    #[error("snapshot does not match its content hash")]
+    Snapshot,
+    #[error("log record {index} does not match its digest")]
+    LogRecord { index: usize },
+    #[error("log holds {on_disk} records, {expected} expected")]
+    Truncated { on_disk: usize, expected: usize },
+    #[error("unreadable: {0}")]
+    Unreadable(String),
+}
+
+impl std::ops::AddAssign for CompactionReport {
+    fn add_assign(&mut self, other: Self) {
+        self.removed_updates += other.removed_updates;
+        self.reclaimed_bytes += other.reclaimed_bytes;
+    }
+}
+
+pub trait DocStore {
+    /// Apply an update and return the document's new version.
+    fn apply_update(&mut self, update: &DocUpdate) -> u64;
+
+    /// Apply updates to any number of documents as one write batch: after a crash the
+    /// store holds all of them or none. Returns each update's new version, in order.
+    ///
+    /// The default applies them one by one, which is enough for stores that lose
+    /// everything on a crash anyway.
+    fn apply_transaction(&mut self, updates: &[DocUpdate]) -> Vec<u64> {
+        updates.iter().map(|update| self.apply_update(update)).collect()
+    }
+
+    /// Current version of `doc_id`, `0` if unknown.
+    fn version(&self, doc_id: &str) -> u64;
+
+    fn content(&self, doc_id: &str) -> Option<Vec<u8>>;
+
+    /// Logged updates with a version greater than `version`, oldest first.
+    fn updates_since(&self, doc_id: &str, version: u64) -> Vec<StoredUpdate>;
+
+    /// The whole logged history of `doc_id` as retention left it, oldest first; empty if
+    /// unknown. Borrowed, for paging through long histories.
+    fn log(&self, doc_id: &str) -> &[StoredUpdate];
+
+    fn latest_snapshot(&self, doc_id: &str) -> Option<Snapshot>;
+
+    /// Snapshot `doc_id` at its current version, remembering it as the latest snapshot.
+    fn make_snapshot(&mut self, doc_id: &str) -> Option<Snapshot>;
+
+    /// Adopt a snapshot received from the network. Snapshots that fail verification or
+    /// are not newer than the local state are ignored; returns whether it was installed.
+    fn install_snapshot(&mut self, snapshot: Snapshot) -> bool;
+
+    fn doc_ids(&self) -> Vec<String>;
+
+    /// Drop logged updates that the latest snapshot already covers and that are older
+    /// than the store's retention window. Pinned documents are left untouched.
+    fn compact(&mut self, doc_id: &str) -> CompactionReport;
+
+    /// [`DocStore::compact`], keeping snapshotted updates for at most `retention` when
+    /// that is shorter than the store's own window (see [`quota`]).
+    ///
+    /// The default ignores `retention`, for stores with no window of their own.
+    fn compact_within(&mut self, doc_id: &str, _retention: Duration) -> CompactionReport {
+        self.compact(doc_id)
+    }
+
+    /// Keep `doc_id` in full (all history, never compacted). Returns false if it was
+    /// already pinned.
+    fn pin(&mut self, doc_id: &str) -> bool;
+
+    /// Returns false if `doc_id` was not pinned.
+    fn unpin(&mut self, doc_id: &str) -> bool;
+
+    fn pins(&self) -> Vec<String>;
+
+    fn is_pinned(&self, doc_id: &str) -> bool {
+        self.pins().iter().any(|p| p == doc_id)
+    }
+
+    fn merge_policy(&self, doc_id: &str) -> MergePolicy;
+
+    fn set_merge_policy(&mut self, doc_id: &str, policy: MergePolicy);
+
+    /// Vector clock of the stamped updates applied to `doc_id`; the basis for stamping
+    /// the next local update.
+    fn clock(&self, doc_id: &str) -> VectorClock;
+
+    /// Highest HLC applied to `doc_id` from the author with clock node id `author`.
+    fn author_hlc(&self, doc_id: &str, author: u64) -> Option<Hlc>;
+
+    /// Whether the update numbered `seq` (see [`Stamp::seq`]) of the author with clock
+    /// node id `author` was applied to `doc_id`.
+    fn has_applied(&self, doc_id: &str, author: u64, seq: u64) -> bool;
+
+    /// Replay check for an update received from `source` (the signed gossipsub author):
+    /// a stamped update must carry `source`'s clock and not be in a place of its
+    /// author's sequence already taken, however late it arrives. It is a repeat if the
+    /// log still holds it or it is the author's latest. Unstamped updates cannot be
+    /// checked and are let through.
+    fn freshness(&self, source: &PeerId, update: &DocUpdate) -> Freshness {
+        let Some(stamp) = &update.stamp else {
+            return Freshness::Fresh;
+        };
+        if !stamp.is_from(source) {
+            return Freshness::Forged;
+        }
+        if !self.has_applied(&update.doc_id, stamp.hlc.node, stamp.seq()) {
+            return Freshness::Fresh;
+        }
+        let logged = self.log(&update.doc_id).iter().any(|u| u.stamp.as_ref().is_some_and(|s| s.hlc == stamp.hlc));
+        if logged || self.author_hlc(&update.doc_id, stamp.hlc.node) == Some(stamp.hlc) {
+            Freshness::Repeat
+        } else {
+            Freshness::Stale
+        }
+    }
+
+    /// Whether [`freshness`](DocStore::freshness) lets `update` through.
+    fn is_fresh(&self, source: &PeerId, update: &DocUpdate) -> bool {
+        self.freshness(source, update) == Freshness::Fresh
+    }
+
+    /// Check what is stored for `doc_id` against the digests recorded when it was
+    /// written. Stores that keep nothing outside memory have nothing to check.
+    fn verify(&mut self, _doc_id: &str) -> Result<(), Corruption> {
+        Ok(())
+    }
+
+    /// Set a corrupted document aside: it is forgotten as if it was never stored, and
+    /// whatever is left of it is kept out of the way for inspection. Returns false if
+    /// `doc_id` is unknown or the store cannot quarantine.
+    fn quarantine(&mut self, _doc_id: &str) -> bool {
+        false
+    }
+
+    /// Keep a document fetch that is under way (see [`crate::node::fetch`]): its manifest
+    /// and the bytes verified so far, replacing whatever was kept for the document, so the
+    /// fetch resumes after a restart. Stores that keep nothing outside memory don't; the
+    /// node holds transfers in memory anyway.
+    fn start_partial_fetch(&mut self, _manifest: &FetchManifest, _verified: &[u8]) {}
+
+    /// Add bytes verified since to `doc_id`'s partial fetch.
+    fn append_partial_fetch(&mut self, _doc_id: &str, _data: &[u8]) {}
+
+    /// `doc_id`'s partial fetch as kept: the manifest and the bytes after it. The bytes
+    /// are checked again before they are trusted.
+    fn partial_fetch(&self, _doc_id: &str) -> Option<(FetchManifest, Vec<u8>)> {
+        None
+    }
+
+    fn clear_partial_fetch(&mut self, _doc_id: &str) {}
+
+    fn compact_all(&mut self) -> CompactionReport {
+        let mut report = CompactionReport::default();
+        for doc_id in self.doc_ids() {
+            report += self.compact(&doc_id);
+        }
+        report
+    }
+}
+
+pub(crate) fn now_ms() -> u64 {
+    web_time::SystemTime::now()
+        .duration_since(web_time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// State of one document, shared by the store implementations.
+#[derive(Debug, Default)]
+pub(crate) struct DocEntry {
+    pub version: u64,
+    pub content: Vec<u8>,
+    /// Version of the update (or snapshot) `content` comes from.
+    pub content_version: u64,
+    pub log: Vec<StoredUpdate>,
+    pub snapshot: Option<Snapshot>,
+    pub clock: VectorClock,
+    /// Highest HLC among the applied stamped updates.
+    pub last_hlc: Option<Hlc>,
+    /// Highest HLC applied per author node, for replay protection.
+    pub authors: BTreeMap<u64, Hlc>,
+    /// The updates applied per author node, for replay protection. Not persisted, see
+    /// [`DocEntry::rebuild_seqs`].
+    pub seqs: BTreeMap<u64, AuthorSeqs>,
+}
+
+impl DocEntry {
+    /// Log `update` and, if it wins under `policy`, make its payload the content.
+    pub fn apply(&mut self, update: &DocUpdate, policy: MergePolicy, now_ms: u64) -> &StoredUpdate {
+        self.advance(self.version + 1, update.stamp.as_ref(), &update.payload, policy);
+        self.log.push(StoredUpdate {
+            version: self.version,
+            payload: update.payload.clone(),
+            applied_at_ms: now_ms,
+            stamp: update.stamp.clone(),
+        });
+        self.log.last().expect("just pushed")
+    }
+
+    /// Apply a logged update again without logging it, to reconstruct a past state.
+    pub fn replay(&mut self, update: &StoredUpdate, policy: MergePolicy) {
+        self.advance(update.version, update.stamp.as_ref(), &update.payload, policy);
+    }
+
+    fn advance(&mut self, version: u64, stamp: Option<&Stamp>, payload: &[u8], policy: MergePolicy) {
+        self.version = version;
+        if self.supersedes(stamp, payload, policy) {
+            self.content = payload.to_vec();
+            self.content_version = version;
+        }
+        if let Some(stamp) = stamp {
+            self.clock.merge(&stamp.clock);
+            self.last_hlc = self.last_hlc.max(Some(stamp.hlc));
+            let seen = self.authors.entry(stamp.hlc.node).or_insert(stamp.hlc);
+            *seen = (*seen).max(stamp.hlc);
+            self.seqs.entry(stamp.hlc.node).or_default().insert(stamp.seq());
+        }
+    }
+
+    /// Work out which updates of each author were applied from the log and clock: those
+    /// logged, and every one older than the oldest logged, or all the clock has seen of
+    /// authors with nothing logged, as compaction only drops old updates.
+    pub fn rebuild_seqs(&mut self) {
+        let mut logged: BTreeMap<u64, Vec<u64>> = BTreeMap::new();
+        for stamp in self.log.iter().filter_map(|u| u.stamp.as_ref()) {
+            logged.entry(stamp.hlc.node).or_default().push(stamp.seq());
+        }
+        self.seqs = self
+            .authors
+            .keys()
+            .map(|&node| {
+                let seqs = logged.remove(&node).unwrap_or_default();
+                let mut applied = match seqs.iter().min() {
+                    Some(oldest) => AuthorSeqs::up_to(oldest.saturating_sub(1)),
+                    None => AuthorSeqs::up_to(self.clock.get(node)),
+                };
+                seqs.into_iter().for_each(|seq| applied.insert(seq));
+                (node, applied)
+            })
+            .collect();
+    }
+
+    fn supersedes(&self, stamp: Option<&Stamp>, payload: &[u8], policy: MergePolicy) -> bool {
+        let Some(stamp) = stamp else {
+            return true;
+        };
+        match (stamp.clock.compare(&self.clock), policy) {
+            (Some(Ordering::Greater), _) => true,
+            // The same clock with different content is a fork of one author's history, as
+            // concurrent as it gets
+            (None, MergePolicy::Lww) | (Some(Ordering::Equal), MergePolicy::Lww) => self.last_wins(stamp, payload),
+            // Already reflected in the current state
+            (Some(_), _) => false,
+            (None, MergePolicy::Manual) => false,
+            (None, MergePolicy::Crdt) => true,
+        }
+    }
+
+    /// Whether an update beats the current content in the total order of
+    /// [`order`](crate::behaviour::docstore::order). The content is the newest update's,
+    /// as nothing causally later can carry an older HLC.
+    fn last_wins(&self, stamp: &Stamp, payload: &[u8]) -> bool {
+        match self.last_hlc {
+            Some(last) => order::compare(&stamp.hlc, payload, &last, &self.content) == Ordering::Greater,
+            None => true,
+        }
+    }
+
+    pub fn make_snapshot(&mut self, doc_id: &str) -> Snapshot {
+        let snapshot = Snapshot::new(doc_id, self.version, self.content.clone());
+        self.snapshot = Some(snapshot.clone());
+        snapshot
+    }
+
+    /// Returns false if `snapshot` is invalid, not newer than this entry or further ahead
+    /// than [`MAX_VERSION_JUMP`](crate::behaviour::docstore::snapshot::MAX_VERSION_JUMP).
+    pub fn install_snapshot(&mut self, snapshot: Snapshot) -> bool {
+        let reach = self.version.saturating_add(crate::behaviour::docstore::snapshot::MAX_VERSION_JUMP);
+        if !snapshot.verify() || snapshot.version <= self.version || snapshot.version > reach {
+            return false;
+        }
+        self.version = snapshot.version;
+        self.content = snapshot.bytes.clone();
+        self.content_version = snapshot.version;
+        // Everything we had is older than the snapshot
+        self.log.clear();
+        self.snapshot = Some(snapshot);
+        true
+    }
+
+    /// The log compaction would leave behind, and what it would reclaim.
+    pub fn compacted_log(&self, retention: Duration, now_ms: u64) -> (Vec<StoredUpdate>, CompactionReport) {
+        let mut report = CompactionReport::default();
+        let Some(covered) = self.snapshot.as_ref().map(|s| s.version) else {
+            return (self.log.clone(), report);
+        };
+        let cutoff = now_ms.saturating_sub(retention.as_millis() as u64);
+        let kept = self
+            .log
+            .iter()
+            .filter(|u| {
+                let drop = u.version <= covered && u.applied_at_ms <= cutoff;
+                if drop {
+                    report.removed_updates += 1;
+                    report.reclaimed_bytes += u.payload.len();
+                }
+                !drop
+            })
+            .cloned()
+            .collect();
+        (kept, report)
+    }
+
+    pub fn compact(&mut self, retention: Duration, now_ms: u64) -> CompactionReport {
+        let (log, report) = self.compacted_log(retention, now_ms);
+        self.log = log;
+        report
+    }
+}
@@ -0,0 +1,473 @@
+//! Per-author quotas, so one peer cannot fill a shared FullNode with documents.
+//!
+//! Received updates are charged to the peer that signed the message carrying them. Stamps
+//! are only accepted from that peer (see [`DocStore::freshness`]), so a stamp's node id
+//! names the author too and usage can be counted again from the store after a restart.
+//! What an author uses is what the store still logs of theirs: the payload bytes of their
+//! updates and the documents holding at least one of them, so compaction frees it again.
+//! Unstamped updates cannot be traced back in the store, so their share is tallied as
+//! they arrive and kept until the node restarts. Updates applied per minute are counted
+//! as they arrive. All updates of a message are admitted together, so a batch cannot
+//! go over a quota that each of its updates alone would fit.
+//!
+//! Every author is held to the default [`AuthorQuota`] unless given one of their own.
+//! Received updates that would go over are ignored like those over a room quota (see
+//! [`super::quota`]); a node's own updates are not limited.
+
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use web_time::Instant;
+
+use super::quota::{QuotaExceeded, NOTICE_INTERVAL};
+use super::DocStore;
+use crate::behaviour::docstore::hlc::node_id;
+use crate::behaviour::docstore::DocUpdate;
+
+/// The window updates per minute are counted in.
+pub const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Authors `/metrics` lists one by one, biggest first; every author counts in the totals.
+pub const METRICS_AUTHORS: usize = 20;
+
+/// Limits for one author; unset ones don't apply.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AuthorQuota {
+    /// Bytes of the author's logged updates, across documents.
+    pub max_bytes: Option<u64>,
+    /// Documents holding logged updates of the author.
+    pub max_docs: Option<u64>,
+    pub max_updates_per_minute: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuthorUsage {
+    pub bytes: u64,
+    pub docs: u64,
+    /// Updates applied in the current [`RATE_WINDOW`].
+    pub updates_this_minute: u64,
+}
+
+/// One author's usage and quota, as [`AuthorQuotas::report`] lists them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuthorReport {
+    /// The author's peer id, or their clock's node id in hex until one of their messages
+    /// was seen since the start.
+    pub author: String,
+    pub usage: AuthorUsage,
+    pub quota: AuthorQuota,
+    /// Whether `quota` is the author's own rather than the default.
+    pub overridden: bool,
+}
+
+/// The file [`AuthorQuotas::from_file`] reads.
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct QuotaFile {
+    default: AuthorQuota,
+    peers: std::collections::BTreeMap<String, AuthorQuota>,
+}
+
+/// The configured quotas and what each author uses, kept up to date as the store
+/// changes. Authors are keyed by their clock's node id.
+#[derive(Debug, Default)]
+pub struct AuthorQuotas {
+    default: AuthorQuota,
+    overrides: HashMap<u64, AuthorQuota>,
+    /// The peer ids behind node ids, for reports.
+    peers: HashMap<u64, PeerId>,
+    /// Logged bytes per author of each stored document, so a change to one updates its
+    /// authors' totals.
+    doc_bytes: HashMap<String, HashMap<u64, u64>>,
+    usage: HashMap<u64, AuthorUsage>,
+    /// Payload bytes per document of the unstamped updates charged to each author.
+    unstamped: HashMap<u64, HashMap<String, u64>>,
+    /// Start of each author's current rate window and the updates applied in it.
+    rate: HashMap<u64, (u64, u64)>,
+    /// When each author's last over-quota notice went out.
+    notified: HashMap<u64, Instant>,
+}
+
+impl AuthorQuotas {
+    pub fn new(default: AuthorQuota, overrides: impl IntoIterator<Item = (PeerId, AuthorQuota)>) -> Self {
+        let mut quotas = Self { default, ..Default::default() };
+        for (peer, quota) in overrides {
+            quotas.set(Some(peer), Some(quota));
+        }
+        quotas
+    }
+
+    /// Read quotas from a JSON file holding the default and per-peer overrides, e.g.
+    /// `{"default": {"max_bytes": 10000000}, "peers": {"12D3...": {"max_bytes": null}}}`.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn from_file(path: &std::path::Path) -> std::io::Result<Self> {
+        let invalid = |e: String| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+        let file: QuotaFile = serde_json::from_slice(&std::fs::read(path)?).map_err(|e| invalid(e.to_string()))?;
+        let peers = file
+            .peers
+            .into_iter()
+            .map(|(peer, quota)| Ok((peer.parse().map_err(|e| invalid(format!("invalid peer id {peer:?}: {e}")))?, quota)))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        Ok(Self::new(file.default, peers))
+    }
+
+    /// Set `peer`'s own quota, or the default with `None`. Lifting a peer's quota (with
+    /// `quota` `None`) holds them to the default again; lifting the default leaves
+    /// everyone without one unlimited. Returns the previous one.
+    pub fn set(&mut self, peer: Option<PeerId>, quota: Option<AuthorQuota>) -> Option<AuthorQuota> {
+        let Some(peer) = peer else {
+            return Some(std::mem::replace(&mut self.default, quota.unwrap_or_default()));
+        };
+        let node = node_id(&peer);
+        self.peers.insert(node, peer);
+        match quota {
+            Some(quota) => self.overrides.insert(node, quota),
+            None => self.overrides.remove(&node),
+        }
+    }
+
+    pub fn default_quota(&self) -> &AuthorQuota {
+        &self.default
+    }
+
+    /// The quota `peer` is held to.
+    pub fn quota(&self, peer: &PeerId) -> &AuthorQuota {
+        self.quota_of(node_id(peer))
+    }
+
+    /// Remember the peer behind its node id, so reports and rejections name it.
+    pub fn learn(&mut self, peer: PeerId) {
+        self.peers.entry(node_id(&peer)).or_insert(peer);
+    }
+
+    pub fn usage(&self, peer: &PeerId, now_ms: u64) -> AuthorUsage {
+        self.usage_of(node_id(peer), now_ms)
+    }
+
+    /// Count every document of `store` from scratch. Rate windows and unstamped updates,
+    /// which the store cannot attribute, are left alone.
+    pub fn recount<S: DocStore + ?Sized>(&mut self, store: &S) {
+        self.doc_bytes.clear();
+        self.usage.clear();
+        for doc_id in store.doc_ids() {
+            self.observe(store, &doc_id);
+        }
+    }
+
+    /// Bring the shares of `doc_id`'s authors up to date after `store` changed it.
+    pub fn observe<S: DocStore + ?Sized>(&mut self, store: &S, doc_id: &str) {
+        let mut bytes: HashMap<u64, u64> = HashMap::new();
+        for update in store.log(doc_id) {
+            if let Some(stamp) = &update.stamp {
+                *bytes.entry(stamp.hlc.node).or_default() += update.payload.len() as u64;
+            }
+        }
+        let previous = if bytes.is_empty() {
+            self.doc_bytes.remove(doc_id)
+        } else {
+            self.doc_bytes.insert(doc_id.to_string(), bytes.clone())
+        };
+        for (node, previous) in previous.unwrap_or_default() {
+            let usage = self.usage.entry(node).or_default();
+            usage.bytes = usage.bytes.saturating_sub(previous);
+            usage.docs = usage.docs.saturating_sub(1);
+            if usage.bytes == 0 && usage.docs == 0 {
+                self.usage.remove(&node);
+            }
+        }
+        for (node, bytes) in bytes {
+            let usage = self.usage.entry(node).or_default();
+            usage.bytes += bytes;
+            usage.docs += 1;
+        }
+    }
+
+    /// Count `updates`, once applied, against the rate of `source`, the peer that signed
+    /// the message carrying them, and tally the unstamped ones as theirs.
+    pub fn charge(&mut self, source: &PeerId, updates: &[DocUpdate], now_ms: u64) {
+        let node = node_id(source);
+        let window_ms = RATE_WINDOW.as_millis() as u64;
+        let (started_ms, count) = self.rate.entry(node).or_insert((now_ms, 0));
+        if now_ms >= *started_ms + window_ms {
+            (*started_ms, *count) = (now_ms, 0);
+        }
+        *count += updates.len() as u64;
+        // Forget windows that are over, so authors who stopped publishing take no room
+        if self.rate.len() > 1024 {
+            self.rate.retain(|_, (started_ms, _)| now_ms < *started_ms + window_ms);
+        }
+        for update in updates.iter().filter(|u| u.stamp.is_none()) {
+            let docs = self.unstamped.entry(node).or_default();
+            *docs.entry(update.doc_id.clone()).or_default() += update.payload.len() as u64;
+        }
+    }
+
+    /// Whether storing `updates`, all signed by `source`, keeps their author within quota.
+    pub fn admit(&self, source: &PeerId, updates: &[DocUpdate], now_ms: u64) -> Result<(), QuotaExceeded> {
+        let node = node_id(source);
+        let quota = self.quota_of(node);
+        let usage = self.usage_of(node, now_ms);
+        let rate = usage.updates_this_minute + updates.len() as u64;
+        if let Some(max) = quota.max_updates_per_minute.filter(|max| rate > *max) {
+            return Err(QuotaExceeded::AuthorRate { author: self.name(node), max });
+        }
+        let new_docs: HashSet<&str> =
+            updates.iter().map(|u| u.doc_id.as_str()).filter(|doc_id| !self.holds(node, doc_id)).collect();
+        if let Some(max) = quota.max_docs.filter(|max| !new_docs.is_empty() && usage.docs + new_docs.len() as u64 > *max) {
+            return Err(QuotaExceeded::AuthorDocs { author: self.name(node), max });
+        }
+        let bytes = usage.bytes + updates.iter().map(|u| u.payload.len() as u64).sum::<u64>();
+        if let Some(max) = quota.max_bytes.filter(|max| bytes > *max) {
+            return Err(QuotaExceeded::AuthorBytes { author: self.name(node), bytes, max });
+        }
+        Ok(())
+    }
+
+    /// Whether `doc_id` holds updates of the author with node id `node`.
+    fn holds(&self, node: u64, doc_id: &str) -> bool {
+        self.doc_bytes.get(doc_id).is_some_and(|authors| authors.contains_key(&node))
+            || self.unstamped.get(&node).is_some_and(|docs| docs.contains_key(doc_id))
+    }
+
+    /// Whether a notice that `peer` is over quota should go out now, at most one per
+    /// [`NOTICE_INTERVAL`].
+    pub fn notice_due(&mut self, peer: &PeerId, now: Instant) -> bool {
+        let node = node_id(peer);
+        if self.notified.get(&node).is_some_and(|at| now.duration_since(*at) < NOTICE_INTERVAL) {
+            return false;
+        }
+        self.notified.insert(node, now);
+        true
+    }
+
+    /// Every author with logged updates or a quota of their own, the biggest first.
+    pub fn report(&self, now_ms: u64) -> Vec<AuthorReport> {
+        let mut nodes: Vec<u64> =
+            self.usage.keys().chain(self.unstamped.keys()).chain(self.overrides.keys()).copied().collect();
+        nodes.sort_unstable();
+        nodes.dedup();
+        let mut reports: Vec<AuthorReport> = nodes
+            .into_iter()
+            .map(|node| AuthorReport {
+                author: self.name(node),
+                usage: self.usage_of(node, now_ms),
+                quota: *self.quota_of(node),
+                overridden: self.overrides.contains_key(&node),
+            })
+            .collect();
+        reports.sort_by(|a, b| b.usage.bytes.cmp(&a.usage.bytes).then_with(|| a.author.cmp(&b.author)));
+        reports
+    }
+
+    fn quota_of(&self, node: u64) -> &AuthorQuota {
+        self.overrides.get(&node).unwrap_or(&self.default)
+    }
+
+    fn usage_of(&self, node: u64, now_ms: u64) -> AuthorUsage {
+        let mut usage = self.usage.get(&node).copied().unwrap_or_default();
+        for (doc_id, bytes) in self.unstamped.get(&node).into_iter().flatten() {
+            usage.bytes += bytes;
+            if !self.doc_bytes.get(doc_id).is_some_and(|authors| authors.contains_key(&node)) {
+                usage.docs += 1;
+            }
+        }
+        usage.updates_this_minute = match self.rate.get(&node) {
+            Some((started_ms, count)) if now_ms < started_ms + RATE_WINDOW.as_millis() as u64 => *count,
+            _ => 0,
+        };
+        usage
+    }
+
+    fn name(&self, node: u64) -> String {
+        self.peers.get(&node).map_or_else(|| format!("{node:016x}"), PeerId::to_string)
+    }
+}
+
+/// Per-author usage as `/metrics` lines, from [`AuthorQuotas::report`]: how many
+/// authors have updates stored or a quota of their own, then usage and limits of the
+/// [`METRICS_AUTHORS`] biggest, labelled with their peer id. Listing every author would
+/// let anyone with a fresh key add series.
+pub fn metrics(reports: &[AuthorReport]) -> Vec<(String, u64)> {
+    let mut metrics = vec![
+        ("docstore_authors".to_string(), reports.iter().filter(|r| r.usage.docs > 0).count() as u64),
+        ("docstore_author_overrides".to_string(), reports.iter().filter(|r| r.overridden).count() as u64),
+    ];
+    for report in reports.iter().take(METRICS_AUTHORS) {
+        let author = &report.author;
+        let usage = report.usage;
+        metrics.push((format!("docstore_author_bytes{{author=\"{author}\"}}"), usage.bytes));
+        metrics.push((format!("docstore_author_docs{{author=\"{author}\"}}"), usage.docs));
+        metrics.push((format!("docstore_author_updates_per_minute{{author=\"{author}\"}}"), usage.updates_this_minute));
+        let quota = report.quota;
+        for (name, max) in [
+            ("max_bytes", quota.max_bytes),
+            ("max_docs", quota.max_docs),
+            ("max_updates_per_minute", quota.max_updates_per_minute),
+        ] {
+            if let Some(max) = max {
+                metrics.push((format!("docstore_author_{name}{{author=\"{author}\"}}"), max));
+            }
+        }
+    }
+    metrics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::behaviour::docstore::{HlcClock, Stamp, UpdateSink, VectorClock};
+    use crate::store::quota::{QuotaSink, RoomQuotas};
+    use crate::store::MemoryDocStore;
+
+    const NOW: u64 = 1_000_000;
+
+    /// A peer stamping its updates like a node would.
+    struct Author {
+        peer_id: PeerId,
+        hlc: HlcClock,
+    }
+
+    impl Author {
+        fn new() -> Self {
+            let peer_id = PeerId::random();
+            Self { peer_id, hlc: HlcClock::for_peer(&peer_id) }
+        }
+
+        fn update(&mut self, doc_id: &str, len: usize) -> DocUpdate {
+            DocUpdate::new(doc_id, vec![7; len]).with_stamp(Stamp::next(&mut self.hlc, &VectorClock::default()))
+        }
+    }
+
+    #[test]
+    fn one_author_hits_the_byte_quota_while_another_continues() {
+        let (mut alice, mut bob) = (Author::new(), Author::new());
+        let mut store = MemoryDocStore::default();
+        let mut rooms = RoomQuotas::default();
+        let mut authors = AuthorQuotas::new(AuthorQuota { max_bytes: Some(100), ..Default::default() }, []);
+        authors.learn(alice.peer_id);
+        let mut sink = QuotaSink::new(&mut store, &mut rooms, &mut authors);
+        for doc_id in ["a", "b", "c"] {
+            let update = alice.update(doc_id, 30);
+            assert_eq!(sink.admit(Some(&alice.peer_id), std::slice::from_ref(&update)), Ok(()));
+            sink.apply_update(&update);
+        }
+        assert_eq!(
+            sink.admit(Some(&alice.peer_id), &[alice.update("d", 11)]),
+            Err(QuotaExceeded::AuthorBytes { author: alice.peer_id.to_string(), bytes: 101, max: 100 })
+        );
+        // A batch counts as a whole, even if each of its updates would fit
+        let batch = [alice.update("a", 6), alice.update("b", 6)];
+        assert!(sink.admit(Some(&alice.peer_id), &batch[..1]).is_ok());
+        assert!(matches!(sink.admit(Some(&alice.peer_id), &batch), Err(QuotaExceeded::AuthorBytes { bytes: 102, .. })));
+        // Bob's usage is his own, even in Alice's documents
+        for doc_id in ["a", "d"] {
+            let update = bob.update(doc_id, 50);
+            assert_eq!(sink.admit(Some(&bob.peer_id), std::slice::from_ref(&update)), Ok(()));
+            sink.apply_update(&update);
+        }
+
+        assert_eq!(authors.usage(&alice.peer_id, NOW), AuthorUsage { bytes: 90, docs: 3, updates_this_minute: 0 });
+        assert_eq!(authors.usage(&bob.peer_id, NOW).bytes, 100);
+
+        // Alice gets more room at runtime, and compaction frees what it drops
+        authors.set(Some(alice.peer_id), Some(AuthorQuota { max_bytes: Some(200), ..Default::default() }));
+        assert_eq!(authors.admit(&alice.peer_id, &[alice.update("d", 11)], NOW), Ok(()));
+        store.make_snapshot("a");
+        store.compact_within("a", Duration::ZERO);
+        authors.recount(&store);
+        assert_eq!(authors.usage(&alice.peer_id, NOW), AuthorUsage { bytes: 60, docs: 2, updates_this_minute: 0 });
+        assert_eq!(authors.usage(&bob.peer_id, NOW), AuthorUsage { bytes: 50, docs: 1, updates_this_minute: 0 });
+    }
+
+    #[test]
+    fn doc_and_rate_limits() {
+        let mut alice = Author::new();
+        let mut store = MemoryDocStore::default();
+        let quota = AuthorQuota { max_docs: Some(2), max_updates_per_minute: Some(3), ..Default::default() };
+        let mut authors = AuthorQuotas::new(quota, []);
+        let alice_id = alice.peer_id;
+        let mut apply = |authors: &mut AuthorQuotas, update: &DocUpdate, now_ms: u64| -> Result<(), QuotaExceeded> {
+            let updates = std::slice::from_ref(update);
+            authors.admit(&alice_id, updates, now_ms)?;
+            store.apply_update(update);
+            authors.charge(&alice_id, updates, now_ms);
+            authors.observe(&store, &update.doc_id);
+            Ok(())
+        };
+        apply(&mut authors, &alice.update("a", 1), NOW).unwrap();
+        apply(&mut authors, &alice.update("b", 1), NOW).unwrap();
+        let third = alice.update("c", 1);
+        assert!(matches!(authors.admit(&alice_id, &[third], NOW), Err(QuotaExceeded::AuthorDocs { max: 2, .. })));
+        let two = [alice.update("a", 1), alice.update("b", 1)];
+        assert!(matches!(authors.admit(&alice_id, &two, NOW), Err(QuotaExceeded::AuthorRate { max: 3, .. })));
+        apply(&mut authors, &alice.update("a", 1), NOW + 1).unwrap();
+        assert!(matches!(
+            authors.admit(&alice_id, &[alice.update("a", 1)], NOW + 2),
+            Err(QuotaExceeded::AuthorRate { max: 3, .. })
+        ));
+        assert_eq!(authors.usage(&alice_id, NOW + 2).updates_this_minute, 3);
+
+        // The next minute starts over
+        let later = NOW + RATE_WINDOW.as_millis() as u64;
+        apply(&mut authors, &alice.update("a", 1), later).unwrap();
+        assert_eq!(authors.usage(&alice.peer_id, later).updates_this_minute, 1);
+    }
+
+    #[test]
+    fn unstamped_updates_count_against_their_signer() {
+        let mallory = PeerId::random();
+        let mut store = MemoryDocStore::default();
+        let mut rooms = RoomQuotas::default();
+        let mut authors = AuthorQuotas::new(AuthorQuota { max_bytes: Some(100), max_docs: Some(2), ..Default::default() }, []);
+        let mut sink = QuotaSink::new(&mut store, &mut rooms, &mut authors);
+        let first = [DocUpdate::new("a", vec![0; 60])];
+        assert_eq!(sink.admit(Some(&mallory), &first), Ok(()));
+        sink.apply_update(&first[0]);
+        sink.charge(&mallory, &first);
+
+        assert!(matches!(
+            sink.admit(Some(&mallory), &[DocUpdate::new("a", vec![0; 41])]),
+            Err(QuotaExceeded::AuthorBytes { bytes: 101, .. })
+        ));
+        let spread = [DocUpdate::new("b", vec![0; 1]), DocUpdate::new("c", vec![0; 1])];
+        assert!(matches!(sink.admit(Some(&mallory), &spread), Err(QuotaExceeded::AuthorDocs { max: 2, .. })));
+        // Recounting the store keeps what it cannot attribute
+        authors.recount(&store);
+        let usage = authors.usage(&mallory, NOW);
+        assert_eq!((usage.bytes, usage.docs), (60, 1));
+    }
+
+    #[test]
+    fn reports_and_metrics_list_the_biggest_authors() {
+        let (mut alice, mut bob) = (Author::new(), Author::new());
+        let mut store = MemoryDocStore::default();
+        store.apply_update(&alice.update("a", 10));
+        store.apply_update(&bob.update("a", 20));
+        let mut authors = AuthorQuotas::new(AuthorQuota { max_docs: Some(5), ..Default::default() }, []);
+        authors.set(Some(alice.peer_id), Some(AuthorQuota::default()));
+        authors.learn(bob.peer_id);
+        authors.recount(&store);
+
+        let report = authors.report(NOW);
+        assert_eq!(report.iter().map(|r| r.author.clone()).collect::<Vec<_>>(), [bob.peer_id.to_string(), alice.peer_id.to_string()]);
+        assert_eq!((report[0].quota.max_docs, report[0].overridden), (Some(5), false));
+        assert_eq!((report[1].quota.max_docs, report[1].overridden), (None, true));
+
+        let bob_label = format!("{{author=\"{}\"}}", bob.peer_id);
+        assert_eq!(
+            metrics(&report)[..6],
+            [
+                ("docstore_authors".to_string(), 2),
+                ("docstore_author_overrides".to_string(), 1),
+                (format!("docstore_author_bytes{bob_label}"), 20),
+                (format!("docstore_author_docs{bob_label}"), 1),
+                (format!("docstore_author_updates_per_minute{bob_label}"), 0),
+                (format!("docstore_author_max_docs{bob_label}"), 5),
+            ]
+        );
+    }
+}
@@ -0,0 +1,1003 @@
+//! Directory-backed [`DocStore`].
+//!
+//! Each document lives in `<root>/<hex doc id>/`:
+//! - `log`: append-only sequence of `[u32 LE length][postcard StoredUpdate]` records;
+//! - `snapshot`: postcard-encoded latest snapshot;
+//! - `clock`: JSON vector clock and highest HLC of the applied stamped updates (overall
+//!   and per author), and the version the current content comes from;
+//! - `digests`: SHA-256 of each `log` record body, 32 bytes each in log order, for
+//!   [`DocStore::verify`] to catch bit rot.
+//!
+//! The pin set is kept as a JSON list in `<root>/pins`, merge policies as a JSON map in
+//! `<root>/policies`.
+//!
+//! Rewrites (compaction, snapshot install) go through a temporary file and a rename, so a
+//! crash leaves either the old or the new file, never a half-written one. A torn record at
+//! the end of `log` (crash during append) is cut off on open, so later appends follow the
+//! last whole record.
+//!
+//! A transaction touches several documents, so it is first written whole to
+//! `<root>/transaction` (postcard: the records each document gains and the clock it ends
+//! up with), then to the documents, and the journal is removed last. A journal found on
+//! open is finished before the store is used.
+//!
+//! A document fetch under way is kept in `<root>/fetches/<hex doc id>/`: its manifest as
+//! JSON in `manifest` and the bytes verified so far appended to `data`.
+//!
+//! A document found corrupted is moved to `<root>/quarantine/<hex doc id>-<unix ms>/`,
+//! which is not a document directory and so is skipped on open, like `fetches`.
+//!
+//! The layout's version is kept as JSON in `<root>/format`; a store in any other version
+//! (or from before the version was recorded) is refused on open until it is upgraded,
+//! see [`crate::node::migrations`].
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use super::{now_ms, CompactionReport, Corruption, DocEntry, DocStore, MergePolicy, StoredUpdate, DEFAULT_RETENTION};
+use crate::behaviour::docstore::{snapshot::content_hash, DocUpdate, Hlc, Snapshot, VectorClock};
+use crate::behaviour::fetch::FetchManifest;
+use crate::node::migrations::{self, Artifact};
+
+const LOG_FILE: &str = "log";
+const SNAPSHOT_FILE: &str = "snapshot";
+const CLOCK_FILE: &str = "clock";
+const DIGESTS_FILE: &str = "digests";
+const QUARANTINE_DIR: &str = "quarantine";
+const PINS_FILE: &str = "pins";
+const POLICIES_FILE: &str = "policies";
+const TRANSACTION_FILE: &str = "transaction";
+const FORMAT_FILE: &str = "format";
+const FETCHES_DIR: &str = "fetches";
+const FETCH_MANIFEST_FILE: &str = "manifest";
+const FETCH_DATA_FILE: &str = "data";
+
+#[derive(Serialize, Deserialize)]
+struct StoredFormat {
+    format_version: u32,
+}
+
+/// Rewritten after every stamped update; unstamped updates always take over the content,
+/// so for those the log alone says which update is current.
+#[derive(Default, Serialize, Deserialize)]
+struct StoredClock {
+    clock: VectorClock,
+    last_hlc: Option<Hlc>,
+    content_version: u64,
+    authors: BTreeMap<u64, Hlc>,
+}
+
+impl From<&DocEntry> for StoredClock {
+    fn from(entry: &DocEntry) -> Self {
+        Self {
+            clock: entry.clock.clone(),
+            last_hlc: entry.last_hlc,
+            content_version: entry.content_version,
+            authors: entry.authors.clone(),
+        }
+    }
+}
+
+/// Write-ahead journal of a transaction, per document: the log records it adds and the
+/// resulting clock.
+#[derive(Serialize, Deserialize)]
+struct StoredTransaction {
+    docs: Vec<(String, Vec<StoredUpdate>, StoredClock)>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredSnapshot {
+    doc_id: String,
+    version: u64,
+    content_hash: [u8; 32],
+    bytes: Vec<u8>,
+}
+
+/// [`DocStore`] persisted under a directory. State is also held in memory; every
+/// mutation is written through before returning.
+#[derive(Debug)]
+pub struct FileDocStore {
+    root: PathBuf,
+    docs: HashMap<String, DocEntry>,
+    pins: HashSet<String>,
+    policies: HashMap<String, MergePolicy>,
+    retention: Duration,
+}
+
+impl FileDocStore {
+    /// Open (or create) a store under `root`.
+    pub fn open(root: impl Into<PathBuf>) -> io::Result<Self> {
+        Self::open_with_retention(root, DEFAULT_RETENTION)
+    }
+
+    pub fn open_with_retention(root: impl Into<PathBuf>, retention: Duration) -> io::Result<Self> {
+        let root = root.into();
+        match format_version(&root)? {
+            Some(version) => migrations::ensure_current(Artifact::Store, version)?,
+            None => {
+                fs::create_dir_all(&root)?;
+                write_json(&root.join(FORMAT_FILE), &StoredFormat { format_version: Artifact::Store.current() })?;
+            }
+        }
+        let mut docs = HashMap::new();
+        for dir in fs::read_dir(&root)? {
+            let dir = dir?;
+            if !dir.file_type()?.is_dir() {
+                continue;
+            }
+            let Some(doc_id) = dir.file_name().to_str().and_then(decode_doc_dir) else {
+                continue;
+            };
+            docs.insert(doc_id, load_entry(&dir.path())?);
+        }
+        let pins = read_json(&root.join(PINS_FILE))?.unwrap_or_default();
+        let policies = read_json(&root.join(POLICIES_FILE))?.unwrap_or_default();
+        let mut store = Self { root, docs, pins, policies, retention };
+        store.recover_transaction()?;
+        Ok(store)
+    }
+
+    /// Read the store under `root` without changing anything, for a startup check: its
+    /// format must be current and every document must load. Returns how many documents
+    /// it holds; there is no store yet where nothing was written.
+    pub fn inspect(root: &Path) -> io::Result<usize> {
+        match format_version(root)? {
+            Some(version) => migrations::ensure_current(Artifact::Store, version)?,
+            None => return Ok(0),
+        }
+        let mut docs = 0;
+        for dir in fs::read_dir(root)? {
+            let dir = dir?;
+            if dir.file_type()?.is_dir() && dir.file_name().to_str().and_then(decode_doc_dir).is_some() {
+                load_entry(&dir.path())?;
+                docs += 1;
+            }
+        }
+        Ok(docs)
+    }
+
+    /// Finish the transaction a crash interrupted, if any: whatever records its documents
+    /// are missing are added and their clocks written again.
+    fn recover_transaction(&mut self) -> io::Result<()> {
+        let path = self.root.join(TRANSACTION_FILE);
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let journal: StoredTransaction =
+            postcard::from_bytes(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        for (doc_id, records, clock) in journal.docs {
+            let dir = self.doc_dir(&doc_id);
+            fs::create_dir_all(&dir)?;
+            let applied = self.version(&doc_id);
+            let mut log = self.docs.get(&doc_id).map(|d| d.log.clone()).unwrap_or_default();
+            log.extend(records.into_iter().filter(|u| u.version > applied));
+            // Rewritten rather than appended to, in case the append was torn
+            self.rewrite_log(&doc_id, &log)?;
+            write_json(&dir.join(CLOCK_FILE), &clock)?;
+            self.docs.insert(doc_id, load_entry(&dir)?);
+        }
+        fs::remove_file(&path)
+    }
+
+    fn doc_dir(&self, doc_id: &str) -> PathBuf {
+        self.root.join(encode_doc_dir(doc_id))
+    }
+
+    fn append(&self, doc_id: &str, update: &StoredUpdate) -> io::Result<()> {
+        let dir = self.doc_dir(doc_id);
+        fs::create_dir_all(&dir)?;
+        append_records(&dir, std::slice::from_ref(update))
+    }
+
+    fn rewrite_log(&self, doc_id: &str, log: &[StoredUpdate]) -> io::Result<()> {
+        let dir = self.doc_dir(doc_id);
+        let bytes: Vec<u8> = log.iter().flat_map(encode_record).collect();
+        let digests: Vec<u8> = frame_bodies(&bytes).into_iter().flat_map(content_hash).collect();
+        write_atomic(&dir.join(LOG_FILE), &bytes)?;
+        write_atomic(&dir.join(DIGESTS_FILE), &digests)
+    }
+
+    fn save_pins(&self) {
+        if let Err(e) = write_json(&self.root.join(PINS_FILE), &self.pins) {
+            tracing::error!("Failed to persist pins: {}", e);
+        }
+    }
+
+    fn fetch_dir(&self, doc_id: &str) -> PathBuf {
+        self.root.join(FETCHES_DIR).join(encode_doc_dir(doc_id))
+    }
+
+    fn save_clock(&self, doc_id: &str, entry: &DocEntry) -> io::Result<()> {
+        write_json(&self.doc_dir(doc_id).join(CLOCK_FILE), &StoredClock::from(entry))
+    }
+
+    /// Journal `tx`, write it to its documents, then drop the journal. Fails before any
+    /// document is touched if the journal can't be written; after that, a failure leaves
+    /// the journal for [`recover_transaction`](Self::recover_transaction) to finish.
+    fn commit_transaction(&self, tx: &StoredTransaction) -> io::Result<()> {
+        let journal = self.root.join(TRANSACTION_FILE);
+        write_atomic(&journal, &postcard::to_allocvec(tx).map_err(io::Error::other)?)?;
+        for (doc_id, records, clock) in &tx.docs {
+            let dir = self.doc_dir(doc_id);
+            fs::create_dir_all(&dir)?;
+            append_records(&dir, records)?;
+            write_json(&dir.join(CLOCK_FILE), clock)?;
+        }
+        fs::remove_file(journal)
+    }
+
+    fn write_snapshot(&self, snapshot: &Snapshot) -> io::Result<()> {
+        let stored = StoredSnapshot {
+            doc_id: snapshot.doc_id.clone(),
+            version: snapshot.version,
+            content_hash: snapshot.content_hash,
+            bytes: snapshot.bytes.clone(),
+        };
+        let bytes = postcard::to_allocvec(&stored).map_err(io::Error::other)?;
+        let dir = self.doc_dir(&snapshot.doc_id);
+        fs::create_dir_all(&dir)?;
+        write_atomic(&dir.join(SNAPSHOT_FILE), &bytes)
+    }
+}
+
+impl DocStore for FileDocStore {
+    fn apply_update(&mut self, update: &DocUpdate) -> u64 {
+        let policy = self.merge_policy(&update.doc_id);
+        let entry = self.docs.entry(update.doc_id.clone()).or_default();
+        let stored = entry.apply(update, policy, now_ms()).clone();
+        let mut result = self.append(&update.doc_id, &stored);
+        if update.stamp.is_some() {
+            result = result.and_then(|_| self.save_clock(&update.doc_id, &self.docs[&update.doc_id]));
+        }
+        if let Err(e) = result {
+            tracing::error!("Failed to persist update v{} of {}: {}", stored.version, update.doc_id, e);
+        }
+        stored.version
+    }
+
+    fn apply_transaction(&mut self, updates: &[DocUpdate]) -> Vec<u64> {
+        let now = now_ms();
+        let mut versions = Vec::with_capacity(updates.len());
+        let mut touched: Vec<(String, Vec<StoredUpdate>)> = Vec::new();
+        for update in updates {
+            let policy = self.merge_policy(&update.doc_id);
+            let entry = self.docs.entry(update.doc_id.clone()).or_default();
+            let stored = entry.apply(update, policy, now).clone();
+            versions.push(stored.version);
+            match touched.iter_mut().find(|(doc_id, _)| *doc_id == update.doc_id) {
+                Some((_, records)) => records.push(stored),
+                None => touched.push((update.doc_id.clone(), vec![stored])),
+            }
+        }
+        let docs = touched
+            .into_iter()
+            .map(|(doc_id, records)| {
+                let clock = StoredClock::from(&self.docs[&doc_id]);
+                (doc_id, records, clock)
+            })
+            .collect();
+        if let Err(e) = self.commit_transaction(&StoredTransaction { docs }) {
+            tracing::error!("Failed to persist a transaction of {} updates: {}", updates.len(), e);
+        }
+        versions
+    }
+
+    fn version(&self, doc_id: &str) -> u64 {
+        self.docs.get(doc_id).map_or(0, |d| d.version)
+    }
+
+    fn content(&self, doc_id: &str) -> Option<Vec<u8>> {
+        self.docs.get(doc_id).map(|d| d.content.clone())
+    }
+
+    fn updates_since(&self, doc_id: &str, version: u64) -> Vec<StoredUpdate> {
+        self.docs
+            .get(doc_id)
+            .map(|d| d.log.iter().filter(|u| u.version > version).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    fn log(&self, doc_id: &str) -> &[StoredUpdate] {
+        self.docs.get(doc_id).map(|d| d.log.as_slice()).unwrap_or_default()
+    }
+
+    fn latest_snapshot(&self, doc_id: &str) -> Option<Snapshot> {
+        self.docs.get(doc_id).and_then(|d| d.snapshot.clone())
+    }
+
+    fn make_snapshot(&mut self, doc_id: &str) -> Option<Snapshot> {
+        let snapshot = self.docs.get_mut(doc_id)?.make_snapshot(doc_id);
+        if let Err(e) = self.write_snapshot(&snapshot) {
+            tracing::error!("Failed to persist snapshot of {}: {}", doc_id, e);
+        }
+        Some(snapshot)
+    }
+
+    fn install_snapshot(&mut self, snapshot: Snapshot) -> bool {
+        if !snapshot.verify() || snapshot.version <= self.version(&snapshot.doc_id) {
+            return false;
+        }
+        // Snapshot first: if we crash before the log is cleared, the stale records are
+        // simply older than the snapshot and get ignored on open.
+        if let Err(e) = self.write_snapshot(&snapshot).and_then(|_| self.rewrite_log(&snapshot.doc_id, &[])) {
+            tracing::error!("Failed to persist snapshot of {}: {}", snapshot.doc_id, e);
+            return false;
+        }
+        self.docs.entry(snapshot.doc_id.clone()).or_default().install_snapshot(snapshot)
+    }
+
+    fn doc_ids(&self) -> Vec<String> {
+        self.docs.keys().cloned().collect()
+    }
+
+    fn compact(&mut self, doc_id: &str) -> CompactionReport {
+        self.compact_within(doc_id, self.retention)
+    }
+
+    fn compact_within(&mut self, doc_id: &str, retention: Duration) -> CompactionReport {
+        if self.pins.contains(doc_id) {
+            return CompactionReport::default();
+        }
+        let Some(entry) = self.docs.get(doc_id) else {
+            return CompactionReport::default();
+        };
+        let (log, report) = entry.compacted_log(retention.min(self.retention), now_ms());
+        if report.removed_updates == 0 {
+            return report;
+        }
+        // Persist first so memory never claims less than disk holds
+        if let Err(e) = self.rewrite_log(doc_id, &log) {
+            tracing::error!("Failed to compact {}: {}", doc_id, e);
+            return CompactionReport::default();
+        }
+        self.docs.get_mut(doc_id).expect("present").log = log;
+        report
+    }
+
+    fn pin(&mut self, doc_id: &str) -> bool {
+        let added = self.pins.insert(doc_id.to_string());
+        if added {
+            self.save_pins();
+        }
+        added
+    }
+
+    fn unpin(&mut self, doc_id: &str) -> bool {
+        let removed = self.pins.remove(doc_id);
+        if removed {
+            self.save_pins();
+        }
+        removed
+    }
+
+    fn pins(&self) -> Vec<String> {
+        self.pins.iter().cloned().collect()
+    }
+
+    fn is_pinned(&self, doc_id: &str) -> bool {
+        self.pins.contains(doc_id)
+    }
+
+    fn merge_policy(&self, doc_id: &str) -> MergePolicy {
+        self.policies.get(doc_id).copied().unwrap_or_default()
+    }
+
+    fn set_merge_policy(&mut self, doc_id: &str, policy: MergePolicy) {
+        if self.policies.insert(doc_id.to_string(), policy) == Some(policy) {
+            return;
+        }
+        if let Err(e) = write_json(&self.root.join(POLICIES_FILE), &self.policies) {
+            tracing::error!("Failed to persist merge policies: {}", e);
+        }
+    }
+
+    fn clock(&self, doc_id: &str) -> VectorClock {
+        self.docs.get(doc_id).map(|d| d.clock.clone()).unwrap_or_default()
+    }
+
+    fn author_hlc(&self, doc_id: &str, author: u64) -> Option<Hlc> {
+        self.docs.get(doc_id)?.authors.get(&author).copied()
+    }
+
+    fn has_applied(&self, doc_id: &str, author: u64, seq: u64) -> bool {
+        self.docs.get(doc_id).and_then(|d| d.seqs.get(&author)).is_some_and(|seqs| seqs.contains(seq))
+    }
+
+    /// Re-reads the snapshot and the log from disk. Log records without a digest yet
+    /// (written before digests were kept, or by an append that crashed before its digest
+    /// did) are trusted and digested now.
+    fn verify(&mut self, doc_id: &str) -> Result<(), Corruption> {
+        let Some(entry) = self.docs.get(doc_id) else {
+            return Ok(());
+        };
+        let dir = self.doc_dir(doc_id);
+        let unreadable = |e: io::Error| Corruption::Unreadable(e.to_string());
+
+        match read_optional(&dir.join(SNAPSHOT_FILE)).map_err(unreadable)? {
+            Some(bytes) => {
+                let stored: StoredSnapshot = postcard::from_bytes(&bytes).map_err(|_| Corruption::Snapshot)?;
+                if content_hash(&stored.bytes) != stored.content_hash {
+                    return Err(Corruption::Snapshot);
+                }
+            }
+            None if entry.snapshot.is_some() => return Err(Corruption::Unreadable("snapshot file is gone".into())),
+            None => {}
+        }
+
+        let log = read_optional(&dir.join(LOG_FILE)).map_err(unreadable)?.unwrap_or_default();
+        let digests = read_optional(&dir.join(DIGESTS_FILE)).map_err(unreadable)?.unwrap_or_default();
+        let bodies = frame_bodies(&log);
+        let digests: Vec<&[u8]> = digests.chunks_exact(32).collect();
+        let expected = entry.log.len().max(digests.len());
+        if bodies.len() < expected {
+            return Err(Corruption::Truncated { on_disk: bodies.len(), expected });
+        }
+        if let Some(index) = bodies.iter().zip(&digests).position(|(body, digest)| content_hash(body) != **digest) {
+            return Err(Corruption::LogRecord { index });
+        }
+        if bodies.len() > digests.len() {
+            // Rewrite rather than append, dropping a torn trailing digest
+            let all: Vec<u8> = bodies.into_iter().flat_map(content_hash).collect();
+            write_atomic(&dir.join(DIGESTS_FILE), &all).map_err(unreadable)?;
+        }
+        Ok(())
+    }
+
+    fn quarantine(&mut self, doc_id: &str) -> bool {
+        if self.docs.remove(doc_id).is_none() {
+            return false;
+        }
+        let quarantine = self.root.join(QUARANTINE_DIR);
+        let target = quarantine.join(format!("{}-{}", encode_doc_dir(doc_id), now_ms()));
+        if let Err(e) = fs::create_dir_all(&quarantine).and_then(|()| fs::rename(self.doc_dir(doc_id), &target)) {
+            // Forgotten all the same: the next open may load it again, and verify it again
+            tracing::error!("Failed to move {} to quarantine: {}", doc_id, e);
+        }
+        true
+    }
+
+    fn start_partial_fetch(&mut self, manifest: &FetchManifest, verified: &[u8]) {
+        let dir = self.fetch_dir(&manifest.doc_id);
+        // The data first: a crash in between leaves bytes the old manifest rejects
+        let saved = fs::create_dir_all(&dir)
+            .and_then(|()| write_atomic(&dir.join(FETCH_DATA_FILE), verified))
+            .and_then(|()| write_json(&dir.join(FETCH_MANIFEST_FILE), manifest));
+        if let Err(e) = saved {
+            tracing::error!("Failed to persist the fetch of {}: {}", manifest.doc_id, e);
+        }
+    }
+
+    fn append_partial_fetch(&mut self, doc_id: &str, data: &[u8]) {
+        let path = self.fetch_dir(doc_id).join(FETCH_DATA_FILE);
+        if let Err(e) = OpenOptions::new().append(true).open(path).and_then(|mut f| f.write_all(data)) {
+            tracing::error!("Failed to persist the fetch of {}: {}", doc_id, e);
+        }
+    }
+
+    fn partial_fetch(&self, doc_id: &str) -> Option<(FetchManifest, Vec<u8>)> {
+        let dir = self.fetch_dir(doc_id);
+        let manifest = read_json(&dir.join(FETCH_MANIFEST_FILE)).ok()??;
+        let data = read_optional(&dir.join(FETCH_DATA_FILE)).ok()?.unwrap_or_default();
+        Some((manifest, data))
+    }
+
+    fn clear_partial_fetch(&mut self, doc_id: &str) {
+        match fs::remove_dir_all(self.fetch_dir(doc_id)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                tracing::error!("Failed to remove the fetch of {}: {}", doc_id, e);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The layout version of the store under `root`: `None` if there is no store yet, 0 for
+/// one written before the version was recorded.
+pub fn format_version(root: &Path) -> io::Result<Option<u32>> {
+    if let Some(format) = read_json::<StoredFormat>(&root.join(FORMAT_FILE))? {
+        return Ok(Some(format.format_version));
+    }
+    if !root.is_dir() {
+        return Ok(None);
+    }
+    let mut legacy = [PINS_FILE, POLICIES_FILE, TRANSACTION_FILE].iter().any(|file| root.join(file).exists());
+    for dir in fs::read_dir(root)? {
+        let dir = dir?;
+        legacy |= dir.file_type()?.is_dir() && dir.file_name().to_str().and_then(decode_doc_dir).is_some();
+    }
+    Ok(legacy.then_some(0))
+}
+
+/// Log records as written before updates were stamped.
+#[derive(Deserialize)]
+struct UnstampedRecord {
+    version: u64,
+    payload: Vec<u8>,
+    applied_at_ms: u64,
+}
+
+/// Bring a store from before the layout version was recorded up to version 1: log
+/// records from before updates were stamped are re-encoded, clocks from before per-author
+/// watermarks get them from the log, logs from before digests get theirs, and the
+/// version is recorded last.
+pub(crate) fn migrate_unversioned(root: &Path) -> io::Result<()> {
+    for dir in fs::read_dir(root)? {
+        let dir = dir?;
+        if dir.file_type()?.is_dir() && dir.file_name().to_str().and_then(decode_doc_dir).is_some() {
+            migrate_doc_dir(&dir.path())?;
+        }
+    }
+    write_json(&root.join(FORMAT_FILE), &StoredFormat { format_version: 1 })
+}
+
+fn migrate_doc_dir(dir: &Path) -> io::Result<()> {
+    let bytes = read_optional(&dir.join(LOG_FILE))?.unwrap_or_default();
+    let mut reencoded = false;
+    let log: Vec<StoredUpdate> = frame_bodies(&bytes)
+        .into_iter()
+        .map_while(|body| {
+            postcard::from_bytes(body).ok().or_else(|| {
+                let old: UnstampedRecord = postcard::from_bytes(body).ok()?;
+                reencoded = true;
+                Some(StoredUpdate { version: old.version, payload: old.payload, applied_at_ms: old.applied_at_ms, stamp: None })
+            })
+        })
+        .collect();
+    if reencoded || !dir.join(DIGESTS_FILE).exists() {
+        let bytes: Vec<u8> = log.iter().flat_map(encode_record).collect();
+        let digests: Vec<u8> = frame_bodies(&bytes).into_iter().flat_map(content_hash).collect();
+        write_atomic(&dir.join(LOG_FILE), &bytes)?;
+        write_atomic(&dir.join(DIGESTS_FILE), &digests)?;
+    }
+
+    // As far as the log still remembers them
+    let clock_path = dir.join(CLOCK_FILE);
+    if let Some(mut clock) = read_json::<serde_json::Value>(&clock_path)? {
+        if let Some(fields) = clock.as_object_mut().filter(|fields| !fields.contains_key("authors")) {
+            let mut authors: BTreeMap<u64, Hlc> = BTreeMap::new();
+            for stamp in log.iter().filter_map(|u| u.stamp.as_ref()) {
+                let newest = authors.entry(stamp.hlc.node).or_insert(stamp.hlc);
+                *newest = (*newest).max(stamp.hlc);
+            }
+            fields.insert("authors".to_string(), serde_json::to_value(&authors).map_err(io::Error::other)?);
+            write_json(&clock_path, &clock)?;
+        }
+    }
+    Ok(())
+}
+
+fn encode_record(update: &StoredUpdate) -> Vec<u8> {
+    let body = postcard::to_allocvec(update).expect("stored update serialization cannot fail");
+    let mut out = Vec::with_capacity(4 + body.len());
+    out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    out.extend_from_slice(&body);
+    out
+}
+
+/// Append `records` to the log in `dir`, then their digests.
+fn append_records(dir: &Path, records: &[StoredUpdate]) -> io::Result<()> {
+    let bytes: Vec<u8> = records.iter().flat_map(encode_record).collect();
+    let mut f = OpenOptions::new().create(true).append(true).open(dir.join(LOG_FILE))?;
+    f.write_all(&bytes)?;
+    f.sync_data()?;
+    let digests: Vec<u8> = frame_bodies(&bytes).into_iter().flat_map(content_hash).collect();
+    let mut f = OpenOptions::new().create(true).append(true).open(dir.join(DIGESTS_FILE))?;
+    f.write_all(&digests)?;
+    f.sync_data()
+}
+
+/// Split a log into its record bodies without decoding them, stopping at a torn one.
+fn frame_bodies(mut bytes: &[u8]) -> Vec<&[u8]> {
+    let mut out = Vec::new();
+    while bytes.len() >= 4 {
+        let len = u32::from_le_bytes(bytes[..4].try_into().expect("4 bytes")) as usize;
+        let Some(body) = bytes.get(4..4 + len) else {
+            break;
+        };
+        out.push(body);
+        bytes = &bytes[4 + len..];
+    }
+    out
+}
+
+/// Bytes of `bytes` taken by whole records; anything after them is a torn record.
+fn framed_len(bytes: &[u8]) -> usize {
+    frame_bodies(bytes).iter().map(|body| 4 + body.len()).sum()
+}
+
+/// Decode log records, stopping at the first torn or unreadable one.
+fn decode_records(bytes: &[u8]) -> Vec<StoredUpdate> {
+    frame_bodies(bytes).into_iter().map_while(|body| postcard::from_bytes(body).ok()).collect()
+}
+
+fn load_entry(dir: &Path) -> io::Result<DocEntry> {
+    let snapshot = match fs::read(dir.join(SNAPSHOT_FILE)) {
+        Ok(bytes) => {
+            let s: StoredSnapshot =
+                postcard::from_bytes(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            Some(Snapshot { doc_id: s.doc_id, version: s.version, content_hash: s.content_hash, bytes: s.bytes })
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+    let log = match fs::read(dir.join(LOG_FILE)) {
+        Ok(bytes) => {
+            // Appends would otherwise land behind the torn record, where nothing reads them
+            let whole = framed_len(&bytes);
+            if whole < bytes.len() {
+                write_atomic(&dir.join(LOG_FILE), &bytes[..whole])?;
+            }
+            decode_records(&bytes[..whole])
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e),
+    };
+
+    let clock: StoredClock = read_json(&dir.join(CLOCK_FILE))?.unwrap_or_default();
+
+    let mut entry = DocEntry {
+        clock: clock.clock,
+        last_hlc: clock.last_hlc,
+        authors: clock.authors,
+        ..Default::default()
+    };
+    if let Some(s) = &snapshot {
+        entry.version = s.version;
+        entry.content = s.bytes.clone();
+        entry.content_version = s.version;
+    }
+    if let Some(last) = log.last().filter(|u| u.version > entry.version) {
+        entry.version = last.version;
+    }
+    let last_unstamped = log.iter().rev().find(|u| u.stamp.is_none()).map_or(0, |u| u.version);
+    let content_version = clock.content_version.max(last_unstamped);
+    if let Some(current) = log.iter().find(|u| u.version == content_version && u.version > entry.content_version) {
+        entry.content = current.payload.clone();
+        entry.content_version = current.version;
+    }
+    entry.log = log;
+    entry.snapshot = snapshot;
+    entry.rebuild_seqs();
+    Ok(entry)
+}
+
+/// `None` if `path` does not exist.
+fn read_optional(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(Some(bytes)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// `None` if `path` does not exist.
+fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> io::Result<Option<T>> {
+    match fs::read(path) {
+        Ok(bytes) => serde_json::from_slice(&bytes).map(Some).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn write_json<T: Serialize>(path: &Path, value: &T) -> io::Result<()> {
+    let bytes = serde_json::to_vec(value).map_err(io::Error::other)?;
+    write_atomic(path, &bytes)
+}
+
+fn write_atomic(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    {
+        let mut f = File::create(&tmp)?;
+        f.write_all(bytes)?;
+        f.sync_all()?;
+    }
+    fs::rename(&tmp, path)
+}
+
+fn encode_doc_dir(doc_id: &str) -> String {
+    doc_id.bytes().map(|b| format!("{b:02x}")).collect()
+}
+
+fn decode_doc_dir(name: &str) -> Option<String> {
+    if name.len() % 2 != 0 {
+        return None;
+    }
+    let bytes = (0..name.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(name.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("filestore-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn state_survives_reopen() {
+        let dir = temp_dir("reopen");
+        let mut store = FileDocStore::open(&dir).unwrap();
+        store.apply_update(&DocUpdate::new("doc/1", b"one".to_vec()));
+        store.make_snapshot("doc/1");
+        store.apply_update(&DocUpdate::new("doc/1", b"two".to_vec()));
+        drop(store);
+
+        let store = FileDocStore::open(&dir).unwrap();
+        assert_eq!(store.doc_ids(), vec!["doc/1".to_string()]);
+        assert_eq!(store.version("doc/1"), 2);
+        assert_eq!(store.content("doc/1"), Some(b"two".to_vec()));
+        assert_eq!(store.latest_snapshot("doc/1").unwrap().version, 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn transactions_survive_reopen() {
+        let dir = temp_dir("transaction");
+        let mut store = FileDocStore::open(&dir).unwrap();
+        store.apply_update(&DocUpdate::new("meta", b"m1".to_vec()));
+        let versions = store.apply_transaction(&[
+            DocUpdate::new("meta", b"m2".to_vec()),
+            DocUpdate::new("content", b"c1".to_vec()),
+            DocUpdate::new("meta", b"m3".to_vec()),
+        ]);
+        assert_eq!(versions, [2, 1, 3]);
+        assert!(!dir.join(TRANSACTION_FILE).exists());
+        drop(store);
+
+        let store = FileDocStore::open(&dir).unwrap();
+        assert_eq!(store.version("meta"), 3);
+        assert_eq!(store.content("meta"), Some(b"m3".to_vec()));
+        assert_eq!(store.content("content"), Some(b"c1".to_vec()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn interrupted_transaction_is_finished_on_open() {
+        let dir = temp_dir("interrupted");
+        let mut store = FileDocStore::open(&dir).unwrap();
+        store.apply_update(&DocUpdate::new("meta", b"m1".to_vec()));
+        let record = |version, payload: &[u8]| StoredUpdate { version, payload: payload.to_vec(), applied_at_ms: 0, stamp: None };
+        let tx = StoredTransaction {
+            docs: vec![
+                ("meta".into(), vec![record(2, b"m2")], StoredClock::default()),
+                ("content".into(), vec![record(1, b"c1")], StoredClock::default()),
+            ],
+        };
+        // What a crash leaves after the journal and the first document were written and
+        // the second document's append was torn
+        write_atomic(&dir.join(TRANSACTION_FILE), &postcard::to_allocvec(&tx).unwrap()).unwrap();
+        store.append("meta", &tx.docs[0].1[0]).unwrap();
+        fs::create_dir_all(dir.join(encode_doc_dir("content"))).unwrap();
+        fs::write(dir.join(encode_doc_dir("content")).join(LOG_FILE), [200, 0, 0, 0, 1]).unwrap();
+        drop(store);
+
+        for _ in 0..2 {
+            let store = FileDocStore::open(&dir).unwrap();
+            assert_eq!(store.version("meta"), 2, "applied exactly once");
+            assert_eq!(store.content("meta"), Some(b"m2".to_vec()));
+            assert_eq!(store.version("content"), 1);
+            assert_eq!(store.content("content"), Some(b"c1".to_vec()));
+            assert!(!dir.join(TRANSACTION_FILE).exists());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pins_survive_reopen() {
+        let dir = temp_dir("pins");
+        let mut store = FileDocStore::open(&dir).unwrap();
+        store.pin("a");
+        store.pin("b");
+        store.unpin("a");
+        drop(store);
+
+        let store = FileDocStore::open(&dir).unwrap();
+        assert_eq!(store.pins(), vec!["b".to_string()]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn torn_trailing_record_is_ignored() {
+        let dir = temp_dir("torn");
+        let mut store = FileDocStore::open(&dir).unwrap();
+        store.apply_update(&DocUpdate::new("doc", b"ok".to_vec()));
+        drop(store);
+        let log = dir.join(encode_doc_dir("doc")).join(LOG_FILE);
+        let mut f = OpenOptions::new().append(true).open(&log).unwrap();
+        f.write_all(&[200, 0, 0, 0, 1, 2]).unwrap();
+
+        let mut store = FileDocStore::open(&dir).unwrap();
+        assert_eq!(store.version("doc"), 1);
+        assert_eq!(store.content("doc"), Some(b"ok".to_vec()));
+
+        // What is appended next is still there after another restart
+        store.apply_update(&DocUpdate::new("doc", b"later".to_vec()));
+        drop(store);
+        let mut store = FileDocStore::open(&dir).unwrap();
+        assert_eq!(store.version("doc"), 2);
+        assert_eq!(store.content("doc"), Some(b"later".to_vec()));
+        assert_eq!(store.verify("doc"), Ok(()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn bit_rot_is_found_and_quarantined() {
+        let dir = temp_dir("rot");
+        let mut store = FileDocStore::open(&dir).unwrap();
+        for doc_id in ["log", "snap"] {
+            store.apply_update(&DocUpdate::new(doc_id, b"first".to_vec()));
+            store.apply_update(&DocUpdate::new(doc_id, b"second".to_vec()));
+        }
+        store.make_snapshot("snap").unwrap();
+        assert_eq!(store.verify("log"), Ok(()));
+        assert_eq!(store.verify("snap"), Ok(()));
+
+        let flip_last_byte = |path: PathBuf| {
+            let mut bytes = fs::read(&path).unwrap();
+            *bytes.last_mut().unwrap() ^= 1;
+            fs::write(path, bytes).unwrap();
+        };
+        flip_last_byte(dir.join(encode_doc_dir("log")).join(LOG_FILE));
+        flip_last_byte(dir.join(encode_doc_dir("snap")).join(SNAPSHOT_FILE));
+        assert_eq!(store.verify("log"), Err(Corruption::LogRecord { index: 1 }));
+        assert_eq!(store.verify("snap"), Err(Corruption::Snapshot));
+
+        assert!(store.quarantine("log"));
+        assert!(!store.quarantine("log"));
+        assert_eq!(store.version("log"), 0);
+        assert!(!store.doc_ids().contains(&"log".to_string()));
+        drop(store);
+        // Kept for inspection, but not loaded again
+        assert_eq!(fs::read_dir(dir.join(QUARANTINE_DIR)).unwrap().count(), 1);
+        let store = FileDocStore::open(&dir).unwrap();
+        assert_eq!(store.version("log"), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn logs_without_digests_are_digested_on_first_verify() {
+        let dir = temp_dir("digest");
+        let mut store = FileDocStore::open(&dir).unwrap();
+        store.apply_update(&DocUpdate::new("doc", b"ok".to_vec()));
+        let digests = dir.join(encode_doc_dir("doc")).join(DIGESTS_FILE);
+        fs::remove_file(&digests).unwrap();
+        assert_eq!(store.verify("doc"), Ok(()));
+        assert_eq!(fs::read(&digests).unwrap().len(), 32);
+
+        // Records lost from the log show against the digests
+        fs::write(dir.join(encode_doc_dir("doc")).join(LOG_FILE), []).unwrap();
+        assert_eq!(store.verify("doc"), Err(Corruption::Truncated { on_disk: 0, expected: 1 }));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn compaction_is_persisted() {
+        let dir = temp_dir("compact");
+        let mut store = FileDocStore::open_with_retention(&dir, Duration::ZERO).unwrap();
+        for n in 0..5u8 {
+            store.apply_update(&DocUpdate::new("doc", vec![n; 10]));
+        }
+        store.make_snapshot("doc");
+        store.apply_update(&DocUpdate::new("doc", vec![9; 10]));
+        assert_eq!(store.compact_all(), CompactionReport { removed_updates: 5, reclaimed_bytes: 50 });
+        drop(store);
+
+        let store = FileDocStore::open(&dir).unwrap();
+        assert_eq!(store.updates_since("doc", 0).len(), 1);
+        assert_eq!(store.version("doc"), 6);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn lww_winner_survives_reopen() {
+        use crate::behaviour::docstore::{HlcClock, Stamp};
+
+        let dir = temp_dir("lww");
+        let mut store = FileDocStore::open(&dir).unwrap();
+        store.set_merge_policy("doc", MergePolicy::Lww);
+        let mut late = HlcClock::new(1);
+        late.tick(u64::MAX / 2);
+        let newer = Stamp::next(&mut late, &VectorClock::default());
+        let older = Stamp::next(&mut HlcClock::new(2), &VectorClock::default());
+        store.apply_update(&DocUpdate::new("doc", b"newer".to_vec()).with_stamp(newer));
+        store.apply_update(&DocUpdate::new("doc", b"older".to_vec()).with_stamp(older));
+        assert_eq!(store.content("doc"), Some(b"newer".to_vec()));
+        drop(store);
+
+        let store = FileDocStore::open(&dir).unwrap();
+        assert_eq!(store.merge_policy("doc"), MergePolicy::Lww);
+        assert_eq!(store.version("doc"), 2);
+        assert_eq!(store.content("doc"), Some(b"newer".to_vec()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn partial_fetches_survive_reopen() {
+        let dir = temp_dir("fetches");
+        let content = vec![7u8; 300];
+        let manifest = FetchManifest::new("doc/1", 4, &content, 100);
+        let mut store = FileDocStore::open(&dir).unwrap();
+        store.start_partial_fetch(&manifest, &content[..100]);
+        store.append_partial_fetch("doc/1", &content[100..200]);
+        drop(store);
+
+        // Kept aside, not taken for a document
+        let mut store = FileDocStore::open(&dir).unwrap();
+        assert!(store.doc_ids().is_empty());
+        assert_eq!(store.partial_fetch("doc/1"), Some((manifest, content[..200].to_vec())));
+        store.clear_partial_fetch("doc/1");
+        assert_eq!(store.partial_fetch("doc/1"), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn replay_watermarks_survive_reopen() {
+        use crate::behaviour::docstore::{HlcClock, Stamp};
+        use libp2p::PeerId;
+
+        let dir = temp_dir("replay");
+        let author = PeerId::random();
+        let old = DocUpdate::new("doc", b"old".to_vec())
+            .with_stamp(Stamp::next(&mut HlcClock::for_peer(&author), &VectorClock::default()));
+        let mut store = FileDocStore::open(&dir).unwrap();
+        assert!(store.is_fresh(&author, &old));
+        store.apply_update(&old);
+        drop(store);
+
+        let store = FileDocStore::open(&dir).unwrap();
+        assert!(!store.is_fresh(&author, &old));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unversioned_stores_open_once_migrated() {
+        use crate::behaviour::docstore::{HlcClock, Stamp};
+        use libp2p::PeerId;
+
+        let dir = temp_dir("unversioned");
+        let author = PeerId::random();
+        let update = DocUpdate::new("doc", b"stamped".to_vec())
+            .with_stamp(Stamp::next(&mut HlcClock::for_peer(&author), &VectorClock::default()));
+        let mut store = FileDocStore::open(&dir).unwrap();
+        store.apply_update(&update);
+        store.pin("doc");
+        drop(store);
+        assert_eq!(format_version(&dir).unwrap(), Some(1));
+
+        // As written before format versions, digests and per-author watermarks
+        let doc_dir = dir.join(encode_doc_dir("doc"));
+        let clock_path = doc_dir.join(CLOCK_FILE);
+        let mut clock: serde_json::Value = read_json(&clock_path).unwrap().unwrap();
+        clock.as_object_mut().unwrap().remove("authors");
+        write_json(&clock_path, &clock).unwrap();
+        fs::remove_file(doc_dir.join(DIGESTS_FILE)).unwrap();
+        fs::remove_file(dir.join(FORMAT_FILE)).unwrap();
+        assert_eq!(format_version(&dir).unwrap(), Some(0));
+        assert_eq!(FileDocStore::open(&dir).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        migrate_unversioned(&dir).unwrap();
+        let mut store = FileDocStore::open(&dir).unwrap();
+        assert_eq!(store.content("doc"), Some(b"stamped".to_vec()));
+        assert!(store.is_pinned("doc"));
+        assert!(!store.is_fresh(&author, &update), "the watermark came back from the log");
+        assert_eq!(fs::read(doc_dir.join(DIGESTS_FILE)).unwrap().len(), 32);
+        assert_eq!(store.verify("doc"), Ok(()));
+        fs::remove_dir_all(&dir).unwrap();
+
+        // A directory with nothing in it yet is simply a new store
+        assert_eq!(format_version(&dir).unwrap(), None);
+    }
+}
@@ -0,0 +1,326 @@
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use super::{now_ms, CompactionReport, DocEntry, DocStore, MergePolicy, StoredUpdate, DEFAULT_RETENTION};
+use crate::behaviour::docstore::{DocUpdate, Hlc, Snapshot, VectorClock};
+
+/// In-memory [`DocStore`]; everything is lost when the process exits.
+#[derive(Debug)]
+pub struct MemoryDocStore {
+    docs: HashMap<String, DocEntry>,
+    pins: HashSet<String>,
+    policies: HashMap<String, MergePolicy>,
+    retention: Duration,
+}
+
+impl Default for MemoryDocStore {
+    fn default() -> Self {
+        Self::with_retention(DEFAULT_RETENTION)
+    }
+}
+
+impl MemoryDocStore {
+    pub fn with_retention(retention: Duration) -> Self {
+        Self { docs: HashMap::new(), pins: HashSet::new(), policies: HashMap::new(), retention }
+    }
+}
+
+impl DocStore for MemoryDocStore {
+    fn apply_update(&mut self, update: &DocUpdate) -> u64 {
+        let policy = self.merge_policy(&update.doc_id);
+        let entry = self.docs.entry(update.doc_id.clone()).or_default();
+        entry.apply(update, policy, now_ms()).version
+    }
+
+    fn version(&self, doc_id: &str) -> u64 {
+        self.docs.get(doc_id).map_or(0, |d| d.version)
+    }
+
+    fn content(&self, doc_id: &str) -> Option<Vec<u8>> {
+        self.docs.get(doc_id).map(|d| d.content.clone())
+    }
+
+    fn updates_since(&self, doc_id: &str, version: u64) -> Vec<StoredUpdate> {
+        self.docs
+            .get(doc_id)
+            .map(|d| d.log.iter().filter(|u| u.version > version).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    fn log(&self, doc_id: &str) -> &[StoredUpdate] {
+        self.docs.get(doc_id).map(|d| d.log.as_slice()).unwrap_or_default()
+    }
+
+    fn latest_snapshot(&self, doc_id: &str) -> Option<Snapshot> {
+        self.docs.get(doc_id).and_then(|d| d.snapshot.clone())
+    }
+
+    fn make_snapshot(&mut self, doc_id: &str) -> Option<Snapshot> {
+        Some(self.docs.get_mut(doc_id)?.make_snapshot(doc_id))
+    }
+
+    fn install_snapshot(&mut self, snapshot: Snapshot) -> bool {
+        if !snapshot.verify() || snapshot.version <= self.version(&snapshot.doc_id) {
+            return false;
+        }
+        self.docs.entry(snapshot.doc_id.clone()).or_default().install_snapshot(snapshot)
+    }
+
+    fn doc_ids(&self) -> Vec<String> {
+        self.docs.keys().cloned().collect()
+    }
+
+    fn compact(&mut self, doc_id: &str) -> CompactionReport {
+        self.compact_within(doc_id, self.retention)
+    }
+
+    fn compact_within(&mut self, doc_id: &str, retention: Duration) -> CompactionReport {
+        if self.pins.contains(doc_id) {
+            return CompactionReport::default();
+        }
+        let retention = retention.min(self.retention);
+        self.docs.get_mut(doc_id).map(|d| d.compact(retention, now_ms())).unwrap_or_default()
+    }
+
+    fn pin(&mut self, doc_id: &str) -> bool {
+        self.pins.insert(doc_id.to_string())
+    }
+
+    fn unpin(&mut self, doc_id: &str) -> bool {
+        self.pins.remove(doc_id)
+    }
+
+    fn pins(&self) -> Vec<String> {
+        self.pins.iter().cloned().collect()
+    }
+
+    fn is_pinned(&self, doc_id: &str) -> bool {
+        self.pins.contains(doc_id)
+    }
+
+    fn merge_policy(&self, doc_id: &str) -> MergePolicy {
+        self.policies.get(doc_id).copied().unwrap_or_default()
+    }
+
+    fn set_merge_policy(&mut self, doc_id: &str, policy: MergePolicy) {
+        self.policies.insert(doc_id.to_string(), policy);
+    }
+
+    fn clock(&self, doc_id: &str) -> VectorClock {
+        self.docs.get(doc_id).map(|d| d.clock.clone()).unwrap_or_default()
+    }
+
+    fn author_hlc(&self, doc_id: &str, author: u64) -> Option<Hlc> {
+        self.docs.get(doc_id)?.authors.get(&author).copied()
+    }
+
+    fn has_applied(&self, doc_id: &str, author: u64, seq: u64) -> bool {
+        self.docs.get(doc_id).and_then(|d| d.seqs.get(&author)).is_some_and(|seqs| seqs.contains(seq))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::behaviour::docstore::{SnapshotAssembler, SnapshotChunk};
+
+    fn update(n: u32) -> DocUpdate {
+        DocUpdate::new("doc", format!("content v{n}").into_bytes())
+    }
+
+    #[test]
+    fn fresh_client_converges_from_snapshot_plus_updates() {
+        let mut full_node = MemoryDocStore::default();
+        for n in 1..=100 {
+            full_node.apply_update(&update(n));
+        }
+        let snapshot = full_node.make_snapshot("doc").unwrap();
+        assert_eq!(snapshot.version, 100);
+        full_node.apply_update(&update(101));
+        full_node.apply_update(&update(102));
+
+        // The client receives the snapshot over the wire in chunks, then the two later updates
+        let mut client = MemoryDocStore::default();
+        let mut assembler = SnapshotAssembler::default();
+        let mut received = None;
+        for chunk in snapshot.chunks(4) {
+            received = assembler.push(SnapshotChunk::decode(&chunk.encode()).unwrap()).unwrap();
+        }
+        assert!(client.install_snapshot(received.unwrap()));
+        for u in full_node.updates_since("doc", snapshot.version) {
+            client.apply_update(&DocUpdate::new("doc", u.payload));
+        }
+
+        assert_eq!(client.version("doc"), 102);
+        assert_eq!(client.content("doc"), full_node.content("doc"));
+        assert_eq!(client.updates_since("doc", 0).len(), 2);
+    }
+
+    #[test]
+    fn stale_corrupt_or_far_ahead_snapshots_are_ignored() {
+        let mut store = MemoryDocStore::default();
+        for n in 1..=5 {
+            store.apply_update(&update(n));
+        }
+        assert!(!store.install_snapshot(Snapshot::new("doc", 5, b"old".to_vec())));
+        let mut corrupt = Snapshot::new("doc", 9, b"new".to_vec());
+        corrupt.bytes = b"bad".to_vec();
+        assert!(!store.install_snapshot(corrupt));
+        // A version the next update would overflow
+        assert!(!store.install_snapshot(Snapshot::new("doc", u64::MAX, b"far".to_vec())));
+        assert_eq!(store.content("doc"), Some(b"content v5".to_vec()));
+    }
+
+    #[test]
+    fn compaction_only_drops_snapshotted_updates_outside_retention() {
+        let mut store = MemoryDocStore::with_retention(Duration::ZERO);
+        for n in 1..=10 {
+            store.apply_update(&update(n));
+        }
+        // Nothing is covered by a snapshot yet
+        assert_eq!(store.compact("doc"), CompactionReport::default());
+
+        store.make_snapshot("doc");
+        store.apply_update(&update(11));
+        let report = store.compact_all();
+        assert_eq!(report.removed_updates, 10);
+        assert_eq!(report.reclaimed_bytes, (1..=10).map(|n| format!("content v{n}").len()).sum());
+        assert_eq!(store.updates_since("doc", 0).len(), 1);
+        assert_eq!(store.content("doc"), Some(b"content v11".to_vec()));
+
+        // Within the retention window the covered updates stay available for gap re-requests
+        let mut retained = MemoryDocStore::with_retention(Duration::from_secs(3600));
+        retained.apply_update(&update(1));
+        retained.make_snapshot("doc");
+        assert_eq!(retained.compact("doc").removed_updates, 0);
+    }
+
+    #[test]
+    fn compaction_skips_pinned_documents() {
+        let mut store = MemoryDocStore::with_retention(Duration::ZERO);
+        for doc in ["pinned", "plain"] {
+            for n in 0..3u8 {
+                store.apply_update(&DocUpdate::new(doc, vec![n]));
+            }
+            store.make_snapshot(doc);
+        }
+        assert!(store.pin("pinned"));
+        assert!(!store.pin("pinned"));
+
+        assert_eq!(store.compact_all().removed_updates, 3);
+        assert_eq!(store.updates_since("pinned", 0).len(), 3);
+        assert!(store.updates_since("plain", 0).is_empty());
+
+        assert!(store.unpin("pinned"));
+        assert_eq!(store.compact("pinned").removed_updates, 3);
+    }
+
+    #[test]
+    fn lww_converges_for_any_delivery_order() {
+        use crate::behaviour::docstore::{HlcClock, Stamp};
+        use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+        // Three authors write concurrently, sometimes catching up on an earlier update first
+        let mut authors: Vec<_> = (1..=3).map(|n| (HlcClock::new(n), MemoryDocStore::default())).collect();
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut published: Vec<DocUpdate> = Vec::new();
+        for i in 0..30 {
+            let (clock, store) = &mut authors[i % 3];
+            if !published.is_empty() && rng.gen_bool(0.3) {
+                let seen = published.choose(&mut rng).unwrap();
+                clock.observe(&seen.stamp.as_ref().unwrap().hlc);
+                store.apply_update(seen);
+            }
+            let stamp = Stamp::next(clock, &store.clock("doc"));
+            let update = DocUpdate::new("doc", format!("{}:{i}", i % 3)).with_stamp(stamp);
+            store.apply_update(&update);
+            published.push(update);
+        }
+        let winner = published.iter().max_by_key(|u| u.stamp.as_ref().unwrap().hlc).unwrap().payload.clone();
+
+        for seed in 0..50 {
+            let mut order = published.clone();
+            order.shuffle(&mut StdRng::seed_from_u64(seed));
+            let mut replica = MemoryDocStore::default();
+            replica.set_merge_policy("doc", MergePolicy::Lww);
+            for update in &order {
+                replica.apply_update(update);
+            }
+            assert_eq!(replica.content("doc"), Some(winner.clone()));
+            assert_eq!(replica.version("doc"), 30);
+        }
+    }
+
+    #[test]
+    fn manual_policy_keeps_content_on_concurrent_updates() {
+        use crate::behaviour::docstore::{HlcClock, Stamp};
+
+        let mut store = MemoryDocStore::default();
+        store.set_merge_policy("doc", MergePolicy::Manual);
+        let base = VectorClock::default();
+        let a = Stamp::next(&mut HlcClock::new(1), &base);
+        let b = Stamp::next(&mut HlcClock::new(2), &base);
+        store.apply_update(&DocUpdate::new("doc", b"a".to_vec()).with_stamp(a));
+        store.apply_update(&DocUpdate::new("doc", b"b".to_vec()).with_stamp(b));
+        assert_eq!(store.content("doc"), Some(b"a".to_vec()));
+        assert_eq!(store.updates_since("doc", 0).len(), 2);
+
+        // A follow-up that has seen both resolves the conflict
+        let resolved = Stamp::next(&mut HlcClock::new(1), &store.clock("doc"));
+        store.apply_update(&DocUpdate::new("doc", b"ab".to_vec()).with_stamp(resolved));
+        assert_eq!(store.content("doc"), Some(b"ab".to_vec()));
+    }
+
+    #[test]
+    fn replayed_envelope_does_not_mutate_state() {
+        use crate::behaviour::docstore::{decode_updates, Envelope, HlcClock, Stamp};
+        use libp2p::PeerId;
+
+        let author = PeerId::random();
+        let mut clock = HlcClock::for_peer(&author);
+        let mut store = MemoryDocStore::default();
+        let receive = |store: &mut MemoryDocStore, source: &PeerId, bytes: &[u8]| {
+            let updates = decode_updates(bytes).unwrap();
+            if !updates.iter().all(|u| store.is_fresh(source, u)) {
+                return false;
+            }
+            for update in &updates {
+                store.apply_update(update);
+            }
+            true
+        };
+
+        let wipe = DocUpdate::new("doc", Vec::new()).with_stamp(Stamp::next(&mut clock, &VectorClock::default()));
+        let captured = Envelope::Update(wipe).encode();
+        assert!(receive(&mut store, &author, &captured));
+        let rewrite = DocUpdate::new("doc", b"fresh".to_vec()).with_stamp(Stamp::next(&mut clock, &store.clock("doc")));
+        assert!(receive(&mut store, &author, &Envelope::Update(rewrite).encode()));
+
+        // Replayed by the author's peer id or relayed under someone else's: both refused
+        assert!(!receive(&mut store, &author, &captured));
+        assert!(!receive(&mut store, &PeerId::random(), &captured));
+        assert_eq!(store.content("doc"), Some(b"fresh".to_vec()));
+        assert_eq!(store.version("doc"), 2);
+    }
+
+    #[test]
+    fn late_updates_are_fresh_until_applied() {
+        use crate::behaviour::docstore::{Freshness, HlcClock, Stamp};
+        use libp2p::PeerId;
+
+        let author = PeerId::random();
+        let mut clock = HlcClock::for_peer(&author);
+        let first = DocUpdate::new("doc", b"a".to_vec()).with_stamp(Stamp::next(&mut clock, &VectorClock::default()));
+        let after = first.stamp.as_ref().unwrap().clock.clone();
+        let second = DocUpdate::new("doc", b"ab".to_vec()).with_stamp(Stamp::next(&mut clock, &after));
+        let mut store = MemoryDocStore::default();
+        store.apply_update(&second);
+
+        assert_eq!(store.freshness(&author, &first), Freshness::Fresh, "overtaken, not replayed");
+        store.apply_update(&first);
+        assert_eq!(store.freshness(&author, &first), Freshness::Repeat);
+        assert_eq!(store.freshness(&author, &second), Freshness::Repeat);
+        assert_eq!(store.freshness(&PeerId::random(), &first), Freshness::Forged);
+        assert_eq!(store.log("doc").len(), 2);
+    }
+}
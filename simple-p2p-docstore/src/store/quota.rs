@@ -0,0 +1,474 @@
+//! Per-room quotas, so one room cannot take all of a shared FullNode's disk. Quotas per
+//! author live in [`super::author_quota`] and are enforced through the same sink.
+//!
+//! A document belongs to the room its id names before the first `/` (`"team/notes"` is
+//! in room `"team"`); ids without one belong to no room and are never limited. A room's
+//! [`RoomQuota`] caps the bytes its logged updates take, how many documents it holds and
+//! how long its snapshotted history is kept. Updates that would go over are turned away
+//! when received, before gossipsub forwards them and all updates of a message together
+//! (see [`UpdateSink::admit`](crate::behaviour::docstore::UpdateSink::admit)), and rooms close
+//! to a limit are compacted down to a fresh snapshot instead of waiting out the
+//! retention window.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::Duration;
+
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use web_time::Instant;
+
+use super::author_quota::AuthorQuotas;
+use super::{CompactionReport, DocStore};
+use crate::behaviour::docstore::{DocUpdate, Freshness, Snapshot};
+
+/// Share of a limit, in percent, from which a room counts as nearly full.
+pub const NEARLY_FULL_PERCENT: u64 = 90;
+
+/// At most one over-quota notice per room in this interval.
+pub const NOTICE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// The room `doc_id` belongs to, if any.
+pub fn room_of(doc_id: &str) -> Option<&str> {
+    doc_id.split_once('/').map(|(room, _)| room).filter(|room| !room.is_empty())
+}
+
+/// Limits for one room; unset ones don't apply.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RoomQuota {
+    /// Bytes of logged updates across the room's documents.
+    pub max_bytes: Option<u64>,
+    pub max_docs: Option<u64>,
+    /// How long updates already covered by a snapshot are kept, when shorter than the
+    /// store's retention window.
+    pub max_age_secs: Option<u64>,
+}
+
+impl RoomQuota {
+    pub fn max_age(&self) -> Option<Duration> {
+        self.max_age_secs.map(Duration::from_secs)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoomUsage {
+    pub bytes: u64,
+    pub docs: u64,
+}
+
+/// Why an update was turned away. Authors are named by peer id, see
+/// [`AuthorReport::author`](super::author_quota::AuthorReport::author).
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error, Serialize, Deserialize)]
+pub enum QuotaExceeded {
+    #[error("room {room} would hold {bytes} bytes, over its quota of {max}")]
+    Bytes { room: String, bytes: u64, max: u64 },
+    #[error("room {room} already holds its quota of {max} documents")]
+    Docs { room: String, max: u64 },
+    #[error("author {author} would have {bytes} bytes stored, over their quota of {max}")]
+    AuthorBytes { author: String, bytes: u64, max: u64 },
+    #[error("author {author} already has updates stored in their quota of {max} documents")]
+    AuthorDocs { author: String, max: u64 },
+    #[error("author {author} already published their quota of {max} updates this minute")]
+    AuthorRate { author: String, max: u64 },
+}
+
+impl QuotaExceeded {
+    /// The room whose quota was hit, `None` for an author's.
+    pub fn room(&self) -> Option<&str> {
+        match self {
+            QuotaExceeded::Bytes { room, .. } | QuotaExceeded::Docs { room, .. } => Some(room),
+            _ => None,
+        }
+    }
+
+    /// The author whose quota was hit, `None` for a room's.
+    pub fn author(&self) -> Option<&str> {
+        match self {
+            QuotaExceeded::AuthorBytes { author, .. }
+            | QuotaExceeded::AuthorDocs { author, .. }
+            | QuotaExceeded::AuthorRate { author, .. } => Some(author),
+            _ => None,
+        }
+    }
+
+    /// The room or author whose quota was hit.
+    pub fn scope(&self) -> &str {
+        self.room().or(self.author()).unwrap_or_default()
+    }
+}
+
+/// One room's usage and quota, as [`RoomQuotas::report`] lists them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoomReport {
+    pub room: String,
+    pub usage: RoomUsage,
+    pub quota: Option<RoomQuota>,
+    pub nearly_full: bool,
+}
+
+/// The configured quotas and what each room uses, kept up to date as the store changes.
+#[derive(Debug, Default)]
+pub struct RoomQuotas {
+    quotas: BTreeMap<String, RoomQuota>,
+    /// Logged bytes per stored document, so a change to one updates its room's total.
+    doc_bytes: HashMap<String, u64>,
+    usage: BTreeMap<String, RoomUsage>,
+    /// When each room's last over-quota notice went out.
+    notified: HashMap<String, Instant>,
+}
+
+impl RoomQuotas {
+    pub fn new(quotas: impl IntoIterator<Item = (String, RoomQuota)>) -> Self {
+        Self { quotas: quotas.into_iter().collect(), ..Default::default() }
+    }
+
+    /// Read quotas from a JSON file mapping room names to [`RoomQuota`]s, e.g.
+    /// `{"team": {"max_bytes": 1000000, "max_docs": 100}}`.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn from_file(path: &std::path::Path) -> std::io::Result<Self> {
+        let quotas: BTreeMap<String, RoomQuota> = serde_json::from_slice(&std::fs::read(path)?)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        Ok(Self::new(quotas))
+    }
+
+    /// Set or (with `None`) lift `room`'s quota. Returns the previous one.
+    pub fn set(&mut self, room: impl Into<String>, quota: Option<RoomQuota>) -> Option<RoomQuota> {
+        let room = room.into();
+        match quota {
+            Some(quota) => self.quotas.insert(room, quota),
+            None => self.quotas.remove(&room),
+        }
+    }
+
+    pub fn quota(&self, room: &str) -> Option<&RoomQuota> {
+        self.quotas.get(room)
+    }
+
+    pub fn usage(&self, room: &str) -> RoomUsage {
+        self.usage.get(room).copied().unwrap_or_default()
+    }
+
+    /// Count every document of `store` from scratch.
+    pub fn recount<S: DocStore + ?Sized>(&mut self, store: &S) {
+        self.doc_bytes.clear();
+        self.usage.clear();
+        for doc_id in store.doc_ids() {
+            self.observe(store, &doc_id);
+        }
+    }
+
+    /// Bring `doc_id`'s share of its room's usage up to date after `store` changed it.
+    pub fn observe<S: DocStore + ?Sized>(&mut self, store: &S, doc_id: &str) {
+        let Some(room) = room_of(doc_id) else {
+            return;
+        };
+        let stored = store.version(doc_id) > 0;
+        let bytes: u64 = store.log(doc_id).iter().map(|u| u.payload.len() as u64).sum();
+        let previous =
+            if stored { self.doc_bytes.insert(doc_id.to_string(), bytes) } else { self.doc_bytes.remove(doc_id) };
+        let usage = self.usage.entry(room.to_string()).or_default();
+        usage.bytes = usage.bytes.saturating_sub(previous.unwrap_or(0)) + if stored { bytes } else { 0 };
+        match (previous.is_some(), stored) {
+            (false, true) => usage.docs += 1,
+            (true, false) => usage.docs = usage.docs.saturating_sub(1),
+            _ => {}
+        }
+    }
+
+    /// Whether storing `updates` together keeps their rooms within quota. `is_new` tells
+    /// whether a document would be added to its room.
+    pub fn admit(&self, is_new: impl Fn(&str) -> bool, updates: &[DocUpdate]) -> Result<(), QuotaExceeded> {
+        let mut added: HashMap<&str, RoomUsage> = HashMap::new();
+        let mut new_docs = HashSet::new();
+        for update in updates {
+            let Some((room, quota)) = room_of(&update.doc_id).and_then(|room| Some((room, self.quotas.get(room)?)))
+            else {
+                continue;
+            };
+            let usage = self.usage(room);
+            let added = added.entry(room).or_default();
+            if is_new(&update.doc_id) && new_docs.insert(update.doc_id.as_str()) {
+                if let Some(max) = quota.max_docs.filter(|max| usage.docs + added.docs >= *max) {
+                    return Err(QuotaExceeded::Docs { room: room.to_string(), max });
+                }
+                added.docs += 1;
+            }
+            added.bytes += update.payload.len() as u64;
+            let bytes = usage.bytes + added.bytes;
+            if let Some(max) = quota.max_bytes.filter(|max| bytes > *max) {
+                return Err(QuotaExceeded::Bytes { room: room.to_string(), bytes, max });
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `room` uses [`NEARLY_FULL_PERCENT`] of one of its limits or more.
+    pub fn is_nearly_full(&self, room: &str) -> bool {
+        let Some(quota) = self.quotas.get(room) else {
+            return false;
+        };
+        let usage = self.usage(room);
+        let near = |used: u64, max: Option<u64>| max.is_some_and(|max| used * 100 >= max * NEARLY_FULL_PERCENT);
+        near(usage.bytes, quota.max_bytes) || near(usage.docs, quota.max_docs)
+    }
+
+    /// The retention to compact `doc_id` with instead of the store's own, if its room's
+    /// quota asks for a shorter one: none at all once the room is nearly full.
+    pub fn retention(&self, doc_id: &str) -> Option<Duration> {
+        let room = room_of(doc_id)?;
+        if self.is_nearly_full(room) {
+            return Some(Duration::ZERO);
+        }
+        self.quotas.get(room)?.max_age()
+    }
+
+    /// Compact every document of `store`, with the retention of its room's quota.
+    /// Documents of nearly full rooms are snapshotted first, so their whole log can go.
+    pub fn compact<S: DocStore + ?Sized>(&mut self, store: &mut S) -> CompactionReport {
+        let mut report = CompactionReport::default();
+        for doc_id in store.doc_ids() {
+            let nearly_full = room_of(&doc_id).is_some_and(|room| self.is_nearly_full(room));
+            if nearly_full && !store.is_pinned(&doc_id) && !store.log(&doc_id).is_empty() {
+                store.make_snapshot(&doc_id);
+            }
+            report += match self.retention(&doc_id) {
+                Some(retention) => store.compact_within(&doc_id, retention),
+                None => store.compact(&doc_id),
+            };
+            self.observe(store, &doc_id);
+        }
+        report
+    }
+
+    /// Whether a notice that `room` is over quota should go out now, at most one per
+    /// [`NOTICE_INTERVAL`].
+    pub fn notice_due(&mut self, room: &str, now: Instant) -> bool {
+        if self.notified.get(room).is_some_and(|at| now.duration_since(*at) < NOTICE_INTERVAL) {
+            return false;
+        }
+        self.notified.insert(room.to_string(), now);
+        true
+    }
+
+    /// Every room that holds documents or has a quota, by name.
+    pub fn report(&self) -> Vec<RoomReport> {
+        let mut rooms: Vec<&String> = self.usage.keys().chain(self.quotas.keys()).collect();
+        rooms.sort();
+        rooms.dedup();
+        rooms
+            .into_iter()
+            .map(|room| RoomReport {
+                room: room.clone(),
+                usage: self.usage(room),
+                quota: self.quotas.get(room).copied(),
+                nearly_full: self.is_nearly_full(room),
+            })
+            .collect()
+    }
+}
+
+/// Per-room usage and limits as `/metrics` lines, labelled with the room, so dashboards
+/// can warn before a quota is hit.
+pub fn metrics(reports: &[RoomReport]) -> Vec<(String, u64)> {
+    let mut metrics = Vec::new();
+    for report in reports {
+        let room = report.room.replace('\\', "\\\\").replace('"', "\\\"");
+        metrics.push((format!("docstore_room_bytes{{room=\"{room}\"}}"), report.usage.bytes));
+        metrics.push((format!("docstore_room_docs{{room=\"{room}\"}}"), report.usage.docs));
+        let quota = report.quota.unwrap_or_default();
+        if let Some(max) = quota.max_bytes {
+            metrics.push((format!("docstore_room_max_bytes{{room=\"{room}\"}}"), max));
+        }
+        if let Some(max) = quota.max_docs {
+            metrics.push((format!("docstore_room_max_docs{{room=\"{room}\"}}"), max));
+        }
+    }
+    metrics
+}
+
+/// A store seen through the room and author quotas: updates are admitted against them,
+/// and every change is counted.
+pub struct QuotaSink<'a, S: ?Sized> {
+    pub store: &'a mut S,
+    pub quotas: &'a mut RoomQuotas,
+    pub authors: &'a mut AuthorQuotas,
+}
+
+impl<'a, S: DocStore + ?Sized> QuotaSink<'a, S> {
+    pub fn new(store: &'a mut S, quotas: &'a mut RoomQuotas, authors: &'a mut AuthorQuotas) -> Self {
+        Self { store, quotas, authors }
+    }
+}
+
+impl<S: DocStore + ?Sized> crate::behaviour::docstore::UpdateSink for QuotaSink<'_, S> {
+    fn freshness(&self, source: &PeerId, update: &DocUpdate) -> Freshness {
+        self.store.freshness(source, update)
+    }
+
+    /// Anonymous messages name no author, so only room quotas apply to them.
+    fn admit(&self, source: Option<&PeerId>, updates: &[DocUpdate]) -> Result<(), QuotaExceeded> {
+        self.quotas.admit(|doc_id| self.store.version(doc_id) == 0, updates)?;
+        match source {
+            Some(source) => self.authors.admit(source, updates, super::now_ms()),
+            None => Ok(()),
+        }
+    }
+
+    fn apply_update(&mut self, update: &DocUpdate) -> u64 {
+        let version = self.store.apply_update(update);
+        self.quotas.observe(&*self.store, &update.doc_id);
+        self.authors.observe(&*self.store, &update.doc_id);
+        version
+    }
+
+    fn apply_transaction(&mut self, updates: &[DocUpdate]) -> Vec<u64> {
+        let versions = self.store.apply_transaction(updates);
+        for update in updates {
+            self.quotas.observe(&*self.store, &update.doc_id);
+            self.authors.observe(&*self.store, &update.doc_id);
+        }
+        versions
+    }
+
+    fn charge(&mut self, source: &PeerId, updates: &[DocUpdate]) {
+        self.authors.charge(source, updates, super::now_ms());
+    }
+
+    fn has_version(&self, doc_id: &str, version: u64) -> bool {
+        version <= self.store.version(doc_id)
+    }
+
+    fn install_snapshot(&mut self, snapshot: &Snapshot) -> bool {
+        let installed = self.store.install_snapshot(snapshot.clone());
+        self.quotas.observe(&*self.store, &snapshot.doc_id);
+        self.authors.observe(&*self.store, &snapshot.doc_id);
+        installed
+    }
+
+    fn current_version(&self, doc_id: &str) -> Option<u64> {
+        Some(self.store.version(doc_id))
+    }
+
+    fn is_author(&self, doc_id: &str, peer_id: &PeerId) -> bool {
+        crate::behaviour::docstore::UpdateSink::is_author(&*self.store, doc_id, peer_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::behaviour::docstore::UpdateSink;
+    use crate::store::MemoryDocStore;
+
+    fn quotas(quota: RoomQuota) -> RoomQuotas {
+        RoomQuotas::new([("team".to_string(), quota)])
+    }
+
+    fn update(doc_id: &str, len: usize) -> DocUpdate {
+        DocUpdate::new(doc_id, vec![7; len])
+    }
+
+    #[test]
+    fn rooms_are_named_by_the_doc_id_prefix() {
+        assert_eq!(room_of("team/notes"), Some("team"));
+        assert_eq!(room_of("team/a/b"), Some("team"));
+        assert_eq!(room_of("notes"), None);
+        assert_eq!(room_of("/notes"), None);
+    }
+
+    #[test]
+    fn admits_updates_until_a_limit_is_hit() {
+        let mut store = MemoryDocStore::default();
+        let mut quotas = quotas(RoomQuota { max_bytes: Some(100), max_docs: Some(2), max_age_secs: None });
+        let mut authors = AuthorQuotas::default();
+        let mut sink = QuotaSink::new(&mut store, &mut quotas, &mut authors);
+        for doc_id in ["team/a", "team/b"] {
+            let update = update(doc_id, 30);
+            assert_eq!(sink.admit(None, std::slice::from_ref(&update)), Ok(()));
+            sink.apply_update(&update);
+        }
+        assert_eq!(sink.admit(None, &[update("team/c", 1)]), Err(QuotaExceeded::Docs { room: "team".into(), max: 2 }));
+        assert_eq!(sink.admit(None, &[update("team/a", 30)]), Ok(()));
+        assert_eq!(
+            sink.admit(None, &[update("team/a", 41)]),
+            Err(QuotaExceeded::Bytes { room: "team".into(), bytes: 101, max: 100 })
+        );
+        // A batch is admitted as a whole
+        assert_eq!(
+            sink.admit(None, &[update("team/a", 30), update("team/b", 11)]),
+            Err(QuotaExceeded::Bytes { room: "team".into(), bytes: 101, max: 100 })
+        );
+        // Other rooms and roomless documents are not limited
+        assert_eq!(sink.admit(None, &[update("other/a", 1000)]), Ok(()));
+        assert_eq!(sink.admit(None, &[update("notes", 1000)]), Ok(()));
+        assert_eq!(quotas.usage("team"), RoomUsage { bytes: 60, docs: 2 });
+    }
+
+    #[test]
+    fn usage_follows_the_store() {
+        let mut store = MemoryDocStore::default();
+        for len in [10, 20] {
+            store.apply_update(&update("team/a", len));
+        }
+        store.apply_update(&update("team/b", 5));
+        let mut quotas = RoomQuotas::default();
+        quotas.recount(&store);
+        assert_eq!(quotas.usage("team"), RoomUsage { bytes: 35, docs: 2 });
+
+        // Recounting a document replaces its share instead of adding to it
+        store.apply_update(&update("team/b", 5));
+        quotas.observe(&store, "team/b");
+        quotas.observe(&store, "team/b");
+        assert_eq!(quotas.usage("team"), RoomUsage { bytes: 40, docs: 2 });
+    }
+
+    #[test]
+    fn nearly_full_rooms_are_compacted_to_a_snapshot() {
+        let mut store = MemoryDocStore::default();
+        let mut quotas = quotas(RoomQuota { max_bytes: Some(100), ..Default::default() });
+        for _ in 0..9 {
+            store.apply_update(&update("team/a", 10));
+        }
+        store.apply_update(&update("other/a", 10));
+        quotas.recount(&store);
+        assert!(quotas.is_nearly_full("team"));
+        assert_eq!(quotas.retention("team/a"), Some(Duration::ZERO));
+        assert_eq!(quotas.retention("other/a"), None);
+
+        let report = quotas.compact(&mut store);
+        assert_eq!(report.removed_updates, 9);
+        assert_eq!(quotas.usage("team"), RoomUsage { bytes: 0, docs: 1 });
+        assert_eq!(store.log("other/a").len(), 1);
+        assert!(!quotas.is_nearly_full("team"));
+    }
+
+    #[test]
+    fn notices_are_rate_limited_per_room() {
+        let mut quotas = RoomQuotas::default();
+        let now = Instant::now();
+        assert!(quotas.notice_due("team", now));
+        assert!(!quotas.notice_due("team", now + NOTICE_INTERVAL / 2));
+        assert!(quotas.notice_due("other", now));
+        assert!(quotas.notice_due("team", now + NOTICE_INTERVAL));
+    }
+
+    #[test]
+    fn reports_and_metrics_cover_rooms_with_usage_or_a_quota() {
+        let mut store = MemoryDocStore::default();
+        store.apply_update(&update("a\"b/doc", 3));
+        let mut quotas = quotas(RoomQuota { max_docs: Some(5), ..Default::default() });
+        quotas.recount(&store);
+        let report = quotas.report();
+        assert_eq!(report.iter().map(|r| r.room.as_str()).collect::<Vec<_>>(), vec!["a\"b", "team"]);
+        assert_eq!(
+            metrics(&report),
+            vec![
+                ("docstore_room_bytes{room=\"a\\\"b\"}".to_string(), 3),
+                ("docstore_room_docs{room=\"a\\\"b\"}".to_string(), 1),
+                ("docstore_room_bytes{room=\"team\"}".to_string(), 0),
+                ("docstore_room_docs{room=\"team\"}".to_string(), 0),
+                ("docstore_room_max_docs{room=\"team\"}".to_string(), 5),
+            ]
+        );
+    }
+}
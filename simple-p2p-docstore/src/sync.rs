@@ -0,0 +1,4 @@
+//! Anti-entropy between stores: working out which documents two peers disagree on
+//! without sending each other every document's version.
+
+pub mod digest;
@@ -0,0 +1,293 @@
+//! Hierarchical digests of a store's document heads. A flat doc-id → version summary
+//! grows with every document a node holds; comparing digests lets two peers skip the
+//! parts of it they already agree on.
+//!
+//! Doc ids are bucketed by the leading nibbles of their SHA-256 hash, [`FANOUT`] buckets
+//! per level and [`DEPTH`] levels. Each bucket's digest is the wrapping sum of a hash of
+//! every (doc id, version) below it, so it is cheap to update as versions change and
+//! independent of insertion order. Peers exchange the root's child digests first,
+//! descend only into the buckets that differ, and swap flat summaries for the leaf
+//! buckets that still differ at the bottom.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use sha2::{Digest, Sha256};
+
+/// Child buckets per bucket; one hash nibble per level.
+pub const FANOUT: usize = 16;
+
+/// Levels below the root. With 16 buckets per level a leaf holds roughly one in 4096
+/// documents.
+pub const DEPTH: usize = 3;
+
+/// The nibbles leading from the root to a bucket; empty for the root itself.
+pub type BucketPath = Vec<u8>;
+
+fn doc_hash(doc_id: &str) -> [u8; 32] {
+    Sha256::digest(doc_id.as_bytes()).into()
+}
+
+fn nibble(hash: &[u8; 32], level: usize) -> u8 {
+    let byte = hash[level / 2];
+    if level % 2 == 0 {
+        byte >> 4
+    } else {
+        byte & 0x0f
+    }
+}
+
+/// Path of the leaf bucket holding a document.
+fn leaf_path(hash: &[u8; 32]) -> BucketPath {
+    (0..DEPTH).map(|level| nibble(hash, level)).collect()
+}
+
+fn entry_hash(doc_id: &str, version: u64) -> u64 {
+    let mut hasher = Sha256::new();
+    hasher.update(doc_id.as_bytes());
+    hasher.update(version.to_be_bytes());
+    let digest = hasher.finalize();
+    u64::from_be_bytes(digest[..8].try_into().expect("8 bytes"))
+}
+
+/// Digest tree over doc id → version heads.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HeadDigest {
+    versions: BTreeMap<String, u64>,
+    /// Digest and document count of every non-empty bucket, keyed by path.
+    buckets: HashMap<BucketPath, (u64, usize)>,
+    /// Documents in each non-empty leaf bucket.
+    leaves: HashMap<BucketPath, BTreeSet<String>>,
+}
+
+impl HeadDigest {
+    pub fn new(versions: impl IntoIterator<Item = (String, u64)>) -> Self {
+        let mut digest = Self::default();
+        for (doc_id, version) in versions {
+            digest.insert(doc_id, version);
+        }
+        digest
+    }
+
+    /// Set a document's version, e.g. after applying an update.
+    pub fn insert(&mut self, doc_id: String, version: u64) {
+        let leaf = leaf_path(&doc_hash(&doc_id));
+        let old = self.versions.insert(doc_id.clone(), version);
+        let delta = entry_hash(&doc_id, version).wrapping_sub(old.map_or(0, |v| entry_hash(&doc_id, v)));
+        self.adjust(&leaf, delta, if old.is_some() { 0 } else { 1 });
+        self.leaves.entry(leaf).or_default().insert(doc_id);
+    }
+
+    pub fn remove(&mut self, doc_id: &str) {
+        let Some(version) = self.versions.remove(doc_id) else {
+            return;
+        };
+        let leaf = leaf_path(&doc_hash(doc_id));
+        self.adjust(&leaf, 0u64.wrapping_sub(entry_hash(doc_id, version)), -1);
+        if let Some(docs) = self.leaves.get_mut(&leaf) {
+            docs.remove(doc_id);
+            if docs.is_empty() {
+                self.leaves.remove(&leaf);
+            }
+        }
+    }
+
+    /// Add `delta` to the digest and `count` to the size of every bucket above `leaf`.
+    fn adjust(&mut self, leaf: &[u8], delta: u64, count: isize) {
+        for len in 0..=DEPTH {
+            let path = &leaf[..len];
+            let (sum, docs) = self.buckets.entry(path.to_vec()).or_insert((0, 0));
+            *sum = sum.wrapping_add(delta);
+            *docs = docs.wrapping_add_signed(count);
+            // Drop emptied buckets so equal heads give equal digests
+            if *docs == 0 {
+                self.buckets.remove(path);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.versions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.versions.is_empty()
+    }
+
+    pub fn version(&self, doc_id: &str) -> Option<u64> {
+        self.versions.get(doc_id).copied()
+    }
+
+    /// Digest of the whole store; equal roots mean equal heads.
+    pub fn root(&self) -> u64 {
+        self.bucket(&[])
+    }
+
+    /// Digest of one bucket; `0` if it holds no documents.
+    pub fn bucket(&self, path: &[u8]) -> u64 {
+        self.buckets.get(path).map_or(0, |(sum, _)| *sum)
+    }
+
+    /// Digests of the buckets directly below `path`, which must be above the leaves.
+    pub fn children(&self, path: &[u8]) -> [u64; FANOUT] {
+        debug_assert!(path.len() < DEPTH);
+        let mut child = path.to_vec();
+        child.push(0);
+        std::array::from_fn(|n| {
+            *child.last_mut().expect("non-empty") = n as u8;
+            self.bucket(&child)
+        })
+    }
+
+    /// Paths of the children of `path` whose digests differ from `theirs`.
+    pub fn differing_children(&self, path: &[u8], theirs: &[u64; FANOUT]) -> Vec<BucketPath> {
+        self.children(path)
+            .iter()
+            .zip(theirs)
+            .enumerate()
+            .filter(|(_, (ours, theirs))| ours != theirs)
+            .map(|(n, _)| {
+                let mut child = path.to_vec();
+                child.push(n as u8);
+                child
+            })
+            .collect()
+    }
+
+    /// The flat doc id → version summary of everything below `path`.
+    pub fn entries(&self, path: &[u8]) -> BTreeMap<String, u64> {
+        self.leaves
+            .iter()
+            .filter(|(leaf, _)| leaf.starts_with(path))
+            .flat_map(|(_, docs)| docs.iter().map(|doc_id| (doc_id.clone(), self.versions[doc_id])))
+            .collect()
+    }
+}
+
+/// What a full comparison of two digests found, and what it cost.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Comparison {
+    /// Documents whose versions differ, including ones only one side has. Sorted.
+    pub differing: Vec<String>,
+    /// Bucket digests exchanged.
+    pub digests: usize,
+    /// Flat summary entries exchanged for the leaf buckets that differ.
+    pub entries: usize,
+    /// Round trips, counting the final exchange of leaf summaries.
+    pub rounds: usize,
+}
+
+/// Runs the descent between `ours` and `theirs` as the two peers would, level by level,
+/// starting with the root's children.
+pub fn compare(ours: &HeadDigest, theirs: &HeadDigest) -> Comparison {
+    let mut comparison = Comparison::default();
+    let mut frontier: Vec<BucketPath> = vec![Vec::new()];
+    while frontier.first().is_some_and(|path| path.len() < DEPTH) {
+        comparison.rounds += 1;
+        frontier = frontier
+            .iter()
+            .flat_map(|path| {
+                comparison.digests += 2 * FANOUT;
+                ours.differing_children(path, &theirs.children(path))
+            })
+            .collect();
+    }
+    if !frontier.is_empty() {
+        comparison.rounds += 1;
+    }
+    let mut differing = Vec::new();
+    for path in &frontier {
+        let (mine, other) = (ours.entries(path), theirs.entries(path));
+        comparison.entries += mine.len() + other.len();
+        differing.extend(mine.iter().filter(|(doc_id, v)| other.get(*doc_id) != Some(v)).map(|(d, _)| d.clone()));
+        differing.extend(other.keys().filter(|doc_id| !mine.contains_key(*doc_id)).cloned());
+    }
+    differing.sort();
+    comparison.differing = differing;
+    comparison
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn docs(n: usize) -> Vec<(String, u64)> {
+        (0..n).map(|i| (format!("doc-{i}"), i as u64 % 7 + 1)).collect()
+    }
+
+    #[test]
+    fn identical_stores_agree_on_the_root() {
+        let mut reversed = docs(500);
+        reversed.reverse();
+        let (a, b) = (HeadDigest::new(docs(500)), HeadDigest::new(reversed));
+        assert_eq!(a.root(), b.root());
+        assert_eq!(a, b);
+        assert_eq!(compare(&a, &b), Comparison { differing: vec![], digests: 2 * FANOUT, entries: 0, rounds: 1 });
+
+        let empty = HeadDigest::default();
+        assert_eq!(compare(&empty, &HeadDigest::new([])).differing, Vec::<String>::new());
+        assert_eq!(empty.root(), 0);
+    }
+
+    #[test]
+    fn finds_every_single_document_difference() {
+        let base = HeadDigest::new(docs(300));
+        for (doc_id, version) in docs(300) {
+            // A newer version on one side
+            let mut bumped = base.clone();
+            bumped.insert(doc_id.clone(), version + 1);
+            let comparison = compare(&base, &bumped);
+            assert_eq!(comparison.differing, [doc_id.clone()]);
+            assert_eq!(comparison.rounds, DEPTH + 1);
+            assert!(comparison.entries < 10, "{doc_id}: {comparison:?}");
+
+            // Missing on one side
+            let mut missing = base.clone();
+            missing.remove(&doc_id);
+            assert_eq!(compare(&missing, &base).differing, [doc_id.clone()]);
+            assert_eq!(compare(&base, &missing).differing, [doc_id]);
+        }
+    }
+
+    #[test]
+    fn empty_against_full_lists_everything() {
+        let full = HeadDigest::new(docs(1000));
+        let mut all: Vec<String> = docs(1000).into_iter().map(|(d, _)| d).collect();
+        all.sort();
+        assert_eq!(compare(&HeadDigest::default(), &full).differing, all);
+        assert_eq!(compare(&full, &HeadDigest::default()).differing, all);
+    }
+
+    #[test]
+    fn updates_match_a_rebuild_and_removals_restore_the_digest() {
+        let mut digest = HeadDigest::new(docs(200));
+        let before = digest.clone();
+        digest.insert("doc-3".into(), 99);
+        digest.insert("new".into(), 1);
+        let mut rebuilt = docs(200);
+        rebuilt[3].1 = 99;
+        rebuilt.push(("new".into(), 1));
+        assert_eq!(digest, HeadDigest::new(rebuilt));
+
+        digest.remove("new");
+        digest.insert("doc-3".into(), docs(200)[3].1);
+        assert_eq!(digest, before);
+        assert_eq!(digest.entries(&[]).len(), 200);
+
+        for (doc_id, _) in docs(200) {
+            digest.remove(&doc_id);
+        }
+        assert_eq!(digest, HeadDigest::default());
+    }
+
+    #[test]
+    fn a_few_differences_cost_far_less_than_the_flat_summary() {
+        let ours = HeadDigest::new(docs(5000));
+        let mut theirs = ours.clone();
+        for doc_id in ["doc-10", "doc-2000", "doc-4999"] {
+            theirs.insert(doc_id.into(), 100);
+        }
+        let comparison = compare(&ours, &theirs);
+        assert_eq!(comparison.differing, ["doc-10", "doc-2000", "doc-4999"]);
+        assert!(comparison.digests + comparison.entries < 1000, "{comparison:?}");
+    }
+}
@@ -0,0 +1,204 @@
+//! Helpers for tests and benchmarks that run several nodes in one process. Nodes listen
+//! on `/memory/<n>` addresses, so nothing touches the network and runs don't compete
+//! for ports. [`replay_session`] turns a session recording into a regression test.
+
+use std::path::Path;
+use std::time::Duration;
+
+use libp2p::multiaddr::Protocol;
+use libp2p::Multiaddr;
+
+use crate::behaviour::docstore::DocstoreGossipsubConfig;
+use crate::node::keys::{generate_identity, KeyType};
+use crate::node::recorder::{self, Recording, RecordingError};
+use crate::node::{Node, NodeBuilder, NodeEvent};
+use crate::store::MemoryDocStore;
+use crate::Error;
+
+const LISTEN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Spawn `builder` with a fresh Ed25519 identity, listening on a random memory address.
+/// Returns the node and its dialable address, `/p2p/` included. Must be called from
+/// within a tokio runtime.
+pub async fn spawn_test_node(builder: NodeBuilder) -> Result<(Node, Multiaddr), Error> {
+    let listen: Multiaddr = format!("/memory/{}", rand::random::<u64>()).parse().expect("valid multiaddr");
+    let mut node = builder.add_listen_addr(listen).spawn(generate_identity(KeyType::Ed25519))?;
+    let addr = tokio::time::timeout(LISTEN_TIMEOUT, async {
+        loop {
+            match node.next_event().await {
+                Some(NodeEvent::ListenStarted { addr }) => return Ok(addr),
+                Some(_) => {}
+                None => return Err(Error::NodeStopped),
+            }
+        }
+    })
+    .await
+    .map_err(|_| Error::Transport("memory listener did not start".into()))??;
+    let addr = addr.with(Protocol::P2p(node.peer_id()));
+    Ok((node, addr))
+}
+
+/// Replay the recording saved at `path` (see [`recorder`]) into a fresh memory store,
+/// with the default configuration, and return the store for the test to check.
+pub fn replay_session(path: impl AsRef<Path>) -> Result<MemoryDocStore, RecordingError> {
+    let recording = Recording::load(path.as_ref())?;
+    let mut store = MemoryDocStore::default();
+    recorder::replay(&recording, DocstoreGossipsubConfig::default(), &mut store)?;
+    Ok(store)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::behaviour::docstore::DocUpdate;
+    use crate::node::NodeRole;
+
+    #[tokio::test]
+    async fn memory_nodes_connect_and_exchange_updates() {
+        let (mut a, addr) = spawn_test_node(NodeBuilder::new(NodeRole::FullNode)).await.unwrap();
+        let (b, _) = spawn_test_node(NodeBuilder::new(NodeRole::Client)).await.unwrap();
+        b.dial(addr).await.unwrap();
+        b.wait_ready(Duration::from_secs(10)).await.unwrap();
+
+        b.publish_doc_update(DocUpdate::new("doc", b"hello".to_vec())).await.unwrap();
+        let received = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let Some(NodeEvent::DocUpdateReceived { peer_id, update }) = a.next_event().await {
+                    return (peer_id, update.payload);
+                }
+            }
+        })
+        .await
+        .expect("update never arrived");
+        assert_eq!(received, (b.peer_id(), b"hello".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn recorded_sessions_replay_to_the_same_documents() {
+        use crate::node::RecorderOptions;
+        use crate::store::DocStore;
+
+        let (mut a, addr) = spawn_test_node(NodeBuilder::new(NodeRole::FullNode)).await.unwrap();
+        let (b, _) = spawn_test_node(NodeBuilder::new(NodeRole::Client)).await.unwrap();
+        assert!(a.start_recording(RecorderOptions { include_outbound: true, ..Default::default() }));
+        b.dial(addr).await.unwrap();
+        b.wait_ready(Duration::from_secs(10)).await.unwrap();
+
+        b.publish_doc_update(DocUpdate::new("doc", b"from b".to_vec())).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !matches!(a.next_event().await, Some(NodeEvent::DocUpdateReceived { .. })) {}
+        })
+        .await
+        .expect("update never arrived");
+        a.publish_doc_update(DocUpdate::new("doc", b"from a".to_vec())).await.unwrap();
+        let recording = a.stop_recording().unwrap();
+        assert_eq!(a.recording_status(), None);
+
+        let path = std::env::temp_dir().join(format!("session-{}.rec", std::process::id()));
+        recording.save(&path).unwrap();
+        let store = replay_session(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let expected = a.get_document("doc").await.unwrap().unwrap();
+        assert_eq!((store.version("doc"), store.content("doc").unwrap()), expected);
+    }
+
+    #[tokio::test]
+    async fn lost_subscriptions_are_resubscribed() {
+        use crate::behaviour::docstore::TopicRegistry;
+        use crate::node::TopicHealthConfig;
+
+        let config = TopicHealthConfig { unhealthy_after: Duration::from_secs(2), startup_grace: Duration::ZERO };
+        let (mut a, addr) = spawn_test_node(NodeBuilder::new(NodeRole::FullNode)).await.unwrap();
+        let (mut b, _) = spawn_test_node(NodeBuilder::new(NodeRole::Client).with_topic_health(config)).await.unwrap();
+        b.dial(addr).await.unwrap();
+        b.wait_ready(Duration::from_secs(10)).await.unwrap();
+
+        let updates = TopicRegistry::default().updates();
+        b.drop_subscription(updates.clone()).unwrap();
+        let (unhealthy, attempts) = tokio::time::timeout(Duration::from_secs(15), async {
+            let mut unhealthy = false;
+            loop {
+                match b.next_event().await {
+                    Some(NodeEvent::TopicUnhealthy { topic, .. }) if topic == updates.to_string() => unhealthy = true,
+                    Some(NodeEvent::TopicRecovered { topic, attempts, .. }) if topic == updates.to_string() => {
+                        return (unhealthy, attempts);
+                    }
+                    Some(_) => {}
+                    None => panic!("node stopped"),
+                }
+            }
+        })
+        .await
+        .expect("topic never recovered");
+        assert!(unhealthy);
+        assert_eq!(attempts, 1);
+
+        // Publishing works again
+        b.publish_doc_update(DocUpdate::new("doc", b"after".to_vec())).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !matches!(a.next_event().await, Some(NodeEvent::DocUpdateReceived { .. })) {}
+        })
+        .await
+        .expect("update never arrived");
+    }
+
+    #[tokio::test]
+    async fn interrupted_fetches_resume_without_downloading_verified_chunks_again() {
+        use crate::behaviour::fetch::DEFAULT_CHUNK_SIZE;
+        use crate::store::DocStore;
+
+        let content: Vec<u8> = (0..4 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+        let mut store = MemoryDocStore::default();
+        store.apply_update(&DocUpdate::new("big", content.clone()));
+        let (a, addr) = spawn_test_node(NodeBuilder::new(NodeRole::FullNode).with_store(store)).await.unwrap();
+        let (b, _) = spawn_test_node(NodeBuilder::new(NodeRole::Client)).await.unwrap();
+        b.dial(addr.clone()).await.unwrap();
+        b.wait_ready(Duration::from_secs(10)).await.unwrap();
+
+        // Cut the connection as soon as the first chunk is in
+        let mut events = b.subscribe_events();
+        let (interrupted, _) = tokio::join!(b.fetch_document("big", Some(a.peer_id())), async {
+            loop {
+                if let Some(Ok(NodeEvent::FetchProgress { .. })) = events.recv().await {
+                    b.disconnect_peer(a.peer_id()).unwrap();
+                    return;
+                }
+            }
+        });
+        assert!(interrupted.is_err());
+
+        // Chunks that were already on their way still count
+        let mut received = Vec::new();
+        b.dial(addr).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                match events.recv().await {
+                    Some(Ok(NodeEvent::Connected { peer_id, .. })) if peer_id == a.peer_id() => return,
+                    Some(Ok(NodeEvent::FetchProgress { received: r, .. })) => received.push(r),
+                    Some(_) => {}
+                    None => panic!("node stopped"),
+                }
+            }
+        })
+        .await
+        .expect("never reconnected");
+        let (peer_id, fetched) = tokio::time::timeout(Duration::from_secs(30), b.fetch_document("big", Some(a.peer_id())))
+            .await
+            .expect("fetch never finished")
+            .unwrap();
+        assert_eq!((peer_id, fetched == content), (a.peer_id(), true));
+
+        // Progress only ever went forward: no chunk was fetched twice
+        let total = content.len() as u64;
+        while received.last() != Some(&total) {
+            match tokio::time::timeout(Duration::from_secs(5), events.recv()).await.expect("progress missing") {
+                Some(Ok(NodeEvent::FetchProgress { received: r, .. })) => received.push(r),
+                Some(_) => {}
+                None => panic!("node stopped"),
+            }
+        }
+        assert!(received.windows(2).all(|pair| pair[0] < pair[1]), "{received:?}");
+        assert_eq!(received.len() as u64 + 1, total / DEFAULT_CHUNK_SIZE as u64);
+        assert_eq!(b.get_document("big").await.unwrap().map(|(_, bytes)| bytes), Some(content));
+    }
+}
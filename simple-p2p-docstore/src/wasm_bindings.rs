@@ -1,31 +1,74 @@
 #![cfg(target_arch = "wasm32")]
 
 use std::sync::Arc;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 
-use futures::{channel::mpsc, stream::StreamExt, task::AtomicWaker};
+use futures::{channel::mpsc, future::FusedFuture, future::FutureExt, stream::StreamExt, task::AtomicWaker};
 use js_sys::{Object, Reflect};
 use libp2p::{
     gossipsub::{self},
     identify, identity, ping,
     request_response::{self, ProtocolSupport},
-    swarm::{NetworkBehaviour, SwarmEvent},
+    swarm::{behaviour::toggle::Toggle, dial_opts::DialOpts, DialError, NetworkBehaviour, SwarmEvent},
     Multiaddr, PeerId, StreamProtocol, Swarm,
     multiaddr::Protocol,
 };
 use libp2p_kad::{Behaviour as KademliaBehaviour, store::MemoryStore, Event as KademliaEvent, QueryResult};
 use libp2p_webrtc_websys::browser::Behaviour as WebRTCBehaviour;
+use serde::{Deserialize, Serialize};
 use wasm_bindgen::prelude::*;
 use wasm_bindgen_futures::spawn_local;
 
-use crate::node::{NodeBuilder, NodeRole};
+use crate::behaviour::docstore::auth::{self, Capability, Credential, Permission, PresenceFrame, RevocationList, RoomAccess, TokenId};
+use crate::behaviour::docstore::guest_link::GuestLink;
+use crate::behaviour::docstore::{
+    scope, ClockLedger, DocUpdate, DocstoreGossipsubConfig, Incoming, MessagePipeline, PublishDebouncer, ReceiptBehaviour, ReceiptOutcome,
+    MessageScope, RoomChannel, RoomId, Rooms, SchemaValidator, Transaction, UpdateReceipt,
+};
+use crate::behaviour::keep_alive::{self, KeepAlive, KeepAliveBehaviour};
+use crate::behaviour::interest::{make_interest_behaviour, InterestBehaviour, InterestRequest, INTEREST_PROTOCOL};
+use crate::behaviour::peers::{PeersBehaviour, PEERS_PROTOCOL};
+use crate::behaviour::rendezvous::{self as rendezvous_behaviour, RendezvousBehaviour, RendezvousEvent};
+use crate::behaviour::mailbox::{MailboxBehaviour, MailboxMessage, MailboxReceipt, MailboxRequest, MailboxResponse, MAILBOX_PROTOCOL};
+use crate::behaviour::members::{
+    members_result, Member, MembersBehaviour, MembersOptions, MembersPage, MembersRequest, MembersResponse, PresenceTracker,
+    RestrictedPresence, MEMBERS_PROTOCOL,
+};
+use crate::behaviour::profile::{self, Profile, ProfileCache, SignedProfile};
+use crate::behaviour::{head_pointer_key, HeadPointer, HeadPointers};
+use crate::behaviour::fetch::{FetchBehaviour, FetchRequest, FetchResponse, FETCH_PROTOCOL};
+use crate::behaviour::history::{
+    self as doc_history, DocumentState, HistoryAt, HistoryBehaviour, HistoryFrom, HistoryOptions, HistoryPage, HistoryRequest,
+    HistoryResponse, HISTORY_PROTOCOL,
+};
+use crate::node::announcements::{Announcement, DhtAnnouncements, RenewalSummary};
+use crate::node::bootstrap::BootstrapDials;
+use crate::node::budget::{BandwidthBudget, BudgetChange, BudgetUsage};
+use crate::node::catch_up::{CatchUp, CatchUpOutcome};
+use crate::node::dedup_cache::DedupCache;
+use crate::node::dial::{self, DialConcurrency, DialOutcome, PendingDials};
+use crate::node::fetch::Transfer;
+use crate::node::keeper::{self, ConnectionKeeper};
+use crate::node::liveness::{PingAction, PingFailures};
+use crate::node::ordering::{Ordered, OrderedDelivery, Origin};
+use crate::node::peer_exchange::{ListedDial, PeerExchange, PEER_EXCHANGE_INTERVAL};
+use crate::node::receipts::{AckTracker, PublishOptions};
+use crate::node::recorder::RecorderOptions;
+use crate::node::topic_health::{TopicChange, TopicHealth};
+use crate::node::relay_discovery::{DEFAULT_RELAYS_TO_DIAL, DEFAULT_RELAY_DISCOVERY_TIMEOUT};
+use crate::node::relay_rank::{PreferredChange, RelayRanking};
+use crate::node::rendezvous::{
+    Registrant, RendezvousPeers, DEFAULT_REGISTRANTS_TO_DIAL, DISCOVER_INTERVAL, DISCOVER_LIMIT, REGISTRATION_TTL,
+};
+use crate::node::seeds::{SeedCheck, SeedDocument, Seeds};
+use crate::node::event_queue::{self, EventFilter, EventScope, Subscription, Subscriptions};
+use crate::node::{
+    AddressBook, BanList, ConnectionStates, Published, DhtBootstrap, DhtSummary, ExternalAddrChange, ExternalAddrs, NodeReadiness, DialFailure, DialOptions, EventHistory, FindPeerOptions, FoundPeer, RemovalReason, HistoryEvent, NodeBuilder, NodeRole, PeerInfo, PeerInfoCache, PeerReputation, PeerSignal, PingPolicy, PublishedRecords, ConnectionQuality, QualityBucket, QualityTracker, RelayCheck, RelayDiscovery, RelayLookup, TrafficCounts, TrafficStats,
+};
+use crate::wasm_ids::{invalid_argument, multiaddr_arg, peer_id_arg, text_arg};
+use crate::wasm_log::LogLevel;
 use crate::wasm_transport::{TransportConfig, build_composite_transport};
-
-#[wasm_bindgen]
-extern "C" {
-    #[wasm_bindgen(js_namespace = console)]
-    fn log(s: &str);
-}
+use crate::wasm_worker::{interned, Target, WorkerLink};
 
 /// Initialize panic hook for better error messages in browser console
 #[wasm_bindgen]
@@ -33,6 +76,20 @@ pub fn init_panic_hook() {
     console_error_panic_hook::set_once();
 }
 
+/// Generate a new identity and return its protobuf encoding, suitable for storing and
+/// passing back as the `identityKey` constructor option.
+/// `key_type` is "ed25519" (default), "secp256k1" or "ecdsa".
+#[wasm_bindgen]
+pub fn generate_keypair(key_type: Option<String>) -> Result<Vec<u8>, JsValue> {
+    let key_type = match key_type {
+        Some(s) => s.parse::<crate::node::keys::KeyType>().map_err(|e| JsValue::from_str(&e))?,
+        None => Default::default(),
+    };
+    crate::node::keys::generate_identity(key_type)
+        .to_protobuf_encoding()
+        .map_err(|e| JsValue::from_str(&e.to_string()))
+}
+
 /// Extract peer ID from a multiaddr if present
 fn extract_peer_id_from_multiaddr(addr: &Multiaddr) -> Option<PeerId> {
     for protocol in addr.iter() {
@@ -49,45 +106,2042 @@ fn get_timestamp_ms() -> f64 {
 }
 
 // Direct message request/response types
+const DIRECT_MESSAGE_PROTOCOL: &str = "/docstore/direct-message/1.0.0";
+
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 struct DirectMessage {
     data: Vec<u8>,
 }
 
+/// Convert a crate error into a structured JS error object: `{ code, message, ...fields }`.
+pub(crate) fn error_to_js(err: &crate::Error) -> JsValue {
+    let obj = Object::new();
+    let _ = Reflect::set(&obj, &"code".into(), &err.code().into());
+    let _ = Reflect::set(&obj, &"message".into(), &err.to_string().into());
+    match err {
+        crate::Error::UpdateTooLarge { size, max } | crate::Error::DocumentTooLarge { size, max } => {
+            let _ = Reflect::set(&obj, &"size".into(), &JsValue::from_f64(*size as f64));
+            let _ = Reflect::set(&obj, &"max".into(), &JsValue::from_f64(*max as f64));
+        }
+        crate::Error::NotReady { waiting_for } => {
+            let _ = Reflect::set(&obj, &"waiting_for".into(), &string_array(waiting_for).into());
+        }
+        crate::Error::BootstrapFailed { failures } => {
+            let _ = Reflect::set(&obj, &"failures".into(), &string_array(failures).into());
+        }
+        crate::Error::SuspendQueueFull { max } => {
+            let _ = Reflect::set(&obj, &"max".into(), &JsValue::from_f64(*max as f64));
+        }
+        crate::Error::RateLimited { retry_after_ms } => {
+            let _ = Reflect::set(&obj, &"retry_after_ms".into(), &JsValue::from_f64(*retry_after_ms as f64));
+        }
+        crate::Error::HistoryUnavailable { earliest } => {
+            let earliest = earliest.map_or(JsValue::NULL, |v| JsValue::from_f64(v as f64));
+            let _ = Reflect::set(&obj, &"earliest".into(), &earliest);
+        }
+        crate::Error::InvalidArgument { field, .. } => {
+            let _ = Reflect::set(&obj, &"invalidField".into(), &field.as_str().into());
+        }
+        _ => {}
+    }
+    obj.into()
+}
+
+fn budget_usage_to_js(usage: &BudgetUsage) -> Result<JsValue, JsValue> {
+    let obj = Object::new();
+    let limit = usage.bytes_per_interval.map_or(JsValue::NULL, |bytes| JsValue::from_f64(bytes as f64));
+    Reflect::set(&obj, &"bytes_per_interval".into(), &limit)?;
+    for (key, value) in [
+        ("interval_ms", usage.interval_ms),
+        ("used_bytes", usage.used_bytes),
+        ("interval_started_ms", usage.interval_started_ms),
+        ("dropped_ephemeral", usage.dropped_ephemeral),
+    ] {
+        Reflect::set(&obj, &key.into(), &JsValue::from_f64(value as f64))?;
+    }
+    Reflect::set(&obj, &"exhausted".into(), &usage.exhausted.into())?;
+    Ok(obj.into())
+}
+
+fn traffic_counts_to_js(counts: &TrafficCounts) -> Result<JsValue, JsValue> {
+    let obj = Object::new();
+    for (key, value) in [
+        ("messages_in", counts.messages_in),
+        ("bytes_in", counts.bytes_in),
+        ("messages_out", counts.messages_out),
+        ("bytes_out", counts.bytes_out),
+        ("relayed_messages_in", counts.relayed_messages_in),
+        ("relayed_bytes_in", counts.relayed_bytes_in),
+        ("forwarded_messages", counts.forwarded_messages),
+        ("forwarded_bytes", counts.forwarded_bytes),
+    ] {
+        Reflect::set(&obj, &key.into(), &JsValue::from_f64(value as f64))?;
+    }
+    Ok(obj.into())
+}
+
+/// Dial `addr`, remembering it so the `dialing` event can name the address and
+/// transient failures are retried as `options` says.
+fn dial_addr(
+    swarm: &mut Swarm<MyBehaviour>,
+    pending_dials: &mut PendingDials,
+    addr: Multiaddr,
+    options: DialOptions,
+) -> Result<(), crate::Error> {
+    options.check(&addr)?;
+    crate::node::addrs::check_not_self(swarm.local_peer_id(), &addr)?;
+    let opts = DialOpts::from(addr.clone());
+    let connection_id = opts.connection_id();
+    swarm.dial(opts).map_err(|e| crate::Error::Transport(e.to_string()))?;
+    pending_dials.started(connection_id, addr, options, web_time::Instant::now());
+    Ok(())
+}
+
+/// Start a catch-up run with `peer`, the best connected peer serving history, if one is
+/// due and the bandwidth budget allows.
+fn start_catch_up(
+    swarm: &mut Swarm<MyBehaviour>,
+    catch_up: &mut CatchUp<request_response::OutboundRequestId>,
+    peer: Option<PeerId>,
+    budget: &BandwidthBudget,
+) {
+    let Some(peer) = peer.filter(|_| catch_up.is_due() && !budget.is_exhausted()) else {
+        return;
+    };
+    let doc_ids = catch_up.start();
+    tracing::info!("Catching up on {} documents from {}", doc_ids.len(), peer);
+    for doc_id in doc_ids {
+        let request = HistoryRequest { from: HistoryFrom::Latest, ..HistoryRequest::new(doc_id.clone()) };
+        let id = swarm.behaviour_mut().history.send_request(&peer, request);
+        catch_up.requested(id, doc_id);
+    }
+}
+
+type FetchReply = futures::channel::oneshot::Sender<Result<(PeerId, Vec<u8>), crate::Error>>;
+
+/// A `fetch_document()` under way.
+struct BrowserFetch {
+    /// The peer asked now.
+    peer_id: PeerId,
+    /// The caller named the peer, so no other is tried.
+    chosen: bool,
+    /// Peers this fetch failed with already.
+    failed: Vec<PeerId>,
+    /// The callers, more than one if the document was asked for again meanwhile.
+    replies: Vec<FetchReply>,
+}
+
+/// Document fetches, see [`crate::node::fetch`]. Browsers keep no store, so transfers
+/// are kept in memory: a fetch resumes after a reconnect, not after a reload.
+#[derive(Default)]
+struct BrowserFetches {
+    transfers: HashMap<String, Transfer>,
+    active: HashMap<String, BrowserFetch>,
+    pending: HashMap<request_response::OutboundRequestId, String>,
+    /// Fetches whose next chunk waits for the bandwidth budget.
+    deferred: Vec<String>,
+}
+
+impl BrowserFetches {
+    fn start(&mut self, swarm: &mut Swarm<MyBehaviour>, doc_id: String, peer_id: PeerId, chosen: bool, reply: FetchReply) {
+        if let Some(active) = self.active.get_mut(&doc_id) {
+            active.replies.push(reply);
+            return;
+        }
+        self.active.insert(doc_id.clone(), BrowserFetch { peer_id, chosen, failed: Vec::new(), replies: vec![reply] });
+        self.request_manifest(swarm, doc_id);
+    }
+
+    fn request_manifest(&mut self, swarm: &mut Swarm<MyBehaviour>, doc_id: String) {
+        let Some(active) = self.active.get(&doc_id) else {
+            return;
+        };
+        let id = swarm.behaviour_mut().fetch.send_request(&active.peer_id, FetchRequest::Manifest { doc_id: doc_id.clone() });
+        self.pending.insert(id, doc_id);
+    }
+
+    /// Handle a response to one of our requests. Returns the document whose fetch `peer`
+    /// failed and why, for [`failed`](Self::failed) to carry on with another peer.
+    fn response(
+        &mut self,
+        swarm: &mut Swarm<MyBehaviour>,
+        budget: &BandwidthBudget,
+        event_sender: &EventSink,
+        request_id: &request_response::OutboundRequestId,
+        response: FetchResponse,
+    ) -> Option<(String, crate::Error)> {
+        let doc_id = self.pending.remove(request_id)?;
+        let accepted = match response {
+            FetchResponse::Manifest(manifest) | FetchResponse::Changed(manifest) => match self.transfers.get_mut(&doc_id) {
+                Some(transfer) => transfer.resume(manifest).map(|_| ()),
+                None => Transfer::new(manifest).map(|transfer| {
+                    self.transfers.insert(doc_id.clone(), transfer);
+                }),
+            },
+            FetchResponse::Chunk { offset, data } => {
+                let transfer = self.transfers.get_mut(&doc_id)?;
+                transfer.accept(offset, &data).map(|()| {
+                    let (received, total) = (transfer.received(), transfer.total());
+                    let _ = event_sender.unbounded_send(Event::FetchProgress { doc_id: doc_id.clone(), received, total });
+                })
+            }
+            response => {
+                let error = crate::behaviour::fetch::failure(&doc_id, response);
+                return Some((doc_id, error));
+            }
+        };
+        if let Err(e) = accepted {
+            return Some((doc_id, e.into()));
+        }
+        self.request_chunk(swarm, budget, doc_id);
+        None
+    }
+
+    /// Ask for `doc_id`'s next chunk, or finish the fetch if there is none.
+    fn request_chunk(&mut self, swarm: &mut Swarm<MyBehaviour>, budget: &BandwidthBudget, doc_id: String) {
+        let (Some(transfer), Some(active)) = (self.transfers.get(&doc_id), self.active.get(&doc_id)) else {
+            return;
+        };
+        let Some((offset, len)) = transfer.next_range() else {
+            let peer_id = active.peer_id;
+            let transfer = self.transfers.remove(&doc_id).expect("checked above");
+            let result = transfer.finish().map(|(_, bytes)| (peer_id, bytes)).map_err(Into::into);
+            self.finish(&doc_id, result);
+            return;
+        };
+        if budget.is_exhausted() {
+            self.deferred.push(doc_id);
+            return;
+        }
+        let request = FetchRequest::Range { doc_id: doc_id.clone(), content_hash: transfer.manifest().content_hash, offset, len };
+        let id = swarm.behaviour_mut().fetch.send_request(&active.peer_id, request);
+        self.pending.insert(id, doc_id);
+    }
+
+    /// The budget is available again: ask for the chunks that waited.
+    fn resume_deferred(&mut self, swarm: &mut Swarm<MyBehaviour>, budget: &BandwidthBudget) {
+        for doc_id in std::mem::take(&mut self.deferred) {
+            self.request_chunk(swarm, budget, doc_id);
+        }
+    }
+
+    /// `peer` failed the fetch of `doc_id`. What was verified is kept; the fetch goes on
+    /// with the best of `ranked` (the connected peers serving fetches, best first) not yet
+    /// tried, unless the caller chose the peer or there is none.
+    fn failed(&mut self, swarm: &mut Swarm<MyBehaviour>, doc_id: String, peer: PeerId, error: crate::Error, ranked: &[PeerId]) {
+        let Some(active) = self.active.get_mut(&doc_id) else {
+            return;
+        };
+        active.failed.push(peer);
+        match ranked.iter().find(|candidate| !active.failed.contains(*candidate)) {
+            Some(next) if !active.chosen => {
+                tracing::debug!("Fetch of {} from {} failed ({}), trying {}", doc_id, peer, error, next);
+                active.peer_id = *next;
+                self.request_manifest(swarm, doc_id);
+            }
+            _ => self.finish(&doc_id, Err(error)),
+        }
+    }
+
+    fn finish(&mut self, doc_id: &str, result: Result<(PeerId, Vec<u8>), crate::Error>) {
+        let Some(active) = self.active.remove(doc_id) else {
+            return;
+        };
+        self.deferred.retain(|d| d != doc_id);
+        let mut replies = active.replies;
+        let first = replies.remove(0);
+        for reply in replies {
+            // Errors don't clone; callers that joined later get the message
+            let _ = reply.send(match &result {
+                Ok(fetched) => Ok(fetched.clone()),
+                Err(e) => Err(crate::Error::FetchFailed { reason: e.to_string() }),
+            });
+        }
+        let _ = first.send(result);
+    }
+}
+
+/// Emit `caughtUp`, or `catchUpFailed` if some documents could not be fetched.
+fn report_catch_up(event_sender: &EventSink, outcome: Option<CatchUpOutcome>) {
+    match outcome {
+        Some(CatchUpOutcome::CaughtUp { doc_count }) => {
+            let _ = event_sender.unbounded_send(Event::CaughtUp { doc_count });
+        }
+        Some(CatchUpOutcome::Failed { doc_count, failed }) => {
+            tracing::warn!("Catch-up failed for {} documents; they only get live updates", failed.len());
+            let msg = format!("could not fetch the current state of {} documents", failed.len());
+            let _ = event_sender.unbounded_send(Event::CatchUpFailed { doc_count, failed, msg });
+        }
+        None => {}
+    }
+}
+
+/// The dedup cache saved under `key`, or an empty one if there is none or it cannot be
+/// read: delivering an update twice beats losing it.
+async fn load_dedup_cache(key: &str) -> DedupCache {
+    use crate::node::dedup_cache::{DEFAULT_PER_DOC, DEFAULT_TOTAL};
+    match crate::wasm_idb::get(key).await {
+        Ok(Some(bytes)) => DedupCache::decode(&bytes, DEFAULT_PER_DOC, DEFAULT_TOTAL).unwrap_or_else(|e| {
+            tracing::warn!("Discarding an unreadable dedup cache: {}", e);
+            DedupCache::default()
+        }),
+        Ok(None) => DedupCache::default(),
+        Err(e) => {
+            tracing::warn!("Could not load the dedup cache: {:?}", e);
+            DedupCache::default()
+        }
+    }
+}
+
+/// Updates and abandoned gaps put out by ordered delivery, each with the peer whose
+/// update set it off, if any.
+type OrderedOutputs = Vec<(Ordered, Option<PeerId>)>;
+
+fn sourced(outputs: Vec<Ordered>, source: Option<PeerId>) -> impl Iterator<Item = (Ordered, Option<PeerId>)> {
+    outputs.into_iter().map(move |output| (output, source))
+}
+
+/// Emit `orderedUpdate` and `gapAbandoned` events. Returns the gaps to re-request, with
+/// the peer to prefer for each.
+fn report_ordered(event_sender: &EventSink, outputs: OrderedOutputs) -> Vec<(String, u64, Option<PeerId>)> {
+    let mut gaps = Vec::new();
+    for (output, source) in outputs {
+        match output {
+            Ordered::Deliver(ordered) => {
+                let _ = event_sender.unbounded_send(Event::OrderedUpdate {
+                    doc_id: ordered.update.doc_id,
+                    data: String::from_utf8_lossy(&ordered.update.payload).to_string(),
+                    origin: ordered.origin,
+                });
+            }
+            Ordered::GapOpened { doc_id, since_ms } => gaps.push((doc_id, since_ms, source)),
+            Ordered::GapAbandoned { doc_id, missing } => {
+                tracing::info!("Gave up on {} missing updates of {}", missing, doc_id);
+                let _ = event_sender.unbounded_send(Event::GapAbandoned { doc_id, missing });
+            }
+        }
+    }
+    gaps
+}
+
+/// What a mailbox request was sent for.
+enum PendingMailbox {
+    Deposit(futures::channel::oneshot::Sender<Result<MailboxReceipt, crate::Error>>),
+    /// `None` for the fetch made when a holder identifies, whose messages become events.
+    Fetch(Option<futures::channel::oneshot::Sender<Result<Vec<MailboxMessage>, crate::Error>>>),
+    Ack,
+}
+
+/// Hand a mailbox response to whoever is waiting for it. Fetched messages are
+/// acknowledged right away, so the holder deletes them.
+fn mailbox_answered(
+    swarm: &mut Swarm<MyBehaviour>,
+    pending_mailbox: &mut HashMap<request_response::OutboundRequestId, PendingMailbox>,
+    event_sender: &EventSink,
+    holder: PeerId,
+    pending: PendingMailbox,
+    result: Result<MailboxResponse, crate::Error>,
+) {
+    let result = result.and_then(|response| match response {
+        MailboxResponse::Rejected { reason } => Err(crate::Error::MailboxRejected { reason }),
+        MailboxResponse::RateLimited { retry_after_ms } => Err(crate::Error::RateLimited { retry_after_ms }),
+        response => Ok(response),
+    });
+    match (pending, result) {
+        (PendingMailbox::Deposit(reply), Ok(MailboxResponse::Deposited { id, expires_at_ms })) => {
+            let _ = reply.send(Ok(MailboxReceipt { id, expires_at_ms }));
+        }
+        (PendingMailbox::Fetch(reply), Ok(MailboxResponse::Messages(messages))) => {
+            if !messages.is_empty() {
+                let ids = messages.iter().map(|m| m.id).collect();
+                let id = swarm.behaviour_mut().mailbox.send_request(&holder, MailboxRequest::Ack { ids });
+                pending_mailbox.insert(id, PendingMailbox::Ack);
+            }
+            match reply {
+                Some(reply) => {
+                    let _ = reply.send(Ok(messages));
+                }
+                None => {
+                    for message in messages {
+                        let _ = event_sender.unbounded_send(Event::MailboxDelivered { holder: holder.to_string(), message });
+                    }
+                }
+            }
+        }
+        (PendingMailbox::Ack, Ok(MailboxResponse::Acked { .. })) => {}
+        (pending, result) => {
+            let error = result
+                .err()
+                .unwrap_or_else(|| crate::Error::Transport(format!("unexpected mailbox response from {holder}")));
+            match pending {
+                PendingMailbox::Deposit(reply) => {
+                    let _ = reply.send(Err(error));
+                }
+                PendingMailbox::Fetch(Some(reply)) => {
+                    let _ = reply.send(Err(error));
+                }
+                PendingMailbox::Fetch(None) | PendingMailbox::Ack => {
+                    tracing::debug!("Mailbox request to {} failed: {}", holder, error)
+                }
+            }
+        }
+    }
+}
+
+/// Acknowledge an update `publisher` asked receipts for: directly if we are connected,
+/// on the receipts topic otherwise. Sent directly, it is kept in `pending_receipts` to be
+/// published if the request fails.
+#[allow(clippy::too_many_arguments)]
+fn send_receipt(
+    swarm: &mut Swarm<MyBehaviour>,
+    pending_receipts: &mut HashMap<request_response::OutboundRequestId, UpdateReceipt>,
+    docstore_config: &DocstoreGossipsubConfig,
+    traffic: &TrafficStats,
+    local_key: &libp2p::identity::Keypair,
+    publisher: PeerId,
+    msg_id: &gossipsub::MessageId,
+    doc_id: &str,
+    applied_version: u64,
+) {
+    let receipt = match UpdateReceipt::sign(local_key, &publisher, msg_id, doc_id, applied_version) {
+        Ok(receipt) => receipt,
+        Err(e) => {
+            tracing::warn!("Failed to sign a receipt for {}: {}", msg_id, e);
+            return;
+        }
+    };
+    if swarm.is_connected(&publisher) {
+        let request_id = swarm.behaviour_mut().receipts.send_request(&publisher, receipt.clone());
+        pending_receipts.insert(request_id, receipt);
+    } else {
+        publish_receipt(swarm, docstore_config, traffic, receipt);
+    }
+}
+
+fn publish_receipt(swarm: &mut Swarm<MyBehaviour>, docstore_config: &DocstoreGossipsubConfig, traffic: &TrafficStats, receipt: UpdateReceipt) {
+    let topic = docstore_config.topics.receipts();
+    if let Err(e) = traffic.publish(&mut swarm.behaviour_mut().gossipsub, topic, receipt.encode()) {
+        tracing::debug!("Failed to publish a receipt for {}: {}", receipt.doc_id, e);
+    }
+}
+
+/// A receipt arrived, directly from `from` or (with `None`) on the receipts topic. Emits
+/// `updateAcknowledged` (or `casConflict`) and returns true if it acknowledged one of our updates for the
+/// first time.
+fn receipt_received(
+    local_peer_id: &PeerId,
+    acks: &mut AckTracker,
+    event_sender: &EventSink,
+    receipt: &UpdateReceipt,
+    from: Option<PeerId>,
+) -> bool {
+    if !receipt.is_for(local_peer_id) {
+        return false;
+    }
+    let peer_id = match receipt.verify() {
+        Ok(signer) if from.is_none_or(|from| from == signer) => signer,
+        Ok(signer) => {
+            tracing::debug!("Ignoring a receipt signed by {} from {:?}", signer, from);
+            return false;
+        }
+        Err(e) => {
+            tracing::debug!("Ignoring receipt from {:?}: {}", from, e);
+            return false;
+        }
+    };
+    let Some(ack) = acks.received(receipt, peer_id) else {
+        return false;
+    };
+    let _ = event_sender.unbounded_send(match ack.outcome {
+        ReceiptOutcome::Applied => Event::UpdateAcknowledged {
+            msg_id: ack.msg_id.to_string(),
+            peer_id: ack.peer_id.to_string(),
+            doc_id: ack.doc_id,
+            applied_version: ack.applied_version,
+        },
+        ReceiptOutcome::CasConflict => Event::CasConflict {
+            msg_id: ack.msg_id.to_string(),
+            peer_id: ack.peer_id.to_string(),
+            doc_id: ack.doc_id,
+            current_version: ack.applied_version,
+        },
+        ReceiptOutcome::QuotaExceeded => Event::QuotaRefused {
+            msg_id: ack.msg_id.to_string(),
+            peer_id: ack.peer_id.to_string(),
+            doc_id: ack.doc_id,
+        },
+    });
+    true
+}
+
+fn rendezvous_register(
+    swarm: &mut Swarm<MyBehaviour>,
+    rendezvous: &mut RendezvousPeers<rendezvous_behaviour::Cookie>,
+    namespace: &str,
+    point: PeerId,
+) {
+    if let Err(e) = swarm.behaviour_mut().rendezvous.register(point, namespace, REGISTRATION_TTL) {
+        // Typically no relay reservation yet, so no address to register
+        tracing::debug!("Cannot register with rendezvous point {} yet: {}", point, e);
+        rendezvous.register_failed(&point, web_time::Instant::now());
+    }
+}
+
+fn rendezvous_discover(
+    swarm: &mut Swarm<MyBehaviour>,
+    rendezvous: &mut RendezvousPeers<rendezvous_behaviour::Cookie>,
+    namespace: &str,
+    point: PeerId,
+) {
+    let Some(cookie) = rendezvous.start_discovery(&point) else {
+        return;
+    };
+    if let Err(e) = swarm.behaviour_mut().rendezvous.discover(point, namespace, cookie, DISCOVER_LIMIT) {
+        tracing::debug!("Cannot discover through rendezvous point {}: {}", point, e);
+        rendezvous.discovery_failed(&point);
+    }
+}
+
+/// Ask a relay for the clients it lists, telling it where we can be reached.
+fn ask_for_peers(swarm: &mut Swarm<MyBehaviour>, peer_exchange: &PeerExchange, relay: PeerId) {
+    let addrs: Vec<Multiaddr> = swarm.external_addresses().chain(swarm.listeners()).cloned().collect();
+    let request = peer_exchange.request(addrs);
+    swarm.behaviour_mut().peers.send_request(&relay, request);
+}
+
+/// Tell relays which room topics we still want, so they stop forwarding the others now.
+fn send_interest(swarm: &mut Swarm<MyBehaviour>, relays: impl IntoIterator<Item = PeerId>, hint: &HashSet<String>) {
+    let request = InterestRequest { topics: hint.iter().cloned().collect() };
+    for relay in relays {
+        swarm.behaviour_mut().interest.send_request(&relay, request.clone());
+    }
+}
+
+/// What a dial by peer id needs to order its addresses, see [`dial_planned`].
+struct DialPlanner<'a> {
+    address_book: &'a AddressBook,
+    reputation: &'a PeerReputation,
+    event_sender: &'a EventSink,
+}
+
+/// Dial `peer_id` on `addrs` best first (see [`dial::plan`]), reporting the order in a
+/// `dialPlan` event.
+fn dial_planned(swarm: &mut Swarm<MyBehaviour>, planner: &DialPlanner, peer_id: PeerId, addrs: Vec<Multiaddr>) -> Result<(), DialError> {
+    let now = web_time::Instant::now();
+    let addrs = dial::plan(addrs, |addr| planner.address_book.record(&peer_id, addr), |relay| planner.reputation.score(relay, now));
+    swarm.dial(DialOpts::peer_id(peer_id).addresses(addrs.clone()).build())?;
+    let _ = planner.event_sender.unbounded_send(Event::DialPlan {
+        peer_id: peer_id.to_string(),
+        addrs: addrs.iter().map(ToString::to_string).collect(),
+    });
+    Ok(())
+}
+
+/// Dial a peer a relay listed: over a circuit through that relay (`relay_addr`), or any
+/// address the peer gave it, directly first. It joins the mesh once connected.
+fn dial_listed(
+    swarm: &mut Swarm<MyBehaviour>,
+    planner: &DialPlanner,
+    peer_exchange: &mut PeerExchange,
+    relay_addr: Option<Multiaddr>,
+    dial: ListedDial,
+) {
+    let circuit = relay_addr
+        .and_then(|addr| addr.with_p2p(dial.relay).ok())
+        .map(|addr| addr.with(Protocol::P2pCircuit).with(Protocol::P2p(dial.peer_id)));
+    let addrs: Vec<Multiaddr> = circuit.into_iter().chain(dial.addrs).collect();
+    tracing::debug!("Dialing {}, listed by {}", dial.peer_id, dial.relay);
+    if let Err(e) = dial_planned(swarm, planner, dial.peer_id, addrs) {
+        tracing::debug!("Dialing listed peer {} failed: {}", dial.peer_id, e);
+        peer_exchange.dial_failed(&dial.peer_id);
+    }
+}
+
+/// Answer `discover_peers()` once no discovery is in flight.
+fn answer_rendezvous_waiters(
+    rendezvous: &mut RendezvousPeers<rendezvous_behaviour::Cookie>,
+    waiters: &mut Vec<futures::channel::oneshot::Sender<Vec<Registrant>>>,
+) {
+    if waiters.is_empty() || rendezvous.is_discovering() {
+        return;
+    }
+    let registrants = rendezvous.registrants(web_time::Instant::now());
+    for reply in waiters.drain(..) {
+        let _ = reply.send(registrants.clone());
+    }
+}
+
+/// `{ id, sender, data: Uint8Array, depositedAtMs, expiresAtMs }`
+fn mailbox_message_to_js(message: &MailboxMessage) -> Result<JsValue, JsValue> {
+    let obj = Object::new();
+    Reflect::set(&obj, &"id".into(), &(message.id as f64).into())?;
+    Reflect::set(&obj, &"sender".into(), &message.sender.as_str().into())?;
+    Reflect::set(&obj, &"data".into(), &js_sys::Uint8Array::from(message.blob.as_slice()).into())?;
+    Reflect::set(&obj, &"depositedAtMs".into(), &(message.deposited_at_ms as f64).into())?;
+    Reflect::set(&obj, &"expiresAtMs".into(), &(message.expires_at_ms as f64).into())?;
+    Ok(obj.into())
+}
+
+/// Put a record of ours into the DHT, expiring after `ttl` if set.
+fn put_record(
+    kademlia: &mut KademliaBehaviour<MemoryStore>,
+    key: libp2p_kad::RecordKey,
+    value: Vec<u8>,
+    ttl: Option<std::time::Duration>,
+) -> Result<libp2p_kad::QueryId, crate::Error> {
+    let mut record = libp2p_kad::Record::new(key, value);
+    // A TTL too long to reckon with never runs out
+    record.expires = ttl.and_then(|ttl| web_time::Instant::now().checked_add(ttl));
+    kademlia.put_record(record, libp2p_kad::Quorum::One).map_err(|e| crate::Error::Dht(e.to_string()))
+}
+
+/// Back online after a long outage: provide every pinned document again and put our head
+/// pointers back.
+fn renew_announcements(
+    swarm: &mut Swarm<MyBehaviour>,
+    announcements: &mut DhtAnnouncements<libp2p_kad::QueryId>,
+    event_sender: &EventSink,
+) {
+    let Some(renewals) = announcements.reconnected(web_time::Instant::now()) else {
+        return;
+    };
+    tracing::info!("Back online after an outage, renewing {} DHT announcements", renewals.len());
+    for announcement in renewals {
+        let key = announcement.key().clone();
+        let query = match (announcement, swarm.behaviour_mut().kademlia.as_mut()) {
+            (Announcement::Provider(key), Some(kademlia)) => kademlia.start_providing(key).ok(),
+            (Announcement::Record { key, value, ttl }, Some(kademlia)) => put_record(kademlia, key, value, ttl).ok(),
+            _ => None,
+        };
+        announcements.issued(key, query);
+    }
+    report_renewal(event_sender, announcements.settle(None, true));
+}
+
+/// Emit `announcementsRenewed` once a renewal pass is over.
+fn report_renewal(event_sender: &EventSink, summary: Option<RenewalSummary>) {
+    if let Some(RenewalSummary { renewed, failed }) = summary {
+        let failed = failed.iter().map(|key| String::from_utf8_lossy(key.as_ref()).into_owned()).collect();
+        let _ = event_sender.unbounded_send(Event::AnnouncementsRenewed { renewed, failed });
+    }
+}
+
+/// Emit `messagePublished`, followed by `publishWarning` if nobody was sent the message.
+fn report_published(event_sender: &EventSink, published: Published) {
+    tracing::debug!("Published message {} to {} peers", published.msg_id, published.sent_to.len());
+    let msg_id = published.msg_id.to_string();
+    let sent_to: Vec<String> = published.sent_to.iter().map(ToString::to_string).collect();
+    let unsent = sent_to.is_empty();
+    let _ = event_sender.unbounded_send(Event::MessagePublished { msg_id: msg_id.clone(), sent_to });
+    if unsent {
+        let _ = event_sender.unbounded_send(Event::PublishWarning {
+            msg_id,
+            msg: "message accepted but sent to no peer".to_string(),
+        });
+    }
+}
+
+/// A bootstrap dial of `addr` gave up: emit `bootstrapFailed`, and settle the
+/// constructor if it was the last one.
+fn bootstrap_failed(
+    bootstrap: &mut BootstrapDials,
+    ready: &mut Option<futures::channel::oneshot::Sender<Result<(), crate::Error>>>,
+    event_sender: &EventSink,
+    addr: &Multiaddr,
+    attempts: u32,
+    msg: String,
+) {
+    if !bootstrap.failed(addr, msg.clone()) {
+        return;
+    }
+    tracing::warn!("Bootstrap {} failed after {} attempts: {}", addr, attempts, msg);
+    let _ = event_sender.unbounded_send(Event::BootstrapFailed { addr: addr.to_string(), attempts, msg });
+    settle_bootstrap(bootstrap, ready);
+}
+
+fn settle_bootstrap(
+    bootstrap: &mut BootstrapDials,
+    ready: &mut Option<futures::channel::oneshot::Sender<Result<(), crate::Error>>>,
+) {
+    if let Some(outcome) = bootstrap.take_outcome() {
+        if let Some(ready) = ready.take() {
+            let _ = ready.send(outcome);
+        }
+    }
+}
+
+/// Make the preferred relays, and no others, explicit peers of the ephemeral gossipsub so
+/// cursors and typing indicators take the fastest paths. Updates and presence still go
+/// to every relay.
+fn apply_relay_change(swarm: &mut Swarm<MyBehaviour>, change: &PreferredChange) {
+    if change.is_empty() {
+        return;
+    }
+    let ephemeral = &mut swarm.behaviour_mut().ephemeral;
+    for relay in &change.removed {
+        ephemeral.remove_explicit_peer(relay);
+    }
+    for relay in &change.added {
+        ephemeral.add_explicit_peer(relay);
+    }
+    tracing::info!("Preferred relays: +{:?} -{:?}", change.added, change.removed);
+}
+
+/// A provider found by `discover_relays()` serves the relay hop protocol: peer with it
+/// explicitly, like with bootstrap relays, and rank it with them.
+fn add_discovered_relay(
+    swarm: &mut Swarm<MyBehaviour>,
+    ranking: &mut RelayRanking,
+    topic_health: &mut TopicHealth,
+    state: &mut SharedState,
+    relay: PeerId,
+) {
+    tracing::info!("Discovered relay {}", relay);
+    swarm.behaviour_mut().gossipsub.add_explicit_peer(&relay);
+    topic_health.add_explicit(relay);
+    let change = ranking.connected(relay);
+    apply_relay_change(swarm, &change);
+    show_relay_ranking(state, ranking);
+}
+
+/// Copy round-trip times and the preferred relays into `get_network_status()`.
+fn show_relay_ranking(state: &mut SharedState, ranking: &RelayRanking) {
+    for relay in &mut state.relays {
+        let Ok(peer_id) = relay.peer_id.parse::<PeerId>() else {
+            continue;
+        };
+        relay.rtt_ms = ranking.rtt(&peer_id).map(|rtt| rtt.as_secs_f64() * 1000.0);
+        relay.preferred = ranking.is_preferred(&peer_id);
+    }
+    state.best_relay = ranking.best();
+}
+
+/// Publish everything the debouncer is holding, one envelope per document.
+fn flush_debounced(
+    swarm: &mut Swarm<MyBehaviour>,
+    docstore_config: &DocstoreGossipsubConfig,
+    debouncer: &mut PublishDebouncer,
+    traffic: &TrafficStats,
+    event_sender: &EventSink,
+) {
+    if debouncer.is_empty() {
+        return;
+    }
+    let published = crate::behaviour::docstore::encode_batch(docstore_config, debouncer.take()).and_then(|envelopes| {
+        envelopes
+            .into_iter()
+            .map(|data| {
+                let topic = docstore_config.topics.updates();
+                Ok(traffic.publish(&mut swarm.behaviour_mut().gossipsub, topic, data)?)
+            })
+            .collect::<Result<Vec<_>, crate::Error>>()
+    });
+    match published {
+        Ok(published) => {
+            for published in published {
+                report_published(event_sender, published);
+            }
+        }
+        Err(e) => {
+            tracing::warn!("Publish error: {}", e);
+            let _ = event_sender.unbounded_send(Event::Error {
+                msg: format!("Publish error: {}", e)
+            });
+        }
+    }
+}
+
+/// How often readiness is re-checked while `ready()` waits.
+const READY_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(250);
+
+/// How often presence is re-sent in joined rooms, so peers that joined since learn our
+/// token and everyone, FullNodes included, sees we are still there.
+const ROOM_PRESENCE_HEARTBEAT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Publishes held back while suspended; further ones fail with `SuspendQueueFull`. Also
+/// caps the publishes held while the bandwidth budget is exhausted.
+const MAX_SUSPENDED_PUBLISHES: usize = 1024;
+
+/// How often the bandwidth budget is checked while one is set; the event loop checks it
+/// after every event as well.
+const BUDGET_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// How long after a change the dedup cache is saved, so a burst of updates costs one write.
+const DEDUP_SAVE_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Publishes held back while the node is suspended, in order. `Some` exactly while it is,
+/// see `WasmNode.suspend()`.
+type Outbox = Arc<std::sync::Mutex<Option<VecDeque<Command>>>>;
+
+/// Send a publish command, or hold it in `outbox` while the node is suspended.
+fn send_publish(cmd_sender: &mpsc::UnboundedSender<Command>, outbox: &Outbox, cmd: Command) -> Result<(), JsValue> {
+    if let Some(queue) = outbox.lock().expect("outbox lock").as_mut() {
+        if queue.len() >= MAX_SUSPENDED_PUBLISHES {
+            return Err(error_to_js(&crate::Error::SuspendQueueFull { max: MAX_SUSPENDED_PUBLISHES }));
+        }
+        queue.push_back(cmd);
+        return Ok(());
+    }
+    cmd_sender
+        .unbounded_send(cmd)
+        .map_err(|e| JsValue::from_str(&format!("Failed to send command: {}", e)))
+}
+
+fn is_suspended(outbox: &Outbox) -> bool {
+    outbox.lock().expect("outbox lock").is_some()
+}
+
+/// Hold `cmd` until the bandwidth budget is available again. Publishes beyond
+/// [`MAX_SUSPENDED_PUBLISHES`] fail instead, like those of a suspended node.
+fn defer_over_budget(deferred: &mut VecDeque<Command>, cmd: Command, event_sender: &EventSink) {
+    let held = deferred.iter().filter(|held| held.is_publish()).count();
+    if !cmd.is_publish() || held < MAX_SUSPENDED_PUBLISHES {
+        deferred.push_back(cmd);
+        return;
+    }
+    let full = crate::Error::SuspendQueueFull { max: MAX_SUSPENDED_PUBLISHES };
+    match cmd {
+        Command::PublishRoom { reply: Some(reply), .. } => {
+            let _ = reply.send(Err(full));
+        }
+        _ => {
+            let _ = event_sender.unbounded_send(Event::Error { msg: format!("Publish error: {}", full) });
+        }
+    }
+}
+
+/// Access state of a restricted room, see [`auth`].
+struct RoomAuth {
+    access: RoomAccess,
+    /// Our own token or guest pass, attached to every presence frame.
+    token: Option<Credential>,
+    /// Everything we revoked as the creator; each new list carries all of them.
+    revoked: Vec<TokenId>,
+}
+
+/// Publish on a joined room's channel.
+fn publish_room(
+    swarm: &mut Swarm<MyBehaviour>,
+    rooms: &Rooms,
+    traffic: &TrafficStats,
+    room_id: &str,
+    channel: RoomChannel,
+    data: Vec<u8>,
+) -> Result<Published, crate::Error> {
+    let topic = rooms.topic(room_id, channel).ok_or_else(|| crate::Error::NotInRoom { room_id: room_id.to_string() })?;
+    let behaviour = swarm.behaviour_mut();
+    let gossipsub = if channel.is_ephemeral() { &mut behaviour.ephemeral } else { &mut behaviour.gossipsub };
+    // Cursors and the like only matter near their author; presence goes room-wide
+    let data = match channel {
+        RoomChannel::Ephemeral => scope::frame_ephemeral(data, MessageScope::Local),
+        _ => data,
+    };
+    Ok(traffic.publish(gossipsub, topic, data)?)
+}
+
+/// Tell the FullNodes among `holders` that we are in `room_id`, so they keep us in its
+/// membership table. Restricted rooms carry our presence `frame`, which shows our token.
+fn report_presence(
+    swarm: &mut Swarm<MyBehaviour>,
+    holders: &[PeerId],
+    room_auth: &HashMap<String, RoomAuth>,
+    private_rooms: &HashSet<String>,
+    room_id: &str,
+    frame: &[u8],
+) {
+    let restricted = room_auth
+        .get(room_id)
+        .map(|auth| RestrictedPresence { creator: auth.access.creator().to_string(), frame: frame.to_vec() });
+    let private = private_rooms.contains(room_id);
+    let request = MembersRequest::Present { room_id: room_id.to_string(), restricted, private };
+    for holder in holders {
+        swarm.behaviour_mut().members.send_request(holder, request.clone());
+    }
+}
+
+/// The payload to deliver for a room message, or `None` to drop it. Open rooms deliver
+/// everything. In restricted rooms presence frames carry tokens and revocations, and
+/// other traffic needs a signed author holding a write grant.
+fn admit_room_message(
+    room_auth: &mut HashMap<String, RoomAuth>,
+    room_id: &str,
+    channel: RoomChannel,
+    author: Option<PeerId>,
+    data: &[u8],
+) -> Option<Vec<u8>> {
+    let Some(auth) = room_auth.get_mut(room_id) else {
+        return Some(data.to_vec());
+    };
+    let author = author?;
+    let now = get_timestamp_ms() as u64;
+    match channel {
+        RoomChannel::Presence => {
+            let frame = PresenceFrame::decode(data).ok()?;
+            auth.access.receive_presence(author, frame, now)
+        }
+        RoomChannel::Updates | RoomChannel::Ephemeral => {
+            auth.access.allows(&author, Permission::Write, now).then(|| data.to_vec())
+        }
+    }
+}
+
 #[derive(NetworkBehaviour)]
 struct MyBehaviour {
     relay: libp2p_relay::client::Behaviour,
     webrtc: WebRTCBehaviour,
     ping: ping::Behaviour,
     gossipsub: gossipsub::Behaviour,
+    ephemeral: gossipsub::Behaviour,
     identify: identify::Behaviour,
-    kademlia: KademliaBehaviour<MemoryStore>,
+    /// A `Toggle` rather than a second behaviour struct, so `{ dht: false }` doesn't need
+    /// its own copy of the event loop. Kademlia is still compiled in; disabling it saves its
+    /// background queries and traffic, not bundle size.
+    kademlia: Toggle<KademliaBehaviour<MemoryStore>>,
     request_response: request_response::cbor::Behaviour<DirectMessage, DirectMessage>,
+    /// Outbound only; browsers keep no history to serve.
+    history: HistoryBehaviour,
+    /// Outbound only, like history.
+    fetch: FetchBehaviour,
+    /// Outbound only; messages for us are held by FullNodes and relay servers.
+    mailbox: MailboxBehaviour,
+    /// Outbound only: reports our presence in rooms to FullNodes and asks them who joined.
+    members: MembersBehaviour,
+    /// Receipts for updates that ask for them, both ways.
+    receipts: ReceiptBehaviour,
+    /// Registration and discovery at rendezvous points, unless built without the feature.
+    rendezvous: RendezvousBehaviour,
+    keep_alive: KeepAliveBehaviour,
+    /// Outbound only: asks relays for the other browsers on them.
+    peers: PeersBehaviour,
+    /// Outbound only: tells relays which room topics we still want.
+    interest: InterestBehaviour,
 }
 
 enum Command {
     Publish(Vec<u8>),
-    FindPeer(libp2p::PeerId),
+    PublishDocUpdate { update: DocUpdate, options: PublishOptions },
+    CommitTransaction(Transaction),
+    SetPublishDebounce(Option<std::time::Duration>),
+    ClearDedupCache,
+    PublishEphemeral { doc_id: String, data: Vec<u8> },
+    SubscribeEphemeral { doc_id: String },
+    /// Start or stop announcing a document in the DHT (used for pins).
+    SetProviding { doc_id: String, provide: bool },
+    FindPeer { peer_id: PeerId, options: FindPeerOptions, reply: futures::channel::oneshot::Sender<Vec<FoundPeer>> },
+    FindPeerLocal { peer_id: PeerId, reply: futures::channel::oneshot::Sender<Option<FoundPeer>> },
+    PublishHeadPointer {
+        doc_id: String,
+        version: u64,
+        content: Vec<u8>,
+        reply: futures::channel::oneshot::Sender<Result<HeadPointer, crate::Error>>,
+    },
+    ResolveHead {
+        author: PeerId,
+        doc_id: String,
+        reply: futures::channel::oneshot::Sender<Result<Option<HeadPointer>, crate::Error>>,
+    },
+    SetProfile { profile: Profile, reply: futures::channel::oneshot::Sender<Result<SignedProfile, crate::Error>> },
+    ResolveProfile { peer_id: PeerId, reply: futures::channel::oneshot::Sender<Result<Option<SignedProfile>, crate::Error>> },
+    DiscoverRelays { reply: futures::channel::oneshot::Sender<Vec<PeerId>> },
+    History {
+        peer_id: Option<PeerId>,
+        request: HistoryRequest,
+        reply: futures::channel::oneshot::Sender<Result<(PeerId, HistoryPage), crate::Error>>,
+    },
+    DocumentAt {
+        peer_id: Option<PeerId>,
+        request: HistoryRequest,
+        reply: futures::channel::oneshot::Sender<Result<(PeerId, DocumentState), crate::Error>>,
+    },
+    FetchDocument { doc_id: String, peer_id: Option<PeerId>, reply: FetchReply },
+    SendToMailbox {
+        holder: PeerId,
+        recipient: PeerId,
+        blob: Vec<u8>,
+        /// `0` for the holder's default.
+        ttl_ms: u64,
+        reply: futures::channel::oneshot::Sender<Result<MailboxReceipt, crate::Error>>,
+    },
+    CheckMailbox { holder: PeerId, reply: futures::channel::oneshot::Sender<Result<Vec<MailboxMessage>, crate::Error>> },
+    DiscoverPeers { reply: futures::channel::oneshot::Sender<Vec<Registrant>> },
     SendDirect { peer_id: libp2p::PeerId, data: Vec<u8> },
     ListenOnRelay { relay_addr: Multiaddr },
     ListenForWebRTC,
-    DialPeer { addr: Multiaddr },
+    DialPeer { addr: Multiaddr, options: DialOptions },
+    DisconnectPeer { peer_id: PeerId },
+    RemovePeerAddress { peer_id: PeerId, addr: Multiaddr },
+    RemovePeer { peer_id: PeerId },
+    BanPeer { peer_id: PeerId, duration: std::time::Duration },
+    MeshInfo(futures::channel::oneshot::Sender<Vec<crate::behaviour::docstore::TopicMeshInfo>>),
+    /// Stop background activity, see `WasmNode.suspend()`.
+    Suspend { disconnect: bool },
+    Resume,
+    /// See `WasmNode.set_bandwidth_budget()`.
+    SetBandwidthBudget { bytes_per_interval: Option<u64> },
+    /// Answered once the node is ready, see `WasmNode.ready()`.
+    WaitReady { reply: futures::channel::oneshot::Sender<NodeReadiness> },
+    /// Add documents to catch up on, see `WasmNode.interest()`.
+    Interest { doc_ids: Vec<String> },
+    /// `creator` is set for restricted rooms, see [`auth`]. `private_members` asks
+    /// FullNodes to keep no membership table for the room.
+    JoinRoom { room: RoomId, creator: Option<PeerId>, token: Option<Credential>, private_members: bool },
+    RoomMembers {
+        peer_id: Option<PeerId>,
+        request: MembersRequest,
+        reply: futures::channel::oneshot::Sender<Result<(PeerId, MembersPage), crate::Error>>,
+    },
+    LeaveRoom { room_id: String },
+    SetTopicInterest { topics: Vec<String> },
+    /// Keep the peers of a document connected, or stop, see `WasmNode.watch_doc()`.
+    WatchDoc { doc_id: String, watch: bool },
+    /// Deliver a document's updates in order, or stop, see `WasmNode.watch_document()`.
+    WatchDocument { doc_id: String, watch: bool },
+    KeepalivePeers { reply: futures::channel::oneshot::Sender<Vec<PeerId>> },
+    SetRoomToken { room_id: String, token: Capability },
+    RevokeCapabilities { room_id: String, ids: Vec<TokenId> },
+    PublishRoom {
+        room_id: String,
+        channel: RoomChannel,
+        data: Vec<u8>,
+        /// Set for updates; presence and ephemeral traffic is fire-and-forget.
+        reply: Option<futures::channel::oneshot::Sender<Result<Published, crate::Error>>>,
+    },
+    RegisterSchema { prefix: String, since_version: u8, validator: Box<dyn SchemaValidator> },
+    UnregisterSchema { prefix: String, reply: futures::channel::oneshot::Sender<bool> },
+    /// Hand an event to the event sink as if the swarm had produced it.
+    #[cfg(feature = "test-util")]
+    InjectEvent(Event),
+}
+
+impl Command {
+    /// Held while the bandwidth budget is exhausted.
+    fn waits_for_budget(&self) -> bool {
+        self.is_publish()
+            || matches!(self, Command::History { .. } | Command::DocumentAt { .. } | Command::FetchDocument { .. })
+    }
+
+    /// Update publishes; presence and ephemeral room traffic is not held.
+    fn is_publish(&self) -> bool {
+        matches!(
+            self,
+            Command::Publish(_)
+                | Command::PublishDocUpdate { .. }
+                | Command::CommitTransaction(_)
+                | Command::PublishRoom { channel: RoomChannel::Updates, .. }
+        )
+    }
+}
+
+/// Serializable so a worker can hand events to the main thread, see [`crate::wasm_worker`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum Event {
+    /// `transport` names what the connection runs over, see [`crate::node::addrs::transport_name`];
+    /// `direction` is "outbound" or "inbound".
+    Connected {
+        peer_id: String,
+        #[serde(deserialize_with = "interned")]
+        transport: &'static str,
+        #[serde(deserialize_with = "interned")]
+        direction: &'static str,
+    },
+    /// A dial started. `addr` is unknown when several addresses are tried at once.
+    Dialing { peer_id: Option<String>, addr: Option<String> },
+    /// A remote is opening a connection to us; `addr` is where it comes from.
+    IncomingConnection { addr: String },
+    /// A bootstrap address could not be reached; the node keeps going on the others.
+    BootstrapFailed { addr: String, attempts: u32, msg: String },
+    /// A dial gave up after `attempts` attempts; `reason` is the last failure.
+    DialFailed { peer_id: Option<String>, reason: DialFailure, attempts: u32, msg: String },
+    Disconnected { peer_id: String },
+    /// `failures` pings in a row to the peer failed; it is no longer an explicit gossipsub
+    /// peer, and its connection is closed if they go on failing.
+    PeerUnresponsive { peer_id: String, failures: u32 },
+    /// A dial to the peer started, trying `addrs` in this order, see `dialConcurrency`.
+    DialPlan { peer_id: String, addrs: Vec<String> },
+    /// The peer's connection quality moved to another bucket, see `peer_quality()`.
+    QualityChanged { peer_id: String, from: QualityBucket, to: QualityBucket, score: u8 },
+    /// Identify info arrived for a connected peer, or changed.
+    PeerIdentified { peer_id: String, agent_version: String, protocols: Vec<String>, shared_protocols: Vec<String> },
+    /// `msg_id` is stable across relays and replays, so duplicates can be dropped by it.
+    MessageReceived { peer_id: String, topic: String, msg_id: String, data: String },
+    EphemeralReceived { peer_id: String, topic: String, doc_id: String, data: String },
+    DocUpdateReceived { peer_id: String, topic: String, doc_id: String, data: String },
+    /// Every part of transaction `id` arrived; its updates were delivered just before, as
+    /// `docUpdateReceived` events. `id` is a decimal string (it doesn't fit a JS number).
+    TransactionApplied { peer_id: String, id: String, doc_ids: Vec<String> },
+    /// Verified snapshot newer than any previously delivered for the document.
+    SnapshotReceived { topic: String, doc_id: String, version: u64, data: String },
+    /// A current announcement signed by `peer_id`, one of the `announcers` option.
+    Announcement {
+        peer_id: String,
+        #[serde(deserialize_with = "interned")]
+        kind: &'static str,
+        #[serde(deserialize_with = "interned")]
+        severity: &'static str,
+        text: String,
+        issued_at_ms: u64,
+        expires_at_ms: Option<u64>,
+    },
+    /// Traffic on a joined room's topics. Delivered only to that room's handle.
+    RoomMessage { room_id: String, channel: RoomChannel, peer_id: String, data: String },
+    /// A peer announced its presence in a joined room for the first time since it was last
+    /// gone. Delivered only to that room's handle, like `MemberLeft`. `display_name` is
+    /// the name its presence carried, unverified; `resolve_profile` checks it.
+    MemberJoined { room_id: String, peer_id: String, display_name: Option<String> },
+    /// A peer sent no presence in a joined room for three heartbeats.
+    MemberLeft { room_id: String, peer_id: String },
+    /// `sent_to` are the peers gossipsub handed the message to.
+    MessagePublished { msg_id: String, sent_to: Vec<String> },
+    /// A published message reached no peer.
+    PublishWarning { msg_id: String, msg: String },
+    PeerDiscovery { peer_id: String, addrs: Vec<String> },
+    DirectMessageReceived { peer_id: String, data: String },
+    DirectMessageSent { peer_id: String },
+    ListenStarted { addr: String },
+    /// A peer observed us on an address not seen before, see `external_addrs()`.
+    ExternalAddrCandidate { addr: String },
+    /// `addr` is a confirmed external address and advertised to peers.
+    ExternalAddrConfirmed { addr: String },
+    ExternalAddrExpired { addr: String },
+    RelayReservationCreated { addr: String },
+    RelayConnectionEstablished {
+        peer_id: String,
+        #[serde(deserialize_with = "interned")]
+        transport: &'static str,
+        #[serde(deserialize_with = "interned")]
+        direction: &'static str,
+    },
+    WebRTCConnectionEstablished {
+        peer_id: String,
+        #[serde(deserialize_with = "interned")]
+        transport: &'static str,
+        #[serde(deserialize_with = "interned")]
+        direction: &'static str,
+    },
+    BannedPeerRejected { peer_id: String },
+    /// A provider found by `discover_relays()` serves the relay hop protocol.
+    RelayDiscovered { peer_id: String },
+    /// A peer published an envelope in a format version we cannot read; it was ignored.
+    UnsupportedVersion { peer_id: String, version: u8 },
+    /// An address was dropped from the DHT routing table.
+    AddressRemoved { peer_id: String, addr: String, reason: RemovalReason },
+    /// A peer was dropped from the DHT routing table.
+    PeerRemoved { peer_id: String, reason: RemovalReason },
+    /// A peer entered the DHT routing table or its addresses there changed.
+    RoutingUpdated { peer_id: String, is_new_peer: bool, addrs: Vec<String>, evicted: Option<String> },
+    /// A connected peer told us no address, so it cannot be routed to.
+    UnroutablePeer { peer_id: String },
+    /// A connected peer with an address did not make it into the routing table.
+    RoutablePeer { peer_id: String, addr: String },
+    /// Kademlia switched between "client" and "server" mode.
+    DhtModeChanged { mode: String },
+    /// The routing table grew or shrank.
+    DhtSummaryChanged { summary: DhtSummary },
+    /// Our mesh for the docstore topic lost its last peer; publishes fail until it refills.
+    MeshEmpty { topic: String },
+    /// `topic` has had no mesh peers (nor explicitly peered relays on it) since
+    /// `empty_since_ms` although we are connected, so the node unsubscribes and subscribes
+    /// again. `attempt` counts the tries since it was last healthy.
+    TopicUnhealthy { topic: String, empty_since_ms: u64, attempt: u32 },
+    /// A `topicUnhealthy` topic has mesh peers again, after `duration_ms`.
+    TopicRecovered { topic: String, duration_ms: u64, attempts: u32 },
+    /// The node was suspended; `disconnected` if its connections were closed too.
+    Suspended { disconnected: bool },
+    /// The node was resumed after `suspended_ms`. Anything published by others meanwhile
+    /// was missed; this is the cue to catch up.
+    Resumed { suspended_ms: f64 },
+    /// `used` bytes of the bandwidth budget's `limit` are spent; publishes, history
+    /// fetches and catch-up wait until `resets_at_ms`, ephemeral messages are dropped.
+    BudgetExceeded { used: u64, limit: u64, resets_at_ms: u64 },
+    /// The bandwidth budget is available again; what waited goes out now.
+    BudgetReset,
+    /// A catch-up run fetched the current state of every document of interest;
+    /// `doc_count` of them had any, delivered as `docUpdateReceived` events before this.
+    CaughtUp { doc_count: usize },
+    /// A catch-up run could not fetch some documents (`failed`); they only get live
+    /// updates until the next run.
+    CatchUpFailed { doc_count: usize, failed: Vec<String>, msg: String },
+    /// Back online after a long outage, our DHT announcements were made again: `renewed`
+    /// succeeded, the keys in `failed` did not.
+    AnnouncementsRenewed { renewed: usize, failed: Vec<String> },
+    /// `holder` handed over a message left for us in its mailbox, fetched when we
+    /// connected to it. Acknowledged, so the holder has deleted it.
+    MailboxDelivered { holder: String, message: MailboxMessage },
+    /// `peer_id` applied an update we published with `{ ackRequested: true }`, bringing
+    /// `doc_id` to `applied_version` there. Reported once per peer.
+    UpdateAcknowledged { msg_id: String, peer_id: String, doc_id: String, applied_version: u64 },
+    /// Nobody acknowledged an update published with `{ ackRequested: true }` within 30
+    /// seconds.
+    UpdateUnacknowledged { msg_id: String, doc_id: String },
+    /// `peer_id` refused a CAS update we published with `try_cas_update()`: `doc_id` is
+    /// at `current_version` there. Reported once per peer; other peers may still have
+    /// applied it.
+    CasConflict { msg_id: String, peer_id: String, doc_id: String, current_version: u64 },
+    /// `peer_id` ignored an update we published asking for receipts, because storing it
+    /// would go over a room's quota or ours there. Why is left in our mailbox at
+    /// `peer_id`, when it holds one.
+    QuotaRefused { msg_id: String, peer_id: String, doc_id: String },
+    /// An update of a document watched with `watch_document()`, in order. `origin` is
+    /// "live" for gossip, "replay" for updates fetched to fill a gap and "snapshot" for
+    /// an installed snapshot standing in for the updates before it.
+    OrderedUpdate { doc_id: String, data: String, origin: Origin },
+    /// A gap in a document watched with `watch_document()` was not filled in time: its
+    /// `missing` updates are given up on, and the updates held back behind it follow.
+    GapAbandoned { doc_id: String, missing: u64 },
+    /// A chunk of `doc_id` fetched with `fetch_document()` was verified: `received` of its
+    /// `total` bytes are in, counting what an earlier attempt fetched.
+    FetchProgress { doc_id: String, received: u64, total: u64 },
+    /// A document bundled in the `seedDocuments` option, delivered before any network
+    /// activity with `origin` "seed".
+    SeedLoaded { doc_id: String, data: String, version: u64 },
+    /// The network's state of a seeded document at `version` replaced its seed.
+    SeedOverridden { doc_id: String, version: u64 },
+    /// An update or snapshot of `doc_id` from `peer_id` failed the validator registered
+    /// for `prefix` with `register_schema()`; it was rejected before being applied.
+    SchemaViolation { peer_id: String, doc_id: String, prefix: String, version: u8, reason: String },
+    Error { msg: String },
+}
+
+impl HistoryEvent for Event {
+    /// Name of the event as reported in the JS object's `type` field.
+    fn kind(&self) -> &'static str {
+        match self {
+            Event::Connected { .. } => "connected",
+            Event::Dialing { .. } => "dialing",
+            Event::IncomingConnection { .. } => "incomingConnection",
+            Event::BootstrapFailed { .. } => "bootstrapFailed",
+            Event::DialFailed { .. } => "dialFailed",
+            Event::Disconnected { .. } => "disconnected",
+            Event::PeerUnresponsive { .. } => "peerUnresponsive",
+            Event::DialPlan { .. } => "dialPlan",
+            Event::QualityChanged { .. } => "qualityChanged",
+            Event::PeerIdentified { .. } => "peerIdentified",
+            Event::MessageReceived { .. } => "messageReceived",
+            Event::EphemeralReceived { .. } => "ephemeralReceived",
+            Event::DocUpdateReceived { .. } => "docUpdateReceived",
+            Event::TransactionApplied { .. } => "transactionApplied",
+            Event::SnapshotReceived { .. } => "snapshotReceived",
+            Event::Announcement { .. } => "announcement",
+            Event::RoomMessage { .. } => "roomMessage",
+            Event::MemberJoined { .. } => "memberJoined",
+            Event::MemberLeft { .. } => "memberLeft",
+            Event::MessagePublished { .. } => "messagePublished",
+            Event::PublishWarning { .. } => "publishWarning",
+            Event::PeerDiscovery { .. } => "peerDiscovery",
+            Event::DirectMessageReceived { .. } => "directMessageReceived",
+            Event::DirectMessageSent { .. } => "directMessageSent",
+            Event::ListenStarted { .. } => "listenStarted",
+            Event::ExternalAddrCandidate { .. } => "externalAddrCandidate",
+            Event::ExternalAddrConfirmed { .. } => "externalAddrConfirmed",
+            Event::ExternalAddrExpired { .. } => "externalAddrExpired",
+            Event::RelayReservationCreated { .. } => "relayReservationCreated",
+            Event::RelayConnectionEstablished { .. } => "relayConnectionEstablished",
+            Event::WebRTCConnectionEstablished { .. } => "webrtcConnectionEstablished",
+            Event::BannedPeerRejected { .. } => "bannedPeerRejected",
+            Event::RelayDiscovered { .. } => "relayDiscovered",
+            Event::UnsupportedVersion { .. } => "unsupportedVersion",
+            Event::AddressRemoved { .. } => "addressRemoved",
+            Event::PeerRemoved { .. } => "peerRemoved",
+            Event::RoutingUpdated { .. } => "routingUpdated",
+            Event::UnroutablePeer { .. } => "unroutablePeer",
+            Event::RoutablePeer { .. } => "routablePeer",
+            Event::DhtModeChanged { .. } => "dhtModeChanged",
+            Event::DhtSummaryChanged { .. } => "dhtSummaryChanged",
+            Event::MeshEmpty { .. } => "meshEmpty",
+            Event::TopicUnhealthy { .. } => "topicUnhealthy",
+            Event::TopicRecovered { .. } => "topicRecovered",
+            Event::Suspended { .. } => "suspended",
+            Event::Resumed { .. } => "resumed",
+            Event::BudgetExceeded { .. } => "budgetExceeded",
+            Event::BudgetReset => "budgetReset",
+            Event::CaughtUp { .. } => "caughtUp",
+            Event::CatchUpFailed { .. } => "catchUpFailed",
+            Event::AnnouncementsRenewed { .. } => "announcementsRenewed",
+            Event::MailboxDelivered { .. } => "mailboxDelivered",
+            Event::UpdateAcknowledged { .. } => "updateAcknowledged",
+            Event::UpdateUnacknowledged { .. } => "updateUnacknowledged",
+            Event::CasConflict { .. } => "casConflict",
+            Event::QuotaRefused { .. } => "quotaRefused",
+            Event::OrderedUpdate { .. } => "orderedUpdate",
+            Event::GapAbandoned { .. } => "gapAbandoned",
+            Event::FetchProgress { .. } => "fetchProgress",
+            Event::SeedLoaded { .. } => "seedLoaded",
+            Event::SeedOverridden { .. } => "seedOverridden",
+            Event::SchemaViolation { .. } => "schemaViolation",
+            Event::Error { .. } => "error",
+        }
+    }
+
+    fn approx_size(&self) -> usize {
+        let heap = match self {
+            Event::Connected { peer_id, .. }
+            | Event::Disconnected { peer_id }
+            | Event::PeerUnresponsive { peer_id, .. }
+            | Event::QualityChanged { peer_id, .. }
+            | Event::DirectMessageSent { peer_id }
+            | Event::RelayConnectionEstablished { peer_id, .. }
+            | Event::WebRTCConnectionEstablished { peer_id, .. }
+            | Event::BannedPeerRejected { peer_id }
+            | Event::RelayDiscovered { peer_id }
+            | Event::UnsupportedVersion { peer_id, .. }
+            | Event::PeerRemoved { peer_id, .. }
+            | Event::UnroutablePeer { peer_id } => peer_id.len(),
+            Event::AddressRemoved { peer_id, addr, .. } | Event::RoutablePeer { peer_id, addr } => {
+                peer_id.len() + addr.len()
+            }
+            Event::RoutingUpdated { peer_id, addrs, evicted, .. } => {
+                peer_id.len() + addrs.iter().map(String::len).sum::<usize>() + evicted.as_ref().map_or(0, String::len)
+            }
+            Event::DhtSummaryChanged { .. }
+            | Event::Suspended { .. }
+            | Event::Resumed { .. }
+            | Event::BudgetExceeded { .. }
+            | Event::BudgetReset
+            | Event::CaughtUp { .. } => 0,
+            Event::CatchUpFailed { failed, msg, .. } => failed.iter().map(String::len).sum::<usize>() + msg.len(),
+            Event::AnnouncementsRenewed { failed, .. } => failed.iter().map(String::len).sum(),
+            Event::MailboxDelivered { holder, message } => holder.len() + message.sender.len() + message.blob.len(),
+            Event::UpdateAcknowledged { msg_id, peer_id, doc_id, .. }
+            | Event::CasConflict { msg_id, peer_id, doc_id, .. }
+            | Event::QuotaRefused { msg_id, peer_id, doc_id } => msg_id.len() + peer_id.len() + doc_id.len(),
+            Event::UpdateUnacknowledged { msg_id, doc_id } => msg_id.len() + doc_id.len(),
+            Event::OrderedUpdate { doc_id, data, .. } => doc_id.len() + data.len(),
+            Event::GapAbandoned { doc_id, .. } | Event::FetchProgress { doc_id, .. } | Event::SeedOverridden { doc_id, .. } => {
+                doc_id.len()
+            }
+            Event::SeedLoaded { doc_id, data, .. } => doc_id.len() + data.len(),
+            Event::SchemaViolation { peer_id, doc_id, prefix, reason, .. } => {
+                peer_id.len() + doc_id.len() + prefix.len() + reason.len()
+            }
+            Event::Dialing { peer_id, addr } => {
+                peer_id.as_ref().map_or(0, String::len) + addr.as_ref().map_or(0, String::len)
+            }
+            Event::DialFailed { peer_id, msg, .. } => peer_id.as_ref().map_or(0, String::len) + msg.len(),
+            Event::BootstrapFailed { addr, msg, .. } => addr.len() + msg.len(),
+            Event::MessageReceived { peer_id, topic, msg_id, data } => {
+                peer_id.len() + topic.len() + msg_id.len() + data.len()
+            }
+            Event::DirectMessageReceived { peer_id, data } => peer_id.len() + data.len(),
+            Event::EphemeralReceived { peer_id, topic, doc_id, data }
+            | Event::DocUpdateReceived { peer_id, topic, doc_id, data } => {
+                peer_id.len() + topic.len() + doc_id.len() + data.len()
+            }
+            Event::SnapshotReceived { topic, doc_id, data, .. } => topic.len() + doc_id.len() + data.len(),
+            Event::Announcement { peer_id, text, .. } => peer_id.len() + text.len(),
+            Event::TransactionApplied { peer_id, id, doc_ids } => {
+                peer_id.len() + id.len() + doc_ids.iter().map(String::len).sum::<usize>()
+            }
+            Event::RoomMessage { room_id, peer_id, data, .. } => room_id.len() + peer_id.len() + data.len(),
+            Event::MemberJoined { room_id, peer_id, display_name } => {
+                room_id.len() + peer_id.len() + display_name.as_ref().map_or(0, String::len)
+            }
+            Event::MemberLeft { room_id, peer_id } => room_id.len() + peer_id.len(),
+            Event::PeerDiscovery { peer_id, addrs } | Event::DialPlan { peer_id, addrs } => {
+                peer_id.len() + addrs.iter().map(String::len).sum::<usize>()
+            }
+            Event::PeerIdentified { peer_id, agent_version, protocols, shared_protocols } => {
+                peer_id.len()
+                    + agent_version.len()
+                    + protocols.iter().chain(shared_protocols).map(String::len).sum::<usize>()
+            }
+            Event::MessagePublished { msg_id, sent_to } => msg_id.len() + sent_to.iter().map(String::len).sum::<usize>(),
+            Event::PublishWarning { msg_id, msg } => msg_id.len() + msg.len(),
+            Event::ListenStarted { addr: s }
+            | Event::ExternalAddrCandidate { addr: s }
+            | Event::ExternalAddrConfirmed { addr: s }
+            | Event::ExternalAddrExpired { addr: s }
+            | Event::RelayReservationCreated { addr: s }
+            | Event::IncomingConnection { addr: s }
+            | Event::MeshEmpty { topic: s }
+            | Event::TopicUnhealthy { topic: s, .. }
+            | Event::TopicRecovered { topic: s, .. }
+            | Event::DhtModeChanged { mode: s }
+            | Event::Error { msg: s } => s.len(),
+        };
+        std::mem::size_of::<Self>() + heap
+    }
+}
+
+impl EventScope for Event {
+    fn topic(&self) -> Option<String> {
+        match self {
+            Event::MessageReceived { topic, .. }
+            | Event::DocUpdateReceived { topic, .. }
+            | Event::EphemeralReceived { topic, .. }
+            | Event::SnapshotReceived { topic, .. }
+            | Event::MeshEmpty { topic }
+            | Event::TopicUnhealthy { topic, .. }
+            | Event::TopicRecovered { topic, .. } => Some(topic.clone()),
+            _ => None,
+        }
+    }
+
+    fn doc_id(&self) -> Option<&str> {
+        match self {
+            Event::EphemeralReceived { doc_id, .. }
+            | Event::DocUpdateReceived { doc_id, .. }
+            | Event::SnapshotReceived { doc_id, .. }
+            | Event::UpdateAcknowledged { doc_id, .. }
+            | Event::UpdateUnacknowledged { doc_id, .. }
+            | Event::CasConflict { doc_id, .. }
+            | Event::QuotaRefused { doc_id, .. }
+            | Event::OrderedUpdate { doc_id, .. }
+            | Event::GapAbandoned { doc_id, .. }
+            | Event::FetchProgress { doc_id, .. }
+            | Event::SeedLoaded { doc_id, .. }
+            | Event::SeedOverridden { doc_id, .. }
+            | Event::SchemaViolation { doc_id, .. } => Some(doc_id),
+            _ => None,
+        }
+    }
+}
+
+impl Event {
+    fn to_js(self) -> Result<JsValue, JsValue> {
+        let obj = Object::new();
+        Reflect::set(&obj, &"type".into(), &self.kind().into())?;
+        match self {
+            Event::Connected { peer_id, transport, direction }
+            | Event::RelayConnectionEstablished { peer_id, transport, direction }
+            | Event::WebRTCConnectionEstablished { peer_id, transport, direction } => {
+                Reflect::set(&obj, &"peer_id".into(), &peer_id.into())?;
+                Reflect::set(&obj, &"transport".into(), &transport.into())?;
+                Reflect::set(&obj, &"direction".into(), &direction.into())?;
+            }
+            Event::Dialing { peer_id, addr } => {
+                Reflect::set(&obj, &"peer_id".into(), &peer_id.map_or(JsValue::NULL, JsValue::from))?;
+                Reflect::set(&obj, &"addr".into(), &addr.map_or(JsValue::NULL, JsValue::from))?;
+            }
+            Event::IncomingConnection { addr } => {
+                Reflect::set(&obj, &"addr".into(), &addr.into())?;
+            }
+            Event::BootstrapFailed { addr, attempts, msg } => {
+                Reflect::set(&obj, &"addr".into(), &addr.into())?;
+                Reflect::set(&obj, &"attempts".into(), &JsValue::from_f64(attempts as f64))?;
+                Reflect::set(&obj, &"msg".into(), &msg.into())?;
+            }
+            Event::DialFailed { peer_id, reason, attempts, msg } => {
+                Reflect::set(&obj, &"peer_id".into(), &peer_id.map_or(JsValue::NULL, JsValue::from))?;
+                Reflect::set(&obj, &"reason".into(), &reason.as_str().into())?;
+                Reflect::set(&obj, &"attempts".into(), &JsValue::from_f64(attempts as f64))?;
+                Reflect::set(&obj, &"msg".into(), &msg.into())?;
+            }
+            Event::Disconnected { peer_id } => {
+                Reflect::set(&obj, &"peer_id".into(), &peer_id.into())?;
+            }
+            Event::PeerUnresponsive { peer_id, failures } => {
+                Reflect::set(&obj, &"peer_id".into(), &peer_id.into())?;
+                Reflect::set(&obj, &"failures".into(), &JsValue::from_f64(failures as f64))?;
+            }
+            Event::QualityChanged { peer_id, from, to, score } => {
+                Reflect::set(&obj, &"peer_id".into(), &peer_id.into())?;
+                Reflect::set(&obj, &"from".into(), &from.as_str().into())?;
+                Reflect::set(&obj, &"to".into(), &to.as_str().into())?;
+                Reflect::set(&obj, &"score".into(), &JsValue::from_f64(score as f64))?;
+            }
+            Event::PeerIdentified { peer_id, agent_version, protocols, shared_protocols } => {
+                Reflect::set(&obj, &"peer_id".into(), &peer_id.into())?;
+                Reflect::set(&obj, &"agent_version".into(), &agent_version.into())?;
+                Reflect::set(&obj, &"protocols".into(), &string_array(&protocols).into())?;
+                Reflect::set(&obj, &"shared_protocols".into(), &string_array(&shared_protocols).into())?;
+            }
+            Event::MessageReceived { peer_id, topic, msg_id, data } => {
+                Reflect::set(&obj, &"peer_id".into(), &peer_id.into())?;
+                Reflect::set(&obj, &"topic".into(), &topic.into())?;
+                Reflect::set(&obj, &"msg_id".into(), &msg_id.into())?;
+                Reflect::set(&obj, &"data".into(), &data.into())?;
+            }
+            Event::EphemeralReceived { peer_id, topic, doc_id, data } => {
+                Reflect::set(&obj, &"peer_id".into(), &peer_id.into())?;
+                Reflect::set(&obj, &"topic".into(), &topic.into())?;
+                Reflect::set(&obj, &"doc_id".into(), &doc_id.into())?;
+                Reflect::set(&obj, &"data".into(), &data.into())?;
+            }
+            Event::DocUpdateReceived { peer_id, topic, doc_id, data } => {
+                Reflect::set(&obj, &"peer_id".into(), &peer_id.into())?;
+                Reflect::set(&obj, &"topic".into(), &topic.into())?;
+                Reflect::set(&obj, &"doc_id".into(), &doc_id.into())?;
+                Reflect::set(&obj, &"data".into(), &data.into())?;
+            }
+            Event::TransactionApplied { peer_id, id, doc_ids } => {
+                Reflect::set(&obj, &"peer_id".into(), &peer_id.into())?;
+                Reflect::set(&obj, &"id".into(), &id.into())?;
+                Reflect::set(&obj, &"doc_ids".into(), &string_array(&doc_ids).into())?;
+            }
+            Event::RoomMessage { room_id, channel, peer_id, data } => {
+                Reflect::set(&obj, &"room_id".into(), &room_id.into())?;
+                Reflect::set(&obj, &"channel".into(), &channel.as_str().into())?;
+                Reflect::set(&obj, &"peer_id".into(), &peer_id.into())?;
+                Reflect::set(&obj, &"data".into(), &data.into())?;
+            }
+            Event::MemberJoined { room_id, peer_id, display_name } => {
+                Reflect::set(&obj, &"room_id".into(), &room_id.into())?;
+                Reflect::set(&obj, &"peer_id".into(), &peer_id.into())?;
+                Reflect::set(&obj, &"display_name".into(), &display_name.map_or(JsValue::NULL, JsValue::from))?;
+            }
+            Event::MemberLeft { room_id, peer_id } => {
+                Reflect::set(&obj, &"room_id".into(), &room_id.into())?;
+                Reflect::set(&obj, &"peer_id".into(), &peer_id.into())?;
+            }
+            Event::SnapshotReceived { topic, doc_id, version, data } => {
+                Reflect::set(&obj, &"topic".into(), &topic.into())?;
+                Reflect::set(&obj, &"doc_id".into(), &doc_id.into())?;
+                Reflect::set(&obj, &"version".into(), &JsValue::from_f64(version as f64))?;
+                Reflect::set(&obj, &"data".into(), &data.into())?;
+            }
+            Event::Announcement { peer_id, kind, severity, text, issued_at_ms, expires_at_ms } => {
+                Reflect::set(&obj, &"peer_id".into(), &peer_id.into())?;
+                Reflect::set(&obj, &"kind".into(), &kind.into())?;
+                Reflect::set(&obj, &"severity".into(), &severity.into())?;
+                Reflect::set(&obj, &"text".into(), &text.into())?;
+                Reflect::set(&obj, &"issued_at_ms".into(), &JsValue::from_f64(issued_at_ms as f64))?;
+                let expires = expires_at_ms.map_or(JsValue::NULL, |ms| JsValue::from_f64(ms as f64));
+                Reflect::set(&obj, &"expires_at_ms".into(), &expires)?;
+            }
+            Event::MessagePublished { msg_id, sent_to } => {
+                Reflect::set(&obj, &"msg_id".into(), &msg_id.into())?;
+                Reflect::set(&obj, &"sent_to".into(), &string_array(&sent_to).into())?;
+            }
+            Event::PublishWarning { msg_id, msg } => {
+                Reflect::set(&obj, &"msg_id".into(), &msg_id.into())?;
+                Reflect::set(&obj, &"msg".into(), &msg.into())?;
+            }
+            Event::PeerDiscovery { peer_id, addrs } | Event::DialPlan { peer_id, addrs } => {
+                Reflect::set(&obj, &"peer_id".into(), &peer_id.into())?;
+                let js_arr = js_sys::Array::new();
+                for a in addrs.iter() {
+                    js_arr.push(&JsValue::from_str(a));
+                }
+                Reflect::set(&obj, &"addrs".into(), &js_arr.into())?;
+            }
+            Event::DirectMessageReceived { peer_id, data } => {
+                Reflect::set(&obj, &"peer_id".into(), &peer_id.into())?;
+                Reflect::set(&obj, &"data".into(), &data.into())?;
+            }
+            Event::DirectMessageSent { peer_id } => {
+                Reflect::set(&obj, &"peer_id".into(), &peer_id.into())?;
+            }
+            Event::ListenStarted { addr }
+            | Event::ExternalAddrCandidate { addr }
+            | Event::ExternalAddrConfirmed { addr }
+            | Event::ExternalAddrExpired { addr } => {
+                Reflect::set(&obj, &"addr".into(), &addr.into())?;
+            }
+            Event::RelayReservationCreated { addr } => {
+                Reflect::set(&obj, &"addr".into(), &addr.into())?;
+            }
+            Event::BannedPeerRejected { peer_id } | Event::RelayDiscovered { peer_id } => {
+                Reflect::set(&obj, &"peer_id".into(), &peer_id.into())?;
+            }
+            Event::UnsupportedVersion { peer_id, version } => {
+                Reflect::set(&obj, &"peer_id".into(), &peer_id.into())?;
+                Reflect::set(&obj, &"version".into(), &JsValue::from(version))?;
+            }
+            Event::AddressRemoved { peer_id, addr, reason } => {
+                Reflect::set(&obj, &"peer_id".into(), &peer_id.into())?;
+                Reflect::set(&obj, &"addr".into(), &addr.into())?;
+                Reflect::set(&obj, &"reason".into(), &reason.as_str().into())?;
+            }
+            Event::PeerRemoved { peer_id, reason } => {
+                Reflect::set(&obj, &"peer_id".into(), &peer_id.into())?;
+                Reflect::set(&obj, &"reason".into(), &reason.as_str().into())?;
+            }
+            Event::RoutingUpdated { peer_id, is_new_peer, addrs, evicted } => {
+                Reflect::set(&obj, &"peer_id".into(), &peer_id.into())?;
+                Reflect::set(&obj, &"is_new_peer".into(), &JsValue::from_bool(is_new_peer))?;
+                Reflect::set(&obj, &"addrs".into(), &string_array(&addrs).into())?;
+                Reflect::set(&obj, &"evicted".into(), &evicted.map_or(JsValue::NULL, JsValue::from))?;
+            }
+            Event::UnroutablePeer { peer_id } => {
+                Reflect::set(&obj, &"peer_id".into(), &peer_id.into())?;
+            }
+            Event::RoutablePeer { peer_id, addr } => {
+                Reflect::set(&obj, &"peer_id".into(), &peer_id.into())?;
+                Reflect::set(&obj, &"addr".into(), &addr.into())?;
+            }
+            Event::DhtModeChanged { mode } => {
+                Reflect::set(&obj, &"mode".into(), &mode.into())?;
+            }
+            Event::DhtSummaryChanged { summary } => {
+                set_dht_summary(&obj, &summary)?;
+            }
+            Event::MeshEmpty { topic } => {
+                Reflect::set(&obj, &"topic".into(), &topic.into())?;
+            }
+            Event::TopicUnhealthy { topic, empty_since_ms, attempt } => {
+                Reflect::set(&obj, &"topic".into(), &topic.into())?;
+                Reflect::set(&obj, &"empty_since_ms".into(), &JsValue::from_f64(empty_since_ms as f64))?;
+                Reflect::set(&obj, &"attempt".into(), &JsValue::from_f64(attempt as f64))?;
+            }
+            Event::TopicRecovered { topic, duration_ms, attempts } => {
+                Reflect::set(&obj, &"topic".into(), &topic.into())?;
+                Reflect::set(&obj, &"duration_ms".into(), &JsValue::from_f64(duration_ms as f64))?;
+                Reflect::set(&obj, &"attempts".into(), &JsValue::from_f64(attempts as f64))?;
+            }
+            Event::Suspended { disconnected } => {
+                Reflect::set(&obj, &"disconnected".into(), &disconnected.into())?;
+            }
+            Event::Resumed { suspended_ms } => {
+                Reflect::set(&obj, &"suspended_ms".into(), &JsValue::from_f64(suspended_ms))?;
+            }
+            Event::BudgetExceeded { used, limit, resets_at_ms } => {
+                Reflect::set(&obj, &"used".into(), &JsValue::from_f64(used as f64))?;
+                Reflect::set(&obj, &"limit".into(), &JsValue::from_f64(limit as f64))?;
+                Reflect::set(&obj, &"resets_at_ms".into(), &JsValue::from_f64(resets_at_ms as f64))?;
+            }
+            Event::BudgetReset => {}
+            Event::CaughtUp { doc_count } => {
+                Reflect::set(&obj, &"doc_count".into(), &JsValue::from_f64(doc_count as f64))?;
+            }
+            Event::CatchUpFailed { doc_count, failed, msg } => {
+                Reflect::set(&obj, &"doc_count".into(), &JsValue::from_f64(doc_count as f64))?;
+                Reflect::set(&obj, &"failed".into(), &string_array(&failed).into())?;
+                Reflect::set(&obj, &"msg".into(), &msg.into())?;
+            }
+            Event::AnnouncementsRenewed { renewed, failed } => {
+                Reflect::set(&obj, &"renewed".into(), &JsValue::from_f64(renewed as f64))?;
+                Reflect::set(&obj, &"failed".into(), &string_array(&failed).into())?;
+            }
+            Event::MailboxDelivered { holder, message } => {
+                Reflect::set(&obj, &"holder".into(), &holder.into())?;
+                Reflect::set(&obj, &"id".into(), &JsValue::from_f64(message.id as f64))?;
+                Reflect::set(&obj, &"sender".into(), &message.sender.into())?;
+                Reflect::set(&obj, &"data".into(), &js_sys::Uint8Array::from(message.blob.as_slice()).into())?;
+                Reflect::set(&obj, &"deposited_at_ms".into(), &JsValue::from_f64(message.deposited_at_ms as f64))?;
+                Reflect::set(&obj, &"expires_at_ms".into(), &JsValue::from_f64(message.expires_at_ms as f64))?;
+            }
+            Event::UpdateAcknowledged { msg_id, peer_id, doc_id, applied_version } => {
+                Reflect::set(&obj, &"msg_id".into(), &msg_id.into())?;
+                Reflect::set(&obj, &"peer_id".into(), &peer_id.into())?;
+                Reflect::set(&obj, &"doc_id".into(), &doc_id.into())?;
+                Reflect::set(&obj, &"applied_version".into(), &JsValue::from_f64(applied_version as f64))?;
+            }
+            Event::UpdateUnacknowledged { msg_id, doc_id } => {
+                Reflect::set(&obj, &"msg_id".into(), &msg_id.into())?;
+                Reflect::set(&obj, &"doc_id".into(), &doc_id.into())?;
+            }
+            Event::CasConflict { msg_id, peer_id, doc_id, current_version } => {
+                Reflect::set(&obj, &"msg_id".into(), &msg_id.into())?;
+                Reflect::set(&obj, &"peer_id".into(), &peer_id.into())?;
+                Reflect::set(&obj, &"doc_id".into(), &doc_id.into())?;
+                Reflect::set(&obj, &"current_version".into(), &JsValue::from_f64(current_version as f64))?;
+            }
+            Event::QuotaRefused { msg_id, peer_id, doc_id } => {
+                Reflect::set(&obj, &"msg_id".into(), &msg_id.into())?;
+                Reflect::set(&obj, &"peer_id".into(), &peer_id.into())?;
+                Reflect::set(&obj, &"doc_id".into(), &doc_id.into())?;
+            }
+            Event::OrderedUpdate { doc_id, data, origin } => {
+                Reflect::set(&obj, &"doc_id".into(), &doc_id.into())?;
+                Reflect::set(&obj, &"data".into(), &data.into())?;
+                Reflect::set(&obj, &"origin".into(), &origin.as_str().into())?;
+            }
+            Event::GapAbandoned { doc_id, missing } => {
+                Reflect::set(&obj, &"doc_id".into(), &doc_id.into())?;
+                Reflect::set(&obj, &"missing".into(), &JsValue::from_f64(missing as f64))?;
+            }
+            Event::FetchProgress { doc_id, received, total } => {
+                Reflect::set(&obj, &"doc_id".into(), &doc_id.into())?;
+                Reflect::set(&obj, &"received".into(), &JsValue::from_f64(received as f64))?;
+                Reflect::set(&obj, &"total".into(), &JsValue::from_f64(total as f64))?;
+            }
+            Event::SeedLoaded { doc_id, data, version } => {
+                Reflect::set(&obj, &"doc_id".into(), &doc_id.into())?;
+                Reflect::set(&obj, &"data".into(), &data.into())?;
+                Reflect::set(&obj, &"version".into(), &JsValue::from_f64(version as f64))?;
+                Reflect::set(&obj, &"origin".into(), &Origin::Seed.as_str().into())?;
+            }
+            Event::SeedOverridden { doc_id, version } => {
+                Reflect::set(&obj, &"doc_id".into(), &doc_id.into())?;
+                Reflect::set(&obj, &"version".into(), &JsValue::from_f64(version as f64))?;
+            }
+            Event::SchemaViolation { peer_id, doc_id, prefix, version, reason } => {
+                Reflect::set(&obj, &"peer_id".into(), &peer_id.into())?;
+                Reflect::set(&obj, &"doc_id".into(), &doc_id.into())?;
+                Reflect::set(&obj, &"prefix".into(), &prefix.into())?;
+                Reflect::set(&obj, &"version".into(), &JsValue::from(version))?;
+                Reflect::set(&obj, &"reason".into(), &reason.into())?;
+            }
+            Event::Error { msg } => {
+                Reflect::set(&obj, &"msg".into(), &msg.into())?;
+            }
+        }
+        Ok(obj.into())
+    }
+}
+
+fn next_to_js(next: event_queue::Next<Event>) -> Result<JsValue, JsValue> {
+    match next {
+        event_queue::Next::Event(event) => event.to_js(),
+        event_queue::Next::Closed => {
+            let obj = Object::new();
+            Reflect::set(&obj, &"type".into(), &"closed".into())?;
+            Ok(obj.into())
+        }
+        event_queue::Next::TimedOut => Ok(JsValue::NULL),
+    }
+}
+
+/// Subscriptions of each joined room, by room id.
+type RoomSubscriptions = Arc<std::sync::Mutex<HashMap<String, Subscriptions<Event>>>>;
+
+/// Event channel that also keeps each event in the node's history, so it can still be
+/// inspected after the consumer missed it, and copies it to matching subscriptions.
+/// Room events bypass all of that and go to their room's subscriptions only.
+struct EventSink {
+    sender: mpsc::UnboundedSender<Event>,
+    history: EventHistory<Event>,
+    subscriptions: Subscriptions<Event>,
+    rooms: RoomSubscriptions,
+    /// Set in a worker: every event, room events included, goes here instead, to be
+    /// delivered by the main thread's node.
+    tap: Option<mpsc::UnboundedSender<Event>>,
+}
+
+impl EventSink {
+    fn unbounded_send(&self, event: Event) -> Result<(), mpsc::TrySendError<Event>> {
+        if let Some(tap) = &self.tap {
+            return tap.unbounded_send(event);
+        }
+        if let Event::RoomMessage { room_id, .. } | Event::MemberJoined { room_id, .. } | Event::MemberLeft { room_id, .. } = &event {
+            if let Some(room) = self.rooms.lock().expect("rooms lock").get(room_id) {
+                room.dispatch(&event);
+            }
+            return Ok(());
+        }
+        self.history.record(&event);
+        self.subscriptions.dispatch(&event);
+        self.sender.unbounded_send(event)
+    }
+}
+
+/// Parse a `subscribe_events` filter:
+/// `{ types?: string[], topic?: string, docId?: string, capacity?: number }`.
+fn event_filter_from_js(filter: &JsValue) -> Result<(EventFilter, usize), JsValue> {
+    let mut out = EventFilter::default();
+    let mut capacity = event_queue::DEFAULT_SUBSCRIPTION_CAPACITY;
+    if filter.is_undefined() || filter.is_null() {
+        return Ok((out, capacity));
+    }
+    let types = Reflect::get(filter, &"types".into())?;
+    if !types.is_undefined() && !types.is_null() {
+        let array = types
+            .dyn_ref::<js_sys::Array>()
+            .ok_or_else(|| JsValue::from_str("types must be an array of event type names"))?;
+        out.kinds = Some(array.iter().filter_map(|t| t.as_string()).collect());
+    }
+    out.topic = Reflect::get(filter, &"topic".into())?.as_string();
+    out.doc_id = Reflect::get(filter, &"docId".into())?.as_string();
+    if let Some(n) = Reflect::get(filter, &"capacity".into())?.as_f64() {
+        capacity = n.max(1.0) as usize;
+    }
+    Ok((out, capacity))
+}
+
+/// A filtered copy of a node's events, created by `WasmNode.subscribe_events()`. Reading
+/// from it does not take events away from `next_event()` or other subscriptions.
+#[wasm_bindgen]
+pub struct EventSubscription {
+    inner: Subscription<Event>,
+}
+
+#[wasm_bindgen]
+impl EventSubscription {
+    /// Resolves with the next matching event, or `{ type: "closed" }` once cancelled and
+    /// nothing is left queued.
+    #[wasm_bindgen]
+    pub async fn next(&self) -> Result<JsValue, JsValue> {
+        next_to_js(self.inner.next().await)
+    }
+
+    /// Like `next()`, resolving with `null` if nothing arrived within `ms` milliseconds.
+    #[wasm_bindgen]
+    pub async fn next_timeout(&self, ms: u32) -> Result<JsValue, JsValue> {
+        let deadline = futures_timer::Delay::new(std::time::Duration::from_millis(ms as u64));
+        next_to_js(self.inner.next_until(deadline).await)
+    }
+
+    /// Stop receiving events. Events already queued can still be read.
+    #[wasm_bindgen]
+    pub fn cancel(&self) {
+        self.inner.cancel();
+    }
+
+    /// Matching events lost because this subscription was not read fast enough.
+    #[wasm_bindgen(getter)]
+    pub fn dropped(&self) -> f64 {
+        self.inner.dropped() as f64
+    }
+}
+
+/// A joined room, created by `WasmNode.join_room()`. Publishes on the room's topics and
+/// receives only the room's traffic, as `roomMessage` events with `room_id`, `channel`
+/// ("updates", "presence" or "ephemeral"), `peer_id` and `data`.
+#[wasm_bindgen]
+pub struct WasmRoom {
+    room_id: String,
+    cmd_sender: mpsc::UnboundedSender<Command>,
+    rooms: RoomSubscriptions,
+    docstore_config: DocstoreGossipsubConfig,
+    read_only: bool,
+    /// Whether this node created the (restricted) room and may revoke its tokens.
+    is_creator: bool,
+    /// What the guest link we joined through grants; read-only guests can't publish.
+    link_permission: Option<Permission>,
+    outbox: Outbox,
+    /// The worker running the node, see `WasmNode.spawn_in_worker()`.
+    remote: Option<WorkerLink>,
+}
+
+#[wasm_bindgen]
+impl WasmRoom {
+    #[wasm_bindgen(getter)]
+    pub fn room_id(&self) -> String {
+        self.room_id.clone()
+    }
+
+    /// Publish an update to the room. Resolves with `{ msg_id, sent_to }`.
+    #[wasm_bindgen]
+    pub async fn publish(&self, data: String) -> Result<JsValue, JsValue> {
+        self.ensure_writable()?;
+        self.ensure_write_permission()?;
+        self.docstore_config.check_update_size(data.len()).map_err(|e| error_to_js(&e))?;
+        if let Some(remote) = &self.remote {
+            self.ensure_joined()?;
+            return remote.call(Target::Room(&self.room_id), "publish", &[data.into()]).await;
+        }
+        let (reply, rx) = futures::channel::oneshot::channel();
+        self.send(RoomChannel::Updates, data, Some(reply))?;
+        let published = rx.await.map_err(|_| JsValue::from_str("node stopped"))?.map_err(|e| error_to_js(&e))?;
+        let obj = Object::new();
+        Reflect::set(&obj, &"msg_id".into(), &published.msg_id.to_string().into())?;
+        let sent_to: Vec<String> = published.sent_to.iter().map(|p| p.to_string()).collect();
+        Reflect::set(&obj, &"sent_to".into(), &string_array(&sent_to).into())?;
+        Ok(obj.into())
+    }
+
+    /// Announce presence (who is here, what they are looking at) to the room. While the
+    /// node is suspended only the latest announcement is kept, and sent on resume.
+    #[wasm_bindgen]
+    pub fn presence(&self, data: String) -> Result<(), JsValue> {
+        self.ensure_writable()?;
+        self.send(RoomChannel::Presence, data, None)
+    }
+
+    /// Publish a low-latency ephemeral message (cursor, typing indicator) to the room.
+    /// Dropped while the node is suspended.
+    #[wasm_bindgen]
+    pub fn publish_ephemeral(&self, data: String) -> Result<(), JsValue> {
+        self.ensure_writable()?;
+        self.ensure_write_permission()?;
+        if is_suspended(&self.outbox) {
+            return Ok(());
+        }
+        self.send(RoomChannel::Ephemeral, data, None)
+    }
+
+    /// Receive the room's events matching `filter`, as `WasmNode.subscribe_events()` does
+    /// for the node's. Ends when the room is left.
+    #[wasm_bindgen]
+    pub fn subscribe_events(&self, filter: JsValue) -> Result<EventSubscription, JsValue> {
+        let (filter, capacity) = event_filter_from_js(&filter)?;
+        let rooms = self.rooms.lock().expect("rooms lock");
+        let subscriptions = rooms.get(&self.room_id).ok_or_else(|| self.not_in_room())?;
+        Ok(EventSubscription { inner: subscriptions.subscribe(filter, capacity) })
+    }
+
+    /// Use `token` (from the room creator's `issue_capability()`) in this restricted room.
+    /// It is attached to our presence announcements from then on.
+    #[wasm_bindgen]
+    pub fn import_capability(&self, token: Vec<u8>) -> Result<(), JsValue> {
+        self.ensure_joined()?;
+        if let Some(remote) = &self.remote {
+            Capability::decode(&token).map_err(|e| error_to_js(&e.into()))?;
+            let token = js_sys::Uint8Array::from(token.as_slice());
+            return remote.post(Target::Room(&self.room_id), "import_capability", &[token.into()]);
+        }
+        let token = Capability::decode(&token).map_err(|e| error_to_js(&e.into()))?;
+        self.cmd_sender
+            .unbounded_send(Command::SetRoomToken { room_id: self.room_id.clone(), token })
+            .map_err(|e| JsValue::from_str(&format!("Failed to send command: {}", e)))
+    }
+
+    /// Revoke tokens (as returned by `issue_capability()`) or guest links (from
+    /// `create_guest_link()`) of a restricted room this node created. The signed
+    /// revocation list goes out on the room's presence topic.
+    #[wasm_bindgen]
+    pub fn revoke(&self, tokens: js_sys::Array) -> Result<(), JsValue> {
+        self.ensure_joined()?;
+        if !self.is_creator {
+            return Err(error_to_js(&crate::Error::NotRoomCreator { room_id: self.room_id.clone() }));
+        }
+        let ids = tokens
+            .iter()
+            .map(|token| {
+                if let Some(link) = token.as_string() {
+                    // Expired links need no revoking, but may be revoked all the same
+                    return GuestLink::parse(&link, 0).map(|link| link.token().id()).map_err(|e| error_to_js(&e.into()));
+                }
+                let bytes = token
+                    .dyn_ref::<js_sys::Uint8Array>()
+                    .ok_or_else(|| JsValue::from_str("tokens must be Uint8Arrays or guest links"))?
+                    .to_vec();
+                Capability::decode(&bytes).map(|token| token.id()).map_err(|e| error_to_js(&e.into()))
+            })
+            .collect::<Result<Vec<_>, JsValue>>()?;
+        if let Some(remote) = &self.remote {
+            return remote.post(Target::Room(&self.room_id), "revoke", &[tokens.into()]);
+        }
+        self.cmd_sender
+            .unbounded_send(Command::RevokeCapabilities { room_id: self.room_id.clone(), ids })
+            .map_err(|e| JsValue::from_str(&format!("Failed to send command: {}", e)))
+    }
+
+    /// Everyone who ever joined the room, as `WasmNode.room_members()` lists them.
+    #[wasm_bindgen]
+    pub async fn members(&self, options: JsValue) -> Result<JsValue, JsValue> {
+        let parsed = members_options(&options)?;
+        if let Some(remote) = &self.remote {
+            let options = with_peer_id_string(&options, "peerId", parsed.peer_id)?;
+            return remote.call(Target::Room(&self.room_id), "members", &[options]).await;
+        }
+        room_members(&self.cmd_sender, self.room_id.clone(), &parsed).await
+    }
+
+    /// Leave the room, as `WasmNode.leave_room()` does.
+    #[wasm_bindgen]
+    pub fn leave(&self) -> Result<bool, JsValue> {
+        leave_room(&self.rooms, &self.cmd_sender, self.remote.as_ref(), self.room_id.clone())
+    }
+}
+
+impl WasmRoom {
+    fn send(
+        &self,
+        channel: RoomChannel,
+        data: String,
+        reply: Option<futures::channel::oneshot::Sender<Result<Published, crate::Error>>>,
+    ) -> Result<(), JsValue> {
+        self.ensure_joined()?;
+        if let Some(remote) = &self.remote {
+            let method = match channel {
+                RoomChannel::Updates => "publish",
+                RoomChannel::Presence => "presence",
+                RoomChannel::Ephemeral => "publish_ephemeral",
+            };
+            return remote.post(Target::Room(&self.room_id), method, &[data.into()]);
+        }
+        let cmd = Command::PublishRoom { room_id: self.room_id.clone(), channel, data: data.into_bytes(), reply };
+        if channel == RoomChannel::Updates {
+            return send_publish(&self.cmd_sender, &self.outbox, cmd);
+        }
+        // Presence goes straight through: the event loop keeps the latest for resume
+        self.cmd_sender
+            .unbounded_send(cmd)
+            .map_err(|e| JsValue::from_str(&format!("Failed to send command: {}", e)))
+    }
+
+    fn ensure_joined(&self) -> Result<(), JsValue> {
+        if !self.rooms.lock().expect("rooms lock").contains_key(&self.room_id) {
+            return Err(self.not_in_room());
+        }
+        Ok(())
+    }
+
+    fn not_in_room(&self) -> JsValue {
+        error_to_js(&crate::Error::NotInRoom { room_id: self.room_id.clone() })
+    }
+
+    fn ensure_writable(&self) -> Result<(), JsValue> {
+        if self.read_only {
+            return Err(error_to_js(&crate::Error::ReadOnly));
+        }
+        Ok(())
+    }
+
+    /// Others would drop what a read-only guest publishes; fail here instead.
+    fn ensure_write_permission(&self) -> Result<(), JsValue> {
+        if self.link_permission == Some(Permission::Read) {
+            return Err(error_to_js(&crate::Error::ReadOnlyRoom { room_id: self.room_id.clone() }));
+        }
+        Ok(())
+    }
+}
+
+/// A transaction being put together, created by `WasmNode.begin_transaction()`. Peers
+/// apply its updates all together, reporting them as `docUpdateReceived` events followed
+/// by one `transactionApplied`.
+#[wasm_bindgen]
+pub struct WasmTransaction {
+    tx: Transaction,
+    cmd_sender: mpsc::UnboundedSender<Command>,
+    docstore_config: DocstoreGossipsubConfig,
+    read_only: bool,
+    outbox: Outbox,
+    /// The worker running the node, see `WasmNode.spawn_in_worker()`.
+    remote: Option<WorkerLink>,
+}
+
+#[wasm_bindgen]
+impl WasmTransaction {
+    /// Decimal string, as in `transactionApplied` events.
+    #[wasm_bindgen(getter)]
+    pub fn id(&self) -> String {
+        self.tx.id.to_string()
+    }
+
+    /// Add an update to any document. Rejects with `UpdateTooLarge` right away.
+    #[wasm_bindgen]
+    pub fn add_update(&mut self, doc_id: String, data: String) -> Result<(), JsValue> {
+        self.docstore_config.check_update_size(data.len()).map_err(|e| error_to_js(&e))?;
+        self.tx.add_update(DocUpdate::new(doc_id, data.into_bytes()));
+        Ok(())
+    }
+
+    /// Publish the updates added so far, in as few messages as fit (usually one), and
+    /// start over empty. Queued like other publishes while the node is suspended.
+    #[wasm_bindgen]
+    pub fn commit(&mut self) -> Result<(), JsValue> {
+        if self.read_only {
+            return Err(error_to_js(&crate::Error::ReadOnly));
+        }
+        if self.tx.is_empty() {
+            return Err(error_to_js(&crate::Error::EmptyTransaction));
+        }
+        let tx = Transaction { id: self.tx.id, updates: std::mem::take(&mut self.tx.updates) };
+        if let Some(remote) = &self.remote {
+            return remote.post(Target::Transaction, "commit", &[crate::wasm_worker::encode(&tx)?.into()]);
+        }
+        send_publish(&self.cmd_sender, &self.outbox, Command::CommitTransaction(tx))
+    }
 }
 
-#[derive(Debug, Clone)]
-enum Event {
-    Connected { peer_id: String },
-    Disconnected { peer_id: String },
-    MessageReceived { peer_id: String, data: String },
-    MessagePublished { msg_id: String },
-    PeerDiscovery { peer_id: String, addrs: Vec<String> },
-    DirectMessageReceived { peer_id: String, data: String },
-    DirectMessageSent { peer_id: String },
-    ListenStarted { addr: String },
-    RelayReservationCreated { addr: String },
-    RelayConnectionEstablished { peer_id: String },
-    WebRTCConnectionEstablished { peer_id: String },
-    Error { msg: String },
+impl WasmTransaction {
+    /// Commit `tx`, put together by a main-thread node, from the worker running its swarm.
+    pub(crate) fn commit_from(&mut self, tx: Transaction) -> Result<(), JsValue> {
+        self.tx = tx;
+        self.commit()
+    }
+}
+
+fn leave_room(
+    rooms: &RoomSubscriptions,
+    cmd_sender: &mpsc::UnboundedSender<Command>,
+    remote: Option<&WorkerLink>,
+    room_id: String,
+) -> Result<bool, JsValue> {
+    let Some(subscriptions) = rooms.lock().expect("rooms lock").remove(&room_id) else {
+        return Ok(false);
+    };
+    subscriptions.close();
+    if let Some(remote) = remote {
+        remote.post(Target::Node, "leave_room", &[room_id.into()])?;
+        return Ok(true);
+    }
+    cmd_sender
+        .unbounded_send(Command::LeaveRoom { room_id })
+        .map_err(|e| JsValue::from_str(&format!("Failed to send command: {}", e)))?;
+    Ok(true)
 }
 
 // Relay information with connection tracking
@@ -97,6 +2151,10 @@ struct RelayInfo {
     full_addr: String,
     connected_at: f64, // timestamp in milliseconds
     supports_relay: bool,
+    /// Smoothed ping round-trip time, for bootstrap relays.
+    rtt_ms: Option<f64>,
+    /// One of the relays ephemeral traffic goes through, see [`RelayRanking`].
+    preferred: bool,
 }
 
 // Shared state for network status
@@ -107,6 +2165,493 @@ struct SharedState {
     discovered_peers: HashMap<String, Vec<String>>,
     subscriptions: Vec<String>,
     relays: Vec<RelayInfo>,
+    pins: Vec<String>,
+    peer_infos: PeerInfoCache,
+    dht_summary: DhtSummary,
+    connections: ConnectionStates,
+    readiness: NodeReadiness,
+    best_relay: Option<PeerId>,
+    external_addrs: ExternalAddrs,
+}
+
+/// Sets `buckets`, `peers` and `pending` on `obj`.
+fn set_dht_summary(obj: &Object, summary: &DhtSummary) -> Result<(), JsValue> {
+    Reflect::set(obj, &"buckets".into(), &JsValue::from_f64(summary.buckets as f64))?;
+    Reflect::set(obj, &"peers".into(), &JsValue::from_f64(summary.peers as f64))?;
+    Reflect::set(obj, &"pending".into(), &JsValue::from_f64(summary.pending as f64))?;
+    Ok(())
+}
+
+fn string_array<S: AsRef<str>>(items: &[S]) -> js_sys::Array {
+    let arr = js_sys::Array::new();
+    for item in items {
+        arr.push(&JsValue::from_str(item.as_ref()));
+    }
+    arr
+}
+
+/// A copy of `options` with `field` set to `peer_id` as a string. A `PeerIdWrapper`
+/// can't be posted to a worker; the string can.
+fn with_peer_id_string(options: &JsValue, field: &str, peer_id: Option<PeerId>) -> Result<JsValue, JsValue> {
+    let Some(peer_id) = peer_id else {
+        return Ok(options.clone());
+    };
+    let copy = Object::assign(&Object::new(), options.unchecked_ref());
+    Reflect::set(&copy, &field.into(), &peer_id.to_string().into())?;
+    Ok(copy.into())
+}
+
+/// The `bootstrap` option: one multiaddr or an array of them.
+fn bootstrap_arg(opts: &JsValue) -> Result<Vec<String>, JsValue> {
+    let bootstrap = Reflect::get(opts, &"bootstrap".into())?;
+    if bootstrap.is_undefined() || bootstrap.is_null() {
+        Ok(Vec::new())
+    } else if js_sys::Array::is_array(&bootstrap) {
+        js_sys::Array::from(&bootstrap).iter().map(|a| text_arg(&a, "bootstrap")).collect()
+    } else {
+        Ok(vec![text_arg(&bootstrap, "bootstrap")?])
+    }
+}
+
+fn bootstrap_multiaddrs(bootstrap: &[String]) -> Result<Vec<Multiaddr>, JsValue> {
+    bootstrap
+        .iter()
+        .map(|addr| crate::node::addrs::validate_browser_addr(addr).into_result().map_err(|e| invalid_argument("bootstrap", e)))
+        .collect()
+}
+
+/// `{ numResults?: number, timeoutMs?: number, dial?: boolean }`, see [`FindPeerOptions`].
+fn find_peer_options(opts: &JsValue) -> Result<FindPeerOptions, JsValue> {
+    let mut out = FindPeerOptions::default();
+    if opts.is_undefined() || opts.is_null() {
+        return Ok(out);
+    }
+    if let Some(n) = Reflect::get(opts, &"numResults".into())?.as_f64() {
+        out.num_results = n.max(1.0) as usize;
+    }
+    if let Some(ms) = Reflect::get(opts, &"timeoutMs".into())?.as_f64() {
+        out.timeout = std::time::Duration::from_millis(ms.max(0.0) as u64);
+    }
+    if let Some(dial) = Reflect::get(opts, &"dial".into())?.as_bool() {
+        out.dial = dial;
+    }
+    Ok(out)
+}
+
+/// `{ retries?: number, backoffMs?: number, timeoutMs?: number, requirePeerId?: boolean }`,
+/// see [`DialOptions`].
+fn dial_options(opts: &JsValue) -> Result<DialOptions, JsValue> {
+    let mut out = DialOptions::default();
+    if opts.is_undefined() || opts.is_null() {
+        return Ok(out);
+    }
+    if let Some(n) = Reflect::get(opts, &"retries".into())?.as_f64() {
+        out.retries = n.max(0.0) as u32;
+    }
+    if let Some(ms) = Reflect::get(opts, &"backoffMs".into())?.as_f64() {
+        out.backoff = std::time::Duration::from_millis(ms.max(0.0) as u64);
+    }
+    if let Some(ms) = Reflect::get(opts, &"timeoutMs".into())?.as_f64() {
+        out.timeout = Some(std::time::Duration::from_millis(ms.max(0.0) as u64));
+    }
+    if let Some(require) = Reflect::get(opts, &"requirePeerId".into())?.as_bool() {
+        out.require_peer_id = require;
+    }
+    Ok(out)
+}
+
+/// `{ peer_id, addrs: string[], dialable: string[] }`
+fn found_peer_to_js(peer: &FoundPeer) -> Result<JsValue, JsValue> {
+    let obj = Object::new();
+    Reflect::set(&obj, &"peer_id".into(), &peer.peer_id.to_string().into())?;
+    let addrs: Vec<String> = peer.addrs.iter().map(ToString::to_string).collect();
+    Reflect::set(&obj, &"addrs".into(), &string_array(&addrs).into())?;
+    let dialable: Vec<String> = peer.dialable.iter().map(ToString::to_string).collect();
+    Reflect::set(&obj, &"dialable".into(), &string_array(&dialable).into())?;
+    Ok(obj.into())
+}
+
+/// `{ addr, valid, dialable, protocols, peer_id, transport, errors }`, see
+/// [`crate::node::addrs::AddrReport`]. `addr` and `peer_id` are null when absent.
+fn addr_report_to_js(report: &crate::node::addrs::AddrReport) -> Result<JsValue, JsValue> {
+    let obj = Object::new();
+    let addr = report.addr.as_ref().map_or(JsValue::NULL, |a| a.to_string().into());
+    Reflect::set(&obj, &"addr".into(), &addr)?;
+    Reflect::set(&obj, &"valid".into(), &report.addr.is_some().into())?;
+    Reflect::set(&obj, &"dialable".into(), &report.is_dialable().into())?;
+    Reflect::set(&obj, &"protocols".into(), &string_array(&report.protocols).into())?;
+    let peer_id = report.peer_id.map_or(JsValue::NULL, |p| p.to_string().into());
+    Reflect::set(&obj, &"peer_id".into(), &peer_id)?;
+    Reflect::set(&obj, &"transport".into(), &report.transport.into())?;
+    Reflect::set(&obj, &"errors".into(), &string_array(&report.errors).into())?;
+    Ok(obj.into())
+}
+
+/// `{ peerId?: string, fromVersion?: number, fromTime?: number, untilMs?: number,
+/// pageSize?: number, cursor?: number }`, see [`HistoryOptions`].
+fn history_options(opts: &JsValue) -> Result<HistoryOptions, JsValue> {
+    let mut out = HistoryOptions::default();
+    if opts.is_undefined() || opts.is_null() {
+        return Ok(out);
+    }
+    let peer_id = Reflect::get(opts, &"peerId".into())?;
+    if !peer_id.is_undefined() && !peer_id.is_null() {
+        out.peer_id = Some(peer_id_arg(&peer_id, "peerId")?);
+    }
+    if let Some(v) = Reflect::get(opts, &"fromVersion".into())?.as_f64() {
+        out.from = HistoryFrom::Version(v.max(0.0) as u64);
+    }
+    if let Some(ms) = Reflect::get(opts, &"fromTime".into())?.as_f64() {
+        out.from = HistoryFrom::Time(ms.max(0.0) as u64);
+    }
+    if let Some(ms) = Reflect::get(opts, &"untilMs".into())?.as_f64() {
+        out.until_ms = Some(ms.max(0.0) as u64);
+    }
+    if let Some(n) = Reflect::get(opts, &"pageSize".into())?.as_f64() {
+        // Out-of-range sizes are left for validation to reject
+        out.page_size = n.clamp(0.0, u32::MAX as f64) as u32;
+    }
+    if let Some(cursor) = Reflect::get(opts, &"cursor".into())?.as_f64() {
+        out.cursor = Some(cursor.max(0.0) as u64);
+    }
+    Ok(out)
+}
+
+/// `document_at` options: `{ version: number }` or `{ time: number }`.
+fn history_at(opts: &JsValue) -> Result<HistoryAt, JsValue> {
+    let get = |field: &str| -> Result<Option<u64>, JsValue> {
+        if opts.is_undefined() || opts.is_null() {
+            return Ok(None);
+        }
+        Ok(Reflect::get(opts, &field.into())?.as_f64().map(|v| v.max(0.0) as u64))
+    };
+    match (get("version")?, get("time")?) {
+        (Some(version), None) => Ok(HistoryAt::Version(version)),
+        (None, Some(ms)) => Ok(HistoryAt::Time(ms)),
+        _ => Err(invalid_argument("options", "give exactly one of version and time")),
+    }
+}
+
+/// A validator calling back into JavaScript, see `WasmNode.register_schema()`.
+struct JsSchema(js_sys::Function);
+
+impl SchemaValidator for JsSchema {
+    fn validate(&self, doc_id: &str, payload: &[u8]) -> Result<(), String> {
+        let verdict = self.0.call2(&JsValue::NULL, &doc_id.into(), &js_sys::Uint8Array::from(payload).into());
+        match verdict {
+            Ok(value) if value.is_undefined() || value.as_bool() == Some(true) => Ok(()),
+            Ok(value) => Err(value.as_string().unwrap_or_else(|| "refused by the validator".to_string())),
+            Err(e) => Err(e.as_string().unwrap_or_else(|| format!("validator threw {:?}", e))),
+        }
+    }
+}
+
+/// `{ peerId, state: "content" | "deleted" | "missing", version?, bytes?, updatedAtMs?,
+/// deletedAtMs? }`; the times are null when the update was compacted away.
+fn document_state_to_js(peer_id: &PeerId, state: &DocumentState) -> Result<JsValue, JsValue> {
+    let obj = Object::new();
+    Reflect::set(&obj, &"peerId".into(), &peer_id.to_string().into())?;
+    let time = |ms: &Option<u64>| ms.map_or(JsValue::NULL, |ms| (ms as f64).into());
+    match state {
+        DocumentState::Content { version, bytes, updated_at_ms } => {
+            Reflect::set(&obj, &"state".into(), &"content".into())?;
+            Reflect::set(&obj, &"version".into(), &(*version as f64).into())?;
+            Reflect::set(&obj, &"bytes".into(), &js_sys::Uint8Array::from(bytes.as_slice()).into())?;
+            Reflect::set(&obj, &"updatedAtMs".into(), &time(updated_at_ms))?;
+        }
+        DocumentState::Deleted { version, deleted_at_ms } => {
+            Reflect::set(&obj, &"state".into(), &"deleted".into())?;
+            Reflect::set(&obj, &"version".into(), &(*version as f64).into())?;
+            Reflect::set(&obj, &"deletedAtMs".into(), &time(deleted_at_ms))?;
+        }
+        DocumentState::Missing => {
+            Reflect::set(&obj, &"state".into(), &"missing".into())?;
+        }
+    }
+    Ok(obj.into())
+}
+
+/// `{ peerId, updates: [{ version, payload: Uint8Array, appliedAtMs }], nextCursor, truncated }`
+fn history_page_to_js(peer_id: &PeerId, page: &HistoryPage) -> Result<JsValue, JsValue> {
+    let obj = Object::new();
+    Reflect::set(&obj, &"peerId".into(), &peer_id.to_string().into())?;
+    let updates = js_sys::Array::new();
+    for update in &page.updates {
+        let u = Object::new();
+        Reflect::set(&u, &"version".into(), &(update.version as f64).into())?;
+        Reflect::set(&u, &"payload".into(), &js_sys::Uint8Array::from(update.payload.as_slice()).into())?;
+        Reflect::set(&u, &"appliedAtMs".into(), &(update.applied_at_ms as f64).into())?;
+        updates.push(&u);
+    }
+    Reflect::set(&obj, &"updates".into(), &updates.into())?;
+    let next = page.next_cursor.map_or(JsValue::NULL, |c| (c as f64).into());
+    Reflect::set(&obj, &"nextCursor".into(), &next)?;
+    Reflect::set(&obj, &"truncated".into(), &page.truncated.into())?;
+    Ok(obj.into())
+}
+
+/// Parse `room_members` options: `{ peerId?: string, after?: string, limit?: number }`.
+fn members_options(opts: &JsValue) -> Result<MembersOptions, JsValue> {
+    let mut out = MembersOptions::default();
+    if opts.is_undefined() || opts.is_null() {
+        return Ok(out);
+    }
+    let peer_id = Reflect::get(opts, &"peerId".into())?;
+    if !peer_id.is_undefined() && !peer_id.is_null() {
+        out.peer_id = Some(peer_id_arg(&peer_id, "peerId")?);
+    }
+    out.after = Reflect::get(opts, &"after".into())?.as_string();
+    if let Some(n) = Reflect::get(opts, &"limit".into())?.as_f64() {
+        out.limit = n.clamp(0.0, u32::MAX as f64) as u32;
+    }
+    Ok(out)
+}
+
+fn member_to_js(member: &Member) -> Result<JsValue, JsValue> {
+    let obj = Object::new();
+    Reflect::set(&obj, &"peerId".into(), &member.peer_id.as_str().into())?;
+    Reflect::set(&obj, &"firstJoinedAtMs".into(), &(member.first_joined_at_ms as f64).into())?;
+    Reflect::set(&obj, &"joinedAtMs".into(), &(member.joined_at_ms as f64).into())?;
+    Reflect::set(&obj, &"lastSeenMs".into(), &(member.last_seen_ms as f64).into())?;
+    let left = member.left_at_ms.map_or(JsValue::NULL, |ms| (ms as f64).into());
+    Reflect::set(&obj, &"leftAtMs".into(), &left)?;
+    Ok(obj.into())
+}
+
+/// `{ peerId, members: [{ peerId, firstJoinedAtMs, joinedAtMs, lastSeenMs, leftAtMs }], next }`
+fn members_page_to_js(peer_id: &PeerId, page: &MembersPage) -> Result<JsValue, JsValue> {
+    let obj = Object::new();
+    Reflect::set(&obj, &"peerId".into(), &peer_id.to_string().into())?;
+    let members = js_sys::Array::new();
+    for member in &page.members {
+        members.push(&member_to_js(member)?);
+    }
+    Reflect::set(&obj, &"members".into(), &members.into())?;
+    let next = page.next.as_deref().map_or(JsValue::NULL, JsValue::from_str);
+    Reflect::set(&obj, &"next".into(), &next)?;
+    Ok(obj.into())
+}
+
+/// Ask a FullNode for a page of a room's members, for `WasmNode.room_members()` and
+/// `WasmRoom.members()`.
+async fn room_members(
+    cmd_sender: &mpsc::UnboundedSender<Command>,
+    room_id: String,
+    options: &MembersOptions,
+) -> Result<JsValue, JsValue> {
+    let (reply, rx) = futures::channel::oneshot::channel();
+    cmd_sender
+        .unbounded_send(Command::RoomMembers { peer_id: options.peer_id, request: options.request(room_id), reply })
+        .map_err(|e| JsValue::from_str(&format!("Failed to send command: {}", e)))?;
+    let (peer_id, page) = rx.await.map_err(|_| JsValue::from_str("node stopped"))?.map_err(|e| error_to_js(&e))?;
+    members_page_to_js(&peer_id, &page)
+}
+
+fn quality_to_js(quality: &ConnectionQuality) -> Result<JsValue, JsValue> {
+    let obj = Object::new();
+    Reflect::set(&obj, &"score".into(), &JsValue::from_f64(quality.score as f64))?;
+    Reflect::set(&obj, &"bucket".into(), &quality.bucket.as_str().into())?;
+    Reflect::set(&obj, &"rtt_ms".into(), &quality.smoothed_rtt_ms.map_or(JsValue::NULL, JsValue::from_f64))?;
+    Reflect::set(&obj, &"loss_rate".into(), &JsValue::from_f64(quality.loss_rate))?;
+    Reflect::set(&obj, &"failure_rate".into(), &JsValue::from_f64(quality.failure_rate))?;
+    Ok(obj.into())
+}
+
+fn profile_to_js(profile: &SignedProfile) -> Result<JsValue, JsValue> {
+    let obj = Object::new();
+    Reflect::set(&obj, &"displayName".into(), &profile.profile.display_name.as_str().into())?;
+    let avatar = profile.profile.avatar_hash.map(|hash| hash.iter().map(|b| format!("{b:02x}")).collect::<String>());
+    Reflect::set(&obj, &"avatarHash".into(), &avatar.map_or(JsValue::NULL, JsValue::from))?;
+    Reflect::set(&obj, &"updatedAtMs".into(), &(profile.updated_at_ms as f64).into())?;
+    Ok(obj.into())
+}
+
+fn head_pointer_to_js(pointer: &HeadPointer) -> Result<JsValue, JsValue> {
+    let obj = Object::new();
+    Reflect::set(&obj, &"docId".into(), &pointer.doc_id.as_str().into())?;
+    Reflect::set(&obj, &"version".into(), &(pointer.version as f64).into())?;
+    let hash: String = pointer.content_hash.iter().map(|b| format!("{b:02x}")).collect();
+    Reflect::set(&obj, &"contentHash".into(), &hash.into())?;
+    Reflect::set(&obj, &"seq".into(), &(pointer.seq as f64).into())?;
+    Ok(obj.into())
+}
+
+/// Options accepted by the `WasmNode` constructor as an optional second argument.
+#[derive(Default)]
+struct WasmNodeOptions {
+    /// `identitySeed`: 32-byte Uint8Array for a deterministic (insecure) identity.
+    identity_seed: Option<Vec<u8>>,
+    /// `identityKey`: protobuf-encoded keypair of any supported type (see `generate_keypair`).
+    identity_key: Option<Vec<u8>>,
+    /// `idleTimeoutMs`: close connections idle for this long (defaults to the client role default).
+    idle_timeout: Option<std::time::Duration>,
+    /// `role`: "client" (default) or "observer" for a node that never publishes.
+    role: Option<NodeRole>,
+    /// `eventHistory`: `{ entries?: number, bytes?: number }` caps for `recent_events`.
+    event_history: Option<(Option<usize>, Option<usize>)>,
+    /// `logLevel`: "off", "error", "info" (default), "debug" or "trace".
+    log_level: Option<LogLevel>,
+    /// `agentVersion`: identify agent string, so apps can be told apart on the network.
+    agent_version: Option<String>,
+    /// `dht`: false builds the node without Kademlia (gossip only).
+    dht: Option<bool>,
+    /// `topicNamespace`: publish and subscribe under `<namespace>/v1/...` instead of `docstore`.
+    topic_namespace: Option<String>,
+    /// `pingFailures`: `{ unresponsiveAfter?: number, disconnectAfter?: number }`, see [`PingPolicy`].
+    ping_failures: Option<(Option<u32>, Option<u32>)>,
+    /// `dialConcurrency`: how many of a peer's addresses are dialed at once.
+    dial_concurrency: Option<std::num::NonZeroU8>,
+    /// `announcers`: peer ids whose signed announcements are accepted.
+    announcers: Vec<PeerId>,
+    /// `snapshotPublishers`: peer ids trusted with snapshots of any document, usually
+    /// the FullNodes the app relies on; otherwise only documents' own authors are.
+    snapshot_publishers: Vec<PeerId>,
+    /// `discoverable`: let relays list us to their other browsers, so they can connect
+    /// to us directly (default false).
+    discoverable: bool,
+    /// `seedDocuments`: `[{ docId, bytes: Uint8Array, version?: number, publishIfAbsent?:
+    /// boolean, pinned?: boolean }]`, delivered before any network activity, see [`Seeds`].
+    seed_documents: Vec<SeedDocument>,
+    /// `dedupCacheKey`: keep the updates delivered in IndexedDB under this key, so they
+    /// are not delivered again after a reload (see [`DedupCache`]). Off by default.
+    dedup_cache_key: Option<String>,
+}
+
+impl WasmNodeOptions {
+    fn from_js(opts: &JsValue) -> Result<Self, JsValue> {
+        let mut out = Self::default();
+        if opts.is_undefined() || opts.is_null() {
+            return Ok(out);
+        }
+        out.identity_seed = Self::bytes(opts, "identitySeed")?;
+        out.identity_key = Self::bytes(opts, "identityKey")?;
+        let idle = Reflect::get(opts, &"idleTimeoutMs".into())?;
+        if let Some(ms) = idle.as_f64() {
+            out.idle_timeout = Some(std::time::Duration::from_millis(ms.max(0.0) as u64));
+        }
+        if let Some(role) = Reflect::get(opts, &"role".into())?.as_string() {
+            let role = role.parse::<NodeRole>().map_err(|e| JsValue::from_str(&e))?;
+            if !matches!(role, NodeRole::Client | NodeRole::Observer) {
+                return Err(JsValue::from_str("browser nodes support only the client and observer roles"));
+            }
+            out.role = Some(role);
+        }
+        let history = Reflect::get(opts, &"eventHistory".into())?;
+        if history.is_object() {
+            let cap = |name: &str| -> Result<Option<usize>, JsValue> {
+                Ok(Reflect::get(&history, &name.into())?.as_f64().map(|n| n.max(0.0) as usize))
+            };
+            out.event_history = Some((cap("entries")?, cap("bytes")?));
+        }
+        if let Some(level) = Reflect::get(opts, &"logLevel".into())?.as_string() {
+            out.log_level = Some(level.parse().map_err(|e: String| JsValue::from_str(&e))?);
+        }
+        out.agent_version = Reflect::get(opts, &"agentVersion".into())?.as_string();
+        out.dht = Reflect::get(opts, &"dht".into())?.as_bool();
+        out.topic_namespace = Reflect::get(opts, &"topicNamespace".into())?.as_string();
+        let ping_failures = Reflect::get(opts, &"pingFailures".into())?;
+        if ping_failures.is_object() {
+            let count = |name: &str| -> Result<Option<u32>, JsValue> {
+                Ok(Reflect::get(&ping_failures, &name.into())?.as_f64().map(|n| n.max(1.0) as u32))
+            };
+            out.ping_failures = Some((count("unresponsiveAfter")?, count("disconnectAfter")?));
+        }
+        if let Some(n) = Reflect::get(opts, &"dialConcurrency".into())?.as_f64() {
+            out.dial_concurrency = std::num::NonZeroU8::new(n.clamp(1.0, u8::MAX as f64) as u8);
+        }
+        out.discoverable = Reflect::get(opts, &"discoverable".into())?.as_bool().unwrap_or(false);
+        let announcers = Reflect::get(opts, &"announcers".into())?;
+        if !announcers.is_undefined() && !announcers.is_null() {
+            for peer in js_sys::Array::from(&announcers).iter() {
+                out.announcers.push(peer_id_arg(&peer, "announcers")?);
+            }
+        }
+        let publishers = Reflect::get(opts, &"snapshotPublishers".into())?;
+        if !publishers.is_undefined() && !publishers.is_null() {
+            for peer in js_sys::Array::from(&publishers).iter() {
+                out.snapshot_publishers.push(peer_id_arg(&peer, "snapshotPublishers")?);
+            }
+        }
+        let seeds = Reflect::get(opts, &"seedDocuments".into())?;
+        if !seeds.is_undefined() && !seeds.is_null() {
+            for seed in js_sys::Array::from(&seeds).iter() {
+                let doc_id = Reflect::get(&seed, &"docId".into())?
+                    .as_string()
+                    .ok_or_else(|| JsValue::from_str("seedDocuments entries need a docId string"))?;
+                let bytes = Self::bytes(&seed, "bytes")?.ok_or_else(|| JsValue::from_str("seedDocuments entries need bytes"))?;
+                out.seed_documents.push(SeedDocument {
+                    version: Reflect::get(&seed, &"version".into())?.as_f64().map_or(0, |v| v.max(0.0) as u64),
+                    publish_if_absent: Reflect::get(&seed, &"publishIfAbsent".into())?.as_bool().unwrap_or(false),
+                    pinned: Reflect::get(&seed, &"pinned".into())?.as_bool().unwrap_or(false),
+                    ..SeedDocument::new(doc_id, bytes)
+                });
+            }
+        }
+        out.dedup_cache_key = Reflect::get(opts, &"dedupCacheKey".into())?.as_string();
+        Ok(out)
+    }
+
+    /// The node's identity, deterministic only when a test seed is supplied.
+    fn keypair(&self) -> Result<identity::Keypair, JsValue> {
+        Ok(match (&self.identity_seed, &self.identity_key) {
+            (Some(seed), _) => {
+                tracing::warn!("⚠ Using an INSECURE identity derived from identitySeed; do not use in production");
+                crate::node::keys::insecure_identity_from_seed(seed)
+                    .map_err(|e| JsValue::from_str(&format!("invalid identitySeed: {e}")))?
+            }
+            (None, Some(bytes)) => identity::Keypair::from_protobuf_encoding(bytes)
+                .map_err(|e| JsValue::from_str(&format!("invalid identityKey: {e}")))?,
+            (None, None) => identity::Keypair::generate_ed25519(),
+        })
+    }
+
+    /// Builder for the behaviours and settings these options ask for.
+    fn node_builder(&self) -> NodeBuilder {
+        let mut node_builder = NodeBuilder::new(self.role.unwrap_or(NodeRole::Client));
+        if let Some(timeout) = self.idle_timeout {
+            node_builder = node_builder.with_idle_timeout(timeout);
+        }
+        if let Some((entries, bytes)) = self.event_history {
+            node_builder = node_builder.with_event_history(
+                entries.unwrap_or(crate::node::history::DEFAULT_HISTORY_ENTRIES),
+                bytes.unwrap_or(crate::node::history::DEFAULT_HISTORY_BYTES),
+            );
+        }
+        if let Some(agent) = self.agent_version.clone() {
+            node_builder = node_builder.with_agent_version(agent);
+        }
+        if let Some(dht) = self.dht {
+            node_builder = node_builder.with_dht_enabled(dht);
+        }
+        if let Some(namespace) = self.topic_namespace.clone() {
+            node_builder = node_builder.with_topic_namespace(namespace);
+        }
+        node_builder = node_builder.with_announcers(self.announcers.iter().copied());
+        node_builder = node_builder.with_snapshot_publishers(self.snapshot_publishers.iter().copied());
+        node_builder = node_builder.with_discoverable(self.discoverable);
+        if let Some((unresponsive_after, disconnect_after)) = self.ping_failures {
+            let defaults = node_builder.ping_policy();
+            node_builder = node_builder.with_ping_policy(PingPolicy {
+                unresponsive_after: unresponsive_after.unwrap_or(defaults.unresponsive_after),
+                disconnect_after: disconnect_after.unwrap_or(defaults.disconnect_after),
+            });
+        }
+        if let Some(factor) = self.dial_concurrency {
+            node_builder = node_builder.with_dial_concurrency(DialConcurrency::new(factor));
+        }
+        node_builder
+    }
+
+    fn bytes(opts: &JsValue, name: &str) -> Result<Option<Vec<u8>>, JsValue> {
+        let value = Reflect::get(opts, &name.into())?;
+        if value.is_undefined() || value.is_null() {
+            return Ok(None);
+        }
+        let array = value
+            .dyn_ref::<js_sys::Uint8Array>()
+            .ok_or_else(|| JsValue::from_str(&format!("{name} must be a Uint8Array")))?;
+        Ok(Some(array.to_vec()))
+    }
 }
 
 #[wasm_bindgen]
@@ -115,16 +2660,177 @@ pub struct WasmNode {
     event_receiver: Arc<futures::lock::Mutex<mpsc::UnboundedReceiver<Event>>>,
     peer_id: String,
     shared_state: Arc<futures::lock::Mutex<SharedState>>,
+    docstore_config: DocstoreGossipsubConfig,
+    read_only: bool,
+    dht_enabled: bool,
+    rendezvous_client: bool,
+    traffic: TrafficStats,
+    history: EventHistory<Event>,
+    subscriptions: Subscriptions<Event>,
+    rooms: RoomSubscriptions,
+    /// Signs capability tokens for restricted rooms.
+    identity: identity::Keypair,
+    /// The relays we bootstrapped from, which guest links send their guests to.
+    bootstrap: Vec<Multiaddr>,
+    outbox: Outbox,
+    reputation: PeerReputation,
+    quality: QualityTracker,
+    /// Set for nodes from `spawn_in_worker()`: the worker runs the swarm, and methods
+    /// hand their calls to it.
+    remote: Option<WorkerLink>,
 }
 
 #[wasm_bindgen]
 impl WasmNode {
+    /// `options` is optional:
+    /// `{ identitySeed?: Uint8Array, identityKey?: Uint8Array, idleTimeoutMs?: number, role?: "client" | "observer",
+    /// eventHistory?: { entries?: number, bytes?: number }, logLevel?: string, dht?: boolean,
+    /// pingFailures?: { unresponsiveAfter?: number, disconnectAfter?: number }, dialConcurrency?: number,
+    /// announcers?: string[], snapshotPublishers?: string[], dedupCacheKey?: string }`.
+    /// `dht: false` leaves out Kademlia for gossip-only nodes; `find_peer` and
+    /// `dht_summary` then reject with code `DhtDisabled`. After `unresponsiveAfter` failed
+    /// pings in a row (default 1) a peer is reported as `peerUnresponsive` and stops being
+    /// an explicit gossipsub peer; after `disconnectAfter` (default 2) its connection is
+    /// closed. Announcements signed by one of `announcers` arrive as `announcement` events;
+    /// without any, all of them are rejected. With `dedupCacheKey`, the updates delivered
+    /// are remembered in IndexedDB under that key and not delivered again after a reload;
+    /// use one key per copy of the state the app keeps.
+    ///
+    /// Methods taking a peer id or multiaddr accept a string or a `PeerIdWrapper` /
+    /// `MultiaddrWrapper`. One that does not parse, here or there, is rejected with
+    /// `{ code: "InvalidArgument", message, invalidField }`.
     #[wasm_bindgen(constructor)]
-    pub fn new(server_multiaddr: String) -> Result<WasmNode, JsValue> {
-        // Create local identity
-        let local_key = identity::Keypair::generate_ed25519();
+    pub fn new(server_multiaddr: String, options: JsValue) -> Result<WasmNode, JsValue> {
+        Self::start(vec![server_multiaddr], options, None).map(|(node, _)| node)
+    }
+
+    /// Start a node that races several relays: `opts` takes the constructor options plus
+    /// `bootstrap`, one multiaddr or an array of them. All addresses are dialed at once and retried on
+    /// transient failures; each one that connects becomes a relay and an explicit
+    /// gossipsub peer. Resolves as soon as the first connects. A bootstrap address that
+    /// gives up emits `bootstrapFailed`; only if all of them do is the promise rejected,
+    /// with code `BootstrapFailed`.
+    #[wasm_bindgen]
+    pub async fn with_config(opts: JsValue) -> Result<WasmNode, JsValue> {
+        Self::launch(opts, None).await
+    }
+
+    /// Start a node as `with_config()` does, in a dedicated Web Worker so the swarm stays
+    /// off the main thread. `opts` takes the same options plus `workerUrl`, the URL of a
+    /// module script doing `import init, { worker_main } from "<pkg>/simple_p2p_docstore.js";
+    /// await init(); worker_main();`. The returned node has the same methods and events as
+    /// an in-thread one; its calls are handed to the worker. Two things differ: `stats()`
+    /// and the peer score methods answer from a copy refreshed every second, and a
+    /// failure in the worker after a synchronous method returned (say `SuspendQueueFull`)
+    /// arrives as an `error` event. The worker is terminated when the node is freed.
+    #[wasm_bindgen]
+    pub async fn spawn_in_worker(opts: JsValue) -> Result<WasmNode, JsValue> {
+        let url = Reflect::get(&opts, &"workerUrl".into())?
+            .as_string()
+            .ok_or_else(|| invalid_argument("workerUrl", "workerUrl must be the URL of the worker script"))?;
+        let bootstrap = bootstrap_multiaddrs(&bootstrap_arg(&opts)?)?;
+        if bootstrap.is_empty() {
+            return Err(JsValue::from_str("at least one bootstrap multiaddr is required"));
+        }
+        let options = WasmNodeOptions::from_js(&opts)?;
+        if let Some(level) = options.log_level {
+            crate::wasm_log::set_level(level);
+        }
+        crate::wasm_log::init();
+
+        // Guest links and capabilities are signed here, so both ends need the same key
+        let identity = options.keypair()?;
+        let key = identity.to_protobuf_encoding().map_err(|e| JsValue::from_str(&format!("identity encoding error: {e}")))?;
+        let worker_opts = Object::assign(&Object::new(), opts.unchecked_ref());
+        Reflect::delete_property(&worker_opts, &"workerUrl".into())?;
+        Reflect::delete_property(&worker_opts, &"identitySeed".into())?;
+        Reflect::set(&worker_opts, &"identityKey".into(), &js_sys::Uint8Array::from(key.as_slice()))?;
+
+        let node_builder = options.node_builder();
+        let docstore_config = node_builder.docstore_config();
+        docstore_config.validate().map_err(|e| error_to_js(&e))?;
+        let history: EventHistory<Event> = node_builder.event_history();
+        let subscriptions = Subscriptions::default();
+        let rooms = RoomSubscriptions::default();
+        #[allow(clippy::disallowed_methods)]
+        let (event_sender, event_receiver) = mpsc::unbounded();
+        let sink = std::rc::Rc::new(EventSink {
+            sender: event_sender,
+            history: history.clone(),
+            subscriptions: subscriptions.clone(),
+            rooms: rooms.clone(),
+            tap: None,
+        });
+        let errors = sink.clone();
+        let (remote, peer_id) = WorkerLink::spawn(
+            &url,
+            &worker_opts,
+            move |event: Event| {
+                let _ = sink.unbounded_send(event);
+            },
+            move |msg| {
+                let _ = errors.unbounded_send(Event::Error { msg });
+            },
+        )
+        .await?;
+
+        // Every command goes to the worker instead
+        #[allow(clippy::disallowed_methods)]
+        let (cmd_sender, _) = mpsc::unbounded();
+        Ok(WasmNode {
+            cmd_sender,
+            event_receiver: Arc::new(futures::lock::Mutex::new(event_receiver)),
+            peer_id,
+            shared_state: Arc::default(),
+            docstore_config,
+            read_only: options.role.unwrap_or(NodeRole::Client).is_read_only(),
+            dht_enabled: node_builder.dht_enabled(),
+            // Decided by the worker's swarm; `discover_peers()` asks it
+            rendezvous_client: true,
+            traffic: TrafficStats::default(),
+            history,
+            subscriptions,
+            rooms,
+            identity,
+            bootstrap,
+            outbox: Outbox::default(),
+            reputation: PeerReputation::default(),
+            quality: QualityTracker::default(),
+            remote: Some(remote),
+        })
+    }
+
+    /// Check an address before using it, e.g. one pasted into a form. Returns
+    /// `{ addr, valid, dialable, protocols, peer_id, transport, errors }`: `valid` if it
+    /// parses, `dialable` if a browser node can dial it, and otherwise one message per
+    /// problem in `errors` (no browser transport, webrtc-direct without `/certhash` or
+    /// `/p2p`, `/dnsaddr`, ...). The constructor and `dial_peer` reject with the first one,
+    /// as an `InvalidArgument` error.
+    #[wasm_bindgen]
+    pub fn validate_multiaddr(addr: JsValue) -> Result<JsValue, JsValue> {
+        addr_report_to_js(&crate::node::addrs::validate_browser_addr(&text_arg(&addr, "addr")?))
+    }
+
+    /// The node and a receiver settled once the first bootstrap address connects, or
+    /// all of them failed.
+    #[allow(clippy::type_complexity)]
+    fn start(
+        bootstrap: Vec<String>,
+        options: JsValue,
+        tap: Option<mpsc::UnboundedSender<Event>>,
+    ) -> Result<(WasmNode, futures::channel::oneshot::Receiver<Result<(), crate::Error>>), JsValue> {
+        if bootstrap.is_empty() {
+            return Err(JsValue::from_str("at least one bootstrap multiaddr is required"));
+        }
+        let options = WasmNodeOptions::from_js(&options)?;
+        if let Some(level) = options.log_level {
+            crate::wasm_log::set_level(level);
+        }
+        crate::wasm_log::init();
+
+        let local_key = options.keypair()?;
         let local_peer_id = PeerId::from(local_key.public());
-        log(&format!("local peer id: {}", local_peer_id));
+        tracing::info!("local peer id: {}", local_peer_id);
 
         // Create transport waker for WebRTC transport
         let transport_waker = Arc::new(AtomicWaker::new());
@@ -136,12 +2842,30 @@ impl WasmNode {
                 .map_err(|e| JsValue::from_str(&format!("transport build error: {e:?}")))?;
 
         // Build behaviours using NodeBuilder (for ping, gossipsub, identify, kademlia)
-        let (ping_beh, gossipsub_beh, identify_beh, kademlia_beh) = 
-            NodeBuilder::new(NodeRole::Client).build_behaviours(&local_key);
+        let role = options.role.unwrap_or(NodeRole::Client);
+        let node_builder = options.node_builder();
+        let ping_policy = node_builder.ping_policy();
+        let keep_alive_interval = keeper::ping_interval(node_builder.idle_timeout());
+        let docstore_config = node_builder.docstore_config();
+        docstore_config.validate().map_err(|e| error_to_js(&e))?;
+        let dht_enabled = node_builder.dht_enabled();
+        let reannounce_after = node_builder.reannounce_after();
+        let record_ttl = node_builder.record_ttl();
+        let max_published_records = node_builder.max_published_records();
+        let topic_health_config = node_builder.topic_health();
+        let discoverable = node_builder.discoverable();
+        let history: EventHistory<Event> = node_builder.event_history();
+        let behaviours = node_builder.build_behaviours(&local_key).map_err(|e| error_to_js(&e))?;
+        let mut local_protocols = node_builder.local_protocols();
+        local_protocols.push(DIRECT_MESSAGE_PROTOCOL.to_string());
         
+        // Separate gossipsub instance for cursors/typing indicators
+        let ephemeral_beh =
+            crate::behaviour::docstore::make_ephemeral_gossipsub(&local_key).map_err(|e| error_to_js(&e))?;
+
         // Create request-response behaviour for direct messaging
         let req_resp_beh = request_response::cbor::Behaviour::<DirectMessage, DirectMessage>::new(
-            [(StreamProtocol::new("/docstore/direct-message/1.0.0"), ProtocolSupport::Full)],
+            [(StreamProtocol::new(DIRECT_MESSAGE_PROTOCOL), ProtocolSupport::Full)],
             request_response::Config::default(),
         );
 
@@ -149,11 +2873,21 @@ impl WasmNode {
         let behaviour = MyBehaviour {
             relay: relay_behaviour,
             webrtc: webrtc_behaviour,
-            ping: ping_beh,
-            gossipsub: gossipsub_beh,
-            identify: identify_beh,
-            kademlia: kademlia_beh,
+            ping: behaviours.ping,
+            gossipsub: behaviours.gossipsub,
+            ephemeral: ephemeral_beh,
+            identify: behaviours.identify,
+            kademlia: Toggle::from(dht_enabled.then_some(behaviours.kademlia)),
             request_response: req_resp_beh,
+            history: behaviours.history,
+            fetch: behaviours.fetch,
+            mailbox: behaviours.mailbox,
+            members: behaviours.members,
+            receipts: behaviours.receipts,
+            rendezvous: behaviours.rendezvous,
+            keep_alive: behaviours.keep_alive,
+            peers: behaviours.peers,
+            interest: make_interest_behaviour(false),
         };
 
         // Build swarm manually (not via SwarmBuilder) because we have custom composite transport
@@ -164,104 +2898,972 @@ impl WasmNode {
             libp2p::swarm::Config::with_executor(Box::new(|fut| {
                 wasm_bindgen_futures::spawn_local(fut);
             }))
-            .with_idle_connection_timeout(std::time::Duration::from_secs(300)),
+            .with_idle_connection_timeout(node_builder.idle_timeout())
+            .with_dial_concurrency_factor(node_builder.dial_concurrency().global),
         );
 
         // Subscribe to docstore topic using behaviour helper
-        crate::behaviour::docstore::subscribe(&mut swarm.behaviour_mut().gossipsub)
+        let updates_topic = docstore_config.topics.updates().to_string();
+        let rendezvous_client = swarm.behaviour().rendezvous.is_client();
+        crate::behaviour::docstore::subscribe(&mut swarm.behaviour_mut().gossipsub, &docstore_config.topics)
+            .map_err(|e| JsValue::from_str(&format!("subscribe error: {e}")))?;
+        tracing::info!("✓ Subscribed to topic: {}", updates_topic);
+        crate::behaviour::docstore::subscribe_snapshots(&mut swarm.behaviour_mut().gossipsub, &docstore_config.topics)
+            .map_err(|e| JsValue::from_str(&format!("subscribe error: {e}")))?;
+        crate::behaviour::docstore::subscribe_announcements(&mut swarm.behaviour_mut().gossipsub, &docstore_config.topics)
+            .map_err(|e| JsValue::from_str(&format!("subscribe error: {e}")))?;
+        crate::behaviour::docstore::subscribe_receipts(&mut swarm.behaviour_mut().gossipsub, &docstore_config.topics)
             .map_err(|e| JsValue::from_str(&format!("subscribe error: {e}")))?;
-        log("✓ Subscribed to topic: docstore/v1/updates");
         
         // Initialize shared state
         let shared_state = Arc::new(futures::lock::Mutex::new(SharedState {
-            subscriptions: vec!["docstore/v1/updates".to_string()],
+            subscriptions: vec![updates_topic],
             ..Default::default()
         }));
         let shared_state_clone = shared_state.clone();
 
-        // Dial the server (webrtc-direct or websocket multiaddr)
-        let addr: Multiaddr = server_multiaddr
-            .parse()
-            .map_err(|e| JsValue::from_str(&format!("invalid multiaddr: {e}")))?;
-        
-        // Extract potential relay peer ID from the server address
-        let relay_peer_id_opt = extract_peer_id_from_multiaddr(&addr);
-        if let Some(relay_peer_id) = relay_peer_id_opt {
-            log(&format!("Detected relay peer: {}", relay_peer_id));
-            // Store relay info immediately (will be validated on connection)
-            let mut state = shared_state.try_lock().expect("lock shared state");
-            state.relays.push(RelayInfo {
-                peer_id: relay_peer_id.to_string(),
-                full_addr: addr.to_string(),
-                connected_at: get_timestamp_ms(),
-                supports_relay: false, // Will be validated on Identify event
-            });
-        } else {
-            log("Warning: Server address does not contain peer ID - relay functionality may be limited");
-        }
-        
-        log(&format!("dialing {}", addr));
-        swarm.dial(addr.clone())
-            .map_err(|e| JsValue::from_str(&format!("dial error: {e}")))?;
+        // The servers to dial (webrtc-direct or websocket multiaddrs)
+        let bootstrap_addrs = bootstrap_multiaddrs(&bootstrap)?;
+        let mut bootstrap = BootstrapDials::new(bootstrap_addrs.clone());
+        let guest_link_relays = bootstrap_addrs.clone();
+        let (bootstrap_ready, mut bootstrap_ready_rx) = futures::channel::oneshot::channel();
+        let mut bootstrap_ready = Some(bootstrap_ready);
 
         // Create command and event channels
         #[allow(clippy::disallowed_methods)]
         let (cmd_sender, mut cmd_receiver) = mpsc::unbounded();
         #[allow(clippy::disallowed_methods)]
         let (event_sender, event_receiver) = mpsc::unbounded();
+        let subscriptions = Subscriptions::default();
+        let room_subscriptions = RoomSubscriptions::default();
+        let event_sender = EventSink {
+            sender: event_sender,
+            history: history.clone(),
+            subscriptions: subscriptions.clone(),
+            rooms: room_subscriptions.clone(),
+            tap,
+        };
+        // Seed content goes out before anything touches the network
+        let mut seeds = Seeds::new(options.seed_documents.clone());
+        for seed in seeds.iter() {
+            let _ = event_sender.unbounded_send(Event::SeedLoaded {
+                doc_id: seed.doc_id.clone(),
+                data: String::from_utf8_lossy(&seed.bytes).to_string(),
+                version: seed.version,
+            });
+        }
+        let publishes_seeds = !role.is_read_only();
+
+        // Race all bootstrap addresses; relays are recorded as they connect
+        let mut pending_dials = PendingDials::default();
+        for addr in bootstrap_addrs.clone() {
+            if extract_peer_id_from_multiaddr(&addr).is_none() {
+                tracing::warn!("Warning: Server address {} does not contain peer ID - relay functionality may be limited", addr);
+            }
+            tracing::info!("dialing {}", addr);
+            // The relay may still be booting, so keep trying for a while
+            if let Err(e) = dial_addr(&mut swarm, &mut pending_dials, addr.clone(), DialOptions::retrying()) {
+                bootstrap_failed(&mut bootstrap, &mut bootstrap_ready, &event_sender, &addr, 1, e.to_string());
+            }
+        }
+        // Nothing left to wait for: reject right away, as a single bad address always did
+        if let Ok(Some(Err(e))) = bootstrap_ready_rx.try_recv() {
+            return Err(error_to_js(&e));
+        }
 
         // Store local_peer_id for later use in event loop
         let local_peer_id_for_events = local_peer_id;
+        let docstore_config_for_loop = docstore_config.clone();
+        let traffic = TrafficStats::default().with_observer(node_builder.observer());
+        let traffic_for_loop = traffic.clone();
+        let reputation = PeerReputation::default();
+        let reputation_for_loop = reputation.clone();
+        let quality = QualityTracker::default();
+        let quality_for_loop = quality.clone();
+
+        // Signs revocation lists of the restricted rooms we create
+        let local_key_for_loop = local_key.clone();
+        let dedup_cache_key = options.dedup_cache_key.clone();
 
         // Spawn the event loop - swarm is moved in and owned by this task
         spawn_local(async move {
-            let topic = crate::behaviour::docstore::docstore_topic();
+            let local_key = local_key_for_loop;
             let mut relay_address: Option<Multiaddr> = None;
             let mut webrtc_listening = false;
+            let docstore_config = docstore_config_for_loop;
+            let traffic = traffic_for_loop;
+            let reputation = reputation_for_loop;
+            let quality = quality_for_loop;
+            let mut debouncer = PublishDebouncer::default();
+            let mut pipeline = MessagePipeline::new(docstore_config.clone(), &local_peer_id_for_events);
+            // Browsers keep no store, so stamping relies on the clocks seen this session
+            let mut ledger = ClockLedger::default();
+            let mut bans = BanList::default();
+            // In memory only: counts dial failures so dead addresses leave the routing table
+            let mut address_book = AddressBook::default();
+            // find_peer queries waiting for Kademlia: (target, dial when found, reply)
+            let mut pending_finds: HashMap<libp2p_kad::QueryId, (PeerId, bool, futures::channel::oneshot::Sender<Vec<FoundPeer>>)> =
+                HashMap::new();
+            // Relay providers dialed and waiting for identify, and the lookups finding them
+            let mut relay_discovery = RelayDiscovery::default();
+            // Pinned documents we provide, renewed after an outage
+            let mut announcements: DhtAnnouncements<libp2p_kad::QueryId> = DhtAnnouncements::new(reannounce_after);
+            let mut pending_relay_lookups: HashMap<libp2p_kad::QueryId, (RelayLookup, futures::channel::oneshot::Sender<Vec<PeerId>>)> =
+                HashMap::new();
+            // Our head pointers, put again before they expire and after an outage
+            let mut head_pointers = HeadPointers::default();
+            let mut published = PublishedRecords::new(max_published_records);
+            let mut republish_timer = futures::future::Fuse::<futures_timer::Delay>::terminated();
+            let mut pending_head_puts: HashMap<
+                libp2p_kad::QueryId,
+                (HeadPointer, futures::channel::oneshot::Sender<Result<HeadPointer, crate::Error>>),
+            > = HashMap::new();
+            // resolve_head queries: (author, doc id, copies found so far, reply)
+            let mut pending_resolves: HashMap<
+                libp2p_kad::QueryId,
+                (PeerId, String, Vec<Vec<u8>>, futures::channel::oneshot::Sender<Result<Option<HeadPointer>, crate::Error>>),
+            > = HashMap::new();
+            // Resolved profiles, ours included once set; our display name rides on presence
+            let mut profiles = ProfileCache::default();
+            let mut own_display_name: Option<String> = None;
+            let mut pending_profile_puts: HashMap<
+                libp2p_kad::QueryId,
+                (SignedProfile, futures::channel::oneshot::Sender<Result<SignedProfile, crate::Error>>),
+            > = HashMap::new();
+            // resolve_profile queries: (peer, copies found so far, reply)
+            let mut pending_profiles: HashMap<
+                libp2p_kad::QueryId,
+                (PeerId, Vec<Vec<u8>>, futures::channel::oneshot::Sender<Result<Option<SignedProfile>, crate::Error>>),
+            > = HashMap::new();
+            // Current state of the documents of interest, fetched from a peer serving history
+            let mut catch_up: CatchUp<request_response::OutboundRequestId> = CatchUp::default();
+            // Seeded documents are fetched like any other, to learn whether the network has newer state
+            catch_up.interest(seeds.doc_ids());
+            let mut pending_history: HashMap<
+                request_response::OutboundRequestId,
+                futures::channel::oneshot::Sender<Result<(PeerId, HistoryPage), crate::Error>>,
+            > = HashMap::new();
+            let mut pending_document_at: HashMap<
+                request_response::OutboundRequestId,
+                futures::channel::oneshot::Sender<Result<(PeerId, DocumentState), crate::Error>>,
+            > = HashMap::new();
+            let mut pending_mailbox: HashMap<request_response::OutboundRequestId, PendingMailbox> = HashMap::new();
+            let mut fetches = BrowserFetches::default();
+            // Our updates waiting for receipts, and the receipts we sent directly
+            let mut acks = AckTracker::default();
+            let mut ack_timer = futures::future::Fuse::<futures_timer::Delay>::terminated();
+            let mut pending_receipts: HashMap<request_response::OutboundRequestId, UpdateReceipt> = HashMap::new();
+            // Documents watched with watch_document(), what they put out since the last
+            // iteration, and the history requests re-requesting their gaps
+            let mut ordering = OrderedDelivery::default();
+            let mut ordered_outputs: OrderedOutputs = Vec::new();
+            let mut pending_gap_fills: HashMap<request_response::OutboundRequestId, String> = HashMap::new();
+            let mut gap_deadline: Option<web_time::Instant> = None;
+            let mut gap_timer = futures::future::Fuse::<futures_timer::Delay>::terminated();
+            // Rendezvous points we register with, and the peers found through them
+            let rendezvous_namespace = docstore_config.topics.rendezvous_namespace();
+            let mut rendezvous: RendezvousPeers<rendezvous_behaviour::Cookie> = RendezvousPeers::new(local_peer_id);
+            let mut rendezvous_waiters: Vec<futures::channel::oneshot::Sender<Vec<Registrant>>> = Vec::new();
+            let mut rendezvous_timer = futures_timer::Delay::new(DISCOVER_INTERVAL).fuse();
+            // Relays listing their other browsers to us, and the ones we dialed from those lists
+            let mut peer_exchange = PeerExchange::new(local_peer_id, discoverable);
+            let mut peer_exchange_timer = futures_timer::Delay::new(PEER_EXCHANGE_INTERVAL).fuse();
+            let mut rooms = Rooms::new(docstore_config.topics.clone());
+            let mut room_auth: HashMap<String, RoomAuth> = HashMap::new();
+            // Who sends presence in the rooms we joined, and the rooms FullNodes keep no table for
+            let mut presence_tracker = PresenceTracker::default();
+            let mut private_rooms: HashSet<String> = HashSet::new();
+            let mut pending_members: HashMap<
+                request_response::OutboundRequestId,
+                futures::channel::oneshot::Sender<Result<(PeerId, MembersPage), crate::Error>>,
+            > = HashMap::new();
+            // The topics set_topic_interest asked relays to limit us to, kept current as rooms change
+            let mut topic_hint: Option<HashSet<String>> = None;
+            // Bootstrap relays by ping time; the best ones carry ephemeral traffic
+            let mut relay_ranking = RelayRanking::default();
+            let mut ping_failures = PingFailures::new(ping_policy);
+            // The last presence frame sent in each room, re-sent by heartbeats and on resume
+            let mut room_presence: HashMap<String, Vec<u8>> = HashMap::new();
+            // Peers of joined rooms and watched documents, pinged so they don't idle out
+            let mut connection_keeper = ConnectionKeeper::default();
+            let mut keep_alive_timer = futures_timer::Delay::new(keep_alive_interval).fuse();
+            // When suspend() was called, and whether it closed the connections
+            let mut suspended_at: Option<f64> = None;
+            let mut suspend_disconnected = false;
+            let mut presence_timer = futures_timer::Delay::new(ROOM_PRESENCE_HEARTBEAT).fuse();
+            let mut flush_timer = futures::future::Fuse::<futures_timer::Delay>::terminated();
+            let mut retry_timer = futures::future::Fuse::<futures_timer::Delay>::terminated();
+            // Only runs while someone waits in ready(): heartbeat grafts raise no swarm event
+            let mut ready_poll = futures::future::Fuse::<futures_timer::Delay>::terminated();
+            let mut ready_waiters: Vec<futures::channel::oneshot::Sender<NodeReadiness>> = Vec::new();
+            let mut readiness = NodeReadiness::default();
+            // Started once the first bootstrap address connects
+            let mut dht_bootstrap = if dht_enabled { DhtBootstrap::Pending } else { DhtBootstrap::NotConfigured };
+            let mut bootstrap_query: Option<libp2p_kad::QueryId> = None;
+            let docstore_topic = docstore_config.topics.updates().hash();
+            let mut docstore_mesh_empty = true;
+            // Subscribed topics, resubscribed when they stay without mesh peers; relays we
+            // peer with explicitly count as meshed
+            let mut topic_health = TopicHealth::new(topic_health_config, docstore_config.heartbeat_interval);
+            let topic_registry = &docstore_config.topics;
+            for topic in [topic_registry.updates(), topic_registry.snapshots(), topic_registry.announce(), topic_registry.receipts()] {
+                topic_health.watch(topic.hash());
+            }
+            let mut topic_health_timer = futures_timer::Delay::new(topic_health_config.check_interval()).fuse();
+            let mut resubscribe_timer = futures::future::Fuse::<futures_timer::Delay>::terminated();
+            let mut dht_summary = DhtSummary::default();
+            // Publishes and history fetches held while the bandwidth budget is exhausted, and
+            // the queue handing them back ahead of new commands once it resets
+            let budget = traffic.budget().clone();
+            let mut deferred: VecDeque<Command> = VecDeque::new();
+            let mut deferred_gaps: Vec<(String, u64, Option<PeerId>)> = Vec::new();
+            let replay: std::rc::Rc<std::cell::RefCell<VecDeque<Command>>> = Default::default();
+            let mut commands = {
+                let replay = replay.clone();
+                futures::stream::poll_fn(move |cx| match replay.borrow_mut().pop_front() {
+                    Some(cmd) => std::task::Poll::Ready(Some(cmd)),
+                    None => cmd_receiver.poll_next_unpin(cx),
+                })
+                .fuse()
+            };
+            let mut budget_timer = futures::future::Fuse::<futures_timer::Delay>::terminated();
+            // Whether the last heartbeat was skipped to save the budget
+            let mut presence_skipped = false;
+            // Updates the app was handed, before a reload too, and the pending save of them
+            let mut dedup = match &dedup_cache_key {
+                Some(key) => load_dedup_cache(key).await,
+                None => DedupCache::default(),
+            };
+            let mut dedup_save_timer = futures::future::Fuse::<futures_timer::Delay>::terminated();
             
             loop {
+                // Checked once per iteration, i.e. after every handled event
+                let mesh_empty = swarm.behaviour().gossipsub.mesh_peers(&docstore_topic).next().is_none();
+                if mesh_empty && !docstore_mesh_empty {
+                    let _ = event_sender.unbounded_send(Event::MeshEmpty { topic: docstore_topic.to_string() });
+                }
+                docstore_mesh_empty = mesh_empty;
+                if dht_bootstrap == DhtBootstrap::Pending && bootstrap_query.is_none() && bootstrap.all_failed() {
+                    dht_bootstrap = DhtBootstrap::Failed;
+                }
+                let now_ready = NodeReadiness {
+                    connected: swarm.network_info().num_peers() > 0,
+                    mesh: !mesh_empty,
+                    dht_bootstrap,
+                };
+                if now_ready != readiness {
+                    readiness = now_ready;
+                    shared_state_clone.lock().await.readiness = readiness;
+                }
+                if readiness.is_ready() {
+                    for reply in ready_waiters.drain(..) {
+                        let _ = reply.send(readiness);
+                    }
+                }
+                // Counts the traffic of the last iteration
+                match budget.poll(get_timestamp_ms() as u64) {
+                    Some(BudgetChange::Exceeded { used, limit, resets_at_ms }) => {
+                        tracing::info!("Bandwidth budget spent ({} of {} bytes), deferring traffic until {}", used, limit, resets_at_ms);
+                        let _ = event_sender.unbounded_send(Event::BudgetExceeded { used, limit, resets_at_ms });
+                    }
+                    Some(BudgetChange::Reset) => {
+                        tracing::info!("Bandwidth budget available, sending {} deferred commands", deferred.len());
+                        replay.borrow_mut().extend(deferred.drain(..));
+                        fetches.resume_deferred(&mut swarm, &budget);
+                        let _ = event_sender.unbounded_send(Event::BudgetReset);
+                        let serving = shared_state_clone.lock().await.peer_infos.supporting(HISTORY_PROTOCOL);
+                        let best = reputation.rank(serving, web_time::Instant::now()).first().copied();
+                        start_catch_up(&mut swarm, &mut catch_up, best, &budget);
+                    }
+                    None => {}
+                }
+                let mut gaps = if ordered_outputs.is_empty() {
+                    Vec::new()
+                } else {
+                    report_ordered(&event_sender, std::mem::take(&mut ordered_outputs))
+                };
+                // Gap re-requests are history fetches too
+                if budget.is_exhausted() {
+                    deferred_gaps.append(&mut gaps);
+                } else {
+                    gaps.append(&mut deferred_gaps);
+                }
+                if !gaps.is_empty() {
+                    let serving = shared_state_clone.lock().await.peer_infos.supporting(HISTORY_PROTOCOL);
+                    for (doc_id, since_ms, source) in gaps {
+                        // The peer the update came from evidently has the ones before it
+                        let peer = source.filter(|source| serving.contains(source)).or_else(|| {
+                            reputation.rank(serving.iter().copied(), web_time::Instant::now()).first().copied()
+                        });
+                        let Some(peer) = peer else {
+                            tracing::debug!("No peer serving history to fill the gap in {}", doc_id);
+                            continue;
+                        };
+                        let request = HistoryOptions { from: HistoryFrom::Time(since_ms), ..Default::default() }.request(doc_id.clone());
+                        let id = swarm.behaviour_mut().history.send_request(&peer, request);
+                        pending_gap_fills.insert(id, doc_id);
+                    }
+                }
+                if dedup_cache_key.is_some() && dedup_save_timer.is_terminated() && dedup.take_dirty() {
+                    dedup_save_timer = futures_timer::Delay::new(DEDUP_SAVE_DELAY).fuse();
+                }
+                if ordering.next_deadline() != gap_deadline {
+                    gap_deadline = ordering.next_deadline();
+                    gap_timer = match gap_deadline {
+                        Some(due) => futures_timer::Delay::new(due.saturating_duration_since(web_time::Instant::now())).fuse(),
+                        None => futures::future::Fuse::terminated(),
+                    };
+                }
+                // Kademlia reports peers entering the routing table but not leaving it
+                if let Some(kademlia) = swarm.behaviour_mut().kademlia.as_mut() {
+                    let summary = DhtSummary::of(kademlia);
+                    if summary != dht_summary {
+                        dht_summary = summary;
+                        shared_state_clone.lock().await.dht_summary = summary;
+                        let _ = event_sender.unbounded_send(Event::DhtSummaryChanged { summary });
+                    }
+                }
+
                 futures::select! {
-                    cmd = cmd_receiver.select_next_some() => {
+                    cmd = commands.next() => {
+                        let Some(cmd) = cmd else {
+                            // WasmNode dropped: don't lose debounced updates on the way out
+                            flush_debounced(&mut swarm, &docstore_config, &mut debouncer, &traffic, &event_sender);
+                            break;
+                        };
+                        if cmd.waits_for_budget() && budget.is_exhausted() {
+                            defer_over_budget(&mut deferred, cmd, &event_sender);
+                            continue;
+                        }
                         match cmd {
                             Command::Publish(data) => {
-                                match swarm.behaviour_mut()
-                                    .gossipsub
-                                    .publish(topic.clone(), data) {
-                                    Ok(msg_id) => {
-                                        log(&format!("Published message: {:?}", msg_id));
-                                        let _ = event_sender.unbounded_send(Event::MessagePublished {
-                                            msg_id: format!("{:?}", msg_id)
+                                // Keep publish order: anything debounced goes out first
+                                flush_debounced(&mut swarm, &docstore_config, &mut debouncer, &traffic, &event_sender);
+                                let published = docstore_config.check_update_size(data.len()).and_then(|()| {
+                                    let topic = docstore_config.topics.updates();
+                                    Ok(traffic.publish(&mut swarm.behaviour_mut().gossipsub, topic, data)?)
+                                });
+                                match published {
+                                    Ok(published) => report_published(&event_sender, published),
+                                    Err(e) => {
+                                        tracing::warn!("Publish error: {}", e);
+                                        let _ = event_sender.unbounded_send(Event::Error {
+                                            msg: format!("Publish error: {}", e)
+                                        });
+                                    }
+                                }
+                            }
+                            Command::PublishDocUpdate { mut update, options } => {
+                                if update.stamp.is_none() {
+                                    let clock = ledger.clock_mut(&update.doc_id);
+                                    let stamp = crate::behaviour::docstore::Stamp::next(pipeline.hlc_mut(), clock);
+                                    clock.merge(&stamp.clock);
+                                    update.stamp = Some(stamp);
+                                }
+                                // The app has applied its own edit; catch-up after a reload brings it back
+                                dedup.delivered(&update.doc_id, update.stamp.as_ref());
+                                if ordering.is_watched(&update.doc_id) {
+                                    let outputs = ordering.push(update.clone(), Origin::Live, web_time::Instant::now());
+                                    ordered_outputs.extend(sourced(outputs, None));
+                                }
+                                if options.wants_receipts() {
+                                    // Receipts name the message, so it goes out alone, after whatever is pending
+                                    flush_debounced(&mut swarm, &docstore_config, &mut debouncer, &traffic, &event_sender);
+                                    let doc_id = update.doc_id.clone();
+                                    let encoded = match options.expected_version {
+                                        Some(expected_version) => {
+                                            crate::behaviour::docstore::encode_cas_update(&docstore_config, update, expected_version)
+                                        }
+                                        None => crate::behaviour::docstore::encode_doc_update_requesting_ack(&docstore_config, update),
+                                    };
+                                    let published = encoded
+                                        .and_then(|data| {
+                                            let topic = docstore_config.topics.updates();
+                                            Ok(traffic.publish(&mut swarm.behaviour_mut().gossipsub, topic, data)?)
+                                        });
+                                    match published {
+                                        Ok(published) => {
+                                            let now = web_time::Instant::now();
+                                            acks.expect(published.msg_id.clone(), doc_id, now);
+                                            if let Some(due) = acks.next_deadline() {
+                                                ack_timer = futures_timer::Delay::new(due.saturating_duration_since(now)).fuse();
+                                            }
+                                            report_published(&event_sender, published);
+                                        }
+                                        Err(e) => {
+                                            tracing::warn!("Publish error: {}", e);
+                                            let _ = event_sender.unbounded_send(Event::Error {
+                                                msg: format!("Publish error: {}", e)
+                                            });
+                                        }
+                                    }
+                                } else if let Some(window) = debouncer.window() {
+                                    if debouncer.push(update) {
+                                        flush_timer = futures_timer::Delay::new(window).fuse();
+                                    }
+                                } else {
+                                    debouncer.push(update);
+                                    flush_debounced(&mut swarm, &docstore_config, &mut debouncer, &traffic, &event_sender);
+                                }
+                            }
+                            Command::CommitTransaction(mut tx) => {
+                                // Whatever was published before the transaction goes out first
+                                flush_debounced(&mut swarm, &docstore_config, &mut debouncer, &traffic, &event_sender);
+                                for update in &mut tx.updates {
+                                    let clock = ledger.clock_mut(&update.doc_id);
+                                    let stamp = update
+                                        .stamp
+                                        .get_or_insert_with(|| crate::behaviour::docstore::Stamp::next(pipeline.hlc_mut(), clock));
+                                    clock.merge(&stamp.clock);
+                                    dedup.delivered(&update.doc_id, Some(&*stamp));
+                                    if ordering.is_watched(&update.doc_id) {
+                                        let outputs = ordering.push(update.clone(), Origin::Live, web_time::Instant::now());
+                                        ordered_outputs.extend(sourced(outputs, None));
+                                    }
+                                }
+                                let published = crate::behaviour::docstore::encode_transaction(&docstore_config, &tx).and_then(|parts| {
+                                    parts
+                                        .into_iter()
+                                        .map(|data| {
+                                            let topic = docstore_config.topics.updates();
+                                            Ok(traffic.publish(&mut swarm.behaviour_mut().gossipsub, topic, data)?)
+                                        })
+                                        .collect::<Result<Vec<_>, crate::Error>>()
+                                });
+                                match published {
+                                    Ok(published) => {
+                                        for published in published {
+                                            report_published(&event_sender, published);
+                                        }
+                                    }
+                                    Err(e) => {
+                                        tracing::warn!("Publish error for transaction {}: {}", tx.id, e);
+                                        let _ = event_sender.unbounded_send(Event::Error {
+                                            msg: format!("Publish error: {}", e)
                                         });
                                     }
+                                }
+                            }
+                            Command::ClearDedupCache => {
+                                dedup.clear();
+                                dedup.take_dirty();
+                                dedup_save_timer = futures::future::Fuse::terminated();
+                                if let Some(key) = dedup_cache_key.clone() {
+                                    spawn_local(async move {
+                                        if let Err(e) = crate::wasm_idb::delete(&key).await {
+                                            tracing::warn!("Could not delete the dedup cache: {:?}", e);
+                                        }
+                                    });
+                                }
+                            }
+                            Command::SetPublishDebounce(window) => {
+                                debouncer.set_window(window);
+                                if debouncer.window().is_none() {
+                                    flush_debounced(&mut swarm, &docstore_config, &mut debouncer, &traffic, &event_sender);
+                                }
+                            }
+                            Command::PublishEphemeral { doc_id, data } => {
+                                if budget.is_exhausted() {
+                                    budget.drop_ephemeral();
+                                    continue;
+                                }
+                                // Ephemeral traffic is fire-and-forget: no MessagePublished event
+                                let topic = docstore_config.topics.ephemeral(&doc_id);
+                                let data = scope::frame_ephemeral(data, MessageScope::Local);
+                                if let Err(e) = traffic.publish(&mut swarm.behaviour_mut().ephemeral, topic, data) {
+                                    tracing::warn!("Ephemeral publish error for {}: {}", doc_id, e);
+                                }
+                            }
+                            Command::MeshInfo(reply) => {
+                                let _ = reply.send(crate::behaviour::docstore::mesh_info(&swarm.behaviour().gossipsub));
+                            }
+                            Command::JoinRoom { room, creator, token, private_members } => {
+                                let room_id = room.as_str().to_string();
+                                if private_members {
+                                    private_rooms.insert(room_id.clone());
+                                }
+                                if let Some(creator) = creator {
+                                    room_auth.insert(room_id.clone(), RoomAuth {
+                                        access: RoomAccess::new(room_id.clone(), creator),
+                                        token,
+                                        revoked: Vec::new(),
+                                    });
+                                }
+                                let session = keeper::room_session(&room_id);
+                                connection_keeper.open(session.clone());
+                                let topics = rooms.join(room);
+                                // A hint without the new topics would have relays leave them again
+                                if let Some(hint) = &mut topic_hint {
+                                    hint.extend(topics.iter().map(|(_, topic)| topic.to_string()));
+                                    let relays = shared_state_clone.lock().await.peer_infos.supporting(INTEREST_PROTOCOL);
+                                    send_interest(&mut swarm, relays, hint);
+                                }
+                                for (channel, topic) in topics {
+                                    let behaviour = swarm.behaviour_mut();
+                                    let gossipsub = if channel.is_ephemeral() { &mut behaviour.ephemeral } else { &mut behaviour.gossipsub };
+                                    // Peers already in the room; later ones are held as they talk
+                                    let hash = topic.hash();
+                                    let members: Vec<PeerId> =
+                                        gossipsub.all_peers().filter(|(_, topics)| topics.contains(&&hash)).map(|(peer, _)| *peer).collect();
+                                    for peer in members {
+                                        connection_keeper.hold(&session, peer);
+                                    }
+                                    match gossipsub.subscribe(&topic) {
+                                        Ok(_) => shared_state_clone.lock().await.subscriptions.push(topic.to_string()),
+                                        Err(e) => {
+                                            let _ = event_sender.unbounded_send(Event::Error {
+                                                msg: format!("Room {} subscribe error: {}", room_id, e)
+                                            });
+                                        }
+                                    }
+                                }
+                                tracing::info!("✓ Joined room {}", room_id);
+                            }
+                            Command::LeaveRoom { room_id } => {
+                                for peer in connection_keeper.close(&keeper::room_session(&room_id)) {
+                                    tracing::debug!("No longer keeping {} alive", peer);
+                                }
+                                // FullNodes only learn we left if they had heard we were there
+                                if room_presence.remove(&room_id).is_some() && !private_rooms.contains(&room_id) {
+                                    let holders = shared_state_clone.lock().await.peer_infos.supporting(MEMBERS_PROTOCOL);
+                                    for holder in holders {
+                                        let request = MembersRequest::Left { room_id: room_id.clone() };
+                                        swarm.behaviour_mut().members.send_request(&holder, request);
+                                    }
+                                }
+                                room_auth.remove(&room_id);
+                                private_rooms.remove(&room_id);
+                                presence_tracker.forget_room(&room_id);
+                                for (channel, topic) in rooms.leave(&room_id) {
+                                    let behaviour = swarm.behaviour_mut();
+                                    let gossipsub = if channel.is_ephemeral() { &mut behaviour.ephemeral } else { &mut behaviour.gossipsub };
+                                    gossipsub.unsubscribe(&topic);
+                                    let topic = topic.to_string();
+                                    if let Some(hint) = &mut topic_hint {
+                                        hint.remove(&topic);
+                                    }
+                                    shared_state_clone.lock().await.subscriptions.retain(|t| *t != topic);
+                                }
+                                if let Some(hint) = &topic_hint {
+                                    let relays = shared_state_clone.lock().await.peer_infos.supporting(INTEREST_PROTOCOL);
+                                    send_interest(&mut swarm, relays, hint);
+                                }
+                            }
+                            Command::SetTopicInterest { topics } => {
+                                let hint: HashSet<String> = topics.into_iter().collect();
+                                let relays = shared_state_clone.lock().await.peer_infos.supporting(INTEREST_PROTOCOL);
+                                send_interest(&mut swarm, relays, &hint);
+                                topic_hint = Some(hint);
+                            }
+                            Command::PublishRoom { room_id, channel, mut data, reply } => {
+                                if channel.is_ephemeral() && budget.is_exhausted() {
+                                    budget.drop_ephemeral();
+                                    continue;
+                                }
+                                if channel == RoomChannel::Presence {
+                                    if let Some(name) = &own_display_name {
+                                        data = profile::tag_presence(name, &data);
+                                    }
+                                    if let Some(auth) = room_auth.get(&room_id) {
+                                        data = PresenceFrame::presence(auth.token.clone(), data).encode();
+                                    }
+                                    room_presence.insert(room_id.clone(), data.clone());
+                                    if suspended_at.is_some() {
+                                        continue;
+                                    }
+                                    let holders = shared_state_clone.lock().await.peer_infos.supporting(MEMBERS_PROTOCOL);
+                                    report_presence(&mut swarm, &holders, &room_auth, &private_rooms, &room_id, &data);
+                                }
+                                let published = publish_room(&mut swarm, &rooms, &traffic, &room_id, channel, data);
+                                match reply {
+                                    Some(reply) => {
+                                        let _ = reply.send(published);
+                                    }
+                                    None => {
+                                        if let Err(e) = published {
+                                            tracing::warn!("Room {} {} publish error: {}", room_id, channel.as_str(), e);
+                                        }
+                                    }
+                                }
+                            }
+                            Command::Suspend { disconnect } => {
+                                if suspended_at.is_some() {
+                                    continue;
+                                }
+                                suspended_at = Some(get_timestamp_ms());
+                                suspend_disconnected = disconnect;
+                                flush_debounced(&mut swarm, &docstore_config, &mut debouncer, &traffic, &event_sender);
+                                presence_timer = futures::future::Fuse::terminated();
+                                retry_timer = futures::future::Fuse::terminated();
+                                let cancelled = pending_dials.cancel_retries();
+                                tracing::info!("Suspended; cancelled {} dial retries", cancelled);
+                                if disconnect {
+                                    let peers: Vec<PeerId> = swarm.connected_peers().copied().collect();
+                                    for peer_id in peers {
+                                        let _ = swarm.disconnect_peer_id(peer_id);
+                                    }
+                                }
+                                let _ = event_sender.unbounded_send(Event::Suspended { disconnected: disconnect });
+                            }
+                            Command::Resume => {
+                                let Some(since) = suspended_at.take() else {
+                                    continue;
+                                };
+                                // Fresh backoff for every bootstrap address we lost
+                                for addr in &bootstrap_addrs {
+                                    let connected = crate::node::addrs::peer_id_of(addr).is_some_and(|p| swarm.is_connected(&p));
+                                    if connected {
+                                        continue;
+                                    }
+                                    if let Err(e) = dial_addr(&mut swarm, &mut pending_dials, addr.clone(), DialOptions::retrying()) {
+                                        tracing::warn!("Redialing {} on resume failed: {}", addr, e);
+                                    }
+                                }
+                                // Closing the relay connection closed its circuit listener
+                                if let Some(relay_addr) = relay_address.clone().filter(|_| suspend_disconnected) {
+                                    if let Err(e) = swarm.listen_on(relay_addr.with(Protocol::P2pCircuit)) {
+                                        tracing::warn!("Failed to listen on relay circuit on resume: {}", e);
+                                    }
+                                }
+                                for (room_id, frame) in &room_presence {
+                                    let _ = publish_room(&mut swarm, &rooms, &traffic, room_id, RoomChannel::Presence, frame.clone());
+                                }
+                                presence_timer = futures_timer::Delay::new(ROOM_PRESENCE_HEARTBEAT).fuse();
+                                let _ = event_sender.unbounded_send(Event::Resumed { suspended_ms: get_timestamp_ms() - since });
+                                // Others' edits meanwhile were missed; if no history peer is left
+                                // connected, the run starts once one identifies
+                                catch_up.reset();
+                                let serving = shared_state_clone.lock().await.peer_infos.supporting(HISTORY_PROTOCOL);
+                                let best = reputation.rank(serving, web_time::Instant::now()).first().copied();
+                                start_catch_up(&mut swarm, &mut catch_up, best, &budget);
+                            }
+                            Command::SetBandwidthBudget { bytes_per_interval } => {
+                                // Polled at the top of the loop, so lifting the budget sends what waited at once
+                                budget.set_limit(bytes_per_interval, get_timestamp_ms() as u64);
+                                budget_timer = match bytes_per_interval {
+                                    Some(_) => futures_timer::Delay::new(BUDGET_POLL_INTERVAL).fuse(),
+                                    None => futures::future::Fuse::terminated(),
+                                };
+                            }
+                            Command::WaitReady { reply } => {
+                                // Answered at the top of the loop once every condition holds
+                                ready_waiters.push(reply);
+                                ready_poll = futures_timer::Delay::new(READY_POLL_INTERVAL).fuse();
+                            }
+                            Command::SetRoomToken { room_id, token } => {
+                                if let Some(auth) = room_auth.get_mut(&room_id) {
+                                    auth.token = Some(token.into());
+                                }
+                            }
+                            Command::RevokeCapabilities { room_id, ids } => {
+                                // WasmRoom::revoke only sends this for restricted rooms we created
+                                let Some(auth) = room_auth.get_mut(&room_id) else {
+                                    continue;
+                                };
+                                auth.revoked.extend(ids);
+                                let result = RevocationList::sign(&local_key, &room_id, auth.revoked.clone(), get_timestamp_ms() as u64)
+                                    .map_err(crate::Error::from)
+                                    .and_then(|list| {
+                                        let _ = auth.access.apply_revocations(&list);
+                                        let frame = PresenceFrame::Revocations(list).encode();
+                                        publish_room(&mut swarm, &rooms, &traffic, &room_id, RoomChannel::Presence, frame)
+                                    });
+                                if let Err(e) = result {
+                                    let _ = event_sender.unbounded_send(Event::Error {
+                                        msg: format!("Room {} revocation error: {}", room_id, e)
+                                    });
+                                }
+                            }
+                            Command::SetProviding { doc_id, provide } => {
+                                let key = crate::behaviour::doc_provider_key(&doc_id);
+                                let Some(kademlia) = swarm.behaviour_mut().kademlia.as_mut() else {
+                                    continue;
+                                };
+                                if provide {
+                                    if let Err(e) = kademlia.start_providing(key.clone()) {
+                                        tracing::warn!("Failed to provide {}: {}", doc_id, e);
+                                    }
+                                    announcements.provide(key);
+                                } else {
+                                    kademlia.stop_providing(&key);
+                                    announcements.stop_providing(&key);
+                                }
+                            }
+                            Command::SubscribeEphemeral { doc_id } => {
+                                match crate::behaviour::docstore::subscribe_ephemeral(
+                                    &mut swarm.behaviour_mut().ephemeral, &docstore_config.topics, &doc_id,
+                                ) {
+                                    Ok(()) => tracing::info!("✓ Subscribed to ephemeral topic for {}", doc_id),
                                     Err(e) => {
-                                        log(&format!("Publish error: {}", e));
                                         let _ = event_sender.unbounded_send(Event::Error {
-                                            msg: format!("Publish error: {}", e)
+                                            msg: format!("Ephemeral subscribe error: {}", e)
                                         });
                                     }
                                 }
                             }
-                            Command::FindPeer(pid) => {
-                                let qid = swarm.behaviour_mut().kademlia.get_closest_peers(pid.clone());
-                                log(&format!("Started find_peer query {:?} for {}", qid, pid.to_string()));
+                            Command::FindPeer { peer_id, options, reply } => {
+                                // `find_peer` refuses before sending when the DHT is disabled
+                                let Some(kademlia) = swarm.behaviour_mut().kademlia.as_mut() else {
+                                    continue;
+                                };
+                                let count = std::num::NonZeroUsize::new(options.num_results).unwrap_or(std::num::NonZeroUsize::MIN);
+                                let qid = kademlia.get_n_closest_peers(peer_id, count);
+                                tracing::debug!("Started find_peer query {:?} for {}", qid, peer_id);
+                                pending_finds.insert(qid, (peer_id, options.dial, reply));
+                            }
+                            Command::PublishHeadPointer { doc_id, version, content, reply } => {
+                                let now_ms = get_timestamp_ms() as u64;
+                                let key = head_pointer_key(swarm.local_peer_id(), &doc_id);
+                                // `publish_head_pointer` refuses before sending when the DHT is disabled
+                                let Some(kademlia) = swarm.behaviour_mut().kademlia.as_mut() else {
+                                    continue;
+                                };
+                                let pointer = match head_pointers.publish(&local_key, &doc_id, version, &content, now_ms) {
+                                    Ok(pointer) => pointer,
+                                    Err(e) => {
+                                        let _ = reply.send(Err(e.into()));
+                                        continue;
+                                    }
+                                };
+                                let value = pointer.encode();
+                                if let Some(ttl) = record_ttl {
+                                    if let Err(e) = published.insert(key.clone(), value.clone(), ttl, now_ms) {
+                                        let _ = reply.send(Err(e));
+                                        continue;
+                                    }
+                                }
+                                match put_record(kademlia, key.clone(), value.clone(), record_ttl) {
+                                    Ok(query) => {
+                                        announcements.record(key, value, record_ttl);
+                                        pending_head_puts.insert(query, (pointer, reply));
+                                    }
+                                    Err(e) => {
+                                        published.forget(&key);
+                                        let _ = reply.send(Err(e));
+                                    }
+                                }
+                                if let Some(due) = published.next_due_ms() {
+                                    let wait = std::time::Duration::from_millis(due.saturating_sub(now_ms));
+                                    republish_timer = futures_timer::Delay::new(wait).fuse();
+                                }
+                            }
+                            Command::ResolveHead { author, doc_id, reply } => {
+                                // `resolve_head` refuses before sending when the DHT is disabled
+                                let Some(kademlia) = swarm.behaviour_mut().kademlia.as_mut() else {
+                                    continue;
+                                };
+                                let query = kademlia.get_record(head_pointer_key(&author, &doc_id));
+                                pending_resolves.insert(query, (author, doc_id, Vec::new(), reply));
+                            }
+                            Command::SetProfile { profile, reply } => {
+                                let now_ms = get_timestamp_ms() as u64;
+                                let key = profile::profile_key(swarm.local_peer_id());
+                                // `set_profile` refuses before sending when the DHT is disabled
+                                let Some(kademlia) = swarm.behaviour_mut().kademlia.as_mut() else {
+                                    continue;
+                                };
+                                let signed = match SignedProfile::sign(&local_key, profile, now_ms) {
+                                    Ok(signed) => signed,
+                                    Err(e) => {
+                                        let _ = reply.send(Err(e.into()));
+                                        continue;
+                                    }
+                                };
+                                let value = signed.encode();
+                                if let Some(ttl) = record_ttl {
+                                    if let Err(e) = published.insert(key.clone(), value.clone(), ttl, now_ms) {
+                                        let _ = reply.send(Err(e));
+                                        continue;
+                                    }
+                                }
+                                match put_record(kademlia, key.clone(), value.clone(), record_ttl) {
+                                    Ok(query) => {
+                                        announcements.record(key, value, record_ttl);
+                                        own_display_name = Some(signed.profile.display_name.clone());
+                                        profiles.insert(*swarm.local_peer_id(), Some(signed.clone()), now_ms);
+                                        pending_profile_puts.insert(query, (signed, reply));
+                                    }
+                                    Err(e) => {
+                                        published.forget(&key);
+                                        let _ = reply.send(Err(e));
+                                    }
+                                }
+                                if let Some(due) = published.next_due_ms() {
+                                    let wait = std::time::Duration::from_millis(due.saturating_sub(now_ms));
+                                    republish_timer = futures_timer::Delay::new(wait).fuse();
+                                }
+                            }
+                            Command::ResolveProfile { peer_id, reply } => {
+                                if let Some(cached) = profiles.get(&peer_id, get_timestamp_ms() as u64) {
+                                    let _ = reply.send(Ok(cached));
+                                    continue;
+                                }
+                                // `resolve_profile` refuses before sending when the DHT is disabled
+                                let Some(kademlia) = swarm.behaviour_mut().kademlia.as_mut() else {
+                                    continue;
+                                };
+                                let query = kademlia.get_record(profile::profile_key(&peer_id));
+                                pending_profiles.insert(query, (peer_id, Vec::new(), reply));
+                            }
+                            Command::DiscoverRelays { reply } => {
+                                // `discover_relays` refuses before sending when the DHT is disabled
+                                let Some(kademlia) = swarm.behaviour_mut().kademlia.as_mut() else {
+                                    continue;
+                                };
+                                let qid = kademlia.get_providers(crate::behaviour::relay_provider_key());
+                                pending_relay_lookups.insert(qid, (RelayLookup::new(DEFAULT_RELAYS_TO_DIAL), reply));
+                            }
+                            Command::WatchDoc { doc_id, watch: true } => {
+                                connection_keeper.open(keeper::doc_session(&doc_id));
+                            }
+                            Command::WatchDoc { doc_id, watch: false } => {
+                                for peer in connection_keeper.close(&keeper::doc_session(&doc_id)) {
+                                    tracing::debug!("No longer keeping {} alive", peer);
+                                }
+                            }
+                            Command::WatchDocument { doc_id, watch: true } => {
+                                let clock = ledger.clock_mut(&doc_id).clone();
+                                ordering.watch(doc_id, clock);
+                            }
+                            Command::WatchDocument { doc_id, watch: false } => {
+                                ordering.unwatch(&doc_id);
+                            }
+                            Command::RegisterSchema { prefix, since_version, validator } => {
+                                pipeline.schemas_mut().register_since(prefix, since_version, validator);
+                            }
+                            Command::UnregisterSchema { prefix, reply } => {
+                                let _ = reply.send(pipeline.schemas_mut().unregister(&prefix));
+                            }
+                            Command::KeepalivePeers { reply } => {
+                                let _ = reply.send(connection_keeper.peers());
+                            }
+                            Command::FindPeerLocal { peer_id, reply } => {
+                                let mut addrs = Vec::new();
+                                for bucket in swarm.behaviour_mut().kademlia.as_mut().into_iter().flat_map(|k| k.kbuckets()) {
+                                    for entry in bucket.iter() {
+                                        if entry.node.key.preimage() == &peer_id {
+                                            addrs.extend(entry.node.value.iter().cloned());
+                                        }
+                                    }
+                                }
+                                addrs.extend(address_book.addresses(&peer_id));
+                                if let Some(info) = shared_state_clone.lock().await.peer_infos.get(&peer_id) {
+                                    addrs.extend(info.listen_addrs.iter().cloned());
+                                }
+                                let found = FoundPeer::new(peer_id, addrs, crate::node::addrs::is_browser_dialable);
+                                let _ = reply.send((!found.addrs.is_empty()).then_some(found));
+                            }
+                            #[cfg(feature = "test-util")]
+                            Command::InjectEvent(event) => {
+                                let _ = event_sender.unbounded_send(event);
+                            }
+                            Command::Interest { doc_ids } => {
+                                catch_up.interest(doc_ids);
+                                let serving = shared_state_clone.lock().await.peer_infos.supporting(HISTORY_PROTOCOL);
+                                let best = reputation.rank(serving, web_time::Instant::now()).first().copied();
+                                start_catch_up(&mut swarm, &mut catch_up, best, &budget);
+                            }
+                            Command::History { peer_id, request, reply } => {
+                                if let Err(reason) = request.validate() {
+                                    let _ = reply.send(Err(crate::Error::HistoryRejected { reason }));
+                                    continue;
+                                }
+                                let peer_id = match peer_id {
+                                    Some(peer_id) => Some(peer_id),
+                                    None => {
+                                        let serving = shared_state_clone.lock().await.peer_infos.supporting(HISTORY_PROTOCOL);
+                                        reputation.rank(serving, web_time::Instant::now()).first().copied()
+                                    }
+                                };
+                                let Some(peer_id) = peer_id else {
+                                    let _ = reply.send(Err(crate::Error::NoHistoryPeer));
+                                    continue;
+                                };
+                                let id = swarm.behaviour_mut().history.send_request(&peer_id, request);
+                                pending_history.insert(id, reply);
+                            }
+                            Command::DocumentAt { peer_id, request, reply } => {
+                                let peer_id = match peer_id {
+                                    Some(peer_id) => Some(peer_id),
+                                    None => {
+                                        let serving = shared_state_clone.lock().await.peer_infos.supporting(HISTORY_PROTOCOL);
+                                        reputation.rank(serving, web_time::Instant::now()).first().copied()
+                                    }
+                                };
+                                let Some(peer_id) = peer_id else {
+                                    let _ = reply.send(Err(crate::Error::NoHistoryPeer));
+                                    continue;
+                                };
+                                let id = swarm.behaviour_mut().history.send_request(&peer_id, request);
+                                pending_document_at.insert(id, reply);
+                            }
+                            Command::FetchDocument { doc_id, peer_id, reply } => {
+                                let chosen = peer_id.is_some();
+                                let peer_id = match peer_id {
+                                    Some(peer_id) => Some(peer_id),
+                                    None => {
+                                        let serving = shared_state_clone.lock().await.peer_infos.supporting(FETCH_PROTOCOL);
+                                        reputation.rank(serving, web_time::Instant::now()).first().copied()
+                                    }
+                                };
+                                let Some(peer_id) = peer_id else {
+                                    let _ = reply.send(Err(crate::Error::NoFetchPeer));
+                                    continue;
+                                };
+                                fetches.start(&mut swarm, doc_id, peer_id, chosen, reply);
+                            }
+                            Command::SendToMailbox { holder, recipient, blob, ttl_ms, reply } => {
+                                let request = MailboxRequest::Deposit { recipient: recipient.to_string(), blob, ttl_ms };
+                                let id = swarm.behaviour_mut().mailbox.send_request(&holder, request);
+                                pending_mailbox.insert(id, PendingMailbox::Deposit(reply));
+                            }
+                            Command::DiscoverPeers { reply } => {
+                                // `discover_peers` refuses before sending on nodes that don't register
+                                for point in rendezvous.points() {
+                                    rendezvous_discover(&mut swarm, &mut rendezvous, &rendezvous_namespace, point);
+                                }
+                                rendezvous_waiters.push(reply);
+                                answer_rendezvous_waiters(&mut rendezvous, &mut rendezvous_waiters);
+                            }
+                            Command::CheckMailbox { holder, reply } => {
+                                let id = swarm.behaviour_mut().mailbox.send_request(&holder, MailboxRequest::Fetch);
+                                pending_mailbox.insert(id, PendingMailbox::Fetch(Some(reply)));
+                            }
+                            Command::RoomMembers { peer_id, request, reply } => {
+                                let peer_id = match peer_id {
+                                    Some(peer_id) => Some(peer_id),
+                                    None => {
+                                        let serving = shared_state_clone.lock().await.peer_infos.supporting(MEMBERS_PROTOCOL);
+                                        reputation.rank(serving, web_time::Instant::now()).first().copied()
+                                    }
+                                };
+                                match peer_id {
+                                    Some(peer_id) => {
+                                        let id = swarm.behaviour_mut().members.send_request(&peer_id, request);
+                                        pending_members.insert(id, reply);
+                                    }
+                                    None => {
+                                        let _ = reply.send(Err(crate::Error::NoMembersPeer));
+                                    }
+                                }
                             }
                             Command::SendDirect { peer_id, data } => {
                                 let msg = DirectMessage { data };
                                 let req_id = swarm.behaviour_mut().request_response.send_request(&peer_id, msg);
-                                log(&format!("Sent direct message request {:?} to {}", req_id, peer_id));
+                                tracing::debug!("Sent direct message request {:?} to {}", req_id, peer_id);
                             }
                             Command::ListenOnRelay { relay_addr } => {
                                 relay_address = Some(relay_addr.clone());
                                 // Build the circuit address for reservation
                                 let circuit_addr = relay_addr.with(Protocol::P2pCircuit);
                                 
-                                log(&format!("Attempting to listen on relay circuit: {}", circuit_addr));
+                                tracing::info!("Attempting to listen on relay circuit: {}", circuit_addr);
                                 match swarm.listen_on(circuit_addr.clone()) {
                                     Ok(listener_id) => {
-                                        log(&format!("✓ Relay circuit listener created: {:?}", listener_id));
+                                        tracing::info!("✓ Relay circuit listener created: {:?}", listener_id);
                                     }
                                     Err(e) => {
-                                        log(&format!("❌ Failed to listen on relay circuit: {}", e));
+                                        tracing::warn!("❌ Failed to listen on relay circuit: {}", e);
                                         let _ = event_sender.unbounded_send(Event::Error {
                                             msg: format!("Listen on relay failed: {}", e)
                                         });
@@ -272,38 +3874,87 @@ impl WasmNode {
                                 if !webrtc_listening {
                                     let webrtc_listen_addr = "/webrtc".parse::<Multiaddr>().unwrap();
                                     
-                                    log("Attempting to listen for incoming WebRTC connections...");
+                                    tracing::info!("Attempting to listen for incoming WebRTC connections...");
                                     match swarm.listen_on(webrtc_listen_addr.clone()) {
                                         Ok(listener_id) => {
-                                            log(&format!("✓ WebRTC listener created: {:?}", listener_id));
+                                            tracing::info!("✓ WebRTC listener created: {:?}", listener_id);
                                             webrtc_listening = true;
                                         }
                                         Err(e) => {
-                                            log(&format!("❌ Failed to create WebRTC listener: {}", e));
+                                            tracing::warn!("❌ Failed to create WebRTC listener: {}", e);
                                             let _ = event_sender.unbounded_send(Event::Error {
                                                 msg: format!("WebRTC listen failed: {}", e)
                                             });
                                         }
                                     }
                                 } else {
-                                    log("⚠ WebRTC listener already active");
+                                    tracing::warn!("⚠ WebRTC listener already active");
+                                }
+                            }
+                            Command::DisconnectPeer { peer_id } => {
+                                tracing::info!("Disconnecting peer {}", peer_id);
+                                let _ = swarm.disconnect_peer_id(peer_id);
+                                if let Some(kademlia) = swarm.behaviour_mut().kademlia.as_mut() {
+                                    kademlia.remove_peer(&peer_id);
+                                }
+                            }
+                            Command::RemovePeerAddress { peer_id, addr } => {
+                                if let Some(kademlia) = swarm.behaviour_mut().kademlia.as_mut() {
+                                    kademlia.remove_address(&peer_id, &addr);
+                                }
+                                address_book.remove_address(&peer_id, &addr);
+                                let _ = event_sender.unbounded_send(Event::AddressRemoved {
+                                    peer_id: peer_id.to_string(),
+                                    addr: addr.to_string(),
+                                    reason: RemovalReason::Requested,
+                                });
+                            }
+                            Command::RemovePeer { peer_id } => {
+                                if let Some(kademlia) = swarm.behaviour_mut().kademlia.as_mut() {
+                                    kademlia.remove_peer(&peer_id);
+                                }
+                                address_book.remove_peer(&peer_id);
+                                let _ = event_sender.unbounded_send(Event::PeerRemoved {
+                                    peer_id: peer_id.to_string(),
+                                    reason: RemovalReason::Requested,
+                                });
+                            }
+                            Command::BanPeer { peer_id, duration } => {
+                                tracing::info!("Banning peer {} for {:?}", peer_id, duration);
+                                bans.ban(peer_id, duration, web_time::Instant::now());
+                                swarm.behaviour_mut().gossipsub.blacklist_peer(&peer_id);
+                                let _ = swarm.disconnect_peer_id(peer_id);
+                                if let Some(kademlia) = swarm.behaviour_mut().kademlia.as_mut() {
+                                    kademlia.remove_peer(&peer_id);
                                 }
                             }
-                            Command::DialPeer { addr } => {
+                            Command::DialPeer { addr, options } => {
+                                if let Some(peer_id) = addr.iter().filter_map(|p| match p {
+                                    Protocol::P2p(peer_id) => Some(peer_id),
+                                    _ => None,
+                                }).last() {
+                                    if bans.is_banned(&peer_id, web_time::Instant::now()) {
+                                        tracing::warn!("Refusing to dial banned peer {}", peer_id);
+                                        let _ = event_sender.unbounded_send(Event::BannedPeerRejected {
+                                            peer_id: peer_id.to_string()
+                                        });
+                                        continue;
+                                    }
+                                }
                                 let addr_str = addr.to_string();
                                 
                                 // Check if this is a browser-to-browser dial (contains /p2p-circuit and /webrtc)
                                 if addr_str.contains("/p2p-circuit") && addr_str.contains("/webrtc") {
-                                    log(&format!("🔗 Browser-to-browser dial: {}", addr));
+                                    tracing::info!("🔗 Browser-to-browser dial: {}", addr);
                                     
                                     // Step 1: Dial relay circuit (for signaling channel)
                                     let relay_circuit_addr_str = addr_str.replace("/webrtc", "");
-                                    log(&format!("  → Dialing relay circuit: {}", relay_circuit_addr_str));
+                                    tracing::debug!("  → Dialing relay circuit: {}", relay_circuit_addr_str);
                                     
                                     match relay_circuit_addr_str.parse::<Multiaddr>() {
                                         Ok(relay_circuit_addr) => {
-                                            if let Err(e) = swarm.dial(relay_circuit_addr.clone()) {
-                                                log(&format!("❌ Failed to dial relay circuit: {:?}", e));
+                                            if let Err(e) = dial_addr(&mut swarm, &mut pending_dials, relay_circuit_addr.clone(), options) {
+                                                tracing::warn!("❌ Failed to dial relay circuit: {:?}", e);
                                                 let _ = event_sender.unbounded_send(Event::Error {
                                                     msg: format!("Relay dial failed: {}", e)
                                                 });
@@ -313,28 +3964,28 @@ impl WasmNode {
                                             // Step 2: Dial WebRTC address (triggers signaling)
                                             let peer_id_str = addr_str.split("/p2p/").last().unwrap_or("");
                                             let webrtc_addr_str = format!("/webrtc/p2p/{}", peer_id_str);
-                                            log(&format!("  → Dialing WebRTC: {}", webrtc_addr_str));
+                                            tracing::debug!("  → Dialing WebRTC: {}", webrtc_addr_str);
                                             
                                             match webrtc_addr_str.parse::<Multiaddr>() {
                                                 Ok(webrtc_addr) => {
-                                                    if let Err(e) = swarm.dial(webrtc_addr) {
-                                                        log(&format!("❌ Failed to dial WebRTC: {:?}", e));
+                                                    if let Err(e) = dial_addr(&mut swarm, &mut pending_dials, webrtc_addr, options) {
+                                                        tracing::warn!("❌ Failed to dial WebRTC: {:?}", e);
                                                     }
                                                 }
                                                 Err(e) => {
-                                                    log(&format!("❌ Invalid WebRTC multiaddr: {:?}", e));
+                                                    tracing::warn!("❌ Invalid WebRTC multiaddr: {:?}", e);
                                                 }
                                             }
                                         }
                                         Err(e) => {
-                                            log(&format!("❌ Invalid relay circuit multiaddr: {:?}", e));
+                                            tracing::warn!("❌ Invalid relay circuit multiaddr: {:?}", e);
                                         }
                                     }
                                 } else {
                                     // Simple direct dial (e.g., relay server via webrtc-direct)
-                                    log(&format!("📞 Direct dial: {}", addr));
-                                    if let Err(e) = swarm.dial(addr.clone()) {
-                                        log(&format!("❌ Dial failed: {:?}", e));
+                                    tracing::info!("📞 Direct dial: {}", addr);
+                                    if let Err(e) = dial_addr(&mut swarm, &mut pending_dials, addr.clone(), options) {
+                                        tracing::warn!("❌ Dial failed: {:?}", e);
                                         let _ = event_sender.unbounded_send(Event::Error {
                                             msg: format!("Dial failed: {}", e)
                                         });
@@ -343,10 +3994,184 @@ impl WasmNode {
                             }
                         }
                     }
+                    _ = ready_poll => {
+                        if !ready_waiters.is_empty() {
+                            ready_poll = futures_timer::Delay::new(READY_POLL_INTERVAL).fuse();
+                        }
+                    }
+                    _ = retry_timer => {
+                        if suspended_at.is_some() {
+                            // Scheduled by an attempt that failed after suspend(); resume redials
+                            pending_dials.cancel_retries();
+                            continue;
+                        }
+                        let now = web_time::Instant::now();
+                        for dial in pending_dials.take_due(now) {
+                            let peer_id = crate::node::addrs::peer_id_of(&dial.addr);
+                            if peer_id.is_some_and(|p| bans.is_banned(&p, now)) {
+                                continue;
+                            }
+                            tracing::debug!("Retrying dial of {} (attempt {})", dial.addr, dial.attempts + 1);
+                            let opts = DialOpts::from(dial.addr.clone());
+                            let connection_id = opts.connection_id();
+                            if let Err(e) = swarm.dial(opts) {
+                                bootstrap_failed(&mut bootstrap, &mut bootstrap_ready, &event_sender, &dial.addr, dial.attempts + 1, e.to_string());
+                                let _ = event_sender.unbounded_send(Event::DialFailed {
+                                    peer_id: peer_id.map(|p| p.to_string()),
+                                    reason: DialFailure::from(&e),
+                                    attempts: dial.attempts + 1,
+                                    msg: e.to_string(),
+                                });
+                            } else {
+                                pending_dials.retried(connection_id, dial);
+                            }
+                        }
+                        if let Some(due) = pending_dials.next_due() {
+                            retry_timer = futures_timer::Delay::new(due.saturating_duration_since(now)).fuse();
+                        }
+                    }
+                    _ = republish_timer => {
+                        let now_ms = get_timestamp_ms() as u64;
+                        if let Some(kademlia) = swarm.behaviour_mut().kademlia.as_mut() {
+                            for due in published.take_due(now_ms) {
+                                if let Err(e) = put_record(kademlia, due.key.clone(), due.value, Some(due.ttl)) {
+                                    tracing::warn!("Failed to republish {:?}: {}", due.key, e);
+                                }
+                            }
+                        }
+                        if let Some(due) = published.next_due_ms() {
+                            let wait = std::time::Duration::from_millis(due.saturating_sub(now_ms));
+                            republish_timer = futures_timer::Delay::new(wait).fuse();
+                        }
+                    }
+                    _ = flush_timer => {
+                        flush_debounced(&mut swarm, &docstore_config, &mut debouncer, &traffic, &event_sender);
+                    }
+                    _ = dedup_save_timer => {
+                        // Whatever changed since the timer started is in this save
+                        dedup.take_dirty();
+                        if let Some(key) = dedup_cache_key.clone() {
+                            let bytes = dedup.encode();
+                            spawn_local(async move {
+                                if let Err(e) = crate::wasm_idb::put(&key, &bytes).await {
+                                    tracing::warn!("Could not save the dedup cache: {:?}", e);
+                                }
+                            });
+                        }
+                    }
+                    _ = gap_timer => {
+                        ordered_outputs.extend(sourced(ordering.expire(web_time::Instant::now()), None));
+                        // Re-armed at the top of the loop
+                        gap_deadline = None;
+                    }
+                    _ = ack_timer => {
+                        let now = web_time::Instant::now();
+                        for unacked in acks.expire(now) {
+                            let _ = event_sender.unbounded_send(Event::UpdateUnacknowledged {
+                                msg_id: unacked.msg_id.to_string(),
+                                doc_id: unacked.doc_id,
+                            });
+                        }
+                        if let Some(due) = acks.next_deadline() {
+                            ack_timer = futures_timer::Delay::new(due.saturating_duration_since(now)).fuse();
+                        }
+                    }
+                    _ = topic_health_timer => {
+                        let now = get_timestamp_ms() as u64;
+                        let connected = swarm.network_info().num_peers() > 0;
+                        let gossipsub = &swarm.behaviour().gossipsub;
+                        let changes = topic_health.check(now, connected, |topic, explicit| {
+                            gossipsub.mesh_peers(topic).next().is_some()
+                                || gossipsub.all_peers().any(|(peer, topics)| explicit.contains(peer) && topics.contains(&topic))
+                        });
+                        for change in changes {
+                            match change {
+                                TopicChange::Unhealthy { topic, empty_since_ms, attempt } => {
+                                    tracing::warn!("{} has had no mesh peers since {}; resubscribing (attempt {})", topic, empty_since_ms, attempt);
+                                    swarm.behaviour_mut().gossipsub.unsubscribe(&gossipsub::IdentTopic::new(topic.as_str()));
+                                    let _ = event_sender.unbounded_send(Event::TopicUnhealthy { topic: topic.to_string(), empty_since_ms, attempt });
+                                }
+                                TopicChange::Recovered { topic, duration, attempts, .. } => {
+                                    tracing::info!("{} has mesh peers again after {:?}", topic, duration);
+                                    let _ = event_sender.unbounded_send(Event::TopicRecovered {
+                                        topic: topic.to_string(),
+                                        duration_ms: duration.as_millis() as u64,
+                                        attempts,
+                                    });
+                                }
+                            }
+                        }
+                        if let Some(due) = topic_health.next_resubscribe_ms() {
+                            resubscribe_timer = futures_timer::Delay::new(std::time::Duration::from_millis(due.saturating_sub(now))).fuse();
+                        }
+                        topic_health_timer = futures_timer::Delay::new(topic_health.config().check_interval()).fuse();
+                    }
+                    _ = resubscribe_timer => {
+                        let now = get_timestamp_ms() as u64;
+                        for topic in topic_health.take_resubscribes(now) {
+                            let topic = gossipsub::IdentTopic::new(topic.as_str());
+                            if let Err(e) = swarm.behaviour_mut().gossipsub.subscribe(&topic) {
+                                tracing::warn!("Resubscribing to {} failed: {}", topic, e);
+                            }
+                        }
+                        if let Some(due) = topic_health.next_resubscribe_ms() {
+                            resubscribe_timer = futures_timer::Delay::new(std::time::Duration::from_millis(due.saturating_sub(now))).fuse();
+                        }
+                    }
+                    _ = budget_timer => {
+                        // Only wakes the loop, whose top polls the budget
+                        if budget.limit().is_some() {
+                            budget_timer = futures_timer::Delay::new(BUDGET_POLL_INTERVAL).fuse();
+                        }
+                    }
+                    _ = presence_timer => {
+                        // Over the bandwidth budget every other heartbeat is skipped, which is
+                        // still within the three that peers wait before counting us gone
+                        presence_skipped = budget.is_exhausted() && !presence_skipped;
+                        if !presence_skipped {
+                            // Re-announce so peers that joined since see our token, and nobody counts us gone
+                            let holders = shared_state_clone.lock().await.peer_infos.supporting(MEMBERS_PROTOCOL);
+                            for (room_id, frame) in &room_presence {
+                                let _ = publish_room(&mut swarm, &rooms, &traffic, room_id, RoomChannel::Presence, frame.clone());
+                                report_presence(&mut swarm, &holders, &room_auth, &private_rooms, room_id, frame);
+                            }
+                        }
+                        for (room_id, peer) in presence_tracker.expire(get_timestamp_ms() as u64) {
+                            let _ = event_sender.unbounded_send(Event::MemberLeft { room_id, peer_id: peer.to_string() });
+                        }
+                        presence_timer = futures_timer::Delay::new(ROOM_PRESENCE_HEARTBEAT).fuse();
+                    }
+                    _ = keep_alive_timer => {
+                        // Released peers get no pings and idle out; suspended nodes let everyone go quiet
+                        if suspended_at.is_none() {
+                            for peer in connection_keeper.peers() {
+                                if swarm.is_connected(&peer) {
+                                    swarm.behaviour_mut().keep_alive.send_request(&peer, KeepAlive);
+                                }
+                            }
+                        }
+                        keep_alive_timer = futures_timer::Delay::new(keep_alive_interval).fuse();
+                    }
+                    _ = rendezvous_timer => {
+                        for point in rendezvous.due_registrations(web_time::Instant::now()) {
+                            rendezvous_register(&mut swarm, &mut rendezvous, &rendezvous_namespace, point);
+                        }
+                        for point in rendezvous.points() {
+                            rendezvous_discover(&mut swarm, &mut rendezvous, &rendezvous_namespace, point);
+                        }
+                        rendezvous_timer = futures_timer::Delay::new(DISCOVER_INTERVAL).fuse();
+                    }
+                    _ = peer_exchange_timer => {
+                        for relay in peer_exchange.relays() {
+                            ask_for_peers(&mut swarm, &peer_exchange, relay);
+                        }
+                        peer_exchange_timer = futures_timer::Delay::new(PEER_EXCHANGE_INTERVAL).fuse();
+                    }
                     event = swarm.select_next_some() => {
+                        traffic.observer().swarm_event(&event);
                         match event {
                             SwarmEvent::Behaviour(beh_event) => {
-                                log(&format!("Behaviour event: {:?}", beh_event));
+                                tracing::trace!("Behaviour event: {:?}", beh_event);
                                 
                                 // Handle request-response separately to consume the channel
                                 if let MyBehaviourEvent::RequestResponse(req_resp_evt) = beh_event {
@@ -356,7 +4181,7 @@ impl WasmNode {
                                             match message {
                                                 request_response::Message::Request { request, channel, .. } => {
                                                     let data = String::from_utf8_lossy(&request.data).to_string();
-                                                    log(&format!("Received direct message from {}: {}", peer, data));
+                                                    tracing::debug!("Received direct message from {}: {}", peer, data);
                                                     let _ = event_sender.unbounded_send(Event::DirectMessageReceived {
                                                         peer_id: peer.to_string(),
                                                         data: data.clone(),
@@ -364,11 +4189,12 @@ impl WasmNode {
                                                     // Send acknowledgment response
                                                     let response = DirectMessage { data: b"ack".to_vec() };
                                                     if let Err(_resp) = swarm.behaviour_mut().request_response.send_response(channel, response) {
-                                                        log(&format!("Failed to send response: response data lost"));
+                                                        tracing::warn!("Failed to send response: response data lost");
                                                     }
                                                 }
                                                 request_response::Message::Response { .. } => {
-                                                    log(&format!("Received direct message response from {}", peer));
+                                                    tracing::debug!("Received direct message response from {}", peer);
+                                                    reputation.record(peer, PeerSignal::FetchSucceeded, web_time::Instant::now());
                                                     let _ = event_sender.unbounded_send(Event::DirectMessageSent {
                                                         peer_id: peer.to_string(),
                                                     });
@@ -376,13 +4202,308 @@ impl WasmNode {
                                             }
                                         }
                                         ReqRespEvent::OutboundFailure { peer, error, .. } => {
-                                            log(&format!("Direct message outbound failure to {:?}: {:?}", peer, error));
+                                            quality.send_failed(&peer);
+                                            tracing::warn!("Direct message outbound failure to {:?}: {:?}", peer, error);
+                                            reputation.record(peer, PeerSignal::FetchFailed, web_time::Instant::now());
                                             let _ = event_sender.unbounded_send(Event::Error {
                                                 msg: format!("Direct message failed: {:?}", error)
                                             });
                                         }
                                         ReqRespEvent::InboundFailure { peer, error, .. } => {
-                                            log(&format!("Direct message inbound failure from {}: {:?}", peer, error));
+                                            tracing::warn!("Direct message inbound failure from {}: {:?}", peer, error);
+                                        }
+                                        _ => {}
+                                    }
+                                } else if let MyBehaviourEvent::History(history_evt) = beh_event {
+                                    match history_evt {
+                                        request_response::Event::Message {
+                                            peer,
+                                            message: request_response::Message::Response { request_id, response },
+                                            ..
+                                        } => {
+                                            let signal = match response {
+                                                HistoryResponse::RateLimited { .. } => PeerSignal::RateLimited,
+                                                _ => PeerSignal::FetchSucceeded,
+                                            };
+                                            reputation.record(peer, signal, web_time::Instant::now());
+                                            if let Some(doc_id) = catch_up.pending(&request_id).map(str::to_string) {
+                                                let outcome = match response {
+                                                    HistoryResponse::Page(page) => {
+                                                        let latest = page.updates.last();
+                                                        match latest {
+                                                            Some(update) => match seeds.observe(&doc_id, update.version) {
+                                                                SeedCheck::Held => {
+                                                                    tracing::debug!("Keeping the pinned seed of {} over version {}", doc_id, update.version);
+                                                                }
+                                                                check => {
+                                                                    if check == SeedCheck::Overridden {
+                                                                        let _ = event_sender.unbounded_send(Event::SeedOverridden {
+                                                                            doc_id: doc_id.clone(),
+                                                                            version: update.version,
+                                                                        });
+                                                                    }
+                                                                    if dedup.seen(&doc_id, update.stamp.as_ref()) {
+                                                                        tracing::debug!("Version {} of {} was delivered before", update.version, doc_id);
+                                                                    } else {
+                                                                        dedup.delivered(&doc_id, update.stamp.as_ref());
+                                                                        let _ = event_sender.unbounded_send(Event::DocUpdateReceived {
+                                                                            peer_id: peer.to_string(),
+                                                                            topic: docstore_config.topics.updates().to_string(),
+                                                                            doc_id,
+                                                                            data: String::from_utf8_lossy(&update.payload).to_string(),
+                                                                        });
+                                                                    }
+                                                                }
+                                                            },
+                                                            // Nobody has the document: the seed may become its first update
+                                                            None => {
+                                                                if let Some(seed) = seeds.absent(&doc_id).filter(|_| publishes_seeds) {
+                                                                    tracing::info!("{} has no {}; publishing its seed", peer, doc_id);
+                                                                    let mut update = DocUpdate::new(seed.doc_id, seed.bytes);
+                                                                    let clock = ledger.clock_mut(&update.doc_id);
+                                                                    let stamp = crate::behaviour::docstore::Stamp::next(pipeline.hlc_mut(), clock);
+                                                                    clock.merge(&stamp.clock);
+                                                                    update.stamp = Some(stamp);
+                                                                    debouncer.push(update);
+                                                                    flush_debounced(&mut swarm, &docstore_config, &mut debouncer, &traffic, &event_sender);
+                                                                }
+                                                            }
+                                                        }
+                                                        catch_up.answered(&request_id, latest.is_some())
+                                                    }
+                                                    _ => catch_up.failed(&request_id),
+                                                };
+                                                let finished = outcome.is_some();
+                                                report_catch_up(&event_sender, outcome);
+                                                // Documents of interest added during the run
+                                                if finished {
+                                                    start_catch_up(&mut swarm, &mut catch_up, Some(peer), &budget);
+                                                }
+                                            } else if let Some(doc_id) = pending_gap_fills.remove(&request_id) {
+                                                match response {
+                                                    HistoryResponse::Page(page) => {
+                                                        let now = web_time::Instant::now();
+                                                        // Unstamped updates have no place in the order, so they cannot fill a gap
+                                                        for stored in page.updates.into_iter().filter(|stored| stored.stamp.is_some()) {
+                                                            let update =
+                                                                DocUpdate { doc_id: doc_id.clone(), payload: stored.payload, stamp: stored.stamp };
+                                                            crate::behaviour::docstore::UpdateSink::apply_update(&mut ledger, &update);
+                                                            ordered_outputs.extend(sourced(ordering.push(update, Origin::Replay, now), None));
+                                                        }
+                                                    }
+                                                    // The held updates go out when the gap times out
+                                                    _ => tracing::debug!("{} would not fill the gap in {}", peer, doc_id),
+                                                }
+                                            } else if let Some(reply) = pending_history.remove(&request_id) {
+                                                let _ = reply.send(doc_history::page_result(response).map(|page| (peer, page)));
+                                            } else if let Some(reply) = pending_document_at.remove(&request_id) {
+                                                let _ = reply.send(doc_history::state_result(response).map(|state| (peer, state)));
+                                            }
+                                        }
+                                        request_response::Event::OutboundFailure { peer, request_id, error, .. } => {
+                                            quality.send_failed(&peer);
+                                            reputation.record(peer, PeerSignal::FetchFailed, web_time::Instant::now());
+                                            if catch_up.pending(&request_id).is_some() {
+                                                tracing::warn!("Catch-up request to {} failed: {}", peer, error);
+                                                report_catch_up(&event_sender, catch_up.failed(&request_id));
+                                            } else if pending_gap_fills.remove(&request_id).is_some() {
+                                                tracing::debug!("Re-requesting a gap from {} failed: {}", peer, error);
+                                            } else if let Some(reply) = pending_history.remove(&request_id) {
+                                                let _ = reply.send(Err(crate::Error::Transport(format!(
+                                                    "history request to {peer} failed: {error}"
+                                                ))));
+                                            } else if let Some(reply) = pending_document_at.remove(&request_id) {
+                                                let _ = reply.send(Err(crate::Error::Transport(format!(
+                                                    "history request to {peer} failed: {error}"
+                                                ))));
+                                            }
+                                        }
+                                        _ => {}
+                                    }
+                                } else if let MyBehaviourEvent::Fetch(fetch_evt) = beh_event {
+                                    let failure = match fetch_evt {
+                                        request_response::Event::Message {
+                                            peer,
+                                            message: request_response::Message::Response { request_id, response },
+                                            ..
+                                        } => {
+                                            let signal = match response {
+                                                FetchResponse::RateLimited { .. } => PeerSignal::RateLimited,
+                                                FetchResponse::NotFound | FetchResponse::Rejected { .. } => PeerSignal::FetchFailed,
+                                                _ => PeerSignal::FetchSucceeded,
+                                            };
+                                            reputation.record(peer, signal, web_time::Instant::now());
+                                            fetches
+                                                .response(&mut swarm, &budget, &event_sender, &request_id, response)
+                                                .map(|(doc_id, error)| (peer, doc_id, error))
+                                        }
+                                        request_response::Event::OutboundFailure { peer, request_id, error, .. } => {
+                                            quality.send_failed(&peer);
+                                            reputation.record(peer, PeerSignal::FetchFailed, web_time::Instant::now());
+                                            let error = crate::Error::Transport(format!("fetch request to {peer} failed: {error}"));
+                                            fetches.pending.remove(&request_id).map(|doc_id| (peer, doc_id, error))
+                                        }
+                                        _ => None,
+                                    };
+                                    if let Some((peer, doc_id, error)) = failure {
+                                        let serving = shared_state_clone.lock().await.peer_infos.supporting(FETCH_PROTOCOL);
+                                        let ranked = reputation.rank(serving, web_time::Instant::now());
+                                        fetches.failed(&mut swarm, doc_id, peer, error, &ranked);
+                                    }
+                                } else if let MyBehaviourEvent::Mailbox(mailbox_evt) = beh_event {
+                                    let answered = match mailbox_evt {
+                                        request_response::Event::Message {
+                                            peer,
+                                            message: request_response::Message::Response { request_id, response },
+                                            ..
+                                        } => Some((peer, request_id, Ok(response))),
+                                        request_response::Event::OutboundFailure { peer, request_id, error, .. } => {
+                                            quality.send_failed(&peer);
+                                            let error = crate::Error::Transport(format!("mailbox request to {peer} failed: {error}"));
+                                            Some((peer, request_id, Err(error)))
+                                        }
+                                        _ => None,
+                                    };
+                                    if let Some((peer, request_id, result)) = answered {
+                                        if let Some(pending) = pending_mailbox.remove(&request_id) {
+                                            mailbox_answered(&mut swarm, &mut pending_mailbox, &event_sender, peer, pending, result);
+                                        }
+                                    }
+                                } else if let MyBehaviourEvent::Members(members_evt) = beh_event {
+                                    match members_evt {
+                                        request_response::Event::Message {
+                                            peer,
+                                            message: request_response::Message::Response { request_id, response },
+                                            ..
+                                        } => {
+                                            if let Some(reply) = pending_members.remove(&request_id) {
+                                                let _ = reply.send(members_result(response).map(|page| (peer, page)));
+                                            } else if let MembersResponse::Refused { reason } = response {
+                                                tracing::debug!("{} refused our presence report: {}", peer, reason);
+                                            }
+                                        }
+                                        request_response::Event::OutboundFailure { peer, request_id, error, .. } => {
+                                            quality.send_failed(&peer);
+                                            if let Some(reply) = pending_members.remove(&request_id) {
+                                                let error = crate::Error::Transport(format!("members request to {peer} failed: {error}"));
+                                                let _ = reply.send(Err(error));
+                                            }
+                                        }
+                                        _ => {}
+                                    }
+                                } else if let MyBehaviourEvent::Receipts(receipt_evt) = beh_event {
+                                    match receipt_evt {
+                                        request_response::Event::Message {
+                                            peer,
+                                            message: request_response::Message::Request { request, channel, .. },
+                                            ..
+                                        } => {
+                                            let awaited = receipt_received(&local_peer_id, &mut acks, &event_sender, &request, Some(peer));
+                                            if swarm.behaviour_mut().receipts.send_response(channel, awaited).is_err() {
+                                                tracing::debug!("Receipt sender {} went away before the response", peer);
+                                            }
+                                        }
+                                        request_response::Event::Message {
+                                            message: request_response::Message::Response { request_id, .. }, ..
+                                        } => {
+                                            pending_receipts.remove(&request_id);
+                                        }
+                                        request_response::Event::OutboundFailure { peer, request_id, error, .. } => {
+                                            quality.send_failed(&peer);
+                                            if let Some(receipt) = pending_receipts.remove(&request_id) {
+                                                tracing::debug!("Sending a receipt to {} failed ({}), publishing it instead", peer, error);
+                                                publish_receipt(&mut swarm, &docstore_config, &traffic, receipt);
+                                            }
+                                        }
+                                        request_response::Event::InboundFailure { peer, error, .. } => {
+                                            tracing::debug!("Receipt from {} failed: {}", peer, error);
+                                        }
+                                        request_response::Event::ResponseSent { .. } => {}
+                                    }
+                                } else if let MyBehaviourEvent::Rendezvous(rendezvous_evt) = beh_event {
+                                    let now = web_time::Instant::now();
+                                    match RendezvousBehaviour::on_event(rendezvous_evt) {
+                                        Some(RendezvousEvent::Registered { point, ttl }) => {
+                                            tracing::debug!("Registered with rendezvous point {} for {:?}", point, ttl);
+                                            rendezvous.registered(&point, ttl, now);
+                                        }
+                                        Some(RendezvousEvent::RegisterFailed { point, error }) => {
+                                            tracing::warn!("Rendezvous point {} refused our registration: {}", point, error);
+                                            rendezvous.register_failed(&point, now);
+                                        }
+                                        Some(RendezvousEvent::Discovered { point, registrations, cookie }) => {
+                                            let discovered = rendezvous.discovered(&point, registrations, cookie, now);
+                                            let unconnected = discovered.new.iter().filter(|r| !swarm.is_connected(&r.peer_id));
+                                            let planner = DialPlanner { address_book: &address_book, reputation: &reputation, event_sender: &event_sender };
+                                            for registrant in unconnected.take(DEFAULT_REGISTRANTS_TO_DIAL).cloned().collect::<Vec<_>>() {
+                                                match dial_planned(&mut swarm, &planner, registrant.peer_id, registrant.addrs) {
+                                                    Ok(()) => {
+                                                        swarm.behaviour_mut().gossipsub.add_explicit_peer(&registrant.peer_id);
+                                                        topic_health.add_explicit(registrant.peer_id);
+                                                    }
+                                                    Err(e) => tracing::debug!("Dialing registrant {} failed: {}", registrant.peer_id, e),
+                                                }
+                                            }
+                                            for registrant in discovered.new {
+                                                let _ = event_sender.unbounded_send(Event::PeerDiscovery {
+                                                    peer_id: registrant.peer_id.to_string(),
+                                                    addrs: registrant.addrs.iter().map(ToString::to_string).collect(),
+                                                });
+                                            }
+                                            if discovered.more {
+                                                rendezvous_discover(&mut swarm, &mut rendezvous, &rendezvous_namespace, point);
+                                            }
+                                        }
+                                        Some(RendezvousEvent::DiscoverFailed { point, error }) => {
+                                            tracing::debug!("Discovery through rendezvous point {} failed: {}", point, error);
+                                            rendezvous.discovery_failed(&point);
+                                        }
+                                        Some(RendezvousEvent::Expired { peer }) => {
+                                            rendezvous.expired(&peer);
+                                            swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer);
+                                            topic_health.remove_explicit(&peer);
+                                        }
+                                        None => {}
+                                    }
+                                    answer_rendezvous_waiters(&mut rendezvous, &mut rendezvous_waiters);
+                                } else if let MyBehaviourEvent::KeepAlive(keep_alive_evt) = beh_event {
+                                    keep_alive::handle_event(&mut swarm.behaviour_mut().keep_alive, keep_alive_evt);
+                                } else if let MyBehaviourEvent::Interest(interest_evt) = beh_event {
+                                    match interest_evt {
+                                        request_response::Event::Message {
+                                            peer,
+                                            message: request_response::Message::Response { response, .. },
+                                            ..
+                                        } if !response.accepted => {
+                                            tracing::debug!("{} refused our topic interest hint", peer);
+                                        }
+                                        request_response::Event::OutboundFailure { peer, error, .. } => {
+                                            quality.send_failed(&peer);
+                                            tracing::debug!("Sending topic interest to {} failed: {}", peer, error);
+                                        }
+                                        _ => {}
+                                    }
+                                } else if let MyBehaviourEvent::Peers(peers_evt) = beh_event {
+                                    match peers_evt {
+                                        request_response::Event::Message {
+                                            peer,
+                                            message: request_response::Message::Response { response, .. },
+                                            ..
+                                        } => {
+                                            let dials = peer_exchange.listed(peer, response, |p| swarm.is_connected(p));
+                                            let relay_addr = {
+                                                let state = shared_state_clone.lock().await;
+                                                state.relays.iter().find(|r| r.peer_id == peer.to_string()).and_then(|r| r.full_addr.parse().ok())
+                                            };
+                                            let planner = DialPlanner { address_book: &address_book, reputation: &reputation, event_sender: &event_sender };
+                                            for dial in dials {
+                                                let relay_addr = relay_addr.clone().or_else(|| relay_address.clone());
+                                                dial_listed(&mut swarm, &planner, &mut peer_exchange, relay_addr, dial);
+                                            }
+                                        }
+                                        request_response::Event::OutboundFailure { peer, error, .. } => {
+                                            quality.send_failed(&peer);
+                                            tracing::debug!("Asking {} for its peers failed: {}", peer, error);
                                         }
                                         _ => {}
                                     }
@@ -392,18 +4513,257 @@ impl WasmNode {
                                     match &beh_event {
                                         MyBehaviourEvent::Gossipsub(GossipsubEvent::Message { 
                                             propagation_source, 
+                                            message_id,
                                             message, 
-                                            .. 
                                         }) => {
-                                            let data = String::from_utf8_lossy(&message.data).to_string();
-                                            log(&format!("Received message from {}: {}", propagation_source, data));
-                                            let _ = event_sender.unbounded_send(Event::MessageReceived {
-                                                peer_id: propagation_source.to_string(),
-                                                data,
-                                            });
+                                            traffic.record_in(message, propagation_source);
+                                            // Rooms route their own messages; the pipeline only judges them
+                                            let room = rooms.route(&message.topic);
+                                            let outputs = if room.is_some() {
+                                                pipeline.validate(&ledger, *propagation_source, message)
+                                            } else {
+                                                pipeline.handle_incoming(&mut ledger, *propagation_source, message)
+                                            };
+                                            let mut accepted = false;
+                                            for output in outputs {
+                                                match output {
+                                                    Incoming::Verdict(acceptance) => {
+                                                        accepted = matches!(acceptance, gossipsub::MessageAcceptance::Accept);
+                                                        let rejected = matches!(acceptance, gossipsub::MessageAcceptance::Reject);
+                                                        crate::behaviour::docstore::report_validation(
+                                                            &mut swarm.behaviour_mut().gossipsub, message_id, propagation_source, acceptance,
+                                                        );
+                                                        if accepted {
+                                                            traffic.record_forward(&swarm.behaviour().gossipsub, message, propagation_source);
+                                                        }
+                                                        if rejected {
+                                                            tracing::warn!("Rejected invalid message {} from {}", message_id, propagation_source);
+                                                            reputation.record(*propagation_source, PeerSignal::InvalidMessage, web_time::Instant::now());
+                                                        }
+                                                    }
+                                                    Incoming::UnsupportedVersion { peer_id, version } => {
+                                                        let _ = event_sender.unbounded_send(Event::UnsupportedVersion {
+                                                            peer_id: peer_id.to_string(),
+                                                            version,
+                                                        });
+                                                    }
+                                                    // The ledger keeps no store, so it has no quotas to go over
+                                                    // and no versions for CAS updates to conflict with
+                                                    Incoming::QuotaExceeded { .. } | Incoming::CasConflict { .. } => {}
+                                                    Incoming::SchemaViolation { peer_id, violation } => {
+                                                        let _ = event_sender.unbounded_send(Event::SchemaViolation {
+                                                            peer_id: peer_id.to_string(),
+                                                            doc_id: violation.doc_id,
+                                                            prefix: violation.prefix,
+                                                            version: violation.version,
+                                                            reason: violation.reason,
+                                                        });
+                                                    }
+                                                    Incoming::Announcement { peer_id, announcement: a } => {
+                                                        let _ = event_sender.unbounded_send(Event::Announcement {
+                                                            peer_id: peer_id.to_string(),
+                                                            kind: a.kind.as_str(),
+                                                            severity: a.severity.as_str(),
+                                                            text: a.text,
+                                                            issued_at_ms: a.issued_at_ms,
+                                                            expires_at_ms: a.expires_at_ms,
+                                                        });
+                                                    }
+                                                    Incoming::Receipt(receipt) => {
+                                                        receipt_received(&local_peer_id, &mut acks, &event_sender, &receipt, None);
+                                                    }
+                                                    Incoming::SnapshotInstalled(snapshot) => {
+                                                        if seeds.observe(&snapshot.doc_id, snapshot.version) == SeedCheck::Overridden {
+                                                            let _ = event_sender.unbounded_send(Event::SeedOverridden {
+                                                                doc_id: snapshot.doc_id.clone(),
+                                                                version: snapshot.version,
+                                                            });
+                                                        }
+                                                        if ordering.is_watched(&snapshot.doc_id) {
+                                                            let clock = ledger.clock_mut(&snapshot.doc_id).clone();
+                                                            let outputs = ordering.snapshot(
+                                                                &snapshot.doc_id,
+                                                                snapshot.bytes.clone(),
+                                                                &clock,
+                                                                web_time::Instant::now(),
+                                                            );
+                                                            ordered_outputs.extend(sourced(outputs, Some(*propagation_source)));
+                                                        }
+                                                        let _ = event_sender.unbounded_send(Event::SnapshotReceived {
+                                                            topic: message.topic.to_string(),
+                                                            doc_id: snapshot.doc_id,
+                                                            version: snapshot.version,
+                                                            data: String::from_utf8_lossy(&snapshot.bytes).to_string(),
+                                                        });
+                                                    }
+                                                    Incoming::UpdateApplied { update, version, ack_to } => {
+                                                        connection_keeper.hold(&keeper::doc_session(&update.doc_id), *propagation_source);
+                                                        // Live edits are always delivered; they only replace a pinned seed once past it
+                                                        if seeds.observe(&update.doc_id, version) == SeedCheck::Overridden {
+                                                            let _ = event_sender.unbounded_send(Event::SeedOverridden {
+                                                                doc_id: update.doc_id.clone(),
+                                                                version,
+                                                            });
+                                                        }
+                                                        if let Some(publisher) = ack_to {
+                                                            send_receipt(
+                                                                &mut swarm,
+                                                                &mut pending_receipts,
+                                                                &docstore_config,
+                                                                &traffic,
+                                                                &local_key,
+                                                                publisher,
+                                                                message_id,
+                                                                &update.doc_id,
+                                                                version,
+                                                            );
+                                                        }
+                                                        if ordering.is_watched(&update.doc_id) {
+                                                            let outputs = ordering.push(update.clone(), Origin::Live, web_time::Instant::now());
+                                                            ordered_outputs.extend(sourced(outputs, Some(*propagation_source)));
+                                                        }
+                                                        // A replay of an update delivered before a reload
+                                                        if !dedup.seen(&update.doc_id, update.stamp.as_ref()) {
+                                                            dedup.delivered(&update.doc_id, update.stamp.as_ref());
+                                                            let _ = event_sender.unbounded_send(Event::DocUpdateReceived {
+                                                                peer_id: propagation_source.to_string(),
+                                                                topic: message.topic.to_string(),
+                                                                doc_id: update.doc_id,
+                                                                data: String::from_utf8_lossy(&update.payload).to_string(),
+                                                            });
+                                                        }
+                                                    }
+                                                    Incoming::TransactionApplied { id, doc_ids } => {
+                                                        let _ = event_sender.unbounded_send(Event::TransactionApplied {
+                                                            peer_id: propagation_source.to_string(),
+                                                            id: id.to_string(),
+                                                            doc_ids,
+                                                        });
+                                                    }
+                                                    Incoming::Message => {
+                                                        let data = String::from_utf8_lossy(&message.data).to_string();
+                                                        tracing::debug!("Received message from {}: {}", propagation_source, data);
+                                                        let _ = event_sender.unbounded_send(Event::MessageReceived {
+                                                            peer_id: propagation_source.to_string(),
+                                                            topic: message.topic.to_string(),
+                                                            msg_id: message_id.to_string(),
+                                                            data,
+                                                        });
+                                                    }
+                                                }
+                                            }
+                                            if let Some((room_id, channel)) = room.filter(|_| accepted) {
+                                                connection_keeper.hold(&keeper::room_session(room_id), *propagation_source);
+                                                if let Some(data) = admit_room_message(&mut room_auth, room_id, channel, message.source, &message.data) {
+                                                    let _ = event_sender.unbounded_send(Event::RoomMessage {
+                                                        room_id: room_id.to_string(),
+                                                        channel,
+                                                        peer_id: message.source.unwrap_or(*propagation_source).to_string(),
+                                                        data: String::from_utf8_lossy(&data).to_string(),
+                                                    });
+                                                }
+                                            }
+                                        }
+                                        MyBehaviourEvent::Ephemeral(GossipsubEvent::Message {
+                                            propagation_source,
+                                            message,
+                                            ..
+                                        }) => {
+                                            // Ephemeral messages are validated and forwarded by gossipsub itself
+                                            traffic.record_in(message, propagation_source);
+                                            traffic.record_forward(&swarm.behaviour().ephemeral, message, propagation_source);
+                                            let (_, payload) = scope::unframe_ephemeral(&message.data);
+                                            if let Some((room_id, channel)) = rooms.route(&message.topic) {
+                                                connection_keeper.hold(&keeper::room_session(room_id), *propagation_source);
+                                                if let Some(data) = admit_room_message(&mut room_auth, room_id, channel, message.source, payload) {
+                                                    let author = message.source.unwrap_or(*propagation_source);
+                                                    let (display_name, data) = match channel {
+                                                        RoomChannel::Presence => profile::untag_presence(&data),
+                                                        _ => (None, data.as_slice()),
+                                                    };
+                                                    if channel == RoomChannel::Presence
+                                                        && presence_tracker.seen(room_id, author, get_timestamp_ms() as u64)
+                                                    {
+                                                        let _ = event_sender.unbounded_send(Event::MemberJoined {
+                                                            room_id: room_id.to_string(),
+                                                            peer_id: author.to_string(),
+                                                            display_name,
+                                                        });
+                                                    }
+                                                    let _ = event_sender.unbounded_send(Event::RoomMessage {
+                                                        room_id: room_id.to_string(),
+                                                        channel,
+                                                        peer_id: author.to_string(),
+                                                        data: String::from_utf8_lossy(&data).to_string(),
+                                                    });
+                                                }
+                                            } else if let Some(doc_id) = docstore_config.topics.ephemeral_doc_id(&message.topic) {
+                                                let _ = event_sender.unbounded_send(Event::EphemeralReceived {
+                                                    peer_id: propagation_source.to_string(),
+                                                    topic: message.topic.to_string(),
+                                                    doc_id: doc_id.to_string(),
+                                                    data: String::from_utf8_lossy(payload).to_string(),
+                                                });
+                                            }
+                                        }
+                                        MyBehaviourEvent::Ping(ping::Event { peer, result, .. }) => {
+                                            let signal = match result {
+                                                Ok(rtt) => PeerSignal::Ping { rtt: *rtt },
+                                                Err(_) => PeerSignal::PingFailed,
+                                            };
+                                            reputation.record(*peer, signal, web_time::Instant::now());
+                                            if let Some(change) = quality.ping(*peer, result.as_ref().ok().copied()) {
+                                                let _ = event_sender.unbounded_send(Event::QualityChanged {
+                                                    peer_id: peer.to_string(),
+                                                    from: change.from,
+                                                    to: change.to,
+                                                    score: change.score,
+                                                });
+                                            }
+                                            if let (Ok(rtt), true) = (result, relay_ranking.is_relay(peer)) {
+                                                let change = relay_ranking.record_rtt(*peer, *rtt);
+                                                apply_relay_change(&mut swarm, &change);
+                                                show_relay_ranking(&mut *shared_state_clone.lock().await, &relay_ranking);
+                                            }
+                                            match ping_failures.record(*peer, result.is_ok()) {
+                                                PingAction::Unresponsive => {
+                                                    let failures = ping_failures.policy().unresponsive_after;
+                                                    tracing::info!("{} missed {} pings in a row", peer, failures);
+                                                    // Stop pushing messages into what is likely a dead connection
+                                                    swarm.behaviour_mut().gossipsub.remove_explicit_peer(peer);
+                                                    topic_health.remove_explicit(peer);
+                                                    let change = relay_ranking.disconnected(peer);
+                                                    apply_relay_change(&mut swarm, &change);
+                                                    show_relay_ranking(&mut *shared_state_clone.lock().await, &relay_ranking);
+                                                    let _ = event_sender.unbounded_send(Event::PeerUnresponsive {
+                                                        peer_id: peer.to_string(),
+                                                        failures,
+                                                    });
+                                                }
+                                                PingAction::Disconnect => {
+                                                    tracing::info!("Closing connection to unresponsive peer {}", peer);
+                                                    let _ = swarm.disconnect_peer_id(*peer);
+                                                    // Explicit peers that aren't connected are redialed by gossipsub
+                                                    if relay_ranking.is_relay(peer) {
+                                                        swarm.behaviour_mut().gossipsub.add_explicit_peer(peer);
+                                                        topic_health.add_explicit(*peer);
+                                                    }
+                                                }
+                                                PingAction::Recovered => {
+                                                    tracing::debug!("{} answers pings again", peer);
+                                                    if relay_ranking.is_relay(peer) {
+                                                        swarm.behaviour_mut().gossipsub.add_explicit_peer(peer);
+                                                        topic_health.add_explicit(*peer);
+                                                        let change = relay_ranking.connected(*peer);
+                                                        apply_relay_change(&mut swarm, &change);
+                                                        show_relay_ranking(&mut *shared_state_clone.lock().await, &relay_ranking);
+                                                    }
+                                                }
+                                                PingAction::None => {}
+                                            }
                                         }
                                         MyBehaviourEvent::Identify(identify::Event::Received { peer_id, info, .. }) => {
-                                            log(&format!("Identify Received for peer {}: addresses: {:?}", peer_id, info.listen_addrs));
+                                            tracing::debug!("Identify Received for peer {}: addresses: {:?}", peer_id, info.listen_addrs);
                                             
                                             // Check if this peer supports relay protocol
                                             let supports_relay = info.protocols.iter().any(|p| {
@@ -411,19 +4771,69 @@ impl WasmNode {
                                             });
                                             
                                             if supports_relay {
-                                                log(&format!("✓ Peer {} supports Circuit Relay", peer_id));
+                                                tracing::debug!("✓ Peer {} supports Circuit Relay", peer_id);
                                             } else {
-                                                log(&format!("⚠ Peer {} does NOT support Circuit Relay", peer_id));
+                                                tracing::debug!("⚠ Peer {} does NOT support Circuit Relay", peer_id);
                                             }
                                             
                                             // Update relay info if this is a known relay, or add it if it supports relay
                                             let mut state = shared_state_clone.lock().await;
+                                            let peer_info = PeerInfo::from(&info);
+                                            if peer_info.supports(HISTORY_PROTOCOL) {
+                                                start_catch_up(&mut swarm, &mut catch_up, Some(peer_id), &budget);
+                                            }
+                                            if peer_info.supports(rendezvous_behaviour::RENDEZVOUS_PROTOCOL)
+                                                && swarm.behaviour().rendezvous.is_client()
+                                                && rendezvous.add_point(peer_id)
+                                            {
+                                                tracing::info!("Registering with rendezvous point {}", peer_id);
+                                                rendezvous_register(&mut swarm, &mut rendezvous, &rendezvous_namespace, peer_id);
+                                                rendezvous_discover(&mut swarm, &mut rendezvous, &rendezvous_namespace, peer_id);
+                                            }
+                                            if peer_info.supports(PEERS_PROTOCOL) && peer_exchange.add_relay(peer_id) {
+                                                ask_for_peers(&mut swarm, &peer_exchange, peer_id);
+                                            }
+                                            if let Some(hint) = &topic_hint {
+                                                let known = state.peer_infos.get(&peer_id).is_some_and(|i| i.supports(INTEREST_PROTOCOL));
+                                                if peer_info.supports(INTEREST_PROTOCOL) && !known {
+                                                    send_interest(&mut swarm, [peer_id], hint);
+                                                }
+                                            }
+                                            match relay_discovery.identified(&peer_id, &peer_info) {
+                                                RelayCheck::Verified => {
+                                                    add_discovered_relay(&mut swarm, &mut relay_ranking, &mut topic_health, &mut state, peer_id);
+                                                    let _ = event_sender.unbounded_send(Event::RelayDiscovered { peer_id: peer_id.to_string() });
+                                                }
+                                                RelayCheck::Rejected => {
+                                                    tracing::warn!("{} is registered as a relay but doesn't serve the hop protocol", peer_id);
+                                                }
+                                                RelayCheck::NotACandidate => {}
+                                            }
+                                            if state.peer_infos.update(peer_id, peer_info.clone()) {
+                                                // Pick up what was left for us while we were away
+                                                if peer_info.supports(MAILBOX_PROTOCOL) {
+                                                    let id = swarm.behaviour_mut().mailbox.send_request(&peer_id, MailboxRequest::Fetch);
+                                                    pending_mailbox.insert(id, PendingMailbox::Fetch(None));
+                                                }
+                                                // A FullNode we just met learns our rooms now rather than at the next heartbeat
+                                                if peer_info.supports(MEMBERS_PROTOCOL) {
+                                                    for (room_id, frame) in &room_presence {
+                                                        report_presence(&mut swarm, &[peer_id], &room_auth, &private_rooms, room_id, frame);
+                                                    }
+                                                }
+                                                let _ = event_sender.unbounded_send(Event::PeerIdentified {
+                                                    peer_id: peer_id.to_string(),
+                                                    shared_protocols: peer_info.shared_protocols(&local_protocols),
+                                                    agent_version: peer_info.agent_version,
+                                                    protocols: peer_info.protocols,
+                                                });
+                                            }
                                             if let Some(relay_info) = state.relays.iter_mut().find(|r| r.peer_id == peer_id.to_string()) {
                                                 relay_info.supports_relay = supports_relay;
                                                 relay_info.connected_at = get_timestamp_ms();
                                                 
                                                 if !supports_relay {
-                                                    log(&format!("❌ ERROR: Server {} does not support relay functionality!", peer_id));
+                                                    tracing::error!("❌ ERROR: Server {} does not support relay functionality!", peer_id);
                                                     let _ = event_sender.unbounded_send(Event::Error {
                                                         msg: format!("Server does not support Circuit Relay protocol - browser-to-browser communication will not work")
                                                     });
@@ -452,25 +4862,51 @@ impl WasmNode {
                                                     full_addr: full_addr.clone(),
                                                     connected_at: get_timestamp_ms(),
                                                     supports_relay: true,
+                                                    rtt_ms: None,
+                                                    preferred: false,
                                                 });
                                                 
-                                                log(&format!("✓ Auto-detected and added relay: {} ({})", peer_id_str, full_addr));
+                                                tracing::info!("✓ Auto-detected and added relay: {} ({})", peer_id_str, full_addr);
                                             }
                                             
                                             // Add addresses to Kademlia
-                                            for addr in &info.listen_addrs {
-                                                swarm.behaviour_mut().kademlia.add_address(peer_id, addr.clone());
-                                                log(&format!("Added address {} for peer {} to Kademlia", addr, peer_id));
+                                            let local = *swarm.local_peer_id();
+                                            for addr in info.listen_addrs.iter().filter(|a| crate::node::addrs::is_peer_addr(&local, peer_id, a)) {
+                                                address_book.observe(*peer_id, addr, get_timestamp_ms() as u64);
+                                                if let Some(kademlia) = swarm.behaviour_mut().kademlia.as_mut() {
+                                                    kademlia.add_address(peer_id, addr.clone());
+                                                }
+                                                tracing::debug!("Added address {} for peer {} to Kademlia", addr, peer_id);
                                             }
+                                            // Only now can queries reach the peer
+                                            renew_announcements(&mut swarm, &mut announcements, &event_sender);
                                         }
                                         MyBehaviourEvent::Kademlia(evt) => {
+                                            traffic.observer().kad_event(&evt);
                                             match evt {
-                                                KademliaEvent::OutboundQueryProgressed { id, result, .. } => {
+                                                KademliaEvent::OutboundQueryProgressed { id, result, step, .. } => {
                                                     match result {
-                                                        QueryResult::GetClosestPeers(Ok(ok)) => {
-                                                            log(&format!("Kademlia get_closest_peers {:?} => {:?}", id, ok.peers));
+                                                        QueryResult::Bootstrap(result) if Some(id) == bootstrap_query => {
+                                                            if result.is_err() || step.last {
+                                                                tracing::debug!("Initial Kademlia bootstrap finished: {:?}", result.as_ref().map(|_| ()));
+                                                                dht_bootstrap = if result.is_ok() { DhtBootstrap::Done } else { DhtBootstrap::Failed };
+                                                                bootstrap_query = None;
+                                                            }
+                                                        }
+                                                        QueryResult::GetClosestPeers(result) => {
+                                                            // A timed out query still reports the closest peers it got to
+                                                            let peers = match result {
+                                                                Ok(ok) => ok.peers,
+                                                                Err(libp2p_kad::GetClosestPeersError::Timeout { peers, .. }) => {
+                                                                    tracing::warn!("Kademlia get_closest_peers {:?} timed out", id);
+                                                                    peers
+                                                                }
+                                                            };
+                                                            // Our own circuit address comes back once a peer has seen it
+                                                            let peers = crate::node::addrs::without_self(swarm.local_peer_id(), peers, |p| p.peer_id);
+                                                            tracing::debug!("Kademlia get_closest_peers {:?} => {:?}", id, peers);
                                                             let mut state = shared_state_clone.lock().await;
-                                                            for p in ok.peers.iter() {
+                                                            for p in peers.iter() {
                                                                 let addrs: Vec<String> = p.addrs.iter().map(|a| a.to_string()).collect();
                                                                 let _ = event_sender.unbounded_send(Event::PeerDiscovery {
                                                                     peer_id: p.peer_id.to_string(),
@@ -479,15 +4915,150 @@ impl WasmNode {
                                                                 // Update shared state
                                                                 state.discovered_peers.insert(p.peer_id.to_string(), addrs);
                                                             }
+                                                            drop(state);
+                                                            if let Some((target, dial, reply)) = pending_finds.remove(&id) {
+                                                                let mut found: Vec<FoundPeer> = peers
+                                                                    .into_iter()
+                                                                    .map(|p| FoundPeer::new(p.peer_id, p.addrs, crate::node::addrs::is_browser_dialable))
+                                                                    .collect();
+                                                                if let Some(pos) = found.iter().position(|p| p.peer_id == target) {
+                                                                    let target = found.remove(pos);
+                                                                    // Circuit addresses need the relay signalling done by dial_peer
+                                                                    let direct: Vec<Multiaddr> = target
+                                                                        .dialable
+                                                                        .iter()
+                                                                        .filter(|a| !a.iter().any(|p| p == Protocol::P2pCircuit))
+                                                                        .cloned()
+                                                                        .collect();
+                                                                    if dial && !direct.is_empty() && !swarm.is_connected(&target.peer_id)
+                                                                        && !bans.is_banned(&target.peer_id, web_time::Instant::now())
+                                                                    {
+                                                                        let planner = DialPlanner {
+                                                                            address_book: &address_book,
+                                                                            reputation: &reputation,
+                                                                            event_sender: &event_sender,
+                                                                        };
+                                                                        if let Err(e) = dial_planned(&mut swarm, &planner, target.peer_id, direct) {
+                                                                            tracing::warn!("Dialing {} after lookup failed: {}", target.peer_id, e);
+                                                                        }
+                                                                    }
+                                                                    found.insert(0, target);
+                                                                }
+                                                                let _ = reply.send(found);
+                                                            }
                                                         }
-                                                        QueryResult::GetClosestPeers(Err(err)) => {
-                                                            log(&format!("Kademlia get_closest_peers {:?} error: {:?}", id, err));
+                                                        QueryResult::StartProviding(result) => {
+                                                            report_renewal(&event_sender, announcements.settle(Some(&id), result.is_ok()));
+                                                        }
+                                                        QueryResult::PutRecord(result) => {
+                                                            let result = result.map(|_| ()).map_err(|e| crate::Error::Dht(e.to_string()));
+                                                            match pending_head_puts.remove(&id) {
+                                                                Some((pointer, reply)) => {
+                                                                    let _ = reply.send(result.map(|()| pointer));
+                                                                }
+                                                                None if pending_profile_puts.contains_key(&id) => {
+                                                                    if let Some((profile, reply)) = pending_profile_puts.remove(&id) {
+                                                                        let _ = reply.send(result.map(|()| profile));
+                                                                    }
+                                                                }
+                                                                // A republish, or a renewal after an outage
+                                                                None => report_renewal(&event_sender, announcements.settle(Some(&id), result.is_ok())),
+                                                            }
+                                                        }
+                                                        QueryResult::GetRecord(result) => {
+                                                            if let Ok(libp2p_kad::GetRecordOk::FoundRecord(found)) = result {
+                                                                if let Some((_, _, values, _)) = pending_resolves.get_mut(&id) {
+                                                                    values.push(found.record.value);
+                                                                } else if let Some((_, values, _)) = pending_profiles.get_mut(&id) {
+                                                                    values.push(found.record.value);
+                                                                }
+                                                            }
+                                                            // Not found, or every copy reported
+                                                            if step.last {
+                                                                if let Some((author, doc_id, values, reply)) = pending_resolves.remove(&id) {
+                                                                    let resolved = head_pointers.resolve(&author, &doc_id, values.iter().map(Vec::as_slice));
+                                                                    let _ = reply.send(resolved.map_err(crate::Error::from));
+                                                                }
+                                                                if let Some((peer_id, values, reply)) = pending_profiles.remove(&id) {
+                                                                    let resolved = profiles.resolve(&peer_id, values.iter().map(Vec::as_slice), get_timestamp_ms() as u64);
+                                                                    let _ = reply.send(resolved.map_err(crate::Error::from));
+                                                                }
+                                                            }
+                                                        }
+                                                        QueryResult::GetProviders(result) => {
+                                                            let providers: Vec<PeerId> = match result {
+                                                                Ok(libp2p_kad::GetProvidersOk::FoundProviders { providers, .. }) => {
+                                                                    crate::node::addrs::without_self(swarm.local_peer_id(), providers, |p| *p)
+                                                                }
+                                                                Ok(libp2p_kad::GetProvidersOk::FinishedWithNoAdditionalRecord { .. }) => Vec::new(),
+                                                                // Providers found before the timeout were already reported
+                                                                Err(e) => {
+                                                                    tracing::debug!("Provider lookup {:?} ended: {}", id, e);
+                                                                    Vec::new()
+                                                                }
+                                                            };
+                                                            if let Some((lookup, _)) = pending_relay_lookups.get_mut(&id) {
+                                                                let now = web_time::Instant::now();
+                                                                let local = *swarm.local_peer_id();
+                                                                let candidates: Vec<PeerId> = providers
+                                                                    .into_iter()
+                                                                    .filter(|peer| !bans.is_banned(peer, now))
+                                                                    .filter(|peer| relay_discovery.found(lookup, *peer, &local))
+                                                                    .collect();
+                                                                let mut state = shared_state_clone.lock().await;
+                                                                for peer in candidates {
+                                                                    // Already identified: settle it now. Connected: wait for identify.
+                                                                    if let Some(info) = state.peer_infos.get(&peer).cloned() {
+                                                                        if relay_discovery.identified(&peer, &info) == RelayCheck::Verified {
+                                                                            add_discovered_relay(&mut swarm, &mut relay_ranking, &mut topic_health, &mut state, peer);
+                                                                            let _ = event_sender.unbounded_send(Event::RelayDiscovered { peer_id: peer.to_string() });
+                                                                        }
+                                                                        continue;
+                                                                    }
+                                                                    if swarm.is_connected(&peer) {
+                                                                        continue;
+                                                                    }
+                                                                    let opts = libp2p::swarm::dial_opts::DialOpts::peer_id(peer)
+                                                                        .extend_addresses_through_behaviour()
+                                                                        .build();
+                                                                    if let Err(e) = swarm.dial(opts) {
+                                                                        tracing::debug!("Dialing relay candidate {} failed: {}", peer, e);
+                                                                        relay_discovery.dial_failed(&peer);
+                                                                    }
+                                                                }
+                                                            }
+                                                            if step.last {
+                                                                if let Some((lookup, reply)) = pending_relay_lookups.remove(&id) {
+                                                                    let _ = reply.send(lookup.into_providers());
+                                                                }
+                                                            }
                                                         }
                                                         _ => {}
                                                     }
                                                 }
+                                                KademliaEvent::RoutingUpdated { peer, is_new_peer, addresses, old_peer, .. } => {
+                                                    let _ = event_sender.unbounded_send(Event::RoutingUpdated {
+                                                        peer_id: peer.to_string(),
+                                                        is_new_peer,
+                                                        addrs: addresses.iter().map(ToString::to_string).collect(),
+                                                        evicted: old_peer.map(|p| p.to_string()),
+                                                    });
+                                                }
+                                                KademliaEvent::UnroutablePeer { peer } => {
+                                                    let _ = event_sender.unbounded_send(Event::UnroutablePeer { peer_id: peer.to_string() });
+                                                }
+                                                KademliaEvent::RoutablePeer { peer, address }
+                                                | KademliaEvent::PendingRoutablePeer { peer, address } => {
+                                                    let _ = event_sender.unbounded_send(Event::RoutablePeer {
+                                                        peer_id: peer.to_string(),
+                                                        addr: address.to_string(),
+                                                    });
+                                                }
+                                                KademliaEvent::ModeChanged { new_mode } => {
+                                                    let _ = event_sender.unbounded_send(Event::DhtModeChanged { mode: new_mode.to_string() });
+                                                }
                                                 _ => {
-                                                    log(&format!("Kademlia event: {:?}", evt));
+                                                    tracing::trace!("Kademlia event: {:?}", evt);
                                                 }
                                             }
                                         }
@@ -495,46 +5066,151 @@ impl WasmNode {
                                     }
                                 }
                             }
-                            SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. } => {
+                            SwarmEvent::ConnectionEstablished { peer_id, connection_id, endpoint, .. } => {
+                                let dialed = pending_dials.addr(&connection_id).cloned();
+                                pending_dials.connected(&connection_id);
+                                shared_state_clone.lock().await.connections.established(peer_id, endpoint.is_dialer());
+                                let now = web_time::Instant::now();
+                                for peer in bans.expire(now) {
+                                    swarm.behaviour_mut().gossipsub.remove_blacklisted_peer(&peer);
+                                }
+                                if bans.is_banned(&peer_id, now) {
+                                    tracing::warn!("Rejecting connection from banned peer {}", peer_id);
+                                    let _ = swarm.disconnect_peer_id(peer_id);
+                                    let _ = event_sender.unbounded_send(Event::BannedPeerRejected {
+                                        peer_id: peer_id.to_string()
+                                    });
+                                    continue;
+                                }
+                                quality.connected(peer_id);
+                                topic_health.connected(get_timestamp_ms() as u64);
+                                if let libp2p::core::ConnectedPoint::Dialer { address, .. } = &endpoint {
+                                    address_book.record_success(peer_id, address, get_timestamp_ms() as u64);
+                                }
+                                let new_bootstrap = dialed.filter(|addr| bootstrap.connected(addr));
+                                let is_relay = new_bootstrap.is_some()
+                                    || relay_ranking.is_relay(&peer_id)
+                                    || relay_discovery.is_verified(&peer_id);
+                                if let Some(addr) = new_bootstrap {
+                                    tracing::info!("Bootstrap {} connected", addr);
+                                    swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+                                    topic_health.add_explicit(peer_id);
+                                    if dht_bootstrap == DhtBootstrap::Pending && bootstrap_query.is_none() {
+                                        if let Some(kademlia) = swarm.behaviour_mut().kademlia.as_mut() {
+                                            kademlia.add_address(&peer_id, addr.clone());
+                                            match kademlia.bootstrap() {
+                                                Ok(query) => bootstrap_query = Some(query),
+                                                Err(e) => {
+                                                    tracing::warn!("Kademlia bootstrap could not start: {}", e);
+                                                    dht_bootstrap = DhtBootstrap::Failed;
+                                                }
+                                            }
+                                        }
+                                    }
+                                    let mut state = shared_state_clone.lock().await;
+                                    if !state.relays.iter().any(|r| r.peer_id == peer_id.to_string()) {
+                                        state.relays.push(RelayInfo {
+                                            peer_id: peer_id.to_string(),
+                                            full_addr: addr.to_string(),
+                                            connected_at: get_timestamp_ms(),
+                                            supports_relay: false, // Will be validated on Identify event
+                                            rtt_ms: None,
+                                            preferred: false,
+                                        });
+                                    }
+                                    drop(state);
+                                    settle_bootstrap(&mut bootstrap, &mut bootstrap_ready);
+                                }
+                                if is_relay {
+                                    let change = relay_ranking.connected(peer_id);
+                                    apply_relay_change(&mut swarm, &change);
+                                    show_relay_ranking(&mut *shared_state_clone.lock().await, &relay_ranking);
+                                }
+                                let transport = crate::node::addrs::transport_name(endpoint.get_remote_address());
+                                let direction = crate::node::connections::direction(&endpoint);
+                                traffic.record_connection(transport);
                                 let remote_addr = endpoint.get_remote_address().to_string();
                                 
                                 // Distinguish between different connection types
                                 if remote_addr.contains("/webrtc") && !remote_addr.contains("/p2p-circuit") {
-                                    log(&format!("✅ Direct WebRTC connection established with {}", peer_id));
+                                    tracing::info!("✅ Direct WebRTC connection established with {}", peer_id);
                                     let _ = event_sender.unbounded_send(Event::WebRTCConnectionEstablished {
-                                        peer_id: peer_id.to_string()
+                                        peer_id: peer_id.to_string(),
+                                        transport,
+                                        direction,
                                     });
                                 } else if remote_addr.contains("/p2p-circuit") {
-                                    log(&format!("🔗 Relay connection established with {} via {}", peer_id, remote_addr));
+                                    tracing::info!("🔗 Relay connection established with {} via {}", peer_id, remote_addr);
                                     let _ = event_sender.unbounded_send(Event::RelayConnectionEstablished {
-                                        peer_id: peer_id.to_string()
+                                        peer_id: peer_id.to_string(),
+                                        transport,
+                                        direction,
                                     });
                                 } else {
-                                    log(&format!("Connected to {peer_id}"));
+                                    tracing::info!("Connected to {peer_id} over {transport}");
                                     let _ = event_sender.unbounded_send(Event::Connected {
-                                        peer_id: peer_id.to_string()
+                                        peer_id: peer_id.to_string(),
+                                        transport,
+                                        direction,
                                     });
                                 }
                                 
+                                if peer_exchange.connected(&peer_id) {
+                                    tracing::info!("Connected to {}, listed by a relay; gossiping directly", peer_id);
+                                    swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+                                    topic_health.add_explicit(peer_id);
+                                }
+
                                 // Update shared state
                                 let mut state = shared_state_clone.lock().await;
                                 let addrs = vec![remote_addr];
                                 state.connected_peers.insert(peer_id.to_string(), addrs);
                             }
-                            SwarmEvent::ConnectionClosed { peer_id, .. } => {
-                                log(&format!("Disconnected from {peer_id}"));
+                            SwarmEvent::ConnectionClosed { peer_id, num_established, .. } => {
+                                tracing::info!("Disconnected from {peer_id}");
                                 let _ = event_sender.unbounded_send(Event::Disconnected {
                                     peer_id: peer_id.to_string()
                                 });
                                 // Update shared state
                                 let mut state = shared_state_clone.lock().await;
+                                state.connections.closed(&peer_id, num_established);
                                 state.connected_peers.remove(&peer_id.to_string());
+                                if num_established == 0 {
+                                    let served_history = state.peer_infos.get(&peer_id).is_some_and(|i| i.supports(HISTORY_PROTOCOL));
+                                    state.peer_infos.remove(&peer_id);
+                                    // Lost touch with every peer serving history: catch up again on reconnect
+                                    if served_history && state.peer_infos.supporting(HISTORY_PROTOCOL).is_empty() {
+                                        catch_up.reset();
+                                    }
+                                    ping_failures.forget(&peer_id);
+                                    quality.disconnected(&peer_id);
+                                    rendezvous.remove_point(&peer_id);
+                                    answer_rendezvous_waiters(&mut rendezvous, &mut rendezvous_waiters);
+                                    if peer_exchange.disconnected(&peer_id) {
+                                        swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
+                                        topic_health.remove_explicit(&peer_id);
+                                    }
+                                    if swarm.connected_peers().next().is_none() {
+                                        announcements.offline(web_time::Instant::now());
+                                    }
+                                    let change = relay_ranking.disconnected(&peer_id);
+                                    apply_relay_change(&mut swarm, &change);
+                                    show_relay_ranking(&mut state, &relay_ranking);
+                                }
                             }
                             SwarmEvent::NewListenAddr { address, .. } => {
-                                log(&format!("Listening on {address}"));
+                                tracing::info!("Listening on {address}");
                                 
                                 // Check if this is a relay reservation (contains P2pCircuit)
                                 if address.iter().any(|p| matches!(p, Protocol::P2pCircuit)) {
+                                    // The circuit is how others reach us; it is what we register at rendezvous points
+                                    if swarm.behaviour().rendezvous.is_client() {
+                                        swarm.add_external_address(address.clone());
+                                    }
+                                    // Relays list the circuit too
+                                    for relay in peer_exchange.relays() {
+                                        ask_for_peers(&mut swarm, &peer_exchange, relay);
+                                    }
                                     // This is our relay reservation! Construct the full WebRTC address
                                     if let Some(ref relay_addr) = relay_address {
                                         let webrtc_reservation_addr = format!(
@@ -542,7 +5218,7 @@ impl WasmNode {
                                             relay_addr,
                                             local_peer_id_for_events
                                         );
-                                        log(&format!("🎉 Relay reservation created: {}", webrtc_reservation_addr));
+                                        tracing::info!("🎉 Relay reservation created: {}", webrtc_reservation_addr);
                                         let _ = event_sender.unbounded_send(Event::RelayReservationCreated {
                                             addr: webrtc_reservation_addr
                                         });
@@ -556,63 +5232,1391 @@ impl WasmNode {
                                     state.listen_addrs.push(addr_str);
                                 }
                             }
-                            SwarmEvent::Dialing { peer_id, .. } => {
-                                log(&format!("Dialing {:?}", peer_id));
+                            SwarmEvent::Dialing { peer_id, connection_id } => {
+                                let addr = pending_dials.addr(&connection_id);
+                                tracing::debug!("Dialing {:?} at {:?}", peer_id, addr);
+                                if let Some(peer) = peer_id {
+                                    shared_state_clone.lock().await.connections.dialing(peer);
+                                }
+                                let _ = event_sender.unbounded_send(Event::Dialing {
+                                    peer_id: peer_id.map(|p| p.to_string()),
+                                    addr: addr.map(ToString::to_string),
+                                });
+                            }
+                            SwarmEvent::IncomingConnection { send_back_addr, .. } => {
+                                tracing::debug!("Incoming connection from {}", send_back_addr);
+                                let _ = event_sender.unbounded_send(Event::IncomingConnection {
+                                    addr: send_back_addr.to_string(),
+                                });
                             }
-                            SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
-                                log(&format!("Connection error to {:?}: {}", peer_id, error));
-                                let _ = event_sender.unbounded_send(Event::Error {
-                                    msg: format!("Connection error: {}", error)
+                            SwarmEvent::OutgoingConnectionError { peer_id, connection_id, error } => {
+                                tracing::warn!("Connection error to {:?}: {}", peer_id, error);
+                                if let Some(peer) = peer_id {
+                                    relay_discovery.dial_failed(&peer);
+                                }
+                                let reason = DialFailure::from(&error);
+                                let now = web_time::Instant::now();
+                                // Peers that keep failing are retried less eagerly
+                                let failing = peer_id.or_else(|| pending_dials.addr(&connection_id).and_then(crate::node::addrs::peer_id_of));
+                                let factor = failing.map_or(1, |peer| {
+                                    reputation.record(peer, PeerSignal::DialFailed, now);
+                                    reputation.retry_backoff_factor(&peer, now)
                                 });
+                                let attempts = match pending_dials.failed_slowed(&connection_id, reason, now, factor) {
+                                    DialOutcome::Retry { .. } => None,
+                                    DialOutcome::GaveUp { addr, attempts } => {
+                                        bootstrap_failed(&mut bootstrap, &mut bootstrap_ready, &event_sender, &addr, attempts, error.to_string());
+                                        Some(attempts)
+                                    }
+                                    // Dials started by the behaviours themselves
+                                    DialOutcome::Unknown => Some(1),
+                                };
+                                if let Some(due) = pending_dials.next_due() {
+                                    retry_timer = futures_timer::Delay::new(due.saturating_duration_since(now)).fuse();
+                                }
+                                if let Some(peer) = &peer_id {
+                                    shared_state_clone.lock().await.connections.dial_failed(peer);
+                                    peer_exchange.dial_failed(peer);
+                                }
+                                if let (Some(peer), libp2p::swarm::DialError::Transport(failed)) = (peer_id, &error) {
+                                    for (addr, _) in failed {
+                                        if address_book.record_failure(&peer, addr) >= crate::node::address_book::MAX_FAILURES {
+                                            tracing::info!("Dropping {} of {} after repeated dial failures", addr, peer);
+                                            if let Some(kademlia) = swarm.behaviour_mut().kademlia.as_mut() {
+                                                kademlia.remove_address(&peer, addr);
+                                            }
+                                            address_book.remove_address(&peer, addr);
+                                            let _ = event_sender.unbounded_send(Event::AddressRemoved {
+                                                peer_id: peer.to_string(),
+                                                addr: addr.to_string(),
+                                                reason: RemovalReason::DialFailures,
+                                            });
+                                        }
+                                    }
+                                }
+                                if let Some(attempts) = attempts {
+                                    let _ = event_sender.unbounded_send(Event::DialFailed {
+                                        peer_id: peer_id.map(|p| p.to_string()),
+                                        reason,
+                                        attempts,
+                                        msg: error.to_string(),
+                                    });
+                                }
+                            }
+                            event @ (SwarmEvent::NewExternalAddrCandidate { .. }
+                            | SwarmEvent::ExternalAddrConfirmed { .. }
+                            | SwarmEvent::ExternalAddrExpired { .. }) => {
+                                let change = shared_state_clone.lock().await.external_addrs.on_swarm_event(&event);
+                                let event = match change {
+                                    Some(ExternalAddrChange::Candidate(addr)) => Event::ExternalAddrCandidate { addr: addr.to_string() },
+                                    Some(ExternalAddrChange::Confirmed(addr)) => Event::ExternalAddrConfirmed { addr: addr.to_string() },
+                                    Some(ExternalAddrChange::Expired(addr)) => Event::ExternalAddrExpired { addr: addr.to_string() },
+                                    None => continue,
+                                };
+                                // What identify advertises changed; tell the connected peers now
+                                if !matches!(event, Event::ExternalAddrCandidate { .. }) {
+                                    let peers: Vec<PeerId> = swarm.connected_peers().copied().collect();
+                                    swarm.behaviour_mut().identify.push(peers);
+                                    // Our registrations carry the addresses too
+                                    rendezvous.renew_all(web_time::Instant::now());
+                                }
+                                let _ = event_sender.unbounded_send(event);
                             }
                             _ => {}
                         }
                     }
                 }
             }
-        });
+        });
+
+        Ok((WasmNode {
+            cmd_sender,
+            event_receiver: Arc::new(futures::lock::Mutex::new(event_receiver)),
+            peer_id: local_peer_id.to_string(),
+            shared_state,
+            docstore_config,
+            read_only: role.is_read_only(),
+            dht_enabled,
+            rendezvous_client,
+            traffic,
+            history,
+            subscriptions,
+            rooms: room_subscriptions,
+            identity: local_key,
+            bootstrap: guest_link_relays,
+            outbox: Outbox::default(),
+            reputation,
+            quality,
+            remote: None,
+        }, bootstrap_ready_rx))
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn peer_id(&self) -> String {
+        self.peer_id.clone()
+    }
+
+    /// Console log level: "off", "error", "info", "debug" or "trace". Shared by every node
+    /// on the page.
+    #[wasm_bindgen]
+    pub fn set_log_level(&self, level: String) -> Result<(), JsValue> {
+        crate::wasm_log::set_level(level.parse().map_err(|e: String| JsValue::from_str(&e))?);
+        // The worker has a log level of its own
+        if let Some(remote) = &self.remote {
+            return remote.post(Target::Node, "set_log_level", &[level.into()]);
+        }
+        Ok(())
+    }
+
+    #[wasm_bindgen]
+    pub fn publish_update(&self, data: String) -> Result<(), JsValue> {
+        self.ensure_writable()?;
+        self.docstore_config.check_update_size(data.len()).map_err(|e| error_to_js(&e))?;
+        if let Some(remote) = &self.remote {
+            return remote.post(Target::Node, "publish_update", &[data.into()]);
+        }
+        send_publish(&self.cmd_sender, &self.outbox, Command::Publish(data.into_bytes()))
+    }
+
+    /// Publish an update for a document. With a debounce window set, rapid calls are
+    /// coalesced into a single batch per document. `options` is optional:
+    /// `{ ackRequested?: boolean }`. With `ackRequested` the update goes out on its own,
+    /// every peer that applies it answers with a signed receipt reported as an
+    /// `updateAcknowledged` event (`msg_id`, `peer_id`, `doc_id`, `applied_version`), and
+    /// `updateUnacknowledged` follows if none arrives within 30 seconds.
+    #[wasm_bindgen]
+    pub fn publish_doc_update(&self, doc_id: String, data: String, options: JsValue) -> Result<(), JsValue> {
+        self.ensure_writable()?;
+        // Fail fast with a structured `{code: "UpdateTooLarge", size, max}` error
+        self.docstore_config.check_update_size(data.len()).map_err(|e| error_to_js(&e))?;
+        if let Some(remote) = &self.remote {
+            return remote.post(Target::Node, "publish_doc_update", &[doc_id.into(), data.into(), options]);
+        }
+        let ack_requested = if options.is_undefined() || options.is_null() {
+            false
+        } else {
+            Reflect::get(&options, &"ackRequested".into())?.as_bool().unwrap_or(false)
+        };
+        let update = DocUpdate::new(doc_id, data.into_bytes());
+        let options = PublishOptions { ack_requested, ..Default::default() };
+        send_publish(&self.cmd_sender, &self.outbox, Command::PublishDocUpdate { update, options })
+    }
+
+    /// Publish `data` as a compare-and-swap update of `doc_id`, applied only by peers
+    /// whose copy is at `expectedVersion`. Best-effort: browsers keep no store, so nothing
+    /// is checked here; every FullNode applies whichever of several concurrent CAS updates
+    /// reached it first and answers the others with a `casConflict` event (`msg_id`,
+    /// `peer_id`, `doc_id`, `current_version`), but different FullNodes may pick different
+    /// winners. Those that apply it answer with `updateAcknowledged`.
+    #[wasm_bindgen]
+    pub fn try_cas_update(&self, doc_id: String, expected_version: f64, data: String) -> Result<(), JsValue> {
+        self.ensure_writable()?;
+        self.docstore_config.check_update_size(data.len()).map_err(|e| error_to_js(&e))?;
+        if !(expected_version >= 0.0 && expected_version.fract() == 0.0) {
+            return Err(invalid_argument("expectedVersion", format!("invalid version {expected_version}")));
+        }
+        if let Some(remote) = &self.remote {
+            return remote.post(Target::Node, "try_cas_update", &[doc_id.into(), expected_version.into(), data.into()]);
+        }
+        let update = DocUpdate::new(doc_id, data.into_bytes());
+        let options = PublishOptions { expected_version: Some(expected_version as u64), ..Default::default() };
+        send_publish(&self.cmd_sender, &self.outbox, Command::PublishDocUpdate { update, options })
+    }
+
+    /// Start a transaction: updates to several documents that peers apply together or not
+    /// at all. Add them with `add_update(doc_id, data)` and publish with `commit()`.
+    #[wasm_bindgen]
+    pub fn begin_transaction(&self) -> WasmTransaction {
+        WasmTransaction {
+            tx: Transaction::new(),
+            cmd_sender: self.cmd_sender.clone(),
+            docstore_config: self.docstore_config.clone(),
+            read_only: self.read_only,
+            outbox: self.outbox.clone(),
+            remote: self.remote.clone(),
+        }
+    }
+
+    /// Coalesce `publish_doc_update` calls made within `ms` milliseconds. `0` disables
+    /// debouncing and flushes anything pending.
+    #[wasm_bindgen]
+    pub fn set_publish_debounce(&self, ms: u32) -> Result<(), JsValue> {
+        if let Some(remote) = &self.remote {
+            return remote.post(Target::Node, "set_publish_debounce", &[ms.into()]);
+        }
+        let window = (ms > 0).then(|| std::time::Duration::from_millis(ms as u64));
+        self.cmd_sender
+            .unbounded_send(Command::SetPublishDebounce(window))
+            .map_err(|e| JsValue::from_str(&format!("Failed to send command: {}", e)))
+    }
+
+    /// Forget which updates were delivered, in memory and in IndexedDB, so catch-up
+    /// delivers them all again. For debugging `dedupCacheKey`.
+    #[wasm_bindgen]
+    pub fn clear_dedup_cache(&self) -> Result<(), JsValue> {
+        if let Some(remote) = &self.remote {
+            return remote.post(Target::Node, "clear_dedup_cache", &[]);
+        }
+        self.cmd_sender
+            .unbounded_send(Command::ClearDedupCache)
+            .map_err(|e| JsValue::from_str(&format!("Failed to send command: {}", e)))
+    }
+
+    /// Publish a low-latency ephemeral message (cursor, typing indicator) for a document.
+    /// Ephemeral messages are never logged, replayed or stored.
+    #[wasm_bindgen]
+    pub fn publish_ephemeral(&self, doc_id: String, data: String) -> Result<(), JsValue> {
+        self.ensure_writable()?;
+        // Stale by the time we would resume
+        if is_suspended(&self.outbox) {
+            return Ok(());
+        }
+        if let Some(remote) = &self.remote {
+            return remote.post(Target::Node, "publish_ephemeral", &[doc_id.into(), data.into()]);
+        }
+        self.cmd_sender
+            .unbounded_send(Command::PublishEphemeral { doc_id, data: data.into_bytes() })
+            .map_err(|e| JsValue::from_str(&format!("Failed to send command: {}", e)))
+    }
+
+    /// Subscribe to a document's ephemeral topic; messages arrive as `ephemeralReceived` events.
+    #[wasm_bindgen]
+    pub fn subscribe_ephemeral(&self, doc_id: String) -> Result<(), JsValue> {
+        if let Some(remote) = &self.remote {
+            return remote.post(Target::Node, "subscribe_ephemeral", &[doc_id.into()]);
+        }
+        self.cmd_sender
+            .unbounded_send(Command::SubscribeEphemeral { doc_id })
+            .map_err(|e| JsValue::from_str(&format!("Failed to send command: {}", e)))
+    }
+
+    /// Join a room: subscribe to its update, presence and ephemeral topics and get a handle
+    /// that publishes and receives on them. Room traffic is only delivered to room handles,
+    /// never to `next_event()` or `subscribe_events()`. Joining a room twice returns another
+    /// handle on the same room.
+    ///
+    /// `options` is optional: `{ creator?: string, token?: Uint8Array, privateMembers?:
+    /// boolean }`. Giving the creator's peer id makes the room restricted: only traffic
+    /// from the creator and from peers whose presence carried a valid token it issued is
+    /// delivered, see `issue_capability()`. `token` is our own, as for
+    /// `WasmRoom.import_capability()`. `privateMembers` asks FullNodes to keep no
+    /// membership table for the room, see `room_members()`.
+    #[wasm_bindgen]
+    pub fn join_room(&self, room_id: String, options: JsValue) -> Result<WasmRoom, JsValue> {
+        let room = RoomId::new(room_id).map_err(|e| error_to_js(&e))?;
+        let room_id = room.as_str().to_string();
+        let (creator, token, private_members) = if options.is_undefined() || options.is_null() {
+            (None, None, false)
+        } else {
+            let creator = Reflect::get(&options, &"creator".into())?;
+            let creator = if creator.is_undefined() || creator.is_null() {
+                None
+            } else {
+                Some(peer_id_arg(&creator, "creator")?)
+            };
+            let token = WasmNodeOptions::bytes(&options, "token")?
+                .map(|bytes| Capability::decode(&bytes).map(Credential::from).map_err(|e| error_to_js(&e.into())))
+                .transpose()?;
+            let private_members = Reflect::get(&options, &"privateMembers".into())?.as_bool().unwrap_or(false);
+            (creator, token, private_members)
+        };
+        let is_creator = creator.is_some_and(|c| c.to_string() == self.peer_id);
+        self.rooms.lock().expect("rooms lock").entry(room_id.clone()).or_default();
+        match &self.remote {
+            Some(remote) => {
+                let options = with_peer_id_string(&options, "creator", creator)?;
+                remote.post(Target::Node, "join_room", &[room_id.as_str().into(), options])?
+            }
+            None => self
+                .cmd_sender
+                .unbounded_send(Command::JoinRoom { room, creator, token, private_members })
+                .map_err(|e| JsValue::from_str(&format!("Failed to send command: {}", e)))?,
+        }
+        Ok(self.room_handle(room_id, is_creator, None))
+    }
+
+    /// Create a link letting whoever holds it `"read"` or `"write"` restricted room
+    /// `room_id`, which this node created, until `expires_at_ms` (Unix ms), without a token
+    /// per guest. The link is a URL-safe string carrying the room's access and the relays
+    /// this node bootstrapped from; open it with `join_with_link()`. Revoke it with
+    /// `WasmRoom.revoke([link])`.
+    #[wasm_bindgen]
+    pub fn create_guest_link(&self, room_id: String, permission: String, expires_at_ms: f64) -> Result<String, JsValue> {
+        let room = RoomId::new(room_id).map_err(|e| error_to_js(&e))?;
+        let permission: Permission = permission.parse().map_err(|e: String| JsValue::from_str(&e))?;
+        let link = GuestLink::create(&self.identity, room.as_str(), permission, expires_at_ms as u64, self.bootstrap.clone())
+            .map_err(|e| error_to_js(&e.into()))?;
+        Ok(link.encode())
+    }
+
+    /// Join the room a guest link (from `create_guest_link()`) is for: dial the relays it
+    /// names and join as for `join_room()` with the link's creator, presenting a pass only
+    /// the holder of the link can sign. On a read-only link `publish()` and
+    /// `publish_ephemeral()` of the returned room fail with code `ReadOnlyRoom`. A link
+    /// that was altered is rejected with code `InvalidGuestLink`, an expired one with
+    /// `GuestLinkExpired`.
+    #[wasm_bindgen]
+    pub fn join_with_link(&self, link: String) -> Result<WasmRoom, JsValue> {
+        let encoded = link;
+        let link = GuestLink::parse(&encoded, get_timestamp_ms() as u64).map_err(|e| error_to_js(&e.into()))?;
+        let local: PeerId = self.peer_id.parse().map_err(|_| JsValue::from_str("invalid local peer id"))?;
+        let pass = link.pass_for(&local).map_err(|e| error_to_js(&e.into()))?;
+        let room = RoomId::new(link.room_id()).map_err(|e| error_to_js(&e))?;
+        if let Some(remote) = &self.remote {
+            let room_id = room.as_str().to_string();
+            self.rooms.lock().expect("rooms lock").entry(room_id.clone()).or_default();
+            remote.post(Target::Node, "join_with_link", &[encoded.into()])?;
+            return Ok(self.room_handle(room_id, false, Some(link.permission())));
+        }
+        // Our own bootstrap relays are dialed already
+        for addr in link.relays().iter().filter(|addr| !self.bootstrap.contains(addr)) {
+            let addr = match crate::node::addrs::validate_browser_addr(&addr.to_string()).into_result() {
+                Ok(addr) => addr,
+                Err(e) => {
+                    tracing::debug!("Not dialing guest link relay {}: {}", addr, e);
+                    continue;
+                }
+            };
+            if crate::node::addrs::check_not_self(&local, &addr).is_err() {
+                continue;
+            }
+            self.cmd_sender
+                .unbounded_send(Command::DialPeer { addr, options: DialOptions::default() })
+                .map_err(|e| JsValue::from_str(&format!("Failed to send command: {}", e)))?;
+        }
+        let room_id = room.as_str().to_string();
+        self.rooms.lock().expect("rooms lock").entry(room_id.clone()).or_default();
+        self.cmd_sender
+            .unbounded_send(Command::JoinRoom {
+                room,
+                creator: Some(link.creator()),
+                token: Some(Credential::Guest(pass)),
+                private_members: false,
+            })
+            .map_err(|e| JsValue::from_str(&format!("Failed to send command: {}", e)))?;
+        Ok(self.room_handle(room_id, false, Some(link.permission())))
+    }
+
+    /// Issue a token letting `grantee` (a peer id) `"read"` or `"write"` restricted room
+    /// `room_id` until `expires_at_ms` (Unix ms), signed with this node's key. Only tokens
+    /// issued by the room's creator are accepted. Hand the bytes to the grantee out of band.
+    #[wasm_bindgen]
+    pub fn issue_capability(&self, room_id: String, grantee: JsValue, permission: String, expires_at_ms: f64) -> Result<Vec<u8>, JsValue> {
+        let room = RoomId::new(room_id).map_err(|e| error_to_js(&e))?;
+        let grantee = peer_id_arg(&grantee, "grantee")?;
+        let permission: Permission = permission.parse().map_err(|e: String| JsValue::from_str(&e))?;
+        let token = auth::issue_capability(&self.identity, room.as_str(), &grantee, permission, expires_at_ms as u64)
+            .map_err(|e| error_to_js(&e.into()))?;
+        Ok(token.encode())
+    }
+
+    /// Leave a room: unsubscribe from its topics and end the event subscriptions of every
+    /// handle on it. Returns false if the room was not joined.
+    #[wasm_bindgen]
+    pub fn leave_room(&self, room_id: String) -> Result<bool, JsValue> {
+        leave_room(&self.rooms, &self.cmd_sender, self.remote.as_ref(), room_id)
+    }
+
+    /// Tell connected relays the only topics (as in `subscriptions` of the status) this
+    /// node still wants. They stop forwarding the rest right away instead of once our
+    /// unsubscribes reach them. Rooms joined or left afterwards update the hint; without a
+    /// hint, relays go by subscriptions alone.
+    #[wasm_bindgen]
+    pub fn set_topic_interest(&self, topics: Vec<String>) -> Result<(), JsValue> {
+        if let Some(remote) = &self.remote {
+            let topics: js_sys::Array = topics.iter().map(|t| JsValue::from_str(t)).collect();
+            return remote.post(Target::Node, "set_topic_interest", &[topics.into()]);
+        }
+        self.cmd_sender
+            .unbounded_send(Command::SetTopicInterest { topics })
+            .map_err(|e| JsValue::from_str(&format!("Failed to send command: {}", e)))
+    }
+
+    /// Pin a document: it keeps being announced in the DHT while this node runs.
+    /// Resolves to false if it was already pinned.
+    #[wasm_bindgen]
+    pub async fn pin(&self, doc_id: String) -> Result<bool, JsValue> {
+        if let Some(remote) = &self.remote {
+            return remote.call(Target::Node, "pin", &[doc_id.into()]).await.map(|pinned| pinned.is_truthy());
+        }
+        let mut state = self.shared_state.lock().await;
+        if state.pins.contains(&doc_id) {
+            return Ok(false);
+        }
+        state.pins.push(doc_id.clone());
+        self.cmd_sender
+            .unbounded_send(Command::SetProviding { doc_id, provide: true })
+            .map_err(|e| JsValue::from_str(&format!("Failed to send command: {}", e)))?;
+        Ok(true)
+    }
+
+    #[wasm_bindgen]
+    pub async fn unpin(&self, doc_id: String) -> Result<bool, JsValue> {
+        if let Some(remote) = &self.remote {
+            return remote.call(Target::Node, "unpin", &[doc_id.into()]).await.map(|unpinned| unpinned.is_truthy());
+        }
+        let mut state = self.shared_state.lock().await;
+        let Some(i) = state.pins.iter().position(|p| *p == doc_id) else {
+            return Ok(false);
+        };
+        state.pins.remove(i);
+        self.cmd_sender
+            .unbounded_send(Command::SetProviding { doc_id, provide: false })
+            .map_err(|e| JsValue::from_str(&format!("Failed to send command: {}", e)))?;
+        Ok(true)
+    }
+
+    /// Per subscribed topic: `{ topic, mesh_peers: string[], subscribed_peers: number }`.
+    #[wasm_bindgen]
+    pub async fn mesh_info(&self) -> Result<JsValue, JsValue> {
+        if let Some(remote) = &self.remote {
+            return remote.call(Target::Node, "mesh_info", &[]).await;
+        }
+        let (reply, rx) = futures::channel::oneshot::channel();
+        self.cmd_sender
+            .unbounded_send(Command::MeshInfo(reply))
+            .map_err(|e| JsValue::from_str(&format!("Failed to send command: {}", e)))?;
+        let topics = rx.await.map_err(|_| JsValue::from_str("node stopped"))?;
+
+        let arr = js_sys::Array::new();
+        for info in topics {
+            let obj = Object::new();
+            Reflect::set(&obj, &"topic".into(), &info.topic.into())?;
+            let mesh = js_sys::Array::new();
+            for peer in &info.mesh_peers {
+                mesh.push(&JsValue::from_str(&peer.to_string()));
+            }
+            Reflect::set(&obj, &"mesh_peers".into(), &mesh.into())?;
+            Reflect::set(&obj, &"subscribed_peers".into(), &JsValue::from_f64(info.subscribed_peers as f64))?;
+            arr.push(&obj);
+        }
+        Ok(arr.into())
+    }
+
+    /// Gossipsub traffic since start or the last `reset_stats()`:
+    /// `{ total, topics: { [topic]: counts }, peers: { [peerId]: counts },
+    /// connections_by_transport: { [transport]: number }, budget }`, where counts are
+    /// `{ messages_in, bytes_in, messages_out, bytes_out, relayed_messages_in, relayed_bytes_in,
+    /// forwarded_messages, forwarded_bytes }` and `budget` is the bandwidth budget's
+    /// `{ bytes_per_interval, interval_ms, used_bytes, interval_started_ms, exhausted,
+    /// dropped_ephemeral }`, which `reset_stats()` leaves alone.
+    #[wasm_bindgen]
+    pub fn stats(&self) -> Result<JsValue, JsValue> {
+        if let Some(remote) = &self.remote {
+            return Ok(remote.stats());
+        }
+        let snapshot = self.traffic.snapshot();
+        let obj = Object::new();
+        Reflect::set(&obj, &"total".into(), &traffic_counts_to_js(&snapshot.total)?)?;
+        for (key, map) in [("topics", &snapshot.topics), ("peers", &snapshot.peers)] {
+            let by_key = Object::new();
+            for (name, counts) in map {
+                Reflect::set(&by_key, &name.into(), &traffic_counts_to_js(counts)?)?;
+            }
+            Reflect::set(&obj, &key.into(), &by_key.into())?;
+        }
+        let by_transport = Object::new();
+        for (transport, count) in &snapshot.connections_by_transport {
+            Reflect::set(&by_transport, &transport.into(), &JsValue::from_f64(*count as f64))?;
+        }
+        Reflect::set(&obj, &"connections_by_transport".into(), &by_transport.into())?;
+        let budget = self.traffic.budget().usage(get_timestamp_ms() as u64);
+        Reflect::set(&obj, &"budget".into(), &budget_usage_to_js(&budget)?)?;
+        Ok(obj.into())
+    }
+
+    /// Start recording every gossipsub message received, and with `includeOutbound` every
+    /// message published, until `stop_recording()`. Recordings hold message data,
+    /// documents included, so only start one when the user asks to. `options` is
+    /// optional: `{ includeOutbound?: boolean, maxBytes?: number }`; recording stops taking
+    /// messages past `maxBytes` of them (16 MiB by default). Resolves to false if a
+    /// recording is already running.
+    #[wasm_bindgen]
+    pub async fn start_recording(&self, options: JsValue) -> Result<JsValue, JsValue> {
+        if let Some(remote) = &self.remote {
+            return remote.call(Target::Node, "start_recording", &[options]).await;
+        }
+        let mut recorder_options = RecorderOptions::default();
+        if !options.is_undefined() && !options.is_null() {
+            recorder_options.include_outbound = Reflect::get(&options, &"includeOutbound".into())?.as_bool().unwrap_or(false);
+            if let Some(max_bytes) = Reflect::get(&options, &"maxBytes".into())?.as_f64() {
+                if !(max_bytes >= 0.0 && max_bytes.fract() == 0.0) {
+                    return Err(invalid_argument("maxBytes", format!("invalid size {max_bytes}")));
+                }
+                recorder_options.max_bytes = max_bytes as usize;
+            }
+        }
+        let peer_id: PeerId = self.peer_id.parse().map_err(|_| JsValue::from_str("invalid local peer id"))?;
+        let now = get_timestamp_ms() as u64;
+        Ok(self.traffic.recorder().start(recorder_options, &peer_id, &self.docstore_config.topics, now).into())
+    }
+
+    /// Stop recording. Resolves to the recording as a `Uint8Array`, to keep in IndexedDB
+    /// or offer as a download, or null if none was running. With `{ redact: true }` every
+    /// payload is zeroed first, keeping the structure, so it can be shared.
+    #[wasm_bindgen]
+    pub async fn stop_recording(&self, options: JsValue) -> Result<JsValue, JsValue> {
+        if let Some(remote) = &self.remote {
+            return remote.call(Target::Node, "stop_recording", &[options]).await;
+        }
+        let redact = !options.is_undefined()
+            && !options.is_null()
+            && Reflect::get(&options, &"redact".into())?.as_bool().unwrap_or(false);
+        let Some(recording) = self.traffic.recorder().stop(get_timestamp_ms() as u64) else {
+            return Ok(JsValue::NULL);
+        };
+        let recording = if redact { recording.redact() } else { recording };
+        Ok(js_sys::Uint8Array::from(recording.encode().as_slice()).into())
+    }
+
+    /// How far the running recording got, `{ messages, bytes, truncated }`, or null if
+    /// none is running.
+    #[wasm_bindgen]
+    pub async fn recording_status(&self) -> Result<JsValue, JsValue> {
+        if let Some(remote) = &self.remote {
+            return remote.call(Target::Node, "recording_status", &[]).await;
+        }
+        let Some(status) = self.traffic.recorder().status() else {
+            return Ok(JsValue::NULL);
+        };
+        let obj = Object::new();
+        Reflect::set(&obj, &"messages".into(), &JsValue::from_f64(status.messages as f64))?;
+        Reflect::set(&obj, &"bytes".into(), &JsValue::from_f64(status.bytes as f64))?;
+        Reflect::set(&obj, &"truncated".into(), &status.truncated.into())?;
+        Ok(obj.into())
+    }
+
+    #[wasm_bindgen]
+    pub fn reset_stats(&self) {
+        if let Some(remote) = &self.remote {
+            if let Err(e) = remote.reset_stats() {
+                tracing::warn!("Failed to reset the worker's stats: {:?}", e);
+            }
+            return;
+        }
+        self.traffic.reset();
+    }
+
+    /// Receive copies of the events matching `filter`
+    /// (`{ types?: string[], topic?: string, docId?: string, capacity?: number }`) through
+    /// a separate handle. Each subscription queues up to `capacity` events (default 256) and
+    /// counts the ones it had to drop; `next_event()` still receives everything.
+    #[wasm_bindgen]
+    pub fn subscribe_events(&self, filter: JsValue) -> Result<EventSubscription, JsValue> {
+        let (filter, capacity) = event_filter_from_js(&filter)?;
+        Ok(EventSubscription { inner: self.subscriptions.subscribe(filter, capacity) })
+    }
+
+    /// Recently emitted events, oldest first, including ones already taken with
+    /// `next_event()`. Each is shaped like a `next_event()` result plus `at_ms`.
+    /// `kinds` filters by `type` (e.g. `["connected", "disconnected", "error"]`); `limit`
+    /// keeps only the latest that many.
+    #[wasm_bindgen]
+    pub fn recent_events(&self, kinds: Option<Vec<String>>, limit: Option<u32>) -> Result<JsValue, JsValue> {
+        let kinds: Option<Vec<&str>> = kinds.as_ref().map(|k| k.iter().map(String::as_str).collect());
+        let arr = js_sys::Array::new();
+        for entry in self.history.recent(kinds.as_deref(), limit.map(|l| l as usize)) {
+            let obj = entry.event.to_js()?;
+            Reflect::set(&obj, &"at_ms".into(), &JsValue::from_f64(entry.at_ms as f64))?;
+            arr.push(&obj);
+        }
+        Ok(arr.into())
+    }
+
+    /// Size of our DHT routing table: `{ buckets, peers, pending }`. Changes are reported as
+    /// `dhtSummaryChanged` events.
+    #[wasm_bindgen]
+    pub async fn dht_summary(&self) -> Result<JsValue, JsValue> {
+        self.ensure_dht()?;
+        if let Some(remote) = &self.remote {
+            return remote.call(Target::Node, "dht_summary", &[]).await;
+        }
+        let summary = self.shared_state.lock().await.dht_summary;
+        let obj = Object::new();
+        set_dht_summary(&obj, &summary)?;
+        Ok(obj.into())
+    }
+
+    /// Resolve once the node is usable: connected to a peer, meshed on the docstore topic,
+    /// and done with the initial DHT bootstrap, successfully or not. With `timeout_ms` the
+    /// promise rejects after that long with code `NotReady` and `waiting_for`, a list of
+    /// "connection", "mesh" and "dht_bootstrap".
+    #[wasm_bindgen]
+    pub async fn ready(&self, timeout_ms: Option<f64>) -> Result<(), JsValue> {
+        if let Some(remote) = &self.remote {
+            let timeout_ms = timeout_ms.map_or(JsValue::UNDEFINED, JsValue::from_f64);
+            return remote.call(Target::Node, "ready", &[timeout_ms]).await.map(drop);
+        }
+        let (reply, rx) = futures::channel::oneshot::channel();
+        self.cmd_sender
+            .unbounded_send(Command::WaitReady { reply })
+            .map_err(|e| JsValue::from_str(&format!("Failed to send command: {}", e)))?;
+        let Some(ms) = timeout_ms else {
+            rx.await.map_err(|_| error_to_js(&crate::Error::NodeStopped))?;
+            return Ok(());
+        };
+        let deadline = futures_timer::Delay::new(std::time::Duration::from_millis(ms.max(0.0) as u64));
+        futures::select! {
+            ready = rx.fuse() => {
+                ready.map_err(|_| error_to_js(&crate::Error::NodeStopped))?;
+                Ok(())
+            }
+            _ = deadline.fuse() => {
+                let waiting_for = self.shared_state.lock().await.readiness.waiting_for();
+                Err(error_to_js(&crate::Error::NotReady { waiting_for }))
+            }
+        }
+    }
+
+    /// The readiness conditions as they stand now: `{ ready, connected, mesh,
+    /// dht_bootstrap, waiting_for }`, with `dht_bootstrap` one of "not_configured",
+    /// "pending", "done" or "failed".
+    #[wasm_bindgen]
+    pub async fn readiness(&self) -> Result<JsValue, JsValue> {
+        if let Some(remote) = &self.remote {
+            return remote.call(Target::Node, "readiness", &[]).await;
+        }
+        let readiness = self.shared_state.lock().await.readiness;
+        let obj = Object::new();
+        Reflect::set(&obj, &"ready".into(), &readiness.is_ready().into())?;
+        Reflect::set(&obj, &"connected".into(), &readiness.connected.into())?;
+        Reflect::set(&obj, &"mesh".into(), &readiness.mesh.into())?;
+        Reflect::set(&obj, &"dht_bootstrap".into(), &readiness.dht_bootstrap.as_str().into())?;
+        Reflect::set(&obj, &"waiting_for".into(), &string_array(&readiness.waiting_for()).into())?;
+        Ok(obj.into())
+    }
+
+    /// Pause background work while the tab is hidden. `options` is optional:
+    /// `{ disconnect?: boolean }`. Update publishes are queued (up to 1024, then they fail
+    /// with code `SuspendQueueFull`), ephemeral ones are dropped, presence heartbeats and
+    /// dial retries stop, and with `disconnect: true` every connection is closed. Emits
+    /// `suspended`. Returns false if the node was already suspended.
+    #[wasm_bindgen]
+    pub fn suspend(&self, options: JsValue) -> Result<bool, JsValue> {
+        let disconnect = if options.is_undefined() || options.is_null() {
+            false
+        } else {
+            Reflect::get(&options, &"disconnect".into())?.as_bool().unwrap_or(false)
+        };
+        let mut outbox = self.outbox.lock().expect("outbox lock");
+        if outbox.is_some() {
+            return Ok(false);
+        }
+        // A worker queues publishes itself; ours stays empty and only marks the suspension
+        match &self.remote {
+            Some(remote) => remote.post(Target::Node, "suspend", &[options])?,
+            None => self
+                .cmd_sender
+                .unbounded_send(Command::Suspend { disconnect })
+                .map_err(|e| JsValue::from_str(&format!("Failed to send command: {}", e)))?,
+        }
+        *outbox = Some(VecDeque::new());
+        Ok(true)
+    }
+
+    /// Undo `suspend()`: redial lost bootstrap relays with a fresh backoff, send the
+    /// queued publishes in order and re-announce presence in every room. Emits `resumed`
+    /// with `suspended_ms`; messages from others in that time were missed, so catch up
+    /// then. Returns false if the node was not suspended.
+    #[wasm_bindgen]
+    pub fn resume(&self) -> Result<bool, JsValue> {
+        let Some(queued) = self.outbox.lock().expect("outbox lock").take() else {
+            return Ok(false);
+        };
+        if let Some(remote) = &self.remote {
+            remote.post(Target::Node, "resume", &[])?;
+            return Ok(true);
+        }
+        for cmd in std::iter::once(Command::Resume).chain(queued) {
+            self.cmd_sender
+                .unbounded_send(cmd)
+                .map_err(|e| JsValue::from_str(&format!("Failed to send command: {}", e)))?;
+        }
+        Ok(true)
+    }
+
+    /// Spend at most `bytesPerInterval` gossipsub bytes, in and out, per minute, for
+    /// metered connections; `null` lifts the limit. Once the minute's budget is spent,
+    /// update publishes are queued (up to 1024, then they fail with code
+    /// `SuspendQueueFull`), history fetches and catch-up pause, ephemeral messages are
+    /// dropped (counted in `stats().budget`) and presence heartbeats go out half as often,
+    /// while pings and identify carry on. Emits `budgetExceeded` with `used`, `limit` and
+    /// `resets_at_ms`, and `budgetReset` when the next minute starts. Setting a budget
+    /// starts a fresh minute, so raising or lifting it sends what waited at once.
+    #[wasm_bindgen]
+    pub fn set_bandwidth_budget(&self, bytes_per_interval: JsValue) -> Result<(), JsValue> {
+        let bytes_per_interval = if bytes_per_interval.is_null() || bytes_per_interval.is_undefined() {
+            None
+        } else {
+            match bytes_per_interval.as_f64() {
+                Some(bytes) if bytes >= 0.0 && bytes.fract() == 0.0 => Some(bytes as u64),
+                _ => return Err(invalid_argument("bytesPerInterval", "must be a whole number of bytes or null")),
+            }
+        };
+        if let Some(remote) = &self.remote {
+            let bytes = bytes_per_interval.map_or(JsValue::NULL, |bytes| JsValue::from_f64(bytes as f64));
+            return remote.post(Target::Node, "set_bandwidth_budget", &[bytes]);
+        }
+        self.cmd_sender
+            .unbounded_send(Command::SetBandwidthBudget { bytes_per_interval })
+            .map_err(|e| JsValue::from_str(&format!("Failed to send command: {}", e)))
+    }
+
+    /// `peer_id`'s reputation: positive for peers that answer pings quickly and serve
+    /// requests, negative for ones that fail dials, pings or requests or send invalid
+    /// messages. Decays back towards `0` (unknown) over time; never shared with peers.
+    #[wasm_bindgen]
+    pub fn peer_score(&self, peer_id: JsValue) -> Result<f64, JsValue> {
+        let peer_id = peer_id_arg(&peer_id, "peerId")?;
+        if let Some(remote) = &self.remote {
+            let peer_id = peer_id.to_string();
+            return Ok(remote.scores().iter().find(|(peer, _)| *peer == peer_id).map_or(0.0, |(_, score)| *score));
+        }
+        Ok(self.reputation.score(&peer_id, web_time::Instant::now()))
+    }
+
+    /// `peer_id`'s smoothed connection quality as `{ score, bucket, rtt_ms, loss_rate,
+    /// failure_rate }`: round trips, lost pings and failed requests combined into a score
+    /// from 0 to 100 and a bucket (`good`, `fair` or `poor`), updated on every ping. Null
+    /// unless the peer is connected.
+    #[wasm_bindgen]
+    pub async fn peer_quality(&self, peer_id: JsValue) -> Result<JsValue, JsValue> {
+        let peer_id = peer_id_arg(&peer_id, "peerId")?;
+        if let Some(remote) = &self.remote {
+            return remote.call(Target::Node, "peer_quality", &[peer_id.to_string().into()]).await;
+        }
+        match self.quality.get(&peer_id) {
+            Some(quality) => quality_to_js(&quality),
+            None => Ok(JsValue::NULL),
+        }
+    }
+
+    /// The `n` best-scoring peers, best first, as `{ peer_id, score }` objects.
+    #[wasm_bindgen]
+    pub fn top_peers(&self, n: u32) -> Result<js_sys::Array, JsValue> {
+        let top: Vec<(String, f64)> = match &self.remote {
+            Some(remote) => remote.scores().into_iter().take(n as usize).collect(),
+            None => self
+                .reputation
+                .top(n as usize, web_time::Instant::now())
+                .into_iter()
+                .map(|(peer_id, score)| (peer_id.to_string(), score))
+                .collect(),
+        };
+        let peers = js_sys::Array::new();
+        for (peer_id, score) in top {
+            let obj = Object::new();
+            Reflect::set(&obj, &"peer_id".into(), &peer_id.into())?;
+            Reflect::set(&obj, &"score".into(), &JsValue::from_f64(score))?;
+            peers.push(&obj.into());
+        }
+        Ok(peers)
+    }
+
+    /// Order `candidates` (peer ids) best first, to choose whom to fetch from.
+    #[wasm_bindgen]
+    pub fn rank_peers(&self, candidates: js_sys::Array) -> Result<js_sys::Array, JsValue> {
+        let candidates = candidates.iter().map(|peer| peer_id_arg(&peer, "candidates")).collect::<Result<Vec<_>, JsValue>>()?;
+        if let Some(remote) = &self.remote {
+            let scores: HashMap<String, f64> = remote.scores().into_iter().collect();
+            let mut ranked: Vec<(String, f64)> = candidates
+                .iter()
+                .map(|peer| {
+                    let peer = peer.to_string();
+                    let score = scores.get(&peer).copied().unwrap_or(0.0);
+                    (peer, score)
+                })
+                .collect();
+            ranked.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+            let ranked: Vec<String> = ranked.into_iter().map(|(peer, _)| peer).collect();
+            return Ok(string_array(&ranked));
+        }
+        let ranked: Vec<String> = self.reputation.rank(candidates, web_time::Instant::now()).iter().map(ToString::to_string).collect();
+        Ok(string_array(&ranked))
+    }
+
+    /// Feed something the application observed of `peer_id` into its score: one of
+    /// "fetch_succeeded", "fetch_failed", "rate_limited", "invalid_message", "dial_failed"
+    /// or "ping_failed".
+    #[wasm_bindgen]
+    pub fn report_peer(&self, peer_id: JsValue, signal: String) -> Result<(), JsValue> {
+        let peer_id = peer_id_arg(&peer_id, "peerId")?;
+        let parsed: PeerSignal = signal.parse().map_err(|e: String| JsValue::from_str(&e))?;
+        if let Some(remote) = &self.remote {
+            return remote.post(Target::Node, "report_peer", &[peer_id.to_string().into(), signal.into()]);
+        }
+        self.reputation.record(peer_id, parsed, web_time::Instant::now());
+        Ok(())
+    }
+
+    /// Peer id of the connected bootstrap relay with the lowest ping time, or null. It and
+    /// the runner-up carry ephemeral traffic; a faster relay takes over only after beating
+    /// one of them by 20% on three pings in a row.
+    #[wasm_bindgen]
+    pub async fn best_relay(&self) -> Option<String> {
+        if let Some(remote) = &self.remote {
+            return remote.call(Target::Node, "best_relay", &[]).await.ok().and_then(|relay| relay.as_string());
+        }
+        self.shared_state.lock().await.best_relay.map(|peer| peer.to_string())
+    }
+
+    /// "connected", "dialing" (a dial is in flight, no connection yet) or "disconnected".
+    #[wasm_bindgen]
+    pub async fn connection_state(&self, peer_id: JsValue) -> Result<String, JsValue> {
+        let peer_id = peer_id_arg(&peer_id, "peerId")?;
+        if let Some(remote) = &self.remote {
+            return remote.call(Target::Node, "connection_state", &[peer_id.to_string().into()]).await.map(|state| state.as_string().unwrap_or_default());
+        }
+        Ok(self.shared_state.lock().await.connections.state(&peer_id).as_str().to_string())
+    }
+
+    /// How other peers see this node: `{ confirmed: string[], candidates: string[] }`, the
+    /// confirmed external addresses it advertises and the unconfirmed ones peers observed
+    /// it on. Changes arrive as `externalAddrCandidate`, `externalAddrConfirmed` and
+    /// `externalAddrExpired` events.
+    #[wasm_bindgen]
+    pub async fn external_addrs(&self) -> Result<JsValue, JsValue> {
+        if let Some(remote) = &self.remote {
+            return remote.call(Target::Node, "external_addrs", &[]).await;
+        }
+        let state = self.shared_state.lock().await;
+        let confirmed: Vec<String> = state.external_addrs.confirmed().iter().map(ToString::to_string).collect();
+        let candidates: Vec<String> = state.external_addrs.candidates().map(ToString::to_string).collect();
+        let obj = Object::new();
+        Reflect::set(&obj, &"confirmed".into(), &string_array(&confirmed).into())?;
+        Reflect::set(&obj, &"candidates".into(), &string_array(&candidates).into())?;
+        Ok(obj.into())
+    }
+
+    /// What a connected peer reported through identify:
+    /// `{ agent_version, protocol_version, protocols: string[], listen_addrs: string[] }`, or
+    /// null if it has not identified itself (yet) or is no longer connected.
+    #[wasm_bindgen]
+    pub async fn peer_info(&self, peer_id: JsValue) -> Result<JsValue, JsValue> {
+        let peer_id = peer_id_arg(&peer_id, "peerId")?;
+        if let Some(remote) = &self.remote {
+            return remote.call(Target::Node, "peer_info", &[peer_id.to_string().into()]).await;
+        }
+        let state = self.shared_state.lock().await;
+        let Some(info) = state.peer_infos.get(&peer_id) else {
+            return Ok(JsValue::NULL);
+        };
+        let obj = Object::new();
+        Reflect::set(&obj, &"agent_version".into(), &info.agent_version.as_str().into())?;
+        Reflect::set(&obj, &"protocol_version".into(), &info.protocol_version.as_str().into())?;
+        Reflect::set(&obj, &"protocols".into(), &string_array(&info.protocols).into())?;
+        let addrs: Vec<String> = info.listen_addrs.iter().map(ToString::to_string).collect();
+        Reflect::set(&obj, &"listen_addrs".into(), &string_array(&addrs).into())?;
+        Ok(obj.into())
+    }
+
+    #[wasm_bindgen]
+    pub async fn pins(&self) -> Result<JsValue, JsValue> {
+        if let Some(remote) = &self.remote {
+            return remote.call(Target::Node, "pins", &[]).await;
+        }
+        let state = self.shared_state.lock().await;
+        let arr = js_sys::Array::new();
+        for pin in &state.pins {
+            arr.push(&JsValue::from_str(pin));
+        }
+        Ok(arr.into())
+    }
+
+    /// Look a peer up in the DHT. `options` is optional:
+    /// `{ numResults?: number, timeoutMs?: number, dial?: boolean }`. Resolves with up to
+    /// `numResults` of the closest peers as `{ peer_id, addrs: string[], dialable: string[] }`,
+    /// the target first if it was found, and rejects once `timeoutMs` has passed. With
+    /// `dial` the target is dialed through its direct dialable addresses. Every peer is
+    /// also reported as a `peerDiscovery` event.
+    #[wasm_bindgen]
+    pub async fn find_peer(&self, peer_id: JsValue, options: JsValue) -> Result<JsValue, JsValue> {
+        self.ensure_dht()?;
+        let peer_id = peer_id_arg(&peer_id, "peerId")?;
+        // Checked here too, so a bad option fails the same way in both modes
+        let options_js = options;
+        let options = find_peer_options(&options_js)?;
+        if let Some(remote) = &self.remote {
+            return remote.call(Target::Node, "find_peer", &[peer_id.to_string().into(), options_js]).await;
+        }
+        let deadline = futures_timer::Delay::new(options.timeout);
+        let (reply, rx) = futures::channel::oneshot::channel();
+        self.cmd_sender
+            .unbounded_send(Command::FindPeer { peer_id, options, reply })
+            .map_err(|e| JsValue::from_str(&format!("Failed to send find peer command: {}", e)))?;
+        let found = futures::select! {
+            found = rx.fuse() => found.map_err(|_| JsValue::from_str("node stopped"))?,
+            _ = deadline.fuse() => return Err(JsValue::from_str(&format!("lookup of {peer_id} timed out"))),
+        };
+        let arr = js_sys::Array::new();
+        for peer in &found {
+            arr.push(&found_peer_to_js(peer)?);
+        }
+        Ok(arr.into())
+    }
+
+    /// Look for relays in the DHT, where relay servers announce themselves. Dials a few of
+    /// the providers found and resolves with the peer ids of all of them, or rejects after
+    /// 30 seconds. Providers that identify shows serving the relay hop protocol join the
+    /// relays used for explicit peering and reconnects, each reported as a
+    /// `relayDiscovered` event; the others are never trusted.
+    #[wasm_bindgen]
+    pub async fn discover_relays(&self) -> Result<JsValue, JsValue> {
+        self.ensure_dht()?;
+        if let Some(remote) = &self.remote {
+            return remote.call(Target::Node, "discover_relays", &[]).await;
+        }
+        let deadline = futures_timer::Delay::new(DEFAULT_RELAY_DISCOVERY_TIMEOUT);
+        let (reply, rx) = futures::channel::oneshot::channel();
+        self.cmd_sender
+            .unbounded_send(Command::DiscoverRelays { reply })
+            .map_err(|e| JsValue::from_str(&format!("Failed to send command: {}", e)))?;
+        let found = futures::select! {
+            found = rx.fuse() => found.map_err(|_| JsValue::from_str("node stopped"))?,
+            _ = deadline.fuse() => return Err(JsValue::from_str("relay discovery timed out")),
+        };
+        let ids: Vec<String> = found.iter().map(ToString::to_string).collect();
+        Ok(string_array(&ids).into())
+    }
+
+    /// Ask every connected rendezvous point for the peers registered under our namespace
+    /// and resolve with all registrants whose registration is still valid, as `[{ peerId,
+    /// addrs, point }]`. The node registers with rendezvous points as it meets them and
+    /// dials a few registrants by itself. Rejects with `RendezvousDisabled` on roles that
+    /// serve rather than register, and in builds without the `rendezvous` feature.
+    #[wasm_bindgen]
+    pub async fn discover_peers(&self) -> Result<JsValue, JsValue> {
+        if let Some(remote) = &self.remote {
+            return remote.call(Target::Node, "discover_peers", &[]).await;
+        }
+        if !self.rendezvous_client {
+            return Err(error_to_js(&crate::Error::RendezvousDisabled));
+        }
+        let (reply, rx) = futures::channel::oneshot::channel();
+        self.cmd_sender
+            .unbounded_send(Command::DiscoverPeers { reply })
+            .map_err(|e| JsValue::from_str(&format!("Failed to send command: {}", e)))?;
+        let registrants = rx.await.map_err(|_| JsValue::from_str("node stopped"))?;
+        let out = js_sys::Array::new();
+        for registrant in &registrants {
+            let obj = Object::new();
+            Reflect::set(&obj, &"peerId".into(), &registrant.peer_id.to_string().into())?;
+            let addrs: Vec<String> = registrant.addrs.iter().map(ToString::to_string).collect();
+            Reflect::set(&obj, &"addrs".into(), &string_array(&addrs).into())?;
+            Reflect::set(&obj, &"point".into(), &registrant.point.to_string().into())?;
+            out.push(&obj);
+        }
+        Ok(out.into())
+    }
+
+    /// What this node already knows about a peer from its routing table, address book and
+    /// identify, without asking the network: `{ peer_id, addrs, dialable }`, or null if it
+    /// knows no address.
+    #[wasm_bindgen]
+    pub async fn find_peer_local(&self, peer_id: JsValue) -> Result<JsValue, JsValue> {
+        let peer_id = peer_id_arg(&peer_id, "peerId")?;
+        if let Some(remote) = &self.remote {
+            return remote.call(Target::Node, "find_peer_local", &[peer_id.to_string().into()]).await;
+        }
+        let (reply, rx) = futures::channel::oneshot::channel();
+        self.cmd_sender
+            .unbounded_send(Command::FindPeerLocal { peer_id, reply })
+            .map_err(|e| JsValue::from_str(&format!("Failed to send command: {}", e)))?;
+        match rx.await.map_err(|_| JsValue::from_str("node stopped"))? {
+            Some(peer) => found_peer_to_js(&peer),
+            None => Ok(JsValue::NULL),
+        }
+    }
+
+    /// Point the DHT at `version` of `doc_id`: a head pointer signed by this node, under a
+    /// key derived from our peer id and `doc_id`, put again before it expires and after an
+    /// outage. Browsers keep no document store, so `content` is the document as the app
+    /// holds it at `version`. Resolves with `{ docId, version, contentHash, seq }`
+    /// (`contentHash` in hex) once the first put reached one peer.
+    #[wasm_bindgen]
+    pub async fn publish_head_pointer(&self, doc_id: String, version: f64, content: Vec<u8>) -> Result<JsValue, JsValue> {
+        self.ensure_writable()?;
+        self.ensure_dht()?;
+        if !(version >= 0.0 && version.fract() == 0.0) {
+            return Err(invalid_argument("version", format!("invalid version {version}")));
+        }
+        if let Some(remote) = &self.remote {
+            return remote.call(Target::Node, "publish_head_pointer", &[doc_id.into(), version.into(), js_sys::Uint8Array::from(content.as_slice()).into()]).await;
+        }
+        let (reply, rx) = futures::channel::oneshot::channel();
+        self.cmd_sender
+            .unbounded_send(Command::PublishHeadPointer { doc_id, version: version as u64, content, reply })
+            .map_err(|e| JsValue::from_str(&format!("Failed to send command: {}", e)))?;
+        let pointer = rx.await.map_err(|_| JsValue::from_str("node stopped"))?.map_err(|e| error_to_js(&e))?;
+        head_pointer_to_js(&pointer)
+    }
+
+    /// Look up `author`'s head pointer for `doc_id`. Resolves with the newest verified
+    /// copy as `{ docId, version, contentHash, seq }`, or null if no peer has one. Rejects
+    /// with `InvalidHeadPointer` if no copy verifies or if the newest is older than a
+    /// pointer seen before, which a peer replaying an old record would cause.
+    #[wasm_bindgen]
+    pub async fn resolve_head(&self, author: JsValue, doc_id: String) -> Result<JsValue, JsValue> {
+        self.ensure_dht()?;
+        let author = peer_id_arg(&author, "author")?;
+        if let Some(remote) = &self.remote {
+            return remote.call(Target::Node, "resolve_head", &[author.to_string().into(), doc_id.into()]).await;
+        }
+        let (reply, rx) = futures::channel::oneshot::channel();
+        self.cmd_sender
+            .unbounded_send(Command::ResolveHead { author, doc_id, reply })
+            .map_err(|e| JsValue::from_str(&format!("Failed to send command: {}", e)))?;
+        match rx.await.map_err(|_| JsValue::from_str("node stopped"))?.map_err(|e| error_to_js(&e))? {
+            Some(pointer) => head_pointer_to_js(&pointer),
+            None => Ok(JsValue::NULL),
+        }
+    }
+
+    /// Publish `{ displayName, avatarHash? }` (`avatarHash` 32 bytes in hex) as this node's
+    /// profile, signed with its identity key, in the DHT. From then on our presence in
+    /// rooms carries the display name, which `memberJoined` events report as
+    /// `display_name`. Resolves with `{ displayName, avatarHash, updatedAtMs }` once the
+    /// first put reached one peer; rejects with `InvalidProfile` past the size limits.
+    #[wasm_bindgen]
+    pub async fn set_profile(&self, profile: JsValue) -> Result<JsValue, JsValue> {
+        self.ensure_writable()?;
+        self.ensure_dht()?;
+        let display_name = Reflect::get(&profile, &"displayName".into())?
+            .as_string()
+            .ok_or_else(|| invalid_argument("displayName", "expected a string"))?;
+        let avatar_hash = Reflect::get(&profile, &"avatarHash".into())?;
+        let avatar_hash = match avatar_hash.as_string() {
+            None if avatar_hash.is_null() || avatar_hash.is_undefined() => None,
+            Some(hex) if hex.len() == 64 && hex.is_ascii() => {
+                let mut hash = [0u8; 32];
+                for (i, byte) in hash.iter_mut().enumerate() {
+                    *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)
+                        .map_err(|_| invalid_argument("avatarHash", format!("not hex: {hex}")))?;
+                }
+                Some(hash)
+            }
+            _ => return Err(invalid_argument("avatarHash", "expected 32 bytes in hex")),
+        };
+        if let Some(remote) = &self.remote {
+            return remote.call(Target::Node, "set_profile", &[profile]).await;
+        }
+        let profile = match avatar_hash {
+            Some(hash) => Profile::new(display_name).with_avatar_hash(hash),
+            None => Profile::new(display_name),
+        };
+        let (reply, rx) = futures::channel::oneshot::channel();
+        self.cmd_sender
+            .unbounded_send(Command::SetProfile { profile, reply })
+            .map_err(|e| JsValue::from_str(&format!("Failed to send command: {}", e)))?;
+        let profile = rx.await.map_err(|_| JsValue::from_str("node stopped"))?.map_err(|e| error_to_js(&e))?;
+        profile_to_js(&profile)
+    }
 
-        Ok(WasmNode {
-            cmd_sender,
-            event_receiver: Arc::new(futures::lock::Mutex::new(event_receiver)),
-            peer_id: local_peer_id.to_string(),
-            shared_state,
-        })
+    /// Look up `peerId`'s profile: the newest copy signed by that peer as
+    /// `{ displayName, avatarHash, updatedAtMs }`, or null if it published none. Results
+    /// are cached for ten minutes. Rejects with `InvalidProfile` if no copy verifies.
+    #[wasm_bindgen]
+    pub async fn resolve_profile(&self, peer_id: JsValue) -> Result<JsValue, JsValue> {
+        self.ensure_dht()?;
+        let peer_id = peer_id_arg(&peer_id, "peerId")?;
+        if let Some(remote) = &self.remote {
+            return remote.call(Target::Node, "resolve_profile", &[peer_id.to_string().into()]).await;
+        }
+        let (reply, rx) = futures::channel::oneshot::channel();
+        self.cmd_sender
+            .unbounded_send(Command::ResolveProfile { peer_id, reply })
+            .map_err(|e| JsValue::from_str(&format!("Failed to send command: {}", e)))?;
+        match rx.await.map_err(|_| JsValue::from_str("node stopped"))?.map_err(|e| error_to_js(&e))? {
+            Some(profile) => profile_to_js(&profile),
+            None => Ok(JsValue::NULL),
+        }
     }
 
-    #[wasm_bindgen(getter)]
-    pub fn peer_id(&self) -> String {
-        self.peer_id.clone()
+    /// Documents whose current state the node fetches by itself: as soon as a peer serving
+    /// history (a FullNode) is connected, and again after losing touch with all of them
+    /// or after `resume()`. Each document's state arrives as a `docUpdateReceived` event,
+    /// followed by one `caughtUp { doc_count }` per run; documents that could not be
+    /// fetched are reported by `catchUpFailed` and only get live updates.
+    #[wasm_bindgen]
+    pub fn interest(&self, doc_ids: Vec<String>) -> Result<(), JsValue> {
+        if let Some(remote) = &self.remote {
+            return remote.post(Target::Node, "interest", &[string_array(&doc_ids).into()]);
+        }
+        self.cmd_sender
+            .unbounded_send(Command::Interest { doc_ids })
+            .map_err(|e| JsValue::from_str(&format!("Failed to send interest command: {}", e)))
     }
 
+    /// Start working on a document: peers its updates arrive from are kept connected,
+    /// however quiet, until `unwatch_doc()`. Peers of joined rooms are kept the same way
+    /// until the room is left.
     #[wasm_bindgen]
-    pub fn publish_update(&self, data: String) -> Result<(), JsValue> {
-        let bytes = data.into_bytes();
+    pub fn watch_doc(&self, doc_id: String) -> Result<(), JsValue> {
+        if let Some(remote) = &self.remote {
+            return remote.post(Target::Node, "watch_doc", &[doc_id.into()]);
+        }
+        self.cmd_sender
+            .unbounded_send(Command::WatchDoc { doc_id, watch: true })
+            .map_err(|e| JsValue::from_str(&format!("Failed to send command: {}", e)))
+    }
+
+    /// Stop watching a document. Its peers that no joined room or other watched document
+    /// needs go back to the idle timeout.
+    #[wasm_bindgen]
+    pub fn unwatch_doc(&self, doc_id: String) -> Result<(), JsValue> {
+        if let Some(remote) = &self.remote {
+            return remote.post(Target::Node, "unwatch_doc", &[doc_id.into()]);
+        }
+        self.cmd_sender
+            .unbounded_send(Command::WatchDoc { doc_id, watch: false })
+            .map_err(|e| JsValue::from_str(&format!("Failed to send command: {}", e)))
+    }
+
+    /// Deliver a document's updates in order from now on, as `orderedUpdate` events
+    /// marked with their `origin`. An update is held back until everything its stamp says
+    /// came before it was delivered; meanwhile the gap is re-requested from a peer serving
+    /// history. If it is not filled within 10 seconds a `gapAbandoned` event fires and
+    /// delivery carries on. `docUpdateReceived` still reports every update as it arrives.
+    #[wasm_bindgen]
+    pub fn watch_document(&self, doc_id: String) -> Result<(), JsValue> {
+        if let Some(remote) = &self.remote {
+            return remote.post(Target::Node, "watch_document", &[doc_id.into()]);
+        }
+        self.cmd_sender
+            .unbounded_send(Command::WatchDocument { doc_id, watch: true })
+            .map_err(|e| JsValue::from_str(&format!("Failed to send command: {}", e)))
+    }
+
+    /// Stop delivering a document's updates in order, dropping any held back.
+    #[wasm_bindgen]
+    pub fn unwatch_document(&self, doc_id: String) -> Result<(), JsValue> {
+        if let Some(remote) = &self.remote {
+            return remote.post(Target::Node, "unwatch_document", &[doc_id.into()]);
+        }
+        self.cmd_sender
+            .unbounded_send(Command::WatchDocument { doc_id, watch: false })
+            .map_err(|e| JsValue::from_str(&format!("Failed to send command: {}", e)))
+    }
+
+    /// Check received updates of the documents whose id starts with `prefix` before they
+    /// are applied: `validator(docId, payload: Uint8Array)` returns `true` (or nothing) to
+    /// accept, `false` or a reason string to refuse; throwing refuses too. Refused
+    /// messages are rejected, penalising the peer that sent them, and reported as
+    /// `schemaViolation` events. Snapshots are checked once assembled. `options` is
+    /// optional: `{ sinceVersion?: number }` applies the validator to envelopes of that
+    /// protocol version on (default: the current one), so documents written under an
+    /// older schema keep theirs. The longest matching prefix wins. Not available when the
+    /// node runs in a worker.
+    #[wasm_bindgen]
+    pub fn register_schema(&self, prefix: String, validator: js_sys::Function, options: JsValue) -> Result<(), JsValue> {
+        if self.remote.is_some() {
+            return Err(invalid_argument("validator", "callbacks cannot reach a node running in a worker"));
+        }
+        let since_version = if options.is_undefined() || options.is_null() {
+            None
+        } else {
+            Reflect::get(&options, &"sinceVersion".into())?.as_f64()
+        };
+        let since_version = match since_version {
+            None => crate::behaviour::docstore::CURRENT_PROTOCOL_VERSION,
+            Some(v) if (0.0..=u8::MAX as f64).contains(&v) => v as u8,
+            Some(_) => return Err(invalid_argument("sinceVersion", "not a protocol version")),
+        };
         self.cmd_sender
-            .unbounded_send(Command::Publish(bytes))
+            .unbounded_send(Command::RegisterSchema { prefix, since_version, validator: Box::new(JsSchema(validator)) })
             .map_err(|e| JsValue::from_str(&format!("Failed to send command: {}", e)))
     }
 
+    /// Stop checking the documents under `prefix`. Resolves with false if it had no
+    /// validator.
+    #[wasm_bindgen]
+    pub async fn unregister_schema(&self, prefix: String) -> Result<JsValue, JsValue> {
+        if let Some(remote) = &self.remote {
+            return remote.call(Target::Node, "unregister_schema", &[prefix.into()]).await;
+        }
+        let (reply, rx) = futures::channel::oneshot::channel();
+        self.cmd_sender
+            .unbounded_send(Command::UnregisterSchema { prefix, reply })
+            .map_err(|e| JsValue::from_str(&format!("Failed to send command: {}", e)))?;
+        Ok(rx.await.map_err(|_| JsValue::from_str("node stopped"))?.into())
+    }
+
+    /// Peer ids currently kept alive for joined rooms and watched documents, for debugging.
+    #[wasm_bindgen]
+    pub async fn keepalive_peers(&self) -> Result<JsValue, JsValue> {
+        if let Some(remote) = &self.remote {
+            return remote.call(Target::Node, "keepalive_peers", &[]).await;
+        }
+        let (reply, rx) = futures::channel::oneshot::channel();
+        self.cmd_sender
+            .unbounded_send(Command::KeepalivePeers { reply })
+            .map_err(|e| JsValue::from_str(&format!("Failed to send command: {}", e)))?;
+        let peers = rx.await.map_err(|_| JsValue::from_str("node stopped"))?;
+        let ids: Vec<String> = peers.iter().map(ToString::to_string).collect();
+        Ok(string_array(&ids).into())
+    }
+
+    /// One page of a document's logged history, from a FullNode. `options` is optional:
+    /// `{ peerId?: string, fromVersion?: number, fromTime?: number, untilMs?: number,
+    /// pageSize?: number, cursor?: number }`; without `peerId` the best-ranked connected
+    /// peer serving history is asked. Resolves with `{ peerId, updates: [{ version,
+    /// payload, appliedAtMs }], nextCursor, truncated }`. For the next page call again
+    /// with the same `peerId` and `cursor: nextCursor`, until `nextCursor` is null.
+    /// `truncated` means the start of the range was already compacted away.
+    #[wasm_bindgen]
+    pub async fn history(&self, doc_id: String, options: JsValue) -> Result<JsValue, JsValue> {
+        let options_js = options;
+        let options = history_options(&options_js)?;
+        if let Some(remote) = &self.remote {
+            let options_js = with_peer_id_string(&options_js, "peerId", options.peer_id)?;
+            return remote.call(Target::Node, "history", &[doc_id.into(), options_js]).await;
+        }
+        let (reply, rx) = futures::channel::oneshot::channel();
+        self.cmd_sender
+            .unbounded_send(Command::History { peer_id: options.peer_id, request: options.request(doc_id), reply })
+            .map_err(|e| JsValue::from_str(&format!("Failed to send history command: {}", e)))?;
+        let (peer_id, page) = rx.await.map_err(|_| JsValue::from_str("node stopped"))?.map_err(|e| error_to_js(&e))?;
+        history_page_to_js(&peer_id, &page)
+    }
+
+    /// `doc_id` as of a point in its history, reconstructed by a FullNode from its logged
+    /// history. `peer` is the FullNode's peer id, or null for the best-ranked connected one;
+    /// `options` is `{ version: number }` or `{ time: number }` (unix ms). Resolves with
+    /// `{ peerId, state: "content", version, bytes, updatedAtMs }`, `{ peerId, state:
+    /// "deleted", version, deletedAtMs }` once an empty update deleted the document, or
+    /// `{ peerId, state: "missing" }` before its first update. Rejects with
+    /// `HistoryUnavailable` (and `earliest`, the oldest version still available) for points
+    /// older than the FullNode's retention kept.
+    #[wasm_bindgen]
+    pub async fn document_at(&self, peer: JsValue, doc_id: String, options: JsValue) -> Result<JsValue, JsValue> {
+        let peer_id = if peer.is_undefined() || peer.is_null() { None } else { Some(peer_id_arg(&peer, "peer")?) };
+        let at = history_at(&options)?;
+        if let Some(remote) = &self.remote {
+            let peer = peer_id.map_or(JsValue::NULL, |p| p.to_string().into());
+            return remote.call(Target::Node, "document_at", &[peer, doc_id.into(), options]).await;
+        }
+        let (reply, rx) = futures::channel::oneshot::channel();
+        self.cmd_sender
+            .unbounded_send(Command::DocumentAt { peer_id, request: HistoryRequest::at(doc_id, at), reply })
+            .map_err(|e| JsValue::from_str(&format!("Failed to send history command: {}", e)))?;
+        let (peer_id, state) = rx.await.map_err(|_| JsValue::from_str("node stopped"))?.map_err(|e| error_to_js(&e))?;
+        document_state_to_js(&peer_id, &state)
+    }
+
+    /// `doc_id`'s current content as a Uint8Array, fetched in chunks from a FullNode
+    /// serving `/docstore/fetch/1.0.0`: `peer`, or null for the best-ranked connected one.
+    /// Every chunk is checked against the hashes the FullNode lists up front, and a
+    /// `fetchProgress` event reports it. Resolves with `{ peerId, bytes }`.
+    ///
+    /// A fetch that fails halfway, e.g. on a dropped connection, keeps what was verified
+    /// (in memory, until the page is closed): calling again resumes from there, whichever
+    /// FullNode serves it then. Without `peer` another FullNode is tried before giving up.
+    /// Chunks wait while the bandwidth budget is exhausted. Rejects with `NoFetchPeer`
+    /// when no FullNode is connected.
+    #[wasm_bindgen]
+    pub async fn fetch_document(&self, peer: JsValue, doc_id: String) -> Result<JsValue, JsValue> {
+        let peer_id = if peer.is_undefined() || peer.is_null() { None } else { Some(peer_id_arg(&peer, "peer")?) };
+        if let Some(remote) = &self.remote {
+            let peer = peer_id.map_or(JsValue::NULL, |p| p.to_string().into());
+            return remote.call(Target::Node, "fetch_document", &[peer, doc_id.into()]).await;
+        }
+        let (reply, rx) = futures::channel::oneshot::channel();
+        self.cmd_sender
+            .unbounded_send(Command::FetchDocument { doc_id, peer_id, reply })
+            .map_err(|e| JsValue::from_str(&format!("Failed to send fetch command: {}", e)))?;
+        let (peer_id, bytes) = rx.await.map_err(|_| JsValue::from_str("node stopped"))?.map_err(|e| error_to_js(&e))?;
+        let obj = Object::new();
+        Reflect::set(&obj, &"peerId".into(), &peer_id.to_string().into())?;
+        Reflect::set(&obj, &"bytes".into(), &js_sys::Uint8Array::from(bytes.as_slice()).into())?;
+        Ok(obj.into())
+    }
+
+    /// One page of everyone who ever joined room `room_id`, from a FullNode's membership
+    /// table. `options` is optional: `{ peerId?: string, after?: string, limit?: number }`;
+    /// without `peerId` the best-ranked connected FullNode is asked. Resolves with
+    /// `{ peerId, members: [{ peerId, firstJoinedAtMs, joinedAtMs, lastSeenMs, leftAtMs }],
+    /// next }`, in peer id order; `leftAtMs` is null while the member is present. For the
+    /// next page call again with the same `peerId` and `after: next`, until `next` is null.
+    /// Rejects with `MembersUnavailable` for rooms joined with `privateMembers`, and with
+    /// `NoMembersPeer` when no FullNode is connected.
+    #[wasm_bindgen]
+    pub async fn room_members(&self, room_id: String, options: JsValue) -> Result<JsValue, JsValue> {
+        let room = RoomId::new(room_id).map_err(|e| error_to_js(&e))?;
+        let parsed = members_options(&options)?;
+        if let Some(remote) = &self.remote {
+            let options = with_peer_id_string(&options, "peerId", parsed.peer_id)?;
+            return remote.call(Target::Node, "room_members", &[room.as_str().into(), options]).await;
+        }
+        room_members(&self.cmd_sender, room.as_str().to_string(), &parsed).await
+    }
+
+    /// Leave `bytes` for `recipient` with `relay_peer`, a relay server or FullNode serving
+    /// `/docstore/mailbox/1.0.0`, to be fetched when the recipient next connects to it. The
+    /// holder can read the bytes, so encrypt them for the recipient first. Kept for
+    /// `ttl_ms`, or the holder's default (7 days). Resolves with `{ id, expiresAtMs }`;
+    /// rejects with `MailboxRejected` when a quota is exceeded.
+    #[wasm_bindgen]
+    pub async fn send_to_mailbox(
+        &self,
+        relay_peer: JsValue,
+        recipient: JsValue,
+        bytes: Vec<u8>,
+        ttl_ms: Option<f64>,
+    ) -> Result<JsValue, JsValue> {
+        let holder = peer_id_arg(&relay_peer, "relayPeer")?;
+        let recipient = peer_id_arg(&recipient, "recipient")?;
+        if let Some(remote) = &self.remote {
+            let args = [
+                holder.to_string().into(),
+                recipient.to_string().into(),
+                js_sys::Uint8Array::from(bytes.as_slice()).into(),
+                ttl_ms.map_or(JsValue::UNDEFINED, JsValue::from_f64),
+            ];
+            return remote.call(Target::Node, "send_to_mailbox", &args).await;
+        }
+        // 0 asks for the holder's default
+        let ttl_ms = ttl_ms.map_or(0, |ms| ms.max(1.0) as u64);
+        let (reply, rx) = futures::channel::oneshot::channel();
+        self.cmd_sender
+            .unbounded_send(Command::SendToMailbox { holder, recipient, blob: bytes, ttl_ms, reply })
+            .map_err(|e| JsValue::from_str(&format!("Failed to send command: {}", e)))?;
+        let receipt = rx.await.map_err(|_| JsValue::from_str("node stopped"))?.map_err(|e| error_to_js(&e))?;
+        let obj = Object::new();
+        Reflect::set(&obj, &"id".into(), &(receipt.id as f64).into())?;
+        Reflect::set(&obj, &"expiresAtMs".into(), &(receipt.expires_at_ms as f64).into())?;
+        Ok(obj.into())
+    }
+
+    /// Fetch the messages `relay_peer` holds for us, oldest first, as `[{ id, sender, data,
+    /// depositedAtMs, expiresAtMs }]`, and acknowledge them so it deletes them. Messages
+    /// are also fetched whenever a holder identifies, as `mailboxDelivered` events.
     #[wasm_bindgen]
-    pub fn find_peer(&self, peer_id: String) -> Result<(), JsValue> {
-        let pid: PeerId = peer_id
-            .parse()
-            .map_err(|e| JsValue::from_str(&format!("invalid peer id: {e}")))?;
-        // Run the query via kademlia
-        match self.cmd_sender.unbounded_send(Command::FindPeer(pid)) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(JsValue::from_str(&format!("Failed to send find peer command: {}", e))),
+    pub async fn check_mailbox(&self, relay_peer: JsValue) -> Result<JsValue, JsValue> {
+        let holder = peer_id_arg(&relay_peer, "relayPeer")?;
+        if let Some(remote) = &self.remote {
+            return remote.call(Target::Node, "check_mailbox", &[holder.to_string().into()]).await;
+        }
+        let (reply, rx) = futures::channel::oneshot::channel();
+        self.cmd_sender
+            .unbounded_send(Command::CheckMailbox { holder, reply })
+            .map_err(|e| JsValue::from_str(&format!("Failed to send command: {}", e)))?;
+        let messages = rx.await.map_err(|_| JsValue::from_str("node stopped"))?.map_err(|e| error_to_js(&e))?;
+        let out = js_sys::Array::new();
+        for message in &messages {
+            out.push(&mailbox_message_to_js(message)?);
         }
+        Ok(out.into())
     }
 
     /// Listen on relay circuit (for incoming browser-to-browser connections)
     /// relay_multiaddr: e.g., "/ip4/127.0.0.1/udp/9090/webrtc-direct/certhash/<hash>/p2p/<relay-id>"
     #[wasm_bindgen]
-    pub fn listen_on_relay(&self, relay_multiaddr: String) -> Result<(), JsValue> {
-        let addr: Multiaddr = relay_multiaddr
-            .parse()
-            .map_err(|e| JsValue::from_str(&format!("invalid relay multiaddr: {e}")))?;
-        
+    pub fn listen_on_relay(&self, relay_multiaddr: JsValue) -> Result<(), JsValue> {
+        let addr = multiaddr_arg(&relay_multiaddr, "relayMultiaddr")?;
+        if let Some(remote) = &self.remote {
+            return remote.post(Target::Node, "listen_on_relay", &[addr.to_string().into()]);
+        }
+
         self.cmd_sender
             .unbounded_send(Command::ListenOnRelay { relay_addr: addr })
             .map_err(|e| JsValue::from_str(&format!("Failed to send listen on relay command: {}", e)))
@@ -621,6 +6625,9 @@ impl WasmNode {
     /// Start listening for incoming WebRTC connections (call after listen_on_relay)
     #[wasm_bindgen]
     pub fn listen_for_webrtc(&self) -> Result<(), JsValue> {
+        if let Some(remote) = &self.remote {
+            return remote.post(Target::Node, "listen_for_webrtc", &[]);
+        }
         self.cmd_sender
             .unbounded_send(Command::ListenForWebRTC)
             .map_err(|e| JsValue::from_str(&format!("Failed to send listen for webrtc command: {}", e)))
@@ -628,108 +6635,127 @@ impl WasmNode {
 
     /// Dial a peer using browser-to-browser WebRTC via relay
     /// peer_addr: e.g., "/ip4/.../p2p/<relay-id>/p2p-circuit/webrtc/p2p/<peer-id>"
+    /// `options` is optional: `{ retries?: number, backoffMs?: number, timeoutMs?: number,
+    /// requirePeerId?: boolean }`. Refused and timed-out attempts are retried with doubling
+    /// backoff; a final failure emits `dialFailed` with the number of `attempts`.
     #[wasm_bindgen]
-    pub fn dial_peer(&self, peer_addr: String) -> Result<(), JsValue> {
-        let addr: Multiaddr = peer_addr
-            .parse()
-            .map_err(|e| JsValue::from_str(&format!("invalid peer multiaddr: {e}")))?;
+    pub fn dial_peer(&self, peer_addr: JsValue, options: JsValue) -> Result<(), JsValue> {
+        let addr = crate::node::addrs::validate_browser_addr(&text_arg(&peer_addr, "peerAddr")?)
+            .into_result()
+            .map_err(|e| invalid_argument("peerAddr", e))?;
+        let options_js = options;
+        let options = dial_options(&options_js)?;
+        options.check(&addr).map_err(|e| error_to_js(&e))?;
+        // Our own circuit address, e.g. from a discovered peer list
+        if let Ok(local) = self.peer_id.parse::<PeerId>() {
+            crate::node::addrs::check_not_self(&local, &addr).map_err(|e| error_to_js(&e))?;
+        }
+        if let Some(remote) = &self.remote {
+            return remote.post(Target::Node, "dial_peer", &[addr.to_string().into(), options_js]);
+        }
         
         self.cmd_sender
-            .unbounded_send(Command::DialPeer { addr })
+            .unbounded_send(Command::DialPeer { addr, options })
             .map_err(|e| JsValue::from_str(&format!("Failed to send dial peer command: {}", e)))
     }
 
+    /// Close all connections to a peer and forget its DHT addresses. The peer may reconnect.
+    #[wasm_bindgen]
+    pub fn disconnect_peer(&self, peer_id: JsValue) -> Result<(), JsValue> {
+        let pid = peer_id_arg(&peer_id, "peerId")?;
+        if let Some(remote) = &self.remote {
+            return remote.post(Target::Node, "disconnect_peer", &[pid.to_string().into()]);
+        }
+        self.cmd_sender
+            .unbounded_send(Command::DisconnectPeer { peer_id: pid })
+            .map_err(|e| JsValue::from_str(&format!("Failed to send disconnect command: {}", e)))
+    }
+
+    /// Drop a known-bad address (e.g. a stale relay circuit) of a peer from the DHT
+    /// routing table. Emits an `addressRemoved` event.
+    #[wasm_bindgen]
+    pub fn remove_peer_address(&self, peer_id: JsValue, addr: JsValue) -> Result<(), JsValue> {
+        let pid = peer_id_arg(&peer_id, "peerId")?;
+        let addr = multiaddr_arg(&addr, "addr")?;
+        if let Some(remote) = &self.remote {
+            return remote.post(Target::Node, "remove_peer_address", &[pid.to_string().into(), addr.to_string().into()]);
+        }
+        self.cmd_sender
+            .unbounded_send(Command::RemovePeerAddress { peer_id: pid, addr })
+            .map_err(|e| JsValue::from_str(&format!("Failed to send remove address command: {}", e)))
+    }
+
+    /// Drop a peer from the DHT routing table without disconnecting it. Emits a
+    /// `peerRemoved` event.
+    #[wasm_bindgen]
+    pub fn remove_peer(&self, peer_id: JsValue) -> Result<(), JsValue> {
+        let pid = peer_id_arg(&peer_id, "peerId")?;
+        if let Some(remote) = &self.remote {
+            return remote.post(Target::Node, "remove_peer", &[pid.to_string().into()]);
+        }
+        self.cmd_sender
+            .unbounded_send(Command::RemovePeer { peer_id: pid })
+            .map_err(|e| JsValue::from_str(&format!("Failed to send remove peer command: {}", e)))
+    }
+
+    /// Disconnect a peer and refuse it for `duration_ms`. Reconnect attempts surface as
+    /// `bannedPeerRejected` events. Bans last until the page is reloaded at most; a
+    /// duration of `Infinity` lasts that long.
+    #[wasm_bindgen]
+    pub fn ban_peer(&self, peer_id: JsValue, duration_ms: f64) -> Result<(), JsValue> {
+        let pid = peer_id_arg(&peer_id, "peerId")?;
+        if let Some(remote) = &self.remote {
+            return remote.post(Target::Node, "ban_peer", &[pid.to_string().into(), duration_ms.into()]);
+        }
+        let duration = std::time::Duration::from_millis(duration_ms.max(0.0) as u64);
+        self.cmd_sender
+            .unbounded_send(Command::BanPeer { peer_id: pid, duration })
+            .map_err(|e| JsValue::from_str(&format!("Failed to send ban command: {}", e)))
+    }
+
     /// Legacy method for backward compatibility - automatically detects relay from connected peers
     #[wasm_bindgen]
     pub fn start_listen(&self) -> Result<(), JsValue> {
         // For backward compatibility, we'll try to auto-detect relay
         // In the new implementation, users should call listen_on_relay() followed by listen_for_webrtc()
-        log("⚠ start_listen() is deprecated. Please use listen_on_relay() and listen_for_webrtc()");
-        log("ℹ For now, please manually specify the relay address using listen_on_relay()");
+        tracing::warn!("⚠ start_listen() is deprecated. Please use listen_on_relay() and listen_for_webrtc()");
+        tracing::info!("ℹ For now, please manually specify the relay address using listen_on_relay()");
         Err(JsValue::from_str("Please use listen_on_relay(relay_addr) instead"))
     }
 
     #[wasm_bindgen]
-    pub fn send_direct(&self, peer_id: String, data: String) -> Result<(), JsValue> {
-        let pid: PeerId = peer_id
-            .parse()
-            .map_err(|e| JsValue::from_str(&format!("invalid peer id: {e}")))?;
+    pub fn send_direct(&self, peer_id: JsValue, data: String) -> Result<(), JsValue> {
+        self.ensure_writable()?;
+        let pid = peer_id_arg(&peer_id, "peerId")?;
+        if let Some(remote) = &self.remote {
+            return remote.post(Target::Node, "send_direct", &[pid.to_string().into(), data.into()]);
+        }
         let bytes = data.into_bytes();
         self.cmd_sender
             .unbounded_send(Command::SendDirect { peer_id: pid, data: bytes })
             .map_err(|e| JsValue::from_str(&format!("Failed to send direct message command: {}", e)))
     }
 
+    /// Resolves with the next event, or `{ type: "closed" }` once the node has stopped and
+    /// no events remain. Concurrent calls are served in turn; each event reaches one caller.
     #[wasm_bindgen]
     pub async fn next_event(&self) -> Result<JsValue, JsValue> {
-        let mut receiver = self.event_receiver.lock().await;
-        if let Some(event) = receiver.next().await {
-            let obj = Object::new();
-            match event {
-                Event::Connected { peer_id } => {
-                    Reflect::set(&obj, &"type".into(), &"connected".into())?;
-                    Reflect::set(&obj, &"peer_id".into(), &peer_id.into())?;
-                }
-                Event::Disconnected { peer_id } => {
-                    Reflect::set(&obj, &"type".into(), &"disconnected".into())?;
-                    Reflect::set(&obj, &"peer_id".into(), &peer_id.into())?;
-                }
-                Event::MessageReceived { peer_id, data } => {
-                    Reflect::set(&obj, &"type".into(), &"messageReceived".into())?;
-                    Reflect::set(&obj, &"peer_id".into(), &peer_id.into())?;
-                    Reflect::set(&obj, &"data".into(), &data.into())?;
-                }
-                Event::MessagePublished { msg_id } => {
-                    Reflect::set(&obj, &"type".into(), &"messagePublished".into())?;
-                    Reflect::set(&obj, &"msg_id".into(), &msg_id.into())?;
-                }
-                Event::PeerDiscovery { peer_id, addrs } => {
-                    Reflect::set(&obj, &"type".into(), &"peerDiscovery".into())?;
-                    Reflect::set(&obj, &"peer_id".into(), &peer_id.into())?;
-                    let js_arr = js_sys::Array::new();
-                    for a in addrs.iter() {
-                        js_arr.push(&JsValue::from_str(a));
-                    }
-                    Reflect::set(&obj, &"addrs".into(), &js_arr.into())?;
-                }
-                Event::DirectMessageReceived { peer_id, data } => {
-                    Reflect::set(&obj, &"type".into(), &"directMessageReceived".into())?;
-                    Reflect::set(&obj, &"peer_id".into(), &peer_id.into())?;
-                    Reflect::set(&obj, &"data".into(), &data.into())?;
-                }
-                Event::DirectMessageSent { peer_id } => {
-                    Reflect::set(&obj, &"type".into(), &"directMessageSent".into())?;
-                    Reflect::set(&obj, &"peer_id".into(), &peer_id.into())?;
-                }
-                Event::ListenStarted { addr } => {
-                    Reflect::set(&obj, &"type".into(), &"listenStarted".into())?;
-                    Reflect::set(&obj, &"addr".into(), &addr.into())?;
-                }
-                Event::RelayReservationCreated { addr } => {
-                    Reflect::set(&obj, &"type".into(), &"relayReservationCreated".into())?;
-                    Reflect::set(&obj, &"addr".into(), &addr.into())?;
-                }
-                Event::RelayConnectionEstablished { peer_id } => {
-                    Reflect::set(&obj, &"type".into(), &"relayConnectionEstablished".into())?;
-                    Reflect::set(&obj, &"peer_id".into(), &peer_id.into())?;
-                }
-                Event::WebRTCConnectionEstablished { peer_id } => {
-                    Reflect::set(&obj, &"type".into(), &"webrtcConnectionEstablished".into())?;
-                    Reflect::set(&obj, &"peer_id".into(), &peer_id.into())?;
-                }
-                Event::Error { msg } => {
-                    Reflect::set(&obj, &"type".into(), &"error".into())?;
-                    Reflect::set(&obj, &"msg".into(), &msg.into())?;
-                }
-            }
-            Ok(obj.into())
-        } else {
-            Err(JsValue::from_str("No events available"))
-        }
+        next_to_js(event_queue::next(&self.event_receiver).await)
+    }
+
+    /// Like `next_event()`, but resolves with `null` if no event arrived within `ms`
+    /// milliseconds. An event is never lost to the timeout: it stays queued for the next call.
+    #[wasm_bindgen]
+    pub async fn next_event_timeout(&self, ms: u32) -> Result<JsValue, JsValue> {
+        let deadline = futures_timer::Delay::new(std::time::Duration::from_millis(ms as u64));
+        next_to_js(event_queue::next_until(&self.event_receiver, deadline).await)
     }
 
     #[wasm_bindgen]
     pub async fn get_network_status(&self) -> Result<JsValue, JsValue> {
+        if let Some(remote) = &self.remote {
+            return remote.call(Target::Node, "get_network_status", &[]).await;
+        }
         let state = self.shared_state.lock().await;
         let obj = Object::new();
         
@@ -750,6 +6776,13 @@ impl WasmNode {
             Reflect::set(&connected_peers, &peer_id.as_str().into(), &addrs_arr.into())?;
         }
         Reflect::set(&obj, &"connected_peers".into(), &connected_peers.into())?;
+
+        // Connection quality of the connected peers, see `peer_quality()`
+        let peer_quality = Object::new();
+        for (peer_id, quality) in self.quality.all() {
+            Reflect::set(&peer_quality, &peer_id.to_string().into(), &quality_to_js(&quality)?)?;
+        }
+        Reflect::set(&obj, &"peer_quality".into(), &peer_quality.into())?;
         
         // Convert discovered_peers (HashMap<String, Vec<String>>)
         let discovered_peers = Object::new();
@@ -777,6 +6810,8 @@ impl WasmNode {
             Reflect::set(&relay_obj, &"full_addr".into(), &JsValue::from_str(&relay.full_addr))?;
             Reflect::set(&relay_obj, &"connected_at".into(), &JsValue::from_f64(relay.connected_at))?;
             Reflect::set(&relay_obj, &"supports_relay".into(), &JsValue::from_bool(relay.supports_relay))?;
+            Reflect::set(&relay_obj, &"rtt_ms".into(), &relay.rtt_ms.map_or(JsValue::NULL, JsValue::from_f64))?;
+            Reflect::set(&relay_obj, &"preferred".into(), &JsValue::from_bool(relay.preferred))?;
             relays.push(&relay_obj.into());
         }
         Reflect::set(&obj, &"relays".into(), &relays.into())?;
@@ -784,3 +6819,61 @@ impl WasmNode {
         Ok(obj.into())
     }
 }
+
+/// Hooks for `tests/wasm.rs`, only built with the `test-util` feature.
+#[cfg(feature = "test-util")]
+#[wasm_bindgen]
+impl WasmNode {
+    /// Deliver a synthetic `docUpdateReceived` event through the event loop, so it takes
+    /// the same path to `next_event()`, subscriptions and history as a received update.
+    #[doc(hidden)]
+    #[wasm_bindgen]
+    pub fn inject_doc_update(&self, peer_id: String, doc_id: String, data: String) -> Result<(), JsValue> {
+        if let Some(remote) = &self.remote {
+            return remote.post(Target::Node, "inject_doc_update", &[peer_id.into(), doc_id.into(), data.into()]);
+        }
+        let topic = self.docstore_config.topics.updates().to_string();
+        self.cmd_sender
+            .unbounded_send(Command::InjectEvent(Event::DocUpdateReceived { peer_id, topic, doc_id, data }))
+            .map_err(|e| JsValue::from_str(&format!("Failed to send inject command: {}", e)))
+    }
+}
+
+impl WasmNode {
+    /// `with_config()`, with every event going to `tap` if set, see [`EventSink`].
+    pub(crate) async fn launch(opts: JsValue, tap: Option<mpsc::UnboundedSender<Event>>) -> Result<WasmNode, JsValue> {
+        let (node, ready) = Self::start(bootstrap_arg(&opts)?, opts, tap)?;
+        ready.await.map_err(|_| error_to_js(&crate::Error::NodeStopped))?.map_err(|e| error_to_js(&e))?;
+        Ok(node)
+    }
+
+    fn room_handle(&self, room_id: String, is_creator: bool, link_permission: Option<Permission>) -> WasmRoom {
+        WasmRoom {
+            room_id,
+            cmd_sender: self.cmd_sender.clone(),
+            rooms: self.rooms.clone(),
+            docstore_config: self.docstore_config.clone(),
+            read_only: self.read_only,
+            is_creator,
+            link_permission,
+            outbox: self.outbox.clone(),
+            remote: self.remote.clone(),
+        }
+    }
+
+    /// Observers are rejected here so nothing is ever queued for the swarm.
+    fn ensure_writable(&self) -> Result<(), JsValue> {
+        if self.read_only {
+            return Err(error_to_js(&crate::Error::ReadOnly));
+        }
+        Ok(())
+    }
+
+    /// Nodes built with `{ dht: false }` reject DHT calls up front.
+    fn ensure_dht(&self) -> Result<(), JsValue> {
+        if !self.dht_enabled {
+            return Err(error_to_js(&crate::Error::DhtDisabled));
+        }
+        Ok(())
+    }
+}
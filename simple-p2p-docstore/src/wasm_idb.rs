@@ -0,0 +1,71 @@
+#![cfg(target_arch = "wasm32")]
+//! Byte values kept in IndexedDB, for the little state a browser node keeps across page
+//! reloads (see [`crate::node::dedup_cache`]). One database with one object store;
+//! values are `Uint8Array`s under string keys. Works in pages and workers alike.
+
+use js_sys::{Promise, Reflect, Uint8Array};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{IdbDatabase, IdbFactory, IdbObjectStore, IdbOpenDbRequest, IdbRequest, IdbTransactionMode};
+
+const DB_NAME: &str = "simple-p2p-docstore";
+const DB_VERSION: u32 = 1;
+const STORE: &str = "state";
+
+pub(crate) async fn get(key: &str) -> Result<Option<Vec<u8>>, JsValue> {
+    let store = object_store(IdbTransactionMode::Readonly).await?;
+    let value = done(&store.get(&key.into())?).await?;
+    Ok(value.dyn_ref::<Uint8Array>().map(Uint8Array::to_vec))
+}
+
+pub(crate) async fn put(key: &str, bytes: &[u8]) -> Result<(), JsValue> {
+    let store = object_store(IdbTransactionMode::Readwrite).await?;
+    done(&store.put_with_key(&Uint8Array::from(bytes), &key.into())?).await.map(drop)
+}
+
+pub(crate) async fn delete(key: &str) -> Result<(), JsValue> {
+    let store = object_store(IdbTransactionMode::Readwrite).await?;
+    done(&store.delete(&key.into())?).await.map(drop)
+}
+
+async fn object_store(mode: IdbTransactionMode) -> Result<IdbObjectStore, JsValue> {
+    let factory: IdbFactory = Reflect::get(&js_sys::global(), &"indexedDB".into())?
+        .dyn_into()
+        .map_err(|_| JsValue::from_str("IndexedDB is not available"))?;
+    let open: IdbOpenDbRequest = factory.open_with_u32(DB_NAME, DB_VERSION)?;
+    // First open: create the store before the open succeeds
+    let upgrade = Closure::once_into_js(move |event: web_sys::Event| {
+        let db = event
+            .target()
+            .and_then(|target| target.dyn_into::<IdbOpenDbRequest>().ok())
+            .and_then(|request| request.result().ok())
+            .and_then(|db| db.dyn_into::<IdbDatabase>().ok());
+        if let Some(db) = db.filter(|db| !db.object_store_names().contains(STORE)) {
+            if let Err(e) = db.create_object_store(STORE) {
+                tracing::warn!("Could not create the IndexedDB store: {:?}", e);
+            }
+        }
+    });
+    open.set_onupgradeneeded(Some(upgrade.unchecked_ref()));
+    let db: IdbDatabase = done(&open).await?.dyn_into()?;
+    db.transaction_with_str_and_mode(STORE, mode)?.object_store(STORE)
+}
+
+/// The result of `request`, once it succeeded.
+async fn done(request: &IdbRequest) -> Result<JsValue, JsValue> {
+    let promise = Promise::new(&mut |resolve, reject| {
+        let succeeded = request.clone();
+        let on_success = Closure::once_into_js(move || {
+            let _ = resolve.call1(&JsValue::NULL, &succeeded.result().unwrap_or(JsValue::UNDEFINED));
+        });
+        let failed = request.clone();
+        let on_error = Closure::once_into_js(move || {
+            let error = failed.error().ok().flatten().map_or_else(|| JsValue::from_str("IndexedDB request failed"), JsValue::from);
+            let _ = reject.call1(&JsValue::NULL, &error);
+        });
+        request.set_onsuccess(Some(on_success.unchecked_ref()));
+        request.set_onerror(Some(on_error.unchecked_ref()));
+    });
+    JsFuture::from(promise).await
+}
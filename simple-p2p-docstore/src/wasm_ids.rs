@@ -0,0 +1,106 @@
+//! Peer ids and multiaddrs at the JS boundary.
+//!
+//! `WasmNode` methods take either a string or a [`PeerIdWrapper`] / [`MultiaddrWrapper`],
+//! and all of them parse through [`peer_id_arg`] and [`multiaddr_arg`], so a bad value
+//! is rejected the same way everywhere: `{ code: "InvalidArgument", message,
+//! invalidField }`, `invalidField` being the parameter or option at fault.
+
+use libp2p::{Multiaddr, PeerId};
+use wasm_bindgen::{prelude::*, JsCast};
+
+use crate::node::ids;
+use crate::wasm_bindings::error_to_js;
+
+/// A parsed peer id, to validate input before handing it to the node.
+#[wasm_bindgen]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerIdWrapper {
+    peer_id: PeerId,
+}
+
+#[wasm_bindgen]
+impl PeerIdWrapper {
+    /// Parse a base58 (`12D3KooW…`, `Qm…`) or CIDv1 (`bafz…`) peer id. Rejects with an
+    /// `InvalidArgument` error.
+    #[wasm_bindgen]
+    pub fn parse(text: &str) -> Result<PeerIdWrapper, JsValue> {
+        let peer_id = ids::parse_peer_id(text).map_err(|reason| invalid_argument("peerId", reason))?;
+        Ok(Self { peer_id })
+    }
+
+    #[wasm_bindgen(js_name = isValid)]
+    pub fn is_valid(text: &str) -> bool {
+        ids::parse_peer_id(text).is_ok()
+    }
+
+    /// The base58 form, whichever encoding was parsed.
+    #[wasm_bindgen(js_name = toString)]
+    pub fn to_js_string(&self) -> String {
+        self.peer_id.to_string()
+    }
+
+    /// The first and last few characters, for display: `12D3Ko…AJU5SA`.
+    #[wasm_bindgen(js_name = shortId)]
+    pub fn short_id(&self) -> String {
+        ids::short_id(&self.peer_id.to_string())
+    }
+}
+
+/// A parsed multiaddr. Whether a browser can dial it is for
+/// `WasmNode.validate_multiaddr()` to say.
+#[wasm_bindgen]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MultiaddrWrapper {
+    addr: Multiaddr,
+}
+
+#[wasm_bindgen]
+impl MultiaddrWrapper {
+    /// Rejects with an `InvalidArgument` error.
+    #[wasm_bindgen]
+    pub fn parse(text: &str) -> Result<MultiaddrWrapper, JsValue> {
+        let addr = ids::parse_multiaddr(text).map_err(|reason| invalid_argument("multiaddr", reason))?;
+        Ok(Self { addr })
+    }
+
+    #[wasm_bindgen(js_name = isValid)]
+    pub fn is_valid(text: &str) -> bool {
+        ids::parse_multiaddr(text).is_ok()
+    }
+
+    #[wasm_bindgen(js_name = toString)]
+    pub fn to_js_string(&self) -> String {
+        self.addr.to_string()
+    }
+
+    /// The address with its `/p2p` peer ids shortened, for display.
+    #[wasm_bindgen(js_name = shortId)]
+    pub fn short_id(&self) -> String {
+        ids::short_multiaddr(&self.addr)
+    }
+}
+
+/// `{ code: "InvalidArgument", message: reason, invalidField: field }`.
+pub(crate) fn invalid_argument(field: &str, reason: impl Into<String>) -> JsValue {
+    error_to_js(&crate::Error::InvalidArgument { field: field.to_string(), reason: reason.into() })
+}
+
+/// The text of a peer id or multiaddr argument: a string, or an object whose
+/// `toString()` gives one, such as the wrappers or a js-libp2p `PeerId`.
+pub(crate) fn text_arg(value: &JsValue, field: &str) -> Result<String, JsValue> {
+    if let Some(text) = value.as_string() {
+        return Ok(text);
+    }
+    match value.dyn_ref::<js_sys::Object>() {
+        Some(object) => Ok(object.to_string().into()),
+        None => Err(invalid_argument(field, format!("{field} must be a string, got {value:?}"))),
+    }
+}
+
+pub(crate) fn peer_id_arg(value: &JsValue, field: &str) -> Result<PeerId, JsValue> {
+    ids::parse_peer_id(&text_arg(value, field)?).map_err(|reason| invalid_argument(field, reason))
+}
+
+pub(crate) fn multiaddr_arg(value: &JsValue, field: &str) -> Result<Multiaddr, JsValue> {
+    ids::parse_multiaddr(&text_arg(value, field)?).map_err(|reason| invalid_argument(field, reason))
+}
@@ -0,0 +1,90 @@
+#![cfg(target_arch = "wasm32")]
+//! Console logging for browser nodes, routed through `tracing` and `tracing-wasm`.
+//!
+//! The level is checked before an event is built, so disabled log lines cost neither the
+//! formatting nor the console call. It is a single setting for the whole wasm module: every
+//! `WasmNode` on the page shares it.
+
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Once;
+
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Layer;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum LogLevel {
+    Off = 0,
+    Error = 1,
+    /// Also includes warnings.
+    Info = 2,
+    /// Per-event detail such as Kademlia query progress and received messages.
+    Debug = 3,
+    /// Everything, including a dump of every behaviour event.
+    Trace = 4,
+}
+
+impl LogLevel {
+    fn filter(self) -> LevelFilter {
+        match self {
+            LogLevel::Off => LevelFilter::OFF,
+            LogLevel::Error => LevelFilter::ERROR,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Debug => LevelFilter::DEBUG,
+            LogLevel::Trace => LevelFilter::TRACE,
+        }
+    }
+
+    fn from_u8(v: u8) -> Self {
+        match v {
+            0 => LogLevel::Off,
+            1 => LogLevel::Error,
+            2 => LogLevel::Info,
+            3 => LogLevel::Debug,
+            _ => LogLevel::Trace,
+        }
+    }
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "off" => Ok(LogLevel::Off),
+            "error" => Ok(LogLevel::Error),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            "trace" => Ok(LogLevel::Trace),
+            other => Err(format!("unknown log level '{other}' (expected off, error, info, debug or trace)")),
+        }
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+static INIT: Once = Once::new();
+
+pub fn level() -> LogLevel {
+    LogLevel::from_u8(LEVEL.load(Ordering::Relaxed))
+}
+
+pub fn set_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Install the console subscriber. Safe to call more than once; only the first call has
+/// an effect, and it leaves an already installed global subscriber in place.
+pub fn init() {
+    INIT.call_once(|| {
+        let config = tracing_wasm::WASMLayerConfigBuilder::new()
+            // Performance marks for every event are far too slow on busy pages
+            .set_report_logs_in_timings(false)
+            .set_max_level(tracing::Level::TRACE)
+            .build();
+        let layer = tracing_wasm::WASMLayer::new(config)
+            .with_filter(tracing_subscriber::filter::filter_fn(|meta| *meta.level() <= level().filter()));
+        let _ = tracing::subscriber::set_global_default(tracing_subscriber::registry().with(layer));
+    });
+}
@@ -5,7 +5,9 @@
 //! - WebRTC transport (for direct browser-to-browser connections)
 //! - Relay client transport (for Circuit Relay v2 signaling)
 //! - WebRTC-direct transport (for connecting to relay servers via WebRTC)
+//! - WebTransport (for servers announcing `/webtransport` addresses)
 //!
+//! Each transport only accepts its own kind of multiaddr, so dialing picks the right one.
 //! The design is modular to allow future expansion (e.g., adding WebSocket transport).
 
 use std::sync::Arc;
@@ -157,8 +159,19 @@ pub fn build_composite_transport(
             futures::future::Either::Right(output) => output,
         });
 
-    // 4. Build the final composite transport
-    // StandardWebRTC OR BrowserWebRTC OR Relay
+    // 4. WebTransport to servers, as an alternative to webrtc-direct
+    let webtransport = libp2p_webtransport_websys::Transport::new(libp2p_webtransport_websys::Config::new(&config.keypair))
+        .map(|(peer_id, connection), _| (peer_id, StreamMuxerBox::new(connection)))
+        .boxed();
+    let combined_webrtc = combined_webrtc
+        .or_transport(webtransport)
+        .map(|either, _| match either {
+            futures::future::Either::Left(output) => output,
+            futures::future::Either::Right(output) => output,
+        });
+
+    // 5. Build the final composite transport
+    // StandardWebRTC OR BrowserWebRTC OR WebTransport OR Relay
     let final_transport = if config.enable_websocket {
         // Future expansion: Add WebSocket transport
         combined_webrtc
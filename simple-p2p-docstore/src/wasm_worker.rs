@@ -0,0 +1,469 @@
+#![cfg(target_arch = "wasm32")]
+//! Running a `WasmNode` in a dedicated Web Worker, so the swarm, its crypto and its timers
+//! stay off the page's main thread.
+//!
+//! `WasmNode.spawn_in_worker()` starts a worker from a small script that loads this module
+//! and calls [`worker_main`]. The worker runs an ordinary node; the main thread gets a
+//! `WasmNode` whose methods are forwarded to it over `postMessage`, with the same
+//! signatures and results. Messages, both ways:
+//!
+//! - `{ type: "loaded" }` once the worker listens, then `start` (the `with_config`
+//!   options) answered by `started` (with the peer id) or `failed`.
+//! - `call`: a method of the node, of a joined room, or a transaction commit, answered by
+//!   `reply` when the caller waits for the result. Calls nobody waits for report their
+//!   failure as `error`.
+//! - `event`: every event of the worker's node, room traffic included, postcard-encoded
+//!   into an `ArrayBuffer` that is transferred rather than copied. The main thread keeps
+//!   the history, subscriptions and room handles as an in-thread node does.
+//! - `mirror`: traffic stats and peer scores, once a second, for the methods that must
+//!   answer synchronously.
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::rc::Rc;
+
+use futures::channel::{mpsc, oneshot};
+use futures::StreamExt;
+use js_sys::{Array, Function, Object, Promise, Reflect, Uint8Array};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::{spawn_local, JsFuture};
+use web_sys::{DedicatedWorkerGlobalScope, MessageEvent, Worker, WorkerOptions, WorkerType};
+
+use crate::behaviour::docstore::Transaction;
+use crate::wasm_bindings::{error_to_js, Event, WasmNode, WasmTransaction};
+
+/// How often the worker sends its stats and peer scores.
+const MIRROR_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// What a forwarded call runs on.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Target<'a> {
+    Node,
+    /// The worker's handle on a joined room.
+    Room(&'a str),
+    /// Commit of a transaction put together on the main thread.
+    Transaction,
+}
+
+type Pending = Rc<RefCell<HashMap<u32, oneshot::Sender<Result<JsValue, JsValue>>>>>;
+
+/// Main-thread end of a node running in a worker. Clones share the worker, which is
+/// terminated once the last of them is dropped.
+#[derive(Clone)]
+pub(crate) struct WorkerLink {
+    inner: Rc<LinkInner>,
+}
+
+struct LinkInner {
+    worker: Worker,
+    next_id: Cell<u32>,
+    pending: Pending,
+    mirror: Rc<RefCell<Mirror>>,
+    _onmessage: Closure<dyn FnMut(MessageEvent)>,
+    _onerror: Closure<dyn FnMut(web_sys::ErrorEvent)>,
+}
+
+impl Drop for LinkInner {
+    fn drop(&mut self) {
+        self.worker.set_onmessage(None);
+        self.worker.set_onerror(None);
+        self.worker.terminate();
+    }
+}
+
+/// The worker node's state last sent with `mirror`.
+struct Mirror {
+    stats: JsValue,
+    /// Best first.
+    scores: Vec<(String, f64)>,
+}
+
+impl WorkerLink {
+    /// Start the worker script at `url`, have it start a node with `opts` and wait until
+    /// that node is up. Returns the node's peer id along with the link. `on_event` gets
+    /// every event the node emits, `on_error` the failure of every call nobody waited for.
+    pub(crate) async fn spawn<E: DeserializeOwned + 'static>(
+        url: &str,
+        opts: &JsValue,
+        on_event: impl Fn(E) + 'static,
+        on_error: impl Fn(String) + 'static,
+    ) -> Result<(Self, String), JsValue> {
+        let options = WorkerOptions::new();
+        options.set_type(WorkerType::Module);
+        let worker = Worker::new_with_options(url, &options)?;
+        let pending: Pending = Rc::default();
+        let mirror = Rc::new(RefCell::new(Mirror { stats: Object::new().into(), scores: Vec::new() }));
+        #[allow(clippy::disallowed_methods)]
+        let (lifecycle, mut lifecycle_rx) = mpsc::unbounded();
+        let lifecycle_on_error = lifecycle.clone();
+
+        let onmessage = {
+            let pending = pending.clone();
+            let mirror = mirror.clone();
+            Closure::<dyn FnMut(MessageEvent)>::new(move |msg: MessageEvent| {
+                let data = msg.data();
+                let kind = Reflect::get(&data, &"type".into()).ok().and_then(|t| t.as_string()).unwrap_or_default();
+                let field = |name: &str| Reflect::get(&data, &name.into()).unwrap_or(JsValue::UNDEFINED);
+                match kind.as_str() {
+                    "event" => match postcard::from_bytes::<E>(&Uint8Array::new(&field("bytes")).to_vec()) {
+                        Ok(event) => on_event(event),
+                        Err(e) => tracing::warn!("Dropping undecodable event from worker: {}", e),
+                    },
+                    "reply" => {
+                        let id = field("id").as_f64().unwrap_or(-1.0) as u32;
+                        if let Some(reply) = pending.borrow_mut().remove(&id) {
+                            let value = field("value");
+                            let _ = reply.send(if field("ok").as_bool() == Some(true) { Ok(value) } else { Err(value) });
+                        }
+                    }
+                    "error" => on_error(describe(&field("error"))),
+                    "mirror" => {
+                        let scores = Array::from(&field("peers"))
+                            .iter()
+                            .filter_map(|peer| {
+                                let id = Reflect::get(&peer, &"peer_id".into()).ok()?.as_string()?;
+                                let score = Reflect::get(&peer, &"score".into()).ok()?.as_f64()?;
+                                Some((id, score))
+                            })
+                            .collect();
+                        *mirror.borrow_mut() = Mirror { stats: field("stats"), scores };
+                    }
+                    "loaded" | "started" | "failed" => {
+                        let _ = lifecycle.unbounded_send((kind, data));
+                    }
+                    _ => tracing::debug!("Ignoring worker message of type {:?}", kind),
+                }
+            })
+        };
+        // A script that fails to load or throws never says "loaded"
+        let onerror = Closure::<dyn FnMut(web_sys::ErrorEvent)>::new(move |event: web_sys::ErrorEvent| {
+            let failed = message("failed");
+            let error = JsValue::from_str(&format!("worker error: {}", event.message()));
+            let _ = Reflect::set(&failed, &"error".into(), &error);
+            let _ = lifecycle_on_error.unbounded_send(("failed".to_string(), failed.into()));
+        });
+        worker.set_onmessage(Some(onmessage.as_ref().unchecked_ref()));
+        worker.set_onerror(Some(onerror.as_ref().unchecked_ref()));
+        let link = WorkerLink {
+            inner: Rc::new(LinkInner {
+                worker,
+                next_id: Cell::new(0),
+                pending,
+                mirror,
+                _onmessage: onmessage,
+                _onerror: onerror,
+            }),
+        };
+
+        // Messages sent before the worker listens would be lost
+        let mut started = false;
+        while let Some((kind, data)) = lifecycle_rx.next().await {
+            match kind.as_str() {
+                "loaded" if !started => {
+                    let start = message("start");
+                    Reflect::set(&start, &"opts".into(), opts)?;
+                    link.inner.worker.post_message(&start)?;
+                    started = true;
+                }
+                "started" => {
+                    let peer_id = Reflect::get(&data, &"peerId".into())?.as_string().unwrap_or_default();
+                    return Ok((link, peer_id));
+                }
+                "failed" => return Err(Reflect::get(&data, &"error".into())?),
+                _ => {}
+            }
+        }
+        Err(error_to_js(&crate::Error::NodeStopped))
+    }
+
+    /// Run `method` in the worker and resolve with what it returned or rejected with.
+    pub(crate) async fn call(&self, target: Target<'_>, method: &str, args: &[JsValue]) -> Result<JsValue, JsValue> {
+        // 0 is for calls nobody waits for
+        let id = self.inner.next_id.get().checked_add(1).unwrap_or(1);
+        self.inner.next_id.set(id);
+        let (reply, rx) = oneshot::channel();
+        self.inner.pending.borrow_mut().insert(id, reply);
+        if let Err(e) = self.send(id, target, method, args) {
+            self.inner.pending.borrow_mut().remove(&id);
+            return Err(e);
+        }
+        rx.await.map_err(|_| error_to_js(&crate::Error::NodeStopped))?
+    }
+
+    /// Run `method` in the worker without waiting for it. Its failure, if any, arrives as
+    /// an `error` event.
+    pub(crate) fn post(&self, target: Target<'_>, method: &str, args: &[JsValue]) -> Result<(), JsValue> {
+        self.send(0, target, method, args)
+    }
+
+    /// The worker's traffic stats as of the last mirror.
+    pub(crate) fn stats(&self) -> JsValue {
+        self.inner.mirror.borrow().stats.clone()
+    }
+
+    /// Forget the mirrored stats along with the worker's.
+    pub(crate) fn reset_stats(&self) -> Result<(), JsValue> {
+        self.inner.mirror.borrow_mut().stats = Object::new().into();
+        self.post(Target::Node, "reset_stats", &[])
+    }
+
+    /// Peer scores as of the last mirror, best first.
+    pub(crate) fn scores(&self) -> Vec<(String, f64)> {
+        self.inner.mirror.borrow().scores.clone()
+    }
+
+    /// `id` 0 asks for no reply.
+    fn send(&self, id: u32, target: Target<'_>, method: &str, args: &[JsValue]) -> Result<(), JsValue> {
+        let call = message("call");
+        Reflect::set(&call, &"id".into(), &JsValue::from_f64(id as f64))?;
+        let (target, room) = match target {
+            Target::Node => ("node", JsValue::UNDEFINED),
+            Target::Room(room_id) => ("room", room_id.into()),
+            Target::Transaction => ("transaction", JsValue::UNDEFINED),
+        };
+        Reflect::set(&call, &"target".into(), &target.into())?;
+        Reflect::set(&call, &"room".into(), &room)?;
+        Reflect::set(&call, &"method".into(), &method.into())?;
+        let array = Array::new();
+        let transfer = Array::new();
+        for arg in args {
+            // Byte arguments were copied out of wasm memory already; hand the copy over
+            if let Some(bytes) = arg.dyn_ref::<Uint8Array>() {
+                transfer.push(&bytes.buffer());
+            }
+            array.push(arg);
+        }
+        Reflect::set(&call, &"args".into(), &array)?;
+        self.inner.worker.post_message_with_transfer(&call, &transfer)
+    }
+}
+
+/// Encode `value` for a `call` or `event` message, in a buffer of its own to transfer.
+pub(crate) fn encode<T: Serialize>(value: &T) -> Result<Uint8Array, JsValue> {
+    let bytes = postcard::to_allocvec(value).map_err(|e| JsValue::from_str(&format!("encode error: {e}")))?;
+    Ok(Uint8Array::from(bytes.as_slice()))
+}
+
+/// Deserialize one of the `&'static str` names events carry (transports, directions,
+/// announcement kinds). Each distinct name is allocated once and kept.
+pub(crate) fn interned<'de, D: Deserializer<'de>>(deserializer: D) -> Result<&'static str, D::Error> {
+    thread_local! {
+        static NAMES: RefCell<HashSet<&'static str>> = RefCell::default();
+    }
+    let name = String::deserialize(deserializer)?;
+    Ok(NAMES.with(|names| {
+        let mut names = names.borrow_mut();
+        if let Some(known) = names.get(name.as_str()) {
+            return *known;
+        }
+        let leaked: &'static str = Box::leak(name.into_boxed_str());
+        names.insert(leaked);
+        leaked
+    }))
+}
+
+fn message(kind: &str) -> Object {
+    let obj = Object::new();
+    let _ = Reflect::set(&obj, &"type".into(), &kind.into());
+    obj
+}
+
+/// `message` of an error object, or the value as a string.
+fn describe(error: &JsValue) -> String {
+    Reflect::get(error, &"message".into())
+        .ok()
+        .and_then(|m| m.as_string())
+        .or_else(|| error.as_string())
+        .unwrap_or_else(|| format!("{error:?}"))
+}
+
+/// Entry point of the worker script: `import init, { worker_main } from "./pkg/...";
+/// await init(); worker_main();`. Waits for `WasmNode.spawn_in_worker()` to send the
+/// options, then runs the node until the worker is terminated.
+#[wasm_bindgen]
+pub fn worker_main() -> Result<(), JsValue> {
+    let scope: DedicatedWorkerGlobalScope = js_sys::global().dyn_into()?;
+    let host = Rc::new(WorkerHost { scope: scope.clone(), node: RefCell::new(None) });
+    let onmessage = Closure::<dyn FnMut(MessageEvent)>::new(move |msg: MessageEvent| {
+        let host = host.clone();
+        spawn_local(async move { host.handle(msg.data()).await });
+    });
+    scope.set_onmessage(Some(onmessage.as_ref().unchecked_ref()));
+    // Lives as long as the worker
+    onmessage.forget();
+    scope.post_message(&message("loaded"))
+}
+
+/// Worker end: the node and the handles calls are forwarded to.
+struct WorkerHost {
+    scope: DedicatedWorkerGlobalScope,
+    node: RefCell<Option<HostedNode>>,
+}
+
+struct HostedNode {
+    node: JsValue,
+    /// Transactions from the main thread are committed through this one.
+    transaction: Rc<RefCell<WasmTransaction>>,
+    rooms: HashMap<String, JsValue>,
+}
+
+impl WorkerHost {
+    async fn handle(self: Rc<Self>, data: JsValue) {
+        let field = |name: &str| Reflect::get(&data, &name.into()).unwrap_or(JsValue::UNDEFINED);
+        match field("type").as_string().as_deref() {
+            Some("start") => self.start(field("opts")).await,
+            Some("call") => {
+                let id = field("id").as_f64().unwrap_or(0.0) as u32;
+                let result = self
+                    .dispatch(
+                        field("target").as_string().unwrap_or_default(),
+                        field("room").as_string(),
+                        field("method").as_string().unwrap_or_default(),
+                        Array::from(&field("args")),
+                    )
+                    .await;
+                let out = if id == 0 {
+                    let Err(error) = result else { return };
+                    let out = message("error");
+                    let _ = Reflect::set(&out, &"error".into(), &error);
+                    out
+                } else {
+                    let out = message("reply");
+                    let _ = Reflect::set(&out, &"id".into(), &JsValue::from_f64(id as f64));
+                    let _ = Reflect::set(&out, &"ok".into(), &result.is_ok().into());
+                    let _ = Reflect::set(&out, &"value".into(), &result.unwrap_or_else(|e| e));
+                    out
+                };
+                if let Err(e) = self.scope.post_message(&out) {
+                    tracing::warn!("Failed to answer the main thread: {:?}", e);
+                }
+            }
+            other => tracing::debug!("Ignoring message of type {:?}", other),
+        }
+    }
+
+    async fn start(self: Rc<Self>, opts: JsValue) {
+        #[allow(clippy::disallowed_methods)]
+        let (tap, mut events) = mpsc::unbounded::<Event>();
+        let node = match WasmNode::launch(opts, Some(tap)).await {
+            Ok(node) => node,
+            Err(error) => {
+                let out = message("failed");
+                let _ = Reflect::set(&out, &"error".into(), &error);
+                let _ = self.scope.post_message(&out);
+                return;
+            }
+        };
+        let peer_id = node.peer_id();
+        let transaction = Rc::new(RefCell::new(node.begin_transaction()));
+        let node = JsValue::from(node);
+        *self.node.borrow_mut() = Some(HostedNode { node: node.clone(), transaction, rooms: HashMap::new() });
+
+        let scope = self.scope.clone();
+        spawn_local(async move {
+            while let Some(event) = events.next().await {
+                let posted = encode(&event).and_then(|bytes| {
+                    let out = message("event");
+                    Reflect::set(&out, &"bytes".into(), &bytes)?;
+                    scope.post_message_with_transfer(&out, &Array::of1(&bytes.buffer()))
+                });
+                if let Err(e) = posted {
+                    tracing::warn!("Failed to forward an event: {:?}", e);
+                }
+            }
+        });
+        let scope = self.scope.clone();
+        spawn_local(async move {
+            loop {
+                futures_timer::Delay::new(MIRROR_INTERVAL).await;
+                let mirrored = (|| {
+                    let out = message("mirror");
+                    Reflect::set(&out, &"stats".into(), &invoke(&node, "stats", &Array::new())?)?;
+                    let everyone = Array::of1(&JsValue::from_f64(u32::MAX as f64));
+                    Reflect::set(&out, &"peers".into(), &invoke(&node, "top_peers", &everyone)?)?;
+                    scope.post_message(&out)
+                })();
+                if let Err(e) = mirrored {
+                    tracing::warn!("Failed to mirror node state: {:?}", e);
+                }
+            }
+        });
+
+        let out = message("started");
+        let _ = Reflect::set(&out, &"peerId".into(), &peer_id.into());
+        let _ = self.scope.post_message(&out);
+    }
+
+    async fn dispatch(&self, target: String, room: Option<String>, method: String, args: Array) -> Result<JsValue, JsValue> {
+        let (node, transaction, room_handle) = {
+            let hosted = self.node.borrow();
+            let hosted = hosted.as_ref().ok_or_else(|| error_to_js(&crate::Error::NodeStopped))?;
+            let room_handle = room.as_ref().and_then(|room_id| hosted.rooms.get(room_id).cloned());
+            (hosted.node.clone(), hosted.transaction.clone(), room_handle)
+        };
+        match target.as_str() {
+            "node" => {
+                let out = resolve(invoke(&node, &method, &args)?).await?;
+                match method.as_str() {
+                    // Room handles can't be cloned across; keep them here by room id
+                    "join_room" | "join_with_link" => {
+                        let room_id = Reflect::get(&out, &"room_id".into())?.as_string().unwrap_or_default();
+                        self.hosted_rooms(|rooms| rooms.insert(room_id, out));
+                        Ok(JsValue::NULL)
+                    }
+                    "leave_room" => {
+                        if let Some(room_id) = args.get(0).as_string() {
+                            self.hosted_rooms(|rooms| rooms.remove(&room_id));
+                        }
+                        Ok(out)
+                    }
+                    _ => Ok(out),
+                }
+            }
+            "room" => {
+                let room_id = room.unwrap_or_default();
+                let handle = room_handle.ok_or_else(|| error_to_js(&crate::Error::NotInRoom { room_id: room_id.clone() }))?;
+                let out = resolve(invoke(&handle, &method, &args)?).await?;
+                if method == "leave" {
+                    self.hosted_rooms(|rooms| rooms.remove(&room_id));
+                }
+                Ok(out)
+            }
+            "transaction" => {
+                let tx: Transaction = decode(&args.get(0))?;
+                transaction.borrow_mut().commit_from(tx)?;
+                Ok(JsValue::UNDEFINED)
+            }
+            other => Err(JsValue::from_str(&format!("unknown call target {other:?}"))),
+        }
+    }
+
+    fn hosted_rooms<R>(&self, f: impl FnOnce(&mut HashMap<String, JsValue>) -> R) -> Option<R> {
+        self.node.borrow_mut().as_mut().map(|hosted| f(&mut hosted.rooms))
+    }
+}
+
+/// Call `object.method(...args)` through its JS binding, as the page would.
+fn invoke(object: &JsValue, method: &str, args: &Array) -> Result<JsValue, JsValue> {
+    let function: Function = Reflect::get(object, &method.into())?
+        .dyn_into()
+        .map_err(|_| JsValue::from_str(&format!("no method {method:?}")))?;
+    function.apply(object, args)
+}
+
+/// The value a promise resolves with, or `value` itself.
+async fn resolve(value: JsValue) -> Result<JsValue, JsValue> {
+    match value.dyn_into::<Promise>() {
+        Ok(promise) => JsFuture::from(promise).await,
+        Err(value) => Ok(value),
+    }
+}
+
+fn decode<T: DeserializeOwned>(bytes: &JsValue) -> Result<T, JsValue> {
+    let bytes = bytes.dyn_ref::<Uint8Array>().ok_or_else(|| JsValue::from_str("expected a Uint8Array"))?.to_vec();
+    postcard::from_bytes(&bytes).map_err(|e| JsValue::from_str(&format!("decode error: {e}")))
+}
@@ -0,0 +1,243 @@
+//! Browser tests for the wasm bindings. Run headless with
+//! `wasm-pack test --headless --firefox -- --features test-util` (or `--chrome`).
+//!
+//! Nodes are pointed at a websocket address nobody listens on: the dial fails in the
+//! background, which leaves construction, option parsing, getters and event conversion
+//! to test without a relay.
+
+#![cfg(all(target_arch = "wasm32", feature = "wasm"))]
+
+use js_sys::{Array, Object, Reflect, Uint8Array};
+use libp2p::identity::Keypair;
+use wasm_bindgen::JsValue;
+use wasm_bindgen_test::*;
+
+use simple_p2p_docstore::behaviour::docstore::TopicRegistry;
+use simple_p2p_docstore::{generate_keypair, MultiaddrWrapper, PeerIdWrapper, WasmNode};
+
+wasm_bindgen_test_configure!(run_in_browser);
+
+fn get(value: &JsValue, key: &str) -> JsValue {
+    Reflect::get(value, &key.into()).expect("readable property")
+}
+
+fn options(pairs: &[(&str, JsValue)]) -> JsValue {
+    let obj = Object::new();
+    for (key, value) in pairs {
+        Reflect::set(&obj, &(*key).into(), value).expect("writable object");
+    }
+    obj.into()
+}
+
+/// A dialable address with nothing behind it.
+fn unreachable_relay() -> String {
+    format!("/ip4/127.0.0.1/tcp/1/ws/p2p/{}", Keypair::generate_ed25519().public().to_peer_id())
+}
+
+/// The message of a constructor error, plain or structured.
+fn start_error(addr: &str, opts: JsValue) -> String {
+    match WasmNode::new(addr.to_string(), opts) {
+        Ok(_) => panic!("{addr} with these options should be rejected"),
+        Err(e) => e.as_string().or_else(|| get(&e, "message").as_string()).expect("constructor errors have a message"),
+    }
+}
+
+/// `(code, invalidField)` of a structured error.
+fn invalid_field(err: &JsValue) -> (Option<String>, Option<String>) {
+    (get(err, "code").as_string(), get(err, "invalidField").as_string())
+}
+
+#[wasm_bindgen_test]
+fn constructor_rejects_bad_addresses_and_options() {
+    assert!(start_error("not a multiaddr", JsValue::UNDEFINED).starts_with("invalid multiaddr"));
+    assert!(start_error("/dnsaddr/example.com", JsValue::UNDEFINED).contains("/dnsaddr cannot be resolved"));
+
+    let relay = unreachable_relay();
+    let role = start_error(&relay, options(&[("role", "full".into())]));
+    assert_eq!(role, "browser nodes support only the client and observer roles");
+    let key = start_error(&relay, options(&[("identityKey", "abc".into())]));
+    assert_eq!(key, "identityKey must be a Uint8Array");
+    let garbage = Uint8Array::from(&[1u8, 2, 3][..]);
+    assert!(start_error(&relay, options(&[("identityKey", garbage.into())])).starts_with("invalid identityKey"));
+    let announcers = Array::of1(&"not a peer id".into());
+    let err = WasmNode::new(relay.clone(), options(&[("announcers", announcers.into())])).err().unwrap();
+    assert_eq!(invalid_field(&err), (Some("InvalidArgument".into()), Some("announcers".into())));
+
+    // Settings that don't make a working node are reported like other crate errors
+    let err = match WasmNode::new(relay, options(&[("topicNamespace", "a/b".into())])) {
+        Ok(_) => panic!("an invalid namespace should be rejected"),
+        Err(e) => e,
+    };
+    assert_eq!(get(&err, "code").as_string().as_deref(), Some("InvalidConfig"));
+}
+
+#[wasm_bindgen_test]
+fn validate_multiaddr_reports_each_problem() {
+    let report = WasmNode::validate_multiaddr(unreachable_relay()).unwrap();
+    assert_eq!(get(&report, "valid"), JsValue::TRUE);
+    assert_eq!(get(&report, "dialable"), JsValue::TRUE);
+    assert_eq!(get(&report, "transport").as_string().as_deref(), Some("websocket"));
+    assert!(get(&report, "peer_id").is_string());
+    let protocols: Vec<String> = Array::from(&get(&report, "protocols")).iter().filter_map(|p| p.as_string()).collect();
+    assert_eq!(protocols, ["ip4", "tcp", "ws", "p2p"]);
+    assert_eq!(Array::from(&get(&report, "errors")).length(), 0);
+
+    let report = WasmNode::validate_multiaddr("/ip4/127.0.0.1/udp/9090/webrtc-direct".into()).unwrap();
+    assert_eq!(get(&report, "valid"), JsValue::TRUE);
+    assert_eq!(get(&report, "dialable"), JsValue::FALSE);
+    assert!(get(&report, "peer_id").is_null());
+    assert_eq!(Array::from(&get(&report, "errors")).length(), 2, "no certhash, no peer id");
+
+    let report = WasmNode::validate_multiaddr("garbage".into()).unwrap();
+    assert_eq!(get(&report, "valid"), JsValue::FALSE);
+    assert!(get(&report, "addr").is_null());
+
+    // The constructor rejects with the report's first error
+    let tcp = "/ip4/127.0.0.1/tcp/4001";
+    let first = Array::from(&get(&WasmNode::validate_multiaddr(tcp.into()).unwrap(), "errors")).get(0);
+    assert_eq!(Some(start_error(tcp, JsValue::UNDEFINED)), first.as_string());
+}
+
+#[wasm_bindgen_test]
+fn peer_id_wrappers_parse_base58_and_cid_encodings() {
+    let base58 = "12D3KooWD3eckifWpRn9wQpMG9R9hX3sD158z7EqHWmweQAJU5SA";
+    let cid = "bafzaajaiaejcal72gwuz2or47oyxxn6b3rkwdmmkrxgkjxzy3rqt5kczyn7lcm3l";
+    assert!(PeerIdWrapper::is_valid(base58) && PeerIdWrapper::is_valid(cid));
+    let (from_base58, from_cid) = (PeerIdWrapper::parse(base58).unwrap(), PeerIdWrapper::parse(cid).unwrap());
+    assert_eq!(from_base58, from_cid);
+    assert_eq!(from_cid.to_js_string(), base58);
+    assert_eq!(from_cid.short_id(), "12D3Ko…AJU5SA");
+
+    assert!(!PeerIdWrapper::is_valid("12D3KooWnope"));
+    let err = PeerIdWrapper::parse("12D3KooWnope").unwrap_err();
+    assert_eq!(invalid_field(&err), (Some("InvalidArgument".into()), Some("peerId".into())));
+    assert!(get(&err, "message").as_string().is_some_and(|m| m.contains("12D3KooWnope")));
+
+    let addr = format!("/ip4/127.0.0.1/tcp/1/ws/p2p/{base58}");
+    assert_eq!(MultiaddrWrapper::parse(&addr).unwrap().short_id(), "/ip4/127.0.0.1/tcp/1/ws/p2p/12D3Ko…AJU5SA");
+    assert!(!MultiaddrWrapper::is_valid("/ip4/nope"));
+}
+
+#[wasm_bindgen_test]
+async fn methods_take_wrappers_or_strings_and_name_the_bad_argument() {
+    let node = WasmNode::new(unreachable_relay(), JsValue::UNDEFINED).unwrap();
+    let peer = Keypair::generate_ed25519().public().to_peer_id().to_string();
+    let wrapper: JsValue = PeerIdWrapper::parse(&peer).unwrap().into();
+    assert_eq!(node.connection_state(wrapper.clone()).await.unwrap(), "disconnected");
+    assert_eq!(node.connection_state(peer.clone().into()).await.unwrap(), "disconnected");
+    node.report_peer(wrapper, "fetch_failed".into()).unwrap();
+    assert!(node.peer_score(peer.into()).unwrap() < 0.0);
+
+    let err = node.ban_peer("garbage".into(), 1000.0).unwrap_err();
+    assert_eq!(invalid_field(&err), (Some("InvalidArgument".into()), Some("peerId".into())));
+    let err = node.peer_score(JsValue::from_f64(1.0)).unwrap_err();
+    assert_eq!(invalid_field(&err).1.as_deref(), Some("peerId"));
+    let err = node.remove_peer_address(PeerIdWrapper::parse(&node.peer_id()).unwrap().into(), "/ip4/nope".into()).unwrap_err();
+    assert_eq!(invalid_field(&err).1.as_deref(), Some("addr"));
+    let err = node.dial_peer("/dnsaddr/example.com".into(), JsValue::UNDEFINED).unwrap_err();
+    assert_eq!(invalid_field(&err).1.as_deref(), Some("peerAddr"));
+}
+
+#[wasm_bindgen_test]
+async fn with_config_needs_a_bootstrap_address() {
+    let err = match WasmNode::with_config(options(&[("bootstrap", Array::new().into())])).await {
+        Ok(_) => panic!("an empty bootstrap list should be rejected"),
+        Err(e) => e,
+    };
+    assert_eq!(err.as_string().as_deref(), Some("at least one bootstrap multiaddr is required"));
+}
+
+#[wasm_bindgen_test]
+fn imported_keys_set_the_peer_id() {
+    for key_type in [None, Some("ed25519"), Some("secp256k1"), Some("ecdsa")] {
+        let key = generate_keypair(key_type.map(String::from)).unwrap();
+        let expected = Keypair::from_protobuf_encoding(&key).unwrap().public().to_peer_id();
+        let opts = options(&[("identityKey", Uint8Array::from(&key[..]).into())]);
+        let node = WasmNode::new(unreachable_relay(), opts).unwrap();
+        assert_eq!(node.peer_id(), expected.to_string(), "{key_type:?}");
+    }
+    assert!(generate_keypair(Some("rsa".into())).is_err());
+}
+
+#[wasm_bindgen_test]
+async fn network_status_starts_empty_and_subscribed() {
+    let node = WasmNode::new(unreachable_relay(), JsValue::UNDEFINED).unwrap();
+    let status = node.get_network_status().await.unwrap();
+
+    let subscriptions: Vec<String> =
+        Array::from(&get(&status, "subscriptions")).iter().filter_map(|s| s.as_string()).collect();
+    assert_eq!(subscriptions, [TopicRegistry::default().updates().to_string()]);
+    for key in ["listen_addrs", "relays"] {
+        assert!(Array::is_array(&get(&status, key)), "{key}");
+        assert_eq!(Array::from(&get(&status, key)).length(), 0, "{key}");
+    }
+    assert!(get(&status, "connected_peers").is_object());
+    assert!(get(&status, "discovered_peers").is_object());
+}
+
+#[wasm_bindgen_test]
+async fn crate_errors_are_structured() {
+    let node = WasmNode::new(unreachable_relay(), options(&[("role", "observer".into())])).unwrap();
+    let err = node.publish_update("hello".into()).unwrap_err();
+    assert_eq!(get(&err, "code").as_string().as_deref(), Some("ReadOnly"));
+    assert!(get(&err, "message").as_string().is_some_and(|m| m.contains("read-only")));
+
+    let node = WasmNode::new(unreachable_relay(), options(&[("dht", false.into())])).unwrap();
+    let err = node.dht_summary().await.unwrap_err();
+    assert_eq!(get(&err, "code").as_string().as_deref(), Some("DhtDisabled"));
+}
+
+#[wasm_bindgen_test]
+async fn head_pointer_calls_check_their_arguments_up_front() {
+    let node = WasmNode::new(unreachable_relay(), JsValue::UNDEFINED).unwrap();
+    let err = node.resolve_head("12D3KooWnope".into(), "notes".into()).await.unwrap_err();
+    assert_eq!(invalid_field(&err), (Some("InvalidArgument".into()), Some("author".into())));
+    let err = node.publish_head_pointer("notes".into(), -1.0, b"v1".to_vec()).await.unwrap_err();
+    assert_eq!(invalid_field(&err), (Some("InvalidArgument".into()), Some("version".into())));
+
+    let node = WasmNode::new(unreachable_relay(), options(&[("dht", false.into())])).unwrap();
+    let err = node.publish_head_pointer("notes".into(), 1.0, b"v1".to_vec()).await.unwrap_err();
+    assert_eq!(get(&err, "code").as_string().as_deref(), Some("DhtDisabled"));
+}
+
+#[wasm_bindgen_test]
+fn transactions_check_their_updates_up_front() {
+    let node = WasmNode::new(unreachable_relay(), JsValue::UNDEFINED).unwrap();
+    let mut tx = node.begin_transaction();
+    assert!(tx.id().parse::<u64>().is_ok());
+    let err = tx.commit().unwrap_err();
+    assert_eq!(get(&err, "code").as_string().as_deref(), Some("EmptyTransaction"));
+    let err = tx.add_update("doc".into(), "x".repeat(1024 * 1024)).unwrap_err();
+    assert_eq!(get(&err, "code").as_string().as_deref(), Some("UpdateTooLarge"));
+    tx.add_update("meta".into(), "m".into()).unwrap();
+    tx.add_update("content".into(), "c".into()).unwrap();
+    tx.commit().unwrap();
+
+    let observer = WasmNode::new(unreachable_relay(), options(&[("role", "observer".into())])).unwrap();
+    let mut tx = observer.begin_transaction();
+    tx.add_update("doc".into(), "x".into()).unwrap();
+    assert_eq!(get(&tx.commit().unwrap_err(), "code").as_string().as_deref(), Some("ReadOnly"));
+}
+
+#[cfg(feature = "test-util")]
+#[wasm_bindgen_test]
+async fn injected_events_reach_consumers_as_js_objects() {
+    let node = WasmNode::new(unreachable_relay(), JsValue::UNDEFINED).unwrap();
+    let types = Array::of1(&"docUpdateReceived".into());
+    let subscription = node.subscribe_events(options(&[("types", types.into())])).unwrap();
+
+    node.inject_doc_update("12D3KooWtest".into(), "doc-1".into(), "hello".into()).unwrap();
+    let event = subscription.next_timeout(5000).await.unwrap();
+    assert!(!event.is_null(), "injected event never arrived");
+    assert_eq!(get(&event, "type").as_string().as_deref(), Some("docUpdateReceived"));
+    assert_eq!(get(&event, "peer_id").as_string().as_deref(), Some("12D3KooWtest"));
+    assert_eq!(get(&event, "topic").as_string(), Some(TopicRegistry::default().updates().to_string()));
+    assert_eq!(get(&event, "doc_id").as_string().as_deref(), Some("doc-1"));
+    assert_eq!(get(&event, "data").as_string().as_deref(), Some("hello"));
+
+    // The same event is kept in the node's history
+    let recent = node.recent_events(Some(vec!["docUpdateReceived".into()]), None).unwrap();
+    let recent = Array::from(&recent);
+    assert_eq!(recent.length(), 1);
+    assert_eq!(get(&recent.get(0), "doc_id").as_string().as_deref(), Some("doc-1"));
+}
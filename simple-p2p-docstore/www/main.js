@@ -112,16 +112,28 @@ async function pollEvents() {
     
     switch (event.type) {
       case "connected":
-        log(`✓ Connected to ${event.peer_id}`);
+        log(`✓ Connected to ${event.peer_id} over ${event.transport} (${event.direction})`);
+        break;
+      case "dialing":
+        log(`… Dialing ${event.addr ?? event.peer_id ?? "unknown peer"}`);
+        break;
+      case "incomingConnection":
+        log(`… Incoming connection from ${event.addr}`);
+        break;
+      case "dialFailed":
+        log(`✗ Dial to ${event.peer_id ?? "unknown peer"} failed (${event.reason}): ${event.msg}`);
         break;
       case "disconnected":
         log(`✗ Disconnected from ${event.peer_id}`);
         break;
       case "messageReceived":
-        log(`📨 Message from ${event.peer_id}: ${event.data}`);
+        log(`📨 Message ${event.msg_id} on ${event.topic} from ${event.peer_id}: ${event.data}`);
         break;
       case "messagePublished":
-        log(`📤 Published message ${event.msg_id}`);
+        log(`📤 Published message ${event.msg_id} to ${event.sent_to.length} peer(s)`);
+        break;
+      case "publishWarning":
+        log(`⚠ Message ${event.msg_id}: ${event.msg}`);
         break;
       case "peerDiscovery":
         // event.addrs is an array of addresses, event.peer_id is string
@@ -187,7 +199,13 @@ async function pollEvents() {
       return;
     }
     try {
-      node = new wasm.WasmNode(addr);
+      if (document.getElementById("useWorker").checked) {
+        // Same API; the swarm runs in www/worker.js
+        const workerUrl = new URL("./worker.js", import.meta.url).href;
+        node = await wasm.WasmNode.spawn_in_worker({ bootstrap: addr, workerUrl });
+      } else {
+        node = new wasm.WasmNode(addr);
+      }
       connectedRelayAddr = addr; // Store for later use
       log(`Started node (peer_id: ${node.peer_id})`);
       log("Connecting to relay server...");
@@ -285,8 +303,11 @@ async function pollEvents() {
       return;
     }
     try {
-      node.find_peer(peerId);
       log(`Started Kademlia find_peer query for ${peerId}`);
+      const found = await node.find_peer(peerId, { dial: true });
+      for (const peer of found) {
+        log(`find_peer: ${peer.peer_id} dialable via [${peer.dialable.join(", ")}]`);
+      }
     } catch (e) {
       log("find_peer error: " + e);
     }
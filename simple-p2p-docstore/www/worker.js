@@ -0,0 +1,5 @@
+// Runs the node for `WasmNode.spawn_in_worker()`; the page talks to it over postMessage.
+import init, { worker_main } from "../pkg/simple_p2p_docstore.js";
+
+await init();
+worker_main();